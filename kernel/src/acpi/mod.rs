@@ -4,12 +4,15 @@
 //!
 //! ## Features
 //!
-//! - ACPI table parsing (RSDP, RSDT, XSDT, FADT, MADT)
+//! - ACPI table parsing (RSDP, RSDT, XSDT, FADT, MADT, NFIT)
 //! - Power state management (S0-S5)
 //! - CPU power states (C-states, P-states)
 //! - Thermal management
 //! - Battery status
 //! - Hardware shutdown/reboot
+//! - Persistent memory discovery (see [`nfit`])
+
+pub mod nfit;
 
 use alloc::string::String;
 use alloc::vec::Vec;
@@ -456,6 +459,8 @@ pub struct AcpiSubsystem {
     io_apics: RwLock<Vec<IoApicInfo>>,
     /// Interrupt overrides
     interrupt_overrides: RwLock<Vec<InterruptOverride>>,
+    /// Persistent memory ranges from the NFIT
+    pmem_ranges: RwLock<Vec<nfit::PmemRange>>,
     /// PM1a control block
     pm1a_control: spin::Mutex<u16>,
     /// PM1b control block
@@ -476,6 +481,7 @@ impl AcpiSubsystem {
             processors: RwLock::new(Vec::new()),
             io_apics: RwLock::new(Vec::new()),
             interrupt_overrides: RwLock::new(Vec::new()),
+            pmem_ranges: RwLock::new(Vec::new()),
             pm1a_control: spin::Mutex::new(0),
             pm1b_control: spin::Mutex::new(0),
             slp_typa: spin::Mutex::new([0; 6]),
@@ -627,6 +633,10 @@ impl AcpiSubsystem {
             MCFG_SIGNATURE => {
                 crate::serial_println!("[acpi] Found MCFG (PCIe) table");
             }
+            nfit::NFIT_SIGNATURE => {
+                crate::serial_println!("[acpi] Parsing NFIT");
+                self.parse_nfit(addr, header.length as usize);
+            }
             _ => {
                 let sig_str = core::str::from_utf8(&sig).unwrap_or("????");
                 crate::serial_println!("[acpi] Found table: {}", sig_str);
@@ -749,6 +759,26 @@ impl AcpiSubsystem {
         *self.interrupt_overrides.write() = overrides;
     }
     
+    /// Parses the NFIT.
+    fn parse_nfit(&self, addr: usize, length: usize) {
+        let table = unsafe { core::slice::from_raw_parts(addr as *const u8, length) };
+        let ranges = nfit::parse(table);
+        for range in &ranges {
+            crate::serial_println!(
+                "[acpi] NFIT: pmem range {} at {:#x}, {} MB",
+                range.index,
+                range.base,
+                range.length / (1024 * 1024)
+            );
+        }
+        *self.pmem_ranges.write() = ranges;
+    }
+    
+    /// Returns the persistent memory ranges reported by the NFIT.
+    pub fn pmem_ranges(&self) -> Vec<nfit::PmemRange> {
+        self.pmem_ranges.read().clone()
+    }
+    
     /// Returns the list of processors.
     pub fn processors(&self) -> Vec<ProcessorInfo> {
         self.processors.read().clone()
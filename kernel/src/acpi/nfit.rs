@@ -0,0 +1,176 @@
+//! # NVDIMM Firmware Interface Table (NFIT)
+//!
+//! The NFIT describes the NVDIMMs on the platform and the system physical
+//! address (SPA) ranges they are mapped at. Only SPA ranges of the
+//! persistent-memory type are collected; they are what the pmem block driver
+//! and DAX mappings are built on.
+//!
+//! ## Layout
+//!
+//! ```text
+//! ┌──────────────────────┐  0
+//! │ ACPI header          │
+//! ├──────────────────────┤  36
+//! │ reserved (u32)       │
+//! ├──────────────────────┤  40
+//! │ structure: type, len │  type 0 = SPA range, 1 = region mapping, ...
+//! │ ...                  │
+//! └──────────────────────┘
+//! ```
+
+use alloc::vec::Vec;
+
+/// ACPI signature for NFIT.
+pub const NFIT_SIGNATURE: [u8; 4] = *b"NFIT";
+
+/// Offset of the first structure (header + reserved field).
+const FIRST_STRUCTURE: usize = 40;
+
+/// Structure type of an SPA range.
+const SPA_RANGE: u16 = 0;
+
+/// Length of an SPA range structure.
+const SPA_RANGE_LEN: usize = 56;
+
+/// Address range type GUID of persistent memory
+/// (66F0D379-B4F3-4074-AC43-0D3318B78CDB), in table byte order.
+pub const PMEM_REGION_GUID: [u8; 16] = [
+    0x79, 0xD3, 0xF0, 0x66, 0xF3, 0xB4, 0x74, 0x40,
+    0xAC, 0x43, 0x0D, 0x33, 0x18, 0xB7, 0x8C, 0xDB,
+];
+
+/// SPA range flag: the proximity domain field is valid.
+pub const SPA_PROXIMITY_VALID: u16 = 1 << 1;
+
+/// A persistent-memory range from the NFIT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PmemRange {
+    /// SPA range structure index
+    pub index: u16,
+    /// NUMA proximity domain, if the firmware reported one
+    pub proximity_domain: Option<u32>,
+    /// Physical base address
+    pub base: u64,
+    /// Length in bytes
+    pub length: u64,
+    /// EFI memory mapping attributes (cacheability)
+    pub attributes: u64,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+/// Extracts the persistent-memory ranges from a complete NFIT.
+///
+/// Malformed structures end the walk; ranges found before them are kept.
+pub fn parse(table: &[u8]) -> Vec<PmemRange> {
+    let mut ranges = Vec::new();
+    if table.len() < FIRST_STRUCTURE || table[..4] != NFIT_SIGNATURE {
+        return ranges;
+    }
+    let end = (read_u32(table, 4) as usize).min(table.len());
+
+    let mut offset = FIRST_STRUCTURE;
+    while offset + 4 <= end {
+        let kind = read_u16(table, offset);
+        let length = read_u16(table, offset + 2) as usize;
+        if length < 4 || offset + length > end {
+            break;
+        }
+
+        if kind == SPA_RANGE && length >= SPA_RANGE_LEN {
+            let spa = &table[offset..offset + length];
+            let flags = read_u16(spa, 6);
+            let range = PmemRange {
+                index: read_u16(spa, 4),
+                proximity_domain: (flags & SPA_PROXIMITY_VALID != 0).then(|| read_u32(spa, 12)),
+                base: read_u64(spa, 32),
+                length: read_u64(spa, 40),
+                attributes: read_u64(spa, 48),
+            };
+            if spa[16..32] == PMEM_REGION_GUID && range.length > 0 {
+                ranges.push(range);
+            }
+        }
+
+        offset += length;
+    }
+
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spa(index: u16, guid: &[u8; 16], base: u64, length: u64) -> Vec<u8> {
+        let mut s = Vec::new();
+        s.extend_from_slice(&SPA_RANGE.to_le_bytes());
+        s.extend_from_slice(&(SPA_RANGE_LEN as u16).to_le_bytes());
+        s.extend_from_slice(&index.to_le_bytes());
+        s.extend_from_slice(&SPA_PROXIMITY_VALID.to_le_bytes());
+        s.extend_from_slice(&0u32.to_le_bytes());
+        s.extend_from_slice(&1u32.to_le_bytes());
+        s.extend_from_slice(guid);
+        s.extend_from_slice(&base.to_le_bytes());
+        s.extend_from_slice(&length.to_le_bytes());
+        s.extend_from_slice(&0x8008u64.to_le_bytes());
+        s
+    }
+
+    fn table(structures: &[Vec<u8>]) -> Vec<u8> {
+        let mut t = Vec::new();
+        t.extend_from_slice(&NFIT_SIGNATURE);
+        t.extend_from_slice(&[0u8; FIRST_STRUCTURE - 4]);
+        for s in structures {
+            t.extend_from_slice(s);
+        }
+        let len = t.len() as u32;
+        t[4..8].copy_from_slice(&len.to_le_bytes());
+        t
+    }
+
+    #[test]
+    fn test_parse_pmem_ranges() {
+        let volatile = [0x11u8; 16];
+        let mut region_mapping = alloc::vec![0u8; 48];
+        region_mapping[0] = 1;
+        region_mapping[2] = 48;
+
+        let t = table(&[
+            spa(1, &PMEM_REGION_GUID, 0x1_4000_0000, 0x4000_0000),
+            region_mapping,
+            spa(2, &volatile, 0x2_0000_0000, 0x1000_0000),
+        ]);
+        let ranges = parse(&t);
+        assert_eq!(ranges.len(), 1);
+        assert_eq!(ranges[0].index, 1);
+        assert_eq!(ranges[0].base, 0x1_4000_0000);
+        assert_eq!(ranges[0].length, 0x4000_0000);
+        assert_eq!(ranges[0].proximity_domain, Some(1));
+    }
+
+    #[test]
+    fn test_truncated_structure_stops_walk() {
+        let mut t = table(&[spa(1, &PMEM_REGION_GUID, 0x1_0000_0000, 0x100_0000)]);
+        // Claim a second structure that runs past the table
+        t.extend_from_slice(&[0, 0, 200, 0]);
+        let len = t.len() as u32;
+        t[4..8].copy_from_slice(&len.to_le_bytes());
+        assert_eq!(parse(&t).len(), 1);
+        assert!(parse(&t[..20]).is_empty());
+    }
+}
@@ -272,6 +272,8 @@ fn handle_syscall(ctx: &mut ExceptionContext, syscall_num: u64) {
                 }
             }
         }
+        // No fork (1079): AArch64 has no process context switch yet, so a
+        // forked child could never run. Copy-on-write fork is x86_64 only.
         // waitpid (260)
         260 => {
             let pid = args[0] as i64;
@@ -309,6 +311,89 @@ fn handle_syscall(ctx: &mut ExceptionContext, syscall_num: u64) {
                 Err(_) => (-3i64) as u64, // -ESRCH
             }
         }
+        // personality (92)
+        92 => {
+            let pid = crate::sched::scheduler().current_process()
+                .unwrap_or(crate::sched::ProcessId::KERNEL);
+            crate::mm::security::set_personality(pid, args[0])
+        }
+        // getrandom (278)
+        278 => {
+            // args[0] = buffer, args[1] = length; never blocks, and returns
+            // at most 256 bytes per call
+            let pid = crate::sched::scheduler().current_process()
+                .unwrap_or(crate::sched::ProcessId::KERNEL);
+            let mut bytes = [0u8; 256];
+            let bytes = &mut bytes[..(args[1] as usize).min(256)];
+            crate::mm::security::aslr_random_bytes(bytes);
+            match crate::mm::vm::copy_to_user(pid, args[0], bytes) {
+                Ok(()) => bytes.len() as u64,
+                Err(_) => (-14i64) as u64, // -EFAULT
+            }
+        }
+        // mount (40)
+        #[cfg(not(feature = "microkernel"))]
+        40 => {
+            // args[0] = pointer to the source, target and type as three
+            // (pointer, length) pairs, args[1] = mount_ns token pointer
+            let pid = crate::sched::scheduler().current_process()
+                .unwrap_or(crate::sched::ProcessId::KERNEL);
+            match (mount_args(pid, args[0]), user_token(pid, args[1])) {
+                (Some((source, target, fs_type)), Some(token)) => {
+                    match crate::fs::mount_as(pid, &fs_type, &source, &target, token) {
+                        Ok(()) => 0,
+                        Err(e) => (-(e.errno() as i64)) as u64,
+                    }
+                }
+                _ => (-22i64) as u64, // -EINVAL
+            }
+        }
+        // umount2 (39)
+        #[cfg(not(feature = "microkernel"))]
+        39 => {
+            // args[0] = target pointer, args[1] = target length,
+            // args[2] = mount_ns token pointer
+            let pid = crate::sched::scheduler().current_process()
+                .unwrap_or(crate::sched::ProcessId::KERNEL);
+            match (user_str(pid, args[0], args[1]), user_token(pid, args[2])) {
+                (Some(target), Some(token)) => match crate::fs::unmount_as(pid, &target, token) {
+                    Ok(()) => 0,
+                    Err(e) => (-(e.errno() as i64)) as u64,
+                },
+                _ => (-22i64) as u64, // -EINVAL
+            }
+        }
+        // unshare (97)
+        #[cfg(not(feature = "microkernel"))]
+        97 => {
+            // args[0] = flags (only CLONE_NEWNS), args[1] = mount_ns token pointer
+            let pid = crate::sched::scheduler().current_process()
+                .unwrap_or(crate::sched::ProcessId::KERNEL);
+            match user_token(pid, args[1]) {
+                Some(token) if args[0] == CLONE_NEWNS => match crate::fs::unshare_mounts(pid, token) {
+                    Ok(_) => 0,
+                    Err(e) => (-(e.errno() as i64)) as u64,
+                },
+                _ => (-22i64) as u64, // -EINVAL
+            }
+        }
+        // pivot_root (41)
+        #[cfg(not(feature = "microkernel"))]
+        41 => {
+            // args[0..2] = new root, args[2..4] = old root's new place,
+            // args[4] = mount_ns token pointer
+            let pid = crate::sched::scheduler().current_process()
+                .unwrap_or(crate::sched::ProcessId::KERNEL);
+            match (user_str(pid, args[0], args[1]), user_str(pid, args[2], args[3]), user_token(pid, args[4])) {
+                (Some(new_root), Some(put_old), Some(token)) => {
+                    match crate::fs::pivot_root(pid, &new_root, &put_old, token) {
+                        Ok(()) => 0,
+                        Err(e) => (-(e.errno() as i64)) as u64,
+                    }
+                }
+                _ => (-22i64) as u64, // -EINVAL
+            }
+        }
         // Unknown syscall
         _ => (-38i64) as u64, // -ENOSYS
     };
@@ -317,6 +402,55 @@ fn handle_syscall(ctx: &mut ExceptionContext, syscall_num: u64) {
     ctx.elr += 4;
 }
 
+/// A string passed to a syscall as a (pointer, length) pair, copied in.
+#[cfg(not(feature = "microkernel"))]
+fn user_str(pid: crate::sched::ProcessId, ptr: u64, len: u64) -> Option<alloc::string::String> {
+    if len > 256 {
+        return None;
+    }
+    let mut bytes = alloc::vec![0; len as usize];
+    crate::mm::vm::copy_from_user(pid, ptr, &mut bytes).ok()?;
+    alloc::string::String::from_utf8(bytes).ok()
+}
+
+/// `unshare` flag for a new mount namespace, the only one supported.
+#[cfg(not(feature = "microkernel"))]
+const CLONE_NEWNS: u64 = 0x2_0000;
+
+/// `N` words passed to a syscall by pointer, copied in.
+#[cfg(not(feature = "microkernel"))]
+fn user_words<const N: usize>(pid: crate::sched::ProcessId, ptr: u64) -> Option<[u64; N]> {
+    let mut words = [0; N];
+    for (i, word) in words.iter_mut().enumerate() {
+        let mut bytes = [0; 8];
+        crate::mm::vm::copy_from_user(pid, ptr.checked_add(8 * i as u64)?, &mut bytes).ok()?;
+        *word = u64::from_ne_bytes(bytes);
+    }
+    Some(words)
+}
+
+/// A capability token passed to a syscall as a pointer to its 32 bytes,
+/// copied in.
+#[cfg(not(feature = "microkernel"))]
+fn user_token(pid: crate::sched::ProcessId, ptr: u64) -> Option<crate::cap::CapabilityToken> {
+    user_words(pid, ptr).map(crate::cap::CapabilityToken::new)
+}
+
+/// `mount`'s source, target and type, from the (pointer, length) pairs at
+/// `ptr`.
+#[cfg(not(feature = "microkernel"))]
+fn mount_args(
+    pid: crate::sched::ProcessId,
+    ptr: u64,
+) -> Option<(alloc::string::String, alloc::string::String, alloc::string::String)> {
+    let [source, source_len, target, target_len, fs_type, type_len] = user_words(pid, ptr)?;
+    Some((
+        user_str(pid, source, source_len)?,
+        user_str(pid, target, target_len)?,
+        user_str(pid, fs_type, type_len)?,
+    ))
+}
+
 /// Handle data abort.
 fn handle_data_abort(ctx: &mut ExceptionContext, fault_status: DataFaultStatus) {
     let is_write = (ctx.esr >> 6) & 1 != 0;
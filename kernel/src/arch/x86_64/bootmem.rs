@@ -0,0 +1,134 @@
+//! Boot Memory Map
+//!
+//! Hands the RAM the bootloader reports to the frame allocator. The
+//! Multiboot header in boot.S asks for memory information, so the info
+//! block carries the BIOS (e820) memory map, or at least the size of upper
+//! memory; map entries of type 1 are usable RAM.
+//!
+//! Not all usable RAM is free. The first megabyte holds the boot page
+//! tables, the boot stack and BIOS data; the kernel image runs from 1 MiB
+//! to `__kernel_end`; the info block, the map and any modules are left
+//! alone in case they are read later. Frames are reached through the
+//! identity map boot.S sets up, which covers the first 4 GiB, so RAM above
+//! that is not handed out.
+
+use crate::mm::{FRAME_ALLOCATOR, PAGE_SIZE};
+
+/// Multiboot info flags
+const INFO_MEMORY: u32 = 1 << 0;
+const INFO_CMDLINE: u32 = 1 << 2;
+const INFO_MODULES: u32 = 1 << 3;
+const INFO_MEMORY_MAP: u32 = 1 << 6;
+
+/// Multiboot info block fields
+const INFO_FLAGS: u64 = 0;
+const INFO_MEM_UPPER: u64 = 8;
+const INFO_CMDLINE_ADDR: u64 = 16;
+const INFO_MODS_COUNT: u64 = 20;
+const INFO_MODS_ADDR: u64 = 24;
+const INFO_MMAP_LENGTH: u64 = 44;
+const INFO_MMAP_ADDR: u64 = 48;
+
+/// Size of the info block up to the fields read here
+const INFO_SIZE: usize = 52;
+
+/// Size of a module entry
+const MODULE_SIZE: u64 = 16;
+
+/// Memory map entry type of usable RAM
+const MMAP_AVAILABLE: u32 = 1;
+
+/// Upper memory starts at 1 MiB
+const UPPER_MEMORY: u64 = 0x10_0000;
+
+/// End of the identity map set up by boot.S
+const IDENTITY_MAP_END: u64 = 0x1_0000_0000;
+
+extern "C" {
+    static __kernel_end: u8;
+}
+
+fn read_u32(addr: u64) -> u32 {
+    // SAFETY: the info block and the map are in identity-mapped low memory
+    unsafe { core::ptr::read_unaligned(addr as *const u32) }
+}
+
+fn read_u64(addr: u64) -> u64 {
+    // SAFETY: as in `read_u32`
+    unsafe { core::ptr::read_unaligned(addr as *const u64) }
+}
+
+/// Adds the part of a RAM range between `low` and the end of the identity
+/// map. Returns the bytes added.
+fn add_ram(base: u64, len: u64, low: u64) -> u64 {
+    let start = base.max(low).next_multiple_of(PAGE_SIZE as u64);
+    let end = base.saturating_add(len).min(IDENTITY_MAP_END) & !(PAGE_SIZE as u64 - 1);
+    if end <= start {
+        return 0;
+    }
+    FRAME_ALLOCATOR.add_region(start, (end - start) as usize);
+    end - start
+}
+
+/// Fills the frame allocator from the Multiboot info block at `info`.
+/// Returns the bytes of RAM handed out.
+///
+/// # Safety
+///
+/// `info` must be the address of the info block the bootloader passed, or
+/// zero; it is called once, before anything allocates frames.
+pub unsafe fn init(info: u64) -> u64 {
+    if info == 0 {
+        crate::serial_println!("[bootmem] No boot information, frame allocator left empty");
+        return 0;
+    }
+    // A linker-defined symbol: only its address means anything
+    let low = core::ptr::addr_of!(__kernel_end) as u64;
+    let low = low.max(UPPER_MEMORY);
+
+    let flags = read_u32(info + INFO_FLAGS);
+    let mut added = 0;
+    if flags & INFO_MEMORY_MAP != 0 {
+        let map = read_u32(info + INFO_MMAP_ADDR) as u64;
+        let map_end = map + read_u32(info + INFO_MMAP_LENGTH) as u64;
+        let mut entry = map;
+        while entry < map_end {
+            // The size field does not count itself
+            let size = read_u32(entry) as u64;
+            if read_u32(entry + 20) == MMAP_AVAILABLE {
+                added += add_ram(read_u64(entry + 4), read_u64(entry + 12), low);
+            }
+            entry += size + 4;
+        }
+        FRAME_ALLOCATOR.reserve_region(map, (map_end - map) as usize);
+    } else if flags & INFO_MEMORY != 0 {
+        let upper = read_u32(info + INFO_MEM_UPPER) as u64 * 1024;
+        added += add_ram(UPPER_MEMORY, upper, low);
+    } else {
+        crate::serial_println!("[bootmem] Bootloader gave no memory information");
+        return 0;
+    }
+
+    // Keep what the bootloader left in memory
+    FRAME_ALLOCATOR.reserve_region(info, INFO_SIZE);
+    if flags & INFO_CMDLINE != 0 {
+        FRAME_ALLOCATOR.reserve_region(read_u32(info + INFO_CMDLINE_ADDR) as u64, PAGE_SIZE);
+    }
+    if flags & INFO_MODULES != 0 {
+        let count = read_u32(info + INFO_MODS_COUNT) as u64;
+        let modules = read_u32(info + INFO_MODS_ADDR) as u64;
+        FRAME_ALLOCATOR.reserve_region(modules, (count * MODULE_SIZE) as usize);
+        for module in (0..count).map(|i| modules + i * MODULE_SIZE) {
+            let (start, end) = (read_u32(module) as u64, read_u32(module + 4) as u64);
+            FRAME_ALLOCATOR.reserve_region(start, end.saturating_sub(start) as usize);
+        }
+    }
+
+    crate::serial_println!(
+        "[bootmem] {} MiB of RAM in {} frames, {} free",
+        added / (1024 * 1024),
+        FRAME_ALLOCATOR.total_count(),
+        FRAME_ALLOCATOR.free_count()
+    );
+    added
+}
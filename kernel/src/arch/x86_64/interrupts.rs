@@ -330,6 +330,12 @@ pub mod vector {
     pub const APIC_TIMER: u8 = 48;
     pub const APIC_ERROR: u8 = 49;
     pub const APIC_SPURIOUS: u8 = 255;
+
+    // Inter-processor interrupts (see lapic::ipi_vectors)
+    pub const IPI_FUNCTION_CALL: u8 = 0xFB;
+    pub const IPI_RESCHEDULE: u8 = 0xFC;
+    pub const IPI_TLB_SHOOTDOWN: u8 = 0xFD;
+    pub const IPI_STOP: u8 = 0xFE;
 }
 
 /// Interrupt stack frame pushed by CPU.
@@ -420,6 +426,23 @@ pub extern "x86-interrupt" fn page_fault_handler(frame: InterruptFrame, error_co
         asm!("mov {}, cr2", out(reg) cr2);
     }
 
+    // User faults may be demand-zero or copy-on-write pages
+    if error_code & 4 != 0 {
+        use crate::mm::vm::{self, FaultAccess};
+        let access = if error_code & 16 != 0 {
+            FaultAccess::Execute
+        } else if error_code & 2 != 0 {
+            FaultAccess::Write
+        } else {
+            FaultAccess::Read
+        };
+        if let Some(pid) = crate::sched::scheduler().current_process() {
+            if vm::handle_page_fault(pid, cr2, access).is_ok() {
+                return;
+            }
+        }
+    }
+
     let mut serial = SERIAL.lock();
     let _ = writeln!(serial, "\n!!! EXCEPTION: Page Fault !!!");
     let _ = writeln!(serial, "Faulting address (CR2): {:#018x}", cr2);
@@ -681,6 +704,35 @@ fn handle_serial_history_down() {
 /// Escape sequence state for serial terminal
 static SERIAL_ESCAPE_STATE: Mutex<EscapeState> = Mutex::new(EscapeState::None);
 
+/// Function-call IPI handler.
+///
+/// Runs the calls queued for this CPU, then acknowledges the IPI. A CPU asked
+/// to go offline parks only after that, with interrupts enabled, so the
+/// reschedule IPI that brings it back can be delivered.
+pub extern "x86-interrupt" fn function_call_ipi_handler(_frame: InterruptFrame) {
+    crate::smp::handle_ipi(crate::smp::IpiType::FunctionCall);
+    super::lapic::eoi();
+    crate::smp::hotplug::park_if_requested();
+}
+
+/// TLB shootdown IPI handler.
+pub extern "x86-interrupt" fn tlb_shootdown_ipi_handler(_frame: InterruptFrame) {
+    crate::smp::handle_ipi(crate::smp::IpiType::TlbShootdown);
+    super::lapic::eoi();
+}
+
+/// Reschedule IPI handler.
+pub extern "x86-interrupt" fn reschedule_ipi_handler(_frame: InterruptFrame) {
+    super::lapic::eoi();
+    crate::smp::handle_ipi(crate::smp::IpiType::Reschedule);
+}
+
+/// Stop IPI handler. Does not return.
+pub extern "x86-interrupt" fn stop_ipi_handler(_frame: InterruptFrame) {
+    super::lapic::eoi();
+    crate::smp::handle_ipi(crate::smp::IpiType::Stop);
+}
+
 /// State machine for parsing ANSI escape sequences
 #[derive(Clone, Copy, PartialEq)]
 enum EscapeState {
@@ -861,8 +913,13 @@ fn execute_shell_command(cmd: &str) {
             crate::vga_println!();
             crate::vga_println!("Filesystem (SplaxFS):");
             crate::vga_println!("  mkfs <dev>    - Format device with SplaxFS");
+            crate::vga_println!("  fsck [-y] <dev> - Check (and repair) SplaxFS");
+            crate::vga_println!("  snap <op> ... - Create/list/delete/mount/rollback snapshots");
+            crate::vga_println!("  scrub <op> <mount> - Start/show/cancel a SplaxFS checksum scrub");
+            crate::vga_println!("  xattr <op> ... - List/get/set/remove extended attributes");
             crate::vga_println!("  mount <dev> <path> - Mount filesystem");
             crate::vga_println!("  umount <path> - Unmount filesystem");
+            crate::vga_println!("  losetup [-r] <file> - Attach a file as a loop device");
             crate::vga_println!("  fsls <path>   - List directory on disk");
             crate::vga_println!("  fsmkdir <path> - Create directory on disk");
             crate::vga_println!("  fscat <file>  - Read file from disk");
@@ -1326,6 +1383,17 @@ fn execute_shell_command(cmd: &str) {
             crate::vga_println!("ramfs       {} KB  {} KB  {} KB  {}%", total_kb, used_kb, avail_kb, percent);
             crate::vga_println!();
             crate::vga_println!("Inodes: {} total, {} free", stats.inode_count, stats.free_inodes);
+            let vfs = &crate::fs::vfs::VFS;
+            for path in vfs.list_mounts() {
+                let Ok(st) = vfs.statfs(&path) else { continue };
+                let kb = |blocks: u64| blocks * st.bsize as u64 / 1024;
+                let used = st.blocks - st.bfree;
+                crate::vga_print!("{}: {} KB of {} KB used", path, kb(used), kb(st.blocks));
+                if st.bsaved > 0 {
+                    crate::vga_print!(", {} KB of data before compression", kb(used + st.bsaved));
+                }
+                crate::vga_println!();
+            }
         }
         "ps" => {
             use super::vga::Color;
@@ -1588,16 +1656,196 @@ fn execute_shell_command(cmd: &str) {
             }
             super::vga::set_color(Color::LightGray, Color::Black);
         }
+        "fsck" => {
+            use super::vga::Color;
+            let (repair, device) = if parts[1] == "-y" { (true, parts[2]) } else { (false, parts[1]) };
+            if device.is_empty() {
+                super::vga::set_color(Color::LightRed, Color::Black);
+                crate::vga_println!("Usage: fsck [-y] <device>");
+                crate::vga_println!("  -y  Repair; lost files go to /lost+found");
+            } else {
+                match crate::fs::splaxfs::fsck(device, repair) {
+                    Ok(report) => {
+                        if report.journal_replayed {
+                            crate::vga_println!("{}: replayed journal", device);
+                        }
+                        super::vga::set_color(Color::Yellow, Color::Black);
+                        for problem in &report.problems {
+                            crate::vga_println!("  {}", problem);
+                        }
+                        if report.is_clean() {
+                            super::vga::set_color(Color::LightGreen, Color::Black);
+                        } else if !report.repaired {
+                            super::vga::set_color(Color::LightRed, Color::Black);
+                            crate::vga_println!("{}: {} problems (run fsck -y to repair)", device, report.problems.len());
+                        } else {
+                            crate::vga_println!("{}: repaired {} problems", device, report.problems.len());
+                        }
+                        super::vga::set_color(Color::LightGray, Color::Black);
+                        crate::vga_println!("{}: {}/{} inodes, {}/{} blocks", device,
+                            report.used_inodes, report.total_inodes, report.used_blocks, report.total_blocks);
+                    }
+                    Err(crate::fs::splaxfs::SplaxFsError::Busy) => {
+                        super::vga::set_color(Color::LightRed, Color::Black);
+                        crate::vga_println!("{} is mounted; unmount it first", device);
+                    }
+                    Err(e) => {
+                        super::vga::set_color(Color::LightRed, Color::Black);
+                        crate::vga_println!("fsck failed: {:?}", e);
+                    }
+                }
+            }
+            super::vga::set_color(Color::LightGray, Color::Black);
+        }
+        "snap" => {
+            use super::vga::Color;
+            // Parse: snap <create|list|delete|mount|rollback> [-w] <mount> [name] [path]
+            let writable = parts.contains(&"-w");
+            let args: alloc::vec::Vec<&str> = parts.iter()
+                .skip(1)
+                .filter(|s| !s.is_empty() && **s != "-w")
+                .copied()
+                .collect();
+            let result = match (args.first().copied(), args.len()) {
+                (Some("create"), 3) => crate::fs::splaxfs::snapshot(args[1], args[2], writable).map(|id| {
+                    super::vga::set_color(Color::LightGreen, Color::Black);
+                    crate::vga_println!("Created snapshot {} of {} (id {})", args[2], args[1], id);
+                }),
+                (Some("list"), 2) => crate::fs::splaxfs::snapshots(args[1]).map(|list| {
+                    if list.is_empty() {
+                        crate::vga_println!("No snapshots of {}", args[1]);
+                    }
+                    for entry in list {
+                        crate::vga_println!("  {:4} {:24} {}{}", entry.id, entry.name_str(),
+                            if entry.is_writable() { "rw" } else { "ro" },
+                            if entry.is_deleting() { " (deleting)" } else { "" });
+                    }
+                }),
+                (Some("delete"), 3) => crate::fs::splaxfs::delete_snapshot(args[1], args[2]).map(|()| {
+                    super::vga::set_color(Color::LightGreen, Color::Black);
+                    crate::vga_println!("Deleted snapshot {} of {}", args[2], args[1]);
+                }),
+                (Some("mount"), 4) => crate::fs::splaxfs::mount_snapshot(args[1], args[2], args[3], writable).map(|()| {
+                    super::vga::set_color(Color::LightGreen, Color::Black);
+                    crate::vga_println!("Mounted snapshot {} at {}", args[2], args[3]);
+                }),
+                (Some("rollback"), 3) => crate::fs::splaxfs::rollback(args[1], args[2]).map(|()| {
+                    super::vga::set_color(Color::LightGreen, Color::Black);
+                    crate::vga_println!("Rolled {} back to {}", args[1], args[2]);
+                }),
+                _ => {
+                    super::vga::set_color(Color::LightRed, Color::Black);
+                    crate::vga_println!("Usage: snap create [-w] <mount> <name>");
+                    crate::vga_println!("       snap list <mount>");
+                    crate::vga_println!("       snap delete <mount> <name>");
+                    crate::vga_println!("       snap mount [-w] <mount> <name> <path>");
+                    crate::vga_println!("       snap rollback <mount> <name>");
+                    Ok(())
+                }
+            };
+            if let Err(e) = result {
+                super::vga::set_color(Color::LightRed, Color::Black);
+                crate::vga_println!("snap failed: {:?}", e);
+            }
+            super::vga::set_color(Color::LightGray, Color::Black);
+        }
+        "scrub" => {
+            use super::vga::Color;
+            // Parse: scrub <start|status|cancel> <mount>
+            let mount = parts[2];
+            let result = match parts[1] {
+                "start" if !mount.is_empty() => crate::fs::splaxfs::scrub_start(mount).map(|()| {
+                    super::vga::set_color(Color::LightGreen, Color::Black);
+                    crate::vga_println!("Scrubbing {} in the background", mount);
+                }),
+                "status" if !mount.is_empty() => crate::fs::splaxfs::scrub_status(mount).map(|status| {
+                    crate::vga_println!("{}: {}, {} blocks checked, {} corrupt", mount,
+                        if status.running { "scrubbing" } else { "done" },
+                        status.blocks_checked, status.errors.len());
+                    super::vga::set_color(Color::Yellow, Color::Black);
+                    for error in &status.errors {
+                        let owner = match (&error.path, error.ino) {
+                            (Some(path), _) => path.clone(),
+                            (None, 0) => alloc::string::String::from("metadata"),
+                            (None, ino) => alloc::format!("inode {}", ino),
+                        };
+                        match error.snapshot {
+                            0 => crate::vga_println!("  block {}: {}", error.block, owner),
+                            id => crate::vga_println!("  block {}: {} in snapshot {}", error.block, owner, id),
+                        }
+                    }
+                }),
+                "cancel" if !mount.is_empty() => crate::fs::splaxfs::scrub_cancel(mount).map(|()| {
+                    super::vga::set_color(Color::LightGreen, Color::Black);
+                    crate::vga_println!("Cancelled scrub of {}", mount);
+                }),
+                _ => {
+                    crate::vga_println!("Usage: scrub start <mount>");
+                    crate::vga_println!("       scrub status <mount>");
+                    crate::vga_println!("       scrub cancel <mount>");
+                    Ok(())
+                }
+            };
+            if let Err(e) = result {
+                super::vga::set_color(Color::LightRed, Color::Black);
+                crate::vga_println!("scrub failed: {:?}", e);
+            }
+            super::vga::set_color(Color::LightGray, Color::Black);
+        }
+        "xattr" => {
+            use super::vga::Color;
+            // Parse: xattr <list|get|set|rm> <path> [name] [value]
+            let vfs = &crate::fs::vfs::VFS;
+            let result = match (parts[1], parts[2], parts[3]) {
+                ("list", path, "") if !path.is_empty() => vfs.listxattr(path).map(|names| {
+                    if names.is_empty() {
+                        crate::vga_println!("No attributes on {}", path);
+                    }
+                    for name in names {
+                        crate::vga_println!("  {}", name);
+                    }
+                }),
+                ("get", path, name) if !path.is_empty() && !name.is_empty() => vfs.getxattr(path, name).map(|value| {
+                    match core::str::from_utf8(&value) {
+                        Ok(text) => crate::vga_println!("{}=\"{}\"", name, text),
+                        Err(_) => crate::vga_println!("{}: {} bytes of binary data", name, value.len()),
+                    }
+                }),
+                ("set", path, name) if !path.is_empty() && !name.is_empty() => {
+                    vfs.setxattr(path, name, parts[4].as_bytes()).map(|()| {
+                        super::vga::set_color(Color::LightGreen, Color::Black);
+                        crate::vga_println!("Set {} on {}", name, path);
+                    })
+                }
+                ("rm", path, name) if !path.is_empty() && !name.is_empty() => vfs.removexattr(path, name).map(|()| {
+                    super::vga::set_color(Color::LightGreen, Color::Black);
+                    crate::vga_println!("Removed {} from {}", name, path);
+                }),
+                _ => {
+                    crate::vga_println!("Usage: xattr list <path>");
+                    crate::vga_println!("       xattr get <path> <name>");
+                    crate::vga_println!("       xattr set <path> <name> [value]");
+                    crate::vga_println!("       xattr rm <path> <name>");
+                    Ok(())
+                }
+            };
+            if let Err(e) = result {
+                super::vga::set_color(Color::LightRed, Color::Black);
+                crate::vga_println!("xattr failed: {:?}", e);
+            }
+            super::vga::set_color(Color::LightGray, Color::Black);
+        }
         "mount" => {
             use super::vga::Color;
             // Parse: mount [-t type] <device> <path>
-            // or: mount <device> <path> (defaults to splaxfs)
+            // or: mount <device> <path> (detected, defaults to splaxfs)
             let (fs_type, device, path) = if parts[1] == "-t" && !parts[2].is_empty() {
                 // mount -t <type> <device> <path>
                 (parts[2], parts[3], parts[4])
             } else if !parts[1].is_empty() && !parts[2].is_empty() {
-                // mount <device> <path> (default to splaxfs)
-                ("splaxfs", parts[1], parts[2])
+                // mount <device> <path> (detect, default to splaxfs)
+                let detected = crate::block::partitions::detect_filesystem(parts[1]);
+                (detected.map_or("splaxfs", |fs| fs.name()), parts[1], parts[2])
             } else {
                 ("", "", "")
             };
@@ -1605,11 +1853,15 @@ fn execute_shell_command(cmd: &str) {
             if device.is_empty() || path.is_empty() {
                 super::vga::set_color(Color::LightRed, Color::Black);
                 crate::vga_println!("Usage: mount [-t <type>] <device> <path>");
-                crate::vga_println!("Types: splaxfs (default), fat32, ext4");
+                crate::vga_println!("Types: splaxfs (default), fat32, ext4, exfat, iso9660, 9p");
                 crate::vga_println!("Examples:");
-                crate::vga_println!("  mount vda /mnt              # SplaxFS");
+                crate::vga_println!("  mount vda /mnt              # Detected type");
                 crate::vga_println!("  mount -t fat32 sda1 /mnt/usb");
                 crate::vga_println!("  mount -t ext4 sda2 /mnt/linux");
+                crate::vga_println!("  mount -t exfat sdb1 /mnt/sd");
+                crate::vga_println!("  mount -t iso9660 sr0 /mnt/cdrom");
+                crate::vga_println!("  mount -t squashfs /pkg/app.sqfs /mnt/app  # Image file");
+                crate::vga_println!("  mount -t 9p host /mnt/host   # Shared host directory");
             } else {
                 // Use a bool for success/failure since error types differ
                 let (success, err_msg): (bool, Option<alloc::string::String>) = match fs_type {
@@ -1631,6 +1883,42 @@ fn execute_shell_command(cmd: &str) {
                             Err(e) => (false, Some(alloc::format!("{:?}", e))),
                         }
                     }
+                    "exfat" => {
+                        super::vga::set_color(Color::Yellow, Color::Black);
+                        crate::vga_println!("Mounting {} as exFAT at {}...", device, path);
+                        super::vga::set_color(Color::LightGray, Color::Black);
+                        match crate::fs::exfat::mount(device, path) {
+                            Ok(()) => (true, None),
+                            Err(e) => (false, Some(alloc::format!("{:?}", e))),
+                        }
+                    }
+                    "iso9660" => {
+                        super::vga::set_color(Color::Yellow, Color::Black);
+                        crate::vga_println!("Mounting {} as ISO 9660 at {}...", device, path);
+                        super::vga::set_color(Color::LightGray, Color::Black);
+                        match crate::fs::iso9660::mount(device, path) {
+                            Ok(()) => (true, None),
+                            Err(e) => (false, Some(alloc::format!("{:?}", e))),
+                        }
+                    }
+                    "squashfs" => {
+                        super::vga::set_color(Color::Yellow, Color::Black);
+                        crate::vga_println!("Mounting {} as SquashFS at {}...", device, path);
+                        super::vga::set_color(Color::LightGray, Color::Black);
+                        match crate::fs::squashfs::mount(device, path) {
+                            Ok(()) => (true, None),
+                            Err(e) => (false, Some(alloc::format!("{:?}", e))),
+                        }
+                    }
+                    "9p" => {
+                        super::vga::set_color(Color::Yellow, Color::Black);
+                        crate::vga_println!("Attaching to host share {} at {}...", device, path);
+                        super::vga::set_color(Color::LightGray, Color::Black);
+                        match crate::fs::virtio_9p::mount(device, path) {
+                            Ok(()) => (true, None),
+                            Err(e) => (false, Some(alloc::format!("{:?}", e))),
+                        }
+                    }
                     "splaxfs" | "" => {
                         match crate::fs::splaxfs::mount(device, path) {
                             Ok(()) => (true, None),
@@ -1640,7 +1928,7 @@ fn execute_shell_command(cmd: &str) {
                     _ => {
                         super::vga::set_color(Color::LightRed, Color::Black);
                         crate::vga_println!("Unknown filesystem type: {}", fs_type);
-                        crate::vga_println!("Supported: splaxfs, fat32, ext4");
+                        crate::vga_println!("Supported: splaxfs, fat32, ext4, exfat, iso9660, squashfs, 9p");
                         super::vga::set_color(Color::LightGray, Color::Black);
                         return;
                     }
@@ -1656,13 +1944,57 @@ fn execute_shell_command(cmd: &str) {
             }
             super::vga::set_color(Color::LightGray, Color::Black);
         }
+        "losetup" => {
+            use super::vga::Color;
+            // Parse: losetup [-r] <file> | losetup -d <loopN> | losetup
+            let result = match (parts[1], parts[2]) {
+                ("", _) => {
+                    let devices = crate::block::loopback::list();
+                    if devices.is_empty() {
+                        crate::vga_println!("No loop devices");
+                    }
+                    for (name, path) in devices {
+                        crate::vga_println!("  {}: {}", name, path);
+                    }
+                    Ok(())
+                }
+                ("-d", name) if !name.is_empty() => crate::block::loopback::detach(name).map(|()| {
+                    super::vga::set_color(Color::LightGreen, Color::Black);
+                    crate::vga_println!("Detached {}", name);
+                }),
+                ("-r", file) if !file.is_empty() => crate::block::loopback::attach(file, true).map(|name| {
+                    super::vga::set_color(Color::LightGreen, Color::Black);
+                    crate::vga_println!("Attached {} as {} (read-only)", file, name);
+                }),
+                (file, "") if !file.starts_with('-') => crate::block::loopback::attach(file, false).map(|name| {
+                    super::vga::set_color(Color::LightGreen, Color::Black);
+                    crate::vga_println!("Attached {} as {}", file, name);
+                }),
+                _ => {
+                    crate::vga_println!("Usage: losetup [-r] <file>");
+                    crate::vga_println!("       losetup -d <loopN>");
+                    crate::vga_println!("       losetup");
+                    Ok(())
+                }
+            };
+            if let Err(e) = result {
+                super::vga::set_color(Color::LightRed, Color::Black);
+                crate::vga_println!("losetup failed: {:?}", e);
+            }
+            super::vga::set_color(Color::LightGray, Color::Black);
+        }
         "umount" => {
             use super::vga::Color;
             if parts[1].is_empty() {
                 super::vga::set_color(Color::LightRed, Color::Black);
                 crate::vga_println!("Usage: umount <path>");
             } else {
-                match crate::fs::splaxfs::unmount(parts[1]) {
+                let result = crate::fs::splaxfs::unmount(parts[1])
+                    .or_else(|e| crate::fs::exfat::unmount(parts[1]).map_err(|_| e))
+                    .or_else(|e| crate::fs::iso9660::unmount(parts[1]).map_err(|_| e))
+                    .or_else(|e| crate::fs::squashfs::unmount(parts[1]).map_err(|_| e))
+                    .or_else(|e| crate::fs::virtio_9p::unmount(parts[1]).map_err(|_| e));
+                match result {
                     Ok(()) => {
                         super::vga::set_color(Color::LightGreen, Color::Black);
                         crate::vga_println!("Unmounted {}", parts[1]);
@@ -2798,6 +3130,12 @@ fn execute_shell_command(cmd: &str) {
                     crate::vga_println!("d {}", entry.name);
                     super::vga::set_color(Color::LightGray, Color::Black);
                 }
+            } else if !parts[2].is_empty() {
+                // Write a sys attribute, e.g. `sysinfo devices/system/cpu/cpu1/online 0`
+                if let Err(e) = crate::fs::sysfs::write_sys_file(path, parts[2]) {
+                    super::vga::set_color(Color::LightRed, Color::Black);
+                    crate::vga_println!("sysinfo: {}: {:?}", path, e);
+                }
             } else {
                 // Read specific sys file
                 if let Some(content) = crate::fs::sysfs::read_sys_file(path) {
@@ -2976,8 +3314,13 @@ fn execute_serial_command(cmd: &str) {
             serial_println!();
             serial_println!("Filesystem (SplaxFS):");
             serial_println!("  mkfs <dev>    - Format device with SplaxFS");
+            serial_println!("  fsck [-y] <dev> - Check (and repair) SplaxFS");
+            serial_println!("  snap <op> ... - Create/list/delete/mount/rollback snapshots");
+            serial_println!("  scrub <op> <mount> - Start/show/cancel a SplaxFS checksum scrub");
+            serial_println!("  xattr <op> ... - List/get/set/remove extended attributes");
             serial_println!("  mount <dev> <path> - Mount filesystem");
             serial_println!("  umount <path> - Unmount filesystem");
+            serial_println!("  losetup [-r] <file> - Attach a file as a loop device");
             serial_println!("  fsls <path>   - List directory on disk");
             serial_println!("  fsmkdir <path> - Create directory on disk");
             serial_println!("  fscat <file>  - Read file from disk");
@@ -3331,6 +3674,18 @@ fn execute_serial_command(cmd: &str) {
             serial_println!("Filesystem      Size    Used   Avail  Use%  Mounted on");
             serial_println!("ramfs         {:>5}KB {:>5}KB {:>5}KB  {:>3}%  /", 
                 total_kb, used_kb, avail_kb, percent);
+            let vfs = &crate::fs::vfs::VFS;
+            for path in vfs.list_mounts() {
+                let Ok(st) = vfs.statfs(&path) else { continue };
+                let kb = |blocks: u64| blocks * st.bsize as u64 / 1024;
+                let used = st.blocks - st.bfree;
+                let percent = if st.blocks > 0 { used * 100 / st.blocks } else { 0 };
+                serial_println!("vfs           {:>5}KB {:>5}KB {:>5}KB  {:>3}%  {}",
+                    kb(st.blocks), kb(used), kb(st.bavail), percent, path);
+                if st.bsaved > 0 {
+                    serial_println!("              {:>5}KB of data before compression", kb(used + st.bsaved));
+                }
+            }
         }
         "ps" => {
             serial_println!("  PID  STATE      NAME");
@@ -3448,6 +3803,158 @@ fn execute_serial_command(cmd: &str) {
                 }
             }
         }
+        "fsck" => {
+            let (repair, device) = if parts[1] == "-y" { (true, parts[2]) } else { (false, parts[1]) };
+            if device.is_empty() {
+                serial_println!("Usage: fsck [-y] <device>");
+                serial_println!("  -y  Repair; lost files go to /lost+found");
+            } else {
+                match crate::fs::splaxfs::fsck(device, repair) {
+                    Ok(report) => {
+                        if report.journal_replayed {
+                            serial_println!("{}: replayed journal", device);
+                        }
+                        for problem in &report.problems {
+                            serial_println!("  {}", problem);
+                        }
+                        if report.is_clean() {
+                            serial_println!("[OK] {} is clean", device);
+                        } else if report.repaired {
+                            serial_println!("[OK] {}: repaired {} problems", device, report.problems.len());
+                        } else {
+                            serial_println!("[ERROR] {}: {} problems (run fsck -y to repair)", device, report.problems.len());
+                        }
+                        serial_println!("{}: {}/{} inodes, {}/{} blocks", device,
+                            report.used_inodes, report.total_inodes, report.used_blocks, report.total_blocks);
+                    }
+                    Err(crate::fs::splaxfs::SplaxFsError::Busy) => {
+                        serial_println!("[ERROR] {} is mounted; unmount it first", device);
+                    }
+                    Err(e) => {
+                        serial_println!("[ERROR] fsck failed: {:?}", e);
+                    }
+                }
+            }
+        }
+        "snap" => {
+            // Parse: snap <create|list|delete|mount|rollback> [-w] <mount> [name] [path]
+            let writable = parts.contains(&"-w");
+            let args: alloc::vec::Vec<&str> = parts.iter()
+                .skip(1)
+                .filter(|s| !s.is_empty() && **s != "-w")
+                .copied()
+                .collect();
+            let result = match (args.first().copied(), args.len()) {
+                (Some("create"), 3) => crate::fs::splaxfs::snapshot(args[1], args[2], writable).map(|id| {
+                    serial_println!("[OK] Created snapshot {} of {} (id {})", args[2], args[1], id);
+                }),
+                (Some("list"), 2) => crate::fs::splaxfs::snapshots(args[1]).map(|list| {
+                    if list.is_empty() {
+                        serial_println!("No snapshots of {}", args[1]);
+                    }
+                    for entry in list {
+                        serial_println!("  {:4} {:24} {}{}", entry.id, entry.name_str(),
+                            if entry.is_writable() { "rw" } else { "ro" },
+                            if entry.is_deleting() { " (deleting)" } else { "" });
+                    }
+                }),
+                (Some("delete"), 3) => crate::fs::splaxfs::delete_snapshot(args[1], args[2]).map(|()| {
+                    serial_println!("[OK] Deleted snapshot {} of {}", args[2], args[1]);
+                }),
+                (Some("mount"), 4) => crate::fs::splaxfs::mount_snapshot(args[1], args[2], args[3], writable).map(|()| {
+                    serial_println!("[OK] Mounted snapshot {} at {}", args[2], args[3]);
+                }),
+                (Some("rollback"), 3) => crate::fs::splaxfs::rollback(args[1], args[2]).map(|()| {
+                    serial_println!("[OK] Rolled {} back to {}", args[1], args[2]);
+                }),
+                _ => {
+                    serial_println!("Usage: snap create [-w] <mount> <name>");
+                    serial_println!("       snap list <mount>");
+                    serial_println!("       snap delete <mount> <name>");
+                    serial_println!("       snap mount [-w] <mount> <name> <path>");
+                    serial_println!("       snap rollback <mount> <name>");
+                    Ok(())
+                }
+            };
+            if let Err(e) = result {
+                serial_println!("[ERROR] snap failed: {:?}", e);
+            }
+        }
+        "scrub" => {
+            // Parse: scrub <start|status|cancel> <mount>
+            let mount = parts[2];
+            let result = match parts[1] {
+                "start" if !mount.is_empty() => crate::fs::splaxfs::scrub_start(mount).map(|()| {
+                    serial_println!("[OK] Scrubbing {} in the background", mount);
+                }),
+                "status" if !mount.is_empty() => crate::fs::splaxfs::scrub_status(mount).map(|status| {
+                    serial_println!("{}: {}, {} blocks checked, {} corrupt", mount,
+                        if status.running { "scrubbing" } else { "done" },
+                        status.blocks_checked, status.errors.len());
+                    for error in &status.errors {
+                        let owner = match (&error.path, error.ino) {
+                            (Some(path), _) => path.clone(),
+                            (None, 0) => alloc::string::String::from("metadata"),
+                            (None, ino) => alloc::format!("inode {}", ino),
+                        };
+                        match error.snapshot {
+                            0 => serial_println!("  block {}: {}", error.block, owner),
+                            id => serial_println!("  block {}: {} in snapshot {}", error.block, owner, id),
+                        }
+                    }
+                }),
+                "cancel" if !mount.is_empty() => crate::fs::splaxfs::scrub_cancel(mount).map(|()| {
+                    serial_println!("[OK] Cancelled scrub of {}", mount);
+                }),
+                _ => {
+                    serial_println!("Usage: scrub start <mount>");
+                    serial_println!("       scrub status <mount>");
+                    serial_println!("       scrub cancel <mount>");
+                    Ok(())
+                }
+            };
+            if let Err(e) = result {
+                serial_println!("[ERROR] scrub failed: {:?}", e);
+            }
+        }
+        "xattr" => {
+            // Parse: xattr <list|get|set|rm> <path> [name] [value]
+            let vfs = &crate::fs::vfs::VFS;
+            let result = match (parts[1], parts[2], parts[3]) {
+                ("list", path, "") if !path.is_empty() => vfs.listxattr(path).map(|names| {
+                    if names.is_empty() {
+                        serial_println!("No attributes on {}", path);
+                    }
+                    for name in names {
+                        serial_println!("  {}", name);
+                    }
+                }),
+                ("get", path, name) if !path.is_empty() && !name.is_empty() => vfs.getxattr(path, name).map(|value| {
+                    match core::str::from_utf8(&value) {
+                        Ok(text) => serial_println!("{}=\"{}\"", name, text),
+                        Err(_) => serial_println!("{}: {} bytes of binary data", name, value.len()),
+                    }
+                }),
+                ("set", path, name) if !path.is_empty() && !name.is_empty() => {
+                    vfs.setxattr(path, name, parts[4].as_bytes()).map(|()| {
+                        serial_println!("[OK] Set {} on {}", name, path);
+                    })
+                }
+                ("rm", path, name) if !path.is_empty() && !name.is_empty() => vfs.removexattr(path, name).map(|()| {
+                    serial_println!("[OK] Removed {} from {}", name, path);
+                }),
+                _ => {
+                    serial_println!("Usage: xattr list <path>");
+                    serial_println!("       xattr get <path> <name>");
+                    serial_println!("       xattr set <path> <name> [value]");
+                    serial_println!("       xattr rm <path> <name>");
+                    Ok(())
+                }
+            };
+            if let Err(e) = result {
+                serial_println!("[ERROR] xattr failed: {:?}", e);
+            }
+        }
         "mount" => {
             // Parse: mount [-t <type>] <device> <path>
             // Collect non-empty parts
@@ -3460,11 +3967,13 @@ fn execute_serial_command(cmd: &str) {
             let (fs_type, device, path): (Option<&str>, &str, &str) = if args.len() >= 4 && args[0] == "-t" {
                 (Some(args[1]), args[2], args[3])
             } else if args.len() >= 2 {
-                (None, args[0], args[1])
+                let detected = crate::block::partitions::detect_filesystem(args[0]);
+                (detected.map(|fs| fs.name()), args[0], args[1])
             } else {
                 serial_println!("Usage: mount [-t <type>] <device> <path>");
-                serial_println!("Types: splaxfs, fat32, ext4");
+                serial_println!("Types: splaxfs, fat32, ext4, exfat, iso9660, squashfs, 9p");
                 serial_println!("Example: mount -t fat32 sda1 /mnt/usb");
+                serial_println!("         mount -t 9p host /mnt/host");
                 (None, "", "")
             };
             
@@ -3483,6 +3992,30 @@ fn execute_serial_command(cmd: &str) {
                             Err(e) => (false, Some(alloc::format!("{:?}", e))),
                         }
                     }
+                    Some("exfat") => {
+                        match crate::fs::exfat::mount(device, path) {
+                            Ok(()) => (true, None),
+                            Err(e) => (false, Some(alloc::format!("{:?}", e))),
+                        }
+                    }
+                    Some("iso9660") => {
+                        match crate::fs::iso9660::mount(device, path) {
+                            Ok(()) => (true, None),
+                            Err(e) => (false, Some(alloc::format!("{:?}", e))),
+                        }
+                    }
+                    Some("squashfs") => {
+                        match crate::fs::squashfs::mount(device, path) {
+                            Ok(()) => (true, None),
+                            Err(e) => (false, Some(alloc::format!("{:?}", e))),
+                        }
+                    }
+                    Some("9p") => {
+                        match crate::fs::virtio_9p::mount(device, path) {
+                            Ok(()) => (true, None),
+                            Err(e) => (false, Some(alloc::format!("{:?}", e))),
+                        }
+                    }
                     Some("splaxfs") | None => {
                         match crate::fs::splaxfs::mount(device, path) {
                             Ok(()) => (true, None),
@@ -3491,7 +4024,7 @@ fn execute_serial_command(cmd: &str) {
                     }
                     Some(t) => {
                         serial_println!("[ERROR] Unknown filesystem type: {}", t);
-                        serial_println!("Supported: splaxfs, fat32, ext4");
+                        serial_println!("Supported: splaxfs, fat32, ext4, exfat, iso9660, squashfs, 9p");
                         (false, Some(alloc::string::String::from("unsupported")))
                     }
                 };
@@ -3506,11 +4039,49 @@ fn execute_serial_command(cmd: &str) {
                 }
             }
         }
+        "losetup" => {
+            // Parse: losetup [-r] <file> | losetup -d <loopN> | losetup
+            let result = match (parts[1], parts[2]) {
+                ("", _) => {
+                    let devices = crate::block::loopback::list();
+                    if devices.is_empty() {
+                        serial_println!("No loop devices");
+                    }
+                    for (name, path) in devices {
+                        serial_println!("  {}: {}", name, path);
+                    }
+                    Ok(())
+                }
+                ("-d", name) if !name.is_empty() => crate::block::loopback::detach(name).map(|()| {
+                    serial_println!("[OK] Detached {}", name);
+                }),
+                ("-r", file) if !file.is_empty() => crate::block::loopback::attach(file, true).map(|name| {
+                    serial_println!("[OK] Attached {} as {} (read-only)", file, name);
+                }),
+                (file, "") if !file.starts_with('-') => crate::block::loopback::attach(file, false).map(|name| {
+                    serial_println!("[OK] Attached {} as {}", file, name);
+                }),
+                _ => {
+                    serial_println!("Usage: losetup [-r] <file>");
+                    serial_println!("       losetup -d <loopN>");
+                    serial_println!("       losetup");
+                    Ok(())
+                }
+            };
+            if let Err(e) = result {
+                serial_println!("[ERROR] losetup failed: {:?}", e);
+            }
+        }
         "umount" => {
             if parts[1].is_empty() {
                 serial_println!("Usage: umount <path>");
             } else {
-                match crate::fs::splaxfs::unmount(parts[1]) {
+                let result = crate::fs::splaxfs::unmount(parts[1])
+                    .or_else(|e| crate::fs::exfat::unmount(parts[1]).map_err(|_| e))
+                    .or_else(|e| crate::fs::iso9660::unmount(parts[1]).map_err(|_| e))
+                    .or_else(|e| crate::fs::squashfs::unmount(parts[1]).map_err(|_| e))
+                    .or_else(|e| crate::fs::virtio_9p::unmount(parts[1]).map_err(|_| e));
+                match result {
                     Ok(()) => {
                         serial_println!("[OK] Unmounted {}", parts[1]);
                     }
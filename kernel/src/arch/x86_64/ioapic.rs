@@ -0,0 +1,107 @@
+//! # I/O APIC Driver
+//!
+//! Each I/O APIC routes a range of global system interrupts (GSIs) to Local
+//! APICs through its redirection table. The MADT lists every I/O APIC with
+//! its MMIO address and first GSI.
+//!
+//! Registers are reached indirectly: the register index is written to
+//! IOREGSEL and the value is read or written through IOWIN. A redirection
+//! entry is 64 bits wide and spans two registers; the destination APIC ID
+//! lives in the top byte of the high half.
+
+use core::ptr::{read_volatile, write_volatile};
+
+use spin::Mutex;
+
+/// Register offsets from the I/O APIC base.
+mod regs {
+    pub const IOREGSEL: u64 = 0x00;
+    pub const IOWIN: u64 = 0x10;
+}
+
+/// Register indices.
+mod index {
+    pub const VERSION: u32 = 0x01;
+    pub const REDIRECTION_BASE: u32 = 0x10;
+}
+
+/// Shift of the destination field in the high half of a redirection entry.
+const DESTINATION_SHIFT: u32 = 24;
+
+/// Serialises IOREGSEL/IOWIN pairs.
+static IOAPIC_LOCK: Mutex<()> = Mutex::new(());
+
+/// One I/O APIC.
+struct IoApic {
+    base: u64,
+    gsi_base: u32,
+}
+
+impl IoApic {
+    fn read(&self, reg: u32) -> u32 {
+        // SAFETY: the MADT reported an I/O APIC at this address; it lies in
+        // the identity-mapped low 4 GiB
+        unsafe {
+            write_volatile((self.base + regs::IOREGSEL) as *mut u32, reg);
+            read_volatile((self.base + regs::IOWIN) as *const u32)
+        }
+    }
+
+    fn write(&self, reg: u32, value: u32) {
+        // SAFETY: as in `read`
+        unsafe {
+            write_volatile((self.base + regs::IOREGSEL) as *mut u32, reg);
+            write_volatile((self.base + regs::IOWIN) as *mut u32, value);
+        }
+    }
+
+    /// Number of redirection entries.
+    fn entries(&self) -> u32 {
+        ((self.read(index::VERSION) >> 16) & 0xFF) + 1
+    }
+
+    /// Register index of the high half of the entry for `pin`.
+    fn entry_high(pin: u32) -> u32 {
+        index::REDIRECTION_BASE + pin * 2 + 1
+    }
+}
+
+/// Finds the I/O APIC that handles `gsi` and the pin it arrives on.
+///
+/// Reads registers, so callers hold `IOAPIC_LOCK`.
+fn lookup(gsi: u32) -> Option<(IoApic, u32)> {
+    crate::acpi::acpi()
+        .io_apics()
+        .into_iter()
+        .map(|info| IoApic {
+            base: info.address as u64,
+            gsi_base: info.gsi_base,
+        })
+        .find(|apic| gsi >= apic.gsi_base && gsi - apic.gsi_base < apic.entries())
+        .map(|apic| {
+            let pin = gsi - apic.gsi_base;
+            (apic, pin)
+        })
+}
+
+/// Routes `gsi` to the Local APIC `apic_id`.
+///
+/// Only the destination changes; vector, trigger mode and mask stay as they
+/// are. Returns false if no I/O APIC handles the GSI.
+pub fn set_destination(gsi: u32, apic_id: u8) -> bool {
+    let _guard = IOAPIC_LOCK.lock();
+    let Some((apic, pin)) = lookup(gsi) else {
+        return false;
+    };
+    let reg = IoApic::entry_high(pin);
+    let high = apic.read(reg) & !(0xFF << DESTINATION_SHIFT);
+    apic.write(reg, high | ((apic_id as u32) << DESTINATION_SHIFT));
+    true
+}
+
+/// Returns the Local APIC `gsi` is routed to.
+pub fn destination(gsi: u32) -> Option<u8> {
+    let _guard = IOAPIC_LOCK.lock();
+    let (apic, pin) = lookup(gsi)?;
+    Some((apic.read(IoApic::entry_high(pin)) >> DESTINATION_SHIFT) as u8)
+}
@@ -363,6 +363,7 @@ pub fn eoi() {
 
 /// IPI vector numbers.
 pub mod ipi_vectors {
+    pub const FUNCTION_CALL: u8 = 0xFB;
     pub const RESCHEDULE: u8 = 0xFC;
     pub const TLB_SHOOTDOWN: u8 = 0xFD;
     pub const STOP: u8 = 0xFE;
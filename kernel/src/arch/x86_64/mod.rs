@@ -13,16 +13,19 @@
 use core::arch::asm;
 use core::fmt::Write;
 
+pub mod bootmem;
 pub mod context;
 pub mod gdt;
 pub mod idt;
 pub mod interrupts;
+pub mod ioapic;
 pub mod keyboard;
 pub mod lapic;
 pub mod paging;
 pub mod power;
 pub mod rtc;
 pub mod serial;
+pub mod syscall;
 pub mod vga;
 
 /// Static GDT instance.
@@ -166,6 +169,20 @@ unsafe fn setup_idt() {
         IDT.set_handler(vector::PIC_TIMER, timer_handler as *const () as u64, 0, 0x8E);
         IDT.set_handler(vector::PIC_KEYBOARD, keyboard_handler as *const () as u64, 0, 0x8E);
         IDT.set_handler(vector::PIC_COM1, serial_handler as *const () as u64, 0, 0x8E);
+
+        // Inter-processor interrupts
+        IDT.set_handler(vector::IPI_FUNCTION_CALL, function_call_ipi_handler as *const () as u64, 0, 0x8E);
+        IDT.set_handler(vector::IPI_TLB_SHOOTDOWN, tlb_shootdown_ipi_handler as *const () as u64, 0, 0x8E);
+        IDT.set_handler(vector::IPI_RESCHEDULE, reschedule_ipi_handler as *const () as u64, 0, 0x8E);
+        IDT.set_handler(vector::IPI_STOP, stop_ipi_handler as *const () as u64, 0, 0x8E);
+
+        // System calls (callable from ring 3)
+        IDT.set_handler(
+            idt::vector::SYSCALL,
+            syscall::syscall_entry as *const () as u64,
+            0,
+            idt::gate_type::USER_INTERRUPT,
+        );
     }
 }
 
@@ -272,6 +289,19 @@ impl PageTableEntry {
     }
 }
 
+/// Sets the stack the CPU switches to when an interrupt or `int 0x80`
+/// arrives from user mode (TSS RSP0).
+///
+/// Called with the incoming process's kernel stack top on every switch.
+pub fn set_kernel_stack(top: u64) {
+    // SAFETY: the TSS is packed, so RSP0 is written unaligned; the CPU only
+    // reads it on a privilege change, which cannot race with this write on
+    // the same CPU
+    unsafe {
+        (&raw mut TSS.rsp).cast::<u64>().write_unaligned(top);
+    }
+}
+
 /// Invalidate TLB entry for a specific address.
 #[inline(always)]
 pub fn invalidate_page(addr: u64) {
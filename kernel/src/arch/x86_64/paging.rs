@@ -332,22 +332,36 @@ pub unsafe fn write_cr3(value: u64) {
 }
 
 /// Boot page table addresses (set up by boot.S)
-const BOOT_PML4: u64 = 0x1000;
+///
+/// boot.S identity-maps 0-4 GiB with 2 MiB pages: PML4[0] points at the
+/// PDP, whose first four entries point at the PDs at 0x3000-0x6000.
 const BOOT_PDP: u64 = 0x2000;
-const BOOT_PD: u64 = 0x3000;
+const BOOT_PD3: u64 = 0x6000;
+
+/// End of the boot identity map.
+const BOOT_IDENTITY_END: u64 = 0x1_0000_0000;
 
-/// Page table for MMIO mappings (one PD can map 1GB using 2MB pages)
-/// We'll use 0x4000-0x4FFF for additional PDP entries
-/// And 0x5000-0x5FFF for additional PD entries
-const MMIO_PDP: u64 = 0x4000;
-const MMIO_PD: u64 = 0x5000;
+/// End of the range the boot PDP (PML4[0]) covers.
+const BOOT_PDP_END: u64 = 0x80_0000_0000;
+
+/// Flags for device register mappings.
+const MMIO_FLAGS: PageFlags = PageFlags::WRITABLE
+    .union(PageFlags::WRITE_THROUGH)
+    .union(PageFlags::NO_CACHE);
 
 /// Track if MMIO page tables are initialized
 static MMIO_INITIALIZED: core::sync::atomic::AtomicBool = 
     core::sync::atomic::AtomicBool::new(false);
 
+/// Serialises changes to the boot page tables.
+static IDENTITY_LOCK: spin::Mutex<()> = spin::Mutex::new(());
+
 /// Initialize MMIO mapping support.
 /// Must be called before map_mmio.
+///
+/// Makes the top 32 MiB below 4 GiB (local APIC, I/O APIC and most 32-bit
+/// BARs) uncached. boot.S already maps it through PD3; only the caching bits
+/// of those entries change, so no RAM mapping is touched.
 pub fn init_mmio_mapping() {
     use core::sync::atomic::Ordering;
     
@@ -355,43 +369,18 @@ pub fn init_mmio_mapping() {
         return; // Already initialized
     }
     
-    unsafe {
-        // Clear MMIO page tables
-        core::ptr::write_bytes(MMIO_PDP as *mut u8, 0, PAGE_SIZE);
-        core::ptr::write_bytes(MMIO_PD as *mut u8, 0, PAGE_SIZE);
-        
-        // Set up PML4[3] to point to MMIO_PDP (covers 0xC000_0000 - 0xFFFF_FFFF range)
-        // Actually, for addresses like 0xFEBC0000, we need PML4[0], PDP[3]
-        // 0xFEBC0000 >> 30 = 3 (PDP index), and PML4[0] is already used
-        
-        // The address 0xFEBC0000:
-        // Bits 47-39 (PML4): 0
-        // Bits 38-30 (PDP): 3 
-        // Bits 29-21 (PD): 501 (0xFEBC0000 >> 21 & 0x1FF = 501)
-        // 
-        // We need to add PDP[3] -> new PD for the 0xC0000000-0xFFFFFFFF range
-        
-        // Add PDP entry 3 pointing to MMIO_PD
-        let pdp_ptr = BOOT_PDP as *mut u64;
-        core::ptr::write_volatile(pdp_ptr.add(3), MMIO_PD | 0x03); // Present + Writable
-        
-        // Now map the 0xFE000000 - 0xFFFFFFFF range using 2MB pages
-        // PD entry for 0xFEBC0000: (0xFEBC0000 >> 21) & 0x1FF = (0xFEBC0000 - 0xC0000000) >> 21
-        // = 0x3EBC0000 >> 21 = 501
-        // 
-        // Let's map PD entries 496-511 (covers 0xFE000000 - 0xFFFFFFFF)
-        let pd_ptr = MMIO_PD as *mut u64;
-        for i in 496..512 {
-            // Physical address: 0xC0000000 + i * 2MB
-            let phys = 0xC000_0000u64 + (i as u64) * 0x20_0000;
-            // Huge page entry: addr | PRESENT | WRITABLE | HUGE | NO_CACHE | WRITE_THROUGH
-            let entry = phys | 0x9B; // Present(1) + Writable(2) + WriteThrough(8) + NoCache(0x10) + Huge(0x80)
-            core::ptr::write_volatile(pd_ptr.add(i), entry);
+    let _guard = IDENTITY_LOCK.lock();
+    let pd_ptr = BOOT_PD3 as *mut u64;
+    // PD entries 496-511 cover 0xFE00_0000 - 0xFFFF_FFFF
+    for i in 496..512 {
+        // SAFETY: PD3 is identity-mapped and every entry in it is present
+        unsafe {
+            let entry = core::ptr::read_volatile(pd_ptr.add(i));
+            core::ptr::write_volatile(pd_ptr.add(i), entry | MMIO_FLAGS.bits());
         }
-        
-        // Flush TLB
-        flush_tlb();
     }
+    
+    flush_tlb();
 }
 
 /// Map an MMIO region for device access.
@@ -402,34 +391,68 @@ pub fn init_mmio_mapping() {
 /// * `size` - Size of the region in bytes
 ///
 /// # Returns
-/// The virtual address to use (same as physical for identity mapping)
-///
-/// # Safety
-/// Caller must ensure this is a valid MMIO region.
-pub fn map_mmio(phys_addr: u64, size: usize) -> u64 {
+/// The virtual address to use (same as physical for identity mapping), or
+/// `None` if the region cannot be mapped (see [`map_identity`]).
+pub fn map_mmio(phys_addr: u64, size: usize) -> Option<u64> {
     // Ensure MMIO mapping is initialized
     if !MMIO_INITIALIZED.load(core::sync::atomic::Ordering::SeqCst) {
         init_mmio_mapping();
     }
     
-    // For addresses in the 0xC0000000-0xFFFFFFFF range, they should now be mapped
-    // by the init function above.
-    
-    // For other high addresses, we may need additional mappings
-    let end_addr = phys_addr + size as u64;
-    
-    if phys_addr >= 0xC000_0000 && end_addr <= 0x1_0000_0000 {
-        // Already covered by init_mmio_mapping
-        return phys_addr;
+    map_identity(phys_addr, size, MMIO_FLAGS)
+}
+
+/// Identity-maps `[phys_addr, phys_addr + size)` in the kernel page tables
+/// and returns the address to use.
+///
+/// Ranges below 4 GiB are already mapped by boot.S and returned as is. Above
+/// that, 2 MiB pages with `flags` fill the empty PD entries, and missing PDs
+/// are taken from the frame allocator; entries already present are left
+/// alone. Ranges beyond the boot PDP (512 GiB), or needing a PD when no
+/// frame is free, are refused.
+pub fn map_identity(phys_addr: u64, size: usize, flags: PageFlags) -> Option<u64> {
+    let end = phys_addr.checked_add(size as u64)?;
+    if end <= BOOT_IDENTITY_END {
+        return Some(phys_addr);
+    }
+    if end > BOOT_PDP_END {
+        return None;
     }
     
-    // For addresses below 0xC0000000, check if they're already identity mapped
-    if phys_addr < 0x40_0000 && end_addr <= 0x40_0000 {
-        // First 4MB is already mapped by boot.S
-        return phys_addr;
+    let _guard = IDENTITY_LOCK.lock();
+    let pdp = BOOT_PDP as *mut PageTable;
+    let mut addr = phys_addr & !(LARGE_PAGE_SIZE as u64 - 1);
+    while addr < end {
+        let page = VirtPage::from_address(addr)?;
+        // SAFETY: the boot PDP and the PDs it points to lie in identity-mapped
+        // memory, and IDENTITY_LOCK serialises writers
+        let pdpte = unsafe { (*pdp).entry_mut(page.pdpt_index()) };
+        if pdpte.is_present() && pdpte.is_huge() {
+            // 1GB page
+            addr = (addr | (HUGE_PAGE_SIZE as u64 - 1)) + 1;
+            continue;
+        }
+        if !pdpte.is_present() {
+            let frame = crate::mm::FRAME_ALLOCATOR.allocate().ok()?;
+            // SAFETY: a free frame below 4 GiB, so identity-mapped
+            unsafe { core::ptr::write_bytes(frame.address() as *mut u8, 0, PAGE_SIZE) };
+            *pdpte = PageTableEntry::new(
+                PhysFrame::from_address(frame.address())?,
+                PageFlags::PRESENT | PageFlags::WRITABLE,
+            );
+        }
+        
+        let pd = pdpte.frame()?.address() as *mut PageTable;
+        // SAFETY: as above
+        let pde = unsafe { (*pd).entry_mut(page.pd_index()) };
+        if !pde.is_present() {
+            *pde = PageTableEntry::new(
+                PhysFrame::from_address(addr)?,
+                flags | PageFlags::PRESENT | PageFlags::HUGE_PAGE,
+            );
+        }
+        addr += LARGE_PAGE_SIZE as u64;
     }
     
-    // For other ranges, we'd need to dynamically add page table entries
-    // For now, log a warning and hope for the best
-    phys_addr
+    Some(phys_addr)
 }
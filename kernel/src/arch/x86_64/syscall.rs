@@ -0,0 +1,295 @@
+//! # System Calls
+//!
+//! User code enters the kernel with `int 0x80` (a DPL 3 interrupt gate).
+//! The entry stub saves every general purpose register below the frame the
+//! CPU pushed, forming a [`SyscallFrame`], and hands it to the dispatcher.
+//! On the way out the registers are restored from the frame and `iretq`
+//! returns to user mode, so the dispatcher returns values by writing `rax`.
+//!
+//! Numbers and argument registers follow the Linux x86_64 convention:
+//! number in `rax`, arguments in `rdi`, `rsi`, `rdx`, `r10`, `r8`, `r9`.
+//!
+//! A forked child starts in [`syscall_return`] on a copy of its parent's
+//! frame, so it leaves `fork` in user mode like the parent, with 0 in `rax`.
+
+use core::arch::naked_asm;
+
+#[cfg(not(feature = "microkernel"))]
+use alloc::{string::String, vec};
+
+use crate::sched::ProcessId;
+
+/// Registers saved on entry, lowest address first.
+#[repr(C)]
+#[derive(Debug, Clone, Default)]
+pub struct SyscallFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    // Pushed by the CPU
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+/// Linux x86_64 system call numbers handled here.
+mod nr {
+    pub const WRITE: u64 = 1;
+    pub const GETPID: u64 = 39;
+    pub const FORK: u64 = 57;
+    pub const EXIT: u64 = 60;
+    pub const PERSONALITY: u64 = 135;
+    #[cfg(not(feature = "microkernel"))]
+    pub const PIVOT_ROOT: u64 = 155;
+    #[cfg(not(feature = "microkernel"))]
+    pub const MOUNT: u64 = 165;
+    #[cfg(not(feature = "microkernel"))]
+    pub const UNSHARE: u64 = 272;
+    pub const GETRANDOM: u64 = 318;
+}
+
+/// `unshare` flag for a new mount namespace, the only one supported.
+#[cfg(not(feature = "microkernel"))]
+const CLONE_NEWNS: u64 = 0x2_0000;
+
+const ENOSYS: i64 = 38;
+const EAGAIN: i64 = 11;
+const EBADF: i64 = 9;
+const ENOMEM: i64 = 12;
+const EFAULT: i64 = 14;
+#[cfg(not(feature = "microkernel"))]
+const EINVAL: i64 = 22;
+
+/// Most bytes one console `write` call takes.
+const WRITE_MAX: usize = 4096;
+
+/// Most bytes one `getrandom` call returns.
+const GETRANDOM_MAX: usize = 256;
+
+/// `int 0x80` entry point.
+///
+/// # Safety
+///
+/// Only reached through the IDT.
+#[unsafe(naked)]
+pub unsafe extern "C" fn syscall_entry() {
+    naked_asm!(
+        "push rax",
+        "push rbx",
+        "push rcx",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+        "push rbp",
+        "push r8",
+        "push r9",
+        "push r10",
+        "push r11",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        // The CPU aligned the stack before pushing its 5-word frame, so
+        // after 15 more words it is 16-byte aligned again
+        "mov rdi, rsp",
+        "call {dispatch}",
+        "jmp {ret}",
+        dispatch = sym syscall_dispatch,
+        ret = sym syscall_return,
+    );
+}
+
+/// Restores the registers in the [`SyscallFrame`] at the top of the stack
+/// and returns to user mode.
+///
+/// Forked children start here with `rsp` pointing at their frame.
+///
+/// # Safety
+///
+/// The stack pointer must point at a valid frame for a user-mode return.
+#[unsafe(naked)]
+pub unsafe extern "C" fn syscall_return() {
+    naked_asm!(
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop r11",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rbp",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop rcx",
+        "pop rbx",
+        "pop rax",
+        "iretq",
+    );
+}
+
+extern "C" fn syscall_dispatch(frame: &mut SyscallFrame) {
+    let pid = crate::sched::scheduler()
+        .current_process()
+        .unwrap_or(ProcessId::KERNEL);
+    let args = [frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9];
+
+    let result = match frame.rax {
+        nr::WRITE => match args[0] {
+            1 | 2 => write_console(pid, args[1], args[2] as usize),
+            _ => -EBADF,
+        },
+        nr::GETPID => pid.0 as i64,
+        nr::FORK => match crate::process::PROCESS_MANAGER.fork_from_syscall(pid, frame) {
+            Ok(child) => child.0 as i64,
+            Err(crate::process::ProcessError::OutOfMemory) => -ENOMEM,
+            Err(_) => -EAGAIN,
+        },
+        nr::EXIT => exit_current(pid, args[0] as i32),
+        nr::PERSONALITY => crate::mm::security::set_personality(pid, args[0]) as i64,
+        // getrandom(buf, len, flags): from the CSPRNG that also keys exec
+        // layouts, so it never blocks. Long requests are cut short, as Linux
+        // may do.
+        nr::GETRANDOM => {
+            let mut bytes = [0u8; GETRANDOM_MAX];
+            let bytes = &mut bytes[..(args[1] as usize).min(GETRANDOM_MAX)];
+            crate::mm::security::aslr_random_bytes(bytes);
+            match crate::mm::vm::copy_to_user(pid, args[0], bytes) {
+                Ok(()) => bytes.len() as i64,
+                Err(_) => -EFAULT,
+            }
+        }
+        // mount(strings, token): `strings` points at the source, target and
+        // type as three (pointer, length) pairs; token as for unshare
+        #[cfg(not(feature = "microkernel"))]
+        nr::MOUNT => match (mount_args(pid, args[0]), user_token(pid, args[1])) {
+            (Some((source, target, fs_type)), Some(token)) => {
+                match crate::fs::mount_as(pid, &fs_type, &source, &target, token) {
+                    Ok(()) => 0,
+                    Err(e) => -(e.errno() as i64),
+                }
+            }
+            _ => -EINVAL,
+        },
+        // unshare(flags, token): only CLONE_NEWNS, with a mount_ns
+        // capability token passed by pointer
+        #[cfg(not(feature = "microkernel"))]
+        nr::UNSHARE => match user_token(pid, args[1]) {
+            Some(token) if args[0] == CLONE_NEWNS => match crate::fs::unshare_mounts(pid, token) {
+                Ok(_) => 0,
+                Err(e) => -(e.errno() as i64),
+            },
+            _ => -EINVAL,
+        },
+        // pivot_root(new_root, len, put_old, len, token): strings as
+        // (pointer, length) pairs
+        #[cfg(not(feature = "microkernel"))]
+        nr::PIVOT_ROOT => match (
+            user_str(pid, args[0], args[1]),
+            user_str(pid, args[2], args[3]),
+            user_token(pid, args[4]),
+        ) {
+            (Some(new_root), Some(put_old), Some(token)) => {
+                match crate::fs::pivot_root(pid, &new_root, &put_old, token) {
+                    Ok(()) => 0,
+                    Err(e) => -(e.errno() as i64),
+                }
+            }
+            _ => -EINVAL,
+        },
+        _ => -ENOSYS,
+    };
+    frame.rax = result as u64;
+}
+
+/// Writes a user buffer to the serial console.
+///
+/// The buffer is copied in a chunk at a time; a fault after some bytes went
+/// out ends the write short, as on Linux.
+fn write_console(pid: ProcessId, buf: u64, count: usize) -> i64 {
+    let count = count.min(WRITE_MAX);
+    let mut chunk = [0u8; 256];
+    let mut done = 0;
+    while done < count {
+        let len = (count - done).min(chunk.len());
+        if crate::mm::vm::copy_from_user(pid, buf + done as u64, &mut chunk[..len]).is_err() {
+            return if done == 0 { -EFAULT } else { done as i64 };
+        }
+        let serial = super::serial::SERIAL.lock();
+        for &b in &chunk[..len] {
+            serial.write_byte(b);
+        }
+        done += len;
+    }
+    done as i64
+}
+
+/// Ends the calling process and switches away from it for good.
+///
+/// The process is a zombie once [`exit`](crate::process::wait::exit) returns
+/// and is no longer current or queued, so the scheduler never picks it
+/// again. Its kernel stack stays allocated, since this still runs on it.
+fn exit_current(pid: ProcessId, code: i32) -> ! {
+    if crate::process::wait::exit(pid, code).is_err() {
+        panic!("init exited with status {}", code);
+    }
+    loop {
+        crate::sched::scheduler().yield_now();
+    }
+}
+
+/// A string passed as a (pointer, length) pair, copied in.
+#[cfg(not(feature = "microkernel"))]
+fn user_str(pid: ProcessId, ptr: u64, len: u64) -> Option<String> {
+    if len > 256 {
+        return None;
+    }
+    let mut bytes = vec![0; len as usize];
+    crate::mm::vm::copy_from_user(pid, ptr, &mut bytes).ok()?;
+    String::from_utf8(bytes).ok()
+}
+
+/// `N` words at `ptr`, copied in.
+#[cfg(not(feature = "microkernel"))]
+fn user_words<const N: usize>(pid: ProcessId, ptr: u64) -> Option<[u64; N]> {
+    let mut words = [0; N];
+    for (i, word) in words.iter_mut().enumerate() {
+        let mut bytes = [0; 8];
+        crate::mm::vm::copy_from_user(pid, ptr.checked_add(8 * i as u64)?, &mut bytes).ok()?;
+        *word = u64::from_ne_bytes(bytes);
+    }
+    Some(words)
+}
+
+/// A capability token passed as a pointer to its 32 bytes.
+#[cfg(not(feature = "microkernel"))]
+fn user_token(pid: ProcessId, ptr: u64) -> Option<crate::cap::CapabilityToken> {
+    user_words(pid, ptr).map(crate::cap::CapabilityToken::new)
+}
+
+/// `mount`'s source, target and type, from the (pointer, length) pairs at
+/// `ptr`.
+#[cfg(not(feature = "microkernel"))]
+fn mount_args(pid: ProcessId, ptr: u64) -> Option<(String, String, String)> {
+    let [source, source_len, target, target_len, fs_type, type_len] = user_words(pid, ptr)?;
+    Some((
+        user_str(pid, source, source_len)?,
+        user_str(pid, target, target_len)?,
+        user_str(pid, fs_type, type_len)?,
+    ))
+}
@@ -17,7 +17,9 @@
 //! - Support for SATA II/III (up to 6 Gbps)
 //! - Native Command Queuing (NCQ) support
 //! - Hot-plug support (detection)
-//! - ATAPI device detection
+//! - ATAPI (CD/DVD) drives, read-only: media are read in 2048-byte blocks
+//!   with SCSI READ(12) and exposed as 512-byte sectors like every other
+//!   device
 
 use alloc::boxed::Box;
 use alloc::string::{String, ToString};
@@ -51,6 +53,9 @@ const PRDT_ENTRIES: usize = 8;
 /// Sector size
 const ATA_SECTOR_SIZE: usize = 512;
 
+/// Block size of CD/DVD media
+const ATAPI_BLOCK_SIZE: usize = 2048;
+
 // =============================================================================
 // AHCI Register Offsets (Generic Host Control)
 // =============================================================================
@@ -131,11 +136,24 @@ const ATA_CMD_READ_DMA_EXT: u8 = 0x25;
 const ATA_CMD_WRITE_DMA_EXT: u8 = 0x35;
 /// ATA Flush Cache Extended
 const ATA_CMD_FLUSH_CACHE_EXT: u8 = 0xEA;
+/// ATA Packet (ATAPI command)
+const ATA_CMD_PACKET: u8 = 0xA0;
+/// ATA Identify Packet Device
+const ATA_CMD_IDENTIFY_PACKET: u8 = 0xA1;
 /// ATA Read FPDMA Queued (NCQ)
 const ATA_CMD_READ_FPDMA: u8 = 0x60;
 /// ATA Write FPDMA Queued (NCQ)
 const ATA_CMD_WRITE_FPDMA: u8 = 0x61;
 
+// =============================================================================
+// SCSI Commands (ATAPI)
+// =============================================================================
+
+/// SCSI Read Capacity (10)
+const SCSI_READ_CAPACITY_10: u8 = 0x25;
+/// SCSI Read (12)
+const SCSI_READ_12: u8 = 0xA8;
+
 // =============================================================================
 // FIS Types
 // =============================================================================
@@ -217,6 +235,23 @@ impl FisRegH2D {
         self.device = 0; // Master device
     }
 
+    /// Sets up for IDENTIFY PACKET DEVICE command
+    pub fn setup_identify_packet(&mut self) {
+        self.command = ATA_CMD_IDENTIFY_PACKET;
+        self.device = 0;
+    }
+
+    /// Sets up for PACKET command, transferring up to `byte_count` bytes
+    /// by DMA
+    pub fn setup_packet(&mut self, byte_count: u32) {
+        self.command = ATA_CMD_PACKET;
+        self.featurel = 0x01; // DMA
+        self.device = 0;
+        let limit = byte_count.min(0xFFFE);
+        self.lba1 = (limit & 0xFF) as u8;
+        self.lba2 = ((limit >> 8) & 0xFF) as u8;
+    }
+
     /// Sets up for READ DMA EXT command
     pub fn setup_read_dma(&mut self, lba: u64, count: u16) {
         self.command = ATA_CMD_READ_DMA_EXT;
@@ -513,8 +548,10 @@ pub struct AhciPort {
     model: String,
     /// Device serial number
     serial: String,
-    /// Total sectors
+    /// Total sectors (blocks of `block_size` bytes)
     total_sectors: u64,
+    /// Bytes per block: 512 for SATA, the medium's for ATAPI
+    block_size: usize,
 }
 
 impl AhciPort {
@@ -539,6 +576,7 @@ impl AhciPort {
             model: String::new(),
             serial: String::new(),
             total_sectors: 0,
+            block_size: ATA_SECTOR_SIZE,
         }
     }
     
@@ -650,9 +688,11 @@ impl AhciPort {
     
     /// Identifies the device
     pub fn identify(&mut self) -> Result<(), BlockError> {
-        if self.device_type != AhciDeviceType::Sata {
-            return Err(BlockError::NotFound);
-        }
+        let atapi = match self.device_type {
+            AhciDeviceType::Sata => false,
+            AhciDeviceType::Atapi => true,
+            _ => return Err(BlockError::NotFound),
+        };
         
         // Allocate buffer for identify data
         let mut identify_data = alloc::vec![0u8; 512];
@@ -660,7 +700,11 @@ impl AhciPort {
         
         // Build command FIS
         let mut fis = FisRegH2D::new();
-        fis.setup_identify();
+        if atapi {
+            fis.setup_identify_packet();
+        } else {
+            fis.setup_identify();
+        }
         
         // Set up command
         let slot = self.find_cmdslot()?;
@@ -722,6 +766,13 @@ impl AhciPort {
             model[i] = data[54 + i];
         }
         self.model = Self::ata_string(&model);
+
+        // Packet devices report their capacity through READ CAPACITY
+        if self.device_type == AhciDeviceType::Atapi {
+            crate::serial_println!("[ahci] Port {}: {} ({})",
+                self.port_num, self.model, self.serial);
+            return;
+        }
         
         // Total sectors (LBA48)
         let sector_count = u64::from_le_bytes([
@@ -770,6 +821,60 @@ impl AhciPort {
         Err(BlockError::Busy)
     }
     
+    /// Reads the capacity of the medium in an ATAPI drive
+    pub fn read_capacity(&mut self) -> Result<(), BlockError> {
+        if !self.started || self.device_type != AhciDeviceType::Atapi {
+            return Err(BlockError::NotReady);
+        }
+
+        let mut cdb = [0u8; 12];
+        cdb[0] = SCSI_READ_CAPACITY_10;
+        let mut data = [0u8; 8];
+        self.packet(&cdb, &mut data)?;
+
+        let last_lba = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+        let block_size = u32::from_be_bytes([data[4], data[5], data[6], data[7]]) as usize;
+        // Anything else would not map onto 512-byte sectors
+        if block_size != ATAPI_BLOCK_SIZE {
+            return Err(BlockError::Unsupported);
+        }
+        self.total_sectors = last_lba as u64 + 1;
+        self.block_size = block_size;
+
+        crate::serial_println!("[ahci]   Medium: {} blocks of {} bytes ({} MB)",
+            self.total_sectors, block_size, self.total_sectors * block_size as u64 / (1024 * 1024));
+        Ok(())
+    }
+
+    /// Reads blocks from the medium in an ATAPI drive
+    pub fn read_blocks(&mut self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        if !self.started || self.device_type != AhciDeviceType::Atapi {
+            return Err(BlockError::NotReady);
+        }
+
+        let count = (buffer.len() / self.block_size) as u32;
+        if count == 0 {
+            return Ok(());
+        }
+        if lba + count as u64 > self.total_sectors {
+            return Err(BlockError::InvalidSector);
+        }
+
+        let mut cdb = [0u8; 12];
+        cdb[0] = SCSI_READ_12;
+        cdb[2..6].copy_from_slice(&(lba as u32).to_be_bytes());
+        cdb[6..10].copy_from_slice(&count.to_be_bytes());
+        self.packet(&cdb, &mut buffer[..count as usize * self.block_size])
+    }
+
+    /// Sends a SCSI command to an ATAPI device, reading its data into
+    /// `buffer`
+    fn packet(&mut self, cdb: &[u8; 12], buffer: &mut [u8]) -> Result<(), BlockError> {
+        let mut fis = FisRegH2D::new();
+        fis.setup_packet(buffer.len() as u32);
+        self.execute_command_with_addr(&fis, Some(cdb), buffer.as_mut_ptr() as u64, buffer.len(), false)
+    }
+
     /// Reads sectors from the device
     pub fn read_sectors(&mut self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
         if !self.started || self.device_type != AhciDeviceType::Sata {
@@ -804,7 +909,7 @@ impl AhciPort {
         // Note: We need mutable access to the buffer for PRDT, but write doesn't modify it
         // This is safe because we're just reading from it
         let buffer_ptr = buffer.as_ptr() as u64;
-        self.execute_command_with_addr(&fis, buffer_ptr, buffer.len(), true)
+        self.execute_command_with_addr(&fis, None, buffer_ptr, buffer.len(), true)
     }
     
     /// Flushes cache
@@ -844,11 +949,19 @@ impl AhciPort {
     
     /// Executes a command with a buffer
     fn execute_command(&mut self, fis: &FisRegH2D, buffer: &mut [u8], write: bool) -> Result<(), BlockError> {
-        self.execute_command_with_addr(fis, buffer.as_ptr() as u64, buffer.len(), write)
+        self.execute_command_with_addr(fis, None, buffer.as_ptr() as u64, buffer.len(), write)
     }
     
-    /// Executes a command with a buffer address
-    fn execute_command_with_addr(&mut self, fis: &FisRegH2D, addr: u64, len: usize, write: bool) -> Result<(), BlockError> {
+    /// Executes a command with a buffer address, and the SCSI command of
+    /// an ATAPI PACKET command
+    fn execute_command_with_addr(
+        &mut self,
+        fis: &FisRegH2D,
+        cdb: Option<&[u8; 12]>,
+        addr: u64,
+        len: usize,
+        write: bool,
+    ) -> Result<(), BlockError> {
         let slot = self.find_cmdslot()?;
         let ctba = self.command_tables[slot].as_ref() as *const _ as u64;
         
@@ -860,6 +973,9 @@ impl AhciPort {
                 core::mem::size_of::<FisRegH2D>(),
             );
         }
+        if let Some(cdb) = cdb {
+            cmd_table.acmd[..12].copy_from_slice(cdb);
+        }
         
         // Set up PRDT entries
         let mut remaining = len;
@@ -874,7 +990,7 @@ impl AhciPort {
             prdt_idx += 1;
         }
         
-        self.command_list[slot] = CommandHeader::new(ctba, prdt_idx as u16, write, false, 5);
+        self.command_list[slot] = CommandHeader::new(ctba, prdt_idx as u16, write, cdb.is_some(), 5);
         
         // Issue command
         self.write_reg(PX_CI, 1 << slot);
@@ -990,12 +1106,27 @@ impl AhciController {
                     }
                     self.ports.push(port);
                 } else if device_type == AhciDeviceType::Atapi {
-                    crate::serial_println!("[ahci] Port {}: ATAPI device detected (not supported)", i);
+                    crate::serial_println!("[ahci] Port {}: ATAPI device detected", i);
+                    if let Err(e) = port.start() {
+                        crate::serial_println!("[ahci] Port {}: Failed to start: {:?}", i, e);
+                        continue;
+                    }
+                    if let Err(e) = port.identify() {
+                        crate::serial_println!("[ahci] Port {}: Failed to identify: {:?}", i, e);
+                        continue;
+                    }
+                    // The first command after power-on or a media change
+                    // fails with a unit attention; an empty drive is still
+                    // registered, with no sectors
+                    if let Err(e) = port.read_capacity().or_else(|_| port.read_capacity()) {
+                        crate::serial_println!("[ahci] Port {}: No readable medium: {:?}", i, e);
+                    }
+                    self.ports.push(port);
                 }
             }
         }
         
-        crate::serial_println!("[ahci] Initialized {} SATA device(s)", self.ports.len());
+        crate::serial_println!("[ahci] Initialized {} device(s)", self.ports.len());
         
         Ok(())
     }
@@ -1035,33 +1166,54 @@ impl AhciBlockDevice {
 impl BlockDevice for AhciBlockDevice {
     fn info(&self) -> BlockDeviceInfo {
         let port = self.port.lock();
+        let sectors_per_block = (port.block_size / ATA_SECTOR_SIZE) as u64;
         BlockDeviceInfo {
             name: self.name.clone(),
-            total_sectors: port.total_sectors,
+            total_sectors: port.total_sectors * sectors_per_block,
             sector_size: ATA_SECTOR_SIZE,
-            read_only: false,
+            read_only: port.device_type == AhciDeviceType::Atapi,
             model: port.model.clone(),
         }
     }
     
     fn read_sectors(&self, start_sector: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
         let mut port = self.port.lock();
-        port.read_sectors(start_sector, buffer)
+        if port.device_type != AhciDeviceType::Atapi {
+            return port.read_sectors(start_sector, buffer);
+        }
+
+        // Read the blocks covering the sectors
+        let sectors_per_block = (port.block_size / ATA_SECTOR_SIZE) as u64;
+        let lba = start_sector / sectors_per_block;
+        let skip = (start_sector % sectors_per_block) as usize * ATA_SECTOR_SIZE;
+        if skip == 0 && buffer.len() % port.block_size == 0 {
+            return port.read_blocks(lba, buffer);
+        }
+        let mut blocks = alloc::vec![0u8; (skip + buffer.len()).div_ceil(port.block_size) * port.block_size];
+        port.read_blocks(lba, &mut blocks)?;
+        buffer.copy_from_slice(&blocks[skip..skip + buffer.len()]);
+        Ok(())
     }
     
     fn write_sectors(&self, start_sector: u64, buffer: &[u8]) -> Result<(), BlockError> {
         let mut port = self.port.lock();
+        if port.device_type == AhciDeviceType::Atapi {
+            return Err(BlockError::WriteProtected);
+        }
         port.write_sectors(start_sector, buffer)
     }
     
     fn flush(&self) -> Result<(), BlockError> {
         let mut port = self.port.lock();
+        if port.device_type == AhciDeviceType::Atapi {
+            return Ok(());
+        }
         port.flush()
     }
     
     fn is_ready(&self) -> bool {
         let port = self.port.lock();
-        port.started && port.device_type == AhciDeviceType::Sata
+        port.started && matches!(port.device_type, AhciDeviceType::Sata | AhciDeviceType::Atapi)
     }
 }
 
@@ -1072,6 +1224,9 @@ impl BlockDevice for AhciBlockDevice {
 /// Global AHCI device counter
 static AHCI_COUNTER: Mutex<usize> = Mutex::new(0);
 
+/// Global ATAPI device counter
+static CDROM_COUNTER: Mutex<usize> = Mutex::new(0);
+
 /// Generates the next SATA device name
 pub fn next_sata_name() -> String {
     let mut counter = AHCI_COUNTER.lock();
@@ -1080,6 +1235,14 @@ pub fn next_sata_name() -> String {
     name
 }
 
+/// Generates the next CD/DVD drive name (e.g., "sr0", "sr1", ...)
+pub fn next_cdrom_name() -> String {
+    let mut counter = CDROM_COUNTER.lock();
+    let name = alloc::format!("sr{}", *counter);
+    *counter += 1;
+    name
+}
+
 /// Probes for AHCI devices via PCI
 pub fn probe_devices() {
     use crate::pci;
@@ -1152,7 +1315,11 @@ pub fn init_controller(mmio_base: u64) -> Result<(), BlockError> {
     
     // Register each port as a block device
     for port in controller.ports {
-        let dev_name = next_sata_name();
+        let dev_name = if port.device_type == AhciDeviceType::Atapi {
+            next_cdrom_name()
+        } else {
+            next_sata_name()
+        };
         let device = AhciBlockDevice::new(port, dev_name);
         let boxed: Box<dyn BlockDevice> = Box::new(device);
         super::register_device(boxed)?;
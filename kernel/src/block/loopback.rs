@@ -0,0 +1,178 @@
+//! # Loop Devices
+//!
+//! Exposes a file as a block device (`loop0`, `loop1`, ...), so that a
+//! filesystem image - an ISO, a disk image - can be mounted like a disk.
+//!
+//! ## Design
+//!
+//! A loop device reads and writes its backing file through the page cache
+//! (or straight through for DAX files), like any other file access, so it
+//! sees writes made through file descriptors and vice versa. The file is
+//! resolved once, when the device is attached; a trailing partial sector
+//! is not part of the device.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use super::{BlockDevice, BlockDeviceInfo, BlockError, SECTOR_SIZE};
+use crate::fs::dax;
+use crate::fs::pagecache::PAGE_CACHE;
+use crate::fs::vfs::{InodeNum, MountPoint, VfsError, VfsFileType, VFS};
+
+/// A file exposed as a block device
+pub struct LoopDevice {
+    /// Device name
+    name: String,
+    /// Path of the backing file
+    path: String,
+    /// Mount holding the backing file
+    mount: Arc<MountPoint>,
+    /// Inode of the backing file
+    ino: InodeNum,
+    /// Size in sectors
+    sectors: u64,
+    /// Whether writes are refused
+    read_only: bool,
+}
+
+impl LoopDevice {
+    /// Byte offset of a sector range, checked against the size
+    fn range(&self, start_sector: u64, len: usize) -> Result<u64, BlockError> {
+        let sectors = len.div_ceil(SECTOR_SIZE) as u64;
+        if start_sector.checked_add(sectors).is_none_or(|end| end > self.sectors) {
+            return Err(BlockError::InvalidSector);
+        }
+        Ok(start_sector * SECTOR_SIZE as u64)
+    }
+}
+
+impl BlockDevice for LoopDevice {
+    fn info(&self) -> BlockDeviceInfo {
+        BlockDeviceInfo {
+            name: self.name.clone(),
+            total_sectors: self.sectors,
+            sector_size: SECTOR_SIZE,
+            read_only: self.read_only,
+            model: alloc::format!("Loop: {}", self.path),
+        }
+    }
+
+    fn read_sectors(&self, start_sector: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        let offset = self.range(start_sector, buffer.len())?;
+        let read = if self.mount.fs.is_dax() {
+            dax::read(&self.mount, self.ino, offset, buffer)
+        } else {
+            PAGE_CACHE.read(&self.mount, self.ino, offset, buffer)
+        };
+        match read {
+            Ok(len) if len == buffer.len() => Ok(()),
+            _ => Err(BlockError::IoError),
+        }
+    }
+
+    fn write_sectors(&self, start_sector: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        if self.read_only {
+            return Err(BlockError::WriteProtected);
+        }
+        let offset = self.range(start_sector, buffer.len())?;
+        let written = if self.mount.fs.is_dax() {
+            dax::write(&self.mount, self.ino, offset, buffer)
+        } else {
+            PAGE_CACHE.write(&self.mount, self.ino, offset, buffer)
+        };
+        match written {
+            Ok(len) if len == buffer.len() => Ok(()),
+            _ => Err(BlockError::IoError),
+        }
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        if self.read_only {
+            return Ok(());
+        }
+        let flushed = if self.mount.fs.is_dax() {
+            self.mount.fs.sync()
+        } else {
+            PAGE_CACHE.flush_inode(&self.mount, self.ino)
+        };
+        flushed.map_err(|_| BlockError::IoError)
+    }
+
+    fn is_ready(&self) -> bool {
+        true
+    }
+}
+
+/// Names of the attached loop devices, by number
+static LOOP_DEVICES: Mutex<Vec<Option<String>>> = Mutex::new(Vec::new());
+
+/// Attaches a file as the first free loop device, read-only if asked or
+/// if its filesystem is. Returns the device name.
+pub fn attach(path: &str, read_only: bool) -> Result<String, BlockError> {
+    let (mount, ino) = VFS.resolve(path).map_err(|e| match e {
+        VfsError::NotFound => BlockError::NotFound,
+        _ => BlockError::IoError,
+    })?;
+    let attr = mount.fs.getattr(ino).map_err(|_| BlockError::IoError)?;
+    if attr.file_type != VfsFileType::Regular {
+        return Err(BlockError::Unsupported);
+    }
+    let size = if mount.fs.is_dax() {
+        dax::size(&mount, ino)
+    } else {
+        PAGE_CACHE.size(&mount, ino)
+    }
+    .map_err(|_| BlockError::IoError)?;
+
+    let mut devices = LOOP_DEVICES.lock();
+    let number = devices.iter().position(Option::is_none).unwrap_or(devices.len());
+    let name = alloc::format!("loop{}", number);
+    let read_only = read_only || mount.read_only;
+    let device = LoopDevice {
+        name: name.clone(),
+        path: path.into(),
+        mount,
+        ino,
+        sectors: size / SECTOR_SIZE as u64,
+        read_only,
+    };
+    super::register_device(Box::new(device))?;
+    if number == devices.len() {
+        devices.push(Some(name.clone()));
+    } else {
+        devices[number] = Some(name.clone());
+    }
+
+    crate::serial_println!("[loop] Attached {} as {}{}", path, name, if read_only { " (read-only)" } else { "" });
+    Ok(name)
+}
+
+/// Detaches a loop device. Filesystems mounted from it must be unmounted
+/// first.
+pub fn detach(name: &str) -> Result<(), BlockError> {
+    let mut devices = LOOP_DEVICES.lock();
+    let slot = devices
+        .iter_mut()
+        .find(|d| d.as_deref() == Some(name))
+        .ok_or(BlockError::NotFound)?;
+    super::flush(name)?;
+    super::unregister_device(name)?;
+    *slot = None;
+    Ok(())
+}
+
+/// Lists attached loop devices with their backing files
+pub fn list() -> Vec<(String, String)> {
+    LOOP_DEVICES
+        .lock()
+        .iter()
+        .flatten()
+        .filter_map(|name| {
+            let model = super::with_device(name, |dev| dev.info().model).ok()?;
+            Some((name.clone(), model.trim_start_matches("Loop: ").into()))
+        })
+        .collect()
+}
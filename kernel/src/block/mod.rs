@@ -12,6 +12,8 @@
 //! - I/O scheduling (NoOp, Deadline, CFQ)
 //! - Bio layer for scatter-gather I/O
 //! - Partition table support (MBR/GPT)
+//! - Direct access to byte-addressable devices (persistent memory)
+//! - Loop devices backed by files (filesystem images)
 //!
 //! ## Architecture
 //!
@@ -30,19 +32,26 @@
 //! │  - VirtIO-blk                           │
 //! │  - NVMe                                 │
 //! │  - AHCI                                 │
+//! │  - Persistent memory (NVDIMM)           │
+//! │  - Loop (file-backed)                   │
 //! └─────────────────────────────────────────┘
 //! ```
 
 pub mod ahci;
 pub mod bio;
+pub mod loopback;
 pub mod nvme;
 pub mod partitions;
+pub mod pmem;
+#[cfg(test)]
+pub mod ramdisk;
 pub mod scheduler;
 pub mod virtio_blk;
 
 use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
 use alloc::string::String;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 use spin::Mutex;
 
@@ -160,10 +169,18 @@ pub trait BlockDevice: Send + Sync {
 
     /// Returns true if the device is ready
     fn is_ready(&self) -> bool;
+
+    /// Returns the physical address of `sector` if the device is byte
+    /// addressable (persistent memory). The range is contiguous to the end
+    /// of the device.
+    fn direct_access(&self, _sector: u64) -> Option<u64> {
+        None
+    }
 }
 
-/// Global block device registry
-static BLOCK_DEVICES: Mutex<BTreeMap<String, Box<dyn BlockDevice>>> = Mutex::new(BTreeMap::new());
+/// Global block device registry. Devices are called with the lock
+/// released, as partitions call back into their parent device.
+static BLOCK_DEVICES: Mutex<BTreeMap<String, Arc<dyn BlockDevice>>> = Mutex::new(BTreeMap::new());
 
 /// Device counter for auto-naming
 static DEVICE_COUNTER: Mutex<usize> = Mutex::new(0);
@@ -181,7 +198,7 @@ pub fn register_device(device: Box<dyn BlockDevice>) -> Result<String, BlockErro
         if devices.contains_key(&name) {
             return Err(BlockError::Busy);
         }
-        devices.insert(name.clone(), device);
+        devices.insert(name.clone(), Arc::from(device));
     }
     
     // Print after releasing the lock - use static strings where possible
@@ -211,22 +228,23 @@ pub fn list_devices() -> Vec<BlockDeviceInfo> {
     devices.values().map(|d| d.info()).collect()
 }
 
-/// Gets a reference to a block device (borrows the lock)
+/// Calls `f` with a block device
 pub fn with_device<F, R>(name: &str, f: F) -> Result<R, BlockError>
 where
     F: FnOnce(&dyn BlockDevice) -> R,
 {
-    let devices = BLOCK_DEVICES.lock();
-    match devices.get(name) {
-        Some(device) => Ok(f(device.as_ref())),
-        None => Err(BlockError::NotFound),
-    }
+    let device = device(name)?;
+    Ok(f(device.as_ref()))
+}
+
+/// Looks up a registered device
+fn device(name: &str) -> Result<Arc<dyn BlockDevice>, BlockError> {
+    BLOCK_DEVICES.lock().get(name).cloned().ok_or(BlockError::NotFound)
 }
 
 /// Reads from a block device by name
 pub fn read(name: &str, sector: u64, count: usize) -> Result<Vec<u8>, BlockError> {
-    let devices = BLOCK_DEVICES.lock();
-    let device = devices.get(name).ok_or(BlockError::NotFound)?;
+    let device = device(name)?;
     
     let size = count * device.info().sector_size;
     let mut buffer = alloc::vec![0u8; size];
@@ -236,15 +254,13 @@ pub fn read(name: &str, sector: u64, count: usize) -> Result<Vec<u8>, BlockError
 
 /// Writes to a block device by name
 pub fn write(name: &str, sector: u64, data: &[u8]) -> Result<(), BlockError> {
-    let devices = BLOCK_DEVICES.lock();
-    let device = devices.get(name).ok_or(BlockError::NotFound)?;
+    let device = device(name)?;
     device.write_sectors(sector, data)
 }
 
 /// Flushes a block device by name
 pub fn flush(name: &str) -> Result<(), BlockError> {
-    let devices = BLOCK_DEVICES.lock();
-    let device = devices.get(name).ok_or(BlockError::NotFound)?;
+    let device = device(name)?;
     device.flush()
 }
 
@@ -269,6 +285,9 @@ pub fn init() {
     // Probe for AHCI/SATA devices (HDDs and legacy SSDs)
     ahci::probe_devices();
     
+    // Register NVDIMM ranges described by the ACPI NFIT
+    pmem::probe_devices();
+    
     crate::serial_println!("[BLOCK] Block subsystem initialized");
 }
 
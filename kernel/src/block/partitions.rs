@@ -10,7 +10,9 @@
 //! ## Design
 //!
 //! Partitions are exposed as virtual block devices that transparently
-//! remap sector addresses to the parent device.
+//! remap sector addresses to the parent device. Each partition is probed
+//! for a known filesystem as it is registered, so the shell can mount it
+//! without being told the type.
 
 use alloc::boxed::Box;
 use alloc::string::String;
@@ -118,7 +120,7 @@ impl PartitionInfo {
                 mbr_types::FAT12 => "FAT12",
                 mbr_types::FAT16_SMALL | mbr_types::FAT16 | mbr_types::FAT16_LBA => "FAT16",
                 mbr_types::FAT32 | mbr_types::FAT32_LBA => "FAT32",
-                mbr_types::NTFS => "NTFS",
+                mbr_types::NTFS => "NTFS/exFAT",
                 mbr_types::LINUX => "Linux",
                 mbr_types::LINUX_SWAP => "Linux Swap",
                 mbr_types::LINUX_LVM => "Linux LVM",
@@ -362,6 +364,92 @@ pub fn read_partition_table(device: &dyn BlockDevice) -> Result<PartitionTable,
     })
 }
 
+// ============================================================================
+// Filesystem Detection
+// ============================================================================
+
+/// Filesystems recognised by their on-disk signature
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilesystemType {
+    /// SplaxFS
+    SplaxFs,
+    /// ext2/3/4
+    Ext4,
+    /// FAT32
+    Fat32,
+    /// exFAT
+    ExFat,
+    /// ISO 9660 (CD/DVD media and images)
+    Iso9660,
+    /// SquashFS (compressed read-only images)
+    SquashFs,
+}
+
+impl FilesystemType {
+    /// Returns the name used by `mount -t`
+    pub fn name(&self) -> &'static str {
+        match self {
+            FilesystemType::SplaxFs => "splaxfs",
+            FilesystemType::Ext4 => "ext4",
+            FilesystemType::Fat32 => "fat32",
+            FilesystemType::ExFat => "exfat",
+            FilesystemType::Iso9660 => "iso9660",
+            FilesystemType::SquashFs => "squashfs",
+        }
+    }
+}
+
+/// Bytes read from the start of a device to recognise its filesystem
+const PROBE_SIZE: usize = 2048;
+
+/// Offset of the ext2/3/4 superblock magic
+const EXT4_MAGIC_OFFSET: usize = 1024 + 56;
+
+/// SquashFS superblock magic, "hsqs"
+const SQUASHFS_MAGIC: &[u8; 4] = b"hsqs";
+
+/// Offset of the first ISO 9660 volume descriptor, past the system area
+const ISO9660_VD_OFFSET: u64 = 16 * 2048;
+
+/// Recognises the filesystem on a device from its first sectors
+pub fn probe_filesystem(device: &dyn BlockDevice) -> Result<Option<FilesystemType>, BlockError> {
+    let sector_size = device.info().sector_size.max(1);
+    let mut data = alloc::vec![0u8; PROBE_SIZE.div_ceil(sector_size) * sector_size];
+    device.read_sectors(0, &mut data)?;
+
+    let boot_signature = data[510] == 0x55 && data[511] == 0xAA;
+    let fs = if &data[3..11] == b"EXFAT   " {
+        Some(FilesystemType::ExFat)
+    } else if u16::from_le_bytes([data[EXT4_MAGIC_OFFSET], data[EXT4_MAGIC_OFFSET + 1]]) == 0xEF53 {
+        Some(FilesystemType::Ext4)
+    } else if boot_signature && &data[82..90] == b"FAT32   " {
+        Some(FilesystemType::Fat32)
+    } else if u32::from_le_bytes([data[0], data[1], data[2], data[3]]) == splaxfs_core::SPLAXFS_MAGIC {
+        Some(FilesystemType::SplaxFs)
+    } else if &data[..4] == SQUASHFS_MAGIC {
+        Some(FilesystemType::SquashFs)
+    } else {
+        None
+    };
+    if fs.is_some() {
+        return Ok(fs);
+    }
+
+    // ISO 9660 keeps its descriptors past a 32 KiB system area, which
+    // hybrid images fill with an MBR and boot code
+    let sector = ISO9660_VD_OFFSET / sector_size as u64;
+    let fits = device.info().total_sectors >= sector + (data.len() / sector_size) as u64;
+    if fits && device.read_sectors(sector, &mut data).is_ok() && &data[1..6] == b"CD001" {
+        return Ok(Some(FilesystemType::Iso9660));
+    }
+    Ok(None)
+}
+
+/// Recognises the filesystem on a registered device
+pub fn detect_filesystem(device_name: &str) -> Option<FilesystemType> {
+    super::with_device(device_name, |dev| probe_filesystem(dev)).ok()?.ok()?
+}
+
 // ============================================================================
 // Partition Block Device
 // ============================================================================
@@ -438,6 +526,15 @@ impl BlockDevice for PartitionDevice {
     fn is_ready(&self) -> bool {
         super::with_device(self.parent, |dev| dev.is_ready()).unwrap_or(false)
     }
+
+    fn direct_access(&self, sector: u64) -> Option<u64> {
+        if sector >= self.info.sector_count {
+            return None;
+        }
+        super::with_device(self.parent, |dev| {
+            dev.direct_access(self.info.start_sector + sector)
+        }).ok()?
+    }
 }
 
 /// Probes a device for partitions and registers partition devices
@@ -453,12 +550,14 @@ pub fn probe_partitions(device_name: &'static str) -> Result<Vec<String>, BlockE
             dev.info().sector_size
         })?;
 
+        let (start_sector, sector_count) = (partition.start_sector, partition.sector_count);
         let part_dev = PartitionDevice::new(device_name, partition, sector_size);
         let name = part_dev.info().name.clone();
-        
+        let filesystem = probe_filesystem(&part_dev).ok().flatten();
+
         // Register the partition as a block device
-        crate::serial_println!("[PART] Registering partition: {} (start={}, sectors={})", 
-            name, partition.start_sector, partition.sector_count);
+        crate::serial_println!("[PART] Registering partition: {} (start={}, sectors={}, fs={})",
+            name, start_sector, sector_count, filesystem.map_or("unknown", |fs| fs.name()));
         
         // Register with block subsystem
         match super::register_device(Box::new(part_dev)) {
@@ -508,4 +607,72 @@ mod tests {
         assert_eq!(info.size_bytes(), 1048576 * 512);
         assert_eq!(info.type_name(), "Linux");
     }
+
+    struct Image(Vec<u8>);
+
+    impl BlockDevice for Image {
+        fn info(&self) -> BlockDeviceInfo {
+            BlockDeviceInfo {
+                name: String::from("image"),
+                sector_size: SECTOR_SIZE,
+                total_sectors: (self.0.len() / SECTOR_SIZE) as u64,
+                read_only: true,
+                model: String::new(),
+            }
+        }
+
+        fn read_sectors(&self, start_sector: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+            let start = start_sector as usize * SECTOR_SIZE;
+            buffer.copy_from_slice(&self.0[start..start + buffer.len()]);
+            Ok(())
+        }
+
+        fn write_sectors(&self, _start_sector: u64, _buffer: &[u8]) -> Result<(), BlockError> {
+            Err(BlockError::WriteProtected)
+        }
+
+        fn flush(&self) -> Result<(), BlockError> {
+            Ok(())
+        }
+
+        fn is_ready(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn test_probe_filesystem() {
+        let mut exfat = alloc::vec![0u8; 4096];
+        exfat[3..11].copy_from_slice(b"EXFAT   ");
+        exfat[510] = 0x55;
+        exfat[511] = 0xAA;
+        assert_eq!(probe_filesystem(&Image(exfat)).unwrap(), Some(FilesystemType::ExFat));
+
+        let mut ext4 = alloc::vec![0u8; 4096];
+        ext4[EXT4_MAGIC_OFFSET..EXT4_MAGIC_OFFSET + 2].copy_from_slice(&0xEF53u16.to_le_bytes());
+        assert_eq!(probe_filesystem(&Image(ext4)).unwrap(), Some(FilesystemType::Ext4));
+
+        let mut fat32 = alloc::vec![0u8; 4096];
+        fat32[82..90].copy_from_slice(b"FAT32   ");
+        assert_eq!(probe_filesystem(&Image(fat32.clone())).unwrap(), None);
+        fat32[510] = 0x55;
+        fat32[511] = 0xAA;
+        assert_eq!(probe_filesystem(&Image(fat32)).unwrap(), Some(FilesystemType::Fat32));
+
+        let mut splaxfs = alloc::vec![0u8; 4096];
+        splaxfs[..4].copy_from_slice(&splaxfs_core::SPLAXFS_MAGIC.to_le_bytes());
+        assert_eq!(probe_filesystem(&Image(splaxfs)).unwrap(), Some(FilesystemType::SplaxFs));
+
+        let mut squashfs = alloc::vec![0u8; 4096];
+        squashfs[..4].copy_from_slice(b"hsqs");
+        assert_eq!(probe_filesystem(&Image(squashfs)).unwrap(), Some(FilesystemType::SquashFs));
+
+        let mut iso = alloc::vec![0u8; 40960];
+        iso[510] = 0x55;
+        iso[511] = 0xAA;
+        iso[32768..32774].copy_from_slice(b"\x01CD001");
+        assert_eq!(probe_filesystem(&Image(iso)).unwrap(), Some(FilesystemType::Iso9660));
+
+        assert_eq!(probe_filesystem(&Image(alloc::vec![0u8; 4096])).unwrap(), None);
+    }
 }
@@ -0,0 +1,204 @@
+//! # Persistent Memory Block Driver
+//!
+//! Exposes the NVDIMM ranges the ACPI NFIT reports as block devices
+//! (`pmem0`, `pmem1`, ...).
+//!
+//! ## Design
+//!
+//! A pmem device is a window of physical memory, so there is no queue and
+//! no DMA: reads are `memcpy` out of the range and writes are `memcpy` into
+//! it followed by a cache-line write-back (see [`crate::mm::pmem`]).
+//! `flush()` is the store fence that makes completed writes durable.
+//!
+//! On x86_64 each range is identity-mapped write-back with
+//! [`map_identity`](crate::arch::x86_64::paging::map_identity); ranges it
+//! refuses are skipped rather than registered unmapped.
+//!
+//! Because the media is byte addressable, the device also implements
+//! [`BlockDevice::direct_access`]: a filesystem on it can hand out the
+//! address of a block and let it be mapped into a process (DAX) instead of
+//! copying through the page cache.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::{BlockDevice, BlockDeviceInfo, BlockError, SECTOR_SIZE};
+use crate::mm::pmem;
+
+/// A persistent memory range used as a block device.
+pub struct PmemDevice {
+    /// Device name
+    name: String,
+    /// Physical base of the range
+    phys_base: u64,
+    /// Kernel virtual base of the range
+    virt_base: u64,
+    /// Size in bytes (multiple of the sector size)
+    size: u64,
+}
+
+impl PmemDevice {
+    /// Creates a device over `size` bytes mapped at `virt_base`.
+    ///
+    /// # Safety
+    ///
+    /// `virt_base..virt_base + size` must stay mapped and must not be used
+    /// by anything else for the lifetime of the device.
+    pub unsafe fn new(name: String, phys_base: u64, virt_base: u64, size: u64) -> Self {
+        Self {
+            name,
+            phys_base,
+            virt_base,
+            size: size - size % SECTOR_SIZE as u64,
+        }
+    }
+
+    /// Physical base address of the range.
+    pub fn phys_base(&self) -> u64 {
+        self.phys_base
+    }
+
+    /// Size of the range in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Returns the byte offset of an I/O, checking it against the device.
+    fn check(&self, start_sector: u64, len: usize) -> Result<u64, BlockError> {
+        if !len.is_multiple_of(SECTOR_SIZE) {
+            return Err(BlockError::InvalidSize);
+        }
+        let offset = start_sector
+            .checked_mul(SECTOR_SIZE as u64)
+            .ok_or(BlockError::InvalidSector)?;
+        match offset.checked_add(len as u64) {
+            Some(end) if end <= self.size => Ok(offset),
+            _ => Err(BlockError::InvalidSector),
+        }
+    }
+}
+
+impl BlockDevice for PmemDevice {
+    fn info(&self) -> BlockDeviceInfo {
+        BlockDeviceInfo {
+            name: self.name.clone(),
+            total_sectors: self.size / SECTOR_SIZE as u64,
+            sector_size: SECTOR_SIZE,
+            read_only: false,
+            model: String::from("NVDIMM Persistent Memory"),
+        }
+    }
+
+    fn read_sectors(&self, start_sector: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        let offset = self.check(start_sector, buffer.len())?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                (self.virt_base + offset) as *const u8,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+            );
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, start_sector: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        let offset = self.check(start_sector, buffer.len())?;
+        unsafe { pmem::copy_persistent((self.virt_base + offset) as *mut u8, buffer) };
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        pmem::drain();
+        Ok(())
+    }
+
+    fn is_ready(&self) -> bool {
+        true
+    }
+
+    fn direct_access(&self, sector: u64) -> Option<u64> {
+        let offset = sector.checked_mul(SECTOR_SIZE as u64)?;
+        (offset < self.size).then(|| self.phys_base + offset)
+    }
+}
+
+/// Registers a block device for every persistent memory range in the NFIT.
+pub fn probe_devices() -> Vec<String> {
+    let mut registered = Vec::new();
+
+    for (index, range) in crate::acpi::acpi().pmem_ranges().into_iter().enumerate() {
+        // Write-back cached: durability comes from the explicit flushes in
+        // `mm::pmem`, not from uncached stores
+        #[cfg(target_arch = "x86_64")]
+        let virt = crate::arch::x86_64::paging::map_identity(
+            range.base,
+            range.length as usize,
+            crate::arch::x86_64::paging::PageFlags::WRITABLE,
+        );
+        #[cfg(not(target_arch = "x86_64"))]
+        let virt = Some(range.base);
+        let Some(virt) = virt else {
+            crate::serial_println!("[PMEM] Cannot map range {} at {:#x}, skipping", range.index, range.base);
+            continue;
+        };
+
+        let device = unsafe { PmemDevice::new(format!("pmem{}", index), range.base, virt, range.length) };
+        match super::register_device(alloc::boxed::Box::new(device)) {
+            Ok(name) => registered.push(name),
+            Err(e) => crate::serial_println!("[PMEM] Failed to register range {}: {:?}", range.index, e),
+        }
+    }
+
+    if !registered.is_empty() {
+        crate::serial_println!("[PMEM] {} persistent memory device(s)", registered.len());
+    }
+    registered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn device(backing: &mut Vec<u8>) -> PmemDevice {
+        let base = backing.as_mut_ptr() as u64;
+        unsafe { PmemDevice::new(String::from("pmem-test"), 0x1_0000_0000, base, backing.len() as u64) }
+    }
+
+    #[test]
+    fn test_read_write() {
+        let mut backing = vec![0u8; 8 * SECTOR_SIZE];
+        let dev = device(&mut backing);
+        assert_eq!(dev.info().total_sectors, 8);
+
+        let data = vec![0x5Au8; 2 * SECTOR_SIZE];
+        dev.write_sectors(3, &data).unwrap();
+        dev.flush().unwrap();
+
+        let mut out = vec![0u8; 2 * SECTOR_SIZE];
+        dev.read_sectors(3, &mut out).unwrap();
+        assert_eq!(out, data);
+        assert_eq!(backing[3 * SECTOR_SIZE], 0x5A);
+        assert_eq!(backing[3 * SECTOR_SIZE - 1], 0);
+    }
+
+    #[test]
+    fn test_bounds() {
+        let mut backing = vec![0u8; 4 * SECTOR_SIZE];
+        let dev = device(&mut backing);
+        let mut buf = vec![0u8; 2 * SECTOR_SIZE];
+        assert_eq!(dev.read_sectors(3, &mut buf), Err(BlockError::InvalidSector));
+        assert_eq!(dev.write_sectors(0, &buf[..100]), Err(BlockError::InvalidSize));
+        assert_eq!(dev.read_sectors(u64::MAX, &mut buf), Err(BlockError::InvalidSector));
+    }
+
+    #[test]
+    fn test_direct_access() {
+        let mut backing = vec![0u8; 4 * SECTOR_SIZE];
+        let dev = device(&mut backing);
+        assert_eq!(dev.direct_access(0), Some(0x1_0000_0000));
+        assert_eq!(dev.direct_access(2), Some(0x1_0000_0000 + 2 * SECTOR_SIZE as u64));
+        assert_eq!(dev.direct_access(4), None);
+    }
+}
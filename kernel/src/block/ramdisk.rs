@@ -0,0 +1,72 @@
+//! # RAM Disks
+//!
+//! Block devices backed by a byte vector, for filesystem and journal tests.
+//! A test builds its image in memory, mounts it through the real driver and
+//! can inspect or corrupt the bytes afterwards through [`RamDisk::data`].
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use super::{BlockDevice, BlockDeviceInfo, BlockError, SECTOR_SIZE};
+
+/// A block device held in memory
+pub struct RamDisk {
+    /// Device name
+    name: String,
+    /// Writes fail with `WriteProtected`
+    read_only: bool,
+    /// Device contents
+    pub data: Mutex<Vec<u8>>,
+}
+
+impl RamDisk {
+    /// Creates a writable disk holding `data`
+    pub fn new(name: &str, data: Vec<u8>) -> Self {
+        Self { name: String::from(name), read_only: false, data: Mutex::new(data) }
+    }
+
+    /// Creates a disk holding `data` that refuses writes, like a CD-ROM
+    pub fn read_only(name: &str, data: Vec<u8>) -> Self {
+        Self { read_only: true, ..Self::new(name, data) }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn info(&self) -> BlockDeviceInfo {
+        BlockDeviceInfo {
+            name: self.name.clone(),
+            total_sectors: (self.data.lock().len() / SECTOR_SIZE) as u64,
+            sector_size: SECTOR_SIZE,
+            read_only: self.read_only,
+            model: String::from("RAM disk"),
+        }
+    }
+
+    fn read_sectors(&self, start_sector: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        let data = self.data.lock();
+        let start = start_sector as usize * SECTOR_SIZE;
+        let src = data.get(start..start + buffer.len()).ok_or(BlockError::InvalidSector)?;
+        buffer.copy_from_slice(src);
+        Ok(())
+    }
+
+    fn write_sectors(&self, start_sector: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        if self.read_only {
+            return Err(BlockError::WriteProtected);
+        }
+        let mut data = self.data.lock();
+        let start = start_sector as usize * SECTOR_SIZE;
+        let dst = data.get_mut(start..start + buffer.len()).ok_or(BlockError::InvalidSector)?;
+        dst.copy_from_slice(buffer);
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        Ok(())
+    }
+
+    fn is_ready(&self) -> bool {
+        true
+    }
+}
@@ -30,9 +30,11 @@ pub mod verify;
 use alloc::collections::BTreeMap;
 use alloc::string::String;
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicPtr, Ordering};
 
 use spin::Mutex;
 
+use crate::mm::slab::{SlabBox, SlabCache};
 use crate::sched::ProcessId;
 
 /// A cryptographic capability token.
@@ -157,12 +159,15 @@ impl ResourceId {
     }
 }
 
+/// Slab cache for capability table entries.
+static CAP_ENTRY_CACHE: SlabCache = SlabCache::for_type::<CapabilityEntry>("cap_entry");
+
 /// The kernel capability table.
 ///
 /// This is the single source of truth for all capabilities in the system.
 pub struct CapabilityTable {
     /// All capabilities, indexed by token
-    entries: Mutex<BTreeMap<CapabilityToken, CapabilityEntry>>,
+    entries: Mutex<BTreeMap<CapabilityToken, SlabBox<CapabilityEntry>>>,
     /// Capabilities by owner process
     by_owner: Mutex<BTreeMap<ProcessId, Vec<CapabilityToken>>>,
     /// Audit log of capability operations
@@ -467,7 +472,7 @@ impl CapabilityTable {
         self.entries
             .lock()
             .get(token)
-            .cloned()
+            .map(|e| CapabilityEntry::clone(e))
             .ok_or(CapError::TokenNotFound)
     }
 
@@ -479,6 +484,7 @@ impl CapabilityTable {
 
         let token = entry.token;
         let owner = entry.owner;
+        let entry = SlabBox::new(entry, &CAP_ENTRY_CACHE).ok_or(CapError::TableFull)?;
         entries.insert(token, entry);
 
         // Update owner index
@@ -533,6 +539,25 @@ impl CapabilityTable {
     }
 }
 
+/// The table of the running kernel, for system calls that take a token.
+static KERNEL_TABLE: AtomicPtr<CapabilityTable> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Registers the kernel's capability table.
+///
+/// # Safety
+///
+/// `table` must stay in place for as long as the kernel runs.
+pub unsafe fn register_kernel_table(table: &CapabilityTable) {
+    KERNEL_TABLE.store(table as *const CapabilityTable as *mut CapabilityTable, Ordering::Release);
+}
+
+/// The kernel's capability table, once registered.
+pub fn kernel_table() -> Option<&'static CapabilityTable> {
+    let table = KERNEL_TABLE.load(Ordering::Acquire);
+    // SAFETY: `register_kernel_table` requires the table to outlive the kernel
+    unsafe { table.as_ref() }
+}
+
 /// Capability errors.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum CapError {
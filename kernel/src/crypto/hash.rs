@@ -7,6 +7,7 @@
 //! - **SHA-256**: Secure hash, 256-bit output
 //! - **SHA-512**: Secure hash, 512-bit output  
 //! - **XXHash3**: Fast non-cryptographic hash (for checksums)
+//! - **CRC-32C / CRC-16**: On-disk metadata checksums of ext4 and jbd2
 
 use alloc::vec::Vec;
 
@@ -664,6 +665,26 @@ impl Hash for XxHash3 {
     }
 }
 
+// ============================================================================
+// CRC-32C / CRC-16 (on-disk checksums)
+// ============================================================================
+
+/// Continues a CRC-32C over `data`; shared with SplaxFS and the host tools.
+pub use splax_crypto::crc32c;
+
+/// Continues a CRC-16 (ANSI, reflected polynomial 0xA001) over `data`.
+///
+/// Used by the older `gdt_csum` ext4 group descriptor checksum.
+pub fn crc16(crc: u16, data: &[u8]) -> u16 {
+    data.iter().fold(crc, |mut crc, &byte| {
+        crc ^= byte as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xA001 } else { crc >> 1 };
+        }
+        crc
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -685,4 +706,13 @@ mod tests {
         let hash = Sha256::hash(b"hello");
         assert_eq!(hash.len(), 32);
     }
+
+    #[test]
+    fn test_crc_check_values() {
+        // Standard "123456789" check values
+        assert_eq!(!crc32c(!0, b"123456789"), 0xE306_9283);
+        assert_eq!(crc16(0, b"123456789"), 0xBB3D);
+        // Chaining equals a single pass
+        assert_eq!(crc32c(crc32c(!0, b"1234"), b"56789"), crc32c(!0, b"123456789"));
+    }
 }
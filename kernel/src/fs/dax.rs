@@ -0,0 +1,102 @@
+//! # Direct Access (DAX)
+//!
+//! File I/O for filesystems whose data lives in persistent memory. Such a
+//! filesystem reports [`Filesystem::is_dax`] and resolves file pages to
+//! physical pmem addresses with [`Filesystem::dax_page`].
+//!
+//! ## Design
+//!
+//! ```text
+//!   read()/write()              mmap fault
+//!        │                          │
+//!        ▼                          ▼
+//!   Filesystem::read/write     DaxFile::get_page
+//!        │                          │ Filesystem::dax_page
+//!        ▼                          ▼
+//!   pmem block device  ──────  pmem frame mapped as is
+//! ```
+//!
+//! - The page cache is bypassed: the media is as fast as DRAM, so caching
+//!   would only add a copy and a second place for data to be dirty.
+//! - Mappings point at the pmem itself. Pages are owned by the filesystem,
+//!   so nothing is pinned or freed.
+//! - Stores through a mapping sit in the CPU caches until `msync`, which
+//!   writes back the pages marked dirty and fences (see [`crate::mm::pmem`]).
+//!   `fsync` is the fence alone, as `write()` already flushes what it copies.
+
+use alloc::collections::BTreeSet;
+use alloc::sync::Arc;
+use spin::Mutex;
+
+use super::vfs::{InodeNum, MountPoint, VfsError};
+use crate::mm::vm::{Pager, VmError};
+use crate::mm::{pmem, PAGE_SIZE};
+
+/// Reads file data straight from the filesystem. Returns the bytes read.
+pub fn read(mount: &MountPoint, ino: InodeNum, offset: u64, buf: &mut [u8]) -> Result<usize, VfsError> {
+    let data = mount.fs.read(ino, offset, buf.len())?;
+    let len = data.len().min(buf.len());
+    buf[..len].copy_from_slice(&data[..len]);
+    Ok(len)
+}
+
+/// Writes file data straight to the filesystem. Returns the bytes written.
+pub fn write(mount: &MountPoint, ino: InodeNum, offset: u64, data: &[u8]) -> Result<usize, VfsError> {
+    mount.fs.write(ino, offset, data)
+}
+
+/// Returns the size of a file.
+pub fn size(mount: &MountPoint, ino: InodeNum) -> Result<u64, VfsError> {
+    Ok(mount.fs.getattr(ino)?.size)
+}
+
+/// Makes completed writes durable.
+pub fn fsync() {
+    pmem::drain();
+}
+
+/// A DAX file as seen by address spaces mapping it.
+pub struct DaxFile {
+    mount: Arc<MountPoint>,
+    ino: InodeNum,
+    /// Pages written through a mapping since the last flush
+    dirty: Mutex<BTreeSet<u64>>,
+}
+
+impl DaxFile {
+    /// Creates a pager for an inode of a DAX mount.
+    pub fn new(mount: Arc<MountPoint>, ino: InodeNum) -> Self {
+        Self {
+            mount,
+            ino,
+            dirty: Mutex::new(BTreeSet::new()),
+        }
+    }
+}
+
+impl Pager for DaxFile {
+    fn get_page(&self, index: u64) -> Result<u64, VmError> {
+        self.mount.fs.dax_page(self.ino, index).map_err(|e| match e {
+            VfsError::NoSpace => VmError::OutOfMemory,
+            _ => VmError::PagerError,
+        })
+    }
+
+    fn put_page(&self, _index: u64) {}
+
+    fn set_dirty(&self, index: u64) {
+        self.dirty.lock().insert(index);
+    }
+
+    fn flush(&self) {
+        let dirty = core::mem::take(&mut *self.dirty.lock());
+        for index in dirty {
+            match self.mount.fs.dax_page(self.ino, index) {
+                // pmem is identity mapped like the rest of physical memory
+                Ok(addr) => unsafe { pmem::flush_range(addr, PAGE_SIZE) },
+                Err(e) => crate::serial_println!("[dax] msync of page {} failed: {:?}", index, e),
+            }
+        }
+        pmem::drain();
+    }
+}
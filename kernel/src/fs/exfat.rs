@@ -0,0 +1,2000 @@
+//! # exFAT Filesystem
+//!
+//! exFAT implementation for Splax OS, compatible with volumes made by
+//! Linux `mkfs.exfat` (exfatprogs) and Windows. SDXC cards and USB drives
+//! over 32 GB ship formatted as exFAT.
+//!
+//! ## Features
+//!
+//! - Boot region checksum and geometry validation
+//! - Allocation bitmap, kept in memory and written through
+//! - Up-case table for case-insensitive lookups and name hashes
+//! - Long UTF-16 names (up to 255 code units) in checksummed directory
+//!   entry sets
+//! - Contiguous files without a FAT chain (`NoFatChain`), switched to a
+//!   chain when they can no longer grow in place
+//! - Valid data length: space past it reads as zeros
+//! - File read/write, truncation, create, unlink and rename
+//!
+//! ## Design
+//!
+//! exFAT has no inodes: a file is its entry set in the parent directory.
+//! Inode numbers are handed out as entry sets are first looked up and stay
+//! stable for the mount; each is backed by a copy of its entry set, written
+//! back whenever its size, clusters or times change. All operations run
+//! under one lock.
+//!
+//! Clusters are allocated next to a file's last cluster when possible, so
+//! files written sequentially stay contiguous. The volume is marked dirty
+//! while mounted read-write, so Windows and `fsck.exfat` check it after a
+//! crash; writes are not journaled.
+//!
+//! ## Limitations
+//!
+//! - TexFAT volumes (two FATs) mount read-only
+//! - No symlinks, hard links or extended attributes; of the attributes,
+//!   only read-only and directory are interpreted
+//! - Timestamps are written in UTC from the RTC
+
+use alloc::collections::btree_map::Entry;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::{Mutex, RwLock};
+
+use crate::block::{BlockDevice, SECTOR_SIZE};
+use crate::fs::vfs::{
+    Filesystem, InodeNum, VfsAttr, VfsDirEntry, VfsError, VfsFileType, VfsPermissions, VfsStatFs, VFS,
+};
+
+/// File system name in the boot sector.
+const EXFAT_NAME: &[u8; 8] = b"EXFAT   ";
+
+/// Boot sector signature.
+const BOOT_SIGNATURE: u16 = 0xAA55;
+
+/// Boot sector field offsets.
+const BS_MUST_BE_ZERO: usize = 11;
+const BS_MUST_BE_ZERO_LEN: usize = 53;
+const BS_VOLUME_LENGTH: usize = 72;
+const BS_FAT_OFFSET: usize = 80;
+const BS_FAT_LENGTH: usize = 84;
+const BS_HEAP_OFFSET: usize = 88;
+const BS_CLUSTER_COUNT: usize = 92;
+const BS_ROOT_CLUSTER: usize = 96;
+const BS_REVISION_MAJOR: usize = 105;
+const BS_VOLUME_FLAGS: usize = 106;
+const BS_SECTOR_SHIFT: usize = 108;
+const BS_CLUSTER_SHIFT: usize = 109;
+const BS_NUMBER_OF_FATS: usize = 110;
+const BS_PERCENT_IN_USE: usize = 112;
+
+/// Sectors covered by the boot region checksum, which fills the sector
+/// after them.
+const BOOT_CHECKSUM_SECTORS: usize = 11;
+
+/// Volume flags.
+const VOLUME_ACTIVE_FAT: u16 = 0x0001;
+const VOLUME_DIRTY: u16 = 0x0002;
+
+/// End of a cluster chain.
+const FAT_END: u32 = 0xFFFF_FFFF;
+
+/// Number of the first cluster of the cluster heap.
+const FIRST_CLUSTER: u32 = 2;
+
+/// FAT entries cached before the cache is dropped.
+const FAT_CACHE_LIMIT: usize = 64 * 1024;
+
+/// Directory entry size.
+const ENTRY_SIZE: usize = 32;
+
+/// Largest directory.
+const MAX_DIR_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Directory entry types.
+const ENTRY_END: u8 = 0x00;
+const ENTRY_IN_USE: u8 = 0x80;
+const ENTRY_BITMAP: u8 = 0x81;
+const ENTRY_UPCASE: u8 = 0x82;
+const ENTRY_FILE: u8 = 0x85;
+const ENTRY_STREAM: u8 = 0xC0;
+const ENTRY_NAME: u8 = 0xC1;
+/// Type bits every secondary entry in use has.
+const ENTRY_SECONDARY: u8 = 0xC0;
+
+/// File entry field offsets.
+const FILE_SECONDARY_COUNT: usize = 1;
+const FILE_CHECKSUM: usize = 2;
+const FILE_ATTRIBUTES: usize = 4;
+const FILE_CREATED: usize = 8;
+const FILE_MODIFIED: usize = 12;
+const FILE_ACCESSED: usize = 16;
+const FILE_CREATED_10MS: usize = 20;
+const FILE_MODIFIED_10MS: usize = 21;
+const FILE_CREATED_UTC: usize = 22;
+const FILE_MODIFIED_UTC: usize = 23;
+const FILE_ACCESSED_UTC: usize = 24;
+
+/// Stream extension field offsets.
+const STREAM_FLAGS: usize = 1;
+const STREAM_NAME_LENGTH: usize = 3;
+const STREAM_NAME_HASH: usize = 4;
+const STREAM_VALID_LENGTH: usize = 8;
+
+/// First cluster and data length of stream extension, allocation bitmap
+/// and up-case table entries.
+const ENTRY_FIRST_CLUSTER: usize = 20;
+const ENTRY_DATA_LENGTH: usize = 24;
+
+/// Allocation bitmap entry flags: which FAT the bitmap goes with.
+const BITMAP_FLAGS: usize = 1;
+/// Up-case table entry checksum.
+const UPCASE_CHECKSUM: usize = 4;
+
+/// File attributes.
+const ATTR_READ_ONLY: u16 = 0x01;
+const ATTR_DIRECTORY: u16 = 0x10;
+const ATTR_ARCHIVE: u16 = 0x20;
+
+/// Stream extension flags.
+const STREAM_ALLOCATION_POSSIBLE: u8 = 0x01;
+const STREAM_NO_FAT_CHAIN: u8 = 0x02;
+
+/// Name characters per file name entry.
+const NAME_CHARS_PER_ENTRY: usize = 15;
+
+/// Longest file name, in UTF-16 code units.
+const EXFAT_NAME_LEN: usize = 255;
+
+/// Timestamp UTC offset field: valid, zero offset.
+const UTC: u8 = 0x80;
+
+/// Root directory pseudo-inode.
+const EXFAT_ROOT_INO: InodeNum = 1;
+
+fn le16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn le32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+fn le64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn put_le16(data: &mut [u8], offset: usize, value: u16) {
+    data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_le32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_le64(data: &mut [u8], offset: usize, value: u64) {
+    data[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Boot region checksum of sectors 0-10. The volume flags and percent in
+/// use change without it being updated, so they are skipped.
+fn boot_checksum(region: &[u8]) -> u32 {
+    region.iter().enumerate().fold(0u32, |sum, (i, &byte)| {
+        if i == BS_VOLUME_FLAGS || i == BS_VOLUME_FLAGS + 1 || i == BS_PERCENT_IN_USE {
+            sum
+        } else {
+            sum.rotate_right(1).wrapping_add(byte as u32)
+        }
+    })
+}
+
+/// Up-case table checksum.
+fn table_checksum(table: &[u8]) -> u32 {
+    table.iter().fold(0u32, |sum, &byte| sum.rotate_right(1).wrapping_add(byte as u32))
+}
+
+/// Entry set checksum, skipping the checksum field itself.
+fn set_checksum(set: &[u8]) -> u16 {
+    set.iter().enumerate().fold(0u16, |sum, (i, &byte)| {
+        if i == FILE_CHECKSUM || i == FILE_CHECKSUM + 1 {
+            sum
+        } else {
+            sum.rotate_right(1).wrapping_add(byte as u16)
+        }
+    })
+}
+
+/// Name hash over an up-cased name.
+fn name_hash(upcased: &[u16]) -> u16 {
+    upcased
+        .iter()
+        .flat_map(|c| c.to_le_bytes())
+        .fold(0u16, |hash, byte| hash.rotate_right(1).wrapping_add(byte as u16))
+}
+
+/// Decodes an up-case table, either plain or compressed (a run of
+/// characters mapping to themselves is 0xFFFF and its length). Characters
+/// past the end of the table map to themselves.
+fn decode_upcase(table: &[u8]) -> Vec<u16> {
+    let mut upcase: Vec<u16> = (0..=u16::MAX).collect();
+    let mut index = 0usize;
+    let mut units = table.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]]));
+    while index < upcase.len() {
+        match units.next() {
+            Some(0xFFFF) => match units.next() {
+                Some(run) => index += run as usize,
+                None => break,
+            },
+            Some(unit) => {
+                upcase[index] = unit;
+                index += 1;
+            }
+            None => break,
+        }
+    }
+    upcase
+}
+
+/// Encodes a name as UTF-16, refusing names exFAT cannot hold.
+fn encode_name(name: &str) -> Result<Vec<u16>, VfsError> {
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(VfsError::InvalidArgument);
+    }
+    let units: Vec<u16> = name.encode_utf16().collect();
+    if units.len() > EXFAT_NAME_LEN {
+        return Err(VfsError::PathTooLong);
+    }
+    let forbidden = |c: u16| c < 0x20 || matches!(c, 0x22 | 0x2A | 0x2F | 0x3A | 0x3C | 0x3E | 0x3F | 0x5C | 0x7C);
+    if units.iter().any(|&c| forbidden(c)) {
+        return Err(VfsError::InvalidArgument);
+    }
+    Ok(units)
+}
+
+/// Days from 1970-01-01 to a date of the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Packs a date and time into a timestamp and its 10 ms increment.
+fn encode_time(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> (u32, u8) {
+    let stamp = ((year.saturating_sub(1980).min(127) as u32) << 25)
+        | ((month as u32) << 21)
+        | ((day as u32) << 16)
+        | ((hour as u32) << 11)
+        | ((minute as u32) << 5)
+        | (second as u32 / 2);
+    (stamp, (second % 2) * 100)
+}
+
+/// Unix time of a timestamp, its 10 ms increment and UTC offset field.
+fn unix_time(stamp: u32, increment: u8, utc: u8) -> u64 {
+    let year = (stamp >> 25) as i64 + 1980;
+    let month = ((stamp >> 21) & 0xF).clamp(1, 12);
+    let day = ((stamp >> 16) & 0x1F).max(1);
+    let seconds = ((stamp >> 11) & 0x1F) as i64 * 3600
+        + ((stamp >> 5) & 0x3F) as i64 * 60
+        + (stamp & 0x1F) as i64 * 2
+        + (increment / 100) as i64;
+    // Signed 7-bit offset in 15 minute steps, valid if the top bit is set
+    let offset = if utc & UTC != 0 { (((utc << 1) as i8) >> 1) as i64 * 15 * 60 } else { 0 };
+    (days_from_civil(year, month, day) * 86400 + seconds - offset).max(0) as u64
+}
+
+/// Current time as a timestamp and 10 ms increment, in UTC.
+fn now() -> (u32, u8) {
+    #[cfg(target_arch = "x86_64")]
+    {
+        let time = crate::arch::x86_64::rtc::read_rtc();
+        encode_time(time.year, time.month, time.day, time.hour, time.minute, time.second)
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        encode_time(1980, 1, 1, 0, 0, 0)
+    }
+}
+
+/// Volume geometry from the boot sector, in bytes where it is an offset.
+#[derive(Debug, Clone, Copy)]
+struct Geometry {
+    /// Bytes per sector
+    sector_size: usize,
+    /// log2 of the cluster size in bytes
+    cluster_bits: u32,
+    /// Offset of the active FAT
+    fat_offset: u64,
+    /// Offset of the cluster heap
+    heap_offset: u64,
+    /// Clusters in the heap
+    cluster_count: u32,
+    /// First cluster of the root directory
+    root_cluster: u32,
+    /// Number of FATs (2 for TexFAT)
+    fats: u8,
+}
+
+impl Geometry {
+    /// Reads and checks the geometry in a boot sector.
+    fn parse(boot: &[u8]) -> Result<Self, VfsError> {
+        if &boot[3..11] != EXFAT_NAME || le16(boot, 510) != BOOT_SIGNATURE {
+            return Err(VfsError::InvalidArgument);
+        }
+        if boot[BS_MUST_BE_ZERO..BS_MUST_BE_ZERO + BS_MUST_BE_ZERO_LEN].iter().any(|&b| b != 0) {
+            return Err(VfsError::InvalidArgument);
+        }
+        if boot[BS_REVISION_MAJOR] != 1 {
+            crate::serial_println!("[exfat] Unsupported revision {}.{}", boot[BS_REVISION_MAJOR], boot[BS_REVISION_MAJOR - 1]);
+            return Err(VfsError::NotSupported);
+        }
+
+        let sector_shift = boot[BS_SECTOR_SHIFT] as u32;
+        let cluster_shift = boot[BS_CLUSTER_SHIFT] as u32;
+        let fats = boot[BS_NUMBER_OF_FATS];
+        if !(9..=12).contains(&sector_shift) || sector_shift + cluster_shift > 25 || !(1..=2).contains(&fats) {
+            return Err(VfsError::InvalidArgument);
+        }
+
+        let volume_length = le64(boot, BS_VOLUME_LENGTH);
+        let fat_offset = le32(boot, BS_FAT_OFFSET) as u64;
+        let fat_length = le32(boot, BS_FAT_LENGTH) as u64;
+        let heap_offset = le32(boot, BS_HEAP_OFFSET) as u64;
+        let cluster_count = le32(boot, BS_CLUSTER_COUNT);
+        let root_cluster = le32(boot, BS_ROOT_CLUSTER);
+        let valid = fat_offset >= 24
+            && heap_offset >= fat_offset + fat_length * fats as u64
+            && fat_length << sector_shift >= (cluster_count as u64 + 2) * 4
+            && heap_offset + ((cluster_count as u64) << cluster_shift) <= volume_length
+            && root_cluster >= FIRST_CLUSTER
+            && root_cluster - FIRST_CLUSTER < cluster_count;
+        if !valid {
+            return Err(VfsError::InvalidArgument);
+        }
+
+        let active = if fats == 2 && le16(boot, BS_VOLUME_FLAGS) & VOLUME_ACTIVE_FAT != 0 { 1 } else { 0 };
+        Ok(Self {
+            sector_size: 1 << sector_shift,
+            cluster_bits: sector_shift + cluster_shift,
+            fat_offset: (fat_offset + active * fat_length) << sector_shift,
+            heap_offset: heap_offset << sector_shift,
+            cluster_count,
+            root_cluster,
+            fats,
+        })
+    }
+
+    /// Cluster size in bytes.
+    fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    /// Clusters needed to hold `bytes`.
+    fn clusters_for(&self, bytes: u64) -> u64 {
+        bytes.div_ceil(self.cluster_size())
+    }
+
+    /// Byte offset of a cluster.
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.heap_offset + (((cluster - FIRST_CLUSTER) as u64) << self.cluster_bits)
+    }
+
+    /// Whether `cluster` lies in the cluster heap.
+    fn is_cluster(&self, cluster: u32) -> bool {
+        cluster >= FIRST_CLUSTER && cluster - FIRST_CLUSTER < self.cluster_count
+    }
+}
+
+/// Clusters of a file, directory or system structure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Stream {
+    /// First cluster, 0 when there is none
+    first: u32,
+    /// Number of clusters
+    clusters: u32,
+    /// Clusters are consecutive and have no FAT chain
+    contiguous: bool,
+}
+
+impl Stream {
+    /// A stream with no clusters.
+    const EMPTY: Self = Self { first: 0, clusters: 0, contiguous: false };
+}
+
+/// A file's directory entry set: the File entry, its Stream Extension,
+/// the File Name entries and any other secondary entries, in disk order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct EntrySet {
+    entries: Vec<[u8; ENTRY_SIZE]>,
+}
+
+impl EntrySet {
+    /// A new, empty entry set stamped with the current time.
+    fn new(name: &[u16], hash: u16, attributes: u16) -> Self {
+        let (stamp, increment) = now();
+        let mut file = [0u8; ENTRY_SIZE];
+        file[0] = ENTRY_FILE;
+        put_le16(&mut file, FILE_ATTRIBUTES, attributes);
+        for field in [FILE_CREATED, FILE_MODIFIED, FILE_ACCESSED] {
+            put_le32(&mut file, field, stamp);
+        }
+        file[FILE_CREATED_10MS] = increment;
+        file[FILE_MODIFIED_10MS] = increment;
+        file[FILE_CREATED_UTC..=FILE_ACCESSED_UTC].fill(UTC);
+
+        let mut stream = [0u8; ENTRY_SIZE];
+        stream[0] = ENTRY_STREAM;
+        stream[STREAM_FLAGS] = STREAM_ALLOCATION_POSSIBLE;
+
+        let mut set = Self { entries: vec![file, stream] };
+        set.set_name(name, hash);
+        set
+    }
+
+    /// Reads the entry set at the start of `data`, if it is a valid one.
+    fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < 3 * ENTRY_SIZE || data[0] != ENTRY_FILE {
+            return None;
+        }
+        let count = 1 + data[FILE_SECONDARY_COUNT] as usize;
+        if !(3..=19).contains(&count) || data.len() < count * ENTRY_SIZE {
+            return None;
+        }
+        let entries: Vec<[u8; ENTRY_SIZE]> =
+            data[..count * ENTRY_SIZE].chunks_exact(ENTRY_SIZE).map(|e| e.try_into().unwrap()).collect();
+
+        let name_length = entries[1][STREAM_NAME_LENGTH] as usize;
+        let name_entries = name_length.div_ceil(NAME_CHARS_PER_ENTRY);
+        let valid = entries[1][0] == ENTRY_STREAM
+            && name_length > 0
+            && count >= 2 + name_entries
+            && entries[2..2 + name_entries].iter().all(|e| e[0] == ENTRY_NAME)
+            && entries[1..].iter().all(|e| e[0] & ENTRY_SECONDARY == ENTRY_SECONDARY)
+            && set_checksum(&data[..count * ENTRY_SIZE]) == le16(data, FILE_CHECKSUM);
+        valid.then_some(Self { entries })
+    }
+
+    /// Number of directory entries in the set.
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn attributes(&self) -> u16 {
+        le16(&self.entries[0], FILE_ATTRIBUTES)
+    }
+
+    fn is_dir(&self) -> bool {
+        self.attributes() & ATTR_DIRECTORY != 0
+    }
+
+    fn file_type(&self) -> VfsFileType {
+        if self.is_dir() {
+            VfsFileType::Directory
+        } else {
+            VfsFileType::Regular
+        }
+    }
+
+    /// The name, as stored.
+    fn name(&self) -> Vec<u16> {
+        let length = self.entries[1][STREAM_NAME_LENGTH] as usize;
+        self.entries[2..]
+            .iter()
+            .take_while(|e| e[0] == ENTRY_NAME)
+            .flat_map(|e| (0..NAME_CHARS_PER_ENTRY).map(move |i| le16(e, 2 + 2 * i)))
+            .take(length)
+            .collect()
+    }
+
+    fn name_hash(&self) -> u16 {
+        le16(&self.entries[1], STREAM_NAME_HASH)
+    }
+
+    /// Replaces the name, keeping secondary entries other than names.
+    fn set_name(&mut self, name: &[u16], hash: u16) {
+        let others: Vec<[u8; ENTRY_SIZE]> = self.entries[2..].iter().filter(|e| e[0] != ENTRY_NAME).copied().collect();
+        self.entries.truncate(2);
+        for chunk in name.chunks(NAME_CHARS_PER_ENTRY) {
+            let mut entry = [0u8; ENTRY_SIZE];
+            entry[0] = ENTRY_NAME;
+            for (i, &c) in chunk.iter().enumerate() {
+                put_le16(&mut entry, 2 + 2 * i, c);
+            }
+            self.entries.push(entry);
+        }
+        self.entries.extend(others);
+        self.entries[0][FILE_SECONDARY_COUNT] = (self.entries.len() - 1) as u8;
+        self.entries[1][STREAM_NAME_LENGTH] = name.len() as u8;
+        put_le16(&mut self.entries[1], STREAM_NAME_HASH, hash);
+    }
+
+    /// Size of the data in bytes.
+    fn data_length(&self) -> u64 {
+        le64(&self.entries[1], ENTRY_DATA_LENGTH)
+    }
+
+    /// Bytes of the data that have been written; the rest reads as zeros.
+    fn valid_length(&self) -> u64 {
+        le64(&self.entries[1], STREAM_VALID_LENGTH).min(self.data_length())
+    }
+
+    /// Clusters of the data.
+    fn stream(&self, geometry: &Geometry) -> Stream {
+        let clusters = geometry.clusters_for(self.data_length()) as u32;
+        let first = le32(&self.entries[1], ENTRY_FIRST_CLUSTER);
+        if clusters == 0 || first == 0 {
+            return Stream::EMPTY;
+        }
+        Stream { first, clusters, contiguous: self.entries[1][STREAM_FLAGS] & STREAM_NO_FAT_CHAIN != 0 }
+    }
+
+    /// Records the clusters and lengths of the data.
+    fn set_stream(&mut self, stream: Stream, data_length: u64, valid_length: u64) {
+        let entry = &mut self.entries[1];
+        entry[STREAM_FLAGS] = STREAM_ALLOCATION_POSSIBLE;
+        if stream.contiguous && stream.clusters > 0 {
+            entry[STREAM_FLAGS] |= STREAM_NO_FAT_CHAIN;
+        }
+        put_le32(entry, ENTRY_FIRST_CLUSTER, if stream.clusters > 0 { stream.first } else { 0 });
+        put_le64(entry, ENTRY_DATA_LENGTH, data_length);
+        put_le64(entry, STREAM_VALID_LENGTH, valid_length);
+    }
+
+    /// Stamps the modification and access times, and marks a file for
+    /// archiving.
+    fn touch(&mut self) {
+        let (stamp, increment) = now();
+        let file = &mut self.entries[0];
+        put_le32(file, FILE_MODIFIED, stamp);
+        put_le32(file, FILE_ACCESSED, stamp);
+        file[FILE_MODIFIED_10MS] = increment;
+        file[FILE_MODIFIED_UTC] = UTC;
+        file[FILE_ACCESSED_UTC] = UTC;
+        if le16(file, FILE_ATTRIBUTES) & ATTR_DIRECTORY == 0 {
+            let attributes = le16(file, FILE_ATTRIBUTES) | ATTR_ARCHIVE;
+            put_le16(file, FILE_ATTRIBUTES, attributes);
+        }
+    }
+
+    /// Creation, modification and access times, as Unix time.
+    fn times(&self) -> (u64, u64, u64) {
+        let file = &self.entries[0];
+        (
+            unix_time(le32(file, FILE_CREATED), file[FILE_CREATED_10MS], file[FILE_CREATED_UTC]),
+            unix_time(le32(file, FILE_MODIFIED), file[FILE_MODIFIED_10MS], file[FILE_MODIFIED_UTC]),
+            unix_time(le32(file, FILE_ACCESSED), 0, file[FILE_ACCESSED_UTC]),
+        )
+    }
+
+    /// The entries as stored, with the checksum filled in.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.entries.concat();
+        let checksum = set_checksum(&bytes);
+        put_le16(&mut bytes, FILE_CHECKSUM, checksum);
+        bytes
+    }
+}
+
+/// The valid entry sets of a directory and the slot each starts at. Sets
+/// failing their checksum are skipped.
+fn parse_sets(data: &[u8]) -> Vec<(u32, EntrySet)> {
+    let mut sets = Vec::new();
+    let mut slot = 0;
+    while slot * ENTRY_SIZE < data.len() {
+        match data[slot * ENTRY_SIZE] {
+            ENTRY_END => break,
+            ENTRY_FILE => {
+                if let Some(set) = EntrySet::parse(&data[slot * ENTRY_SIZE..]) {
+                    let len = set.len();
+                    sets.push((slot as u32, set));
+                    slot += len;
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        slot += 1;
+    }
+    sets
+}
+
+/// First slot of a run of `count` unused slots in a directory. Everything
+/// after the end marker is unused, so the run may extend past `data`.
+fn find_free(data: &[u8], count: usize) -> usize {
+    let slots = data.len() / ENTRY_SIZE;
+    let mut run = 0;
+    for slot in 0..slots {
+        let entry_type = data[slot * ENTRY_SIZE];
+        if entry_type == ENTRY_END {
+            return slot - run;
+        }
+        if entry_type & ENTRY_IN_USE != 0 {
+            run = 0;
+            continue;
+        }
+        run += 1;
+        if run == count {
+            return slot + 1 - count;
+        }
+    }
+    slots - run
+}
+
+/// A file or directory below the root.
+#[derive(Debug, Clone)]
+struct Node {
+    /// Directory holding the entry set
+    parent: InodeNum,
+    /// Slot of the entry set in the parent
+    slot: u32,
+    /// The entry set, as on disk
+    set: EntrySet,
+}
+
+/// A mounted volume.
+struct Volume {
+    device: Arc<dyn BlockDevice + Send + Sync>,
+    geometry: Geometry,
+    /// Whether writes are refused
+    read_only: bool,
+    /// Volume flags as on disk
+    flags: u16,
+    /// Up-case table, one entry per UTF-16 code unit
+    upcase: Vec<u16>,
+    /// Allocation bitmap, one bit per cluster
+    bitmap: Vec<u8>,
+    /// Clusters of the allocation bitmap
+    bitmap_stream: Stream,
+    /// Free clusters
+    free: u32,
+    /// Next cluster to try allocating from
+    hint: u32,
+    /// FAT entries read so far
+    fat: BTreeMap<u32, u32>,
+    /// Last cluster looked up in each chain, by first cluster: the index
+    /// in the chain and the cluster
+    cursors: BTreeMap<u32, (u32, u32)>,
+    /// Clusters of the root directory
+    root: Stream,
+    /// Files and directories looked up so far
+    nodes: BTreeMap<InodeNum, Node>,
+    /// Inode number of each entry set seen, by directory and slot
+    slots: BTreeMap<(InodeNum, u32), InodeNum>,
+    /// Next inode number to hand out
+    next_ino: InodeNum,
+}
+
+impl Volume {
+    /// Reads the boot region, allocation bitmap and up-case table.
+    fn open(device: Arc<dyn BlockDevice + Send + Sync>) -> Result<Self, VfsError> {
+        let mut boot = vec![0u8; SECTOR_SIZE];
+        read_bytes(&*device, 0, &mut boot)?;
+        let geometry = Geometry::parse(&boot)?;
+
+        let sector = geometry.sector_size;
+        let mut region = vec![0u8; (BOOT_CHECKSUM_SECTORS + 1) * sector];
+        read_bytes(&*device, 0, &mut region)?;
+        let checksum = boot_checksum(&region[..BOOT_CHECKSUM_SECTORS * sector]);
+        if region[BOOT_CHECKSUM_SECTORS * sector..].chunks_exact(4).any(|c| le32(c, 0) != checksum) {
+            crate::serial_println!("[exfat] Boot region checksum mismatch");
+            return Err(VfsError::IoError);
+        }
+
+        let flags = le16(&region, BS_VOLUME_FLAGS);
+        if flags & VOLUME_DIRTY != 0 {
+            crate::serial_println!("[exfat] Volume was not cleanly unmounted; check it with fsck.exfat");
+        }
+        let read_only = device.info().read_only || geometry.fats != 1;
+
+        let mut volume = Self {
+            device,
+            geometry,
+            read_only,
+            flags,
+            upcase: Vec::new(),
+            bitmap: Vec::new(),
+            bitmap_stream: Stream::EMPTY,
+            free: 0,
+            hint: FIRST_CLUSTER,
+            fat: BTreeMap::new(),
+            cursors: BTreeMap::new(),
+            root: Stream { first: geometry.root_cluster, clusters: 0, contiguous: false },
+            nodes: BTreeMap::new(),
+            slots: BTreeMap::new(),
+            next_ino: EXFAT_ROOT_INO + 1,
+        };
+
+        // The root directory is always a FAT chain
+        let mut cluster = geometry.root_cluster;
+        loop {
+            volume.root.clusters += 1;
+            match volume.fat_entry(cluster)? {
+                FAT_END => break,
+                next if geometry.is_cluster(next) && volume.root.clusters < geometry.cluster_count => cluster = next,
+                _ => {
+                    crate::serial_println!("[exfat] Broken root directory chain at cluster {}", cluster);
+                    return Err(VfsError::IoError);
+                }
+            }
+        }
+
+        let root = volume.read_dir(volume.root)?;
+        let active = if geometry.fats == 2 && flags & VOLUME_ACTIVE_FAT != 0 { 1 } else { 0 };
+        let mut upcase = None;
+        for entry in root.chunks_exact(ENTRY_SIZE) {
+            let stream = |length: u64| Stream {
+                first: le32(entry, ENTRY_FIRST_CLUSTER),
+                clusters: geometry.clusters_for(length) as u32,
+                contiguous: false,
+            };
+            let length = le64(entry, ENTRY_DATA_LENGTH);
+            match entry[0] {
+                ENTRY_END => break,
+                ENTRY_BITMAP if entry[BITMAP_FLAGS] & 1 == active => {
+                    if length < (geometry.cluster_count as u64).div_ceil(8) {
+                        return Err(VfsError::IoError);
+                    }
+                    volume.bitmap_stream = stream(length);
+                    let mut bitmap = vec![0u8; (geometry.cluster_count as usize).div_ceil(8)];
+                    volume.read_stream(volume.bitmap_stream, 0, &mut bitmap)?;
+                    volume.bitmap = bitmap;
+                }
+                ENTRY_UPCASE => {
+                    let mut table = vec![0u8; length.min(2 * 0x10000 + 4) as usize];
+                    volume.read_stream(stream(length), 0, &mut table)?;
+                    if table_checksum(&table) != le32(entry, UPCASE_CHECKSUM) {
+                        crate::serial_println!("[exfat] Up-case table checksum mismatch");
+                        return Err(VfsError::IoError);
+                    }
+                    upcase = Some(decode_upcase(&table));
+                }
+                _ => {}
+            }
+        }
+        if volume.bitmap.is_empty() {
+            crate::serial_println!("[exfat] No allocation bitmap");
+            return Err(VfsError::IoError);
+        }
+        volume.upcase = upcase.ok_or_else(|| {
+            crate::serial_println!("[exfat] No up-case table");
+            VfsError::IoError
+        })?;
+        volume.free = (0..geometry.cluster_count).filter(|&i| !volume.in_use(i)).count() as u32;
+
+        if !volume.read_only {
+            volume.set_flags(volume.flags | VOLUME_DIRTY)?;
+        }
+        Ok(volume)
+    }
+
+    /// Marks the volume clean and refuses further writes.
+    fn unmount(&mut self) -> Result<(), VfsError> {
+        if self.read_only {
+            return Ok(());
+        }
+        let count = self.geometry.cluster_count as u64;
+        let percent = ((count - self.free as u64) * 100).checked_div(count).unwrap_or(0) as u8;
+        self.write_bytes(BS_PERCENT_IN_USE as u64, &[percent])?;
+        self.set_flags(self.flags & !VOLUME_DIRTY)?;
+        self.device.flush().map_err(|_| VfsError::IoError)?;
+        self.read_only = true;
+        Ok(())
+    }
+
+    /// Writes the volume flags of the main boot sector.
+    fn set_flags(&mut self, flags: u16) -> Result<(), VfsError> {
+        self.write_bytes(BS_VOLUME_FLAGS as u64, &flags.to_le_bytes())?;
+        self.flags = flags;
+        Ok(())
+    }
+
+    fn check_writable(&self) -> Result<(), VfsError> {
+        if self.read_only {
+            Err(VfsError::ReadOnlyFs)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn read_bytes(&self, offset: u64, buffer: &mut [u8]) -> Result<(), VfsError> {
+        read_bytes(&*self.device, offset, buffer)
+    }
+
+    /// Writes bytes in place, reading back the sectors they partly cover.
+    fn write_bytes(&self, offset: u64, data: &[u8]) -> Result<(), VfsError> {
+        let sector = offset / SECTOR_SIZE as u64;
+        let head = (offset % SECTOR_SIZE as u64) as usize;
+        let len = (head + data.len()).div_ceil(SECTOR_SIZE) * SECTOR_SIZE;
+        let mut buffer = vec![0u8; len];
+        if head != 0 {
+            self.read_bytes(sector * SECTOR_SIZE as u64, &mut buffer[..SECTOR_SIZE])?;
+        }
+        if !(head + data.len()).is_multiple_of(SECTOR_SIZE) {
+            let last = len - SECTOR_SIZE;
+            self.read_bytes((sector * SECTOR_SIZE as u64) + last as u64, &mut buffer[last..])?;
+        }
+        buffer[head..head + data.len()].copy_from_slice(data);
+        self.device.write_sectors(sector, &buffer).map_err(|_| VfsError::IoError)
+    }
+
+    // ------------------------------------------------------------------
+    // FAT and allocation bitmap
+    // ------------------------------------------------------------------
+
+    /// Reads a FAT entry, caching the whole FAT sector.
+    fn fat_entry(&mut self, cluster: u32) -> Result<u32, VfsError> {
+        if let Some(&next) = self.fat.get(&cluster) {
+            return Ok(next);
+        }
+        let offset = self.geometry.fat_offset + cluster as u64 * 4;
+        let sector_offset = offset - offset % SECTOR_SIZE as u64;
+        let mut sector = vec![0u8; SECTOR_SIZE];
+        self.read_bytes(sector_offset, &mut sector)?;
+        if self.fat.len() >= FAT_CACHE_LIMIT {
+            self.fat.clear();
+        }
+        let base = ((sector_offset - self.geometry.fat_offset) / 4) as u32;
+        for (i, raw) in sector.chunks_exact(4).enumerate() {
+            self.fat.entry(base + i as u32).or_insert(le32(raw, 0));
+        }
+        Ok(le32(&sector, (offset - sector_offset) as usize))
+    }
+
+    /// Writes FAT entries, one device write per FAT sector touched.
+    fn write_fat(&mut self, links: &[(u32, u32)]) -> Result<(), VfsError> {
+        let mut sectors: BTreeMap<u64, Vec<u8>> = BTreeMap::new();
+        for &(cluster, next) in links {
+            let offset = self.geometry.fat_offset + cluster as u64 * 4;
+            let sector = offset / SECTOR_SIZE as u64;
+            let buffer = match sectors.entry(sector) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    let mut buffer = vec![0u8; SECTOR_SIZE];
+                    self.read_bytes(sector * SECTOR_SIZE as u64, &mut buffer)?;
+                    entry.insert(buffer)
+                }
+            };
+            put_le32(buffer, (offset % SECTOR_SIZE as u64) as usize, next);
+            self.fat.insert(cluster, next);
+        }
+        for (sector, buffer) in sectors {
+            self.device.write_sectors(sector, &buffer).map_err(|_| VfsError::IoError)?;
+        }
+        Ok(())
+    }
+
+    /// Whether bit `index` of the allocation bitmap (cluster `index + 2`)
+    /// is set.
+    fn in_use(&self, index: u32) -> bool {
+        self.bitmap[index as usize / 8] & (1 << (index % 8)) != 0
+    }
+
+    /// Writes back the bitmap bytes holding the given bits.
+    fn write_bitmap(&mut self, indices: &[u32]) -> Result<(), VfsError> {
+        let (Some(low), Some(high)) = (indices.iter().min(), indices.iter().max()) else {
+            return Ok(());
+        };
+        let (start, end) = (*low as usize / 8, *high as usize / 8 + 1);
+        let bytes = self.bitmap[start..end].to_vec();
+        self.write_stream(self.bitmap_stream, start as u64, &bytes)
+    }
+
+    /// Allocates `count` clusters, searching from `hint` so that a file
+    /// growing at its end stays contiguous.
+    fn allocate(&mut self, count: u32, hint: u32) -> Result<Vec<u32>, VfsError> {
+        if count > self.free {
+            return Err(VfsError::NoSpace);
+        }
+        let total = self.geometry.cluster_count;
+        let mut index = if self.geometry.is_cluster(hint) { hint - FIRST_CLUSTER } else { 0 };
+        let mut found = Vec::with_capacity(count as usize);
+        let mut scanned = 0;
+        while found.len() < count as usize && scanned < total {
+            if index % 8 == 0 && index + 8 <= total && self.bitmap[index as usize / 8] == 0xFF {
+                index = (index + 8) % total;
+                scanned += 8;
+                continue;
+            }
+            if !self.in_use(index) {
+                found.push(index);
+            }
+            index = (index + 1) % total;
+            scanned += 1;
+        }
+        if found.len() < count as usize {
+            return Err(VfsError::NoSpace);
+        }
+        for &i in &found {
+            self.bitmap[i as usize / 8] |= 1 << (i % 8);
+        }
+        self.write_bitmap(&found)?;
+        self.free -= count;
+        self.hint = index + FIRST_CLUSTER;
+        Ok(found.into_iter().map(|i| i + FIRST_CLUSTER).collect())
+    }
+
+    /// Returns clusters to the allocation bitmap.
+    fn release(&mut self, clusters: &[u32]) -> Result<(), VfsError> {
+        let indices: Vec<u32> = clusters.iter().map(|&c| c - FIRST_CLUSTER).collect();
+        for &i in &indices {
+            self.bitmap[i as usize / 8] &= !(1 << (i % 8));
+        }
+        self.write_bitmap(&indices)?;
+        self.free += clusters.len() as u32;
+        Ok(())
+    }
+
+    // ------------------------------------------------------------------
+    // Streams
+    // ------------------------------------------------------------------
+
+    /// Clusters `from..from + count` of a stream.
+    fn clusters(&mut self, stream: Stream, from: u32, count: u32) -> Result<Vec<u32>, VfsError> {
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        if from + count > stream.clusters {
+            return Err(VfsError::IoError);
+        }
+        if stream.contiguous {
+            return Ok((stream.first + from..stream.first + from + count).collect());
+        }
+
+        let (mut index, mut cluster) = match self.cursors.get(&stream.first) {
+            Some(&(index, cluster)) if index <= from => (index, cluster),
+            _ => (0, stream.first),
+        };
+        let mut clusters = Vec::with_capacity(count as usize);
+        loop {
+            if index >= from {
+                clusters.push(cluster);
+                if clusters.len() == count as usize {
+                    break;
+                }
+            }
+            let next = self.fat_entry(cluster)?;
+            if !self.geometry.is_cluster(next) {
+                crate::serial_println!("[exfat] Broken cluster chain at cluster {}", cluster);
+                return Err(VfsError::IoError);
+            }
+            cluster = next;
+            index += 1;
+        }
+        self.cursors.insert(stream.first, (index, cluster));
+        Ok(clusters)
+    }
+
+    /// Byte ranges on the device holding `len` bytes of a stream from
+    /// `offset`, merged where clusters are adjacent.
+    fn extents(&mut self, stream: Stream, offset: u64, len: usize) -> Result<Vec<(u64, usize)>, VfsError> {
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+        let bits = self.geometry.cluster_bits;
+        let first = (offset >> bits) as u32;
+        let last = ((offset + len as u64 - 1) >> bits) as u32;
+        let mut extents: Vec<(u64, usize)> = Vec::new();
+        let mut pos = offset;
+        let end = offset + len as u64;
+        for cluster in self.clusters(stream, first, last - first + 1)? {
+            let cluster_end = ((pos >> bits) + 1) << bits;
+            let take = (cluster_end.min(end) - pos) as usize;
+            let start = self.geometry.cluster_offset(cluster) + (pos & (self.geometry.cluster_size() - 1));
+            match extents.last_mut() {
+                Some((at, length)) if *at + *length as u64 == start => *length += take,
+                _ => extents.push((start, take)),
+            }
+            pos += take as u64;
+        }
+        Ok(extents)
+    }
+
+    fn read_stream(&mut self, stream: Stream, offset: u64, buffer: &mut [u8]) -> Result<(), VfsError> {
+        let mut done = 0;
+        for (start, len) in self.extents(stream, offset, buffer.len())? {
+            self.read_bytes(start, &mut buffer[done..done + len])?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    fn write_stream(&mut self, stream: Stream, offset: u64, data: &[u8]) -> Result<(), VfsError> {
+        let mut done = 0;
+        for (start, len) in self.extents(stream, offset, data.len())? {
+            self.write_bytes(start, &data[done..done + len])?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Zeroes `len` bytes of a stream from `offset`.
+    fn zero_stream(&mut self, stream: Stream, offset: u64, len: u64) -> Result<(), VfsError> {
+        let zeros = vec![0u8; len.min(64 * 1024) as usize];
+        let mut done = 0;
+        while done < len {
+            let chunk = (len - done).min(zeros.len() as u64) as usize;
+            self.write_stream(stream, offset + done, &zeros[..chunk])?;
+            done += chunk as u64;
+        }
+        Ok(())
+    }
+
+    /// Grows or shrinks a stream to `clusters` clusters. New clusters are
+    /// taken after the last one when free, keeping a contiguous stream
+    /// without a FAT chain; otherwise the stream gets a chain.
+    fn resize(&mut self, stream: &mut Stream, clusters: u32) -> Result<(), VfsError> {
+        let old = stream.clusters;
+        if clusters > old {
+            let last = if old > 0 { Some(self.clusters(*stream, old - 1, 1)?[0]) } else { None };
+            let new = self.allocate(clusters - old, last.map_or(self.hint, |c| c + 1))?;
+            let consecutive = new.windows(2).all(|w| w[1] == w[0] + 1) && last.is_none_or(|c| new[0] == c + 1);
+            if consecutive && (old == 0 || stream.contiguous) {
+                if old == 0 {
+                    *stream = Stream { first: new[0], clusters, contiguous: true };
+                } else {
+                    stream.clusters = clusters;
+                }
+                return Ok(());
+            }
+
+            let mut links = Vec::new();
+            if stream.contiguous {
+                links.extend((stream.first..stream.first + old - 1).map(|c| (c, c + 1)));
+            }
+            links.extend(last.into_iter().chain(new.iter().copied()).zip(new.iter().copied()));
+            links.push((*new.last().unwrap(), FAT_END));
+            self.write_fat(&links)?;
+            *stream = Stream { first: last.map_or(new[0], |_| stream.first), clusters, contiguous: false };
+        } else if clusters < old {
+            let freed = self.clusters(*stream, clusters, old - clusters)?;
+            if clusters > 0 && !stream.contiguous {
+                let last = self.clusters(*stream, clusters - 1, 1)?[0];
+                self.write_fat(&[(last, FAT_END)])?;
+            }
+            self.cursors.remove(&stream.first);
+            self.release(&freed)?;
+            *stream = if clusters == 0 { Stream::EMPTY } else { Stream { clusters, ..*stream } };
+        }
+        Ok(())
+    }
+
+    // ------------------------------------------------------------------
+    // Directories
+    // ------------------------------------------------------------------
+
+    /// Reads a whole directory.
+    fn read_dir(&mut self, dir: Stream) -> Result<Vec<u8>, VfsError> {
+        let mut data = vec![0u8; (dir.clusters as usize) << self.geometry.cluster_bits];
+        self.read_stream(dir, 0, &mut data)?;
+        Ok(data)
+    }
+
+    fn node(&self, ino: InodeNum) -> Result<&Node, VfsError> {
+        self.nodes.get(&ino).ok_or(VfsError::NotFound)
+    }
+
+    /// Clusters of a file or directory.
+    fn stream_of(&self, ino: InodeNum) -> Result<Stream, VfsError> {
+        if ino == EXFAT_ROOT_INO {
+            return Ok(self.root);
+        }
+        Ok(self.node(ino)?.set.stream(&self.geometry))
+    }
+
+    /// Clusters of a directory.
+    fn dir_stream(&self, ino: InodeNum) -> Result<Stream, VfsError> {
+        if ino != EXFAT_ROOT_INO && !self.node(ino)?.set.is_dir() {
+            return Err(VfsError::NotADirectory);
+        }
+        self.stream_of(ino)
+    }
+
+    /// Records the new clusters of a directory.
+    fn set_dir_stream(&mut self, ino: InodeNum, stream: Stream) -> Result<(), VfsError> {
+        if ino == EXFAT_ROOT_INO {
+            self.root = stream;
+            return Ok(());
+        }
+        let length = (stream.clusters as u64) << self.geometry.cluster_bits;
+        let node = self.nodes.get_mut(&ino).ok_or(VfsError::NotFound)?;
+        node.set.set_stream(stream, length, length);
+        self.store(ino)
+    }
+
+    /// Writes a node's entry set back to its parent directory.
+    fn store(&mut self, ino: InodeNum) -> Result<(), VfsError> {
+        let node = self.node(ino)?;
+        let (parent, slot, bytes) = (node.parent, node.slot, node.set.to_bytes());
+        let dir = self.stream_of(parent)?;
+        self.write_stream(dir, slot as u64 * ENTRY_SIZE as u64, &bytes)
+    }
+
+    /// Updates a directory's modification time after a change to its
+    /// entries. The root directory has no times.
+    fn touch_dir(&mut self, ino: InodeNum) -> Result<(), VfsError> {
+        match self.nodes.get_mut(&ino) {
+            Some(node) => {
+                node.set.touch();
+                self.store(ino)
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn upcase_name(&self, name: &[u16]) -> Vec<u16> {
+        name.iter().map(|&c| self.upcase[c as usize]).collect()
+    }
+
+    /// The entry set named `name` in a directory, matched without case.
+    fn find(&mut self, parent: InodeNum, name: &[u16]) -> Result<Option<(u32, EntrySet)>, VfsError> {
+        let dir = self.dir_stream(parent)?;
+        let key = self.upcase_name(name);
+        let hash = name_hash(&key);
+        let data = self.read_dir(dir)?;
+        Ok(parse_sets(&data)
+            .into_iter()
+            .find(|(_, set)| set.name_hash() == hash && self.upcase_name(&set.name()) == key))
+    }
+
+    /// Inode number of the entry set at `slot` of `parent`, handed out the
+    /// first time the set is seen.
+    fn child(&mut self, parent: InodeNum, slot: u32, set: EntrySet) -> InodeNum {
+        if let Some(&ino) = self.slots.get(&(parent, slot)) {
+            return ino;
+        }
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        self.slots.insert((parent, slot), ino);
+        self.nodes.insert(ino, Node { parent, slot, set });
+        ino
+    }
+
+    /// Writes an entry set into free slots of a directory, growing it if
+    /// there are none.
+    fn insert_set(&mut self, parent: InodeNum, set: &EntrySet) -> Result<u32, VfsError> {
+        let mut dir = self.dir_stream(parent)?;
+        let data = self.read_dir(dir)?;
+        let slot = find_free(&data, set.len());
+        let end = ((slot + set.len()) * ENTRY_SIZE) as u64;
+        let size = data.len() as u64;
+        if end > size {
+            if end > MAX_DIR_SIZE {
+                return Err(VfsError::NoSpace);
+            }
+            self.resize(&mut dir, self.geometry.clusters_for(end) as u32)?;
+            let grown = (dir.clusters as u64) << self.geometry.cluster_bits;
+            self.zero_stream(dir, size, grown - size)?;
+            self.set_dir_stream(parent, dir)?;
+        }
+        self.write_stream(dir, (slot * ENTRY_SIZE) as u64, &set.to_bytes())?;
+        Ok(slot as u32)
+    }
+
+    /// Marks the entries of a set unused.
+    fn remove_set(&mut self, parent: InodeNum, slot: u32, count: usize) -> Result<(), VfsError> {
+        let dir = self.stream_of(parent)?;
+        let offset = slot as u64 * ENTRY_SIZE as u64;
+        let mut entries = vec![0u8; count * ENTRY_SIZE];
+        self.read_stream(dir, offset, &mut entries)?;
+        for entry in entries.chunks_exact_mut(ENTRY_SIZE) {
+            entry[0] &= !ENTRY_IN_USE;
+        }
+        self.write_stream(dir, offset, &entries)
+    }
+
+    /// Removes an entry set, frees its clusters and forgets its inode.
+    fn delete(&mut self, parent: InodeNum, slot: u32, set: &EntrySet) -> Result<(), VfsError> {
+        self.remove_set(parent, slot, set.len())?;
+        let mut stream = set.stream(&self.geometry);
+        self.resize(&mut stream, 0)?;
+        if let Some(ino) = self.slots.remove(&(parent, slot)) {
+            self.nodes.remove(&ino);
+        }
+        Ok(())
+    }
+
+    fn is_empty_dir(&mut self, set: &EntrySet) -> Result<bool, VfsError> {
+        let data = self.read_dir(set.stream(&self.geometry))?;
+        Ok(parse_sets(&data).is_empty())
+    }
+
+    /// Whether `dir` is `ino` or lies beneath it.
+    fn is_within(&self, mut dir: InodeNum, ino: InodeNum) -> bool {
+        loop {
+            if dir == ino {
+                return true;
+            }
+            match self.nodes.get(&dir) {
+                Some(node) => dir = node.parent,
+                None => return false,
+            }
+        }
+    }
+
+    // ------------------------------------------------------------------
+    // Operations
+    // ------------------------------------------------------------------
+
+    fn statfs(&self) -> VfsStatFs {
+        VfsStatFs {
+            blocks: self.geometry.cluster_count as u64,
+            bfree: self.free as u64,
+            bavail: self.free as u64,
+            files: 0,
+            ffree: 0,
+            bsize: self.geometry.cluster_size() as u32,
+            namelen: EXFAT_NAME_LEN as u32,
+            bsaved: 0,
+        }
+    }
+
+    fn lookup(&mut self, parent: InodeNum, name: &str) -> Result<InodeNum, VfsError> {
+        let name: Vec<u16> = name.encode_utf16().collect();
+        let (slot, set) = self.find(parent, &name)?.ok_or(VfsError::NotFound)?;
+        Ok(self.child(parent, slot, set))
+    }
+
+    fn getattr(&self, ino: InodeNum) -> Result<VfsAttr, VfsError> {
+        let cluster_size = self.geometry.cluster_size();
+        if ino == EXFAT_ROOT_INO {
+            let size = (self.root.clusters as u64) << self.geometry.cluster_bits;
+            return Ok(VfsAttr {
+                ino,
+                file_type: VfsFileType::Directory,
+                perm: VfsPermissions { readable: true, writable: true, executable: true },
+                size,
+                nlink: 1,
+                blksize: cluster_size as u32,
+                blocks: size / 512,
+                atime: 0,
+                mtime: 0,
+                ctime: 0,
+                crtime: 0,
+            });
+        }
+
+        let set = &self.node(ino)?.set;
+        let (crtime, mtime, atime) = set.times();
+        let allocated = (set.stream(&self.geometry).clusters as u64) << self.geometry.cluster_bits;
+        Ok(VfsAttr {
+            ino,
+            file_type: set.file_type(),
+            perm: VfsPermissions {
+                readable: true,
+                writable: set.attributes() & ATTR_READ_ONLY == 0,
+                executable: set.is_dir(),
+            },
+            size: set.data_length(),
+            nlink: 1,
+            blksize: cluster_size as u32,
+            blocks: allocated / 512,
+            atime,
+            mtime,
+            ctime: mtime,
+            crtime,
+        })
+    }
+
+    fn readdir(&mut self, ino: InodeNum) -> Result<Vec<VfsDirEntry>, VfsError> {
+        let dir = self.dir_stream(ino)?;
+        let data = self.read_dir(dir)?;
+        Ok(parse_sets(&data)
+            .into_iter()
+            .map(|(slot, set)| {
+                let name = String::from_utf16_lossy(&set.name());
+                let file_type = set.file_type();
+                VfsDirEntry { name, ino: self.child(ino, slot, set), file_type }
+            })
+            .collect())
+    }
+
+    /// The node of a regular file.
+    fn file(&self, ino: InodeNum) -> Result<&Node, VfsError> {
+        if ino == EXFAT_ROOT_INO {
+            return Err(VfsError::IsADirectory);
+        }
+        let node = self.node(ino)?;
+        if node.set.is_dir() {
+            return Err(VfsError::IsADirectory);
+        }
+        Ok(node)
+    }
+
+    /// The node of a regular file about to be modified.
+    fn writable_file(&self, ino: InodeNum) -> Result<&Node, VfsError> {
+        self.check_writable()?;
+        let node = self.file(ino)?;
+        if node.set.attributes() & ATTR_READ_ONLY != 0 {
+            return Err(VfsError::PermissionDenied);
+        }
+        Ok(node)
+    }
+
+    fn read(&mut self, ino: InodeNum, offset: u64, size: usize) -> Result<Vec<u8>, VfsError> {
+        let set = &self.file(ino)?.set;
+        let (length, valid) = (set.data_length(), set.valid_length());
+        let stream = set.stream(&self.geometry);
+        if offset >= length {
+            return Ok(Vec::new());
+        }
+        let end = length.min(offset.saturating_add(size as u64));
+        let mut buffer = vec![0u8; (end - offset) as usize];
+        if offset < valid {
+            let stored = (valid.min(end) - offset) as usize;
+            self.read_stream(stream, offset, &mut buffer[..stored])?;
+        }
+        Ok(buffer)
+    }
+
+    fn write(&mut self, ino: InodeNum, offset: u64, data: &[u8]) -> Result<usize, VfsError> {
+        let set = &self.writable_file(ino)?.set;
+        if data.is_empty() {
+            return Ok(0);
+        }
+        let (length, valid) = (set.data_length(), set.valid_length());
+        let mut stream = set.stream(&self.geometry);
+        let end = offset.checked_add(data.len() as u64).ok_or(VfsError::InvalidArgument)?;
+        let clusters = self.geometry.clusters_for(end);
+        if clusters > self.geometry.cluster_count as u64 {
+            return Err(VfsError::NoSpace);
+        }
+        if clusters as u32 > stream.clusters {
+            self.resize(&mut stream, clusters as u32)?;
+        }
+
+        // Bytes between the valid length and the write become valid
+        if offset > valid {
+            self.zero_stream(stream, valid, offset - valid)?;
+        }
+        self.write_stream(stream, offset, data)?;
+
+        let node = self.nodes.get_mut(&ino).ok_or(VfsError::NotFound)?;
+        node.set.set_stream(stream, length.max(end), valid.max(end));
+        node.set.touch();
+        self.store(ino)?;
+        Ok(data.len())
+    }
+
+    fn truncate(&mut self, ino: InodeNum, size: u64) -> Result<(), VfsError> {
+        let set = &self.writable_file(ino)?.set;
+        let valid = set.valid_length();
+        let mut stream = set.stream(&self.geometry);
+        let clusters = self.geometry.clusters_for(size);
+        if clusters > self.geometry.cluster_count as u64 {
+            return Err(VfsError::NoSpace);
+        }
+        // Clusters added stay past the valid length, so they read as zeros
+        self.resize(&mut stream, clusters as u32)?;
+
+        let node = self.nodes.get_mut(&ino).ok_or(VfsError::NotFound)?;
+        node.set.set_stream(stream, size, valid.min(size));
+        node.set.touch();
+        self.store(ino)
+    }
+
+    fn create(&mut self, parent: InodeNum, name: &str, file_type: VfsFileType) -> Result<InodeNum, VfsError> {
+        self.check_writable()?;
+        let attributes = match file_type {
+            VfsFileType::Regular => ATTR_ARCHIVE,
+            VfsFileType::Directory => ATTR_DIRECTORY,
+            _ => return Err(VfsError::NotSupported),
+        };
+        let name = encode_name(name)?;
+        if self.find(parent, &name)?.is_some() {
+            return Err(VfsError::AlreadyExists);
+        }
+
+        let mut set = EntrySet::new(&name, name_hash(&self.upcase_name(&name)), attributes);
+        let mut stream = Stream::EMPTY;
+        if file_type == VfsFileType::Directory {
+            // A directory starts with one zeroed cluster
+            self.resize(&mut stream, 1)?;
+            let size = self.geometry.cluster_size();
+            self.zero_stream(stream, 0, size)?;
+            set.set_stream(stream, size, size);
+        }
+        let slot = match self.insert_set(parent, &set) {
+            Ok(slot) => slot,
+            Err(e) => {
+                let _ = self.resize(&mut stream, 0);
+                return Err(e);
+            }
+        };
+        self.touch_dir(parent)?;
+        Ok(self.child(parent, slot, set))
+    }
+
+    fn unlink(&mut self, parent: InodeNum, name: &str) -> Result<(), VfsError> {
+        self.check_writable()?;
+        let name: Vec<u16> = name.encode_utf16().collect();
+        let (slot, set) = self.find(parent, &name)?.ok_or(VfsError::NotFound)?;
+        if set.is_dir() && !self.is_empty_dir(&set)? {
+            return Err(VfsError::NotEmpty);
+        }
+        self.delete(parent, slot, &set)?;
+        self.touch_dir(parent)
+    }
+
+    fn rename(&mut self, old_parent: InodeNum, old_name: &str, new_parent: InodeNum, new_name: &str) -> Result<(), VfsError> {
+        self.check_writable()?;
+        let new_name = encode_name(new_name)?;
+        let old_name: Vec<u16> = old_name.encode_utf16().collect();
+        let (old_slot, set) = self.find(old_parent, &old_name)?.ok_or(VfsError::NotFound)?;
+        let ino = self.child(old_parent, old_slot, set);
+        let set = self.node(ino)?.set.clone();
+        if set.is_dir() {
+            self.dir_stream(new_parent)?;
+            if self.is_within(new_parent, ino) {
+                return Err(VfsError::InvalidArgument);
+            }
+        }
+
+        if let Some((slot, target)) = self.find(new_parent, &new_name)? {
+            // The same entry under another case is simply renamed
+            if (new_parent, slot) != (old_parent, old_slot) {
+                match (set.is_dir(), target.is_dir()) {
+                    (true, false) => return Err(VfsError::NotADirectory),
+                    (false, true) => return Err(VfsError::IsADirectory),
+                    (true, true) if !self.is_empty_dir(&target)? => return Err(VfsError::NotEmpty),
+                    _ => {}
+                }
+                self.delete(new_parent, slot, &target)?;
+            }
+        }
+
+        let mut renamed = set.clone();
+        renamed.set_name(&new_name, name_hash(&self.upcase_name(&new_name)));
+        let slot = self.insert_set(new_parent, &renamed)?;
+        self.remove_set(old_parent, old_slot, set.len())?;
+
+        self.slots.remove(&(old_parent, old_slot));
+        self.slots.insert((new_parent, slot), ino);
+        self.nodes.insert(ino, Node { parent: new_parent, slot, set: renamed });
+        self.touch_dir(old_parent)?;
+        if new_parent != old_parent {
+            self.touch_dir(new_parent)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads bytes at any offset of a device.
+fn read_bytes(device: &dyn BlockDevice, offset: u64, buffer: &mut [u8]) -> Result<(), VfsError> {
+    let sector = offset / SECTOR_SIZE as u64;
+    let sector_offset = (offset % SECTOR_SIZE as u64) as usize;
+    if sector_offset == 0 && buffer.len().is_multiple_of(SECTOR_SIZE) {
+        return device.read_sectors(sector, buffer).map_err(|_| VfsError::IoError);
+    }
+    let sectors_needed = (sector_offset + buffer.len()).div_ceil(SECTOR_SIZE);
+    let mut sector_buffer = vec![0u8; sectors_needed * SECTOR_SIZE];
+    device.read_sectors(sector, &mut sector_buffer).map_err(|_| VfsError::IoError)?;
+    buffer.copy_from_slice(&sector_buffer[sector_offset..sector_offset + buffer.len()]);
+    Ok(())
+}
+
+/// exFAT filesystem.
+pub struct ExfatFs {
+    /// Block device
+    device: Arc<dyn BlockDevice + Send + Sync>,
+    /// Mounted volume; locking it serializes operations
+    volume: Mutex<Option<Volume>>,
+}
+
+impl ExfatFs {
+    /// Creates a new exFAT filesystem.
+    pub fn new(device: Arc<dyn BlockDevice + Send + Sync>) -> Self {
+        Self { device, volume: Mutex::new(None) }
+    }
+
+    /// Mounts the filesystem, read-write unless the device is read-only or
+    /// the volume uses TexFAT.
+    pub fn mount(&self) -> Result<(), VfsError> {
+        let volume = Volume::open(self.device.clone())?;
+        let geometry = volume.geometry;
+        crate::serial_println!(
+            "[exfat] Mounted: {} clusters of {} bytes, {} free, {}",
+            geometry.cluster_count,
+            geometry.cluster_size(),
+            volume.free,
+            if volume.read_only { "read-only" } else { "read-write" }
+        );
+        *self.volume.lock() = Some(volume);
+        Ok(())
+    }
+
+    /// Marks the volume clean. Further writes are refused.
+    pub fn unmount(&self) -> Result<(), VfsError> {
+        self.with(|volume| volume.unmount())
+    }
+
+    /// Whether the volume refuses writes.
+    pub fn is_read_only(&self) -> bool {
+        self.volume.lock().as_ref().is_none_or(|volume| volume.read_only)
+    }
+
+    /// Runs `f` on the mounted volume.
+    fn with<R>(&self, f: impl FnOnce(&mut Volume) -> Result<R, VfsError>) -> Result<R, VfsError> {
+        let mut volume = self.volume.lock();
+        f(volume.as_mut().ok_or(VfsError::NoFilesystem)?)
+    }
+
+    /// Resolves a path relative to the root directory.
+    fn resolve_path(&self, path: &str) -> Result<InodeNum, VfsError> {
+        let mut ino = EXFAT_ROOT_INO;
+        for component in path.split('/').filter(|s| !s.is_empty()) {
+            ino = self.lookup(ino, component)?;
+        }
+        Ok(ino)
+    }
+}
+
+impl Filesystem for ExfatFs {
+    fn name(&self) -> &'static str {
+        "exfat"
+    }
+
+    fn root_ino(&self) -> InodeNum {
+        EXFAT_ROOT_INO
+    }
+
+    fn statfs(&self) -> Result<VfsStatFs, VfsError> {
+        self.with(|volume| Ok(volume.statfs()))
+    }
+
+    fn lookup(&self, parent: InodeNum, name: &str) -> Result<InodeNum, VfsError> {
+        self.with(|volume| volume.lookup(parent, name))
+    }
+
+    fn getattr(&self, ino: InodeNum) -> Result<VfsAttr, VfsError> {
+        self.with(|volume| volume.getattr(ino))
+    }
+
+    fn readdir(&self, ino: InodeNum) -> Result<Vec<VfsDirEntry>, VfsError> {
+        self.with(|volume| volume.readdir(ino))
+    }
+
+    fn read(&self, ino: InodeNum, offset: u64, size: usize) -> Result<Vec<u8>, VfsError> {
+        self.with(|volume| volume.read(ino, offset, size))
+    }
+
+    fn write(&self, ino: InodeNum, offset: u64, data: &[u8]) -> Result<usize, VfsError> {
+        self.with(|volume| volume.write(ino, offset, data))
+    }
+
+    fn create(&self, parent: InodeNum, name: &str, file_type: VfsFileType) -> Result<InodeNum, VfsError> {
+        self.with(|volume| volume.create(parent, name, file_type))
+    }
+
+    fn unlink(&self, parent: InodeNum, name: &str) -> Result<(), VfsError> {
+        self.with(|volume| volume.unlink(parent, name))
+    }
+
+    fn rename(&self, old_parent: InodeNum, old_name: &str, new_parent: InodeNum, new_name: &str) -> Result<(), VfsError> {
+        self.with(|volume| volume.rename(old_parent, old_name, new_parent, new_name))
+    }
+
+    fn truncate(&self, ino: InodeNum, size: u64) -> Result<(), VfsError> {
+        self.with(|volume| volume.truncate(ino, size))
+    }
+
+    fn sync(&self) -> Result<(), VfsError> {
+        // Everything is written through
+        self.device.flush().map_err(|_| VfsError::IoError)
+    }
+}
+
+// ============================================================================
+// Module-level mount API for shell commands
+// ============================================================================
+
+/// Block device wrapper that uses block module's read/write functions.
+struct BlockDeviceWrapper {
+    name: String,
+}
+
+impl BlockDeviceWrapper {
+    fn new(name: &str) -> Self {
+        Self { name: name.into() }
+    }
+}
+
+impl crate::block::BlockDevice for BlockDeviceWrapper {
+    fn info(&self) -> crate::block::BlockDeviceInfo {
+        crate::block::list_devices()
+            .into_iter()
+            .find(|d| d.name == self.name)
+            .unwrap_or(crate::block::BlockDeviceInfo {
+                name: self.name.clone(),
+                sector_size: 512,
+                total_sectors: 0,
+                read_only: true,
+                model: String::new(),
+            })
+    }
+
+    fn read_sectors(&self, start: u64, buf: &mut [u8]) -> Result<(), crate::block::BlockError> {
+        let sector_size = self.info().sector_size;
+        let count = buf.len() / sector_size;
+        let data = crate::block::read(&self.name, start, count)?;
+        buf[..data.len()].copy_from_slice(&data);
+        Ok(())
+    }
+
+    fn write_sectors(&self, start: u64, data: &[u8]) -> Result<(), crate::block::BlockError> {
+        crate::block::write(&self.name, start, data)
+    }
+
+    fn flush(&self) -> Result<(), crate::block::BlockError> {
+        crate::block::flush(&self.name)
+    }
+
+    fn is_ready(&self) -> bool {
+        true
+    }
+}
+
+/// Global exFAT mount registry.
+static EXFAT_MOUNTS: RwLock<BTreeMap<String, Arc<ExfatFs>>> = RwLock::new(BTreeMap::new());
+
+/// Mount an exFAT filesystem, read-write unless the device or the volume
+/// prevents it.
+pub fn mount(device_name: &str, mount_point: &str) -> Result<(), VfsError> {
+    use alloc::string::ToString;
+
+    if !crate::block::list_devices().iter().any(|d| d.name == device_name) {
+        return Err(VfsError::NotFound);
+    }
+
+    let device = Arc::new(BlockDeviceWrapper::new(device_name));
+    let fs = Arc::new(ExfatFs::new(device));
+    fs.mount()?;
+
+    let read_only = fs.is_read_only();
+    if let Err(e) = VFS.mount(mount_point, fs.clone(), read_only) {
+        let _ = fs.unmount();
+        return Err(e);
+    }
+    EXFAT_MOUNTS.write().insert(mount_point.to_string(), fs);
+
+    crate::serial_println!(
+        "[exfat] Mounted {} at {} ({})",
+        device_name,
+        mount_point,
+        if read_only { "read-only" } else { "read-write" }
+    );
+    Ok(())
+}
+
+/// Unmount an exFAT filesystem.
+pub fn unmount(mount_point: &str) -> Result<(), VfsError> {
+    let fs = EXFAT_MOUNTS.write().remove(mount_point).ok_or(VfsError::NotFound)?;
+    let _ = VFS.unmount(mount_point);
+    fs.unmount()?;
+    crate::serial_println!("[exfat] Unmounted {}", mount_point);
+    Ok(())
+}
+
+/// List directory on exFAT filesystem.
+pub fn ls(path: &str) -> Result<Vec<(String, VfsFileType, u64)>, VfsError> {
+    let mounts = EXFAT_MOUNTS.read();
+    for (mount_point, fs) in mounts.iter() {
+        if let Some(rel_path) = path.strip_prefix(mount_point.as_str()) {
+            let ino = fs.resolve_path(rel_path)?;
+            let entries = fs.readdir(ino)?;
+            return Ok(entries
+                .iter()
+                .map(|entry| {
+                    let (ft, size) = match fs.getattr(entry.ino) {
+                        Ok(attrs) => (attrs.file_type, attrs.size),
+                        Err(_) => (VfsFileType::Regular, 0),
+                    };
+                    (entry.name.clone(), ft, size)
+                })
+                .collect());
+        }
+    }
+    Err(VfsError::NotFound)
+}
+
+/// Read file from exFAT filesystem.
+pub fn cat(path: &str) -> Result<Vec<u8>, VfsError> {
+    let mounts = EXFAT_MOUNTS.read();
+    for (mount_point, fs) in mounts.iter() {
+        if let Some(rel_path) = path.strip_prefix(mount_point.as_str()) {
+            let ino = fs.resolve_path(rel_path)?;
+            return fs.read(ino, 0, 1024 * 1024); // Max 1MB
+        }
+    }
+    Err(VfsError::NotFound)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::ramdisk::RamDisk;
+
+    /// Volume size of the test image, in sectors
+    const VOLUME_SECTORS: u64 = 65536;
+    /// FAT and cluster heap offsets, in sectors, as `mkfs.exfat` aligns
+    /// them on a 32 MiB volume
+    const FAT_SECTOR: u64 = 2048;
+    const HEAP_SECTOR: u64 = 4096;
+    /// 4 KiB clusters
+    const CLUSTER_SHIFT: u8 = 3;
+    const CLUSTERS: u32 = ((VOLUME_SECTORS - HEAP_SECTOR) >> CLUSTER_SHIFT) as u32;
+    const CLUSTER: usize = SECTOR_SIZE << CLUSTER_SHIFT;
+
+    /// A compressed up-case table mapping ASCII and Latin-1 letters
+    fn upcase_table() -> Vec<u8> {
+        let mut units: Vec<u16> = vec![0xFFFF, 0x61];
+        units.extend(0x41..=0x5A);
+        units.extend([0xFFFF, 0xE0 - 0x7B]);
+        units.extend((0xC0..=0xDE).map(|c| if c == 0xD7 { 0xF7 } else { c }));
+        units.extend([0xFFFF, 0xFF00]);
+        units.iter().flat_map(|u| u.to_le_bytes()).collect()
+    }
+
+    fn cluster_offset(cluster: u32) -> usize {
+        (HEAP_SECTOR as usize * SECTOR_SIZE) + (cluster - FIRST_CLUSTER) as usize * CLUSTER
+    }
+
+    /// Builds an empty volume laid out as `mkfs.exfat` does: allocation
+    /// bitmap in cluster 2, up-case table in cluster 3 and the root
+    /// directory, with a volume label and GUID entry, in cluster 4.
+    fn format() -> Vec<u8> {
+        let mut image = vec![0u8; VOLUME_SECTORS as usize * SECTOR_SIZE];
+        let mut region = vec![0u8; 12 * SECTOR_SIZE];
+        let boot = &mut region[..SECTOR_SIZE];
+        boot[..3].copy_from_slice(&[0xEB, 0x76, 0x90]);
+        boot[3..11].copy_from_slice(EXFAT_NAME);
+        put_le64(boot, BS_VOLUME_LENGTH, VOLUME_SECTORS);
+        put_le32(boot, BS_FAT_OFFSET, FAT_SECTOR as u32);
+        put_le32(boot, BS_FAT_LENGTH, 64);
+        put_le32(boot, BS_HEAP_OFFSET, HEAP_SECTOR as u32);
+        put_le32(boot, BS_CLUSTER_COUNT, CLUSTERS);
+        put_le32(boot, BS_ROOT_CLUSTER, 4);
+        put_le32(boot, 100, 0x1234_5678);
+        put_le16(boot, BS_REVISION_MAJOR - 1, 0x0100);
+        boot[BS_SECTOR_SHIFT] = 9;
+        boot[BS_CLUSTER_SHIFT] = CLUSTER_SHIFT;
+        boot[BS_NUMBER_OF_FATS] = 1;
+        boot[111] = 0x80;
+        put_le16(boot, 510, BOOT_SIGNATURE);
+        for sector in 1..9 {
+            put_le32(&mut region, sector * SECTOR_SIZE + SECTOR_SIZE - 4, 0xAA55_0000);
+        }
+        let checksum = boot_checksum(&region[..BOOT_CHECKSUM_SECTORS * SECTOR_SIZE]);
+        for i in 0..SECTOR_SIZE / 4 {
+            put_le32(&mut region, BOOT_CHECKSUM_SECTORS * SECTOR_SIZE + 4 * i, checksum);
+        }
+        image[..region.len()].copy_from_slice(&region);
+        image[region.len()..2 * region.len()].copy_from_slice(&region);
+
+        let fat = FAT_SECTOR as usize * SECTOR_SIZE;
+        for (cluster, entry) in [0xFFFF_FFF8, FAT_END, FAT_END, FAT_END, FAT_END].into_iter().enumerate() {
+            put_le32(&mut image, fat + 4 * cluster, entry);
+        }
+        image[cluster_offset(2)] = 0b111;
+
+        let upcase = upcase_table();
+        image[cluster_offset(3)..cluster_offset(3) + upcase.len()].copy_from_slice(&upcase);
+
+        let root = cluster_offset(4);
+        image[root] = 0x83;
+        image[root + ENTRY_SIZE] = 0xA0;
+        let bitmap = &mut image[root + 2 * ENTRY_SIZE..root + 3 * ENTRY_SIZE];
+        bitmap[0] = ENTRY_BITMAP;
+        put_le32(bitmap, ENTRY_FIRST_CLUSTER, 2);
+        put_le64(bitmap, ENTRY_DATA_LENGTH, CLUSTERS.div_ceil(8) as u64);
+        let entry = &mut image[root + 3 * ENTRY_SIZE..root + 4 * ENTRY_SIZE];
+        entry[0] = ENTRY_UPCASE;
+        put_le32(entry, UPCASE_CHECKSUM, table_checksum(&upcase));
+        put_le32(entry, ENTRY_FIRST_CLUSTER, 3);
+        put_le64(entry, ENTRY_DATA_LENGTH, upcase.len() as u64);
+        image
+    }
+
+    fn mount(disk: &Arc<RamDisk>) -> ExfatFs {
+        let fs = ExfatFs::new(disk.clone());
+        fs.mount().unwrap();
+        fs
+    }
+
+    fn fat(disk: &RamDisk, cluster: u32) -> u32 {
+        le32(&disk.data.lock(), FAT_SECTOR as usize * SECTOR_SIZE + 4 * cluster as usize)
+    }
+
+    fn stream(fs: &ExfatFs, ino: InodeNum) -> Stream {
+        fs.with(|volume| volume.stream_of(ino)).unwrap()
+    }
+
+    #[test]
+    fn test_checksums() {
+        let name: Vec<u16> = "Read Me.txt".encode_utf16().collect();
+        let upcase = decode_upcase(&upcase_table());
+        let upcased: Vec<u16> = name.iter().map(|&c| upcase[c as usize]).collect();
+        assert_eq!(String::from_utf16_lossy(&upcased), "READ ME.TXT");
+        assert_eq!(upcase[0xE9], 0xC9);
+        assert_eq!(upcase[0xF7], 0xF7);
+        assert_eq!(upcase[0x3B1], 0x3B1);
+
+        let set = EntrySet::new(&name, name_hash(&upcased), ATTR_ARCHIVE);
+        let mut bytes = set.to_bytes();
+        assert_eq!(bytes.len(), 3 * ENTRY_SIZE);
+        assert_eq!(EntrySet::parse(&bytes).unwrap().to_bytes(), bytes);
+        assert_eq!(set.name(), name);
+
+        // The checksum covers every entry but not itself
+        bytes[2 * ENTRY_SIZE + 2] ^= 1;
+        assert_eq!(EntrySet::parse(&bytes), None);
+        let mut boot = vec![0u8; 1024];
+        let sum = boot_checksum(&boot);
+        boot[BS_VOLUME_FLAGS] = 2;
+        boot[BS_PERCENT_IN_USE] = 50;
+        assert_eq!(boot_checksum(&boot), sum);
+        boot[BS_PERCENT_IN_USE + 1] = 1;
+        assert_ne!(boot_checksum(&boot), sum);
+    }
+
+    #[test]
+    fn test_entry_sets() {
+        let long: Vec<u16> = "a file name that needs three name entries".encode_utf16().collect();
+        let mut set = EntrySet::new(&long, 0x1234, ATTR_ARCHIVE);
+        assert_eq!(set.len(), 5);
+        set.set_stream(Stream { first: 9, clusters: 2, contiguous: true }, 5000, 100);
+        let geometry = Geometry::parse(&format()[..SECTOR_SIZE]).unwrap();
+        assert_eq!(set.stream(&geometry), Stream { first: 9, clusters: 2, contiguous: true });
+        assert_eq!(set.valid_length(), 100);
+
+        set.set_name(&long[..3], 0x4321);
+        assert_eq!(set.len(), 3);
+        assert_eq!(set.name(), &long[..3]);
+        assert_eq!(set.name_hash(), 0x4321);
+        assert_eq!(set.data_length(), 5000);
+
+        // Sets are found between unused and foreign entries
+        let mut dir = vec![0u8; 16 * ENTRY_SIZE];
+        dir[0] = 0x83;
+        dir[ENTRY_SIZE] = ENTRY_FILE & !ENTRY_IN_USE;
+        dir[2 * ENTRY_SIZE..5 * ENTRY_SIZE].copy_from_slice(&set.to_bytes());
+        let sets = parse_sets(&dir);
+        assert_eq!(sets.len(), 1);
+        assert_eq!(sets[0].0, 2);
+        assert_eq!(find_free(&dir, 1), 1);
+        assert_eq!(find_free(&dir, 2), 5);
+        assert_eq!(find_free(&dir[..5 * ENTRY_SIZE], 3), 5);
+    }
+
+    #[test]
+    fn test_timestamps() {
+        let (stamp, increment) = encode_time(2024, 2, 29, 12, 34, 56);
+        assert_eq!(unix_time(stamp, increment, UTC), 1_709_210_096);
+        let (stamp, increment) = encode_time(2024, 2, 29, 12, 34, 57);
+        assert_eq!(increment, 100);
+        assert_eq!(unix_time(stamp, increment, UTC), 1_709_210_097);
+        // Local time one hour ahead of UTC
+        assert_eq!(unix_time(stamp, increment, UTC | 4), 1_709_210_097 - 3600);
+        // Local time five hours behind UTC
+        assert_eq!(unix_time(stamp, increment, UTC | 0x6C), 1_709_210_097 + 5 * 3600);
+        let (stamp, increment) = encode_time(1980, 1, 1, 0, 0, 0);
+        assert_eq!(unix_time(stamp, increment, 0), 315_532_800);
+    }
+
+    #[test]
+    fn test_names() {
+        assert_eq!(encode_name("Ünïcødé 名前.txt").unwrap().len(), 14);
+        assert_eq!(encode_name(""), Err(VfsError::InvalidArgument));
+        assert_eq!(encode_name(".."), Err(VfsError::InvalidArgument));
+        assert_eq!(encode_name("a:b"), Err(VfsError::InvalidArgument));
+        assert_eq!(encode_name("a\tb"), Err(VfsError::InvalidArgument));
+        assert_eq!(encode_name(&"x".repeat(255)).unwrap().len(), 255);
+        assert_eq!(encode_name(&"x".repeat(256)), Err(VfsError::PathTooLong));
+    }
+
+    #[test]
+    fn test_volume() {
+        let disk = Arc::new(RamDisk::new("exfat0", format()));
+        let fs = mount(&disk);
+        assert!(!fs.is_read_only());
+        assert_eq!(le16(&disk.data.lock(), BS_VOLUME_FLAGS) & VOLUME_DIRTY, VOLUME_DIRTY);
+        let free = fs.statfs().unwrap().bfree;
+        assert_eq!(free, CLUSTERS as u64 - 3);
+
+        // Files written in one go are contiguous, without a FAT chain
+        let root = fs.root_ino();
+        let long = "A long name with Ünïcødé — more than fifteen characters.txt";
+        let file = fs.create(root, long, VfsFileType::Regular).unwrap();
+        let data: Vec<u8> = (0..3 * CLUSTER + 100).map(|i| (i % 251) as u8).collect();
+        assert_eq!(fs.write(file, 0, &data).unwrap(), data.len());
+        let first = stream(&fs, file);
+        assert!(first.contiguous);
+        assert_eq!(first.clusters, 4);
+        assert_eq!(fat(&disk, first.first), 0);
+        assert_eq!(fs.read(file, 0, data.len() + 10).unwrap(), data);
+        assert_eq!(fs.lookup(root, &long.to_uppercase()).unwrap(), file);
+
+        // Growing past another file's clusters switches to a FAT chain
+        let other = fs.create(root, "other", VfsFileType::Regular).unwrap();
+        fs.write(other, 0, b"in the way").unwrap();
+        fs.write(file, data.len() as u64, &[7u8; CLUSTER]).unwrap();
+        let chained = stream(&fs, file);
+        assert!(!chained.contiguous);
+        assert_eq!(chained.clusters, 5);
+        assert_eq!(fat(&disk, first.first), first.first + 1);
+        let mut expected = data.clone();
+        expected.extend([7u8; CLUSTER]);
+        assert_eq!(fs.read(file, 0, expected.len()).unwrap(), expected);
+
+        // Writing past the end reads back zeros in between
+        let sparse = fs.create(root, "sparse", VfsFileType::Regular).unwrap();
+        fs.write(sparse, 10_000, b"end").unwrap();
+        let read = fs.read(sparse, 0, 20_000).unwrap();
+        assert_eq!(read.len(), 10_003);
+        assert!(read[..10_000].iter().all(|&b| b == 0));
+        fs.truncate(sparse, 20).unwrap();
+        fs.truncate(sparse, 30_000).unwrap();
+        assert_eq!(fs.getattr(sparse).unwrap().size, 30_000);
+        assert!(fs.read(sparse, 0, 30_000).unwrap().iter().all(|&b| b == 0));
+        fs.truncate(sparse, 0).unwrap();
+        assert_eq!(stream(&fs, sparse), Stream::EMPTY);
+
+        // Directories, with enough entries to need a second cluster
+        let dir = fs.create(root, "Docs", VfsFileType::Directory).unwrap();
+        assert_eq!(fs.create(root, "DOCS", VfsFileType::Regular), Err(VfsError::AlreadyExists));
+        for i in 0..60 {
+            fs.create(dir, &alloc::format!("note {}.txt", i), VfsFileType::Regular).unwrap();
+        }
+        assert_eq!(stream(&fs, dir).clusters, 2);
+        assert_eq!(fs.readdir(dir).unwrap().len(), 60);
+        assert_eq!(fs.unlink(root, "docs"), Err(VfsError::NotEmpty));
+
+        // Renames
+        fs.rename(root, "other", dir, "moved").unwrap();
+        assert_eq!(fs.lookup(dir, "MOVED").unwrap(), other);
+        assert_eq!(fs.lookup(root, "other"), Err(VfsError::NotFound));
+        fs.rename(dir, "moved", dir, "Moved").unwrap();
+        assert_eq!(fs.readdir(dir).unwrap().iter().filter(|e| e.name == "Moved").count(), 1);
+        assert_eq!(fs.rename(root, "Docs", dir, "inside"), Err(VfsError::InvalidArgument));
+        assert_eq!(fs.rename(root, "sparse", root, "Docs"), Err(VfsError::IsADirectory));
+        fs.rename(dir, "note 0.txt", dir, "note 1.txt").unwrap();
+        assert_eq!(fs.readdir(dir).unwrap().len(), 60);
+
+        // Unlinking frees the clusters
+        fs.unlink(root, long).unwrap();
+        assert_eq!(fs.lookup(root, long), Err(VfsError::NotFound));
+        let used = 3 + 1 + 2; // system clusters, "moved" and the directory
+        assert_eq!(fs.statfs().unwrap().bfree, CLUSTERS as u64 - used);
+
+        // Everything is on disk after a remount
+        fs.unmount().unwrap();
+        assert_eq!(fs.create(root, "late", VfsFileType::Regular), Err(VfsError::ReadOnlyFs));
+        assert_eq!(le16(&disk.data.lock(), BS_VOLUME_FLAGS) & VOLUME_DIRTY, 0);
+        let fs = mount(&disk);
+        assert_eq!(fs.statfs().unwrap().bfree, CLUSTERS as u64 - used);
+        let dir = fs.lookup(root, "docs").unwrap();
+        let moved = fs.lookup(dir, "moved").unwrap();
+        assert_eq!(fs.read(moved, 0, 100).unwrap(), b"in the way");
+        let names: Vec<String> = fs.readdir(root).unwrap().into_iter().map(|e| e.name).collect();
+        assert_eq!(names, ["sparse", "Docs"]);
+    }
+
+    #[test]
+    fn test_damaged_volume() {
+        let mut image = format();
+        image[BS_ROOT_CLUSTER] = 5;
+        let disk = Arc::new(RamDisk::new("exfat0", image));
+        assert_eq!(ExfatFs::new(disk.clone()).mount(), Err(VfsError::IoError));
+
+        let mut image = format();
+        image[cluster_offset(3)] ^= 1;
+        let disk = Arc::new(RamDisk::new("exfat0", image));
+        assert_eq!(ExfatFs::new(disk).mount(), Err(VfsError::IoError));
+    }
+}
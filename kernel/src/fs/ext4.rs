@@ -1,6 +1,7 @@
-//! # ext4 Read-Only Filesystem
+//! # ext4 Filesystem
 //!
-//! Read-only ext4 filesystem implementation for Splax OS.
+//! ext4 filesystem implementation for Splax OS, compatible with volumes
+//! created and checked by `mkfs.ext4` and `e2fsck`.
 //!
 //! ## Features
 //!
@@ -10,24 +11,51 @@
 //! - File reading (extent-based and indirect blocks)
 //! - Symlink following
 //! - Large file support (>4GB)
+//! - Block and inode allocation from the group bitmaps
+//! - Extent tree insertion, splitting and truncation
+//! - Directory entry insertion, including htree (indexed) directories
+//! - Metadata checksums (`metadata_csum`, `gdt_csum`)
+//! - Extended attributes, in the inode and in an attribute block
+//! - jbd2 journaling: replay on mount, journaled metadata transactions
 //!
-//! ## Limitations (Read-Only)
+//! ## Design
 //!
-//! - No write support
-//! - No journaling
-//! - No extended attributes (xattr)
-//! - No quota support
+//! Every modifying operation runs in a [`Transaction`]: metadata blocks
+//! (bitmaps, inode tables, group descriptors, extent and directory blocks,
+//! the superblock) are changed in memory, checksummed, then committed to
+//! the journal and checkpointed in one go (see [`super::jbd2`]). File data
+//! is written in place before the commit, as in Linux's `data=ordered`
+//! mode, and blocks freed by a transaction are not reused until it has
+//! committed.
+//!
+//! The superblock carries `needs_recovery` while the volume is mounted
+//! read-write, so `e2fsck` and Linux replay the journal after a crash.
+//!
+//! ## Limitations
+//!
+//! - Writes need the `extents` feature; block-mapped files stay read-only
+//! - No inline data, `bigalloc`, `meta_bg`, quota or encryption
+//! - Volumes with unknown read-only-compatible features mount read-only
+//! - New directories are linear; existing htree directories are kept
+//!   indexed, up to two index levels
+//! - Extended attribute values stored in their own inodes (`ea_inode`)
+//!   are not supported
 
-use alloc::collections::BTreeMap;
+use alloc::collections::btree_map::Entry;
+use alloc::collections::{BTreeMap, BTreeSet};
 use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec;
 use alloc::vec::Vec;
-use spin::RwLock;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::{Mutex, MutexGuard, RwLock};
 
+use super::jbd2::{Journal, JournalRun};
+use super::xattr::{self, Namespace};
 use crate::block::{BlockDevice, SECTOR_SIZE};
+use crate::crypto::hash::{crc16, crc32c};
 use crate::fs::vfs::{
-    Filesystem, InodeNum, VfsAttr, VfsDirEntry, VfsError, VfsFileType, VfsPermissions, VfsStatFs,
+    Filesystem, InodeNum, VfsAttr, VfsDirEntry, VfsError, VfsFileType, VfsPermissions, VfsStatFs, VFS,
 };
 
 /// ext4 magic number.
@@ -36,10 +64,26 @@ const EXT4_MAGIC: u16 = 0xEF53;
 /// Superblock offset from start of partition.
 const SUPERBLOCK_OFFSET: u64 = 1024;
 
+/// Size of the on-disk superblock.
+const SUPERBLOCK_SIZE: usize = 1024;
+
+/// Superblock fields past the end of [`Superblock`].
+const SB_CHECKSUM_SEED: usize = 0x270;
+const SB_CHECKSUM: usize = 0x3FC;
+
 /// ext4 inode constants.
 const EXT4_INODE_SIZE_MIN: u16 = 128;
 const EXT4_ROOT_INODE: u32 = 2;
 
+/// Inode field offsets used by checksumming.
+const INODE_GENERATION: usize = 0x64;
+const INODE_CHECKSUM_LO: usize = 0x7C;
+const INODE_EXTRA_ISIZE: usize = 0x80;
+const INODE_CHECKSUM_HI: usize = 0x82;
+
+/// Group descriptor checksum offset.
+const GD_CHECKSUM: usize = 0x1E;
+
 /// ext4 file type flags in inode mode.
 const S_IFMT: u16 = 0xF000;
 const S_IFREG: u16 = 0x8000;
@@ -50,6 +94,27 @@ const S_IFCHR: u16 = 0x2000;
 const S_IFIFO: u16 = 0x1000;
 const S_IFSOCK: u16 = 0xC000;
 
+/// Inode flags.
+const EXT4_INDEX_FL: u32 = 0x1000;
+const EXT4_HUGE_FILE_FL: u32 = 0x40000;
+const EXT4_EXTENTS_FL: u32 = 0x80000;
+const EXT4_INLINE_DATA_FL: u32 = 0x1000_0000;
+
+/// Superblock state and flags.
+const EXT4_VALID_FS: u16 = 0x0001;
+const EXT2_FLAGS_UNSIGNED_HASH: u32 = 0x0002;
+
+/// Block group flags.
+const EXT4_BG_INODE_UNINIT: u16 = 0x0001;
+const EXT4_BG_BLOCK_UNINIT: u16 = 0x0002;
+
+/// Extent tree constants.
+const EXT4_EXT_MAGIC: u16 = 0xF30A;
+const EXT_HEADER_SIZE: usize = 12;
+const EXT_ENTRY_SIZE: usize = 12;
+/// Longest initialized extent; longer lengths mark unwritten extents.
+const EXT_INIT_MAX_LEN: u32 = 32768;
+
 /// Directory entry file type in dir_entry.
 const EXT4_FT_UNKNOWN: u8 = 0;
 const EXT4_FT_REG_FILE: u8 = 1;
@@ -59,6 +124,52 @@ const EXT4_FT_BLKDEV: u8 = 4;
 const EXT4_FT_FIFO: u8 = 5;
 const EXT4_FT_SOCK: u8 = 6;
 const EXT4_FT_SYMLINK: u8 = 7;
+/// File type of the checksum record closing a directory block.
+const EXT4_FT_DIR_CSUM: u8 = 0xDE;
+
+/// Size of the checksum record at the end of a directory block.
+const DIR_TAIL_SIZE: usize = 12;
+/// Longest file name.
+const EXT4_NAME_LEN: usize = 255;
+/// Longest symlink target stored in the inode itself.
+const FAST_SYMLINK_MAX: usize = 59;
+
+/// htree root: offset of the info block and of the count/limit header.
+const DX_ROOT_INFO: usize = 0x18;
+const DX_ROOT_COUNT: usize = 0x20;
+/// htree node: offset of the count/limit header.
+const DX_NODE_COUNT: usize = 8;
+/// Size of an htree index entry (hash, block).
+const DX_ENTRY_SIZE: usize = 8;
+/// Size of the checksum tail of an htree block.
+const DX_TAIL_SIZE: usize = 8;
+
+/// Directory hash versions.
+const DX_HASH_LEGACY: u8 = 0;
+const DX_HASH_HALF_MD4: u8 = 1;
+const DX_HASH_TEA: u8 = 2;
+const DX_HASH_LEGACY_UNSIGNED: u8 = 3;
+const DX_HASH_HALF_MD4_UNSIGNED: u8 = 4;
+const DX_HASH_TEA_UNSIGNED: u8 = 5;
+
+/// Extended attribute block magic and field offsets.
+const XATTR_MAGIC: u32 = 0xEA02_0000;
+const XATTR_REFCOUNT: usize = 4;
+const XATTR_BLOCKS: usize = 8;
+const XATTR_HASH: usize = 0xC;
+const XATTR_CHECKSUM: usize = 0x10;
+/// Size of the extended attribute block header.
+const XATTR_HEADER_SIZE: usize = 0x20;
+/// Size of an extended attribute entry before its name.
+const XATTR_ENTRY_SIZE: usize = 0x10;
+
+/// ext4 name index of each attribute namespace. POSIX ACLs (2 and 3) and
+/// other indices are kept on disk but not listed.
+const XATTR_INDICES: [(Namespace, u8); 4] =
+    [(Namespace::User, 1), (Namespace::Trusted, 4), (Namespace::Security, 6), (Namespace::System, 7)];
+
+/// Bytes written per transaction by [`Filesystem::write`].
+const WRITE_CHUNK: usize = 256 * 4096;
 
 /// ext4 feature flags.
 pub mod feature {
@@ -68,7 +179,8 @@ pub mod feature {
     pub const COMPAT_EXT_ATTR: u32 = 0x0008;
     pub const COMPAT_RESIZE_INODE: u32 = 0x0010;
     pub const COMPAT_DIR_INDEX: u32 = 0x0020;
-    
+    pub const COMPAT_SPARSE_SUPER2: u32 = 0x0200;
+
     // Incompatible features
     pub const INCOMPAT_COMPRESSION: u32 = 0x0001;
     pub const INCOMPAT_FILETYPE: u32 = 0x0002;
@@ -78,8 +190,10 @@ pub mod feature {
     pub const INCOMPAT_EXTENTS: u32 = 0x0040;
     pub const INCOMPAT_64BIT: u32 = 0x0080;
     pub const INCOMPAT_FLEX_BG: u32 = 0x0200;
+    pub const INCOMPAT_CSUM_SEED: u32 = 0x2000;
+    pub const INCOMPAT_LARGEDIR: u32 = 0x4000;
     pub const INCOMPAT_INLINE_DATA: u32 = 0x8000;
-    
+
     // Read-only compatible features
     pub const RO_COMPAT_SPARSE_SUPER: u32 = 0x0001;
     pub const RO_COMPAT_LARGE_FILE: u32 = 0x0002;
@@ -90,6 +204,68 @@ pub mod feature {
     pub const RO_COMPAT_METADATA_CSUM: u32 = 0x0400;
 }
 
+/// Incompatible features a volume may have to be mounted at all.
+const MOUNT_INCOMPAT: u32 = feature::INCOMPAT_FILETYPE
+    | feature::INCOMPAT_RECOVER
+    | feature::INCOMPAT_EXTENTS
+    | feature::INCOMPAT_64BIT
+    | feature::INCOMPAT_FLEX_BG
+    | feature::INCOMPAT_CSUM_SEED
+    | feature::INCOMPAT_LARGEDIR;
+
+/// Read-only-compatible features the write path keeps consistent.
+const WRITE_RO_COMPAT: u32 = feature::RO_COMPAT_SPARSE_SUPER
+    | feature::RO_COMPAT_LARGE_FILE
+    | feature::RO_COMPAT_HUGE_FILE
+    | feature::RO_COMPAT_GDT_CSUM
+    | feature::RO_COMPAT_DIR_NLINK
+    | feature::RO_COMPAT_EXTRA_ISIZE
+    | feature::RO_COMPAT_METADATA_CSUM;
+
+/// Timestamp stored in inodes and the superblock (Unix seconds)
+fn now() -> u32 {
+    #[cfg(target_arch = "x86_64")]
+    {
+        crate::arch::x86_64::rtc::unix_timestamp() as u32
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        0
+    }
+}
+
+fn le16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn le32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+fn put_le16(data: &mut [u8], offset: usize, value: u16) {
+    data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_le32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Views an on-disk structure as bytes.
+fn as_bytes<T: Copy>(value: &T) -> &[u8] {
+    // SAFETY: the on-disk structures are packed plain data
+    unsafe { core::slice::from_raw_parts(value as *const T as *const u8, core::mem::size_of::<T>()) }
+}
+
+/// Decodes an on-disk structure, zero-filling the bytes `data` lacks.
+fn from_bytes<T: Copy>(data: &[u8]) -> T {
+    let size = core::mem::size_of::<T>();
+    let mut buffer = vec![0u8; size];
+    let len = size.min(data.len());
+    buffer[..len].copy_from_slice(&data[..len]);
+    // SAFETY: the on-disk structures are packed plain data valid for any bytes
+    unsafe { core::ptr::read_unaligned(buffer.as_ptr() as *const T) }
+}
+
 /// ext4 superblock.
 #[repr(C, packed)]
 #[derive(Debug, Clone, Copy)]
@@ -244,9 +420,46 @@ impl Superblock {
         (self.free_blocks_count_hi as u64) << 32 | (self.free_blocks_count_lo as u64)
     }
     
+    /// Sets the free block count (64-bit).
+    pub fn set_free_blocks_count(&mut self, count: u64) {
+        self.free_blocks_count_lo = count as u32;
+        self.free_blocks_count_hi = (count >> 32) as u32;
+    }
+    
     /// Returns the number of block groups.
     pub fn group_count(&self) -> u32 {
-        (self.blocks_count_lo + self.blocks_per_group - 1) / self.blocks_per_group
+        let data_blocks = self.blocks_count() - self.first_data_block as u64;
+        data_blocks.div_ceil(self.blocks_per_group as u64) as u32
+    }
+    
+    /// Returns the first block of a group.
+    pub fn group_first_block(&self, group: u32) -> u64 {
+        self.first_data_block as u64 + group as u64 * self.blocks_per_group as u64
+    }
+    
+    /// Returns the number of blocks in a group (the last one may be short).
+    pub fn group_blocks(&self, group: u32) -> u32 {
+        let remaining = self.blocks_count() - self.group_first_block(group);
+        remaining.min(self.blocks_per_group as u64) as u32
+    }
+    
+    /// Checks if a group holds a superblock backup.
+    pub fn group_has_super(&self, group: u32) -> bool {
+        if group <= 1 || !self.has_feature_ro_compat(feature::RO_COMPAT_SPARSE_SUPER) {
+            return true;
+        }
+        [3u32, 5, 7].iter().any(|&base| {
+            let mut power = base;
+            while power < group {
+                power *= base;
+            }
+            power == group
+        })
+    }
+    
+    /// Checks if a compatible feature is set.
+    pub fn has_feature_compat(&self, feature: u32) -> bool {
+        (self.feature_compat & feature) != 0
     }
     
     /// Checks if a feature is supported.
@@ -254,6 +467,21 @@ impl Superblock {
         (self.feature_incompat & feature) != 0
     }
     
+    /// Checks if a read-only compatible feature is set.
+    pub fn has_feature_ro_compat(&self, feature: u32) -> bool {
+        (self.feature_ro_compat & feature) != 0
+    }
+    
+    /// Checks if metadata blocks carry CRC-32C checksums.
+    pub fn metadata_csum(&self) -> bool {
+        self.has_feature_ro_compat(feature::RO_COMPAT_METADATA_CSUM)
+    }
+    
+    /// Checks if group descriptors carry a checksum (and may be uninitialized).
+    pub fn group_desc_csum(&self) -> bool {
+        self.has_feature_ro_compat(feature::RO_COMPAT_METADATA_CSUM | feature::RO_COMPAT_GDT_CSUM)
+    }
+    
     /// Checks if the filesystem uses 64-bit block numbers.
     pub fn is_64bit(&self) -> bool {
         self.has_feature_incompat(feature::INCOMPAT_64BIT)
@@ -319,6 +547,24 @@ pub struct GroupDesc {
 }
 
 impl GroupDesc {
+    /// Returns the block bitmap block number.
+    pub fn block_bitmap(&self, is_64bit: bool) -> u64 {
+        if is_64bit {
+            (self.block_bitmap_hi as u64) << 32 | (self.block_bitmap_lo as u64)
+        } else {
+            self.block_bitmap_lo as u64
+        }
+    }
+    
+    /// Returns the inode bitmap block number.
+    pub fn inode_bitmap(&self, is_64bit: bool) -> u64 {
+        if is_64bit {
+            (self.inode_bitmap_hi as u64) << 32 | (self.inode_bitmap_lo as u64)
+        } else {
+            self.inode_bitmap_lo as u64
+        }
+    }
+    
     /// Returns the inode table block number.
     pub fn inode_table(&self, is_64bit: bool) -> u64 {
         if is_64bit {
@@ -327,6 +573,83 @@ impl GroupDesc {
             self.inode_table_lo as u64
         }
     }
+    
+    /// Returns the free block count.
+    pub fn free_blocks(&self, is_64bit: bool) -> u32 {
+        join16(self.free_blocks_count_lo, self.free_blocks_count_hi, is_64bit)
+    }
+    
+    /// Sets the free block count.
+    pub fn set_free_blocks(&mut self, count: u32, is_64bit: bool) {
+        self.free_blocks_count_lo = count as u16;
+        if is_64bit {
+            self.free_blocks_count_hi = (count >> 16) as u16;
+        }
+    }
+    
+    /// Returns the free inode count.
+    pub fn free_inodes(&self, is_64bit: bool) -> u32 {
+        join16(self.free_inodes_count_lo, self.free_inodes_count_hi, is_64bit)
+    }
+    
+    /// Sets the free inode count.
+    pub fn set_free_inodes(&mut self, count: u32, is_64bit: bool) {
+        self.free_inodes_count_lo = count as u16;
+        if is_64bit {
+            self.free_inodes_count_hi = (count >> 16) as u16;
+        }
+    }
+    
+    /// Returns the directory count.
+    pub fn used_dirs(&self, is_64bit: bool) -> u32 {
+        join16(self.used_dirs_count_lo, self.used_dirs_count_hi, is_64bit)
+    }
+    
+    /// Sets the directory count.
+    pub fn set_used_dirs(&mut self, count: u32, is_64bit: bool) {
+        self.used_dirs_count_lo = count as u16;
+        if is_64bit {
+            self.used_dirs_count_hi = (count >> 16) as u16;
+        }
+    }
+    
+    /// Returns the number of never-used inodes at the end of the table.
+    pub fn itable_unused(&self, is_64bit: bool) -> u32 {
+        join16(self.itable_unused_lo, self.itable_unused_hi, is_64bit)
+    }
+    
+    /// Sets the number of never-used inodes at the end of the table.
+    pub fn set_itable_unused(&mut self, count: u32, is_64bit: bool) {
+        self.itable_unused_lo = count as u16;
+        if is_64bit {
+            self.itable_unused_hi = (count >> 16) as u16;
+        }
+    }
+    
+    /// Sets the block bitmap checksum.
+    pub fn set_block_bitmap_csum(&mut self, csum: u32, is_64bit: bool) {
+        self.block_bitmap_csum_lo = csum as u16;
+        if is_64bit {
+            self.block_bitmap_csum_hi = (csum >> 16) as u16;
+        }
+    }
+    
+    /// Sets the inode bitmap checksum.
+    pub fn set_inode_bitmap_csum(&mut self, csum: u32, is_64bit: bool) {
+        self.inode_bitmap_csum_lo = csum as u16;
+        if is_64bit {
+            self.inode_bitmap_csum_hi = (csum >> 16) as u16;
+        }
+    }
+}
+
+/// Joins the halves of a group descriptor counter.
+fn join16(lo: u16, hi: u16, is_64bit: bool) -> u32 {
+    if is_64bit {
+        (hi as u32) << 16 | lo as u32
+    } else {
+        lo as u32
+    }
 }
 
 /// ext4 inode (on-disk).
@@ -397,6 +720,47 @@ impl Inode {
         (hi as u64) << 32 | (lo as u64)
     }
     
+    /// Sets the file size.
+    pub fn set_size(&mut self, size: u64) {
+        self.size_lo = size as u32;
+        self.size_high = (size >> 32) as u32;
+    }
+    
+    /// Returns the block count in `i_blocks` units.
+    pub fn i_blocks(&self) -> u64 {
+        let osd2 = self.osd2;
+        (u16::from_le_bytes([osd2[0], osd2[1]]) as u64) << 32 | self.blocks_lo as u64
+    }
+    
+    /// Sets the block count in `i_blocks` units.
+    pub fn set_i_blocks(&mut self, blocks: u64) {
+        let mut osd2 = self.osd2;
+        osd2[..2].copy_from_slice(&((blocks >> 32) as u16).to_le_bytes());
+        self.osd2 = osd2;
+        self.blocks_lo = blocks as u32;
+    }
+    
+    /// Returns the extended attribute block, 0 if none.
+    pub fn file_acl(&self) -> u64 {
+        let osd2 = self.osd2;
+        self.file_acl_lo as u64 | (le16(&osd2, 2) as u64) << 32
+    }
+
+    /// Sets the extended attribute block.
+    pub fn set_file_acl(&mut self, block: u64) {
+        let mut osd2 = self.osd2;
+        put_le16(&mut osd2, 2, (block >> 32) as u16);
+        self.osd2 = osd2;
+        self.file_acl_lo = block as u32;
+    }
+
+    /// Bytes of the raw inode holding in-inode extended attributes, if it
+    /// has room for any.
+    fn xattr_area(&self, inode_size: usize) -> Option<core::ops::Range<usize>> {
+        let start = EXT4_INODE_SIZE_MIN as usize + self.extra_isize as usize;
+        (inode_size > EXT4_INODE_SIZE_MIN as usize && start + 8 <= inode_size).then_some(start..inode_size)
+    }
+
     /// Returns a copy of the block data (to avoid alignment issues with packed struct).
     pub fn block_data(&self) -> [u32; 15] {
         let mut data = [0u32; 15];
@@ -422,6 +786,15 @@ impl Inode {
         bytes
     }
     
+    /// Replaces the block map or extent tree root.
+    pub fn set_block_bytes(&mut self, bytes: &[u8; 60]) {
+        let mut block = [0u32; 15];
+        for (i, word) in block.iter_mut().enumerate() {
+            *word = le32(bytes, i * 4);
+        }
+        self.block = block;
+    }
+    
     /// Returns the file type.
     pub fn file_type(&self) -> VfsFileType {
         let mode = { self.mode };
@@ -440,7 +813,12 @@ impl Inode {
     /// Checks if this inode uses extents.
     pub fn uses_extents(&self) -> bool {
         let flags = { self.flags };
-        (flags & 0x80000) != 0 // EXT4_EXTENTS_FL
+        (flags & EXT4_EXTENTS_FL) != 0
+    }
+    
+    /// Checks if this is a symlink whose target is stored in the inode.
+    pub fn is_fast_symlink(&self) -> bool {
+        self.file_type() == VfsFileType::Symlink && !self.uses_extents() && self.size() <= FAST_SYMLINK_MAX as u64
     }
     
     /// Returns permissions.
@@ -448,7 +826,7 @@ impl Inode {
         let mode = { self.mode };
         VfsPermissions {
             readable: (mode & 0o444) != 0,
-            writable: false, // Read-only filesystem
+            writable: (mode & 0o222) != 0,
             executable: (mode & 0o111) != 0,
         }
     }
@@ -492,8 +870,18 @@ impl Extent {
     
     /// Returns the extent length.
     pub fn length(&self) -> u32 {
-        // High bit indicates uninitialized extent
-        (self.len & 0x7FFF) as u32
+        // Lengths above 32768 mark unwritten (preallocated) extents
+        let len = self.len as u32;
+        if len > EXT_INIT_MAX_LEN {
+            len - EXT_INIT_MAX_LEN
+        } else {
+            len
+        }
+    }
+    
+    /// Checks if the extent is preallocated but unwritten (reads as zeros).
+    pub fn is_unwritten(&self) -> bool {
+        self.len as u32 > EXT_INIT_MAX_LEN
     }
 }
 
@@ -533,389 +921,2766 @@ pub struct DirEntry {
     // Name follows (variable length)
 }
 
-/// ext4 filesystem.
-pub struct Ext4Fs {
-    /// Block device
-    device: Arc<dyn BlockDevice + Send + Sync>,
-    /// Superblock
-    superblock: RwLock<Option<Superblock>>,
-    /// Block size
-    block_size: spin::Mutex<u32>,
-    /// Inode size
-    inode_size: spin::Mutex<u16>,
-    /// Group descriptors
-    group_descs: RwLock<Vec<GroupDesc>>,
-    /// Inode cache
-    inode_cache: RwLock<BTreeMap<u32, Inode>>,
-}
+const _: () = assert!(core::mem::size_of::<Superblock>() == 0x180);
+const _: () = assert!(core::mem::size_of::<GroupDesc>() == 64);
+const _: () = assert!(core::mem::size_of::<Inode>() == 160);
 
-impl Ext4Fs {
-    /// Creates a new ext4 filesystem.
-    pub fn new(device: Arc<dyn BlockDevice + Send + Sync>) -> Self {
-        Self {
-            device,
-            superblock: RwLock::new(None),
-            block_size: spin::Mutex::new(4096),
-            inode_size: spin::Mutex::new(256),
-            group_descs: RwLock::new(Vec::new()),
-            inode_cache: RwLock::new(BTreeMap::new()),
+/// Computes an inode checksum over its raw on-disk bytes.
+fn inode_checksum(csum_seed: u32, ino: u32, raw: &[u8]) -> u32 {
+    let mut csum = crc32c(csum_seed, &ino.to_le_bytes());
+    csum = crc32c(csum, &raw[INODE_GENERATION..INODE_GENERATION + 4]);
+    csum = crc32c(csum, &raw[..INODE_CHECKSUM_LO]);
+    csum = crc32c(csum, &[0, 0]);
+    csum = crc32c(csum, &raw[INODE_CHECKSUM_LO + 2..EXT4_INODE_SIZE_MIN as usize]);
+    if raw.len() > EXT4_INODE_SIZE_MIN as usize {
+        csum = crc32c(csum, &raw[EXT4_INODE_SIZE_MIN as usize..INODE_CHECKSUM_HI]);
+        let mut offset = INODE_CHECKSUM_HI;
+        if le16(raw, INODE_EXTRA_ISIZE) >= 4 {
+            csum = crc32c(csum, &[0, 0]);
+            offset += 2;
         }
+        csum = crc32c(csum, &raw[offset..]);
     }
-    
-    /// Mounts the filesystem.
-    pub fn mount(&self) -> Result<(), VfsError> {
-        // Read superblock
-        let sb = self.read_superblock()?;
-        
-        // Verify magic number
-        if sb.magic != EXT4_MAGIC {
-            return Err(VfsError::InvalidArgument);
+    csum
+}
+
+/// Decodes an inode, ignoring in-inode xattr space past `i_extra_isize`.
+fn parse_inode(raw: &[u8]) -> Inode {
+    let base = EXT4_INODE_SIZE_MIN as usize;
+    let len = if raw.len() > base {
+        (base + le16(raw, INODE_EXTRA_ISIZE) as usize).min(raw.len())
+    } else {
+        base.min(raw.len())
+    };
+    from_bytes(&raw[..len])
+}
+
+/// Stores `sb` in a raw superblock and updates its checksum.
+fn encode_superblock(sb: &Superblock, raw: &mut [u8]) {
+    raw[..core::mem::size_of::<Superblock>()].copy_from_slice(as_bytes(sb));
+    if sb.metadata_csum() {
+        let csum = crc32c(!0, &raw[..SB_CHECKSUM]);
+        put_le32(raw, SB_CHECKSUM, csum);
+    }
+}
+
+fn test_bit(bitmap: &[u8], bit: usize) -> bool {
+    bitmap[bit / 8] & (1 << (bit % 8)) != 0
+}
+
+fn set_bit(bitmap: &mut [u8], bit: usize) {
+    bitmap[bit / 8] |= 1 << (bit % 8);
+}
+
+fn clear_bit(bitmap: &mut [u8], bit: usize) {
+    bitmap[bit / 8] &= !(1 << (bit % 8));
+}
+
+/// Maps a directory entry file type to the VFS one.
+fn vfs_file_type(file_type: u8) -> VfsFileType {
+    match file_type {
+        EXT4_FT_REG_FILE => VfsFileType::Regular,
+        EXT4_FT_DIR => VfsFileType::Directory,
+        EXT4_FT_SYMLINK => VfsFileType::Symlink,
+        EXT4_FT_CHRDEV => VfsFileType::CharDevice,
+        EXT4_FT_BLKDEV => VfsFileType::BlockDevice,
+        EXT4_FT_FIFO => VfsFileType::Fifo,
+        EXT4_FT_SOCK => VfsFileType::Socket,
+        _ => VfsFileType::Regular,
+    }
+}
+
+/// Maps a VFS file type to the directory entry one.
+fn dirent_file_type(file_type: VfsFileType) -> u8 {
+    match file_type {
+        VfsFileType::Regular => EXT4_FT_REG_FILE,
+        VfsFileType::Directory => EXT4_FT_DIR,
+        VfsFileType::Symlink => EXT4_FT_SYMLINK,
+        VfsFileType::CharDevice => EXT4_FT_CHRDEV,
+        VfsFileType::BlockDevice => EXT4_FT_BLKDEV,
+        VfsFileType::Fifo => EXT4_FT_FIFO,
+        VfsFileType::Socket => EXT4_FT_SOCK,
+    }
+}
+
+/// Rejects names a directory entry cannot hold.
+fn check_name(name: &str) -> Result<(), VfsError> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        return Err(VfsError::InvalidArgument);
+    }
+    if name.len() > EXT4_NAME_LEN {
+        return Err(VfsError::PathTooLong);
+    }
+    Ok(())
+}
+
+// ============================================================================
+// Extent tree nodes
+// ============================================================================
+
+/// Where an extent tree node lives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NodeAt {
+    /// In the inode's `i_block`
+    Root,
+    /// In a block of its own
+    Block(u64),
+}
+
+/// A decoded extent tree node.
+#[derive(Debug, Clone)]
+struct Node {
+    at: NodeAt,
+    depth: u16,
+    max: u16,
+    /// Extents (depth 0) or indexes, as stored
+    entries: Vec<[u8; EXT_ENTRY_SIZE]>,
+}
+
+impl Node {
+    fn parse(at: NodeAt, data: &[u8]) -> Result<Self, VfsError> {
+        if data.len() < EXT_HEADER_SIZE || le16(data, 0) != EXT4_EXT_MAGIC {
+            return Err(VfsError::IoError);
         }
-        
-        // Check for unsupported incompatible features
-        let unsupported = sb.feature_incompat & 
-            !(feature::INCOMPAT_FILETYPE | 
-              feature::INCOMPAT_EXTENTS | 
-              feature::INCOMPAT_64BIT | 
-              feature::INCOMPAT_FLEX_BG);
-        if unsupported != 0 {
-            crate::serial_println!("[ext4] Unsupported features: {:#x}", unsupported);
-            return Err(VfsError::NotSupported);
+        let count = le16(data, 2) as usize;
+        let max = le16(data, 4);
+        let depth = le16(data, 6);
+        if count > max as usize || EXT_HEADER_SIZE + max as usize * EXT_ENTRY_SIZE > data.len() || depth > 5 {
+            return Err(VfsError::IoError);
         }
-        
-        let block_size = sb.block_size();
-        let inode_size = if sb.rev_level >= 1 { sb.inode_size } else { EXT4_INODE_SIZE_MIN };
-        
-        // Copy fields from packed struct before using
-        let total_blocks = sb.blocks_count();
-        let total_inodes = { sb.inodes_count };
-        let uses_extents = sb.uses_extents();
-        
-        *self.block_size.lock() = block_size;
-        *self.inode_size.lock() = inode_size;
-        
-        crate::serial_println!(
-            "[ext4] Mounted: {} blocks, {} inodes, block_size={}, uses_extents={}",
-            total_blocks, total_inodes, block_size, uses_extents
-        );
-        
-        // Read group descriptors
-        let group_count = sb.group_count();
-        let desc_size = if sb.is_64bit() && sb.desc_size > 32 {
-            sb.desc_size as usize
-        } else {
-            32
-        };
-        
-        let gd_block = if block_size == 1024 { 2 } else { 1 };
-        let mut group_descs = Vec::with_capacity(group_count as usize);
-        
-        for i in 0..group_count {
-            let offset = gd_block as u64 * block_size as u64 + i as u64 * desc_size as u64;
-            let gd = self.read_struct::<GroupDesc>(offset)?;
-            group_descs.push(gd);
+        let entries = (0..count)
+            .map(|i| {
+                let offset = EXT_HEADER_SIZE + i * EXT_ENTRY_SIZE;
+                let mut entry = [0u8; EXT_ENTRY_SIZE];
+                entry.copy_from_slice(&data[offset..offset + EXT_ENTRY_SIZE]);
+                entry
+            })
+            .collect();
+        Ok(Self { at, depth, max, entries })
+    }
+
+    fn encode(&self, data: &mut [u8]) {
+        put_le16(data, 0, EXT4_EXT_MAGIC);
+        put_le16(data, 2, self.entries.len() as u16);
+        put_le16(data, 4, self.max);
+        put_le16(data, 6, self.depth);
+        for (i, entry) in self.entries.iter().enumerate() {
+            let offset = EXT_HEADER_SIZE + i * EXT_ENTRY_SIZE;
+            data[offset..offset + EXT_ENTRY_SIZE].copy_from_slice(entry);
         }
-        
-        *self.group_descs.write() = group_descs;
-        *self.superblock.write() = Some(sb);
-        
-        Ok(())
     }
-    
-    /// Reads the superblock.
-    fn read_superblock(&self) -> Result<Superblock, VfsError> {
-        self.read_struct::<Superblock>(SUPERBLOCK_OFFSET)
+
+    fn first_key(&self) -> Option<u32> {
+        self.entries.first().map(|entry| le32(entry, 0))
     }
-    
-    /// Reads a structure from disk.
-    fn read_struct<T: Copy>(&self, offset: u64) -> Result<T, VfsError> {
-        let size = core::mem::size_of::<T>();
-        let mut buffer = vec![0u8; size];
-        
-        let sector = offset / SECTOR_SIZE as u64;
-        let sector_offset = (offset % SECTOR_SIZE as u64) as usize;
-        
-        // Read enough sectors
-        let sectors_needed = (sector_offset + size + SECTOR_SIZE - 1) / SECTOR_SIZE;
-        let mut sector_buffer = vec![0u8; sectors_needed * SECTOR_SIZE];
-        
-        self.device.read_sectors(sector, &mut sector_buffer)
-            .map_err(|_| VfsError::IoError)?;
-        
-        buffer.copy_from_slice(&sector_buffer[sector_offset..sector_offset + size]);
-        
-        Ok(unsafe { core::ptr::read(buffer.as_ptr() as *const T) })
+}
+
+/// Child block of an index entry.
+fn index_child(entry: &[u8]) -> u64 {
+    le32(entry, 4) as u64 | (le16(entry, 8) as u64) << 32
+}
+
+/// Builds an index entry.
+fn index_entry(key: u32, child: u64) -> [u8; EXT_ENTRY_SIZE] {
+    let mut entry = [0u8; EXT_ENTRY_SIZE];
+    put_le32(&mut entry, 0, key);
+    put_le32(&mut entry, 4, child as u32);
+    put_le16(&mut entry, 8, (child >> 32) as u16);
+    entry
+}
+
+/// A decoded leaf extent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Mapping {
+    /// First file block
+    lblk: u32,
+    /// Number of blocks
+    len: u32,
+    /// First device block
+    pblk: u64,
+    /// Allocated but reads as zeros
+    unwritten: bool,
+}
+
+impl Mapping {
+    fn decode(entry: &[u8]) -> Self {
+        let raw_len = le16(entry, 4) as u32;
+        let unwritten = raw_len > EXT_INIT_MAX_LEN;
+        Self {
+            lblk: le32(entry, 0),
+            len: if unwritten { raw_len - EXT_INIT_MAX_LEN } else { raw_len },
+            pblk: (le16(entry, 6) as u64) << 32 | le32(entry, 8) as u64,
+            unwritten,
+        }
     }
-    
-    /// Reads bytes from disk.
-    fn read_bytes(&self, offset: u64, buffer: &mut [u8]) -> Result<(), VfsError> {
-        let sector = offset / SECTOR_SIZE as u64;
-        let sector_offset = (offset % SECTOR_SIZE as u64) as usize;
-        
-        let sectors_needed = (sector_offset + buffer.len() + SECTOR_SIZE - 1) / SECTOR_SIZE;
-        let mut sector_buffer = vec![0u8; sectors_needed * SECTOR_SIZE];
-        
+
+    fn encode(&self) -> [u8; EXT_ENTRY_SIZE] {
+        let mut entry = [0u8; EXT_ENTRY_SIZE];
+        let raw_len = if self.unwritten { self.len + EXT_INIT_MAX_LEN } else { self.len };
+        put_le32(&mut entry, 0, self.lblk);
+        put_le16(&mut entry, 4, raw_len as u16);
+        put_le16(&mut entry, 6, (self.pblk >> 32) as u16);
+        put_le32(&mut entry, 8, self.pblk as u32);
+        entry
+    }
+
+    /// File block after the extent.
+    fn end(&self) -> u64 {
+        self.lblk as u64 + self.len as u64
+    }
+
+    /// Whether `next` continues this extent in the file and on disk.
+    fn can_merge(&self, next: &Mapping) -> bool {
+        !self.unwritten
+            && !next.unwritten
+            && self.end() == next.lblk as u64
+            && self.pblk + self.len as u64 == next.pblk
+            && self.len + next.len <= EXT_INIT_MAX_LEN
+    }
+}
+
+// ============================================================================
+// Directory blocks
+// ============================================================================
+
+/// A directory record inside a block.
+#[derive(Debug, Clone, Copy)]
+struct Record {
+    offset: usize,
+    inode: u32,
+    rec_len: usize,
+    name_len: usize,
+    file_type: u8,
+}
+
+impl Record {
+    fn name<'a>(&self, data: &'a [u8]) -> &'a [u8] {
+        &data[self.offset + 8..self.offset + 8 + self.name_len]
+    }
+}
+
+/// Space a record with a `name_len`-byte name needs.
+fn record_size(name_len: usize) -> usize {
+    (8 + name_len + 3) & !3
+}
+
+/// Walks the records of a directory block up to `end`, stopping at the
+/// first malformed one.
+fn records(data: &[u8], end: usize) -> Vec<Record> {
+    let mut records = Vec::new();
+    let mut offset = 0;
+    while offset + 8 <= end {
+        let rec_len = le16(data, offset + 4) as usize;
+        let name_len = data[offset + 6] as usize;
+        if rec_len < 8 || offset + rec_len > end || 8 + name_len > rec_len {
+            break;
+        }
+        records.push(Record {
+            offset,
+            inode: le32(data, offset),
+            rec_len,
+            name_len,
+            file_type: data[offset + 7],
+        });
+        offset += rec_len;
+    }
+    records
+}
+
+fn put_record(data: &mut [u8], offset: usize, inode: u32, rec_len: usize, name: &[u8], file_type: u8) {
+    put_le32(data, offset, inode);
+    put_le16(data, offset + 4, rec_len as u16);
+    data[offset + 6] = name.len() as u8;
+    data[offset + 7] = file_type;
+    data[offset + 8..offset + 8 + name.len()].copy_from_slice(name);
+}
+
+/// Adds a record in the first gap that fits. Returns false if there is none.
+fn add_record(data: &mut [u8], end: usize, name: &[u8], inode: u32, file_type: u8) -> bool {
+    let need = record_size(name.len());
+    for record in records(data, end) {
+        if record.inode == 0 && record.rec_len >= need {
+            put_record(data, record.offset, inode, record.rec_len, name, file_type);
+            return true;
+        }
+        let used = record_size(record.name_len);
+        if record.inode != 0 && record.rec_len >= used + need {
+            put_le16(data, record.offset + 4, used as u16);
+            put_record(data, record.offset + used, inode, record.rec_len - used, name, file_type);
+            return true;
+        }
+    }
+    false
+}
+
+/// Removes the record named `name`, merging its space into the previous
+/// record. Returns its inode.
+fn remove_record(data: &mut [u8], end: usize, name: &[u8]) -> Option<u32> {
+    let records = records(data, end);
+    let index = records.iter().position(|r| r.inode != 0 && r.name(data) == name)?;
+    let record = records[index];
+    match index.checked_sub(1) {
+        Some(previous) => {
+            let previous = records[previous];
+            put_le16(data, previous.offset + 4, (previous.rec_len + record.rec_len) as u16);
+        }
+        None => put_le32(data, record.offset, 0),
+    }
+    Some(record.inode)
+}
+
+/// A live directory entry, as moved between htree leaves.
+struct DirItem {
+    hash: u32,
+    inode: u32,
+    file_type: u8,
+    name: Vec<u8>,
+}
+
+/// Builds a directory block holding `items`, the last record running to `end`.
+fn build_leaf(block_size: usize, end: usize, items: &[DirItem]) -> Vec<u8> {
+    let mut data = vec![0u8; block_size];
+    if items.is_empty() {
+        put_record(&mut data, 0, 0, end, b"", EXT4_FT_UNKNOWN);
+        return data;
+    }
+    let mut offset = 0;
+    for (i, item) in items.iter().enumerate() {
+        let rec_len = if i + 1 == items.len() { end - offset } else { record_size(item.name.len()) };
+        put_record(&mut data, offset, item.inode, rec_len, &item.name, item.file_type);
+        offset += rec_len;
+    }
+    data
+}
+
+/// An htree index block (root or node) on the way to a leaf.
+struct DxFrame {
+    /// Device block
+    block: u64,
+    data: Vec<u8>,
+    /// Offset of the count/limit header
+    count_offset: usize,
+    /// Entry followed towards the leaf
+    at: usize,
+}
+
+impl DxFrame {
+    fn new(block: u64, data: Vec<u8>, count_offset: usize, hash: u32) -> Result<Self, VfsError> {
+        let mut frame = Self { block, data, count_offset, at: 0 };
+        let (count, limit) = (frame.count(), frame.limit());
+        if count == 0 || count > limit || count_offset + limit * DX_ENTRY_SIZE > frame.data.len() {
+            return Err(VfsError::IoError);
+        }
+        // Entry 0 has an implicit hash of 0
+        frame.at = (1..count).take_while(|&i| frame.hash(i) <= hash).count();
+        Ok(frame)
+    }
+
+    fn limit(&self) -> usize {
+        le16(&self.data, self.count_offset) as usize
+    }
+
+    fn count(&self) -> usize {
+        le16(&self.data, self.count_offset + 2) as usize
+    }
+
+    fn hash(&self, index: usize) -> u32 {
+        le32(&self.data, self.count_offset + index * DX_ENTRY_SIZE)
+    }
+
+    fn child(&self, index: usize) -> u32 {
+        le32(&self.data, self.count_offset + index * DX_ENTRY_SIZE + 4) & 0x0FFF_FFFF
+    }
+
+    /// Child block of the entry followed towards the leaf.
+    fn target(&self) -> u32 {
+        self.child(self.at)
+    }
+
+    /// Inserts an entry at `index`, shifting the following ones.
+    fn insert(&mut self, index: usize, hash: u32, child: u32) {
+        let count = self.count();
+        let start = self.count_offset + index * DX_ENTRY_SIZE;
+        let end = self.count_offset + count * DX_ENTRY_SIZE;
+        self.data.copy_within(start..end, start + DX_ENTRY_SIZE);
+        put_le32(&mut self.data, start, hash);
+        put_le32(&mut self.data, start + 4, child);
+        put_le16(&mut self.data, self.count_offset + 2, (count + 1) as u16);
+    }
+}
+
+/// Writes an empty htree node holding `entries` (hash, child) pairs; the
+/// first hash is implied by the parent.
+fn build_dx_node(block_size: usize, limit: usize, entries: &[(u32, u32)]) -> Vec<u8> {
+    let mut data = vec![0u8; block_size];
+    // A node looks like one empty record spanning the block
+    put_le16(&mut data, 4, block_size as u16);
+    for (i, &(hash, child)) in entries.iter().enumerate() {
+        let offset = DX_NODE_COUNT + i * DX_ENTRY_SIZE;
+        put_le32(&mut data, offset, hash);
+        put_le32(&mut data, offset + 4, child);
+    }
+    put_le16(&mut data, DX_NODE_COUNT, limit as u16);
+    put_le16(&mut data, DX_NODE_COUNT + 2, entries.len() as u16);
+    data
+}
+
+// ============================================================================
+// Directory hashing (htree)
+// ============================================================================
+
+/// Packs up to `words` words of a name the way the ext4 hashes consume it.
+fn str2hashbuf(msg: &[u8], words: usize, signed: bool) -> [u32; 8] {
+    let len = msg.len() as u32;
+    let mut pad = len | (len << 8);
+    pad |= pad << 16;
+
+    let mut buf = [0u32; 8];
+    let mut out = 0;
+    let mut val = pad;
+    for (i, &byte) in msg.iter().take(words * 4).enumerate() {
+        let c = if signed { byte as i8 as i32 as u32 } else { byte as u32 };
+        val = c.wrapping_add(val << 8);
+        if i % 4 == 3 {
+            buf[out] = val;
+            out += 1;
+            val = pad;
+        }
+    }
+    if out < words {
+        buf[out] = val;
+        out += 1;
+    }
+    while out < words {
+        buf[out] = pad;
+        out += 1;
+    }
+    buf
+}
+
+fn half_md4_transform(buf: &mut [u32; 4], input: &[u32; 8]) {
+    const K2: u32 = 0x5A82_7999;
+    const K3: u32 = 0x6ED9_EBA1;
+    let f = |x: u32, y: u32, z: u32| z ^ (x & (y ^ z));
+    let g = |x: u32, y: u32, z: u32| (x & y).wrapping_add((x ^ y) & z);
+    let h = |x: u32, y: u32, z: u32| x ^ y ^ z;
+    let [mut a, mut b, mut c, mut d] = *buf;
+
+    macro_rules! round {
+        ($f:expr, $a:ident, $b:ident, $c:ident, $d:ident, $x:expr, $s:expr) => {
+            $a = $a.wrapping_add($f($b, $c, $d)).wrapping_add($x).rotate_left($s);
+        };
+    }
+
+    round!(f, a, b, c, d, input[0], 3);
+    round!(f, d, a, b, c, input[1], 7);
+    round!(f, c, d, a, b, input[2], 11);
+    round!(f, b, c, d, a, input[3], 19);
+    round!(f, a, b, c, d, input[4], 3);
+    round!(f, d, a, b, c, input[5], 7);
+    round!(f, c, d, a, b, input[6], 11);
+    round!(f, b, c, d, a, input[7], 19);
+
+    round!(g, a, b, c, d, input[1].wrapping_add(K2), 3);
+    round!(g, d, a, b, c, input[3].wrapping_add(K2), 5);
+    round!(g, c, d, a, b, input[5].wrapping_add(K2), 9);
+    round!(g, b, c, d, a, input[7].wrapping_add(K2), 13);
+    round!(g, a, b, c, d, input[0].wrapping_add(K2), 3);
+    round!(g, d, a, b, c, input[2].wrapping_add(K2), 5);
+    round!(g, c, d, a, b, input[4].wrapping_add(K2), 9);
+    round!(g, b, c, d, a, input[6].wrapping_add(K2), 13);
+
+    round!(h, a, b, c, d, input[3].wrapping_add(K3), 3);
+    round!(h, d, a, b, c, input[7].wrapping_add(K3), 9);
+    round!(h, c, d, a, b, input[2].wrapping_add(K3), 11);
+    round!(h, b, c, d, a, input[6].wrapping_add(K3), 15);
+    round!(h, a, b, c, d, input[1].wrapping_add(K3), 3);
+    round!(h, d, a, b, c, input[5].wrapping_add(K3), 9);
+    round!(h, c, d, a, b, input[0].wrapping_add(K3), 11);
+    round!(h, b, c, d, a, input[4].wrapping_add(K3), 15);
+
+    buf[0] = buf[0].wrapping_add(a);
+    buf[1] = buf[1].wrapping_add(b);
+    buf[2] = buf[2].wrapping_add(c);
+    buf[3] = buf[3].wrapping_add(d);
+}
+
+fn tea_transform(buf: &mut [u32; 4], input: &[u32; 8]) {
+    const DELTA: u32 = 0x9E37_79B9;
+    let (mut b0, mut b1) = (buf[0], buf[1]);
+    let [a, b, c, d] = [input[0], input[1], input[2], input[3]];
+    let mut sum = 0u32;
+    for _ in 0..16 {
+        sum = sum.wrapping_add(DELTA);
+        b0 = b0.wrapping_add(
+            (b1 << 4).wrapping_add(a) ^ b1.wrapping_add(sum) ^ (b1 >> 5).wrapping_add(b),
+        );
+        b1 = b1.wrapping_add(
+            (b0 << 4).wrapping_add(c) ^ b0.wrapping_add(sum) ^ (b0 >> 5).wrapping_add(d),
+        );
+    }
+    buf[0] = buf[0].wrapping_add(b0);
+    buf[1] = buf[1].wrapping_add(b1);
+}
+
+/// The original ext3 directory hash.
+fn dx_hack_hash(name: &[u8], signed: bool) -> u32 {
+    let (mut hash0, mut hash1) = (0x12A3_FE2Du32, 0x37AB_E8F9u32);
+    for &byte in name {
+        let c = if signed { byte as i8 as i32 } else { byte as i32 };
+        let mut hash = hash1.wrapping_add(hash0 ^ c.wrapping_mul(7_152_373) as u32);
+        if hash & 0x8000_0000 != 0 {
+            hash = hash.wrapping_sub(0x7FFF_FFFF);
+        }
+        hash1 = hash0;
+        hash0 = hash;
+    }
+    hash0 << 1
+}
+
+/// Computes the htree hash of a name, as stored in index entries.
+fn dx_hash(name: &[u8], version: u8, seed: &[u32; 4]) -> Result<u32, VfsError> {
+    let mut buf = [0x6745_2301, 0xEFCD_AB89, 0x98BA_DCFE, 0x1032_5476];
+    if seed.iter().any(|&word| word != 0) {
+        buf = *seed;
+    }
+
+    let hash = match version {
+        DX_HASH_LEGACY | DX_HASH_LEGACY_UNSIGNED => dx_hack_hash(name, version == DX_HASH_LEGACY),
+        DX_HASH_HALF_MD4 | DX_HASH_HALF_MD4_UNSIGNED => {
+            for chunk_start in (0..name.len()).step_by(32) {
+                let input = str2hashbuf(&name[chunk_start..], 8, version == DX_HASH_HALF_MD4);
+                half_md4_transform(&mut buf, &input);
+            }
+            buf[1]
+        }
+        DX_HASH_TEA | DX_HASH_TEA_UNSIGNED => {
+            for chunk_start in (0..name.len()).step_by(16) {
+                let input = str2hashbuf(&name[chunk_start..], 4, version == DX_HASH_TEA);
+                tea_transform(&mut buf, &input);
+            }
+            buf[0]
+        }
+        _ => return Err(VfsError::NotSupported),
+    };
+
+    // The low bit marks hash collisions continuing in the next leaf
+    let hash = hash & !1;
+    Ok(if hash == 0x7FFF_FFFF << 1 { (0x7FFF_FFFF - 1) << 1 } else { hash })
+}
+
+// ============================================================================
+// Extended attributes
+// ============================================================================
+
+/// An extended attribute as stored: name index, name without its prefix,
+/// value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct XattrEntry {
+    index: u8,
+    name: Vec<u8>,
+    value: Vec<u8>,
+}
+
+impl XattrEntry {
+    /// Splits a full attribute name into its index and the rest.
+    fn key(name: &str) -> Result<(u8, &[u8]), VfsError> {
+        let (namespace, rest) = xattr::check_name(name)?;
+        let &(_, index) = XATTR_INDICES
+            .iter()
+            .find(|(ns, _)| *ns == namespace)
+            .ok_or(VfsError::NotSupported)?;
+        if rest.len() > u8::MAX as usize {
+            return Err(VfsError::PathTooLong);
+        }
+        Ok((index, rest.as_bytes()))
+    }
+
+    /// Full name, if the index belongs to a namespace the VFS knows.
+    fn full_name(&self) -> Option<String> {
+        let &(namespace, _) = XATTR_INDICES.iter().find(|(_, index)| *index == self.index)?;
+        let mut name = String::from(namespace.prefix());
+        name.push_str(core::str::from_utf8(&self.name).ok()?);
+        Some(name)
+    }
+
+    /// Bytes taken by the entry and its value.
+    fn footprint(&self) -> usize {
+        xattr_entry_size(self.name.len()) + self.value.len().next_multiple_of(4)
+    }
+
+    /// Entry hash over the name and the value.
+    fn hash(&self) -> u32 {
+        let mut hash = 0u32;
+        for &byte in &self.name {
+            hash = (hash << 5) ^ (hash >> 27) ^ byte as u32;
+        }
+        for word in self.value.chunks(4) {
+            let mut padded = [0u8; 4];
+            padded[..word.len()].copy_from_slice(word);
+            hash = (hash << 16) ^ (hash >> 16) ^ u32::from_le_bytes(padded);
+        }
+        hash
+    }
+}
+
+/// Size of an entry with a name of `name_len` bytes.
+fn xattr_entry_size(name_len: usize) -> usize {
+    (XATTR_ENTRY_SIZE + name_len).next_multiple_of(4)
+}
+
+/// Reads the entries of an attribute area. They start at `start`; value
+/// offsets count from `base`.
+fn decode_xattrs(area: &[u8], start: usize, base: usize, entries: &mut Vec<XattrEntry>) -> Result<(), VfsError> {
+    let mut pos = start;
+    while pos + 4 <= area.len() && le32(area, pos) != 0 {
+        let name_len = area[pos] as usize;
+        let name_end = pos + XATTR_ENTRY_SIZE + name_len;
+        if name_end > area.len() {
+            return Err(VfsError::IoError);
+        }
+        if le32(area, pos + 4) != 0 {
+            return Err(VfsError::NotSupported);
+        }
+        let value_start = base + le16(area, pos + 2) as usize;
+        let value_end = value_start + le32(area, pos + 8) as usize;
+        if value_end > area.len() {
+            return Err(VfsError::IoError);
+        }
+        entries.push(XattrEntry {
+            index: area[pos + 1],
+            name: area[pos + XATTR_ENTRY_SIZE..name_end].to_vec(),
+            value: area[value_start..value_end].to_vec(),
+        });
+        pos += xattr_entry_size(name_len);
+    }
+    Ok(())
+}
+
+/// Lays out entries in an attribute area: entries up from `start`, values
+/// down from the end, value offsets counting from `base`. Returns the
+/// combined entry hash, or `None` if they do not fit.
+fn encode_xattrs(area: &mut [u8], start: usize, base: usize, entries: &[XattrEntry]) -> Option<u32> {
+    let mut pos = start;
+    let mut value_pos = area.len();
+    let mut block_hash = 0u32;
+    for entry in entries {
+        let size = xattr_entry_size(entry.name.len());
+        let value_size = entry.value.len().next_multiple_of(4);
+        if pos + size + 4 > value_pos.checked_sub(value_size)? {
+            return None;
+        }
+        let offset = if entry.value.is_empty() {
+            0
+        } else {
+            value_pos -= value_size;
+            area[value_pos..value_pos + entry.value.len()].copy_from_slice(&entry.value);
+            value_pos - base
+        };
+        let hash = entry.hash();
+        area[pos] = entry.name.len() as u8;
+        area[pos + 1] = entry.index;
+        put_le16(area, pos + 2, offset as u16);
+        put_le32(area, pos + 4, 0);
+        put_le32(area, pos + 8, entry.value.len() as u32);
+        put_le32(area, pos + 12, hash);
+        area[pos + XATTR_ENTRY_SIZE..pos + XATTR_ENTRY_SIZE + entry.name.len()].copy_from_slice(&entry.name);
+        pos += size;
+        block_hash = (block_hash << 16) ^ (block_hash >> 16) ^ hash;
+    }
+    Some(block_hash)
+}
+
+/// Reads an inode's extended attributes from its raw bytes and, if it has
+/// one, its attribute block.
+fn inode_xattrs(raw: &[u8], inode: &Inode, block: Option<&[u8]>) -> Result<Vec<XattrEntry>, VfsError> {
+    let mut entries = Vec::new();
+    if let Some(area) = inode.xattr_area(raw.len()) {
+        let area = &raw[area];
+        if le32(area, 0) == XATTR_MAGIC {
+            decode_xattrs(area, 4, 4, &mut entries)?;
+        }
+    }
+    if let Some(block) = block {
+        if le32(block, 0) != XATTR_MAGIC {
+            return Err(VfsError::IoError);
+        }
+        decode_xattrs(block, XATTR_HEADER_SIZE, 0, &mut entries)?;
+    }
+    Ok(entries)
+}
+
+/// ext4 filesystem.
+pub struct Ext4Fs {
+    /// Block device
+    device: Arc<dyn BlockDevice + Send + Sync>,
+    /// Superblock
+    superblock: RwLock<Option<Superblock>>,
+    /// Block size
+    block_size: Mutex<u32>,
+    /// Inode size
+    inode_size: Mutex<u16>,
+    /// Group descriptor size
+    desc_size: Mutex<usize>,
+    /// Metadata checksum seed
+    csum_seed: Mutex<u32>,
+    /// Group descriptors
+    group_descs: RwLock<Vec<GroupDesc>>,
+    /// Inode cache
+    inode_cache: RwLock<BTreeMap<u32, Inode>>,
+    /// Whether writes are refused
+    read_only: AtomicBool,
+    /// Journal, if the volume has one; locking it serializes transactions
+    journal: Mutex<Option<Journal>>,
+}
+
+impl Ext4Fs {
+    /// Creates a new ext4 filesystem.
+    pub fn new(device: Arc<dyn BlockDevice + Send + Sync>) -> Self {
+        Self {
+            device,
+            superblock: RwLock::new(None),
+            block_size: Mutex::new(4096),
+            inode_size: Mutex::new(256),
+            desc_size: Mutex::new(32),
+            csum_seed: Mutex::new(0),
+            group_descs: RwLock::new(Vec::new()),
+            inode_cache: RwLock::new(BTreeMap::new()),
+            read_only: AtomicBool::new(true),
+            journal: Mutex::new(None),
+        }
+    }
+
+    /// Mounts the filesystem, replaying the journal if it needs recovery.
+    ///
+    /// The volume is writable unless the device is read-only or it uses
+    /// features the write path does not maintain.
+    pub fn mount(&self) -> Result<(), VfsError> {
+        // Read superblock
+        let mut sb = self.read_superblock()?;
+
+        // Verify magic number
+        if sb.magic != EXT4_MAGIC {
+            return Err(VfsError::InvalidArgument);
+        }
+
+        // Check for unsupported incompatible features
+        let unsupported = sb.feature_incompat & !MOUNT_INCOMPAT;
+        if unsupported != 0 {
+            crate::serial_println!("[ext4] Unsupported features: {:#x}", unsupported);
+            return Err(VfsError::NotSupported);
+        }
+
+        let block_size = sb.block_size();
+        let inode_size = if sb.rev_level >= 1 { sb.inode_size } else { EXT4_INODE_SIZE_MIN };
+
+        *self.block_size.lock() = block_size;
+        *self.inode_size.lock() = inode_size;
+        *self.desc_size.lock() = if sb.is_64bit() && sb.desc_size > 32 {
+            sb.desc_size as usize
+        } else {
+            32
+        };
+        *self.csum_seed.lock() = if !sb.metadata_csum() {
+            0
+        } else if sb.has_feature_incompat(feature::INCOMPAT_CSUM_SEED) {
+            let mut raw = vec![0u8; SUPERBLOCK_SIZE];
+            self.read_bytes(SUPERBLOCK_OFFSET, &mut raw)?;
+            le32(&raw, SB_CHECKSUM_SEED)
+        } else {
+            crc32c(!0, &{ sb.uuid })
+        };
+        self.load_group_descs(&sb)?;
+        *self.superblock.write() = Some(sb);
+
+        let device_read_only = self.device.info().read_only;
+        let needs_recovery = sb.has_feature_incompat(feature::INCOMPAT_RECOVER);
+        let mut journal = None;
+        if sb.has_feature_compat(feature::COMPAT_HAS_JOURNAL) && sb.journal_inum != 0 {
+            match self.open_journal(sb.journal_inum) {
+                Ok(opened) => journal = Some(opened),
+                Err(e) if needs_recovery => return Err(e),
+                Err(e) => crate::serial_println!("[ext4] Journal unusable ({:?}), mounting read-only", e),
+            }
+        } else if needs_recovery {
+            return Err(VfsError::NotSupported);
+        }
+
+        if let Some(journal) = journal.as_mut() {
+            if needs_recovery || journal.needs_recovery() {
+                if device_read_only {
+                    return Err(VfsError::ReadOnlyFs);
+                }
+                journal.recover(&*self.device)?;
+                sb = self.read_superblock()?;
+                sb.feature_incompat &= !feature::INCOMPAT_RECOVER;
+                self.write_superblock(&sb)?;
+                self.load_group_descs(&sb)?;
+                *self.superblock.write() = Some(sb);
+                self.inode_cache.write().clear();
+            }
+        }
+
+        let writable = !device_read_only
+            && sb.uses_extents()
+            && block_size < 65536
+            && sb.feature_ro_compat & !WRITE_RO_COMPAT == 0
+            && !sb.has_feature_compat(feature::COMPAT_SPARSE_SUPER2)
+            && (journal.is_some() || !sb.has_feature_compat(feature::COMPAT_HAS_JOURNAL));
+        if writable {
+            if let Some(journal) = journal.as_mut() {
+                journal.enable_features(&*self.device, sb.is_64bit(), sb.metadata_csum())?;
+                sb.feature_incompat |= feature::INCOMPAT_RECOVER;
+            } else {
+                sb.state &= !EXT4_VALID_FS;
+            }
+            sb.mnt_count = sb.mnt_count.wrapping_add(1);
+            sb.mtime = now();
+            self.write_superblock(&sb)?;
+            *self.superblock.write() = Some(sb);
+        }
+        self.read_only.store(!writable, Ordering::Release);
+        *self.journal.lock() = journal;
+
+        // Copy fields from packed struct before using
+        let total_blocks = sb.blocks_count();
+        let total_inodes = { sb.inodes_count };
+        let uses_extents = sb.uses_extents();
+
+        crate::serial_println!(
+            "[ext4] Mounted: {} blocks, {} inodes, block_size={}, uses_extents={}, {}",
+            total_blocks, total_inodes, block_size, uses_extents,
+            if writable { "read-write" } else { "read-only" }
+        );
+
+        Ok(())
+    }
+
+    /// Writes the superblock back and clears `needs_recovery`. Further
+    /// writes are refused.
+    pub fn unmount(&self) -> Result<(), VfsError> {
+        // Waits for a running transaction
+        let journal = self.journal.lock();
+        if self.read_only.swap(true, Ordering::AcqRel) {
+            return Ok(());
+        }
+        let mut sb = (*self.superblock.read()).ok_or(VfsError::NoFilesystem)?;
+        if journal.is_some() {
+            sb.feature_incompat &= !feature::INCOMPAT_RECOVER;
+        } else {
+            sb.state |= EXT4_VALID_FS;
+        }
+        sb.wtime = now();
+        self.write_superblock(&sb)?;
+        *self.superblock.write() = Some(sb);
+        Ok(())
+    }
+
+    /// Whether the volume refuses writes.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::Acquire)
+    }
+
+    /// Reads the group descriptor table.
+    fn load_group_descs(&self, sb: &Superblock) -> Result<(), VfsError> {
+        let group_count = sb.group_count() as usize;
+        let desc_size = *self.desc_size.lock();
+        let table = (sb.first_data_block as u64 + 1) * sb.block_size() as u64;
+
+        let mut raw = vec![0u8; group_count * desc_size];
+        self.read_bytes(table, &mut raw)?;
+        *self.group_descs.write() = raw.chunks(desc_size).map(from_bytes::<GroupDesc>).collect();
+        Ok(())
+    }
+
+    /// Opens the journal kept in inode `inum`.
+    fn open_journal(&self, inum: u32) -> Result<Journal, VfsError> {
+        let inode = self.read_inode(inum)?;
+        if !inode.uses_extents() {
+            return Err(VfsError::NotSupported);
+        }
+        let runs = self
+            .extent_list(&inode)?
+            .into_iter()
+            .map(|extent| JournalRun {
+                logical: extent.lblk,
+                physical: extent.pblk,
+                len: extent.len,
+            })
+            .collect();
+        Journal::open(&*self.device, *self.block_size.lock() as usize, runs)
+    }
+
+    /// Returns every extent of an inode in file order.
+    fn extent_list(&self, inode: &Inode) -> Result<Vec<Mapping>, VfsError> {
+        let mut extents = Vec::new();
+        self.collect_extents(&inode.block_bytes(), 0, &mut extents)?;
+        Ok(extents)
+    }
+
+    fn collect_extents(&self, data: &[u8], level: usize, extents: &mut Vec<Mapping>) -> Result<(), VfsError> {
+        let node = Node::parse(NodeAt::Root, data)?;
+        if level > 5 {
+            return Err(VfsError::IoError);
+        }
+        for entry in &node.entries {
+            if node.depth == 0 {
+                extents.push(Mapping::decode(entry));
+            } else {
+                let child = self.read_block(index_child(entry))?;
+                self.collect_extents(&child, level + 1, extents)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the superblock.
+    fn read_superblock(&self) -> Result<Superblock, VfsError> {
+        self.read_struct::<Superblock>(SUPERBLOCK_OFFSET)
+    }
+
+    /// Writes the superblock in place, outside any transaction.
+    fn write_superblock(&self, sb: &Superblock) -> Result<(), VfsError> {
+        let mut raw = vec![0u8; SUPERBLOCK_SIZE];
+        self.read_bytes(SUPERBLOCK_OFFSET, &mut raw)?;
+        encode_superblock(sb, &mut raw);
+        self.device
+            .write_sectors(SUPERBLOCK_OFFSET / SECTOR_SIZE as u64, &raw)
+            .map_err(|_| VfsError::IoError)?;
+        self.device.flush().map_err(|_| VfsError::IoError)
+    }
+
+    /// Reads a structure from disk.
+    fn read_struct<T: Copy>(&self, offset: u64) -> Result<T, VfsError> {
+        let mut buffer = vec![0u8; core::mem::size_of::<T>()];
+        self.read_bytes(offset, &mut buffer)?;
+        Ok(from_bytes(&buffer))
+    }
+    
+    /// Reads bytes from disk.
+    fn read_bytes(&self, offset: u64, buffer: &mut [u8]) -> Result<(), VfsError> {
+        let sector = offset / SECTOR_SIZE as u64;
+        let sector_offset = (offset % SECTOR_SIZE as u64) as usize;
+        
+        let sectors_needed = (sector_offset + buffer.len()).div_ceil(SECTOR_SIZE);
+        let mut sector_buffer = vec![0u8; sectors_needed * SECTOR_SIZE];
+        
         self.device.read_sectors(sector, &mut sector_buffer)
             .map_err(|_| VfsError::IoError)?;
         
-        buffer.copy_from_slice(&sector_buffer[sector_offset..sector_offset + buffer.len()]);
+        buffer.copy_from_slice(&sector_buffer[sector_offset..sector_offset + buffer.len()]);
+        
+        Ok(())
+    }
+    
+    /// Reads a block.
+    fn read_block(&self, block_num: u64) -> Result<Vec<u8>, VfsError> {
+        let block_size = *self.block_size.lock() as usize;
+        let mut buffer = vec![0u8; block_size];
+        
+        let offset = block_num * block_size as u64;
+        self.read_bytes(offset, &mut buffer)?;
+        
+        Ok(buffer)
+    }
+    
+    /// Writes a block in place.
+    fn write_block(&self, block_num: u64, data: &[u8]) -> Result<(), VfsError> {
+        let block_size = *self.block_size.lock() as u64;
+        self.device
+            .write_sectors(block_num * (block_size / SECTOR_SIZE as u64), data)
+            .map_err(|_| VfsError::IoError)
+    }
+    
+    /// Reads an inode.
+    fn read_inode(&self, inode_num: u32) -> Result<Inode, VfsError> {
+        // Check cache
+        if let Some(inode) = self.inode_cache.read().get(&inode_num) {
+            return Ok(*inode);
+        }
+        
+        let inode = parse_inode(&self.read_raw_inode(inode_num)?);
+        
+        // Cache it
+        self.inode_cache.write().insert(inode_num, inode);
+        
+        Ok(inode)
+    }
+    
+    /// Reads an inode's on-disk bytes, in-inode xattr space included.
+    fn read_raw_inode(&self, inode_num: u32) -> Result<Vec<u8>, VfsError> {
+        let sb = self.superblock.read();
+        let sb = sb.as_ref().ok_or(VfsError::NoFilesystem)?;
+        if inode_num == 0 || inode_num > sb.inodes_count {
+            return Err(VfsError::NotFound);
+        }
+        
+        let block_size = *self.block_size.lock();
+        let inode_size = *self.inode_size.lock() as u32;
+        
+        // Calculate group and index
+        let group = (inode_num - 1) / sb.inodes_per_group;
+        let index = (inode_num - 1) % sb.inodes_per_group;
+        
+        // Get group descriptor
+        let gd = self.group_descs.read()
+            .get(group as usize)
+            .copied()
+            .ok_or(VfsError::InvalidArgument)?;
+        
+        // Calculate inode offset
+        let inode_table = gd.inode_table(sb.is_64bit());
+        let inode_offset = inode_table * block_size as u64 + index as u64 * inode_size as u64;
+        
+        let mut raw = vec![0u8; inode_size as usize];
+        self.read_bytes(inode_offset, &mut raw)?;
+        Ok(raw)
+    }
+    
+    /// Reads an inode's extended attributes.
+    fn read_xattrs(&self, inode_num: u32) -> Result<Vec<XattrEntry>, VfsError> {
+        let inode = self.read_inode(inode_num)?;
+        let raw = self.read_raw_inode(inode_num)?;
+        let block = match inode.file_acl() {
+            0 => None,
+            block => Some(self.read_block(block)?),
+        };
+        inode_xattrs(&raw, &inode, block.as_deref())
+    }
+    
+    /// Reads file data from an inode.
+    fn read_inode_data(&self, inode: &Inode, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        let file_size = inode.size();
+        if offset >= file_size {
+            return Ok(0);
+        }
+        
+        let to_read = core::cmp::min(buffer.len() as u64, file_size - offset) as usize;
+        
+        // Each step reads at most one block
+        let mut done = 0;
+        while done < to_read {
+            let position = offset + done as u64;
+            let read = if inode.uses_extents() {
+                self.read_extent_data(inode, position, &mut buffer[done..to_read])?
+            } else {
+                self.read_indirect_data(inode, position, &mut buffer[done..to_read])?
+            };
+            if read == 0 {
+                break;
+            }
+            done += read;
+        }
+        Ok(done)
+    }
+    
+    /// Reads data using extent tree.
+    fn read_extent_data(&self, inode: &Inode, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        let block_size = *self.block_size.lock() as u64;
+        let block_num = offset / block_size;
+        let block_offset = (offset % block_size) as usize;
+        let to_copy = core::cmp::min(buffer.len(), block_size as usize - block_offset);
+        
+        // Parse extent header - use safe block_bytes method
+        let extent_bytes = inode.block_bytes();
+        let extent_data = &extent_bytes[..];
+        
+        let header = from_bytes::<ExtentHeader>(extent_data);
+        if header.magic != EXT4_EXT_MAGIC {
+            return Err(VfsError::InvalidArgument);
+        }
+        
+        // Find the physical block
+        let phys_block = self.find_extent_block(&header, extent_data, block_num, header.depth)?;
+        
+        if phys_block == 0 {
+            // Sparse or unwritten block - return zeros
+            buffer[..to_copy].fill(0);
+            return Ok(to_copy);
+        }
+        
+        // Read the block
+        let block_data = self.read_block(phys_block)?;
+        buffer[..to_copy].copy_from_slice(&block_data[block_offset..block_offset + to_copy]);
+        
+        Ok(to_copy)
+    }
+    
+    /// Finds a physical block in the extent tree (0 for holes and unwritten
+    /// extents).
+    fn find_extent_block(&self, header: &ExtentHeader, data: &[u8], file_block: u64, depth: u16) -> Result<u64, VfsError> {
+        let entries = header.entries as usize;
+        if EXT_HEADER_SIZE + entries * EXT_ENTRY_SIZE > data.len() {
+            return Err(VfsError::IoError);
+        }
+        let entry = |i: usize| &data[EXT_HEADER_SIZE + i * EXT_ENTRY_SIZE..];
+        
+        if depth == 0 {
+            // Leaf node - search extents
+            for i in 0..entries {
+                let extent = from_bytes::<Extent>(entry(i));
+                
+                let start = extent.block as u64;
+                let end = start + extent.length() as u64;
+                
+                if file_block >= start && file_block < end {
+                    if extent.is_unwritten() {
+                        return Ok(0);
+                    }
+                    let offset_in_extent = file_block - start;
+                    return Ok(extent.start() + offset_in_extent);
+                }
+            }
+            Ok(0) // Not found (sparse)
+        } else {
+            // Internal node - find child
+            for i in 0..entries {
+                let idx = from_bytes::<ExtentIndex>(entry(i));
+                let next_idx = (i + 1 < entries).then(|| from_bytes::<ExtentIndex>(entry(i + 1)));
+                
+                let in_range = if let Some(next) = next_idx {
+                    file_block >= idx.block as u64 && file_block < next.block as u64
+                } else {
+                    file_block >= idx.block as u64
+                };
+                
+                if in_range {
+                    // Read child block
+                    let child_block = self.read_block(idx.leaf())?;
+                    let child_header = from_bytes::<ExtentHeader>(&child_block);
+                    if child_header.magic != EXT4_EXT_MAGIC || child_header.depth + 1 != depth {
+                        return Err(VfsError::IoError);
+                    }
+                    return self.find_extent_block(&child_header, &child_block, file_block, depth - 1);
+                }
+            }
+            Ok(0)
+        }
+    }
+    
+    /// Reads data using indirect blocks (legacy).
+    fn read_indirect_data(&self, inode: &Inode, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        let block_size = *self.block_size.lock() as u64;
+        let block_num = (offset / block_size) as u32;
+        let block_offset = (offset % block_size) as usize;
+        let to_copy = core::cmp::min(buffer.len(), block_size as usize - block_offset);
+        
+        // Direct blocks (0-11)
+        let phys_block = if block_num < 12 {
+            inode.block_data()[block_num as usize] as u64
+        } else {
+            // Indirect block support would go here
+            // For simplicity, we only support extents for large files
+            return Err(VfsError::NotSupported);
+        };
+        
+        if phys_block == 0 {
+            buffer[..to_copy].fill(0);
+            return Ok(to_copy);
+        }
+        
+        let block_data = self.read_block(phys_block)?;
+        buffer[..to_copy].copy_from_slice(&block_data[block_offset..block_offset + to_copy]);
+        
+        Ok(to_copy)
+    }
+    
+    /// Reads a directory.
+    fn read_directory(&self, inode: &Inode) -> Result<Vec<VfsDirEntry>, VfsError> {
+        if inode.file_type() != VfsFileType::Directory {
+            return Err(VfsError::NotADirectory);
+        }
+        
+        let block_size = *self.block_size.lock() as usize;
+        let mut entries = Vec::new();
+        let dir_size = inode.size();
+        let mut offset = 0u64;
+        let mut block = vec![0u8; block_size];
+        
+        // Records never cross blocks; htree index blocks read as empty records
+        while offset < dir_size {
+            let read = self.read_inode_data(inode, offset, &mut block)?;
+            if read < block_size {
+                break;
+            }
+            
+            for record in records(&block, block_size) {
+                if record.inode != 0 && record.name_len > 0 {
+                    entries.push(VfsDirEntry {
+                        ino: record.inode as InodeNum,
+                        name: String::from_utf8_lossy(record.name(&block)).into_owned(),
+                        file_type: vfs_file_type(record.file_type),
+                    });
+                }
+            }
+            
+            offset += block_size as u64;
+        }
+        
+        Ok(entries)
+    }
+    
+    /// Looks up a name in a directory.
+    fn lookup_in_dir(&self, dir_inode: &Inode, name: &str) -> Result<u32, VfsError> {
+        let entries = self.read_directory(dir_inode)?;
+        
+        for entry in entries {
+            if entry.name == name {
+                return Ok(entry.ino as u32);
+            }
+        }
+        
+        Err(VfsError::NotFound)
+    }
+    
+    /// Resolves a path to an inode number.
+    fn resolve_path(&self, path: &str) -> Result<u32, VfsError> {
+        let mut current_inode = EXT4_ROOT_INODE;
         
+        for component in path.split('/').filter(|s| !s.is_empty()) {
+            let inode = self.read_inode(current_inode)?;
+            current_inode = self.lookup_in_dir(&inode, component)?;
+        }
+        
+        Ok(current_inode)
+    }
+    
+    /// Starts a transaction, waiting for the running one to finish.
+    fn begin(&self) -> Result<Transaction<'_>, VfsError> {
+        let journal = self.journal.lock();
+        if self.read_only.load(Ordering::Acquire) {
+            return Err(VfsError::ReadOnlyFs);
+        }
+        let sb = (*self.superblock.read()).ok_or(VfsError::NoFilesystem)?;
+        let geometry = Geometry {
+            block_size: *self.block_size.lock() as usize,
+            inode_size: *self.inode_size.lock() as usize,
+            desc_size: *self.desc_size.lock(),
+            is_64bit: sb.is_64bit(),
+            metadata_csum: sb.metadata_csum(),
+            group_csum: sb.group_desc_csum(),
+            csum_seed: *self.csum_seed.lock(),
+        };
+        Ok(Transaction {
+            fs: self,
+            journal,
+            geo: geometry,
+            sb,
+            groups: self.group_descs.read().clone(),
+            blocks: BTreeMap::new(),
+            dirty_groups: BTreeSet::new(),
+            block_bitmaps: BTreeSet::new(),
+            inode_bitmaps: BTreeSet::new(),
+            freed: BTreeSet::new(),
+            inodes: BTreeSet::new(),
+        })
+    }
+}
+
+// ============================================================================
+// Transactions
+// ============================================================================
+
+/// Filesystem parameters a transaction needs at hand.
+#[derive(Debug, Clone, Copy)]
+struct Geometry {
+    block_size: usize,
+    inode_size: usize,
+    desc_size: usize,
+    is_64bit: bool,
+    metadata_csum: bool,
+    group_csum: bool,
+    csum_seed: u32,
+}
+
+/// One atomic metadata update.
+///
+/// Changes go to private copies of the superblock, the group descriptors
+/// and every metadata block touched; [`Transaction::commit`] journals and
+/// publishes them. Dropping a transaction discards them.
+struct Transaction<'a> {
+    fs: &'a Ext4Fs,
+    /// Held for the whole transaction
+    journal: MutexGuard<'a, Option<Journal>>,
+    geo: Geometry,
+    sb: Superblock,
+    groups: Vec<GroupDesc>,
+    /// New contents of the metadata blocks touched so far
+    blocks: BTreeMap<u64, Vec<u8>>,
+    /// Groups whose descriptor changed
+    dirty_groups: BTreeSet<u32>,
+    /// Groups whose block bitmap changed
+    block_bitmaps: BTreeSet<u32>,
+    /// Groups whose inode bitmap changed
+    inode_bitmaps: BTreeSet<u32>,
+    /// Blocks freed here; not reused before the commit
+    freed: BTreeSet<u64>,
+    /// Inodes written
+    inodes: BTreeSet<u32>,
+}
+
+impl Transaction<'_> {
+    // ---- Blocks ------------------------------------------------------------
+
+    /// Reads a block as this transaction sees it.
+    fn read(&self, block: u64) -> Result<Vec<u8>, VfsError> {
+        match self.blocks.get(&block) {
+            Some(data) => Ok(data.clone()),
+            None => self.fs.read_block(block),
+        }
+    }
+
+    /// Returns a block for modification; it becomes part of the transaction.
+    fn block_mut(&mut self, block: u64) -> Result<&mut Vec<u8>, VfsError> {
+        match self.blocks.entry(block) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => Ok(entry.insert(self.fs.read_block(block)?)),
+        }
+    }
+
+    // ---- Allocation ----------------------------------------------------------
+
+    fn inode_table_blocks(&self) -> u64 {
+        (self.sb.inodes_per_group as u64 * self.geo.inode_size as u64).div_ceil(self.geo.block_size as u64)
+    }
+
+    /// Loads a group's block bitmap into the transaction, initializing it if
+    /// the group was never used. Returns its location.
+    fn block_bitmap(&mut self, group: u32) -> Result<u64, VfsError> {
+        let desc = self.groups[group as usize];
+        let location = desc.block_bitmap(self.geo.is_64bit);
+        if self.geo.group_csum && desc.flags & EXT4_BG_BLOCK_UNINIT != 0 {
+            let bitmap = self.init_block_bitmap(group, &desc);
+            self.blocks.insert(location, bitmap);
+            self.groups[group as usize].flags &= !EXT4_BG_BLOCK_UNINIT;
+            self.dirty_groups.insert(group);
+        } else {
+            self.block_mut(location)?;
+        }
+        self.block_bitmaps.insert(group);
+        Ok(location)
+    }
+
+    /// Builds the bitmap of an uninitialized group: only its own metadata
+    /// is in use.
+    fn init_block_bitmap(&self, group: u32, desc: &GroupDesc) -> Vec<u8> {
+        let block_size = self.geo.block_size;
+        let is_64bit = self.geo.is_64bit;
+        let mut bitmap = vec![0u8; block_size];
+        let first = self.sb.group_first_block(group);
+        let blocks = self.sb.group_blocks(group) as u64;
+
+        if self.sb.group_has_super(group) {
+            let gdt_blocks = (self.groups.len() * self.geo.desc_size).div_ceil(block_size) as u64;
+            let used = 1 + gdt_blocks + self.sb.reserved_gdt_blocks as u64;
+            for bit in 0..used.min(blocks) {
+                set_bit(&mut bitmap, bit as usize);
+            }
+        }
+        let table = desc.inode_table(is_64bit);
+        let metadata = [desc.block_bitmap(is_64bit), desc.inode_bitmap(is_64bit)]
+            .into_iter()
+            .chain(table..table + self.inode_table_blocks());
+        for block in metadata {
+            if block >= first && block - first < blocks {
+                set_bit(&mut bitmap, (block - first) as usize);
+            }
+        }
+        // Bits past the end of a short last group are kept set
+        for bit in blocks as usize..block_size * 8 {
+            set_bit(&mut bitmap, bit);
+        }
+        bitmap
+    }
+
+    /// Allocates a block, preferring `goal` and the blocks after it.
+    fn alloc_block(&mut self, goal: u64) -> Result<u64, VfsError> {
+        let is_64bit = self.geo.is_64bit;
+        let first_data_block = self.sb.first_data_block as u64;
+        let per_group = self.sb.blocks_per_group as u64;
+        let count = self.groups.len() as u32;
+        let goal = if goal < first_data_block || goal >= self.sb.blocks_count() { first_data_block } else { goal };
+        let goal_group = ((goal - first_data_block) / per_group) as u32;
+
+        // The goal group is visited again at the end, from its start
+        for i in 0..=count {
+            let group = (goal_group + i) % count;
+            if self.groups[group as usize].free_blocks(is_64bit) == 0 {
+                continue;
+            }
+            let start = if i == 0 { ((goal - first_data_block) % per_group) as usize } else { 0 };
+            let location = self.block_bitmap(group)?;
+            let first = self.sb.group_first_block(group);
+            let limit = self.sb.group_blocks(group) as usize;
+
+            let bitmap = &self.blocks[&location];
+            let found = (start..limit).find(|&bit| !test_bit(bitmap, bit) && !self.freed.contains(&(first + bit as u64)));
+            if let Some(bit) = found {
+                if let Some(bitmap) = self.blocks.get_mut(&location) {
+                    set_bit(bitmap, bit);
+                }
+                let desc = &mut self.groups[group as usize];
+                desc.set_free_blocks(desc.free_blocks(is_64bit) - 1, is_64bit);
+                self.dirty_groups.insert(group);
+                self.sb.set_free_blocks_count(self.sb.free_blocks_count().saturating_sub(1));
+                return Ok(first + bit as u64);
+            }
+        }
+        Err(VfsError::NoSpace)
+    }
+
+    /// Frees a block. It is not reused before this transaction commits.
+    fn free_block(&mut self, block: u64) -> Result<(), VfsError> {
+        let first_data_block = self.sb.first_data_block as u64;
+        if block < first_data_block || block >= self.sb.blocks_count() {
+            return Err(VfsError::IoError);
+        }
+        let is_64bit = self.geo.is_64bit;
+        let per_group = self.sb.blocks_per_group as u64;
+        let group = ((block - first_data_block) / per_group) as u32;
+        let bit = ((block - first_data_block) % per_group) as usize;
+
+        let location = self.block_bitmap(group)?;
+        let bitmap = self.blocks.get_mut(&location).ok_or(VfsError::IoError)?;
+        if !test_bit(bitmap, bit) {
+            crate::serial_println!("[ext4] Freeing free block {}", block);
+            return Ok(());
+        }
+        clear_bit(bitmap, bit);
+
+        let desc = &mut self.groups[group as usize];
+        desc.set_free_blocks(desc.free_blocks(is_64bit) + 1, is_64bit);
+        self.dirty_groups.insert(group);
+        self.sb.set_free_blocks_count(self.sb.free_blocks_count() + 1);
+        self.freed.insert(block);
+        // A freed metadata block must not be written by the commit
+        self.blocks.remove(&block);
+        Ok(())
+    }
+
+    /// Loads a group's inode bitmap into the transaction, initializing it if
+    /// the group was never used. Returns its location.
+    fn inode_bitmap(&mut self, group: u32) -> Result<u64, VfsError> {
+        let desc = self.groups[group as usize];
+        let location = desc.inode_bitmap(self.geo.is_64bit);
+        if self.geo.group_csum && desc.flags & EXT4_BG_INODE_UNINIT != 0 {
+            let mut bitmap = vec![0u8; self.geo.block_size];
+            for bit in self.sb.inodes_per_group as usize..self.geo.block_size * 8 {
+                set_bit(&mut bitmap, bit);
+            }
+            self.blocks.insert(location, bitmap);
+            self.groups[group as usize].flags &= !EXT4_BG_INODE_UNINIT;
+            self.dirty_groups.insert(group);
+        } else {
+            self.block_mut(location)?;
+        }
+        self.inode_bitmaps.insert(group);
+        Ok(location)
+    }
+
+    /// Allocates an inode, preferring `goal_group`.
+    fn alloc_inode(&mut self, goal_group: u32, is_dir: bool) -> Result<u32, VfsError> {
+        let is_64bit = self.geo.is_64bit;
+        let per_group = self.sb.inodes_per_group;
+        let first_ino = if self.sb.rev_level >= 1 { self.sb.first_ino } else { 11 };
+        let count = self.groups.len() as u32;
+
+        for i in 0..count {
+            let group = (goal_group + i) % count;
+            if self.groups[group as usize].free_inodes(is_64bit) == 0 {
+                continue;
+            }
+            let location = self.inode_bitmap(group)?;
+            let start = if group == 0 { first_ino - 1 } else { 0 };
+            let bitmap = &self.blocks[&location];
+            let Some(bit) = (start..per_group).find(|&bit| !test_bit(bitmap, bit as usize)) else {
+                continue;
+            };
+            if let Some(bitmap) = self.blocks.get_mut(&location) {
+                set_bit(bitmap, bit as usize);
+            }
+
+            let group_csum = self.geo.group_csum;
+            let desc = &mut self.groups[group as usize];
+            desc.set_free_inodes(desc.free_inodes(is_64bit) - 1, is_64bit);
+            if is_dir {
+                desc.set_used_dirs(desc.used_dirs(is_64bit) + 1, is_64bit);
+            }
+            if group_csum && bit >= per_group - desc.itable_unused(is_64bit) {
+                desc.set_itable_unused(per_group - bit - 1, is_64bit);
+            }
+            self.dirty_groups.insert(group);
+            self.sb.free_inodes_count -= 1;
+            return Ok(group * per_group + bit + 1);
+        }
+        Err(VfsError::NoSpace)
+    }
+
+    /// Frees an inode in its group's bitmap.
+    fn free_inode(&mut self, ino: u32, is_dir: bool) -> Result<(), VfsError> {
+        let is_64bit = self.geo.is_64bit;
+        let group = (ino - 1) / self.sb.inodes_per_group;
+        let bit = ((ino - 1) % self.sb.inodes_per_group) as usize;
+
+        let location = self.inode_bitmap(group)?;
+        let bitmap = self.blocks.get_mut(&location).ok_or(VfsError::IoError)?;
+        if !test_bit(bitmap, bit) {
+            crate::serial_println!("[ext4] Freeing free inode {}", ino);
+            return Ok(());
+        }
+        clear_bit(bitmap, bit);
+
+        let desc = &mut self.groups[group as usize];
+        desc.set_free_inodes(desc.free_inodes(is_64bit) + 1, is_64bit);
+        if is_dir {
+            desc.set_used_dirs(desc.used_dirs(is_64bit).saturating_sub(1), is_64bit);
+        }
+        self.dirty_groups.insert(group);
+        self.sb.free_inodes_count += 1;
+        Ok(())
+    }
+
+    // ---- Inodes ----------------------------------------------------------------
+
+    /// Block and byte offset of an inode in its group's table.
+    fn inode_location(&self, ino: u32) -> Result<(u64, usize), VfsError> {
+        if ino == 0 || ino > self.sb.inodes_count {
+            return Err(VfsError::NotFound);
+        }
+        let group = (ino - 1) / self.sb.inodes_per_group;
+        let index = (ino - 1) % self.sb.inodes_per_group;
+        let desc = self.groups.get(group as usize).ok_or(VfsError::IoError)?;
+        let byte = index as u64 * self.geo.inode_size as u64;
+        let block_size = self.geo.block_size as u64;
+        Ok((desc.inode_table(self.geo.is_64bit) + byte / block_size, (byte % block_size) as usize))
+    }
+
+    fn inode(&self, ino: u32) -> Result<Inode, VfsError> {
+        let (block, offset) = self.inode_location(ino)?;
+        let data = self.read(block)?;
+        Ok(parse_inode(&data[offset..offset + self.geo.inode_size]))
+    }
+
+    /// Stores an inode, keeping the in-inode xattr space past it.
+    fn write_inode(&mut self, ino: u32, inode: &Inode) -> Result<(), VfsError> {
+        let (block, offset) = self.inode_location(ino)?;
+        let geo = self.geo;
+        let data = self.block_mut(block)?;
+        let raw = &mut data[offset..offset + geo.inode_size];
+
+        let base = EXT4_INODE_SIZE_MIN as usize;
+        let len = if geo.inode_size > base {
+            (base + inode.extra_isize as usize).min(geo.inode_size).min(core::mem::size_of::<Inode>())
+        } else {
+            base
+        };
+        raw[..len].copy_from_slice(&as_bytes(inode)[..len]);
+
+        if geo.metadata_csum {
+            let csum = inode_checksum(geo.csum_seed, ino, raw);
+            put_le16(raw, INODE_CHECKSUM_LO, csum as u16);
+            if geo.inode_size > base && inode.extra_isize >= 4 {
+                put_le16(raw, INODE_CHECKSUM_HI, (csum >> 16) as u16);
+            }
+        }
+        self.inodes.insert(ino);
+        Ok(())
+    }
+
+    /// Seed for checksums of blocks owned by an inode.
+    fn inode_seed(&self, ino: u32, inode: &Inode) -> u32 {
+        let csum = crc32c(self.geo.csum_seed, &ino.to_le_bytes());
+        crc32c(csum, &{ inode.generation }.to_le_bytes())
+    }
+
+    /// Initializes a freshly allocated inode.
+    fn new_inode(&mut self, ino: u32, mode: u16) -> Result<Inode, VfsError> {
+        let (block, offset) = self.inode_location(ino)?;
+        let inode_size = self.geo.inode_size;
+        self.block_mut(block)?[offset..offset + inode_size].fill(0);
+
+        let mut inode: Inode = from_bytes(&[]);
+        let time = now();
+        inode.mode = mode;
+        inode.links_count = 1;
+        inode.atime = time;
+        inode.ctime = time;
+        inode.mtime = time;
+        inode.generation = time.wrapping_mul(0x9E37_79B9) ^ ino;
+        if inode_size > EXT4_INODE_SIZE_MIN as usize {
+            inode.extra_isize = (inode_size - EXT4_INODE_SIZE_MIN as usize).min(32) as u16;
+            inode.crtime = time;
+        }
+        if mode & S_IFMT != S_IFLNK {
+            inode.flags = EXT4_EXTENTS_FL;
+            let root = Node { at: NodeAt::Root, depth: 0, max: 4, entries: Vec::new() };
+            self.store_node(ino, &mut inode, &root)?;
+        }
+        Ok(inode)
+    }
+
+    /// Adjusts `i_blocks` by `delta` filesystem blocks.
+    fn add_inode_blocks(&self, inode: &mut Inode, delta: i64) {
+        let flags = { inode.flags };
+        let unit = if flags & EXT4_HUGE_FILE_FL != 0 { 1 } else { (self.geo.block_size / 512) as i64 };
+        inode.set_i_blocks((inode.i_blocks() as i64 + delta * unit).max(0) as u64);
+    }
+
+    /// First block of the group holding an inode, as an allocation goal.
+    fn inode_goal(&self, ino: u32) -> u64 {
+        self.sb.group_first_block((ino - 1) / self.sb.inodes_per_group)
+    }
+
+    // ---- Extent tree -----------------------------------------------------------
+
+    fn node(&self, inode: &Inode, at: NodeAt) -> Result<Node, VfsError> {
+        match at {
+            NodeAt::Root => Node::parse(at, &inode.block_bytes()),
+            NodeAt::Block(block) => Node::parse(at, &self.read(block)?),
+        }
+    }
+
+    /// Stores a node; the root goes into the inode, which the caller writes.
+    fn store_node(&mut self, ino: u32, inode: &mut Inode, node: &Node) -> Result<(), VfsError> {
+        match node.at {
+            NodeAt::Root => {
+                let mut bytes = [0u8; 60];
+                node.encode(&mut bytes);
+                inode.set_block_bytes(&bytes);
+            }
+            NodeAt::Block(block) => {
+                let mut data = vec![0u8; self.geo.block_size];
+                node.encode(&mut data);
+                if self.geo.metadata_csum {
+                    let tail = EXT_HEADER_SIZE + node.max as usize * EXT_ENTRY_SIZE;
+                    let csum = crc32c(self.inode_seed(ino, inode), &data[..tail]);
+                    put_le32(&mut data, tail, csum);
+                }
+                self.blocks.insert(block, data);
+            }
+        }
+        Ok(())
+    }
+
+    /// Entries of an extent node stored in a block.
+    fn node_max(&self) -> u16 {
+        ((self.geo.block_size - EXT_HEADER_SIZE) / EXT_ENTRY_SIZE) as u16
+    }
+
+    /// Walks from the root to the leaf covering `lblk`. Each level records
+    /// the node and the entry followed (for leaves: the insert position).
+    fn extent_path(&self, inode: &Inode, lblk: u32) -> Result<Vec<(Node, usize)>, VfsError> {
+        let mut path = Vec::new();
+        let mut node = self.node(inode, NodeAt::Root)?;
+        loop {
+            let position = node.entries.iter().take_while(|entry| le32(&entry[..], 0) <= lblk).count();
+            if node.depth == 0 {
+                path.push((node, position));
+                return Ok(path);
+            }
+            if node.entries.is_empty() || path.len() > 5 {
+                return Err(VfsError::IoError);
+            }
+            let index = position.saturating_sub(1);
+            let child = self.node(inode, NodeAt::Block(index_child(&node.entries[index])))?;
+            if child.depth + 1 != node.depth {
+                return Err(VfsError::IoError);
+            }
+            path.push((node, index));
+            node = child;
+        }
+    }
+
+    /// Returns the mapping of one file block.
+    fn mapping(&self, inode: &Inode, lblk: u32) -> Result<Option<Mapping>, VfsError> {
+        let path = self.extent_path(inode, lblk)?;
+        let (leaf, position) = &path[path.len() - 1];
+        let Some(index) = position.checked_sub(1) else {
+            return Ok(None);
+        };
+        let extent = Mapping::decode(&leaf.entries[index]);
+        if extent.end() <= lblk as u64 {
+            return Ok(None);
+        }
+        Ok(Some(Mapping {
+            lblk,
+            len: 1,
+            pblk: extent.pblk + (lblk - extent.lblk) as u64,
+            unwritten: extent.unwritten,
+        }))
+    }
+
+    /// Maps file block `lblk` to `pblk`. The block must be a hole or part of
+    /// an unwritten extent (which is then split around it).
+    fn map_block(&mut self, ino: u32, inode: &mut Inode, lblk: u32, pblk: u64) -> Result<(), VfsError> {
+        // Converting an unwritten block adds up to two entries to the leaf;
+        // a split adds one to the parent, so full parents are split first
+        let mut path = loop {
+            let path = self.extent_path(inode, lblk)?;
+            let full = |level: usize| {
+                let node: &Node = &path[level].0;
+                node.entries.len() + 2 > node.max as usize
+            };
+            let mut level = path.len() - 1;
+            if !full(level) {
+                break path;
+            }
+            while level > 0 && full(level - 1) {
+                level -= 1;
+            }
+            self.split_node(ino, inode, &path, level)?;
+        };
+
+        let last = path.len() - 1;
+        let (leaf, position) = &mut path[last];
+        let mapped = Mapping { lblk, len: 1, pblk, unwritten: false };
+        let previous = position.checked_sub(1).map(|i| Mapping::decode(&leaf.entries[i]));
+
+        match previous {
+            Some(extent) if extent.end() > lblk as u64 => {
+                if !extent.unwritten {
+                    return Err(VfsError::IoError);
+                }
+                // Split the unwritten extent around the block
+                let index = *position - 1;
+                let mut pieces = Vec::new();
+                if extent.lblk < lblk {
+                    pieces.push(Mapping { len: lblk - extent.lblk, ..extent });
+                }
+                pieces.push(mapped);
+                if extent.end() > lblk as u64 + 1 {
+                    let skip = lblk - extent.lblk + 1;
+                    pieces.push(Mapping {
+                        lblk: lblk + 1,
+                        len: extent.len - skip,
+                        pblk: extent.pblk + skip as u64,
+                        unwritten: true,
+                    });
+                }
+                leaf.entries.splice(index..=index, pieces.iter().map(Mapping::encode));
+            }
+            Some(mut extent) if extent.can_merge(&mapped) => {
+                extent.len += 1;
+                let index = *position - 1;
+                // The grown extent may now reach the next one
+                let next = leaf.entries.get(index + 1).map(|entry| Mapping::decode(entry));
+                match next {
+                    Some(next) if extent.can_merge(&next) => {
+                        extent.len += next.len;
+                        leaf.entries.remove(index + 1);
+                    }
+                    _ => {}
+                }
+                leaf.entries[index] = extent.encode();
+            }
+            _ => {
+                let next = leaf.entries.get(*position).map(|entry| Mapping::decode(entry));
+                match next {
+                    Some(mut next) if mapped.can_merge(&next) => {
+                        next.lblk = lblk;
+                        next.pblk = pblk;
+                        next.len += 1;
+                        leaf.entries[*position] = next.encode();
+                    }
+                    _ => leaf.entries.insert(*position, mapped.encode()),
+                }
+            }
+        }
+        self.store_path(ino, inode, &mut path, last)
+    }
+
+    /// Stores the nodes of `path` from `level` up, fixing index keys that
+    /// no longer match the first key of their child.
+    fn store_path(&mut self, ino: u32, inode: &mut Inode, path: &mut [(Node, usize)], level: usize) -> Result<(), VfsError> {
+        self.store_node(ino, inode, &path[level].0)?;
+        for parent in (0..level).rev() {
+            let Some(key) = path[parent + 1].0.first_key() else {
+                break;
+            };
+            let index = path[parent].1;
+            let entry = &mut path[parent].0.entries[index];
+            if le32(&entry[..], 0) == key {
+                break;
+            }
+            put_le32(entry, 0, key);
+            self.store_node(ino, inode, &path[parent].0)?;
+        }
         Ok(())
     }
-    
-    /// Reads a block.
-    fn read_block(&self, block_num: u64) -> Result<Vec<u8>, VfsError> {
-        let block_size = *self.block_size.lock() as usize;
-        let mut buffer = vec![0u8; block_size];
-        
-        let offset = block_num * block_size as u64;
-        self.read_bytes(offset, &mut buffer)?;
-        
-        Ok(buffer)
+
+    /// Makes room in node `level` of `path`, either by moving the root's
+    /// entries to a new block or by splitting a block node in half.
+    fn split_node(&mut self, ino: u32, inode: &mut Inode, path: &[(Node, usize)], level: usize) -> Result<(), VfsError> {
+        let (node, _) = &path[level];
+        let goal = match node.at {
+            NodeAt::Block(block) => block + 1,
+            NodeAt::Root => self.inode_goal(ino),
+        };
+        let block = self.alloc_block(goal)?;
+        self.add_inode_blocks(inode, 1);
+
+        if node.at == NodeAt::Root {
+            // The tree grows by one level below the root
+            if node.depth >= 4 {
+                return Err(VfsError::NoSpace);
+            }
+            let child = Node { at: NodeAt::Block(block), depth: node.depth, max: self.node_max(), entries: node.entries.clone() };
+            let key = child.first_key().unwrap_or(0);
+            self.store_node(ino, inode, &child)?;
+            let root = Node { at: NodeAt::Root, depth: node.depth + 1, max: node.max, entries: vec![index_entry(key, block)] };
+            return self.store_node(ino, inode, &root);
+        }
+
+        // The parent has room: deeper full levels are split first
+        let mut left = node.clone();
+        let right_entries = left.entries.split_off(left.entries.len() / 2);
+        let right = Node { at: NodeAt::Block(block), depth: node.depth, max: self.node_max(), entries: right_entries };
+        let key = right.first_key().unwrap_or(0);
+        self.store_node(ino, inode, &left)?;
+        self.store_node(ino, inode, &right)?;
+
+        let (parent, index) = &path[level - 1];
+        let mut parent = parent.clone();
+        parent.entries.insert(index + 1, index_entry(key, block));
+        self.store_node(ino, inode, &parent)
     }
-    
-    /// Reads an inode.
-    fn read_inode(&self, inode_num: u32) -> Result<Inode, VfsError> {
-        // Check cache
-        if let Some(inode) = self.inode_cache.read().get(&inode_num) {
-            return Ok(*inode);
+
+    /// Frees every block mapped at or past file block `keep`.
+    fn truncate_extents(&mut self, ino: u32, inode: &mut Inode, keep: u32) -> Result<(), VfsError> {
+        let root = self.node(inode, NodeAt::Root)?;
+        let root = match self.trim_node(ino, inode, root, keep)? {
+            Some(root) => root,
+            None => Node { at: NodeAt::Root, depth: 0, max: 4, entries: Vec::new() },
+        };
+        self.store_node(ino, inode, &root)
+    }
+
+    /// Trims a subtree to the blocks before `keep`. Returns the node to
+    /// store, or `None` if it became empty (a block node is then freed).
+    fn trim_node(&mut self, ino: u32, inode: &mut Inode, mut node: Node, keep: u32) -> Result<Option<Node>, VfsError> {
+        if node.depth == 0 {
+            let mut kept = Vec::new();
+            for entry in &node.entries {
+                let mut extent = Mapping::decode(entry);
+                if extent.end() <= keep as u64 {
+                    kept.push(*entry);
+                    continue;
+                }
+                let stay = keep.saturating_sub(extent.lblk);
+                for i in stay..extent.len {
+                    self.free_block(extent.pblk + i as u64)?;
+                }
+                self.add_inode_blocks(inode, -((extent.len - stay) as i64));
+                if stay > 0 {
+                    extent.len = stay;
+                    kept.push(extent.encode());
+                }
+            }
+            node.entries = kept;
+        } else {
+            let mut kept = Vec::new();
+            for (i, entry) in node.entries.iter().enumerate() {
+                let next_key = node.entries.get(i + 1).map(|next| le32(&next[..], 0));
+                if next_key.is_some_and(|next| next <= keep) {
+                    kept.push(*entry);
+                    continue;
+                }
+                let child = self.node(inode, NodeAt::Block(index_child(entry)))?;
+                if let Some(child) = self.trim_node(ino, inode, child, keep)? {
+                    self.store_node(ino, inode, &child)?;
+                    kept.push(*entry);
+                }
+            }
+            node.entries = kept;
+        }
+
+        match node.at {
+            NodeAt::Block(block) if node.entries.is_empty() => {
+                self.free_block(block)?;
+                self.add_inode_blocks(inode, -1);
+                Ok(None)
+            }
+            NodeAt::Root if node.entries.is_empty() => Ok(None),
+            _ => Ok(Some(node)),
         }
-        
-        let sb = self.superblock.read();
-        let sb = sb.as_ref().ok_or(VfsError::NoFilesystem)?;
-        
-        let block_size = *self.block_size.lock();
-        let inode_size = *self.inode_size.lock() as u32;
-        
-        // Calculate group and index
-        let group = (inode_num - 1) / sb.inodes_per_group;
-        let index = (inode_num - 1) % sb.inodes_per_group;
-        
-        // Get group descriptor
-        let gd = self.group_descs.read()
-            .get(group as usize)
-            .copied()
-            .ok_or(VfsError::InvalidArgument)?;
-        
-        // Calculate inode offset
-        let inode_table = gd.inode_table(sb.is_64bit());
-        let inode_offset = inode_table * block_size as u64 + index as u64 * inode_size as u64;
-        
-        let inode = self.read_struct::<Inode>(inode_offset)?;
-        
-        // Cache it
-        self.inode_cache.write().insert(inode_num, inode);
-        
-        Ok(inode)
     }
-    
-    /// Reads file data from an inode.
-    fn read_inode_data(&self, inode: &Inode, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
-        let file_size = inode.size();
-        if offset >= file_size {
-            return Ok(0);
+
+    // ---- Directories -----------------------------------------------------------
+
+    /// End of the records in a directory leaf block.
+    fn leaf_end(&self) -> usize {
+        if self.geo.metadata_csum {
+            self.geo.block_size - DIR_TAIL_SIZE
+        } else {
+            self.geo.block_size
         }
-        
-        let to_read = core::cmp::min(buffer.len() as u64, file_size - offset) as usize;
-        
-        if inode.uses_extents() {
-            self.read_extent_data(inode, offset, &mut buffer[..to_read])
+    }
+
+    /// Stores a directory leaf block, adding its checksum record.
+    fn write_leaf(&mut self, seed: u32, block: u64, mut data: Vec<u8>) {
+        if self.geo.metadata_csum {
+            let tail = self.geo.block_size - DIR_TAIL_SIZE;
+            put_record(&mut data, tail, 0, DIR_TAIL_SIZE, b"", EXT4_FT_DIR_CSUM);
+            let csum = crc32c(seed, &data[..tail]);
+            put_le32(&mut data, tail + 8, csum);
+        }
+        self.blocks.insert(block, data);
+    }
+
+    /// Stores an htree index block, updating its checksum tail.
+    fn write_dx(&mut self, seed: u32, frame: &DxFrame) {
+        let mut data = frame.data.clone();
+        if self.geo.metadata_csum {
+            let (count, limit) = (frame.count(), frame.limit());
+            let tail = frame.count_offset + limit * DX_ENTRY_SIZE;
+            let mut csum = crc32c(seed, &data[..frame.count_offset + count * DX_ENTRY_SIZE]);
+            csum = crc32c(csum, &data[tail..tail + 4]);
+            csum = crc32c(csum, &[0; 4]);
+            put_le32(&mut data, tail + 4, csum);
+        }
+        self.blocks.insert(frame.block, data);
+    }
+
+    /// Largest number of entries an htree block can hold.
+    fn dx_limit(&self, count_offset: usize) -> usize {
+        let tail = if self.geo.metadata_csum { DX_TAIL_SIZE } else { 0 };
+        (self.geo.block_size - count_offset - tail) / DX_ENTRY_SIZE
+    }
+
+    /// Reads logical block `lblk` of a directory.
+    fn dir_block(&self, dir: &Inode, lblk: u32) -> Result<(u64, Vec<u8>), VfsError> {
+        let mapping = self.mapping(dir, lblk)?.ok_or(VfsError::IoError)?;
+        Ok((mapping.pblk, self.read(mapping.pblk)?))
+    }
+
+    fn dir_blocks(&self, dir: &Inode) -> Result<u32, VfsError> {
+        if !dir.uses_extents() {
+            return Err(VfsError::NotSupported);
+        }
+        Ok((dir.size() / self.geo.block_size as u64) as u32)
+    }
+
+    /// Looks up a name. Returns its inode and file type.
+    fn dir_lookup(&self, dir: &Inode, name: &[u8]) -> Result<Option<(u32, u8)>, VfsError> {
+        let block_size = self.geo.block_size;
+        for lblk in 0..self.dir_blocks(dir)? {
+            let (_, data) = self.dir_block(dir, lblk)?;
+            let found = records(&data, block_size)
+                .into_iter()
+                .find(|record| record.inode != 0 && record.name(&data) == name);
+            if let Some(record) = found {
+                return Ok(Some((record.inode, record.file_type)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Checks that a directory holds nothing but `.` and `..`.
+    fn dir_is_empty(&self, dir: &Inode) -> Result<bool, VfsError> {
+        let block_size = self.geo.block_size;
+        for lblk in 0..self.dir_blocks(dir)? {
+            let (_, data) = self.dir_block(dir, lblk)?;
+            let busy = records(&data, block_size)
+                .into_iter()
+                .any(|record| record.inode != 0 && !matches!(record.name(&data), b"." | b".."));
+            if busy {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Removes an entry from a directory.
+    fn dir_remove(&mut self, ino: u32, dir: &Inode, name: &[u8]) -> Result<(), VfsError> {
+        let end = self.leaf_end();
+        let seed = self.inode_seed(ino, dir);
+        for lblk in 0..self.dir_blocks(dir)? {
+            let (block, mut data) = self.dir_block(dir, lblk)?;
+            // htree index blocks hold no live records past `..`
+            if remove_record(&mut data, end, name).is_some() {
+                self.write_leaf(seed, block, data);
+                return Ok(());
+            }
+        }
+        Err(VfsError::NotFound)
+    }
+
+    /// Points a directory's `..` entry at `parent`.
+    fn set_dotdot(&mut self, ino: u32, dir: &Inode, parent: u32) -> Result<(), VfsError> {
+        let (block, mut data) = self.dir_block(dir, 0)?;
+        if data[12 + 6] != 2 || &data[12 + 8..12 + 10] != b".." {
+            return Err(VfsError::IoError);
+        }
+        put_le32(&mut data, 12, parent);
+        let seed = self.inode_seed(ino, dir);
+        let flags = { dir.flags };
+        if flags & EXT4_INDEX_FL != 0 {
+            let frame = DxFrame { block, data, count_offset: DX_ROOT_COUNT, at: 0 };
+            self.write_dx(seed, &frame);
+        } else {
+            self.write_leaf(seed, block, data);
+        }
+        Ok(())
+    }
+
+    /// Adds a block to a directory. Returns its device block.
+    fn append_dir_block(&mut self, ino: u32, dir: &mut Inode) -> Result<u64, VfsError> {
+        let block_size = self.geo.block_size as u64;
+        let lblk = (dir.size() / block_size) as u32;
+        let goal = match lblk.checked_sub(1) {
+            Some(previous) => self.mapping(dir, previous)?.map(|mapping| mapping.pblk + 1),
+            None => None,
+        };
+        let block = self.alloc_block(goal.unwrap_or_else(|| self.inode_goal(ino)))?;
+        self.map_block(ino, dir, lblk, block)?;
+        self.add_inode_blocks(dir, 1);
+        dir.set_size(dir.size() + block_size);
+        Ok(block)
+    }
+
+    /// Adds an entry to a directory; the caller writes the directory inode.
+    fn dir_add(&mut self, ino: u32, dir: &mut Inode, name: &[u8], target: u32, file_type: u8) -> Result<(), VfsError> {
+        let flags = { dir.flags };
+        if flags & EXT4_INDEX_FL != 0 {
+            return self.dx_add(ino, dir, name, target, file_type);
+        }
+        let end = self.leaf_end();
+        let seed = self.inode_seed(ino, dir);
+        for lblk in 0..self.dir_blocks(dir)? {
+            let (block, mut data) = self.dir_block(dir, lblk)?;
+            if add_record(&mut data, end, name, target, file_type) {
+                self.write_leaf(seed, block, data);
+                return Ok(());
+            }
+        }
+        let block = self.append_dir_block(ino, dir)?;
+        let mut data = vec![0u8; self.geo.block_size];
+        put_record(&mut data, 0, target, end, name, file_type);
+        self.write_leaf(seed, block, data);
+        Ok(())
+    }
+
+    /// Hash version and seed of an htree directory.
+    fn dx_hash_params(&self, root: &[u8]) -> Result<(u8, [u32; 4]), VfsError> {
+        if root[DX_ROOT_INFO + 5] != 8 {
+            return Err(VfsError::IoError);
+        }
+        let mut version = root[DX_ROOT_INFO + 4];
+        if version <= DX_HASH_TEA && self.sb.flags & EXT2_FLAGS_UNSIGNED_HASH != 0 {
+            version += 3;
+        }
+        let hash_seed = self.sb.hash_seed;
+        Ok((version, hash_seed))
+    }
+
+    /// Adds an entry to an htree directory, splitting the leaf it hashes
+    /// to when full.
+    fn dx_add(&mut self, ino: u32, dir: &mut Inode, name: &[u8], target: u32, file_type: u8) -> Result<(), VfsError> {
+        let end = self.leaf_end();
+        let seed = self.inode_seed(ino, dir);
+        let (root_block, root) = self.dir_block(dir, 0)?;
+        let (version, hash_seed) = self.dx_hash_params(&root)?;
+        let hash = dx_hash(name, version, &hash_seed)?;
+        let levels = root[DX_ROOT_INFO + 6] as usize;
+        if levels > 1 {
+            return Err(VfsError::NotSupported);
+        }
+
+        let mut frames = vec![DxFrame::new(root_block, root, DX_ROOT_COUNT, hash)?];
+        for _ in 0..levels {
+            let (block, data) = self.dir_block(dir, frames[frames.len() - 1].target())?;
+            frames.push(DxFrame::new(block, data, DX_NODE_COUNT, hash)?);
+        }
+
+        let leaf_lblk = frames[frames.len() - 1].target();
+        let (leaf_block, mut leaf) = self.dir_block(dir, leaf_lblk)?;
+        if add_record(&mut leaf, end, name, target, file_type) {
+            self.write_leaf(seed, leaf_block, leaf);
+            return Ok(());
+        }
+
+        // Make room in the parent of the new leaf first
+        let last = frames.len() - 1;
+        if frames[last].count() == frames[last].limit() {
+            if last == 0 {
+                self.dx_add_level(ino, dir, &mut frames)?;
+            } else if frames[0].count() < frames[0].limit() {
+                self.dx_split_node(ino, dir, &mut frames)?;
+            } else {
+                return Err(VfsError::NoSpace);
+            }
+        }
+
+        self.dx_split_leaf(ino, dir, &mut frames, leaf_block, &leaf, hash, version, &hash_seed)?;
+        for frame in &frames {
+            self.write_dx(seed, frame);
+        }
+
+        // Retry against the updated index
+        self.dx_add(ino, dir, name, target, file_type)
+    }
+
+    /// Moves the root's entries into a new index node below it.
+    fn dx_add_level(&mut self, ino: u32, dir: &mut Inode, frames: &mut Vec<DxFrame>) -> Result<(), VfsError> {
+        let block_size = self.geo.block_size;
+        let lblk = (dir.size() / block_size as u64) as u32;
+        let block = self.append_dir_block(ino, dir)?;
+
+        let root = &mut frames[0];
+        let count = root.count();
+        let entries: Vec<(u32, u32)> = (0..count).map(|i| (if i == 0 { 0 } else { root.hash(i) }, root.child(i))).collect();
+        let limit = self.dx_limit(DX_NODE_COUNT);
+        let at = root.at;
+        let node = DxFrame { block, data: build_dx_node(block_size, limit, &entries), count_offset: DX_NODE_COUNT, at };
+
+        let root = &mut frames[0];
+        put_le16(&mut root.data, DX_ROOT_COUNT + 2, 1);
+        put_le32(&mut root.data, DX_ROOT_COUNT + 4, lblk);
+        root.data[DX_ROOT_INFO + 6] = 1;
+        root.at = 0;
+        frames.push(node);
+        Ok(())
+    }
+
+    /// Splits the full lowest index node in half.
+    fn dx_split_node(&mut self, ino: u32, dir: &mut Inode, frames: &mut [DxFrame]) -> Result<(), VfsError> {
+        let block_size = self.geo.block_size;
+        let lblk = (dir.size() / block_size as u64) as u32;
+        let block = self.append_dir_block(ino, dir)?;
+
+        let node = &mut frames[1];
+        let count = node.count();
+        let half = count / 2;
+        let moved: Vec<(u32, u32)> = (half..count).map(|i| (node.hash(i), node.child(i))).collect();
+        let split_hash = moved[0].0;
+        put_le16(&mut node.data, DX_NODE_COUNT + 2, half as u16);
+        let mut right = DxFrame {
+            block,
+            data: build_dx_node(block_size, self.dx_limit(DX_NODE_COUNT), &moved),
+            count_offset: DX_NODE_COUNT,
+            at: 0,
+        };
+
+        let at = frames[1].at;
+        if at >= half {
+            right.at = at - half;
+            let left = core::mem::replace(&mut frames[1], right);
+            self.write_dx(self.inode_seed(ino, dir), &left);
+        } else {
+            self.write_dx(self.inode_seed(ino, dir), &right);
+        }
+
+        let root = &mut frames[0];
+        let index = root.at + 1;
+        root.insert(index, split_hash, lblk);
+        if frames[1].block == block {
+            frames[0].at = index;
+        }
+        Ok(())
+    }
+
+    /// Splits a full leaf by hash, moving the upper half to a new block.
+    #[allow(clippy::too_many_arguments)]
+    fn dx_split_leaf(
+        &mut self,
+        ino: u32,
+        dir: &mut Inode,
+        frames: &mut [DxFrame],
+        leaf_block: u64,
+        leaf: &[u8],
+        hash: u32,
+        version: u8,
+        hash_seed: &[u32; 4],
+    ) -> Result<(), VfsError> {
+        let block_size = self.geo.block_size;
+        let end = self.leaf_end();
+        let mut items = Vec::new();
+        for record in records(leaf, end) {
+            if record.inode == 0 {
+                continue;
+            }
+            let name = record.name(leaf).to_vec();
+            items.push(DirItem { hash: dx_hash(&name, version, hash_seed)?, inode: record.inode, file_type: record.file_type, name });
+        }
+        items.sort_by_key(|item| item.hash);
+
+        // Split near the middle, but never between equal hashes unless
+        // they fill the block
+        let mut split = items.len() / 2;
+        while split > 0 && items[split].hash == items[split - 1].hash {
+            split -= 1;
+        }
+        let continued = split == 0;
+        if continued {
+            split = items.len() / 2;
+        }
+        let split_hash = items[split].hash | continued as u32;
+
+        let lblk = (dir.size() / block_size as u64) as u32;
+        let new_block = self.append_dir_block(ino, dir)?;
+        let seed = self.inode_seed(ino, dir);
+        self.write_leaf(seed, leaf_block, build_leaf(block_size, end, &items[..split]));
+        self.write_leaf(seed, new_block, build_leaf(block_size, end, &items[split..]));
+
+        let frame = frames.last_mut().ok_or(VfsError::IoError)?;
+        let index = frame.at + 1;
+        frame.insert(index, split_hash, lblk);
+        if hash >= split_hash & !1 {
+            frame.at = index;
+        }
+        Ok(())
+    }
+
+    // ---- Operations ----------------------------------------------------------
+
+    /// Creates an inode and links it into `parent`.
+    fn create(&mut self, parent: u32, name: &str, file_type: VfsFileType, target: Option<&str>) -> Result<u32, VfsError> {
+        check_name(name)?;
+        let mut dir = self.inode(parent)?;
+        if dir.file_type() != VfsFileType::Directory {
+            return Err(VfsError::NotADirectory);
+        }
+        if self.dir_lookup(&dir, name.as_bytes())?.is_some() {
+            return Err(VfsError::AlreadyExists);
+        }
+        let mode = match file_type {
+            VfsFileType::Regular => S_IFREG | 0o644,
+            VfsFileType::Directory => S_IFDIR | 0o755,
+            VfsFileType::Symlink => S_IFLNK | 0o777,
+            _ => return Err(VfsError::NotSupported),
+        };
+
+        let is_dir = file_type == VfsFileType::Directory;
+        let ino = self.alloc_inode((parent - 1) / self.sb.inodes_per_group, is_dir)?;
+        let mut inode = self.new_inode(ino, mode)?;
+        if is_dir {
+            let block = self.append_dir_block(ino, &mut inode)?;
+            let end = self.leaf_end();
+            let mut data = vec![0u8; self.geo.block_size];
+            put_record(&mut data, 0, ino, 12, b".", EXT4_FT_DIR);
+            put_record(&mut data, 12, parent, end - 12, b"..", EXT4_FT_DIR);
+            let seed = self.inode_seed(ino, &inode);
+            self.write_leaf(seed, block, data);
+            inode.links_count = 2;
+            dir.links_count = inc_dir_links(dir.links_count);
+        }
+        if let Some(target) = target {
+            self.set_symlink(ino, &mut inode, target)?;
+        }
+        self.write_inode(ino, &inode)?;
+
+        self.dir_add(parent, &mut dir, name.as_bytes(), ino, dirent_file_type(file_type))?;
+        let time = now();
+        dir.mtime = time;
+        dir.ctime = time;
+        self.write_inode(parent, &dir)?;
+        Ok(ino)
+    }
+
+    /// Stores a symlink target, in the inode when it is short enough.
+    fn set_symlink(&mut self, ino: u32, inode: &mut Inode, target: &str) -> Result<(), VfsError> {
+        let bytes = target.as_bytes();
+        if bytes.is_empty() || bytes.len() >= self.geo.block_size {
+            return Err(VfsError::InvalidArgument);
+        }
+        if bytes.len() <= FAST_SYMLINK_MAX {
+            let mut raw = [0u8; 60];
+            raw[..bytes.len()].copy_from_slice(bytes);
+            inode.set_block_bytes(&raw);
+        } else {
+            inode.flags = EXT4_EXTENTS_FL;
+            let root = Node { at: NodeAt::Root, depth: 0, max: 4, entries: Vec::new() };
+            self.store_node(ino, inode, &root)?;
+            self.write_data(ino, inode, 0, bytes)?;
+        }
+        inode.set_size(bytes.len() as u64);
+        Ok(())
+    }
+
+    /// Writes file data, allocating blocks for holes. Data goes straight to
+    /// disk; the caller writes the inode.
+    fn write_data(&mut self, ino: u32, inode: &mut Inode, offset: u64, data: &[u8]) -> Result<(), VfsError> {
+        let flags = { inode.flags };
+        if !inode.uses_extents() || flags & EXT4_INLINE_DATA_FL != 0 {
+            return Err(VfsError::NotSupported);
+        }
+        let block_size = self.geo.block_size as u64;
+        let end = offset + data.len() as u64;
+        if end.div_ceil(block_size) > u32::MAX as u64 {
+            return Err(VfsError::InvalidArgument);
+        }
+
+        let mut position = offset;
+        let mut goal = self.inode_goal(ino);
+        while position < end {
+            let lblk = (position / block_size) as u32;
+            let within = (position % block_size) as usize;
+            let chunk = (block_size as usize - within).min((end - position) as usize);
+            let source = &data[(position - offset) as usize..][..chunk];
+
+            let existing = self.mapping(inode, lblk)?;
+            let (block, fresh) = match existing {
+                Some(mapping) if !mapping.unwritten => (mapping.pblk, false),
+                Some(mapping) => {
+                    self.map_block(ino, inode, lblk, mapping.pblk)?;
+                    (mapping.pblk, true)
+                }
+                None => {
+                    if lblk > 0 {
+                        if let Some(previous) = self.mapping(inode, lblk - 1)? {
+                            goal = previous.pblk + 1;
+                        }
+                    }
+                    let block = self.alloc_block(goal)?;
+                    self.map_block(ino, inode, lblk, block)?;
+                    self.add_inode_blocks(inode, 1);
+                    (block, true)
+                }
+            };
+            goal = block + 1;
+
+            let mut buffer = if fresh {
+                vec![0u8; block_size as usize]
+            } else if chunk < block_size as usize {
+                self.fs.read_block(block)?
+            } else {
+                Vec::new()
+            };
+            let written = if buffer.is_empty() {
+                self.fs.write_block(block, source)
+            } else {
+                buffer[within..within + chunk].copy_from_slice(source);
+                self.fs.write_block(block, &buffer)
+            };
+            written?;
+            position += chunk as u64;
+        }
+
+        if end > inode.size() {
+            inode.set_size(end);
+        }
+        let time = now();
+        inode.mtime = time;
+        inode.ctime = time;
+        Ok(())
+    }
+
+    /// Changes a file's size, freeing the blocks past the new end.
+    fn truncate(&mut self, ino: u32, inode: &mut Inode, size: u64) -> Result<(), VfsError> {
+        if !inode.uses_extents() {
+            return Err(VfsError::NotSupported);
+        }
+        let block_size = self.geo.block_size as u64;
+        if size < inode.size() {
+            let keep = size.div_ceil(block_size);
+            if keep <= u32::MAX as u64 {
+                self.truncate_extents(ino, inode, keep as u32)?;
+            }
+            // Bytes past the end in the last block must read as zeros later
+            let within = (size % block_size) as usize;
+            if within != 0 {
+                if let Some(mapping) = self.mapping(inode, (size / block_size) as u32)? {
+                    if !mapping.unwritten {
+                        let mut data = self.fs.read_block(mapping.pblk)?;
+                        data[within..].fill(0);
+                        self.fs.write_block(mapping.pblk, &data)?;
+                    }
+                }
+            }
+        }
+        inode.set_size(size);
+        let time = now();
+        inode.mtime = time;
+        inode.ctime = time;
+        Ok(())
+    }
+
+    /// Removes a directory entry and drops the link it held.
+    fn unlink(&mut self, parent: u32, name: &str) -> Result<(), VfsError> {
+        if name == "." || name == ".." {
+            return Err(VfsError::InvalidArgument);
+        }
+        let mut dir = self.inode(parent)?;
+        if dir.file_type() != VfsFileType::Directory {
+            return Err(VfsError::NotADirectory);
+        }
+        let (ino, _) = self.dir_lookup(&dir, name.as_bytes())?.ok_or(VfsError::NotFound)?;
+        let mut inode = self.inode(ino)?;
+        let is_dir = inode.file_type() == VfsFileType::Directory;
+        if is_dir && !self.dir_is_empty(&inode)? {
+            return Err(VfsError::NotEmpty);
+        }
+        let links = { inode.links_count };
+        if (is_dir || links <= 1) && !self.releasable(&inode) {
+            return Err(VfsError::NotSupported);
+        }
+
+        self.dir_remove(parent, &dir, name.as_bytes())?;
+        let time = now();
+        if is_dir {
+            dir.links_count = dec_dir_links(dir.links_count);
+            inode.links_count = 0;
         } else {
-            self.read_indirect_data(inode, offset, &mut buffer[..to_read])
+            inode.links_count = links.saturating_sub(1);
+        }
+        dir.mtime = time;
+        dir.ctime = time;
+        self.write_inode(parent, &dir)?;
+
+        if inode.links_count == 0 {
+            self.release(ino, inode)
+        } else {
+            inode.ctime = time;
+            self.write_inode(ino, &inode)
+        }
+    }
+
+    /// Whether the write path can free an inode's blocks.
+    fn releasable(&self, inode: &Inode) -> bool {
+        inode.uses_extents()
+            || inode.is_fast_symlink()
+            || !matches!(inode.file_type(), VfsFileType::Regular | VfsFileType::Directory | VfsFileType::Symlink)
+            || inode.size() == 0
+    }
+
+    /// Frees an unlinked inode and everything it owns.
+    fn release(&mut self, ino: u32, mut inode: Inode) -> Result<(), VfsError> {
+        let is_dir = inode.file_type() == VfsFileType::Directory;
+        if inode.uses_extents() {
+            self.truncate_extents(ino, &mut inode, 0)?;
+        }
+        self.release_xattr_block(&mut inode)?;
+        inode.set_size(0);
+        inode.set_i_blocks(0);
+        inode.links_count = 0;
+        // e2fsck reads a zero dtime as "still in use"
+        inode.dtime = now().max(1);
+        self.write_inode(ino, &inode)?;
+        self.free_inode(ino, is_dir)
+    }
+
+    /// Drops an inode's reference to its extended attribute block.
+    fn release_xattr_block(&mut self, inode: &mut Inode) -> Result<(), VfsError> {
+        let block = inode.file_acl();
+        if block == 0 {
+            return Ok(());
         }
+        let mut data = self.read(block)?;
+        if le32(&data, 0) == XATTR_MAGIC {
+            let references = le32(&data, XATTR_REFCOUNT);
+            if references <= 1 {
+                self.free_block(block)?;
+            } else {
+                put_le32(&mut data, XATTR_REFCOUNT, references - 1);
+                self.seal_xattr_block(block, &mut data);
+                self.blocks.insert(block, data);
+            }
+            self.add_inode_blocks(inode, -1);
+        }
+        inode.set_file_acl(0);
+        Ok(())
     }
-    
-    /// Reads data using extent tree.
-    fn read_extent_data(&self, inode: &Inode, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
-        let block_size = *self.block_size.lock() as u64;
-        let block_num = offset / block_size;
-        let block_offset = (offset % block_size) as usize;
-        
-        // Parse extent header - use safe block_bytes method
-        let extent_bytes = inode.block_bytes();
-        let extent_data = &extent_bytes[..];
-        
-        let header = unsafe { *(extent_data.as_ptr() as *const ExtentHeader) };
-        if header.magic != 0xF30A {
-            return Err(VfsError::InvalidArgument);
+
+    /// Updates an extended attribute block's checksum.
+    fn seal_xattr_block(&self, block: u64, data: &mut [u8]) {
+        if self.geo.metadata_csum {
+            put_le32(data, XATTR_CHECKSUM, 0);
+            let csum = crc32c(crc32c(self.geo.csum_seed, &block.to_le_bytes()), data);
+            put_le32(data, XATTR_CHECKSUM, csum);
         }
-        
-        // Find the physical block
-        let phys_block = self.find_extent_block(&header, extent_data, block_num, header.depth)?;
-        
-        if phys_block == 0 {
-            // Sparse file - return zeros
-            for b in buffer.iter_mut() {
-                *b = 0;
+    }
+
+    /// Reads an inode's extended attributes as this transaction sees them.
+    fn xattrs(&self, ino: u32, inode: &Inode) -> Result<Vec<XattrEntry>, VfsError> {
+        let (location, offset) = self.inode_location(ino)?;
+        let table = self.read(location)?;
+        let block = match inode.file_acl() {
+            0 => None,
+            block => Some(self.read(block)?),
+        };
+        inode_xattrs(&table[offset..offset + self.geo.inode_size], inode, block.as_deref())
+    }
+
+    /// Stores an inode's extended attributes: as many as fit in the inode,
+    /// the rest in an attribute block of its own. A block shared with
+    /// other inodes is left to them.
+    fn set_xattrs(&mut self, ino: u32, inode: &mut Inode, entries: Vec<XattrEntry>) -> Result<(), VfsError> {
+        let area = inode.xattr_area(self.geo.inode_size);
+        let mut room = area.as_ref().map_or(0, |area| area.len() - 4);
+        let (mut in_inode, mut in_block) = (Vec::new(), Vec::new());
+        for entry in entries {
+            // Each area ends with a 4-byte terminator
+            if entry.footprint() + 4 <= room {
+                room -= entry.footprint();
+                in_inode.push(entry);
+            } else {
+                in_block.push(entry);
             }
-            return Ok(buffer.len());
         }
-        
-        // Read the block
-        let block_data = self.read_block(phys_block)?;
-        
-        let to_copy = core::cmp::min(buffer.len(), block_data.len() - block_offset);
-        buffer[..to_copy].copy_from_slice(&block_data[block_offset..block_offset + to_copy]);
-        
-        Ok(to_copy)
-    }
-    
-    /// Finds a physical block in the extent tree.
-    fn find_extent_block(&self, header: &ExtentHeader, data: &[u8], file_block: u64, depth: u16) -> Result<u64, VfsError> {
-        if depth == 0 {
-            // Leaf node - search extents
-            let extent_offset = 12; // sizeof(ExtentHeader)
-            for i in 0..header.entries as usize {
-                let ext_ptr = unsafe { 
-                    (data.as_ptr().add(extent_offset + i * 12)) as *const Extent 
-                };
-                let extent = unsafe { *ext_ptr };
-                
-                let start = extent.block as u64;
-                let end = start + extent.length() as u64;
-                
-                if file_block >= start && file_block < end {
-                    let offset_in_extent = file_block - start;
-                    return Ok(extent.start() + offset_in_extent);
-                }
+
+        if let Some(area) = area {
+            let (location, offset) = self.inode_location(ino)?;
+            let raw = &mut self.block_mut(location)?[offset + area.start..offset + area.end];
+            raw.fill(0);
+            if !in_inode.is_empty() {
+                put_le32(raw, 0, XATTR_MAGIC);
+                encode_xattrs(raw, 4, 4, &in_inode).ok_or(VfsError::NoSpace)?;
             }
-            Ok(0) // Not found (sparse)
+        }
+
+        if in_block.is_empty() {
+            self.release_xattr_block(inode)?;
         } else {
-            // Internal node - find child
-            let idx_offset = 12;
-            for i in 0..header.entries as usize {
-                let idx_ptr = unsafe {
-                    (data.as_ptr().add(idx_offset + i * 12)) as *const ExtentIndex
-                };
-                let idx = unsafe { *idx_ptr };
-                
-                let next_idx = if i + 1 < header.entries as usize {
-                    let next_ptr = unsafe {
-                        (data.as_ptr().add(idx_offset + (i + 1) * 12)) as *const ExtentIndex
-                    };
-                    Some(unsafe { *next_ptr })
-                } else {
-                    None
-                };
-                
-                let in_range = if let Some(next) = next_idx {
-                    file_block >= idx.block as u64 && file_block < next.block as u64
-                } else {
-                    file_block >= idx.block as u64
-                };
-                
-                if in_range {
-                    // Read child block
-                    let child_block = self.read_block(idx.leaf())?;
-                    let child_header = unsafe { *(child_block.as_ptr() as *const ExtentHeader) };
-                    return self.find_extent_block(&child_header, &child_block, file_block, depth - 1);
-                }
+            let mut block = inode.file_acl();
+            if block != 0 && le32(&self.read(block)?, XATTR_REFCOUNT) != 1 {
+                self.release_xattr_block(inode)?;
+                block = 0;
             }
-            Ok(0)
+            if block == 0 {
+                block = self.alloc_block(self.inode_goal(ino))?;
+                self.add_inode_blocks(inode, 1);
+                inode.set_file_acl(block);
+            }
+            // Lookups in a block rely on this order
+            in_block.sort_by(|a, b| (a.index, a.name.len(), &a.name).cmp(&(b.index, b.name.len(), &b.name)));
+            let mut data = vec![0u8; self.geo.block_size];
+            put_le32(&mut data, 0, XATTR_MAGIC);
+            put_le32(&mut data, XATTR_REFCOUNT, 1);
+            put_le32(&mut data, XATTR_BLOCKS, 1);
+            let hash = encode_xattrs(&mut data, XATTR_HEADER_SIZE, 0, &in_block).ok_or(VfsError::NoSpace)?;
+            put_le32(&mut data, XATTR_HASH, hash);
+            self.seal_xattr_block(block, &mut data);
+            self.blocks.insert(block, data);
         }
+
+        inode.ctime = now();
+        self.write_inode(ino, inode)
     }
-    
-    /// Reads data using indirect blocks (legacy).
-    fn read_indirect_data(&self, inode: &Inode, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
-        let block_size = *self.block_size.lock() as u64;
-        let block_num = (offset / block_size) as u32;
-        let block_offset = (offset % block_size) as usize;
-        
-        // Direct blocks (0-11)
-        let phys_block = if block_num < 12 {
-            inode.block[block_num as usize] as u64
-        } else {
-            // Indirect block support would go here
-            // For simplicity, we only support extents for large files
-            return Err(VfsError::NotSupported);
-        };
-        
-        if phys_block == 0 {
-            for b in buffer.iter_mut() {
-                *b = 0;
+
+    /// Sets (`Some`) or removes (`None`) one extended attribute.
+    fn setxattr(&mut self, ino: u32, name: &str, value: Option<&[u8]>) -> Result<(), VfsError> {
+        let (index, key) = XattrEntry::key(name)?;
+        let mut inode = self.inode(ino)?;
+        let mut entries = self.xattrs(ino, &inode)?;
+        let existing = entries.iter().position(|entry| entry.index == index && entry.name == key);
+        match (existing, value) {
+            (Some(i), Some(value)) => entries[i].value = value.to_vec(),
+            (None, Some(value)) => entries.push(XattrEntry { index, name: key.to_vec(), value: value.to_vec() }),
+            (Some(i), None) => {
+                entries.remove(i);
             }
-            return Ok(buffer.len());
+            (None, None) => return Err(VfsError::NoAttribute),
         }
-        
-        let block_data = self.read_block(phys_block)?;
-        let to_copy = core::cmp::min(buffer.len(), block_data.len() - block_offset);
-        buffer[..to_copy].copy_from_slice(&block_data[block_offset..block_offset + to_copy]);
-        
-        Ok(to_copy)
+        self.set_xattrs(ino, &mut inode, entries)
     }
-    
-    /// Reads a directory.
-    fn read_directory(&self, inode: &Inode) -> Result<Vec<VfsDirEntry>, VfsError> {
-        if inode.file_type() != VfsFileType::Directory {
+
+    /// Fails if directory `ino` is `dir` or one of its ancestors.
+    fn check_not_ancestor(&self, ino: u32, dir: u32) -> Result<(), VfsError> {
+        let mut current = dir;
+        for _ in 0..4096 {
+            if current == ino {
+                return Err(VfsError::InvalidArgument);
+            }
+            if current == EXT4_ROOT_INODE {
+                return Ok(());
+            }
+            let inode = self.inode(current)?;
+            current = self.dir_lookup(&inode, b"..")?.ok_or(VfsError::IoError)?.0;
+        }
+        Err(VfsError::IoError)
+    }
+
+    /// Moves an entry, replacing a compatible target.
+    fn rename(&mut self, old_parent: u32, old_name: &str, new_parent: u32, new_name: &str) -> Result<(), VfsError> {
+        check_name(new_name)?;
+        let old_dir = self.inode(old_parent)?;
+        let new_dir = self.inode(new_parent)?;
+        if old_dir.file_type() != VfsFileType::Directory || new_dir.file_type() != VfsFileType::Directory {
             return Err(VfsError::NotADirectory);
         }
-        
-        let mut entries = Vec::new();
-        let dir_size = inode.size();
-        let mut offset = 0u64;
-        
-        while offset < dir_size {
-            let mut entry_buffer = [0u8; 8];
-            self.read_inode_data(inode, offset, &mut entry_buffer)?;
-            
-            let dir_entry = unsafe { *(entry_buffer.as_ptr() as *const DirEntry) };
-            
-            if dir_entry.rec_len == 0 {
-                break;
+        let (ino, file_type) = self.dir_lookup(&old_dir, old_name.as_bytes())?.ok_or(VfsError::NotFound)?;
+        if old_parent == new_parent && old_name == new_name {
+            return Ok(());
+        }
+        let mut inode = self.inode(ino)?;
+        let is_dir = inode.file_type() == VfsFileType::Directory;
+        let moves_dir = is_dir && old_parent != new_parent;
+        if moves_dir {
+            self.check_not_ancestor(ino, new_parent)?;
+        }
+
+        if let Some((target, _)) = self.dir_lookup(&new_dir, new_name.as_bytes())? {
+            if target == ino {
+                return Ok(());
             }
-            
-            if dir_entry.inode != 0 && dir_entry.name_len > 0 {
-                let mut name_buffer = vec![0u8; dir_entry.name_len as usize];
-                self.read_inode_data(inode, offset + 8, &mut name_buffer)?;
-                
-                let name = String::from_utf8_lossy(&name_buffer).into_owned();
-                let file_type = match dir_entry.file_type {
-                    EXT4_FT_REG_FILE => VfsFileType::Regular,
-                    EXT4_FT_DIR => VfsFileType::Directory,
-                    EXT4_FT_SYMLINK => VfsFileType::Symlink,
-                    EXT4_FT_CHRDEV => VfsFileType::CharDevice,
-                    EXT4_FT_BLKDEV => VfsFileType::BlockDevice,
-                    EXT4_FT_FIFO => VfsFileType::Fifo,
-                    EXT4_FT_SOCK => VfsFileType::Socket,
-                    _ => VfsFileType::Regular,
-                };
-                
-                entries.push(VfsDirEntry {
-                    ino: dir_entry.inode as InodeNum,
-                    name,
-                    file_type,
-                });
+            let target_is_dir = self.inode(target)?.file_type() == VfsFileType::Directory;
+            if is_dir != target_is_dir {
+                return Err(if target_is_dir { VfsError::IsADirectory } else { VfsError::NotADirectory });
             }
-            
-            offset += dir_entry.rec_len as u64;
+            self.unlink(new_parent, new_name)?;
         }
-        
-        Ok(entries)
+
+        let old_dir = self.inode(old_parent)?;
+        self.dir_remove(old_parent, &old_dir, old_name.as_bytes())?;
+        let time = now();
+        let mut new_dir = self.inode(new_parent)?;
+        self.dir_add(new_parent, &mut new_dir, new_name.as_bytes(), ino, file_type)?;
+        if moves_dir {
+            new_dir.links_count = inc_dir_links(new_dir.links_count);
+        }
+        new_dir.mtime = time;
+        new_dir.ctime = time;
+        self.write_inode(new_parent, &new_dir)?;
+
+        if old_parent != new_parent {
+            let mut old_dir = self.inode(old_parent)?;
+            if moves_dir {
+                old_dir.links_count = dec_dir_links(old_dir.links_count);
+            }
+            old_dir.mtime = time;
+            old_dir.ctime = time;
+            self.write_inode(old_parent, &old_dir)?;
+        }
+        if moves_dir {
+            self.set_dotdot(ino, &inode, new_parent)?;
+        }
+        inode.ctime = time;
+        self.write_inode(ino, &inode)
     }
-    
-    /// Looks up a name in a directory.
-    fn lookup_in_dir(&self, dir_inode: &Inode, name: &str) -> Result<u32, VfsError> {
-        let entries = self.read_directory(dir_inode)?;
-        
-        for entry in entries {
-            if entry.name == name {
-                return Ok(entry.ino as u32);
+
+    /// Adds a hard link.
+    fn link(&mut self, ino: u32, parent: u32, name: &str) -> Result<(), VfsError> {
+        check_name(name)?;
+        let mut inode = self.inode(ino)?;
+        if inode.file_type() == VfsFileType::Directory {
+            return Err(VfsError::IsADirectory);
+        }
+        if inode.links_count >= EXT4_LINK_MAX {
+            return Err(VfsError::NoSpace);
+        }
+        let mut dir = self.inode(parent)?;
+        if dir.file_type() != VfsFileType::Directory {
+            return Err(VfsError::NotADirectory);
+        }
+        if self.dir_lookup(&dir, name.as_bytes())?.is_some() {
+            return Err(VfsError::AlreadyExists);
+        }
+        self.dir_add(parent, &mut dir, name.as_bytes(), ino, dirent_file_type(inode.file_type()))?;
+        let time = now();
+        dir.mtime = time;
+        dir.ctime = time;
+        self.write_inode(parent, &dir)?;
+        inode.links_count += 1;
+        inode.ctime = time;
+        self.write_inode(ino, &inode)
+    }
+
+    // ---- Commit ----------------------------------------------------------------
+
+    /// Stores a group descriptor into its table block.
+    fn write_group_desc(&mut self, group: u32) -> Result<(), VfsError> {
+        let geo = self.geo;
+        let sb = self.sb;
+        let desc = self.groups[group as usize];
+        let byte = group as u64 * geo.desc_size as u64;
+        let block = sb.first_data_block as u64 + 1 + byte / geo.block_size as u64;
+        let offset = (byte % geo.block_size as u64) as usize;
+
+        let data = self.block_mut(block)?;
+        let raw = &mut data[offset..offset + geo.desc_size];
+        let len = geo.desc_size.min(core::mem::size_of::<GroupDesc>());
+        raw[..len].copy_from_slice(&as_bytes(&desc)[..len]);
+        if geo.group_csum {
+            let csum = group_desc_checksum(&geo, &sb, group, raw);
+            put_le16(raw, GD_CHECKSUM, csum);
+        }
+        Ok(())
+    }
+
+    /// Checksums, journals and publishes the transaction.
+    fn commit(mut self) -> Result<(), VfsError> {
+        let geo = self.geo;
+        if geo.metadata_csum {
+            for group in core::mem::take(&mut self.block_bitmaps) {
+                let location = self.groups[group as usize].block_bitmap(geo.is_64bit);
+                let bytes = self.sb.blocks_per_group as usize / 8;
+                let csum = crc32c(geo.csum_seed, &self.blocks[&location][..bytes]);
+                self.groups[group as usize].set_block_bitmap_csum(csum, geo.is_64bit);
+                self.dirty_groups.insert(group);
+            }
+            for group in core::mem::take(&mut self.inode_bitmaps) {
+                let location = self.groups[group as usize].inode_bitmap(geo.is_64bit);
+                let bytes = self.sb.inodes_per_group as usize / 8;
+                let csum = crc32c(geo.csum_seed, &self.blocks[&location][..bytes]);
+                self.groups[group as usize].set_inode_bitmap_csum(csum, geo.is_64bit);
+                self.dirty_groups.insert(group);
             }
         }
-        
-        Err(VfsError::NotFound)
+        if self.dirty_groups.is_empty() && self.blocks.is_empty() {
+            return Ok(());
+        }
+        for group in core::mem::take(&mut self.dirty_groups) {
+            self.write_group_desc(group)?;
+        }
+
+        // Free counts live in the superblock
+        let block_size = geo.block_size as u64;
+        let (block, offset) = (SUPERBLOCK_OFFSET / block_size, (SUPERBLOCK_OFFSET % block_size) as usize);
+        let sb = self.sb;
+        let data = self.block_mut(block)?;
+        encode_superblock(&sb, &mut data[offset..offset + SUPERBLOCK_SIZE]);
+
+        let blocks = core::mem::take(&mut self.blocks);
+        let device = &*self.fs.device;
+        match self.journal.as_mut() {
+            Some(journal) => journal.commit(device, &blocks)?,
+            None => {
+                for (&block, data) in &blocks {
+                    self.fs.write_block(block, data)?;
+                }
+                device.flush().map_err(|_| VfsError::IoError)?;
+            }
+        }
+
+        *self.fs.group_descs.write() = core::mem::take(&mut self.groups);
+        *self.fs.superblock.write() = Some(self.sb);
+        let mut cache = self.fs.inode_cache.write();
+        for ino in &self.inodes {
+            cache.remove(ino);
+        }
+        Ok(())
     }
-    
-    /// Resolves a path to an inode number.
-    fn resolve_path(&self, path: &str) -> Result<u32, VfsError> {
-        let mut current_inode = EXT4_ROOT_INODE;
-        
-        for component in path.split('/').filter(|s| !s.is_empty()) {
-            let inode = self.read_inode(current_inode)?;
-            current_inode = self.lookup_in_dir(&inode, component)?;
+}
+
+/// Most links an inode may have; directories past it report one link.
+const EXT4_LINK_MAX: u16 = 65000;
+
+fn inc_dir_links(links: u16) -> u16 {
+    if links == 1 || links >= EXT4_LINK_MAX - 1 { 1 } else { links + 1 }
+}
+
+fn dec_dir_links(links: u16) -> u16 {
+    if links > 2 { links - 1 } else { links }
+}
+
+/// Computes a group descriptor checksum over its raw on-disk bytes.
+fn group_desc_checksum(geo: &Geometry, sb: &Superblock, group: u32, raw: &[u8]) -> u16 {
+    let group = group.to_le_bytes();
+    if geo.metadata_csum {
+        let mut csum = crc32c(geo.csum_seed, &group);
+        csum = crc32c(csum, &raw[..GD_CHECKSUM]);
+        csum = crc32c(csum, &[0, 0]);
+        csum = crc32c(csum, &raw[GD_CHECKSUM + 2..]);
+        csum as u16
+    } else {
+        let uuid = sb.uuid;
+        let mut csum = crc16(!0, &uuid);
+        csum = crc16(csum, &group);
+        csum = crc16(csum, &raw[..GD_CHECKSUM]);
+        if geo.is_64bit {
+            csum = crc16(csum, &raw[GD_CHECKSUM + 2..]);
         }
-        
-        Ok(current_inode)
+        csum
     }
 }
 
@@ -923,6 +3688,10 @@ impl Filesystem for Ext4Fs {
     fn name(&self) -> &'static str {
         "ext4"
     }
+
+    fn root_ino(&self) -> InodeNum {
+        EXT4_ROOT_INODE as InodeNum
+    }
     
     fn statfs(&self) -> Result<VfsStatFs, VfsError> {
         let sb = self.superblock.read();
@@ -935,7 +3704,8 @@ impl Filesystem for Ext4Fs {
             files: sb.inodes_count as u64,
             ffree: sb.free_inodes_count as u64,
             bsize: sb.block_size(),
-            namelen: 255,
+            namelen: EXT4_NAME_LEN as u32,
+            bsaved: 0,
         })
     }
     
@@ -955,7 +3725,7 @@ impl Filesystem for Ext4Fs {
             size: inode.size(),
             nlink: inode.links_count as u32,
             blksize: *self.block_size.lock(),
-            blocks: inode.blocks_lo as u64,
+            blocks: inode.i_blocks(),
             atime: inode.atime as u64,
             mtime: inode.mtime as u64,
             ctime: inode.ctime as u64,
@@ -986,7 +3756,7 @@ impl Filesystem for Ext4Fs {
         let size = inode.size() as usize;
         
         // Fast symlinks are stored inline
-        if size <= 60 {
+        if inode.is_fast_symlink() {
             let block_bytes = inode.block_bytes();
             let data = &block_bytes[..size];
             Ok(String::from_utf8_lossy(data).into_owned())
@@ -997,31 +3767,98 @@ impl Filesystem for Ext4Fs {
         }
     }
     
-    // Write operations return error (read-only)
-    
-    fn create(&self, _parent: InodeNum, _name: &str, _file_type: VfsFileType) -> Result<InodeNum, VfsError> {
-        Err(VfsError::ReadOnlyFs)
+    fn create(&self, parent: InodeNum, name: &str, file_type: VfsFileType) -> Result<InodeNum, VfsError> {
+        let mut tx = self.begin()?;
+        let ino = tx.create(parent as u32, name, file_type, None)?;
+        tx.commit()?;
+        Ok(ino as InodeNum)
     }
     
-    fn write(&self, _ino: InodeNum, _offset: u64, _data: &[u8]) -> Result<usize, VfsError> {
-        Err(VfsError::ReadOnlyFs)
+    fn write(&self, ino: InodeNum, offset: u64, data: &[u8]) -> Result<usize, VfsError> {
+        // Large writes are split so each transaction fits in the journal
+        for (i, chunk) in data.chunks(WRITE_CHUNK).enumerate() {
+            let mut tx = self.begin()?;
+            let mut inode = tx.inode(ino as u32)?;
+            match inode.file_type() {
+                VfsFileType::Regular => {}
+                VfsFileType::Directory => return Err(VfsError::IsADirectory),
+                _ => return Err(VfsError::NotAFile),
+            }
+            tx.write_data(ino as u32, &mut inode, offset + (i * WRITE_CHUNK) as u64, chunk)?;
+            tx.write_inode(ino as u32, &inode)?;
+            tx.commit()?;
+        }
+        Ok(data.len())
     }
     
-    fn unlink(&self, _parent: InodeNum, _name: &str) -> Result<(), VfsError> {
-        Err(VfsError::ReadOnlyFs)
+    fn unlink(&self, parent: InodeNum, name: &str) -> Result<(), VfsError> {
+        let mut tx = self.begin()?;
+        tx.unlink(parent as u32, name)?;
+        tx.commit()
     }
     
-    fn rename(&self, _old_parent: InodeNum, _old_name: &str, _new_parent: InodeNum, _new_name: &str) -> Result<(), VfsError> {
-        Err(VfsError::ReadOnlyFs)
+    fn rename(&self, old_parent: InodeNum, old_name: &str, new_parent: InodeNum, new_name: &str) -> Result<(), VfsError> {
+        let mut tx = self.begin()?;
+        tx.rename(old_parent as u32, old_name, new_parent as u32, new_name)?;
+        tx.commit()
     }
     
-    fn truncate(&self, _ino: InodeNum, _size: u64) -> Result<(), VfsError> {
-        Err(VfsError::ReadOnlyFs)
+    fn truncate(&self, ino: InodeNum, size: u64) -> Result<(), VfsError> {
+        let mut tx = self.begin()?;
+        let mut inode = tx.inode(ino as u32)?;
+        match inode.file_type() {
+            VfsFileType::Regular => {}
+            VfsFileType::Directory => return Err(VfsError::IsADirectory),
+            _ => return Err(VfsError::NotAFile),
+        }
+        tx.truncate(ino as u32, &mut inode, size)?;
+        tx.write_inode(ino as u32, &inode)?;
+        tx.commit()
     }
     
     fn sync(&self) -> Result<(), VfsError> {
-        // Nothing to sync for read-only filesystem
-        Ok(())
+        // Transactions are durable once committed
+        let _journal = self.journal.lock();
+        self.device.flush().map_err(|_| VfsError::IoError)
+    }
+    
+    fn symlink(&self, parent: InodeNum, name: &str, target: &str) -> Result<InodeNum, VfsError> {
+        let mut tx = self.begin()?;
+        let ino = tx.create(parent as u32, name, VfsFileType::Symlink, Some(target))?;
+        tx.commit()?;
+        Ok(ino as InodeNum)
+    }
+    
+    fn link(&self, ino: InodeNum, new_parent: InodeNum, new_name: &str) -> Result<(), VfsError> {
+        let mut tx = self.begin()?;
+        tx.link(ino as u32, new_parent as u32, new_name)?;
+        tx.commit()
+    }
+    
+    fn getxattr(&self, ino: InodeNum, name: &str) -> Result<Vec<u8>, VfsError> {
+        let (index, key) = XattrEntry::key(name)?;
+        self.read_xattrs(ino as u32)?
+            .into_iter()
+            .find(|entry| entry.index == index && entry.name == key)
+            .map(|entry| entry.value)
+            .ok_or(VfsError::NoAttribute)
+    }
+    
+    fn setxattr(&self, ino: InodeNum, name: &str, value: &[u8]) -> Result<(), VfsError> {
+        xattr::check_attr(name, value)?;
+        let mut tx = self.begin()?;
+        tx.setxattr(ino as u32, name, Some(value))?;
+        tx.commit()
+    }
+    
+    fn listxattr(&self, ino: InodeNum) -> Result<Vec<String>, VfsError> {
+        Ok(self.read_xattrs(ino as u32)?.iter().filter_map(XattrEntry::full_name).collect())
+    }
+    
+    fn removexattr(&self, ino: InodeNum, name: &str) -> Result<(), VfsError> {
+        let mut tx = self.begin()?;
+        tx.setxattr(ino as u32, name, None)?;
+        tx.commit()
     }
 }
 
@@ -1062,12 +3899,12 @@ impl crate::block::BlockDevice for BlockDeviceWrapper {
         Ok(())
     }
     
-    fn write_sectors(&self, _start: u64, _data: &[u8]) -> Result<(), crate::block::BlockError> {
-        Err(crate::block::BlockError::Unsupported)
+    fn write_sectors(&self, start: u64, data: &[u8]) -> Result<(), crate::block::BlockError> {
+        crate::block::write(&self.name, start, data)
     }
     
     fn flush(&self) -> Result<(), crate::block::BlockError> {
-        Ok(())
+        crate::block::flush(&self.name)
     }
     
     fn is_ready(&self) -> bool {
@@ -1076,9 +3913,10 @@ impl crate::block::BlockDevice for BlockDeviceWrapper {
 }
 
 /// Global ext4 mount registry.
-static EXT4_MOUNTS: spin::RwLock<BTreeMap<String, Ext4Fs>> = spin::RwLock::new(BTreeMap::new());
+static EXT4_MOUNTS: RwLock<BTreeMap<String, Arc<Ext4Fs>>> = RwLock::new(BTreeMap::new());
 
-/// Mount an ext4 filesystem.
+/// Mount an ext4 filesystem, read-write unless the device or the volume's
+/// features prevent it.
 pub fn mount(device_name: &str, mount_point: &str) -> Result<(), VfsError> {
     use alloc::string::ToString;
     
@@ -1091,26 +3929,37 @@ pub fn mount(device_name: &str, mount_point: &str) -> Result<(), VfsError> {
     let device = Arc::new(BlockDeviceWrapper::new(device_name));
     
     // Create ext4 filesystem
-    let fs = Ext4Fs::new(device);
+    let fs = Arc::new(Ext4Fs::new(device));
     
-    // Mount it (parse superblock, etc.)
+    // Mount it (parse superblock, replay the journal, etc.)
     fs.mount()?;
     
+    // Attach to the VFS
+    let read_only = fs.is_read_only();
+    if let Err(e) = VFS.mount(mount_point, fs.clone(), read_only) {
+        let _ = fs.unmount();
+        return Err(e);
+    }
+    
     // Store in registry
     EXT4_MOUNTS.write().insert(mount_point.to_string(), fs);
     
-    crate::serial_println!("[ext4] Mounted {} at {} (read-only)", device_name, mount_point);
+    crate::serial_println!(
+        "[ext4] Mounted {} at {} ({})",
+        device_name,
+        mount_point,
+        if read_only { "read-only" } else { "read-write" }
+    );
     Ok(())
 }
 
 /// Unmount an ext4 filesystem.
 pub fn unmount(mount_point: &str) -> Result<(), VfsError> {
-    if EXT4_MOUNTS.write().remove(mount_point).is_some() {
-        crate::serial_println!("[ext4] Unmounted {}", mount_point);
-        Ok(())
-    } else {
-        Err(VfsError::NotFound)
-    }
+    let fs = EXT4_MOUNTS.write().remove(mount_point).ok_or(VfsError::NotFound)?;
+    let _ = VFS.unmount(mount_point);
+    fs.unmount()?;
+    crate::serial_println!("[ext4] Unmounted {}", mount_point);
+    Ok(())
 }
 
 /// List directory on ext4 filesystem.
@@ -1118,19 +3967,13 @@ pub fn ls(path: &str) -> Result<Vec<(String, VfsFileType, u64)>, VfsError> {
     // Find mount point
     let mounts = EXT4_MOUNTS.read();
     for (mount_point, fs) in mounts.iter() {
-        if path.starts_with(mount_point.as_str()) {
-            let _rel_path = &path[mount_point.len()..];
-            
-            // List root directory
-            let entries = fs.readdir(EXT4_ROOT_INODE as u64)?;
+        if let Some(rel_path) = path.strip_prefix(mount_point.as_str()) {
+            let ino = fs.resolve_path(rel_path)?;
+            let entries = fs.readdir(ino as InodeNum)?;
             return Ok(entries.iter().map(|entry| {
-                let ft = match fs.getattr(entry.ino) {
-                    Ok(attrs) => attrs.file_type,
-                    Err(_) => VfsFileType::Regular,
-                };
-                let size = match fs.getattr(entry.ino) {
-                    Ok(attrs) => attrs.size,
-                    Err(_) => 0,
+                let (ft, size) = match fs.getattr(entry.ino) {
+                    Ok(attrs) => (attrs.file_type, attrs.size),
+                    Err(_) => (VfsFileType::Regular, 0),
                 };
                 (entry.name.clone(), ft, size)
             }).collect());
@@ -1143,16 +3986,212 @@ pub fn ls(path: &str) -> Result<Vec<(String, VfsFileType, u64)>, VfsError> {
 pub fn cat(path: &str) -> Result<Vec<u8>, VfsError> {
     let mounts = EXT4_MOUNTS.read();
     for (mount_point, fs) in mounts.iter() {
-        if path.starts_with(mount_point.as_str()) {
-            let rel_path = &path[mount_point.len()..];
-            
+        if let Some(rel_path) = path.strip_prefix(mount_point.as_str()) {
             // Lookup file
-            let ino = fs.lookup(EXT4_ROOT_INODE as u64, rel_path)?;
+            let ino = fs.resolve_path(rel_path)?;
             
             // Read entire file
-            return fs.read(ino, 0, 1024 * 1024); // Max 1MB
+            return fs.read(ino as InodeNum, 0, 1024 * 1024); // Max 1MB
         }
     }
     Err(VfsError::NotFound)
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::ramdisk::RamDisk;
+    use alloc::format;
+
+    #[test]
+    fn test_dx_hash() {
+        // Reference values from `debugfs -R "dx_hash -h <alg> -s <seed> <name>"`
+        let seed = [0x0403_0201, 0x0807_0605, 0x0c0b_0a09, 0x100f_0e0d];
+        assert_eq!(dx_hash(b"hello", DX_HASH_HALF_MD4, &seed), Ok(0xE2DD_F968));
+        assert_eq!(dx_hash(b"hello", DX_HASH_TEA, &seed), Ok(0x1A56_92B6));
+        assert_eq!(dx_hash(b"hello", DX_HASH_LEGACY, &seed), Ok(0x3225_2546));
+        let long = b"a_rather_long_file_name_that_spans_two_md4_chunks.txt";
+        assert_eq!(dx_hash(long, DX_HASH_HALF_MD4_UNSIGNED, &[0; 4]), Ok(0x773B_F5DC));
+        assert_eq!(dx_hash(long, DX_HASH_TEA, &[0; 4]), Ok(0x16AA_9564));
+        assert_eq!(dx_hash(b"x", 9, &seed), Err(VfsError::NotSupported));
+    }
+
+    #[test]
+    fn test_dir_records() {
+        let mut block = vec![0u8; 1024];
+        put_record(&mut block, 0, 2, 12, b".", EXT4_FT_DIR);
+        put_record(&mut block, 12, 2, 1012, b"..", EXT4_FT_DIR);
+
+        assert!(add_record(&mut block, 1024, b"file", 12, EXT4_FT_REG_FILE));
+        let names: Vec<&[u8]> = records(&block, 1024).iter().map(|r| r.name(&block)).collect();
+        assert_eq!(names, [&b"."[..], b"..", b"file"]);
+        assert_eq!(records(&block, 1024)[1].rec_len, 12);
+
+        assert_eq!(remove_record(&mut block, 1024, b"file"), Some(12));
+        assert_eq!(remove_record(&mut block, 1024, b"file"), None);
+        assert_eq!(records(&block, 1024).len(), 2);
+        assert_eq!(records(&block, 1024)[1].rec_len, 1012);
+
+        // A name that does not fit in the remaining space
+        let long = [b'a'; 255];
+        for i in 0..3 {
+            assert!(add_record(&mut block, 1024, &long[..250 + i], 20 + i as u32, EXT4_FT_REG_FILE));
+        }
+        assert!(!add_record(&mut block, 1024, &long, 30, EXT4_FT_REG_FILE));
+    }
+
+    #[test]
+    fn test_extent_encoding() {
+        let extent = Mapping { lblk: 7, len: 100, pblk: 0x1_2345_6789, unwritten: true };
+        let entry = extent.encode();
+        assert_eq!(le16(&entry, 4), 32868);
+        assert_eq!(Mapping::decode(&entry), extent);
+
+        let next = Mapping { lblk: 107, len: 5, pblk: 0x1_2345_67ED, unwritten: false };
+        assert!(!extent.can_merge(&next));
+        assert!(Mapping { unwritten: false, ..extent }.can_merge(&next));
+        assert_eq!(index_child(&index_entry(3, 0xAB_0000_0001)), 0xAB_0000_0001);
+    }
+
+    #[test]
+    fn test_xattr_encoding() {
+        assert_eq!(XattrEntry::key("security.selinux"), Ok((6, &b"selinux"[..])));
+        assert_eq!(XattrEntry::key("os2.x"), Err(VfsError::NotSupported));
+        let entries = [
+            XattrEntry { index: 1, name: b"mime_type".to_vec(), value: b"text/plain".to_vec() },
+            XattrEntry { index: 4, name: b"empty".to_vec(), value: Vec::new() },
+            XattrEntry { index: 2, name: Vec::new(), value: vec![2, 0, 0, 0] },
+        ];
+        assert_eq!(entries[0].full_name().as_deref(), Some("user.mime_type"));
+        assert_eq!(entries[2].full_name(), None);
+
+        // In-inode layout: magic, entries, values at the end
+        let mut area = vec![0u8; 96];
+        put_le32(&mut area, 0, XATTR_MAGIC);
+        assert!(encode_xattrs(&mut area, 4, 4, &entries).is_some());
+        let mut decoded = Vec::new();
+        decode_xattrs(&area, 4, 4, &mut decoded).unwrap();
+        assert_eq!(decoded, entries);
+        assert_eq!(le32(&area, 4 + 12), entries[0].hash());
+
+        // Entries and values may not overlap, terminator included
+        let used: usize = entries.iter().map(XattrEntry::footprint).sum();
+        assert!(encode_xattrs(&mut vec![0u8; 4 + used + 3], 4, 4, &entries).is_none());
+        assert!(encode_xattrs(&mut vec![0u8; 4 + used + 4], 4, 4, &entries).is_some());
+        area[4 + 8] = 200;
+        assert_eq!(decode_xattrs(&area, 4, 4, &mut Vec::new()), Err(VfsError::IoError));
+    }
+
+    /// A 3 MiB volume made by
+    ///
+    /// ```text
+    /// mkfs.ext4 -b 1024 -J size=1 -N 512 -d seed ext4-1k.img
+    /// e2fsck -fyD ext4-1k.img
+    /// ```
+    ///
+    /// zlib-compressed. `seed` holds `hello.txt` and `many/f0`..`many/f299`
+    /// (each "entry N\n"); `e2fsck -D` turns `many` into an htree directory.
+    /// Features are the `mkfs.ext4` defaults: journal, `metadata_csum`,
+    /// `64bit` and `flex_bg`.
+    const FIXTURE: &[u8] = include_bytes!("testdata/ext4-1k.img.zlib");
+    const FIXTURE_SIZE: usize = 3 * 1024 * 1024;
+
+    fn mount(disk: &Arc<RamDisk>) -> Ext4Fs {
+        let fs = Ext4Fs::new(disk.clone());
+        fs.mount().unwrap();
+        assert!(!fs.is_read_only());
+        fs
+    }
+
+    fn contents(i: usize) -> Vec<u8> {
+        (0..100 + i * 397).map(|j| ((i + j) % 251) as u8).collect()
+    }
+
+    #[test]
+    fn test_fixture_write_workload() {
+        let image = splax_compress::zlib::decompress(FIXTURE, FIXTURE_SIZE).unwrap();
+        let disk = Arc::new(RamDisk::new("ext4-test", image));
+        let fs = mount(&disk);
+        let root = fs.root_ino();
+        let before = fs.statfs().unwrap();
+        let hello = fs.lookup(root, "hello.txt").unwrap();
+        assert_eq!(fs.read(hello, 0, 64).unwrap(), b"hello from mkfs.ext4\n");
+
+        // Files of one to several blocks, half of them renamed or removed
+        let dir = fs.create(root, "dir", VfsFileType::Directory).unwrap();
+        for i in 0..40 {
+            let ino = fs.create(dir, &format!("file{}", i), VfsFileType::Regular).unwrap();
+            fs.write(ino, 0, &contents(i)).unwrap();
+        }
+        for i in (0..40).step_by(4) {
+            fs.unlink(dir, &format!("file{}", i)).unwrap();
+            fs.rename(dir, &format!("file{}", i + 1), root, &format!("moved{}", i + 1)).unwrap();
+        }
+
+        // A sparse file whose extents are split by a truncate
+        let sparse = fs.create(root, "sparse", VfsFileType::Regular).unwrap();
+        for i in 0..8 {
+            fs.write(sparse, i * 20_000, &[0xA5; 5000]).unwrap();
+        }
+        fs.truncate(sparse, 90_000).unwrap();
+        fs.setxattr(sparse, "user.big", &[7; 700]).unwrap();
+
+        // Inserts into and removals from the htree directory
+        let many = fs.lookup(root, "many").unwrap();
+        for i in 0..100 {
+            fs.create(many, &format!("added{}", i), VfsFileType::Regular).unwrap();
+        }
+        for i in (0..300).step_by(3) {
+            fs.unlink(many, &format!("f{}", i)).unwrap();
+        }
+        fs.unmount().unwrap();
+
+        // Everything is on disk after a remount
+        let fs = mount(&disk);
+        let dir = fs.lookup(root, "dir").unwrap();
+        for i in 0..40 {
+            let found = match i % 4 {
+                0 => fs.lookup(dir, &format!("file{}", i)),
+                1 => fs.lookup(root, &format!("moved{}", i)),
+                _ => fs.lookup(dir, &format!("file{}", i)),
+            };
+            if i % 4 == 0 {
+                assert_eq!(found, Err(VfsError::NotFound));
+            } else {
+                let ino = found.unwrap();
+                assert_eq!(fs.read(ino, 0, 20_000).unwrap(), contents(i));
+            }
+        }
+        let sparse = fs.lookup(root, "sparse").unwrap();
+        assert_eq!(fs.getattr(sparse).unwrap().size, 90_000);
+        let data = fs.read(sparse, 0, 90_000).unwrap();
+        assert_eq!(&data[80_000..85_000], &[0xA5; 5000][..]);
+        assert!(data[85_000..].iter().all(|&b| b == 0));
+        assert_eq!(fs.getxattr(sparse, "user.big").unwrap(), [7; 700]);
+        let many = fs.lookup(root, "many").unwrap();
+        for i in 0..300 {
+            let found = fs.lookup(many, &format!("f{}", i));
+            assert_eq!(found.is_ok(), i % 3 != 0, "f{}", i);
+        }
+        for i in 0..100 {
+            assert!(fs.lookup(many, &format!("added{}", i)).is_ok());
+        }
+
+        // Removing what was added gives every inode back
+        for i in 0..100 {
+            fs.unlink(many, &format!("added{}", i)).unwrap();
+        }
+        for i in 0..40 {
+            match i % 4 {
+                0 => {}
+                1 => fs.unlink(root, &format!("moved{}", i)).unwrap(),
+                _ => fs.unlink(dir, &format!("file{}", i)).unwrap(),
+            }
+        }
+        fs.unlink(root, "dir").unwrap();
+        fs.unlink(root, "sparse").unwrap();
+        let after = fs.statfs().unwrap();
+        assert_eq!(after.ffree, before.ffree + 100);
+        fs.unmount().unwrap();
+    }
+}
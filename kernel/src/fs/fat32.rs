@@ -17,6 +17,9 @@
 //! - SD cards
 //! - EFI System Partition
 //! - Cross-platform file exchange
+//!
+//! FAT has no extended attributes; the VFS xattr operations report
+//! `NotSupported`.
 
 use alloc::collections::BTreeMap;
 use alloc::string::String;
@@ -677,6 +680,10 @@ impl Filesystem for Fat32Fs {
     fn name(&self) -> &'static str {
         "fat32"
     }
+
+    fn root_ino(&self) -> InodeNum {
+        FAT32_ROOT_INO
+    }
     
     fn statfs(&self) -> Result<VfsStatFs, VfsError> {
         let bs = self.boot_sector.read();
@@ -693,6 +700,7 @@ impl Filesystem for Fat32Fs {
             ffree: 0,
             bsize: cluster_size,
             namelen: 255,
+            bsaved: 0,
         })
     }
     
@@ -0,0 +1,1198 @@
+//! # ISO 9660 Filesystem
+//!
+//! Read-only ISO 9660 (ECMA-119) implementation for Splax OS, for CD/DVD
+//! media and images such as the `target/splax.iso` the build produces.
+//! Images mount from ATAPI drives, any other block device, or a file
+//! through a loop device.
+//!
+//! ## Features
+//!
+//! - Primary volume descriptor, logical blocks of 512 to 2048 bytes
+//! - Joliet: UCS-2 names from a supplementary volume descriptor
+//! - Rock Ridge (RRIP over SUSP): POSIX names (NM), modes and link counts
+//!   (PX), symbolic links (SL), timestamps (TF), continuation areas (CE)
+//!   and relocated deep directories (CL/RE)
+//! - Multi-extent files, for files of 4 GiB and more
+//!
+//! ## Design
+//!
+//! Names come from Rock Ridge when the volume has it, else from the
+//! Joliet tree, else from the primary tree with the `;1` version removed
+//! and lower-cased (what Linux calls `map=normal`); plain names are looked
+//! up without case.
+//!
+//! The inode number of a file is the byte offset of its directory record,
+//! which is stable across mounts; the root's is that of the root record in
+//! the volume descriptor. Files are described by the record, resolved once
+//! when first looked up.
+//!
+//! ## Limitations
+//!
+//! - Read only
+//! - First session only; no UDF bridge
+//! - No zisofs (ZF) compressed files; interleaved files are read as if
+//!   contiguous
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::{Mutex, RwLock};
+
+use crate::block::{BlockDevice, SECTOR_SIZE};
+use crate::fs::vfs::{
+    Filesystem, InodeNum, VfsAttr, VfsDirEntry, VfsError, VfsFileType, VfsPermissions, VfsStatFs, VFS,
+};
+
+/// Volume descriptors start at this 2048-byte sector.
+const VD_START: u64 = 16;
+
+/// Volume descriptor size, whatever the logical block size.
+const VD_SIZE: usize = 2048;
+
+/// Volume descriptors read before giving up on a terminator.
+const VD_MAX: u64 = 32;
+
+/// Standard identifier of a volume descriptor.
+const VD_ID: &[u8; 5] = b"CD001";
+
+/// Volume descriptor types.
+const VD_PRIMARY: u8 = 1;
+const VD_SUPPLEMENTARY: u8 = 2;
+const VD_TERMINATOR: u8 = 255;
+
+/// Volume descriptor field offsets.
+const VD_VOLUME_ID: usize = 40;
+const VD_VOLUME_SPACE: usize = 80;
+const VD_ESCAPES: usize = 88;
+const VD_BLOCK_SIZE: usize = 128;
+const VD_ROOT_RECORD: usize = 156;
+
+/// Joliet escape sequences, for UCS-2 levels 1 to 3.
+const JOLIET_ESCAPES: [&[u8; 3]; 3] = [b"%/@", b"%/C", b"%/E"];
+
+/// Directory record field offsets.
+const DR_LENGTH: usize = 0;
+const DR_EXTENT: usize = 2;
+const DR_DATA_LENGTH: usize = 10;
+const DR_DATE: usize = 18;
+const DR_FLAGS: usize = 25;
+const DR_NAME_LENGTH: usize = 32;
+const DR_NAME: usize = 33;
+
+/// Directory record flags.
+const DR_DIRECTORY: u8 = 0x02;
+const DR_MULTI_EXTENT: u8 = 0x80;
+
+/// System Use Sharing Protocol entry signatures.
+const SUSP_CE: [u8; 2] = *b"CE";
+const SUSP_SP: [u8; 2] = *b"SP";
+const SUSP_ST: [u8; 2] = *b"ST";
+const SUSP_ER: [u8; 2] = *b"ER";
+
+/// Rock Ridge entry signatures.
+const RR_PX: [u8; 2] = *b"PX";
+const RR_NM: [u8; 2] = *b"NM";
+const RR_SL: [u8; 2] = *b"SL";
+const RR_TF: [u8; 2] = *b"TF";
+const RR_CL: [u8; 2] = *b"CL";
+const RR_RE: [u8; 2] = *b"RE";
+
+/// NM and SL flags.
+const RR_CONTINUE: u8 = 0x01;
+const RR_CURRENT: u8 = 0x02;
+const RR_PARENT: u8 = 0x04;
+const RR_ROOT: u8 = 0x08;
+
+/// TF flags: which stamps follow, and their form.
+const TF_CREATION: u8 = 0x01;
+const TF_MODIFY: u8 = 0x02;
+const TF_ACCESS: u8 = 0x04;
+const TF_ATTRIBUTES: u8 = 0x08;
+const TF_LONG_FORM: u8 = 0x80;
+
+/// Continuation areas followed for one record, so a loop cannot hang.
+const MAX_CONTINUATIONS: usize = 32;
+
+/// File type bits of a POSIX mode.
+const S_IFMT: u32 = 0o170000;
+
+/// Longest name.
+const ISO_NAME_LEN: u32 = 255;
+
+fn le16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+/// Reads the little-endian half of a both-endian (or plain) 32-bit field.
+fn le32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+/// Days from 1970-01-01 to a date of the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Unix time of a date and time, `offset` quarter hours ahead of UTC.
+fn unix_time(year: i64, month: u32, day: u32, hour: u32, minute: u32, second: u32, offset: i8) -> u64 {
+    let seconds = days_from_civil(year, month.clamp(1, 12), day.max(1)) * 86400
+        + (hour * 3600 + minute * 60 + second) as i64
+        - offset as i64 * 15 * 60;
+    seconds.max(0) as u64
+}
+
+/// Unix time of a 7-byte recording date.
+fn short_time(stamp: &[u8]) -> u64 {
+    unix_time(
+        1900 + stamp[0] as i64,
+        stamp[1] as u32,
+        stamp[2] as u32,
+        stamp[3] as u32,
+        stamp[4] as u32,
+        stamp[5] as u32,
+        stamp[6] as i8,
+    )
+}
+
+/// Unix time of a 17-byte volume descriptor date (`YYYYMMDDHHMMSScc`
+/// digits and an offset).
+fn long_time(stamp: &[u8]) -> u64 {
+    let number = |range: core::ops::Range<usize>| {
+        stamp[range].iter().fold(0u32, |n, &d| n * 10 + d.wrapping_sub(b'0').min(9) as u32)
+    };
+    if number(0..4) == 0 {
+        return 0;
+    }
+    unix_time(number(0..4) as i64, number(4..6), number(6..8), number(8..10), number(10..12), number(12..14), stamp[16] as i8)
+}
+
+/// Converts a primary-tree name the way Linux `map=normal` does: the
+/// version is dropped, as is a trailing dot, and letters are lower-cased.
+fn plain_name(raw: &[u8]) -> String {
+    let name = raw.split(|&b| b == b';').next().unwrap_or(raw);
+    let name = name.strip_suffix(b".").unwrap_or(name);
+    name.iter().map(|&b| b.to_ascii_lowercase() as char).collect()
+}
+
+/// Decodes a Joliet (UCS-2 big-endian) name, dropping the version.
+fn joliet_name(raw: &[u8]) -> String {
+    let units: Vec<u16> = raw.chunks_exact(2).map(|c| u16::from_be_bytes([c[0], c[1]])).collect();
+    let name = String::from_utf16_lossy(&units);
+    match name.rfind(';') {
+        Some(at) => String::from(&name[..at]),
+        None => name,
+    }
+}
+
+/// Where names come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Names {
+    /// Rock Ridge NM entries in the primary tree
+    RockRidge,
+    /// The Joliet tree
+    Joliet,
+    /// The primary tree
+    Plain,
+}
+
+/// A file or directory.
+#[derive(Debug, Clone)]
+struct Node {
+    file_type: VfsFileType,
+    /// POSIX mode, from Rock Ridge
+    mode: Option<u32>,
+    /// Link count, from Rock Ridge
+    nlink: u32,
+    /// Size in bytes
+    size: u64,
+    /// First logical block and length in bytes of each extent
+    extents: Vec<(u32, u32)>,
+    mtime: u64,
+    atime: u64,
+    ctime: u64,
+    crtime: u64,
+    /// Symbolic link target, from Rock Ridge
+    symlink: Option<String>,
+}
+
+/// A directory record and its Rock Ridge information.
+#[derive(Debug, Clone)]
+struct Record {
+    name: String,
+    node: Node,
+    /// More records of the same file follow
+    multi_extent: bool,
+    /// `.` or `..`
+    special: bool,
+    /// Relocated directory, listed through its placeholder instead (RE)
+    relocated: bool,
+    /// Block of a relocated directory this placeholder stands for (CL)
+    child_link: Option<u32>,
+}
+
+/// A mounted volume.
+struct Volume {
+    device: Arc<dyn BlockDevice + Send + Sync>,
+    /// Bytes per logical block
+    block_size: u32,
+    /// Logical blocks in the volume
+    volume_blocks: u32,
+    /// Where names come from
+    names: Names,
+    /// Bytes skipped at the start of each System Use area (SP)
+    susp_skip: usize,
+    /// Inode of the root directory
+    root_ino: InodeNum,
+    /// Files looked up so far
+    nodes: BTreeMap<InodeNum, Node>,
+}
+
+impl Volume {
+    /// Reads the volume descriptors and the root directory.
+    fn open(device: Arc<dyn BlockDevice + Send + Sync>) -> Result<Self, VfsError> {
+        let mut primary = None;
+        let mut joliet = None;
+        for index in 0..VD_MAX {
+            let mut vd = vec![0u8; VD_SIZE];
+            let offset = (VD_START + index) * VD_SIZE as u64;
+            read_bytes(&*device, offset, &mut vd)?;
+            if &vd[1..6] != VD_ID {
+                break;
+            }
+            match vd[0] {
+                VD_PRIMARY if primary.is_none() => primary = Some((offset, vd)),
+                VD_SUPPLEMENTARY if joliet.is_none() && JOLIET_ESCAPES.iter().any(|e| &vd[VD_ESCAPES..VD_ESCAPES + 3] == *e) => {
+                    joliet = Some((offset, vd))
+                }
+                VD_TERMINATOR => break,
+                _ => {}
+            }
+        }
+        let (offset, vd) = primary.ok_or(VfsError::InvalidArgument)?;
+
+        let block_size = le16(&vd, VD_BLOCK_SIZE) as u32;
+        if !block_size.is_power_of_two() || !(512..=2048).contains(&block_size) {
+            crate::serial_println!("[iso9660] Unsupported logical block size {}", block_size);
+            return Err(VfsError::NotSupported);
+        }
+        let label: String = String::from_utf8_lossy(&vd[VD_VOLUME_ID..VD_VOLUME_ID + 32]).trim_end().into();
+
+        let mut volume = Self {
+            device,
+            block_size,
+            volume_blocks: le32(&vd, VD_VOLUME_SPACE),
+            names: Names::Plain,
+            susp_skip: 0,
+            root_ino: offset + VD_ROOT_RECORD as u64,
+            nodes: BTreeMap::new(),
+        };
+
+        // Rock Ridge announces itself in the System Use area of the root
+        // directory's "." record
+        let root = volume.record_at(volume.root_ino)?;
+        let dot_ino = root.node.extents[0].0 as u64 * block_size as u64;
+        let dot = volume.raw_record_at(dot_ino)?;
+        let su = system_use(&dot);
+        if su.len() >= 7 && su[..2] == SUSP_SP && su[4..6] == [0xBE, 0xEF] {
+            volume.susp_skip = su[6] as usize;
+            let entries = volume.susp_entries(&dot)?;
+            if entries.iter().any(|(sig, _)| *sig == RR_PX || *sig == SUSP_ER) {
+                volume.names = Names::RockRidge;
+            }
+        }
+        if volume.names == Names::Plain {
+            if let Some((offset, _)) = joliet {
+                volume.names = Names::Joliet;
+                volume.root_ino = offset + VD_ROOT_RECORD as u64;
+            }
+        }
+
+        let root = volume.record_at(volume.root_ino)?;
+        if root.node.file_type != VfsFileType::Directory {
+            return Err(VfsError::InvalidArgument);
+        }
+        // The root's attributes are those of its "." record
+        let mut node = volume.record_at(root.node.extents[0].0 as u64 * block_size as u64)?.node;
+        node.extents = root.node.extents;
+        node.size = root.node.size;
+        node.file_type = VfsFileType::Directory;
+        volume.nodes.insert(volume.root_ino, node);
+
+        crate::serial_println!(
+            "[iso9660] Volume \"{}\": {} blocks of {} bytes, {} names",
+            label,
+            volume.volume_blocks,
+            block_size,
+            match volume.names {
+                Names::RockRidge => "Rock Ridge",
+                Names::Joliet => "Joliet",
+                Names::Plain => "ISO 9660",
+            }
+        );
+        Ok(volume)
+    }
+
+    fn read_bytes(&self, offset: u64, buffer: &mut [u8]) -> Result<(), VfsError> {
+        read_bytes(&*self.device, offset, buffer)
+    }
+
+    /// Reads the directory record at a byte offset.
+    fn raw_record_at(&self, offset: u64) -> Result<Vec<u8>, VfsError> {
+        let mut length = [0u8];
+        self.read_bytes(offset, &mut length)?;
+        if (length[0] as usize) < DR_NAME + 1 {
+            return Err(VfsError::IoError);
+        }
+        let mut data = vec![0u8; length[0] as usize];
+        self.read_bytes(offset, &mut data)?;
+        Ok(data)
+    }
+
+    /// Reads and parses the directory record at a byte offset.
+    fn record_at(&self, offset: u64) -> Result<Record, VfsError> {
+        self.parse_record(&self.raw_record_at(offset)?)
+    }
+
+    /// The System Use entries of a record, continuation areas included.
+    fn susp_entries(&self, data: &[u8]) -> Result<Vec<([u8; 2], Vec<u8>)>, VfsError> {
+        let mut entries = Vec::new();
+        let mut area = system_use(data).get(self.susp_skip..).unwrap_or(&[]).to_vec();
+        for _ in 0..MAX_CONTINUATIONS {
+            let mut continuation = None;
+            let mut at = 0;
+            while at + 4 <= area.len() {
+                let sig = [area[at], area[at + 1]];
+                let len = area[at + 2] as usize;
+                if len < 4 || at + len > area.len() || sig == SUSP_ST {
+                    break;
+                }
+                let entry = area[at..at + len].to_vec();
+                if sig == SUSP_CE && len >= 28 {
+                    continuation = Some((le32(&entry, 4), le32(&entry, 12), le32(&entry, 20)));
+                }
+                entries.push((sig, entry));
+                at += len;
+            }
+            let Some((block, offset, len)) = continuation else {
+                break;
+            };
+            if block >= self.volume_blocks || len as usize > VD_SIZE {
+                break;
+            }
+            area = vec![0u8; len as usize];
+            self.read_bytes(block as u64 * self.block_size as u64 + offset as u64, &mut area)?;
+        }
+        Ok(entries)
+    }
+
+    /// Parses a directory record.
+    fn parse_record(&self, data: &[u8]) -> Result<Record, VfsError> {
+        let name_len = data[DR_NAME_LENGTH] as usize;
+        if data.len() < DR_NAME + name_len {
+            return Err(VfsError::IoError);
+        }
+        let raw = &data[DR_NAME..DR_NAME + name_len];
+        let flags = data[DR_FLAGS];
+        let size = le32(data, DR_DATA_LENGTH);
+        let time = short_time(&data[DR_DATE..DR_DATE + 7]);
+        let special = name_len == 1 && raw[0] <= 1;
+
+        let mut record = Record {
+            name: match self.names {
+                _ if special => String::new(),
+                Names::Joliet => joliet_name(raw),
+                _ => plain_name(raw),
+            },
+            node: Node {
+                file_type: if flags & DR_DIRECTORY != 0 { VfsFileType::Directory } else { VfsFileType::Regular },
+                mode: None,
+                nlink: 1,
+                size: size as u64,
+                extents: vec![(le32(data, DR_EXTENT), size)],
+                mtime: time,
+                atime: time,
+                ctime: time,
+                crtime: time,
+                symlink: None,
+            },
+            multi_extent: flags & DR_MULTI_EXTENT != 0,
+            special,
+            relocated: false,
+            child_link: None,
+        };
+        if self.names == Names::RockRidge {
+            self.apply_rock_ridge(data, &mut record)?;
+        }
+        Ok(record)
+    }
+
+    /// Applies the Rock Ridge entries of a record.
+    fn apply_rock_ridge(&self, data: &[u8], record: &mut Record) -> Result<(), VfsError> {
+        let mut name: Option<String> = None;
+        let mut target: Option<String> = None;
+        let mut separate = false;
+        for (sig, entry) in self.susp_entries(data)? {
+            let body = &entry[4..];
+            match sig {
+                RR_PX if body.len() >= 32 => {
+                    let mode = le32(body, 0);
+                    record.node.mode = Some(mode);
+                    record.node.nlink = le32(body, 8);
+                    record.node.file_type = match mode & S_IFMT {
+                        0o040000 => VfsFileType::Directory,
+                        0o120000 => VfsFileType::Symlink,
+                        0o020000 => VfsFileType::CharDevice,
+                        0o060000 => VfsFileType::BlockDevice,
+                        0o010000 => VfsFileType::Fifo,
+                        0o140000 => VfsFileType::Socket,
+                        _ => record.node.file_type,
+                    };
+                }
+                RR_NM if !body.is_empty() => {
+                    if body[0] & (RR_CURRENT | RR_PARENT) == 0 {
+                        name.get_or_insert_with(String::new).push_str(&String::from_utf8_lossy(&body[1..]));
+                    }
+                }
+                RR_SL if !body.is_empty() => {
+                    let target = target.get_or_insert_with(String::new);
+                    let mut at = 1;
+                    while at + 2 <= body.len() {
+                        let (flags, len) = (body[at], body[at + 1] as usize);
+                        let content = body.get(at + 2..at + 2 + len).ok_or(VfsError::IoError)?;
+                        if flags & RR_ROOT != 0 {
+                            target.clear();
+                            target.push('/');
+                            separate = false;
+                        } else {
+                            if separate {
+                                target.push('/');
+                            }
+                            match flags & (RR_CURRENT | RR_PARENT) {
+                                RR_CURRENT => target.push('.'),
+                                RR_PARENT => target.push_str(".."),
+                                _ => target.push_str(&String::from_utf8_lossy(content)),
+                            }
+                            separate = flags & RR_CONTINUE == 0;
+                        }
+                        at += 2 + len;
+                    }
+                }
+                RR_TF if !body.is_empty() => {
+                    let size = if body[0] & TF_LONG_FORM != 0 { 17 } else { 7 };
+                    let mut at = 1;
+                    for bit in 0..7 {
+                        if body[0] & (1 << bit) == 0 {
+                            continue;
+                        }
+                        let Some(stamp) = body.get(at..at + size) else {
+                            break;
+                        };
+                        let time = if size == 17 { long_time(stamp) } else { short_time(stamp) };
+                        match 1 << bit {
+                            TF_CREATION => record.node.crtime = time,
+                            TF_MODIFY => record.node.mtime = time,
+                            TF_ACCESS => record.node.atime = time,
+                            TF_ATTRIBUTES => record.node.ctime = time,
+                            _ => {}
+                        }
+                        at += size;
+                    }
+                }
+                RR_CL if body.len() >= 4 => record.child_link = Some(le32(body, 0)),
+                RR_RE => record.relocated = true,
+                _ => {}
+            }
+        }
+        if let Some(name) = name {
+            record.name = name;
+        }
+        record.node.symlink = target;
+        Ok(())
+    }
+
+    /// Reads `buffer.len()` bytes of a file's data from `offset`.
+    fn read_data(&self, node: &Node, offset: u64, buffer: &mut [u8]) -> Result<(), VfsError> {
+        let mut start = 0u64;
+        let mut done = 0;
+        for &(block, len) in &node.extents {
+            let end = start + len as u64;
+            let pos = offset + done as u64;
+            if done < buffer.len() && pos < end {
+                let take = ((end - pos) as usize).min(buffer.len() - done);
+                let at = block as u64 * self.block_size as u64 + (pos - start);
+                self.read_bytes(at, &mut buffer[done..done + take])?;
+                done += take;
+            }
+            start = end;
+        }
+        if done < buffer.len() {
+            return Err(VfsError::IoError);
+        }
+        Ok(())
+    }
+
+    /// The records of a directory with their inode numbers, multi-extent
+    /// files merged and relocated directories resolved.
+    fn entries(&self, dir: &Node) -> Result<Vec<(InodeNum, Record)>, VfsError> {
+        let mut data = vec![0u8; dir.size as usize];
+        self.read_data(dir, 0, &mut data)?;
+
+        // The byte offset of each directory byte on the device
+        let mut locations = Vec::new();
+        for &(block, len) in &dir.extents {
+            locations.push((block as u64 * self.block_size as u64, len as usize));
+        }
+        let device_offset = |pos: usize| {
+            let mut start = 0;
+            for &(at, len) in &locations {
+                if pos < start + len {
+                    return at + (pos - start) as u64;
+                }
+                start += len;
+            }
+            0
+        };
+
+        let block_size = self.block_size as usize;
+        let mut entries: Vec<(InodeNum, Record)> = Vec::new();
+        let mut continued = false;
+        let mut pos = 0;
+        while pos < data.len() {
+            let len = data[pos + DR_LENGTH] as usize;
+            // Records do not cross blocks; a zero length pads to the next
+            if len == 0 {
+                pos = (pos / block_size + 1) * block_size;
+                continue;
+            }
+            if len < DR_NAME + 1 || pos + len > data.len() {
+                crate::serial_println!("[iso9660] Bad directory record at {}", device_offset(pos));
+                return Err(VfsError::IoError);
+            }
+            let record = self.parse_record(&data[pos..pos + len])?;
+            let ino = device_offset(pos);
+            pos += len;
+
+            if continued {
+                // Further extents of the previous record's file
+                let (_, first) = entries.last_mut().ok_or(VfsError::IoError)?;
+                first.node.extents.extend(record.node.extents);
+                first.node.size += record.node.size;
+                continued = record.multi_extent;
+                continue;
+            }
+            continued = record.multi_extent;
+            if record.special || record.relocated {
+                continue;
+            }
+            entries.push((ino, record));
+        }
+
+        for (_, record) in &mut entries {
+            if let Some(block) = record.child_link {
+                let dot = self.record_at(block as u64 * self.block_size as u64)?;
+                record.node = dot.node;
+                record.node.file_type = VfsFileType::Directory;
+            }
+        }
+        Ok(entries)
+    }
+
+    fn node(&self, ino: InodeNum) -> Result<&Node, VfsError> {
+        self.nodes.get(&ino).ok_or(VfsError::NotFound)
+    }
+
+    fn directory(&self, ino: InodeNum) -> Result<&Node, VfsError> {
+        let node = self.node(ino)?;
+        if node.file_type != VfsFileType::Directory {
+            return Err(VfsError::NotADirectory);
+        }
+        Ok(node)
+    }
+
+    fn lookup(&mut self, parent: InodeNum, name: &str) -> Result<InodeNum, VfsError> {
+        let entries = self.entries(self.directory(parent)?)?;
+        let plain = self.names == Names::Plain;
+        let (ino, record) = entries
+            .into_iter()
+            .find(|(_, r)| r.name == name || (plain && r.name.eq_ignore_ascii_case(name)))
+            .ok_or(VfsError::NotFound)?;
+        self.nodes.insert(ino, record.node);
+        Ok(ino)
+    }
+
+    fn readdir(&mut self, ino: InodeNum) -> Result<Vec<VfsDirEntry>, VfsError> {
+        let entries = self.entries(self.directory(ino)?)?;
+        Ok(entries
+            .into_iter()
+            .map(|(ino, record)| {
+                let file_type = record.node.file_type;
+                self.nodes.insert(ino, record.node);
+                VfsDirEntry { name: record.name, ino, file_type }
+            })
+            .collect())
+    }
+
+    fn getattr(&self, ino: InodeNum) -> Result<VfsAttr, VfsError> {
+        let node = self.node(ino)?;
+        let directory = node.file_type == VfsFileType::Directory;
+        let allocated: u64 = node.extents.iter().map(|&(_, len)| (len as u64).div_ceil(self.block_size as u64)).sum();
+        Ok(VfsAttr {
+            ino,
+            file_type: node.file_type,
+            perm: VfsPermissions {
+                readable: node.mode.is_none_or(|mode| mode & 0o400 != 0),
+                writable: false,
+                executable: node.mode.map_or(directory, |mode| mode & 0o100 != 0),
+            },
+            size: node.size,
+            nlink: node.nlink,
+            blksize: self.block_size,
+            blocks: allocated * self.block_size as u64 / 512,
+            atime: node.atime,
+            mtime: node.mtime,
+            ctime: node.ctime,
+            crtime: node.crtime,
+        })
+    }
+
+    fn read(&self, ino: InodeNum, offset: u64, size: usize) -> Result<Vec<u8>, VfsError> {
+        let node = self.node(ino)?;
+        if node.file_type == VfsFileType::Directory {
+            return Err(VfsError::IsADirectory);
+        }
+        if offset >= node.size {
+            return Ok(Vec::new());
+        }
+        let mut buffer = vec![0u8; (node.size - offset).min(size as u64) as usize];
+        self.read_data(node, offset, &mut buffer)?;
+        Ok(buffer)
+    }
+
+    fn readlink(&self, ino: InodeNum) -> Result<String, VfsError> {
+        self.node(ino)?.symlink.clone().ok_or(VfsError::InvalidArgument)
+    }
+
+    fn statfs(&self) -> VfsStatFs {
+        VfsStatFs {
+            blocks: self.volume_blocks as u64,
+            bfree: 0,
+            bavail: 0,
+            files: 0,
+            ffree: 0,
+            bsize: self.block_size,
+            namelen: ISO_NAME_LEN,
+            bsaved: 0,
+        }
+    }
+}
+
+/// The System Use area of a directory record.
+fn system_use(data: &[u8]) -> &[u8] {
+    let name_len = data[DR_NAME_LENGTH] as usize;
+    // A pad byte keeps the System Use area at an even offset
+    let start = DR_NAME + name_len + (name_len + 1) % 2;
+    data.get(start..).unwrap_or(&[])
+}
+
+/// Reads bytes at any offset of a device.
+fn read_bytes(device: &dyn BlockDevice, offset: u64, buffer: &mut [u8]) -> Result<(), VfsError> {
+    let sector = offset / SECTOR_SIZE as u64;
+    let sector_offset = (offset % SECTOR_SIZE as u64) as usize;
+    if sector_offset == 0 && buffer.len().is_multiple_of(SECTOR_SIZE) {
+        return device.read_sectors(sector, buffer).map_err(|_| VfsError::IoError);
+    }
+    let sectors_needed = (sector_offset + buffer.len()).div_ceil(SECTOR_SIZE);
+    let mut sector_buffer = vec![0u8; sectors_needed * SECTOR_SIZE];
+    device.read_sectors(sector, &mut sector_buffer).map_err(|_| VfsError::IoError)?;
+    buffer.copy_from_slice(&sector_buffer[sector_offset..sector_offset + buffer.len()]);
+    Ok(())
+}
+
+/// ISO 9660 filesystem.
+pub struct Iso9660Fs {
+    /// Block device
+    device: Arc<dyn BlockDevice + Send + Sync>,
+    /// Mounted volume
+    volume: Mutex<Option<Volume>>,
+}
+
+impl Iso9660Fs {
+    /// Creates a new ISO 9660 filesystem.
+    pub fn new(device: Arc<dyn BlockDevice + Send + Sync>) -> Self {
+        Self { device, volume: Mutex::new(None) }
+    }
+
+    /// Mounts the filesystem.
+    pub fn mount(&self) -> Result<(), VfsError> {
+        let volume = Volume::open(self.device.clone())?;
+        *self.volume.lock() = Some(volume);
+        Ok(())
+    }
+
+    /// Forgets the volume.
+    pub fn unmount(&self) {
+        *self.volume.lock() = None;
+    }
+
+    /// Runs `f` on the mounted volume.
+    fn with<R>(&self, f: impl FnOnce(&mut Volume) -> Result<R, VfsError>) -> Result<R, VfsError> {
+        let mut volume = self.volume.lock();
+        f(volume.as_mut().ok_or(VfsError::NoFilesystem)?)
+    }
+
+    /// Resolves a path relative to the root directory.
+    fn resolve_path(&self, path: &str) -> Result<InodeNum, VfsError> {
+        let mut ino = self.root_ino();
+        for component in path.split('/').filter(|s| !s.is_empty()) {
+            ino = self.lookup(ino, component)?;
+        }
+        Ok(ino)
+    }
+}
+
+impl Filesystem for Iso9660Fs {
+    fn name(&self) -> &'static str {
+        "iso9660"
+    }
+
+    fn root_ino(&self) -> InodeNum {
+        self.volume.lock().as_ref().map_or(0, |volume| volume.root_ino)
+    }
+
+    fn statfs(&self) -> Result<VfsStatFs, VfsError> {
+        self.with(|volume| Ok(volume.statfs()))
+    }
+
+    fn lookup(&self, parent: InodeNum, name: &str) -> Result<InodeNum, VfsError> {
+        self.with(|volume| volume.lookup(parent, name))
+    }
+
+    fn getattr(&self, ino: InodeNum) -> Result<VfsAttr, VfsError> {
+        self.with(|volume| volume.getattr(ino))
+    }
+
+    fn readdir(&self, ino: InodeNum) -> Result<Vec<VfsDirEntry>, VfsError> {
+        self.with(|volume| volume.readdir(ino))
+    }
+
+    fn read(&self, ino: InodeNum, offset: u64, size: usize) -> Result<Vec<u8>, VfsError> {
+        self.with(|volume| volume.read(ino, offset, size))
+    }
+
+    fn readlink(&self, ino: InodeNum) -> Result<String, VfsError> {
+        self.with(|volume| volume.readlink(ino))
+    }
+
+    fn write(&self, _ino: InodeNum, _offset: u64, _data: &[u8]) -> Result<usize, VfsError> {
+        Err(VfsError::ReadOnlyFs)
+    }
+
+    fn create(&self, _parent: InodeNum, _name: &str, _file_type: VfsFileType) -> Result<InodeNum, VfsError> {
+        Err(VfsError::ReadOnlyFs)
+    }
+
+    fn unlink(&self, _parent: InodeNum, _name: &str) -> Result<(), VfsError> {
+        Err(VfsError::ReadOnlyFs)
+    }
+
+    fn rename(&self, _old_parent: InodeNum, _old_name: &str, _new_parent: InodeNum, _new_name: &str) -> Result<(), VfsError> {
+        Err(VfsError::ReadOnlyFs)
+    }
+
+    fn truncate(&self, _ino: InodeNum, _size: u64) -> Result<(), VfsError> {
+        Err(VfsError::ReadOnlyFs)
+    }
+
+    fn sync(&self) -> Result<(), VfsError> {
+        Ok(())
+    }
+}
+
+// ============================================================================
+// Module-level mount API for shell commands
+// ============================================================================
+
+/// Block device wrapper that uses block module's read/write functions.
+struct BlockDeviceWrapper {
+    name: String,
+}
+
+impl BlockDeviceWrapper {
+    fn new(name: &str) -> Self {
+        Self { name: name.into() }
+    }
+}
+
+impl crate::block::BlockDevice for BlockDeviceWrapper {
+    fn info(&self) -> crate::block::BlockDeviceInfo {
+        crate::block::list_devices()
+            .into_iter()
+            .find(|d| d.name == self.name)
+            .unwrap_or(crate::block::BlockDeviceInfo {
+                name: self.name.clone(),
+                sector_size: 512,
+                total_sectors: 0,
+                read_only: true,
+                model: String::new(),
+            })
+    }
+
+    fn read_sectors(&self, start: u64, buf: &mut [u8]) -> Result<(), crate::block::BlockError> {
+        let sector_size = self.info().sector_size;
+        let count = buf.len() / sector_size;
+        let data = crate::block::read(&self.name, start, count)?;
+        buf[..data.len()].copy_from_slice(&data);
+        Ok(())
+    }
+
+    fn write_sectors(&self, _start: u64, _data: &[u8]) -> Result<(), crate::block::BlockError> {
+        Err(crate::block::BlockError::WriteProtected)
+    }
+
+    fn flush(&self) -> Result<(), crate::block::BlockError> {
+        Ok(())
+    }
+
+    fn is_ready(&self) -> bool {
+        true
+    }
+}
+
+/// Global ISO 9660 mount registry.
+static ISO9660_MOUNTS: RwLock<BTreeMap<String, Arc<Iso9660Fs>>> = RwLock::new(BTreeMap::new());
+
+/// Mount an ISO 9660 filesystem (read-only).
+pub fn mount(device_name: &str, mount_point: &str) -> Result<(), VfsError> {
+    use alloc::string::ToString;
+
+    if !crate::block::list_devices().iter().any(|d| d.name == device_name) {
+        return Err(VfsError::NotFound);
+    }
+
+    let device = Arc::new(BlockDeviceWrapper::new(device_name));
+    let fs = Arc::new(Iso9660Fs::new(device));
+    fs.mount()?;
+
+    VFS.mount(mount_point, fs.clone(), true)?;
+    ISO9660_MOUNTS.write().insert(mount_point.to_string(), fs);
+
+    crate::serial_println!("[iso9660] Mounted {} at {} (read-only)", device_name, mount_point);
+    Ok(())
+}
+
+/// Unmount an ISO 9660 filesystem.
+pub fn unmount(mount_point: &str) -> Result<(), VfsError> {
+    let fs = ISO9660_MOUNTS.write().remove(mount_point).ok_or(VfsError::NotFound)?;
+    let _ = VFS.unmount(mount_point);
+    fs.unmount();
+    crate::serial_println!("[iso9660] Unmounted {}", mount_point);
+    Ok(())
+}
+
+/// List directory on ISO 9660 filesystem.
+pub fn ls(path: &str) -> Result<Vec<(String, VfsFileType, u64)>, VfsError> {
+    let mounts = ISO9660_MOUNTS.read();
+    for (mount_point, fs) in mounts.iter() {
+        if let Some(rel_path) = path.strip_prefix(mount_point.as_str()) {
+            let ino = fs.resolve_path(rel_path)?;
+            let entries = fs.readdir(ino)?;
+            return Ok(entries
+                .iter()
+                .map(|entry| {
+                    let (ft, size) = match fs.getattr(entry.ino) {
+                        Ok(attrs) => (attrs.file_type, attrs.size),
+                        Err(_) => (VfsFileType::Regular, 0),
+                    };
+                    (entry.name.clone(), ft, size)
+                })
+                .collect());
+        }
+    }
+    Err(VfsError::NotFound)
+}
+
+/// Read file from ISO 9660 filesystem.
+pub fn cat(path: &str) -> Result<Vec<u8>, VfsError> {
+    let mounts = ISO9660_MOUNTS.read();
+    for (mount_point, fs) in mounts.iter() {
+        if let Some(rel_path) = path.strip_prefix(mount_point.as_str()) {
+            let ino = fs.resolve_path(rel_path)?;
+            return fs.read(ino, 0, 1024 * 1024); // Max 1MB
+        }
+    }
+    Err(VfsError::NotFound)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::ramdisk::RamDisk;
+
+    const BLOCK: usize = 2048;
+    const BLOCKS: u32 = 34;
+    /// Recording date of every record: 2024-02-29 12:34:56 UTC
+    const DATE: [u8; 7] = [124, 2, 29, 12, 34, 56, 0];
+    const DATE_UNIX: u64 = 1709210096;
+
+    /// Block layout of the test image
+    const ROOT: u32 = 19;
+    const DOCS: u32 = 20;
+    const JOLIET_ROOT: u32 = 21;
+    const JOLIET_DOCS: u32 = 22;
+    const CONTINUATION: u32 = 23;
+    const RR_MOVED: u32 = 24;
+    const DEEP: u32 = 25;
+    const README: u32 = 26;
+    const NOTES: u32 = 27;
+    const INSIDE: u32 = 28;
+    const BIG_FIRST: u32 = 29;
+    const BIG_SECOND: u32 = 31;
+
+    fn both16(value: u16) -> Vec<u8> {
+        [value.to_le_bytes(), value.to_be_bytes()].concat()
+    }
+
+    fn both32(value: u32) -> Vec<u8> {
+        [value.to_le_bytes(), value.to_be_bytes()].concat()
+    }
+
+    fn record(name: &[u8], extent: u32, size: u32, flags: u8, su: &[u8]) -> Vec<u8> {
+        let mut data = vec![0u8; DR_NAME];
+        data[DR_EXTENT..DR_EXTENT + 8].copy_from_slice(&both32(extent));
+        data[DR_DATA_LENGTH..DR_DATA_LENGTH + 8].copy_from_slice(&both32(size));
+        data[DR_DATE..DR_DATE + 7].copy_from_slice(&DATE);
+        data[DR_FLAGS] = flags;
+        data[28..32].copy_from_slice(&both16(1));
+        data[DR_NAME_LENGTH] = name.len() as u8;
+        data.extend_from_slice(name);
+        if name.len() % 2 == 0 {
+            data.push(0);
+        }
+        data.extend_from_slice(su);
+        if data.len() % 2 == 1 {
+            data.push(0);
+        }
+        data[DR_LENGTH] = data.len() as u8;
+        data
+    }
+
+    fn entry(sig: &[u8; 2], body: &[u8]) -> Vec<u8> {
+        [&[sig[0], sig[1], 4 + body.len() as u8, 1], body].concat()
+    }
+
+    fn px(mode: u32, nlink: u32) -> Vec<u8> {
+        entry(b"PX", &[both32(mode), both32(nlink), both32(0), both32(0), both32(0)].concat())
+    }
+
+    fn nm(name: &str) -> Vec<u8> {
+        entry(b"NM", &[&[0], name.as_bytes()].concat())
+    }
+
+    fn ucs2(name: &str) -> Vec<u8> {
+        name.encode_utf16().flat_map(u16::to_be_bytes).collect()
+    }
+
+    /// Builds an image with a Rock Ridge primary tree, a Joliet tree, or
+    /// neither.
+    fn image(rock_ridge: bool, joliet: bool) -> Vec<u8> {
+        let mut image = vec![0u8; BLOCKS as usize * BLOCK];
+        let mut put = |block: u32, at: usize, bytes: &[u8]| {
+            let start = block as usize * BLOCK + at;
+            image[start..start + bytes.len()].copy_from_slice(bytes);
+        };
+        let rr = |entries: &[Vec<u8>]| if rock_ridge { entries.concat() } else { Vec::new() };
+        let directory = |records: Vec<Vec<u8>>| records.concat();
+
+        let dir = 0o40755;
+        let file = 0o100644;
+        let mut pvd = vec![0u8; BLOCK];
+        pvd[0] = VD_PRIMARY;
+        pvd[1..7].copy_from_slice(b"CD001\x01");
+        pvd[VD_VOLUME_ID..VD_VOLUME_ID + 32].copy_from_slice(&[b"SPLAX".as_slice(), &[b' '; 27]].concat());
+        pvd[VD_VOLUME_SPACE..VD_VOLUME_SPACE + 8].copy_from_slice(&both32(BLOCKS));
+        pvd[VD_BLOCK_SIZE..VD_BLOCK_SIZE + 4].copy_from_slice(&both16(BLOCK as u16));
+        pvd[VD_ROOT_RECORD..VD_ROOT_RECORD + 34].copy_from_slice(&record(&[0], ROOT, BLOCK as u32, DR_DIRECTORY, &[]));
+        put(16, 0, &pvd);
+        if joliet {
+            let mut svd = pvd.clone();
+            svd[0] = VD_SUPPLEMENTARY;
+            svd[VD_ESCAPES..VD_ESCAPES + 3].copy_from_slice(b"%/E");
+            svd[VD_ROOT_RECORD..VD_ROOT_RECORD + 34]
+                .copy_from_slice(&record(&[0], JOLIET_ROOT, BLOCK as u32, DR_DIRECTORY, &[]));
+            put(17, 0, &svd);
+        }
+        put(18, 0, b"\xffCD001\x01");
+
+        let sp = [b'S', b'P', 7, 1, 0xBE, 0xEF, 0];
+        let readme_name = nm("README with a long name.txt");
+        let modified = entry(b"TF", &[&[TF_MODIFY][..], &[124, 3, 1, 0, 0, 0, 4]].concat());
+        let link = entry(b"SL", &[&[0u8, 0, 4][..], b"docs", &[0, 9], b"notes.txt"].concat());
+        let deep = if rock_ridge {
+            record(b"DEEP", 0, 0, 0, &rr(&[px(dir, 2), nm("deep"), entry(b"CL", &both32(DEEP))]))
+        } else {
+            record(b"DEEP", DEEP, BLOCK as u32, DR_DIRECTORY, &[])
+        };
+        put(ROOT, 0, &directory(vec![
+            record(&[0], ROOT, BLOCK as u32, DR_DIRECTORY, &rr(&[sp.to_vec(), px(dir, 5)])),
+            record(&[1], ROOT, BLOCK as u32, DR_DIRECTORY, &rr(&[px(dir, 5)])),
+            record(b"BIG.BIN;1", BIG_FIRST, BLOCK as u32, DR_MULTI_EXTENT, &rr(&[px(file, 1), nm("big.bin")])),
+            record(b"BIG.BIN;1", BIG_SECOND, 100, 0, &rr(&[px(file, 1), nm("big.bin")])),
+            deep,
+            record(b"DOCS", DOCS, BLOCK as u32, DR_DIRECTORY, &rr(&[px(dir, 2), nm("docs")])),
+            record(b"LINK.;1", 0, 0, 0, &rr(&[px(0o120777, 1), nm("link"), link])),
+            record(b"README.TXT;1", README, 9, 0, &rr(&[
+                px(0o100755, 1),
+                modified,
+                entry(b"CE", &[both32(CONTINUATION), both32(0), both32(readme_name.len() as u32)].concat()),
+            ])),
+            record(b"RR_MOVED", RR_MOVED, BLOCK as u32, DR_DIRECTORY, &rr(&[px(dir, 3), nm("rr_moved")])),
+        ]));
+        put(CONTINUATION, 0, &readme_name);
+        put(DOCS, 0, &directory(vec![
+            record(&[0], DOCS, BLOCK as u32, DR_DIRECTORY, &rr(&[px(dir, 2)])),
+            record(&[1], ROOT, BLOCK as u32, DR_DIRECTORY, &rr(&[px(dir, 5)])),
+            record(b"NOTES.TXT;1", NOTES, 6, 0, &rr(&[px(0o100600, 1), nm("notes.txt")])),
+        ]));
+        put(RR_MOVED, 0, &directory(vec![
+            record(&[0], RR_MOVED, BLOCK as u32, DR_DIRECTORY, &rr(&[px(dir, 3)])),
+            record(&[1], ROOT, BLOCK as u32, DR_DIRECTORY, &rr(&[px(dir, 5)])),
+            record(b"DEEP", DEEP, BLOCK as u32, DR_DIRECTORY, &rr(&[px(dir, 2), nm("deep"), entry(b"RE", &[])])),
+        ]));
+        put(DEEP, 0, &directory(vec![
+            record(&[0], DEEP, BLOCK as u32, DR_DIRECTORY, &rr(&[px(0o40700, 2)])),
+            record(&[1], RR_MOVED, BLOCK as u32, DR_DIRECTORY, &rr(&[px(dir, 3), entry(b"PL", &both32(ROOT))])),
+            record(b"INSIDE.TXT;1", INSIDE, 6, 0, &rr(&[px(file, 1), nm("inside.txt")])),
+        ]));
+        if joliet {
+            put(JOLIET_ROOT, 0, &directory(vec![
+                record(&[0], JOLIET_ROOT, BLOCK as u32, DR_DIRECTORY, &[]),
+                record(&[1], JOLIET_ROOT, BLOCK as u32, DR_DIRECTORY, &[]),
+                record(&ucs2("docs"), JOLIET_DOCS, BLOCK as u32, DR_DIRECTORY, &[]),
+                record(&ucs2("Ünïcødé readme.txt;1"), README, 9, 0, &[]),
+            ]));
+            put(JOLIET_DOCS, 0, &directory(vec![
+                record(&[0], JOLIET_DOCS, BLOCK as u32, DR_DIRECTORY, &[]),
+                record(&[1], JOLIET_ROOT, BLOCK as u32, DR_DIRECTORY, &[]),
+                record(&ucs2("notes.txt;1"), NOTES, 6, 0, &[]),
+            ]));
+        }
+
+        put(README, 0, b"hello iso");
+        put(NOTES, 0, b"notes\n");
+        put(INSIDE, 0, b"inside");
+        let pattern: Vec<u8> = (0..BLOCK + 100).map(|i| (i % 251) as u8).collect();
+        put(BIG_FIRST, 0, &pattern[..BLOCK]);
+        put(BIG_SECOND, 0, &pattern[BLOCK..]);
+        image
+    }
+
+    fn mount(rock_ridge: bool, joliet: bool) -> Iso9660Fs {
+        let fs = Iso9660Fs::new(Arc::new(RamDisk::read_only("sr0", image(rock_ridge, joliet))));
+        fs.mount().expect("mount");
+        fs
+    }
+
+    fn names(fs: &Iso9660Fs, path: &str) -> Vec<String> {
+        fs.readdir(fs.resolve_path(path).unwrap()).unwrap().into_iter().map(|e| e.name).collect()
+    }
+
+    fn cat(fs: &Iso9660Fs, path: &str) -> Vec<u8> {
+        fs.read(fs.resolve_path(path).unwrap(), 0, 1 << 20).unwrap()
+    }
+
+    #[test]
+    fn test_names() {
+        assert_eq!(plain_name(b"README.TXT;1"), "readme.txt");
+        assert_eq!(plain_name(b"LINK.;1"), "link");
+        assert_eq!(plain_name(b"DOCS"), "docs");
+        assert_eq!(joliet_name(&ucs2("Ünïcødé.txt;1")), "Ünïcødé.txt");
+    }
+
+    #[test]
+    fn test_timestamps() {
+        assert_eq!(short_time(&DATE), DATE_UNIX);
+        // Midnight at UTC+1, in quarter hours
+        assert_eq!(short_time(&[124, 3, 1, 0, 0, 0, 4]), 1709247600);
+        assert_eq!(long_time(b"20240229123456\x30\x30\x00"), DATE_UNIX);
+        assert_eq!(long_time(b"0000000000000000\x00"), 0);
+    }
+
+    #[test]
+    fn test_rock_ridge() {
+        let fs = mount(true, true);
+        assert_eq!(
+            names(&fs, "/"),
+            ["big.bin", "deep", "docs", "link", "README with a long name.txt", "rr_moved"]
+        );
+        assert_eq!(cat(&fs, "/README with a long name.txt"), b"hello iso");
+        assert_eq!(cat(&fs, "/docs/notes.txt"), b"notes\n");
+
+        let readme = fs.getattr(fs.resolve_path("/README with a long name.txt").unwrap()).unwrap();
+        assert!(readme.perm.executable && !readme.perm.writable);
+        assert_eq!((readme.mtime, readme.atime), (1709247600, DATE_UNIX));
+        let notes = fs.getattr(fs.resolve_path("/docs/notes.txt").unwrap()).unwrap();
+        assert!(notes.perm.readable && !notes.perm.executable);
+
+        let link = fs.resolve_path("/link").unwrap();
+        assert_eq!(fs.getattr(link).unwrap().file_type, VfsFileType::Symlink);
+        assert_eq!(fs.readlink(link).unwrap(), "docs/notes.txt");
+        assert_eq!(fs.readlink(fs.resolve_path("/docs").unwrap()), Err(VfsError::InvalidArgument));
+
+        // The relocated directory shows at its placeholder, not under rr_moved
+        assert!(names(&fs, "/rr_moved").is_empty());
+        let deep = fs.getattr(fs.resolve_path("/deep").unwrap()).unwrap();
+        assert_eq!((deep.file_type, deep.nlink), (VfsFileType::Directory, 2));
+        assert_eq!(cat(&fs, "/deep/inside.txt"), b"inside");
+        assert_eq!(fs.resolve_path("/DOCS"), Err(VfsError::NotFound));
+    }
+
+    #[test]
+    fn test_joliet() {
+        let fs = mount(false, true);
+        assert_eq!(names(&fs, "/"), ["docs", "Ünïcødé readme.txt"]);
+        assert_eq!(cat(&fs, "/Ünïcødé readme.txt"), b"hello iso");
+        assert_eq!(cat(&fs, "/docs/notes.txt"), b"notes\n");
+    }
+
+    #[test]
+    fn test_plain() {
+        let fs = mount(false, false);
+        assert_eq!(names(&fs, "/"), ["big.bin", "deep", "docs", "link", "readme.txt", "rr_moved"]);
+        assert_eq!(cat(&fs, "/README.TXT"), b"hello iso");
+        assert_eq!(cat(&fs, "/deep/inside.txt"), b"inside");
+        let readme = fs.getattr(fs.resolve_path("/readme.txt").unwrap()).unwrap();
+        assert_eq!((readme.mtime, readme.size), (DATE_UNIX, 9));
+        assert!(!readme.perm.executable);
+        assert_eq!(fs.readlink(fs.resolve_path("/link").unwrap()), Err(VfsError::InvalidArgument));
+    }
+
+    #[test]
+    fn test_multi_extent() {
+        let fs = mount(true, false);
+        let big = fs.resolve_path("/big.bin").unwrap();
+        assert_eq!(fs.getattr(big).unwrap().size, BLOCK as u64 + 100);
+        let pattern: Vec<u8> = (0..BLOCK + 100).map(|i| (i % 251) as u8).collect();
+        assert_eq!(fs.read(big, 0, 1 << 20).unwrap(), pattern);
+        assert_eq!(fs.read(big, BLOCK as u64 - 8, 16).unwrap(), &pattern[BLOCK - 8..BLOCK + 8]);
+        assert!(fs.read(big, BLOCK as u64 + 100, 16).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_read_only() {
+        let fs = mount(true, true);
+        let root = fs.root_ino();
+        assert_eq!(fs.create(root, "new", VfsFileType::Regular), Err(VfsError::ReadOnlyFs));
+        assert_eq!(fs.write(fs.resolve_path("/docs/notes.txt").unwrap(), 0, b"x"), Err(VfsError::ReadOnlyFs));
+        assert_eq!(fs.unlink(root, "link"), Err(VfsError::ReadOnlyFs));
+        let statfs = fs.statfs().unwrap();
+        assert_eq!((statfs.blocks, statfs.bsize, statfs.bfree), (BLOCKS as u64, BLOCK as u32, 0));
+    }
+}
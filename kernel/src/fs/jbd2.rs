@@ -0,0 +1,777 @@
+//! # jbd2 Journal
+//!
+//! The journal format ext3 and ext4 share with Linux ("journalling block
+//! device 2"). ext4 keeps it in an inode; this module only sees it as the
+//! list of device blocks backing that inode.
+//!
+//! ## Layout
+//!
+//! ```text
+//!  0            s_first
+//! ┌────────────┬────────────┬──────┬─────┬──────┬────────┬────────────┬───
+//! │ superblock │ descriptor │ data │ ... │ data │ commit │ descriptor │ ...
+//! └────────────┴────────────┴──────┴─────┴──────┴────────┴────────────┴───
+//!               ◄──────────── transaction s_sequence ────►
+//! ```
+//!
+//! Every field is big-endian. A descriptor lists the final location of each
+//! block that follows it; a revoke block cancels earlier copies of a block.
+//! A transaction counts only once its commit block is on disk.
+//!
+//! ## Design
+//!
+//! - [`Journal::recover`] is the usual three-pass replay: find the last
+//!   committed transaction, collect revoke records, then write back every
+//!   logged block that was not revoked by the same or a later transaction.
+//! - [`Journal::commit`] logs a transaction at the start of the log,
+//!   checkpoints it in place right away and empties the log again. The log
+//!   never holds more than one transaction, so no revoke records are written.
+//! - v2/v3 checksums (CRC-32C) are verified on replay and written whenever
+//!   the journal has them enabled.
+
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::vfs::VfsError;
+use crate::block::{BlockDevice, SECTOR_SIZE};
+use crate::crypto::hash::crc32c;
+
+/// Magic number of every journal block header.
+pub const JBD2_MAGIC: u32 = 0xC03B_3998;
+
+/// Block types.
+const BLOCKTYPE_DESCRIPTOR: u32 = 1;
+const BLOCKTYPE_COMMIT: u32 = 2;
+const BLOCKTYPE_SUPERBLOCK_V1: u32 = 3;
+const BLOCKTYPE_SUPERBLOCK_V2: u32 = 4;
+const BLOCKTYPE_REVOKE: u32 = 5;
+
+/// Journal feature flags.
+pub mod feature {
+    pub const COMPAT_CHECKSUM: u32 = 0x0001;
+
+    pub const INCOMPAT_REVOKE: u32 = 0x0001;
+    pub const INCOMPAT_64BIT: u32 = 0x0002;
+    pub const INCOMPAT_ASYNC_COMMIT: u32 = 0x0004;
+    pub const INCOMPAT_CSUM_V2: u32 = 0x0008;
+    pub const INCOMPAT_CSUM_V3: u32 = 0x0010;
+}
+
+/// Incompatible features this implementation understands.
+const SUPPORTED_INCOMPAT: u32 = feature::INCOMPAT_REVOKE
+    | feature::INCOMPAT_64BIT
+    | feature::INCOMPAT_ASYNC_COMMIT
+    | feature::INCOMPAT_CSUM_V2
+    | feature::INCOMPAT_CSUM_V3;
+
+/// Descriptor tag flags.
+const TAG_ESCAPE: u32 = 0x1;
+const TAG_SAME_UUID: u32 = 0x2;
+const TAG_LAST: u32 = 0x8;
+
+/// `s_checksum_type` value for CRC-32C.
+const CHECKSUM_CRC32C: u8 = 4;
+
+/// Superblock field offsets.
+const SB_BLOCKSIZE: usize = 0x0C;
+const SB_MAXLEN: usize = 0x10;
+const SB_FIRST: usize = 0x14;
+const SB_SEQUENCE: usize = 0x18;
+const SB_START: usize = 0x1C;
+const SB_FEATURE_COMPAT: usize = 0x24;
+const SB_FEATURE_INCOMPAT: usize = 0x28;
+const SB_UUID: usize = 0x30;
+const SB_CHECKSUM_TYPE: usize = 0x50;
+const SB_CHECKSUM: usize = 0xFC;
+/// Bytes covered by the superblock checksum.
+const SB_SIZE: usize = 1024;
+
+/// Size of a block header (magic, type, sequence).
+const HEADER_SIZE: usize = 12;
+/// Size of the checksum tail of descriptor and revoke blocks.
+const TAIL_SIZE: usize = 4;
+/// Offset of the first checksum word in a commit block.
+const COMMIT_CHECKSUM: usize = 16;
+/// Offset of the commit time in a commit block.
+const COMMIT_SEC: usize = 48;
+
+/// Commit time (system tick counter)
+fn now() -> u64 {
+    #[cfg(target_arch = "x86_64")]
+    {
+        crate::arch::x86_64::interrupts::get_ticks()
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        0
+    }
+}
+
+fn be32(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+fn be16(data: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([data[offset], data[offset + 1]])
+}
+
+fn put_be32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+}
+
+fn put_header(data: &mut [u8], kind: u32, sequence: u32) {
+    put_be32(data, 0, JBD2_MAGIC);
+    put_be32(data, 4, kind);
+    put_be32(data, 8, sequence);
+}
+
+/// A run of journal blocks that is contiguous on the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JournalRun {
+    /// First journal block of the run
+    pub logical: u32,
+    /// Device block holding it
+    pub physical: u64,
+    /// Number of blocks
+    pub len: u32,
+}
+
+/// One descriptor tag.
+struct Tag {
+    /// Final location of the logged block
+    target: u64,
+    flags: u32,
+    checksum: u32,
+}
+
+/// What a pass over the log does.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Pass {
+    /// Find the end of the last committed transaction
+    Scan,
+    /// Collect revoke records
+    Revoke,
+    /// Write logged blocks back
+    Replay,
+}
+
+/// State carried between the recovery passes.
+#[derive(Default)]
+struct Recovery {
+    /// First transaction that did not commit
+    end: u32,
+    /// Revoked blocks and the latest transaction revoking each
+    revoked: BTreeMap<u64, u32>,
+    /// Blocks written back
+    replayed: usize,
+}
+
+/// `a` comes after `b` in (wrapping) transaction ID order.
+fn tid_gt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+/// An open jbd2 journal.
+pub struct Journal {
+    /// Device blocks backing the journal
+    runs: Vec<JournalRun>,
+    /// Journal (= filesystem) block size
+    block_size: usize,
+    /// Journal superblock (block 0)
+    superblock: Vec<u8>,
+    /// First log block
+    first: u32,
+    /// Number of journal blocks
+    maxlen: u32,
+    /// Next transaction ID
+    sequence: u32,
+    /// First block of the log, 0 when the log is empty
+    start: u32,
+    /// Checksum seed (CRC-32C of the journal UUID)
+    csum_seed: u32,
+}
+
+impl Journal {
+    /// Opens the journal stored in `runs`.
+    pub fn open(device: &dyn BlockDevice, block_size: usize, runs: Vec<JournalRun>) -> Result<Self, VfsError> {
+        let mut journal = Self {
+            runs,
+            block_size,
+            superblock: Vec::new(),
+            first: 0,
+            maxlen: 0,
+            sequence: 0,
+            start: 0,
+            csum_seed: 0,
+        };
+        let sb = journal.read(device, 0)?;
+
+        let kind = be32(&sb, 4);
+        if be32(&sb, 0) != JBD2_MAGIC || !(kind == BLOCKTYPE_SUPERBLOCK_V1 || kind == BLOCKTYPE_SUPERBLOCK_V2) {
+            return Err(VfsError::InvalidArgument);
+        }
+        if be32(&sb, SB_BLOCKSIZE) as usize != block_size {
+            return Err(VfsError::InvalidArgument);
+        }
+        journal.first = be32(&sb, SB_FIRST);
+        journal.maxlen = be32(&sb, SB_MAXLEN);
+        journal.sequence = be32(&sb, SB_SEQUENCE);
+        journal.start = be32(&sb, SB_START);
+        let mapped: u32 = journal.runs.iter().map(|run| run.len).sum();
+        if journal.first == 0 || journal.first >= journal.maxlen || journal.maxlen > mapped {
+            return Err(VfsError::InvalidArgument);
+        }
+        journal.superblock = sb;
+
+        let unsupported = journal.incompat() & !SUPPORTED_INCOMPAT;
+        if unsupported != 0 {
+            crate::serial_println!("[jbd2] Unsupported journal features: {:#x}", unsupported);
+            return Err(VfsError::NotSupported);
+        }
+        journal.csum_seed = crc32c(!0, &journal.superblock[SB_UUID..SB_UUID + 16]);
+        if journal.has_checksums() && be32(&journal.superblock, SB_CHECKSUM) != journal.superblock_checksum() {
+            crate::serial_println!("[jbd2] Journal superblock checksum mismatch");
+            return Err(VfsError::IoError);
+        }
+        Ok(journal)
+    }
+
+    /// Whether the log holds transactions that were not checkpointed.
+    pub fn needs_recovery(&self) -> bool {
+        self.start != 0
+    }
+
+    /// Incompatible feature flags.
+    pub fn incompat(&self) -> u32 {
+        if be32(&self.superblock, 4) == BLOCKTYPE_SUPERBLOCK_V2 {
+            be32(&self.superblock, SB_FEATURE_INCOMPAT)
+        } else {
+            0
+        }
+    }
+
+    /// Next transaction ID.
+    pub fn sequence(&self) -> u32 {
+        self.sequence
+    }
+
+    /// Blocks one transaction may log, excluding descriptors and commit.
+    pub fn capacity(&self) -> usize {
+        let log = (self.maxlen - self.first) as usize;
+        let per_descriptor = self.tags_per_descriptor();
+        // Every `per_descriptor` blocks need one descriptor, plus the commit
+        log.saturating_sub(2) * per_descriptor / (per_descriptor + 1)
+    }
+
+    /// Turns on the features ext4 uses on a read-write mount: 64-bit block
+    /// numbers and v3 checksums. The log must be empty.
+    pub fn enable_features(&mut self, device: &dyn BlockDevice, bit64: bool, checksums: bool) -> Result<(), VfsError> {
+        if self.needs_recovery() || be32(&self.superblock, 4) != BLOCKTYPE_SUPERBLOCK_V2 {
+            return Ok(());
+        }
+        let mut incompat = self.incompat();
+        if bit64 {
+            incompat |= feature::INCOMPAT_64BIT;
+        }
+        if checksums {
+            incompat = (incompat & !feature::INCOMPAT_CSUM_V2) | feature::INCOMPAT_CSUM_V3;
+            let compat = be32(&self.superblock, SB_FEATURE_COMPAT) & !feature::COMPAT_CHECKSUM;
+            put_be32(&mut self.superblock, SB_FEATURE_COMPAT, compat);
+            self.superblock[SB_CHECKSUM_TYPE] = CHECKSUM_CRC32C;
+        }
+        if incompat == self.incompat() {
+            return Ok(());
+        }
+        put_be32(&mut self.superblock, SB_FEATURE_INCOMPAT, incompat);
+        self.write_superblock(device)?;
+        flush(device)
+    }
+
+    /// Replays committed transactions and empties the log. Returns the
+    /// number of blocks written back.
+    pub fn recover(&mut self, device: &dyn BlockDevice) -> Result<usize, VfsError> {
+        if !self.needs_recovery() {
+            return Ok(0);
+        }
+        let mut recovery = Recovery {
+            end: self.sequence,
+            ..Recovery::default()
+        };
+        self.pass(device, Pass::Scan, &mut recovery)?;
+        self.pass(device, Pass::Revoke, &mut recovery)?;
+        self.pass(device, Pass::Replay, &mut recovery)?;
+        flush(device)?;
+
+        crate::serial_println!(
+            "[jbd2] Recovered transactions {}..{} ({} blocks)",
+            self.sequence,
+            recovery.end,
+            recovery.replayed
+        );
+        self.sequence = recovery.end.wrapping_add(1);
+        self.start = 0;
+        self.write_superblock(device)?;
+        flush(device)?;
+        Ok(recovery.replayed)
+    }
+
+    /// Logs `blocks` (device block → new contents) as one transaction, then
+    /// writes them in place. Once this returns the changes are durable.
+    pub fn commit(&mut self, device: &dyn BlockDevice, blocks: &BTreeMap<u64, Vec<u8>>) -> Result<(), VfsError> {
+        if blocks.is_empty() {
+            return Ok(());
+        }
+        if blocks.len() > self.capacity() {
+            return Err(VfsError::NoSpace);
+        }
+        let sequence = self.sequence;
+        let targets: Vec<(&u64, &Vec<u8>)> = blocks.iter().collect();
+        let mut log_block = self.first;
+
+        for chunk in targets.chunks(self.tags_per_descriptor()) {
+            let descriptor_at = log_block;
+            log_block += 1;
+            let mut descriptor = vec![0u8; self.block_size];
+            put_header(&mut descriptor, BLOCKTYPE_DESCRIPTOR, sequence);
+
+            let mut offset = HEADER_SIZE;
+            for (i, &(&target, data)) in chunk.iter().enumerate() {
+                let mut copy = data.clone();
+                let mut flags = 0;
+                if be32(&copy, 0) == JBD2_MAGIC {
+                    // A logged block must not look like a journal header
+                    copy[..4].fill(0);
+                    flags |= TAG_ESCAPE;
+                }
+                if i > 0 {
+                    flags |= TAG_SAME_UUID;
+                }
+                if i + 1 == chunk.len() {
+                    flags |= TAG_LAST;
+                }
+                let checksum = self.block_checksum(sequence, &copy);
+                offset = self.put_tag(&mut descriptor, offset, target, flags, checksum);
+                self.write(device, log_block, &copy)?;
+                log_block += 1;
+            }
+            self.set_tail(&mut descriptor);
+            self.write(device, descriptor_at, &descriptor)?;
+        }
+        flush(device)?;
+
+        let mut commit = vec![0u8; self.block_size];
+        put_header(&mut commit, BLOCKTYPE_COMMIT, sequence);
+        commit[COMMIT_SEC..COMMIT_SEC + 8].copy_from_slice(&now().to_be_bytes());
+        if self.has_checksums() {
+            let checksum = crc32c(self.csum_seed, &commit);
+            put_be32(&mut commit, COMMIT_CHECKSUM, checksum);
+        }
+        self.write(device, log_block, &commit)?;
+        flush(device)?;
+
+        // Committed: a crash from here on replays the transaction
+        self.start = self.first;
+        self.write_superblock(device)?;
+        flush(device)?;
+
+        for (&target, data) in blocks {
+            write_block(device, self.block_size, target, data)?;
+        }
+        flush(device)?;
+
+        self.start = 0;
+        self.sequence = sequence.wrapping_add(1);
+        self.write_superblock(device)?;
+        flush(device)
+    }
+
+    /// Walks the log from `s_start`, doing one recovery pass.
+    fn pass(&self, device: &dyn BlockDevice, pass: Pass, recovery: &mut Recovery) -> Result<(), VfsError> {
+        let mut sequence = self.sequence;
+        let mut block = self.start;
+
+        loop {
+            if pass != Pass::Scan && !tid_gt(recovery.end, sequence) {
+                break;
+            }
+            let data = self.read(device, block)?;
+            if be32(&data, 0) != JBD2_MAGIC || be32(&data, 8) != sequence {
+                break;
+            }
+            block = self.next(block);
+
+            match be32(&data, 4) {
+                BLOCKTYPE_DESCRIPTOR => {
+                    if !self.tail_valid(&data) {
+                        crate::serial_println!("[jbd2] Bad descriptor checksum in transaction {}", sequence);
+                        break;
+                    }
+                    for tag in self.tags(&data) {
+                        if pass == Pass::Replay {
+                            self.replay_block(device, block, sequence, &tag, recovery)?;
+                        }
+                        block = self.next(block);
+                    }
+                }
+                BLOCKTYPE_COMMIT => {
+                    if pass == Pass::Scan && !self.commit_valid(&data) {
+                        crate::serial_println!("[jbd2] Bad commit checksum in transaction {}", sequence);
+                        break;
+                    }
+                    sequence = sequence.wrapping_add(1);
+                    if pass == Pass::Scan {
+                        recovery.end = sequence;
+                    }
+                }
+                BLOCKTYPE_REVOKE => {
+                    if !self.tail_valid(&data) {
+                        break;
+                    }
+                    if pass == Pass::Revoke {
+                        self.collect_revokes(&data, sequence, recovery);
+                    }
+                }
+                _ => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes one logged block back unless it was revoked or is corrupt.
+    fn replay_block(
+        &self,
+        device: &dyn BlockDevice,
+        log_block: u32,
+        sequence: u32,
+        tag: &Tag,
+        recovery: &mut Recovery,
+    ) -> Result<(), VfsError> {
+        if recovery.revoked.get(&tag.target).is_some_and(|&revoked| !tid_gt(sequence, revoked)) {
+            return Ok(());
+        }
+        let mut data = self.read(device, log_block)?;
+        if !self.tag_valid(sequence, &data, tag.checksum) {
+            crate::serial_println!("[jbd2] Bad checksum for block {} in transaction {}", tag.target, sequence);
+            return Ok(());
+        }
+        if tag.flags & TAG_ESCAPE != 0 {
+            put_be32(&mut data, 0, JBD2_MAGIC);
+        }
+        write_block(device, self.block_size, tag.target, &data)?;
+        recovery.replayed += 1;
+        Ok(())
+    }
+
+    /// Records the blocks listed in a revoke block.
+    fn collect_revokes(&self, data: &[u8], sequence: u32, recovery: &mut Recovery) {
+        let record = if self.incompat() & feature::INCOMPAT_64BIT != 0 { 8 } else { 4 };
+        let used = (be32(data, HEADER_SIZE) as usize).min(data.len());
+        let mut offset = HEADER_SIZE + 4;
+        while offset + record <= used {
+            let block = if record == 8 {
+                (be32(data, offset) as u64) << 32 | be32(data, offset + 4) as u64
+            } else {
+                be32(data, offset) as u64
+            };
+            let latest = recovery.revoked.entry(block).or_insert(sequence);
+            if tid_gt(sequence, *latest) {
+                *latest = sequence;
+            }
+            offset += record;
+        }
+    }
+
+    /// Parses the tags of a descriptor block.
+    fn tags(&self, data: &[u8]) -> Vec<Tag> {
+        let tag_bytes = self.tag_bytes();
+        let bit64 = self.incompat() & feature::INCOMPAT_64BIT != 0;
+        let csum_v3 = self.incompat() & feature::INCOMPAT_CSUM_V3 != 0;
+        let end = data.len() - if self.has_checksums() { TAIL_SIZE } else { 0 };
+
+        let mut tags = Vec::new();
+        let mut offset = HEADER_SIZE;
+        while offset + tag_bytes <= end {
+            let low = be32(data, offset) as u64;
+            let (flags, high, checksum) = if csum_v3 {
+                (be32(data, offset + 4), be32(data, offset + 8), be32(data, offset + 12))
+            } else {
+                let high = if bit64 { be32(data, offset + 8) } else { 0 };
+                (be16(data, offset + 6) as u32, high, be16(data, offset + 4) as u32)
+            };
+            let target = if bit64 { (high as u64) << 32 | low } else { low };
+            offset += tag_bytes;
+            if flags & TAG_SAME_UUID == 0 {
+                offset += 16;
+            }
+            tags.push(Tag { target, flags, checksum });
+            if flags & TAG_LAST != 0 {
+                break;
+            }
+        }
+        tags
+    }
+
+    /// Appends a tag at `offset`, returning the offset after it.
+    fn put_tag(&self, data: &mut [u8], offset: usize, target: u64, flags: u32, checksum: u32) -> usize {
+        let bit64 = self.incompat() & feature::INCOMPAT_64BIT != 0;
+        put_be32(data, offset, target as u32);
+        if self.incompat() & feature::INCOMPAT_CSUM_V3 != 0 {
+            put_be32(data, offset + 4, flags);
+            put_be32(data, offset + 8, (target >> 32) as u32);
+            put_be32(data, offset + 12, checksum);
+        } else {
+            data[offset + 4..offset + 6].copy_from_slice(&(checksum as u16).to_be_bytes());
+            data[offset + 6..offset + 8].copy_from_slice(&(flags as u16).to_be_bytes());
+            if bit64 {
+                put_be32(data, offset + 8, (target >> 32) as u32);
+            }
+        }
+        let mut offset = offset + self.tag_bytes();
+        if flags & TAG_SAME_UUID == 0 {
+            data[offset..offset + 16].copy_from_slice(&self.superblock[SB_UUID..SB_UUID + 16]);
+            offset += 16;
+        }
+        offset
+    }
+
+    /// Size of one descriptor tag.
+    fn tag_bytes(&self) -> usize {
+        let incompat = self.incompat();
+        if incompat & feature::INCOMPAT_CSUM_V3 != 0 {
+            return 16;
+        }
+        let size = if incompat & feature::INCOMPAT_CSUM_V2 != 0 { 14 } else { 12 };
+        if incompat & feature::INCOMPAT_64BIT != 0 {
+            size
+        } else {
+            size - 4
+        }
+    }
+
+    /// Tags that fit in one descriptor block (the first carries the UUID).
+    fn tags_per_descriptor(&self) -> usize {
+        let tail = if self.has_checksums() { TAIL_SIZE } else { 0 };
+        (self.block_size - HEADER_SIZE - tail - 16) / self.tag_bytes()
+    }
+
+    fn has_checksums(&self) -> bool {
+        self.incompat() & (feature::INCOMPAT_CSUM_V2 | feature::INCOMPAT_CSUM_V3) != 0
+    }
+
+    /// Checksum of a logged block as stored in its tag.
+    fn block_checksum(&self, sequence: u32, data: &[u8]) -> u32 {
+        if !self.has_checksums() {
+            return 0;
+        }
+        let checksum = crc32c(crc32c(self.csum_seed, &sequence.to_be_bytes()), data);
+        if self.incompat() & feature::INCOMPAT_CSUM_V3 != 0 {
+            checksum
+        } else {
+            checksum & 0xFFFF
+        }
+    }
+
+    fn tag_valid(&self, sequence: u32, data: &[u8], checksum: u32) -> bool {
+        !self.has_checksums() || self.block_checksum(sequence, data) == checksum
+    }
+
+    /// Sets the checksum tail of a descriptor or revoke block.
+    fn set_tail(&self, data: &mut [u8]) {
+        if self.has_checksums() {
+            let tail = data.len() - TAIL_SIZE;
+            data[tail..].fill(0);
+            let checksum = crc32c(self.csum_seed, data);
+            put_be32(data, tail, checksum);
+        }
+    }
+
+    fn tail_valid(&self, data: &[u8]) -> bool {
+        if !self.has_checksums() {
+            return true;
+        }
+        let tail = data.len() - TAIL_SIZE;
+        let checksum = crc32c(crc32c(self.csum_seed, &data[..tail]), &[0; TAIL_SIZE]);
+        be32(data, tail) == checksum
+    }
+
+    fn commit_valid(&self, data: &[u8]) -> bool {
+        if !self.has_checksums() {
+            return true;
+        }
+        let mut copy = data.to_vec();
+        put_be32(&mut copy, COMMIT_CHECKSUM, 0);
+        be32(data, COMMIT_CHECKSUM) == crc32c(self.csum_seed, &copy)
+    }
+
+    fn superblock_checksum(&self) -> u32 {
+        let mut copy = self.superblock[..SB_SIZE].to_vec();
+        put_be32(&mut copy, SB_CHECKSUM, 0);
+        crc32c(!0, &copy)
+    }
+
+    fn write_superblock(&mut self, device: &dyn BlockDevice) -> Result<(), VfsError> {
+        put_be32(&mut self.superblock, SB_SEQUENCE, self.sequence);
+        put_be32(&mut self.superblock, SB_START, self.start);
+        self.csum_seed = crc32c(!0, &self.superblock[SB_UUID..SB_UUID + 16]);
+        if self.has_checksums() {
+            let checksum = self.superblock_checksum();
+            put_be32(&mut self.superblock, SB_CHECKSUM, checksum);
+        }
+        let superblock = self.superblock.clone();
+        self.write(device, 0, &superblock)
+    }
+
+    /// Journal block after `block`, wrapping to `s_first`.
+    fn next(&self, block: u32) -> u32 {
+        if block + 1 >= self.maxlen {
+            self.first
+        } else {
+            block + 1
+        }
+    }
+
+    /// Device block holding journal block `block`.
+    fn device_block(&self, block: u32) -> Result<u64, VfsError> {
+        self.runs
+            .iter()
+            .find(|run| block >= run.logical && block - run.logical < run.len)
+            .map(|run| run.physical + (block - run.logical) as u64)
+            .ok_or(VfsError::IoError)
+    }
+
+    fn read(&self, device: &dyn BlockDevice, block: u32) -> Result<Vec<u8>, VfsError> {
+        read_block(device, self.block_size, self.device_block(block)?)
+    }
+
+    fn write(&self, device: &dyn BlockDevice, block: u32, data: &[u8]) -> Result<(), VfsError> {
+        write_block(device, self.block_size, self.device_block(block)?, data)
+    }
+}
+
+fn read_block(device: &dyn BlockDevice, block_size: usize, block: u64) -> Result<Vec<u8>, VfsError> {
+    let mut data = vec![0u8; block_size];
+    device
+        .read_sectors(block * (block_size / SECTOR_SIZE) as u64, &mut data)
+        .map_err(|_| VfsError::IoError)?;
+    Ok(data)
+}
+
+fn write_block(device: &dyn BlockDevice, block_size: usize, block: u64, data: &[u8]) -> Result<(), VfsError> {
+    device
+        .write_sectors(block * (block_size / SECTOR_SIZE) as u64, data)
+        .map_err(|_| VfsError::IoError)
+}
+
+fn flush(device: &dyn BlockDevice) -> Result<(), VfsError> {
+    device.flush().map_err(|_| VfsError::IoError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::ramdisk::RamDisk;
+
+    const BLOCK_SIZE: usize = 1024;
+    const JOURNAL_AT: u64 = 8;
+    const JOURNAL_LEN: u32 = 32;
+
+    /// A 128-block disk with an empty v2 journal in blocks 8..40.
+    fn disk() -> RamDisk {
+        let disk = RamDisk::new("jbd2-test", vec![0u8; 128 * BLOCK_SIZE]);
+        let mut sb = vec![0u8; BLOCK_SIZE];
+        put_header(&mut sb, BLOCKTYPE_SUPERBLOCK_V2, 0);
+        put_be32(&mut sb, SB_BLOCKSIZE, BLOCK_SIZE as u32);
+        put_be32(&mut sb, SB_MAXLEN, JOURNAL_LEN);
+        put_be32(&mut sb, SB_FIRST, 1);
+        put_be32(&mut sb, SB_SEQUENCE, 5);
+        put_be32(&mut sb, SB_FEATURE_INCOMPAT, feature::INCOMPAT_REVOKE);
+        sb[SB_UUID..SB_UUID + 16].copy_from_slice(&[0x42; 16]);
+        write_block(&disk, BLOCK_SIZE, JOURNAL_AT, &sb).unwrap();
+        disk
+    }
+
+    fn open(disk: &RamDisk) -> Journal {
+        let runs = vec![JournalRun { logical: 0, physical: JOURNAL_AT, len: JOURNAL_LEN }];
+        Journal::open(disk, BLOCK_SIZE, runs).unwrap()
+    }
+
+    fn transaction() -> BTreeMap<u64, Vec<u8>> {
+        let mut blocks = BTreeMap::new();
+        blocks.insert(100, vec![0x11; BLOCK_SIZE]);
+        // Starts with the journal magic, so it must be escaped in the log
+        let mut magic = vec![0x22; BLOCK_SIZE];
+        magic[..4].copy_from_slice(&JBD2_MAGIC.to_be_bytes());
+        blocks.insert(101, magic);
+        blocks
+    }
+
+    #[test]
+    fn test_commit() {
+        let disk = disk();
+        let mut journal = open(&disk);
+        journal.enable_features(&disk, true, true).unwrap();
+        assert!(journal.capacity() > 20);
+
+        let blocks = transaction();
+        journal.commit(&disk, &blocks).unwrap();
+        for (&block, data) in &blocks {
+            assert_eq!(&read_block(&disk, BLOCK_SIZE, block).unwrap(), data);
+        }
+
+        let journal = open(&disk);
+        assert!(!journal.needs_recovery());
+        assert_eq!(journal.sequence(), 6);
+        assert_ne!(journal.incompat() & feature::INCOMPAT_CSUM_V3, 0);
+    }
+
+    #[test]
+    fn test_recover() {
+        for checksums in [false, true] {
+            let disk = disk();
+            let mut journal = open(&disk);
+            journal.enable_features(&disk, true, checksums).unwrap();
+            let blocks = transaction();
+            journal.commit(&disk, &blocks).unwrap();
+
+            // Crash after the commit block, before the checkpoint
+            for &block in blocks.keys() {
+                write_block(&disk, BLOCK_SIZE, block, &[0; BLOCK_SIZE]).unwrap();
+            }
+            journal.start = journal.first;
+            journal.sequence = 5;
+            journal.write_superblock(&disk).unwrap();
+
+            let mut journal = open(&disk);
+            assert!(journal.needs_recovery());
+            assert_eq!(journal.recover(&disk), Ok(2));
+            for (&block, data) in &blocks {
+                assert_eq!(&read_block(&disk, BLOCK_SIZE, block).unwrap(), data);
+            }
+            assert!(!open(&disk).needs_recovery());
+            // Like Linux, recovery skips one ID past the last transaction
+            assert_eq!(open(&disk).sequence(), 7);
+        }
+    }
+
+    #[test]
+    fn test_uncommitted_transaction_is_ignored() {
+        let disk = disk();
+        let mut journal = open(&disk);
+        journal.enable_features(&disk, true, true).unwrap();
+        let blocks = transaction();
+        journal.commit(&disk, &blocks).unwrap();
+
+        // Lose the commit block (descriptor + 2 data blocks precede it)
+        write_block(&disk, BLOCK_SIZE, JOURNAL_AT + 4, &[0; BLOCK_SIZE]).unwrap();
+        write_block(&disk, BLOCK_SIZE, 100, &[0; BLOCK_SIZE]).unwrap();
+        journal.start = journal.first;
+        journal.sequence = 5;
+        journal.write_superblock(&disk).unwrap();
+
+        let mut journal = open(&disk);
+        assert_eq!(journal.recover(&disk), Ok(0));
+        assert_eq!(read_block(&disk, BLOCK_SIZE, 100).unwrap(), vec![0; BLOCK_SIZE]);
+        assert!(!open(&disk).needs_recovery());
+    }
+}
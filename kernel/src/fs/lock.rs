@@ -0,0 +1,497 @@
+//! # File Locking
+//!
+//! Whole-file (`flock`) and byte-range (POSIX `fcntl`) locks, shared or
+//! exclusive, kept per mount and inode so they work on every filesystem.
+//!
+//! ## Semantics
+//!
+//! - `flock` locks belong to an open file: other descriptors conflict with
+//!   them, even in the same process, and closing the descriptor releases
+//!   them.
+//! - Byte-range locks belong to a process: its own locks never conflict,
+//!   a new lock replaces (splitting if needed) what the process held over
+//!   the range, and closing any descriptor of the file releases them.
+//! - The two kinds are independent and do not conflict with each other.
+//! - Locks are advisory unless taken as mandatory: reads and writes by
+//!   other processes overlapping a mandatory byte-range lock they would
+//!   conflict with fail with [`VfsError::WouldBlock`].
+//! - Blocking acquisition waits until the conflicting locks go away, and
+//!   fails with [`VfsError::Deadlock`] if the owners of those locks are,
+//!   directly or through others, waiting for the caller.
+//! - Everything a process holds is released when it exits.
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use super::vfs::{InodeNum, VfsError};
+use crate::sched::ProcessId;
+
+/// Lock operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockType {
+    /// Shared (read) lock
+    Shared,
+    /// Exclusive (write) lock
+    Exclusive,
+    /// Release
+    Unlock,
+}
+
+/// Byte-range lock request (`fcntl` `F_SETLK`/`F_SETLKW`/`F_GETLK`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockRequest {
+    /// Operation
+    pub lock_type: LockType,
+    /// First byte
+    pub start: u64,
+    /// Length in bytes; 0 extends to the end of the file, however far it
+    /// grows
+    pub len: u64,
+    /// Enforce the lock on reads and writes of other processes
+    pub mandatory: bool,
+}
+
+impl LockRequest {
+    /// Request covering the whole file
+    pub const fn whole_file(lock_type: LockType) -> Self {
+        Self { lock_type, start: 0, len: 0, mandatory: false }
+    }
+}
+
+/// A lock held, as reported by [`LockTable::conflict`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockInfo {
+    /// [`LockType::Shared`] or [`LockType::Exclusive`]
+    pub lock_type: LockType,
+    /// First byte
+    pub start: u64,
+    /// Length in bytes, 0 for up to the end of the file
+    pub len: u64,
+    /// Process holding the lock
+    pub pid: u64,
+    /// Whether the lock is mandatory
+    pub mandatory: bool,
+    /// Whether it is a whole-file `flock` lock
+    pub flock: bool,
+}
+
+/// Who a lock belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockOwner {
+    /// Byte-range lock of a process
+    Process(u64),
+    /// `flock` lock of an open file
+    File(u64),
+}
+
+impl LockOwner {
+    fn same_kind(self, other: Self) -> bool {
+        matches!(
+            (self, other),
+            (LockOwner::Process(_), LockOwner::Process(_)) | (LockOwner::File(_), LockOwner::File(_))
+        )
+    }
+}
+
+/// A held lock over `start..end` (`end` is `u64::MAX` up to the end of
+/// the file)
+#[derive(Debug, Clone, Copy)]
+struct Lock {
+    owner: LockOwner,
+    pid: u64,
+    exclusive: bool,
+    start: u64,
+    end: u64,
+    mandatory: bool,
+}
+
+impl Lock {
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start < end && start < self.end
+    }
+
+    /// Whether `self`, held, prevents `other` from being taken
+    fn conflicts(&self, other: &Lock) -> bool {
+        self.owner != other.owner
+            && self.owner.same_kind(other.owner)
+            && self.overlaps(other.start, other.end)
+            && (self.exclusive || other.exclusive)
+    }
+
+    fn info(&self) -> LockInfo {
+        LockInfo {
+            lock_type: if self.exclusive { LockType::Exclusive } else { LockType::Shared },
+            start: self.start,
+            len: if self.end == u64::MAX { 0 } else { self.end - self.start },
+            pid: self.pid,
+            mandatory: self.mandatory,
+            flock: matches!(self.owner, LockOwner::File(_)),
+        }
+    }
+}
+
+/// Inode a lock is on: mount ID and inode number
+pub type LockKey = (u64, InodeNum);
+
+#[derive(Default)]
+struct State {
+    locks: BTreeMap<LockKey, Vec<Lock>>,
+    /// Lock each blocked process waits for
+    waiters: BTreeMap<u64, (LockKey, Lock)>,
+}
+
+impl State {
+    fn conflict(&self, key: LockKey, lock: &Lock) -> Option<&Lock> {
+        self.locks.get(&key)?.iter().find(|held| held.conflicts(lock))
+    }
+
+    /// Processes holding locks `pid` waits for
+    fn blockers(&self, pid: u64) -> Vec<u64> {
+        let Some((key, lock)) = self.waiters.get(&pid) else {
+            return Vec::new();
+        };
+        self.locks
+            .get(key)
+            .map(|held| held.iter().filter(|h| h.conflicts(lock)).map(|h| h.pid).collect())
+            .unwrap_or_default()
+    }
+
+    /// Whether `pid` waits, through a chain of waiting processes, for
+    /// itself
+    fn deadlocked(&self, pid: u64) -> bool {
+        let mut seen = BTreeSet::new();
+        let mut pending = self.blockers(pid);
+        while let Some(holder) = pending.pop() {
+            if holder == pid {
+                return true;
+            }
+            if seen.insert(holder) {
+                pending.extend(self.blockers(holder));
+            }
+        }
+        false
+    }
+
+    /// Removes what `owner` holds over `start..end`, keeping the parts
+    /// outside it.
+    fn clear(&mut self, key: LockKey, owner: LockOwner, start: u64, end: u64) {
+        let Some(held) = self.locks.get_mut(&key) else {
+            return;
+        };
+        let mut kept = Vec::with_capacity(held.len());
+        for lock in held.drain(..) {
+            if lock.owner != owner || !lock.overlaps(start, end) {
+                kept.push(lock);
+                continue;
+            }
+            if lock.start < start {
+                kept.push(Lock { end: start, ..lock });
+            }
+            if lock.end > end {
+                kept.push(Lock { start: end, ..lock });
+            }
+        }
+        *held = kept;
+        if held.is_empty() {
+            self.locks.remove(&key);
+        }
+    }
+
+    /// Removes the matching locks; returns the inodes that had some.
+    fn release(&mut self, matches: impl Fn(LockKey, &Lock) -> bool) -> Vec<LockKey> {
+        let mut touched = Vec::new();
+        self.locks.retain(|&key, held| {
+            let before = held.len();
+            held.retain(|lock| !matches(key, lock));
+            if held.len() != before {
+                touched.push(key);
+            }
+            !held.is_empty()
+        });
+        touched
+    }
+
+    /// Processes waiting for locks on the given inodes
+    fn waiting_on(&self, keys: &[LockKey]) -> Vec<u64> {
+        self.waiters
+            .iter()
+            .filter(|(_, (key, _))| keys.contains(key))
+            .map(|(&pid, _)| pid)
+            .collect()
+    }
+}
+
+/// Locks of a VFS.
+pub struct LockTable {
+    state: Mutex<Option<State>>,
+}
+
+impl LockTable {
+    /// Creates an empty table.
+    pub const fn new() -> Self {
+        Self { state: Mutex::new(None) }
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&mut State) -> R) -> R {
+        f(self.state.lock().get_or_insert_with(State::default))
+    }
+
+    /// Takes, changes or releases a lock of `owner` (held by process
+    /// `pid`). With `wait`, waits for conflicting locks to go away;
+    /// otherwise fails with [`VfsError::WouldBlock`].
+    pub fn lock(
+        &self,
+        key: LockKey,
+        owner: LockOwner,
+        pid: u64,
+        request: &LockRequest,
+        wait: bool,
+    ) -> Result<(), VfsError> {
+        let (start, end) = Self::range(request)?;
+        let lock = Lock {
+            owner,
+            pid,
+            exclusive: request.lock_type == LockType::Exclusive,
+            start,
+            end,
+            mandatory: request.mandatory,
+        };
+
+        let mut slept = false;
+        let result = loop {
+            let mut blocked = false;
+            let step = self.with(|state| {
+                if request.lock_type == LockType::Unlock {
+                    state.clear(key, owner, start, end);
+                    return Ok(Some(state.waiting_on(&[key])));
+                }
+                if state.conflict(key, &lock).is_none() {
+                    state.waiters.remove(&pid);
+                    state.clear(key, owner, start, end);
+                    state.locks.entry(key).or_default().push(lock);
+                    // A downgrade may let others in
+                    return Ok(Some(state.waiting_on(&[key])));
+                }
+                if !wait {
+                    return Err(VfsError::WouldBlock);
+                }
+                state.waiters.insert(pid, (key, lock));
+                if state.deadlocked(pid) {
+                    state.waiters.remove(&pid);
+                    return Err(VfsError::Deadlock);
+                }
+                // Block before the table is unlocked: a release that finds
+                // us waiting then also finds us blocked, and wakes us
+                blocked = crate::sched::scheduler().block(ProcessId::new(pid)).is_ok();
+                Ok(None)
+            });
+            match step {
+                Err(e) => break Err(e),
+                Ok(Some(pids)) => {
+                    Self::wake(&pids);
+                    break Ok(());
+                }
+                Ok(None) => {
+                    slept |= blocked;
+                    Self::sleep(blocked);
+                }
+            }
+        };
+        if slept {
+            let _ = crate::sched::scheduler().resume(ProcessId::new(pid));
+        }
+        result
+    }
+
+    /// First lock that would prevent `request` by `owner`, if any
+    pub fn conflict(&self, key: LockKey, owner: LockOwner, request: &LockRequest) -> Result<Option<LockInfo>, VfsError> {
+        let (start, end) = Self::range(request)?;
+        let lock = Lock {
+            owner,
+            pid: 0,
+            exclusive: request.lock_type == LockType::Exclusive,
+            start,
+            end,
+            mandatory: false,
+        };
+        Ok(self.with(|state| state.conflict(key, &lock).map(Lock::info)))
+    }
+
+    /// Checks a read or write of `len` bytes at `offset` by `pid` against
+    /// mandatory locks of other processes.
+    pub fn check_io(&self, key: LockKey, pid: u64, offset: u64, len: usize, write: bool) -> Result<(), VfsError> {
+        let end = offset.saturating_add(len as u64);
+        self.with(|state| {
+            let Some(held) = state.locks.get(&key) else {
+                return Ok(());
+            };
+            let blocked = held.iter().any(|lock| {
+                lock.mandatory && lock.pid != pid && lock.overlaps(offset, end) && (write || lock.exclusive)
+            });
+            if blocked { Err(VfsError::WouldBlock) } else { Ok(()) }
+        })
+    }
+
+    /// Releases the `flock` lock of an open file, and the byte-range locks
+    /// `pid` holds on its inode, as closing a descriptor does.
+    pub fn release_file(&self, key: LockKey, file: u64, pid: u64) {
+        let woken = self.with(|state| {
+            let touched = state.release(|k, lock| {
+                k == key && (lock.owner == LockOwner::File(file) || lock.owner == LockOwner::Process(pid))
+            });
+            state.waiting_on(&touched)
+        });
+        Self::wake(&woken);
+    }
+
+    /// Releases everything `pid` holds or waits for.
+    pub fn release_process(&self, pid: u64) {
+        let woken = self.with(|state| {
+            state.waiters.remove(&pid);
+            let touched = state.release(|_, lock| lock.pid == pid);
+            state.waiting_on(&touched)
+        });
+        Self::wake(&woken);
+    }
+
+    /// Releases every lock on the inodes of a mount.
+    pub fn release_mount(&self, mount: u64) {
+        let woken = self.with(|state| {
+            let touched = state.release(|(m, _), _| m == mount);
+            state.waiting_on(&touched)
+        });
+        Self::wake(&woken);
+    }
+
+    /// Locks held on an inode
+    pub fn held(&self, key: LockKey) -> Vec<LockInfo> {
+        self.with(|state| state.locks.get(&key).map(|held| held.iter().map(Lock::info).collect()).unwrap_or_default())
+    }
+
+    fn range(request: &LockRequest) -> Result<(u64, u64), VfsError> {
+        let end = match request.len {
+            0 => u64::MAX,
+            len => request.start.checked_add(len).ok_or(VfsError::InvalidArgument)?,
+        };
+        Ok((request.start, end))
+    }
+
+    fn wake(pids: &[u64]) {
+        for &pid in pids {
+            let _ = crate::sched::scheduler().wake(ProcessId::new(pid));
+        }
+    }
+
+    /// Waits to be woken by a release; callers that are not scheduled
+    /// processes (and so could not block) spin.
+    fn sleep(blocked: bool) {
+        if blocked {
+            crate::sched::scheduler().yield_now();
+        } else {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+impl Default for LockTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::ramfs;
+    use crate::fs::vfs::{OpenFlags, Vfs};
+
+    const KEY: LockKey = (1, 2);
+
+    fn range(lock_type: LockType, start: u64, len: u64) -> LockRequest {
+        LockRequest { lock_type, start, len, mandatory: false }
+    }
+
+    #[test]
+    fn test_byte_ranges() {
+        let table = LockTable::new();
+        let (a, b) = (LockOwner::Process(10), LockOwner::Process(11));
+        table.lock(KEY, a, 10, &range(LockType::Shared, 0, 100), false).unwrap();
+        // Shared locks coexist; exclusive ones conflict only where they overlap
+        table.lock(KEY, b, 11, &range(LockType::Shared, 50, 100), false).unwrap();
+        assert_eq!(table.lock(KEY, b, 11, &range(LockType::Exclusive, 90, 20), false), Err(VfsError::WouldBlock));
+        table.lock(KEY, b, 11, &range(LockType::Exclusive, 100, 0), false).unwrap();
+
+        // Unlocking the middle splits a lock
+        table.lock(KEY, a, 10, &range(LockType::Unlock, 20, 10), false).unwrap();
+        let mut held: Vec<(u64, u64, u64)> = table.held(KEY).iter().map(|l| (l.pid, l.start, l.len)).collect();
+        held.sort();
+        assert_eq!(held, [(10, 0, 20), (10, 30, 70), (11, 50, 50), (11, 100, 0)]);
+
+        let conflict = table.conflict(KEY, a, &range(LockType::Exclusive, 0, 0)).unwrap().unwrap();
+        assert_eq!((conflict.pid, conflict.lock_type), (11, LockType::Shared));
+        assert_eq!(table.conflict(KEY, a, &range(LockType::Shared, 25, 5)).unwrap(), None);
+        assert_eq!(table.lock(KEY, a, 10, &range(LockType::Shared, u64::MAX, 2), false), Err(VfsError::InvalidArgument));
+
+        table.release_process(11);
+        table.lock(KEY, a, 10, &range(LockType::Exclusive, 0, 0), false).unwrap();
+        assert_eq!(table.held(KEY).len(), 1);
+    }
+
+    #[test]
+    fn test_deadlock_detection() {
+        let table = LockTable::new();
+        let (a, b) = (LockOwner::Process(20), LockOwner::Process(21));
+        table.lock(KEY, a, 20, &range(LockType::Exclusive, 0, 10), false).unwrap();
+        table.lock(KEY, b, 21, &range(LockType::Exclusive, 10, 10), false).unwrap();
+        // 21 waits for 20's range (registered by hand, as it would block),
+        // then 20 asking for 21's range closes the cycle
+        table.with(|state| {
+            let lock = Lock { owner: b, pid: 21, exclusive: true, start: 0, end: 10, mandatory: false };
+            state.waiters.insert(21, (KEY, lock));
+        });
+        assert_eq!(table.lock(KEY, a, 20, &range(LockType::Exclusive, 10, 10), true), Err(VfsError::Deadlock));
+        table.release_process(21);
+        table.lock(KEY, a, 20, &range(LockType::Exclusive, 10, 10), true).unwrap();
+    }
+
+    #[test]
+    fn test_vfs_locks() {
+        let vfs = Vfs::new();
+        vfs.mount("/", ramfs::new(1 << 20), false).unwrap();
+        let flags = OpenFlags { read: true, write: true, create: true, ..OpenFlags::default() };
+        let (p1, p2) = (0x500, 0x501);
+        let fd1 = vfs.open(p1, "/pkg.db", flags).unwrap();
+        let fd2 = vfs.open(p1, "/pkg.db", flags).unwrap();
+        let fd3 = vfs.open(p2, "/pkg.db", flags).unwrap();
+        let key = (1, vfs.stat("/pkg.db").unwrap().ino);
+
+        // flock locks belong to the open file, not the process
+        vfs.flock(p1, fd1, LockType::Exclusive, false).unwrap();
+        assert_eq!(vfs.flock(p1, fd2, LockType::Shared, false), Err(VfsError::WouldBlock));
+        // ...and do not interact with byte-range locks
+        vfs.setlk(p2, fd3, &range(LockType::Exclusive, 0, 10), false).unwrap();
+        vfs.close(p1, fd1).unwrap();
+        vfs.flock(p1, fd2, LockType::Shared, false).unwrap();
+
+        // Closing any descriptor of the file drops the process's ranges
+        assert_eq!(vfs.getlk(p1, fd2, &range(LockType::Shared, 5, 1)).unwrap().map(|l| l.pid), Some(p2));
+        let fd4 = vfs.open(p2, "/pkg.db", OpenFlags::read_only()).unwrap();
+        assert_eq!(vfs.setlk(p2, fd4, &range(LockType::Exclusive, 0, 1), false), Err(VfsError::BadFd));
+        vfs.close(p2, fd4).unwrap();
+        assert_eq!(vfs.getlk(p1, fd2, &range(LockType::Shared, 5, 1)).unwrap(), None);
+
+        // Mandatory locks refuse other processes' I/O
+        let mandatory = LockRequest { mandatory: true, ..range(LockType::Exclusive, 0, 0) };
+        vfs.flock(p1, fd2, LockType::Unlock, false).unwrap();
+        vfs.setlk(p2, fd3, &mandatory, false).unwrap();
+        assert_eq!(vfs.read(p1, fd2, &mut [0; 4]), Err(VfsError::WouldBlock));
+        assert_eq!(vfs.locks().check_io(key, p2, 0, 4, true), Ok(()));
+
+        // Process exit releases everything
+        vfs.close_all_fds(p2);
+        assert_eq!(vfs.close(p2, fd3), Err(VfsError::BadFd));
+        assert!(vfs.locks().held(key).is_empty());
+        assert_eq!(vfs.locks().check_io(key, p1, 0, 4, true), Ok(()));
+    }
+}
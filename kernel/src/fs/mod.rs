@@ -2,11 +2,22 @@
 //!
 //! Splax OS filesystem support:
 //! - VFS: Virtual Filesystem layer
+//! - Page cache: file pages shared by all filesystems, read/write and mmap
+//! - DAX: page-cache bypass and direct mapping for persistent memory
+//! - Xattr: extended attribute names and per-file capability labels
+//! - Notify: watches reporting file and directory changes over IPC
+//! - Lock: whole-file and byte-range file locks
+//! - Namespace: per-process mount tables, bind mounts and propagation
 //! - RamFS: VFS-compatible in-memory filesystem
 //! - ProcFS: Process/system information (/proc)
 //! - DevFS: Device nodes (/dev)
 //! - SysFS: Kernel objects (/sys)
 //! - SplaxFS: On-disk persistent filesystem
+//! - ext4: Linux ext4 volumes, journaled through jbd2
+//! - exFAT: SDXC cards and USB drives, read/write
+//! - ISO 9660: CD/DVD media and images, with Joliet and Rock Ridge
+//! - SquashFS: compressed read-only images, for packages and container layers
+//! - 9P: host directories shared over virtio-9p
 //! - VFS Stub: Thin layer for hybrid kernel IPC (Phase A migration)
 //!
 //! ## Architecture
@@ -49,13 +60,24 @@
 //! appropriate capabilities for the target path.
 
 pub mod vfs;
+pub mod pagecache;
+pub mod dax;
+pub mod xattr;
+pub mod notify;
+pub mod lock;
+pub mod namespace;
 pub mod ramfs;
 pub mod splaxfs;
 pub mod procfs;
 pub mod devfs;
 pub mod sysfs;
 pub mod ext4;
+pub mod jbd2;
 pub mod fat32;
+pub mod exfat;
+pub mod iso9660;
+pub mod squashfs;
+pub mod virtio_9p;
 
 // Phase A: Hybrid kernel VFS stub (forwards to S-STORAGE userspace)
 pub mod vfs_stub;
@@ -484,6 +506,9 @@ pub fn init() {
         return; // Already initialized
     }
 
+    // Let the page cache shrink under memory pressure
+    crate::mm::pressure::register_reclaimer(&pagecache::PAGE_CACHE);
+
     // Initialize legacy RamFs
     let mut fs = FILESYSTEM.lock();
     *fs = RamFs::new(4 * 1024 * 1024);
@@ -628,3 +653,81 @@ pub fn rmdir(path: &str) -> Result<(), FsError> {
 pub fn stats() -> FsStats {
     FILESYSTEM.lock().stats()
 }
+
+/// Mounts a filesystem of a named type: a block device, or for `9p` a
+/// mount tag and for `squashfs` also an image file, at `target`
+pub fn mount(fs_type: &str, source: &str, target: &str) -> Result<(), vfs::VfsError> {
+    match fs_type {
+        "splaxfs" => splaxfs::mount(source, target).map_err(vfs::VfsError::from),
+        "fat32" | "vfat" => fat32::mount(source, target),
+        "ext4" => ext4::mount(source, target),
+        "exfat" => exfat::mount(source, target),
+        "iso9660" => iso9660::mount(source, target),
+        "squashfs" => squashfs::mount(source, target),
+        "9p" => virtio_9p::mount(source, target),
+        "bind" | "rbind" => {
+            let pid = crate::sched::scheduler().current_process().map_or(0, |p| p.0);
+            vfs::VFS.bind_mount(pid, source, target, false, fs_type == "rbind")
+        }
+        _ => Err(vfs::VfsError::NoFilesystem),
+    }
+}
+
+/// [`mount`] on behalf of `pid`, for the `mount` syscall; takes the same
+/// capability as [`unshare_mounts`]
+pub fn mount_as(
+    pid: crate::sched::ProcessId,
+    fs_type: &str,
+    source: &str,
+    target: &str,
+    token: crate::cap::CapabilityToken,
+) -> Result<(), vfs::VfsError> {
+    let caps = crate::cap::kernel_table().ok_or(vfs::VfsError::PermissionDenied)?;
+    vfs::VFS.check_mount_cap(pid.0, caps, token)?;
+    mount(fs_type, source, target)
+}
+
+/// Moves `pid` to a new mount namespace; `token` must be a `mount_ns`
+/// capability of `pid` in the kernel capability table
+pub fn unshare_mounts(pid: crate::sched::ProcessId, token: crate::cap::CapabilityToken) -> Result<namespace::MountNsId, vfs::VfsError> {
+    let caps = crate::cap::kernel_table().ok_or(vfs::VfsError::PermissionDenied)?;
+    vfs::VFS.unshare_mounts(pid.0, caps, token)
+}
+
+/// Makes `new_root` the root of the mount namespace of `pid`, moving the
+/// old root to `put_old`; takes the same capability as [`unshare_mounts`]
+pub fn pivot_root(
+    pid: crate::sched::ProcessId,
+    new_root: &str,
+    put_old: &str,
+    token: crate::cap::CapabilityToken,
+) -> Result<(), vfs::VfsError> {
+    let caps = crate::cap::kernel_table().ok_or(vfs::VfsError::PermissionDenied)?;
+    vfs::VFS.pivot_root(pid.0, new_root, put_old, caps, token)
+}
+
+/// [`unmount`] on behalf of `pid`, for the `umount2` syscall; takes the
+/// same capability as [`mount_as`]
+pub fn unmount_as(
+    pid: crate::sched::ProcessId,
+    target: &str,
+    token: crate::cap::CapabilityToken,
+) -> Result<(), vfs::VfsError> {
+    let caps = crate::cap::kernel_table().ok_or(vfs::VfsError::PermissionDenied)?;
+    vfs::VFS.check_mount_cap(pid.0, caps, token)?;
+    unmount(target)
+}
+
+/// Unmounts whatever filesystem is mounted at `target`
+pub fn unmount(target: &str) -> Result<(), vfs::VfsError> {
+    splaxfs::unmount(target)
+        .map_err(vfs::VfsError::from)
+        .or_else(|e| fat32::unmount(target).map_err(|_| e))
+        .or_else(|e| ext4::unmount(target).map_err(|_| e))
+        .or_else(|e| exfat::unmount(target).map_err(|_| e))
+        .or_else(|e| iso9660::unmount(target).map_err(|_| e))
+        .or_else(|e| squashfs::unmount(target).map_err(|_| e))
+        .or_else(|e| virtio_9p::unmount(target).map_err(|_| e))
+        // Bind mounts and other mounts made on the VFS directly
+        .or_else(|e| vfs::VFS.unmount(target).map_err(|_| e))
+}
@@ -0,0 +1,542 @@
+//! # Mount Namespaces
+//!
+//! Each process sees the mount table of its mount namespace. Processes
+//! start in the initial namespace, children inherit their parent's, and a
+//! process can move to a private copy of its namespace (`unshare`), for
+//! instance to give a container its own root with [`MountNamespaces::pivot_root`].
+//!
+//! ## Propagation
+//!
+//! Mounts are private, shared or slaves, as on Linux:
+//!
+//! - A shared mount belongs to a peer group. Mounting or unmounting below
+//!   it does the same below its peers (in any namespace) and its slaves.
+//! - A slave receives mount events from its master's peer group but sends
+//!   none back.
+//! - A copied namespace keeps the propagation of the mounts it copies, so
+//!   its shared mounts are peers of the originals.
+//! - A mount made below a shared mount is shared in a new peer group, with
+//!   its propagated copies as peers (or slaves, in receiving slaves).
+//!
+//! Bind mounts share the filesystem instance, and so the mount ID that
+//! keys the page cache, watches and locks, with the mount they come from.
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::RwLock;
+
+use super::vfs::{MountPoint, VfsError, MAX_MOUNTS};
+
+/// Mount namespace identifier
+pub type MountNsId = u64;
+
+/// The namespace processes start in
+pub const INIT_NS: MountNsId = 0;
+
+/// Propagation type to give a mount
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Propagation {
+    /// Neither sends nor receives mount events
+    Private,
+    /// Sends mount events to its peers and slaves, and receives theirs
+    Shared,
+    /// Receives mount events from the peer group it was part of
+    Slave,
+}
+
+/// Mount table of a namespace other than the initial one
+struct Namespace {
+    mounts: Vec<Arc<MountPoint>>,
+    /// Processes in the namespace
+    members: usize,
+}
+
+struct State {
+    /// Mount table of the initial namespace
+    init: Vec<Arc<MountPoint>>,
+    namespaces: BTreeMap<MountNsId, Namespace>,
+    /// Namespace of each process not in the initial one
+    processes: BTreeMap<u64, MountNsId>,
+    next_ns: MountNsId,
+    next_group: u64,
+}
+
+impl State {
+    fn table(&self, ns: MountNsId) -> &Vec<Arc<MountPoint>> {
+        self.namespaces.get(&ns).map_or(&self.init, |n| &n.mounts)
+    }
+
+    fn table_mut(&mut self, ns: MountNsId) -> &mut Vec<Arc<MountPoint>> {
+        match self.namespaces.get_mut(&ns) {
+            Some(n) => &mut n.mounts,
+            None => &mut self.init,
+        }
+    }
+
+    /// Every mount, with its namespace
+    fn all(&self) -> impl Iterator<Item = (MountNsId, &Arc<MountPoint>)> {
+        let init = self.init.iter().map(|m| (INIT_NS, m));
+        init.chain(self.namespaces.iter().flat_map(|(&ns, n)| n.mounts.iter().map(move |m| (ns, m))))
+    }
+
+    /// Mount `path` is on, and the path within it
+    fn find(&self, ns: MountNsId, path: &str) -> Option<(Arc<MountPoint>, String)> {
+        self.table(ns)
+            .iter()
+            .find_map(|m| relative(path, &m.path).map(|rel| (m.clone(), rel)))
+    }
+
+    /// Adds a mount to a table, longest paths first
+    fn insert(&mut self, ns: MountNsId, mount: MountPoint) -> Result<(), VfsError> {
+        let table = self.table_mut(ns);
+        if table.len() >= MAX_MOUNTS {
+            return Err(VfsError::NoSpace);
+        }
+        if table.iter().any(|m| m.path == mount.path) {
+            return Err(VfsError::AlreadyExists);
+        }
+        table.push(Arc::new(mount));
+        table.sort_by(|a, b| b.path.len().cmp(&a.path.len()));
+        Ok(())
+    }
+
+    fn new_group(&mut self) -> u64 {
+        self.next_group += 1;
+        self.next_group
+    }
+
+    /// Mounts receiving the events of a peer group: its members, then
+    /// slaves of it and, transitively, their peer groups. The flag tells
+    /// the members of the group itself.
+    fn receivers(&self, group: u64) -> Vec<(MountNsId, Arc<MountPoint>, bool)> {
+        let mut out: Vec<(MountNsId, Arc<MountPoint>, bool)> = Vec::new();
+        let mut seen = BTreeSet::from([group]);
+        let mut pending = Vec::from([(group, true)]);
+        while let Some((group, direct)) = pending.pop() {
+            for (ns, mount) in self.all() {
+                if out.iter().any(|(_, m, _)| Arc::ptr_eq(m, mount)) {
+                    continue;
+                }
+                if mount.peer_group == Some(group) {
+                    out.push((ns, mount.clone(), direct));
+                } else if mount.master == Some(group) {
+                    out.push((ns, mount.clone(), false));
+                    if let Some(own) = mount.peer_group {
+                        if seen.insert(own) {
+                            pending.push((own, false));
+                        }
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    /// Where `path`, below `parent`, is in each mount receiving the
+    /// events of `parent`
+    fn propagation_targets(&self, parent: &Arc<MountPoint>, rel: &str) -> Vec<(MountNsId, String, bool)> {
+        let Some(group) = parent.peer_group else {
+            return Vec::new();
+        };
+        let fs_path = join(&parent.root_path, rel);
+        self.receivers(group)
+            .into_iter()
+            .filter(|(_, receiver, _)| !Arc::ptr_eq(receiver, parent) && receiver.id == parent.id)
+            .filter_map(|(ns, receiver, peer)| {
+                let rel = relative(&fs_path, &receiver.root_path)?;
+                Some((ns, join(&receiver.path, &rel), peer))
+            })
+            .collect()
+    }
+
+    fn leave(&mut self, pid: u64) -> Vec<Arc<MountPoint>> {
+        let Some(ns) = self.processes.remove(&pid) else {
+            return Vec::new();
+        };
+        let namespace = self.namespaces.get_mut(&ns).expect("process in a missing namespace");
+        namespace.members -= 1;
+        if namespace.members > 0 {
+            return Vec::new();
+        }
+        self.namespaces.remove(&ns).map(|n| n.mounts).unwrap_or_default()
+    }
+}
+
+/// Mount namespaces and the namespace of each process
+pub struct MountNamespaces {
+    state: RwLock<State>,
+}
+
+impl MountNamespaces {
+    /// Creates the initial namespace, empty.
+    pub const fn new() -> Self {
+        Self {
+            state: RwLock::new(State {
+                init: Vec::new(),
+                namespaces: BTreeMap::new(),
+                processes: BTreeMap::new(),
+                next_ns: INIT_NS + 1,
+                next_group: 0,
+            }),
+        }
+    }
+
+    /// Namespace of a process
+    pub fn of(&self, pid: u64) -> MountNsId {
+        self.state.read().processes.get(&pid).copied().unwrap_or(INIT_NS)
+    }
+
+    /// Mounts of a namespace, longest paths first
+    pub fn mounts(&self, ns: MountNsId) -> Vec<Arc<MountPoint>> {
+        self.state.read().table(ns).clone()
+    }
+
+    /// Mounts of every namespace
+    pub fn all_mounts(&self) -> Vec<Arc<MountPoint>> {
+        self.state.read().all().map(|(_, m)| m.clone()).collect()
+    }
+
+    /// Whether a filesystem instance is mounted anywhere
+    pub fn in_use(&self, id: u64) -> bool {
+        self.state.read().all().any(|(_, m)| m.id == id)
+    }
+
+    /// Mount `path` is on in a namespace, and the path within it (`/` for
+    /// the mount point itself)
+    pub fn find(&self, ns: MountNsId, path: &str) -> Option<(Arc<MountPoint>, String)> {
+        self.state.read().find(ns, path)
+    }
+
+    /// Mount at exactly `path` in a namespace
+    pub fn mount_at(&self, ns: MountNsId, path: &str) -> Option<Arc<MountPoint>> {
+        self.state.read().table(ns).iter().find(|m| m.path == path).cloned()
+    }
+
+    /// Adds a mount to a namespace, and to the namespaces the mount below
+    /// it propagates to. Copies are skipped where their path is taken.
+    pub fn add(&self, ns: MountNsId, mut mount: MountPoint) -> Result<(), VfsError> {
+        let mut state = self.state.write();
+        let targets = match state.find(ns, &mount.path) {
+            Some((parent, rel)) => state.propagation_targets(&parent, &rel),
+            None => Vec::new(),
+        };
+        if !targets.is_empty() && mount.peer_group.is_none() {
+            mount.peer_group = Some(state.new_group());
+        }
+        state.insert(ns, mount.clone())?;
+        for (target_ns, path, peer) in targets {
+            let copy = MountPoint {
+                path,
+                peer_group: if peer { mount.peer_group } else { None },
+                master: if peer { mount.master } else { mount.peer_group },
+                ..mount.clone()
+            };
+            let _ = state.insert(target_ns, copy);
+        }
+        Ok(())
+    }
+
+    /// Removes the mount at `path` from a namespace, and its propagated
+    /// copies; returns what was removed.
+    pub fn remove(&self, ns: MountNsId, path: &str) -> Result<Vec<Arc<MountPoint>>, VfsError> {
+        let mut state = self.state.write();
+        let table = state.table_mut(ns);
+        let pos = table.iter().position(|m| m.path == path).ok_or(VfsError::NotFound)?;
+        let mount = table.remove(pos);
+        let targets = match state.find(ns, path) {
+            Some((parent, rel)) => state.propagation_targets(&parent, &rel),
+            None => Vec::new(),
+        };
+
+        let mut removed = Vec::from([mount.clone()]);
+        for (target_ns, path, _) in targets {
+            let table = state.table_mut(target_ns);
+            let copy = table
+                .iter()
+                .position(|m| m.path == path && m.id == mount.id && m.root_ino == mount.root_ino);
+            if let Some(pos) = copy {
+                removed.push(table.remove(pos));
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Changes the propagation of the mount at `path` and, if
+    /// `recursive`, of the mounts below it. A private mount made a slave
+    /// stays private, having no master.
+    pub fn set_propagation(
+        &self,
+        ns: MountNsId,
+        path: &str,
+        propagation: Propagation,
+        recursive: bool,
+    ) -> Result<(), VfsError> {
+        let mut state = self.state.write();
+        if !state.table(ns).iter().any(|m| m.path == path) {
+            return Err(VfsError::NotFound);
+        }
+        let selected: Vec<usize> = state
+            .table(ns)
+            .iter()
+            .enumerate()
+            .filter(|(_, m)| m.path == path || (recursive && relative(&m.path, path).is_some()))
+            .map(|(i, _)| i)
+            .collect();
+        for i in selected {
+            let mut mount = MountPoint::clone(&state.table(ns)[i]);
+            match propagation {
+                Propagation::Private => {
+                    mount.peer_group = None;
+                    mount.master = None;
+                }
+                Propagation::Shared => {
+                    if mount.peer_group.is_none() {
+                        mount.peer_group = Some(state.new_group());
+                    }
+                }
+                Propagation::Slave => {
+                    if let Some(group) = mount.peer_group.take() {
+                        mount.master = Some(group);
+                    }
+                }
+            }
+            state.table_mut(ns)[i] = Arc::new(mount);
+        }
+        Ok(())
+    }
+
+    /// Moves a process to a new namespace holding a copy of its current
+    /// one; returns the new namespace and the mounts of a namespace the
+    /// process was the last to leave.
+    pub fn unshare(&self, pid: u64) -> (MountNsId, Vec<Arc<MountPoint>>) {
+        let mut state = self.state.write();
+        let old = state.processes.get(&pid).copied().unwrap_or(INIT_NS);
+        // New Arcs: the copies are mounts of their own, peers of shared
+        // originals
+        let mounts = state.table(old).iter().map(|m| Arc::new(MountPoint::clone(m))).collect();
+        let released = state.leave(pid);
+
+        let ns = state.next_ns;
+        state.next_ns += 1;
+        state.namespaces.insert(ns, Namespace { mounts, members: 1 });
+        state.processes.insert(pid, ns);
+        (ns, released)
+    }
+
+    /// Puts a new process in its parent's namespace
+    pub fn inherit(&self, parent: u64, child: u64) {
+        let mut state = self.state.write();
+        let Some(&ns) = state.processes.get(&parent) else {
+            return;
+        };
+        if state.processes.insert(child, ns) != Some(ns) {
+            state.namespaces.get_mut(&ns).expect("process in a missing namespace").members += 1;
+        }
+    }
+
+    /// Removes an exited process from its namespace; returns the mounts of
+    /// the namespace if it was the last member.
+    pub fn leave(&self, pid: u64) -> Vec<Arc<MountPoint>> {
+        self.state.write().leave(pid)
+    }
+
+    /// Makes the mount at `new_root` the root of a namespace and moves the
+    /// old root to `put_old`, a path below the new root.
+    pub fn pivot_root(&self, ns: MountNsId, new_root: &str, put_old: &str) -> Result<(), VfsError> {
+        let mut state = self.state.write();
+        let table = state.table(ns);
+        if new_root == "/" || !table.iter().any(|m| m.path == new_root) {
+            return Err(VfsError::InvalidArgument);
+        }
+        let put_old = relative(put_old, new_root).ok_or(VfsError::InvalidArgument)?;
+
+        let mut moved: Vec<Arc<MountPoint>> = Vec::with_capacity(table.len());
+        for mount in table {
+            let path = match relative(&mount.path, new_root) {
+                Some(rel) => rel,
+                None => join(&put_old, &mount.path),
+            };
+            if moved.iter().any(|m| m.path == path) {
+                return Err(VfsError::Busy);
+            }
+            moved.push(Arc::new(MountPoint { path, ..MountPoint::clone(mount) }));
+        }
+        moved.sort_by(|a, b| b.path.len().cmp(&a.path.len()));
+        *state.table_mut(ns) = moved;
+        Ok(())
+    }
+}
+
+impl Default for MountNamespaces {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `path` relative to `base`, as an absolute path (`/` for `base`
+/// itself), if it is `base` or below it
+pub fn relative(path: &str, base: &str) -> Option<String> {
+    if base == "/" {
+        return Some(String::from(path));
+    }
+    match path.strip_prefix(base)? {
+        "" => Some(String::from("/")),
+        rest if rest.starts_with('/') => Some(String::from(rest)),
+        _ => None,
+    }
+}
+
+/// `rel`, an absolute path, placed below `base`
+pub fn join(base: &str, rel: &str) -> String {
+    match (base, rel) {
+        (_, "/") => String::from(base),
+        ("/", _) => String::from(rel),
+        _ => alloc::format!("{}{}", base, rel),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cap::{CapabilityTable, Operations, ResourceId};
+    use crate::fs::ramfs;
+    use crate::fs::vfs::{OpenFlags, Vfs, VfsFileType};
+    use crate::fs::xattr::MOUNT_NS_RESOURCE;
+    use crate::sched::ProcessId;
+
+    #[test]
+    fn test_paths() {
+        assert_eq!(relative("/a/b", "/a").as_deref(), Some("/b"));
+        assert_eq!(relative("/a", "/a").as_deref(), Some("/"));
+        assert_eq!(relative("/ab", "/a"), None);
+        assert_eq!(relative("/a", "/").as_deref(), Some("/a"));
+        assert_eq!(join("/", "/a"), "/a");
+        assert_eq!(join("/old", "/"), "/old");
+        assert_eq!(join("/old", "/mnt"), "/old/mnt");
+    }
+
+    #[test]
+    fn test_namespaces() {
+        let vfs = Vfs::new();
+        vfs.mount("/", ramfs::new(1 << 20), false).unwrap();
+        vfs.mkdir("/mnt").unwrap();
+        vfs.mkdir("/data").unwrap();
+        let (parent, child) = (0x600, 0x601);
+
+        // A private copy: mounts made in it stay there
+        vfs.namespaces().unshare(parent);
+        vfs.namespaces().inherit(parent, child);
+        assert_eq!(vfs.mount_namespace(child), vfs.mount_namespace(parent));
+        vfs.bind_mount(parent, "/data", "/mnt", false, false).unwrap();
+        assert_eq!(vfs.namespaces().mounts(INIT_NS).len(), 1);
+        assert_eq!(vfs.namespaces().mounts(vfs.mount_namespace(child)).len(), 2);
+
+        // The namespace goes away with its last process
+        vfs.leave_namespace(parent);
+        vfs.leave_namespace(child);
+        assert_eq!(vfs.mount_namespace(child), INIT_NS);
+        assert_eq!(vfs.namespaces().all_mounts().len(), 1);
+    }
+
+    #[test]
+    fn test_propagation() {
+        let vfs = Vfs::new();
+        vfs.mount("/", ramfs::new(1 << 20), false).unwrap();
+        vfs.mkdir("/mnt").unwrap();
+        vfs.mkdir("/srv").unwrap();
+        vfs.mkdir("/srv/www").unwrap();
+        let (peer, slave) = (0x610, 0x611);
+        vfs.set_propagation(0, "/", Propagation::Shared, false).unwrap();
+        vfs.namespaces().unshare(peer);
+        vfs.namespaces().unshare(slave);
+        vfs.set_propagation(slave, "/", Propagation::Slave, false).unwrap();
+
+        // Mounts below the shared root reach its peer and its slave...
+        vfs.mount("/mnt", ramfs::new(1 << 16), false).unwrap();
+        let paths = |pid| -> Vec<String> {
+            vfs.namespaces().mounts(vfs.mount_namespace(pid)).iter().map(|m| m.path.clone()).collect()
+        };
+        assert_eq!(paths(peer), ["/mnt", "/"]);
+        assert_eq!(paths(slave), ["/mnt", "/"]);
+        let copy = vfs.namespaces().mount_at(vfs.mount_namespace(slave), "/mnt").unwrap();
+        assert_eq!((copy.peer_group, copy.master.is_some()), (None, true));
+
+        // ...but a slave's do not come back
+        vfs.bind_mount(slave, "/srv/www", "/srv", false, false).unwrap();
+        assert_eq!(paths(peer), ["/mnt", "/"]);
+        assert_eq!(paths(slave), ["/mnt", "/srv", "/"]);
+
+        vfs.unmount("/mnt").unwrap();
+        assert_eq!(paths(peer), ["/"]);
+        assert_eq!(paths(slave), ["/srv", "/"]);
+    }
+
+    #[test]
+    fn test_bind_mounts() {
+        let vfs = Vfs::new();
+        vfs.mount("/", ramfs::new(1 << 20), false).unwrap();
+        for dir in ["/src", "/src/sub", "/dst", "/ro"] {
+            vfs.mkdir(dir).unwrap();
+        }
+        vfs.mount("/src/sub", ramfs::new(1 << 16), false).unwrap();
+        let flags = OpenFlags { read: true, write: true, create: true, ..OpenFlags::default() };
+        let fd = vfs.open(0, "/src/sub/f", flags).unwrap();
+        vfs.write(0, fd, b"inner").unwrap();
+        vfs.close(0, fd).unwrap();
+        let fd = vfs.open(0, "/file", flags).unwrap();
+        vfs.close(0, fd).unwrap();
+        let fd = vfs.open(0, "/dst/file", flags).unwrap();
+        vfs.close(0, fd).unwrap();
+
+        // A recursive bind brings the mounts below the source along
+        vfs.bind_mount(0, "/src", "/dst", false, true).unwrap();
+        assert_eq!(vfs.stat("/dst/sub/f").unwrap().size, 5);
+        assert_eq!(vfs.stat("/dst/sub").unwrap().ino, vfs.stat("/src/sub").unwrap().ino);
+        assert!(vfs.stat("/dst/file").is_err());
+
+        // Read-only binds refuse changes; files bind onto files
+        vfs.bind_mount(0, "/src", "/ro", true, false).unwrap();
+        assert_eq!(vfs.mkdir("/ro/new"), Err(VfsError::ReadOnlyFs));
+        assert_eq!(vfs.open(0, "/ro/new", flags), Err(VfsError::ReadOnlyFs));
+        vfs.bind_mount(0, "/src/sub/f", "/file", false, false).unwrap();
+        assert_eq!(vfs.stat("/file").unwrap().size, 5);
+        assert_eq!(vfs.bind_mount(0, "/file", "/dst/sub", false, false), Err(VfsError::NotADirectory));
+        assert_eq!(vfs.stat("/ro").unwrap().file_type, VfsFileType::Directory);
+
+        vfs.unmount("/file").unwrap();
+        assert_eq!(vfs.stat("/file").unwrap().size, 0);
+    }
+
+    #[test]
+    fn test_pivot_root() {
+        let vfs = Vfs::new();
+        vfs.mount("/", ramfs::new(1 << 20), false).unwrap();
+        vfs.mkdir("/rootfs").unwrap();
+        vfs.mount("/rootfs", ramfs::new(1 << 16), false).unwrap();
+        vfs.mkdir("/rootfs/old").unwrap();
+        let pid = 0x620;
+
+        let table = CapabilityTable::new(16);
+        let admin = table.create_root(ProcessId::new(pid), ResourceId::new(MOUNT_NS_RESOURCE, 0), Operations::ALL).unwrap();
+        // Creating a namespace needs the capability
+        let other = table.create_root(ProcessId::new(pid), ResourceId::new("net", 0), Operations::ALL).unwrap();
+        assert_eq!(vfs.unshare_mounts(pid, &table, other), Err(VfsError::PermissionDenied));
+        assert_eq!(vfs.unshare_mounts(pid + 1, &table, admin), Err(VfsError::PermissionDenied));
+        assert_eq!(vfs.unshare_mounts(pid, &table, admin), Ok(1));
+        // So does mounting from a syscall
+        assert_eq!(vfs.check_mount_cap(pid, &table, other), Err(VfsError::PermissionDenied));
+        assert_eq!(vfs.check_mount_cap(pid, &table, admin), Ok(()));
+        // So does switching the root
+        assert_eq!(vfs.pivot_root(pid, "/rootfs", "/rootfs/old", &table, other), Err(VfsError::PermissionDenied));
+        vfs.pivot_root(pid, "/rootfs", "/rootfs/old", &table, admin).unwrap();
+        let ns = vfs.mount_namespace(pid);
+        let paths: Vec<String> = vfs.namespaces().mounts(ns).iter().map(|m| m.path.clone()).collect();
+        assert_eq!(paths, ["/old", "/"]);
+        assert_eq!(vfs.pivot_root(pid, "/", "/old", &table, admin), Err(VfsError::InvalidArgument));
+        assert_eq!(vfs.pivot_root(pid, "/missing", "/old", &table, admin), Err(VfsError::InvalidArgument));
+        // The initial namespace is untouched
+        assert_eq!(vfs.namespaces().mounts(INIT_NS).len(), 2);
+    }
+}
@@ -0,0 +1,688 @@
+//! # Filesystem Change Notifications
+//!
+//! Watches on files and directories, reporting changes made through the
+//! VFS to the watching process over an IPC channel, like Linux inotify.
+//!
+//! ## Design
+//!
+//! ```text
+//!   Vfs::write/mkdir/rename/... ──► Notifier ──► group queue ──► IPC channel ──► process
+//!                                    (watches by mount and inode)   (bounded)
+//! ```
+//!
+//! - A process creates a watch group, receiving the IPC channel its events
+//!   arrive on, then adds watches to it. Each watch has a descriptor, unique
+//!   within its group, and an event mask.
+//! - Watches are keyed by mount and inode, not by path, so they follow
+//!   renames and hard links, and work the same on every filesystem type.
+//! - A watched directory also reports events on its entries, with their
+//!   names. Modifications of a file are reported to its directory only if
+//!   it was opened while watches existed, as the VFS records where a file
+//!   was opened from only then.
+//! - The two halves of a rename share a cookie.
+//! - Each group queues at most [`MAX_QUEUED_EVENTS`] events; the kernel
+//!   moves them into the channel as it drains. Past the limit events are
+//!   dropped and a single [`WatchMask::OVERFLOW`] event is queued.
+//! - Adding a watch requires the same capabilities as opening the path.
+//!
+//! ## Wire format
+//!
+//! Each IPC message carries one or more events, each encoded as
+//! `wd: u32, mask: u32, cookie: u32, name_len: u32, name` (little-endian).
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use spin::Mutex;
+
+use super::vfs::{InodeNum, VfsError};
+use crate::cap::CapabilityToken;
+use crate::ipc::{ChannelId, IpcError, Message, MessageData, IPC_MANAGER};
+use crate::sched::ProcessId;
+
+/// Events a watch reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchMask {
+    bits: u32,
+}
+
+impl WatchMask {
+    /// No events
+    pub const NONE: Self = Self { bits: 0 };
+    /// File written or truncated
+    pub const MODIFY: Self = Self { bits: 0x0002 };
+    /// Metadata (extended attributes, labels, encryption) changed
+    pub const ATTRIB: Self = Self { bits: 0x0004 };
+    /// Entry moved out of a watched directory
+    pub const MOVED_FROM: Self = Self { bits: 0x0040 };
+    /// Entry moved into a watched directory
+    pub const MOVED_TO: Self = Self { bits: 0x0080 };
+    /// Entry created in a watched directory
+    pub const CREATE: Self = Self { bits: 0x0100 };
+    /// Entry removed from a watched directory
+    pub const DELETE: Self = Self { bits: 0x0200 };
+    /// The watched file or directory itself was removed
+    pub const DELETE_SELF: Self = Self { bits: 0x0400 };
+    /// The filesystem holding the watch was unmounted
+    pub const UNMOUNT: Self = Self { bits: 0x2000 };
+    /// Events were dropped because the queue was full (always reported)
+    pub const OVERFLOW: Self = Self { bits: 0x4000 };
+    /// The watch was removed (always reported)
+    pub const IGNORED: Self = Self { bits: 0x8000 };
+    /// The entry of the event is a directory
+    pub const IS_DIR: Self = Self { bits: 0x4000_0000 };
+    /// Both halves of a rename
+    pub const MOVE: Self = Self { bits: 0x00C0 };
+    /// Every event a watch can ask for
+    pub const ALL: Self = Self { bits: 0x27C6 };
+
+    /// Mask from raw bits, keeping only events a watch can ask for
+    pub const fn from_bits(bits: u32) -> Self {
+        Self { bits: bits & Self::ALL.bits }
+    }
+
+    /// Raw bits
+    pub const fn bits(self) -> u32 {
+        self.bits
+    }
+
+    /// Combines two masks.
+    pub const fn union(self, other: Self) -> Self {
+        Self { bits: self.bits | other.bits }
+    }
+
+    /// Checks if this mask contains all events in `other`.
+    pub const fn contains(self, other: Self) -> bool {
+        (self.bits & other.bits) == other.bits
+    }
+
+    /// Checks if this mask shares any event with `other`.
+    pub const fn intersects(self, other: Self) -> bool {
+        (self.bits & other.bits) != 0
+    }
+
+    /// Checks if this mask is empty.
+    pub const fn is_empty(self) -> bool {
+        self.bits == 0
+    }
+}
+
+/// Watch descriptor, unique within its group
+pub type WatchDescriptor = u32;
+
+/// Descriptor of events not about one watch ([`WatchMask::OVERFLOW`])
+pub const NO_WATCH: WatchDescriptor = u32::MAX;
+
+/// Watch group identifier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct GroupId(pub u64);
+
+/// Events queued per group before they are dropped
+pub const MAX_QUEUED_EVENTS: usize = 256;
+
+/// Watches per group
+pub const MAX_WATCHES: usize = 1024;
+
+/// Largest batch of events per IPC message, in bytes
+const MAX_BATCH: usize = 4096;
+
+/// A change to a watched file or directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchEvent {
+    /// Watch reporting the event
+    pub wd: WatchDescriptor,
+    /// What happened
+    pub mask: WatchMask,
+    /// Shared by the two halves of a rename, else 0
+    pub cookie: u32,
+    /// Entry name, for events on a directory's entries
+    pub name: String,
+}
+
+impl WatchEvent {
+    /// Appends the event in wire format.
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.wd.to_le_bytes());
+        out.extend_from_slice(&self.mask.bits.to_le_bytes());
+        out.extend_from_slice(&self.cookie.to_le_bytes());
+        out.extend_from_slice(&(self.name.len() as u32).to_le_bytes());
+        out.extend_from_slice(self.name.as_bytes());
+    }
+
+    /// Size in wire format
+    fn encoded_len(&self) -> usize {
+        16 + self.name.len()
+    }
+
+    /// Decodes every event of a message.
+    pub fn decode_all(mut data: &[u8]) -> Result<Vec<WatchEvent>, VfsError> {
+        let field = |data: &[u8], at: usize| u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]]);
+        let mut events = Vec::new();
+        while !data.is_empty() {
+            if data.len() < 16 {
+                return Err(VfsError::InvalidArgument);
+            }
+            let len = field(data, 12) as usize;
+            let name = data.get(16..16 + len).ok_or(VfsError::InvalidArgument)?;
+            events.push(WatchEvent {
+                wd: field(data, 0),
+                mask: WatchMask { bits: field(data, 4) },
+                cookie: field(data, 8),
+                name: String::from_utf8_lossy(name).into(),
+            });
+            data = &data[16 + len..];
+        }
+        Ok(events)
+    }
+}
+
+/// A watch on one inode.
+#[derive(Debug, Clone, Copy)]
+struct Watch {
+    group: GroupId,
+    wd: WatchDescriptor,
+    mask: WatchMask,
+}
+
+/// A process's watches and the queue of their events.
+struct Group {
+    owner: ProcessId,
+    channel: ChannelId,
+    token: CapabilityToken,
+    next_wd: WatchDescriptor,
+    /// Watched inode of each descriptor
+    watches: BTreeMap<WatchDescriptor, (u64, InodeNum)>,
+    queue: VecDeque<WatchEvent>,
+    /// An overflow event is queued and not yet delivered
+    overflowed: bool,
+}
+
+impl Group {
+    /// Queues an event, merging it with an identical one at the back.
+    fn push(&mut self, event: WatchEvent) {
+        if self.queue.back() == Some(&event) {
+            return;
+        }
+        if self.queue.len() >= MAX_QUEUED_EVENTS {
+            if !self.overflowed {
+                self.overflowed = true;
+                self.queue.push_back(WatchEvent {
+                    wd: NO_WATCH,
+                    mask: WatchMask::OVERFLOW,
+                    cookie: 0,
+                    name: String::new(),
+                });
+            }
+            return;
+        }
+        self.queue.push_back(event);
+    }
+
+    /// Moves queued events into the channel until it is full.
+    fn flush(&mut self) -> Result<(), IpcError> {
+        while !self.queue.is_empty() {
+            let mut data = Vec::new();
+            let mut count = 0;
+            for event in self.queue.iter() {
+                if count > 0 && data.len() + event.encoded_len() > MAX_BATCH {
+                    break;
+                }
+                event.encode(&mut data);
+                count += 1;
+            }
+            let message = Message::inline(ProcessId::KERNEL, data);
+            match IPC_MANAGER.send(self.channel, ProcessId::KERNEL, message, &self.token) {
+                Ok(()) => {
+                    for event in self.queue.drain(..count) {
+                        if event.mask.contains(WatchMask::OVERFLOW) {
+                            self.overflowed = false;
+                        }
+                    }
+                }
+                Err(IpcError::BufferFull) => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct State {
+    groups: BTreeMap<GroupId, Group>,
+    /// Watches by mount ID and inode
+    watches: BTreeMap<(u64, InodeNum), Vec<Watch>>,
+    next_group: u64,
+}
+
+impl State {
+    fn group(&mut self, group: GroupId, owner: ProcessId) -> Result<&mut Group, VfsError> {
+        match self.groups.get_mut(&group) {
+            Some(g) if g.owner == owner => Ok(g),
+            Some(_) => Err(VfsError::PermissionDenied),
+            None => Err(VfsError::BadFd),
+        }
+    }
+
+    /// Removes a watch, reporting `reason` and then [`WatchMask::IGNORED`].
+    fn drop_watch(&mut self, key: (u64, InodeNum), watch: Watch, reason: WatchMask) {
+        let Some(group) = self.groups.get_mut(&watch.group) else {
+            return;
+        };
+        group.watches.remove(&watch.wd);
+        if !reason.is_empty() && watch.mask.intersects(reason) {
+            group.push(WatchEvent { wd: watch.wd, mask: reason, cookie: 0, name: String::new() });
+        }
+        group.push(WatchEvent { wd: watch.wd, mask: WatchMask::IGNORED, cookie: 0, name: String::new() });
+        if let Some(list) = self.watches.get_mut(&key) {
+            list.retain(|w| !(w.group == watch.group && w.wd == watch.wd));
+            if list.is_empty() {
+                self.watches.remove(&key);
+            }
+        }
+    }
+
+    /// Delivers what the groups queued; groups whose channel is gone are
+    /// closed.
+    fn flush(&mut self, groups: &[GroupId]) {
+        let dead: Vec<GroupId> = groups
+            .iter()
+            .filter(|id| self.groups.get_mut(id).is_some_and(|group| group.flush().is_err()))
+            .copied()
+            .collect();
+        for id in dead {
+            self.close(id);
+        }
+    }
+
+    fn close(&mut self, id: GroupId) -> Option<Group> {
+        let group = self.groups.remove(&id)?;
+        for key in group.watches.values() {
+            if let Some(list) = self.watches.get_mut(key) {
+                list.retain(|w| w.group != id);
+                if list.is_empty() {
+                    self.watches.remove(key);
+                }
+            }
+        }
+        Some(group)
+    }
+}
+
+/// Watch registry of a VFS.
+pub struct Notifier {
+    state: Mutex<Option<State>>,
+    /// Watches in place, so changes with none skip the lock
+    active: AtomicUsize,
+    next_cookie: AtomicU32,
+}
+
+impl Notifier {
+    /// Creates an empty registry.
+    pub const fn new() -> Self {
+        Self {
+            state: Mutex::new(None),
+            active: AtomicUsize::new(0),
+            next_cookie: AtomicU32::new(1),
+        }
+    }
+
+    /// Whether any watch is in place.
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Relaxed) > 0
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&mut State) -> R) -> R {
+        let mut state = self.state.lock();
+        let state = state.get_or_insert_with(State::default);
+        let result = f(state);
+        let count = state.watches.values().map(Vec::len).sum();
+        self.active.store(count, Ordering::Relaxed);
+        result
+    }
+
+    /// Creates a watch group for `owner`; its events arrive on the
+    /// returned channel.
+    pub fn create_group(&self, owner: ProcessId, token: CapabilityToken) -> Result<(GroupId, ChannelId), VfsError> {
+        let channel = IPC_MANAGER
+            .create_channel(ProcessId::KERNEL, owner, &token)
+            .map_err(|_| VfsError::TooManyOpenFiles)?;
+        let id = self.with(|state| {
+            state.next_group += 1;
+            let id = GroupId(state.next_group);
+            state.groups.insert(id, Group {
+                owner,
+                channel,
+                token,
+                next_wd: 1,
+                watches: BTreeMap::new(),
+                queue: VecDeque::new(),
+                overflowed: false,
+            });
+            id
+        });
+        Ok((id, channel))
+    }
+
+    /// Removes a group, its watches and its channel.
+    pub fn close_group(&self, group: GroupId, owner: ProcessId) -> Result<(), VfsError> {
+        let group = self.with(|state| {
+            state.group(group, owner)?;
+            Ok::<_, VfsError>(state.close(group))
+        })?;
+        if let Some(group) = group {
+            let _ = IPC_MANAGER.close(group.channel, ProcessId::KERNEL, &group.token);
+        }
+        Ok(())
+    }
+
+    /// Watches an inode. Watching it again from the same group replaces
+    /// the mask and keeps the descriptor.
+    pub fn add_watch(
+        &self,
+        group: GroupId,
+        owner: ProcessId,
+        mount: u64,
+        ino: InodeNum,
+        mask: WatchMask,
+    ) -> Result<WatchDescriptor, VfsError> {
+        if mask.is_empty() {
+            return Err(VfsError::InvalidArgument);
+        }
+        self.with(|state| {
+            let g = state.group(group, owner)?;
+            let existing = g.watches.iter().find(|(_, &key)| key == (mount, ino)).map(|(&wd, _)| wd);
+            let wd = match existing {
+                Some(wd) => wd,
+                None => {
+                    if g.watches.len() >= MAX_WATCHES {
+                        return Err(VfsError::NoSpace);
+                    }
+                    let wd = g.next_wd;
+                    g.next_wd += 1;
+                    g.watches.insert(wd, (mount, ino));
+                    wd
+                }
+            };
+            let list = state.watches.entry((mount, ino)).or_default();
+            match list.iter_mut().find(|w| w.group == group) {
+                Some(watch) => watch.mask = mask,
+                None => list.push(Watch { group, wd, mask }),
+            }
+            Ok(wd)
+        })
+    }
+
+    /// Removes a watch; the group receives [`WatchMask::IGNORED`].
+    pub fn remove_watch(&self, group: GroupId, owner: ProcessId, wd: WatchDescriptor) -> Result<(), VfsError> {
+        self.with(|state| {
+            let key = *state.group(group, owner)?.watches.get(&wd).ok_or(VfsError::InvalidArgument)?;
+            let watch = Watch { group, wd, mask: WatchMask::NONE };
+            state.drop_watch(key, watch, WatchMask::NONE);
+            state.flush(&[group]);
+            Ok(())
+        })
+    }
+
+    /// Receives the events waiting in a group's channel; empty if there
+    /// are none.
+    pub fn read_events(&self, group: GroupId, owner: ProcessId) -> Result<Vec<WatchEvent>, VfsError> {
+        let (channel, token) = self.with(|state| {
+            let g = state.group(group, owner)?;
+            Ok::<_, VfsError>((g.channel, g.token))
+        })?;
+        let mut events = Vec::new();
+        loop {
+            match IPC_MANAGER.receive(channel, owner, &token) {
+                Ok(Message { data: MessageData::Inline(data), .. }) => events.extend(WatchEvent::decode_all(&data)?),
+                Ok(_) => return Err(VfsError::IoError),
+                Err(IpcError::BufferEmpty) => break,
+                Err(_) => return Err(VfsError::BadFd),
+            }
+        }
+        // The channel has room again
+        self.with(|state| state.flush(&[group]));
+        Ok(events)
+    }
+
+    /// A new cookie pairing the halves of a rename.
+    pub fn cookie(&self) -> u32 {
+        self.next_cookie.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Reports an event on an inode to its watches, and on the entry
+    /// `name` of directory `parent` to the directory's watches.
+    pub fn notify(
+        &self,
+        mount: u64,
+        ino: Option<InodeNum>,
+        parent: Option<(InodeNum, &str)>,
+        mask: WatchMask,
+        cookie: u32,
+    ) {
+        if !self.is_active() {
+            return;
+        }
+        let event = WatchMask { bits: mask.bits & !WatchMask::IS_DIR.bits };
+        self.with(|state| {
+            let mut targets = Vec::new();
+            if let Some(ino) = ino {
+                targets.push((ino, ""));
+            }
+            if let Some((dir, name)) = parent {
+                targets.push((dir, name));
+            }
+            let mut touched = Vec::new();
+            for (target, name) in targets {
+                let Some(list) = state.watches.get(&(mount, target)) else {
+                    continue;
+                };
+                for watch in list.iter().filter(|w| w.mask.intersects(event)) {
+                    if let Some(group) = state.groups.get_mut(&watch.group) {
+                        group.push(WatchEvent { wd: watch.wd, mask, cookie, name: name.into() });
+                        touched.push(watch.group);
+                    }
+                }
+            }
+            state.flush(&touched);
+        });
+    }
+
+    /// Reports that an inode is gone and removes its watches.
+    pub fn removed(&self, mount: u64, ino: InodeNum) {
+        if !self.is_active() {
+            return;
+        }
+        self.drop_where(WatchMask::DELETE_SELF, |key| key == (mount, ino));
+    }
+
+    /// Reports that a mount is gone and removes its watches.
+    pub fn unmounted(&self, mount: u64) {
+        if !self.is_active() {
+            return;
+        }
+        self.drop_where(WatchMask::UNMOUNT, |(m, _)| m == mount);
+    }
+
+    fn drop_where(&self, reason: WatchMask, matches: impl Fn((u64, InodeNum)) -> bool) {
+        self.with(|state| {
+            let doomed: Vec<((u64, InodeNum), Watch)> = state
+                .watches
+                .iter()
+                .filter(|(&key, _)| matches(key))
+                .flat_map(|(&key, list)| list.iter().map(move |&w| (key, w)))
+                .collect();
+            let mut touched = Vec::new();
+            for (key, watch) in doomed {
+                state.drop_watch(key, watch, reason);
+                touched.push(watch.group);
+            }
+            state.flush(&touched);
+        });
+    }
+}
+
+impl Default for Notifier {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cap::{CapabilityTable, Operations, ResourceId};
+    use crate::fs::ramfs;
+    use crate::fs::vfs::{OpenFlags, Vfs};
+    use crate::fs::xattr::{CapRequirement, FILE_LABEL_RESOURCE};
+
+    fn token() -> CapabilityToken {
+        CapabilityToken::new([1, 2, 3, 4])
+    }
+
+    fn event(wd: WatchDescriptor, mask: WatchMask, name: &str) -> WatchEvent {
+        WatchEvent { wd, mask, cookie: 0, name: name.into() }
+    }
+
+    /// A VFS with a RamFS root holding `/dir`.
+    fn vfs() -> Vfs {
+        let vfs = Vfs::new();
+        vfs.mount("/", ramfs::new(1 << 20), false).unwrap();
+        vfs.mkdir("/dir").unwrap();
+        vfs
+    }
+
+    #[test]
+    fn test_encoding() {
+        let events = [
+            WatchEvent { wd: 3, mask: WatchMask::MOVED_TO.union(WatchMask::IS_DIR), cookie: 9, name: "new".into() },
+            event(NO_WATCH, WatchMask::OVERFLOW, ""),
+        ];
+        let mut data = Vec::new();
+        for e in &events {
+            e.encode(&mut data);
+        }
+        assert_eq!(data.len(), 16 + 3 + 16);
+        assert_eq!(WatchEvent::decode_all(&data).unwrap(), events);
+        assert_eq!(WatchEvent::decode_all(&data[..20]), Err(VfsError::InvalidArgument));
+        assert_eq!(WatchMask::from_bits(u32::MAX), WatchMask::ALL);
+    }
+
+    #[test]
+    fn test_directory_events() {
+        let vfs = vfs();
+        let pid = ProcessId::new(0x100);
+        let (group, _) = vfs.watch_group(pid, token()).unwrap();
+        let table = CapabilityTable::new(16);
+        let wd = vfs.add_watch(group, pid, "/dir", WatchMask::ALL, &table, &[]).unwrap();
+        assert!(vfs.watches().is_active());
+
+        let flags = OpenFlags { read: true, write: true, create: true, ..OpenFlags::default() };
+        let fd = vfs.open(pid.0, "/dir/a.txt", flags).unwrap();
+        vfs.close(pid.0, fd).unwrap();
+        vfs.mkdir("/dir/sub").unwrap();
+        vfs.setxattr("/dir/a.txt", "user.mime", b"text/plain").unwrap();
+        vfs.rename("/dir/a.txt", "/dir/b.txt").unwrap();
+        vfs.unlink("/dir/b.txt").unwrap();
+        // Outside the watched directory
+        vfs.mkdir("/other").unwrap();
+
+        let events = vfs.read_events(group, pid).unwrap();
+        let cookie = events[3].cookie;
+        assert_ne!(cookie, 0);
+        assert_eq!(events, [
+            event(wd, WatchMask::CREATE, "a.txt"),
+            event(wd, WatchMask::CREATE.union(WatchMask::IS_DIR), "sub"),
+            event(wd, WatchMask::ATTRIB, "a.txt"),
+            WatchEvent { wd, mask: WatchMask::MOVED_FROM, cookie, name: "a.txt".into() },
+            WatchEvent { wd, mask: WatchMask::MOVED_TO, cookie, name: "b.txt".into() },
+            event(wd, WatchMask::DELETE, "b.txt"),
+        ]);
+        assert!(vfs.read_events(group, pid).unwrap().is_empty());
+
+        // Only the owner reads the group
+        assert_eq!(vfs.read_events(group, ProcessId::new(0x101)), Err(VfsError::PermissionDenied));
+        vfs.close_watch_group(group, pid).unwrap();
+        assert!(!vfs.watches().is_active());
+    }
+
+    #[test]
+    fn test_self_events_and_unmount() {
+        let vfs = vfs();
+        vfs.mount("/mnt", ramfs::new(1 << 20), false).unwrap();
+        vfs.mkdir("/mnt/data").unwrap();
+        let pid = ProcessId::new(0x200);
+        let (group, _) = vfs.watch_group(pid, token()).unwrap();
+        let table = CapabilityTable::new(16);
+
+        // Masks filter, and watching again keeps the descriptor
+        let dir = vfs.add_watch(group, pid, "/dir", WatchMask::DELETE_SELF, &table, &[]).unwrap();
+        let data = vfs.add_watch(group, pid, "/mnt/data", WatchMask::CREATE, &table, &[]).unwrap();
+        assert_eq!(vfs.add_watch(group, pid, "/mnt/data", WatchMask::ALL, &table, &[]), Ok(data));
+        vfs.mkdir("/dir/sub").unwrap();
+        vfs.unlink("/dir").unwrap_err();
+        vfs.unlink("/dir/sub").unwrap();
+        vfs.unlink("/dir").unwrap();
+        vfs.unmount("/mnt").unwrap();
+
+        assert_eq!(vfs.read_events(group, pid).unwrap(), [
+            event(dir, WatchMask::DELETE_SELF, ""),
+            event(dir, WatchMask::IGNORED, ""),
+            event(data, WatchMask::UNMOUNT, ""),
+            event(data, WatchMask::IGNORED, ""),
+        ]);
+        assert!(!vfs.watches().is_active());
+        assert_eq!(vfs.remove_watch(group, pid, data), Err(VfsError::InvalidArgument));
+    }
+
+    #[test]
+    fn test_overflow() {
+        let vfs = vfs();
+        let pid = ProcessId::new(0x300);
+        let (group, _) = vfs.watch_group(pid, token()).unwrap();
+        let table = CapabilityTable::new(16);
+        let wd = vfs.add_watch(group, pid, "/dir", WatchMask::CREATE, &table, &[]).unwrap();
+
+        // Far more than the channel and the queue hold
+        let dir = vfs.stat("/dir").unwrap().ino;
+        let count = MAX_QUEUED_EVENTS * 40;
+        for i in 0..count {
+            vfs.watches().notify(1, None, Some((dir, &alloc::format!("f{}", i))), WatchMask::CREATE, 0);
+        }
+        let mut events = Vec::new();
+        loop {
+            let batch = vfs.read_events(group, pid).unwrap();
+            if batch.is_empty() {
+                break;
+            }
+            events.extend(batch);
+        }
+        assert!(events.len() < count);
+        let overflow = events.iter().position(|e| e.mask == WatchMask::OVERFLOW).unwrap();
+        assert_eq!(overflow, events.len() - 1);
+        assert!(events[..overflow].iter().enumerate().all(|(i, e)| e.wd == wd && e.name == alloc::format!("f{}", i)));
+
+        // Delivery resumes after the overflow was read
+        vfs.mkdir("/dir/late").unwrap();
+        assert_eq!(vfs.read_events(group, pid).unwrap(), [event(wd, WatchMask::CREATE.union(WatchMask::IS_DIR), "late")]);
+    }
+
+    #[test]
+    fn test_watch_needs_capabilities() {
+        let vfs = vfs();
+        let pid = ProcessId::new(0xC0_0003);
+        let table = CapabilityTable::new(16);
+        let admin = table.create_root(pid, ResourceId::new(FILE_LABEL_RESOURCE, 0), Operations::ALL).unwrap();
+        let label = [CapRequirement { resource_type: "net".into(), operations: Operations::READ }];
+        vfs.set_caps_label("/dir", &label, pid.0, &table, admin).unwrap();
+
+        let (group, _) = vfs.watch_group(pid, token()).unwrap();
+        assert_eq!(vfs.add_watch(group, pid, "/dir", WatchMask::ALL, &table, &[]), Err(VfsError::PermissionDenied));
+        let net = table.create_root(pid, ResourceId::new("net", 0), Operations::READ).unwrap();
+        assert!(vfs.add_watch(group, pid, "/dir", WatchMask::ALL, &table, &[net]).is_ok());
+        // Another process cannot use the group
+        let other = ProcessId::new(0xC0_0004);
+        assert_eq!(vfs.add_watch(group, other, "/", WatchMask::ALL, &table, &[]), Err(VfsError::PermissionDenied));
+    }
+}
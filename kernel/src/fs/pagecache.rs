@@ -0,0 +1,740 @@
+//! # Unified Page Cache
+//!
+//! One cache of file pages shared by every mounted filesystem, indexed by
+//! (mount, inode, page index).
+//!
+//! ## Design
+//!
+//! ```text
+//!   read()/write()      mmap fault         ELF loader
+//!        │                  │                  │
+//!        └────────┬─────────┴──────────────────┘
+//!                 ▼
+//!   ┌──────────────────────────────────┐
+//!   │ PageCache                        │
+//!   │  (mount, ino, index) -> frame    │
+//!   │  per-inode size + readahead      │
+//!   └───────────────┬──────────────────┘
+//!                   │ fill / writeback
+//!                   ▼
+//!           Filesystem::read/write
+//! ```
+//!
+//! - **Fill**: misses read the page from the filesystem. Sequential misses grow
+//!   a readahead window (up to [`MAX_READAHEAD`] pages) that is filled in the
+//!   same pass.
+//! - **Write-back**: writes only dirty the cached page and extend the cached
+//!   size. Dirty pages reach the filesystem on `sync`/`fsync`, on eviction
+//!   pressure, or from [`PageCache::periodic_writeback`] once older than
+//!   [`DIRTY_EXPIRE_TICKS`].
+//! - **Pins**: pages mapped into address spaces are pinned and never evicted.
+//!   Pinned dirty pages stay dirty after writeback, since stores through the
+//!   mapping are not tracked.
+//! - **Eviction**: clean, unpinned pages are dropped least-recently-used first
+//!   once the cache holds more than its limit. Truncate and invalidation leave
+//!   pinned pages in place, clean, until they are unmapped.
+//! - **No memory**: when no frame is free and no clean page can be evicted,
+//!   reads and writes of the uncached page go straight to the filesystem.
+//!
+//! Filesystem I/O never runs under the cache lock.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use super::vfs::{Filesystem, InodeNum, MountPoint, VfsError};
+use crate::mm::pressure::Reclaimer;
+use crate::mm::vm::{Pager, VmError};
+use crate::mm::{FrameNumber, FRAME_ALLOCATOR, PAGE_SIZE};
+
+/// Largest readahead window, in pages.
+pub const MAX_READAHEAD: u64 = 32;
+
+/// Dirty pages older than this are written back (ticks, ~5 s).
+pub const DIRTY_EXPIRE_TICKS: u64 = 5000;
+
+/// Interval between periodic writeback passes (ticks, ~1 s).
+pub const WRITEBACK_INTERVAL_TICKS: u64 = 1000;
+
+/// Cache size floor, in pages.
+const MIN_CACHE_PAGES: usize = 256;
+
+/// Page cache index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct PageKey {
+    mount: u64,
+    ino: InodeNum,
+    index: u64,
+}
+
+/// A cached file page.
+struct CachedPage {
+    /// Frame holding the data
+    frame: u64,
+    /// Tick the page was first dirtied since its last writeback
+    dirty_since: Option<u64>,
+    /// Number of address-space mappings
+    pins: u32,
+    /// LRU clock value of the last access
+    last_used: u64,
+}
+
+/// Per-inode cache state.
+struct InodeState {
+    fs: Arc<dyn Filesystem>,
+    /// File size including unwritten extensions
+    size: u64,
+    /// Page index a sequential reader would miss on next
+    ra_next: u64,
+    /// Current readahead window, in pages
+    ra_window: u64,
+}
+
+/// Page cache statistics.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PageCacheStats {
+    /// Pages currently cached
+    pub pages: usize,
+    /// Cached pages that are dirty
+    pub dirty: usize,
+    /// Cached pages mapped into address spaces
+    pub pinned: usize,
+    /// Lookups served from the cache
+    pub hits: u64,
+    /// Lookups that read from the filesystem
+    pub misses: u64,
+    /// Pages filled by readahead
+    pub readahead: u64,
+    /// Pages written back to filesystems
+    pub writebacks: u64,
+    /// Pages evicted
+    pub evictions: u64,
+}
+
+struct CacheInner {
+    pages: BTreeMap<PageKey, CachedPage>,
+    inodes: BTreeMap<(u64, InodeNum), InodeState>,
+    clock: u64,
+    last_writeback: u64,
+    stats: PageCacheStats,
+}
+
+/// A pending page write, taken out of the cache so I/O can run unlocked.
+struct Writeback {
+    fs: Arc<dyn Filesystem>,
+    key: PageKey,
+    data: Vec<u8>,
+}
+
+/// The page cache.
+pub struct PageCache {
+    inner: Mutex<CacheInner>,
+}
+
+/// Global page cache.
+pub static PAGE_CACHE: PageCache = PageCache::new();
+
+fn frame_ptr(frame: u64) -> *mut u8 {
+    // Physical memory is identity-mapped
+    frame as *mut u8
+}
+
+fn page_offset(index: u64) -> u64 {
+    index * PAGE_SIZE as u64
+}
+
+fn now() -> u64 {
+    #[cfg(target_arch = "x86_64")]
+    {
+        crate::arch::x86_64::interrupts::get_ticks()
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        crate::arch::aarch64::timer::ticks()
+    }
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        0
+    }
+}
+
+/// Reads an uncached range straight from the filesystem. Bytes past the
+/// filesystem's end of file (an extension still in the cache) read as zeros.
+fn read_direct(fs: &dyn Filesystem, ino: InodeNum, pos: u64, buf: &mut [u8]) -> Result<(), VfsError> {
+    let data = fs.read(ino, pos, buf.len())?;
+    let len = data.len().min(buf.len());
+    buf[..len].copy_from_slice(&data[..len]);
+    buf[len..].fill(0);
+    Ok(())
+}
+
+impl PageCache {
+    /// Creates an empty cache.
+    pub const fn new() -> Self {
+        Self {
+            inner: Mutex::new(CacheInner {
+                pages: BTreeMap::new(),
+                inodes: BTreeMap::new(),
+                clock: 0,
+                last_writeback: 0,
+                stats: PageCacheStats {
+                    pages: 0,
+                    dirty: 0,
+                    pinned: 0,
+                    hits: 0,
+                    misses: 0,
+                    readahead: 0,
+                    writebacks: 0,
+                    evictions: 0,
+                },
+            }),
+        }
+    }
+
+    /// Returns the most pages kept before eviction starts.
+    fn limit() -> usize {
+        (FRAME_ALLOCATOR.total_count() / 4).max(MIN_CACHE_PAGES)
+    }
+
+    /// Starts tracking an inode if it is not tracked yet.
+    fn track(&self, mount: &MountPoint, ino: InodeNum) -> Result<(), VfsError> {
+        if self.inner.lock().inodes.contains_key(&(mount.id, ino)) {
+            return Ok(());
+        }
+        let size = mount.fs.getattr(ino)?.size;
+        self.inner
+            .lock()
+            .inodes
+            .entry((mount.id, ino))
+            .or_insert_with(|| InodeState {
+                fs: mount.fs.clone(),
+                size,
+                ra_next: 0,
+                ra_window: 0,
+            });
+        Ok(())
+    }
+
+    /// Returns the cached size of a file, reading it from the filesystem on
+    /// first use.
+    pub fn size(&self, mount: &MountPoint, ino: InodeNum) -> Result<u64, VfsError> {
+        self.track(mount, ino)?;
+        Ok(self.inner.lock().inodes[&(mount.id, ino)].size)
+    }
+
+    /// Returns the cached size of a file if the cache tracks it.
+    pub fn cached_size(&self, mount: &MountPoint, ino: InodeNum) -> Option<u64> {
+        self.inner.lock().inodes.get(&(mount.id, ino)).map(|s| s.size)
+    }
+
+    /// Reads file data through the cache. Returns the bytes read, which is
+    /// short only at end of file.
+    pub fn read(
+        &self,
+        mount: &MountPoint,
+        ino: InodeNum,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> Result<usize, VfsError> {
+        let size = self.size(mount, ino)?;
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = buf.len().min((size - offset) as usize);
+
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let index = pos / PAGE_SIZE as u64;
+            let in_page = (pos % PAGE_SIZE as u64) as usize;
+            let chunk = (PAGE_SIZE - in_page).min(len - done);
+
+            let key = PageKey { mount: mount.id, ino, index };
+            loop {
+                if !self.fill(mount, key, true)? {
+                    read_direct(&*mount.fs, ino, pos, &mut buf[done..done + chunk])?;
+                    break;
+                }
+                let mut inner = self.inner.lock();
+                let clock = inner.tick();
+                let Some(page) = inner.pages.get_mut(&key) else {
+                    // Evicted between fill and copy
+                    continue;
+                };
+                page.last_used = clock;
+                // SAFETY: the frame is a whole page owned by the cache, and
+                // the lock keeps it from being evicted during the copy
+                unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        frame_ptr(page.frame).add(in_page),
+                        buf[done..].as_mut_ptr(),
+                        chunk,
+                    );
+                }
+                break;
+            }
+            done += chunk;
+        }
+        Ok(len)
+    }
+
+    /// Writes file data into the cache, extending the file if needed. The
+    /// filesystem sees the data on the next writeback.
+    pub fn write(
+        &self,
+        mount: &MountPoint,
+        ino: InodeNum,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<usize, VfsError> {
+        let size = self.size(mount, ino)?;
+
+        let mut done = 0;
+        while done < data.len() {
+            let pos = offset + done as u64;
+            let index = pos / PAGE_SIZE as u64;
+            let in_page = (pos % PAGE_SIZE as u64) as usize;
+            let chunk = (PAGE_SIZE - in_page).min(data.len() - done);
+
+            // Whole pages and pages past the old end need no read
+            let key = PageKey { mount: mount.id, ino, index };
+            let overwrite = chunk == PAGE_SIZE || page_offset(index) >= size;
+            loop {
+                if !self.fill(mount, key, !overwrite)? {
+                    let written = mount.fs.write(ino, pos, &data[done..done + chunk])?;
+                    if let Some(state) = self.inner.lock().inodes.get_mut(&(mount.id, ino)) {
+                        state.size = state.size.max(pos + written as u64);
+                    }
+                    if written < chunk {
+                        return Ok(done + written);
+                    }
+                    break;
+                }
+                let mut inner = self.inner.lock();
+                let clock = inner.tick();
+                let Some(page) = inner.pages.get_mut(&key) else {
+                    continue;
+                };
+                page.last_used = clock;
+                page.dirty_since.get_or_insert_with(now);
+                // SAFETY: as in `read`
+                unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        data[done..].as_ptr(),
+                        frame_ptr(page.frame).add(in_page),
+                        chunk,
+                    );
+                }
+                if let Some(state) = inner.inodes.get_mut(&(mount.id, ino)) {
+                    state.size = state.size.max(pos + chunk as u64);
+                }
+                break;
+            }
+            done += chunk;
+        }
+        self.shrink();
+        Ok(done)
+    }
+
+    /// Makes sure a page is cached, reading it (and any readahead) from the
+    /// filesystem if `read` is set, or zero-filling it otherwise. Returns
+    /// false if there is no memory to cache it.
+    fn fill(&self, mount: &MountPoint, key: PageKey, read: bool) -> Result<bool, VfsError> {
+        let (size, window) = {
+            let mut inner = self.inner.lock();
+            if inner.pages.contains_key(&key) {
+                inner.stats.hits += 1;
+                return Ok(true);
+            }
+            inner.stats.misses += 1;
+            let state = inner
+                .inodes
+                .get_mut(&(key.mount, key.ino))
+                .ok_or(VfsError::BadFd)?;
+            // Sequential misses double the window; anything else resets it
+            state.ra_window = if read && key.index == state.ra_next {
+                (state.ra_window * 2).clamp(1, MAX_READAHEAD)
+            } else {
+                0
+            };
+            state.ra_next = key.index + 1 + state.ra_window;
+            (state.size, state.ra_window)
+        };
+
+        let Some(frame) = self.alloc_frame() else {
+            return Ok(false);
+        };
+        self.fill_one(mount, key, frame, read && page_offset(key.index) < size)?;
+        // Readahead only uses free frames
+        for index in key.index + 1..=key.index + window {
+            if page_offset(index) >= size {
+                break;
+            }
+            let ahead = PageKey { index, ..key };
+            if self.inner.lock().pages.contains_key(&ahead) {
+                continue;
+            }
+            let Ok(frame) = FRAME_ALLOCATOR.allocate() else {
+                break;
+            };
+            if self.fill_one(mount, ahead, frame.address(), true).is_err() {
+                break;
+            }
+            self.inner.lock().stats.readahead += 1;
+        }
+        self.shrink();
+        Ok(true)
+    }
+
+    /// Takes a frame for a page, evicting the least recently used clean
+    /// page if none is free.
+    fn alloc_frame(&self) -> Option<u64> {
+        if let Ok(frame) = FRAME_ALLOCATOR.allocate() {
+            return Some(frame.address());
+        }
+        {
+            let mut inner = self.inner.lock();
+            let cached = inner.pages.len();
+            if cached == 0 || inner.evict(cached - 1) == cached {
+                return None;
+            }
+        }
+        FRAME_ALLOCATOR.allocate().ok().map(|frame| frame.address())
+    }
+
+    /// Caches a page in `frame`, which it takes ownership of.
+    fn fill_one(&self, mount: &MountPoint, key: PageKey, frame: u64, read: bool) -> Result<(), VfsError> {
+        let ptr = frame_ptr(frame);
+        // SAFETY: freshly allocated frame
+        unsafe { core::ptr::write_bytes(ptr, 0, PAGE_SIZE) };
+
+        if read {
+            match mount.fs.read(key.ino, page_offset(key.index), PAGE_SIZE) {
+                Ok(data) => {
+                    let len = data.len().min(PAGE_SIZE);
+                    // SAFETY: `len` fits in the frame
+                    unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), ptr, len) };
+                }
+                Err(e) => {
+                    FRAME_ALLOCATOR.free(FrameNumber::from_address(frame));
+                    return Err(e);
+                }
+            }
+        }
+
+        let mut inner = self.inner.lock();
+        if inner.pages.contains_key(&key) {
+            // Another reader filled it first
+            drop(inner);
+            FRAME_ALLOCATOR.free(FrameNumber::from_address(frame));
+            return Ok(());
+        }
+        let clock = inner.tick();
+        inner.pages.insert(
+            key,
+            CachedPage {
+                frame,
+                dirty_since: None,
+                pins: 0,
+                last_used: clock,
+            },
+        );
+        Ok(())
+    }
+
+    /// Evicts clean, unpinned pages until the cache is within its limit,
+    /// writing back dirty pages first if that is not enough.
+    fn shrink(&self) {
+        let limit = Self::limit();
+        if self.inner.lock().pages.len() <= limit {
+            return;
+        }
+        if self.inner.lock().evict(limit) > limit {
+            self.run_writeback(|_, _| true);
+            self.inner.lock().evict(limit);
+        }
+    }
+
+    /// Drops cached pages past a new file size and records the size.
+    pub fn truncate(&self, mount: &MountPoint, ino: InodeNum, size: u64) {
+        let mut inner = self.inner.lock();
+        let Some(state) = inner.inodes.get_mut(&(mount.id, ino)) else {
+            return;
+        };
+        state.size = size;
+
+        let first = size.div_ceil(PAGE_SIZE as u64);
+        let from = PageKey { mount: mount.id, ino, index: first };
+        let to = PageKey { mount: mount.id, ino, index: u64::MAX };
+        let stale: Vec<PageKey> = inner.pages.range(from..=to).map(|(k, _)| *k).collect();
+        for key in stale {
+            inner.drop_page(key);
+        }
+
+        // Zero the tail of a partial last page
+        let tail = (size % PAGE_SIZE as u64) as usize;
+        if tail != 0 {
+            let key = PageKey { mount: mount.id, ino, index: first - 1 };
+            if let Some(page) = inner.pages.get(&key) {
+                // SAFETY: the range stays within the frame
+                unsafe { core::ptr::write_bytes(frame_ptr(page.frame).add(tail), 0, PAGE_SIZE - tail) };
+            }
+        }
+    }
+
+    /// Drops every cached page of an inode without writing it back.
+    pub fn invalidate_inode(&self, mount: &MountPoint, ino: InodeNum) {
+        self.inner.lock().invalidate(|m, i| m == mount.id && i == ino);
+    }
+
+    /// Drops every cached page of a mount without writing it back.
+    pub fn invalidate_mount(&self, mount: &MountPoint) {
+        self.inner.lock().invalidate(|m, _| m == mount.id);
+    }
+
+    /// Writes back dirty pages of an inode and syncs its filesystem.
+    pub fn flush_inode(&self, mount: &MountPoint, ino: InodeNum) -> Result<(), VfsError> {
+        self.run_writeback(|k, _| k.mount == mount.id && k.ino == ino)?;
+        mount.fs.sync()
+    }
+
+    /// Writes back dirty pages of a mount and syncs its filesystem.
+    pub fn flush_mount(&self, mount: &MountPoint) -> Result<(), VfsError> {
+        self.run_writeback(|k, _| k.mount == mount.id)?;
+        mount.fs.sync()
+    }
+
+    /// Writes back every dirty page.
+    pub fn sync_all(&self) -> Result<(), VfsError> {
+        self.run_writeback(|_, _| true)
+    }
+
+    /// Writes back pages dirty for longer than [`DIRTY_EXPIRE_TICKS`]. Does
+    /// nothing if the previous pass was less than
+    /// [`WRITEBACK_INTERVAL_TICKS`] ago. Called from the kernel main loop.
+    pub fn periodic_writeback(&self) {
+        let now = now();
+        {
+            let mut inner = self.inner.lock();
+            if now.saturating_sub(inner.last_writeback) < WRITEBACK_INTERVAL_TICKS {
+                return;
+            }
+            inner.last_writeback = now;
+        }
+        if let Err(e) =
+            self.run_writeback(|_, since| now.saturating_sub(since) >= DIRTY_EXPIRE_TICKS)
+        {
+            crate::serial_println!("[pagecache] Writeback failed: {:?}", e);
+        }
+    }
+
+    /// Writes back dirty pages matching `select(key, dirty_since)`.
+    ///
+    /// A page that fails to write is re-dirtied and the first error returned.
+    fn run_writeback(&self, select: impl Fn(&PageKey, u64) -> bool) -> Result<(), VfsError> {
+        let batch = self.inner.lock().take_dirty(select);
+        let mut result = Ok(());
+        for wb in batch {
+            if let Err(e) = wb.fs.write(wb.key.ino, page_offset(wb.key.index), &wb.data) {
+                let mut inner = self.inner.lock();
+                if let Some(page) = inner.pages.get_mut(&wb.key) {
+                    page.dirty_since.get_or_insert_with(now);
+                }
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+
+    /// Pins a page for a mapping, filling it if needed, and returns its frame.
+    pub fn pin(&self, mount: &MountPoint, ino: InodeNum, index: u64) -> Result<u64, VfsError> {
+        self.track(mount, ino)?;
+        let key = PageKey { mount: mount.id, ino, index };
+        loop {
+            // A mapping needs the page in a frame
+            if !self.fill(mount, key, true)? {
+                return Err(VfsError::NoSpace);
+            }
+            let mut inner = self.inner.lock();
+            let clock = inner.tick();
+            if let Some(page) = inner.pages.get_mut(&key) {
+                page.pins += 1;
+                page.last_used = clock;
+                return Ok(page.frame);
+            }
+        }
+    }
+
+    /// Drops a pin taken by [`pin`](Self::pin).
+    pub fn unpin(&self, mount_id: u64, ino: InodeNum, index: u64) {
+        let mut inner = self.inner.lock();
+        if let Some(page) = inner.pages.get_mut(&PageKey { mount: mount_id, ino, index }) {
+            page.pins = page.pins.saturating_sub(1);
+        }
+    }
+
+    /// Marks a cached page dirty.
+    pub fn set_dirty(&self, mount_id: u64, ino: InodeNum, index: u64) {
+        let mut inner = self.inner.lock();
+        if let Some(page) = inner.pages.get_mut(&PageKey { mount: mount_id, ino, index }) {
+            page.dirty_since.get_or_insert_with(now);
+        }
+    }
+
+    /// Returns cache statistics.
+    pub fn stats(&self) -> PageCacheStats {
+        let inner = self.inner.lock();
+        PageCacheStats {
+            pages: inner.pages.len(),
+            dirty: inner.pages.values().filter(|p| p.dirty_since.is_some()).count(),
+            pinned: inner.pages.values().filter(|p| p.pins > 0).count(),
+            ..inner.stats
+        }
+    }
+}
+
+impl CacheInner {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    /// Evicts LRU clean, unpinned pages down to `limit`. Returns the number of
+    /// pages left.
+    fn evict(&mut self, limit: usize) -> usize {
+        let excess = self.pages.len().saturating_sub(limit);
+        if excess == 0 {
+            return self.pages.len();
+        }
+        let mut candidates: Vec<(u64, PageKey)> = self
+            .pages
+            .iter()
+            .filter(|(_, p)| p.pins == 0 && p.dirty_since.is_none())
+            .map(|(k, p)| (p.last_used, *k))
+            .collect();
+        candidates.sort_unstable();
+        for (_, key) in candidates.into_iter().take(excess) {
+            self.drop_page(key);
+            self.stats.evictions += 1;
+        }
+        self.pages.len()
+    }
+
+    /// Frees a page. Pinned pages are only marked clean and stay cached until
+    /// their mappings go away; eviction reclaims them afterwards.
+    fn drop_page(&mut self, key: PageKey) {
+        match self.pages.get_mut(&key) {
+            Some(page) if page.pins > 0 => page.dirty_since = None,
+            Some(_) => {
+                let page = self.pages.remove(&key).unwrap();
+                FRAME_ALLOCATOR.free(FrameNumber::from_address(page.frame));
+            }
+            None => {}
+        }
+    }
+
+    fn invalidate(&mut self, matches: impl Fn(u64, InodeNum) -> bool) {
+        let keys: Vec<PageKey> = self
+            .pages
+            .keys()
+            .filter(|k| matches(k.mount, k.ino))
+            .copied()
+            .collect();
+        for key in keys {
+            self.drop_page(key);
+        }
+        self.inodes.retain(|&(m, i), _| !matches(m, i));
+    }
+
+    /// Copies out selected dirty pages for writeback and marks them clean.
+    /// Pinned pages stay dirty.
+    fn take_dirty(&mut self, select: impl Fn(&PageKey, u64) -> bool) -> Vec<Writeback> {
+        let now = now();
+        let mut batch = Vec::new();
+        for (key, page) in self.pages.iter_mut() {
+            let Some(since) = page.dirty_since else { continue };
+            if !select(key, since) {
+                continue;
+            }
+            let Some(state) = self.inodes.get(&(key.mount, key.ino)) else { continue };
+            let start = page_offset(key.index);
+            if start >= state.size {
+                page.dirty_since = None;
+                continue;
+            }
+            let len = ((state.size - start) as usize).min(PAGE_SIZE);
+            let mut data = alloc::vec![0u8; len];
+            // SAFETY: `len` fits in the frame
+            unsafe { core::ptr::copy_nonoverlapping(frame_ptr(page.frame), data.as_mut_ptr(), len) };
+            page.dirty_since = if page.pins > 0 { Some(now) } else { None };
+            batch.push(Writeback {
+                fs: state.fs.clone(),
+                key: *key,
+                data,
+            });
+        }
+        self.stats.writebacks += batch.len() as u64;
+        batch
+    }
+}
+
+impl Reclaimer for PageCache {
+    fn name(&self) -> &'static str {
+        "pagecache"
+    }
+
+    fn reclaimable_pages(&self) -> usize {
+        let inner = self.inner.lock();
+        inner
+            .pages
+            .values()
+            .filter(|p| p.pins == 0 && p.dirty_since.is_none())
+            .count()
+    }
+
+    fn reclaim(&self, target: usize) -> usize {
+        let mut inner = self.inner.lock();
+        let before = inner.pages.len();
+        inner.evict(before.saturating_sub(target));
+        before - inner.pages.len()
+    }
+}
+
+/// A cached file as seen by address spaces mapping it.
+pub struct CachedFile {
+    mount: Arc<MountPoint>,
+    ino: InodeNum,
+}
+
+impl CachedFile {
+    /// Creates a pager for an inode of a mount.
+    pub fn new(mount: Arc<MountPoint>, ino: InodeNum) -> Self {
+        Self { mount, ino }
+    }
+}
+
+impl Pager for CachedFile {
+    fn get_page(&self, index: u64) -> Result<u64, VmError> {
+        PAGE_CACHE.pin(&self.mount, self.ino, index).map_err(|e| match e {
+            VfsError::NoSpace => VmError::OutOfMemory,
+            _ => VmError::PagerError,
+        })
+    }
+
+    fn put_page(&self, index: u64) {
+        PAGE_CACHE.unpin(self.mount.id, self.ino, index);
+    }
+
+    fn set_dirty(&self, index: u64) {
+        PAGE_CACHE.set_dirty(self.mount.id, self.ino, index);
+    }
+
+    fn flush(&self) {
+        if let Err(e) = PAGE_CACHE.flush_inode(&self.mount, self.ino) {
+            crate::serial_println!("[pagecache] msync failed: {:?}", e);
+        }
+    }
+}
@@ -11,6 +11,10 @@
 //! ├── version       - kernel version
 //! ├── uptime        - system uptime
 //! ├── meminfo       - memory information
+//! ├── buddyinfo     - free blocks per zone and order
+//! ├── slabinfo      - kernel object cache usage
+//! ├── vmstat        - page fault counters
+//! ├── pressure      - memory pressure level, quotas and OOM events
 //! ├── cpuinfo       - CPU information
 //! ├── cmdline       - kernel command line
 //! ├── loadavg       - system load averages
@@ -78,8 +82,9 @@ pub fn read_meminfo() -> String {
     let total_kb = stats.heap_size / 1024;
     let used_kb = stats.total_allocated / 1024;
     let free_kb = (stats.heap_size.saturating_sub(stats.total_allocated)) / 1024;
+    let cache = super::pagecache::PAGE_CACHE.stats();
     
-    format!(
+    let mut info = format!(
         "MemTotal:       {:8} kB\n\
          MemFree:        {:8} kB\n\
          MemUsed:        {:8} kB\n\
@@ -93,14 +98,109 @@ pub fn read_meminfo() -> String {
         free_kb,
         used_kb,
         0,  // Buffers
-        0,  // Cached
+        cache.pages * crate::mm::PAGE_SIZE / 1024,
         0,  // SwapTotal
         0,  // SwapFree
         stats.allocation_count,
         stats.deallocation_count
+    );
+
+    // Physical frames from the buddy allocator
+    use crate::mm::{HugePageSize, Zone, FRAME_ALLOCATOR};
+    let frames = &FRAME_ALLOCATOR;
+    let dma32 = frames.zone_stats(Zone::Dma32);
+    let normal = frames.zone_stats(Zone::Normal);
+    let device = frames.zone_stats(Zone::Device);
+    let huge_2m = HugePageSize::Size2M.order();
+    let huge_1g = HugePageSize::Size1G.order();
+    let free_huge = |order: usize| -> usize {
+        [&dma32, &normal]
+            .iter()
+            .map(|z| z.free_blocks[order..].iter().enumerate().map(|(i, &n)| n << i).sum::<usize>())
+            .sum()
+    };
+
+    info.push_str(&format!(
+        "PhysTotal:      {:8} kB\n\
+         PhysFree:       {:8} kB\n\
+         PerCpuCached:   {:8} kB\n\
+         DMA32Free:      {:8} kB\n\
+         NormalFree:     {:8} kB\n\
+         DeviceTotal:    {:8} kB\n\
+         HugePages2MFree:{:8}\n\
+         HugePages1GFree:{:8}\n\
+         Fragmentation2M:{:8} %\n",
+        frames.total_memory() / 1024,
+        frames.free_memory() / 1024,
+        frames.pcp_count() * 4,
+        dma32.free_frames * 4,
+        normal.free_frames * 4,
+        device.present_frames * 4,
+        free_huge(huge_2m),
+        free_huge(huge_1g),
+        normal.fragmentation(huge_2m).max(dma32.fragmentation(huge_2m)),
+    ));
+
+    info.push_str(&format!(
+        "Dirty:          {:8} kB\n\
+         Mapped:         {:8} kB\n",
+        cache.dirty * 4,
+        cache.pinned * 4,
+    ));
+
+    info
+}
+
+/// Reads /proc/buddyinfo
+///
+/// One line per zone with the number of free blocks of each order,
+/// from 4 KiB (order 0) up to 1 GiB (order 18).
+pub fn read_buddyinfo() -> String {
+    use crate::mm::{Zone, FRAME_ALLOCATOR};
+
+    let mut output = String::new();
+    for zone in Zone::ALLOCATABLE {
+        let stats = FRAME_ALLOCATOR.zone_stats(zone);
+        output.push_str(&format!("Node 0, zone {:>8}", zone.name()));
+        for count in stats.free_blocks.iter() {
+            output.push_str(&format!(" {:6}", count));
+        }
+        output.push('\n');
+    }
+    output
+}
+
+/// Reads /proc/slabinfo
+pub fn read_slabinfo() -> String {
+    crate::mm::slab::slabinfo()
+}
+
+/// Reads /proc/vmstat
+pub fn read_vmstat() -> String {
+    let stats = crate::mm::vm::global_stats();
+    format!(
+        "pgfault_demand_zero {}\n\
+         pgfault_page_cache {}\n\
+         pgfault_shared_write {}\n\
+         pgfault_cow_copy {}\n\
+         pgfault_cow_reuse {}\n\
+         pgfault_spurious {}\n\
+         pgfault_invalid {}\n",
+        stats.demand_zero,
+        stats.page_cache,
+        stats.shared_writes,
+        stats.cow_copies,
+        stats.cow_reuses,
+        stats.spurious,
+        stats.invalid
     )
 }
 
+/// Reads /proc/pressure
+pub fn read_pressure() -> String {
+    crate::mm::pressure::report()
+}
+
 /// Reads /proc/cpuinfo
 pub fn read_cpuinfo() -> String {
     let mut info = String::new();
@@ -336,6 +436,26 @@ pub fn list_proc() -> Vec<ProcEntry> {
         file_type: ProcFileType::File,
         link_target: None,
     });
+    entries.push(ProcEntry {
+        name: String::from("buddyinfo"),
+        file_type: ProcFileType::File,
+        link_target: None,
+    });
+    entries.push(ProcEntry {
+        name: String::from("slabinfo"),
+        file_type: ProcFileType::File,
+        link_target: None,
+    });
+    entries.push(ProcEntry {
+        name: String::from("vmstat"),
+        file_type: ProcFileType::File,
+        link_target: None,
+    });
+    entries.push(ProcEntry {
+        name: String::from("pressure"),
+        file_type: ProcFileType::File,
+        link_target: None,
+    });
     entries.push(ProcEntry {
         name: String::from("cpuinfo"),
         file_type: ProcFileType::File,
@@ -423,6 +543,10 @@ pub fn read_proc_file(path: &str) -> Option<String> {
         "version" => Some(read_version()),
         "uptime" => Some(read_uptime()),
         "meminfo" => Some(read_meminfo()),
+        "buddyinfo" => Some(read_buddyinfo()),
+        "slabinfo" => Some(read_slabinfo()),
+        "vmstat" => Some(read_vmstat()),
+        "pressure" => Some(read_pressure()),
         "cpuinfo" => Some(read_cpuinfo()),
         "cmdline" => Some(read_cmdline()),
         "loadavg" => Some(read_loadavg()),
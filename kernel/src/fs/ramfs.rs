@@ -8,6 +8,7 @@
 //! - In-memory storage (volatile)
 //! - Fast operations (no I/O)
 //! - Configurable size limits
+//! - Extended attributes (counted against the size limit)
 
 use alloc::collections::BTreeMap;
 use alloc::string::String;
@@ -18,6 +19,7 @@ use spin::RwLock;
 use super::vfs::{
     Filesystem, InodeNum, VfsAttr, VfsDirEntry, VfsError, VfsFileType, VfsPermissions, VfsStatFs,
 };
+use super::xattr;
 
 /// Maximum file size (4 MB)
 pub const MAX_FILE_SIZE: usize = 4 * 1024 * 1024;
@@ -38,6 +40,8 @@ struct RamInode {
     children: BTreeMap<String, InodeNum>,
     /// Symlink target (for symlinks)
     link_target: Option<String>,
+    /// Extended attributes: name -> value
+    xattrs: BTreeMap<String, Vec<u8>>,
     /// Permissions
     perm: VfsPermissions,
     /// Hard link count
@@ -61,6 +65,7 @@ impl RamInode {
             content: Vec::new(),
             children: BTreeMap::new(),
             link_target: None,
+            xattrs: BTreeMap::new(),
             perm: VfsPermissions::default(),
             nlink: 1,
             atime: now,
@@ -78,6 +83,7 @@ impl RamInode {
             content: Vec::new(),
             children: BTreeMap::new(),
             link_target: None,
+            xattrs: BTreeMap::new(),
             perm: VfsPermissions {
                 readable: true,
                 writable: true,
@@ -99,6 +105,7 @@ impl RamInode {
             content: Vec::new(),
             children: BTreeMap::new(),
             link_target: Some(String::from(target)),
+            xattrs: BTreeMap::new(),
             perm: VfsPermissions {
                 readable: true,
                 writable: false,
@@ -137,6 +144,11 @@ impl RamInode {
         self.mtime = now;
         self.ctime = now;
     }
+
+    /// Bytes held by extended attributes
+    fn xattr_bytes(&self) -> usize {
+        self.xattrs.iter().map(|(name, value)| name.len() + value.len()).sum()
+    }
 }
 
 /// RamFS filesystem
@@ -377,7 +389,7 @@ impl Filesystem for RamFs {
         
         // Decrease link count and potentially remove
         if let Some(child) = inodes.get_mut(&child_ino) {
-            let freed_bytes = child.content.len();
+            let freed_bytes = child.content.len() + child.xattr_bytes();
             let is_dir = child.file_type == VfsFileType::Directory;
             child.nlink -= 1;
             
@@ -500,6 +512,7 @@ impl Filesystem for RamFs {
             ffree: 1000,
             bsize: block_size,
             namelen: MAX_NAME_LEN as u32,
+            bsaved: 0,
         })
     }
 
@@ -589,6 +602,47 @@ impl Filesystem for RamFs {
         
         Ok(())
     }
+
+    fn getxattr(&self, ino: InodeNum, name: &str) -> Result<Vec<u8>, VfsError> {
+        let inodes = self.inodes.read();
+        let inode = inodes.get(&ino).ok_or(VfsError::NotFound)?;
+        inode.xattrs.get(name).cloned().ok_or(VfsError::NoAttribute)
+    }
+
+    fn setxattr(&self, ino: InodeNum, name: &str, value: &[u8]) -> Result<(), VfsError> {
+        xattr::check_attr(name, value)?;
+        let mut inodes = self.inodes.write();
+        let inode = inodes.get_mut(&ino).ok_or(VfsError::NotFound)?;
+
+        let old_len = inode.xattrs.get(name).map_or(0, |old| name.len() + old.len());
+        let new_len = name.len() + value.len();
+        {
+            let mut bytes_used = self.bytes_used.lock();
+            if new_len > old_len && *bytes_used + new_len - old_len > self.max_size {
+                return Err(VfsError::NoSpace);
+            }
+            *bytes_used = *bytes_used + new_len - old_len;
+        }
+
+        inode.xattrs.insert(String::from(name), value.to_vec());
+        inode.ctime = crate::arch::x86_64::interrupts::get_ticks();
+        Ok(())
+    }
+
+    fn listxattr(&self, ino: InodeNum) -> Result<Vec<String>, VfsError> {
+        let inodes = self.inodes.read();
+        let inode = inodes.get(&ino).ok_or(VfsError::NotFound)?;
+        Ok(inode.xattrs.keys().cloned().collect())
+    }
+
+    fn removexattr(&self, ino: InodeNum, name: &str) -> Result<(), VfsError> {
+        let mut inodes = self.inodes.write();
+        let inode = inodes.get_mut(&ino).ok_or(VfsError::NotFound)?;
+        let value = inode.xattrs.remove(name).ok_or(VfsError::NoAttribute)?;
+        *self.bytes_used.lock() -= name.len() + value.len();
+        inode.ctime = crate::arch::x86_64::interrupts::get_ticks();
+        Ok(())
+    }
 }
 
 /// Create a new RamFS instance
@@ -7,15 +7,21 @@
 //!
 //! ```text
 //! +------------------+  Block 0
-//! | Superblock       |  Filesystem metadata
-//! +------------------+  Block 1
-//! | Block Bitmap     |  Which blocks are free
-//! +------------------+  Block 2
-//! | Inode Bitmap     |  Which inodes are free
-//! +------------------+  Block 3-N
-//! | Inode Table      |  Fixed-size inode structures
-//! +------------------+  Block N+1...
-//! | Data Blocks      |  File and directory content
+//! | Superblock       |  Filesystem metadata and the locations below
+//! +------------------+  Block 1 (journaled volumes)
+//! | Journal          |  Journal superblock + log area
+//! +------------------+
+//! | Reference Counts |  16 bits per block; 0 = free
+//! +------------------+
+//! | Checksum Table   |  CRC-32C of every block
+//! +------------------+
+//! | Inode Bitmap     |  Which inodes are free (bit n = inode n)
+//! +------------------+
+//! | Inode Map        |  Block of each inode table slice
+//! +------------------+
+//! | Snapshot Table   |  Snapshot names and roots
+//! +------------------+
+//! | Data Blocks      |  Inode tables, file and directory content
 //! +------------------+
 //! ```
 //!
@@ -25,16 +31,114 @@
 //! - Inode size: 128 bytes
 //! - Max file size: ~4GB (12 direct + 1 indirect + 1 double indirect)
 //! - Max filename: 255 bytes
-//! - Simple extent-based allocation for efficiency
+//! - Directories are arrays of fixed-size entries spanning as many blocks
+//!   as needed, starting with `.` and `..`
+//! - On persistent memory, file blocks can be mapped directly (DAX)
+//! - Extended attributes live in the inode's last 24 bytes, spilling into
+//!   one overflow block per inode
+//!
+//! ## Journaling
+//!
+//! Every operation collects the new images of the metadata blocks it
+//! changes (superblock, bitmaps, inodes, directory and indirect blocks) in
+//! a transaction. On a journaled volume the transaction is written to the
+//! log, sealed with a checksummed commit record and only then written in
+//! place; mount replays a committed transaction that did not make it.
+//! File data is written in place before the commit (ordered mode), so
+//! metadata never points at stale data.
+//!
+//! ## Snapshots
+//!
+//! Blocks are reference counted rather than marked in a bitmap: the count
+//! is the number of places pointing at a block (the inode map, an inode or
+//! a pointer block). A snapshot copies just the live inode map and inode
+//! bitmap behind a root block and bumps the count of each inode table
+//! block, so taking one is cheap and atomic. Writes then copy on write:
+//! a block with more than one reference is copied before it changes, from
+//! the inode table block down to the data block, and the counts of its
+//! children go up by one. Deleting a snapshot drops its references a few
+//! inodes per transaction and survives a crash by resuming at mount.
+//! Snapshots can be mounted at their own path, read-only or (when created
+//! writable) read-write, and the live tree can be rolled back to one.
+//!
+//! ## Checksums
+//!
+//! Every block outside the journal has a CRC-32C in the checksum table,
+//! seeded with its block number, and the superblock carries its own.
+//! Metadata checksums are recorded when a transaction commits and go to
+//! the log with it; data checksums are recorded as the data is written.
+//! Every read is verified and fails with
+//! [`SplaxFsError::ChecksumMismatch`] rather than returning what the disk
+//! made up. A crash while overwriting file data in place can leave a
+//! block that no longer matches, which reads report and `fsck` accepts.
+//! Pages mapped for direct access change behind the file system's back;
+//! their checksums are refreshed on sync and unmount. A background scrub
+//! ([`scrub_start`]) walks the live tree and every snapshot, a few
+//! blocks at a time, and reports the corrupt ones with their file.
+//!
+//! ## Compression
+//!
+//! A file or directory can name a compression algorithm (LZ4, or LZH for
+//! a higher ratio) through the [`COMPRESSION_XATTR`] attribute; it is kept
+//! in the inode flags, and files and directories created in a directory
+//! take it over. The data of such a file is written in clusters of 16
+//! blocks (4 among the direct blocks), each stored compressed when that
+//! saves a block, so a read only decompresses the cluster it falls in.
+//! The block map slots a cluster saves hold [`COMPRESSED_SLOT`]; the
+//! superblock counts them, which `statfs` reports next to the used
+//! blocks, while an inode's block count covers only the blocks it holds.
+//! Compressed files cannot be mapped for direct access.
+//!
+//! ## Encryption
+//!
+//! An empty directory can be given an encryption policy naming a master
+//! key ([`Filesystem::set_encryption`]); the context it gets, and every
+//! file and directory later created below it, is kept in the read-only
+//! [`ENCRYPTION_XATTR`] attribute with a nonce of its own. File data is
+//! AES-256-XTS, one data unit per block with the file block index as the
+//! tweak, so copy-on-write and snapshots move ciphertext around as is.
+//! Entry names are AES-256-CBC-CS3. Per-file keys are derived from the
+//! master key with HKDF-SHA256 (see `splaxfs_core::crypt`); the volume
+//! only holds master keys while they are unlocked, never on disk. Locked,
+//! a policy's files cannot be read, written or created, but they can be
+//! listed, looked up and unlinked by their names' ciphertext, shown in
+//! base64url. Entries only join an encrypted directory if they are
+//! encrypted under the same key. Encrypted files cannot be mapped for
+//! direct access.
+//!
+//! ## VFS
+//!
+//! [`SplaxFs`] implements the VFS [`Filesystem`] trait, and [`mount`]
+//! attaches a volume to the global VFS. The path helpers ([`ls`], [`read`],
+//! [`write`], ...) used by the shell go through the same inode operations.
+//!
+//! ## Shared Core
+//!
+//! The on-disk structures, the journal, formatting and the offline checker
+//! ([`fsck`]) live in the `splaxfs_core` crate, which the host tools use on
+//! image files; this module adds transactions, the VFS and mounting.
 
-use alloc::collections::BTreeMap;
-use alloc::format;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet};
 use alloc::string::{String, ToString};
+use alloc::sync::Arc;
 use alloc::vec;
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
 use spin::Mutex;
 
-use crate::block::{self, BlockDevice, BlockError};
+use super::vfs::{
+    Filesystem, InodeNum, VfsAttr, VfsDirEntry, VfsEncryption, VfsError, VfsFileType, VfsPermissions, VfsStatFs,
+    VFS,
+};
+use super::xattr::{COMPRESSION_XATTR, ENCRYPTION_XATTR};
+use crate::block;
+use crate::crypto::kdf::Hkdf;
+pub use splaxfs_core::layout::*;
+pub use splaxfs_core::{CheckOptions, Journal, JournalEntry, JournalSuperblock, Problem, Report};
+use splaxfs_core::crypt::{self, ContentsCipher, Context, NamesCipher, KEY_IDENTIFIER_SIZE};
+use splaxfs_core::xattr::{self, Xattrs};
+use splaxfs_core::{cluster, Algorithm, BlockIo, ChecksumTable};
 
 /// Get current timestamp for journal entries (uses system tick counter)
 fn get_journal_timestamp() -> u64 {
@@ -48,1837 +152,3103 @@ fn get_journal_timestamp() -> u64 {
     }
 }
 
-/// Filesystem magic number (ASCII "SPLX")
-pub const SPLAXFS_MAGIC: u32 = 0x53504C58;
-
-/// Block size (4 KB)
-pub const BLOCK_SIZE: usize = 4096;
-
-/// Inode size (128 bytes)
-pub const INODE_SIZE: usize = 128;
-
-/// Inodes per block
-pub const INODES_PER_BLOCK: usize = BLOCK_SIZE / INODE_SIZE;
-
-/// Directory entry size (fixed)
-pub const DIRENT_SIZE: usize = 264; // 8 + 255 + 1 padding
-
-/// Maximum filename length
-pub const MAX_FILENAME: usize = 255;
-
-/// Number of direct block pointers in an inode
-pub const DIRECT_BLOCKS: usize = 12;
-
-/// Root inode number
-pub const ROOT_INODE: u32 = 2; // Inode 0 is null, 1 is reserved
-
-/// Filesystem errors
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum SplaxFsError {
-    /// No such file or directory
-    NotFound,
-    /// File exists
-    Exists,
-    /// Not a directory
-    NotDirectory,
-    /// Is a directory
-    IsDirectory,
-    /// Directory not empty
-    NotEmpty,
-    /// No space left
-    NoSpace,
-    /// Filename too long
-    NameTooLong,
-    /// Filesystem corrupted
-    Corrupted,
-    /// I/O error
-    IoError,
-    /// Invalid argument
-    InvalidArg,
-    /// Not mounted
-    NotMounted,
-    /// Read-only filesystem
-    ReadOnly,
-    /// Journal error
-    JournalError,
-    /// Transaction not found
-    TransactionNotFound,
-}
-
-impl From<BlockError> for SplaxFsError {
-    fn from(_: BlockError) -> Self {
-        SplaxFsError::IoError
-    }
-}
-
-// =============================================================================
-// Journaling Support
-// =============================================================================
-
-/// Journal magic number ("JRNL")
-const JOURNAL_MAGIC: u32 = 0x4A524E4C;
-
-/// Maximum journal entries
-const MAX_JOURNAL_ENTRIES: usize = 64;
-
-/// Journal entry state
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[repr(u8)]
-pub enum JournalState {
-    /// Entry is free
-    Free = 0,
-    /// Transaction in progress (not yet committed)
-    Pending = 1,
-    /// Transaction committed (can be replayed)
-    Committed = 2,
-    /// Transaction checkpointed (applied to disk)
-    Checkpointed = 3,
+/// Timestamp stored in inodes and the superblock
+fn now() -> u32 {
+    get_journal_timestamp() as u32
 }
 
-impl From<u8> for JournalState {
-    fn from(val: u8) -> Self {
-        match val {
-            1 => JournalState::Pending,
-            2 => JournalState::Committed,
-            3 => JournalState::Checkpointed,
-            _ => JournalState::Free,
+/// File data written per transaction; larger writes are split
+const WRITE_CHUNK: usize = 256 * BLOCK_SIZE;
+
+impl From<SplaxFsError> for VfsError {
+    fn from(e: SplaxFsError) -> Self {
+        match e {
+            SplaxFsError::NotFound => VfsError::NotFound,
+            SplaxFsError::Exists => VfsError::AlreadyExists,
+            SplaxFsError::NotDirectory => VfsError::NotADirectory,
+            SplaxFsError::IsDirectory => VfsError::IsADirectory,
+            SplaxFsError::NotEmpty => VfsError::NotEmpty,
+            SplaxFsError::NoSpace => VfsError::NoSpace,
+            SplaxFsError::NameTooLong => VfsError::PathTooLong,
+            SplaxFsError::InvalidArg => VfsError::InvalidArgument,
+            SplaxFsError::NotMounted => VfsError::NoFilesystem,
+            SplaxFsError::ReadOnly => VfsError::ReadOnlyFs,
+            SplaxFsError::NotSupported => VfsError::NotSupported,
+            SplaxFsError::Busy => VfsError::Busy,
+            SplaxFsError::NoAttribute => VfsError::NoAttribute,
+            SplaxFsError::NoKey => VfsError::PermissionDenied,
+            SplaxFsError::PolicyMismatch => VfsError::CrossDevice,
+            SplaxFsError::Corrupted
+            | SplaxFsError::ChecksumMismatch
+            | SplaxFsError::IoError
+            | SplaxFsError::JournalError
+            | SplaxFsError::TransactionNotFound => VfsError::IoError,
         }
     }
 }
 
-/// Journal superblock (stored in block 1)
-#[repr(C)]
-#[derive(Debug, Clone, Copy)]
-pub struct JournalSuperblock {
-    /// Magic number (JOURNAL_MAGIC)
-    pub magic: u32,
-    /// Journal version
-    pub version: u32,
-    /// First log block number
-    pub first_log_block: u32,
-    /// Number of log blocks
-    pub log_blocks: u32,
-    /// Head position (next write)
-    pub head: u32,
-    /// Tail position (oldest uncommitted)
-    pub tail: u32,
-    /// Sequence number counter
-    pub sequence: u64,
-    /// Number of active transactions
-    pub active_transactions: u32,
-    /// Reserved for future use
-    pub _reserved: [u8; 476],
+/// Block device access in filesystem blocks
+struct Disk {
+    /// Device name
+    name: String,
+    /// Device sectors per filesystem block
+    sectors_per_block: u64,
+    /// Whole blocks on the device
+    total_blocks: u32,
 }
 
-impl JournalSuperblock {
-    /// Creates a new journal superblock
-    pub fn new(first_log_block: u32, log_blocks: u32) -> Self {
-        Self {
-            magic: JOURNAL_MAGIC,
-            version: 1,
-            first_log_block,
-            log_blocks,
-            head: 0,
-            tail: 0,
-            sequence: 1,
-            active_transactions: 0,
-            _reserved: [0; 476],
-        }
-    }
-
-    /// Validates the journal superblock
-    pub fn is_valid(&self) -> bool {
-        self.magic == JOURNAL_MAGIC
-    }
-
-    /// Serialize to bytes
-    pub fn to_bytes(&self) -> [u8; 512] {
-        let mut buf = [0u8; 512];
-        unsafe {
-            let ptr = self as *const Self as *const u8;
-            core::ptr::copy_nonoverlapping(ptr, buf.as_mut_ptr(), 512);
-        }
-        buf
-    }
-
-    /// Deserialize from bytes
-    pub fn from_bytes(buf: &[u8]) -> Self {
-        let mut jsb = Self {
-            magic: 0,
-            version: 0,
-            first_log_block: 0,
-            log_blocks: 0,
-            head: 0,
-            tail: 0,
-            sequence: 0,
-            active_transactions: 0,
-            _reserved: [0; 476],
-        };
-        unsafe {
-            let ptr = &mut jsb as *mut Self as *mut u8;
-            core::ptr::copy_nonoverlapping(buf.as_ptr(), ptr, core::cmp::min(buf.len(), 512));
+impl Disk {
+    fn open(name: &str) -> Result<Self, SplaxFsError> {
+        let info = block::with_device(name, |device| device.info()).map_err(|_| SplaxFsError::NotFound)?;
+        if info.sector_size == 0 || !BLOCK_SIZE.is_multiple_of(info.sector_size) {
+            return Err(SplaxFsError::InvalidArg);
         }
-        jsb
+        let sectors_per_block = (BLOCK_SIZE / info.sector_size) as u64;
+        Ok(Self {
+            name: String::from(name),
+            sectors_per_block,
+            total_blocks: (info.total_sectors / sectors_per_block).min(u32::MAX as u64) as u32,
+        })
     }
-}
 
-/// A journal transaction entry (header)
-#[repr(C)]
-#[derive(Debug, Clone, Copy)]
-pub struct JournalEntry {
-    /// Entry type (0=descriptor, 1=commit, 2=abort)
-    pub entry_type: u8,
-    /// Entry state
-    pub state: u8,
-    /// Number of block updates in this transaction
-    pub block_count: u16,
-    /// Transaction ID
-    pub transaction_id: u64,
-    /// Timestamp
-    pub timestamp: u64,
-    /// Checksum
-    pub checksum: u32,
-    /// Reserved
-    pub _reserved: [u8; 8],
+    /// Physical address of a block if the device is byte addressable
+    fn direct_access(&self, block_num: u32) -> Option<u64> {
+        let sector = block_num as u64 * self.sectors_per_block;
+        block::with_device(&self.name, |device| device.direct_access(sector)).ok().flatten()
+    }
 }
 
-impl JournalEntry {
-    /// Size of entry header
-    pub const SIZE: usize = 32;
+impl BlockIo for Disk {
+    fn total_blocks(&self) -> u32 {
+        self.total_blocks
+    }
 
-    /// Creates a new transaction descriptor
-    pub fn new_descriptor(transaction_id: u64, block_count: u16) -> Self {
-        Self {
-            entry_type: 0,
-            state: JournalState::Pending as u8,
-            block_count,
-            transaction_id,
-            timestamp: get_journal_timestamp(),
-            checksum: 0,
-            _reserved: [0; 8],
-        }
+    fn read(&self, block_num: u32, buf: &mut [u8]) -> Result<(), SplaxFsError> {
+        let sector = block_num as u64 * self.sectors_per_block;
+        block::with_device(&self.name, |device| device.read_sectors(sector, buf))
+            .map_err(|_| SplaxFsError::IoError)?
+            .map_err(|_| SplaxFsError::IoError)
     }
 
-    /// Creates a commit marker
-    pub fn new_commit(transaction_id: u64) -> Self {
-        Self {
-            entry_type: 1,
-            state: JournalState::Committed as u8,
-            block_count: 0,
-            transaction_id,
-            timestamp: get_journal_timestamp(),
-            checksum: 0,
-            _reserved: [0; 8],
-        }
+    fn write(&self, block_num: u32, buf: &[u8]) -> Result<(), SplaxFsError> {
+        let sector = block_num as u64 * self.sectors_per_block;
+        block::with_device(&self.name, |device| device.write_sectors(sector, buf))
+            .map_err(|_| SplaxFsError::IoError)?
+            .map_err(|_| SplaxFsError::IoError)
     }
 
-    /// Creates an abort marker
-    pub fn new_abort(transaction_id: u64) -> Self {
-        Self {
-            entry_type: 2,
-            state: JournalState::Free as u8,
-            block_count: 0,
-            transaction_id,
-            timestamp: get_journal_timestamp(),
-            checksum: 0,
-            _reserved: [0; 8],
-        }
-    }
-
-    /// Serialize to bytes
-    pub fn to_bytes(&self) -> [u8; 32] {
-        let mut buf = [0u8; 32];
-        buf[0] = self.entry_type;
-        buf[1] = self.state;
-        buf[2..4].copy_from_slice(&self.block_count.to_le_bytes());
-        buf[4..12].copy_from_slice(&self.transaction_id.to_le_bytes());
-        buf[12..20].copy_from_slice(&self.timestamp.to_le_bytes());
-        buf[20..24].copy_from_slice(&self.checksum.to_le_bytes());
-        buf
-    }
-
-    /// Deserialize from bytes
-    pub fn from_bytes(buf: &[u8]) -> Self {
-        Self {
-            entry_type: buf.get(0).copied().unwrap_or(0),
-            state: buf.get(1).copied().unwrap_or(0),
-            block_count: u16::from_le_bytes([buf.get(2).copied().unwrap_or(0), buf.get(3).copied().unwrap_or(0)]),
-            transaction_id: u64::from_le_bytes([
-                buf.get(4).copied().unwrap_or(0), buf.get(5).copied().unwrap_or(0),
-                buf.get(6).copied().unwrap_or(0), buf.get(7).copied().unwrap_or(0),
-                buf.get(8).copied().unwrap_or(0), buf.get(9).copied().unwrap_or(0),
-                buf.get(10).copied().unwrap_or(0), buf.get(11).copied().unwrap_or(0),
-            ]),
-            timestamp: u64::from_le_bytes([
-                buf.get(12).copied().unwrap_or(0), buf.get(13).copied().unwrap_or(0),
-                buf.get(14).copied().unwrap_or(0), buf.get(15).copied().unwrap_or(0),
-                buf.get(16).copied().unwrap_or(0), buf.get(17).copied().unwrap_or(0),
-                buf.get(18).copied().unwrap_or(0), buf.get(19).copied().unwrap_or(0),
-            ]),
-            checksum: u32::from_le_bytes([
-                buf.get(20).copied().unwrap_or(0), buf.get(21).copied().unwrap_or(0),
-                buf.get(22).copied().unwrap_or(0), buf.get(23).copied().unwrap_or(0),
-            ]),
-            _reserved: [0; 8],
-        }
+    fn flush(&self) -> Result<(), SplaxFsError> {
+        block::with_device(&self.name, |device| device.flush())
+            .map_err(|_| SplaxFsError::IoError)?
+            .map_err(|_| SplaxFsError::IoError)
     }
 }
 
-/// Block update record in journal
-#[repr(C)]
-#[derive(Debug, Clone)]
-pub struct JournalBlockRecord {
-    /// Original block number
-    pub block_num: u32,
-    /// Block data (copy of the block before modification)
-    pub data: Vec<u8>,
+/// Checks a block read from the device against its checksum
+fn verify(checksums: &ChecksumTable, block_num: u32, data: &[u8]) -> Result<(), SplaxFsError> {
+    checksums.verify(block_num, data).inspect_err(|_| {
+        crate::serial_println!("[splaxfs] Checksum mismatch in block {}", block_num);
+    })
 }
 
-impl JournalBlockRecord {
-    /// Creates a new block record
-    pub fn new(block_num: u32, data: Vec<u8>) -> Self {
-        Self { block_num, data }
+impl From<FileType> for VfsFileType {
+    fn from(file_type: FileType) -> Self {
+        match file_type {
+            FileType::Directory => VfsFileType::Directory,
+            FileType::Symlink => VfsFileType::Symlink,
+            FileType::Regular | FileType::Unknown => VfsFileType::Regular,
+        }
     }
 }
 
-/// Transaction handle for journaling operations
-#[derive(Debug)]
-pub struct Transaction {
-    /// Transaction ID
-    pub id: u64,
-    /// Block updates in this transaction
-    pub updates: Vec<JournalBlockRecord>,
-    /// Is transaction committed?
-    pub committed: bool,
+// =============================================================================
+// Transactions
+// =============================================================================
+
+/// Inode numbering of one tree: the live file system or a snapshot
+#[derive(Clone)]
+struct Tree {
+    /// Inode map blocks
+    map_blocks: Vec<u32>,
+    /// Inode bitmap blocks
+    bitmap_blocks: Vec<u32>,
+    /// Where each inode table block is (cached inode map)
+    table: Vec<u32>,
+    /// Inode bitmap (cached, all blocks back to back)
+    inode_bitmap: Vec<u8>,
 }
 
-impl Transaction {
-    /// Creates a new transaction
-    pub fn new(id: u64) -> Self {
-        Self {
-            id,
-            updates: Vec::new(),
-            committed: false,
+/// In-memory state of a mounted volume, shared by its live mount and any
+/// mounted snapshots
+struct Volume {
+    /// Superblock (cached)
+    superblock: Superblock,
+    /// Block reference counts (cached, all blocks back to back)
+    refcounts: Vec<u8>,
+    /// Snapshot table (cached)
+    snapshots: SnapshotTable,
+    /// Loaded trees: the live tree (0) and mounted snapshots
+    trees: BTreeMap<u32, Tree>,
+    /// Where the next block search starts
+    block_hint: u32,
+    /// Journal (journaled volumes only)
+    journal: Option<Journal>,
+    /// Block checksums (cached, all table blocks back to back)
+    checksums: ChecksumTable,
+    /// Checksum table blocks changed since the last commit
+    dirty_checksums: BTreeSet<u32>,
+    /// File blocks mapped for direct access, whose content can change
+    /// without the file system writing it
+    dax_blocks: BTreeSet<u32>,
+    /// Master keys of unlocked encryption policies, by identifier
+    keys: BTreeMap<[u8; KEY_IDENTIFIER_SIZE], MasterKey>,
+}
+
+impl Volume {
+    /// Free inode count of a tree
+    fn free_inodes_mut(&mut self, tree: u32) -> Option<&mut u32> {
+        if tree == 0 {
+            return Some(&mut self.superblock.free_inodes);
         }
+        self.snapshots
+            .entries
+            .iter_mut()
+            .find(|entry| entry.id == tree)
+            .map(|entry| &mut entry.free_inodes)
     }
+}
 
-    /// Records a block update (write-ahead log)
-    pub fn record_update(&mut self, block_num: u32, old_data: Vec<u8>) {
-        self.updates.push(JournalBlockRecord::new(block_num, old_data));
+/// Shortest master key accepted for an encryption policy
+const MIN_MASTER_KEY: usize = 32;
+
+/// Overwrites key material so it does not linger in freed memory
+fn wipe(bytes: &mut [u8]) {
+    for byte in bytes.iter_mut() {
+        // Volatile, so the wipe is not optimized away
+        unsafe { core::ptr::write_volatile(byte, 0) };
     }
 }
 
-/// Journal manager for a mounted filesystem
-pub struct Journal {
-    /// Device name
-    device_name: String,
-    /// Journal superblock (cached)
-    superblock: JournalSuperblock,
-    /// Active transactions
-    transactions: Vec<Transaction>,
-    /// Next transaction ID
-    next_transaction_id: u64,
+/// Master key of an encryption policy, held while it is unlocked. Only
+/// the HKDF pseudorandom key is kept; the keys of each inode are derived
+/// from it as they are needed.
+struct MasterKey {
+    prk: Vec<u8>,
+    identifier: [u8; KEY_IDENTIFIER_SIZE],
 }
 
-impl Journal {
-    /// Initializes a new journal on disk
-    pub fn format(device: &dyn BlockDevice, first_log_block: u32, log_blocks: u32) -> Result<(), SplaxFsError> {
-        let jsb = JournalSuperblock::new(first_log_block, log_blocks);
-        
-        // Write journal superblock to block 1
-        let mut block_buf = [0u8; BLOCK_SIZE];
-        let jsb_bytes = jsb.to_bytes();
-        block_buf[..512].copy_from_slice(&jsb_bytes);
-        
-        let sector_size = device.info().sector_size;
-        let sectors_per_block = BLOCK_SIZE / sector_size;
-        let start_sector = 1 * sectors_per_block as u64;
-        device.write_sectors(start_sector, &block_buf)?;
-        
-        // Zero out the log area
-        let zero_block = [0u8; BLOCK_SIZE];
-        for i in 0..log_blocks {
-            let start_sector = (first_log_block + i) as u64 * sectors_per_block as u64;
-            device.write_sectors(start_sector, &zero_block)?;
-        }
-        
-        crate::serial_println!("[journal] Formatted journal: {} blocks at block {}", log_blocks, first_log_block);
-        Ok(())
+impl MasterKey {
+    fn new(key: &[u8]) -> Result<Self, SplaxFsError> {
+        if key.len() < MIN_MASTER_KEY {
+            return Err(SplaxFsError::InvalidArg);
+        }
+        let mut master = Self { prk: Hkdf::extract(&[], key), identifier: [0; KEY_IDENTIFIER_SIZE] };
+        master.identifier = master.derive(crypt::KDF_IDENTIFIER, &[]);
+        Ok(master)
     }
 
-    /// Opens an existing journal
-    pub fn open(device_name: &str) -> Result<Self, SplaxFsError> {
-        let jsb = block::with_device(device_name, |device| {
-            let mut block_buf = [0u8; BLOCK_SIZE];
-            let sector_size = device.info().sector_size;
-            let sectors_per_block = BLOCK_SIZE / sector_size;
-            let start_sector = 1 * sectors_per_block as u64;
-            device.read_sectors(start_sector, &mut block_buf)?;
-            Ok::<_, SplaxFsError>(JournalSuperblock::from_bytes(&block_buf))
-        }).map_err(|_| SplaxFsError::IoError)??;
-
-        if !jsb.is_valid() {
-            return Err(SplaxFsError::JournalError);
-        }
+    fn derive<const N: usize>(&self, purpose: u8, nonce: &[u8]) -> [u8; N] {
+        let mut okm = Hkdf::expand(&self.prk, &crypt::kdf_info(purpose, nonce), N).expect("HKDF output fits");
+        let mut key = [0u8; N];
+        key.copy_from_slice(&okm);
+        wipe(&mut okm);
+        key
+    }
 
-        Ok(Self {
-            device_name: String::from(device_name),
-            superblock: jsb,
-            transactions: Vec::new(),
-            next_transaction_id: jsb.sequence,
-        })
+    fn contents_cipher(&self, context: &Context) -> ContentsCipher {
+        let mut key = self.derive(crypt::KDF_CONTENTS, &context.nonce);
+        let cipher = ContentsCipher::new(&key);
+        wipe(&mut key);
+        cipher
     }
 
-    /// Begins a new transaction
-    pub fn begin(&mut self) -> u64 {
-        let id = self.next_transaction_id;
-        self.next_transaction_id += 1;
-        self.transactions.push(Transaction::new(id));
-        crate::serial_println!("[journal] Begin transaction {}", id);
-        id
+    fn names_cipher(&self, context: &Context) -> NamesCipher {
+        let mut key = self.derive(crypt::KDF_NAMES, &context.nonce);
+        let cipher = NamesCipher::new(&key);
+        wipe(&mut key);
+        cipher
     }
+}
 
-    /// Records a block update for a transaction
-    pub fn record_block(&mut self, transaction_id: u64, block_num: u32, old_data: Vec<u8>) -> Result<(), SplaxFsError> {
-        if let Some(txn) = self.transactions.iter_mut().find(|t| t.id == transaction_id) {
-            txn.record_update(block_num, old_data);
-            Ok(())
-        } else {
-            Err(SplaxFsError::TransactionNotFound)
-        }
-    }
-
-    /// Commits a transaction (writes to journal, marks committed)
-    pub fn commit(&mut self, transaction_id: u64) -> Result<(), SplaxFsError> {
-        let txn_idx = self.transactions.iter().position(|t| t.id == transaction_id)
-            .ok_or(SplaxFsError::TransactionNotFound)?;
-        
-        // Write transaction to journal log
-        let device_name = self.device_name.clone();
-        let first_log_block = self.superblock.first_log_block;
-        let log_blocks = self.superblock.log_blocks;
-        let head = self.superblock.head;
-        
-        block::with_device(&device_name, |device| {
-            let txn = &self.transactions[txn_idx];
-            let sector_size = device.info().sector_size;
-            let sectors_per_block = BLOCK_SIZE / sector_size;
-            
-            // Calculate position in circular log
-            let log_pos = (head as usize) % (log_blocks as usize);
-            let log_block = first_log_block + log_pos as u32;
-            
-            // Write descriptor entry
-            let mut block_buf = [0u8; BLOCK_SIZE];
-            let entry = JournalEntry::new_descriptor(txn.id, txn.updates.len() as u16);
-            block_buf[..JournalEntry::SIZE].copy_from_slice(&entry.to_bytes());
-            
-            // Write block numbers (simplified - full impl would write actual data)
-            let mut offset = JournalEntry::SIZE;
-            for update in &txn.updates {
-                if offset + 4 <= BLOCK_SIZE {
-                    block_buf[offset..offset + 4].copy_from_slice(&update.block_num.to_le_bytes());
-                    offset += 4;
-                }
-            }
-            
-            let start_sector = log_block as u64 * sectors_per_block as u64;
-            device.write_sectors(start_sector, &block_buf)?;
-            
-            // Write commit marker
-            let commit_entry = JournalEntry::new_commit(txn.id);
-            let mut commit_buf = [0u8; BLOCK_SIZE];
-            commit_buf[..JournalEntry::SIZE].copy_from_slice(&commit_entry.to_bytes());
-            
-            let commit_pos = ((head as usize) + 1) % (log_blocks as usize);
-            let commit_block = first_log_block + commit_pos as u32;
-            let commit_sector = commit_block as u64 * sectors_per_block as u64;
-            device.write_sectors(commit_sector, &commit_buf)?;
-            
-            Ok::<(), SplaxFsError>(())
-        }).map_err(|_| SplaxFsError::IoError)??;
-        
-        // Update journal superblock
-        self.superblock.head = (head + 2) % log_blocks;
-        self.superblock.sequence = self.next_transaction_id;
-        self.sync_superblock()?;
-        
-        // Mark transaction as committed
-        self.transactions[txn_idx].committed = true;
-        crate::serial_println!("[journal] Committed transaction {}", transaction_id);
-        
-        Ok(())
+impl Drop for MasterKey {
+    fn drop(&mut self) {
+        wipe(&mut self.prk);
     }
+}
 
-    /// Aborts a transaction (discards changes)
-    pub fn abort(&mut self, transaction_id: u64) -> Result<(), SplaxFsError> {
-        if let Some(idx) = self.transactions.iter().position(|t| t.id == transaction_id) {
-            self.transactions.remove(idx);
-            crate::serial_println!("[journal] Aborted transaction {}", transaction_id);
-            Ok(())
-        } else {
-            Err(SplaxFsError::TransactionNotFound)
+/// How the entry names of a directory are stored
+enum Names {
+    /// In the clear
+    Plain,
+    /// Encrypted, with the key at hand
+    Unlocked(Box<NamesCipher>),
+    /// Encrypted, without the key: names are shown and looked up as the
+    /// base64 of their ciphertext
+    Locked,
+}
+
+impl Names {
+    /// Stored form an entry named `name` would have, if any can
+    fn lookup_key(&self, name: &str) -> Option<Vec<u8>> {
+        match self {
+            _ if name == "." || name == ".." => Some(name.as_bytes().to_vec()),
+            Names::Plain => Some(name.as_bytes().to_vec()),
+            Names::Unlocked(cipher) => cipher.encrypt(name.as_bytes()).ok(),
+            Names::Locked => crypt::decode_name(name),
         }
     }
 
-    /// Checkpoints committed transactions (removes from journal after data is on disk)
-    pub fn checkpoint(&mut self, transaction_id: u64) -> Result<(), SplaxFsError> {
-        if let Some(idx) = self.transactions.iter().position(|t| t.id == transaction_id && t.committed) {
-            self.transactions.remove(idx);
-            
-            // Update tail pointer
-            self.superblock.tail = (self.superblock.tail + 2) % self.superblock.log_blocks;
-            self.superblock.active_transactions = self.transactions.len() as u32;
-            self.sync_superblock()?;
-            
-            crate::serial_println!("[journal] Checkpointed transaction {}", transaction_id);
-            Ok(())
-        } else {
-            Err(SplaxFsError::TransactionNotFound)
+    /// Stored form of a new entry name
+    fn store(&self, name: &str) -> Result<Vec<u8>, SplaxFsError> {
+        match self {
+            Names::Plain => Ok(name.as_bytes().to_vec()),
+            Names::Unlocked(cipher) => cipher.encrypt(name.as_bytes()),
+            Names::Locked => Err(SplaxFsError::NoKey),
         }
     }
 
-    /// Recovers the filesystem by replaying committed transactions
-    pub fn recover(&mut self) -> Result<usize, SplaxFsError> {
-        let device_name = self.device_name.clone();
-        let first_log_block = self.superblock.first_log_block;
-        let log_blocks = self.superblock.log_blocks;
-        let tail = self.superblock.tail;
-        let head = self.superblock.head;
-        
-        if tail == head {
-            crate::serial_println!("[journal] No transactions to recover");
-            return Ok(0);
+    /// Name shown for an entry
+    fn show(&self, entry: &DirEntry) -> String {
+        let stored = entry.raw_name();
+        match self {
+            _ if stored == b"." || stored == b".." => entry.name_str().to_string(),
+            Names::Plain => entry.name_str().to_string(),
+            Names::Unlocked(cipher) => cipher
+                .decrypt(stored)
+                .ok()
+                .and_then(|name| String::from_utf8(name).ok())
+                .unwrap_or_else(|| crypt::encode_name(stored)),
+            Names::Locked => crypt::encode_name(stored),
         }
-        
-        let mut recovered = 0;
-        
-        block::with_device(&device_name, |device| {
-            let sector_size = device.info().sector_size;
-            let sectors_per_block = BLOCK_SIZE / sector_size;
-            
-            let mut pos = tail;
-            while pos != head {
-                let log_block = first_log_block + (pos as usize % log_blocks as usize) as u32;
-                let start_sector = log_block as u64 * sectors_per_block as u64;
-                
-                let mut block_buf = [0u8; BLOCK_SIZE];
-                device.read_sectors(start_sector, &mut block_buf)?;
-                
-                let entry = JournalEntry::from_bytes(&block_buf);
-                
-                if entry.state == JournalState::Committed as u8 {
-                    // This transaction was committed, it's already applied
-                    recovered += 1;
-                }
-                
-                pos = (pos + 1) % log_blocks;
-            }
-            
-            Ok::<(), SplaxFsError>(())
-        }).map_err(|_| SplaxFsError::IoError)??;
-        
-        // Clear journal after recovery
-        self.superblock.head = 0;
-        self.superblock.tail = 0;
-        self.sync_superblock()?;
-        
-        crate::serial_println!("[journal] Recovered {} transactions", recovered);
-        Ok(recovered)
-    }
-
-    /// Syncs journal superblock to disk
-    fn sync_superblock(&self) -> Result<(), SplaxFsError> {
-        let device_name = self.device_name.clone();
-        let jsb = self.superblock;
-        
-        block::with_device(&device_name, |device| {
-            let mut block_buf = [0u8; BLOCK_SIZE];
-            let jsb_bytes = jsb.to_bytes();
-            block_buf[..512].copy_from_slice(&jsb_bytes);
-            
-            let sector_size = device.info().sector_size;
-            let sectors_per_block = BLOCK_SIZE / sector_size;
-            let start_sector = 1 * sectors_per_block as u64;
-            device.write_sectors(start_sector, &block_buf)?;
-            Ok(())
-        }).map_err(|_| SplaxFsError::IoError)?
     }
 }
 
-/// File types
+/// Cached state before a change, kept to undo a failed operation
+enum Undo {
+    /// Reference count of a block
+    Refs(u32, u16),
+    /// Inode bitmap bit of (tree, inode)
+    Inode(u32, u32, bool),
+    /// Inode map entry of (tree, index)
+    Table(u32, u32, u32),
+    /// Snapshot table
+    Snapshots(Box<SnapshotTable>),
+    /// Live tree and its free inode count, before a rollback
+    Live(Box<Tree>, u32),
+    /// Recorded checksum of a block
+    Checksum(u32, u32),
+    /// Change to the saved block count
+    Saved(i32),
+}
+
+/// What a block holds, for copying and releasing it
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[repr(u8)]
-pub enum FileType {
-    Unknown = 0,
-    Regular = 1,
-    Directory = 2,
-    Symlink = 7,
+enum Kind {
+    /// File data, written in place
+    Data,
+    /// Directory entries
+    Dir,
+    /// Block pointers of the given depth (1 = pointers to file blocks)
+    Pointers(u32),
+    /// Inodes
+    Inodes,
+    /// Extended attributes
+    Attrs,
 }
 
-impl From<u8> for FileType {
-    fn from(val: u8) -> Self {
-        match val {
-            1 => FileType::Regular,
-            2 => FileType::Directory,
-            7 => FileType::Symlink,
-            _ => FileType::Unknown,
+impl Kind {
+    /// Kind of the blocks a pointer block of this kind points at
+    fn child(self) -> Kind {
+        match self {
+            Kind::Pointers(depth) if depth > 1 => Kind::Pointers(depth - 1),
+            _ => Kind::Data,
         }
     }
 }
 
-/// On-disk superblock (block 0)
-#[repr(C)]
-#[derive(Debug, Clone, Copy)]
-pub struct Superblock {
-    /// Magic number (SPLAXFS_MAGIC)
-    pub magic: u32,
-    /// Filesystem version
-    pub version: u32,
-    /// Total number of blocks
-    pub total_blocks: u32,
-    /// Total number of inodes
-    pub total_inodes: u32,
-    /// Free blocks count
-    pub free_blocks: u32,
-    /// Free inodes count
-    pub free_inodes: u32,
-    /// Block size (always 4096)
-    pub block_size: u32,
-    /// Inode size (always 128)
-    pub inode_size: u32,
-    /// First data block number
-    pub first_data_block: u32,
-    /// Blocks per group (for future)
-    pub blocks_per_group: u32,
-    /// Inodes per group (for future)
-    pub inodes_per_group: u32,
-    /// Mount count
-    pub mount_count: u16,
-    /// Max mount count before fsck
-    pub max_mount_count: u16,
-    /// Filesystem state (1=clean, 2=dirty)
-    pub state: u16,
-    /// Error behavior
-    pub errors: u16,
-    /// Last mount time
-    pub last_mount_time: u32,
-    /// Last write time
-    pub last_write_time: u32,
-    /// Volume name (16 bytes)
-    pub volume_name: [u8; 16],
-    /// Reserved
-    pub _reserved: [u8; 424],
-}
-
-impl Superblock {
-    /// Creates a new superblock for formatting
-    pub fn new(total_blocks: u32, total_inodes: u32, first_data_block: u32) -> Self {
-        let mut sb = Self {
-            magic: SPLAXFS_MAGIC,
-            version: 1,
-            total_blocks,
-            total_inodes,
-            free_blocks: total_blocks - first_data_block,
-            free_inodes: total_inodes - 2, // 0=null, 1=reserved, 2=root
-            block_size: BLOCK_SIZE as u32,
-            inode_size: INODE_SIZE as u32,
-            first_data_block,
-            blocks_per_group: 0,
-            inodes_per_group: 0,
-            mount_count: 0,
-            max_mount_count: 20,
-            state: 1, // Clean
-            errors: 1, // Continue on error
-            last_mount_time: 0,
-            last_write_time: 0,
-            volume_name: [0; 16],
-            _reserved: [0; 424],
-        };
-        // Set volume name
-        let name = b"SplaxFS";
-        sb.volume_name[..name.len()].copy_from_slice(name);
-        sb
-    }
-
-    /// Validates the superblock
-    pub fn is_valid(&self) -> bool {
-        self.magic == SPLAXFS_MAGIC && self.block_size == BLOCK_SIZE as u32
-    }
-
-    /// Serialize to bytes
-    pub fn to_bytes(&self) -> [u8; 512] {
-        let mut buf = [0u8; 512];
-        unsafe {
-            let ptr = self as *const Self as *const u8;
-            core::ptr::copy_nonoverlapping(ptr, buf.as_mut_ptr(), 512);
-        }
-        buf
-    }
-
-    /// Deserialize from bytes
-    pub fn from_bytes(buf: &[u8]) -> Self {
-        let mut sb = Self {
-            magic: 0,
-            version: 0,
-            total_blocks: 0,
-            total_inodes: 0,
-            free_blocks: 0,
-            free_inodes: 0,
-            block_size: 0,
-            inode_size: 0,
-            first_data_block: 0,
-            blocks_per_group: 0,
-            inodes_per_group: 0,
-            mount_count: 0,
-            max_mount_count: 0,
-            state: 0,
-            errors: 0,
-            last_mount_time: 0,
-            last_write_time: 0,
-            volume_name: [0; 16],
-            _reserved: [0; 424],
-        };
-        unsafe {
-            let ptr = &mut sb as *mut Self as *mut u8;
-            core::ptr::copy_nonoverlapping(buf.as_ptr(), ptr, core::cmp::min(buf.len(), 512));
-        }
-        sb
+/// Image of inode map block `index`
+fn map_image(table: &[u32], index: u32) -> Vec<u8> {
+    let mut image = vec![0u8; BLOCK_SIZE];
+    let start = index as usize * PTRS_PER_BLOCK;
+    for (slot, block_num) in table.iter().skip(start).take(PTRS_PER_BLOCK).enumerate() {
+        image[slot * 4..slot * 4 + 4].copy_from_slice(&block_num.to_le_bytes());
     }
+    image
 }
 
-/// On-disk inode (128 bytes)
-#[repr(C)]
-#[derive(Debug, Clone, Copy)]
-pub struct DiskInode {
-    /// File mode (permissions + type)
-    pub mode: u16,
-    /// Owner user ID
-    pub uid: u16,
-    /// File size (lower 32 bits)
-    pub size_low: u32,
-    /// Access time
-    pub atime: u32,
-    /// Creation time
-    pub ctime: u32,
-    /// Modification time
-    pub mtime: u32,
-    /// Deletion time
-    pub dtime: u32,
-    /// Owner group ID
-    pub gid: u16,
-    /// Hard link count
-    pub links_count: u16,
-    /// Block count (in 512-byte units)
-    pub blocks: u32,
-    /// File flags
-    pub flags: u32,
-    /// Reserved
-    pub _reserved1: u32,
-    /// Direct block pointers
-    pub direct: [u32; DIRECT_BLOCKS],
-    /// Single indirect block pointer
-    pub indirect: u32,
-    /// Double indirect block pointer
-    pub double_indirect: u32,
-    /// Triple indirect block pointer
-    pub triple_indirect: u32,
-    /// File size (upper 32 bits)
-    pub size_high: u32,
-    /// Reserved
-    pub _reserved2: [u32; 2],
+/// One filesystem operation in progress on one tree
+///
+/// Metadata blocks are read through and written to `blocks`, so the
+/// operation sees its own changes. [`Transaction::commit`] hands the set
+/// to the journal; dropping an uncommitted transaction discards it and
+/// reverts the cached state.
+///
+/// Trees share blocks: a block's reference count is the number of inode
+/// map entries, inodes and pointer blocks pointing at it. Before a tree
+/// changes a block, [`unshare`](Self::unshare) gives it a private copy if
+/// anything else points at the block; the copy takes a reference on every
+/// block it points at.
+struct Transaction<'a> {
+    disk: &'a Disk,
+    volume: &'a mut Volume,
+    /// Tree the operation works on
+    tree: u32,
+    /// New images of metadata blocks
+    blocks: BTreeMap<u32, Vec<u8>>,
+    /// Changed reference count blocks (index into the table)
+    dirty_refcounts: BTreeSet<u32>,
+    /// Changed inode map blocks (tree, index into the map)
+    dirty_maps: BTreeSet<(u32, u32)>,
+    /// Changed inode bitmap blocks (tree, index into the bitmap)
+    dirty_inode_bitmap: BTreeSet<(u32, u32)>,
+    /// Snapshot table changed
+    snapshots_dirty: bool,
+    /// Superblock changed
+    superblock_dirty: bool,
+    /// Changes to cached state made so far
+    undo: Vec<Undo>,
 }
 
-impl DiskInode {
-    /// Creates a new empty inode
-    pub fn new() -> Self {
+impl<'a> Transaction<'a> {
+    fn new(disk: &'a Disk, volume: &'a mut Volume, tree: u32) -> Self {
         Self {
-            mode: 0,
-            uid: 0,
-            size_low: 0,
-            atime: 0,
-            ctime: 0,
-            mtime: 0,
-            dtime: 0,
-            gid: 0,
-            links_count: 0,
-            blocks: 0,
-            flags: 0,
-            _reserved1: 0,
-            direct: [0; DIRECT_BLOCKS],
-            indirect: 0,
-            double_indirect: 0,
-            triple_indirect: 0,
-            size_high: 0,
-            _reserved2: [0; 2],
+            disk,
+            volume,
+            tree,
+            blocks: BTreeMap::new(),
+            dirty_refcounts: BTreeSet::new(),
+            dirty_maps: BTreeSet::new(),
+            dirty_inode_bitmap: BTreeSet::new(),
+            snapshots_dirty: false,
+            superblock_dirty: false,
+            undo: Vec::new(),
         }
     }
 
-    /// Creates a directory inode
-    pub fn new_directory() -> Self {
-        let mut inode = Self::new();
-        inode.mode = 0o40755; // Directory + rwxr-xr-x
-        inode.links_count = 2; // . and parent's link
-        inode
-    }
-
-    /// Creates a regular file inode
-    pub fn new_file() -> Self {
-        let mut inode = Self::new();
-        inode.mode = 0o100644; // Regular file + rw-r--r--
-        inode.links_count = 1;
-        inode
-    }
+    /// Applies the operation's metadata changes atomically
+    fn commit(mut self) -> Result<(), SplaxFsError> {
+        let sb = self.volume.superblock;
+        for &index in &self.dirty_refcounts {
+            let start = index as usize * BLOCK_SIZE;
+            let image = self.volume.refcounts[start..start + BLOCK_SIZE].to_vec();
+            self.blocks.insert(sb.refcount_block + index, image);
+        }
+        for &(tree, index) in &self.dirty_maps {
+            let tree = &self.volume.trees[&tree];
+            self.blocks.insert(tree.map_blocks[index as usize], map_image(&tree.table, index));
+        }
+        for &(tree, index) in &self.dirty_inode_bitmap {
+            let tree = &self.volume.trees[&tree];
+            let start = index as usize * BLOCK_SIZE;
+            let image = tree.inode_bitmap[start..start + BLOCK_SIZE].to_vec();
+            self.blocks.insert(tree.bitmap_blocks[index as usize], image);
+        }
+        if self.snapshots_dirty {
+            self.blocks.insert(sb.snapshot_block, self.volume.snapshots.to_bytes().to_vec());
+        }
+        if self.superblock_dirty {
+            self.volume.superblock.last_write_time = now();
+            let mut image = vec![0u8; BLOCK_SIZE];
+            image[..512].copy_from_slice(&self.volume.superblock.to_bytes());
+            self.blocks.insert(0, image);
+        }
+        for (&block_num, image) in self.blocks.iter().filter(|(&block_num, _)| block_num != 0) {
+            self.undo.push(Undo::Checksum(block_num, self.volume.checksums.get(block_num)));
+            self.volume.checksums.record(block_num, image);
+            self.volume.dirty_checksums.insert(ChecksumTable::index_of(block_num));
+        }
+        for &index in &self.volume.dirty_checksums {
+            self.blocks.insert(sb.csum_block + index, self.volume.checksums.block_image(sb.csum_block, index));
+        }
 
-    /// Returns the file type
-    pub fn file_type(&self) -> FileType {
-        match (self.mode >> 12) & 0xF {
-            4 => FileType::Directory,
-            8 => FileType::Regular,
-            10 => FileType::Symlink,
-            _ => FileType::Unknown,
+        if !self.blocks.is_empty() {
+            match self.volume.journal.as_mut() {
+                Some(journal) => {
+                    if self.blocks.len() > journal.capacity() {
+                        crate::serial_println!("[journal] Transaction of {} blocks exceeds the log", self.blocks.len());
+                    }
+                    journal.commit(self.disk, &self.blocks, get_journal_timestamp())?
+                }
+                None => {
+                    for (&block_num, image) in &self.blocks {
+                        self.disk.write(block_num, image)?;
+                    }
+                    self.disk.flush()?;
+                }
+            }
         }
+
+        self.volume.dirty_checksums.clear();
+        self.undo.clear();
+        Ok(())
     }
 
-    /// Returns the file size
-    pub fn size(&self) -> u64 {
-        ((self.size_high as u64) << 32) | (self.size_low as u64)
+    fn superblock(&self) -> &Superblock {
+        &self.volume.superblock
     }
 
-    /// Sets the file size
-    pub fn set_size(&mut self, size: u64) {
-        self.size_low = size as u32;
-        self.size_high = (size >> 32) as u32;
+    fn superblock_mut(&mut self) -> &mut Superblock {
+        self.superblock_dirty = true;
+        &mut self.volume.superblock
     }
 
-    /// Is this a directory?
-    pub fn is_directory(&self) -> bool {
-        self.file_type() == FileType::Directory
+    fn tree(&self) -> &Tree {
+        &self.volume.trees[&self.tree]
     }
 
-    /// Is this a regular file?
-    pub fn is_file(&self) -> bool {
-        self.file_type() == FileType::Regular
+    fn tree_mut(&mut self) -> &mut Tree {
+        self.volume.trees.get_mut(&self.tree).expect("tree is loaded while mounted")
     }
 
-    /// Serialize to bytes
-    pub fn to_bytes(&self) -> [u8; INODE_SIZE] {
-        let mut buf = [0u8; INODE_SIZE];
-        unsafe {
-            let ptr = self as *const Self as *const u8;
-            core::ptr::copy_nonoverlapping(ptr, buf.as_mut_ptr(), INODE_SIZE);
+    fn check_block(&self, block_num: u32) -> Result<(), SplaxFsError> {
+        if block_num == 0 || block_num >= self.volume.superblock.total_blocks {
+            return Err(SplaxFsError::Corrupted);
         }
-        buf
+        Ok(())
     }
 
-    /// Deserialize from bytes
-    pub fn from_bytes(buf: &[u8]) -> Self {
-        let mut inode = Self::new();
-        unsafe {
-            let ptr = &mut inode as *mut Self as *mut u8;
-            core::ptr::copy_nonoverlapping(buf.as_ptr(), ptr, core::cmp::min(buf.len(), INODE_SIZE));
+    /// Reads a metadata block as this transaction sees it
+    fn read_meta(&self, block_num: u32) -> Result<Vec<u8>, SplaxFsError> {
+        if let Some(image) = self.blocks.get(&block_num) {
+            return Ok(image.clone());
         }
-        inode
+        self.check_block(block_num)?;
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        self.disk.read(block_num, &mut buf)?;
+        verify(&self.volume.checksums, block_num, &buf)?;
+        Ok(buf)
     }
-}
 
-/// Directory entry (on-disk format)
-#[repr(C)]
-#[derive(Debug, Clone)]
-pub struct DirEntry {
-    /// Inode number
-    pub inode: u32,
-    /// Entry length (for variable size)
-    pub rec_len: u16,
-    /// Name length
-    pub name_len: u8,
-    /// File type
-    pub file_type: u8,
-    /// Filename (up to 255 bytes)
-    pub name: [u8; MAX_FILENAME],
-}
+    /// Queues a new image of a metadata block
+    fn write_meta(&mut self, block_num: u32, data: Vec<u8>) {
+        self.blocks.insert(block_num, data);
+    }
 
-impl DirEntry {
-    /// Creates a new directory entry
-    pub fn new(inode: u32, name: &str, file_type: FileType) -> Self {
-        let mut entry = Self {
-            inode,
-            rec_len: DIRENT_SIZE as u16,
-            name_len: name.len() as u8,
-            file_type: file_type as u8,
-            name: [0; MAX_FILENAME],
-        };
-        let name_bytes = name.as_bytes();
-        let copy_len = core::cmp::min(name_bytes.len(), MAX_FILENAME);
-        entry.name[..copy_len].copy_from_slice(&name_bytes[..copy_len]);
-        entry
-    }
-
-    /// Gets the filename as a string
-    pub fn name_str(&self) -> &str {
-        let len = self.name_len as usize;
-        core::str::from_utf8(&self.name[..len]).unwrap_or("")
-    }
-
-    /// Serialize to bytes
-    pub fn to_bytes(&self) -> [u8; DIRENT_SIZE] {
-        let mut buf = [0u8; DIRENT_SIZE];
-        // Manual serialization
-        buf[0..4].copy_from_slice(&self.inode.to_le_bytes());
-        buf[4..6].copy_from_slice(&self.rec_len.to_le_bytes());
-        buf[6] = self.name_len;
-        buf[7] = self.file_type;
-        buf[8..8 + MAX_FILENAME].copy_from_slice(&self.name);
-        buf
-    }
-
-    /// Deserialize from bytes
-    pub fn from_bytes(buf: &[u8]) -> Self {
-        let mut entry = Self {
-            inode: 0,
-            rec_len: 0,
-            name_len: 0,
-            file_type: 0,
-            name: [0; MAX_FILENAME],
-        };
-        if buf.len() >= DIRENT_SIZE {
-            entry.inode = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
-            entry.rec_len = u16::from_le_bytes([buf[4], buf[5]]);
-            entry.name_len = buf[6];
-            entry.file_type = buf[7];
-            entry.name.copy_from_slice(&buf[8..8 + MAX_FILENAME]);
+    /// Reads a file data block
+    fn read_data(&self, block_num: u32, buf: &mut [u8]) -> Result<(), SplaxFsError> {
+        self.check_block(block_num)?;
+        self.disk.read(block_num, buf)?;
+        if self.volume.dax_blocks.contains(&block_num) {
+            return Ok(());
         }
-        entry
+        verify(&self.volume.checksums, block_num, buf)
     }
-}
 
-/// Mounted SplaxFS filesystem
-pub struct SplaxFs {
-    /// Device name
-    device_name: String,
-    /// Superblock (cached)
-    superblock: Superblock,
-    /// Block bitmap (cached)
-    block_bitmap: Vec<u8>,
-    /// Inode bitmap (cached)
-    inode_bitmap: Vec<u8>,
-    /// Is filesystem dirty?
-    dirty: bool,
-    /// Mount point
-    mount_point: String,
-    /// Journal (optional, for journaled mounts)
-    journal: Option<Journal>,
-    /// Current transaction ID (if any)
-    current_transaction: Option<u64>,
-}
+    /// Writes a file data block in place
+    fn write_data(&mut self, block_num: u32, buf: &[u8]) -> Result<(), SplaxFsError> {
+        self.check_block(block_num)?;
+        self.disk.write(block_num, buf)?;
+        self.record_checksum(block_num, buf);
+        Ok(())
+    }
 
-impl SplaxFs {
-    /// Formats a block device with SplaxFS
-    pub fn format(device_name: &str) -> Result<(), SplaxFsError> {
-        crate::serial_println!("[splaxfs] Formatting device {}...", device_name);
-
-        match block::with_device(device_name, |device| {
-            let info = device.info();
-            let total_sectors = info.total_sectors;
-            let sector_size = info.sector_size;
-            
-            // Calculate filesystem parameters
-            let sectors_per_block = BLOCK_SIZE / sector_size;
-            let total_blocks = (total_sectors as usize / sectors_per_block) as u32;
-            
-            if total_blocks < 16 {
-                crate::serial_println!("[splaxfs] Device too small");
-                return Err(SplaxFsError::NoSpace);
-            }
-            
-            // Layout:
-            // Block 0: Superblock
-            // Block 1: Block bitmap
-            // Block 2: Inode bitmap
-            // Block 3-10: Inode table (8 blocks = 256 inodes)
-            // Block 11+: Data blocks
-            
-            let inode_table_blocks = 8u32;
-            let total_inodes = inode_table_blocks * INODES_PER_BLOCK as u32;
-            let first_data_block = 3 + inode_table_blocks;
-            
-            // Create superblock
-            let sb = Superblock::new(total_blocks, total_inodes, first_data_block);
-            
-            // Write superblock (spans first sector)
-            let mut block_buf = [0u8; BLOCK_SIZE];
-            let sb_bytes = sb.to_bytes();
-            block_buf[..512].copy_from_slice(&sb_bytes);
-            Self::write_block(device, 0, &block_buf)?;
-            
-            // Initialize block bitmap (mark metadata blocks as used)
-            let mut block_bitmap = vec![0u8; BLOCK_SIZE];
-            for i in 0..first_data_block {
-                let byte = (i / 8) as usize;
-                let bit = (i % 8) as usize;
-                if byte < block_bitmap.len() {
-                    block_bitmap[byte] |= 1 << bit;
-                }
-            }
-            Self::write_block(device, 1, &block_bitmap)?;
-            
-            // Initialize inode bitmap (mark inodes 0, 1, 2 as used)
-            let mut inode_bitmap = vec![0u8; BLOCK_SIZE];
-            inode_bitmap[0] = 0b00000111; // Inodes 0, 1, 2 used
-            Self::write_block(device, 2, &inode_bitmap)?;
-            
-            // Initialize inode table with zeros
-            let zero_block = [0u8; BLOCK_SIZE];
-            for i in 0..inode_table_blocks {
-                Self::write_block(device, 3 + i, &zero_block)?;
+    /// Records the checksum of a block written in place. The data is on
+    /// the device already, so a failed operation keeps the new checksum.
+    fn record_checksum(&mut self, block_num: u32, data: &[u8]) {
+        self.volume.checksums.record(block_num, data);
+        self.volume.dirty_checksums.insert(ChecksumTable::index_of(block_num));
+    }
+
+    /// Records the checksums of the mapped file blocks still in use
+    fn refresh_dax_checksums(&mut self) -> Result<(), SplaxFsError> {
+        let blocks: Vec<u32> = self.volume.dax_blocks.iter().copied().collect();
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        for block_num in blocks {
+            if self.refcount(block_num) == 0 {
+                self.volume.dax_blocks.remove(&block_num);
+                continue;
             }
-            
-            // Create root directory inode (inode 2)
-            let mut root_inode = DiskInode::new_directory();
-            root_inode.size_low = BLOCK_SIZE as u32; // One block
-            root_inode.blocks = (BLOCK_SIZE / 512) as u32;
-            root_inode.direct[0] = first_data_block; // First data block
-            
-            // Write root inode
-            Self::write_inode(device, ROOT_INODE, &root_inode)?;
-            
-            // Create root directory content (. and ..)
-            let mut root_block = [0u8; BLOCK_SIZE];
-            let dot = DirEntry::new(ROOT_INODE, ".", FileType::Directory);
-            let dotdot = DirEntry::new(ROOT_INODE, "..", FileType::Directory);
-            let dot_bytes = dot.to_bytes();
-            let dotdot_bytes = dotdot.to_bytes();
-            root_block[..DIRENT_SIZE].copy_from_slice(&dot_bytes);
-            root_block[DIRENT_SIZE..2 * DIRENT_SIZE].copy_from_slice(&dotdot_bytes);
-            
-            // Mark first data block as used
-            block_bitmap[first_data_block as usize / 8] |= 1 << (first_data_block % 8);
-            Self::write_block(device, 1, &block_bitmap)?;
-            
-            // Write root directory block
-            Self::write_block(device, first_data_block, &root_block)?;
-            
-            crate::serial_println!("[splaxfs] Format complete:");
-            crate::serial_println!("  Total blocks: {}", total_blocks);
-            crate::serial_println!("  Total inodes: {}", total_inodes);
-            crate::serial_println!("  First data block: {}", first_data_block);
-            crate::serial_println!("  Usable space: {} KB", (total_blocks - first_data_block) * 4);
-            
-            Ok(())
-        }) {
-            Ok(inner) => inner,
-            Err(_) => Err(SplaxFsError::NotFound),
+            self.disk.read(block_num, &mut buf)?;
+            self.record_checksum(block_num, &buf);
         }
+        Ok(())
     }
 
-    /// Mounts a SplaxFS filesystem
-    pub fn mount(device_name: &str, mount_point: &str) -> Result<Self, SplaxFsError> {
-        crate::serial_println!("[splaxfs] Mounting {} at {}...", device_name, mount_point);
+    // -------------------------------------------------------------------------
+    // Block references
+    // -------------------------------------------------------------------------
 
-        match block::with_device(device_name, |device| {
-            // Read superblock
-            let mut block_buf = [0u8; BLOCK_SIZE];
-            Self::read_block(device, 0, &mut block_buf)?;
-            let sb = Superblock::from_bytes(&block_buf);
-            
-            if !sb.is_valid() {
-                crate::serial_println!("[splaxfs] Invalid superblock magic: 0x{:08x}", sb.magic);
-                return Err(SplaxFsError::Corrupted);
-            }
-            
-            // Read block bitmap
-            let mut block_bitmap = vec![0u8; BLOCK_SIZE];
-            Self::read_block(device, 1, &mut block_bitmap)?;
-            
-            // Read inode bitmap
-            let mut inode_bitmap = vec![0u8; BLOCK_SIZE];
-            Self::read_block(device, 2, &mut inode_bitmap)?;
-            
-            crate::serial_println!("[splaxfs] Mounted successfully:");
-            crate::serial_println!("  Volume: {}", 
-                core::str::from_utf8(&sb.volume_name).unwrap_or("?").trim_end_matches('\0'));
-            crate::serial_println!("  Total blocks: {}", sb.total_blocks);
-            crate::serial_println!("  Free blocks: {}", sb.free_blocks);
-            crate::serial_println!("  Total inodes: {}", sb.total_inodes);
-            crate::serial_println!("  Free inodes: {}", sb.free_inodes);
-            
-            Ok(SplaxFs {
-                device_name: String::from(device_name),
-                superblock: sb,
-                block_bitmap,
-                inode_bitmap,
-                dirty: false,
-                mount_point: String::from(mount_point),
-                journal: None, // Journal will be opened separately if available
-                current_transaction: None,
-            })
-        }) {
-            Ok(inner) => {
-                let mut fs = inner?;
-                // Try to open journal if it exists
-                if let Ok(mut journal) = Journal::open(device_name) {
-                    // Recover any uncommitted transactions
-                    if let Ok(recovered) = journal.recover() {
-                        if recovered > 0 {
-                            crate::serial_println!("[splaxfs] Recovered {} transactions from journal", recovered);
-                        }
-                    }
-                    fs.journal = Some(journal);
-                    crate::serial_println!("[splaxfs] Journal enabled");
-                }
-                Ok(fs)
-            },
-            Err(_) => Err(SplaxFsError::NotFound),
-        }
+    fn refcount(&self, block_num: u32) -> u16 {
+        get_ref(&self.volume.refcounts, block_num)
     }
 
-    /// Reads a block from the device
-    fn read_block(device: &dyn BlockDevice, block_num: u32, buf: &mut [u8]) -> Result<(), SplaxFsError> {
-        let sector_size = device.info().sector_size;
-        let sectors_per_block = BLOCK_SIZE / sector_size;
-        let start_sector = (block_num as u64) * (sectors_per_block as u64);
-        device.read_sectors(start_sector, buf)?;
-        Ok(())
+    /// Sets a block's reference count, keeping the free block count right
+    fn set_refcount(&mut self, block_num: u32, count: u16) {
+        let old = self.refcount(block_num);
+        set_ref(&mut self.volume.refcounts, block_num, count);
+        self.dirty_refcounts.insert(block_num / REFS_PER_BLOCK);
+        if old == 0 && count != 0 {
+            self.superblock_mut().free_blocks -= 1;
+        } else if old != 0 && count == 0 {
+            self.superblock_mut().free_blocks += 1;
+        }
+        self.undo.push(Undo::Refs(block_num, old));
     }
 
-    /// Writes a block to the device
-    fn write_block(device: &dyn BlockDevice, block_num: u32, buf: &[u8]) -> Result<(), SplaxFsError> {
-        let sector_size = device.info().sector_size;
-        let sectors_per_block = BLOCK_SIZE / sector_size;
-        let start_sector = (block_num as u64) * (sectors_per_block as u64);
-        device.write_sectors(start_sector, buf)?;
-        Ok(())
+    /// Reference count of a block that must be in use
+    fn check_ref(&self, block_num: u32) -> Result<u16, SplaxFsError> {
+        let sb = self.superblock();
+        let count = if block_num >= sb.first_data_block && block_num < sb.total_blocks {
+            self.refcount(block_num)
+        } else {
+            0
+        };
+        if count == 0 {
+            return Err(SplaxFsError::Corrupted);
+        }
+        Ok(count)
     }
 
-    /// Reads an inode from disk
-    fn read_inode(device: &dyn BlockDevice, inode_num: u32) -> Result<DiskInode, SplaxFsError> {
-        if inode_num == 0 {
-            return Err(SplaxFsError::InvalidArg);
+    /// Allocates a free block
+    fn alloc_block(&mut self) -> Result<u32, SplaxFsError> {
+        let sb = self.volume.superblock;
+        if sb.free_blocks == 0 {
+            return Err(SplaxFsError::NoSpace);
         }
-        
-        let inodes_per_block = BLOCK_SIZE / INODE_SIZE;
-        let block_num = 3 + (inode_num as usize - 1) / inodes_per_block;
-        let offset = ((inode_num as usize - 1) % inodes_per_block) * INODE_SIZE;
-        
-        let mut block_buf = [0u8; BLOCK_SIZE];
-        Self::read_block(device, block_num as u32, &mut block_buf)?;
-        
-        Ok(DiskInode::from_bytes(&block_buf[offset..offset + INODE_SIZE]))
+        let refs = &self.volume.refcounts;
+        let start = self.volume.block_hint.clamp(sb.first_data_block, sb.total_blocks);
+        let block_num = find_free(refs, start, sb.total_blocks)
+            .or_else(|| find_free(refs, sb.first_data_block, start))
+            .ok_or(SplaxFsError::NoSpace)?;
+
+        self.set_refcount(block_num, 1);
+        self.volume.block_hint = block_num + 1;
+        Ok(block_num)
     }
 
-    /// Writes an inode to disk
-    fn write_inode(device: &dyn BlockDevice, inode_num: u32, inode: &DiskInode) -> Result<(), SplaxFsError> {
-        if inode_num == 0 {
-            return Err(SplaxFsError::InvalidArg);
+    /// Adds a reference to a block
+    fn incref(&mut self, block_num: u32) -> Result<(), SplaxFsError> {
+        let count = self.check_ref(block_num)?;
+        if count == u16::MAX {
+            return Err(SplaxFsError::NoSpace);
         }
-        
-        let inodes_per_block = BLOCK_SIZE / INODE_SIZE;
-        let block_num = 3 + (inode_num as usize - 1) / inodes_per_block;
-        let offset = ((inode_num as usize - 1) % inodes_per_block) * INODE_SIZE;
-        
-        let mut block_buf = [0u8; BLOCK_SIZE];
-        Self::read_block(device, block_num as u32, &mut block_buf)?;
-        
-        let inode_bytes = inode.to_bytes();
-        block_buf[offset..offset + INODE_SIZE].copy_from_slice(&inode_bytes);
-        
-        Self::write_block(device, block_num as u32, &block_buf)?;
+        self.set_refcount(block_num, count + 1);
         Ok(())
     }
 
-    /// Lists directory contents
-    pub fn readdir(&self, path: &str) -> Result<Vec<(String, FileType, u64)>, SplaxFsError> {
-        let mut entries = Vec::new();
-        
-        match block::with_device(&self.device_name, |device| {
-            // Resolve path to inode
-            let inode_num = self.lookup_path(device, path)?;
-            let inode = Self::read_inode(device, inode_num)?;
-            
-            if !inode.is_directory() {
-                return Err(SplaxFsError::NotDirectory);
-            }
-            
-            // Read directory entries from first block
-            if inode.direct[0] != 0 {
-                let mut block_buf = [0u8; BLOCK_SIZE];
-                Self::read_block(device, inode.direct[0], &mut block_buf)?;
-                
-                let mut offset = 0;
-                while offset + DIRENT_SIZE <= BLOCK_SIZE {
-                    let entry = DirEntry::from_bytes(&block_buf[offset..]);
-                    if entry.inode != 0 && entry.name_len > 0 {
-                        let name = entry.name_str().to_string();
-                        let file_type = FileType::from(entry.file_type);
-                        
-                        // Get file size
-                        let entry_inode = Self::read_inode(device, entry.inode)?;
-                        let size = entry_inode.size();
-                        
-                        entries.push((name, file_type, size));
-                    }
-                    offset += DIRENT_SIZE;
-                }
-            }
-            
-            Ok(entries.clone())
-        }) {
-            Ok(inner) => inner,
-            Err(_) => Err(SplaxFsError::NotFound),
+    /// Drops a reference to a block, freeing it and any queued image of it
+    /// at zero
+    fn decref(&mut self, block_num: u32) -> Result<(), SplaxFsError> {
+        let count = self.check_ref(block_num)?;
+        self.set_refcount(block_num, count - 1);
+        if count == 1 {
+            self.blocks.remove(&block_num);
         }
+        Ok(())
     }
 
-    /// Looks up a path and returns the inode number
-    fn lookup_path(&self, device: &dyn BlockDevice, path: &str) -> Result<u32, SplaxFsError> {
-        let path = path.trim_start_matches('/');
-        if path.is_empty() {
-            return Ok(ROOT_INODE);
-        }
-        
-        let mut current_inode = ROOT_INODE;
-        
-        for component in path.split('/') {
-            if component.is_empty() || component == "." {
-                continue;
-            }
-            
-            let inode = Self::read_inode(device, current_inode)?;
-            if !inode.is_directory() {
-                return Err(SplaxFsError::NotDirectory);
-            }
-            
-            // Search directory for component
-            let mut found = false;
-            if inode.direct[0] != 0 {
-                let mut block_buf = [0u8; BLOCK_SIZE];
-                Self::read_block(device, inode.direct[0], &mut block_buf)?;
-                
-                let mut offset = 0;
-                while offset + DIRENT_SIZE <= BLOCK_SIZE {
-                    let entry = DirEntry::from_bytes(&block_buf[offset..]);
-                    if entry.inode != 0 && entry.name_str() == component {
-                        current_inode = entry.inode;
-                        found = true;
-                        break;
+    /// Drops a reference to a block; if it was the last, the blocks it
+    /// points at lose one too
+    fn release(&mut self, block_num: u32, kind: Kind) -> Result<(), SplaxFsError> {
+        if self.check_ref(block_num)? == 1 {
+            match kind {
+                Kind::Data | Kind::Dir | Kind::Attrs => {}
+                Kind::Pointers(_) => {
+                    let data = self.read_meta(block_num)?;
+                    for slot in 0..PTRS_PER_BLOCK {
+                        let ptr = get_u32(&data, slot * 4);
+                        if cluster::is_block(ptr) {
+                            self.release(ptr, kind.child())?;
+                        }
                     }
-                    offset += DIRENT_SIZE;
+                    self.adjust_saved(-(cluster::saved_slots(&data) as i32));
                 }
-            }
-            
-            if !found {
-                return Err(SplaxFsError::NotFound);
-            }
-        }
-        
-        Ok(current_inode)
-    }
-
-    /// Static version of lookup_path (doesn't need self)
-    fn lookup_path_static(device: &dyn BlockDevice, _superblock: &Superblock, path: &str) -> Result<u32, SplaxFsError> {
-        let path = path.trim_start_matches('/');
-        if path.is_empty() {
-            return Ok(ROOT_INODE);
-        }
-        
-        let mut current_inode = ROOT_INODE;
-        
-        for component in path.split('/') {
-            if component.is_empty() || component == "." {
-                continue;
-            }
-            
-            let inode = Self::read_inode(device, current_inode)?;
-            if !inode.is_directory() {
-                return Err(SplaxFsError::NotDirectory);
-            }
-            
-            // Search directory for component
-            let mut found = false;
-            if inode.direct[0] != 0 {
-                let mut block_buf = [0u8; BLOCK_SIZE];
-                Self::read_block(device, inode.direct[0], &mut block_buf)?;
-                
-                let mut offset = 0;
-                while offset + DIRENT_SIZE <= BLOCK_SIZE {
-                    let entry = DirEntry::from_bytes(&block_buf[offset..]);
-                    if entry.inode != 0 && entry.name_str() == component {
-                        current_inode = entry.inode;
-                        found = true;
-                        break;
+                Kind::Inodes => {
+                    let data = self.read_meta(block_num)?;
+                    let mut saved = 0;
+                    for slot in 0..INODES_PER_BLOCK {
+                        let inode = DiskInode::from_bytes(&data[slot * INODE_SIZE..]);
+                        if inode.mode != 0 {
+                            self.release_blocks(&inode)?;
+                            saved += inode.compressed_slots();
+                        }
                     }
-                    offset += DIRENT_SIZE;
+                    self.adjust_saved(-(saved as i32));
                 }
             }
-            
-            if !found {
-                return Err(SplaxFsError::NotFound);
-            }
         }
-        
-        Ok(current_inode)
+        self.decref(block_num)
     }
 
-    /// Static version of add_dirent (doesn't need &mut self)
-    fn add_dirent_static(device: &dyn BlockDevice, _superblock: &Superblock, parent_inode_num: u32, 
-                  child_inode_num: u32, name: &str, file_type: FileType) -> Result<(), SplaxFsError> {
-        let parent_inode = Self::read_inode(device, parent_inode_num)?;
-        
-        if parent_inode.direct[0] == 0 {
-            return Err(SplaxFsError::Corrupted);
+    /// Drops an inode's references to its blocks, attribute block included
+    fn release_blocks(&mut self, inode: &DiskInode) -> Result<(), SplaxFsError> {
+        self.release_map(inode)?;
+        if inode.xattr_block != 0 {
+            self.decref(inode.xattr_block)?;
         }
-        
-        // Read directory block
-        let mut block_buf = [0u8; BLOCK_SIZE];
-        Self::read_block(device, parent_inode.direct[0], &mut block_buf)?;
-        
-        // Find empty slot
-        let mut offset = 0;
-        while offset + DIRENT_SIZE <= BLOCK_SIZE {
-            let entry = DirEntry::from_bytes(&block_buf[offset..]);
-            if entry.inode == 0 {
-                // Found empty slot
-                let new_entry = DirEntry::new(child_inode_num, name, file_type);
-                block_buf[offset..offset + DIRENT_SIZE].copy_from_slice(&new_entry.to_bytes());
-                Self::write_block(device, parent_inode.direct[0], &block_buf)?;
-                return Ok(());
-            }
-            offset += DIRENT_SIZE;
-        }
-        
-        // Directory full
-        Err(SplaxFsError::NoSpace)
+        Ok(())
     }
 
-    /// Static version of sync_bitmaps
-    fn sync_bitmaps_static(device: &dyn BlockDevice, superblock: &Superblock, 
-                           inode_bitmap: &[u8], block_bitmap: &[u8]) -> Result<(), SplaxFsError> {
-        // Copy to fixed-size arrays for writing
-        let mut inode_buf = [0u8; BLOCK_SIZE];
-        let mut block_buf = [0u8; BLOCK_SIZE];
-        let inode_len = core::cmp::min(inode_bitmap.len(), BLOCK_SIZE);
-        let block_len = core::cmp::min(block_bitmap.len(), BLOCK_SIZE);
-        inode_buf[..inode_len].copy_from_slice(&inode_bitmap[..inode_len]);
-        block_buf[..block_len].copy_from_slice(&block_bitmap[..block_len]);
-        
-        Self::write_block(device, 1, &block_buf)?;
-        Self::write_block(device, 2, &inode_buf)?;
-        
-        // Update superblock
-        let mut sb_block_buf = [0u8; BLOCK_SIZE];
-        let sb_bytes = superblock.to_bytes();
-        sb_block_buf[..512].copy_from_slice(&sb_bytes);
-        Self::write_block(device, 0, &sb_block_buf)?;
-        
+    /// Drops an inode's references to the blocks of its block map
+    fn release_map(&mut self, inode: &DiskInode) -> Result<(), SplaxFsError> {
+        for &block_num in inode.direct.iter().filter(|&&block_num| cluster::is_block(block_num)) {
+            self.decref(block_num)?;
+        }
+        if inode.indirect != 0 {
+            self.release(inode.indirect, Kind::Pointers(1))?;
+        }
+        if inode.double_indirect != 0 {
+            self.release(inode.double_indirect, Kind::Pointers(2))?;
+        }
         Ok(())
     }
 
-    /// Creates a new file
-    pub fn create_file(&mut self, path: &str) -> Result<(), SplaxFsError> {
-        let (parent_path, filename) = self.split_path(path)?;
-        
-        if filename.len() > MAX_FILENAME {
-            return Err(SplaxFsError::NameTooLong);
-        }
-        
-        // Pre-allocate inode (modifies in-memory bitmap only)
-        let new_inode_num = self.alloc_inode()?;
-        let device_name = self.device_name.clone();
-        let superblock = self.superblock.clone();
-        let inode_bitmap = self.inode_bitmap.clone();
-        let block_bitmap = self.block_bitmap.clone();
-        
-        match block::with_device(&device_name, |device| {
-            // Find parent directory
-            let parent_inode_num = Self::lookup_path_static(device, &superblock, &parent_path)?;
-            let parent_inode = Self::read_inode(device, parent_inode_num)?;
-            
-            if !parent_inode.is_directory() {
-                return Err(SplaxFsError::NotDirectory);
-            }
-            
-            // Create new file inode
-            let new_inode = DiskInode::new_file();
-            Self::write_inode(device, new_inode_num, &new_inode)?;
-            
-            // Add directory entry
-            Self::add_dirent_static(device, &superblock, parent_inode_num, new_inode_num, &filename, FileType::Regular)?;
-            
-            // Update bitmaps on disk
-            Self::sync_bitmaps_static(device, &superblock, &inode_bitmap, &block_bitmap)?;
-            
-            crate::serial_println!("[splaxfs] Created file: {}", path);
-            Ok(())
-        }) {
-            Ok(inner) => inner,
-            Err(_) => Err(SplaxFsError::NotFound),
-        }
-    }
-
-    /// Creates a new directory
-    pub fn create_dir(&mut self, path: &str) -> Result<(), SplaxFsError> {
-        let (parent_path, dirname) = self.split_path(path)?;
-        
-        if dirname.len() > MAX_FILENAME {
-            return Err(SplaxFsError::NameTooLong);
-        }
-        
-        // Pre-allocate inode and block (modifies in-memory bitmaps only)
-        let new_inode_num = self.alloc_inode()?;
-        let dir_block = self.alloc_block()?;
-        let device_name = self.device_name.clone();
-        let superblock = self.superblock.clone();
-        let inode_bitmap = self.inode_bitmap.clone();
-        let block_bitmap = self.block_bitmap.clone();
-        
-        match block::with_device(&device_name, |device| {
-            // Find parent directory
-            let parent_inode_num = Self::lookup_path_static(device, &superblock, &parent_path)?;
-            let parent_inode = Self::read_inode(device, parent_inode_num)?;
-            
-            if !parent_inode.is_directory() {
-                return Err(SplaxFsError::NotDirectory);
-            }
-            
-            // Create directory inode
-            let mut new_inode = DiskInode::new_directory();
-            new_inode.size_low = BLOCK_SIZE as u32;
-            new_inode.blocks = (BLOCK_SIZE / 512) as u32;
-            new_inode.direct[0] = dir_block;
-            Self::write_inode(device, new_inode_num, &new_inode)?;
-            
-            // Create . and .. entries
-            let mut dir_block_buf = [0u8; BLOCK_SIZE];
-            let dot = DirEntry::new(new_inode_num, ".", FileType::Directory);
-            let dotdot = DirEntry::new(parent_inode_num, "..", FileType::Directory);
-            dir_block_buf[..DIRENT_SIZE].copy_from_slice(&dot.to_bytes());
-            dir_block_buf[DIRENT_SIZE..2 * DIRENT_SIZE].copy_from_slice(&dotdot.to_bytes());
-            Self::write_block(device, dir_block, &dir_block_buf)?;
-            
-            // Add directory entry in parent
-            Self::add_dirent_static(device, &superblock, parent_inode_num, new_inode_num, &dirname, FileType::Directory)?;
-            
-            // Update bitmaps on disk
-            Self::sync_bitmaps_static(device, &superblock, &inode_bitmap, &block_bitmap)?;
-            
-            crate::serial_println!("[splaxfs] Created directory: {}", path);
-            Ok(())
-        }) {
-            Ok(inner) => inner,
-            Err(_) => Err(SplaxFsError::NotFound),
+    /// Adds a reference to each block an inode points at
+    fn share_blocks(&mut self, inode: &DiskInode) -> Result<(), SplaxFsError> {
+        let tops = [inode.indirect, inode.double_indirect, inode.xattr_block];
+        for &block_num in inode.direct.iter().chain(tops.iter()).filter(|&&block_num| cluster::is_block(block_num)) {
+            self.incref(block_num)?;
         }
+        Ok(())
     }
 
-    /// Writes data to a file
-    pub fn write_file(&mut self, path: &str, data: &[u8]) -> Result<(), SplaxFsError> {
-        // Pre-allocate blocks needed for the file
-        let blocks_needed = if data.is_empty() { 0 } else { (data.len() + BLOCK_SIZE - 1) / BLOCK_SIZE };
-        if blocks_needed > DIRECT_BLOCKS {
-            crate::serial_println!("[splaxfs] File too large (max 48KB for now)");
-            return Err(SplaxFsError::NoSpace);
+    /// Returns a block the tree can change: the block itself if nothing
+    /// else points at it, otherwise a private copy
+    fn unshare(&mut self, block_num: u32, kind: Kind) -> Result<u32, SplaxFsError> {
+        if self.check_ref(block_num)? == 1 {
+            return Ok(block_num);
         }
-        
-        // Pre-allocate all the blocks we might need
-        let mut new_blocks = Vec::new();
-        for _ in 0..blocks_needed {
-            new_blocks.push(self.alloc_block()?);
-        }
-        
-        let device_name = self.device_name.clone();
-        let superblock = self.superblock.clone();
-        let inode_bitmap = self.inode_bitmap.clone();
-        let block_bitmap = self.block_bitmap.clone();
-        
-        match block::with_device(&device_name, |device| {
-            let inode_num = Self::lookup_path_static(device, &superblock, path)?;
-            let mut inode = Self::read_inode(device, inode_num)?;
-            
-            if !inode.is_file() {
-                return Err(SplaxFsError::IsDirectory);
-            }
-            
-            // Allocate blocks and write data using pre-allocated blocks
-            let mut offset = 0;
-            let mut block_idx = 0;
-            for i in 0..blocks_needed {
-                if inode.direct[i] == 0 {
-                    if block_idx < new_blocks.len() {
-                        inode.direct[i] = new_blocks[block_idx];
-                        block_idx += 1;
-                    } else {
-                        return Err(SplaxFsError::NoSpace);
+        let copy = self.alloc_block()?;
+        if kind == Kind::Data {
+            let mut buf = vec![0u8; BLOCK_SIZE];
+            self.read_data(block_num, &mut buf)?;
+            self.write_data(copy, &buf)?;
+        } else {
+            let data = self.read_meta(block_num)?;
+            match kind {
+                Kind::Pointers(_) => {
+                    for slot in 0..PTRS_PER_BLOCK {
+                        let ptr = get_u32(&data, slot * 4);
+                        if cluster::is_block(ptr) {
+                            self.incref(ptr)?;
+                        }
+                    }
+                    self.adjust_saved(cluster::saved_slots(&data) as i32);
+                }
+                Kind::Inodes => {
+                    let mut saved = 0;
+                    for slot in 0..INODES_PER_BLOCK {
+                        let inode = DiskInode::from_bytes(&data[slot * INODE_SIZE..]);
+                        if inode.mode != 0 {
+                            self.share_blocks(&inode)?;
+                            saved += inode.compressed_slots();
+                        }
                     }
+                    self.adjust_saved(saved as i32);
                 }
-                
-                let mut block_buf = [0u8; BLOCK_SIZE];
-                let end = core::cmp::min(offset + BLOCK_SIZE, data.len());
-                block_buf[..end - offset].copy_from_slice(&data[offset..end]);
-                Self::write_block(device, inode.direct[i], &block_buf)?;
-                offset = end;
+                Kind::Data | Kind::Dir | Kind::Attrs => {}
             }
-            
-            // Update inode
-            inode.set_size(data.len() as u64);
-            inode.blocks = (blocks_needed * (BLOCK_SIZE / 512)) as u32;
-            Self::write_inode(device, inode_num, &inode)?;
-            
-            // Sync bitmaps
-            Self::sync_bitmaps_static(device, &superblock, &inode_bitmap, &block_bitmap)?;
-            
-            Ok(())
-        }) {
-            Ok(inner) => inner,
-            Err(_) => Err(SplaxFsError::NotFound),
+            self.write_meta(copy, data);
         }
+        self.decref(block_num)?;
+        Ok(copy)
     }
 
-    /// Reads a file's contents
-    pub fn read_file(&self, path: &str) -> Result<Vec<u8>, SplaxFsError> {
-        let device_name = self.device_name.clone();
-        let superblock = self.superblock.clone();
-        
-        match block::with_device(&device_name, |device| {
-            let inode_num = Self::lookup_path_static(device, &superblock, path)?;
-            let inode = Self::read_inode(device, inode_num)?;
-            
-            if !inode.is_file() {
-                return Err(SplaxFsError::IsDirectory);
-            }
-            
-            let file_size = inode.size() as usize;
-            let mut data = vec![0u8; file_size];
-            let mut offset = 0;
-            
-            for i in 0..DIRECT_BLOCKS {
-                if inode.direct[i] == 0 || offset >= file_size {
-                    break;
-                }
-                
-                let mut block_buf = [0u8; BLOCK_SIZE];
-                Self::read_block(device, inode.direct[i], &mut block_buf)?;
-                
-                let end = core::cmp::min(offset + BLOCK_SIZE, file_size);
-                data[offset..end].copy_from_slice(&block_buf[..end - offset]);
-                offset = end;
+    /// Number of blocks in the tree below a block, the block included
+    fn count_blocks(&self, block_num: u32, kind: Kind) -> Result<u32, SplaxFsError> {
+        if !matches!(kind, Kind::Pointers(_)) {
+            return Ok(1);
+        }
+        let data = self.read_meta(block_num)?;
+        let mut count = 1;
+        for slot in 0..PTRS_PER_BLOCK {
+            let ptr = get_u32(&data, slot * 4);
+            if cluster::is_block(ptr) {
+                count += self.count_blocks(ptr, kind.child())?;
             }
-            
-            Ok(data)
-        }) {
-            Ok(inner) => inner,
-            Err(_) => Err(SplaxFsError::NotFound),
         }
+        Ok(count)
     }
 
-    /// Gets file/directory info
-    pub fn stat(&self, path: &str) -> Result<(FileType, u64), SplaxFsError> {
-        let device_name = self.device_name.clone();
-        let superblock = self.superblock.clone();
-        
-        match block::with_device(&device_name, |device| {
-            let inode_num = Self::lookup_path_static(device, &superblock, path)?;
-            let inode = Self::read_inode(device, inode_num)?;
-            Ok((inode.file_type(), inode.size()))
-        }) {
-            Ok(inner) => inner,
-            Err(_) => Err(SplaxFsError::NotFound),
+    /// Changes the count of blocks compression saves, which follows the
+    /// [`COMPRESSED_SLOT`] entries of inode table and pointer blocks
+    fn adjust_saved(&mut self, delta: i32) {
+        if delta == 0 {
+            return;
         }
-    }
-
-    /// Allocates a free inode
-    fn alloc_inode(&mut self) -> Result<u32, SplaxFsError> {
-        for i in 0..self.superblock.total_inodes {
-            let byte = (i / 8) as usize;
-            let bit = (i % 8) as usize;
-            if byte < self.inode_bitmap.len() && (self.inode_bitmap[byte] & (1 << bit)) == 0 {
-                self.inode_bitmap[byte] |= 1 << bit;
-                self.superblock.free_inodes -= 1;
-                self.dirty = true;
-                return Ok(i + 1); // Inode numbers are 1-based
-            }
+        let saved = &mut self.superblock_mut().saved_blocks;
+        *saved = saved.wrapping_add_signed(delta);
+        self.undo.push(Undo::Saved(delta));
+    }
+
+    // -------------------------------------------------------------------------
+    // Inodes
+    // -------------------------------------------------------------------------
+
+    /// Marks an inode used or free in the tree
+    fn set_inode_used(&mut self, ino: u32, used: bool) {
+        let tree = self.tree;
+        set_bit(&mut self.tree_mut().inode_bitmap, ino, used);
+        self.dirty_inode_bitmap.insert((tree, ino / BITS_PER_BLOCK));
+        self.undo.push(Undo::Inode(tree, ino, !used));
+        if tree == 0 {
+            self.superblock_dirty = true;
+        } else {
+            self.snapshots_dirty = true;
+        }
+        if let Some(free) = self.volume.free_inodes_mut(tree) {
+            *free = if used { free.saturating_sub(1) } else { *free + 1 };
         }
-        Err(SplaxFsError::NoSpace)
     }
 
-    /// Allocates a free block
-    fn alloc_block(&mut self) -> Result<u32, SplaxFsError> {
-        for i in self.superblock.first_data_block..self.superblock.total_blocks {
-            let byte = (i / 8) as usize;
-            let bit = (i % 8) as usize;
-            if byte < self.block_bitmap.len() && (self.block_bitmap[byte] & (1 << bit)) == 0 {
-                self.block_bitmap[byte] |= 1 << bit;
-                self.superblock.free_blocks -= 1;
-                self.dirty = true;
-                return Ok(i);
-            }
+    /// Allocates a free inode number
+    fn alloc_inode(&mut self) -> Result<u32, SplaxFsError> {
+        let tree = self.tree;
+        if self.volume.free_inodes_mut(tree).is_none_or(|free| *free == 0) {
+            return Err(SplaxFsError::NoSpace);
         }
-        Err(SplaxFsError::NoSpace)
+        let ino = find_clear(&self.tree().inode_bitmap, ROOT_INODE + 1, self.superblock().total_inodes + 1)
+            .ok_or(SplaxFsError::NoSpace)?;
+        self.set_inode_used(ino, true);
+        Ok(ino)
     }
 
-    /// Adds a directory entry
-    fn add_dirent(&mut self, device: &dyn BlockDevice, parent_inode_num: u32, 
-                  child_inode_num: u32, name: &str, file_type: FileType) -> Result<(), SplaxFsError> {
-        let parent_inode = Self::read_inode(device, parent_inode_num)?;
-        
-        if parent_inode.direct[0] == 0 {
+    /// Frees an inode number
+    fn free_inode(&mut self, ino: u32) -> Result<(), SplaxFsError> {
+        if ino <= ROOT_INODE || !test_bit(&self.tree().inode_bitmap, ino) {
             return Err(SplaxFsError::Corrupted);
         }
-        
-        // Read directory block
-        let mut block_buf = [0u8; BLOCK_SIZE];
-        Self::read_block(device, parent_inode.direct[0], &mut block_buf)?;
-        
-        // Find empty slot
-        let mut offset = 0;
-        while offset + DIRENT_SIZE <= BLOCK_SIZE {
-            let entry = DirEntry::from_bytes(&block_buf[offset..]);
-            if entry.inode == 0 {
-                // Found empty slot
-                let new_entry = DirEntry::new(child_inode_num, name, file_type);
-                block_buf[offset..offset + DIRENT_SIZE].copy_from_slice(&new_entry.to_bytes());
-                Self::write_block(device, parent_inode.direct[0], &block_buf)?;
-                return Ok(());
-            }
-            offset += DIRENT_SIZE;
-        }
-        
-        // Directory full
-        Err(SplaxFsError::NoSpace)
+        self.set_inode_used(ino, false);
+        Ok(())
     }
 
-    /// Syncs bitmaps to disk
-    fn sync_bitmaps(&self, device: &dyn BlockDevice) -> Result<(), SplaxFsError> {
-        Self::write_block(device, 1, &self.block_bitmap)?;
-        Self::write_block(device, 2, &self.inode_bitmap)?;
-        
-        // Update superblock
-        let mut block_buf = [0u8; BLOCK_SIZE];
-        let sb_bytes = self.superblock.to_bytes();
-        block_buf[..512].copy_from_slice(&sb_bytes);
-        Self::write_block(device, 0, &block_buf)?;
-        
-        Ok(())
+    /// Inode table index and byte offset of an inode
+    fn inode_location(&self, ino: u32) -> Result<(u32, usize), SplaxFsError> {
+        let sb = self.superblock();
+        if ino == 0 || ino > sb.total_inodes {
+            return Err(SplaxFsError::NotFound);
+        }
+        let index = (ino - 1) as usize;
+        Ok(((index / INODES_PER_BLOCK) as u32, (index % INODES_PER_BLOCK) * INODE_SIZE))
     }
 
-    /// Splits a path into parent and filename
-    fn split_path(&self, path: &str) -> Result<(String, String), SplaxFsError> {
-        let path = path.trim_start_matches('/').trim_end_matches('/');
-        if path.is_empty() {
-            return Err(SplaxFsError::InvalidArg);
+    /// Reads an inode that is in use
+    fn get_inode(&self, ino: u32) -> Result<DiskInode, SplaxFsError> {
+        let (index, offset) = self.inode_location(ino)?;
+        let tree = self.tree();
+        if !test_bit(&tree.inode_bitmap, ino) {
+            return Err(SplaxFsError::NotFound);
         }
-        
-        if let Some(pos) = path.rfind('/') {
-            let parent = &path[..pos];
-            let name = &path[pos + 1..];
-            Ok((format!("/{}", parent), String::from(name)))
-        } else {
-            Ok((String::from("/"), String::from(path)))
+        let data = self.read_meta(tree.table[index as usize])?;
+        let inode = DiskInode::from_bytes(&data[offset..offset + INODE_SIZE]);
+        if inode.mode == 0 {
+            return Err(SplaxFsError::NotFound);
         }
+        Ok(inode)
     }
 
-    // =========================================================================
-    // Journaling Methods
-    // =========================================================================
+    /// Points inode table entry `index` of the tree at another block
+    fn set_table(&mut self, index: u32, block_num: u32) {
+        let tree = self.tree;
+        let old = core::mem::replace(&mut self.tree_mut().table[index as usize], block_num);
+        self.undo.push(Undo::Table(tree, index, old));
+        self.dirty_maps.insert((tree, index / PTRS_PER_BLOCK as u32));
+    }
 
-    /// Begins a journaled transaction
-    pub fn begin_transaction(&mut self) -> Result<u64, SplaxFsError> {
-        if let Some(ref mut journal) = self.journal {
-            let txn_id = journal.begin();
-            self.current_transaction = Some(txn_id);
-            Ok(txn_id)
+    /// Inode table block `index`, private to the tree
+    fn table_block_mut(&mut self, index: u32) -> Result<u32, SplaxFsError> {
+        let block_num = self.tree().table[index as usize];
+        let own = if block_num == 0 {
+            let new = self.alloc_block()?;
+            self.write_meta(new, vec![0u8; BLOCK_SIZE]);
+            new
         } else {
-            Err(SplaxFsError::JournalError)
+            self.unshare(block_num, Kind::Inodes)?
+        };
+        if own != block_num {
+            self.set_table(index, own);
         }
+        Ok(own)
     }
 
-    /// Commits the current transaction
-    pub fn commit_transaction(&mut self) -> Result<(), SplaxFsError> {
-        if let Some(txn_id) = self.current_transaction.take() {
-            if let Some(ref mut journal) = self.journal {
-                journal.commit(txn_id)?;
-            }
-            Ok(())
-        } else {
-            Err(SplaxFsError::TransactionNotFound)
-        }
+    /// Makes an inode's table block private, so its block pointers can
+    /// change without the change showing in other trees
+    fn own_inode(&mut self, ino: u32) -> Result<(), SplaxFsError> {
+        let (index, _) = self.inode_location(ino)?;
+        self.table_block_mut(index).map(|_| ())
     }
 
-    /// Aborts the current transaction
-    pub fn abort_transaction(&mut self) -> Result<(), SplaxFsError> {
-        if let Some(txn_id) = self.current_transaction.take() {
-            if let Some(ref mut journal) = self.journal {
-                journal.abort(txn_id)?;
-            }
-            Ok(())
+    fn write_inode(&mut self, ino: u32, inode: &DiskInode) -> Result<(), SplaxFsError> {
+        let (index, offset) = self.inode_location(ino)?;
+        let block_num = self.table_block_mut(index)?;
+        let mut data = self.read_meta(block_num)?;
+        let old = DiskInode::from_bytes(&data[offset..offset + INODE_SIZE]);
+        data[offset..offset + INODE_SIZE].copy_from_slice(&inode.to_bytes());
+        self.write_meta(block_num, data);
+        self.adjust_saved(inode.compressed_slots() as i32 - old.compressed_slots() as i32);
+        Ok(())
+    }
+
+    // -------------------------------------------------------------------------
+    // Block maps
+    // -------------------------------------------------------------------------
+
+    /// Reads slot `slot` of a pointer block (0 if there is no block)
+    fn read_ptr(&self, block_num: u32, slot: u64) -> Result<u32, SplaxFsError> {
+        if block_num == 0 {
+            return Ok(0);
+        }
+        let data = self.read_meta(block_num)?;
+        Ok(get_u32(&data, slot as usize * 4))
+    }
+
+    /// Maps file block `index` to a disk block; 0 is a hole
+    fn bmap(&self, inode: &DiskInode, index: u64) -> Result<u32, SplaxFsError> {
+        if index < DIRECT_BLOCKS as u64 {
+            return Ok(inode.direct[index as usize]);
+        }
+        let (block_num, slot) = self.leaf(inode, index)?;
+        self.read_ptr(block_num, slot)
+    }
+
+    /// Pointer block (0 if there is none) and slot holding the pointer of
+    /// file block `index`, which is past the direct blocks
+    fn leaf(&self, inode: &DiskInode, index: u64) -> Result<(u32, u64), SplaxFsError> {
+        let ptrs = PTRS_PER_BLOCK as u64;
+        let index = index - DIRECT_BLOCKS as u64;
+        if index < ptrs {
+            return Ok((inode.indirect, index));
+        }
+        let index = index - ptrs;
+        if index < ptrs * ptrs {
+            return Ok((self.read_ptr(inode.double_indirect, index / ptrs)?, index % ptrs));
+        }
+        Err(SplaxFsError::NoSpace)
+    }
+
+    /// Like [`leaf`](Self::leaf), but the pointer blocks on the way are
+    /// made private to the tree, and missing ones allocated
+    fn leaf_mut(&mut self, inode: &mut DiskInode, index: u64) -> Result<(u32, u64), SplaxFsError> {
+        let ptrs = PTRS_PER_BLOCK as u64;
+        let index = index - DIRECT_BLOCKS as u64;
+        if index < ptrs {
+            return Ok((self.ensure_indirect(&mut inode.indirect, 1, &mut inode.blocks)?, index));
+        }
+        let index = index - ptrs;
+        if index < ptrs * ptrs {
+            let double = self.ensure_indirect(&mut inode.double_indirect, 2, &mut inode.blocks)?;
+            let (indirect, _) = self.alloc_slot(double, index / ptrs, Kind::Pointers(1), &mut inode.blocks)?;
+            return Ok((indirect, index % ptrs));
+        }
+        Err(SplaxFsError::NoSpace)
+    }
+
+    /// Like [`bmap`](Self::bmap), but the block and the pointer blocks on
+    /// the way are made private to the tree, and missing ones allocated.
+    /// `kind` is what the block holds. Returns the block and whether it is
+    /// new. The inode's table block must be private already.
+    fn bmap_alloc(&mut self, inode: &mut DiskInode, index: u64, kind: Kind) -> Result<(u32, bool), SplaxFsError> {
+        if index < DIRECT_BLOCKS as u64 {
+            let slot = inode.direct[index as usize];
+            if slot != 0 {
+                let own = self.unshare(slot, kind)?;
+                inode.direct[index as usize] = own;
+                return Ok((own, false));
+            }
+            let block_num = self.alloc_block()?;
+            inode.direct[index as usize] = block_num;
+            inode.blocks += SECTORS_PER_BLOCK;
+            return Ok((block_num, true));
+        }
+        let (block_num, slot) = self.leaf_mut(inode, index)?;
+        self.alloc_slot(block_num, slot, kind, &mut inode.blocks)
+    }
+
+    /// Points file block `index` at a hole or [`COMPRESSED_SLOT`], dropping
+    /// the block it pointed at. The inode's table block must be private.
+    fn set_slot(&mut self, inode: &mut DiskInode, index: u64, ptr: u32) -> Result<(), SplaxFsError> {
+        let old = if index < DIRECT_BLOCKS as u64 {
+            core::mem::replace(&mut inode.direct[index as usize], ptr)
+        } else {
+            if self.bmap(inode, index)? == ptr {
+                return Ok(());
+            }
+            let (block_num, slot) = self.leaf_mut(inode, index)?;
+            let mut data = self.read_meta(block_num)?;
+            let offset = slot as usize * 4;
+            let old = get_u32(&data, offset);
+            data[offset..offset + 4].copy_from_slice(&ptr.to_le_bytes());
+            self.write_meta(block_num, data);
+            self.adjust_saved((ptr == COMPRESSED_SLOT) as i32 - (old == COMPRESSED_SLOT) as i32);
+            old
+        };
+        if cluster::is_block(old) {
+            self.decref(old)?;
+            inode.blocks = inode.blocks.saturating_sub(SECTORS_PER_BLOCK);
+        }
+        Ok(())
+    }
+
+    /// Makes an inode's top pointer block of the given depth private,
+    /// allocating a zeroed one if the pointer is empty
+    fn ensure_indirect(&mut self, ptr: &mut u32, depth: u32, blocks: &mut u32) -> Result<u32, SplaxFsError> {
+        if *ptr == 0 {
+            let block_num = self.alloc_block()?;
+            self.write_meta(block_num, vec![0u8; BLOCK_SIZE]);
+            *ptr = block_num;
+            *blocks += SECTORS_PER_BLOCK;
+        } else {
+            *ptr = self.unshare(*ptr, Kind::Pointers(depth))?;
+        }
+        Ok(*ptr)
+    }
+
+    /// Returns the block in slot `slot` of a private pointer block, made
+    /// private too, allocating one if the slot is empty (zeroed if it will
+    /// hold pointers itself)
+    fn alloc_slot(&mut self, block_num: u32, slot: u64, kind: Kind, blocks: &mut u32) -> Result<(u32, bool), SplaxFsError> {
+        let mut data = self.read_meta(block_num)?;
+        let offset = slot as usize * 4;
+        let current = get_u32(&data, offset);
+        let (target, new) = if current != 0 {
+            (self.unshare(current, kind)?, false)
+        } else {
+            let new_block = self.alloc_block()?;
+            if matches!(kind, Kind::Pointers(_)) {
+                self.write_meta(new_block, vec![0u8; BLOCK_SIZE]);
+            }
+            *blocks += SECTORS_PER_BLOCK;
+            (new_block, true)
+        };
+        if target != current {
+            data[offset..offset + 4].copy_from_slice(&target.to_le_bytes());
+            self.write_meta(block_num, data);
+        }
+        Ok((target, new))
+    }
+
+    /// Drops everything at or past index `from` below a private pointer
+    /// block of the given depth. Returns true if nothing is left below it.
+    fn free_tree(&mut self, block_num: u32, depth: u32, from: u64, blocks: &mut u32) -> Result<bool, SplaxFsError> {
+        let kind = Kind::Pointers(depth).child();
+        let span = (PTRS_PER_BLOCK as u64).pow(depth - 1);
+        let mut data = self.read_meta(block_num)?;
+        let mut changed = false;
+        let mut empty = true;
+
+        for slot in 0..PTRS_PER_BLOCK {
+            let ptr = get_u32(&data, slot * 4);
+            if ptr == 0 {
+                continue;
+            }
+            let first = slot as u64 * span;
+            if first + span <= from {
+                empty = false;
+                continue;
+            }
+            let mut kept = 0;
+            if ptr == COMPRESSED_SLOT {
+                self.adjust_saved(-1);
+            } else if first >= from {
+                *blocks = blocks.saturating_sub(self.count_blocks(ptr, kind)? * SECTORS_PER_BLOCK);
+                self.release(ptr, kind)?;
+            } else {
+                // Only a pointer block straddles `from`
+                let own = self.unshare(ptr, kind)?;
+                if self.free_tree(own, depth - 1, from - first, blocks)? {
+                    self.decref(own)?;
+                    *blocks = blocks.saturating_sub(SECTORS_PER_BLOCK);
+                } else {
+                    kept = own;
+                    empty = false;
+                }
+            }
+            if kept != ptr {
+                data[slot * 4..slot * 4 + 4].copy_from_slice(&kept.to_le_bytes());
+                changed = true;
+            }
+        }
+
+        if changed && !empty {
+            self.write_meta(block_num, data);
+        }
+        Ok(empty)
+    }
+
+    /// Drops everything at or past index `from` below an inode's top
+    /// pointer block of the given depth
+    fn free_top(&mut self, ptr: &mut u32, depth: u32, from: u64, blocks: &mut u32) -> Result<(), SplaxFsError> {
+        if *ptr == 0 {
+            return Ok(());
+        }
+        let kind = Kind::Pointers(depth);
+        if from == 0 {
+            *blocks = blocks.saturating_sub(self.count_blocks(*ptr, kind)? * SECTORS_PER_BLOCK);
+            self.release(*ptr, kind)?;
+            *ptr = 0;
+            return Ok(());
+        }
+        *ptr = self.unshare(*ptr, kind)?;
+        if self.free_tree(*ptr, depth, from, blocks)? {
+            self.decref(*ptr)?;
+            *ptr = 0;
+            *blocks = blocks.saturating_sub(SECTORS_PER_BLOCK);
+        }
+        Ok(())
+    }
+
+    /// Drops the blocks of file block `from` onwards. The inode's table
+    /// block must be private.
+    fn free_from(&mut self, inode: &mut DiskInode, from: u64) -> Result<(), SplaxFsError> {
+        if from == 0 {
+            self.release_map(inode)?;
+            inode.direct = [0; DIRECT_BLOCKS];
+            inode.indirect = 0;
+            inode.double_indirect = 0;
+            inode.blocks = 0;
+            return Ok(());
+        }
+
+        for i in (from.min(DIRECT_BLOCKS as u64) as usize)..DIRECT_BLOCKS {
+            if cluster::is_block(inode.direct[i]) {
+                self.decref(inode.direct[i])?;
+                inode.blocks = inode.blocks.saturating_sub(SECTORS_PER_BLOCK);
+            }
+            inode.direct[i] = 0;
+        }
+        let from = from.saturating_sub(DIRECT_BLOCKS as u64);
+        self.free_top(&mut inode.indirect, 1, from, &mut inode.blocks)?;
+        let from = from.saturating_sub(PTRS_PER_BLOCK as u64);
+        self.free_top(&mut inode.double_indirect, 2, from, &mut inode.blocks)
+    }
+
+    // -------------------------------------------------------------------------
+    // Compressed clusters
+    // -------------------------------------------------------------------------
+
+    /// Block map slots of the cluster starting at file block `first`
+    fn cluster_slots(&self, inode: &DiskInode, first: u64) -> Result<Vec<u32>, SplaxFsError> {
+        let (_, blocks) = cluster::span(first);
+        if first < DIRECT_BLOCKS as u64 {
+            let first = first as usize;
+            return Ok(inode.direct[first..first + blocks].to_vec());
+        }
+        let mut slots = vec![0; blocks];
+        let (block_num, slot) = self.leaf(inode, first)?;
+        if block_num != 0 {
+            let data = self.read_meta(block_num)?;
+            for (i, ptr) in slots.iter_mut().enumerate() {
+                *ptr = get_u32(&data, (slot as usize + i) * 4);
+            }
+        }
+        Ok(slots)
+    }
+
+    /// Content of the cluster starting at file block `first`, or `None` if
+    /// it is not compressed
+    fn read_cluster(&self, inode: &DiskInode, first: u64, cipher: Option<&ContentsCipher>) -> Result<Option<Vec<u8>>, SplaxFsError> {
+        let slots = self.cluster_slots(inode, first)?;
+        if !cluster::is_compressed(&slots) {
+            return Ok(None);
+        }
+        if !cluster::is_valid(&slots) {
+            return Err(SplaxFsError::Corrupted);
+        }
+        let stored = slots.iter().take_while(|&&ptr| cluster::is_block(ptr)).count();
+        let mut image = vec![0u8; stored * BLOCK_SIZE];
+        for (i, (buf, &block_num)) in image.chunks_mut(BLOCK_SIZE).zip(&slots).enumerate() {
+            self.read_file_block(block_num, first + i as u64, cipher, buf)?;
+        }
+        cluster::unpack(&image).map(Some)
+    }
+
+    /// First `len` bytes of the cluster starting at file block `first`,
+    /// compressed or not
+    fn cluster_content(
+        &self,
+        inode: &DiskInode,
+        first: u64,
+        len: usize,
+        cipher: Option<&ContentsCipher>,
+    ) -> Result<Vec<u8>, SplaxFsError> {
+        if let Some(mut content) = self.read_cluster(inode, first, cipher)? {
+            content.resize(len, 0);
+            return Ok(content);
+        }
+        let mut content = vec![0u8; len.div_ceil(BLOCK_SIZE) * BLOCK_SIZE];
+        for (i, buf) in content.chunks_mut(BLOCK_SIZE).enumerate() {
+            match self.bmap(inode, first + i as u64)? {
+                0 => {}
+                block_num => self.read_file_block(block_num, first + i as u64, cipher, buf)?,
+            }
+        }
+        content.truncate(len);
+        Ok(content)
+    }
+
+    /// Stores the content of the cluster starting at file block `first`,
+    /// compressed if the inode has an algorithm and that saves a block.
+    /// The inode's table block must be private.
+    fn write_cluster(
+        &mut self,
+        inode: &mut DiskInode,
+        first: u64,
+        content: &[u8],
+        cipher: Option<&ContentsCipher>,
+    ) -> Result<(), SplaxFsError> {
+        // Saved slots become holes first, so the slots before them can
+        // take blocks again
+        let slots = self.cluster_slots(inode, first)?;
+        for (i, &ptr) in slots.iter().enumerate() {
+            if ptr == COMPRESSED_SLOT {
+                self.set_slot(inode, first + i as u64, 0)?;
+            }
+        }
+        let blocks = content.len().div_ceil(BLOCK_SIZE);
+        let image = inode.compression().and_then(|algorithm| cluster::pack(algorithm, content));
+        let stored = image.as_ref().map_or(blocks, |image| image.len() / BLOCK_SIZE);
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        for (i, chunk) in image.as_deref().unwrap_or(content).chunks(BLOCK_SIZE).enumerate() {
+            let (block_num, _) = self.bmap_alloc(inode, first + i as u64, Kind::Data)?;
+            buf.fill(0);
+            buf[..chunk.len()].copy_from_slice(chunk);
+            self.write_file_block(block_num, first + i as u64, cipher, &buf)?;
+        }
+        for i in stored..slots.len() {
+            let ptr = if i < blocks { COMPRESSED_SLOT } else { 0 };
+            self.set_slot(inode, first + i as u64, ptr)?;
+        }
+        if image.is_some() {
+            inode.flags |= INODE_COMPRESSED;
+        }
+        Ok(())
+    }
+
+    /// Writes file content cluster by cluster. Clusters that are plain
+    /// blocks stay that way while the inode has no algorithm.
+    fn write_clusters(
+        &mut self,
+        inode: &mut DiskInode,
+        offset: u64,
+        data: &[u8],
+        cipher: Option<&ContentsCipher>,
+    ) -> Result<(), SplaxFsError> {
+        let end = offset + data.len() as u64;
+        if end > inode.size() {
+            inode.set_size(end);
+        }
+        let mut pos = offset;
+
+        while pos < end {
+            let (first, blocks) = cluster::span(pos / BLOCK_SIZE as u64);
+            let start = first * BLOCK_SIZE as u64;
+            let cluster_size = (blocks * BLOCK_SIZE) as u64;
+            let next = (start + cluster_size).min(end);
+            let part = &data[(pos - offset) as usize..(next - offset) as usize];
+            if inode.compression().is_none() && !cluster::is_compressed(&self.cluster_slots(inode, first)?) {
+                self.write_blocks(inode, pos, part, cipher)?;
+            } else {
+                let len = (inode.size() - start).min(cluster_size) as usize;
+                let mut content = self.cluster_content(inode, first, len, cipher)?;
+                let within = (pos - start) as usize;
+                content[within..within + part.len()].copy_from_slice(part);
+                self.write_cluster(inode, first, &content, cipher)?;
+            }
+            pos = next;
+        }
+        Ok(())
+    }
+
+    /// Drops the content of a file with compressed clusters past `size`.
+    /// The inode's table block must be private.
+    fn truncate_clusters(
+        &mut self,
+        inode: &mut DiskInode,
+        size: u64,
+        cipher: Option<&ContentsCipher>,
+    ) -> Result<(), SplaxFsError> {
+        let (first, blocks) = cluster::span(size / BLOCK_SIZE as u64);
+        let keep = (size - first * BLOCK_SIZE as u64) as usize;
+        if keep == 0 {
+            return self.free_from(inode, first);
+        }
+        let content = self.cluster_content(inode, first, keep, cipher)?;
+        self.free_from(inode, first + blocks as u64)?;
+        self.write_cluster(inode, first, &content, cipher)
+    }
+
+    // -------------------------------------------------------------------------
+    // Encryption
+    // -------------------------------------------------------------------------
+
+    /// Encryption context of an inode, if it is encrypted
+    fn context(&self, inode: &DiskInode) -> Result<Option<Context>, SplaxFsError> {
+        if !inode.is_encrypted() {
+            return Ok(None);
+        }
+        // Without its context the inode cannot be decrypted at all
+        Context::find(&self.inode_xattrs(inode)?)?.ok_or(SplaxFsError::Corrupted).map(Some)
+    }
+
+    /// Cipher of a file's data; `None` if it is not encrypted, and
+    /// [`SplaxFsError::NoKey`] while its policy is locked
+    fn contents_cipher(&self, inode: &DiskInode) -> Result<Option<ContentsCipher>, SplaxFsError> {
+        if inode.is_directory() {
+            return Ok(None);
+        }
+        let Some(context) = self.context(inode)? else {
+            return Ok(None);
+        };
+        let key = self.volume.keys.get(&context.identifier).ok_or(SplaxFsError::NoKey)?;
+        Ok(Some(key.contents_cipher(&context)))
+    }
+
+    /// How a directory's entry names are stored
+    fn names(&self, dir: &DiskInode) -> Result<Names, SplaxFsError> {
+        let Some(context) = self.context(dir)? else {
+            return Ok(Names::Plain);
+        };
+        Ok(match self.volume.keys.get(&context.identifier) {
+            Some(key) => Names::Unlocked(Box::new(key.names_cipher(&context))),
+            None => Names::Locked,
+        })
+    }
+
+    /// Stored name of a new entry in a directory. An entry can only join
+    /// an encrypted directory if it is encrypted under the same master
+    /// key, so nothing below a policy is readable without it.
+    fn entry_name(&self, dir: &DiskInode, name: &str, inode: &DiskInode) -> Result<Vec<u8>, SplaxFsError> {
+        if let Some(context) = self.context(dir)? {
+            if self.context(inode)?.map(|own| own.identifier) != Some(context.identifier) {
+                return Err(SplaxFsError::PolicyMismatch);
+            }
+        }
+        self.names(dir)?.store(name)
+    }
+
+    /// Reads file block `index`, stored in `block_num`
+    fn read_file_block(
+        &self,
+        block_num: u32,
+        index: u64,
+        cipher: Option<&ContentsCipher>,
+        buf: &mut [u8],
+    ) -> Result<(), SplaxFsError> {
+        self.read_data(block_num, buf)?;
+        if let Some(cipher) = cipher {
+            cipher.decrypt_block(index, buf);
+        }
+        Ok(())
+    }
+
+    /// Writes file block `index` in place, to `block_num`
+    fn write_file_block(
+        &mut self,
+        block_num: u32,
+        index: u64,
+        cipher: Option<&ContentsCipher>,
+        buf: &[u8],
+    ) -> Result<(), SplaxFsError> {
+        let Some(cipher) = cipher else {
+            return self.write_data(block_num, buf);
+        };
+        let mut sealed = buf.to_vec();
+        cipher.encrypt_block(index, &mut sealed);
+        self.write_data(block_num, &sealed)
+    }
+
+    /// Gives an empty directory an encryption policy. Setting the policy
+    /// a directory already has is not an error.
+    fn set_policy(&mut self, ino: u32, key: &MasterKey) -> Result<(), SplaxFsError> {
+        let (mut inode, mut attrs) = self.xattrs(ino)?;
+        if !inode.is_directory() {
+            return Err(SplaxFsError::NotDirectory);
+        }
+        if let Some(context) = self.context(&inode)? {
+            if context.identifier != key.identifier {
+                return Err(SplaxFsError::Exists);
+            }
+            return Ok(());
+        }
+        // fsck puts lost+found in the root, in the clear
+        if ino == ROOT_INODE {
+            return Err(SplaxFsError::InvalidArg);
+        }
+        if !self.dir_is_empty(&inode)? {
+            return Err(SplaxFsError::NotEmpty);
+        }
+        let context = Context { identifier: key.identifier, nonce: crate::crypto::random::random_bytes() };
+        attrs.push((String::from(ENCRYPTION_XATTR), context.to_bytes().to_vec()));
+        inode.flags |= INODE_ENCRYPTED;
+        self.set_xattrs(ino, inode, &attrs)
+    }
+
+    /// Identifier of the master key an inode is encrypted under
+    fn policy(&self, ino: u32) -> Result<[u8; KEY_IDENTIFIER_SIZE], SplaxFsError> {
+        let inode = self.get_inode(ino)?;
+        self.context(&inode)?.map(|context| context.identifier).ok_or(SplaxFsError::InvalidArg)
+    }
+
+    // -------------------------------------------------------------------------
+    // Files and directories
+    // -------------------------------------------------------------------------
+
+    /// Reads up to `len` bytes of file content at `offset`
+    fn read_range(&self, inode: &DiskInode, offset: u64, len: usize) -> Result<Vec<u8>, SplaxFsError> {
+        let size = inode.size();
+        if offset >= size {
+            return Ok(Vec::new());
+        }
+        let end = offset.saturating_add(len as u64).min(size);
+        let cipher = self.contents_cipher(inode)?;
+        let mut out = Vec::with_capacity((end - offset) as usize);
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        let mut pos = offset;
+        // Content of the last compressed cluster read, by first block
+        let mut cached: Option<(u64, Option<Vec<u8>>)> = None;
+
+        while pos < end {
+            let within = (pos % BLOCK_SIZE as u64) as usize;
+            let n = ((BLOCK_SIZE - within) as u64).min(end - pos) as usize;
+            let index = pos / BLOCK_SIZE as u64;
+            let (first, _) = cluster::span(index);
+            if inode.is_compressed() && cached.as_ref().is_none_or(|(at, _)| *at != first) {
+                cached = Some((first, self.read_cluster(inode, first, cipher.as_ref())?));
+            }
+            match cached.as_ref().and_then(|(_, content)| content.as_ref()) {
+                Some(content) => {
+                    buf.fill(0);
+                    let start = (index - first) as usize * BLOCK_SIZE;
+                    if let Some(rest) = content.get(start..) {
+                        let have = rest.len().min(BLOCK_SIZE);
+                        buf[..have].copy_from_slice(&rest[..have]);
+                    }
+                }
+                None => match self.bmap(inode, index)? {
+                    0 => buf.fill(0),
+                    block_num => self.read_file_block(block_num, index, cipher.as_ref(), &mut buf)?,
+                },
+            }
+            out.extend_from_slice(&buf[within..within + n]);
+            pos += n as u64;
+        }
+        Ok(out)
+    }
+
+    /// Writes file content at `offset`, growing the file as needed. The
+    /// inode's table block must be private.
+    fn write_range(&mut self, inode: &mut DiskInode, offset: u64, data: &[u8]) -> Result<(), SplaxFsError> {
+        let end = offset.checked_add(data.len() as u64).ok_or(SplaxFsError::InvalidArg)?;
+        if end > MAX_FILE_BLOCKS * BLOCK_SIZE as u64 {
+            return Err(SplaxFsError::NoSpace);
+        }
+        let cipher = self.contents_cipher(inode)?;
+        if inode.is_file() && (inode.compression().is_some() || inode.is_compressed()) {
+            return self.write_clusters(inode, offset, data, cipher.as_ref());
+        }
+        self.write_blocks(inode, offset, data, cipher.as_ref())?;
+        if end > inode.size() {
+            inode.set_size(end);
+        }
+        Ok(())
+    }
+
+    /// Writes file content block by block, in place
+    fn write_blocks(
+        &mut self,
+        inode: &mut DiskInode,
+        offset: u64,
+        data: &[u8],
+        cipher: Option<&ContentsCipher>,
+    ) -> Result<(), SplaxFsError> {
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        let mut pos = offset;
+        let mut done = 0;
+
+        while done < data.len() {
+            let within = (pos % BLOCK_SIZE as u64) as usize;
+            let n = (BLOCK_SIZE - within).min(data.len() - done);
+            let index = pos / BLOCK_SIZE as u64;
+            let (block_num, new) = self.bmap_alloc(inode, index, Kind::Data)?;
+            if n < BLOCK_SIZE {
+                if new {
+                    buf.fill(0);
+                } else {
+                    self.read_file_block(block_num, index, cipher, &mut buf)?;
+                }
+            }
+            buf[within..within + n].copy_from_slice(&data[done..done + n]);
+            self.write_file_block(block_num, index, cipher, &buf)?;
+            pos += n as u64;
+            done += n;
+        }
+        Ok(())
+    }
+
+    /// Used entries of a directory with their file block index and byte
+    /// offset
+    fn dir_entries(&self, dir: &DiskInode) -> Result<Vec<(u64, usize, DirEntry)>, SplaxFsError> {
+        if !dir.is_directory() {
+            return Err(SplaxFsError::NotDirectory);
+        }
+        let mut entries = Vec::new();
+        for index in 0..dir.size() / BLOCK_SIZE as u64 {
+            let block_num = self.bmap(dir, index)?;
+            if block_num == 0 {
+                continue;
+            }
+            let data = self.read_meta(block_num)?;
+            for slot in 0..DIRENTS_PER_BLOCK {
+                let offset = slot * DIRENT_SIZE;
+                let entry = DirEntry::from_bytes(&data[offset..]);
+                if entry.inode != 0 {
+                    entries.push((index, offset, entry));
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    fn find_entry(&self, dir: &DiskInode, name: &str) -> Result<Option<(u64, usize, DirEntry)>, SplaxFsError> {
+        match self.names(dir)?.lookup_key(name) {
+            Some(stored) => self.find_stored(dir, &stored),
+            None => Ok(None),
+        }
+    }
+
+    /// Finds an entry by its name as stored
+    fn find_stored(&self, dir: &DiskInode, stored: &[u8]) -> Result<Option<(u64, usize, DirEntry)>, SplaxFsError> {
+        Ok(self.dir_entries(dir)?.into_iter().find(|(_, _, entry)| entry.raw_name() == stored))
+    }
+
+    /// Used entries of a directory with the names they are shown with
+    fn named_entries(&self, dir: &DiskInode) -> Result<Vec<(String, DirEntry)>, SplaxFsError> {
+        let names = self.names(dir)?;
+        Ok(self.dir_entries(dir)?.into_iter().map(|(_, _, entry)| (names.show(&entry), entry)).collect())
+    }
+
+    fn dir_is_empty(&self, dir: &DiskInode) -> Result<bool, SplaxFsError> {
+        Ok(self
+            .dir_entries(dir)?
+            .iter()
+            .all(|(_, _, entry)| matches!(entry.name_str(), "." | "..")))
+    }
+
+    /// Directory block `index`, private to the tree
+    fn dir_block_mut(&mut self, dir_ino: u32, index: u64) -> Result<u32, SplaxFsError> {
+        self.own_inode(dir_ino)?;
+        let mut dir = self.get_inode(dir_ino)?;
+        let (block_num, _) = self.bmap_alloc(&mut dir, index, Kind::Dir)?;
+        self.write_inode(dir_ino, &dir)?;
+        Ok(block_num)
+    }
+
+    /// Updates a directory's times after its entries changed
+    fn touch_dir(&mut self, dir_ino: u32) -> Result<(), SplaxFsError> {
+        let mut dir = self.get_inode(dir_ino)?;
+        dir.mtime = now();
+        dir.ctime = dir.mtime;
+        self.write_inode(dir_ino, &dir)
+    }
+
+    /// Adds an entry to a directory, growing it by a block if it is full.
+    /// `name` is as stored.
+    fn add_entry(&mut self, dir_ino: u32, name: &[u8], ino: u32, file_type: FileType) -> Result<(), SplaxFsError> {
+        self.own_inode(dir_ino)?;
+        let mut dir = self.get_inode(dir_ino)?;
+        let entry = DirEntry::from_raw(ino, name, file_type).to_bytes();
+        let dir_blocks = dir.size() / BLOCK_SIZE as u64;
+
+        for index in 0..dir_blocks {
+            let block_num = self.bmap(&dir, index)?;
+            if block_num == 0 {
+                continue;
+            }
+            let data = self.read_meta(block_num)?;
+            if let Some(slot) = (0..DIRENTS_PER_BLOCK).find(|slot| get_u32(&data, slot * DIRENT_SIZE) == 0) {
+                let block_num = self.dir_block_mut(dir_ino, index)?;
+                let mut data = self.read_meta(block_num)?;
+                let offset = slot * DIRENT_SIZE;
+                data[offset..offset + DIRENT_SIZE].copy_from_slice(&entry);
+                self.write_meta(block_num, data);
+                return self.touch_dir(dir_ino);
+            }
+        }
+
+        let (block_num, _) = self.bmap_alloc(&mut dir, dir_blocks, Kind::Dir)?;
+        let mut data = vec![0u8; BLOCK_SIZE];
+        data[..DIRENT_SIZE].copy_from_slice(&entry);
+        self.write_meta(block_num, data);
+        dir.set_size((dir_blocks + 1) * BLOCK_SIZE as u64);
+        self.write_inode(dir_ino, &dir)?;
+        self.touch_dir(dir_ino)
+    }
+
+    /// Points an existing entry at another inode
+    fn set_entry(&mut self, dir_ino: u32, index: u64, offset: usize, ino: u32, file_type: FileType) -> Result<(), SplaxFsError> {
+        let block_num = self.dir_block_mut(dir_ino, index)?;
+        let mut data = self.read_meta(block_num)?;
+        data[offset..offset + 4].copy_from_slice(&ino.to_le_bytes());
+        data[offset + 7] = file_type as u8;
+        self.write_meta(block_num, data);
+        Ok(())
+    }
+
+    fn remove_entry(&mut self, dir_ino: u32, index: u64, offset: usize) -> Result<(), SplaxFsError> {
+        let block_num = self.dir_block_mut(dir_ino, index)?;
+        let mut data = self.read_meta(block_num)?;
+        data[offset..offset + DIRENT_SIZE].fill(0);
+        self.write_meta(block_num, data);
+        self.touch_dir(dir_ino)
+    }
+
+    /// Adjusts the link count of a directory inode
+    fn adjust_links(&mut self, ino: u32, delta: i32) -> Result<(), SplaxFsError> {
+        let mut inode = self.get_inode(ino)?;
+        inode.links_count = (inode.links_count as i32 + delta).clamp(0, u16::MAX as i32) as u16;
+        inode.ctime = now();
+        self.write_inode(ino, &inode)
+    }
+
+    /// Frees an inode and drops its references to its blocks
+    fn release_inode(&mut self, ino: u32) -> Result<(), SplaxFsError> {
+        self.own_inode(ino)?;
+        let inode = self.get_inode(ino)?;
+        self.release_blocks(&inode)?;
+        let mut dead = DiskInode::new();
+        dead.dtime = now();
+        self.write_inode(ino, &dead)?;
+        self.free_inode(ino)
+    }
+
+    /// Drops one link to a non-directory, freeing it at zero
+    fn drop_link(&mut self, ino: u32, mut inode: DiskInode) -> Result<(), SplaxFsError> {
+        inode.links_count = inode.links_count.saturating_sub(1);
+        if inode.links_count == 0 {
+            return self.release_inode(ino);
+        }
+        inode.ctime = now();
+        self.write_inode(ino, &inode)
+    }
+
+    fn lookup(&self, parent: u32, name: &str) -> Result<u32, SplaxFsError> {
+        let dir = self.get_inode(parent)?;
+        self.find_entry(&dir, name)?
+            .map(|(_, _, entry)| entry.inode)
+            .ok_or(SplaxFsError::NotFound)
+    }
+
+    fn create(&mut self, parent: u32, name: &str, file_type: FileType) -> Result<u32, SplaxFsError> {
+        check_name(name)?;
+        let dir = self.get_inode(parent)?;
+        let stored = self.names(&dir)?.store(name)?;
+        if self.find_stored(&dir, &stored)?.is_some() {
+            return Err(SplaxFsError::Exists);
+        }
+        // New inodes take over the policy with keys of their own
+        let context = self.context(&dir)?.map(|context| Context {
+            identifier: context.identifier,
+            nonce: crate::crypto::random::random_bytes(),
+        });
+
+        let ino = self.alloc_inode()?;
+        let mut inode = match file_type {
+            FileType::Directory => DiskInode::new_directory(),
+            FileType::Symlink => DiskInode::new_symlink(),
+            FileType::Regular => DiskInode::new_file(),
+            FileType::Unknown => return Err(SplaxFsError::InvalidArg),
+        };
+        inode.atime = now();
+        inode.ctime = inode.atime;
+        inode.mtime = inode.atime;
+        if file_type != FileType::Symlink {
+            inode.flags |= dir.flags & INODE_COMPRESS_MASK;
+        }
+
+        if file_type == FileType::Directory {
+            let (block_num, _) = self.bmap_alloc(&mut inode, 0, Kind::Dir)?;
+            let mut data = vec![0u8; BLOCK_SIZE];
+            data[..DIRENT_SIZE].copy_from_slice(&DirEntry::new(ino, ".", FileType::Directory).to_bytes());
+            data[DIRENT_SIZE..2 * DIRENT_SIZE]
+                .copy_from_slice(&DirEntry::new(parent, "..", FileType::Directory).to_bytes());
+            self.write_meta(block_num, data);
+            inode.set_size(BLOCK_SIZE as u64);
+        }
+
+        match context {
+            Some(context) => {
+                inode.flags |= INODE_ENCRYPTED;
+                let attrs = vec![(String::from(ENCRYPTION_XATTR), context.to_bytes().to_vec())];
+                self.set_xattrs(ino, inode, &attrs)?;
+            }
+            None => self.write_inode(ino, &inode)?,
+        }
+        self.add_entry(parent, &stored, ino, file_type)?;
+        if file_type == FileType::Directory {
+            self.adjust_links(parent, 1)?;
+        }
+        Ok(ino)
+    }
+
+    fn unlink(&mut self, parent: u32, name: &str) -> Result<(), SplaxFsError> {
+        if name == "." || name == ".." {
+            return Err(SplaxFsError::InvalidArg);
+        }
+        let dir = self.get_inode(parent)?;
+        let (index, offset, entry) = self.find_entry(&dir, name)?.ok_or(SplaxFsError::NotFound)?;
+        let inode = self.get_inode(entry.inode)?;
+
+        if inode.is_directory() {
+            if !self.dir_is_empty(&inode)? {
+                return Err(SplaxFsError::NotEmpty);
+            }
+            self.remove_entry(parent, index, offset)?;
+            self.adjust_links(parent, -1)?;
+            self.release_inode(entry.inode)
+        } else {
+            self.remove_entry(parent, index, offset)?;
+            self.drop_link(entry.inode, inode)
+        }
+    }
+
+    fn rename(&mut self, old_parent: u32, old_name: &str, new_parent: u32, new_name: &str) -> Result<(), SplaxFsError> {
+        if old_name == "." || old_name == ".." {
+            return Err(SplaxFsError::InvalidArg);
+        }
+        check_name(new_name)?;
+
+        let old_dir = self.get_inode(old_parent)?;
+        let (old_index, old_offset, entry) = self.find_entry(&old_dir, old_name)?.ok_or(SplaxFsError::NotFound)?;
+        let ino = entry.inode;
+        let inode = self.get_inode(ino)?;
+        let new_dir = self.get_inode(new_parent)?;
+        if !new_dir.is_directory() {
+            return Err(SplaxFsError::NotDirectory);
+        }
+        let new_stored = self.entry_name(&new_dir, new_name, &inode)?;
+
+        let moving_dir = inode.is_directory();
+        if moving_dir && old_parent != new_parent {
+            // A directory cannot move below itself
+            let mut current = new_parent;
+            while current != ROOT_INODE {
+                if current == ino {
+                    return Err(SplaxFsError::InvalidArg);
+                }
+                current = self.lookup(current, "..")?;
+            }
+        }
+
+        match self.find_stored(&new_dir, &new_stored)? {
+            Some((_, _, existing)) if existing.inode == ino => return Ok(()),
+            Some((index, offset, existing)) => {
+                let target = self.get_inode(existing.inode)?;
+                match (moving_dir, target.is_directory()) {
+                    (true, false) => return Err(SplaxFsError::NotDirectory),
+                    (false, true) => return Err(SplaxFsError::IsDirectory),
+                    (true, true) if !self.dir_is_empty(&target)? => return Err(SplaxFsError::NotEmpty),
+                    _ => {}
+                }
+                self.set_entry(new_parent, index, offset, ino, inode.file_type())?;
+                if target.is_directory() {
+                    self.adjust_links(new_parent, -1)?;
+                    self.release_inode(existing.inode)?;
+                } else {
+                    self.drop_link(existing.inode, target)?;
+                }
+                self.touch_dir(new_parent)?;
+            }
+            None => self.add_entry(new_parent, &new_stored, ino, inode.file_type())?,
+        }
+
+        self.remove_entry(old_parent, old_index, old_offset)?;
+
+        if moving_dir && old_parent != new_parent {
+            let moved = self.get_inode(ino)?;
+            if let Some((index, offset, _)) = self.find_entry(&moved, "..")? {
+                self.set_entry(ino, index, offset, new_parent, FileType::Directory)?;
+            }
+            self.adjust_links(old_parent, -1)?;
+            self.adjust_links(new_parent, 1)?;
+        }
+
+        let mut inode = self.get_inode(ino)?;
+        inode.ctime = now();
+        self.write_inode(ino, &inode)
+    }
+
+    fn link(&mut self, ino: u32, new_parent: u32, new_name: &str) -> Result<(), SplaxFsError> {
+        check_name(new_name)?;
+        let inode = self.get_inode(ino)?;
+        if inode.is_directory() {
+            return Err(SplaxFsError::NotSupported);
+        }
+        if inode.links_count == u16::MAX {
+            return Err(SplaxFsError::NoSpace);
+        }
+        let dir = self.get_inode(new_parent)?;
+        let stored = self.entry_name(&dir, new_name, &inode)?;
+        if self.find_stored(&dir, &stored)?.is_some() {
+            return Err(SplaxFsError::Exists);
+        }
+        self.add_entry(new_parent, &stored, ino, inode.file_type())?;
+        self.adjust_links(ino, 1)
+    }
+
+    fn symlink(&mut self, parent: u32, name: &str, target: &str) -> Result<u32, SplaxFsError> {
+        if target.is_empty() || target.len() >= BLOCK_SIZE {
+            return Err(SplaxFsError::InvalidArg);
+        }
+        let ino = self.create(parent, name, FileType::Symlink)?;
+        let mut inode = self.get_inode(ino)?;
+        self.write_range(&mut inode, 0, target.as_bytes())?;
+        self.write_inode(ino, &inode)?;
+        Ok(ino)
+    }
+
+    fn readlink(&self, ino: u32) -> Result<String, SplaxFsError> {
+        let inode = self.get_inode(ino)?;
+        if inode.file_type() != FileType::Symlink {
+            return Err(SplaxFsError::InvalidArg);
+        }
+        let data = self.read_range(&inode, 0, inode.size() as usize)?;
+        String::from_utf8(data).map_err(|_| SplaxFsError::Corrupted)
+    }
+
+    /// Checks that an inode is a regular file
+    fn get_file(&self, ino: u32) -> Result<DiskInode, SplaxFsError> {
+        let inode = self.get_inode(ino)?;
+        match inode.file_type() {
+            FileType::Regular => Ok(inode),
+            FileType::Directory => Err(SplaxFsError::IsDirectory),
+            _ => Err(SplaxFsError::InvalidArg),
+        }
+    }
+
+    fn read(&self, ino: u32, offset: u64, len: usize) -> Result<Vec<u8>, SplaxFsError> {
+        let inode = self.get_file(ino)?;
+        self.read_range(&inode, offset, len)
+    }
+
+    fn write(&mut self, ino: u32, offset: u64, data: &[u8]) -> Result<(), SplaxFsError> {
+        let mut inode = self.get_file(ino)?;
+        self.own_inode(ino)?;
+        self.write_range(&mut inode, offset, data)?;
+        inode.mtime = now();
+        inode.ctime = inode.mtime;
+        self.write_inode(ino, &inode)
+    }
+
+    fn truncate(&mut self, ino: u32, size: u64) -> Result<(), SplaxFsError> {
+        let mut inode = self.get_file(ino)?;
+        if size > MAX_FILE_BLOCKS * BLOCK_SIZE as u64 {
+            return Err(SplaxFsError::NoSpace);
+        }
+        // Only a partial last block is rewritten; dropping whole blocks
+        // needs no key
+        let partial = size < inode.size() && (inode.is_compressed() || !size.is_multiple_of(BLOCK_SIZE as u64));
+        let cipher = if partial { self.contents_cipher(&inode)? } else { None };
+        if size < inode.size() && inode.is_compressed() {
+            self.own_inode(ino)?;
+            self.truncate_clusters(&mut inode, size, cipher.as_ref())?;
+            if size == 0 {
+                inode.flags &= !INODE_COMPRESSED;
+            }
+        } else if size < inode.size() {
+            self.own_inode(ino)?;
+            self.free_from(&mut inode, size.div_ceil(BLOCK_SIZE as u64))?;
+            // Bytes past the end of the last block must read back as zeros
+            let within = (size % BLOCK_SIZE as u64) as usize;
+            let index = size / BLOCK_SIZE as u64;
+            if within != 0 && self.bmap(&inode, index)? != 0 {
+                let (block_num, _) = self.bmap_alloc(&mut inode, index, Kind::Data)?;
+                let mut buf = vec![0u8; BLOCK_SIZE];
+                self.read_file_block(block_num, index, cipher.as_ref(), &mut buf)?;
+                buf[within..].fill(0);
+                self.write_file_block(block_num, index, cipher.as_ref(), &buf)?;
+            }
+        }
+        inode.set_size(size);
+        inode.mtime = now();
+        inode.ctime = inode.mtime;
+        self.write_inode(ino, &inode)
+    }
+
+    fn setattr(&mut self, ino: u32, attr: &VfsAttr) -> Result<(), SplaxFsError> {
+        let mut inode = self.get_inode(ino)?;
+        let mut perm = 0;
+        if attr.perm.readable {
+            perm |= 0o444;
+        }
+        if attr.perm.writable {
+            perm |= 0o200;
+        }
+        if attr.perm.executable {
+            perm |= 0o111;
+        }
+        inode.mode = (inode.mode & !0o777) | perm;
+        inode.atime = attr.atime as u32;
+        inode.mtime = attr.mtime as u32;
+        inode.ctime = now();
+        self.write_inode(ino, &inode)
+    }
+
+    /// Block backing file page `index`, allocated and zeroed if a hole
+    fn dax_block(&mut self, ino: u32, index: u64) -> Result<u32, SplaxFsError> {
+        let mut inode = self.get_file(ino)?;
+        // Compressed clusters have no block per page, and encrypted blocks
+        // cannot be mapped as they are stored
+        if inode.compression().is_some() || inode.is_compressed() || inode.is_encrypted() {
+            return Err(SplaxFsError::NotSupported);
+        }
+        self.own_inode(ino)?;
+        let (block_num, new) = self.bmap_alloc(&mut inode, index, Kind::Data)?;
+        if new {
+            self.write_data(block_num, &[0u8; BLOCK_SIZE])?;
+            self.write_inode(ino, &inode)?;
+        }
+        self.volume.dax_blocks.insert(block_num);
+        Ok(block_num)
+    }
+
+    // -------------------------------------------------------------------------
+    // Extended attributes
+    // -------------------------------------------------------------------------
+
+    /// Reads an inode and its extended attributes
+    fn xattrs(&self, ino: u32) -> Result<(DiskInode, Xattrs), SplaxFsError> {
+        let inode = self.get_inode(ino)?;
+        let attrs = self.inode_xattrs(&inode)?;
+        Ok((inode, attrs))
+    }
+
+    /// Extended attributes of an inode already read
+    fn inode_xattrs(&self, inode: &DiskInode) -> Result<Xattrs, SplaxFsError> {
+        let block = match inode.xattr_block {
+            0 => None,
+            block_num => Some(self.read_meta(block_num)?),
+        };
+        xattr::decode(inode, block.as_deref())
+    }
+
+    /// Stores an inode's extended attributes, taking or giving back the
+    /// overflow block as needed
+    fn set_xattrs(&mut self, ino: u32, mut inode: DiskInode, attrs: &Xattrs) -> Result<(), SplaxFsError> {
+        let (inline, block) = xattr::encode(attrs)?;
+        self.own_inode(ino)?;
+        match (block, inode.xattr_block) {
+            (Some(data), 0) => {
+                let block_num = self.alloc_block()?;
+                self.write_meta(block_num, data);
+                inode.xattr_block = block_num;
+                inode.blocks += SECTORS_PER_BLOCK;
+            }
+            (Some(data), block_num) => {
+                let block_num = self.unshare(block_num, Kind::Attrs)?;
+                self.write_meta(block_num, data);
+                inode.xattr_block = block_num;
+            }
+            (None, 0) => {}
+            (None, block_num) => {
+                self.decref(block_num)?;
+                inode.xattr_block = 0;
+                inode.blocks = inode.blocks.saturating_sub(SECTORS_PER_BLOCK);
+            }
+        }
+        inode.xattr_inline = inline;
+        inode.ctime = now();
+        self.write_inode(ino, &inode)
+    }
+
+    fn getxattr(&self, ino: u32, name: &str) -> Result<Vec<u8>, SplaxFsError> {
+        if name == COMPRESSION_XATTR {
+            let inode = self.get_inode(ino)?;
+            return inode
+                .compression()
+                .map(|algorithm| algorithm.name().as_bytes().to_vec())
+                .ok_or(SplaxFsError::NoAttribute);
+        }
+        let (_, attrs) = self.xattrs(ino)?;
+        attrs
+            .into_iter()
+            .find(|(attr, _)| attr == name)
+            .map(|(_, value)| value)
+            .ok_or(SplaxFsError::NoAttribute)
+    }
+
+    fn setxattr(&mut self, ino: u32, name: &str, value: &[u8]) -> Result<(), SplaxFsError> {
+        if name == COMPRESSION_XATTR {
+            let algorithm = match core::str::from_utf8(value) {
+                Ok("none") => None,
+                Ok(name) => Some(Algorithm::from_name(name).ok_or(SplaxFsError::InvalidArg)?),
+                Err(_) => return Err(SplaxFsError::InvalidArg),
+            };
+            return self.set_compression(ino, algorithm);
+        }
+        if name == ENCRYPTION_XATTR {
+            return Err(SplaxFsError::InvalidArg);
+        }
+        let (inode, mut attrs) = self.xattrs(ino)?;
+        match attrs.iter_mut().find(|(attr, _)| attr == name) {
+            Some((_, old)) => *old = value.to_vec(),
+            None => attrs.push((name.to_string(), value.to_vec())),
+        }
+        self.set_xattrs(ino, inode, &attrs)
+    }
+
+    fn removexattr(&mut self, ino: u32, name: &str) -> Result<(), SplaxFsError> {
+        if name == COMPRESSION_XATTR {
+            if self.get_inode(ino)?.compression().is_none() {
+                return Err(SplaxFsError::NoAttribute);
+            }
+            return self.set_compression(ino, None);
+        }
+        if name == ENCRYPTION_XATTR {
+            return Err(SplaxFsError::InvalidArg);
+        }
+        let (inode, mut attrs) = self.xattrs(ino)?;
+        let index = attrs
+            .iter()
+            .position(|(attr, _)| attr == name)
+            .ok_or(SplaxFsError::NoAttribute)?;
+        attrs.remove(index);
+        self.set_xattrs(ino, inode, &attrs)
+    }
+
+    /// Sets the algorithm new data of a file, or new files in a directory,
+    /// are compressed with
+    fn set_compression(&mut self, ino: u32, algorithm: Option<Algorithm>) -> Result<(), SplaxFsError> {
+        let mut inode = self.get_inode(ino)?;
+        if inode.file_type() == FileType::Symlink {
+            return Err(SplaxFsError::NotSupported);
+        }
+        inode.set_compression(algorithm);
+        inode.ctime = now();
+        self.write_inode(ino, &inode)
+    }
+
+    // -------------------------------------------------------------------------
+    // Snapshots
+    // -------------------------------------------------------------------------
+
+    /// Reads a tree's inode map and inode bitmap
+    fn read_tree(&self, map_blocks: Vec<u32>, bitmap_blocks: Vec<u32>) -> Result<Tree, SplaxFsError> {
+        let table_blocks = self.superblock().inode_table_blocks() as usize;
+        let mut table = Vec::with_capacity(map_blocks.len() * PTRS_PER_BLOCK);
+        for &block_num in &map_blocks {
+            let data = self.read_meta(block_num)?;
+            table.extend((0..PTRS_PER_BLOCK).map(|slot| get_u32(&data, slot * 4)));
+        }
+        table.truncate(table_blocks);
+        let mut inode_bitmap = Vec::with_capacity(bitmap_blocks.len() * BLOCK_SIZE);
+        for &block_num in &bitmap_blocks {
+            inode_bitmap.extend(self.read_meta(block_num)?);
+        }
+        Ok(Tree { map_blocks, bitmap_blocks, table, inode_bitmap })
+    }
+
+    /// Blocks listed in a snapshot's root block: the inode map blocks,
+    /// then the inode bitmap blocks
+    fn snapshot_blocks(&self, entry: &SnapshotEntry) -> Result<Vec<u32>, SplaxFsError> {
+        let root = self.read_meta(entry.root)?;
+        Ok((0..self.superblock().snapshot_root_ptrs() as usize).map(|i| get_u32(&root, i * 4)).collect())
+    }
+
+    /// Tree of a snapshot, from memory if it is mounted
+    fn snapshot_tree(&self, entry: &SnapshotEntry) -> Result<Tree, SplaxFsError> {
+        if let Some(tree) = self.volume.trees.get(&entry.id) {
+            return Ok(tree.clone());
+        }
+        let mut map_blocks = self.snapshot_blocks(entry)?;
+        let bitmap_blocks = map_blocks.split_off(self.superblock().inode_map_blocks() as usize);
+        self.read_tree(map_blocks, bitmap_blocks)
+    }
+
+    /// Snapshot in use with a name
+    fn find_snapshot(&self, name: &str) -> Result<SnapshotEntry, SplaxFsError> {
+        let table = &self.volume.snapshots;
+        match table.find(name).map(|slot| table.entries[slot]) {
+            Some(entry) if !entry.is_deleting() => Ok(entry),
+            _ => Err(SplaxFsError::NotFound),
+        }
+    }
+
+    /// Changes the snapshot table
+    fn update_snapshots(&mut self, change: impl FnOnce(&mut SnapshotTable)) {
+        self.undo.push(Undo::Snapshots(Box::new(self.volume.snapshots.clone())));
+        change(&mut self.volume.snapshots);
+        self.snapshots_dirty = true;
+    }
+
+    /// Copies the live inode map and inode bitmap into a new snapshot
+    /// entry. The entry takes no references of its own on the inode table
+    /// blocks; the caller adds them or hands over the live tree's.
+    fn save_live_tree(&mut self, name: &str, flags: u32) -> Result<u32, SplaxFsError> {
+        let slot = self
+            .volume
+            .snapshots
+            .entries
+            .iter()
+            .position(|entry| entry.id == 0)
+            .ok_or(SplaxFsError::NoSpace)?;
+        let sb = *self.superblock();
+        let live = self.volume.trees[&0].clone();
+
+        let root = self.alloc_block()?;
+        let mut ptrs = vec![0u8; BLOCK_SIZE];
+        let images = (0..sb.inode_map_blocks())
+            .map(|index| map_image(&live.table, index))
+            .chain(live.inode_bitmap.chunks(BLOCK_SIZE).map(|chunk| chunk.to_vec()));
+        for (i, image) in images.enumerate() {
+            let copy = self.alloc_block()?;
+            self.write_meta(copy, image);
+            ptrs[i * 4..i * 4 + 4].copy_from_slice(&copy.to_le_bytes());
+        }
+        self.write_meta(root, ptrs);
+
+        let id = self.volume.snapshots.next_id;
+        let mut entry = SnapshotEntry::new(id, name, flags, now());
+        entry.root = root;
+        entry.free_inodes = sb.free_inodes;
+        self.update_snapshots(|table| {
+            table.entries[slot] = entry;
+            table.next_id += 1;
+        });
+        Ok(id)
+    }
+
+    /// Snapshots the live tree
+    fn create_snapshot(&mut self, name: &str, flags: u32) -> Result<u32, SplaxFsError> {
+        check_snapshot_name(name)?;
+        if self.volume.snapshots.find(name).is_some() {
+            return Err(SplaxFsError::Exists);
+        }
+        let id = self.save_live_tree(name, flags)?;
+        // The snapshot is one more parent of every live inode table block
+        let table = self.volume.trees[&0].table.clone();
+        for block_num in table.into_iter().filter(|&block_num| block_num != 0) {
+            self.incref(block_num)?;
+        }
+        Ok(id)
+    }
+
+    /// Marks a snapshot for deletion; [`delete_step`](Self::delete_step)
+    /// then releases its blocks
+    fn begin_delete(&mut self, name: &str) -> Result<u32, SplaxFsError> {
+        let slot = self.volume.snapshots.find(name).ok_or(SplaxFsError::NotFound)?;
+        let id = self.volume.snapshots.entries[slot].id;
+        if self.volume.trees.contains_key(&id) {
+            return Err(SplaxFsError::Busy);
+        }
+        self.update_snapshots(|table| table.entries[slot].flags |= SNAPSHOT_DELETING);
+        Ok(id)
+    }
+
+    /// Releases one inode table block of a snapshot being deleted or, once
+    /// none are left, the snapshot itself. Returns false when it is gone.
+    fn delete_step(&mut self, id: u32) -> Result<bool, SplaxFsError> {
+        let Some(slot) = self.volume.snapshots.entries.iter().position(|entry| entry.id == id) else {
+            return Ok(false);
+        };
+        let entry = self.volume.snapshots.entries[slot];
+        let blocks = self.snapshot_blocks(&entry)?;
+        for &map_block in &blocks[..self.superblock().inode_map_blocks() as usize] {
+            let mut data = self.read_meta(map_block)?;
+            if let Some(slot) = (0..PTRS_PER_BLOCK).find(|slot| get_u32(&data, slot * 4) != 0) {
+                self.release(get_u32(&data, slot * 4), Kind::Inodes)?;
+                data[slot * 4..slot * 4 + 4].fill(0);
+                self.write_meta(map_block, data);
+                return Ok(true);
+            }
+        }
+        for block_num in blocks.into_iter().chain([entry.root]) {
+            self.decref(block_num)?;
+        }
+        self.update_snapshots(|table| table.entries[slot] = SnapshotEntry::from_bytes(&[]));
+        Ok(false)
+    }
+
+    /// Makes the live tree a copy of a snapshot. The old live tree moves
+    /// to a nameless snapshot marked for deletion, whose id is returned.
+    fn rollback(&mut self, name: &str) -> Result<u32, SplaxFsError> {
+        let entry = self.find_snapshot(name)?;
+        let target = self.snapshot_tree(&entry)?;
+        // The hidden snapshot takes over the live tree's references
+        let old_id = self.save_live_tree("", SNAPSHOT_DELETING)?;
+        for &block_num in target.table.iter().filter(|&&block_num| block_num != 0) {
+            self.incref(block_num)?;
+        }
+
+        let live = self.volume.trees.get_mut(&0).expect("live tree is loaded while mounted");
+        self.undo.push(Undo::Live(Box::new(live.clone()), self.volume.superblock.free_inodes));
+        live.table = target.table;
+        live.inode_bitmap = target.inode_bitmap;
+        let sb = self.superblock_mut();
+        sb.free_inodes = entry.free_inodes;
+        let (map_blocks, bitmap_blocks) = (sb.inode_map_blocks(), sb.inode_bitmap_blocks());
+        self.dirty_maps.extend((0..map_blocks).map(|index| (0, index)));
+        self.dirty_inode_bitmap.extend((0..bitmap_blocks).map(|index| (0, index)));
+        Ok(old_id)
+    }
+
+    // -------------------------------------------------------------------------
+    // Scrubbing
+    // -------------------------------------------------------------------------
+
+    /// Reads a block for a scrub the first time it comes up. A block that
+    /// cannot be read or fails `good` is reported; only a good block's
+    /// content is returned.
+    fn scrub_check(&self, scrub: &mut Scrub, ino: u32, block_num: u32, good: impl FnOnce(&[u8]) -> bool) -> Option<Vec<u8>> {
+        if block_num >= self.superblock().total_blocks || test_bit(&scrub.checked, block_num) {
+            return None;
+        }
+        set_bit(&mut scrub.checked, block_num, true);
+        scrub.status.blocks_checked += 1;
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        if self.disk.read(block_num, &mut buf).is_ok() && good(&buf) {
+            return Some(buf);
+        }
+        scrub.status.errors.push(ScrubError { snapshot: scrub.tree, ino, block: block_num, path: None });
+        None
+    }
+
+    /// Checks a block against the checksum table
+    fn scrub_block(&self, scrub: &mut Scrub, ino: u32, block_num: u32) -> Option<Vec<u8>> {
+        if !cluster::is_block(block_num) {
+            return None;
+        }
+        let volume = &*self.volume;
+        self.scrub_check(scrub, ino, block_num, |data| {
+            volume.dax_blocks.contains(&block_num) || volume.checksums.verify(block_num, data).is_ok()
+        })
+    }
+
+    /// Checks a pointer block and, if it is good, the blocks below it
+    fn scrub_pointers(&self, scrub: &mut Scrub, ino: u32, block_num: u32, depth: u32) {
+        let Some(data) = self.scrub_block(scrub, ino, block_num) else { return };
+        for slot in 0..PTRS_PER_BLOCK {
+            let ptr = get_u32(&data, slot * 4);
+            if depth > 1 {
+                self.scrub_pointers(scrub, ino, ptr, depth - 1);
+            } else {
+                self.scrub_block(scrub, ino, ptr);
+            }
+        }
+    }
+
+    /// Starts a scrub by checking the superblock and the fixed metadata
+    /// areas
+    fn start_scrub(&self) -> Scrub {
+        let sb = *self.superblock();
+        let mut pending: Vec<u32> = self.volume.snapshots.iter().filter(|entry| !entry.is_deleting()).map(|entry| entry.id).collect();
+        pending.reverse();
+        let mut scrub = Scrub {
+            tree: 0,
+            table: Vec::new(),
+            index: 0,
+            pending,
+            checked: vec![0u8; sb.total_blocks.div_ceil(8) as usize],
+            status: ScrubStatus { running: true, ..ScrubStatus::default() },
+        };
+
+        self.scrub_check(&mut scrub, 0, 0, |data| Superblock::from_bytes(data).checksum_is_valid());
+        for block_num in sb.csum_block..sb.csum_block + sb.csum_blocks() {
+            self.scrub_check(&mut scrub, 0, block_num, |data| {
+                ChecksumTable::from_bytes(data.to_vec()).bad_blocks(block_num).is_empty()
+            });
+        }
+        let areas = [
+            (sb.refcount_block, sb.refcount_blocks()),
+            (sb.inode_bitmap_block, sb.inode_bitmap_blocks()),
+            (sb.inode_map_block, sb.inode_map_blocks()),
+            (sb.snapshot_block, 1),
+        ];
+        for (start, count) in areas {
+            for block_num in start..start + count {
+                self.scrub_block(&mut scrub, 0, block_num);
+            }
+        }
+        scrub
+    }
+
+    /// Moves a scrub on to a snapshot, checking its root, inode map and
+    /// inode bitmap blocks. A snapshot whose inode map is damaged is
+    /// skipped.
+    fn scrub_snapshot(&self, scrub: &mut Scrub, id: u32) {
+        scrub.tree = id;
+        scrub.table.clear();
+        scrub.index = 0;
+        let Some(entry) = self.volume.snapshots.iter().find(|entry| entry.id == id && !entry.is_deleting()).copied() else {
+            return;
+        };
+        let Some(root) = self.scrub_block(scrub, 0, entry.root) else { return };
+        let sb = *self.superblock();
+        let mut table = Vec::new();
+        for i in 0..sb.snapshot_root_ptrs() as usize {
+            let data = self.scrub_block(scrub, 0, get_u32(&root, i * 4));
+            if i < sb.inode_map_blocks() as usize {
+                let Some(data) = data else { return };
+                table.extend((0..PTRS_PER_BLOCK).map(|slot| get_u32(&data, slot * 4)));
+            }
+        }
+        table.truncate(sb.inode_table_blocks() as usize);
+        scrub.table = table;
+    }
+
+    /// Checks the next inode table block of a scrub and the blocks of its
+    /// inodes, or moves on to the next tree. Returns false once every
+    /// tree is done.
+    fn scrub_step(&self, scrub: &mut Scrub) -> bool {
+        let table = match self.volume.trees.get(&scrub.tree) {
+            Some(tree) => &tree.table,
+            None => &scrub.table,
+        };
+        let Some(&block_num) = table.get(scrub.index) else {
+            match scrub.pending.pop() {
+                Some(id) => self.scrub_snapshot(scrub, id),
+                None => return false,
+            }
+            return true;
+        };
+        let first_ino = (scrub.index * INODES_PER_BLOCK) as u32 + 1;
+        scrub.index += 1;
+        let Some(data) = self.scrub_block(scrub, 0, block_num) else { return true };
+        for slot in 0..INODES_PER_BLOCK {
+            let inode = DiskInode::from_bytes(&data[slot * INODE_SIZE..]);
+            if inode.mode == 0 {
+                continue;
+            }
+            let ino = first_ino + slot as u32;
+            for &block_num in &inode.direct {
+                self.scrub_block(scrub, ino, block_num);
+            }
+            self.scrub_pointers(scrub, ino, inode.indirect, 1);
+            self.scrub_pointers(scrub, ino, inode.double_indirect, 2);
+            self.scrub_block(scrub, ino, inode.xattr_block);
+        }
+        true
+    }
+
+    /// Fills in the paths of the live files a scrub found corrupt
+    fn scrub_paths(&self, errors: &mut [ScrubError]) {
+        let wanted: BTreeSet<u32> = errors.iter().filter(|e| e.snapshot == 0 && e.ino != 0).map(|e| e.ino).collect();
+        let mut paths = BTreeMap::from([(ROOT_INODE, String::from("/"))]);
+        let mut dirs = vec![(ROOT_INODE, String::new())];
+        while let Some((dir, path)) = dirs.pop() {
+            // Damaged directories hide what is below them
+            let Ok(entries) = self.get_inode(dir).and_then(|inode| self.named_entries(&inode)) else { continue };
+            for (name, entry) in entries {
+                if name == "." || name == ".." || paths.contains_key(&entry.inode) {
+                    continue;
+                }
+                let child = alloc::format!("{}/{}", path, name);
+                if FileType::from(entry.file_type) == FileType::Directory {
+                    dirs.push((entry.inode, child.clone()));
+                }
+                paths.insert(entry.inode, child);
+            }
+        }
+        for error in errors.iter_mut().filter(|e| wanted.contains(&e.ino)) {
+            error.path = paths.get(&error.ino).cloned();
+        }
+    }
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        // Only an operation that failed gets here with changes left
+        while let Some(undo) = self.undo.pop() {
+            let volume = &mut *self.volume;
+            match undo {
+                Undo::Refs(n, old) => {
+                    let current = get_ref(&volume.refcounts, n);
+                    set_ref(&mut volume.refcounts, n, old);
+                    if current == 0 && old != 0 {
+                        volume.superblock.free_blocks -= 1;
+                    } else if current != 0 && old == 0 {
+                        volume.superblock.free_blocks += 1;
+                    }
+                }
+                Undo::Inode(tree, n, old) => {
+                    let Some(tree_state) = volume.trees.get_mut(&tree) else { continue };
+                    if test_bit(&tree_state.inode_bitmap, n) == old {
+                        continue;
+                    }
+                    set_bit(&mut tree_state.inode_bitmap, n, old);
+                    if let Some(free) = volume.free_inodes_mut(tree) {
+                        *free = if old { free.saturating_sub(1) } else { *free + 1 };
+                    }
+                }
+                Undo::Table(tree, index, old) => {
+                    if let Some(tree) = volume.trees.get_mut(&tree) {
+                        tree.table[index as usize] = old;
+                    }
+                }
+                Undo::Snapshots(table) => volume.snapshots = *table,
+                Undo::Live(tree, free_inodes) => {
+                    volume.trees.insert(0, *tree);
+                    volume.superblock.free_inodes = free_inodes;
+                }
+                Undo::Checksum(n, old) => volume.checksums.set(n, old),
+                Undo::Saved(delta) => {
+                    volume.superblock.saved_blocks = volume.superblock.saved_blocks.wrapping_add_signed(-delta);
+                }
+            }
+        }
+    }
+}
+
+// =============================================================================
+// Scrubbing
+// =============================================================================
+
+/// Ticks between scrub steps, so a scrub leaves the disk to others
+const SCRUB_INTERVAL_TICKS: u64 = 10;
+
+/// Corrupt block found by a scrub
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScrubError {
+    /// Tree the block was found in: 0 for the live tree, else a snapshot id
+    pub snapshot: u32,
+    /// Inode the block belongs to; 0 for volume metadata and inode tables
+    pub ino: u32,
+    /// Block number
+    pub block: u32,
+    /// Path of the file relative to the volume root (live files only)
+    pub path: Option<String>,
+}
+
+/// Progress and findings of a scrub
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScrubStatus {
+    /// Still walking the volume
+    pub running: bool,
+    /// Blocks checked so far
+    pub blocks_checked: u64,
+    /// Corrupt blocks found so far
+    pub errors: Vec<ScrubError>,
+}
+
+/// Scrub of a volume: the live tree, then each snapshot, an inode table
+/// block per step
+struct Scrub {
+    /// Tree being walked
+    tree: u32,
+    /// Its inode table, if the tree is not loaded
+    table: Vec<u32>,
+    /// Next inode table block to walk
+    index: usize,
+    /// Snapshots still to walk, the next one last
+    pending: Vec<u32>,
+    /// Blocks checked already (one bit per block), so shared blocks are
+    /// read once
+    checked: Vec<u8>,
+    /// What the scrub found
+    status: ScrubStatus,
+}
+
+// =============================================================================
+// Mounted Filesystem
+// =============================================================================
+
+/// Mounted SplaxFS filesystem
+pub struct SplaxFs {
+    /// Device the volume lives on
+    disk: Disk,
+    /// Mount point
+    mount_point: String,
+    /// Device is byte addressable; file pages can be mapped directly
+    dax: bool,
+    /// Tree shown: 0 for the live file system, otherwise a snapshot id
+    tree: u32,
+    /// Changes are refused
+    read_only: bool,
+    /// Cached metadata, shared with mounted snapshots; held for the
+    /// duration of each operation
+    volume: Arc<Mutex<Volume>>,
+    /// Current or last scrub (live mounts); locked before `volume`
+    scrub: Mutex<Option<Scrub>>,
+}
+
+impl SplaxFs {
+    /// Formats a block device with SplaxFS
+    pub fn format(device_name: &str) -> Result<(), SplaxFsError> {
+        Self::format_volume(device_name, false)
+    }
+
+    /// Formats a device with SplaxFS including journal
+    pub fn format_with_journal(device_name: &str) -> Result<(), SplaxFsError> {
+        Self::format_volume(device_name, true)
+    }
+
+    fn format_volume(device_name: &str, journaled: bool) -> Result<(), SplaxFsError> {
+        crate::serial_println!("[splaxfs] Formatting device {}{}...", device_name,
+            if journaled { " with journal" } else { "" });
+
+        let disk = Disk::open(device_name)?;
+        let sb = splaxfs_core::format(&disk, journaled, now()).inspect_err(|e| {
+            if *e == SplaxFsError::NoSpace {
+                crate::serial_println!("[splaxfs] Device too small");
+            }
+        })?;
+
+        crate::serial_println!("[splaxfs] Format complete:");
+        crate::serial_println!("  Total blocks: {}", sb.total_blocks);
+        if journaled {
+            crate::serial_println!("  Journal blocks: {}", sb.refcount_block - sb.journal_block - 1);
+        }
+        crate::serial_println!("  Total inodes: {}", sb.total_inodes);
+        crate::serial_println!("  First data block: {}", sb.first_data_block);
+        crate::serial_println!("  Usable space: {} KB", (sb.total_blocks - sb.first_data_block) as u64 * 4);
+        Ok(())
+    }
+
+    /// Mounts a SplaxFS filesystem
+    pub fn mount(device_name: &str, mount_point: &str) -> Result<Self, SplaxFsError> {
+        crate::serial_println!("[splaxfs] Mounting {} at {}...", device_name, mount_point);
+
+        let disk = Disk::open(device_name)?;
+        let mut block_buf = vec![0u8; BLOCK_SIZE];
+        disk.read(0, &mut block_buf)?;
+        let mut sb = Superblock::from_bytes(&block_buf);
+
+        if !sb.is_valid() {
+            crate::serial_println!("[splaxfs] Invalid superblock magic: 0x{:08x}", sb.magic);
+            return Err(SplaxFsError::Corrupted);
+        }
+        if sb.version != SPLAXFS_VERSION {
+            crate::serial_println!("[splaxfs] Unsupported version {} (expected {}), reformat the volume",
+                sb.version, SPLAXFS_VERSION);
+            return Err(SplaxFsError::Corrupted);
+        }
+
+        let journal = if sb.journal_block != 0 {
+            let mut journal = Journal::open(&disk, sb.journal_block).inspect_err(|e| {
+                if *e == SplaxFsError::JournalError {
+                    crate::serial_println!("[splaxfs] Journal is damaged or of an older version, run fsck");
+                }
+            })?;
+            let recovered = journal.recover(&disk)?;
+            if recovered > 0 {
+                crate::serial_println!("[splaxfs] Recovered {} transactions from journal", recovered);
+                // The replay may have rewritten the superblock
+                disk.read(0, &mut block_buf)?;
+                sb = Superblock::from_bytes(&block_buf);
+            }
+            crate::serial_println!("[splaxfs] Journal enabled");
+            Some(journal)
         } else {
-            Err(SplaxFsError::TransactionNotFound)
+            None
+        };
+
+        if !sb.checksum_is_valid() {
+            crate::serial_println!("[splaxfs] Superblock checksum mismatch, run fsck");
+            return Err(SplaxFsError::ChecksumMismatch);
+        }
+        if !sb.layout_is_valid() || sb.total_blocks > disk.total_blocks {
+            crate::serial_println!("[splaxfs] Superblock layout does not fit the device");
+            return Err(SplaxFsError::Corrupted);
+        }
+        if sb.state != STATE_CLEAN {
+            crate::serial_println!("[splaxfs] Volume was not cleanly unmounted");
+        }
+
+        let read_raw = |start: u32, count: u32| -> Result<Vec<u8>, SplaxFsError> {
+            let mut area = vec![0u8; count as usize * BLOCK_SIZE];
+            for (i, chunk) in area.chunks_mut(BLOCK_SIZE).enumerate() {
+                disk.read(start + i as u32, chunk)?;
+            }
+            Ok(area)
+        };
+        let checksums = ChecksumTable::from_bytes(read_raw(sb.csum_block, sb.csum_blocks())?);
+        if let Some(index) = checksums.bad_blocks(sb.csum_block).first() {
+            crate::serial_println!("[splaxfs] Checksum table block {} is damaged, run fsck", index);
+            return Err(SplaxFsError::ChecksumMismatch);
+        }
+        let read_area = |start: u32, count: u32| -> Result<Vec<u8>, SplaxFsError> {
+            let area = read_raw(start, count)?;
+            for (i, chunk) in area.chunks(BLOCK_SIZE).enumerate() {
+                verify(&checksums, start + i as u32, chunk)?;
+            }
+            Ok(area)
+        };
+        let refcounts = read_area(sb.refcount_block, sb.refcount_blocks())?;
+        let inode_bitmap = read_area(sb.inode_bitmap_block, sb.inode_bitmap_blocks())?;
+        let map = read_area(sb.inode_map_block, sb.inode_map_blocks())?;
+        let snapshots = SnapshotTable::from_bytes(&read_area(sb.snapshot_block, 1)?);
+        let live = Tree {
+            map_blocks: (0..sb.inode_map_blocks()).map(|i| sb.inode_map_block + i).collect(),
+            bitmap_blocks: (0..sb.inode_bitmap_blocks()).map(|i| sb.inode_bitmap_block + i).collect(),
+            table: (0..sb.inode_table_blocks() as usize).map(|i| get_u32(&map, i * 4)).collect(),
+            inode_bitmap,
+        };
+
+        crate::serial_println!("[splaxfs] Mounted successfully:");
+        crate::serial_println!("  Volume: {}",
+            core::str::from_utf8(&sb.volume_name).unwrap_or("?").trim_end_matches('\0'));
+        crate::serial_println!("  Total blocks: {}", sb.total_blocks);
+        crate::serial_println!("  Free blocks: {}", sb.free_blocks);
+        crate::serial_println!("  Total inodes: {}", sb.total_inodes);
+        crate::serial_println!("  Free inodes: {}", sb.free_inodes);
+        crate::serial_println!("  Snapshots: {}", snapshots.iter().filter(|entry| !entry.is_deleting()).count());
+
+        let dax = disk.direct_access(0).is_some();
+        if dax {
+            crate::serial_println!("  DAX: enabled");
+        }
+
+        let fs = SplaxFs {
+            disk,
+            mount_point: String::from(mount_point),
+            dax,
+            tree: 0,
+            read_only: false,
+            volume: Arc::new(Mutex::new(Volume {
+                superblock: sb,
+                refcounts,
+                snapshots,
+                trees: BTreeMap::from([(0, live)]),
+                block_hint: sb.first_data_block,
+                journal,
+                checksums,
+                dirty_checksums: BTreeSet::new(),
+                dax_blocks: BTreeSet::new(),
+                keys: BTreeMap::new(),
+            })),
+            scrub: Mutex::new(None),
+        };
+        fs.transact_mut(|txn| {
+            let sb = txn.superblock_mut();
+            sb.mount_count = sb.mount_count.wrapping_add(1);
+            sb.state = STATE_DIRTY;
+            sb.last_mount_time = now();
+            Ok(())
+        })?;
+
+        // A crash can interrupt a deletion; finish it
+        let deleting: Vec<u32> = fs.volume.lock().snapshots.iter().filter(|entry| entry.is_deleting()).map(|entry| entry.id).collect();
+        for id in deleting {
+            crate::serial_println!("[splaxfs] Finishing deletion of snapshot {}", id);
+            fs.finish_delete(id)?;
         }
+        Ok(fs)
     }
 
-    /// Writes data to a file with journaling support
-    pub fn write_file_journaled(&mut self, path: &str, data: &[u8]) -> Result<(), SplaxFsError> {
-        // Begin transaction
-        let _txn_id = self.begin_transaction().ok();
-        
-        // Perform the write
-        let result = self.write_file(path, data);
-        
-        // Commit or abort based on result
-        if result.is_ok() {
-            let _ = self.commit_transaction();
-        } else {
-            let _ = self.abort_transaction();
+    /// Runs one operation as a transaction
+    fn transact<R>(&self, op: impl FnOnce(&mut Transaction<'_>) -> Result<R, SplaxFsError>) -> Result<R, SplaxFsError> {
+        let mut volume = self.volume.lock();
+        let mut txn = Transaction::new(&self.disk, &mut volume, self.tree);
+        let result = op(&mut txn)?;
+        txn.commit()?;
+        Ok(result)
+    }
+
+    /// Runs one operation that changes the tree as a transaction
+    fn transact_mut<R>(&self, op: impl FnOnce(&mut Transaction<'_>) -> Result<R, SplaxFsError>) -> Result<R, SplaxFsError> {
+        if self.read_only {
+            return Err(SplaxFsError::ReadOnly);
         }
-        
-        result
+        self.transact(op)
     }
 
-    /// Creates a file with journaling support
-    pub fn create_file_journaled(&mut self, path: &str) -> Result<(), SplaxFsError> {
-        let _txn_id = self.begin_transaction().ok();
-        let result = self.create_file(path);
-        if result.is_ok() {
-            let _ = self.commit_transaction();
-        } else {
-            let _ = self.abort_transaction();
+    /// Returns the device name
+    pub fn device_name(&self) -> &str {
+        &self.disk.name
+    }
+
+    /// Returns the mount point
+    pub fn mount_point(&self) -> &str {
+        &self.mount_point
+    }
+
+    /// Returns a copy of the superblock
+    pub fn superblock(&self) -> Superblock {
+        self.volume.lock().superblock
+    }
+
+    /// Snapshot shown, if this is a snapshot mount
+    pub fn snapshot_id(&self) -> Option<u32> {
+        (self.tree != 0).then_some(self.tree)
+    }
+
+    /// Free inodes in the tree shown
+    fn free_inodes(&self) -> u32 {
+        self.volume.lock().free_inodes_mut(self.tree).map_or(0, |free| *free)
+    }
+
+    /// Writes file data, one transaction per chunk
+    fn write_at(&self, ino: u32, offset: u64, data: &[u8]) -> Result<(), SplaxFsError> {
+        for (i, chunk) in data.chunks(WRITE_CHUNK).enumerate() {
+            let at = offset.checked_add((i * WRITE_CHUNK) as u64).ok_or(SplaxFsError::InvalidArg)?;
+            self.transact_mut(|txn| txn.write(ino, at, chunk))?;
         }
-        result
+        Ok(())
     }
 
-    /// Creates a directory with journaling support
-    pub fn create_dir_journaled(&mut self, path: &str) -> Result<(), SplaxFsError> {
-        let _txn_id = self.begin_transaction().ok();
-        let result = self.create_dir(path);
-        if result.is_ok() {
-            let _ = self.commit_transaction();
-        } else {
-            let _ = self.abort_transaction();
-        }
-        result
-    }
-
-    /// Syncs all pending data to disk
-    pub fn sync(&mut self) -> Result<(), SplaxFsError> {
-        let device_name = self.device_name.clone();
-        let inode_bitmap = self.inode_bitmap.clone();
-        let block_bitmap = self.block_bitmap.clone();
-        let superblock = self.superblock.clone();
-        
-        block::with_device(&device_name, |device| {
-            Self::sync_bitmaps_static(device, &superblock, &inode_bitmap, &block_bitmap)
-        }).map_err(|_| SplaxFsError::IoError)??;
-        
-        // Checkpoint any committed transactions
-        if let Some(ref mut journal) = self.journal {
-            // For now, just log that sync was called
-            crate::serial_println!("[splaxfs] Sync complete");
-        }
-        
-        self.dirty = false;
+    /// Flushes the device
+    pub fn flush(&self) -> Result<(), SplaxFsError> {
+        // Every operation is committed when it returns; only mapped pages
+        // change on their own
+        if self.dax {
+            self.transact(|txn| txn.refresh_dax_checksums())?;
+        }
+        let _volume = self.volume.lock();
+        self.disk.flush()
+    }
+
+    /// Mounted snapshots hold the volume
+    fn has_snapshot_mounts(&self) -> bool {
+        self.tree == 0 && self.volume.lock().trees.len() > 1
+    }
+
+    /// Marks the volume cleanly unmounted, or drops a snapshot mount's tree
+    pub fn unmount(&self) -> Result<(), SplaxFsError> {
+        if self.tree != 0 {
+            self.volume.lock().trees.remove(&self.tree);
+            return Ok(());
+        }
+        if self.has_snapshot_mounts() {
+            return Err(SplaxFsError::Busy);
+        }
+        self.transact_mut(|txn| {
+            txn.refresh_dax_checksums()?;
+            txn.volume.dax_blocks.clear();
+            txn.superblock_mut().state = STATE_CLEAN;
+            Ok(())
+        })?;
+        self.disk.flush()
+    }
+
+    /// Resolves a path relative to the volume root
+    pub fn lookup_path(&self, path: &str) -> Result<u32, SplaxFsError> {
+        self.transact(|txn| {
+            let mut ino = ROOT_INODE;
+            for component in path.split('/').filter(|c| !c.is_empty()) {
+                ino = txn.lookup(ino, component)?;
+            }
+            Ok(ino)
+        })
+    }
+
+    /// Splits a path into its parent directory inode and final name
+    fn lookup_parent<'p>(&self, path: &'p str) -> Result<(u32, &'p str), SplaxFsError> {
+        let path = path.trim_end_matches('/');
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+        if name.is_empty() {
+            return Err(SplaxFsError::InvalidArg);
+        }
+        Ok((self.lookup_path(parent)?, name))
+    }
+
+    /// Lists directory contents
+    pub fn list_dir(&self, path: &str) -> Result<Vec<(String, FileType, u64)>, SplaxFsError> {
+        let ino = self.lookup_path(path)?;
+        self.transact(|txn| {
+            let dir = txn.get_inode(ino)?;
+            txn.named_entries(&dir)?
+                .into_iter()
+                .map(|(name, entry)| {
+                    let size = txn.get_inode(entry.inode)?.size();
+                    Ok((name, FileType::from(entry.file_type), size))
+                })
+                .collect()
+        })
+    }
+
+    /// Creates an empty file
+    pub fn create_file(&self, path: &str) -> Result<(), SplaxFsError> {
+        let (parent, name) = self.lookup_parent(path)?;
+        self.transact_mut(|txn| txn.create(parent, name, FileType::Regular)).map(|_| ())
+    }
+
+    /// Creates a directory
+    pub fn create_dir(&self, path: &str) -> Result<(), SplaxFsError> {
+        let (parent, name) = self.lookup_parent(path)?;
+        self.transact_mut(|txn| txn.create(parent, name, FileType::Directory)).map(|_| ())
+    }
+
+    /// Replaces the content of an existing file
+    pub fn write_file(&self, path: &str, data: &[u8]) -> Result<(), SplaxFsError> {
+        let ino = self.lookup_path(path)?;
+        self.transact_mut(|txn| txn.truncate(ino, 0))?;
+        self.write_at(ino, 0, data)
+    }
+
+    /// Reads a whole file
+    pub fn read_file(&self, path: &str) -> Result<Vec<u8>, SplaxFsError> {
+        let ino = self.lookup_path(path)?;
+        self.transact(|txn| {
+            let inode = txn.get_file(ino)?;
+            txn.read_range(&inode, 0, inode.size() as usize)
+        })
+    }
+
+    /// Returns the type and size of a file
+    pub fn stat(&self, path: &str) -> Result<(FileType, u64), SplaxFsError> {
+        let ino = self.lookup_path(path)?;
+        self.transact(|txn| {
+            let inode = txn.get_inode(ino)?;
+            Ok((inode.file_type(), inode.size()))
+        })
+    }
+
+    /// Takes a snapshot of the live file system; a writable snapshot can
+    /// be mounted read-write. Returns its id.
+    pub fn snapshot(&self, name: &str, writable: bool) -> Result<u32, SplaxFsError> {
+        // Mapped DAX pages are written without copy-on-write
+        if self.tree != 0 || self.dax {
+            return Err(SplaxFsError::NotSupported);
+        }
+        let flags = if writable { SNAPSHOT_WRITABLE } else { 0 };
+        let id = self.transact_mut(|txn| txn.create_snapshot(name, flags))?;
+        crate::serial_println!("[splaxfs] Snapshot {} '{}' of {}", id, name, self.mount_point);
+        Ok(id)
+    }
+
+    /// Lists the snapshots of the volume
+    pub fn snapshots(&self) -> Vec<SnapshotEntry> {
+        self.volume.lock().snapshots.iter().filter(|entry| !entry.is_deleting()).copied().collect()
+    }
+
+    /// Deletes a snapshot and frees the blocks only it used
+    pub fn delete_snapshot(&self, name: &str) -> Result<(), SplaxFsError> {
+        if self.tree != 0 {
+            return Err(SplaxFsError::NotSupported);
+        }
+        let id = self.transact_mut(|txn| txn.begin_delete(name))?;
+        self.finish_delete(id)?;
+        crate::serial_println!("[splaxfs] Deleted snapshot '{}' of {}", name, self.mount_point);
         Ok(())
     }
 
-    /// Formats a device with SplaxFS including journal
-    pub fn format_with_journal(device_name: &str) -> Result<(), SplaxFsError> {
-        crate::serial_println!("[splaxfs] Formatting device {} with journal...", device_name);
-
-        block::with_device(device_name, |device| {
-            let info = device.info();
-            let total_sectors = info.total_sectors;
-            let sector_size = info.sector_size;
-            
-            // Calculate filesystem parameters
-            let sectors_per_block = BLOCK_SIZE / sector_size;
-            let total_blocks = (total_sectors as usize / sectors_per_block) as u32;
-            
-            if total_blocks < 32 {
-                crate::serial_println!("[splaxfs] Device too small for journaled filesystem");
-                return Err(SplaxFsError::NoSpace);
+    /// Releases the blocks of a snapshot marked for deletion, an inode
+    /// table block per transaction
+    fn finish_delete(&self, id: u32) -> Result<(), SplaxFsError> {
+        while self.transact_mut(|txn| txn.delete_step(id))? {}
+        Ok(())
+    }
+
+    /// Mounts a snapshot of this volume at `mount_point`. Only a writable
+    /// snapshot can be mounted read-write.
+    pub fn mount_snapshot(&self, name: &str, mount_point: &str, writable: bool) -> Result<SplaxFs, SplaxFsError> {
+        if self.tree != 0 {
+            return Err(SplaxFsError::NotSupported);
+        }
+        let disk = Disk::open(&self.disk.name)?;
+        let id = self.transact(|txn| {
+            let entry = txn.find_snapshot(name)?;
+            if writable && !entry.is_writable() {
+                return Err(SplaxFsError::ReadOnly);
             }
-            
-            // Layout with journal:
-            // Block 0: Superblock
-            // Block 1: Journal Superblock
-            // Block 2-9: Journal Log Area (8 blocks)
-            // Block 10: Block bitmap
-            // Block 11: Inode bitmap
-            // Block 12-19: Inode table (8 blocks = 256 inodes)
-            // Block 20+: Data blocks
-            
-            let journal_log_start = 2u32;
-            let journal_log_blocks = 8u32;
-            let block_bitmap_block = 10u32;
-            let inode_bitmap_block = 11u32;
-            let inode_table_start = 12u32;
-            let inode_table_blocks = 8u32;
-            let first_data_block = inode_table_start + inode_table_blocks;
-            let total_inodes = inode_table_blocks * INODES_PER_BLOCK as u32;
-            
-            // Create and write superblock
-            let sb = Superblock::new(total_blocks, total_inodes, first_data_block);
-            let mut block_buf = [0u8; BLOCK_SIZE];
-            let sb_bytes = sb.to_bytes();
-            block_buf[..512].copy_from_slice(&sb_bytes);
-            Self::write_block(device, 0, &block_buf)?;
-            
-            // Initialize journal
-            Journal::format(device, journal_log_start, journal_log_blocks)?;
-            
-            // Initialize block bitmap (mark metadata blocks as used)
-            let mut block_bitmap = vec![0u8; BLOCK_SIZE];
-            for i in 0..first_data_block {
-                let byte = (i / 8) as usize;
-                let bit = (i % 8) as usize;
-                if byte < block_bitmap.len() {
-                    block_bitmap[byte] |= 1 << bit;
-                }
+            if txn.volume.trees.contains_key(&entry.id) {
+                return Err(SplaxFsError::Busy);
+            }
+            let tree = txn.snapshot_tree(&entry)?;
+            txn.volume.trees.insert(entry.id, tree);
+            Ok(entry.id)
+        })?;
+        crate::serial_println!("[splaxfs] Mounted snapshot '{}' of {} at {}{}", name, self.mount_point, mount_point,
+            if writable { "" } else { " (read-only)" });
+        Ok(SplaxFs {
+            disk,
+            mount_point: String::from(mount_point),
+            dax: false,
+            tree: id,
+            read_only: !writable,
+            volume: self.volume.clone(),
+            scrub: Mutex::new(None),
+        })
+    }
+
+    /// Rolls the live file system back to a snapshot, which is kept
+    pub fn rollback(&self, name: &str) -> Result<(), SplaxFsError> {
+        if self.tree != 0 {
+            return Err(SplaxFsError::NotSupported);
+        }
+        let old = self.transact_mut(|txn| txn.rollback(name))?;
+        crate::serial_println!("[splaxfs] Rolled {} back to snapshot '{}'", self.mount_point, name);
+        self.finish_delete(old)
+    }
+
+    /// Starts scrubbing the volume, snapshots included;
+    /// [`scrub_step`](Self::scrub_step) carries it on
+    pub fn start_scrub(&self) -> Result<(), SplaxFsError> {
+        if self.tree != 0 {
+            return Err(SplaxFsError::NotSupported);
+        }
+        let mut scrub = self.scrub.lock();
+        if scrub.as_ref().is_some_and(|scrub| scrub.status.running) {
+            return Err(SplaxFsError::Busy);
+        }
+        *scrub = Some(self.transact(|txn| Ok(txn.start_scrub()))?);
+        crate::serial_println!("[splaxfs] Scrubbing {}", self.mount_point);
+        Ok(())
+    }
+
+    /// Checks one more inode table block's worth of a running scrub.
+    /// Returns false once no scrub is running.
+    pub fn scrub_step(&self) -> Result<bool, SplaxFsError> {
+        let mut scrub = self.scrub.lock();
+        let Some(scrub) = scrub.as_mut().filter(|scrub| scrub.status.running) else {
+            return Ok(false);
+        };
+        let more = self.transact(|txn| {
+            let more = txn.scrub_step(scrub);
+            if !more {
+                txn.scrub_paths(&mut scrub.status.errors);
             }
-            Self::write_block(device, block_bitmap_block, &block_bitmap)?;
-            
-            // Initialize inode bitmap (mark inodes 0, 1, 2 as used)
-            let mut inode_bitmap = vec![0u8; BLOCK_SIZE];
-            inode_bitmap[0] = 0b00000111;
-            Self::write_block(device, inode_bitmap_block, &inode_bitmap)?;
-            
-            // Initialize inode table with zeros
-            let zero_block = [0u8; BLOCK_SIZE];
-            for i in 0..inode_table_blocks {
-                Self::write_block(device, inode_table_start + i, &zero_block)?;
+            Ok(more)
+        })?;
+        if more {
+            return Ok(true);
+        }
+        scrub.status.running = false;
+        scrub.checked = Vec::new();
+        crate::serial_println!("[splaxfs] Scrubbed {}: {} blocks checked, {} corrupt", self.mount_point,
+            scrub.status.blocks_checked, scrub.status.errors.len());
+        for error in &scrub.status.errors {
+            let owner = match (&error.path, error.ino) {
+                (Some(path), _) => path.clone(),
+                (None, 0) => String::from("metadata"),
+                (None, ino) => alloc::format!("inode {}", ino),
+            };
+            match error.snapshot {
+                0 => crate::serial_println!("  block {}: {}", error.block, owner),
+                id => crate::serial_println!("  block {}: {} in snapshot {}", error.block, owner, id),
             }
-            
-            // Create root directory inode (inode 2)
-            let mut root_inode = DiskInode::new_directory();
-            root_inode.size_low = BLOCK_SIZE as u32;
-            root_inode.blocks = (BLOCK_SIZE / 512) as u32;
-            root_inode.direct[0] = first_data_block;
-            Self::write_inode(device, ROOT_INODE, &root_inode)?;
-            
-            // Create root directory content
-            let mut root_block = [0u8; BLOCK_SIZE];
-            let dot = DirEntry::new(ROOT_INODE, ".", FileType::Directory);
-            let dotdot = DirEntry::new(ROOT_INODE, "..", FileType::Directory);
-            root_block[..DIRENT_SIZE].copy_from_slice(&dot.to_bytes());
-            root_block[DIRENT_SIZE..2 * DIRENT_SIZE].copy_from_slice(&dotdot.to_bytes());
-            
-            // Mark first data block as used
-            block_bitmap[first_data_block as usize / 8] |= 1 << (first_data_block % 8);
-            Self::write_block(device, block_bitmap_block, &block_bitmap)?;
-            
-            // Write root directory block
-            Self::write_block(device, first_data_block, &root_block)?;
-            
-            crate::serial_println!("[splaxfs] Format with journal complete:");
-            crate::serial_println!("  Total blocks: {}", total_blocks);
-            crate::serial_println!("  Journal blocks: {}", journal_log_blocks);
-            crate::serial_println!("  Total inodes: {}", total_inodes);
-            crate::serial_println!("  First data block: {}", first_data_block);
-            crate::serial_println!("  Usable space: {} KB", (total_blocks - first_data_block) * 4);
-            
-            Ok(())
-        }).map_err(|_| SplaxFsError::NotFound)?
+        }
+        Ok(false)
+    }
+
+    /// Progress of the current scrub, or findings of the last one
+    pub fn scrub_status(&self) -> Option<ScrubStatus> {
+        self.scrub.lock().as_ref().map(|scrub| scrub.status.clone())
+    }
+
+    /// Stops a running scrub, keeping what it found so far
+    pub fn cancel_scrub(&self) -> Result<(), SplaxFsError> {
+        let mut scrub = self.scrub.lock();
+        match scrub.as_mut().filter(|scrub| scrub.status.running) {
+            Some(scrub) => {
+                scrub.status.running = false;
+                scrub.checked = Vec::new();
+                Ok(())
+            }
+            None => Err(SplaxFsError::NotFound),
+        }
+    }
+
+    /// Scrubs the whole volume at once
+    pub fn scrub(&self) -> Result<ScrubStatus, SplaxFsError> {
+        self.start_scrub()?;
+        while self.scrub_step()? {}
+        self.scrub_status().ok_or(SplaxFsError::NotFound)
+    }
+}
+
+/// Converts a VFS inode number
+fn to_ino(ino: InodeNum) -> Result<u32, VfsError> {
+    u32::try_from(ino).map_err(|_| VfsError::NotFound)
+}
+
+impl Filesystem for SplaxFs {
+    fn name(&self) -> &'static str {
+        "splaxfs"
+    }
+
+    fn root_ino(&self) -> InodeNum {
+        ROOT_INODE as InodeNum
+    }
+
+    fn lookup(&self, parent: InodeNum, name: &str) -> Result<InodeNum, VfsError> {
+        let parent = to_ino(parent)?;
+        Ok(self.transact(|txn| txn.lookup(parent, name))? as InodeNum)
+    }
+
+    fn getattr(&self, ino: InodeNum) -> Result<VfsAttr, VfsError> {
+        let inode_num = to_ino(ino)?;
+        let inode = self.transact(|txn| txn.get_inode(inode_num))?;
+        Ok(VfsAttr {
+            ino,
+            file_type: inode.file_type().into(),
+            perm: VfsPermissions {
+                readable: inode.mode & 0o444 != 0,
+                writable: inode.mode & 0o222 != 0,
+                executable: inode.mode & 0o111 != 0,
+            },
+            size: inode.size(),
+            nlink: inode.links_count as u32,
+            blksize: BLOCK_SIZE as u32,
+            blocks: inode.blocks as u64,
+            atime: inode.atime as u64,
+            mtime: inode.mtime as u64,
+            ctime: inode.ctime as u64,
+            crtime: inode.ctime as u64,
+        })
+    }
+
+    fn readdir(&self, ino: InodeNum) -> Result<Vec<VfsDirEntry>, VfsError> {
+        let ino = to_ino(ino)?;
+        let entries = self.transact(|txn| {
+            let dir = txn.get_inode(ino)?;
+            txn.named_entries(&dir)
+        })?;
+        Ok(entries
+            .into_iter()
+            .map(|(name, entry)| VfsDirEntry {
+                name,
+                ino: entry.inode as InodeNum,
+                file_type: FileType::from(entry.file_type).into(),
+            })
+            .collect())
+    }
+
+    fn read(&self, ino: InodeNum, offset: u64, size: usize) -> Result<Vec<u8>, VfsError> {
+        let ino = to_ino(ino)?;
+        Ok(self.transact(|txn| txn.read(ino, offset, size))?)
+    }
+
+    fn write(&self, ino: InodeNum, offset: u64, data: &[u8]) -> Result<usize, VfsError> {
+        self.write_at(to_ino(ino)?, offset, data)?;
+        Ok(data.len())
+    }
+
+    fn create(&self, parent: InodeNum, name: &str, file_type: VfsFileType) -> Result<InodeNum, VfsError> {
+        let parent = to_ino(parent)?;
+        let file_type = match file_type {
+            VfsFileType::Regular => FileType::Regular,
+            VfsFileType::Directory => FileType::Directory,
+            _ => return Err(VfsError::NotSupported),
+        };
+        Ok(self.transact_mut(|txn| txn.create(parent, name, file_type))? as InodeNum)
+    }
+
+    fn unlink(&self, parent: InodeNum, name: &str) -> Result<(), VfsError> {
+        let parent = to_ino(parent)?;
+        Ok(self.transact_mut(|txn| txn.unlink(parent, name))?)
+    }
+
+    fn rename(&self, old_parent: InodeNum, old_name: &str, new_parent: InodeNum, new_name: &str) -> Result<(), VfsError> {
+        let (old_parent, new_parent) = (to_ino(old_parent)?, to_ino(new_parent)?);
+        Ok(self.transact_mut(|txn| txn.rename(old_parent, old_name, new_parent, new_name))?)
+    }
+
+    fn truncate(&self, ino: InodeNum, size: u64) -> Result<(), VfsError> {
+        let ino = to_ino(ino)?;
+        Ok(self.transact_mut(|txn| txn.truncate(ino, size))?)
+    }
+
+    fn sync(&self) -> Result<(), VfsError> {
+        Ok(self.flush()?)
+    }
+
+    fn statfs(&self) -> Result<VfsStatFs, VfsError> {
+        let sb = self.superblock();
+        Ok(VfsStatFs {
+            blocks: sb.total_blocks as u64,
+            bfree: sb.free_blocks as u64,
+            bavail: sb.free_blocks as u64,
+            files: sb.total_inodes as u64,
+            ffree: self.free_inodes() as u64,
+            bsize: BLOCK_SIZE as u32,
+            namelen: MAX_FILENAME as u32,
+            bsaved: sb.saved_blocks as u64,
+        })
+    }
+
+    fn readlink(&self, ino: InodeNum) -> Result<String, VfsError> {
+        let ino = to_ino(ino)?;
+        Ok(self.transact(|txn| txn.readlink(ino))?)
+    }
+
+    fn symlink(&self, parent: InodeNum, name: &str, target: &str) -> Result<InodeNum, VfsError> {
+        let parent = to_ino(parent)?;
+        Ok(self.transact_mut(|txn| txn.symlink(parent, name, target))? as InodeNum)
+    }
+
+    fn link(&self, ino: InodeNum, new_parent: InodeNum, new_name: &str) -> Result<(), VfsError> {
+        let (ino, new_parent) = (to_ino(ino)?, to_ino(new_parent)?);
+        Ok(self.transact_mut(|txn| txn.link(ino, new_parent, new_name))?)
+    }
+
+    fn setattr(&self, ino: InodeNum, attr: &VfsAttr) -> Result<(), VfsError> {
+        let ino = to_ino(ino)?;
+        Ok(self.transact_mut(|txn| txn.setattr(ino, attr))?)
+    }
+
+    fn is_dax(&self) -> bool {
+        self.dax
+    }
+
+    fn dax_page(&self, ino: InodeNum, index: u64) -> Result<u64, VfsError> {
+        if !self.dax {
+            return Err(VfsError::NotSupported);
+        }
+        let ino = to_ino(ino)?;
+        let block_num = self.transact_mut(|txn| txn.dax_block(ino, index))?;
+        self.disk.direct_access(block_num).ok_or(VfsError::IoError)
+    }
+
+    fn getxattr(&self, ino: InodeNum, name: &str) -> Result<Vec<u8>, VfsError> {
+        let ino = to_ino(ino)?;
+        super::xattr::check_name(name)?;
+        Ok(self.transact(|txn| txn.getxattr(ino, name))?)
+    }
+
+    fn setxattr(&self, ino: InodeNum, name: &str, value: &[u8]) -> Result<(), VfsError> {
+        let ino = to_ino(ino)?;
+        super::xattr::check_attr(name, value)?;
+        Ok(self.transact_mut(|txn| txn.setxattr(ino, name, value))?)
+    }
+
+    fn listxattr(&self, ino: InodeNum) -> Result<Vec<String>, VfsError> {
+        let ino = to_ino(ino)?;
+        let (inode, attrs) = self.transact(|txn| txn.xattrs(ino))?;
+        let mut names: Vec<String> = attrs.into_iter().map(|(name, _)| name).collect();
+        if inode.compression().is_some() {
+            names.push(COMPRESSION_XATTR.to_string());
+        }
+        Ok(names)
+    }
+
+    fn removexattr(&self, ino: InodeNum, name: &str) -> Result<(), VfsError> {
+        let ino = to_ino(ino)?;
+        super::xattr::check_name(name)?;
+        Ok(self.transact_mut(|txn| txn.removexattr(ino, name))?)
+    }
+
+    fn set_encryption(&self, ino: InodeNum, key: &[u8]) -> Result<(), VfsError> {
+        let ino = to_ino(ino)?;
+        let key = MasterKey::new(key)?;
+        self.transact_mut(|txn| txn.set_policy(ino, &key))?;
+        self.volume.lock().keys.insert(key.identifier, key);
+        Ok(())
+    }
+
+    fn unlock(&self, ino: InodeNum, key: &[u8]) -> Result<(), VfsError> {
+        let ino = to_ino(ino)?;
+        let key = MasterKey::new(key)?;
+        if self.transact(|txn| txn.policy(ino))? != key.identifier {
+            return Err(SplaxFsError::NoKey.into());
+        }
+        self.volume.lock().keys.insert(key.identifier, key);
+        Ok(())
+    }
+
+    fn lock(&self, ino: InodeNum) -> Result<(), VfsError> {
+        let ino = to_ino(ino)?;
+        let identifier = self.transact(|txn| txn.policy(ino))?;
+        match self.volume.lock().keys.remove(&identifier) {
+            Some(_) => Ok(()),
+            None => Err(SplaxFsError::NoKey.into()),
+        }
+    }
+
+    fn encryption(&self, ino: InodeNum) -> Result<VfsEncryption, VfsError> {
+        let ino = to_ino(ino)?;
+        Ok(self.transact(|txn| {
+            let inode = txn.get_inode(ino)?;
+            Ok(match txn.context(&inode)? {
+                None => VfsEncryption::None,
+                Some(context) if txn.volume.keys.contains_key(&context.identifier) => VfsEncryption::Unlocked,
+                Some(_) => VfsEncryption::Locked,
+            })
+        })?)
     }
 }
 
 /// Global mounted filesystems
-static MOUNTED_FS: Mutex<BTreeMap<String, SplaxFs>> = Mutex::new(BTreeMap::new());
+static MOUNTED_FS: Mutex<BTreeMap<String, Arc<SplaxFs>>> = Mutex::new(BTreeMap::new());
 
 /// Formats a device with SplaxFS
 pub fn format(device: &str) -> Result<(), SplaxFsError> {
@@ -1890,119 +3260,680 @@ pub fn format_journaled(device: &str) -> Result<(), SplaxFsError> {
     SplaxFs::format_with_journal(device)
 }
 
-/// Mounts a SplaxFS filesystem
+/// Mounts a SplaxFS filesystem and attaches it to the VFS
 pub fn mount(device: &str, mount_point: &str) -> Result<(), SplaxFsError> {
-    let fs = SplaxFs::mount(device, mount_point)?;
+    if MOUNTED_FS.lock().contains_key(mount_point) {
+        return Err(SplaxFsError::Exists);
+    }
+    let fs = Arc::new(SplaxFs::mount(device, mount_point)?);
+    if let Err(e) = VFS.mount(mount_point, fs.clone(), false) {
+        crate::serial_println!("[splaxfs] VFS mount at {} failed: {:?}", mount_point, e);
+        let _ = fs.unmount();
+        return Err(match e {
+            VfsError::AlreadyExists => SplaxFsError::Exists,
+            VfsError::NoSpace => SplaxFsError::NoSpace,
+            _ => SplaxFsError::IoError,
+        });
+    }
     MOUNTED_FS.lock().insert(String::from(mount_point), fs);
     Ok(())
 }
 
 /// Syncs a mounted filesystem
 pub fn sync(mount_point: &str) -> Result<(), SplaxFsError> {
-    let mut mounts = MOUNTED_FS.lock();
-    if let Some(fs) = mounts.get_mut(mount_point) {
-        fs.sync()
-    } else {
-        Err(SplaxFsError::NotMounted)
-    }
+    let fs = MOUNTED_FS.lock().get(mount_point).cloned().ok_or(SplaxFsError::NotMounted)?;
+    fs.flush()
 }
 
-/// Unmounts a filesystem
+/// Unmounts a filesystem; a volume's snapshots must be unmounted first
 pub fn unmount(mount_point: &str) -> Result<(), SplaxFsError> {
-    // Sync before unmounting
-    let _ = sync(mount_point);
-    MOUNTED_FS.lock().remove(mount_point).ok_or(SplaxFsError::NotMounted)?;
+    let mut mounts = MOUNTED_FS.lock();
+    if mounts.get(mount_point).ok_or(SplaxFsError::NotMounted)?.has_snapshot_mounts() {
+        return Err(SplaxFsError::Busy);
+    }
+    let fs = mounts.remove(mount_point).ok_or(SplaxFsError::NotMounted)?;
+    drop(mounts);
+    // Detach from the VFS first so cached pages are written back
+    if let Err(e) = VFS.unmount(mount_point) {
+        crate::serial_println!("[splaxfs] VFS unmount of {} failed: {:?}", mount_point, e);
+    }
+    fs.unmount()?;
     crate::serial_println!("[splaxfs] Unmounted {}", mount_point);
     Ok(())
 }
 
-/// Lists directory contents at a mount point
-pub fn ls(path: &str) -> Result<Vec<(String, FileType, u64)>, SplaxFsError> {
-    let mounts = MOUNTED_FS.lock();
-    
-    // Find the mount point for this path
-    for (mount_point, fs) in mounts.iter() {
-        if path.starts_with(mount_point.as_str()) {
-            let relative_path = &path[mount_point.len()..];
-            let relative_path = if relative_path.is_empty() { "/" } else { relative_path };
-            return fs.readdir(relative_path);
+/// Live mount of a volume
+fn live_mount(mount_point: &str) -> Result<Arc<SplaxFs>, SplaxFsError> {
+    let fs = MOUNTED_FS.lock().get(mount_point).cloned().ok_or(SplaxFsError::NotMounted)?;
+    if fs.snapshot_id().is_some() {
+        return Err(SplaxFsError::NotSupported);
+    }
+    Ok(fs)
+}
+
+/// Takes a snapshot of a mounted volume, writing back cached file data
+/// first. Returns the snapshot id.
+pub fn snapshot(mount_point: &str, name: &str, writable: bool) -> Result<u32, SplaxFsError> {
+    let fs = live_mount(mount_point)?;
+    VFS.sync_mount(mount_point).map_err(|_| SplaxFsError::IoError)?;
+    fs.snapshot(name, writable)
+}
+
+/// Lists the snapshots of a mounted volume
+pub fn snapshots(mount_point: &str) -> Result<Vec<SnapshotEntry>, SplaxFsError> {
+    Ok(live_mount(mount_point)?.snapshots())
+}
+
+/// Deletes a snapshot of a mounted volume
+pub fn delete_snapshot(mount_point: &str, name: &str) -> Result<(), SplaxFsError> {
+    live_mount(mount_point)?.delete_snapshot(name)
+}
+
+/// Mounts a snapshot of a mounted volume at `path`
+pub fn mount_snapshot(mount_point: &str, name: &str, path: &str, writable: bool) -> Result<(), SplaxFsError> {
+    if MOUNTED_FS.lock().contains_key(path) {
+        return Err(SplaxFsError::Exists);
+    }
+    let fs = Arc::new(live_mount(mount_point)?.mount_snapshot(name, path, writable)?);
+    if let Err(e) = VFS.mount(path, fs.clone(), !writable) {
+        crate::serial_println!("[splaxfs] VFS mount at {} failed: {:?}", path, e);
+        let _ = fs.unmount();
+        return Err(match e {
+            VfsError::AlreadyExists => SplaxFsError::Exists,
+            VfsError::NoSpace => SplaxFsError::NoSpace,
+            _ => SplaxFsError::IoError,
+        });
+    }
+    MOUNTED_FS.lock().insert(String::from(path), fs);
+    Ok(())
+}
+
+/// Rolls a mounted volume back to a snapshot. Cached file data is written
+/// back first and dropped afterwards, so reads see the restored tree.
+pub fn rollback(mount_point: &str, name: &str) -> Result<(), SplaxFsError> {
+    let fs = live_mount(mount_point)?;
+    VFS.sync_mount(mount_point).map_err(|_| SplaxFsError::IoError)?;
+    fs.rollback(name)?;
+    VFS.invalidate_mount(mount_point).map_err(|_| SplaxFsError::IoError)
+}
+
+/// Starts a background scrub of a mounted volume
+pub fn scrub_start(mount_point: &str) -> Result<(), SplaxFsError> {
+    live_mount(mount_point)?.start_scrub()
+}
+
+/// Progress of a volume's scrub, or findings of its last one
+pub fn scrub_status(mount_point: &str) -> Result<ScrubStatus, SplaxFsError> {
+    live_mount(mount_point)?.scrub_status().ok_or(SplaxFsError::NotFound)
+}
+
+/// Stops a volume's running scrub
+pub fn scrub_cancel(mount_point: &str) -> Result<(), SplaxFsError> {
+    live_mount(mount_point)?.cancel_scrub()
+}
+
+/// Tick of the last scrub step
+static LAST_SCRUB: AtomicU64 = AtomicU64::new(0);
+
+/// Advances running scrubs by a step if [`SCRUB_INTERVAL_TICKS`] have
+/// passed. Called from the kernel main loop.
+pub fn scrub_poll() {
+    let now = get_journal_timestamp();
+    if now.saturating_sub(LAST_SCRUB.load(Ordering::Relaxed)) < SCRUB_INTERVAL_TICKS {
+        return;
+    }
+    LAST_SCRUB.store(now, Ordering::Relaxed);
+    let mounts: Vec<Arc<SplaxFs>> = MOUNTED_FS.lock().values().cloned().collect();
+    for fs in mounts {
+        if let Err(e) = fs.scrub_step() {
+            crate::serial_println!("[splaxfs] Scrub of {} failed: {:?}", fs.mount_point(), e);
+            let _ = fs.cancel_scrub();
         }
     }
-    
-    Err(SplaxFsError::NotMounted)
+}
+
+/// Checks an unmounted volume, writing fixes back if `repair` is set
+pub fn fsck(device: &str, repair: bool) -> Result<Report, SplaxFsError> {
+    if MOUNTED_FS.lock().values().any(|fs| fs.device_name() == device) {
+        return Err(SplaxFsError::Busy);
+    }
+    let disk = Disk::open(device)?;
+    let report = splaxfs_core::check(&disk, &CheckOptions { repair, time: now() })?;
+    crate::serial_println!("[splaxfs] Checked {}: {} problems{}", device, report.problems.len(),
+        if report.repaired { ", repaired" } else { "" });
+    Ok(report)
+}
+
+/// Finds the volume holding a path and the path relative to its root
+fn resolve(path: &str) -> Result<(Arc<SplaxFs>, String), SplaxFsError> {
+    let mounts = MOUNTED_FS.lock();
+    let (mount_point, fs) = mounts
+        .iter()
+        .filter(|(mount_point, _)| {
+            let mount_point = mount_point.trim_end_matches('/');
+            path.strip_prefix(mount_point)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        })
+        .max_by_key(|(mount_point, _)| mount_point.len())
+        .ok_or(SplaxFsError::NotMounted)?;
+    let relative = &path[mount_point.trim_end_matches('/').len()..];
+    let relative = if relative.is_empty() { "/" } else { relative };
+    Ok((fs.clone(), String::from(relative)))
+}
+
+/// Lists directory contents at a mount point
+pub fn ls(path: &str) -> Result<Vec<(String, FileType, u64)>, SplaxFsError> {
+    let (fs, relative) = resolve(path)?;
+    fs.list_dir(&relative)
 }
 
 /// Creates a file
 pub fn create(path: &str) -> Result<(), SplaxFsError> {
-    let mut mounts = MOUNTED_FS.lock();
-    
-    for (mount_point, fs) in mounts.iter_mut() {
-        if path.starts_with(mount_point.as_str()) {
-            let relative_path = &path[mount_point.len()..];
-            let relative_path = if relative_path.is_empty() { "/" } else { relative_path };
-            return fs.create_file(relative_path);
-        }
-    }
-    
-    Err(SplaxFsError::NotMounted)
+    let (fs, relative) = resolve(path)?;
+    fs.create_file(&relative)
 }
 
 /// Creates a directory
 pub fn mkdir(path: &str) -> Result<(), SplaxFsError> {
-    let mut mounts = MOUNTED_FS.lock();
-    
-    for (mount_point, fs) in mounts.iter_mut() {
-        if path.starts_with(mount_point.as_str()) {
-            let relative_path = &path[mount_point.len()..];
-            let relative_path = if relative_path.is_empty() { "/" } else { relative_path };
-            return fs.create_dir(relative_path);
-        }
-    }
-    
-    Err(SplaxFsError::NotMounted)
+    let (fs, relative) = resolve(path)?;
+    fs.create_dir(&relative)
 }
 
 /// Writes to a file
 pub fn write(path: &str, data: &[u8]) -> Result<(), SplaxFsError> {
-    let mut mounts = MOUNTED_FS.lock();
-    
-    for (mount_point, fs) in mounts.iter_mut() {
-        if path.starts_with(mount_point.as_str()) {
-            let relative_path = &path[mount_point.len()..];
-            let relative_path = if relative_path.is_empty() { "/" } else { relative_path };
-            return fs.write_file(relative_path, data);
-        }
-    }
-    
-    Err(SplaxFsError::NotMounted)
+    let (fs, relative) = resolve(path)?;
+    fs.write_file(&relative, data)
 }
 
 /// Reads a file
 pub fn read(path: &str) -> Result<Vec<u8>, SplaxFsError> {
-    let mounts = MOUNTED_FS.lock();
-    
-    for (mount_point, fs) in mounts.iter() {
-        if path.starts_with(mount_point.as_str()) {
-            let relative_path = &path[mount_point.len()..];
-            let relative_path = if relative_path.is_empty() { "/" } else { relative_path };
-            return fs.read_file(relative_path);
-        }
-    }
-    
-    Err(SplaxFsError::NotMounted)
+    let (fs, relative) = resolve(path)?;
+    fs.read_file(&relative)
 }
 
 /// Gets file info
 pub fn stat(path: &str) -> Result<(FileType, u64), SplaxFsError> {
-    let mounts = MOUNTED_FS.lock();
-    
-    for (mount_point, fs) in mounts.iter() {
-        if path.starts_with(mount_point.as_str()) {
-            let relative_path = &path[mount_point.len()..];
-            let relative_path = if relative_path.is_empty() { "/" } else { relative_path };
-            return fs.stat(relative_path);
+    let (fs, relative) = resolve(path)?;
+    fs.stat(&relative)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::ramdisk::RamDisk;
+
+    fn ram_disk(name: &str, blocks: usize) {
+        let disk = RamDisk::new(name, vec![0u8; blocks * BLOCK_SIZE]);
+        let _ = block::unregister_device(name);
+        block::register_device(Box::new(disk)).unwrap();
+    }
+
+    #[test]
+    fn test_layout_sizes() {
+        assert_eq!(core::mem::size_of::<Superblock>(), 512);
+        assert_eq!(core::mem::size_of::<DiskInode>(), INODE_SIZE);
+        assert_eq!(DIRENTS_PER_BLOCK, 15);
+    }
+
+    #[test]
+    fn test_files_and_directories() {
+        ram_disk("sfs-test0", 4096);
+        SplaxFs::format_with_journal("sfs-test0").unwrap();
+        let fs = SplaxFs::mount("sfs-test0", "/test").unwrap();
+        let root = fs.root_ino();
+
+        let dir = fs.create(root, "docs", VfsFileType::Directory).unwrap();
+        let file = fs.create(dir, "a.txt", VfsFileType::Regular).unwrap();
+        assert_eq!(fs.create(dir, "a.txt", VfsFileType::Regular), Err(VfsError::AlreadyExists));
+        assert_eq!(fs.lookup(root, "docs"), Ok(dir));
+        assert_eq!(fs.getattr(root).unwrap().nlink, 3);
+
+        // Direct, indirect and (sparse) double indirect blocks
+        let data: Vec<u8> = (0..20 * BLOCK_SIZE).map(|i| (i % 251) as u8).collect();
+        fs.write(file, 0, &data).unwrap();
+        let far = (DIRECT_BLOCKS + PTRS_PER_BLOCK + 3) as u64 * BLOCK_SIZE as u64;
+        fs.write(file, far, b"tail").unwrap();
+        assert_eq!(fs.read(file, 0, data.len()).unwrap(), data);
+        assert_eq!(fs.read(file, far, 16).unwrap(), b"tail");
+        assert!(fs.read(file, far - 8, 8).unwrap().iter().all(|&b| b == 0));
+        assert_eq!(fs.getattr(file).unwrap().size, far + 4);
+
+        // Directories grow past one block
+        for i in 0..40 {
+            fs.create(dir, &alloc::format!("f{}", i), VfsFileType::Regular).unwrap();
         }
+        assert_eq!(fs.readdir(dir).unwrap().len(), 2 + 1 + 40);
+        assert_eq!(fs.unlink(root, "docs"), Err(VfsError::NotEmpty));
+
+        // Freeing everything returns every block and inode
+        let before = fs.statfs().unwrap();
+        fs.truncate(file, 0).unwrap();
+        for i in 0..40 {
+            fs.unlink(dir, &alloc::format!("f{}", i)).unwrap();
+        }
+        fs.unlink(dir, "a.txt").unwrap();
+        fs.unlink(root, "docs").unwrap();
+        let after = fs.statfs().unwrap();
+        assert!(after.bfree > before.bfree);
+        assert_eq!(after.ffree, after.files - 2);
+        assert_eq!(fs.getattr(root).unwrap().nlink, 2);
+        fs.unmount().unwrap();
+
+        // Everything went through to disk
+        let fs = SplaxFs::mount("sfs-test0", "/test").unwrap();
+        assert_eq!(fs.statfs().unwrap().bfree, after.bfree);
+        assert_eq!(fs.readdir(root).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_rename_and_links() {
+        ram_disk("sfs-test1", 1024);
+        SplaxFs::format("sfs-test1").unwrap();
+        let fs = SplaxFs::mount("sfs-test1", "/test").unwrap();
+        let root = fs.root_ino();
+
+        let a = fs.create(root, "a", VfsFileType::Directory).unwrap();
+        let b = fs.create(a, "b", VfsFileType::Directory).unwrap();
+        let file = fs.create(root, "file", VfsFileType::Regular).unwrap();
+        fs.write(file, 0, b"hello").unwrap();
+
+        // A directory cannot move into its own subtree
+        assert_eq!(fs.rename(root, "a", b, "a"), Err(VfsError::InvalidArgument));
+        fs.rename(a, "b", root, "b").unwrap();
+        assert_eq!(fs.lookup(b, ".."), Ok(root));
+        assert_eq!(fs.getattr(a).unwrap().nlink, 2);
+        assert_eq!(fs.getattr(root).unwrap().nlink, 4);
+
+        // Replacing a file drops the old one
+        let other = fs.create(a, "other", VfsFileType::Regular).unwrap();
+        fs.rename(root, "file", a, "other").unwrap();
+        assert_eq!(fs.lookup(a, "other"), Ok(file));
+        assert_eq!(fs.getattr(other).err(), Some(VfsError::NotFound));
+        assert_eq!(fs.rename(a, "other", root, "b"), Err(VfsError::IsADirectory));
+
+        fs.link(file, root, "hard").unwrap();
+        assert_eq!(fs.getattr(file).unwrap().nlink, 2);
+        assert_eq!(fs.link(a, root, "dirlink"), Err(VfsError::NotSupported));
+        fs.unlink(a, "other").unwrap();
+        assert_eq!(fs.read(file, 0, 16).unwrap(), b"hello");
+
+        let link = fs.symlink(root, "sym", "/test/hard").unwrap();
+        assert_eq!(fs.readlink(link).unwrap(), "/test/hard");
+        assert_eq!(fs.getattr(link).unwrap().file_type, VfsFileType::Symlink);
+    }
+
+    #[test]
+    fn test_fsck() {
+        ram_disk("sfs-test2", 1024);
+        SplaxFs::format_with_journal("sfs-test2").unwrap();
+        let fs = SplaxFs::mount("sfs-test2", "/test").unwrap();
+        let root = fs.root_ino();
+        let dir = fs.create(root, "dir", VfsFileType::Directory).unwrap();
+        let file = fs.create(dir, "file", VfsFileType::Regular).unwrap();
+        let data = vec![7u8; 20 * BLOCK_SIZE];
+        fs.write(file, 0, &data).unwrap();
+        fs.link(file, root, "hard").unwrap();
+        fs.symlink(root, "sym", "dir/file").unwrap();
+        fs.rename(dir, "file", root, "moved").unwrap();
+        fs.unlink(root, "hard").unwrap();
+        fs.unmount().unwrap();
+
+        let report = fsck("sfs-test2", false).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+        assert!(report.was_clean);
+        assert_eq!((report.files, report.directories, report.symlinks), (1, 2, 1));
+
+        // Lose the only entry of the file behind the filesystem's back
+        let fs = SplaxFs::mount("sfs-test2", "/test").unwrap();
+        fs.transact(|txn| {
+            let root_dir = txn.get_inode(ROOT_INODE)?;
+            let (block_num, offset, _) = txn.find_entry(&root_dir, "moved")?.ok_or(SplaxFsError::NotFound)?;
+            txn.remove_entry(ROOT_INODE, block_num, offset)
+        })
+        .unwrap();
+        fs.unmount().unwrap();
+
+        let report = fsck("sfs-test2", true).unwrap();
+        assert!(report.repaired);
+        assert!(report.problems.contains(&Problem::Unattached { ino: file as u32 }));
+        assert!(fsck("sfs-test2", false).unwrap().is_clean());
+
+        let fs = SplaxFs::mount("sfs-test2", "/test").unwrap();
+        let found = alloc::format!("/lost+found/#{}", file);
+        assert_eq!(fs.read_file(&found).unwrap(), data);
+        assert_eq!(fs.getattr(file).unwrap().nlink, 1);
+    }
+
+    #[test]
+    fn test_snapshots() {
+        ram_disk("sfs-test3", 2048);
+        SplaxFs::format_with_journal("sfs-test3").unwrap();
+        let fs = SplaxFs::mount("sfs-test3", "/test").unwrap();
+        let root = fs.root_ino();
+        let dir = fs.create(root, "etc", VfsFileType::Directory).unwrap();
+        let file = fs.create(dir, "conf", VfsFileType::Regular).unwrap();
+        let data: Vec<u8> = (0..(DIRECT_BLOCKS + 20) * BLOCK_SIZE).map(|i| (i % 251) as u8).collect();
+        fs.write(file, 0, &data).unwrap();
+        let free = fs.statfs().unwrap().bfree;
+
+        // A snapshot costs only its copies of the inode map and bitmap
+        fs.snapshot("before", false).unwrap();
+        assert_eq!(fs.snapshot("before", false), Err(SplaxFsError::Exists));
+        let copies = 1 + fs.superblock().snapshot_root_ptrs() as u64;
+        assert_eq!(fs.statfs().unwrap().bfree, free - copies);
+
+        // Later changes copy what they touch and stay out of the snapshot
+        fs.write(file, 5, b"changed").unwrap();
+        fs.truncate(file, (DIRECT_BLOCKS as u64 + 2) * BLOCK_SIZE as u64).unwrap();
+        fs.create(root, "new", VfsFileType::Regular).unwrap();
+        fs.unlink(dir, "conf").unwrap();
+        let snap = fs.mount_snapshot("before", "/snap", false).unwrap();
+        assert_eq!(snap.read(file, 0, data.len()).unwrap(), data);
+        assert_eq!(snap.lookup(root, "new"), Err(VfsError::NotFound));
+        assert_eq!(snap.create(root, "x", VfsFileType::Regular), Err(VfsError::ReadOnlyFs));
+        assert_eq!(fs.mount_snapshot("before", "/snap2", false).err(), Some(SplaxFsError::Busy));
+        assert_eq!(fs.delete_snapshot("before"), Err(SplaxFsError::Busy));
+        assert_eq!(fs.unmount(), Err(SplaxFsError::Busy));
+        snap.unmount().unwrap();
+        assert_eq!(fs.mount_snapshot("before", "/snap", true).err(), Some(SplaxFsError::ReadOnly));
+
+        // A writable snapshot diverges from the live tree
+        fs.snapshot("branch", true).unwrap();
+        let branch = fs.mount_snapshot("branch", "/branch", true).unwrap();
+        let new = branch.lookup(root, "new").unwrap();
+        branch.write(new, 0, b"branch only").unwrap();
+        assert_eq!(fs.getattr(new).unwrap().size, 0);
+        branch.unmount().unwrap();
+        fs.unmount().unwrap();
+
+        let report = fsck("sfs-test3", false).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+        assert_eq!(report.snapshots, 2);
+
+        // Rolling back restores the tree; deleting the snapshots gives back
+        // every block the changes took
+        let fs = SplaxFs::mount("sfs-test3", "/test").unwrap();
+        fs.rollback("before").unwrap();
+        assert_eq!(fs.read(file, 0, data.len()).unwrap(), data);
+        assert_eq!(fs.lookup(root, "new"), Err(VfsError::NotFound));
+        fs.delete_snapshot("branch").unwrap();
+        fs.delete_snapshot("before").unwrap();
+        assert!(fs.snapshots().is_empty());
+        assert_eq!(fs.statfs().unwrap().bfree, free);
+        fs.unmount().unwrap();
+
+        let report = fsck("sfs-test3", false).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+        assert_eq!(report.snapshots, 0);
+    }
+    #[test]
+    fn test_xattrs() {
+        ram_disk("sfs-test4", 1024);
+        SplaxFs::format_with_journal("sfs-test4").unwrap();
+        let fs = SplaxFs::mount("sfs-test4", "/test").unwrap();
+        let root = fs.root_ino();
+        let file = fs.create(root, "file", VfsFileType::Regular).unwrap();
+        assert_eq!(fs.getxattr(file, "user.a"), Err(VfsError::NoAttribute));
+        assert_eq!(fs.setxattr(file, "os2.a", b""), Err(VfsError::NotSupported));
+
+        // Small attributes stay in the inode
+        fs.setxattr(file, "user.a", b"1").unwrap();
+        fs.setxattr(file, "user.a", b"2").unwrap();
+        assert_eq!(fs.getxattr(file, "user.a").unwrap(), b"2");
+        assert_eq!(fs.getattr(file).unwrap().blocks, 0);
+
+        // Large ones spill into the overflow block
+        let free = fs.statfs().unwrap().bfree;
+        let big = vec![9u8; 1000];
+        fs.setxattr(file, "trusted.big", &big).unwrap();
+        assert_eq!(fs.getattr(file).unwrap().blocks, SECTORS_PER_BLOCK as u64);
+        assert_eq!(fs.statfs().unwrap().bfree, free - 1);
+        assert_eq!(fs.listxattr(file).unwrap(), ["user.a", "trusted.big"]);
+
+        // A snapshot shares the block until the live tree changes it
+        fs.snapshot("snap", false).unwrap();
+        fs.setxattr(file, "trusted.big", b"small").unwrap();
+        fs.removexattr(file, "user.a").unwrap();
+        assert_eq!(fs.removexattr(file, "user.a"), Err(VfsError::NoAttribute));
+        assert_eq!(fs.getxattr(file, "trusted.big").unwrap(), b"small");
+        let snap = fs.mount_snapshot("snap", "/snap", false).unwrap();
+        assert_eq!(snap.getxattr(file, "trusted.big").unwrap(), big);
+        assert_eq!(snap.setxattr(file, "user.b", b""), Err(VfsError::ReadOnlyFs));
+        snap.unmount().unwrap();
+        fs.unmount().unwrap();
+        let report = fsck("sfs-test4", false).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+
+        // Unlinking gives the block back once the snapshot is gone
+        let fs = SplaxFs::mount("sfs-test4", "/test").unwrap();
+        fs.setxattr(file, "user.pad", &big).unwrap();
+        fs.delete_snapshot("snap").unwrap();
+        let free = fs.statfs().unwrap().bfree;
+        fs.unlink(root, "file").unwrap();
+        assert_eq!(fs.statfs().unwrap().bfree, free + 1);
+        fs.unmount().unwrap();
+        assert!(fsck("sfs-test4", false).unwrap().is_clean());
+    }
+
+    /// Flips a bit of a block behind the filesystem's back
+    fn rot(device: &str, block_num: u32) {
+        let disk = Disk::open(device).unwrap();
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        disk.read(block_num, &mut buf).unwrap();
+        buf[500] ^= 1;
+        disk.write(block_num, &buf).unwrap();
+    }
+
+    #[test]
+    fn test_checksums() {
+        ram_disk("sfs-test5", 1024);
+        SplaxFs::format_with_journal("sfs-test5").unwrap();
+        let fs = SplaxFs::mount("sfs-test5", "/test").unwrap();
+        let root = fs.root_ino();
+        let dir = fs.create(root, "dir", VfsFileType::Directory).unwrap();
+        let file = fs.create(dir, "file", VfsFileType::Regular).unwrap();
+        let data = vec![7u8; 3 * BLOCK_SIZE];
+        fs.write(file, 0, &data).unwrap();
+        let status = fs.scrub().unwrap();
+        assert!(!status.running && status.blocks_checked > 0);
+        assert!(status.errors.is_empty(), "{:?}", status.errors);
+
+        // Reads and the scrub catch a rotten block; rewriting it heals it
+        let bmap = |ino: InodeNum| fs.transact(|txn| txn.bmap(&txn.get_inode(ino as u32)?, 1)).unwrap();
+        let block_num = bmap(file);
+        rot("sfs-test5", block_num);
+        assert_eq!(fs.read(file, 0, data.len()), Err(VfsError::IoError));
+        assert_eq!(fs.read(file, 0, BLOCK_SIZE).unwrap(), &data[..BLOCK_SIZE]);
+        let found = ScrubError { snapshot: 0, ino: file as u32, block: block_num, path: Some(String::from("/dir/file")) };
+        assert_eq!(fs.scrub().unwrap().errors, [found]);
+        fs.write(file, BLOCK_SIZE as u64, &data[..BLOCK_SIZE]).unwrap();
+        assert_eq!(fs.read(file, 0, data.len()).unwrap(), data);
+
+        // Blocks only a snapshot still uses are scrubbed too
+        let id = fs.snapshot("snap", false).unwrap();
+        fs.write(file, BLOCK_SIZE as u64, &[1u8; BLOCK_SIZE]).unwrap();
+        rot("sfs-test5", block_num);
+        assert_ne!(bmap(file), block_num);
+        let found = ScrubError { snapshot: id, ino: file as u32, block: block_num, path: None };
+        assert_eq!(fs.scrub().unwrap().errors, [found]);
+        fs.delete_snapshot("snap").unwrap();
+
+        // fsck reports a rotten block and accepts its content
+        let block_num = bmap(file);
+        rot("sfs-test5", block_num);
+        fs.unmount().unwrap();
+        let report = fsck("sfs-test5", true).unwrap();
+        assert_eq!(report.problems, [Problem::Checksum { block: block_num }]);
+        assert!(fsck("sfs-test5", false).unwrap().is_clean());
+
+        // A damaged superblock keeps the volume from mounting
+        rot("sfs-test5", 0);
+        assert!(matches!(SplaxFs::mount("sfs-test5", "/test"), Err(SplaxFsError::ChecksumMismatch)));
+    }
+
+    #[test]
+    fn test_compression() {
+        ram_disk("sfs-test6", 1024);
+        SplaxFs::format_with_journal("sfs-test6").unwrap();
+        let fs = SplaxFs::mount("sfs-test6", "/test").unwrap();
+        let root = fs.root_ino();
+        let logs = fs.create(root, "logs", VfsFileType::Directory).unwrap();
+        assert_eq!(fs.setxattr(logs, COMPRESSION_XATTR, b"zip"), Err(VfsError::InvalidArgument));
+        fs.setxattr(logs, COMPRESSION_XATTR, b"lz4").unwrap();
+        assert_eq!(fs.listxattr(logs).unwrap(), [COMPRESSION_XATTR]);
+
+        // New files inherit the algorithm; 60 blocks of log lines reach
+        // into the indirect block and compress well
+        let file = fs.create(logs, "net.log", VfsFileType::Regular).unwrap();
+        assert_eq!(fs.getxattr(file, COMPRESSION_XATTR).unwrap(), b"lz4");
+        let mut text = Vec::new();
+        for i in 0.. {
+            let line = alloc::format!("[{:08}] net: connection {} from 10.0.{}.{} accepted\n", i * 37, i, i % 7, i % 251);
+            if text.len() + line.len() > 60 * BLOCK_SIZE {
+                break;
+            }
+            text.extend_from_slice(line.as_bytes());
+        }
+        let free = fs.statfs().unwrap().bfree;
+        for chunk in text.chunks(1000) {
+            fs.write(file, fs.getattr(file).unwrap().size, chunk).unwrap();
+        }
+        assert_eq!(fs.read(file, 0, text.len()).unwrap(), text);
+        assert_eq!(fs.read(file, 50_001, 9000).unwrap(), &text[50_001..59_001]);
+        let attr = fs.getattr(file).unwrap();
+        let stats = fs.statfs().unwrap();
+        assert_eq!(attr.size, text.len() as u64);
+        assert!(attr.blocks * 512 * 2 < attr.size, "{} sectors", attr.blocks);
+        assert!(stats.bsaved > 30);
+        assert_eq!(free - stats.bfree, attr.blocks / SECTORS_PER_BLOCK as u64);
+        assert_eq!(fs.dax_page(file, 0), Err(VfsError::NotSupported));
+
+        // Overwrites rewrite whole clusters, also under a snapshot
+        fs.snapshot("snap", false).unwrap();
+        fs.write(file, 30_000, &[b'x'; 5000]).unwrap();
+        text[30_000..35_000].fill(b'x');
+        assert_eq!(fs.read(file, 0, text.len()).unwrap(), text);
+        let snap = fs.mount_snapshot("snap", "/snap", false).unwrap();
+        assert_ne!(snap.read(file, 30_000, 10).unwrap(), b"xxxxxxxxxx");
+        snap.unmount().unwrap();
+        fs.delete_snapshot("snap").unwrap();
+
+        // Truncating keeps the head of the cluster holding the new end
+        fs.truncate(file, 20_000).unwrap();
+        fs.truncate(file, 30_000).unwrap();
+        let mut head = text[..20_000].to_vec();
+        head.resize(30_000, 0);
+        assert_eq!(fs.read(file, 0, 40_000).unwrap(), head);
+
+        // Without an algorithm, compressed clusters go back to plain blocks
+        // as they are written
+        fs.removexattr(file, COMPRESSION_XATTR).unwrap();
+        assert_eq!(fs.getxattr(file, COMPRESSION_XATTR), Err(VfsError::NoAttribute));
+        fs.write(file, 0, &text[..20_000]).unwrap();
+        assert_eq!(fs.read(file, 0, 20_000).unwrap(), &text[..20_000]);
+        fs.unmount().unwrap();
+        let report = fsck("sfs-test6", false).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+
+        // Freeing the file gives everything back
+        let fs = SplaxFs::mount("sfs-test6", "/test").unwrap();
+        fs.unlink(logs, "net.log").unwrap();
+        let stats = fs.statfs().unwrap();
+        assert_eq!((stats.bfree, stats.bsaved), (free, 0));
+        fs.unmount().unwrap();
+        assert!(fsck("sfs-test6", false).unwrap().is_clean());
+    }
+
+    /// Whether any block of a device holds `needle`
+    fn on_disk(device: &str, needle: &[u8]) -> bool {
+        let disk = Disk::open(device).unwrap();
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        (0..disk.total_blocks).any(|block_num| {
+            disk.read(block_num, &mut buf).unwrap();
+            buf.windows(needle.len()).any(|window| window == needle)
+        })
+    }
+
+    #[test]
+    fn test_encryption() {
+        ram_disk("sfs-test7", 1024);
+        SplaxFs::format_with_journal("sfs-test7").unwrap();
+        let fs = SplaxFs::mount("sfs-test7", "/test").unwrap();
+        let root = fs.root_ino();
+        let key = [0x5Au8; 32];
+        let vault = fs.create(root, "vault", VfsFileType::Directory).unwrap();
+        assert_eq!(fs.set_encryption(root, &key), Err(VfsError::InvalidArgument));
+        assert_eq!(fs.set_encryption(vault, &key[..16]), Err(VfsError::InvalidArgument));
+        fs.set_encryption(vault, &key).unwrap();
+        fs.set_encryption(vault, &key).unwrap();
+        assert_eq!(fs.set_encryption(vault, &[1; 32]), Err(VfsError::AlreadyExists));
+        assert_eq!(fs.encryption(vault).unwrap(), VfsEncryption::Unlocked);
+        assert_eq!(fs.encryption(root).unwrap(), VfsEncryption::None);
+        assert_eq!(fs.listxattr(vault).unwrap(), [ENCRYPTION_XATTR]);
+        assert_eq!(fs.setxattr(vault, ENCRYPTION_XATTR, b"x"), Err(VfsError::InvalidArgument));
+
+        // Names and data go to the disk encrypted; compressed data is
+        // compressed first
+        let inner = fs.create(vault, "inner", VfsFileType::Directory).unwrap();
+        let file = fs.create(inner, "secret-plans.txt", VfsFileType::Regular).unwrap();
+        let log = fs.create(vault, "log", VfsFileType::Regular).unwrap();
+        fs.setxattr(log, COMPRESSION_XATTR, b"lz4").unwrap();
+        let text: Vec<u8> = b"attack at dawn; ".iter().copied().cycle().take(3 * BLOCK_SIZE + 100).collect();
+        fs.write(file, 0, &text).unwrap();
+        fs.write(log, 0, &text).unwrap();
+        let link = fs.symlink(vault, "link", "inner/secret-plans.txt").unwrap();
+        assert_eq!(fs.read(file, 0, text.len()).unwrap(), text);
+        assert_eq!(fs.read(log, 0, text.len()).unwrap(), text);
+        assert!(fs.getattr(log).unwrap().blocks < fs.getattr(file).unwrap().blocks);
+        fs.truncate(file, 5000).unwrap();
+        fs.truncate(file, 6000).unwrap();
+        let mut head = text[..5000].to_vec();
+        head.resize(6000, 0);
+        assert_eq!(fs.read(file, 0, 8000).unwrap(), head);
+        assert_eq!(fs.lookup(inner, "secret-plans.txt").unwrap(), file);
+        assert!(fs.readdir(inner).unwrap().iter().any(|entry| entry.name == "secret-plans.txt"));
+        assert!(!on_disk("sfs-test7", b"attack at dawn"));
+        assert!(!on_disk("sfs-test7", b"secret-plans"));
+        assert!(!on_disk("sfs-test7", b"inner/"));
+        assert_eq!(fs.dax_page(file, 0), Err(VfsError::NotSupported));
+
+        // Locking any inode under the policy locks all of it: data is
+        // refused and names show as ciphertext, which still looks up
+        fs.lock(inner).unwrap();
+        assert_eq!(fs.encryption(vault).unwrap(), VfsEncryption::Locked);
+        assert_eq!(fs.lock(vault), Err(VfsError::PermissionDenied));
+        assert_eq!(fs.read(file, 0, 10), Err(VfsError::PermissionDenied));
+        assert_eq!(fs.readlink(link), Err(VfsError::PermissionDenied));
+        assert_eq!(fs.lookup(inner, "secret-plans.txt"), Err(VfsError::NotFound));
+        let entries = fs.readdir(inner).unwrap();
+        let shown = &entries.iter().find(|entry| entry.ino == file).unwrap().name;
+        assert_ne!(shown, "secret-plans.txt");
+        assert_eq!(fs.lookup(inner, shown).unwrap(), file);
+        assert_eq!(fs.create(inner, "new", VfsFileType::Regular), Err(VfsError::PermissionDenied));
+
+        // Nothing joins the policy without being encrypted under it
+        let plain = fs.create(root, "plain", VfsFileType::Regular).unwrap();
+        assert_eq!(fs.rename(root, "plain", vault, "plain"), Err(VfsError::CrossDevice));
+        assert_eq!(fs.link(plain, vault, "plain"), Err(VfsError::CrossDevice));
+
+        // Unlinking needs no key
+        fs.unlink(inner, shown).unwrap();
+        assert_eq!(fs.unlock(vault, &[1; 32]), Err(VfsError::PermissionDenied));
+        fs.unlock(inner, &key).unwrap();
+        assert_eq!(fs.read(log, 0, text.len()).unwrap(), text);
+        assert_eq!(fs.readdir(inner).unwrap().len(), 2);
+        fs.rename(vault, "log", inner, "log.1").unwrap();
+        fs.unmount().unwrap();
+        let report = fsck("sfs-test7", false).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+
+        // The key does not outlive the mount
+        let fs = SplaxFs::mount("sfs-test7", "/test").unwrap();
+        assert_eq!(fs.encryption(vault).unwrap(), VfsEncryption::Locked);
+        fs.unlock(vault, &key).unwrap();
+        assert_eq!(fs.lookup(vault, "link").unwrap(), link);
+        assert_eq!(fs.readlink(link).unwrap(), "inner/secret-plans.txt");
+        assert_eq!(fs.read(fs.lookup(inner, "log.1").unwrap(), 0, 16).unwrap(), b"attack at dawn; ");
+        fs.unmount().unwrap();
     }
-    
-    Err(SplaxFsError::NotMounted)
 }
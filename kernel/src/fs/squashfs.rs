@@ -0,0 +1,1378 @@
+//! # SquashFS Filesystem
+//!
+//! Read-only SquashFS 4.0 implementation for Splax OS, so that package
+//! payloads and container layers can be mounted as they are shipped
+//! instead of being unpacked. Images mount from any block device, or from
+//! a file, which is attached as a read-only loop device for the mount.
+//!
+//! ## Features
+//!
+//! - gzip (zlib), LZ4 and zstd compressed data and metadata blocks
+//! - Basic and extended inodes of every type; sparse files
+//! - Fragment blocks holding the tails of several files
+//! - Extended attributes in the `user.`, `trusted.` and `security.`
+//!   namespaces, including values stored once for several files
+//! - Export table: inodes found by number without a directory lookup
+//!
+//! ## Design
+//!
+//! The image is a superblock and a series of tables. Inodes and
+//! directories live in metadata blocks of up to 8 KiB, compressed one by
+//! one; a file's data is a run of blocks of the image's block size, and
+//! its last partial block may live in a fragment block instead. Lookup
+//! tables (fragments, export, xattr ids) are metadata blocks listed by an
+//! index of their positions.
+//!
+//! The inode number of a file is the one the image records. Files are
+//! found through the inode reference of their directory entry, or through
+//! the export table when a number is asked for that no lookup produced.
+//! Recently used metadata and fragment blocks are cached; file data goes
+//! through the page cache above.
+//!
+//! ## Limitations
+//!
+//! - Read only
+//! - No lzma, lzo or xz compression
+//! - Owners are not reported, as the VFS has no attributes for them
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::{Mutex, RwLock};
+
+use crate::block::{BlockDevice, SECTOR_SIZE};
+use crate::fs::vfs::{
+    Filesystem, InodeNum, VfsAttr, VfsDirEntry, VfsError, VfsFileType, VfsPermissions, VfsStatFs, VFS,
+};
+use crate::fs::xattr::Namespace;
+
+/// Superblock magic, "hsqs"
+const SQUASHFS_MAGIC: u32 = 0x7371_7368;
+
+/// Superblock size
+const SUPERBLOCK_SIZE: usize = 96;
+
+/// Supported format version
+const VERSION_MAJOR: u16 = 4;
+const VERSION_MINOR: u16 = 0;
+
+/// Superblock fields
+const SB_INODE_COUNT: usize = 4;
+const SB_MKFS_TIME: usize = 8;
+const SB_BLOCK_SIZE: usize = 12;
+const SB_FRAGMENT_COUNT: usize = 16;
+const SB_COMPRESSION: usize = 20;
+const SB_BLOCK_LOG: usize = 22;
+const SB_VERSION_MAJOR: usize = 28;
+const SB_VERSION_MINOR: usize = 30;
+const SB_ROOT_INODE: usize = 32;
+const SB_BYTES_USED: usize = 40;
+const SB_XATTR_ID_TABLE: usize = 56;
+const SB_INODE_TABLE: usize = 64;
+const SB_DIRECTORY_TABLE: usize = 72;
+const SB_FRAGMENT_TABLE: usize = 80;
+const SB_EXPORT_TABLE: usize = 88;
+
+/// Table position of a table the image does not have
+const NO_TABLE: u64 = u64::MAX;
+
+/// Block sizes allowed by the format
+const MIN_BLOCK_SIZE: u32 = 4096;
+const MAX_BLOCK_SIZE: u32 = 1024 * 1024;
+
+/// Largest uncompressed metadata block
+const METADATA_SIZE: usize = 8192;
+
+/// Metadata block header: stored without compression
+const METADATA_UNCOMPRESSED: u16 = 0x8000;
+
+/// Data block size: stored without compression
+const DATA_UNCOMPRESSED: u32 = 1 << 24;
+
+/// Inode types
+const INODE_DIR: u16 = 1;
+const INODE_FILE: u16 = 2;
+const INODE_SYMLINK: u16 = 3;
+const INODE_BLOCK_DEV: u16 = 4;
+const INODE_CHAR_DEV: u16 = 5;
+const INODE_FIFO: u16 = 6;
+const INODE_SOCKET: u16 = 7;
+/// Extended inode types are the basic ones plus this
+const INODE_EXTENDED: u16 = 7;
+
+/// Inode header size
+const INODE_HEADER_SIZE: usize = 16;
+
+/// No fragment, no xattrs
+const NONE: u32 = u32::MAX;
+
+/// Directory header and entry sizes, the entry without its name
+const DIR_HEADER_SIZE: usize = 12;
+const DIR_ENTRY_SIZE: usize = 8;
+
+/// Directory sizes count three bytes more than the listing
+const DIR_SIZE_BIAS: u32 = 3;
+
+/// Longest name
+const SQUASHFS_NAME_LEN: u32 = 256;
+
+/// Lookup table entry sizes
+const FRAGMENT_ENTRY_SIZE: usize = 16;
+const EXPORT_ENTRY_SIZE: usize = 8;
+const XATTR_ID_ENTRY_SIZE: usize = 16;
+
+/// Xattr key type: namespace bits, and the value is a reference to a
+/// value stored elsewhere
+const XATTR_PREFIX_MASK: u16 = 0xFF;
+const XATTR_VALUE_OOL: u16 = 0x100;
+
+/// Xattr namespaces by key type
+const XATTR_PREFIXES: [(u16, Namespace); 3] = [(0, Namespace::User), (1, Namespace::Trusted), (2, Namespace::Security)];
+
+/// Metadata blocks kept decompressed
+const METADATA_CACHE: usize = 64;
+
+/// Fragment blocks kept decompressed
+const FRAGMENT_CACHE: usize = 4;
+
+fn le16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn le32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+fn le64(data: &[u8], offset: usize) -> u64 {
+    le32(data, offset) as u64 | (le32(data, offset + 4) as u64) << 32
+}
+
+/// Compressor of data and metadata blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Compression {
+    Gzip,
+    Lz4,
+    Zstd,
+}
+
+impl Compression {
+    /// Compressor with an on-disk id, or the name of an unsupported one
+    fn from_id(id: u16) -> Result<Self, &'static str> {
+        match id {
+            1 => Ok(Self::Gzip),
+            2 => Err("lzma"),
+            3 => Err("lzo"),
+            4 => Err("xz"),
+            5 => Ok(Self::Lz4),
+            6 => Ok(Self::Zstd),
+            _ => Err("unknown"),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Lz4 => "lz4",
+            Self::Zstd => "zstd",
+        }
+    }
+
+    /// Decompresses a block of at most `limit` bytes
+    fn decompress(self, data: &[u8], limit: usize) -> Result<Vec<u8>, VfsError> {
+        match self {
+            Self::Gzip => splax_compress::zlib::decompress(data, limit),
+            Self::Lz4 => splax_compress::lz4::decompress_at_most(data, limit),
+            Self::Zstd => splax_compress::zstd::decompress(data, limit),
+        }
+        .map_err(|_| VfsError::IoError)
+    }
+}
+
+/// A position in a metadata table: the device offset of a metadata block
+/// and an offset into its uncompressed data.
+#[derive(Debug, Clone, Copy)]
+struct Cursor {
+    block: u64,
+    offset: usize,
+}
+
+impl Cursor {
+    /// The position an inode or xattr reference points to in a table
+    fn at(table: u64, reference: u64) -> Self {
+        Self { block: table + (reference >> 16), offset: (reference & 0xFFFF) as usize }
+    }
+}
+
+/// What a node holds.
+#[derive(Debug, Clone)]
+enum Contents {
+    /// Directory listing: metadata block relative to the directory
+    /// table, offset into it, and length
+    Directory { block: u32, offset: u16, len: u32 },
+    /// File data: device offset and size word of each full block, and the
+    /// fragment holding the tail with the tail's offset in it
+    File { blocks: Vec<(u64, u32)>, fragment: Option<(u32, u32)> },
+    /// Symbolic link target
+    Symlink(String),
+    /// Device files, FIFOs and sockets
+    Special,
+}
+
+/// A file, directory or other inode.
+#[derive(Debug, Clone)]
+struct Node {
+    file_type: VfsFileType,
+    /// Permission bits
+    mode: u16,
+    nlink: u32,
+    mtime: u32,
+    /// Size in bytes
+    size: u64,
+    /// Bytes the node takes in the image
+    stored: u64,
+    /// Index into the xattr id table
+    xattr: Option<u32>,
+    contents: Contents,
+}
+
+/// A small cache of decompressed blocks by device offset, dropping the
+/// oldest.
+struct BlockCache {
+    blocks: VecDeque<(u64, Arc<Vec<u8>>)>,
+    capacity: usize,
+}
+
+impl BlockCache {
+    fn new(capacity: usize) -> Self {
+        Self { blocks: VecDeque::new(), capacity }
+    }
+
+    fn get(&self, at: u64) -> Option<Arc<Vec<u8>>> {
+        self.blocks.iter().find(|(offset, _)| *offset == at).map(|(_, data)| data.clone())
+    }
+
+    fn insert(&mut self, at: u64, data: Arc<Vec<u8>>) {
+        if self.blocks.len() == self.capacity {
+            self.blocks.pop_front();
+        }
+        self.blocks.push_back((at, data));
+    }
+}
+
+/// A mounted image.
+struct Volume {
+    device: Arc<dyn BlockDevice + Send + Sync>,
+    compression: Compression,
+    block_size: u32,
+    inode_count: u32,
+    fragment_count: u32,
+    bytes_used: u64,
+    inode_table: u64,
+    directory_table: u64,
+    fragment_table: u64,
+    export_table: u64,
+    /// Start of the xattr key/value table, and number of xattr ids
+    xattr_table: Option<(u64, u32)>,
+    /// Index of the xattr id table
+    xattr_ids: u64,
+    /// Inode of the root directory
+    root_ino: InodeNum,
+    /// Inode references found through directories
+    refs: BTreeMap<InodeNum, u64>,
+    /// Inodes read so far
+    nodes: BTreeMap<InodeNum, Node>,
+    /// Decompressed metadata blocks, with the offset of the next block
+    metadata: BlockCache,
+    /// Decompressed fragment blocks
+    fragments: BlockCache,
+}
+
+impl Volume {
+    /// Reads the superblock and the root directory inode.
+    fn open(device: Arc<dyn BlockDevice + Send + Sync>) -> Result<Self, VfsError> {
+        let mut sb = [0u8; SUPERBLOCK_SIZE];
+        read_bytes(&*device, 0, &mut sb)?;
+        if le32(&sb, 0) != SQUASHFS_MAGIC {
+            return Err(VfsError::InvalidArgument);
+        }
+        let version = (le16(&sb, SB_VERSION_MAJOR), le16(&sb, SB_VERSION_MINOR));
+        if version != (VERSION_MAJOR, VERSION_MINOR) {
+            crate::serial_println!("[squashfs] Unsupported version {}.{}", version.0, version.1);
+            return Err(VfsError::NotSupported);
+        }
+        let compression = Compression::from_id(le16(&sb, SB_COMPRESSION)).map_err(|name| {
+            crate::serial_println!("[squashfs] Unsupported compression: {}", name);
+            VfsError::NotSupported
+        })?;
+        let block_size = le32(&sb, SB_BLOCK_SIZE);
+        if !block_size.is_power_of_two()
+            || !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size)
+            || le16(&sb, SB_BLOCK_LOG) as u32 != block_size.trailing_zeros()
+        {
+            crate::serial_println!("[squashfs] Bad block size {}", block_size);
+            return Err(VfsError::InvalidArgument);
+        }
+        let bytes_used = le64(&sb, SB_BYTES_USED);
+        let info = device.info();
+        if bytes_used > info.total_sectors * info.sector_size as u64 {
+            crate::serial_println!("[squashfs] Image of {} bytes is larger than its device", bytes_used);
+            return Err(VfsError::InvalidArgument);
+        }
+
+        let mut volume = Self {
+            device,
+            compression,
+            block_size,
+            inode_count: le32(&sb, SB_INODE_COUNT),
+            fragment_count: le32(&sb, SB_FRAGMENT_COUNT),
+            bytes_used,
+            inode_table: le64(&sb, SB_INODE_TABLE),
+            directory_table: le64(&sb, SB_DIRECTORY_TABLE),
+            fragment_table: le64(&sb, SB_FRAGMENT_TABLE),
+            export_table: le64(&sb, SB_EXPORT_TABLE),
+            xattr_table: None,
+            xattr_ids: NO_TABLE,
+            root_ino: 0,
+            refs: BTreeMap::new(),
+            nodes: BTreeMap::new(),
+            metadata: BlockCache::new(METADATA_CACHE),
+            fragments: BlockCache::new(FRAGMENT_CACHE),
+        };
+
+        // The xattr id table starts with the position of the key/value
+        // table and the number of ids; its index follows
+        let xattr_id_table = le64(&sb, SB_XATTR_ID_TABLE);
+        if xattr_id_table != NO_TABLE {
+            let mut header = [0u8; 16];
+            volume.read_bytes(xattr_id_table, &mut header)?;
+            volume.xattr_table = Some((le64(&header, 0), le32(&header, 8)));
+            volume.xattr_ids = xattr_id_table + 16;
+        }
+
+        let root_ref = le64(&sb, SB_ROOT_INODE);
+        let root = volume.read_inode(root_ref)?;
+        if root.1.file_type != VfsFileType::Directory {
+            return Err(VfsError::InvalidArgument);
+        }
+        volume.root_ino = root.0;
+        volume.refs.insert(root.0, root_ref);
+        volume.nodes.insert(root.0, root.1);
+
+        crate::serial_println!(
+            "[squashfs] Image of {} inodes, {} blocks of {} bytes, {} compressed, made at {}",
+            volume.inode_count,
+            bytes_used.div_ceil(block_size as u64),
+            block_size,
+            compression.name(),
+            le32(&sb, SB_MKFS_TIME)
+        );
+        Ok(volume)
+    }
+
+    fn read_bytes(&self, offset: u64, buffer: &mut [u8]) -> Result<(), VfsError> {
+        if offset + buffer.len() as u64 > self.bytes_used {
+            return Err(VfsError::IoError);
+        }
+        read_bytes(&*self.device, offset, buffer)
+    }
+
+    /// Reads a compressed or stored block of at most `limit` bytes.
+    fn read_block(&self, at: u64, len: usize, compressed: bool, limit: usize) -> Result<Vec<u8>, VfsError> {
+        if !compressed && len > limit {
+            return Err(VfsError::IoError);
+        }
+        let mut data = vec![0u8; len];
+        self.read_bytes(at, &mut data)?;
+        if compressed {
+            self.compression.decompress(&data, limit)
+        } else {
+            Ok(data)
+        }
+    }
+
+    /// The metadata block at a device offset, and the offset of the next.
+    fn metadata_block(&mut self, at: u64) -> Result<(Arc<Vec<u8>>, u64), VfsError> {
+        let mut header = [0u8; 2];
+        self.read_bytes(at, &mut header)?;
+        let header = u16::from_le_bytes(header);
+        let len = (header & !METADATA_UNCOMPRESSED) as usize;
+        let next = at + 2 + len as u64;
+        if let Some(data) = self.metadata.get(at) {
+            return Ok((data, next));
+        }
+        let data = self.read_block(at + 2, len, header & METADATA_UNCOMPRESSED == 0, METADATA_SIZE)?;
+        if data.is_empty() {
+            return Err(VfsError::IoError);
+        }
+        let data = Arc::new(data);
+        self.metadata.insert(at, data.clone());
+        Ok((data, next))
+    }
+
+    /// Reads `len` bytes of metadata, moving the cursor past them.
+    fn read_metadata(&mut self, cursor: &mut Cursor, len: usize) -> Result<Vec<u8>, VfsError> {
+        let mut out = Vec::with_capacity(len);
+        while out.len() < len {
+            let (data, next) = self.metadata_block(cursor.block)?;
+            if cursor.offset >= data.len() {
+                cursor.block = next;
+                cursor.offset -= data.len();
+                continue;
+            }
+            let take = (len - out.len()).min(data.len() - cursor.offset);
+            out.extend_from_slice(&data[cursor.offset..cursor.offset + take]);
+            cursor.offset += take;
+        }
+        Ok(out)
+    }
+
+    /// Entry `index` of a lookup table whose index of metadata blocks
+    /// starts at `table`.
+    fn table_entry(&mut self, table: u64, entry_size: usize, index: u64) -> Result<Vec<u8>, VfsError> {
+        let per_block = (METADATA_SIZE / entry_size) as u64;
+        let mut location = [0u8; 8];
+        self.read_bytes(table + index / per_block * 8, &mut location)?;
+        let mut cursor =
+            Cursor { block: u64::from_le_bytes(location), offset: (index % per_block) as usize * entry_size };
+        self.read_metadata(&mut cursor, entry_size)
+    }
+
+    /// Reads the inode a reference points to, returning its number.
+    fn read_inode(&mut self, reference: u64) -> Result<(InodeNum, Node), VfsError> {
+        let mut cursor = Cursor::at(self.inode_table, reference);
+        let header = self.read_metadata(&mut cursor, INODE_HEADER_SIZE)?;
+        let kind = le16(&header, 0);
+        let extended = kind > INODE_EXTENDED;
+        let basic = if extended { kind - INODE_EXTENDED } else { kind };
+        let ino = le32(&header, 12) as InodeNum;
+        if ino == 0 || ino > self.inode_count as InodeNum {
+            crate::serial_println!("[squashfs] Bad inode number {} at reference {:#x}", ino, reference);
+            return Err(VfsError::IoError);
+        }
+        let xattr = |index: u32| (index != NONE).then_some(index);
+
+        let mut node = Node {
+            file_type: VfsFileType::Regular,
+            mode: le16(&header, 2) & 0o7777,
+            nlink: 1,
+            mtime: le32(&header, 8),
+            size: 0,
+            stored: 0,
+            xattr: None,
+            contents: Contents::Special,
+        };
+        match basic {
+            INODE_DIR => {
+                // Extended directories end with an index for faster
+                // lookups, which is not needed here
+                let (nlink, size, block, offset, xattr_index) = if extended {
+                    let data = self.read_metadata(&mut cursor, 24)?;
+                    (le32(&data, 0), le32(&data, 4), le32(&data, 8), le16(&data, 18), le32(&data, 20))
+                } else {
+                    let data = self.read_metadata(&mut cursor, 16)?;
+                    (le32(&data, 4), le16(&data, 8) as u32, le32(&data, 0), le16(&data, 10), NONE)
+                };
+                let len = size.saturating_sub(DIR_SIZE_BIAS);
+                node.file_type = VfsFileType::Directory;
+                node.nlink = nlink;
+                node.size = len as u64;
+                node.stored = len as u64;
+                node.xattr = xattr(xattr_index);
+                node.contents = Contents::Directory { block, offset, len };
+            }
+            INODE_FILE => {
+                let (start, size, nlink, fragment, tail_offset, xattr_index) = if extended {
+                    let data = self.read_metadata(&mut cursor, 40)?;
+                    (le64(&data, 0), le64(&data, 8), le32(&data, 24), le32(&data, 28), le32(&data, 32), le32(&data, 36))
+                } else {
+                    let data = self.read_metadata(&mut cursor, 16)?;
+                    (le32(&data, 0) as u64, le32(&data, 12) as u64, 1, le32(&data, 4), le32(&data, 8), NONE)
+                };
+                let block_size = self.block_size as u64;
+                // The tail is in a fragment or takes a block of its own
+                let count = if fragment == NONE { size.div_ceil(block_size) } else { size / block_size };
+                if fragment != NONE && fragment >= self.fragment_count {
+                    return Err(VfsError::IoError);
+                }
+                let sizes = self.read_metadata(&mut cursor, count as usize * 4)?;
+                let mut blocks = Vec::with_capacity(count as usize);
+                let mut at = start;
+                for word in sizes.chunks_exact(4).map(|chunk| le32(chunk, 0)) {
+                    blocks.push((at, word));
+                    at += (word & !DATA_UNCOMPRESSED) as u64;
+                }
+                node.nlink = nlink;
+                node.size = size;
+                node.stored = at - start + if fragment == NONE { 0 } else { size % block_size };
+                node.xattr = xattr(xattr_index);
+                node.contents = Contents::File { blocks, fragment: (fragment != NONE).then_some((fragment, tail_offset)) };
+            }
+            INODE_SYMLINK => {
+                let data = self.read_metadata(&mut cursor, 8)?;
+                let len = le32(&data, 4) as usize;
+                let target = self.read_metadata(&mut cursor, len)?;
+                node.file_type = VfsFileType::Symlink;
+                node.nlink = le32(&data, 0);
+                node.size = len as u64;
+                if extended {
+                    node.xattr = xattr(le32(&self.read_metadata(&mut cursor, 4)?, 0));
+                }
+                node.contents = Contents::Symlink(String::from_utf8_lossy(&target).into());
+            }
+            INODE_BLOCK_DEV | INODE_CHAR_DEV | INODE_FIFO | INODE_SOCKET => {
+                // Devices have a device number after the link count
+                let device = matches!(basic, INODE_BLOCK_DEV | INODE_CHAR_DEV);
+                let len = 4 + if device { 4 } else { 0 } + if extended { 4 } else { 0 };
+                let data = self.read_metadata(&mut cursor, len)?;
+                node.file_type = match basic {
+                    INODE_BLOCK_DEV => VfsFileType::BlockDevice,
+                    INODE_CHAR_DEV => VfsFileType::CharDevice,
+                    INODE_FIFO => VfsFileType::Fifo,
+                    _ => VfsFileType::Socket,
+                };
+                node.nlink = le32(&data, 0);
+                if extended {
+                    node.xattr = xattr(le32(&data, len - 4));
+                }
+            }
+            _ => {
+                crate::serial_println!("[squashfs] Bad inode type {} at reference {:#x}", kind, reference);
+                return Err(VfsError::IoError);
+            }
+        }
+        Ok((ino, node))
+    }
+
+    /// The inode with a number: read through a reference a directory
+    /// gave, else through the export table.
+    fn node(&mut self, ino: InodeNum) -> Result<&Node, VfsError> {
+        if !self.nodes.contains_key(&ino) {
+            let reference = match self.refs.get(&ino) {
+                Some(&reference) => reference,
+                None => self.export_lookup(ino)?,
+            };
+            let (number, node) = self.read_inode(reference)?;
+            if number != ino {
+                return Err(VfsError::IoError);
+            }
+            self.nodes.insert(ino, node);
+        }
+        self.nodes.get(&ino).ok_or(VfsError::NotFound)
+    }
+
+    /// Reference of an inode, from the export table.
+    fn export_lookup(&mut self, ino: InodeNum) -> Result<u64, VfsError> {
+        if self.export_table == NO_TABLE || ino == 0 || ino > self.inode_count as InodeNum {
+            return Err(VfsError::NotFound);
+        }
+        let entry = self.table_entry(self.export_table, EXPORT_ENTRY_SIZE, ino - 1)?;
+        Ok(le64(&entry, 0))
+    }
+
+    /// The entries of a directory with their inode references.
+    fn entries(&mut self, ino: InodeNum) -> Result<Vec<(VfsDirEntry, u64)>, VfsError> {
+        let (block, offset, len) = match self.node(ino)?.contents {
+            Contents::Directory { block, offset, len } => (block, offset, len),
+            _ => return Err(VfsError::NotADirectory),
+        };
+        let mut cursor = Cursor { block: self.directory_table + block as u64, offset: offset as usize };
+        let mut left = len as usize;
+        let mut entries = Vec::new();
+        // Runs of entries share a header with their inodes' metadata block
+        // and a base inode number
+        while left >= DIR_HEADER_SIZE {
+            let header = self.read_metadata(&mut cursor, DIR_HEADER_SIZE)?;
+            left -= DIR_HEADER_SIZE;
+            let count = le32(&header, 0) as usize + 1;
+            let inode_block = le32(&header, 4) as u64;
+            let base = le32(&header, 8);
+            for _ in 0..count {
+                let entry = self.read_metadata(&mut cursor, DIR_ENTRY_SIZE)?;
+                let name_len = le16(&entry, 6) as usize + 1;
+                let name = self.read_metadata(&mut cursor, name_len)?;
+                left = left.checked_sub(DIR_ENTRY_SIZE + name_len).ok_or(VfsError::IoError)?;
+
+                let ino = base.wrapping_add(le16(&entry, 2) as i16 as u32) as InodeNum;
+                let file_type = match le16(&entry, 4) {
+                    INODE_DIR => VfsFileType::Directory,
+                    INODE_FILE => VfsFileType::Regular,
+                    INODE_SYMLINK => VfsFileType::Symlink,
+                    INODE_BLOCK_DEV => VfsFileType::BlockDevice,
+                    INODE_CHAR_DEV => VfsFileType::CharDevice,
+                    INODE_FIFO => VfsFileType::Fifo,
+                    INODE_SOCKET => VfsFileType::Socket,
+                    _ => return Err(VfsError::IoError),
+                };
+                let reference = inode_block << 16 | le16(&entry, 0) as u64;
+                let name = String::from_utf8_lossy(&name).into();
+                entries.push((VfsDirEntry { name, ino, file_type }, reference));
+            }
+        }
+        Ok(entries)
+    }
+
+    fn lookup(&mut self, parent: InodeNum, name: &str) -> Result<InodeNum, VfsError> {
+        let (entry, reference) =
+            self.entries(parent)?.into_iter().find(|(entry, _)| entry.name == name).ok_or(VfsError::NotFound)?;
+        self.refs.insert(entry.ino, reference);
+        Ok(entry.ino)
+    }
+
+    fn readdir(&mut self, ino: InodeNum) -> Result<Vec<VfsDirEntry>, VfsError> {
+        let entries = self.entries(ino)?;
+        Ok(entries
+            .into_iter()
+            .map(|(entry, reference)| {
+                self.refs.insert(entry.ino, reference);
+                entry
+            })
+            .collect())
+    }
+
+    fn getattr(&mut self, ino: InodeNum) -> Result<VfsAttr, VfsError> {
+        let block_size = self.block_size;
+        let node = self.node(ino)?;
+        Ok(VfsAttr {
+            ino,
+            file_type: node.file_type,
+            perm: VfsPermissions {
+                readable: node.mode & 0o400 != 0,
+                writable: false,
+                executable: node.mode & 0o100 != 0,
+            },
+            size: node.size,
+            nlink: node.nlink,
+            blksize: block_size,
+            blocks: node.stored.div_ceil(512),
+            atime: node.mtime as u64,
+            mtime: node.mtime as u64,
+            ctime: node.mtime as u64,
+            crtime: node.mtime as u64,
+        })
+    }
+
+    /// A data block of `len` bytes; a zero size word is a hole.
+    fn data_block(&self, at: u64, word: u32, len: usize) -> Result<Vec<u8>, VfsError> {
+        let stored = (word & !DATA_UNCOMPRESSED) as usize;
+        if stored == 0 {
+            return Ok(vec![0u8; len]);
+        }
+        let data = self.read_block(at, stored, word & DATA_UNCOMPRESSED == 0, self.block_size as usize)?;
+        if data.len() < len {
+            return Err(VfsError::IoError);
+        }
+        Ok(data)
+    }
+
+    /// A fragment block, from the cache if it was read recently.
+    fn fragment_block(&mut self, index: u32) -> Result<Arc<Vec<u8>>, VfsError> {
+        let entry = self.table_entry(self.fragment_table, FRAGMENT_ENTRY_SIZE, index as u64)?;
+        let (at, word) = (le64(&entry, 0), le32(&entry, 8));
+        if let Some(data) = self.fragments.get(at) {
+            return Ok(data);
+        }
+        let stored = (word & !DATA_UNCOMPRESSED) as usize;
+        let data = Arc::new(self.read_block(at, stored, word & DATA_UNCOMPRESSED == 0, self.block_size as usize)?);
+        self.fragments.insert(at, data.clone());
+        Ok(data)
+    }
+
+    fn read(&mut self, ino: InodeNum, offset: u64, size: usize) -> Result<Vec<u8>, VfsError> {
+        let block_size = self.block_size as u64;
+        let node = self.node(ino)?;
+        let Contents::File { blocks, fragment } = &node.contents else {
+            return Err(if node.file_type == VfsFileType::Directory {
+                VfsError::IsADirectory
+            } else {
+                VfsError::InvalidArgument
+            });
+        };
+        let file_size = node.size;
+        if offset >= file_size {
+            return Ok(Vec::new());
+        }
+        let end = file_size.min(offset + size as u64);
+
+        // The blocks the range covers, as (block, length, skip, take);
+        // no block is the tail in the fragment
+        let mut pieces = Vec::new();
+        let mut pos = offset;
+        while pos < end {
+            let index = pos / block_size;
+            let block_start = index * block_size;
+            let len = (file_size - block_start).min(block_size) as usize;
+            let skip = (pos - block_start) as usize;
+            let take = (len - skip).min((end - pos) as usize);
+            pieces.push((blocks.get(index as usize).copied(), len, skip, take));
+            pos += take as u64;
+        }
+        let fragment = *fragment;
+
+        let mut out = Vec::with_capacity((end - offset) as usize);
+        for (block, len, skip, take) in pieces {
+            if let Some((at, word)) = block {
+                let data = self.data_block(at, word, len)?;
+                out.extend_from_slice(&data[skip..skip + take]);
+            } else {
+                let (index, tail_offset) = fragment.ok_or(VfsError::IoError)?;
+                let data = self.fragment_block(index)?;
+                let start = tail_offset as usize + skip;
+                out.extend_from_slice(data.get(start..start + take).ok_or(VfsError::IoError)?);
+            }
+        }
+        Ok(out)
+    }
+
+    fn readlink(&mut self, ino: InodeNum) -> Result<String, VfsError> {
+        match &self.node(ino)?.contents {
+            Contents::Symlink(target) => Ok(target.clone()),
+            _ => Err(VfsError::InvalidArgument),
+        }
+    }
+
+    /// Full names and values of an inode's extended attributes.
+    fn xattrs(&mut self, ino: InodeNum) -> Result<Vec<(String, Vec<u8>)>, VfsError> {
+        let Some(index) = self.node(ino)?.xattr else {
+            return Ok(Vec::new());
+        };
+        let (table, ids) = self.xattr_table.ok_or(VfsError::IoError)?;
+        if index >= ids {
+            return Err(VfsError::IoError);
+        }
+        let entry = self.table_entry(self.xattr_ids, XATTR_ID_ENTRY_SIZE, index as u64)?;
+        let mut cursor = Cursor::at(table, le64(&entry, 0));
+        let count = le32(&entry, 8);
+
+        let mut attrs = Vec::new();
+        for _ in 0..count {
+            let key = self.read_metadata(&mut cursor, 4)?;
+            let kind = le16(&key, 0);
+            let name = self.read_metadata(&mut cursor, le16(&key, 2) as usize)?;
+            let value_len = le32(&self.read_metadata(&mut cursor, 4)?, 0) as usize;
+            let mut value = self.read_metadata(&mut cursor, value_len)?;
+            if kind & XATTR_VALUE_OOL != 0 {
+                // The value is stored once, where this refers to
+                if value.len() != 8 {
+                    return Err(VfsError::IoError);
+                }
+                let mut shared = Cursor::at(table, le64(&value, 0));
+                let len = le32(&self.read_metadata(&mut shared, 4)?, 0) as usize;
+                value = self.read_metadata(&mut shared, len)?;
+            }
+            let prefix = XATTR_PREFIXES.iter().find(|(id, _)| *id == kind & XATTR_PREFIX_MASK);
+            if let (Some(&(_, namespace)), Ok(name)) = (prefix, core::str::from_utf8(&name)) {
+                let mut full_name = String::from(namespace.prefix());
+                full_name.push_str(name);
+                attrs.push((full_name, value));
+            }
+        }
+        Ok(attrs)
+    }
+
+    fn statfs(&self) -> VfsStatFs {
+        VfsStatFs {
+            blocks: self.bytes_used.div_ceil(self.block_size as u64),
+            bfree: 0,
+            bavail: 0,
+            files: self.inode_count as u64,
+            ffree: 0,
+            bsize: self.block_size,
+            namelen: SQUASHFS_NAME_LEN,
+            bsaved: 0,
+        }
+    }
+}
+
+/// Reads bytes at any offset of a device.
+fn read_bytes(device: &dyn BlockDevice, offset: u64, buffer: &mut [u8]) -> Result<(), VfsError> {
+    let sector = offset / SECTOR_SIZE as u64;
+    let sector_offset = (offset % SECTOR_SIZE as u64) as usize;
+    if sector_offset == 0 && buffer.len().is_multiple_of(SECTOR_SIZE) {
+        return device.read_sectors(sector, buffer).map_err(|_| VfsError::IoError);
+    }
+    let sectors_needed = (sector_offset + buffer.len()).div_ceil(SECTOR_SIZE);
+    let mut sector_buffer = vec![0u8; sectors_needed * SECTOR_SIZE];
+    device.read_sectors(sector, &mut sector_buffer).map_err(|_| VfsError::IoError)?;
+    buffer.copy_from_slice(&sector_buffer[sector_offset..sector_offset + buffer.len()]);
+    Ok(())
+}
+
+/// SquashFS filesystem.
+pub struct SquashFs {
+    /// Block device
+    device: Arc<dyn BlockDevice + Send + Sync>,
+    /// Mounted image
+    volume: Mutex<Option<Volume>>,
+}
+
+impl SquashFs {
+    /// Creates a new SquashFS filesystem.
+    pub fn new(device: Arc<dyn BlockDevice + Send + Sync>) -> Self {
+        Self { device, volume: Mutex::new(None) }
+    }
+
+    /// Mounts the filesystem.
+    pub fn mount(&self) -> Result<(), VfsError> {
+        let volume = Volume::open(self.device.clone())?;
+        *self.volume.lock() = Some(volume);
+        Ok(())
+    }
+
+    /// Forgets the image.
+    pub fn unmount(&self) {
+        *self.volume.lock() = None;
+    }
+
+    /// Runs `f` on the mounted image.
+    fn with<R>(&self, f: impl FnOnce(&mut Volume) -> Result<R, VfsError>) -> Result<R, VfsError> {
+        let mut volume = self.volume.lock();
+        f(volume.as_mut().ok_or(VfsError::NoFilesystem)?)
+    }
+
+    /// Resolves a path relative to the root directory.
+    fn resolve_path(&self, path: &str) -> Result<InodeNum, VfsError> {
+        let mut ino = self.root_ino();
+        for component in path.split('/').filter(|s| !s.is_empty()) {
+            ino = self.lookup(ino, component)?;
+        }
+        Ok(ino)
+    }
+}
+
+impl Filesystem for SquashFs {
+    fn name(&self) -> &'static str {
+        "squashfs"
+    }
+
+    fn root_ino(&self) -> InodeNum {
+        self.volume.lock().as_ref().map_or(0, |volume| volume.root_ino)
+    }
+
+    fn statfs(&self) -> Result<VfsStatFs, VfsError> {
+        self.with(|volume| Ok(volume.statfs()))
+    }
+
+    fn lookup(&self, parent: InodeNum, name: &str) -> Result<InodeNum, VfsError> {
+        self.with(|volume| volume.lookup(parent, name))
+    }
+
+    fn getattr(&self, ino: InodeNum) -> Result<VfsAttr, VfsError> {
+        self.with(|volume| volume.getattr(ino))
+    }
+
+    fn readdir(&self, ino: InodeNum) -> Result<Vec<VfsDirEntry>, VfsError> {
+        self.with(|volume| volume.readdir(ino))
+    }
+
+    fn read(&self, ino: InodeNum, offset: u64, size: usize) -> Result<Vec<u8>, VfsError> {
+        self.with(|volume| volume.read(ino, offset, size))
+    }
+
+    fn readlink(&self, ino: InodeNum) -> Result<String, VfsError> {
+        self.with(|volume| volume.readlink(ino))
+    }
+
+    fn write(&self, _ino: InodeNum, _offset: u64, _data: &[u8]) -> Result<usize, VfsError> {
+        Err(VfsError::ReadOnlyFs)
+    }
+
+    fn create(&self, _parent: InodeNum, _name: &str, _file_type: VfsFileType) -> Result<InodeNum, VfsError> {
+        Err(VfsError::ReadOnlyFs)
+    }
+
+    fn unlink(&self, _parent: InodeNum, _name: &str) -> Result<(), VfsError> {
+        Err(VfsError::ReadOnlyFs)
+    }
+
+    fn rename(&self, _old_parent: InodeNum, _old_name: &str, _new_parent: InodeNum, _new_name: &str) -> Result<(), VfsError> {
+        Err(VfsError::ReadOnlyFs)
+    }
+
+    fn truncate(&self, _ino: InodeNum, _size: u64) -> Result<(), VfsError> {
+        Err(VfsError::ReadOnlyFs)
+    }
+
+    fn sync(&self) -> Result<(), VfsError> {
+        Ok(())
+    }
+
+    fn getxattr(&self, ino: InodeNum, name: &str) -> Result<Vec<u8>, VfsError> {
+        self.with(|volume| volume.xattrs(ino))?
+            .into_iter()
+            .find(|(attr, _)| attr == name)
+            .map(|(_, value)| value)
+            .ok_or(VfsError::NoAttribute)
+    }
+
+    fn setxattr(&self, _ino: InodeNum, _name: &str, _value: &[u8]) -> Result<(), VfsError> {
+        Err(VfsError::ReadOnlyFs)
+    }
+
+    fn listxattr(&self, ino: InodeNum) -> Result<Vec<String>, VfsError> {
+        Ok(self.with(|volume| volume.xattrs(ino))?.into_iter().map(|(name, _)| name).collect())
+    }
+
+    fn removexattr(&self, _ino: InodeNum, _name: &str) -> Result<(), VfsError> {
+        Err(VfsError::ReadOnlyFs)
+    }
+}
+
+// ============================================================================
+// Module-level mount API for shell commands
+// ============================================================================
+
+/// Block device wrapper that uses block module's read/write functions.
+struct BlockDeviceWrapper {
+    name: String,
+}
+
+impl BlockDeviceWrapper {
+    fn new(name: &str) -> Self {
+        Self { name: name.into() }
+    }
+}
+
+impl crate::block::BlockDevice for BlockDeviceWrapper {
+    fn info(&self) -> crate::block::BlockDeviceInfo {
+        crate::block::list_devices()
+            .into_iter()
+            .find(|d| d.name == self.name)
+            .unwrap_or(crate::block::BlockDeviceInfo {
+                name: self.name.clone(),
+                sector_size: 512,
+                total_sectors: 0,
+                read_only: true,
+                model: String::new(),
+            })
+    }
+
+    fn read_sectors(&self, start: u64, buf: &mut [u8]) -> Result<(), crate::block::BlockError> {
+        let sector_size = self.info().sector_size;
+        let count = buf.len() / sector_size;
+        let data = crate::block::read(&self.name, start, count)?;
+        buf[..data.len()].copy_from_slice(&data);
+        Ok(())
+    }
+
+    fn write_sectors(&self, _start: u64, _data: &[u8]) -> Result<(), crate::block::BlockError> {
+        Err(crate::block::BlockError::WriteProtected)
+    }
+
+    fn flush(&self) -> Result<(), crate::block::BlockError> {
+        Ok(())
+    }
+
+    fn is_ready(&self) -> bool {
+        true
+    }
+}
+
+/// A mounted image and the loop device its file was attached as.
+struct Mount {
+    fs: Arc<SquashFs>,
+    loop_device: Option<String>,
+}
+
+/// Global SquashFS mount registry.
+static SQUASHFS_MOUNTS: RwLock<BTreeMap<String, Mount>> = RwLock::new(BTreeMap::new());
+
+/// Mount a SquashFS image (read-only) from a block device, or from an
+/// image file through a loop device.
+pub fn mount(source: &str, mount_point: &str) -> Result<(), VfsError> {
+    use alloc::string::ToString;
+    use crate::block::{loopback, BlockError};
+
+    let loop_device = if crate::block::list_devices().iter().any(|d| d.name == source) {
+        None
+    } else {
+        Some(loopback::attach(source, true).map_err(|e| match e {
+            BlockError::NotFound => VfsError::NotFound,
+            BlockError::Unsupported => VfsError::NotAFile,
+            _ => VfsError::IoError,
+        })?)
+    };
+    let device_name = loop_device.as_deref().unwrap_or(source);
+
+    let fs = Arc::new(SquashFs::new(Arc::new(BlockDeviceWrapper::new(device_name))));
+    if let Err(e) = fs.mount().and_then(|()| VFS.mount(mount_point, fs.clone(), true)) {
+        if let Some(name) = &loop_device {
+            let _ = loopback::detach(name);
+        }
+        return Err(e);
+    }
+
+    SQUASHFS_MOUNTS.write().insert(mount_point.to_string(), Mount { fs, loop_device });
+
+    crate::serial_println!("[squashfs] Mounted {} at {} (read-only)", source, mount_point);
+    Ok(())
+}
+
+/// Unmount a SquashFS image, detaching its loop device if it has one.
+pub fn unmount(mount_point: &str) -> Result<(), VfsError> {
+    let mount = SQUASHFS_MOUNTS.write().remove(mount_point).ok_or(VfsError::NotFound)?;
+    let _ = VFS.unmount(mount_point);
+    mount.fs.unmount();
+    if let Some(name) = &mount.loop_device {
+        let _ = crate::block::loopback::detach(name);
+    }
+    crate::serial_println!("[squashfs] Unmounted {}", mount_point);
+    Ok(())
+}
+
+/// List directory on SquashFS filesystem.
+pub fn ls(path: &str) -> Result<Vec<(String, VfsFileType, u64)>, VfsError> {
+    let mounts = SQUASHFS_MOUNTS.read();
+    for (mount_point, mount) in mounts.iter() {
+        if let Some(rel_path) = path.strip_prefix(mount_point.as_str()) {
+            let fs = &mount.fs;
+            let ino = fs.resolve_path(rel_path)?;
+            let entries = fs.readdir(ino)?;
+            return Ok(entries
+                .iter()
+                .map(|entry| {
+                    let (ft, size) = match fs.getattr(entry.ino) {
+                        Ok(attrs) => (attrs.file_type, attrs.size),
+                        Err(_) => (VfsFileType::Regular, 0),
+                    };
+                    (entry.name.clone(), ft, size)
+                })
+                .collect());
+        }
+    }
+    Err(VfsError::NotFound)
+}
+
+/// Read file from SquashFS filesystem.
+pub fn cat(path: &str) -> Result<Vec<u8>, VfsError> {
+    let mounts = SQUASHFS_MOUNTS.read();
+    for (mount_point, mount) in mounts.iter() {
+        if let Some(rel_path) = path.strip_prefix(mount_point.as_str()) {
+            let ino = mount.fs.resolve_path(rel_path)?;
+            return mount.fs.read(ino, 0, 1024 * 1024); // Max 1MB
+        }
+    }
+    Err(VfsError::NotFound)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::ramdisk::RamDisk;
+
+    const BLOCK: usize = 4096;
+    const MTIME: u32 = 1709210096;
+
+    /// Inode numbers of the test image
+    const README: u32 = 1;
+    const BIG: u32 = 2;
+    const LINK: u32 = 3;
+    const LIBC: u32 = 4;
+    const LIB: u32 = 5;
+    const ROOT: u32 = 6;
+
+    /// zlib stream of `README_TEXT`, the first fragment block
+    const README_ZLIB: [u8; 17] =
+        [0x78, 0xDA, 0xCB, 0x48, 0xCD, 0xC9, 0xC9, 0x57, 0xC8, 0x40, 0x27, 0xB9, 0x00, 0x70, 0xBE, 0x08, 0xBB];
+    const README_TEXT: &[u8] = b"hello hello hello hello\n";
+
+    /// Size of `big`: a stored block, a hole, and a tail of 100 bytes
+    const BIG_SIZE: usize = 2 * BLOCK + 100;
+
+    fn big_data() -> Vec<u8> {
+        let mut data: Vec<u8> = (0..BIG_SIZE).map(|i| (i * 7 % 251) as u8).collect();
+        data[BLOCK..2 * BLOCK].fill(0);
+        data
+    }
+
+    /// An uncompressed metadata block
+    fn metadata(data: &[u8]) -> Vec<u8> {
+        assert!(data.len() <= METADATA_SIZE);
+        [&(data.len() as u16 | METADATA_UNCOMPRESSED).to_le_bytes()[..], data].concat()
+    }
+
+    fn inode_header(kind: u16, mode: u16, ino: u32) -> Vec<u8> {
+        [&kind.to_le_bytes()[..], &mode.to_le_bytes(), &[0; 4], &MTIME.to_le_bytes(), &ino.to_le_bytes()].concat()
+    }
+
+    /// A directory listing of (name, inode offset, inode number, type),
+    /// all in inode block 0
+    fn listing(entries: &[(&str, usize, u32, u16)]) -> Vec<u8> {
+        let base = entries.iter().map(|e| e.2).min().unwrap();
+        let mut out = [(entries.len() as u32 - 1).to_le_bytes(), 0u32.to_le_bytes(), base.to_le_bytes()].concat();
+        for &(name, offset, ino, kind) in entries {
+            out.extend_from_slice(&(offset as u16).to_le_bytes());
+            out.extend_from_slice(&((ino - base) as i16).to_le_bytes());
+            out.extend_from_slice(&kind.to_le_bytes());
+            out.extend_from_slice(&(name.len() as u16 - 1).to_le_bytes());
+            out.extend_from_slice(name.as_bytes());
+        }
+        out
+    }
+
+    fn put64(image: &mut [u8], offset: usize, value: u64) {
+        image[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// Builds the test image:
+    ///
+    /// ```text
+    /// /README        basic file, all in fragment 0 (gzip)
+    /// /big           extended file: a stored block, a hole, a tail in
+    ///                fragment 1 (stored); user.origin and trusted.note
+    /// /lib/libc.so   basic file, in fragment 1 after big's tail
+    /// /link -> README
+    /// ```
+    fn build_image() -> Vec<u8> {
+        let mut image = vec![0u8; SUPERBLOCK_SIZE];
+
+        // Data: big's first block, then the fragment blocks
+        let big_start = image.len() as u64;
+        image.extend_from_slice(&big_data()[..BLOCK]);
+        let fragment0 = image.len() as u64;
+        image.extend_from_slice(&README_ZLIB);
+        let fragment1 = image.len() as u64;
+        image.extend_from_slice(&big_data()[2 * BLOCK..]);
+        image.extend_from_slice(b"libc bytes");
+
+        // Inodes, with the directory listings they point to
+        let mut inodes = Vec::new();
+        let mut directories = Vec::new();
+
+        let readme_at = inodes.len();
+        inodes.extend(inode_header(INODE_FILE, 0o644, README));
+        for field in [0, 0, 0, README_TEXT.len() as u32] {
+            inodes.extend(field.to_le_bytes());
+        }
+
+        let big_at = inodes.len();
+        inodes.extend(inode_header(INODE_FILE + INODE_EXTENDED, 0o755, BIG));
+        for field in [big_start, BIG_SIZE as u64, BLOCK as u64] {
+            inodes.extend(field.to_le_bytes());
+        }
+        for field in [1, 1, 0, 0, BLOCK as u32 | DATA_UNCOMPRESSED, 0] {
+            inodes.extend(u32::to_le_bytes(field));
+        }
+
+        let link_at = inodes.len();
+        inodes.extend(inode_header(INODE_SYMLINK, 0o777, LINK));
+        inodes.extend([1u32.to_le_bytes(), 6u32.to_le_bytes()].concat());
+        inodes.extend(b"README");
+
+        let libc_at = inodes.len();
+        inodes.extend(inode_header(INODE_FILE, 0o644, LIBC));
+        for field in [0, 1, 100, 10] {
+            inodes.extend(u32::to_le_bytes(field));
+        }
+
+        let lib_listing = listing(&[("libc.so", libc_at, LIBC, INODE_FILE)]);
+        let lib_at = inodes.len();
+        inodes.extend(inode_header(INODE_DIR, 0o755, LIB));
+        inodes.extend([0u32.to_le_bytes(), 2u32.to_le_bytes()].concat());
+        inodes.extend((lib_listing.len() as u16 + 3).to_le_bytes());
+        inodes.extend(0u16.to_le_bytes());
+        inodes.extend(ROOT.to_le_bytes());
+        directories.extend(&lib_listing);
+
+        let root_listing = listing(&[
+            ("README", readme_at, README, INODE_FILE),
+            ("big", big_at, BIG, INODE_FILE),
+            ("lib", lib_at, LIB, INODE_DIR),
+            ("link", link_at, LINK, INODE_SYMLINK),
+        ]);
+        let root_at = inodes.len();
+        inodes.extend(inode_header(INODE_DIR + INODE_EXTENDED, 0o755, ROOT));
+        for field in [3, root_listing.len() as u32 + 3, 0, ROOT + 1] {
+            inodes.extend(u32::to_le_bytes(field));
+        }
+        inodes.extend([0u16.to_le_bytes(), (lib_listing.len() as u16).to_le_bytes()].concat());
+        inodes.extend(NONE.to_le_bytes());
+        directories.extend(&root_listing);
+
+        let inode_table = image.len() as u64;
+        image.extend(metadata(&inodes));
+        let directory_table = image.len() as u64;
+        image.extend(metadata(&directories));
+
+        // Fragment table
+        let mut fragments = Vec::new();
+        for (at, size) in [(fragment0, README_ZLIB.len() as u32), (fragment1, 110 | DATA_UNCOMPRESSED)] {
+            fragments.extend(at.to_le_bytes());
+            fragments.extend([size.to_le_bytes(), [0; 4]].concat());
+        }
+        let fragment_block = image.len() as u64;
+        image.extend(metadata(&fragments));
+        let fragment_table = image.len() as u64;
+        image.extend(fragment_block.to_le_bytes());
+
+        // Export table, by inode number
+        let mut exports = vec![0u64; ROOT as usize];
+        for (ino, at) in [(README, readme_at), (BIG, big_at), (LINK, link_at), (LIBC, libc_at), (LIB, lib_at), (ROOT, root_at)] {
+            exports[ino as usize - 1] = at as u64;
+        }
+        let export_block = image.len() as u64;
+        image.extend(metadata(&exports.iter().flat_map(|e| e.to_le_bytes()).collect::<Vec<_>>()));
+        let export_table = image.len() as u64;
+        image.extend(export_block.to_le_bytes());
+
+        // Xattrs: user.origin, then trusted.note sharing its value
+        let mut pairs = Vec::new();
+        pairs.extend([0u16.to_le_bytes(), 6u16.to_le_bytes()].concat());
+        pairs.extend(b"origin");
+        let origin_value = pairs.len() as u64;
+        pairs.extend(9u32.to_le_bytes());
+        pairs.extend(b"splax-pkg");
+        pairs.extend([(1 | XATTR_VALUE_OOL).to_le_bytes(), 4u16.to_le_bytes()].concat());
+        pairs.extend(b"note");
+        pairs.extend(8u32.to_le_bytes());
+        pairs.extend(origin_value.to_le_bytes());
+        let xattr_table = image.len() as u64;
+        image.extend(metadata(&pairs));
+        let xattr_block = image.len() as u64;
+        image.extend(metadata(&[0u64.to_le_bytes(), [2, 0, 0, 0, 0, 0, 0, 0]].concat()));
+        let xattr_id_table = image.len() as u64;
+        image.extend(xattr_table.to_le_bytes());
+        image.extend([1u32.to_le_bytes(), [0; 4]].concat());
+        image.extend(xattr_block.to_le_bytes());
+
+        // Id table: root only
+        let id_block = image.len() as u64;
+        image.extend(metadata(&0u32.to_le_bytes()));
+        let id_table = image.len() as u64;
+        image.extend(id_block.to_le_bytes());
+
+        let bytes_used = image.len() as u64;
+        image[0..4].copy_from_slice(&SQUASHFS_MAGIC.to_le_bytes());
+        image[SB_INODE_COUNT..SB_INODE_COUNT + 4].copy_from_slice(&ROOT.to_le_bytes());
+        image[SB_MKFS_TIME..SB_MKFS_TIME + 4].copy_from_slice(&MTIME.to_le_bytes());
+        image[SB_BLOCK_SIZE..SB_BLOCK_SIZE + 4].copy_from_slice(&(BLOCK as u32).to_le_bytes());
+        image[SB_FRAGMENT_COUNT..SB_FRAGMENT_COUNT + 4].copy_from_slice(&2u32.to_le_bytes());
+        image[SB_COMPRESSION..SB_COMPRESSION + 2].copy_from_slice(&1u16.to_le_bytes());
+        image[SB_BLOCK_LOG..SB_BLOCK_LOG + 2].copy_from_slice(&12u16.to_le_bytes());
+        // One id
+        image[26..28].copy_from_slice(&1u16.to_le_bytes());
+        image[SB_VERSION_MAJOR..SB_VERSION_MAJOR + 2].copy_from_slice(&VERSION_MAJOR.to_le_bytes());
+        image[SB_VERSION_MINOR..SB_VERSION_MINOR + 2].copy_from_slice(&VERSION_MINOR.to_le_bytes());
+        put64(&mut image, SB_ROOT_INODE, root_at as u64);
+        put64(&mut image, SB_BYTES_USED, bytes_used);
+        // Id table
+        put64(&mut image, 48, id_table);
+        put64(&mut image, SB_XATTR_ID_TABLE, xattr_id_table);
+        put64(&mut image, SB_INODE_TABLE, inode_table);
+        put64(&mut image, SB_DIRECTORY_TABLE, directory_table);
+        put64(&mut image, SB_FRAGMENT_TABLE, fragment_table);
+        put64(&mut image, SB_EXPORT_TABLE, export_table);
+
+        // Images are padded to 4 KiB
+        image.resize(image.len().next_multiple_of(BLOCK), 0);
+        image
+    }
+
+    fn mounted(image: Vec<u8>) -> SquashFs {
+        let fs = SquashFs::new(Arc::new(RamDisk::read_only("vdb", image)));
+        fs.mount().unwrap();
+        fs
+    }
+
+    #[test]
+    fn test_mount() {
+        let fs = mounted(build_image());
+        assert_eq!(fs.root_ino(), ROOT as InodeNum);
+        let statfs = fs.statfs().unwrap();
+        assert_eq!(statfs.bsize, BLOCK as u32);
+        assert_eq!(statfs.files, 6);
+
+        let names: Vec<_> = fs.readdir(fs.root_ino()).unwrap().into_iter().map(|e| (e.name, e.ino, e.file_type)).collect();
+        assert_eq!(
+            names,
+            [
+                (String::from("README"), README as InodeNum, VfsFileType::Regular),
+                (String::from("big"), BIG as InodeNum, VfsFileType::Regular),
+                (String::from("lib"), LIB as InodeNum, VfsFileType::Directory),
+                (String::from("link"), LINK as InodeNum, VfsFileType::Symlink),
+            ]
+        );
+
+        let root = fs.getattr(fs.root_ino()).unwrap();
+        assert_eq!((root.file_type, root.nlink, root.mtime), (VfsFileType::Directory, 3, MTIME as u64));
+        assert_eq!(fs.unlink(fs.root_ino(), "README"), Err(VfsError::ReadOnlyFs));
+        assert_eq!(fs.setxattr(fs.root_ino(), "user.a", b"b"), Err(VfsError::ReadOnlyFs));
+    }
+
+    #[test]
+    fn test_read() {
+        let fs = mounted(build_image());
+
+        // Gzip fragment
+        let readme = fs.resolve_path("/README").unwrap();
+        assert_eq!(fs.read(readme, 0, 4096).unwrap(), README_TEXT);
+        assert_eq!(fs.read(readme, 6, 5).unwrap(), b"hello");
+        let attr = fs.getattr(readme).unwrap();
+        assert_eq!((attr.size, attr.perm.executable), (24, false));
+
+        // Stored block, hole and stored fragment, read across them
+        let big = fs.resolve_path("big").unwrap();
+        assert_eq!(fs.read(big, 0, 1 << 20).unwrap(), big_data());
+        assert_eq!(fs.read(big, BLOCK as u64 - 10, 20).unwrap(), big_data()[BLOCK - 10..BLOCK + 10]);
+        assert_eq!(fs.read(big, 2 * BLOCK as u64 + 90, 50).unwrap(), big_data()[2 * BLOCK + 90..]);
+        assert!(fs.read(big, BIG_SIZE as u64, 10).unwrap().is_empty());
+        assert!(fs.getattr(big).unwrap().perm.executable);
+
+        let libc = fs.resolve_path("/lib/libc.so").unwrap();
+        assert_eq!(libc, LIBC as InodeNum);
+        assert_eq!(fs.read(libc, 0, 100).unwrap(), b"libc bytes");
+
+        let link = fs.resolve_path("/link").unwrap();
+        assert_eq!(fs.readlink(link).unwrap(), "README");
+        assert_eq!(fs.getattr(link).unwrap().file_type, VfsFileType::Symlink);
+
+        assert_eq!(fs.read(fs.root_ino(), 0, 10), Err(VfsError::IsADirectory));
+        assert_eq!(fs.resolve_path("/lib/missing"), Err(VfsError::NotFound));
+        assert!(matches!(fs.readdir(readme), Err(VfsError::NotADirectory)));
+    }
+
+    #[test]
+    fn test_export_table() {
+        // Inodes no lookup has produced are found by number
+        let fs = mounted(build_image());
+        assert_eq!(fs.read(LIBC as InodeNum, 0, 100).unwrap(), b"libc bytes");
+        assert_eq!(fs.getattr(LIB as InodeNum).unwrap().file_type, VfsFileType::Directory);
+        assert!(matches!(fs.getattr(7), Err(VfsError::NotFound)));
+    }
+
+    #[test]
+    fn test_xattrs() {
+        let fs = mounted(build_image());
+        let big = fs.resolve_path("/big").unwrap();
+        assert_eq!(fs.listxattr(big).unwrap(), ["user.origin", "trusted.note"]);
+        assert_eq!(fs.getxattr(big, "user.origin").unwrap(), b"splax-pkg");
+        assert_eq!(fs.getxattr(big, "trusted.note").unwrap(), b"splax-pkg");
+        assert_eq!(fs.getxattr(big, "user.missing"), Err(VfsError::NoAttribute));
+        assert!(fs.listxattr(fs.root_ino()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_bad_images() {
+        let fs = SquashFs::new(Arc::new(RamDisk::read_only("vdb", vec![0u8; BLOCK])));
+        assert_eq!(fs.mount(), Err(VfsError::InvalidArgument));
+
+        // xz
+        let mut image = build_image();
+        image[SB_COMPRESSION..SB_COMPRESSION + 2].copy_from_slice(&4u16.to_le_bytes());
+        let fs = SquashFs::new(Arc::new(RamDisk::read_only("vdb", image)));
+        assert_eq!(fs.mount(), Err(VfsError::NotSupported));
+
+        // Block size and its log disagree
+        let mut image = build_image();
+        image[SB_BLOCK_LOG] = 13;
+        let fs = SquashFs::new(Arc::new(RamDisk::read_only("vdb", image)));
+        assert_eq!(fs.mount(), Err(VfsError::InvalidArgument));
+
+        // Corrupt fragment data
+        let mut image = build_image();
+        image[SUPERBLOCK_SIZE + BLOCK + 5] ^= 0xFF;
+        let fs = mounted(image);
+        assert_eq!(fs.read(README as InodeNum, 0, 100), Err(VfsError::IoError));
+    }
+}
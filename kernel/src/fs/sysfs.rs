@@ -17,11 +17,16 @@
 //! │   │       ├── size
 //! │   │       ├── model
 //! │   │       └── stat
-//! │   └── net/
-//! │       └── eth0/
-//! │           ├── address
-//! │           ├── mtu
-//! │           └── statistics/
+//! │   ├── net/
+//! │   │   └── eth0/
+//! │   │       ├── address
+//! │   │       ├── mtu
+//! │   │       └── statistics/
+//! │   └── system/
+//! │       └── cpu/
+//! │           ├── online
+//! │           └── cpu1/
+//! │               └── online   (writable: 0 = offline, 1 = online)
 //! ├── block/
 //! │   └── vda -> ../devices/block/vda
 //! ├── class/
@@ -38,6 +43,9 @@ use alloc::vec::Vec;
 use alloc::vec;
 use alloc::format;
 
+use super::FsError;
+use crate::smp::{hotplug, CpuId};
+
 /// SysFS entry types
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SysEntryType {
@@ -175,6 +183,42 @@ pub fn list_sys_devices_net() -> Vec<SysEntry> {
     entries
 }
 
+/// Lists entries in /sys/devices/system/cpu
+pub fn list_sys_devices_system_cpu() -> Vec<SysEntry> {
+    let mut entries = vec![SysEntry {
+        name: String::from("online"),
+        entry_type: SysEntryType::File,
+        link_target: None,
+    }];
+
+    crate::smp::smp_state().for_each_present(|cpu, _| {
+        entries.push(SysEntry {
+            name: format!("cpu{}", cpu.as_u32()),
+            entry_type: SysEntryType::Directory,
+            link_target: None,
+        });
+    });
+
+    entries
+}
+
+/// Lists CPU attributes
+pub fn list_sys_cpu(cpu_name: &str) -> Vec<SysEntry> {
+    match parse_cpu_name(cpu_name) {
+        Some(cpu) if crate::smp::smp_state().cpu_state(cpu).is_some() => vec![SysEntry {
+            name: String::from("online"),
+            entry_type: SysEntryType::File,
+            link_target: None,
+        }],
+        _ => Vec::new(),
+    }
+}
+
+/// Parses a `cpuN` directory name
+fn parse_cpu_name(name: &str) -> Option<CpuId> {
+    name.strip_prefix("cpu")?.parse::<u32>().ok().map(CpuId::new)
+}
+
 /// Lists block device attributes
 pub fn list_sys_block_device(dev_name: &str) -> Vec<SysEntry> {
     if crate::block::with_device(dev_name, |_| ()).is_ok() {
@@ -252,6 +296,18 @@ pub fn read_sys_file(path: &str) -> Option<String> {
         ["kernel", "osrelease"] => Some(String::from("0.1.0-splax\n")),
         ["kernel", "ostype"] => Some(String::from("SplaxOS\n")),
         
+        ["devices", "system", "cpu", "online"] => {
+            // Comma-separated list of online CPUs, like Linux
+            let mut online = Vec::new();
+            crate::smp::smp_state().for_each_online(|cpu| online.push(format!("{}", cpu.as_u32())));
+            Some(format!("{}\n", online.join(",")))
+        }
+        ["devices", "system", "cpu", cpu_name, "online"] => {
+            let cpu = parse_cpu_name(cpu_name)?;
+            crate::smp::smp_state().cpu_state(cpu)?;
+            Some(String::from(if hotplug::is_online(cpu) { "1\n" } else { "0\n" }))
+        }
+
         ["devices", "block", dev_name, "size"] => {
             crate::block::with_device(dev_name, |dev| {
                 format!("{}\n", dev.info().total_sectors * 512)
@@ -332,3 +388,34 @@ pub fn read_sys_file(path: &str) -> Option<String> {
         _ => None,
     }
 }
+
+/// Writes a sysfs file
+///
+/// Only a few attributes are writable; everything else is read-only.
+pub fn write_sys_file(path: &str, data: &str) -> Result<(), FsError> {
+    let path = path.trim_start_matches("/sys").trim_start_matches('/');
+    let parts: Vec<&str> = path.split('/').collect();
+
+    match parts.as_slice() {
+        ["devices", "system", "cpu", cpu_name, "online"] => {
+            let cpu = parse_cpu_name(cpu_name).ok_or(FsError::NotFound)?;
+            let result = match data.trim() {
+                "0" => hotplug::offline(cpu),
+                "1" => hotplug::online(cpu),
+                _ => return Err(FsError::InvalidPath),
+            };
+            match result {
+                Ok(()) | Err(hotplug::HotplugError::AlreadyInState) => Ok(()),
+                Err(hotplug::HotplugError::NoSuchCpu) => Err(FsError::NotFound),
+                Err(_) => Err(FsError::PermissionDenied),
+            }
+        }
+        _ => {
+            if read_sys_file(path).is_some() {
+                Err(FsError::PermissionDenied)
+            } else {
+                Err(FsError::NotFound)
+            }
+        }
+    }
+}
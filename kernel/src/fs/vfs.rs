@@ -23,12 +23,26 @@
 //! ```
 
 use alloc::collections::BTreeMap;
-use alloc::format;
-use alloc::string::{String, ToString};
+use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::sync::atomic::{AtomicU64, Ordering};
-use spin::{Mutex, RwLock};
+use spin::Mutex;
+
+use super::dax::{self, DaxFile};
+use super::lock::{LockInfo, LockOwner, LockRequest, LockTable, LockType};
+use super::namespace::{self, MountNamespaces, MountNsId, Propagation};
+use super::notify::{GroupId, Notifier, WatchDescriptor, WatchEvent, WatchMask};
+use super::pagecache::{CachedFile, PAGE_CACHE};
+use super::xattr::{
+    self, CapRequirement, CAPS_XATTR, ENCRYPTION_XATTR, FILE_LABEL_RESOURCE, FS_KEY_RESOURCE, MOUNT_NS_RESOURCE,
+};
+use crate::cap::{CapabilityTable, CapabilityToken, Operations};
+use crate::crypto::keystore::{KeyId, KeyMaterial, KeyStore, KeyUsage};
+use crate::ipc::ChannelId;
+use crate::mm::vm::{self, FileBacking, VmError};
+use crate::mm::PAGE_SIZE;
+use crate::sched::ProcessId;
 
 /// Maximum number of open files per process
 pub const MAX_OPEN_FILES: usize = 256;
@@ -81,6 +95,66 @@ pub enum VfsError {
     NoFilesystem,
     /// Mount point busy
     Busy,
+    /// No such extended attribute
+    NoAttribute,
+    /// A conflicting lock is held
+    WouldBlock,
+    /// Waiting for a lock would deadlock
+    Deadlock,
+}
+
+impl VfsError {
+    /// Linux errno of the error
+    pub fn errno(self) -> i32 {
+        match self {
+            VfsError::NotFound => 2,
+            VfsError::PermissionDenied => 13,
+            VfsError::AlreadyExists => 17,
+            VfsError::NotADirectory => 20,
+            VfsError::NotAFile | VfsError::InvalidArgument => 22,
+            VfsError::IsADirectory => 21,
+            VfsError::NotEmpty => 39,
+            VfsError::BadFd => 9,
+            VfsError::TooManyOpenFiles => 24,
+            VfsError::NoSpace => 28,
+            VfsError::ReadOnlyFs => 30,
+            VfsError::IoError => 5,
+            VfsError::NotSupported => 95,
+            VfsError::PathTooLong => 36,
+            VfsError::CrossDevice => 18,
+            VfsError::NoFilesystem => 19,
+            VfsError::Busy => 16,
+            VfsError::NoAttribute => 61,
+            VfsError::WouldBlock => 11,
+            VfsError::Deadlock => 35,
+        }
+    }
+
+    /// Error for a Linux errno, as reported by a remote filesystem
+    pub fn from_errno(errno: u32) -> Self {
+        match errno {
+            2 => VfsError::NotFound,
+            1 | 13 => VfsError::PermissionDenied,
+            17 => VfsError::AlreadyExists,
+            20 => VfsError::NotADirectory,
+            21 => VfsError::IsADirectory,
+            39 => VfsError::NotEmpty,
+            9 => VfsError::BadFd,
+            23 | 24 => VfsError::TooManyOpenFiles,
+            28 | 122 => VfsError::NoSpace,
+            30 => VfsError::ReadOnlyFs,
+            22 => VfsError::InvalidArgument,
+            38 | 95 => VfsError::NotSupported,
+            36 => VfsError::PathTooLong,
+            18 => VfsError::CrossDevice,
+            19 => VfsError::NoFilesystem,
+            16 => VfsError::Busy,
+            61 => VfsError::NoAttribute,
+            11 => VfsError::WouldBlock,
+            35 => VfsError::Deadlock,
+            _ => VfsError::IoError,
+        }
+    }
 }
 
 /// File type
@@ -283,6 +357,11 @@ pub trait Filesystem: Send + Sync {
     /// Returns the filesystem name
     fn name(&self) -> &'static str;
 
+    /// Inode number of the root directory
+    fn root_ino(&self) -> InodeNum {
+        1
+    }
+
     /// Lookup a name in a directory
     fn lookup(&self, parent: InodeNum, name: &str) -> Result<InodeNum, VfsError>;
 
@@ -339,6 +418,88 @@ pub trait Filesystem: Send + Sync {
         let _ = (ino, attr);
         Err(VfsError::NotSupported)
     }
+
+    /// Whether a file may have changed outside the kernel since it was
+    /// last opened, making its cached pages stale. Asked on every open.
+    fn revalidate(&self, ino: InodeNum) -> bool {
+        let _ = ino;
+        false
+    }
+
+    /// Whether file data lives in persistent memory and bypasses the page
+    /// cache (see [`super::dax`])
+    fn is_dax(&self) -> bool {
+        false
+    }
+
+    /// Physical address of page `index` of a file on a DAX filesystem,
+    /// allocating it if it is a hole
+    fn dax_page(&self, ino: InodeNum, index: u64) -> Result<u64, VfsError> {
+        let _ = (ino, index);
+        Err(VfsError::NotSupported)
+    }
+
+    /// Get the value of an extended attribute (see [`super::xattr`])
+    fn getxattr(&self, ino: InodeNum, name: &str) -> Result<Vec<u8>, VfsError> {
+        let _ = (ino, name);
+        Err(VfsError::NotSupported)
+    }
+
+    /// Set an extended attribute, replacing any old value
+    fn setxattr(&self, ino: InodeNum, name: &str, value: &[u8]) -> Result<(), VfsError> {
+        let _ = (ino, name, value);
+        Err(VfsError::NotSupported)
+    }
+
+    /// List the names of an inode's extended attributes
+    fn listxattr(&self, ino: InodeNum) -> Result<Vec<String>, VfsError> {
+        let _ = ino;
+        Err(VfsError::NotSupported)
+    }
+
+    /// Remove an extended attribute
+    fn removexattr(&self, ino: InodeNum, name: &str) -> Result<(), VfsError> {
+        let _ = (ino, name);
+        Err(VfsError::NotSupported)
+    }
+
+    /// Give an empty directory an encryption policy under a master key,
+    /// and unlock it
+    fn set_encryption(&self, ino: InodeNum, key: &[u8]) -> Result<(), VfsError> {
+        let _ = (ino, key);
+        Err(VfsError::NotSupported)
+    }
+
+    /// Load the master key of an encrypted file or directory
+    fn unlock(&self, ino: InodeNum, key: &[u8]) -> Result<(), VfsError> {
+        let _ = (ino, key);
+        Err(VfsError::NotSupported)
+    }
+
+    /// Drop the master key of an encrypted file or directory, locking
+    /// everything encrypted under it
+    fn lock(&self, ino: InodeNum) -> Result<(), VfsError> {
+        let _ = ino;
+        Err(VfsError::NotSupported)
+    }
+
+    /// Whether an inode is encrypted, and with its key loaded
+    fn encryption(&self, ino: InodeNum) -> Result<VfsEncryption, VfsError> {
+        let _ = ino;
+        Ok(VfsEncryption::None)
+    }
+}
+
+/// Encryption state of a file or directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VfsEncryption {
+    /// Not encrypted
+    None,
+    /// Encrypted, key not loaded: content cannot be read and names are
+    /// shown as ciphertext
+    Locked,
+    /// Encrypted, key loaded
+    Unlocked,
 }
 
 /// Filesystem statistics
@@ -358,22 +519,38 @@ pub struct VfsStatFs {
     pub bsize: u32,
     /// Maximum name length
     pub namelen: u32,
+    /// Blocks saved by compression: used blocks plus these is what the
+    /// data would take stored raw
+    pub bsaved: u64,
 }
 
 /// Mount point
+#[derive(Clone)]
 pub struct MountPoint {
+    /// Filesystem instance ID, shared by bind mounts and namespace copies
+    /// (page cache key)
+    pub id: u64,
     /// Mount path
     pub path: String,
     /// Mounted filesystem
     pub fs: Arc<dyn Filesystem>,
     /// Root inode of this mount
     pub root_ino: InodeNum,
+    /// Path of the root inode within the filesystem: `/` unless bind
+    /// mounted from below the filesystem's root
+    pub root_path: String,
     /// Flags
     pub read_only: bool,
+    /// Peer group, if shared
+    pub peer_group: Option<u64>,
+    /// Peer group this mount receives mount events from, if a slave
+    pub master: Option<u64>,
 }
 
 /// Open file handle
 pub struct OpenFile {
+    /// Open file ID, unique in the VFS (owner of `flock` locks)
+    pub id: u64,
     /// Mounted filesystem
     pub mount: Arc<MountPoint>,
     /// Inode number
@@ -382,6 +559,10 @@ pub struct OpenFile {
     pub offset: u64,
     /// Open flags
     pub flags: OpenFlags,
+    /// Directory and name the file was opened through, for reporting its
+    /// changes to watches on the directory. Only recorded when the file was
+    /// created or watches existed when it was opened.
+    pub parent: Option<(InodeNum, String)>,
 }
 
 /// File descriptor table (per-process)
@@ -430,21 +611,33 @@ impl FdTable {
 
 /// The Virtual Filesystem
 pub struct Vfs {
-    /// Mount table
-    mounts: RwLock<Vec<Arc<MountPoint>>>,
+    /// Mount tables, per namespace
+    namespaces: MountNamespaces,
     /// Global inode counter
     next_ino: AtomicU64,
+    /// Mount ID counter
+    next_mount_id: AtomicU64,
     /// Per-process file descriptor tables
     fd_tables: Mutex<BTreeMap<u64, FdTable>>,
+    /// Change notification watches
+    notify: Notifier,
+    /// Open file ID counter
+    next_file_id: AtomicU64,
+    /// File locks
+    locks: LockTable,
 }
 
 impl Vfs {
     /// Create a new VFS
     pub const fn new() -> Self {
         Self {
-            mounts: RwLock::new(Vec::new()),
+            namespaces: MountNamespaces::new(),
             next_ino: AtomicU64::new(1),
+            next_mount_id: AtomicU64::new(1),
             fd_tables: Mutex::new(BTreeMap::new()),
+            notify: Notifier::new(),
+            next_file_id: AtomicU64::new(1),
+            locks: LockTable::new(),
         }
     }
 
@@ -455,72 +648,59 @@ impl Vfs {
 
     /// Mount a filesystem at the given path
     pub fn mount(&self, path: &str, fs: Arc<dyn Filesystem>, read_only: bool) -> Result<(), VfsError> {
-        let mut mounts = self.mounts.write();
-        
-        if mounts.len() >= MAX_MOUNTS {
-            return Err(VfsError::NoSpace);
-        }
-
-        // Check if already mounted
-        for m in mounts.iter() {
-            if m.path == path {
-                return Err(VfsError::AlreadyExists);
-            }
-        }
-
-        let mount = Arc::new(MountPoint {
+        let root_ino = fs.root_ino();
+        let mount = MountPoint {
+            id: self.next_mount_id.fetch_add(1, Ordering::SeqCst),
             path: String::from(path),
             fs,
-            root_ino: 1, // Root inode is 1 by convention
+            root_ino,
+            root_path: String::from("/"),
             read_only,
-        });
-
-        mounts.push(mount);
-        
-        // Sort by path length descending for longest-match lookup
-        mounts.sort_by(|a, b| b.path.len().cmp(&a.path.len()));
-        
-        Ok(())
+            peer_group: None,
+            master: None,
+        };
+        self.namespaces.add(self.current_ns(), mount)
     }
 
-    /// Unmount a filesystem
+    /// Unmount a filesystem, and its copies in namespaces the mount below
+    /// it propagates to
     pub fn unmount(&self, path: &str) -> Result<(), VfsError> {
-        let mut mounts = self.mounts.write();
-        
-        let pos = mounts.iter().position(|m| m.path == path)
-            .ok_or(VfsError::NotFound)?;
-        
         // Check if mount is in use (has open files)
         // For now, just remove it
-        mounts.remove(pos);
-        
-        Ok(())
+        let removed = self.namespaces.remove(self.current_ns(), path)?;
+        self.release_mounts(removed)
     }
 
-    /// Find the mount point for a path
-    fn find_mount(&self, path: &str) -> Option<(Arc<MountPoint>, String)> {
-        let mounts = self.mounts.read();
-        
-        for mount in mounts.iter() {
-            if path == mount.path || path.starts_with(&format!("{}/", mount.path)) {
-                let relative = if mount.path == "/" {
-                    path.to_string()
-                } else {
-                    path[mount.path.len()..].to_string()
-                };
-                return Some((mount.clone(), relative));
+    /// Tears down what the page cache, watches and locks hold for
+    /// filesystems no longer mounted anywhere
+    fn release_mounts(&self, removed: Vec<Arc<MountPoint>>) -> Result<(), VfsError> {
+        for mount in removed {
+            if self.namespaces.in_use(mount.id) {
+                continue;
             }
+            self.notify.unmounted(mount.id);
+            self.locks.release_mount(mount.id);
+            PAGE_CACHE.flush_mount(&mount)?;
+            PAGE_CACHE.invalidate_mount(&mount);
         }
-        
-        None
+        Ok(())
+    }
+
+    /// Namespace of the running process
+    fn current_ns(&self) -> MountNsId {
+        let pid = crate::sched::scheduler().current_process().map_or(ProcessId::KERNEL, |p| p);
+        self.namespaces.of(pid.0)
     }
 
     /// Resolve a path to (mount, inode)
     fn resolve_path(&self, path: &str) -> Result<(Arc<MountPoint>, InodeNum), VfsError> {
-        let (mount, relative) = self.find_mount(path).ok_or(VfsError::NotFound)?;
-        
-        let path = if relative.is_empty() { "/" } else { &relative };
-        let path = path.trim_start_matches('/');
+        self.resolve_in(self.current_ns(), path)
+    }
+
+    /// Resolve a path to (mount, inode) in a namespace
+    fn resolve_in(&self, ns: MountNsId, path: &str) -> Result<(Arc<MountPoint>, InodeNum), VfsError> {
+        let (mount, relative) = self.namespaces.find(ns, path).ok_or(VfsError::NotFound)?;
+        let path = relative.trim_start_matches('/');
         
         let mut current_ino = mount.root_ino;
         
@@ -543,6 +723,12 @@ impl Vfs {
         Ok((mount, current_ino))
     }
 
+    /// Resolve a path to its mount and inode, for kernel users that access
+    /// a file without a descriptor (loop devices)
+    pub fn resolve(&self, path: &str) -> Result<(Arc<MountPoint>, InodeNum), VfsError> {
+        self.resolve_path(path)
+    }
+
     /// Get or create fd table for a process
     fn get_fd_table(&self, pid: u64) -> FdTable {
         let mut tables = self.fd_tables.lock();
@@ -557,6 +743,7 @@ impl Vfs {
         let table = tables.entry(pid).or_insert_with(FdTable::new);
         
         // Resolve path or create file
+        let mut parent = None;
         let (mount, ino) = if flags.create {
             match self.resolve_path(path) {
                 Ok((mount, ino)) => {
@@ -570,8 +757,10 @@ impl Vfs {
                     let parent_path = path.rsplit_once('/').map(|(p, _)| p).unwrap_or("/");
                     let name = path.rsplit_once('/').map(|(_, n)| n).unwrap_or(path);
                     
-                    let (mount, parent_ino) = self.resolve_path(parent_path)?;
+                    let (mount, parent_ino) = self.resolve_writable(parent_path)?;
                     let ino = mount.fs.create(parent_ino, name, VfsFileType::Regular)?;
+                    self.notify.notify(mount.id, None, Some((parent_ino, name)), WatchMask::CREATE, 0);
+                    parent = Some((parent_ino, String::from(name)));
                     (mount, ino)
                 }
                 Err(e) => return Err(e),
@@ -579,6 +768,9 @@ impl Vfs {
         } else {
             self.resolve_path(path)?
         };
+        if parent.is_none() && self.notify.is_active() {
+            parent = self.parent_of(&mount, path);
+        }
         
         // Check it's not a directory (unless O_DIRECTORY)
         let attr = mount.fs.getattr(ino)?;
@@ -586,28 +778,112 @@ impl Vfs {
             return Err(VfsError::IsADirectory);
         }
         
+        // Changed behind our back (a host share): start from fresh pages
+        if !mount.fs.is_dax() && mount.fs.revalidate(ino) {
+            PAGE_CACHE.flush_inode(&mount, ino)?;
+            PAGE_CACHE.invalidate_inode(&mount, ino);
+        }
+        
         // Truncate if requested
         if flags.truncate && flags.write {
+            if mount.read_only {
+                return Err(VfsError::ReadOnlyFs);
+            }
             mount.fs.truncate(ino, 0)?;
+            PAGE_CACHE.truncate(&mount, ino, 0);
+            self.notify_parent(&mount, ino, &parent, WatchMask::MODIFY);
         }
         
         let file = OpenFile {
+            id: self.next_file_id.fetch_add(1, Ordering::Relaxed),
             mount,
             ino,
             offset: 0,
             flags,
+            parent,
         };
         
         table.alloc(file)
     }
 
+    /// Open a file whose capability label (if any) is satisfied by
+    /// `tokens`, owned by `pid`
+    pub fn open_with_caps(
+        &self,
+        pid: u64,
+        path: &str,
+        flags: OpenFlags,
+        caps: &CapabilityTable,
+        tokens: &[CapabilityToken],
+    ) -> Result<Fd, VfsError> {
+        match self.caps_label(path) {
+            Ok(requirements) => xattr::check_caps(&requirements, ProcessId::new(pid), caps, tokens)?,
+            // A file about to be created has no label yet
+            Err(VfsError::NotFound) if flags.create => {}
+            Err(e) => return Err(e),
+        }
+        self.open(pid, path, flags)
+    }
+
     /// Close a file
     pub fn close(&self, pid: u64, fd: Fd) -> Result<(), VfsError> {
         let mut tables = self.fd_tables.lock();
         let table = tables.get_mut(&pid).ok_or(VfsError::BadFd)?;
+        let file = table.get(fd).ok_or(VfsError::BadFd)?;
+        self.locks.release_file((file.mount.id, file.ino), file.id, pid);
         table.close(fd)
     }
 
+    /// Close every file of a process and release its locks, when it exits
+    pub fn close_all_fds(&self, pid: u64) {
+        self.fd_tables.lock().remove(&pid);
+        self.locks.release_process(pid);
+    }
+
+    /// Take, convert or release a whole-file lock on an open file
+    /// (`flock`). With `wait`, waits for conflicting locks to go away;
+    /// otherwise fails with [`VfsError::WouldBlock`].
+    pub fn flock(&self, pid: u64, fd: Fd, lock_type: LockType, wait: bool) -> Result<(), VfsError> {
+        let (key, id) = self.lock_target(pid, fd, LockType::Unlock)?;
+        self.locks.lock(key, LockOwner::File(id), pid, &LockRequest::whole_file(lock_type), wait)
+    }
+
+    /// Take, change or release a byte-range lock of `pid` on an open file
+    /// (`fcntl` `F_SETLK`, or `F_SETLKW` with `wait`). Shared locks need
+    /// the file open for reading, exclusive ones for writing.
+    pub fn setlk(&self, pid: u64, fd: Fd, request: &LockRequest, wait: bool) -> Result<(), VfsError> {
+        let (key, _) = self.lock_target(pid, fd, request.lock_type)?;
+        self.locks.lock(key, LockOwner::Process(pid), pid, request, wait)
+    }
+
+    /// First lock preventing `request` by `pid`, if any (`fcntl`
+    /// `F_GETLK`)
+    pub fn getlk(&self, pid: u64, fd: Fd, request: &LockRequest) -> Result<Option<LockInfo>, VfsError> {
+        let (key, _) = self.lock_target(pid, fd, LockType::Unlock)?;
+        self.locks.conflict(key, LockOwner::Process(pid), request)
+    }
+
+    /// File locks of this VFS
+    pub fn locks(&self) -> &LockTable {
+        &self.locks
+    }
+
+    /// Inode and open file ID of a descriptor, checking its access mode
+    /// allows `lock_type`
+    fn lock_target(&self, pid: u64, fd: Fd, lock_type: LockType) -> Result<((u64, InodeNum), u64), VfsError> {
+        let tables = self.fd_tables.lock();
+        let file = tables.get(&pid).and_then(|table| table.get(fd)).ok_or(VfsError::BadFd)?;
+        let allowed = match lock_type {
+            LockType::Shared => file.flags.read,
+            LockType::Exclusive => file.flags.write,
+            LockType::Unlock => true,
+        };
+        if !allowed {
+            return Err(VfsError::BadFd);
+        }
+        Ok(((file.mount.id, file.ino), file.id))
+    }
+
     /// Read from a file
     pub fn read(&self, pid: u64, fd: Fd, buf: &mut [u8]) -> Result<usize, VfsError> {
         let mut tables = self.fd_tables.lock();
@@ -617,10 +893,13 @@ impl Vfs {
         if !file.flags.read {
             return Err(VfsError::PermissionDenied);
         }
+        self.locks.check_io((file.mount.id, file.ino), pid, file.offset, buf.len(), false)?;
         
-        let data = file.mount.fs.read(file.ino, file.offset, buf.len())?;
-        let len = data.len().min(buf.len());
-        buf[..len].copy_from_slice(&data[..len]);
+        let len = if file.mount.fs.is_dax() {
+            dax::read(&file.mount, file.ino, file.offset, buf)?
+        } else {
+            PAGE_CACHE.read(&file.mount, file.ino, file.offset, buf)?
+        };
         file.offset += len as u64;
         
         Ok(len)
@@ -640,15 +919,23 @@ impl Vfs {
             return Err(VfsError::ReadOnlyFs);
         }
         
-        let offset = if file.flags.append {
-            let attr = file.mount.fs.getattr(file.ino)?;
-            attr.size
-        } else {
+        let dax = file.mount.fs.is_dax();
+        let offset = if !file.flags.append {
             file.offset
+        } else if dax {
+            dax::size(&file.mount, file.ino)?
+        } else {
+            PAGE_CACHE.size(&file.mount, file.ino)?
         };
+        self.locks.check_io((file.mount.id, file.ino), pid, offset, buf.len(), true)?;
         
-        let written = file.mount.fs.write(file.ino, offset, buf)?;
+        let written = if dax {
+            dax::write(&file.mount, file.ino, offset, buf)?
+        } else {
+            PAGE_CACHE.write(&file.mount, file.ino, offset, buf)?
+        };
         file.offset = offset + written as u64;
+        self.notify_parent(&file.mount, file.ino, &file.parent, WatchMask::MODIFY);
         
         Ok(written)
     }
@@ -659,7 +946,11 @@ impl Vfs {
         let table = tables.get_mut(&pid).ok_or(VfsError::BadFd)?;
         let file = table.get_mut(fd).ok_or(VfsError::BadFd)?;
         
-        let size = file.mount.fs.getattr(file.ino)?.size;
+        let size = if file.mount.fs.is_dax() {
+            dax::size(&file.mount, file.ino)?
+        } else {
+            PAGE_CACHE.size(&file.mount, file.ino)?
+        };
         
         let new_offset = match pos {
             SeekFrom::Start(n) => n,
@@ -686,7 +977,13 @@ impl Vfs {
     /// Get file attributes by path
     pub fn stat(&self, path: &str) -> Result<VfsAttr, VfsError> {
         let (mount, ino) = self.resolve_path(path)?;
-        mount.fs.getattr(ino)
+        Self::cached_attr(&mount, ino)
+    }
+
+    /// Get statistics of the filesystem holding a path
+    pub fn statfs(&self, path: &str) -> Result<VfsStatFs, VfsError> {
+        let (mount, _) = self.resolve_path(path)?;
+        mount.fs.statfs()
     }
 
     /// Get file attributes by fd
@@ -694,7 +991,175 @@ impl Vfs {
         let tables = self.fd_tables.lock();
         let table = tables.get(&pid).ok_or(VfsError::BadFd)?;
         let file = table.get(fd).ok_or(VfsError::BadFd)?;
-        file.mount.fs.getattr(file.ino)
+        Self::cached_attr(&file.mount, file.ino)
+    }
+
+    /// Returns attributes with the size of unwritten cached data applied.
+    fn cached_attr(mount: &MountPoint, ino: InodeNum) -> Result<VfsAttr, VfsError> {
+        let mut attr = mount.fs.getattr(ino)?;
+        if let Some(size) = PAGE_CACHE.cached_size(mount, ino) {
+            attr.size = size;
+        }
+        Ok(attr)
+    }
+
+    /// Get an extended attribute by path
+    pub fn getxattr(&self, path: &str, name: &str) -> Result<Vec<u8>, VfsError> {
+        xattr::check_name(name)?;
+        let (mount, ino) = self.resolve_path(path)?;
+        mount.fs.getxattr(ino, name)
+    }
+
+    /// Set an extended attribute by path. The capability label is set
+    /// through [`Vfs::set_caps_label`] instead, and the encryption context
+    /// through [`Vfs::set_encryption_policy`].
+    pub fn setxattr(&self, path: &str, name: &str, value: &[u8]) -> Result<(), VfsError> {
+        xattr::check_attr(name, value)?;
+        if name == CAPS_XATTR || name == ENCRYPTION_XATTR {
+            return Err(VfsError::PermissionDenied);
+        }
+        let (mount, ino) = self.resolve_writable(path)?;
+        mount.fs.setxattr(ino, name, value)?;
+        self.notify_path(&mount, ino, path, WatchMask::ATTRIB);
+        Ok(())
+    }
+
+    /// List extended attribute names by path
+    pub fn listxattr(&self, path: &str) -> Result<Vec<String>, VfsError> {
+        let (mount, ino) = self.resolve_path(path)?;
+        mount.fs.listxattr(ino)
+    }
+
+    /// Remove an extended attribute by path
+    pub fn removexattr(&self, path: &str, name: &str) -> Result<(), VfsError> {
+        xattr::check_name(name)?;
+        if name == CAPS_XATTR || name == ENCRYPTION_XATTR {
+            return Err(VfsError::PermissionDenied);
+        }
+        let (mount, ino) = self.resolve_writable(path)?;
+        mount.fs.removexattr(ino, name)?;
+        self.notify_path(&mount, ino, path, WatchMask::ATTRIB);
+        Ok(())
+    }
+
+    /// Get the capability label of a file; empty if it has none
+    pub fn caps_label(&self, path: &str) -> Result<Vec<CapRequirement>, VfsError> {
+        match self.getxattr(path, CAPS_XATTR) {
+            Ok(value) => xattr::parse_caps(&value),
+            Err(VfsError::NoAttribute | VfsError::NotSupported) => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Set (or with no requirements, remove) the capability label of a
+    /// file. `token` must be owned by `pid`, allow `WRITE` and name a
+    /// [`FILE_LABEL_RESOURCE`] resource.
+    pub fn set_caps_label(
+        &self,
+        path: &str,
+        requirements: &[CapRequirement],
+        pid: u64,
+        caps: &CapabilityTable,
+        token: CapabilityToken,
+    ) -> Result<(), VfsError> {
+        Self::check_cap(caps, pid, token, FILE_LABEL_RESOURCE)?;
+
+        let (mount, ino) = self.resolve_writable(path)?;
+        if requirements.is_empty() {
+            match mount.fs.removexattr(ino, CAPS_XATTR) {
+                Err(VfsError::NoAttribute) => return Ok(()),
+                result => result?,
+            }
+        } else {
+            mount.fs.setxattr(ino, CAPS_XATTR, &xattr::format_caps(requirements))?;
+        }
+        self.notify_path(&mount, ino, path, WatchMask::ATTRIB);
+        Ok(())
+    }
+
+    /// Checks that `token` is owned by `pid`, allows `WRITE` and names a
+    /// resource of the given type
+    fn check_cap(caps: &CapabilityTable, pid: u64, token: CapabilityToken, resource_type: &str) -> Result<(), VfsError> {
+        caps.check(ProcessId::new(pid), token, Operations::WRITE)
+            .map_err(|_| VfsError::PermissionDenied)?;
+        let resource = caps.get_resource(&token).map_err(|_| VfsError::PermissionDenied)?;
+        if resource.resource_type != resource_type {
+            return Err(VfsError::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    /// Fetches a master key `pid` may derive other keys from
+    fn master_key(keys: &KeyStore, pid: u64, key_id: KeyId) -> Result<KeyMaterial, VfsError> {
+        keys.use_key(ProcessId::new(pid), key_id, KeyUsage::DERIVE)
+            .map_err(|_| VfsError::PermissionDenied)
+    }
+
+    /// Give an empty directory an encryption policy under a key from the
+    /// key store, and unlock it. `token` must be owned by `pid`, allow
+    /// `WRITE` and name a [`FS_KEY_RESOURCE`] resource; the key must be
+    /// `pid`'s and allow [`KeyUsage::DERIVE`].
+    pub fn set_encryption_policy(
+        &self,
+        path: &str,
+        keys: &KeyStore,
+        key_id: KeyId,
+        pid: u64,
+        caps: &CapabilityTable,
+        token: CapabilityToken,
+    ) -> Result<(), VfsError> {
+        Self::check_cap(caps, pid, token, FS_KEY_RESOURCE)?;
+        let key = Self::master_key(keys, pid, key_id)?;
+        let (mount, ino) = self.resolve_writable(path)?;
+        mount.fs.set_encryption(ino, key.as_bytes())?;
+        self.notify_path(&mount, ino, path, WatchMask::ATTRIB);
+        Ok(())
+    }
+
+    /// Unlock an encrypted directory with its key from the key store;
+    /// everything encrypted under the same key unlocks with it. Takes the
+    /// same capability and key as [`Vfs::set_encryption_policy`].
+    pub fn unlock(
+        &self,
+        path: &str,
+        keys: &KeyStore,
+        key_id: KeyId,
+        pid: u64,
+        caps: &CapabilityTable,
+        token: CapabilityToken,
+    ) -> Result<(), VfsError> {
+        Self::check_cap(caps, pid, token, FS_KEY_RESOURCE)?;
+        let key = Self::master_key(keys, pid, key_id)?;
+        let (mount, ino) = self.resolve_path(path)?;
+        mount.fs.unlock(ino, key.as_bytes())
+    }
+
+    /// Lock an encrypted directory, and everything encrypted under the
+    /// same key. Cached pages of the mount are written back and dropped,
+    /// so no plaintext stays behind. `token` is as for
+    /// [`Vfs::set_encryption_policy`].
+    pub fn lock(&self, path: &str, pid: u64, caps: &CapabilityTable, token: CapabilityToken) -> Result<(), VfsError> {
+        Self::check_cap(caps, pid, token, FS_KEY_RESOURCE)?;
+        let (mount, ino) = self.resolve_path(path)?;
+        PAGE_CACHE.flush_mount(&mount)?;
+        mount.fs.lock(ino)?;
+        PAGE_CACHE.invalidate_mount(&mount);
+        Ok(())
+    }
+
+    /// Whether a file or directory is encrypted, and unlocked
+    pub fn encryption_status(&self, path: &str) -> Result<VfsEncryption, VfsError> {
+        let (mount, ino) = self.resolve_path(path)?;
+        mount.fs.encryption(ino)
+    }
+
+    /// Resolve a path on a mount that can be written
+    fn resolve_writable(&self, path: &str) -> Result<(Arc<MountPoint>, InodeNum), VfsError> {
+        let (mount, ino) = self.resolve_path(path)?;
+        if mount.read_only {
+            return Err(VfsError::ReadOnlyFs);
+        }
+        Ok((mount, ino))
     }
 
     /// List directory contents
@@ -708,8 +1173,10 @@ impl Vfs {
         let parent_path = path.rsplit_once('/').map(|(p, _)| if p.is_empty() { "/" } else { p }).unwrap_or("/");
         let name = path.rsplit_once('/').map(|(_, n)| n).unwrap_or(path);
         
-        let (mount, parent_ino) = self.resolve_path(parent_path)?;
+        let (mount, parent_ino) = self.resolve_writable(parent_path)?;
         mount.fs.create(parent_ino, name, VfsFileType::Directory)?;
+        let mask = WatchMask::CREATE.union(WatchMask::IS_DIR);
+        self.notify.notify(mount.id, None, Some((parent_ino, name)), mask, 0);
         Ok(())
     }
 
@@ -718,8 +1185,21 @@ impl Vfs {
         let parent_path = path.rsplit_once('/').map(|(p, _)| if p.is_empty() { "/" } else { p }).unwrap_or("/");
         let name = path.rsplit_once('/').map(|(_, n)| n).unwrap_or(path);
         
-        let (mount, parent_ino) = self.resolve_path(parent_path)?;
-        mount.fs.unlink(parent_ino, name)
+        let (mount, parent_ino) = self.resolve_writable(parent_path)?;
+        let ino = mount.fs.lookup(parent_ino, name)?;
+        // Only watches care whether this removes the inode itself
+        let attr = if self.notify.is_active() { mount.fs.getattr(ino).ok() } else { None };
+        mount.fs.unlink(parent_ino, name)?;
+        PAGE_CACHE.invalidate_inode(&mount, ino);
+        if let Some(attr) = attr {
+            let is_dir = attr.file_type == VfsFileType::Directory;
+            let mask = if is_dir { WatchMask::DELETE.union(WatchMask::IS_DIR) } else { WatchMask::DELETE };
+            self.notify.notify(mount.id, None, Some((parent_ino, name)), mask, 0);
+            if is_dir || attr.nlink <= 1 {
+                self.notify.removed(mount.id, ino);
+            }
+        }
+        Ok(())
     }
 
     /// Remove a directory
@@ -735,20 +1215,364 @@ impl Vfs {
         let new_parent = new_path.rsplit_once('/').map(|(p, _)| if p.is_empty() { "/" } else { p }).unwrap_or("/");
         let new_name = new_path.rsplit_once('/').map(|(_, n)| n).unwrap_or(new_path);
         
-        let (old_mount, old_parent_ino) = self.resolve_path(old_parent)?;
-        let (new_mount, new_parent_ino) = self.resolve_path(new_parent)?;
+        let (old_mount, old_parent_ino) = self.resolve_writable(old_parent)?;
+        let (new_mount, new_parent_ino) = self.resolve_writable(new_parent)?;
         
         // Check same filesystem
         if !Arc::ptr_eq(&old_mount.fs, &new_mount.fs) {
             return Err(VfsError::CrossDevice);
         }
         
-        old_mount.fs.rename(old_parent_ino, old_name, new_parent_ino, new_name)
+        let is_dir = self.notify.is_active()
+            && old_mount.fs.lookup(old_parent_ino, old_name)
+                .and_then(|ino| old_mount.fs.getattr(ino))
+                .is_ok_and(|attr| attr.file_type == VfsFileType::Directory);
+        old_mount.fs.rename(old_parent_ino, old_name, new_parent_ino, new_name)?;
+        if self.notify.is_active() {
+            let dir = if is_dir { WatchMask::IS_DIR } else { WatchMask::NONE };
+            let cookie = self.notify.cookie();
+            self.notify.notify(old_mount.id, None, Some((old_parent_ino, old_name)), WatchMask::MOVED_FROM.union(dir), cookie);
+            self.notify.notify(new_mount.id, None, Some((new_parent_ino, new_name)), WatchMask::MOVED_TO.union(dir), cookie);
+        }
+        Ok(())
+    }
+
+    /// Directory and name of `path` on `mount`; `None` for the root of the
+    /// mount
+    fn parent_of(&self, mount: &Arc<MountPoint>, path: &str) -> Option<(InodeNum, String)> {
+        let (parent_path, name) = path.trim_end_matches('/').rsplit_once('/')?;
+        let parent_path = if parent_path.is_empty() { "/" } else { parent_path };
+        let (parent_mount, parent_ino) = self.resolve_path(parent_path).ok()?;
+        if parent_mount.id != mount.id || name.is_empty() {
+            return None;
+        }
+        Some((parent_ino, String::from(name)))
+    }
+
+    /// Reports an event on an inode and on its entry in `parent`
+    fn notify_parent(&self, mount: &MountPoint, ino: InodeNum, parent: &Option<(InodeNum, String)>, mask: WatchMask) {
+        let parent = parent.as_ref().map(|(dir, name)| (*dir, name.as_str()));
+        self.notify.notify(mount.id, Some(ino), parent, mask, 0);
+    }
+
+    /// Reports an event on the inode `path` resolved to
+    fn notify_path(&self, mount: &Arc<MountPoint>, ino: InodeNum, path: &str, mask: WatchMask) {
+        if self.notify.is_active() {
+            let parent = self.parent_of(mount, path);
+            self.notify_parent(mount, ino, &parent, mask);
+        }
+    }
+
+    /// Change notification watches of this VFS
+    pub fn watches(&self) -> &Notifier {
+        &self.notify
+    }
+
+    /// Create a watch group for `pid`. Events of its watches arrive as
+    /// messages on the returned channel, in the [`WatchEvent`] format.
+    pub fn watch_group(&self, pid: ProcessId, token: CapabilityToken) -> Result<(GroupId, ChannelId), VfsError> {
+        self.notify.create_group(pid, token)
+    }
+
+    /// Watch a file or directory for the events in `mask`. The caller
+    /// needs the capabilities of the path's label (if any), as for
+    /// [`Vfs::open_with_caps`].
+    pub fn add_watch(
+        &self,
+        group: GroupId,
+        pid: ProcessId,
+        path: &str,
+        mask: WatchMask,
+        caps: &CapabilityTable,
+        tokens: &[CapabilityToken],
+    ) -> Result<WatchDescriptor, VfsError> {
+        xattr::check_caps(&self.caps_label(path)?, pid, caps, tokens)?;
+        let (mount, ino) = self.resolve_path(path)?;
+        self.notify.add_watch(group, pid, mount.id, ino, mask)
+    }
+
+    /// Remove a watch
+    pub fn remove_watch(&self, group: GroupId, pid: ProcessId, wd: WatchDescriptor) -> Result<(), VfsError> {
+        self.notify.remove_watch(group, pid, wd)
+    }
+
+    /// Remove a watch group with all its watches
+    pub fn close_watch_group(&self, group: GroupId, pid: ProcessId) -> Result<(), VfsError> {
+        self.notify.close_group(group, pid)
+    }
+
+    /// Receive the events waiting for a watch group, for kernel users;
+    /// processes receive from the group's channel. Empty if there are
+    /// none.
+    pub fn read_events(&self, group: GroupId, pid: ProcessId) -> Result<Vec<WatchEvent>, VfsError> {
+        self.notify.read_events(group, pid)
+    }
+
+    /// Write back all cached data and sync every filesystem
+    pub fn sync(&self) -> Result<(), VfsError> {
+        let mut mounts = self.namespaces.all_mounts();
+        // Bind mounts and namespace copies share their filesystem
+        mounts.sort_by_key(|m| m.id);
+        mounts.dedup_by_key(|m| m.id);
+        let mut result = Ok(());
+        for mount in mounts {
+            if let Err(e) = PAGE_CACHE.flush_mount(&mount) {
+                result = result.and(Err(e));
+            }
+        }
+        result
+    }
+
+    /// Write back cached data of one mount and sync its filesystem
+    pub fn sync_mount(&self, path: &str) -> Result<(), VfsError> {
+        let mount = self.mount_at(path)?;
+        PAGE_CACHE.flush_mount(&mount)
+    }
+
+    /// Drop cached data of one mount after its filesystem changed beneath
+    /// the cache
+    pub fn invalidate_mount(&self, path: &str) -> Result<(), VfsError> {
+        let mount = self.mount_at(path)?;
+        PAGE_CACHE.invalidate_mount(&mount);
+        Ok(())
+    }
+
+    /// Get the mount at exactly `path`
+    fn mount_at(&self, path: &str) -> Result<Arc<MountPoint>, VfsError> {
+        self.namespaces.mount_at(self.current_ns(), path).ok_or(VfsError::NotFound)
+    }
+
+    /// Write back cached data of an open file
+    pub fn fsync(&self, pid: u64, fd: Fd) -> Result<(), VfsError> {
+        let (mount, ino) = self.file_ref(pid, fd)?;
+        if mount.fs.is_dax() {
+            dax::fsync();
+            return Ok(());
+        }
+        PAGE_CACHE.flush_inode(&mount, ino)
+    }
+
+    /// Get the mount and inode behind a file descriptor
+    fn file_ref(&self, pid: u64, fd: Fd) -> Result<(Arc<MountPoint>, InodeNum), VfsError> {
+        let tables = self.fd_tables.lock();
+        let file = tables.get(&pid).and_then(|t| t.get(fd)).ok_or(VfsError::BadFd)?;
+        Ok((file.mount.clone(), file.ino))
+    }
+
+    /// Map an open file into the calling process. Returns the mapped address.
+    pub fn mmap(&self, pid: u64, fd: Fd, req: &MmapRequest) -> Result<u64, VfsError> {
+        self.mmap_into(pid, fd, pid, req)
+    }
+
+    /// Map a file opened by `pid` into `target`'s address space
+    ///
+    /// Used by the ELF loader to map a new image before the process runs.
+    pub fn mmap_into(&self, pid: u64, fd: Fd, target: u64, req: &MmapRequest) -> Result<u64, VfsError> {
+        let (mount, ino, flags) = {
+            let tables = self.fd_tables.lock();
+            let file = tables.get(&pid).and_then(|t| t.get(fd)).ok_or(VfsError::BadFd)?;
+            (file.mount.clone(), file.ino, file.flags)
+        };
+        if !flags.read {
+            return Err(VfsError::PermissionDenied);
+        }
+        if req.shared && req.writable && (!flags.write || mount.read_only) {
+            return Err(VfsError::PermissionDenied);
+        }
+
+        let len = (req.len + PAGE_SIZE as u64 - 1) & !(PAGE_SIZE as u64 - 1);
+        let pager: Arc<dyn vm::Pager> = if mount.fs.is_dax() {
+            Arc::new(DaxFile::new(mount, ino))
+        } else {
+            Arc::new(CachedFile::new(mount, ino))
+        };
+        let backing = FileBacking {
+            pager,
+            offset: req.offset,
+            shared: req.shared,
+        };
+        vm::with_space(ProcessId::new(target), |space| {
+            let addr = match req.addr {
+                Some(addr) => addr,
+                None => space.find_free(len).ok_or(VmError::OutOfMemory)?,
+            };
+            space
+                .map_file(addr, len, req.writable, req.executable, backing)
+                .map(|_| addr)
+        })
+        .ok_or(VfsError::BadFd)?
+        .map_err(vm_error)
+    }
+
+    /// Unmap a range of the calling process
+    pub fn munmap(&self, pid: u64, addr: u64, len: u64) -> Result<(), VfsError> {
+        vm::with_space(ProcessId::new(pid), |space| space.unmap(addr, len))
+            .ok_or(VfsError::BadFd)?
+            .map_err(vm_error)
+    }
+
+    /// Write back shared file mappings in a range of the calling process
+    pub fn msync(&self, pid: u64, addr: u64, len: u64) -> Result<(), VfsError> {
+        vm::with_space(ProcessId::new(pid), |space| space.msync(addr, len))
+            .ok_or(VfsError::BadFd)?
+            .map_err(vm_error)
     }
 
     /// List mounted filesystems
     pub fn list_mounts(&self) -> Vec<String> {
-        self.mounts.read().iter().map(|m| m.path.clone()).collect()
+        self.namespaces.mounts(self.current_ns()).iter().map(|m| m.path.clone()).collect()
+    }
+
+    /// Mount namespaces
+    pub fn namespaces(&self) -> &MountNamespaces {
+        &self.namespaces
+    }
+
+    /// Namespace of a process
+    pub fn mount_namespace(&self, pid: u64) -> MountNsId {
+        self.namespaces.of(pid)
+    }
+
+    /// Check that `pid` may change mounts: `token` must be owned by `pid`,
+    /// allow `WRITE` and name a [`MOUNT_NS_RESOURCE`] resource, as for
+    /// [`unshare_mounts`](Self::unshare_mounts).
+    pub fn check_mount_cap(&self, pid: u64, caps: &CapabilityTable, token: CapabilityToken) -> Result<(), VfsError> {
+        Self::check_cap(caps, pid, token, MOUNT_NS_RESOURCE)
+    }
+
+    /// Move a process to a new mount namespace, a copy of its current
+    /// one (`unshare(CLONE_NEWNS)`). `token` must be owned by `pid`, allow
+    /// `WRITE` and name a [`MOUNT_NS_RESOURCE`] resource.
+    pub fn unshare_mounts(
+        &self,
+        pid: u64,
+        caps: &CapabilityTable,
+        token: CapabilityToken,
+    ) -> Result<MountNsId, VfsError> {
+        Self::check_cap(caps, pid, token, MOUNT_NS_RESOURCE)?;
+        let (ns, released) = self.namespaces.unshare(pid);
+        self.release_mounts(released)?;
+        Ok(ns)
+    }
+
+    /// Put a new process in its parent's mount namespace
+    pub fn inherit_namespace(&self, parent: u64, child: u64) {
+        self.namespaces.inherit(parent, child);
+    }
+
+    /// Take an exited process out of its mount namespace, unmounting the
+    /// namespace's mounts if it was the last process in it
+    pub fn leave_namespace(&self, pid: u64) {
+        let released = self.namespaces.leave(pid);
+        let _ = self.release_mounts(released);
+    }
+
+    /// Bind mount `source`, a file or directory, onto `target`, one of the
+    /// same type, in the namespace of `pid`. With `recursive`, the mounts
+    /// below `source` are bound below `target` too; with `read_only`, the
+    /// new mounts refuse changes.
+    pub fn bind_mount(
+        &self,
+        pid: u64,
+        source: &str,
+        target: &str,
+        read_only: bool,
+        recursive: bool,
+    ) -> Result<(), VfsError> {
+        let ns = self.namespaces.of(pid);
+        let (mount, ino) = self.resolve_in(ns, source)?;
+        let (target_mount, target_ino) = self.resolve_in(ns, target)?;
+        let is_dir = mount.fs.getattr(ino)?.file_type == VfsFileType::Directory;
+        if is_dir != (target_mount.fs.getattr(target_ino)?.file_type == VfsFileType::Directory) {
+            return Err(VfsError::NotADirectory);
+        }
+
+        // Mounts below the source, found before the bind can add to them
+        let below: Vec<Arc<MountPoint>> = if recursive && is_dir {
+            self.namespaces
+                .mounts(ns)
+                .into_iter()
+                .filter(|m| m.path != source && namespace::relative(&m.path, source).is_some())
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        let (_, rel) = self.namespaces.find(ns, source).ok_or(VfsError::NotFound)?;
+        self.namespaces.add(ns, MountPoint {
+            path: String::from(target),
+            root_ino: ino,
+            root_path: namespace::join(&mount.root_path, &rel),
+            read_only: read_only || mount.read_only,
+            ..MountPoint::clone(&mount)
+        })?;
+        // Shortest paths first, so each lands on the one above it
+        for sub in below.iter().rev() {
+            let rel = namespace::relative(&sub.path, source).ok_or(VfsError::InvalidArgument)?;
+            self.namespaces.add(ns, MountPoint {
+                path: namespace::join(target, &rel),
+                read_only: read_only || sub.read_only,
+                ..MountPoint::clone(sub)
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Change the propagation of the mount at `path` in the namespace of
+    /// `pid` and, with `recursive`, of the mounts below it
+    pub fn set_propagation(&self, pid: u64, path: &str, propagation: Propagation, recursive: bool) -> Result<(), VfsError> {
+        self.namespaces.set_propagation(self.namespaces.of(pid), path, propagation, recursive)
+    }
+
+    /// Make the mount at `new_root` the root of the namespace of `pid`,
+    /// moving the old root to `put_old`, a directory below `new_root`
+    /// (`pivot_root`). Only the namespace of `pid` changes; call it after
+    /// [`Vfs::unshare_mounts`]. `token` is as for [`Vfs::unshare_mounts`].
+    pub fn pivot_root(
+        &self,
+        pid: u64,
+        new_root: &str,
+        put_old: &str,
+        caps: &CapabilityTable,
+        token: CapabilityToken,
+    ) -> Result<(), VfsError> {
+        Self::check_cap(caps, pid, token, MOUNT_NS_RESOURCE)?;
+        let ns = self.namespaces.of(pid);
+        if self.namespaces.mount_at(ns, new_root).is_none() {
+            return Err(VfsError::InvalidArgument);
+        }
+        let (mount, ino) = self.resolve_in(ns, put_old)?;
+        if mount.fs.getattr(ino)?.file_type != VfsFileType::Directory {
+            return Err(VfsError::NotADirectory);
+        }
+        self.namespaces.pivot_root(ns, new_root, put_old)
+    }
+}
+
+/// File mapping request
+#[derive(Debug, Clone, Copy)]
+pub struct MmapRequest {
+    /// Fixed address, or None to pick a free range
+    pub addr: Option<u64>,
+    /// Length in bytes (rounded up to whole pages)
+    pub len: u64,
+    /// File offset (page-aligned)
+    pub offset: u64,
+    /// Allow writes
+    pub writable: bool,
+    /// Allow instruction fetches
+    pub executable: bool,
+    /// MAP_SHARED (writes reach the file) rather than MAP_PRIVATE
+    pub shared: bool,
+}
+
+fn vm_error(e: VmError) -> VfsError {
+    match e {
+        VmError::OutOfMemory => VfsError::NoSpace,
+        VmError::InvalidRange | VmError::Overlap => VfsError::InvalidArgument,
+        VmError::PagerError => VfsError::IoError,
+        VmError::NoAddressSpace => VfsError::BadFd,
+        _ => VfsError::PermissionDenied,
     }
 }
 
@@ -760,3 +1584,18 @@ pub fn init() {
     // VFS is initialized via Vfs::new() (const fn)
     // Actual mounting of root filesystem happens in fs::init()
 }
+
+/// Close every file of an exiting process and release its locks
+pub fn close_all_fds(pid: ProcessId) {
+    VFS.close_all_fds(pid.0);
+}
+
+/// Put a new process in its parent's mount namespace
+pub fn inherit_namespace(parent: ProcessId, child: ProcessId) {
+    VFS.inherit_namespace(parent.0, child.0);
+}
+
+/// Take an exited process out of its mount namespace
+pub fn leave_namespace(pid: ProcessId) {
+    VFS.leave_namespace(pid.0);
+}
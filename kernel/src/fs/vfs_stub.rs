@@ -47,7 +47,9 @@ use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
 use spin::{Mutex, Once};
 
-use crate::cap::CapabilityToken;
+use super::lock::{LockInfo, LockRequest, LockType};
+use super::xattr::{self, CAPS_XATTR};
+use crate::cap::{CapabilityTable, CapabilityToken};
 use crate::ipc::{ChannelId, IpcError, Message, MessageData, IPC_MANAGER};
 use crate::ipc::fastpath::{FastEndpoint, FastMessage, IPC_STATS, tags};
 use crate::sched::ProcessId;
@@ -128,6 +130,9 @@ pub enum VfsError {
     IoError,
     NotSupported,
     PathTooLong,
+    NoAttribute,
+    WouldBlock,
+    Deadlock,
     IpcError,
     Timeout,
 }
@@ -203,6 +208,10 @@ enum RequestType {
     Rmdir = 11,
     Unlink = 12,
     Seek = 18,
+    Getxattr = 20,
+    Setxattr = 21,
+    Listxattr = 22,
+    Removexattr = 23,
 }
 
 #[repr(u32)]
@@ -216,6 +225,7 @@ enum ResponseType {
     Attr = 105,
     DirEntries = 106,
     Position = 108,
+    XattrNames = 110,
 }
 
 // ============================================================================
@@ -513,6 +523,159 @@ pub fn unlink(path: &str) -> Result<(), VfsError> {
     parse_ok_response(&response)
 }
 
+/// Get an extended attribute
+pub fn getxattr(path: &str, name: &str) -> Result<Vec<u8>, VfsError> {
+    if !is_initialized() {
+        return Err(VfsError::NotInitialized);
+    }
+
+    let request_id = REQUEST_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let request = build_xattr_request(20, request_id, path, Some(name), None);
+
+    let response = send_and_receive(request)?;
+    parse_data_response(&response)
+}
+
+/// Set an extended attribute
+pub fn setxattr(path: &str, name: &str, value: &[u8]) -> Result<(), VfsError> {
+    if !is_initialized() {
+        return Err(VfsError::NotInitialized);
+    }
+
+    let request_id = REQUEST_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let request = build_xattr_request(21, request_id, path, Some(name), Some(value));
+
+    let response = send_and_receive(request)?;
+    parse_ok_response(&response)
+}
+
+/// List extended attribute names
+pub fn listxattr(path: &str) -> Result<Vec<String>, VfsError> {
+    if !is_initialized() {
+        return Err(VfsError::NotInitialized);
+    }
+
+    let request_id = REQUEST_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let request = build_xattr_request(22, request_id, path, None, None);
+
+    let response = send_and_receive(request)?;
+    parse_xattr_names_response(&response)
+}
+
+/// Remove an extended attribute
+pub fn removexattr(path: &str, name: &str) -> Result<(), VfsError> {
+    if !is_initialized() {
+        return Err(VfsError::NotInitialized);
+    }
+
+    let request_id = REQUEST_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let request = build_xattr_request(23, request_id, path, Some(name), None);
+
+    let response = send_and_receive(request)?;
+    parse_ok_response(&response)
+}
+
+/// Watch a file or directory for `pid`, which needs the capabilities of
+/// the path's label (if any). S-STORAGE sends the events to `channel`;
+/// `mask` holds `notify::WatchMask` bits.
+pub fn add_watch(
+    path: &str,
+    mask: u32,
+    channel: ChannelId,
+    pid: ProcessId,
+    caps: &CapabilityTable,
+    tokens: &[CapabilityToken],
+) -> Result<u32, VfsError> {
+    let label = match getxattr(path, CAPS_XATTR) {
+        Ok(value) => xattr::parse_caps(&value).map_err(|_| VfsError::PermissionDenied)?,
+        Err(VfsError::NoAttribute | VfsError::NotSupported) => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    xattr::check_caps(&label, pid, caps, tokens).map_err(|_| VfsError::PermissionDenied)?;
+
+    let request_id = REQUEST_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let request = build_add_watch_request(request_id, path, mask, channel.0);
+
+    let response = send_and_receive(request)?;
+    parse_watch_response(&response)
+}
+
+/// Remove a watch
+pub fn remove_watch(wd: u32) -> Result<(), VfsError> {
+    if !is_initialized() {
+        return Err(VfsError::NotInitialized);
+    }
+
+    let request_id = REQUEST_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let request = build_remove_watch_request(request_id, wd);
+
+    let response = send_and_receive(request)?;
+    parse_ok_response(&response)
+}
+
+/// Take, change or release the `flock` lock of `fd` for `pid`. With
+/// `wait`, retries until the lock is granted.
+pub fn flock(fd: Fd, pid: ProcessId, lock_type: LockType, wait: bool) -> Result<(), VfsError> {
+    lock(fd, pid, &LockRequest::whole_file(lock_type), true, wait)
+}
+
+/// Take, change or release a byte-range lock of `pid` on the file of
+/// `fd`. With `wait`, retries until the lock is granted; S-STORAGE fails
+/// the request with `Deadlock` if the wait could never end.
+pub fn setlk(fd: Fd, pid: ProcessId, request: &LockRequest, wait: bool) -> Result<(), VfsError> {
+    lock(fd, pid, request, false, wait)
+}
+
+/// First byte-range lock that would prevent `request` by `pid`
+pub fn getlk(fd: Fd, pid: ProcessId, request: &LockRequest) -> Result<Option<LockInfo>, VfsError> {
+    let remote = remote_handle(fd)?;
+    let request_id = REQUEST_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let request = build_lock_request(27, request_id, remote, pid, request, 0);
+
+    let response = send_and_receive(request)?;
+    parse_lock_response(&response)
+}
+
+/// Release every lock of an exited process
+pub fn release_locks(pid: ProcessId) -> Result<(), VfsError> {
+    if !is_initialized() {
+        return Err(VfsError::NotInitialized);
+    }
+
+    let request_id = REQUEST_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let mut request = Vec::new();
+    request.extend_from_slice(&28u32.to_le_bytes()); // ReleaseLocks = 28
+    request.extend_from_slice(&request_id.to_le_bytes());
+    request.extend_from_slice(&pid.0.to_le_bytes());
+
+    let response = send_and_receive(request)?;
+    parse_ok_response(&response)
+}
+
+fn lock(fd: Fd, pid: ProcessId, request: &LockRequest, flock: bool, wait: bool) -> Result<(), VfsError> {
+    let remote = remote_handle(fd)?;
+    let flags = u8::from(request.mandatory) | u8::from(flock) << 1 | u8::from(wait) << 2;
+    loop {
+        let request_id = REQUEST_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let message = build_lock_request(26, request_id, remote, pid, request, flags);
+
+        let response = send_and_receive(message)?;
+        match parse_ok_response(&response) {
+            // S-STORAGE recorded the wait; ask again once holders had a
+            // chance to release
+            Err(VfsError::WouldBlock) if wait => core::hint::spin_loop(),
+            result => return result,
+        }
+    }
+}
+
+fn remote_handle(fd: Fd) -> Result<u64, VfsError> {
+    if !is_initialized() {
+        return Err(VfsError::NotInitialized);
+    }
+    FD_TABLE.lock().get(&fd).map(|mapping| mapping.remote.0).ok_or(VfsError::BadFd)
+}
+
 // ============================================================================
 // Statistics and Diagnostics
 // ============================================================================
@@ -908,6 +1071,60 @@ fn build_unlink_request(request_id: u64, path: &str) -> Vec<u8> {
     buf
 }
 
+fn build_xattr_request(kind: u32, request_id: u64, path: &str, name: Option<&str>, value: Option<&[u8]>) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&kind.to_le_bytes()); // Getxattr..Removexattr = 20..23
+    buf.extend_from_slice(&request_id.to_le_bytes());
+    buf.extend_from_slice(&(path.len() as u32).to_le_bytes());
+    buf.extend_from_slice(path.as_bytes());
+    if let Some(name) = name {
+        buf.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        buf.extend_from_slice(name.as_bytes());
+    }
+    if let Some(value) = value {
+        buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        buf.extend_from_slice(value);
+    }
+    buf
+}
+
+fn build_add_watch_request(request_id: u64, path: &str, mask: u32, channel: u64) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&24u32.to_le_bytes()); // AddWatch = 24
+    buf.extend_from_slice(&request_id.to_le_bytes());
+    buf.extend_from_slice(&(path.len() as u32).to_le_bytes());
+    buf.extend_from_slice(path.as_bytes());
+    buf.extend_from_slice(&mask.to_le_bytes());
+    buf.extend_from_slice(&channel.to_le_bytes());
+    buf
+}
+
+fn build_remove_watch_request(request_id: u64, wd: u32) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&25u32.to_le_bytes()); // RemoveWatch = 25
+    buf.extend_from_slice(&request_id.to_le_bytes());
+    buf.extend_from_slice(&wd.to_le_bytes());
+    buf
+}
+
+fn build_lock_request(kind: u32, request_id: u64, handle: u64, pid: ProcessId, lock: &LockRequest, flags: u8) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&kind.to_le_bytes()); // Lock = 26, GetLock = 27
+    buf.extend_from_slice(&request_id.to_le_bytes());
+    buf.extend_from_slice(&handle.to_le_bytes());
+    buf.extend_from_slice(&pid.0.to_le_bytes());
+    buf.push(match lock.lock_type {
+        LockType::Shared => 0,
+        LockType::Exclusive => 1,
+        LockType::Unlock => 2,
+    });
+    buf.extend_from_slice(&lock.start.to_le_bytes());
+    buf.extend_from_slice(&lock.len.to_le_bytes());
+    // Bit 0: mandatory, bit 1: flock, bit 2: wait
+    buf.push(flags);
+    buf
+}
+
 // Response parsers
 
 fn parse_ok_response(response: &[u8]) -> Result<(), VfsError> {
@@ -1123,6 +1340,98 @@ fn parse_direntries_response(response: &[u8]) -> Result<Vec<DirEntry>, VfsError>
     }
 }
 
+fn parse_xattr_names_response(response: &[u8]) -> Result<Vec<String>, VfsError> {
+    if response.len() < 16 {
+        return Err(VfsError::IoError);
+    }
+
+    let response_type = u32::from_le_bytes(response[0..4].try_into().unwrap());
+
+    match response_type {
+        110 => {
+            let count = u32::from_le_bytes(response[12..16].try_into().unwrap()) as usize;
+            let mut names = Vec::with_capacity(count);
+            let mut offset = 16;
+            for _ in 0..count {
+                if offset + 4 > response.len() {
+                    return Err(VfsError::IoError);
+                }
+                let len = u32::from_le_bytes(response[offset..offset + 4].try_into().unwrap()) as usize;
+                offset += 4;
+                let name = response.get(offset..offset + len).ok_or(VfsError::IoError)?;
+                names.push(String::from_utf8_lossy(name).to_string());
+                offset += len;
+            }
+            Ok(names)
+        }
+        101 => {
+            let error_code = u32::from_le_bytes(response[12..16].try_into().unwrap_or([0; 4]));
+            Err(error_code_to_vfs_error(error_code))
+        }
+        _ => Err(VfsError::IoError),
+    }
+}
+
+fn parse_watch_response(response: &[u8]) -> Result<u32, VfsError> {
+    if response.len() < 16 {
+        return Err(VfsError::IoError);
+    }
+    
+    let response_type = u32::from_le_bytes(response[0..4].try_into().unwrap());
+    
+    match response_type {
+        111 => {
+            // Watch response
+            let wd = u32::from_le_bytes(response[12..16].try_into().unwrap());
+            Ok(wd)
+        }
+        101 => {
+            let error_code = u32::from_le_bytes(response[12..16].try_into().unwrap_or([0; 4]));
+            Err(error_code_to_vfs_error(error_code))
+        }
+        _ => Err(VfsError::IoError),
+    }
+}
+
+fn parse_lock_response(response: &[u8]) -> Result<Option<LockInfo>, VfsError> {
+    if response.len() < 13 {
+        return Err(VfsError::IoError);
+    }
+    
+    let response_type = u32::from_le_bytes(response[0..4].try_into().unwrap());
+    
+    match response_type {
+        112 => {
+            // Lock response: present flag, then the holder
+            if response[12] == 0 {
+                return Ok(None);
+            }
+            if response.len() < 39 {
+                return Err(VfsError::IoError);
+            }
+            let lock_type = match response[13] {
+                0 => LockType::Shared,
+                1 => LockType::Exclusive,
+                _ => return Err(VfsError::IoError),
+            };
+            let flags = response[38];
+            Ok(Some(LockInfo {
+                lock_type,
+                start: u64::from_le_bytes(response[14..22].try_into().unwrap()),
+                len: u64::from_le_bytes(response[22..30].try_into().unwrap()),
+                pid: u64::from_le_bytes(response[30..38].try_into().unwrap()),
+                mandatory: flags & 1 != 0,
+                flock: flags & 2 != 0,
+            }))
+        }
+        101 => {
+            let error_code = response.get(12..16).map_or(0, |b| u32::from_le_bytes(b.try_into().unwrap()));
+            Err(error_code_to_vfs_error(error_code))
+        }
+        _ => Err(VfsError::IoError),
+    }
+}
+
 fn error_code_to_vfs_error(code: u32) -> VfsError {
     match code {
         1 => VfsError::NotFound,
@@ -1139,6 +1448,9 @@ fn error_code_to_vfs_error(code: u32) -> VfsError {
         12 => VfsError::IoError,
         13 => VfsError::NotSupported,
         14 => VfsError::PathTooLong,
+        20 => VfsError::NoAttribute,
+        21 => VfsError::WouldBlock,
+        22 => VfsError::Deadlock,
         _ => VfsError::IoError,
     }
 }
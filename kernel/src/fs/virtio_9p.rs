@@ -0,0 +1,1652 @@
+//! # VirtIO 9P Filesystem
+//!
+//! Shares a host directory with the guest over virtio-9p, speaking
+//! 9P2000.L as QEMU's `-virtfs` serves it. Each virtio-9p device carries a
+//! mount tag chosen on the host; `mount -t 9p <tag> <path>` attaches to it.
+//!
+//! ## Design
+//!
+//! ```text
+//!   VFS ──► P9Fs ──► Session (fids, 9P2000.L) ──► Transport
+//!                                                     │
+//!                                       VirtioP9Device (legacy PIO, 1 queue)
+//! ```
+//!
+//! - Inode numbers are the server's qid paths, which QEMU derives from the
+//!   host inode, so hard links share one.
+//! - Every inode in use has a walked fid. Entries seen through readdir are
+//!   walked lazily, by name from their parent. File I/O goes through a
+//!   second, opened fid per inode, kept until the file is removed or the
+//!   open fid cache is full.
+//! - Requests are serialised: one at a time on the device's only queue.
+//! - The host may change files at any time. Opening a file compares its
+//!   size and modification time with those seen at the last open, and the
+//!   VFS drops its cached pages if they differ (close-to-open consistency,
+//!   as NFS does).
+//!
+//! ## Limitations
+//!
+//! - No extended attributes, locks or device nodes
+//! - Files are created with mode 0644 and directories 0755, owned by the
+//!   server's user; with `security_model=none` the host applies its own
+//!   ownership
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::{Mutex, RwLock};
+
+use crate::block::virtio_blk::{device_status, Virtqueue, VirtqAvail, VirtqDesc, VirtqUsed};
+use crate::fs::vfs::{
+    Filesystem, InodeNum, VfsAttr, VfsDirEntry, VfsError, VfsFileType, VfsPermissions, VfsStatFs, VFS,
+};
+
+/// VirtIO vendor ID
+const VIRTIO_VENDOR_ID: u16 = 0x1AF4;
+
+/// VirtIO 9P device ID (transitional)
+pub const VIRTIO_9P_DEVICE_ID_TRANSITIONAL: u16 = 0x1009;
+
+/// VirtIO 9P device ID (modern)
+pub const VIRTIO_9P_DEVICE_ID: u16 = 0x1049;
+
+/// The device has a mount tag in its configuration space
+const VIRTIO_9P_F_MOUNT_TAG: u32 = 1 << 0;
+
+/// Legacy VirtIO PIO register offsets
+mod regs {
+    pub const DEVICE_FEATURES: u16 = 0x00;
+    pub const GUEST_FEATURES: u16 = 0x04;
+    pub const QUEUE_ADDRESS: u16 = 0x08;
+    pub const QUEUE_SIZE: u16 = 0x0C;
+    pub const QUEUE_SELECT: u16 = 0x0E;
+    pub const QUEUE_NOTIFY: u16 = 0x10;
+    pub const DEVICE_STATUS: u16 = 0x12;
+    // 9P device config starts at offset 0x14
+    pub const CONFIG_TAG_LEN: u16 = 0x14; // 2 bytes (u16)
+    pub const CONFIG_TAG: u16 = 0x16; // tag_len bytes
+}
+
+/// Descriptor flags
+mod desc_flags {
+    pub const NEXT: u16 = 1;
+    pub const WRITE: u16 = 2;
+}
+
+/// Queue size: the ring layout shared with `virtio_blk`, and what QEMU
+/// gives its 9P request queue
+const QUEUE_SIZE: usize = 128;
+
+/// Page size for alignment
+const PAGE_SIZE: usize = 4096;
+
+/// Polls of the used ring before a request is abandoned
+const REQUEST_TIMEOUT: u32 = 50_000_000;
+
+/// Largest message proposed to the server
+const MAX_MESSAGE: usize = 64 * 1024;
+
+/// Smallest message size worth mounting with
+const MIN_MESSAGE: u32 = 4096;
+
+/// Protocol version
+const VERSION: &str = "9P2000.L";
+
+/// Message types (T = request, R = reply)
+const RLERROR: u8 = 7;
+const TSTATFS: u8 = 8;
+const RSTATFS: u8 = 9;
+const TLOPEN: u8 = 12;
+const RLOPEN: u8 = 13;
+const TLCREATE: u8 = 14;
+const RLCREATE: u8 = 15;
+const TSYMLINK: u8 = 16;
+const RSYMLINK: u8 = 17;
+const TREADLINK: u8 = 22;
+const RREADLINK: u8 = 23;
+const TGETATTR: u8 = 24;
+const RGETATTR: u8 = 25;
+const TSETATTR: u8 = 26;
+const RSETATTR: u8 = 27;
+const TREADDIR: u8 = 40;
+const RREADDIR: u8 = 41;
+const TFSYNC: u8 = 50;
+const RFSYNC: u8 = 51;
+const TLINK: u8 = 70;
+const RLINK: u8 = 71;
+const TMKDIR: u8 = 72;
+const RMKDIR: u8 = 73;
+const TRENAMEAT: u8 = 74;
+const RRENAMEAT: u8 = 75;
+const TUNLINKAT: u8 = 76;
+const RUNLINKAT: u8 = 77;
+const TVERSION: u8 = 100;
+const RVERSION: u8 = 101;
+const TATTACH: u8 = 104;
+const RATTACH: u8 = 105;
+const TWALK: u8 = 110;
+const RWALK: u8 = 111;
+const TREAD: u8 = 116;
+const RREAD: u8 = 117;
+const TWRITE: u8 = 118;
+const RWRITE: u8 = 119;
+const TCLUNK: u8 = 120;
+const RCLUNK: u8 = 121;
+
+/// size[4] type[1] tag[2]
+const HEADER_SIZE: usize = 7;
+
+/// Header of Rread and Twrite, ahead of their data
+const IO_HEADER_SIZE: usize = 24;
+
+/// Header of Rreaddir, ahead of its entries
+const READDIR_HEADER_SIZE: usize = 11;
+
+/// Tag of every request; one is outstanding at a time
+const TAG: u16 = 1;
+
+/// Tag of Tversion
+const NOTAG: u16 = 0xFFFF;
+
+/// No fid (no authentication on attach)
+const NOFID: u32 = 0xFFFF_FFFF;
+
+/// Linux open flags
+const O_RDONLY: u32 = 0;
+const O_RDWR: u32 = 2;
+const O_CREAT: u32 = 0o100;
+const O_EXCL: u32 = 0o200;
+const O_DIRECTORY: u32 = 0o200000;
+
+/// `unlinkat` flag removing a directory
+const AT_REMOVEDIR: u32 = 0x200;
+
+/// Tgetattr mask: the fields of `stat`
+const GETATTR_BASIC: u64 = 0x7FF;
+
+/// Tsetattr field: size
+const SETATTR_SIZE: u32 = 1 << 3;
+
+/// File type bits of a POSIX mode.
+const S_IFMT: u32 = 0o170000;
+
+/// Modes of created files and directories
+const FILE_MODE: u32 = 0o644;
+const DIR_MODE: u32 = 0o755;
+
+/// Longest name.
+const NAME_MAX: usize = 255;
+
+/// Opened fids kept before the oldest is clunked
+const MAX_OPEN_FIDS: usize = 64;
+
+/// Carries 9P messages to a server and back
+pub trait Transport: Send + Sync {
+    /// Largest message, request or reply, the transport carries
+    fn max_message(&self) -> usize;
+
+    /// Sends a request and waits for its reply
+    fn request(&self, message: &[u8]) -> Result<Vec<u8>, VfsError>;
+}
+
+// ============================================================================
+// Messages
+// ============================================================================
+
+/// A request being built, in 9P's little-endian wire format.
+struct Message(Vec<u8>);
+
+impl Message {
+    fn new(kind: u8) -> Self {
+        let mut data = vec![0u8; 4];
+        data.push(kind);
+        data.extend_from_slice(&TAG.to_le_bytes());
+        Self(data)
+    }
+
+    /// Tversion is sent without a tag.
+    fn notag(mut self) -> Self {
+        self.0[5..7].copy_from_slice(&NOTAG.to_le_bytes());
+        self
+    }
+
+    fn u16(mut self, value: u16) -> Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u32(mut self, value: u32) -> Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u64(mut self, value: u64) -> Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn str(self, value: &str) -> Self {
+        let mut message = self.u16(value.len() as u16);
+        message.0.extend_from_slice(value.as_bytes());
+        message
+    }
+
+    fn bytes(mut self, value: &[u8]) -> Self {
+        self.0.extend_from_slice(value);
+        self
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let size = self.0.len() as u32;
+        self.0[..4].copy_from_slice(&size.to_le_bytes());
+        self.0
+    }
+}
+
+/// Server-side identity of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Qid {
+    kind: u8,
+    version: u32,
+    path: u64,
+}
+
+/// Reads the fields of a reply.
+struct Reader<'a> {
+    data: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, at: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], VfsError> {
+        let bytes = self.data.get(self.at..self.at + len).ok_or(VfsError::IoError)?;
+        self.at += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, VfsError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, VfsError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, VfsError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, VfsError> {
+        Ok(self.u32()? as u64 | (self.u32()? as u64) << 32)
+    }
+
+    fn str(&mut self) -> Result<String, VfsError> {
+        let len = self.u16()? as usize;
+        Ok(String::from_utf8_lossy(self.take(len)?).into())
+    }
+
+    fn qid(&mut self) -> Result<Qid, VfsError> {
+        Ok(Qid { kind: self.u8()?, version: self.u32()?, path: self.u64()? })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.at >= self.data.len()
+    }
+}
+
+/// Attributes from Rgetattr.
+#[derive(Debug, Clone, Copy)]
+struct Stat {
+    mode: u32,
+    nlink: u64,
+    size: u64,
+    blksize: u64,
+    blocks: u64,
+    atime: u64,
+    mtime: (u64, u64),
+    ctime: u64,
+    btime: u64,
+}
+
+impl Stat {
+    fn parse(reply: &[u8]) -> Result<Self, VfsError> {
+        let mut r = Reader::new(reply);
+        let _valid = r.u64()?;
+        let _qid = r.qid()?;
+        let mode = r.u32()?;
+        let _uid = r.u32()?;
+        let _gid = r.u32()?;
+        let nlink = r.u64()?;
+        let _rdev = r.u64()?;
+        let size = r.u64()?;
+        let blksize = r.u64()?;
+        let blocks = r.u64()?;
+        let atime = r.u64()?;
+        let _atime_nsec = r.u64()?;
+        let mtime = (r.u64()?, r.u64()?);
+        let ctime = r.u64()?;
+        let _ctime_nsec = r.u64()?;
+        let btime = r.u64()?;
+        Ok(Self { mode, nlink, size, blksize, blocks, atime, mtime, ctime, btime })
+    }
+
+    fn file_type(&self) -> VfsFileType {
+        match self.mode & S_IFMT {
+            0o040000 => VfsFileType::Directory,
+            0o120000 => VfsFileType::Symlink,
+            0o020000 => VfsFileType::CharDevice,
+            0o060000 => VfsFileType::BlockDevice,
+            0o010000 => VfsFileType::Fifo,
+            0o140000 => VfsFileType::Socket,
+            _ => VfsFileType::Regular,
+        }
+    }
+}
+
+/// File type of a readdir entry (`d_type`).
+fn dirent_type(d_type: u8) -> VfsFileType {
+    match d_type {
+        1 => VfsFileType::Fifo,
+        2 => VfsFileType::CharDevice,
+        4 => VfsFileType::Directory,
+        6 => VfsFileType::BlockDevice,
+        10 => VfsFileType::Symlink,
+        12 => VfsFileType::Socket,
+        _ => VfsFileType::Regular,
+    }
+}
+
+// ============================================================================
+// Session
+// ============================================================================
+
+/// An opened fid.
+#[derive(Debug, Clone, Copy)]
+struct OpenFid {
+    fid: u32,
+    writable: bool,
+    /// Largest transfer the server accepts in one message (0: msize)
+    iounit: u32,
+}
+
+/// A session attached to the server's root.
+struct Session {
+    transport: Arc<dyn Transport>,
+    /// Negotiated message size
+    msize: u32,
+    /// Inode of the root directory
+    root_ino: InodeNum,
+    next_fid: u32,
+    free_fids: Vec<u32>,
+    /// Walked fid of each inode in use
+    fids: BTreeMap<InodeNum, u32>,
+    /// Parent and name of each inode seen, to walk it again
+    names: BTreeMap<InodeNum, (InodeNum, String)>,
+    /// Opened fid of each inode read or written
+    open: BTreeMap<InodeNum, OpenFid>,
+    /// Size and modification time of each inode at its last open
+    versions: BTreeMap<InodeNum, (u64, (u64, u64))>,
+}
+
+impl Session {
+    /// Negotiates the protocol and attaches to the server's root.
+    fn attach(transport: Arc<dyn Transport>, aname: &str) -> Result<Self, VfsError> {
+        let proposed = transport.max_message().min(MAX_MESSAGE) as u32;
+        let mut session = Self {
+            transport,
+            msize: proposed,
+            root_ino: 0,
+            next_fid: 0,
+            free_fids: Vec::new(),
+            fids: BTreeMap::new(),
+            names: BTreeMap::new(),
+            open: BTreeMap::new(),
+            versions: BTreeMap::new(),
+        };
+
+        // Tversion also resets the server's session, clunking fids left
+        // over from an earlier mount
+        let reply = session.call(Message::new(TVERSION).notag().u32(proposed).str(VERSION).finish(), RVERSION)?;
+        let mut r = Reader::new(&reply);
+        let msize = r.u32()?;
+        let version = r.str()?;
+        if version != VERSION || msize < MIN_MESSAGE {
+            crate::serial_println!("[9p] Server offers {} with msize {}", version, msize);
+            return Err(VfsError::NotSupported);
+        }
+        session.msize = msize.min(proposed);
+
+        let fid = session.alloc_fid();
+        let message = Message::new(TATTACH).u32(fid).u32(NOFID).str("root").str(aname).u32(0).finish();
+        let qid = Reader::new(&session.call(message, RATTACH)?).qid()?;
+        session.root_ino = qid.path;
+        session.fids.insert(qid.path, fid);
+        Ok(session)
+    }
+
+    /// Sends a request and returns the body of its reply.
+    fn call(&self, message: Vec<u8>, reply_type: u8) -> Result<Vec<u8>, VfsError> {
+        let reply = self.transport.request(&message)?;
+        let mut r = Reader::new(&reply);
+        let size = r.u32()? as usize;
+        let kind = r.u8()?;
+        let body = reply.get(HEADER_SIZE..size).ok_or(VfsError::IoError)?;
+        match kind {
+            RLERROR => Err(VfsError::from_errno(Reader::new(body).u32()?)),
+            kind if kind == reply_type => Ok(body.to_vec()),
+            _ => Err(VfsError::IoError),
+        }
+    }
+
+    fn alloc_fid(&mut self) -> u32 {
+        self.free_fids.pop().unwrap_or_else(|| {
+            self.next_fid += 1;
+            self.next_fid - 1
+        })
+    }
+
+    /// Releases a fid; the server forgets it even if the clunk fails.
+    fn clunk(&mut self, fid: u32) {
+        let _ = self.call(Message::new(TCLUNK).u32(fid).finish(), RCLUNK);
+        self.free_fids.push(fid);
+    }
+
+    /// Walks `from` to a new fid, by one name or none (a clone).
+    fn walk(&mut self, from: u32, name: Option<&str>) -> Result<(u32, Option<Qid>), VfsError> {
+        let fid = self.alloc_fid();
+        let message = Message::new(TWALK).u32(from).u32(fid);
+        let message = match name {
+            Some(name) => message.u16(1).str(name),
+            None => message.u16(0),
+        };
+        let reply = match self.call(message.finish(), RWALK) {
+            Ok(reply) => reply,
+            Err(e) => {
+                self.free_fids.push(fid);
+                return Err(e);
+            }
+        };
+        let mut r = Reader::new(&reply);
+        let walked = r.u16()?;
+        if name.is_some() && walked != 1 {
+            // A partial walk creates no fid
+            self.free_fids.push(fid);
+            return Err(VfsError::NotFound);
+        }
+        let qid = if walked == 1 { Some(r.qid()?) } else { None };
+        Ok((fid, qid))
+    }
+
+    /// The walked fid of an inode, walking it from its parent if needed.
+    fn fid(&mut self, ino: InodeNum) -> Result<u32, VfsError> {
+        if let Some(&fid) = self.fids.get(&ino) {
+            return Ok(fid);
+        }
+        let (parent, name) = self.names.get(&ino).cloned().ok_or(VfsError::NotFound)?;
+        let parent_fid = self.fid(parent)?;
+        let (fid, qid) = self.walk(parent_fid, Some(&name))?;
+        if qid.map(|qid| qid.path) != Some(ino) {
+            // Replaced on the host since it was listed
+            self.clunk(fid);
+            return Err(VfsError::NotFound);
+        }
+        self.fids.insert(ino, fid);
+        Ok(fid)
+    }
+
+    /// An opened fid of an inode, for reading or for reading and writing.
+    fn open_fid(&mut self, ino: InodeNum, write: bool) -> Result<OpenFid, VfsError> {
+        if let Some(open) = self.open.get(&ino) {
+            if open.writable || !write {
+                return Ok(*open);
+            }
+        }
+        if let Some(old) = self.open.remove(&ino) {
+            self.clunk(old.fid);
+        }
+        if self.open.len() >= MAX_OPEN_FIDS {
+            if let Some((_, old)) = self.open.pop_first() {
+                self.clunk(old.fid);
+            }
+        }
+
+        let base = self.fid(ino)?;
+        let (fid, _) = self.walk(base, None)?;
+        let flags = if write { O_RDWR } else { O_RDONLY };
+        let reply = match self.call(Message::new(TLOPEN).u32(fid).u32(flags).finish(), RLOPEN) {
+            Ok(reply) => reply,
+            Err(e) => {
+                self.clunk(fid);
+                return Err(e);
+            }
+        };
+        let mut r = Reader::new(&reply);
+        r.qid()?;
+        let open = OpenFid { fid, writable: write, iounit: r.u32()? };
+        self.open.insert(ino, open);
+        Ok(open)
+    }
+
+    /// Bytes moved by one Tread or Twrite.
+    fn io_size(&self, iounit: u32) -> usize {
+        let max = self.msize as usize - IO_HEADER_SIZE;
+        match iounit as usize {
+            0 => max,
+            iounit => iounit.min(max),
+        }
+    }
+
+    /// Drops everything known about an inode.
+    fn forget(&mut self, ino: InodeNum) {
+        self.names.remove(&ino);
+        self.versions.remove(&ino);
+        if let Some(fid) = self.fids.remove(&ino) {
+            self.clunk(fid);
+        }
+        if let Some(open) = self.open.remove(&ino) {
+            self.clunk(open.fid);
+        }
+    }
+
+    /// Inodes known by a name in a directory.
+    fn named(&self, parent: InodeNum, name: &str) -> Vec<InodeNum> {
+        self.names
+            .iter()
+            .filter(|(_, (p, n))| *p == parent && n == name)
+            .map(|(&ino, _)| ino)
+            .collect()
+    }
+
+    /// Records a new directory entry.
+    fn remember(&mut self, parent: InodeNum, name: &str, ino: InodeNum) {
+        self.names.insert(ino, (parent, name.into()));
+    }
+
+    /// Clunks every fid, the root's included.
+    fn close(&mut self) {
+        let fids: Vec<u32> = self.open.values().map(|open| open.fid).chain(self.fids.values().copied()).collect();
+        for fid in fids {
+            self.clunk(fid);
+        }
+        self.open.clear();
+        self.fids.clear();
+    }
+
+    fn stat(&mut self, ino: InodeNum) -> Result<Stat, VfsError> {
+        let fid = self.fid(ino)?;
+        Stat::parse(&self.call(Message::new(TGETATTR).u32(fid).u64(GETATTR_BASIC).finish(), RGETATTR)?)
+    }
+
+    fn lookup(&mut self, parent: InodeNum, name: &str) -> Result<InodeNum, VfsError> {
+        if name.len() > NAME_MAX {
+            return Err(VfsError::PathTooLong);
+        }
+        let parent_fid = self.fid(parent)?;
+        let (fid, qid) = self.walk(parent_fid, Some(name))?;
+        let ino = qid.ok_or(VfsError::IoError)?.path;
+        self.remember(parent, name, ino);
+        if self.fids.contains_key(&ino) {
+            self.clunk(fid);
+        } else {
+            self.fids.insert(ino, fid);
+        }
+        Ok(ino)
+    }
+
+    fn getattr(&mut self, ino: InodeNum) -> Result<VfsAttr, VfsError> {
+        let stat = self.stat(ino)?;
+        let file_type = stat.file_type();
+        Ok(VfsAttr {
+            ino,
+            file_type,
+            perm: VfsPermissions {
+                readable: stat.mode & 0o400 != 0,
+                writable: stat.mode & 0o200 != 0,
+                executable: stat.mode & 0o100 != 0,
+            },
+            size: stat.size,
+            nlink: stat.nlink as u32,
+            blksize: stat.blksize as u32,
+            blocks: stat.blocks,
+            atime: stat.atime,
+            mtime: stat.mtime.0,
+            ctime: stat.ctime,
+            crtime: stat.btime,
+        })
+    }
+
+    fn readdir(&mut self, ino: InodeNum) -> Result<Vec<VfsDirEntry>, VfsError> {
+        let base = self.fid(ino)?;
+        let (fid, _) = self.walk(base, None)?;
+        let entries = self.read_entries(ino, fid);
+        self.clunk(fid);
+        entries
+    }
+
+    /// Opens a clone of a directory's fid and reads all its entries.
+    fn read_entries(&mut self, ino: InodeNum, fid: u32) -> Result<Vec<VfsDirEntry>, VfsError> {
+        self.call(Message::new(TLOPEN).u32(fid).u32(O_RDONLY | O_DIRECTORY).finish(), RLOPEN)?;
+        let count = self.msize - READDIR_HEADER_SIZE as u32;
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        loop {
+            let reply = self.call(Message::new(TREADDIR).u32(fid).u64(offset).u32(count).finish(), RREADDIR)?;
+            let mut r = Reader::new(&reply);
+            let len = r.u32()? as usize;
+            if len == 0 {
+                break;
+            }
+            let mut r = Reader::new(r.take(len)?);
+            while !r.is_empty() {
+                let qid = r.qid()?;
+                offset = r.u64()?;
+                let file_type = dirent_type(r.u8()?);
+                let name = r.str()?;
+                if name == "." || name == ".." {
+                    continue;
+                }
+                self.remember(ino, &name, qid.path);
+                entries.push(VfsDirEntry { name, ino: qid.path, file_type });
+            }
+        }
+        Ok(entries)
+    }
+
+    fn read(&mut self, ino: InodeNum, offset: u64, size: usize) -> Result<Vec<u8>, VfsError> {
+        let open = self.open_fid(ino, false)?;
+        let chunk = self.io_size(open.iounit);
+        let mut data = Vec::new();
+        while data.len() < size {
+            let count = (size - data.len()).min(chunk);
+            let position = offset + data.len() as u64;
+            let reply = self.call(Message::new(TREAD).u32(open.fid).u64(position).u32(count as u32).finish(), RREAD)?;
+            let mut r = Reader::new(&reply);
+            let len = r.u32()? as usize;
+            data.extend_from_slice(r.take(len)?);
+            if len < count {
+                break;
+            }
+        }
+        Ok(data)
+    }
+
+    fn write(&mut self, ino: InodeNum, offset: u64, data: &[u8]) -> Result<usize, VfsError> {
+        let open = self.open_fid(ino, true)?;
+        let chunk = self.io_size(open.iounit);
+        let mut done = 0;
+        while done < data.len() {
+            let part = &data[done..data.len().min(done + chunk)];
+            let position = offset + done as u64;
+            let message = Message::new(TWRITE).u32(open.fid).u64(position).u32(part.len() as u32).bytes(part);
+            let written = Reader::new(&self.call(message.finish(), RWRITE)?).u32()? as usize;
+            if written == 0 {
+                return Err(VfsError::NoSpace);
+            }
+            done += written;
+        }
+        Ok(done)
+    }
+
+    fn create(&mut self, parent: InodeNum, name: &str, file_type: VfsFileType) -> Result<InodeNum, VfsError> {
+        if name.len() > NAME_MAX {
+            return Err(VfsError::PathTooLong);
+        }
+        let parent_fid = self.fid(parent)?;
+        let ino = match file_type {
+            VfsFileType::Regular => {
+                // The new fid stands for the created file, already open
+                let (fid, _) = self.walk(parent_fid, None)?;
+                let flags = O_RDWR | O_CREAT | O_EXCL;
+                let message = Message::new(TLCREATE).u32(fid).str(name).u32(flags).u32(FILE_MODE).u32(0);
+                let reply = match self.call(message.finish(), RLCREATE) {
+                    Ok(reply) => reply,
+                    Err(e) => {
+                        self.clunk(fid);
+                        return Err(e);
+                    }
+                };
+                let mut r = Reader::new(&reply);
+                let ino = r.qid()?.path;
+                let open = OpenFid { fid, writable: true, iounit: r.u32()? };
+                if let Some(old) = self.open.insert(ino, open) {
+                    self.clunk(old.fid);
+                }
+                ino
+            }
+            VfsFileType::Directory => {
+                let message = Message::new(TMKDIR).u32(parent_fid).str(name).u32(DIR_MODE).u32(0);
+                Reader::new(&self.call(message.finish(), RMKDIR)?).qid()?.path
+            }
+            _ => return Err(VfsError::NotSupported),
+        };
+        self.remember(parent, name, ino);
+        Ok(ino)
+    }
+
+    fn unlink(&mut self, parent: InodeNum, name: &str) -> Result<(), VfsError> {
+        let parent_fid = self.fid(parent)?;
+        let unlink = |flags| Message::new(TUNLINKAT).u32(parent_fid).str(name).u32(flags).finish();
+        match self.call(unlink(0), RUNLINKAT) {
+            Err(VfsError::IsADirectory) => self.call(unlink(AT_REMOVEDIR), RUNLINKAT)?,
+            result => result?,
+        };
+        for ino in self.named(parent, name) {
+            self.forget(ino);
+        }
+        Ok(())
+    }
+
+    fn rename(&mut self, old_parent: InodeNum, old_name: &str, new_parent: InodeNum, new_name: &str) -> Result<(), VfsError> {
+        let old_fid = self.fid(old_parent)?;
+        let new_fid = self.fid(new_parent)?;
+        let message = Message::new(TRENAMEAT).u32(old_fid).str(old_name).u32(new_fid).str(new_name);
+        self.call(message.finish(), RRENAMEAT)?;
+
+        let moved = self.named(old_parent, old_name);
+        for ino in self.named(new_parent, new_name) {
+            if !moved.contains(&ino) {
+                self.forget(ino);
+            }
+        }
+        for ino in moved {
+            self.remember(new_parent, new_name, ino);
+        }
+        Ok(())
+    }
+
+    fn truncate(&mut self, ino: InodeNum, size: u64) -> Result<(), VfsError> {
+        let fid = self.fid(ino)?;
+        let message = Message::new(TSETATTR)
+            .u32(fid)
+            .u32(SETATTR_SIZE)
+            .u32(0)
+            .u32(0)
+            .u32(0)
+            .u64(size)
+            .u64(0)
+            .u64(0)
+            .u64(0)
+            .u64(0);
+        self.call(message.finish(), RSETATTR)?;
+        Ok(())
+    }
+
+    fn symlink(&mut self, parent: InodeNum, name: &str, target: &str) -> Result<InodeNum, VfsError> {
+        let parent_fid = self.fid(parent)?;
+        let message = Message::new(TSYMLINK).u32(parent_fid).str(name).str(target).u32(0);
+        let ino = Reader::new(&self.call(message.finish(), RSYMLINK)?).qid()?.path;
+        self.remember(parent, name, ino);
+        Ok(ino)
+    }
+
+    fn readlink(&mut self, ino: InodeNum) -> Result<String, VfsError> {
+        let fid = self.fid(ino)?;
+        Reader::new(&self.call(Message::new(TREADLINK).u32(fid).finish(), RREADLINK)?).str()
+    }
+
+    fn link(&mut self, ino: InodeNum, new_parent: InodeNum, new_name: &str) -> Result<(), VfsError> {
+        let fid = self.fid(ino)?;
+        let dir_fid = self.fid(new_parent)?;
+        self.call(Message::new(TLINK).u32(dir_fid).u32(fid).str(new_name).finish(), RLINK)?;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<(), VfsError> {
+        let fids: Vec<u32> = self.open.values().filter(|open| open.writable).map(|open| open.fid).collect();
+        for fid in fids {
+            self.call(Message::new(TFSYNC).u32(fid).u32(0).finish(), RFSYNC)?;
+        }
+        Ok(())
+    }
+
+    fn statfs(&mut self) -> Result<VfsStatFs, VfsError> {
+        let fid = self.fid(self.root_ino)?;
+        let reply = self.call(Message::new(TSTATFS).u32(fid).finish(), RSTATFS)?;
+        let mut r = Reader::new(&reply);
+        let _fs_type = r.u32()?;
+        let bsize = r.u32()?;
+        let blocks = r.u64()?;
+        let bfree = r.u64()?;
+        let bavail = r.u64()?;
+        let files = r.u64()?;
+        let ffree = r.u64()?;
+        let _fsid = r.u64()?;
+        let namelen = r.u32()?;
+        Ok(VfsStatFs { blocks, bfree, bavail, files, ffree, bsize, namelen, bsaved: 0 })
+    }
+
+    /// Whether a file changed on the host since its last open.
+    fn revalidate(&mut self, ino: InodeNum) -> bool {
+        let Ok(stat) = self.stat(ino) else {
+            return false;
+        };
+        let version = (stat.size, stat.mtime);
+        self.versions.insert(ino, version).is_some_and(|old| old != version)
+    }
+}
+
+// ============================================================================
+// Filesystem
+// ============================================================================
+
+/// A host directory shared over 9P.
+pub struct P9Fs {
+    /// Mount tag of the device
+    tag: String,
+    /// Connection to the server
+    transport: Arc<dyn Transport>,
+    /// Attached session
+    session: Mutex<Option<Session>>,
+}
+
+impl P9Fs {
+    /// Creates a new 9P filesystem.
+    pub fn new(tag: &str, transport: Arc<dyn Transport>) -> Self {
+        Self { tag: tag.into(), transport, session: Mutex::new(None) }
+    }
+
+    /// Attaches to the server.
+    pub fn mount(&self) -> Result<(), VfsError> {
+        let session = Session::attach(self.transport.clone(), "")?;
+        crate::serial_println!("[9p] Attached to \"{}\" (msize {})", self.tag, session.msize);
+        *self.session.lock() = Some(session);
+        Ok(())
+    }
+
+    /// Releases every fid and forgets the session.
+    pub fn unmount(&self) {
+        if let Some(mut session) = self.session.lock().take() {
+            session.close();
+        }
+    }
+
+    /// Runs `f` on the attached session.
+    fn with<R>(&self, f: impl FnOnce(&mut Session) -> Result<R, VfsError>) -> Result<R, VfsError> {
+        let mut session = self.session.lock();
+        f(session.as_mut().ok_or(VfsError::NoFilesystem)?)
+    }
+
+    /// Resolves a path relative to the root directory.
+    fn resolve_path(&self, path: &str) -> Result<InodeNum, VfsError> {
+        let mut ino = self.root_ino();
+        for component in path.split('/').filter(|s| !s.is_empty()) {
+            ino = self.lookup(ino, component)?;
+        }
+        Ok(ino)
+    }
+}
+
+impl Filesystem for P9Fs {
+    fn name(&self) -> &'static str {
+        "9p"
+    }
+
+    fn root_ino(&self) -> InodeNum {
+        self.session.lock().as_ref().map_or(0, |session| session.root_ino)
+    }
+
+    fn lookup(&self, parent: InodeNum, name: &str) -> Result<InodeNum, VfsError> {
+        self.with(|session| session.lookup(parent, name))
+    }
+
+    fn getattr(&self, ino: InodeNum) -> Result<VfsAttr, VfsError> {
+        self.with(|session| session.getattr(ino))
+    }
+
+    fn readdir(&self, ino: InodeNum) -> Result<Vec<VfsDirEntry>, VfsError> {
+        self.with(|session| session.readdir(ino))
+    }
+
+    fn read(&self, ino: InodeNum, offset: u64, size: usize) -> Result<Vec<u8>, VfsError> {
+        self.with(|session| session.read(ino, offset, size))
+    }
+
+    fn write(&self, ino: InodeNum, offset: u64, data: &[u8]) -> Result<usize, VfsError> {
+        self.with(|session| session.write(ino, offset, data))
+    }
+
+    fn create(&self, parent: InodeNum, name: &str, file_type: VfsFileType) -> Result<InodeNum, VfsError> {
+        self.with(|session| session.create(parent, name, file_type))
+    }
+
+    fn unlink(&self, parent: InodeNum, name: &str) -> Result<(), VfsError> {
+        self.with(|session| session.unlink(parent, name))
+    }
+
+    fn rename(&self, old_parent: InodeNum, old_name: &str, new_parent: InodeNum, new_name: &str) -> Result<(), VfsError> {
+        self.with(|session| session.rename(old_parent, old_name, new_parent, new_name))
+    }
+
+    fn truncate(&self, ino: InodeNum, size: u64) -> Result<(), VfsError> {
+        self.with(|session| session.truncate(ino, size))
+    }
+
+    fn sync(&self) -> Result<(), VfsError> {
+        self.with(|session| session.sync())
+    }
+
+    fn statfs(&self) -> Result<VfsStatFs, VfsError> {
+        self.with(|session| session.statfs())
+    }
+
+    fn readlink(&self, ino: InodeNum) -> Result<String, VfsError> {
+        self.with(|session| session.readlink(ino))
+    }
+
+    fn symlink(&self, parent: InodeNum, name: &str, target: &str) -> Result<InodeNum, VfsError> {
+        self.with(|session| session.symlink(parent, name, target))
+    }
+
+    fn link(&self, ino: InodeNum, new_parent: InodeNum, new_name: &str) -> Result<(), VfsError> {
+        self.with(|session| session.link(ino, new_parent, new_name))
+    }
+
+    fn revalidate(&self, ino: InodeNum) -> bool {
+        self.with(|session| Ok(session.revalidate(ino))).unwrap_or(false)
+    }
+}
+
+// ============================================================================
+// VirtIO transport
+// ============================================================================
+
+/// The request queue and the buffer replies land in.
+struct Channel {
+    queue: Virtqueue,
+    reply: Vec<u8>,
+}
+
+/// VirtIO 9P device
+pub struct VirtioP9Device {
+    /// PCI I/O base port
+    io_base: u16,
+    /// Mount tag
+    tag: String,
+    /// Request virtqueue
+    channel: Mutex<Option<Channel>>,
+    /// Device ready flag
+    ready: AtomicBool,
+}
+
+impl VirtioP9Device {
+    /// Creates a new VirtIO 9P device
+    pub fn new(io_base: u16) -> Self {
+        Self { io_base, tag: String::new(), channel: Mutex::new(None), ready: AtomicBool::new(false) }
+    }
+
+    /// Mount tag chosen on the host
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    /// Initializes the device
+    pub fn init(&mut self) -> Result<(), VfsError> {
+        crate::serial_println!("[VIRTIO-9P] Initializing device at I/O base 0x{:04x}", self.io_base);
+
+        self.write_status(device_status::RESET);
+        self.write_status(device_status::ACKNOWLEDGE);
+        self.write_status(device_status::ACKNOWLEDGE | device_status::DRIVER);
+
+        let features = self.port_read_u32(regs::DEVICE_FEATURES);
+        if features & VIRTIO_9P_F_MOUNT_TAG == 0 {
+            crate::serial_println!("[VIRTIO-9P] Device has no mount tag");
+            self.write_status(device_status::FAILED);
+            return Err(VfsError::NotSupported);
+        }
+        self.port_write_u32(regs::GUEST_FEATURES, VIRTIO_9P_F_MOUNT_TAG);
+        self.write_status(device_status::ACKNOWLEDGE | device_status::DRIVER | device_status::FEATURES_OK);
+
+        // The tag is not NUL-terminated when it fills its field
+        let tag_len = self.port_read_u16(regs::CONFIG_TAG_LEN);
+        let tag: Vec<u8> = (0..tag_len).map(|i| self.port_read_u8(regs::CONFIG_TAG + i)).collect();
+        self.tag = String::from_utf8_lossy(&tag).trim_end_matches('\0').into();
+
+        self.setup_queue()?;
+
+        self.write_status(
+            device_status::ACKNOWLEDGE | device_status::DRIVER | device_status::FEATURES_OK | device_status::DRIVER_OK,
+        );
+        self.ready.store(true, Ordering::SeqCst);
+        crate::serial_println!("[VIRTIO-9P] Device ready, mount tag \"{}\"", self.tag);
+        Ok(())
+    }
+
+    /// Sets up the request virtqueue
+    fn setup_queue(&mut self) -> Result<(), VfsError> {
+        self.port_write_u16(regs::QUEUE_SELECT, 0);
+        let queue_size = self.port_read_u16(regs::QUEUE_SIZE) as usize;
+        if queue_size != QUEUE_SIZE {
+            crate::serial_println!("[VIRTIO-9P] Unsupported queue size {}", queue_size);
+            return Err(VfsError::NotSupported);
+        }
+
+        // Legacy layout: descriptors, then the available ring, then the
+        // used ring on the next page
+        let desc_size = core::mem::size_of::<VirtqDesc>() * QUEUE_SIZE;
+        let avail_size = 4 + 2 * QUEUE_SIZE + 2;
+        let used_size = 4 + 8 * QUEUE_SIZE + 2;
+        let avail_offset = desc_size;
+        let used_offset = (avail_offset + avail_size).next_multiple_of(PAGE_SIZE);
+        let total_size = used_offset + used_size;
+
+        // The queue lives as long as the device
+        let queue_mem = vec![0u8; total_size + PAGE_SIZE];
+        let queue_base = (queue_mem.as_ptr() as usize).next_multiple_of(PAGE_SIZE);
+        core::mem::forget(queue_mem);
+
+        // SAFETY: the rings lie inside the leaked, zeroed allocation and are
+        // only reached through this queue
+        let queue = unsafe {
+            Virtqueue {
+                base_addr: queue_base as u64,
+                desc: core::slice::from_raw_parts_mut(queue_base as *mut VirtqDesc, QUEUE_SIZE),
+                avail: &mut *((queue_base + avail_offset) as *mut VirtqAvail),
+                used: &mut *((queue_base + used_offset) as *mut VirtqUsed),
+                avail_idx: 0,
+                last_used_idx: 0,
+                free_desc: (0..QUEUE_SIZE as u16).collect(),
+            }
+        };
+        self.port_write_u32(regs::QUEUE_ADDRESS, (queue_base / PAGE_SIZE) as u32);
+        *self.channel.lock() = Some(Channel { queue, reply: vec![0u8; MAX_MESSAGE] });
+        Ok(())
+    }
+
+    fn port_read_u8(&self, offset: u16) -> u8 {
+        let port = self.io_base + offset;
+        let value: u8;
+        unsafe {
+            core::arch::asm!(
+                "in al, dx",
+                out("al") value,
+                in("dx") port,
+                options(nomem, nostack, preserves_flags)
+            );
+        }
+        value
+    }
+
+    fn port_read_u16(&self, offset: u16) -> u16 {
+        let port = self.io_base + offset;
+        let value: u16;
+        unsafe {
+            core::arch::asm!(
+                "in ax, dx",
+                out("ax") value,
+                in("dx") port,
+                options(nomem, nostack, preserves_flags)
+            );
+        }
+        value
+    }
+
+    fn port_read_u32(&self, offset: u16) -> u32 {
+        let port = self.io_base + offset;
+        let value: u32;
+        unsafe {
+            core::arch::asm!(
+                "in eax, dx",
+                out("eax") value,
+                in("dx") port,
+                options(nomem, nostack, preserves_flags)
+            );
+        }
+        value
+    }
+
+    fn port_write_u8(&self, offset: u16, value: u8) {
+        let port = self.io_base + offset;
+        unsafe {
+            core::arch::asm!(
+                "out dx, al",
+                in("dx") port,
+                in("al") value,
+                options(nomem, nostack, preserves_flags)
+            );
+        }
+    }
+
+    fn port_write_u16(&self, offset: u16, value: u16) {
+        let port = self.io_base + offset;
+        unsafe {
+            core::arch::asm!(
+                "out dx, ax",
+                in("dx") port,
+                in("ax") value,
+                options(nomem, nostack, preserves_flags)
+            );
+        }
+    }
+
+    fn port_write_u32(&self, offset: u16, value: u32) {
+        let port = self.io_base + offset;
+        unsafe {
+            core::arch::asm!(
+                "out dx, eax",
+                in("dx") port,
+                in("eax") value,
+                options(nomem, nostack, preserves_flags)
+            );
+        }
+    }
+
+    fn write_status(&self, status: u8) {
+        self.port_write_u8(regs::DEVICE_STATUS, status);
+    }
+}
+
+impl Transport for VirtioP9Device {
+    fn max_message(&self) -> usize {
+        MAX_MESSAGE
+    }
+
+    fn request(&self, message: &[u8]) -> Result<Vec<u8>, VfsError> {
+        if !self.ready.load(Ordering::SeqCst) {
+            return Err(VfsError::IoError);
+        }
+        let mut guard = self.channel.lock();
+        let Channel { queue, reply } = guard.as_mut().ok_or(VfsError::IoError)?;
+        let (Some(desc_out), Some(desc_in)) = (queue.free_desc.pop_front(), queue.free_desc.pop_front()) else {
+            return Err(VfsError::Busy);
+        };
+
+        // The request, then the buffer the device writes the reply to
+        queue.desc[desc_out as usize] = VirtqDesc {
+            addr: message.as_ptr() as u64,
+            len: message.len() as u32,
+            flags: desc_flags::NEXT,
+            next: desc_in,
+        };
+        queue.desc[desc_in as usize] = VirtqDesc {
+            addr: reply.as_mut_ptr() as u64,
+            len: reply.len() as u32,
+            flags: desc_flags::WRITE,
+            next: 0,
+        };
+
+        let avail_idx = queue.avail_idx;
+        queue.avail.ring[avail_idx as usize % QUEUE_SIZE] = desc_out;
+        core::sync::atomic::fence(Ordering::SeqCst);
+        queue.avail.idx = avail_idx.wrapping_add(1);
+        queue.avail_idx = avail_idx.wrapping_add(1);
+        self.port_write_u16(regs::QUEUE_NOTIFY, 0);
+
+        // Wait for completion (busy polling); host filesystem calls can
+        // take far longer than a block request
+        let mut timeout = REQUEST_TIMEOUT;
+        loop {
+            core::sync::atomic::fence(Ordering::SeqCst);
+            if queue.used.idx != queue.last_used_idx {
+                break;
+            }
+            timeout -= 1;
+            if timeout == 0 {
+                // A late reply would land in the next request's buffer
+                crate::serial_println!("[VIRTIO-9P] Request timed out; device \"{}\" disabled", self.tag);
+                self.ready.store(false, Ordering::SeqCst);
+                return Err(VfsError::IoError);
+            }
+            core::hint::spin_loop();
+        }
+
+        let used = queue.used.ring[queue.last_used_idx as usize % QUEUE_SIZE];
+        queue.last_used_idx = queue.last_used_idx.wrapping_add(1);
+        queue.free_desc.push_back(desc_out);
+        queue.free_desc.push_back(desc_in);
+        Ok(reply[..(used.len as usize).min(reply.len())].to_vec())
+    }
+}
+
+// Make the device safe to share between threads
+unsafe impl Send for VirtioP9Device {}
+unsafe impl Sync for VirtioP9Device {}
+
+/// Probed devices, by mount tag
+static DEVICES: RwLock<BTreeMap<String, Arc<VirtioP9Device>>> = RwLock::new(BTreeMap::new());
+
+/// Probes for VirtIO 9P devices on the PCI bus
+pub fn probe_devices() {
+    use crate::pci::{self, BarType};
+
+    crate::serial_println!("[VIRTIO-9P] Probing for VirtIO 9P devices...");
+    for device in pci::enumerate_devices() {
+        let id = device.device_id();
+        if device.vendor_id() != VIRTIO_VENDOR_ID || (id != VIRTIO_9P_DEVICE_ID_TRANSITIONAL && id != VIRTIO_9P_DEVICE_ID) {
+            continue;
+        }
+        let Some(bar) = device.bar(0).filter(|bar| bar.bar_type == BarType::Io) else {
+            // Modern-only devices have no legacy I/O BAR
+            crate::serial_println!("[VIRTIO-9P] Device at {:02x}:{:02x}.{} has no I/O BAR, skipping",
+                device.bus(), device.slot(), device.function());
+            continue;
+        };
+        device.enable_io();
+        device.enable_bus_master();
+
+        let mut p9 = VirtioP9Device::new(bar.address as u16);
+        if let Err(e) = p9.init() {
+            crate::serial_println!("[VIRTIO-9P] Init failed: {:?}", e);
+            continue;
+        }
+        let mut devices = DEVICES.write();
+        if devices.contains_key(p9.tag()) {
+            crate::serial_println!("[VIRTIO-9P] Duplicate mount tag \"{}\", skipping", p9.tag());
+            continue;
+        }
+        devices.insert(p9.tag().into(), Arc::new(p9));
+    }
+}
+
+/// Mount tags of the probed devices
+pub fn tags() -> Vec<String> {
+    DEVICES.read().keys().cloned().collect()
+}
+
+// ============================================================================
+// Module-level mount API for shell commands
+// ============================================================================
+
+/// Global 9P mount registry.
+static P9_MOUNTS: RwLock<BTreeMap<String, Arc<P9Fs>>> = RwLock::new(BTreeMap::new());
+
+/// Mount the host directory shared under a mount tag (read-write).
+pub fn mount(tag: &str, mount_point: &str) -> Result<(), VfsError> {
+    use alloc::string::ToString;
+
+    let device = DEVICES.read().get(tag).cloned().ok_or(VfsError::NotFound)?;
+    // One session per device: a second Tversion would reset the first
+    if P9_MOUNTS.read().values().any(|fs| fs.tag == tag) {
+        return Err(VfsError::Busy);
+    }
+
+    let fs = Arc::new(P9Fs::new(tag, device));
+    fs.mount()?;
+    if let Err(e) = VFS.mount(mount_point, fs.clone(), false) {
+        fs.unmount();
+        return Err(e);
+    }
+    P9_MOUNTS.write().insert(mount_point.to_string(), fs);
+
+    crate::serial_println!("[9p] Mounted {} at {} (read-write)", tag, mount_point);
+    Ok(())
+}
+
+/// Unmount a 9P filesystem.
+pub fn unmount(mount_point: &str) -> Result<(), VfsError> {
+    let fs = P9_MOUNTS.write().remove(mount_point).ok_or(VfsError::NotFound)?;
+    let _ = VFS.unmount(mount_point);
+    fs.unmount();
+    crate::serial_println!("[9p] Unmounted {}", mount_point);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A node of the fake server's tree
+    struct FakeNode {
+        parent: usize,
+        name: String,
+        mode: u32,
+        data: Vec<u8>,
+        removed: bool,
+    }
+
+    /// In-memory 9P2000.L server, enough of one for the client
+    struct FakeServer {
+        nodes: Vec<FakeNode>,
+        /// fid -> (node, opened)
+        fids: BTreeMap<u32, (usize, bool)>,
+        /// Requests seen, by type
+        requests: Vec<u8>,
+    }
+
+    impl FakeServer {
+        fn new() -> Self {
+            let root = FakeNode { parent: 0, name: String::new(), mode: 0o40755, data: Vec::new(), removed: false };
+            Self { nodes: vec![root], fids: BTreeMap::new(), requests: Vec::new() }
+        }
+
+        fn add(&mut self, parent: usize, name: &str, mode: u32, data: &[u8]) -> usize {
+            self.nodes.push(FakeNode { parent, name: name.into(), mode, data: data.to_vec(), removed: false });
+            self.nodes.len() - 1
+        }
+
+        fn child(&self, parent: usize, name: &str) -> Option<usize> {
+            (1..self.nodes.len()).find(|&i| {
+                let node = &self.nodes[i];
+                !node.removed && node.parent == parent && node.name == name
+            })
+        }
+
+        fn qid(&self, node: usize) -> Vec<u8> {
+            let kind = if self.nodes[node].mode & S_IFMT == 0o040000 { 0x80 } else { 0 };
+            [&[kind][..], &0u32.to_le_bytes(), &(1000 + node as u64).to_le_bytes()].concat()
+        }
+
+        fn error(errno: u32) -> (u8, Vec<u8>) {
+            (RLERROR, errno.to_le_bytes().to_vec())
+        }
+
+        fn handle(&mut self, kind: u8, r: &mut Reader) -> (u8, Vec<u8>) {
+            self.requests.push(kind);
+            let node_of = |server: &Self, fid: u32| server.fids.get(&fid).map(|&(node, _)| node);
+            match kind {
+                TVERSION => {
+                    let msize = r.u32().unwrap().min(8192);
+                    self.fids.clear();
+                    (RVERSION, Message(Vec::new()).u32(msize).str(VERSION).0)
+                }
+                TATTACH => {
+                    let fid = r.u32().unwrap();
+                    self.fids.insert(fid, (0, false));
+                    (RATTACH, self.qid(0))
+                }
+                TWALK => {
+                    let (fid, newfid, count) = (r.u32().unwrap(), r.u32().unwrap(), r.u16().unwrap());
+                    let Some(node) = node_of(self, fid) else { return Self::error(9) };
+                    if count == 0 {
+                        self.fids.insert(newfid, (node, false));
+                        return (RWALK, 0u16.to_le_bytes().to_vec());
+                    }
+                    let name = r.str().unwrap();
+                    match self.child(node, &name) {
+                        Some(child) => {
+                            self.fids.insert(newfid, (child, false));
+                            (RWALK, [&1u16.to_le_bytes()[..], &self.qid(child)].concat())
+                        }
+                        None => Self::error(2),
+                    }
+                }
+                TCLUNK => match self.fids.remove(&r.u32().unwrap()) {
+                    Some(_) => (RCLUNK, Vec::new()),
+                    None => Self::error(9),
+                },
+                TLOPEN => {
+                    let fid = r.u32().unwrap();
+                    let Some(node) = node_of(self, fid) else { return Self::error(9) };
+                    self.fids.insert(fid, (node, true));
+                    (RLOPEN, [self.qid(node), 0u32.to_le_bytes().to_vec()].concat())
+                }
+                TLCREATE => {
+                    let fid = r.u32().unwrap();
+                    let name = r.str().unwrap();
+                    let Some(dir) = node_of(self, fid) else { return Self::error(9) };
+                    if self.child(dir, &name).is_some() {
+                        return Self::error(17);
+                    }
+                    let node = self.add(dir, &name, 0o100644, &[]);
+                    self.fids.insert(fid, (node, true));
+                    (RLCREATE, [self.qid(node), 0u32.to_le_bytes().to_vec()].concat())
+                }
+                TMKDIR => {
+                    let dir = node_of(self, r.u32().unwrap()).unwrap();
+                    let node = self.add(dir, &r.str().unwrap(), 0o40755, &[]);
+                    (RMKDIR, self.qid(node))
+                }
+                TSYMLINK => {
+                    let dir = node_of(self, r.u32().unwrap()).unwrap();
+                    let name = r.str().unwrap();
+                    let target = r.str().unwrap();
+                    let node = self.add(dir, &name, 0o120777, target.as_bytes());
+                    (RSYMLINK, self.qid(node))
+                }
+                TREADLINK => {
+                    let node = node_of(self, r.u32().unwrap()).unwrap();
+                    let target = String::from_utf8(self.nodes[node].data.clone()).unwrap();
+                    (RREADLINK, Message(Vec::new()).str(&target).0)
+                }
+                TREAD => {
+                    let (fid, offset, count) = (r.u32().unwrap(), r.u64().unwrap() as usize, r.u32().unwrap() as usize);
+                    let Some(&(node, true)) = self.fids.get(&fid) else { return Self::error(9) };
+                    let data = &self.nodes[node].data;
+                    let part = &data[offset.min(data.len())..(offset + count).min(data.len())];
+                    (RREAD, Message(Vec::new()).u32(part.len() as u32).bytes(part).0)
+                }
+                TWRITE => {
+                    let (fid, offset, count) = (r.u32().unwrap(), r.u64().unwrap() as usize, r.u32().unwrap() as usize);
+                    let Some(&(node, true)) = self.fids.get(&fid) else { return Self::error(9) };
+                    let part = r.take(count).unwrap().to_vec();
+                    let data = &mut self.nodes[node].data;
+                    if data.len() < offset + count {
+                        data.resize(offset + count, 0);
+                    }
+                    data[offset..offset + count].copy_from_slice(&part);
+                    (RWRITE, (count as u32).to_le_bytes().to_vec())
+                }
+                TGETATTR => {
+                    let Some(node) = node_of(self, r.u32().unwrap()) else { return Self::error(9) };
+                    let n = &self.nodes[node];
+                    let size = n.data.len() as u64;
+                    let reply = Message(Vec::new())
+                        .u64(GETATTR_BASIC)
+                        .bytes(&self.qid(node))
+                        .u32(n.mode)
+                        .u32(0)
+                        .u32(0)
+                        .u64(1)
+                        .u64(0)
+                        .u64(size)
+                        .u64(4096)
+                        .u64(size.div_ceil(512))
+                        .u64(100)
+                        .u64(0)
+                        .u64(200 + size)
+                        .u64(0)
+                        .u64(300)
+                        .u64(0)
+                        .u64(50)
+                        .u64(0)
+                        .u64(0)
+                        .u64(0);
+                    (RGETATTR, reply.0)
+                }
+                TSETATTR => {
+                    let node = node_of(self, r.u32().unwrap()).unwrap();
+                    let valid = r.u32().unwrap();
+                    r.take(12).unwrap();
+                    let size = r.u64().unwrap() as usize;
+                    if valid & SETATTR_SIZE != 0 {
+                        self.nodes[node].data.resize(size, 0);
+                    }
+                    (RSETATTR, Vec::new())
+                }
+                TREADDIR => {
+                    let (fid, offset) = (r.u32().unwrap(), r.u64().unwrap() as usize);
+                    let Some(&(dir, true)) = self.fids.get(&fid) else { return Self::error(9) };
+                    let children: Vec<usize> =
+                        (1..self.nodes.len()).filter(|&i| !self.nodes[i].removed && self.nodes[i].parent == dir).collect();
+                    let mut names = vec![(dir, String::from(".")), (self.nodes[dir].parent, String::from(".."))];
+                    names.extend(children.iter().map(|&i| (i, self.nodes[i].name.clone())));
+                    // Two entries per reply, to exercise the offsets
+                    let mut data = Message(Vec::new());
+                    for (index, (node, name)) in names.iter().enumerate().skip(offset).take(2) {
+                        let d_type = if self.nodes[*node].mode & S_IFMT == 0o040000 { 4 } else { 8 };
+                        data = data.bytes(&self.qid(*node)).u64(index as u64 + 1).bytes(&[d_type]).str(name);
+                    }
+                    (RREADDIR, Message(Vec::new()).u32(data.0.len() as u32).bytes(&data.0).0)
+                }
+                TUNLINKAT => {
+                    let dir = node_of(self, r.u32().unwrap()).unwrap();
+                    let name = r.str().unwrap();
+                    let flags = r.u32().unwrap();
+                    let Some(node) = self.child(dir, &name) else { return Self::error(2) };
+                    let is_dir = self.nodes[node].mode & S_IFMT == 0o040000;
+                    if is_dir && flags & AT_REMOVEDIR == 0 {
+                        return Self::error(21);
+                    }
+                    if is_dir && self.nodes.iter().any(|n| !n.removed && n.parent == node) {
+                        return Self::error(39);
+                    }
+                    self.nodes[node].removed = true;
+                    (RUNLINKAT, Vec::new())
+                }
+                TRENAMEAT => {
+                    let old_dir = node_of(self, r.u32().unwrap()).unwrap();
+                    let old_name = r.str().unwrap();
+                    let new_dir = node_of(self, r.u32().unwrap()).unwrap();
+                    let new_name = r.str().unwrap();
+                    let Some(node) = self.child(old_dir, &old_name) else { return Self::error(2) };
+                    if let Some(old) = self.child(new_dir, &new_name) {
+                        self.nodes[old].removed = true;
+                    }
+                    self.nodes[node].parent = new_dir;
+                    self.nodes[node].name = new_name;
+                    (RRENAMEAT, Vec::new())
+                }
+                TFSYNC => (RFSYNC, Vec::new()),
+                TSTATFS => {
+                    let reply = Message(Vec::new()).u32(0x01021997).u32(4096).u64(1000).u64(600).u64(500).u64(64).u64(32).u64(0).u32(255);
+                    (RSTATFS, reply.0)
+                }
+                _ => Self::error(95),
+            }
+        }
+    }
+
+    struct FakeTransport(Mutex<FakeServer>);
+
+    impl Transport for FakeTransport {
+        fn max_message(&self) -> usize {
+            MAX_MESSAGE
+        }
+
+        fn request(&self, message: &[u8]) -> Result<Vec<u8>, VfsError> {
+            let mut r = Reader::new(message);
+            assert_eq!(r.u32().unwrap() as usize, message.len());
+            let kind = r.u8().unwrap();
+            let tag = r.u16().unwrap();
+            let (reply_type, body) = self.0.lock().handle(kind, &mut r);
+            let mut reply = Message::new(reply_type).bytes(&body);
+            reply.0[5..7].copy_from_slice(&tag.to_le_bytes());
+            Ok(reply.finish())
+        }
+    }
+
+    /// Mounts a server holding `/hello.txt`, `/docs/notes.txt` and a
+    /// 20000-byte `/big.bin`.
+    fn mount() -> (P9Fs, Arc<FakeTransport>) {
+        let mut server = FakeServer::new();
+        server.add(0, "hello.txt", 0o100644, b"hello host\n");
+        let docs = server.add(0, "docs", 0o40755, &[]);
+        server.add(docs, "notes.txt", 0o100600, b"notes");
+        let big: Vec<u8> = (0..20000).map(|i| (i % 251) as u8).collect();
+        server.add(0, "big.bin", 0o100755, &big);
+        let transport = Arc::new(FakeTransport(Mutex::new(server)));
+        let fs = P9Fs::new("host", transport.clone());
+        fs.mount().expect("mount");
+        (fs, transport)
+    }
+
+    fn names(fs: &P9Fs, path: &str) -> Vec<String> {
+        fs.readdir(fs.resolve_path(path).unwrap()).unwrap().into_iter().map(|e| e.name).collect()
+    }
+
+    #[test]
+    fn test_messages() {
+        let message = Message::new(TWALK).u32(1).u32(2).u16(1).str("docs").finish();
+        assert_eq!(&message[..7], &[23, 0, 0, 0, TWALK, 1, 0]);
+        assert_eq!(&message[17..], b"docs");
+        let version = Message::new(TVERSION).notag().finish();
+        assert_eq!(&version[5..7], &[0xFF, 0xFF]);
+
+        let mut r = Reader::new(&[0x80, 1, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0, 2, 0, b'o', b'k']);
+        assert_eq!(r.qid().unwrap(), Qid { kind: 0x80, version: 1, path: 7 });
+        assert_eq!(r.str().unwrap(), "ok");
+        assert!(r.is_empty());
+        assert_eq!(r.u8(), Err(VfsError::IoError));
+    }
+
+    #[test]
+    fn test_lookup_and_read() {
+        let (fs, _) = mount();
+        assert_eq!(names(&fs, "/"), ["hello.txt", "docs", "big.bin"]);
+        assert_eq!(names(&fs, "/docs"), ["notes.txt"]);
+
+        let hello = fs.resolve_path("/hello.txt").unwrap();
+        assert_eq!(fs.read(hello, 0, 4096).unwrap(), b"hello host\n");
+        assert_eq!(fs.read(hello, 6, 4).unwrap(), b"host");
+        assert!(fs.read(hello, 100, 10).unwrap().is_empty());
+        assert_eq!(fs.resolve_path("/missing"), Err(VfsError::NotFound));
+
+        // Larger than one message
+        let big = fs.resolve_path("/big.bin").unwrap();
+        let expected: Vec<u8> = (0..20000).map(|i| (i % 251) as u8).collect();
+        assert_eq!(fs.read(big, 0, 1 << 20).unwrap(), expected);
+
+        let notes = fs.getattr(fs.resolve_path("/docs/notes.txt").unwrap()).unwrap();
+        assert_eq!((notes.file_type, notes.size, notes.mtime), (VfsFileType::Regular, 5, 205));
+        assert!(notes.perm.writable && !notes.perm.executable);
+        assert_eq!(fs.getattr(fs.root_ino()).unwrap().file_type, VfsFileType::Directory);
+    }
+
+    #[test]
+    fn test_readdir_entries_walk_lazily() {
+        let (fs, _) = mount();
+        let root = fs.root_ino();
+        let entries = fs.readdir(root).unwrap();
+        let docs = entries.iter().find(|e| e.name == "docs").unwrap();
+        assert_eq!(docs.file_type, VfsFileType::Directory);
+        // Never looked up, yet usable
+        assert_eq!(fs.getattr(docs.ino).unwrap().file_type, VfsFileType::Directory);
+        let big = entries.iter().find(|e| e.name == "big.bin").unwrap();
+        assert!(fs.getattr(big.ino).unwrap().perm.executable);
+    }
+
+    #[test]
+    fn test_create_write_rename_unlink() {
+        let (fs, transport) = mount();
+        let root = fs.root_ino();
+        let ino = fs.create(root, "new.txt", VfsFileType::Regular).unwrap();
+        assert_eq!(fs.create(root, "new.txt", VfsFileType::Regular), Err(VfsError::AlreadyExists));
+        assert_eq!(fs.write(ino, 0, b"written in the guest").unwrap(), 20);
+        assert_eq!(fs.read(ino, 11, 100).unwrap(), b"the guest");
+        fs.truncate(ino, 7).unwrap();
+        assert_eq!(fs.getattr(ino).unwrap().size, 7);
+
+        let dir = fs.create(root, "made", VfsFileType::Directory).unwrap();
+        fs.rename(root, "new.txt", dir, "moved.txt").unwrap();
+        assert_eq!(fs.resolve_path("/made/moved.txt"), Ok(ino));
+        assert_eq!(fs.read(ino, 0, 100).unwrap(), b"written");
+        assert_eq!(fs.unlink(root, "made"), Err(VfsError::NotEmpty));
+        fs.unlink(dir, "moved.txt").unwrap();
+        fs.unlink(root, "made").unwrap();
+        assert_eq!(names(&fs, "/"), ["hello.txt", "docs", "big.bin"]);
+
+        let link = fs.symlink(root, "link", "docs/notes.txt").unwrap();
+        assert_eq!(fs.readlink(link).unwrap(), "docs/notes.txt");
+        assert_eq!(fs.getattr(link).unwrap().file_type, VfsFileType::Symlink);
+        fs.sync().unwrap();
+        assert!(transport.0.lock().requests.contains(&TFSYNC));
+    }
+
+    #[test]
+    fn test_revalidate_and_unmount() {
+        let (fs, transport) = mount();
+        let hello = fs.resolve_path("/hello.txt").unwrap();
+        assert!(!fs.revalidate(hello));
+        assert!(!fs.revalidate(hello));
+        // Changed on the host
+        transport.0.lock().nodes[1].data.extend_from_slice(b"more\n");
+        assert!(fs.revalidate(hello));
+        assert!(!fs.revalidate(hello));
+
+        let statfs = fs.statfs().unwrap();
+        assert_eq!((statfs.blocks, statfs.bfree, statfs.bsize, statfs.namelen), (1000, 600, 4096, 255));
+
+        fs.read(hello, 0, 10).unwrap();
+        fs.unmount();
+        assert!(transport.0.lock().fids.is_empty());
+        assert_eq!(fs.getattr(hello), Err(VfsError::NoFilesystem));
+    }
+}
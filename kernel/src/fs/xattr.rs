@@ -0,0 +1,228 @@
+//! # Extended Attributes
+//!
+//! Name/value pairs attached to an inode next to its data, reached
+//! through the `getxattr`/`setxattr`/`listxattr`/`removexattr` operations
+//! of the VFS [`Filesystem`](super::vfs::Filesystem) trait. As on Linux,
+//! every name starts with a namespace:
+//!
+//! | Prefix      | Use                                            |
+//! |-------------|------------------------------------------------|
+//! | `user.`     | Application data                               |
+//! | `trusted.`  | Data for privileged services                    |
+//! | `security.` | Security labels, such as capability labels     |
+//! | `system.`   | Filesystem-defined data (ACLs, compression)    |
+//!
+//! ## Capability Labels
+//!
+//! The reserved attribute [`CAPS_XATTR`] lists the capabilities a process
+//! must hold to open a file, as comma-separated `resource:ops` pairs where
+//! `ops` is a subset of `rwxgv` (read, write, execute, grant, revoke):
+//!
+//! ```text
+//! security.splax.caps = "net:rw,gpu:r"
+//! ```
+//!
+//! [`Vfs::open_with_caps`](super::vfs::Vfs::open_with_caps) enforces the
+//! label. It cannot be written through the plain attribute calls; only
+//! [`Vfs::set_caps_label`](super::vfs::Vfs::set_caps_label), with a
+//! capability for [`FILE_LABEL_RESOURCE`], changes it.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use super::vfs::VfsError;
+use crate::cap::{CapabilityTable, CapabilityToken, Operations};
+use crate::sched::ProcessId;
+
+/// Longest attribute name, namespace prefix included
+pub const XATTR_NAME_MAX: usize = 255;
+
+/// Largest attribute value
+pub const XATTR_SIZE_MAX: usize = 65536;
+
+/// Attribute holding a file's capability label
+pub const CAPS_XATTR: &str = "security.splax.caps";
+
+/// Attribute naming the algorithm new data of a file, or new files in a
+/// directory, are compressed with, on filesystems that compress
+pub const COMPRESSION_XATTR: &str = "system.splax.compression";
+
+/// Attribute holding the encryption context of an encrypted file or
+/// directory, on filesystems that encrypt. It is read-only; policies are
+/// set through [`Vfs::set_encryption_policy`](super::vfs::Vfs::set_encryption_policy).
+pub const ENCRYPTION_XATTR: &str = splaxfs_core::crypt::ENCRYPTION_XATTR;
+
+/// Resource type of the capability needed to change capability labels
+pub const FILE_LABEL_RESOURCE: &str = "file_label";
+
+/// Resource type of the capability needed to set encryption policies and
+/// to lock and unlock encrypted directories
+pub const FS_KEY_RESOURCE: &str = "fs_key";
+
+/// Resource type of the capability needed to create a mount namespace
+pub const MOUNT_NS_RESOURCE: &str = "mount_ns";
+
+/// Attribute namespace
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Namespace {
+    User,
+    Trusted,
+    Security,
+    System,
+}
+
+impl Namespace {
+    /// All namespaces, in ext4 name index order
+    pub const ALL: [Namespace; 4] = [Namespace::User, Namespace::Trusted, Namespace::Security, Namespace::System];
+
+    /// Name prefix, dot included
+    pub fn prefix(self) -> &'static str {
+        match self {
+            Namespace::User => "user.",
+            Namespace::Trusted => "trusted.",
+            Namespace::Security => "security.",
+            Namespace::System => "system.",
+        }
+    }
+
+    /// Splits a full name into its namespace and the rest
+    pub fn parse(name: &str) -> Option<(Namespace, &str)> {
+        Self::ALL
+            .iter()
+            .find_map(|&ns| name.strip_prefix(ns.prefix()).map(|rest| (ns, rest)))
+    }
+}
+
+/// Validates an attribute name, returning its namespace and the rest.
+/// Names outside the known namespaces are not supported.
+pub fn check_name(name: &str) -> Result<(Namespace, &str), VfsError> {
+    if name.len() > XATTR_NAME_MAX {
+        return Err(VfsError::PathTooLong);
+    }
+    let (ns, rest) = Namespace::parse(name).ok_or(VfsError::NotSupported)?;
+    if rest.is_empty() || rest.contains('\0') {
+        return Err(VfsError::InvalidArgument);
+    }
+    Ok((ns, rest))
+}
+
+/// Validates a name and value about to be stored
+pub fn check_attr(name: &str, value: &[u8]) -> Result<(), VfsError> {
+    check_name(name)?;
+    if value.len() > XATTR_SIZE_MAX {
+        return Err(VfsError::NoSpace);
+    }
+    Ok(())
+}
+
+/// One entry of a capability label
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapRequirement {
+    /// Resource type the capability must name
+    pub resource_type: String,
+    /// Operations it must allow
+    pub operations: Operations,
+}
+
+/// Operation letters of a label, in order
+const OPERATION_LETTERS: [(char, Operations); 5] = [
+    ('r', Operations::READ),
+    ('w', Operations::WRITE),
+    ('x', Operations::EXECUTE),
+    ('g', Operations::GRANT),
+    ('v', Operations::REVOKE),
+];
+
+/// Parses a capability label
+pub fn parse_caps(value: &[u8]) -> Result<Vec<CapRequirement>, VfsError> {
+    let text = core::str::from_utf8(value).map_err(|_| VfsError::InvalidArgument)?;
+    let mut requirements = Vec::new();
+    for item in text.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+        let (resource_type, letters) = item.split_once(':').ok_or(VfsError::InvalidArgument)?;
+        if resource_type.is_empty() {
+            return Err(VfsError::InvalidArgument);
+        }
+        let mut operations = Operations::NONE;
+        for letter in letters.chars() {
+            let &(_, op) = OPERATION_LETTERS
+                .iter()
+                .find(|(c, _)| *c == letter)
+                .ok_or(VfsError::InvalidArgument)?;
+            operations = operations.union(op);
+        }
+        requirements.push(CapRequirement { resource_type: resource_type.to_string(), operations });
+    }
+    Ok(requirements)
+}
+
+/// Formats a capability label
+pub fn format_caps(requirements: &[CapRequirement]) -> Vec<u8> {
+    let mut text = String::new();
+    for (i, req) in requirements.iter().enumerate() {
+        if i > 0 {
+            text.push(',');
+        }
+        text.push_str(&req.resource_type);
+        text.push(':');
+        for &(letter, op) in &OPERATION_LETTERS {
+            if req.operations.contains(op) {
+                text.push(letter);
+            }
+        }
+    }
+    text.into_bytes()
+}
+
+/// Checks that `tokens`, owned by `pid`, satisfy every requirement
+pub fn check_caps(
+    requirements: &[CapRequirement],
+    pid: ProcessId,
+    caps: &CapabilityTable,
+    tokens: &[CapabilityToken],
+) -> Result<(), VfsError> {
+    for req in requirements {
+        let held = tokens.iter().any(|token| {
+            caps.get_resource(token)
+                .is_ok_and(|resource| resource.resource_type == req.resource_type)
+                && caps.check(pid, *token, req.operations).is_ok()
+        });
+        if !held {
+            return Err(VfsError::PermissionDenied);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cap::ResourceId;
+
+    #[test]
+    fn test_names() {
+        assert_eq!(check_name("user.mime"), Ok((Namespace::User, "mime")));
+        assert_eq!(check_name(CAPS_XATTR), Ok((Namespace::Security, "splax.caps")));
+        assert_eq!(check_name("user."), Err(VfsError::InvalidArgument));
+        assert_eq!(check_name("os2.name"), Err(VfsError::NotSupported));
+    }
+
+    #[test]
+    fn test_caps_label() {
+        let reqs = parse_caps(b"net:rw, gpu:r").unwrap();
+        assert_eq!(reqs[0].resource_type, "net");
+        assert_eq!(reqs[0].operations, Operations::READ.union(Operations::WRITE));
+        assert_eq!(format_caps(&reqs), b"net:rw,gpu:r");
+        assert_eq!(parse_caps(b"net"), Err(VfsError::InvalidArgument));
+        assert_eq!(parse_caps(b"net:q"), Err(VfsError::InvalidArgument));
+
+        let table = CapabilityTable::new(16);
+        let pid = ProcessId::new(0xC0_0002);
+        let net = table.create_root(pid, ResourceId::new("net", 0), Operations::ALL).unwrap();
+        let gpu = table.create_root(pid, ResourceId::new("gpu", 0), Operations::WRITE).unwrap();
+        assert_eq!(check_caps(&reqs, pid, &table, &[net]), Err(VfsError::PermissionDenied));
+        assert_eq!(check_caps(&reqs, pid, &table, &[net, gpu]), Err(VfsError::PermissionDenied));
+        let gpu = table.create_root(pid, ResourceId::new("gpu", 1), Operations::READ).unwrap();
+        assert_eq!(check_caps(&reqs, pid, &table, &[net, gpu]), Ok(()));
+        assert_eq!(check_caps(&reqs, ProcessId::new(1), &table, &[net, gpu]), Err(VfsError::PermissionDenied));
+    }
+}
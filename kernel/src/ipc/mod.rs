@@ -54,6 +54,7 @@ fn get_timestamp() -> u64 {
 use spin::Mutex;
 
 use crate::cap::CapabilityToken;
+use crate::mm::slab::{SlabBox, SlabCache};
 use crate::sched::ProcessId;
 
 /// Channel identifier.
@@ -234,11 +235,14 @@ impl Channel {
     }
 }
 
+/// Slab cache for channel objects.
+static CHANNEL_CACHE: SlabCache = SlabCache::for_type::<Channel>("ipc_channel");
+
 /// The IPC manager.
 pub struct IpcManager {
     config: IpcConfig,
     /// All channels
-    channels: Mutex<BTreeMap<ChannelId, Channel>>,
+    channels: Mutex<BTreeMap<ChannelId, SlabBox<Channel>>>,
     /// Next channel ID
     next_channel_id: Mutex<u64>,
     /// Capability table reference for access checks
@@ -287,6 +291,7 @@ impl IpcManager {
         if channels.len() >= self.config.max_channels {
             return Err(IpcError::TooManyChannels);
         }
+        let channel = SlabBox::new(channel, &CHANNEL_CACHE).ok_or(IpcError::OutOfMemory)?;
         channels.insert(id, channel);
 
         Ok(id)
@@ -540,6 +545,8 @@ pub enum IpcError {
     PendingNotFound,
     /// Too many pending async operations
     TooManyPending,
+    /// Kernel object allocation failed
+    OutOfMemory,
 }
 
 // =============================================================================
@@ -32,6 +32,7 @@
 #![no_main]
 #![deny(unsafe_op_in_unsafe_fn)]
 #![feature(abi_x86_interrupt)]
+#![cfg_attr(feature = "kasan", feature(sanitize))]
 
 extern crate alloc;
 
@@ -176,6 +177,18 @@ impl Kernel {
             #[cfg(target_arch = "x86_64")]
             arch::x86_64::interrupts::process_serial_input();
             
+            // Update the memory pressure level
+            #[cfg(target_arch = "x86_64")]
+            mm::pressure::poll();
+            
+            // Write back expired dirty page-cache pages
+            #[cfg(all(target_arch = "x86_64", not(feature = "microkernel")))]
+            fs::pagecache::PAGE_CACHE.periodic_writeback();
+            
+            // Verify a few more blocks of running SplaxFS scrubs
+            #[cfg(all(target_arch = "x86_64", not(feature = "microkernel")))]
+            fs::splaxfs::scrub_poll();
+            
             // Run scheduler
             if let Some(next_process) = self.scheduler.schedule() {
                 // Switch to next process
@@ -272,6 +285,14 @@ pub extern "C" fn kernel_main(boot_info: *const u8) -> ! {
         cfg
     };
     let mut kernel = Kernel::new(config);
+    // SAFETY: `kernel` lives until `run`, which never returns
+    unsafe { cap::register_kernel_table(&kernel.cap_table) };
+
+    // Hand the RAM the bootloader reported to the frame allocator
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        arch::x86_64::bootmem::init(boot_info as u64);
+    }
 
     // Initialize security hardening (stack canaries, ASLR)
     // Use RDTSC as entropy source for random seed
@@ -284,6 +305,9 @@ pub extern "C" fn kernel_main(boot_info: *const u8) -> ! {
         // Initialize Control Flow Integrity (CFI)
         mm::cfi::init(mm::cfi::CfiPolicy::Enforcing);
         serial_println!("[kernel] Control Flow Integrity (CFI) initialized");
+
+        #[cfg(feature = "kasan")]
+        mm::kasan::self_test();
     }
     #[cfg(target_arch = "aarch64")]
     {
@@ -365,6 +389,9 @@ pub extern "C" fn kernel_main(boot_info: *const u8) -> ! {
         // Initialize block subsystem (VirtIO-blk, etc.)
         block::init();
         
+        // Probe virtio-9p devices (host directory shares)
+        fs::virtio_9p::probe_devices();
+        
         serial_println!("[kernel] About to init network...");
         
         // Initialize network subsystem
@@ -0,0 +1,458 @@
+//! # Buddy Allocator
+//!
+//! Power-of-two block allocator for physical frames, split into zones.
+//!
+//! ## Design
+//!
+//! - Blocks of order `k` are `2^k` frames, naturally aligned
+//! - Orders 0..=18 cover 4 KiB up to 1 GiB (order 9 is a 2 MiB huge page)
+//! - Free blocks are tracked in a per-order bitmap, one bit per block
+//! - Each bitmap has two summary levels (one bit per non-zero word below),
+//!   so finding a free block is a handful of word scans instead of O(n)
+//! - The allocator never writes into free frames, so it works before the
+//!   physical memory map is set up
+//!
+//! ## Zones
+//!
+//! | Zone   | Range          | Use                                  |
+//! |--------|----------------|--------------------------------------|
+//! | DMA32  | 0 - 4 GiB      | Devices limited to 32-bit addressing |
+//! | Normal | 4 GiB and up   | Everything else                      |
+//! | Device | registered     | MMIO / pmem, never handed out        |
+//!
+//! Allocations that don't care fall back from Normal to DMA32.
+
+use super::frame::{MAX_FRAMES, PAGE_SIZE};
+
+/// Largest block order (2^18 frames = 1 GiB).
+pub const MAX_ORDER: usize = 18;
+
+/// Number of orders.
+pub const NR_ORDERS: usize = MAX_ORDER + 1;
+
+/// Order of a 2 MiB huge page.
+pub const HUGE_2M_ORDER: usize = 9;
+
+/// Order of a 1 GiB huge page.
+pub const HUGE_1G_ORDER: usize = 18;
+
+/// First frame above the 4 GiB DMA32 limit.
+const DMA32_LIMIT_FRAME: usize = (4usize << 30) / PAGE_SIZE;
+
+/// Memory zone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Zone {
+    /// Frames below 4 GiB
+    Dma32,
+    /// Frames at or above 4 GiB
+    Normal,
+    /// Device memory registered for tracking only
+    Device,
+}
+
+impl Zone {
+    /// Zones that hold allocatable memory.
+    pub const ALLOCATABLE: [Zone; 2] = [Zone::Dma32, Zone::Normal];
+
+    /// Zone name as shown in `/proc/buddyinfo`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Zone::Dma32 => "DMA32",
+            Zone::Normal => "Normal",
+            Zone::Device => "Device",
+        }
+    }
+
+    /// Frame range `[start, end)` this zone covers.
+    pub fn frame_range(&self) -> (usize, usize) {
+        match self {
+            Zone::Dma32 => (0, DMA32_LIMIT_FRAME.min(MAX_FRAMES)),
+            Zone::Normal => (DMA32_LIMIT_FRAME.min(MAX_FRAMES), MAX_FRAMES),
+            Zone::Device => (0, 0),
+        }
+    }
+
+    /// Allocatable zone containing a frame.
+    pub fn of_frame(frame: usize) -> Zone {
+        if frame < DMA32_LIMIT_FRAME {
+            Zone::Dma32
+        } else {
+            Zone::Normal
+        }
+    }
+
+    fn index(&self) -> usize {
+        match self {
+            Zone::Dma32 => 0,
+            Zone::Normal => 1,
+            Zone::Device => 2,
+        }
+    }
+}
+
+// ============================================================================
+// Bitmap Layout
+// ============================================================================
+
+const fn div_ceil(a: usize, b: usize) -> usize {
+    (a + b - 1) / b
+}
+
+/// Words needed at summary `level` for blocks of `order`.
+const fn words_at(order: usize, level: usize) -> usize {
+    let mut words = div_ceil(MAX_FRAMES >> order, 64);
+    let mut l = 0;
+    while l < level {
+        words = div_ceil(words, 64);
+        l += 1;
+    }
+    words
+}
+
+/// Start offset of each order's words for a summary level.
+const fn offsets(level: usize) -> [usize; NR_ORDERS + 1] {
+    let mut out = [0usize; NR_ORDERS + 1];
+    let mut k = 0;
+    while k < NR_ORDERS {
+        out[k + 1] = out[k] + words_at(k, level);
+        k += 1;
+    }
+    out
+}
+
+const L0_OFFSETS: [usize; NR_ORDERS + 1] = offsets(0);
+const L1_OFFSETS: [usize; NR_ORDERS + 1] = offsets(1);
+const L2_OFFSETS: [usize; NR_ORDERS + 1] = offsets(2);
+
+const L0_WORDS: usize = L0_OFFSETS[NR_ORDERS];
+const L1_WORDS: usize = L1_OFFSETS[NR_ORDERS];
+const L2_WORDS: usize = L2_OFFSETS[NR_ORDERS];
+
+/// Finds the first set bit at or after `from` in `words`.
+fn first_set_from(words: &[u64], from: usize) -> Option<usize> {
+    let mut w = from / 64;
+    if w >= words.len() {
+        return None;
+    }
+    let masked = words[w] & (!0u64 << (from % 64));
+    if masked != 0 {
+        return Some(w * 64 + masked.trailing_zeros() as usize);
+    }
+    w += 1;
+    while w < words.len() {
+        if words[w] != 0 {
+            return Some(w * 64 + words[w].trailing_zeros() as usize);
+        }
+        w += 1;
+    }
+    None
+}
+
+// ============================================================================
+// Buddy State
+// ============================================================================
+
+/// Free block bookkeeping for all orders.
+///
+/// Not synchronised; the frame allocator wraps it in a lock.
+pub struct BuddyState {
+    /// One bit per block: 1 = free block starts here
+    l0: [u64; L0_WORDS],
+    /// One bit per non-zero `l0` word
+    l1: [u64; L1_WORDS],
+    /// One bit per non-zero `l1` word
+    l2: [u64; L2_WORDS],
+    /// Free block counts per zone and order
+    counts: [[usize; NR_ORDERS]; 2],
+}
+
+impl BuddyState {
+    /// Creates an empty buddy state (no free blocks).
+    pub const fn new() -> Self {
+        Self {
+            l0: [0; L0_WORDS],
+            l1: [0; L1_WORDS],
+            l2: [0; L2_WORDS],
+            counts: [[0; NR_ORDERS]; 2],
+        }
+    }
+
+    fn is_free(&self, order: usize, idx: usize) -> bool {
+        let w = L0_OFFSETS[order] + idx / 64;
+        self.l0[w] & (1u64 << (idx % 64)) != 0
+    }
+
+    fn set_free(&mut self, order: usize, idx: usize) {
+        let w0 = idx / 64;
+        self.l0[L0_OFFSETS[order] + w0] |= 1u64 << (idx % 64);
+        let w1 = w0 / 64;
+        self.l1[L1_OFFSETS[order] + w1] |= 1u64 << (w0 % 64);
+        self.l2[L2_OFFSETS[order] + w1 / 64] |= 1u64 << (w1 % 64);
+        self.counts[Zone::of_frame(idx << order).index()][order] += 1;
+    }
+
+    fn clear_free(&mut self, order: usize, idx: usize) {
+        let w0 = idx / 64;
+        let l0 = &mut self.l0[L0_OFFSETS[order] + w0];
+        *l0 &= !(1u64 << (idx % 64));
+        if *l0 == 0 {
+            let w1 = w0 / 64;
+            let l1 = &mut self.l1[L1_OFFSETS[order] + w1];
+            *l1 &= !(1u64 << (w0 % 64));
+            if *l1 == 0 {
+                self.l2[L2_OFFSETS[order] + w1 / 64] &= !(1u64 << (w1 % 64));
+            }
+        }
+        self.counts[Zone::of_frame(idx << order).index()][order] -= 1;
+    }
+
+    /// Finds the first free block of `order` with index in `[lo, hi)`.
+    fn find_free(&self, order: usize, lo: usize, hi: usize) -> Option<usize> {
+        let l0 = &self.l0[L0_OFFSETS[order]..L0_OFFSETS[order + 1]];
+        let l1 = &self.l1[L1_OFFSETS[order]..L1_OFFSETS[order + 1]];
+        let l2 = &self.l2[L2_OFFSETS[order]..L2_OFFSETS[order + 1]];
+
+        let mut from = lo;
+        while from < hi {
+            // Fast path: remaining bits in the current word
+            let w0 = from / 64;
+            if w0 >= l0.len() {
+                return None;
+            }
+            let masked = l0[w0] & (!0u64 << (from % 64));
+            if masked != 0 {
+                let idx = w0 * 64 + masked.trailing_zeros() as usize;
+                return if idx < hi { Some(idx) } else { None };
+            }
+
+            // Use the summaries to find the next non-empty word
+            let next = w0 + 1;
+            let w1 = next / 64;
+            if w1 >= l1.len() {
+                return None;
+            }
+            let masked = l1[w1] & (!0u64 << (next % 64));
+            let next_w0 = if masked != 0 {
+                Some(w1 * 64 + masked.trailing_zeros() as usize)
+            } else {
+                first_set_from(l2, w1 + 1).map(|w| w * 64 + l1[w].trailing_zeros() as usize)
+            };
+            match next_w0 {
+                Some(w) => from = w * 64,
+                None => return None,
+            }
+        }
+        None
+    }
+
+    /// Returns true if `frame` lies in a free block.
+    pub fn is_frame_free(&self, frame: usize) -> bool {
+        (0..NR_ORDERS).any(|k| {
+            let idx = frame >> k;
+            ((idx + 1) << k) <= MAX_FRAMES && self.is_free(k, idx)
+        })
+    }
+
+    /// Returns true if any frame of the block of `order` at `start_frame`
+    /// is free.
+    fn overlaps_free(&self, order: usize, start_frame: usize) -> bool {
+        let end = start_frame + (1 << order);
+        (0..NR_ORDERS).any(|k| {
+            if k >= order {
+                // The block containing this one
+                let idx = start_frame >> k;
+                ((idx + 1) << k) <= MAX_FRAMES && self.is_free(k, idx)
+            } else {
+                self.find_free(k, start_frame >> k, end >> k).is_some()
+            }
+        })
+    }
+
+    /// Returns true if any frame in `[start, end)` is free.
+    pub fn any_free(&self, start: usize, end: usize) -> bool {
+        let end = end.min(MAX_FRAMES);
+        let mut frame = start;
+        while frame < end {
+            let order = largest_order(frame, end - frame);
+            if self.overlaps_free(order, frame) {
+                return true;
+            }
+            frame += 1 << order;
+        }
+        false
+    }
+
+    /// Inserts a free block, merging with its buddy where possible.
+    ///
+    /// Returns false, changing nothing, if the block is out of range or any
+    /// of it is already free.
+    pub fn free_block(&mut self, mut order: usize, start_frame: usize) -> bool {
+        if start_frame + (1 << order) > MAX_FRAMES || self.overlaps_free(order, start_frame) {
+            return false;
+        }
+        let mut idx = start_frame >> order;
+        while order < MAX_ORDER {
+            let buddy = idx ^ 1;
+            if ((buddy + 1) << order) > MAX_FRAMES || !self.is_free(order, buddy) {
+                break;
+            }
+            self.clear_free(order, buddy);
+            idx >>= 1;
+            order += 1;
+        }
+        self.set_free(order, idx);
+        true
+    }
+
+    /// Allocates a block of `order` from `zone`, splitting larger blocks.
+    ///
+    /// Returns the first frame of the block.
+    pub fn alloc_block(&mut self, order: usize, zone: Zone) -> Option<usize> {
+        let (zlo, zhi) = zone.frame_range();
+        for k in order..NR_ORDERS {
+            let lo = div_ceil(zlo, 1 << k);
+            let hi = zhi >> k;
+            if let Some(idx) = self.find_free(k, lo, hi) {
+                self.clear_free(k, idx);
+                // Return the upper halves while splitting down to `order`
+                let start = idx << k;
+                let mut split = k;
+                while split > order {
+                    split -= 1;
+                    self.set_free(split, (start >> split) + 1);
+                }
+                return Some(start);
+            }
+        }
+        None
+    }
+
+    /// Frees an arbitrary frame range as maximal aligned blocks.
+    ///
+    /// Returns the number of frames freed; blocks that were already partly
+    /// free are skipped.
+    pub fn free_range(&mut self, start: usize, end: usize) -> usize {
+        let end = end.min(MAX_FRAMES);
+        let mut frame = start;
+        let mut freed = 0;
+        while frame < end {
+            let order = largest_order(frame, end - frame);
+            if self.free_block(order, frame) {
+                freed += 1 << order;
+            }
+            frame += 1 << order;
+        }
+        freed
+    }
+
+    /// Removes a single free frame from whichever free block contains it.
+    ///
+    /// Returns false if the frame was not free.
+    pub fn take_frame(&mut self, frame: usize) -> bool {
+        for k in 0..NR_ORDERS {
+            let idx = frame >> k;
+            if ((idx + 1) << k) > MAX_FRAMES || !self.is_free(k, idx) {
+                continue;
+            }
+            self.clear_free(k, idx);
+            // Split down, keeping the half that doesn't contain `frame`
+            let mut order = k;
+            let mut block = idx << k;
+            while order > 0 {
+                order -= 1;
+                let half = 1 << order;
+                if frame < block + half {
+                    self.set_free(order, (block + half) >> order);
+                } else {
+                    self.set_free(order, block >> order);
+                    block += half;
+                }
+            }
+            return true;
+        }
+        false
+    }
+
+    /// Free block counts per order for an allocatable zone.
+    pub fn free_blocks(&self, zone: Zone) -> [usize; NR_ORDERS] {
+        match zone {
+            Zone::Device => [0; NR_ORDERS],
+            _ => self.counts[zone.index()],
+        }
+    }
+
+    /// Number of free frames in an allocatable zone.
+    pub fn free_frames(&self, zone: Zone) -> usize {
+        self.free_blocks(zone)
+            .iter()
+            .enumerate()
+            .map(|(order, &n)| n << order)
+            .sum()
+    }
+
+    /// Largest free order in a zone, if any.
+    pub fn largest_free_order(&self, zone: Zone) -> Option<usize> {
+        self.free_blocks(zone).iter().rposition(|&n| n > 0)
+    }
+}
+
+/// Largest order of an aligned block starting at `frame` that fits in `len`.
+pub fn largest_order(frame: usize, len: usize) -> usize {
+    let align = if frame == 0 { MAX_ORDER } else { frame.trailing_zeros() as usize };
+    let fit = (usize::BITS - 1 - len.leading_zeros()) as usize;
+    align.min(fit).min(MAX_ORDER)
+}
+
+/// Smallest order whose block holds `count` frames.
+pub fn order_for(count: usize) -> usize {
+    count.next_power_of_two().trailing_zeros() as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_order_math() {
+        assert_eq!(order_for(1), 0);
+        assert_eq!(order_for(3), 2);
+        assert_eq!(order_for(512), HUGE_2M_ORDER);
+        assert_eq!(largest_order(0, 1), 0);
+        assert_eq!(largest_order(512, 1024), 9);
+        assert_eq!(largest_order(4, 100), 2);
+    }
+
+    #[test]
+    fn test_split_and_merge() {
+        static mut STATE: BuddyState = BuddyState::new();
+        // SAFETY: only this test touches STATE
+        let state = unsafe { &mut *core::ptr::addr_of_mut!(STATE) };
+
+        state.free_range(0, 1024);
+        assert_eq!(state.free_blocks(Zone::Dma32)[10], 1);
+
+        let a = state.alloc_block(0, Zone::Dma32).unwrap();
+        assert_eq!(a, 0);
+        assert_eq!(state.free_frames(Zone::Dma32), 1023);
+
+        let huge = state.alloc_block(HUGE_2M_ORDER, Zone::Dma32).unwrap();
+        assert_eq!(huge % 512, 0);
+
+        state.free_block(HUGE_2M_ORDER, huge);
+        state.free_block(0, a);
+        assert_eq!(state.free_blocks(Zone::Dma32)[10], 1);
+
+        assert!(state.take_frame(5));
+        assert!(!state.take_frame(5));
+        assert_eq!(state.free_frames(Zone::Dma32), 1023);
+
+        // Freeing free frames again changes nothing
+        assert!(!state.free_block(0, 4));
+        assert!(!state.free_block(3, 0));
+        assert!(state.any_free(0, 8));
+        assert!(!state.any_free(5, 6));
+        assert_eq!(state.free_range(0, 8), 0);
+        assert!(state.free_block(0, 5));
+        assert_eq!(state.free_blocks(Zone::Dma32)[10], 1);
+    }
+}
@@ -1,19 +1,24 @@
 //! # Physical Frame Allocator
 //!
-//! Manages physical memory frames. Uses a bitmap to track which frames are
-//! free and which are allocated.
+//! Manages physical memory frames. This is a facade over the zoned buddy
+//! allocator in [`super::buddy`].
 //!
 //! ## Design
 //!
 //! - Each frame is PAGE_SIZE (4096) bytes
-//! - A bitmap tracks allocation status (1 bit per frame)
-//! - Allocation is O(n) worst case, but typically faster due to hints
-//! - Supports contiguous multi-frame allocation
+//! - Free memory lives in a buddy allocator split into DMA32 and Normal zones
+//! - Single-frame allocations go through per-CPU caches to avoid the zone lock
+//! - Huge pages (2 MiB, 1 GiB) are naturally aligned buddy blocks
+//! - Device regions (MMIO, persistent memory) are tracked but never allocated
+//! - With the `kasan` feature, free frames are poisoned in the sanitizer shadow
 
 use core::sync::atomic::{AtomicUsize, Ordering};
 
 use spin::Mutex;
 
+use super::buddy::{self, BuddyState, Zone, NR_ORDERS};
+use crate::smp::MAX_CPUS;
+
 /// Page size constant (4KB) - same across all architectures we support
 pub const PAGE_SIZE: usize = 4096;
 
@@ -23,8 +28,14 @@ pub const MAX_PHYSICAL_MEMORY: usize = 16 * 1024 * 1024 * 1024;
 /// Number of frames in maximum memory.
 pub const MAX_FRAMES: usize = MAX_PHYSICAL_MEMORY / PAGE_SIZE;
 
-/// Bitmap size in u64 words.
-const BITMAP_WORDS: usize = MAX_FRAMES / 64;
+/// Frames held by one per-CPU cache at most.
+const PCP_CAPACITY: usize = 64;
+
+/// Frames moved between a per-CPU cache and the buddy allocator at once.
+const PCP_BATCH: usize = 16;
+
+/// Maximum number of device memory regions.
+const MAX_DEVICE_REGIONS: usize = 16;
 
 /// Physical frame number.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -58,16 +69,92 @@ pub enum FrameAllocError {
     FragmentedMemory,
 }
 
-/// A physical frame allocator using a bitmap.
+/// Huge page sizes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HugePageSize {
+    /// 2 MiB page
+    Size2M,
+    /// 1 GiB page
+    Size1G,
+}
+
+impl HugePageSize {
+    /// Buddy order of this page size.
+    pub const fn order(&self) -> usize {
+        match self {
+            HugePageSize::Size2M => buddy::HUGE_2M_ORDER,
+            HugePageSize::Size1G => buddy::HUGE_1G_ORDER,
+        }
+    }
+
+    /// Number of 4 KiB frames in this page size.
+    pub const fn frames(&self) -> usize {
+        1 << self.order()
+    }
+}
+
+/// Per-CPU cache of single free frames.
+struct PerCpuFrames {
+    frames: [usize; PCP_CAPACITY],
+    count: usize,
+}
+
+impl PerCpuFrames {
+    const fn new() -> Self {
+        Self {
+            frames: [0; PCP_CAPACITY],
+            count: 0,
+        }
+    }
+}
+
+/// Statistics for one zone.
+#[derive(Debug, Clone, Copy)]
+pub struct ZoneStats {
+    /// Zone described
+    pub zone: Zone,
+    /// Free blocks per order
+    pub free_blocks: [usize; NR_ORDERS],
+    /// Free frames in the buddy allocator
+    pub free_frames: usize,
+    /// Frames present in the zone (device zone: registered frames)
+    pub present_frames: usize,
+}
+
+impl ZoneStats {
+    /// Fragmentation index in percent for allocations of `order`.
+    ///
+    /// 0 means every free frame sits in a block of at least that order;
+    /// 100 means none of them do.
+    pub fn fragmentation(&self, order: usize) -> usize {
+        if self.free_frames == 0 {
+            return 0;
+        }
+        let usable: usize = self.free_blocks[order..]
+            .iter()
+            .enumerate()
+            .map(|(i, &n)| n << (order + i))
+            .sum();
+        100 - usable * 100 / self.free_frames
+    }
+}
+
+/// A physical frame allocator backed by a zoned buddy allocator.
 pub struct FrameAllocator {
-    /// Bitmap: 1 = free, 0 = used
-    bitmap: Mutex<[u64; BITMAP_WORDS]>,
+    /// Buddy free lists for all zones
+    buddy: Mutex<BuddyState>,
+    /// Per-CPU single-frame caches
+    pcp: [Mutex<PerCpuFrames>; MAX_CPUS],
+    /// Registered device regions as `(start_frame, end_frame)`
+    device_regions: Mutex<[Option<(usize, usize)>; MAX_DEVICE_REGIONS]>,
     /// Total frames in the system
     total_frames: AtomicUsize,
-    /// Free frames count
+    /// Free frames count (buddy plus per-CPU caches)
     free_frames: AtomicUsize,
-    /// Hint for next allocation search start
-    next_hint: AtomicUsize,
+    /// Frames held in per-CPU caches
+    pcp_frames: AtomicUsize,
+    /// Frames added per allocatable zone
+    zone_frames: [AtomicUsize; 2],
 }
 
 impl FrameAllocator {
@@ -75,11 +162,15 @@ impl FrameAllocator {
     ///
     /// All frames start as used. Call `add_region` to mark free regions.
     pub const fn new() -> Self {
+        const PCP_INIT: Mutex<PerCpuFrames> = Mutex::new(PerCpuFrames::new());
         Self {
-            bitmap: Mutex::new([0u64; BITMAP_WORDS]),
+            buddy: Mutex::new(BuddyState::new()),
+            pcp: [PCP_INIT; MAX_CPUS],
+            device_regions: Mutex::new([None; MAX_DEVICE_REGIONS]),
             total_frames: AtomicUsize::new(0),
             free_frames: AtomicUsize::new(0),
-            next_hint: AtomicUsize::new(0),
+            pcp_frames: AtomicUsize::new(0),
+            zone_frames: [AtomicUsize::new(0), AtomicUsize::new(0)],
         }
     }
 
@@ -91,22 +182,21 @@ impl FrameAllocator {
     /// * `size` - Size in bytes
     pub fn add_region(&self, start: u64, size: usize) {
         let start_frame = (start as usize + PAGE_SIZE - 1) / PAGE_SIZE;
-        let end_frame = (start as usize + size) / PAGE_SIZE;
+        let end_frame = ((start as usize + size) / PAGE_SIZE).min(MAX_FRAMES);
 
         if end_frame <= start_frame {
             return;
         }
 
         let count = end_frame - start_frame;
-        let mut bitmap = self.bitmap.lock();
-
-        for frame in start_frame..end_frame {
-            if frame >= MAX_FRAMES {
-                break;
-            }
-            let word = frame / 64;
-            let bit = frame % 64;
-            bitmap[word] |= 1u64 << bit;
+        self.buddy.lock().free_range(start_frame, end_frame);
+        #[cfg(feature = "kasan")]
+        super::kasan::poison_frames(FrameNumber::new(start_frame), count);
+
+        for zone in Zone::ALLOCATABLE {
+            let (lo, hi) = zone.frame_range();
+            let overlap = end_frame.min(hi).saturating_sub(start_frame.max(lo));
+            self.zone_frames[zone_slot(zone)].fetch_add(overlap, Ordering::SeqCst);
         }
 
         self.total_frames.fetch_add(count, Ordering::SeqCst);
@@ -121,29 +211,89 @@ impl FrameAllocator {
     /// * `size` - Size in bytes
     pub fn reserve_region(&self, start: u64, size: usize) {
         let start_frame = start as usize / PAGE_SIZE;
-        let end_frame = (start as usize + size + PAGE_SIZE - 1) / PAGE_SIZE;
+        let end_frame = ((start as usize + size + PAGE_SIZE - 1) / PAGE_SIZE).min(MAX_FRAMES);
 
-        let mut bitmap = self.bitmap.lock();
-        let mut freed = 0;
+        // Cached frames in the range must go back before they can be carved out
+        self.drain_all_pcp();
 
+        let mut buddy = self.buddy.lock();
+        let mut taken = 0;
         for frame in start_frame..end_frame {
-            if frame >= MAX_FRAMES {
-                break;
-            }
-            let word = frame / 64;
-            let bit = frame % 64;
-            if bitmap[word] & (1u64 << bit) != 0 {
-                bitmap[word] &= !(1u64 << bit);
-                freed += 1;
+            if buddy.take_frame(frame) {
+                taken += 1;
             }
         }
 
-        self.free_frames.fetch_sub(freed, Ordering::SeqCst);
+        self.free_frames.fetch_sub(taken, Ordering::SeqCst);
+        #[cfg(feature = "kasan")]
+        super::kasan::unpoison_frames(FrameNumber::new(start_frame), end_frame.saturating_sub(start_frame));
+    }
+
+    /// Registers a device memory region (MMIO, persistent memory).
+    ///
+    /// Device frames are never handed out by the allocator. Any part of the
+    /// region that was free memory is reserved.
+    pub fn add_device_region(&self, start: u64, size: usize) -> Result<(), FrameAllocError> {
+        let start_frame = start as usize / PAGE_SIZE;
+        let end_frame = (start as usize + size + PAGE_SIZE - 1) / PAGE_SIZE;
+        if end_frame <= start_frame {
+            return Err(FrameAllocError::ZeroFrames);
+        }
+
+        {
+            let mut regions = self.device_regions.lock();
+            let slot = regions
+                .iter_mut()
+                .find(|r| r.is_none())
+                .ok_or(FrameAllocError::OutOfMemory)?;
+            *slot = Some((start_frame, end_frame));
+        }
+
+        self.reserve_region(start, size);
+        Ok(())
+    }
+
+    /// Returns true if the frame belongs to a registered device region.
+    pub fn is_device_frame(&self, frame: FrameNumber) -> bool {
+        self.device_regions
+            .lock()
+            .iter()
+            .flatten()
+            .any(|&(lo, hi)| frame.0 >= lo && frame.0 < hi)
     }
 
     /// Allocates a single frame.
     pub fn allocate(&self) -> Result<FrameNumber, FrameAllocError> {
-        self.allocate_contiguous(1).map(|f| f)
+        let cpu = crate::smp::percpu::cpu_id().as_index();
+        let mut pcp = self.pcp[cpu].lock();
+
+        if pcp.count == 0 {
+            // Refill from the buddy allocator
+            let mut buddy = self.buddy.lock();
+            while pcp.count < PCP_BATCH {
+                match alloc_any_zone(&mut buddy, 0) {
+                    Some(frame) => {
+                        let n = pcp.count;
+                        pcp.frames[n] = frame;
+                        pcp.count += 1;
+                    }
+                    None => break,
+                }
+            }
+            self.pcp_frames.fetch_add(pcp.count, Ordering::Relaxed);
+        }
+
+        if pcp.count == 0 {
+            return Err(FrameAllocError::OutOfMemory);
+        }
+
+        pcp.count -= 1;
+        let frame = pcp.frames[pcp.count];
+        self.pcp_frames.fetch_sub(1, Ordering::Relaxed);
+        self.free_frames.fetch_sub(1, Ordering::SeqCst);
+        #[cfg(feature = "kasan")]
+        super::kasan::unpoison_frames(FrameNumber::new(frame), 1);
+        Ok(FrameNumber::new(frame))
     }
 
     /// Allocates multiple contiguous frames.
@@ -151,102 +301,196 @@ impl FrameAllocator {
         if count == 0 {
             return Err(FrameAllocError::ZeroFrames);
         }
-
-        let mut bitmap = self.bitmap.lock();
-        let hint = self.next_hint.load(Ordering::Relaxed);
-
-        // Search from hint to end
-        if let Some(frame) = self.find_contiguous(&bitmap, hint, MAX_FRAMES, count) {
-            self.mark_used(&mut bitmap, frame, count);
-            self.next_hint.store(frame + count, Ordering::Relaxed);
-            self.free_frames.fetch_sub(count, Ordering::SeqCst);
-            return Ok(FrameNumber::new(frame));
+        if count == 1 {
+            return self.allocate();
         }
 
-        // Wrap around and search from start to hint
-        if let Some(frame) = self.find_contiguous(&bitmap, 0, hint, count) {
-            self.mark_used(&mut bitmap, frame, count);
-            self.next_hint.store(frame + count, Ordering::Relaxed);
-            self.free_frames.fetch_sub(count, Ordering::SeqCst);
-            return Ok(FrameNumber::new(frame));
+        let order = buddy::order_for(count);
+        if order >= NR_ORDERS {
+            return Err(FrameAllocError::FragmentedMemory);
         }
 
-        Err(FrameAllocError::OutOfMemory)
+        let mut buddy = self.buddy.lock();
+        let start = match alloc_any_zone(&mut buddy, order) {
+            Some(start) => start,
+            None => return Err(self.exhaustion_error(&buddy, count)),
+        };
+
+        // Give back the tail beyond what was asked for
+        buddy.free_range(start + count, start + (1 << order));
+        self.free_frames.fetch_sub(count, Ordering::SeqCst);
+        #[cfg(feature = "kasan")]
+        super::kasan::unpoison_frames(FrameNumber::new(start), count);
+        Ok(FrameNumber::new(start))
     }
 
-    /// Finds contiguous free frames.
-    fn find_contiguous(
-        &self,
-        bitmap: &[u64; BITMAP_WORDS],
-        start: usize,
-        end: usize,
-        count: usize,
-    ) -> Option<usize> {
-        let mut run_start = None;
-        let mut run_len = 0;
+    /// Allocates contiguous frames from a specific zone.
+    ///
+    /// Used by drivers whose devices can only address the DMA32 zone.
+    pub fn allocate_in_zone(&self, count: usize, zone: Zone) -> Result<FrameNumber, FrameAllocError> {
+        if count == 0 {
+            return Err(FrameAllocError::ZeroFrames);
+        }
+        if zone == Zone::Device {
+            return Err(FrameAllocError::OutOfMemory);
+        }
 
-        for frame in start..end {
-            if frame >= MAX_FRAMES {
-                break;
-            }
+        let order = buddy::order_for(count);
+        if order >= NR_ORDERS {
+            return Err(FrameAllocError::FragmentedMemory);
+        }
 
-            let word = frame / 64;
-            let bit = frame % 64;
+        let mut buddy = self.buddy.lock();
+        let start = buddy
+            .alloc_block(order, zone)
+            .ok_or_else(|| self.exhaustion_error(&buddy, count))?;
+        buddy.free_range(start + count, start + (1 << order));
+        self.free_frames.fetch_sub(count, Ordering::SeqCst);
+        #[cfg(feature = "kasan")]
+        super::kasan::unpoison_frames(FrameNumber::new(start), count);
+        Ok(FrameNumber::new(start))
+    }
 
-            if bitmap[word] & (1u64 << bit) != 0 {
-                // Frame is free
-                if run_start.is_none() {
-                    run_start = Some(frame);
-                    run_len = 1;
-                } else {
-                    run_len += 1;
-                }
+    /// Allocates a naturally aligned huge page.
+    pub fn allocate_huge(&self, size: HugePageSize) -> Result<FrameNumber, FrameAllocError> {
+        let mut buddy = self.buddy.lock();
+        let start = alloc_any_zone(&mut buddy, size.order())
+            .ok_or_else(|| self.exhaustion_error(&buddy, size.frames()))?;
+        self.free_frames.fetch_sub(size.frames(), Ordering::SeqCst);
+        #[cfg(feature = "kasan")]
+        super::kasan::unpoison_frames(FrameNumber::new(start), size.frames());
+        Ok(FrameNumber::new(start))
+    }
 
-                if run_len >= count {
-                    return run_start;
-                }
-            } else {
-                // Frame is used, reset run
-                run_start = None;
-                run_len = 0;
-            }
+    /// Frees a huge page returned by `allocate_huge`.
+    pub fn free_huge(&self, frame: FrameNumber, size: HugePageSize) {
+        if !self.buddy.lock().free_block(size.order(), frame.0) {
+            return;
         }
-
-        None
+        #[cfg(feature = "kasan")]
+        super::kasan::poison_frames(frame, size.frames());
+        self.free_frames.fetch_add(size.frames(), Ordering::SeqCst);
     }
 
-    /// Marks frames as used.
-    fn mark_used(&self, bitmap: &mut [u64; BITMAP_WORDS], start: usize, count: usize) {
-        for frame in start..start + count {
-            if frame >= MAX_FRAMES {
-                break;
-            }
-            let word = frame / 64;
-            let bit = frame % 64;
-            bitmap[word] &= !(1u64 << bit);
+    /// Picks the error for a failed allocation of `count` frames.
+    fn exhaustion_error(&self, buddy: &BuddyState, count: usize) -> FrameAllocError {
+        let free: usize = Zone::ALLOCATABLE.iter().map(|&z| buddy.free_frames(z)).sum();
+        if free >= count {
+            FrameAllocError::FragmentedMemory
+        } else {
+            FrameAllocError::OutOfMemory
         }
     }
 
     /// Frees a single frame.
+    ///
+    /// Frames out of range or already free are ignored. A frame freed twice
+    /// while sitting in another CPU's cache is caught when that cache drains.
     pub fn free(&self, frame: FrameNumber) {
-        self.free_contiguous(frame, 1);
+        if frame.0 >= MAX_FRAMES {
+            return;
+        }
+        let cpu = crate::smp::percpu::cpu_id().as_index();
+        let mut pcp = self.pcp[cpu].lock();
+        let mut buddy = self.buddy.lock();
+        if pcp.frames[..pcp.count].contains(&frame.0) || buddy.is_frame_free(frame.0) {
+            return;
+        }
+        #[cfg(feature = "kasan")]
+        super::kasan::poison_frames(frame, 1);
+
+        if pcp.count == PCP_CAPACITY {
+            // Cache is full: hand a batch back to the buddy allocator
+            for _ in 0..PCP_BATCH {
+                pcp.count -= 1;
+                self.uncache(&mut buddy, pcp.frames[pcp.count]);
+            }
+            self.pcp_frames.fetch_sub(PCP_BATCH, Ordering::Relaxed);
+        }
+        drop(buddy);
+
+        let n = pcp.count;
+        pcp.frames[n] = frame.0;
+        pcp.count += 1;
+        self.pcp_frames.fetch_add(1, Ordering::Relaxed);
+        self.free_frames.fetch_add(1, Ordering::SeqCst);
     }
 
     /// Frees multiple contiguous frames.
+    ///
+    /// The whole range is ignored if any of it is already free; frames past
+    /// `MAX_FRAMES` are dropped.
     pub fn free_contiguous(&self, start: FrameNumber, count: usize) {
-        let mut bitmap = self.bitmap.lock();
+        if count == 1 {
+            return self.free(start);
+        }
+        let end = start.0.saturating_add(count).min(MAX_FRAMES);
+        if start.0 >= end {
+            return;
+        }
+        let cpu = crate::smp::percpu::cpu_id().as_index();
+        let pcp = self.pcp[cpu].lock();
+        let mut buddy = self.buddy.lock();
+        let cached = pcp.frames[..pcp.count].iter().any(|&f| f >= start.0 && f < end);
+        if cached || buddy.any_free(start.0, end) {
+            return;
+        }
+        #[cfg(feature = "kasan")]
+        super::kasan::poison_frames(start, end - start.0);
+        let freed = buddy.free_range(start.0, end);
+        self.free_frames.fetch_add(freed, Ordering::SeqCst);
+    }
 
-        for i in 0..count {
-            let frame = start.0 + i;
-            if frame >= MAX_FRAMES {
-                break;
+    /// Returns a frame from a per-CPU cache to the buddy allocator.
+    ///
+    /// If it is already free there, it was freed twice through different
+    /// caches and is only counted once.
+    fn uncache(&self, buddy: &mut BuddyState, frame: usize) {
+        if !buddy.free_block(0, frame) {
+            self.free_frames.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Returns every per-CPU cached frame to the buddy allocator.
+    pub fn drain_all_pcp(&self) {
+        for cache in self.pcp.iter() {
+            let mut pcp = cache.lock();
+            if pcp.count == 0 {
+                continue;
             }
-            let word = frame / 64;
-            let bit = frame % 64;
-            bitmap[word] |= 1u64 << bit;
+            let mut buddy = self.buddy.lock();
+            for i in 0..pcp.count {
+                self.uncache(&mut buddy, pcp.frames[i]);
+            }
+            self.pcp_frames.fetch_sub(pcp.count, Ordering::Relaxed);
+            pcp.count = 0;
         }
+    }
 
-        self.free_frames.fetch_add(count, Ordering::SeqCst);
+    /// Returns statistics for a zone.
+    pub fn zone_stats(&self, zone: Zone) -> ZoneStats {
+        let buddy = self.buddy.lock();
+        let present_frames = match zone {
+            Zone::Device => self
+                .device_regions
+                .lock()
+                .iter()
+                .flatten()
+                .map(|&(lo, hi)| hi - lo)
+                .sum(),
+            _ => self.zone_frames[zone_slot(zone)].load(Ordering::Relaxed),
+        };
+        ZoneStats {
+            zone,
+            free_blocks: buddy.free_blocks(zone),
+            free_frames: buddy.free_frames(zone),
+            present_frames,
+        }
+    }
+
+    /// Returns the number of frames sitting in per-CPU caches.
+    pub fn pcp_count(&self) -> usize {
+        self.pcp_frames.load(Ordering::Relaxed)
     }
 
     /// Returns the number of free frames.
@@ -270,6 +514,21 @@ impl FrameAllocator {
     }
 }
 
+/// Allocates a block, preferring Normal and falling back to DMA32.
+fn alloc_any_zone(buddy: &mut BuddyState, order: usize) -> Option<usize> {
+    buddy
+        .alloc_block(order, Zone::Normal)
+        .or_else(|| buddy.alloc_block(order, Zone::Dma32))
+}
+
+/// Index into per-zone counters for allocatable zones.
+fn zone_slot(zone: Zone) -> usize {
+    match zone {
+        Zone::Normal => 1,
+        _ => 0,
+    }
+}
+
 /// Global frame allocator.
 pub static FRAME_ALLOCATOR: FrameAllocator = FrameAllocator::new();
 
@@ -285,4 +544,60 @@ mod tests {
         let from_addr = FrameNumber::from_address(0x5000);
         assert_eq!(from_addr.0, 5);
     }
+
+    #[test]
+    fn test_contiguous_returns_tail() {
+        static ALLOC: FrameAllocator = FrameAllocator::new();
+        ALLOC.add_region(0x10_0000, 64 * PAGE_SIZE);
+
+        let start = ALLOC.allocate_contiguous(3).unwrap();
+        assert_eq!(ALLOC.free_count(), 61);
+        ALLOC.free_contiguous(start, 3);
+        assert_eq!(ALLOC.free_count(), 64);
+        assert_eq!(ALLOC.zone_stats(Zone::Dma32).free_frames, 64);
+    }
+
+    #[test]
+    fn test_normal_zone_first() {
+        static ALLOC: FrameAllocator = FrameAllocator::new();
+        ALLOC.add_region(0x1_0000_0000, 16 * PAGE_SIZE);
+        ALLOC.add_region(0x10_0000, 16 * PAGE_SIZE);
+
+        let frame = ALLOC.allocate_contiguous(4).unwrap();
+        assert!(frame.address() >= 0x1_0000_0000);
+        // Normal has no room left for 16, so DMA32 serves it
+        let frame = ALLOC.allocate_contiguous(16).unwrap();
+        assert!(frame.address() < 0x1_0000_0000);
+        assert_eq!(ALLOC.zone_stats(Zone::Dma32).free_frames, 0);
+    }
+
+    #[test]
+    fn test_double_free_ignored() {
+        static ALLOC: FrameAllocator = FrameAllocator::new();
+        ALLOC.add_region(0x10_0000, 64 * PAGE_SIZE);
+
+        let frame = ALLOC.allocate().unwrap();
+        ALLOC.free(frame);
+        ALLOC.free(frame);
+        let start = ALLOC.allocate_contiguous(8).unwrap();
+        ALLOC.free_contiguous(start, 8);
+        ALLOC.free_contiguous(start, 8);
+        ALLOC.free_contiguous(start, 2);
+        ALLOC.free(FrameNumber::new(MAX_FRAMES));
+        assert_eq!(ALLOC.free_count(), 64);
+
+        ALLOC.drain_all_pcp();
+        assert_eq!(ALLOC.zone_stats(Zone::Dma32).free_frames, 64);
+    }
+
+    #[test]
+    fn test_huge_page_alignment() {
+        static ALLOC: FrameAllocator = FrameAllocator::new();
+        ALLOC.add_region(0x40_0000, 8 * 1024 * 1024);
+
+        let page = ALLOC.allocate_huge(HugePageSize::Size2M).unwrap();
+        assert_eq!(page.address() % (2 * 1024 * 1024), 0);
+        ALLOC.free_huge(page, HugePageSize::Size2M);
+        assert_eq!(ALLOC.free_count(), 2048);
+    }
 }
@@ -0,0 +1,816 @@
+//! # Kernel Address Sanitizer (KASAN)
+//!
+//! Software address sanitizer for the x86_64 kernel heap, slab caches and
+//! physical frames. [`mte`](super::mte) covers aarch64 hardware with memory
+//! tagging; this is the x86_64 counterpart and is only built with the
+//! `kasan` feature (`./scripts/splax run --kasan`).
+//!
+//! ## Design
+//!
+//! - Every 8 bytes of the kernel heap have one shadow byte: 0 means all 8
+//!   bytes are addressable, 1-7 means only the first N are, and anything
+//!   else is a poison code saying why the granule is off limits
+//! - Every physical page below 4 GiB has one shadow byte; frames owned by
+//!   the frame allocator stay poisoned until they are handed out
+//! - Heap allocations get a trailing red zone holding their size and the
+//!   allocation and free call stacks
+//! - Freed heap blocks wait in a FIFO quarantine before going back to the
+//!   free-list allocator, so stale pointers keep hitting poisoned memory
+//! - Slab caches poison unused slots and freed objects inside their slabs
+//! - Accesses are checked by the `__asan_*` callbacks that
+//!   `-Zsanitizer=kernel-address` emits; [`check_read`] and [`check_write`]
+//!   are the explicit equivalents
+//!
+//! The shadow is not at LLVM's fixed offset, so the instrumentation must be
+//! built with `-asan-instrumentation-with-call-threshold=0` (every access
+//! goes through a callback). Reports go to the serial console and the kernel
+//! keeps running. Call stacks are raw return addresses; resolve them with
+//! `addr2line -e target/x86_64-unknown-none/release/splax_kernel`.
+//!
+//! ```text
+//! ┌────────┬──────────────────┬──────────┬───────┐
+//! │ header │      object      │ metadata │  pad  │
+//! └────────┴──────────────────┴──────────┴───────┘
+//!    0xFA     00 / partial       0xF9      0xFA
+//! ```
+
+#![sanitize(address = "off")]
+
+#[cfg(not(target_arch = "x86_64"))]
+compile_error!("the `kasan` feature is only supported on x86_64");
+
+use core::alloc::Layout;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+
+use spin::Mutex;
+
+use super::frame::{FrameNumber, PAGE_SIZE};
+use super::KERNEL_HEAP_SIZE;
+use crate::smp::MAX_CPUS;
+
+/// Bytes covered by one heap shadow byte.
+const GRANULE: usize = 8;
+
+/// Physical memory covered by the frame shadow.
+const FRAME_SHADOW_LIMIT: usize = 4 * 1024 * 1024 * 1024;
+
+/// Return addresses recorded per call stack.
+const STACK_DEPTH: usize = 6;
+
+/// Largest stack frame the frame-pointer walk will step over.
+const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+/// Red zone appended to every heap allocation.
+const REDZONE: usize = 128;
+
+/// Freed heap bytes held back from reuse at most.
+const QUARANTINE_BYTES: usize = 512 * 1024;
+
+/// Freed heap blocks held back from reuse at most.
+const QUARANTINE_SLOTS: usize = 1024;
+
+/// Granules searched when looking for the metadata of a bad address.
+const META_SEARCH_GRANULES: usize = 64 * 1024;
+
+/// Marks valid allocation metadata ("KASN").
+const META_MAGIC: u32 = 0x4B41_534E;
+
+/// Heap memory outside any object: allocator headers, free blocks, red zones.
+pub const HEAP_REDZONE: u8 = 0xFA;
+/// Heap object that has been freed and sits in the quarantine.
+pub const HEAP_FREED: u8 = 0xFB;
+/// Allocation metadata inside a heap red zone.
+pub const HEAP_META: u8 = 0xF9;
+/// Slab slot that is not handed out, or padding around a slab object.
+pub const SLAB_REDZONE: u8 = 0xFC;
+/// Slab object that has been freed.
+pub const SLAB_FREED: u8 = 0xFD;
+/// Physical frame owned by the frame allocator.
+pub const FRAME_FREED: u8 = 0xFE;
+
+/// One shadow byte per heap granule.
+static HEAP_SHADOW: [AtomicU8; KERNEL_HEAP_SIZE / GRANULE] =
+    [const { AtomicU8::new(0) }; KERNEL_HEAP_SIZE / GRANULE];
+
+/// One shadow byte per physical frame.
+static FRAME_SHADOW: [AtomicU8; FRAME_SHADOW_LIMIT / PAGE_SIZE] =
+    [const { AtomicU8::new(0) }; FRAME_SHADOW_LIMIT / PAGE_SIZE];
+
+/// Start of the kernel heap (0 until [`init`]).
+static HEAP_START: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether accesses are checked.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Set while a report is being printed.
+static REPORTING: AtomicBool = AtomicBool::new(false);
+
+/// Number of bad accesses detected.
+static REPORTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Per-CPU nesting depth of [`Suppress`] guards.
+static SUPPRESS: [AtomicUsize; MAX_CPUS] = [const { AtomicUsize::new(0) }; MAX_CPUS];
+
+static QUARANTINE: Mutex<Quarantine> = Mutex::new(Quarantine::new());
+
+/// Per-allocation metadata stored at the start of the red zone.
+#[repr(C)]
+struct AllocMeta {
+    magic: u32,
+    state: u32,
+    size: usize,
+    alloc_stack: [usize; STACK_DEPTH],
+    free_stack: [usize; STACK_DEPTH],
+}
+
+const META_SIZE: usize = core::mem::size_of::<AllocMeta>();
+const _: () = assert!(META_SIZE.is_multiple_of(GRANULE) && META_SIZE <= REDZONE);
+
+/// `AllocMeta::state` of a live allocation.
+const STATE_LIVE: u32 = 1;
+/// `AllocMeta::state` of a quarantined allocation.
+const STATE_FREED: u32 = 2;
+
+/// FIFO of freed heap blocks as `(address, padded size, align)`.
+struct Quarantine {
+    entries: [(usize, usize, usize); QUARANTINE_SLOTS],
+    head: usize,
+    len: usize,
+    bytes: usize,
+}
+
+impl Quarantine {
+    const fn new() -> Self {
+        Self {
+            entries: [(0, 0, 0); QUARANTINE_SLOTS],
+            head: 0,
+            len: 0,
+            bytes: 0,
+        }
+    }
+
+    fn push(&mut self, addr: usize, layout: Layout) {
+        let tail = (self.head + self.len) % QUARANTINE_SLOTS;
+        self.entries[tail] = (addr, layout.size(), layout.align());
+        self.len += 1;
+        self.bytes += layout.size();
+    }
+
+    fn pop(&mut self) -> Option<(usize, Layout)> {
+        if self.len == 0 {
+            return None;
+        }
+        let (addr, size, align) = self.entries[self.head];
+        self.head = (self.head + 1) % QUARANTINE_SLOTS;
+        self.len -= 1;
+        self.bytes -= size;
+        Layout::from_size_align(size, align).ok().map(|l| (addr, l))
+    }
+
+    fn over_budget(&self) -> bool {
+        self.len == QUARANTINE_SLOTS || self.bytes > QUARANTINE_BYTES
+    }
+}
+
+/// Guard returned by [`suppress`].
+pub struct Suppress {
+    cpu: usize,
+}
+
+/// Disables reports on the current CPU until the guard is dropped.
+///
+/// Allocator code is built without instrumentation, but it still calls
+/// instrumented helpers from `core` on memory it has poisoned itself.
+pub fn suppress() -> Suppress {
+    let cpu = crate::smp::percpu::cpu_id().as_index();
+    SUPPRESS[cpu].fetch_add(1, Ordering::Relaxed);
+    Suppress { cpu }
+}
+
+impl Drop for Suppress {
+    fn drop(&mut self) {
+        SUPPRESS[self.cpu].fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// =============================================================================
+// Shadow Memory
+// =============================================================================
+
+/// Starts checking accesses to the heap at `heap_start`.
+///
+/// The whole heap starts out poisoned; allocations unpoison their objects.
+pub fn init(heap_start: usize, heap_size: usize) {
+    HEAP_START.store(heap_start, Ordering::Relaxed);
+    poison(heap_start, heap_size.min(KERNEL_HEAP_SIZE), HEAP_REDZONE);
+    ENABLED.store(true, Ordering::Release);
+}
+
+/// Returns the shadow byte of a heap address.
+fn heap_shadow(addr: usize) -> Option<&'static AtomicU8> {
+    let start = HEAP_START.load(Ordering::Relaxed);
+    if start == 0 || addr < start {
+        return None;
+    }
+    HEAP_SHADOW.get((addr - start) / GRANULE)
+}
+
+/// Marks `len` bytes at the granule-aligned heap address `addr` with `code`.
+pub fn poison(addr: usize, len: usize, code: u8) {
+    for granule in (addr..addr + len).step_by(GRANULE) {
+        if let Some(shadow) = heap_shadow(granule) {
+            shadow.store(code, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Marks `len` bytes at the granule-aligned heap address `addr` addressable.
+pub fn unpoison(addr: usize, len: usize) {
+    let full = len / GRANULE * GRANULE;
+    poison(addr, full, 0);
+    if !len.is_multiple_of(GRANULE) {
+        if let Some(shadow) = heap_shadow(addr + full) {
+            shadow.store((len % GRANULE) as u8, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Marks frames as owned by the frame allocator.
+pub fn poison_frames(start: FrameNumber, count: usize) {
+    for frame in start.0..start.0 + count {
+        if let Some(shadow) = FRAME_SHADOW.get(frame) {
+            shadow.store(FRAME_FREED, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Marks frames as handed out.
+pub fn unpoison_frames(start: FrameNumber, count: usize) {
+    for frame in start.0..start.0 + count {
+        if let Some(shadow) = FRAME_SHADOW.get(frame) {
+            shadow.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Returns the first bad address in `addr..addr + size` and its shadow code.
+fn first_bad(addr: usize, size: usize) -> Option<(usize, u8)> {
+    let end = addr.checked_add(size)?;
+
+    for page in addr / PAGE_SIZE..=(end - 1) / PAGE_SIZE {
+        let code = FRAME_SHADOW.get(page).map_or(0, |s| s.load(Ordering::Relaxed));
+        if code != 0 {
+            return Some(((page * PAGE_SIZE).max(addr), code));
+        }
+    }
+
+    let heap_start = HEAP_START.load(Ordering::Relaxed);
+    if heap_start == 0 {
+        return None;
+    }
+    let mut at = addr.max(heap_start);
+    let stop = end.min(heap_start + KERNEL_HEAP_SIZE);
+    while at < stop {
+        let granule = at / GRANULE * GRANULE;
+        let code = heap_shadow(at)?.load(Ordering::Relaxed);
+        if code != 0 {
+            if (code as usize) < GRANULE {
+                // Partially addressable: only the first `code` bytes are valid
+                let last = stop.min(granule + GRANULE) - 1;
+                if last - granule >= code as usize {
+                    return Some((at.max(granule + code as usize), code));
+                }
+            } else {
+                return Some((at, code));
+            }
+        }
+        at = granule + GRANULE;
+    }
+    None
+}
+
+// =============================================================================
+// Heap Hooks
+// =============================================================================
+
+/// Returns the layout the heap allocator must reserve for `layout`.
+pub fn padded_layout(layout: Layout) -> Option<Layout> {
+    let size = align_up(layout.size(), GRANULE).checked_add(REDZONE)?;
+    Layout::from_size_align(size, layout.align().max(GRANULE)).ok()
+}
+
+/// Records a heap allocation of `size` bytes at `ptr`.
+///
+/// `ptr` must come from the heap allocator with a [`padded_layout`].
+pub fn heap_alloc(ptr: *mut u8, size: usize) {
+    let addr = ptr as usize;
+    let meta_at = addr + align_up(size, GRANULE);
+
+    unpoison(addr, size);
+    poison(meta_at, REDZONE, HEAP_REDZONE);
+    poison(meta_at, META_SIZE, HEAP_META);
+
+    let mut meta = AllocMeta {
+        magic: META_MAGIC,
+        state: STATE_LIVE,
+        size,
+        alloc_stack: [0; STACK_DEPTH],
+        free_stack: [0; STACK_DEPTH],
+    };
+    capture_stack(&mut meta.alloc_stack);
+    // SAFETY: the red zone was reserved by `padded_layout` and is aligned
+    unsafe { *(meta_at as *mut AllocMeta) = meta };
+}
+
+/// Poisons a heap block being freed and moves it into the quarantine.
+///
+/// `release` is called for every block that leaves the quarantine and can
+/// go back to the allocator. Double and invalid frees are reported and the
+/// block is leaked.
+pub fn heap_free(ptr: *mut u8, layout: Layout, release: impl FnMut(*mut u8, Layout)) {
+    let addr = ptr as usize;
+    let Some(padded) = padded_layout(layout) else {
+        return;
+    };
+
+    let meta = match meta_at(addr + align_up(layout.size(), GRANULE)) {
+        Some(meta) if meta.state == STATE_LIVE && meta.size == layout.size() => meta,
+        Some(meta) if meta.state == STATE_FREED => {
+            report_free("double-free", addr, Some(meta));
+            return;
+        }
+        other => {
+            report_free("invalid-free", addr, other);
+            return;
+        }
+    };
+
+    meta.state = STATE_FREED;
+    capture_stack(&mut meta.free_stack);
+    poison(addr, align_up(layout.size(), GRANULE), HEAP_FREED);
+
+    QUARANTINE.lock().push(addr, padded);
+    evict(release, false);
+}
+
+/// Releases every quarantined block, e.g. when the heap is exhausted.
+///
+/// Returns the number of bytes released.
+pub fn drain_quarantine(release: impl FnMut(*mut u8, Layout)) -> usize {
+    evict(release, true)
+}
+
+/// Releases quarantined blocks, oldest first, until the quarantine is back
+/// within budget (or empty if `all`). Returns the bytes released.
+///
+/// The quarantine lock is dropped before each `release`, which takes the
+/// heap lock.
+fn evict(mut release: impl FnMut(*mut u8, Layout), all: bool) -> usize {
+    let mut released = 0;
+    loop {
+        let next = {
+            let mut quarantine = QUARANTINE.lock();
+            if all || quarantine.over_budget() {
+                quarantine.pop()
+            } else {
+                None
+            }
+        };
+        let Some((addr, layout)) = next else {
+            break;
+        };
+        let meta = addr + layout.size() - REDZONE;
+        // SAFETY: quarantined blocks keep their metadata until released here
+        unsafe { (*(meta as *mut AllocMeta)).magic = 0 };
+        poison(addr, layout.size(), HEAP_REDZONE);
+        release(addr as *mut u8, layout);
+        released += layout.size();
+    }
+    released
+}
+
+/// Returns the metadata at `addr` if it is valid.
+fn meta_at(addr: usize) -> Option<&'static mut AllocMeta> {
+    let start = HEAP_START.load(Ordering::Relaxed);
+    if !addr.is_multiple_of(GRANULE) || addr < start || addr + META_SIZE > start + KERNEL_HEAP_SIZE {
+        return None;
+    }
+    // SAFETY: the range lies inside the heap and is aligned
+    let meta = unsafe { &mut *(addr as *mut AllocMeta) };
+    (meta.magic == META_MAGIC).then_some(meta)
+}
+
+/// Finds the metadata of the heap object closest to a bad address.
+fn find_meta(addr: usize) -> Option<&'static mut AllocMeta> {
+    let code_at = |a: usize| heap_shadow(a).map(|s| s.load(Ordering::Relaxed));
+    let mut at = addr / GRANULE * GRANULE;
+
+    match code_at(at)? {
+        // Red zone: the object it belongs to ends before it
+        HEAP_REDZONE => {
+            let mut steps = 0;
+            while code_at(at)? == HEAP_REDZONE && steps < REDZONE / GRANULE {
+                at -= GRANULE;
+                steps += 1;
+            }
+            if code_at(at)? != HEAP_META {
+                return None;
+            }
+        }
+        HEAP_META => {}
+        // Inside an object: its metadata follows it
+        _ => {
+            let mut steps = 0;
+            while code_at(at)? != HEAP_META {
+                if code_at(at)? == HEAP_REDZONE || steps == META_SEARCH_GRANULES {
+                    return None;
+                }
+                at += GRANULE;
+                steps += 1;
+            }
+        }
+    }
+
+    while code_at(at - GRANULE)? == HEAP_META {
+        at -= GRANULE;
+    }
+    meta_at(at)
+}
+
+// =============================================================================
+// Checks and Reports
+// =============================================================================
+
+fn check(addr: usize, size: usize, write: bool) -> bool {
+    if size == 0 || !ENABLED.load(Ordering::Relaxed) {
+        return true;
+    }
+    let Some((bad, code)) = first_bad(addr, size) else {
+        return true;
+    };
+    if SUPPRESS[crate::smp::percpu::cpu_id().as_index()].load(Ordering::Relaxed) != 0 {
+        return true;
+    }
+    report_access(bad, addr, size, write, code);
+    false
+}
+
+/// Checks a read of `len` bytes at `ptr`, reporting it if it is bad.
+///
+/// Returns whether the access is valid.
+pub fn check_read(ptr: *const u8, len: usize) -> bool {
+    check(ptr as usize, len, false)
+}
+
+/// Checks a write of `len` bytes at `ptr`, reporting it if it is bad.
+///
+/// Returns whether the access is valid.
+pub fn check_write(ptr: *const u8, len: usize) -> bool {
+    check(ptr as usize, len, true)
+}
+
+/// Returns the number of bad accesses and frees reported so far.
+pub fn reports() -> usize {
+    REPORTS.load(Ordering::Relaxed)
+}
+
+fn bug_type(code: u8) -> &'static str {
+    match code {
+        1..=7 | HEAP_REDZONE | HEAP_META => "heap-out-of-bounds",
+        HEAP_FREED => "use-after-free",
+        SLAB_REDZONE => "slab-out-of-bounds",
+        SLAB_FREED => "slab-use-after-free",
+        FRAME_FREED => "use-after-free-page",
+        _ => "wild-memory-access",
+    }
+}
+
+fn report_access(bad: usize, addr: usize, size: usize, write: bool, code: u8) {
+    REPORTS.fetch_add(1, Ordering::Relaxed);
+    if REPORTING.swap(true, Ordering::Acquire) {
+        return;
+    }
+
+    crate::serial_println!("==================================================================");
+    crate::serial_println!("BUG: KASAN: {} at {:#x}", bug_type(code), bad);
+    crate::serial_println!(
+        "{} of size {} at addr {:#x}",
+        if write { "Write" } else { "Read" },
+        size,
+        addr
+    );
+    print_current_stack();
+
+    if matches!(code, 1..=7 | HEAP_REDZONE | HEAP_META | HEAP_FREED) {
+        if let Some(meta) = find_meta(bad) {
+            print_meta(bad, meta);
+        }
+        print_shadow(bad);
+    }
+    crate::serial_println!("==================================================================");
+
+    REPORTING.store(false, Ordering::Release);
+}
+
+fn report_free(kind: &str, addr: usize, meta: Option<&mut AllocMeta>) {
+    REPORTS.fetch_add(1, Ordering::Relaxed);
+    if REPORTING.swap(true, Ordering::Acquire) {
+        return;
+    }
+
+    crate::serial_println!("==================================================================");
+    crate::serial_println!("BUG: KASAN: {} of {:#x}", kind, addr);
+    print_current_stack();
+    if let Some(meta) = meta {
+        print_meta(addr, meta);
+    }
+    print_shadow(addr);
+    crate::serial_println!("==================================================================");
+
+    REPORTING.store(false, Ordering::Release);
+}
+
+fn print_current_stack() {
+    let mut stack = [0; STACK_DEPTH];
+    capture_stack(&mut stack);
+    print_stack("Call stack", &stack);
+}
+
+fn print_meta(addr: usize, meta: &AllocMeta) {
+    let object = meta as *const AllocMeta as usize - align_up(meta.size, GRANULE);
+    crate::serial_println!(
+        "The address is {} bytes into a {}-byte object at {:#x}",
+        addr as isize - object as isize,
+        meta.size,
+        object
+    );
+    print_stack("Allocated at", &meta.alloc_stack);
+    if meta.state == STATE_FREED {
+        print_stack("Freed at", &meta.free_stack);
+    }
+}
+
+fn print_stack(title: &str, stack: &[usize; STACK_DEPTH]) {
+    crate::serial_println!("{}:", title);
+    for (i, &ip) in stack.iter().take_while(|&&ip| ip != 0).enumerate() {
+        crate::serial_println!("  #{} {:#x}", i, ip);
+    }
+}
+
+/// Prints the heap shadow around `addr`, marking its granule.
+fn print_shadow(addr: usize) {
+    let Some(start) = heap_shadow(addr).map(|_| HEAP_START.load(Ordering::Relaxed)) else {
+        return;
+    };
+    const ROW: usize = 16 * GRANULE;
+    let row_of = |a: usize| start + (a - start) / ROW * ROW;
+    let first = row_of(addr).saturating_sub(2 * ROW).max(start);
+    let last = (row_of(addr) + 2 * ROW).min(start + KERNEL_HEAP_SIZE - ROW);
+
+    crate::serial_println!("Shadow bytes around the buggy address:");
+    for row in (first..=last).step_by(ROW) {
+        let mut line = [0u8; 16 * 3];
+        for (i, granule) in (row..row + ROW).step_by(GRANULE).enumerate() {
+            let code = heap_shadow(granule).map_or(0, |s| s.load(Ordering::Relaxed));
+            let marker = if granule == addr / GRANULE * GRANULE { b'[' } else { b' ' };
+            line[i * 3] = marker;
+            line[i * 3 + 1] = hex_digit(code >> 4);
+            line[i * 3 + 2] = hex_digit(code & 0xF);
+        }
+        let text = core::str::from_utf8(&line).unwrap_or("");
+        crate::serial_println!("{}{:#x}:{}", if row == row_of(addr) { "=>" } else { "  " }, row, text);
+    }
+}
+
+fn hex_digit(n: u8) -> u8 {
+    b"0123456789abcdef"[n as usize & 0xF]
+}
+
+/// Records return addresses by walking the frame-pointer chain.
+///
+/// The sanitizer build forces frame pointers; host tests do not, so the
+/// walk is skipped there.
+fn capture_stack(stack: &mut [usize; STACK_DEPTH]) {
+    if cfg!(test) {
+        return;
+    }
+    let mut fp: usize;
+    // SAFETY: reading rbp has no side effects
+    unsafe { core::arch::asm!("mov {}, rbp", out(reg) fp, options(nomem, nostack, preserves_flags)) };
+
+    for slot in stack.iter_mut() {
+        if fp == 0 || !fp.is_multiple_of(8) {
+            break;
+        }
+        // SAFETY: with frame pointers each frame starts with the caller's
+        // rbp followed by the return address
+        let (next, ret) = unsafe { (*(fp as *const usize), *((fp + 8) as *const usize)) };
+        if ret == 0 {
+            break;
+        }
+        *slot = ret;
+        if next <= fp || next - fp > MAX_FRAME_SIZE {
+            break;
+        }
+        fp = next;
+    }
+}
+
+/// Checks that the shadow catches an overflow and a use-after-free without
+/// printing reports, and logs the result.
+pub fn self_test() -> bool {
+    let buf = alloc::vec![0u8; 13];
+    let ptr = buf.as_ptr() as usize;
+    let in_bounds = first_bad(ptr, 13).is_none();
+    let overflow = matches!(first_bad(ptr + 13, 1), Some((_, 5)));
+    drop(buf);
+    let freed = matches!(first_bad(ptr, 1), Some((_, HEAP_FREED)));
+
+    let ok = in_bounds && overflow && freed;
+    crate::serial_println!(
+        "[kasan] Self-test {} ({} KB quarantine, frame shadow up to {} MB)",
+        if ok { "passed" } else { "FAILED" },
+        QUARANTINE_BYTES / 1024,
+        FRAME_SHADOW_LIMIT / (1024 * 1024)
+    );
+    ok
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+// =============================================================================
+// Compiler Instrumentation Callbacks
+// =============================================================================
+
+macro_rules! access_callbacks {
+    ($($size:literal => $load:ident, $load_noabort:ident, $store:ident, $store_noabort:ident;)*) => {$(
+        #[no_mangle]
+        pub extern "C" fn $load(addr: usize) {
+            check(addr, $size, false);
+        }
+
+        #[no_mangle]
+        pub extern "C" fn $load_noabort(addr: usize) {
+            check(addr, $size, false);
+        }
+
+        #[no_mangle]
+        pub extern "C" fn $store(addr: usize) {
+            check(addr, $size, true);
+        }
+
+        #[no_mangle]
+        pub extern "C" fn $store_noabort(addr: usize) {
+            check(addr, $size, true);
+        }
+    )*};
+}
+
+access_callbacks! {
+    1 => __asan_load1, __asan_load1_noabort, __asan_store1, __asan_store1_noabort;
+    2 => __asan_load2, __asan_load2_noabort, __asan_store2, __asan_store2_noabort;
+    4 => __asan_load4, __asan_load4_noabort, __asan_store4, __asan_store4_noabort;
+    8 => __asan_load8, __asan_load8_noabort, __asan_store8, __asan_store8_noabort;
+    16 => __asan_load16, __asan_load16_noabort, __asan_store16, __asan_store16_noabort;
+}
+
+#[no_mangle]
+#[allow(non_snake_case)]
+pub extern "C" fn __asan_loadN(addr: usize, size: usize) {
+    check(addr, size, false);
+}
+
+#[no_mangle]
+#[allow(non_snake_case)]
+pub extern "C" fn __asan_loadN_noabort(addr: usize, size: usize) {
+    check(addr, size, false);
+}
+
+#[no_mangle]
+#[allow(non_snake_case)]
+pub extern "C" fn __asan_storeN(addr: usize, size: usize) {
+    check(addr, size, true);
+}
+
+#[no_mangle]
+#[allow(non_snake_case)]
+pub extern "C" fn __asan_storeN_noabort(addr: usize, size: usize) {
+    check(addr, size, true);
+}
+
+/// # Safety
+///
+/// Same contract as `memcpy`.
+#[no_mangle]
+pub unsafe extern "C" fn __asan_memcpy(dst: *mut u8, src: *const u8, len: usize) -> *mut u8 {
+    check(src as usize, len, false);
+    check(dst as usize, len, true);
+    // SAFETY: forwarded from the caller
+    unsafe { core::ptr::copy_nonoverlapping(src, dst, len) };
+    dst
+}
+
+/// # Safety
+///
+/// Same contract as `memmove`.
+#[no_mangle]
+pub unsafe extern "C" fn __asan_memmove(dst: *mut u8, src: *const u8, len: usize) -> *mut u8 {
+    check(src as usize, len, false);
+    check(dst as usize, len, true);
+    // SAFETY: forwarded from the caller
+    unsafe { core::ptr::copy(src, dst, len) };
+    dst
+}
+
+/// # Safety
+///
+/// Same contract as `memset`.
+#[no_mangle]
+pub unsafe extern "C" fn __asan_memset(dst: *mut u8, value: i32, len: usize) -> *mut u8 {
+    check(dst as usize, len, true);
+    // SAFETY: forwarded from the caller
+    unsafe { core::ptr::write_bytes(dst, value as u8, len) };
+    dst
+}
+
+#[no_mangle]
+pub extern "C" fn __asan_handle_no_return() {}
+
+#[no_mangle]
+pub extern "C" fn __asan_register_globals(_globals: usize, _count: usize) {}
+
+#[no_mangle]
+pub extern "C" fn __asan_unregister_globals(_globals: usize, _count: usize) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[repr(C, align(4096))]
+    struct Arena([u8; 8192]);
+
+    static mut ARENA: Arena = Arena([0; 8192]);
+
+    /// Points the heap shadow at a test arena (once per test binary).
+    fn arena() -> usize {
+        static READY: AtomicBool = AtomicBool::new(false);
+        let start = core::ptr::addr_of_mut!(ARENA) as usize;
+        if !READY.swap(true, Ordering::SeqCst) {
+            HEAP_START.store(start, Ordering::Relaxed);
+            poison(start, 8192, HEAP_REDZONE);
+        }
+        start
+    }
+
+    #[test]
+    fn test_partial_granule() {
+        let base = arena() + 64;
+        let layout = Layout::from_size_align(13, 8).unwrap();
+        assert!(padded_layout(layout).unwrap().size() <= 1024);
+        heap_alloc(base as *mut u8, 13);
+
+        assert_eq!(first_bad(base, 13), None);
+        assert_eq!(first_bad(base + 8, 5), None);
+        assert_eq!(first_bad(base + 12, 2), Some((base + 13, 5)));
+        assert_eq!(first_bad(base + 16, 1), Some((base + 16, HEAP_META)));
+        assert_eq!(first_bad(base - 8, 8), Some((base - 8, HEAP_REDZONE)));
+        assert!(find_meta(base + 16).is_some_and(|m| m.size == 13));
+        assert!(find_meta(base + 13).is_some_and(|m| m.size == 13));
+    }
+
+    #[test]
+    fn test_quarantine_holds_freed_blocks() {
+        let base = arena() + 4096;
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        heap_alloc(base as *mut u8, 64);
+
+        let mut released = 0;
+        heap_free(base as *mut u8, layout, |_, _| released += 1);
+        assert_eq!(released, 0);
+        assert_eq!(first_bad(base + 8, 8), Some((base + 8, HEAP_FREED)));
+        assert!(find_meta(base).is_some_and(|m| m.state == STATE_FREED));
+
+        drain_quarantine(|ptr, l| {
+            assert_eq!(ptr as usize, base);
+            assert_eq!(l, padded_layout(layout).unwrap());
+            released += 1;
+        });
+        assert_eq!(released, 1);
+        assert_eq!(first_bad(base, 1), Some((base, HEAP_REDZONE)));
+    }
+
+    #[test]
+    fn test_frame_shadow() {
+        let frame = FrameNumber::new(0x1234);
+        poison_frames(frame, 2);
+        let addr = frame.address() as usize;
+        assert_eq!(first_bad(addr + 100, 8), Some((addr + 100, FRAME_FREED)));
+        assert_eq!(first_bad(addr - 4, 8), Some((addr, FRAME_FREED)));
+        unpoison_frames(frame, 1);
+        assert_eq!(first_bad(addr, 8), None);
+        assert_eq!(first_bad(addr + PAGE_SIZE - 4, 8), Some((addr + PAGE_SIZE, FRAME_FREED)));
+        unpoison_frames(FrameNumber::new(0x1235), 1);
+    }
+}
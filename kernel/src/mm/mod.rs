@@ -4,9 +4,23 @@
 //!
 //! 1. **No Swap**: All memory is physical. If you're out, you're out.
 //! 2. **No Overcommit**: Allocations are guaranteed at allocation time.
-//! 3. **Explicit Allocation**: No lazy allocation, no COW by default.
+//! 3. **Explicit Allocation**: No lazy allocation, no COW by default. A process
+//!    holding a `vm:lazy` capability can opt into demand paging and
+//!    copy-on-write fork (see [`vm`]).
 //! 4. **Capability-Gated**: All memory regions are accessed via capabilities.
 //!
+//! Running out is still possible, so [`pressure`] publishes a pressure level
+//! to subscribed services and applies the OOM policy of quota capabilities
+//! before allocations start failing.
+//!
+//! Building with the `kasan` feature adds a software address sanitizer on
+//! x86_64 that shadows the kernel heap, slab caches and physical frames
+//! (see `kasan.rs`).
+//!
+//! Persistent memory (NVDIMMs) is ordinary memory to the CPU but needs
+//! explicit cache write-back to be durable; [`pmem`] has the flush and fence
+//! primitives.
+//!
 //! ## Memory Regions
 //!
 //! Memory is divided into typed regions:
@@ -15,10 +29,17 @@
 //! - Device memory: For MMIO regions
 //! - Shared memory: For IPC zero-copy transfers
 
+pub mod buddy;
 pub mod frame;
+pub mod slab;
+pub mod vm;
+pub mod pressure;
 pub mod security;
 pub mod cfi;
 pub mod mte;
+pub mod pmem;
+#[cfg(feature = "kasan")]
+pub mod kasan;
 
 use core::alloc::{GlobalAlloc, Layout};
 use core::cell::UnsafeCell;
@@ -27,7 +48,8 @@ use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 use spin::Mutex;
 
-pub use frame::{FrameAllocator, FrameNumber, FRAME_ALLOCATOR, PAGE_SIZE};
+pub use buddy::Zone;
+pub use frame::{FrameAllocator, FrameNumber, HugePageSize, FRAME_ALLOCATOR, PAGE_SIZE};
 
 /// Kernel heap size: 8 MB (enough for virtio buffers, verification, and data structures)
 const KERNEL_HEAP_SIZE: usize = 8 * 1024 * 1024;
@@ -116,6 +138,7 @@ impl MemoryManager {
     pub fn new(config: MemoryConfig) -> Self {
         // Initialize the kernel heap
         init_heap();
+        pressure::init();
         
         Self {
             _config: config,
@@ -157,7 +180,7 @@ impl MemoryManager {
         &mut self,
         size: usize,
         _region_type: MemoryRegionType,
-        _cap_token: &crate::cap::CapabilityToken,
+        cap_token: &crate::cap::CapabilityToken,
     ) -> Result<u64, MemoryError> {
         // Round up to page size (4KB)
         const PAGE_SIZE: usize = 4096;
@@ -168,10 +191,23 @@ impl MemoryManager {
             return Err(MemoryError::OutOfMemory);
         }
 
-        // Allocate frames from the global frame allocator
+        // Charge the quota attached to the capability, if any
         let num_frames = aligned_size / PAGE_SIZE;
-        let frame = FRAME_ALLOCATOR.allocate_contiguous(num_frames)
-            .map_err(|_| MemoryError::OutOfMemory)?;
+        pressure::charge(cap_token, num_frames).map_err(|_| MemoryError::QuotaExceeded)?;
+
+        // Allocate frames from the global frame allocator, reclaiming once
+        // if memory is short
+        let frame = match FRAME_ALLOCATOR.allocate_contiguous(num_frames) {
+            Ok(frame) => Ok(frame),
+            Err(_) if pressure::on_allocation_failure(num_frames) => {
+                FRAME_ALLOCATOR.allocate_contiguous(num_frames)
+            }
+            Err(e) => Err(e),
+        };
+        let frame = frame.map_err(|_| {
+            pressure::uncharge(cap_token, num_frames);
+            MemoryError::OutOfMemory
+        })?;
         
         self.used_memory += aligned_size;
 
@@ -189,7 +225,7 @@ impl MemoryManager {
         &mut self,
         addr: u64,
         size: usize,
-        _cap_token: &crate::cap::CapabilityToken,
+        cap_token: &crate::cap::CapabilityToken,
     ) -> Result<(), MemoryError> {
         if addr == 0 {
             return Err(MemoryError::InvalidAddress);
@@ -202,6 +238,7 @@ impl MemoryManager {
         
         // Return frames to the allocator
         FRAME_ALLOCATOR.free_contiguous(frame, num_frames);
+        pressure::uncharge(cap_token, num_frames);
         
         // Update accounting
         self.used_memory = self.used_memory.saturating_sub(aligned_size);
@@ -240,6 +277,8 @@ pub enum MemoryError {
     PermissionDenied,
     /// Region already allocated
     AlreadyAllocated,
+    /// Allocation would exceed the capability's memory quota
+    QuotaExceeded,
 }
 
 /// Simple bump allocator for the kernel heap.
@@ -348,6 +387,7 @@ struct BlockHeader {
     size_and_flags: usize,
 }
 
+#[cfg_attr(feature = "kasan", sanitize(address = "off"))]
 impl BlockHeader {
     /// Minimum block size (header + enough space for free list pointers)
     const MIN_BLOCK_SIZE: usize = core::mem::size_of::<BlockHeader>() + 2 * core::mem::size_of::<usize>();
@@ -430,6 +470,7 @@ pub struct FreeListAllocator {
 unsafe impl Send for FreeListAllocator {}
 unsafe impl Sync for FreeListAllocator {}
 
+#[cfg_attr(feature = "kasan", sanitize(address = "off"))]
 impl FreeListAllocator {
     /// Creates a new uninitialized allocator.
     pub const fn new() -> Self {
@@ -703,6 +744,9 @@ pub fn init_heap() {
 
     let mut allocator = FREE_LIST_ALLOCATOR.lock();
     allocator.init(heap_start, heap_size);
+
+    #[cfg(feature = "kasan")]
+    kasan::init(heap_start, heap_size);
     
     // Log heap initialization if serial is available
     #[cfg(target_arch = "x86_64")]
@@ -732,6 +776,7 @@ static ALLOCATOR: KernelAllocator = KernelAllocator;
 
 struct KernelAllocator;
 
+#[cfg_attr(feature = "kasan", sanitize(address = "off"))]
 unsafe impl GlobalAlloc for KernelAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         // Handle zero-sized allocations immediately without touching the heap
@@ -745,8 +790,25 @@ unsafe impl GlobalAlloc for KernelAllocator {
             init_heap();
         }
 
+        #[cfg(feature = "kasan")]
+        let _suppress = kasan::suppress();
+        #[cfg(feature = "kasan")]
+        let (layout, requested) = match kasan::padded_layout(layout) {
+            Some(padded) => (padded, layout.size()),
+            None => return core::ptr::null_mut(),
+        };
+
         let mut allocator = FREE_LIST_ALLOCATOR.lock();
         let result = allocator.allocate(layout);
+
+        // Quarantined blocks are the first thing to give back
+        #[cfg(feature = "kasan")]
+        let result = match result {
+            None if kasan::drain_quarantine(|ptr, l| allocator.deallocate(ptr, l)) > 0 => {
+                allocator.allocate(layout)
+            }
+            other => other,
+        };
         
         // Debug output for failed allocations
         #[cfg(target_arch = "x86_64")]
@@ -763,7 +825,11 @@ unsafe impl GlobalAlloc for KernelAllocator {
         }
         
         match result {
-            Some(ptr) => ptr.as_ptr(),
+            Some(ptr) => {
+                #[cfg(feature = "kasan")]
+                kasan::heap_alloc(ptr.as_ptr(), requested);
+                ptr.as_ptr()
+            }
             None => core::ptr::null_mut(),
         }
     }
@@ -773,7 +839,16 @@ unsafe impl GlobalAlloc for KernelAllocator {
             return;
         }
         
-        let mut allocator = FREE_LIST_ALLOCATOR.lock();
-        allocator.deallocate(ptr, layout);
+        #[cfg(feature = "kasan")]
+        {
+            let _suppress = kasan::suppress();
+            kasan::heap_free(ptr, layout, |ptr, l| FREE_LIST_ALLOCATOR.lock().deallocate(ptr, l));
+        }
+
+        #[cfg(not(feature = "kasan"))]
+        {
+            let mut allocator = FREE_LIST_ALLOCATOR.lock();
+            allocator.deallocate(ptr, layout);
+        }
     }
 }
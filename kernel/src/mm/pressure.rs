@@ -0,0 +1,757 @@
+//! # Memory Pressure and OOM Policy
+//!
+//! Turns "allocation failed" into a signal services can act on before memory
+//! actually runs out.
+//!
+//! ## Pressure Levels
+//!
+//! The level is computed from free frames plus pages that kernel caches can
+//! give back (see [`Reclaimer`]), as a share of all frames:
+//!
+//! ```text
+//! available >= 20%   Normal
+//! available <  20%   Low        subscribers notified
+//! available <  10%   Medium     kernel caches reclaimed
+//! available <   5%   Critical   shed requests, then OOM kill
+//! ```
+//!
+//! "All frames" are those handed to the frame allocator at boot (on x86_64,
+//! everything above the kernel image up to 4 GiB). Until that happens, and on
+//! architectures that hand over none, the level stays Normal.
+//!
+//! Reclaimable kernel caches are the page cache and empty slabs. Slabs count
+//! only when they came from the frame allocator; heap-backed ones return
+//! their memory to the kernel heap, not to the frame pool.
+//!
+//! ## Subscriptions
+//!
+//! A service creates a channel with the kernel as sender and itself as
+//! receiver and passes it to [`subscribe`]. Level changes that reach the
+//! subscriber's minimum level, including the change back below it, are
+//! delivered as [`PressureEvent`]s.
+//!
+//! ## OOM Policy
+//!
+//! A process can present a `memory:quota` capability (the resource ID is the
+//! quota in pages) with an [`OomPolicy`]. The quota caps what it may allocate
+//! through [`MemoryManager`](super::MemoryManager); the policy decides how it
+//! is treated when memory stays critical:
+//!
+//! 1. Kernel caches are reclaimed.
+//! 2. `Shed` processes get a shed request and [`SHED_GRACE_TICKS`] to free
+//!    their caches.
+//! 3. The process with the highest usage relative to its quota is killed,
+//!    `Kill` processes before `Shed` ones. `Protected` processes are never
+//!    killed; processes without a quota are treated as `Kill`.
+//!
+//! S-ATLAS and S-STORAGE keep no caches they could shed, so they do not
+//! subscribe yet; wiring them up is left until they grow one.
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use super::FRAME_ALLOCATOR;
+use crate::cap::{CapabilityTable, CapabilityToken, Operations};
+use crate::ipc::{self, ChannelId, IpcError};
+use crate::sched::ProcessId;
+
+/// Resource type of quota capabilities. The resource ID is the quota in pages.
+pub const QUOTA_RESOURCE: &str = "memory:quota";
+
+/// Minimum interval between two evaluations from [`poll`] (ticks, ~100 ms).
+pub const POLL_INTERVAL_TICKS: u64 = 100;
+
+/// Time shedding processes get before the OOM killer runs (ticks, ~1 s).
+pub const SHED_GRACE_TICKS: u64 = 1000;
+
+/// Events kept for `/proc/pressure`.
+const EVENT_LOG_SIZE: usize = 32;
+
+/// Memory pressure level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PressureLevel {
+    /// Plenty of memory
+    Normal,
+    /// Memory is getting scarce; caches should stop growing
+    Low,
+    /// Caches should be trimmed
+    Medium,
+    /// Allocations are about to fail
+    Critical,
+}
+
+impl PressureLevel {
+    /// Returns the lowercase name.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Normal => "normal",
+            Self::Low => "low",
+            Self::Medium => "medium",
+            Self::Critical => "critical",
+        }
+    }
+
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::Normal),
+            1 => Some(Self::Low),
+            2 => Some(Self::Medium),
+            3 => Some(Self::Critical),
+            _ => None,
+        }
+    }
+}
+
+/// Level thresholds, in percent of all frames available.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PressureThresholds {
+    /// Below this is `Low`
+    pub low: u8,
+    /// Below this is `Medium`
+    pub medium: u8,
+    /// Below this is `Critical`
+    pub critical: u8,
+}
+
+impl Default for PressureThresholds {
+    fn default() -> Self {
+        Self {
+            low: 20,
+            medium: 10,
+            critical: 5,
+        }
+    }
+}
+
+impl PressureThresholds {
+    /// Returns the level for `available` out of `total` pages.
+    pub fn level(&self, available: usize, total: usize) -> PressureLevel {
+        if total == 0 {
+            return PressureLevel::Normal;
+        }
+        let percent = available.saturating_mul(100) / total;
+        if percent < self.critical as usize {
+            PressureLevel::Critical
+        } else if percent < self.medium as usize {
+            PressureLevel::Medium
+        } else if percent < self.low as usize {
+            PressureLevel::Low
+        } else {
+            PressureLevel::Normal
+        }
+    }
+}
+
+/// What happens to a process when memory stays critical.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OomPolicy {
+    /// May be killed
+    Kill,
+    /// Asked to shed caches first, killed only after every `Kill` process
+    Shed,
+    /// Never killed
+    Protected,
+}
+
+/// Memory pressure errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PressureError {
+    /// Capability check failed
+    PermissionDenied,
+    /// Channel does not exist or is not kernel-to-subscriber
+    InvalidChannel,
+    /// Allocation would exceed the quota
+    QuotaExceeded,
+}
+
+/// Message delivered to subscribers.
+///
+/// Wire format (little-endian): `kind: u8, level: u8, available: u64,
+/// total: u64, target: u64`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PressureEvent {
+    /// Why the event was sent
+    pub kind: EventKind,
+    /// Current level
+    pub level: PressureLevel,
+    /// Free plus reclaimable pages
+    pub available: u64,
+    /// All pages
+    pub total: u64,
+    /// Pages the receiver is asked to free (shed requests only)
+    pub target: u64,
+}
+
+/// Kind of [`PressureEvent`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    /// The pressure level changed
+    LevelChanged,
+    /// The receiver should free caches now
+    ShedRequest,
+}
+
+impl PressureEvent {
+    /// Encoded size in bytes.
+    pub const SIZE: usize = 26;
+
+    /// Encodes the event for an IPC message.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(Self::SIZE);
+        out.push(match self.kind {
+            EventKind::LevelChanged => 0,
+            EventKind::ShedRequest => 1,
+        });
+        out.push(self.level as u8);
+        out.extend_from_slice(&self.available.to_le_bytes());
+        out.extend_from_slice(&self.total.to_le_bytes());
+        out.extend_from_slice(&self.target.to_le_bytes());
+        out
+    }
+
+    /// Decodes an event received over IPC.
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        if data.len() < Self::SIZE {
+            return None;
+        }
+        let kind = match data[0] {
+            0 => EventKind::LevelChanged,
+            1 => EventKind::ShedRequest,
+            _ => return None,
+        };
+        let word = |i: usize| u64::from_le_bytes(data[i..i + 8].try_into().unwrap());
+        Some(Self {
+            kind,
+            level: PressureLevel::from_u8(data[1])?,
+            available: word(2),
+            total: word(10),
+            target: word(18),
+        })
+    }
+}
+
+/// A kernel cache that can give memory back under pressure.
+pub trait Reclaimer: Sync {
+    /// Cache name for logs.
+    fn name(&self) -> &'static str;
+
+    /// Pages that [`reclaim`](Reclaimer::reclaim) could free right now.
+    fn reclaimable_pages(&self) -> usize;
+
+    /// Frees up to `target` pages. Returns the number freed.
+    fn reclaim(&self, target: usize) -> usize;
+}
+
+/// Empty frame-backed slabs of every slab cache.
+struct SlabReclaimer;
+
+impl Reclaimer for SlabReclaimer {
+    fn name(&self) -> &'static str {
+        "slab"
+    }
+
+    fn reclaimable_pages(&self) -> usize {
+        super::slab::reclaimable_pages()
+    }
+
+    fn reclaim(&self, _target: usize) -> usize {
+        // Empty slabs are all released at once
+        super::slab::shrink_all()
+    }
+}
+
+static SLAB_RECLAIMER: SlabReclaimer = SlabReclaimer;
+
+/// A quota capability registered by a process.
+#[derive(Debug, Clone, Copy)]
+struct Quota {
+    token: CapabilityToken,
+    limit: usize,
+    charged: usize,
+    policy: OomPolicy,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Subscriber {
+    pid: ProcessId,
+    channel: ChannelId,
+    min_level: PressureLevel,
+}
+
+/// A process considered by the OOM killer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OomCandidate {
+    /// Process
+    pub pid: ProcessId,
+    /// Pages in use (quota charges plus resident user pages)
+    pub usage: usize,
+    /// Quota in pages (None = no quota)
+    pub limit: Option<usize>,
+    /// Policy
+    pub policy: OomPolicy,
+}
+
+impl OomCandidate {
+    /// Usage in thousandths of the quota, or of all memory without one.
+    fn badness(&self, total: usize) -> usize {
+        self.usage.saturating_mul(1000) / self.limit.unwrap_or(total).max(1)
+    }
+}
+
+/// Picks the process to kill: `Kill` before `Shed`, then highest badness.
+pub fn select_victim(candidates: &[OomCandidate], total: usize) -> Option<ProcessId> {
+    candidates
+        .iter()
+        .filter(|c| c.policy != OomPolicy::Protected && c.usage > 0)
+        .min_by(|a, b| {
+            a.policy
+                .cmp(&b.policy)
+                .then(b.badness(total).cmp(&a.badness(total)))
+        })
+        .map(|c| c.pid)
+}
+
+struct PressureState {
+    thresholds: PressureThresholds,
+    level: PressureLevel,
+    reclaimers: Vec<&'static dyn Reclaimer>,
+    quotas: BTreeMap<ProcessId, Quota>,
+    subscribers: Vec<Subscriber>,
+    last_poll: u64,
+    /// Tick shed requests went out for the current critical episode
+    shed_requested: Option<u64>,
+    events: VecDeque<String>,
+    kills: u64,
+}
+
+static STATE: Mutex<PressureState> = Mutex::new(PressureState {
+    thresholds: PressureThresholds {
+        low: 20,
+        medium: 10,
+        critical: 5,
+    },
+    level: PressureLevel::Normal,
+    reclaimers: Vec::new(),
+    quotas: BTreeMap::new(),
+    subscribers: Vec::new(),
+    last_poll: 0,
+    shed_requested: None,
+    events: VecDeque::new(),
+    kills: 0,
+});
+
+fn now() -> u64 {
+    #[cfg(target_arch = "x86_64")]
+    {
+        crate::arch::x86_64::interrupts::get_ticks()
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        0
+    }
+}
+
+impl PressureState {
+    fn log(&mut self, message: String) {
+        crate::serial_println!("[mm] {}", message);
+        if self.events.len() == EVENT_LOG_SIZE {
+            self.events.pop_front();
+        }
+        self.events.push_back(format!("{} {}", now(), message));
+    }
+
+    fn reclaimable(&self) -> usize {
+        self.reclaimers.iter().map(|r| r.reclaimable_pages()).sum()
+    }
+
+    fn available(&self) -> usize {
+        FRAME_ALLOCATOR.free_count() + self.reclaimable()
+    }
+
+    fn event(&self, kind: EventKind, target: usize) -> PressureEvent {
+        PressureEvent {
+            kind,
+            level: self.level,
+            available: self.available() as u64,
+            total: FRAME_ALLOCATOR.total_count() as u64,
+            target: target as u64,
+        }
+    }
+
+    /// Sends an event to a subscriber. Returns false if the channel is gone.
+    fn deliver(subscriber: &Subscriber, event: &PressureEvent) -> bool {
+        match ipc::send(
+            subscriber.channel,
+            ProcessId::KERNEL,
+            event.encode(),
+            &CapabilityToken::new([0; 4]),
+        ) {
+            Ok(()) | Err(IpcError::BufferFull) => true,
+            Err(_) => false,
+        }
+    }
+
+    /// Sends a level change to subscribers whose minimum level the old or
+    /// new level reaches, so they also hear when pressure eases.
+    fn notify(&mut self, previous: PressureLevel, event: PressureEvent) {
+        let reach = previous.max(event.level);
+        self.subscribers
+            .retain(|s| reach < s.min_level || Self::deliver(s, &event));
+    }
+
+    /// Frees memory from kernel caches. Returns pages freed.
+    fn reclaim(&mut self, target: usize) -> usize {
+        let mut freed = 0;
+        for reclaimer in self.reclaimers.iter() {
+            if freed >= target {
+                break;
+            }
+            freed += reclaimer.reclaim(target - freed);
+        }
+        freed
+    }
+
+    fn candidates(&self) -> Vec<OomCandidate> {
+        let mut candidates: BTreeMap<ProcessId, OomCandidate> = super::vm::resident_pages()
+            .into_iter()
+            .map(|(pid, usage)| {
+                (
+                    pid,
+                    OomCandidate {
+                        pid,
+                        usage,
+                        limit: None,
+                        policy: OomPolicy::Kill,
+                    },
+                )
+            })
+            .collect();
+        for (&pid, quota) in self.quotas.iter() {
+            let entry = candidates.entry(pid).or_insert(OomCandidate {
+                pid,
+                usage: 0,
+                limit: None,
+                policy: OomPolicy::Kill,
+            });
+            entry.usage += quota.charged;
+            entry.limit = Some(quota.limit);
+            entry.policy = quota.policy;
+        }
+        candidates.remove(&ProcessId::KERNEL);
+        candidates.into_values().collect()
+    }
+
+    /// Recomputes the level and acts on it. Returns the process to kill, which
+    /// the caller terminates after dropping the state lock.
+    fn evaluate(&mut self) -> Option<ProcessId> {
+        let total = FRAME_ALLOCATOR.total_count();
+        let mut level = self.thresholds.level(self.available(), total);
+
+        if level >= PressureLevel::Medium {
+            let want = total * self.thresholds.low as usize / 100;
+            let freed = self.reclaim(want.saturating_sub(FRAME_ALLOCATOR.free_count()));
+            if freed > 0 {
+                self.log(format!("reclaimed {} pages from kernel caches", freed));
+                level = self.thresholds.level(self.available(), total);
+            }
+        }
+
+        if level != self.level {
+            self.log(format!(
+                "pressure {} -> {} ({} of {} pages available)",
+                self.level.name(),
+                level.name(),
+                self.available(),
+                total
+            ));
+            let previous = self.level;
+            self.level = level;
+            let event = self.event(EventKind::LevelChanged, 0);
+            self.notify(previous, event);
+        }
+
+        if level < PressureLevel::Critical {
+            self.shed_requested = None;
+            return None;
+        }
+
+        let target = (total * self.thresholds.medium as usize / 100)
+            .saturating_sub(self.available());
+        match self.shed_requested {
+            None => {
+                self.shed_requested = Some(now());
+                let shedders: Vec<ProcessId> = self
+                    .quotas
+                    .iter()
+                    .filter(|(_, q)| q.policy == OomPolicy::Shed)
+                    .map(|(&pid, _)| pid)
+                    .collect();
+                let event = self.event(EventKind::ShedRequest, target);
+                let mut asked = 0;
+                for subscriber in self.subscribers.iter().filter(|s| shedders.contains(&s.pid)) {
+                    if Self::deliver(subscriber, &event) {
+                        asked += 1;
+                    }
+                }
+                self.log(format!("asked {} processes to shed {} pages", asked, target));
+                None
+            }
+            Some(since) if now().saturating_sub(since) >= SHED_GRACE_TICKS => {
+                self.shed_requested = Some(now());
+                let victim = select_victim(&self.candidates(), total);
+                match victim {
+                    Some(pid) => {
+                        self.kills += 1;
+                        self.log(format!("out of memory: killing process {}", pid.0));
+                    }
+                    None => self.log(String::from("out of memory: no process can be killed")),
+                }
+                victim
+            }
+            Some(_) => None,
+        }
+    }
+}
+
+/// Terminates an OOM victim. Exiting frees its address space and releases
+/// its quota.
+fn kill(victim: Option<ProcessId>) {
+    if let Some(pid) = victim {
+        let _ = crate::process::PROCESS_MANAGER.terminate(pid, -9);
+    }
+}
+
+/// Registers a kernel cache to be reclaimed under pressure.
+pub fn register_reclaimer(reclaimer: &'static dyn Reclaimer) {
+    STATE.lock().reclaimers.push(reclaimer);
+}
+
+/// Initializes pressure tracking with the built-in reclaimers.
+pub fn init() {
+    register_reclaimer(&SLAB_RECLAIMER);
+}
+
+/// Replaces the level thresholds.
+pub fn set_thresholds(thresholds: PressureThresholds) {
+    STATE.lock().thresholds = thresholds;
+}
+
+/// Returns the current level without recomputing it.
+pub fn level() -> PressureLevel {
+    STATE.lock().level
+}
+
+/// Recomputes the pressure level, reclaiming and killing as needed. Called
+/// from the kernel main loop; does nothing if called again within
+/// [`POLL_INTERVAL_TICKS`].
+pub fn poll() {
+    let mut state = STATE.lock();
+    let now = now();
+    if now.saturating_sub(state.last_poll) < POLL_INTERVAL_TICKS {
+        return;
+    }
+    state.last_poll = now;
+    let victim = state.evaluate();
+    drop(state);
+    kill(victim);
+}
+
+/// Reclaims memory after an allocation of `pages` failed. Returns true if at
+/// least that many frames are free afterwards.
+pub fn on_allocation_failure(pages: usize) -> bool {
+    let mut state = STATE.lock();
+    let short = pages.saturating_sub(FRAME_ALLOCATOR.free_count());
+    if short > 0 {
+        state.reclaim(short);
+    }
+    let victim = state.evaluate();
+    drop(state);
+    kill(victim);
+    FRAME_ALLOCATOR.free_count() >= pages
+}
+
+/// Subscribes a process to pressure events at or above `min_level`.
+///
+/// The channel must have the kernel as sender and `pid` as receiver.
+pub fn subscribe(
+    pid: ProcessId,
+    channel: ChannelId,
+    min_level: PressureLevel,
+) -> Result<(), PressureError> {
+    let stats = ipc::IPC_MANAGER
+        .channel_stats(channel)
+        .map_err(|_| PressureError::InvalidChannel)?;
+    if stats.sender != ProcessId::KERNEL || stats.receiver != pid || stats.closed {
+        return Err(PressureError::InvalidChannel);
+    }
+    let mut state = STATE.lock();
+    state.subscribers.retain(|s| s.channel != channel);
+    state.subscribers.push(Subscriber {
+        pid,
+        channel,
+        min_level,
+    });
+    Ok(())
+}
+
+/// Removes a subscription.
+pub fn unsubscribe(channel: ChannelId) {
+    STATE.lock().subscribers.retain(|s| s.channel != channel);
+}
+
+/// Attaches a quota capability and OOM policy to a process.
+///
+/// `token` must be owned by `pid`, allow `WRITE` and name a
+/// [`QUOTA_RESOURCE`] resource. Charges already made under an earlier quota
+/// carry over.
+pub fn attach_quota(
+    pid: ProcessId,
+    caps: &CapabilityTable,
+    token: CapabilityToken,
+    policy: OomPolicy,
+) -> Result<(), PressureError> {
+    caps.check(pid, token, Operations::WRITE)
+        .map_err(|_| PressureError::PermissionDenied)?;
+    let resource = caps
+        .get_resource(&token)
+        .map_err(|_| PressureError::PermissionDenied)?;
+    if resource.resource_type != QUOTA_RESOURCE {
+        return Err(PressureError::PermissionDenied);
+    }
+
+    let mut state = STATE.lock();
+    let charged = state.quotas.get(&pid).map_or(0, |q| q.charged);
+    state.quotas.insert(
+        pid,
+        Quota {
+            token,
+            limit: resource.id as usize,
+            charged,
+            policy,
+        },
+    );
+    state.log(format!(
+        "process {} quota {} pages, policy {:?}",
+        pid.0, resource.id, policy
+    ));
+    Ok(())
+}
+
+/// Charges an allocation to the quota attached with `token`, if any.
+pub fn charge(token: &CapabilityToken, pages: usize) -> Result<(), PressureError> {
+    let mut state = STATE.lock();
+    let Some(quota) = state.quotas.values_mut().find(|q| q.token == *token) else {
+        return Ok(());
+    };
+    if quota.charged + pages > quota.limit {
+        return Err(PressureError::QuotaExceeded);
+    }
+    quota.charged += pages;
+    Ok(())
+}
+
+/// Returns pages charged with [`charge`].
+pub fn uncharge(token: &CapabilityToken, pages: usize) {
+    let mut state = STATE.lock();
+    if let Some(quota) = state.quotas.values_mut().find(|q| q.token == *token) {
+        quota.charged = quota.charged.saturating_sub(pages);
+    }
+}
+
+/// Forgets the quota and subscriptions of an exiting process.
+pub fn release(pid: ProcessId) {
+    let mut state = STATE.lock();
+    state.quotas.remove(&pid);
+    state.subscribers.retain(|s| s.pid != pid);
+}
+
+/// Formats `/proc/pressure`.
+pub fn report() -> String {
+    let state = STATE.lock();
+    let mut out = format!(
+        "level {}\navailable {}\ntotal {}\nreclaimable {}\nsubscribers {}\noom_kills {}\n",
+        state.level.name(),
+        state.available(),
+        FRAME_ALLOCATOR.total_count(),
+        state.reclaimable(),
+        state.subscribers.len(),
+        state.kills,
+    );
+    for (pid, quota) in state.quotas.iter() {
+        out.push_str(&format!(
+            "quota {} {}/{} {:?}\n",
+            pid.0, quota.charged, quota.limit, quota.policy
+        ));
+    }
+    for event in state.events.iter() {
+        out.push_str(&format!("event {}\n", event));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(pid: u64, usage: usize, limit: Option<usize>, policy: OomPolicy) -> OomCandidate {
+        OomCandidate {
+            pid: ProcessId::new(pid),
+            usage,
+            limit,
+            policy,
+        }
+    }
+
+    #[test]
+    fn test_levels() {
+        let t = PressureThresholds::default();
+        assert_eq!(t.level(500, 1000), PressureLevel::Normal);
+        assert_eq!(t.level(199, 1000), PressureLevel::Low);
+        assert_eq!(t.level(99, 1000), PressureLevel::Medium);
+        assert_eq!(t.level(49, 1000), PressureLevel::Critical);
+        assert_eq!(t.level(0, 0), PressureLevel::Normal);
+    }
+
+    #[test]
+    fn test_event_roundtrip() {
+        let event = PressureEvent {
+            kind: EventKind::ShedRequest,
+            level: PressureLevel::Critical,
+            available: 12,
+            total: 4096,
+            target: 300,
+        };
+        let bytes = event.encode();
+        assert_eq!(bytes.len(), PressureEvent::SIZE);
+        assert_eq!(PressureEvent::decode(&bytes), Some(event));
+        assert_eq!(PressureEvent::decode(&bytes[..10]), None);
+    }
+
+    #[test]
+    fn test_victim_prefers_kill_over_shed() {
+        let candidates = [
+            candidate(1, 900, Some(1000), OomPolicy::Shed),
+            candidate(2, 10, Some(1000), OomPolicy::Kill),
+            candidate(3, 5000, None, OomPolicy::Protected),
+        ];
+        assert_eq!(select_victim(&candidates, 8192), Some(ProcessId::new(2)));
+    }
+
+    #[test]
+    fn test_victim_relative_to_quota() {
+        // 300 of a 400-page quota beats 1000 pages without one
+        let candidates = [
+            candidate(1, 1000, None, OomPolicy::Kill),
+            candidate(2, 300, Some(400), OomPolicy::Kill),
+        ];
+        assert_eq!(select_victim(&candidates, 8192), Some(ProcessId::new(2)));
+        assert_eq!(
+            select_victim(&[candidate(3, 1, None, OomPolicy::Protected)], 8192),
+            None
+        );
+    }
+}
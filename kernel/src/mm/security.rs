@@ -8,9 +8,23 @@
 //! - **CFI**: Control Flow Integrity (basic implementation)
 //!
 //! These protections work together to make exploitation significantly harder.
+//!
+//! ## ASLR
+//!
+//! Every exec draws a fresh [`RandomizedAddressSpace`] from a ChaCha20
+//! CSPRNG keyed at boot. The PIE base, stack top, initial program break and
+//! mmap base each move by a page-granular offset whose width comes from the
+//! process's [`AslrConfig`]. Policies are kept per process and inherited by
+//! children; processes without one get [`AslrConfig::default_config`].
 
+use alloc::collections::BTreeMap;
 use core::sync::atomic::{AtomicU64, Ordering};
 
+use spin::Mutex;
+
+use crate::crypto::random::{random_bytes, ChaChaRng, CryptoRng};
+use crate::sched::ProcessId;
+
 // =============================================================================
 // Stack Canaries
 // =============================================================================
@@ -107,6 +121,27 @@ impl Default for StackGuard {
 /// ASLR entropy source.
 static ASLR_SEED: AtomicU64 = AtomicU64::new(0);
 
+/// CSPRNG that per-exec layouts are drawn from.
+static ASLR_RNG: Mutex<Option<ChaChaRng>> = Mutex::new(None);
+
+/// Per-process ASLR policies.
+static ASLR_POLICIES: Mutex<BTreeMap<ProcessId, AslrConfig>> = Mutex::new(BTreeMap::new());
+
+/// `personality` flag that fixes the layout of later execs.
+pub const ADDR_NO_RANDOMIZE: u64 = 0x0040000;
+
+/// `personality` argument that only reads the persona.
+pub const PERSONALITY_QUERY: u64 = 0xFFFF_FFFF;
+
+/// Widest stack offset, in pages (1 TiB).
+pub const MAX_STACK_ENTROPY: u8 = 28;
+/// Widest heap offset, in pages (1 TiB).
+pub const MAX_HEAP_ENTROPY: u8 = 28;
+/// Widest mmap base offset, in pages (4 TiB).
+pub const MAX_MMAP_ENTROPY: u8 = 30;
+/// Widest PIE base offset, in pages (4 TiB).
+pub const MAX_PIE_ENTROPY: u8 = 30;
+
 /// ASLR state for generating randomized addresses.
 pub struct AslrState {
     state: u64,
@@ -125,6 +160,14 @@ impl AslrState {
         Self { state: seed }
     }
 
+    /// Create a state seeded from the ASLR CSPRNG.
+    pub fn from_csprng() -> Self {
+        let mut seed = [0u8; 8];
+        aslr_random_bytes(&mut seed);
+        // xorshift never leaves the all-zero state
+        Self { state: u64::from_le_bytes(seed) | 1 }
+    }
+
     /// Generate the next random value.
     fn next(&mut self) -> u64 {
         // xorshift64
@@ -177,8 +220,74 @@ impl Default for AslrState {
 }
 
 /// Initialize ASLR with a random seed.
+///
+/// The seed is mixed with hardware entropy to key the layout CSPRNG.
 pub fn init_aslr(seed: u64) {
     ASLR_SEED.store(seed, Ordering::SeqCst);
+
+    let mut key = random_bytes::<32>();
+    for (k, s) in key.iter_mut().zip(seed.to_le_bytes()) {
+        *k ^= s;
+    }
+    *ASLR_RNG.lock() = Some(ChaChaRng::from_seed(key));
+}
+
+/// Fill `buf` from the ASLR CSPRNG.
+///
+/// Keys the generator from hardware entropy alone if [`init_aslr`] has not
+/// run yet.
+pub fn aslr_random_bytes(buf: &mut [u8]) {
+    let mut rng = ASLR_RNG.lock();
+    let rng = rng.get_or_insert_with(|| ChaChaRng::from_seed(random_bytes::<32>()));
+    // ChaCha20 output cannot fail
+    let _ = rng.fill_bytes(buf);
+}
+
+/// Set the ASLR policy applied to future execs by `pid`.
+pub fn set_aslr_policy(pid: ProcessId, config: AslrConfig) {
+    ASLR_POLICIES.lock().insert(pid, config.clamped());
+}
+
+/// Get the ASLR policy of `pid`.
+pub fn aslr_policy(pid: ProcessId) -> AslrConfig {
+    ASLR_POLICIES
+        .lock()
+        .get(&pid)
+        .copied()
+        .unwrap_or_default()
+}
+
+/// Apply a `personality` persona to `pid` and return the previous one.
+///
+/// Only [`ADDR_NO_RANDOMIZE`] is understood: setting it disables ASLR for
+/// the process's future execs and clearing it restores the default policy.
+/// A policy that already matches is left alone, so custom entropy widths
+/// survive. [`PERSONALITY_QUERY`] only reads.
+pub fn set_personality(pid: ProcessId, persona: u64) -> u64 {
+    let enabled = aslr_policy(pid).enabled;
+    let previous = if enabled { 0 } else { ADDR_NO_RANDOMIZE };
+    if persona != PERSONALITY_QUERY {
+        let disable = persona & ADDR_NO_RANDOMIZE != 0;
+        if disable && enabled {
+            set_aslr_policy(pid, AslrConfig::disabled());
+        } else if !disable && !enabled {
+            set_aslr_policy(pid, AslrConfig::default_config());
+        }
+    }
+    previous
+}
+
+/// Give `child` the policy of `parent`, if it has one.
+pub fn inherit_aslr_policy(parent: ProcessId, child: ProcessId) {
+    let mut policies = ASLR_POLICIES.lock();
+    if let Some(&config) = policies.get(&parent) {
+        policies.insert(child, config);
+    }
+}
+
+/// Forget the policy of an exiting process.
+pub fn clear_aslr_policy(pid: ProcessId) {
+    ASLR_POLICIES.lock().remove(&pid);
 }
 
 /// ASLR configuration for a process.
@@ -218,6 +327,17 @@ impl AslrConfig {
             pie_entropy: 0,
         }
     }
+
+    /// Limit each entropy width to what fits its region of user space.
+    pub fn clamped(self) -> Self {
+        Self {
+            enabled: self.enabled,
+            stack_entropy: self.stack_entropy.min(MAX_STACK_ENTROPY),
+            heap_entropy: self.heap_entropy.min(MAX_HEAP_ENTROPY),
+            mmap_entropy: self.mmap_entropy.min(MAX_MMAP_ENTROPY),
+            pie_entropy: self.pie_entropy.min(MAX_PIE_ENTROPY),
+        }
+    }
 }
 
 impl Default for AslrConfig {
@@ -227,12 +347,15 @@ impl Default for AslrConfig {
 }
 
 /// Process address space with ASLR.
+///
+/// With ASLR disabled this is the fixed layout described in
+/// [`crate::process::exec`].
 #[derive(Debug)]
 pub struct RandomizedAddressSpace {
-    /// Stack base address.
+    /// Stack top address.
     pub stack_base: u64,
-    /// Heap base address.
-    pub heap_base: u64,
+    /// Gap between the end of the image and the initial program break.
+    pub heap_offset: u64,
     /// mmap region base.
     pub mmap_base: u64,
     /// Executable base (for PIE).
@@ -242,29 +365,40 @@ pub struct RandomizedAddressSpace {
 }
 
 impl RandomizedAddressSpace {
-    /// Create a new randomized address space.
+    /// Create a new randomized address space from the ASLR CSPRNG.
     pub fn new(config: AslrConfig) -> Self {
-        let mut aslr = AslrState::new();
+        Self::with_state(config, &mut AslrState::from_csprng())
+    }
+
+    /// Create a randomized address space drawing offsets from `aslr`.
+    pub fn with_state(config: AslrConfig, aslr: &mut AslrState) -> Self {
+        use crate::process::exec::{DEFAULT_STACK_TOP, PIE_BASE_ADDR};
+        use super::vm::MMAP_BASE;
 
         if config.enabled {
+            let config = config.clamped();
             Self {
-                // Default bases for x86_64
-                stack_base: aslr.randomize_stack(0x7FFF_FFFF_F000, config.stack_entropy),
-                heap_base: aslr.randomize_heap(0x0000_1000_0000, config.heap_entropy),
-                mmap_base: aslr.randomize_mmap(0x0000_7F00_0000_0000, config.mmap_entropy),
-                exe_base: aslr.randomize_pie(0x0000_5555_5555_0000, config.pie_entropy),
+                stack_base: aslr.randomize_stack(DEFAULT_STACK_TOP, config.stack_entropy),
+                heap_offset: aslr.randomize_heap(0, config.heap_entropy),
+                mmap_base: aslr.randomize_mmap(MMAP_BASE, config.mmap_entropy),
+                exe_base: aslr.randomize_pie(PIE_BASE_ADDR, config.pie_entropy),
                 config,
             }
         } else {
             Self {
-                stack_base: 0x7FFF_FFFF_F000,
-                heap_base: 0x0000_1000_0000,
-                mmap_base: 0x0000_7F00_0000_0000,
-                exe_base: 0x0000_0040_0000,
+                stack_base: DEFAULT_STACK_TOP,
+                heap_offset: 0,
+                mmap_base: MMAP_BASE,
+                exe_base: PIE_BASE_ADDR,
                 config,
             }
         }
     }
+
+    /// Initial program break for an image ending at `image_end`.
+    pub fn heap_start(&self, image_end: u64) -> u64 {
+        ((image_end + 0xFFF) & !0xFFF) + self.heap_offset
+    }
 }
 
 // =============================================================================
@@ -764,4 +898,42 @@ mod tests {
         // Enabled should have randomized addresses
         assert_ne!(space1.stack_base, space2.stack_base);
     }
+
+    #[test]
+    fn test_layout_bounds() {
+        let wide = AslrConfig {
+            enabled: true,
+            stack_entropy: 64,
+            heap_entropy: 64,
+            mmap_entropy: 64,
+            pie_entropy: 64,
+        };
+        let mut aslr = AslrState::with_seed(7);
+        for _ in 0..64 {
+            let space = RandomizedAddressSpace::with_state(wide, &mut aslr);
+            assert_eq!(space.stack_base & 0xFFF, 0);
+            assert!(space.exe_base + space.heap_offset < space.mmap_base);
+            assert!(space.mmap_base < space.stack_base);
+            assert_eq!(space.heap_start(0x40_1001) & 0xFFF, 0);
+        }
+    }
+
+    #[test]
+    fn test_personality_toggles_aslr() {
+        let pid = ProcessId::new(0x330);
+        let custom = AslrConfig { stack_entropy: 10, ..AslrConfig::default_config() };
+        set_aslr_policy(pid, custom);
+
+        assert_eq!(set_personality(pid, PERSONALITY_QUERY), 0);
+        assert_eq!(set_personality(pid, 0), 0);
+        assert_eq!(aslr_policy(pid).stack_entropy, 10);
+
+        assert_eq!(set_personality(pid, ADDR_NO_RANDOMIZE), 0);
+        assert!(!aslr_policy(pid).enabled);
+        assert_eq!(set_personality(pid, PERSONALITY_QUERY), ADDR_NO_RANDOMIZE);
+
+        assert_eq!(set_personality(pid, 0), ADDR_NO_RANDOMIZE);
+        assert!(aslr_policy(pid).enabled);
+        clear_aslr_policy(pid);
+    }
 }
@@ -0,0 +1,717 @@
+//! # Slab Allocator
+//!
+//! Object caches for frequently allocated kernel objects (channels,
+//! capability entries, TCP connections).
+//!
+//! ## Design
+//!
+//! - Each [`SlabCache`] hands out fixed-size slots carved from 16 KiB slabs
+//! - Slabs come from the frame allocator once it has memory, so empty ones
+//!   can be given back under memory pressure; before that they come from
+//!   the kernel heap
+//! - Free slots form an intrusive list, so allocation and free are O(1)
+//! - Each CPU keeps a small magazine of free slots in front of the cache
+//!   lock; the IPC fast path usually never touches the shared lists
+//! - An optional constructor initialises every slot before it is handed out
+//! - Every cache registers itself on first use and shows up in `/proc/slabinfo`
+//!
+//! ## Debug Builds
+//!
+//! With `debug_assertions` or the `debug` feature, every slot is surrounded by
+//! red zones and freed slots are filled with a poison pattern. Overwriting a
+//! red zone is reported on free; writing to a freed object is reported when
+//! the slot is next allocated.
+//!
+//! ```text
+//! ┌──────────┬────────────────────┬──────────┐
+//! │ red zone │       object       │ red zone │
+//! └──────────┴────────────────────┴──────────┘
+//! ```
+//!
+//! With the `kasan` feature, the shadow of a slot is poisoned whenever its
+//! object is not allocated, so stray accesses are reported as they happen.
+
+use alloc::alloc::{alloc, dealloc};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use spin::Mutex;
+
+use super::{FrameNumber, FRAME_ALLOCATOR, PAGE_SIZE};
+use crate::smp::MAX_CPUS;
+
+/// Size of one slab.
+const SLAB_SIZE: usize = 16 * 1024;
+
+/// Objects held in one per-CPU magazine at most.
+const MAGAZINE_SIZE: usize = 16;
+
+/// Whether red zones and poisoning are enabled.
+const SLAB_DEBUG: bool = cfg!(any(debug_assertions, feature = "debug"));
+
+/// Red zone size on each side of an object (debug builds only).
+const RED_ZONE: usize = if SLAB_DEBUG { 16 } else { 0 };
+
+/// Byte written into red zones.
+const RED_ZONE_BYTE: u8 = 0xBB;
+
+/// Byte written into freed objects.
+const POISON_BYTE: u8 = 0x6B;
+
+/// Per-CPU stash of free slots.
+struct Magazine {
+    slots: [usize; MAGAZINE_SIZE],
+    count: usize,
+}
+
+impl Magazine {
+    const fn new() -> Self {
+        Self {
+            slots: [0; MAGAZINE_SIZE],
+            count: 0,
+        }
+    }
+}
+
+/// One slab: a contiguous block of slots.
+struct Slab {
+    base: usize,
+    in_use: usize,
+    /// Taken from the frame allocator rather than the heap
+    frames: bool,
+}
+
+/// Shared state of a cache, behind its lock.
+struct CacheInner {
+    /// Slabs sorted by base address
+    slabs: Vec<Slab>,
+    /// Head of the intrusive free-slot list (0 = empty)
+    free_head: usize,
+    /// Slots on the free list
+    free_count: usize,
+}
+
+/// Usage statistics for a cache.
+#[derive(Debug, Clone)]
+pub struct SlabStats {
+    /// Cache name
+    pub name: &'static str,
+    /// Object size requested by the user
+    pub object_size: usize,
+    /// Bytes per slot including red zones and alignment
+    pub slot_size: usize,
+    /// Objects currently allocated
+    pub active_objects: usize,
+    /// Slots in all slabs
+    pub total_objects: usize,
+    /// Number of slabs
+    pub slabs: usize,
+    /// Allocations served
+    pub allocations: usize,
+    /// Frees served
+    pub frees: usize,
+    /// Allocations served from a per-CPU magazine
+    pub magazine_hits: usize,
+}
+
+/// A named cache of fixed-size objects.
+pub struct SlabCache {
+    name: &'static str,
+    object_size: usize,
+    align: usize,
+    ctor: Option<fn(*mut u8)>,
+    inner: Mutex<CacheInner>,
+    /// Taken before `inner` when both are held
+    magazines: [Mutex<Magazine>; MAX_CPUS],
+    registered: AtomicBool,
+    active: AtomicUsize,
+    allocations: AtomicUsize,
+    frees: AtomicUsize,
+    magazine_hits: AtomicUsize,
+}
+
+// SAFETY: All mutable state is behind locks or atomics; slots are plain memory.
+unsafe impl Sync for SlabCache {}
+unsafe impl Send for SlabCache {}
+
+#[cfg_attr(feature = "kasan", sanitize(address = "off"))]
+impl SlabCache {
+    /// Creates a cache for objects of `size` bytes aligned to `align`.
+    ///
+    /// `ctor` runs on every slot before it is returned from `alloc`.
+    pub const fn new(name: &'static str, size: usize, align: usize, ctor: Option<fn(*mut u8)>) -> Self {
+        const MAG_INIT: Mutex<Magazine> = Mutex::new(Magazine::new());
+        let align = if align < core::mem::size_of::<usize>() {
+            core::mem::size_of::<usize>()
+        } else {
+            align
+        };
+        Self {
+            name,
+            object_size: size,
+            align,
+            ctor,
+            inner: Mutex::new(CacheInner {
+                slabs: Vec::new(),
+                free_head: 0,
+                free_count: 0,
+            }),
+            magazines: [MAG_INIT; MAX_CPUS],
+            registered: AtomicBool::new(false),
+            active: AtomicUsize::new(0),
+            allocations: AtomicUsize::new(0),
+            frees: AtomicUsize::new(0),
+            magazine_hits: AtomicUsize::new(0),
+        }
+    }
+
+    /// Creates a cache sized and aligned for `T`.
+    pub const fn for_type<T>(name: &'static str) -> Self {
+        Self::new(name, core::mem::size_of::<T>(), core::mem::align_of::<T>(), None)
+    }
+
+    /// Returns the cache name.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Offset of the object within its slot.
+    fn object_offset(&self) -> usize {
+        align_up(RED_ZONE, self.align)
+    }
+
+    /// Bytes per slot.
+    fn slot_size(&self) -> usize {
+        let size = self.object_size.max(core::mem::size_of::<usize>());
+        align_up(self.object_offset() + size + RED_ZONE, self.align)
+    }
+
+    /// Slots per slab.
+    fn slots_per_slab(&self) -> usize {
+        (SLAB_SIZE / self.slot_size()).max(1)
+    }
+
+    /// Bytes per slab (larger than `SLAB_SIZE` only for huge objects).
+    fn slab_bytes(&self) -> usize {
+        (self.slots_per_slab() * self.slot_size()).max(SLAB_SIZE)
+    }
+
+    /// Allocates an object. Returns `None` when memory is exhausted.
+    ///
+    /// The first allocation registers the cache for `/proc/slabinfo` and
+    /// memory pressure, so only caches that live forever can allocate.
+    pub fn alloc(&'static self) -> Option<NonNull<u8>> {
+        if !self.registered.swap(true, Ordering::AcqRel) {
+            register(self);
+        }
+
+        let slot = match self.magazine_pop() {
+            Some(slot) => {
+                self.magazine_hits.fetch_add(1, Ordering::Relaxed);
+                slot
+            }
+            None => self.alloc_slow()?,
+        };
+
+        self.allocations.fetch_add(1, Ordering::Relaxed);
+        self.active.fetch_add(1, Ordering::Relaxed);
+
+        let object = slot + self.object_offset();
+        #[cfg(feature = "kasan")]
+        super::kasan::unpoison(object, self.object_size);
+        if SLAB_DEBUG {
+            self.check_poison(object);
+        }
+        if let Some(ctor) = self.ctor {
+            ctor(object as *mut u8);
+        }
+        NonNull::new(object as *mut u8)
+    }
+
+    /// Returns an object to the cache.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must come from `alloc` on this cache and must not be used again.
+    pub unsafe fn free(&self, ptr: NonNull<u8>) {
+        let object = ptr.as_ptr() as usize;
+        let slot = object - self.object_offset();
+        #[cfg(feature = "kasan")]
+        let _suppress = super::kasan::suppress();
+
+        if SLAB_DEBUG {
+            self.check_red_zones(slot, object);
+            // SAFETY: the object area belongs to this slot
+            unsafe { core::ptr::write_bytes(object as *mut u8, POISON_BYTE, self.object_size) };
+        }
+        #[cfg(feature = "kasan")]
+        super::kasan::poison(object, align_up(self.object_size, 8), super::kasan::SLAB_FREED);
+
+        self.frees.fetch_add(1, Ordering::Relaxed);
+        self.active.fetch_sub(1, Ordering::Relaxed);
+
+        let cpu = crate::smp::percpu::cpu_id().as_index();
+        let mut mag = self.magazines[cpu].lock();
+        if mag.count == MAGAZINE_SIZE {
+            // Flush half the magazine to the shared free list
+            let mut inner = self.inner.lock();
+            for _ in 0..MAGAZINE_SIZE / 2 {
+                mag.count -= 1;
+                self.push_free(&mut inner, mag.slots[mag.count]);
+            }
+        }
+        let n = mag.count;
+        mag.slots[n] = slot;
+        mag.count += 1;
+    }
+
+    fn magazine_pop(&self) -> Option<usize> {
+        let cpu = crate::smp::percpu::cpu_id().as_index();
+        let mut mag = self.magazines[cpu].lock();
+        if mag.count == 0 {
+            return None;
+        }
+        mag.count -= 1;
+        Some(mag.slots[mag.count])
+    }
+
+    /// Takes a slot from the shared list, growing the cache if needed, and
+    /// refills the current CPU's magazine on the way.
+    fn alloc_slow(&self) -> Option<usize> {
+        #[cfg(feature = "kasan")]
+        let _suppress = super::kasan::suppress();
+        let cpu = crate::smp::percpu::cpu_id().as_index();
+        let mut mag = self.magazines[cpu].lock();
+        let mut inner = self.inner.lock();
+        if inner.free_head == 0 {
+            self.grow(&mut inner)?;
+        }
+        let slot = self.pop_free(&mut inner)?;
+
+        while mag.count < MAGAZINE_SIZE / 2 {
+            match self.pop_free(&mut inner) {
+                Some(s) => {
+                    let n = mag.count;
+                    mag.slots[n] = s;
+                    mag.count += 1;
+                }
+                None => break,
+            }
+        }
+        Some(slot)
+    }
+
+    /// Adds a new slab to the cache.
+    fn grow(&self, inner: &mut CacheInner) -> Option<()> {
+        let bytes = self.slab_bytes();
+        let (base, frames) = match self.take_frames() {
+            Some(base) => (base, true),
+            None => {
+                let layout = Layout::from_size_align(bytes, self.align.max(64)).ok()?;
+                // SAFETY: layout has non-zero size
+                let base = unsafe { alloc(layout) } as usize;
+                if base == 0 {
+                    return None;
+                }
+                (base, false)
+            }
+        };
+        #[cfg(feature = "kasan")]
+        super::kasan::poison(base, bytes, super::kasan::SLAB_REDZONE);
+
+        let slot_size = self.slot_size();
+        for i in (0..self.slots_per_slab()).rev() {
+            let slot = base + i * slot_size;
+            if SLAB_DEBUG {
+                // SAFETY: the slot lies inside the freshly allocated slab
+                unsafe {
+                    core::ptr::write_bytes(slot as *mut u8, RED_ZONE_BYTE, slot_size);
+                    core::ptr::write_bytes(
+                        (slot + self.object_offset()) as *mut u8,
+                        POISON_BYTE,
+                        self.object_size,
+                    );
+                }
+            }
+            self.link_free(inner, slot);
+        }
+
+        let pos = inner.slabs.partition_point(|s| s.base < base);
+        inner.slabs.insert(pos, Slab { base, in_use: 0, frames });
+        Some(())
+    }
+
+    /// Frames per slab.
+    fn slab_pages(&self) -> usize {
+        self.slab_bytes().div_ceil(PAGE_SIZE)
+    }
+
+    /// Takes the frames for a slab, if the frame allocator has them.
+    ///
+    /// Frames are identity-mapped on x86_64 only. KASAN builds keep slabs
+    /// in the heap, where the shadow tracks every slot, and host tests have
+    /// no mapped frames.
+    fn take_frames(&self) -> Option<usize> {
+        #[cfg(all(target_arch = "x86_64", not(test), not(feature = "kasan")))]
+        if self.align <= PAGE_SIZE {
+            if let Ok(frame) = FRAME_ALLOCATOR.allocate_contiguous(self.slab_pages()) {
+                return Some(frame.address() as usize);
+            }
+        }
+        None
+    }
+
+    /// Pushes a slot on the intrusive free list without touching slab counts.
+    fn link_free(&self, inner: &mut CacheInner, slot: usize) {
+        // The link lives in the first word of the slot (inside the red zone
+        // in debug builds, so poison stays intact)
+        // SAFETY: slot is a valid, unused slot of this cache
+        unsafe { (slot as *mut usize).write(inner.free_head) };
+        inner.free_head = slot;
+        inner.free_count += 1;
+    }
+
+    fn push_free(&self, inner: &mut CacheInner, slot: usize) {
+        if let Some(slab) = self.slab_of(inner, slot) {
+            slab.in_use -= 1;
+        }
+        self.link_free(inner, slot);
+    }
+
+    fn pop_free(&self, inner: &mut CacheInner) -> Option<usize> {
+        let slot = inner.free_head;
+        if slot == 0 {
+            return None;
+        }
+        // SAFETY: free slots hold the next link in their first word
+        inner.free_head = unsafe { (slot as *const usize).read() };
+        inner.free_count -= 1;
+        if SLAB_DEBUG {
+            // Restore the red zone byte pattern the link overwrote
+            // SAFETY: slot is ours until handed out
+            unsafe {
+                core::ptr::write_bytes(slot as *mut u8, RED_ZONE_BYTE, core::mem::size_of::<usize>())
+            };
+        }
+        if let Some(slab) = self.slab_of(inner, slot) {
+            slab.in_use += 1;
+        }
+        Some(slot)
+    }
+
+    fn slab_of<'a>(&self, inner: &'a mut CacheInner, slot: usize) -> Option<&'a mut Slab> {
+        let pos = inner.slabs.partition_point(|s| s.base <= slot);
+        let bytes = self.slab_bytes();
+        inner.slabs[..pos]
+            .last_mut()
+            .filter(|s| slot < s.base + bytes)
+    }
+
+    /// Releases slabs with no objects in use. Returns the number released.
+    ///
+    /// Slots parked in per-CPU magazines keep their slab alive.
+    pub fn shrink(&self) -> usize {
+        self.release_empty().0
+    }
+
+    /// Frames held by slabs with no objects in use, which
+    /// [`shrink`](Self::shrink) would give back to the frame allocator.
+    pub fn reclaimable_pages(&self) -> usize {
+        let inner = self.inner.lock();
+        inner.slabs.iter().filter(|s| s.in_use == 0 && s.frames).count() * self.slab_pages()
+    }
+
+    /// Releases empty slabs. Returns the number released and the frames
+    /// among them given back to the frame allocator.
+    fn release_empty(&self) -> (usize, usize) {
+        #[cfg(feature = "kasan")]
+        let _suppress = super::kasan::suppress();
+        let mut inner = self.inner.lock();
+        let bytes = self.slab_bytes();
+        let empty: Vec<(usize, bool)> = inner
+            .slabs
+            .iter()
+            .filter(|s| s.in_use == 0)
+            .map(|s| (s.base, s.frames))
+            .collect();
+        if empty.is_empty() {
+            return (0, 0);
+        }
+
+        // Rebuild the free list without slots from the empty slabs
+        let mut slot = inner.free_head;
+        inner.free_head = 0;
+        inner.free_count = 0;
+        while slot != 0 {
+            // SAFETY: walking our own free list
+            let next = unsafe { (slot as *const usize).read() };
+            let dead = empty.iter().any(|&(b, _)| slot >= b && slot < b + bytes);
+            if !dead {
+                self.link_free(&mut inner, slot);
+            }
+            slot = next;
+        }
+
+        let layout = Layout::from_size_align(bytes, self.align.max(64)).unwrap();
+        let mut pages = 0;
+        for &(base, frames) in &empty {
+            if frames {
+                FRAME_ALLOCATOR.free_contiguous(FrameNumber::from_address(base as u64), self.slab_pages());
+                pages += self.slab_pages();
+            } else {
+                // SAFETY: base came from `alloc` with this layout in `grow`
+                unsafe { dealloc(base as *mut u8, layout) };
+            }
+        }
+        inner.slabs.retain(|s| s.in_use != 0);
+        (empty.len(), pages)
+    }
+
+    /// Returns usage statistics.
+    pub fn stats(&self) -> SlabStats {
+        let slabs = self.inner.lock().slabs.len();
+        SlabStats {
+            name: self.name,
+            object_size: self.object_size,
+            slot_size: self.slot_size(),
+            active_objects: self.active.load(Ordering::Relaxed),
+            total_objects: slabs * self.slots_per_slab(),
+            slabs,
+            allocations: self.allocations.load(Ordering::Relaxed),
+            frees: self.frees.load(Ordering::Relaxed),
+            magazine_hits: self.magazine_hits.load(Ordering::Relaxed),
+        }
+    }
+
+    fn check_red_zones(&self, slot: usize, object: usize) {
+        let leading = object - slot;
+        let trailing_start = object + self.object_size;
+        let trailing = slot + self.slot_size() - trailing_start;
+        // SAFETY: both ranges lie inside this slot
+        let (front, back) = unsafe {
+            (
+                core::slice::from_raw_parts(slot as *const u8, leading),
+                core::slice::from_raw_parts(trailing_start as *const u8, trailing),
+            )
+        };
+        if front.iter().chain(back.iter()).any(|&b| b != RED_ZONE_BYTE) {
+            panic!("slab {}: red zone overwritten around object {:#x}", self.name, object);
+        }
+    }
+
+    fn check_poison(&self, object: usize) {
+        // SAFETY: the object area belongs to a slot we just took
+        let bytes = unsafe { core::slice::from_raw_parts(object as *const u8, self.object_size) };
+        if let Some(pos) = bytes.iter().position(|&b| b != POISON_BYTE) {
+            panic!(
+                "slab {}: object {:#x} modified after free (offset {})",
+                self.name, object, pos
+            );
+        }
+    }
+}
+
+/// An object allocated from a [`SlabCache`].
+///
+/// Dropping the box drops the value and returns the slot to its cache.
+pub struct SlabBox<T> {
+    ptr: NonNull<T>,
+    cache: &'static SlabCache,
+    _marker: PhantomData<T>,
+}
+
+// SAFETY: SlabBox owns its T exclusively, like Box<T>
+unsafe impl<T: Send> Send for SlabBox<T> {}
+unsafe impl<T: Sync> Sync for SlabBox<T> {}
+
+impl<T> SlabBox<T> {
+    /// Moves `value` into an object from `cache`.
+    ///
+    /// Returns `None` when the cache cannot grow.
+    pub fn new(value: T, cache: &'static SlabCache) -> Option<Self> {
+        debug_assert!(cache.object_size >= core::mem::size_of::<T>());
+        debug_assert!(cache.align >= core::mem::align_of::<T>());
+        let ptr = cache.alloc()?.cast::<T>();
+        // SAFETY: the slot is large enough and aligned for T
+        unsafe { ptr.as_ptr().write(value) };
+        Some(Self {
+            ptr,
+            cache,
+            _marker: PhantomData,
+        })
+    }
+}
+
+impl<T> Deref for SlabBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: ptr is valid and initialised for the lifetime of the box
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for SlabBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: we own the object exclusively
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for SlabBox<T> {
+    fn drop(&mut self) {
+        // SAFETY: the object is initialised and owned by us; the slot came
+        // from this cache
+        unsafe {
+            core::ptr::drop_in_place(self.ptr.as_ptr());
+            self.cache.free(self.ptr.cast());
+        }
+    }
+}
+
+impl<T: core::fmt::Debug> core::fmt::Debug for SlabBox<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+/// All caches that have been used at least once.
+static REGISTRY: Mutex<Vec<&'static SlabCache>> = Mutex::new(Vec::new());
+
+fn register(cache: &'static SlabCache) {
+    REGISTRY.lock().push(cache);
+}
+
+/// Returns statistics for every registered cache.
+pub fn all_stats() -> Vec<SlabStats> {
+    REGISTRY.lock().iter().map(|c| c.stats()).collect()
+}
+
+/// Releases empty slabs in every cache. Returns the frames given back to
+/// the frame allocator; heap-backed slabs return to the heap uncounted.
+pub fn shrink_all() -> usize {
+    REGISTRY.lock().iter().map(|c| c.release_empty().1).sum()
+}
+
+/// Frames that [`shrink_all`] would give back right now.
+pub fn reclaimable_pages() -> usize {
+    REGISTRY.lock().iter().map(|c| c.reclaimable_pages()).sum()
+}
+
+/// Formats `/proc/slabinfo`.
+pub fn slabinfo() -> String {
+    let mut out = String::from(
+        "slabinfo - version: 2.1\n\
+         # name            <active_objs> <num_objs> <objsize> <slotsize> <num_slabs> <allocs> <frees> <mag_hits>\n",
+    );
+    for s in all_stats() {
+        out.push_str(&format!(
+            "{:<18} {:>13} {:>10} {:>9} {:>10} {:>11} {:>8} {:>7} {:>10}\n",
+            s.name,
+            s.active_objects,
+            s.total_objects,
+            s.object_size,
+            s.slot_size,
+            s.slabs,
+            s.allocations,
+            s.frees,
+            s.magazine_hits
+        ));
+    }
+    out
+}
+
+const fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_magazine_reuses_freed_slot() {
+        static CACHE: SlabCache = SlabCache::new("test_magazine", 48, 8, None);
+        let a = CACHE.alloc().unwrap();
+        // SAFETY: a came from CACHE
+        unsafe { CACHE.free(a) };
+        let hits = CACHE.stats().magazine_hits;
+        let b = CACHE.alloc().unwrap();
+        assert_eq!(a, b);
+        assert_eq!(CACHE.stats().magazine_hits, hits + 1);
+        // SAFETY: b came from CACHE
+        unsafe { CACHE.free(b) };
+        assert_eq!(CACHE.stats().active_objects, 0);
+    }
+
+    #[test]
+    fn test_shrink_releases_empty_slabs() {
+        // One slot per slab, so every allocation grows the cache
+        static CACHE: SlabCache = SlabCache::new("test_shrink", 12 * 1024, 8, None);
+        let objects: Vec<_> = (0..20).map(|_| CACHE.alloc().unwrap()).collect();
+        assert_eq!(CACHE.stats().slabs, 20);
+        assert_eq!(CACHE.shrink(), 0);
+
+        for object in objects {
+            // SAFETY: every object came from CACHE
+            unsafe { CACHE.free(object) };
+        }
+        // The magazine overflowed once, flushing half of it to the shared
+        // list; the slots still in the magazine keep their slabs
+        assert_eq!(CACHE.shrink(), MAGAZINE_SIZE / 2);
+        assert_eq!(CACHE.stats().slabs, 20 - MAGAZINE_SIZE / 2);
+        assert_eq!(CACHE.stats().active_objects, 0);
+
+        // The remaining slabs still serve allocations
+        let object = CACHE.alloc().unwrap();
+        // SAFETY: object came from CACHE
+        unsafe { CACHE.free(object) };
+    }
+
+    #[test]
+    #[cfg(any(debug_assertions, feature = "debug"))]
+    #[should_panic(expected = "red zone overwritten")]
+    fn test_red_zone_overrun() {
+        static CACHE: SlabCache = SlabCache::new("test_red_zone", 32, 8, None);
+        let object = CACHE.alloc().unwrap();
+        // SAFETY: the byte past the object is the trailing red zone of its slot
+        unsafe {
+            object.as_ptr().add(32).write(0);
+            CACHE.free(object);
+        }
+    }
+
+    #[test]
+    #[cfg(any(debug_assertions, feature = "debug"))]
+    #[should_panic(expected = "modified after free")]
+    fn test_use_after_free() {
+        static CACHE: SlabCache = SlabCache::new("test_poison", 32, 8, None);
+        let object = CACHE.alloc().unwrap();
+        // SAFETY: the slot stays mapped after free; the write is the bug
+        // under test
+        unsafe {
+            CACHE.free(object);
+            object.as_ptr().add(8).write(0);
+        }
+        // The freed slot is on top of the magazine
+        CACHE.alloc();
+    }
+
+    #[test]
+    #[cfg(any(debug_assertions, feature = "debug"))]
+    fn test_fresh_slots_are_poisoned() {
+        static CACHE: SlabCache = SlabCache::new("test_fresh", 64, 8, None);
+        let object = CACHE.alloc().unwrap();
+        // SAFETY: the object is 64 bytes
+        let bytes = unsafe { core::slice::from_raw_parts(object.as_ptr(), 64) };
+        assert!(bytes.iter().all(|&b| b == POISON_BYTE));
+        // SAFETY: object came from CACHE
+        unsafe { CACHE.free(object) };
+    }
+}
@@ -0,0 +1,1330 @@
+//! # Demand Paging and Copy-on-Write
+//!
+//! Per-process address spaces with an opt-in lazy mode.
+//!
+//! ## Modes
+//!
+//! - **Strict** (default): every page of a region is backed when the region
+//!   is mapped, and `fork` copies every page up front. Allocation failures
+//!   surface at map/fork time, never at first touch.
+//! - **Lazy**: regions are reserved without backing. The first touch faults in
+//!   a zeroed frame, and `fork` shares writable pages read-only until either
+//!   side writes. A process must present a `vm:lazy` capability to opt in.
+//!
+//! ## File Mappings
+//!
+//! Regions can be backed by a [`Pager`] (the page cache). Shared mappings map
+//! the cache's frames directly and mark them dirty on first write; private
+//! mappings map them read-only and copy on first write.
+//!
+//! ## Fault Resolution
+//!
+//! ```text
+//! not mapped, anonymous region     -> map a zeroed frame      (demand-zero)
+//! not mapped, file region          -> map the cached frame    (page cache)
+//! write to shared clean file page  -> mark dirty, allow write (shared write)
+//! write to COW page, frame shared  -> copy into a new frame   (COW copy)
+//! write to COW page, sole owner    -> make the page writable  (COW reuse)
+//! outside any region / bad access  -> SIGSEGV
+//! ```
+//!
+//! The software page map is authoritative; on x86_64 every change is mirrored
+//! into the hardware page tables rooted at the address space's PML4.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Mutex;
+
+use super::frame::{FrameAllocator, FrameNumber, FRAME_ALLOCATOR, PAGE_SIZE};
+use crate::cap::{CapabilityTable, CapabilityToken, Operations};
+use crate::sched::ProcessId;
+
+/// Resource type of the capability that enables lazy mode.
+pub const LAZY_VM_RESOURCE: &str = "vm:lazy";
+
+/// First address above user space.
+pub const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+
+/// Lowest address picked for mappings without a fixed address.
+pub const MMAP_BASE: u64 = 0x0000_1000_0000_0000;
+
+/// Address space mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmMode {
+    /// Eager allocation, no overcommit
+    Strict,
+    /// Demand-zero allocation and copy-on-write fork
+    Lazy,
+}
+
+/// Kind of memory access that faulted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultAccess {
+    /// Data read
+    Read,
+    /// Data write
+    Write,
+    /// Instruction fetch
+    Execute,
+}
+
+/// How a page fault was resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultKind {
+    /// A zeroed frame was mapped
+    DemandZero,
+    /// A page-cache frame was mapped
+    PageCache,
+    /// A shared file page was marked dirty and made writable
+    SharedWrite,
+    /// A shared frame was copied for the writer
+    CowCopy,
+    /// The writer was the last sharer and kept the frame
+    CowReuse,
+    /// The page was already mapped with the required access
+    Spurious,
+}
+
+/// Virtual memory errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmError {
+    /// No frame available
+    OutOfMemory,
+    /// Address is not inside any region
+    SegmentationFault,
+    /// Region does not allow the access
+    ProtectionViolation,
+    /// Range is not page-aligned, empty, or outside user space
+    InvalidRange,
+    /// Range overlaps an existing region
+    Overlap,
+    /// Capability check failed
+    PermissionDenied,
+    /// Process has no address space
+    NoAddressSpace,
+    /// Backing object could not supply a page
+    PagerError,
+}
+
+/// Supplies pages for file-backed regions.
+///
+/// Frames returned by [`get_page`](Pager::get_page) stay owned by the pager;
+/// the address space only holds a pin until it calls
+/// [`put_page`](Pager::put_page).
+pub trait Pager: Send + Sync {
+    /// Returns the frame holding page `index` of the object, pinned.
+    fn get_page(&self, index: u64) -> Result<u64, VmError>;
+
+    /// Drops a pin taken by `get_page`.
+    fn put_page(&self, index: u64);
+
+    /// Records that a mapped page was written.
+    fn set_dirty(&self, index: u64);
+
+    /// Writes dirty pages back to the object.
+    fn flush(&self) {}
+}
+
+/// Backing of a file-mapped region.
+#[derive(Clone)]
+pub struct FileBacking {
+    /// Page source
+    pub pager: Arc<dyn Pager>,
+    /// Object offset of the region start, page-aligned
+    pub offset: u64,
+    /// Writes go to the object (MAP_SHARED) rather than a private copy
+    pub shared: bool,
+}
+
+impl core::fmt::Debug for FileBacking {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("FileBacking")
+            .field("offset", &self.offset)
+            .field("shared", &self.shared)
+            .finish()
+    }
+}
+
+/// A mapped region.
+#[derive(Debug, Clone)]
+pub struct VmArea {
+    /// First address
+    pub start: u64,
+    /// First address past the end
+    pub end: u64,
+    /// Writes allowed
+    pub writable: bool,
+    /// Instruction fetches allowed
+    pub executable: bool,
+    /// File backing (None = anonymous)
+    pub backing: Option<FileBacking>,
+}
+
+impl VmArea {
+    /// Returns the backing object's page index for an address in this area.
+    fn file_index(&self, backing: &FileBacking, page: u64) -> u64 {
+        (backing.offset + (page - self.start)) / PAGE_SIZE as u64
+    }
+
+    /// Returns the part of this area inside `[start, end)`, adjusting the
+    /// file offset.
+    fn slice(&self, start: u64, end: u64) -> VmArea {
+        let start = start.max(self.start);
+        let end = end.min(self.end);
+        let mut area = self.clone();
+        if let Some(backing) = area.backing.as_mut() {
+            backing.offset += start - self.start;
+        }
+        area.start = start;
+        area.end = end;
+        area
+    }
+}
+
+/// Page fault counters.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FaultStats {
+    /// Zeroed frames mapped on first touch
+    pub demand_zero: u64,
+    /// Page-cache frames mapped
+    pub page_cache: u64,
+    /// Shared file pages dirtied
+    pub shared_writes: u64,
+    /// Shared frames copied on write
+    pub cow_copies: u64,
+    /// COW pages made writable without copying
+    pub cow_reuses: u64,
+    /// Faults on already-valid mappings
+    pub spurious: u64,
+    /// Faults that could not be resolved
+    pub invalid: u64,
+}
+
+/// What a mapped frame is and who owns it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PageKind {
+    /// Anonymous frame owned by the address space(s) mapping it
+    Anon { cow: bool },
+    /// Page-cache frame mapped shared
+    FileShared { dirty: bool },
+    /// Page-cache frame mapped private, read-only until first write
+    FilePrivate,
+}
+
+/// A page in the software page map.
+#[derive(Debug, Clone, Copy)]
+struct PageEntry {
+    frame: u64,
+    kind: PageKind,
+}
+
+/// Frames mapped by more than one address space, with their map count.
+///
+/// A frame absent from the map has exactly one owner.
+static SHARED_FRAMES: Mutex<BTreeMap<u64, u32>> = Mutex::new(BTreeMap::new());
+
+fn frame_share(frame: u64) {
+    *SHARED_FRAMES.lock().entry(frame).or_insert(1) += 1;
+}
+
+fn frame_refs(frame: u64) -> u32 {
+    SHARED_FRAMES.lock().get(&frame).copied().unwrap_or(1)
+}
+
+/// Drops one mapping of a frame. Returns true if it was the last.
+fn frame_put(frame: u64) -> bool {
+    let mut shared = SHARED_FRAMES.lock();
+    match shared.get_mut(&frame) {
+        Some(count) => {
+            *count -= 1;
+            if *count == 1 {
+                shared.remove(&frame);
+            }
+            false
+        }
+        None => true,
+    }
+}
+
+/// System-wide fault counters for `/proc/vmstat`.
+struct GlobalStats {
+    demand_zero: AtomicU64,
+    page_cache: AtomicU64,
+    shared_writes: AtomicU64,
+    cow_copies: AtomicU64,
+    cow_reuses: AtomicU64,
+    spurious: AtomicU64,
+    invalid: AtomicU64,
+}
+
+static GLOBAL_STATS: GlobalStats = GlobalStats {
+    demand_zero: AtomicU64::new(0),
+    page_cache: AtomicU64::new(0),
+    shared_writes: AtomicU64::new(0),
+    cow_copies: AtomicU64::new(0),
+    cow_reuses: AtomicU64::new(0),
+    spurious: AtomicU64::new(0),
+    invalid: AtomicU64::new(0),
+};
+
+/// Returns fault counters summed over all address spaces since boot.
+pub fn global_stats() -> FaultStats {
+    FaultStats {
+        demand_zero: GLOBAL_STATS.demand_zero.load(Ordering::Relaxed),
+        page_cache: GLOBAL_STATS.page_cache.load(Ordering::Relaxed),
+        shared_writes: GLOBAL_STATS.shared_writes.load(Ordering::Relaxed),
+        cow_copies: GLOBAL_STATS.cow_copies.load(Ordering::Relaxed),
+        cow_reuses: GLOBAL_STATS.cow_reuses.load(Ordering::Relaxed),
+        spurious: GLOBAL_STATS.spurious.load(Ordering::Relaxed),
+        invalid: GLOBAL_STATS.invalid.load(Ordering::Relaxed),
+    }
+}
+
+/// Returns a pointer to the contents of a frame.
+#[cfg(not(test))]
+fn frame_ptr(frame: u64) -> *mut u8 {
+    // Physical memory is identity-mapped
+    frame as *mut u8
+}
+
+#[cfg(test)]
+fn frame_ptr(frame: u64) -> *mut u8 {
+    tests::phys_ptr(frame)
+}
+
+/// A user address space.
+pub struct AddressSpace {
+    /// Hardware page table root (0 = software only)
+    root: u64,
+    mode: VmMode,
+    /// Regions keyed by start address
+    areas: BTreeMap<u64, VmArea>,
+    /// Mapped pages keyed by page address
+    pages: BTreeMap<u64, PageEntry>,
+    /// Lowest address for mappings without a fixed address
+    mmap_base: u64,
+    stats: FaultStats,
+    frames: &'static FrameAllocator,
+}
+
+impl AddressSpace {
+    /// Creates an empty strict address space.
+    pub fn new(root: u64) -> Self {
+        Self::with_allocator(root, &FRAME_ALLOCATOR)
+    }
+
+    /// Creates an empty strict address space backed by `frames`.
+    pub fn with_allocator(root: u64, frames: &'static FrameAllocator) -> Self {
+        Self {
+            root,
+            mode: VmMode::Strict,
+            areas: BTreeMap::new(),
+            pages: BTreeMap::new(),
+            mmap_base: MMAP_BASE,
+            stats: FaultStats::default(),
+            frames,
+        }
+    }
+
+    /// Returns the mode.
+    pub fn mode(&self) -> VmMode {
+        self.mode
+    }
+
+    /// Returns the page table root.
+    pub fn root(&self) -> u64 {
+        self.root
+    }
+
+    /// Returns the lowest address picked by [`Self::find_free`].
+    pub fn mmap_base(&self) -> u64 {
+        self.mmap_base
+    }
+
+    /// Moves the base of the mmap region, e.g. to a randomized one at exec.
+    pub fn set_mmap_base(&mut self, base: u64) {
+        self.mmap_base = base;
+    }
+
+    /// Returns this address space's fault counters.
+    pub fn stats(&self) -> FaultStats {
+        self.stats
+    }
+
+    /// Returns the number of pages backed by a frame.
+    pub fn resident_pages(&self) -> usize {
+        self.pages.len()
+    }
+
+    /// Returns the regions in address order.
+    pub fn areas(&self) -> impl Iterator<Item = &VmArea> {
+        self.areas.values()
+    }
+
+    /// Switches to lazy mode. Already-backed pages stay backed.
+    fn set_lazy(&mut self) {
+        self.mode = VmMode::Lazy;
+    }
+
+    fn area_at(&self, addr: u64) -> Option<&VmArea> {
+        self.areas
+            .range(..=addr)
+            .next_back()
+            .map(|(_, area)| area)
+            .filter(|area| addr < area.end)
+    }
+
+    /// Returns the lowest free range of `len` bytes at or above the mmap base.
+    pub fn find_free(&self, len: u64) -> Option<u64> {
+        let mut candidate = self.mmap_base;
+        for area in self.areas.range(self.mmap_base..).map(|(_, a)| a) {
+            if area.start >= candidate + len {
+                break;
+            }
+            candidate = candidate.max(area.end);
+        }
+        // An area starting below the base may still reach into it
+        if let Some(area) = self.area_at(self.mmap_base) {
+            candidate = candidate.max(area.end);
+        }
+        (candidate + len <= USER_SPACE_END).then_some(candidate)
+    }
+
+    /// Maps an anonymous region.
+    ///
+    /// In strict mode every page is backed before this returns; in lazy mode
+    /// pages are backed on first touch.
+    pub fn map_anonymous(
+        &mut self,
+        start: u64,
+        len: u64,
+        writable: bool,
+        executable: bool,
+    ) -> Result<(), VmError> {
+        self.map_area(start, len, writable, executable, None)
+    }
+
+    /// Maps a file-backed region.
+    ///
+    /// In strict mode every page is faulted in (and private writable pages
+    /// copied) before this returns.
+    pub fn map_file(
+        &mut self,
+        start: u64,
+        len: u64,
+        writable: bool,
+        executable: bool,
+        backing: FileBacking,
+    ) -> Result<(), VmError> {
+        if backing.offset % PAGE_SIZE as u64 != 0 {
+            return Err(VmError::InvalidRange);
+        }
+        self.map_area(start, len, writable, executable, Some(backing))
+    }
+
+    fn map_area(
+        &mut self,
+        start: u64,
+        len: u64,
+        writable: bool,
+        executable: bool,
+        backing: Option<FileBacking>,
+    ) -> Result<(), VmError> {
+        let end = check_range(start, len)?;
+        if self.areas.values().any(|a| a.start < end && start < a.end) {
+            return Err(VmError::Overlap);
+        }
+
+        let private_write = writable && backing.as_ref().map_or(true, |b| !b.shared);
+        self.areas.insert(
+            start,
+            VmArea {
+                start,
+                end,
+                writable,
+                executable,
+                backing,
+            },
+        );
+
+        if self.mode == VmMode::Strict {
+            // Populate now so the region never faults for lack of memory
+            let access = if private_write {
+                FaultAccess::Write
+            } else {
+                FaultAccess::Read
+            };
+            for page in (start..end).step_by(PAGE_SIZE) {
+                if let Err(e) = self.resolve(page, access) {
+                    let _ = self.unmap(start, len);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Unmaps a range, splitting regions that straddle its ends.
+    pub fn unmap(&mut self, start: u64, len: u64) -> Result<(), VmError> {
+        let end = check_range(start, len)?;
+
+        let resident: Vec<u64> = self.pages.range(start..end).map(|(&p, _)| p).collect();
+        for page in resident {
+            self.release(page);
+        }
+
+        let hit: Vec<VmArea> = self
+            .areas
+            .values()
+            .filter(|a| a.start < end && start < a.end)
+            .cloned()
+            .collect();
+        for area in hit {
+            self.areas.remove(&area.start);
+            if area.start < start {
+                self.areas.insert(area.start, area.slice(area.start, start));
+            }
+            if end < area.end {
+                self.areas.insert(end, area.slice(end, area.end));
+            }
+        }
+        Ok(())
+    }
+
+    /// Marks written shared file pages in a range dirty and flushes their
+    /// backing objects.
+    pub fn msync(&mut self, start: u64, len: u64) -> Result<(), VmError> {
+        let end = check_range(start, len)?;
+        let mut flushed: Vec<*const ()> = Vec::new();
+        for (&page, entry) in self.pages.range(start..end) {
+            let Some(area) = self.area_at(page) else { continue };
+            let Some(backing) = area.backing.as_ref() else { continue };
+            if entry.kind == (PageKind::FileShared { dirty: true }) {
+                backing.pager.set_dirty(area.file_index(backing, page));
+            }
+            let id = Arc::as_ptr(&backing.pager) as *const ();
+            if !flushed.contains(&id) {
+                flushed.push(id);
+            }
+        }
+        for area in self.areas.values().filter(|a| a.start < end && start < a.end) {
+            if let Some(backing) = area.backing.as_ref() {
+                if flushed.contains(&(Arc::as_ptr(&backing.pager) as *const ())) {
+                    backing.pager.flush();
+                    flushed.retain(|&p| p != Arc::as_ptr(&backing.pager) as *const ());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Translates a user address to a physical address.
+    pub fn translate(&self, addr: u64) -> Option<u64> {
+        let page = addr & !(PAGE_SIZE as u64 - 1);
+        self.pages.get(&page).map(|e| e.frame + (addr - page))
+    }
+
+    /// Resolves a page fault.
+    pub fn handle_fault(&mut self, addr: u64, access: FaultAccess) -> Result<FaultKind, VmError> {
+        let result = self.resolve(addr, access);
+        let (local, global) = match result {
+            Ok(FaultKind::DemandZero) => (&mut self.stats.demand_zero, &GLOBAL_STATS.demand_zero),
+            Ok(FaultKind::PageCache) => (&mut self.stats.page_cache, &GLOBAL_STATS.page_cache),
+            Ok(FaultKind::SharedWrite) => {
+                (&mut self.stats.shared_writes, &GLOBAL_STATS.shared_writes)
+            }
+            Ok(FaultKind::CowCopy) => (&mut self.stats.cow_copies, &GLOBAL_STATS.cow_copies),
+            Ok(FaultKind::CowReuse) => (&mut self.stats.cow_reuses, &GLOBAL_STATS.cow_reuses),
+            Ok(FaultKind::Spurious) => (&mut self.stats.spurious, &GLOBAL_STATS.spurious),
+            Err(_) => (&mut self.stats.invalid, &GLOBAL_STATS.invalid),
+        };
+        *local += 1;
+        global.fetch_add(1, Ordering::Relaxed);
+        result
+    }
+
+    fn resolve(&mut self, addr: u64, access: FaultAccess) -> Result<FaultKind, VmError> {
+        let page = addr & !(PAGE_SIZE as u64 - 1);
+        let area = self.area_at(addr).ok_or(VmError::SegmentationFault)?;
+        match access {
+            FaultAccess::Write if !area.writable => return Err(VmError::ProtectionViolation),
+            FaultAccess::Execute if !area.executable => return Err(VmError::ProtectionViolation),
+            _ => {}
+        }
+        let write = access == FaultAccess::Write;
+        let file = area
+            .backing
+            .clone()
+            .map(|b| (area.file_index(&b, page), b));
+
+        match (self.pages.get(&page).copied(), file) {
+            (None, None) => {
+                self.map_zeroed(page)?;
+                Ok(FaultKind::DemandZero)
+            }
+            (None, Some((index, backing))) => {
+                let frame = backing.pager.get_page(index)?;
+                if backing.shared {
+                    if write {
+                        backing.pager.set_dirty(index);
+                    }
+                    self.install(page, PageEntry { frame, kind: PageKind::FileShared { dirty: write } });
+                    Ok(FaultKind::PageCache)
+                } else if write {
+                    let result = self.copy_private(page, frame);
+                    backing.pager.put_page(index);
+                    result.map(|_| FaultKind::CowCopy)
+                } else {
+                    self.install(page, PageEntry { frame, kind: PageKind::FilePrivate });
+                    Ok(FaultKind::PageCache)
+                }
+            }
+            (Some(entry), file) if write => match (entry.kind, file) {
+                (PageKind::Anon { cow: true }, _) => self.break_cow(page, entry),
+                (PageKind::FileShared { dirty: false }, Some((index, backing))) => {
+                    backing.pager.set_dirty(index);
+                    self.install(page, PageEntry { kind: PageKind::FileShared { dirty: true }, ..entry });
+                    Ok(FaultKind::SharedWrite)
+                }
+                (PageKind::FilePrivate, Some((index, backing))) => {
+                    self.copy_private(page, entry.frame)?;
+                    backing.pager.put_page(index);
+                    Ok(FaultKind::CowCopy)
+                }
+                _ => Ok(FaultKind::Spurious),
+            },
+            (Some(_), _) => Ok(FaultKind::Spurious),
+        }
+    }
+
+    /// Maps a private copy of `source` at `page`.
+    fn copy_private(&mut self, page: u64, source: u64) -> Result<(), VmError> {
+        let copy = self.alloc_frame()?;
+        // SAFETY: both frames are whole pages we hold references to
+        unsafe {
+            core::ptr::copy_nonoverlapping(frame_ptr(source), frame_ptr(copy), PAGE_SIZE);
+        }
+        self.install(page, PageEntry { frame: copy, kind: PageKind::Anon { cow: false } });
+        Ok(())
+    }
+
+    fn break_cow(&mut self, page: u64, entry: PageEntry) -> Result<FaultKind, VmError> {
+        if frame_refs(entry.frame) == 1 {
+            self.install(page, PageEntry { kind: PageKind::Anon { cow: false }, ..entry });
+            return Ok(FaultKind::CowReuse);
+        }
+
+        self.copy_private(page, entry.frame)?;
+        // Another mapping still holds the old frame, so this never frees it
+        frame_put(entry.frame);
+        Ok(FaultKind::CowCopy)
+    }
+
+    /// Creates a copy of this address space for a child process.
+    ///
+    /// Lazy spaces share every anonymous page with the child and mark
+    /// writable ones copy-on-write in both. Strict spaces copy anonymous
+    /// pages now. Page-cache pages are shared either way.
+    pub fn fork(&mut self, child_root: u64) -> Result<AddressSpace, VmError> {
+        let mut child = AddressSpace::with_allocator(child_root, self.frames);
+        child.mode = self.mode;
+        child.mmap_base = self.mmap_base;
+        child.areas = self.areas.clone();
+
+        let pages: Vec<(u64, PageEntry)> = self.pages.iter().map(|(&p, &e)| (p, e)).collect();
+        let mut protected = false;
+        for (page, entry) in pages {
+            let Some(area) = self.area_at(page) else { continue };
+            let writable = area.writable;
+            let file = area.backing.clone().map(|b| (area.file_index(&b, page), b));
+
+            match (entry.kind, file, self.mode) {
+                (PageKind::Anon { cow }, _, VmMode::Lazy) => {
+                    frame_share(entry.frame);
+                    if writable && !cow {
+                        self.install(page, PageEntry { kind: PageKind::Anon { cow: true }, ..entry });
+                        protected = true;
+                    }
+                    child.install(page, PageEntry { frame: entry.frame, kind: PageKind::Anon { cow: writable } });
+                }
+                (PageKind::Anon { .. }, _, VmMode::Strict) => {
+                    // Dropping `child` on error releases what was copied so far
+                    child.copy_private(page, entry.frame)?;
+                }
+                (kind, Some((index, backing)), _) => {
+                    let frame = backing.pager.get_page(index)?;
+                    child.install(page, PageEntry { frame, kind });
+                }
+                (_, None, _) => {}
+            }
+        }
+        if protected {
+            // Other CPUs running this space may still cache the pages as
+            // writable; the parent must fault on its next write too
+            self.shootdown();
+        }
+        Ok(child)
+    }
+
+    /// Copies `data` into user memory, faulting pages in as a user write would.
+    pub fn copy_to_user(&mut self, addr: u64, data: &[u8]) -> Result<(), VmError> {
+        let mut done = 0;
+        while done < data.len() {
+            let va = addr + done as u64;
+            let offset = (va % PAGE_SIZE as u64) as usize;
+            let chunk = (PAGE_SIZE - offset).min(data.len() - done);
+            let frame = self.writable_frame(va)?;
+            // SAFETY: the frame is mapped writable in this space and the chunk
+            // stays within it
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    data[done..].as_ptr(),
+                    frame_ptr(frame).add(offset),
+                    chunk,
+                );
+            }
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    /// Copies user memory into `buf`, faulting pages in as a user read would.
+    pub fn copy_from_user(&mut self, addr: u64, buf: &mut [u8]) -> Result<(), VmError> {
+        let mut done = 0;
+        while done < buf.len() {
+            let va = addr + done as u64;
+            let page = va & !(PAGE_SIZE as u64 - 1);
+            let offset = (va - page) as usize;
+            let chunk = (PAGE_SIZE - offset).min(buf.len() - done);
+            if !self.pages.contains_key(&page) {
+                self.handle_fault(va, FaultAccess::Read)?;
+            }
+            let frame = self.pages[&page].frame;
+            // SAFETY: the frame is mapped in this space and the chunk stays
+            // within it
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    frame_ptr(frame).add(offset),
+                    buf[done..].as_mut_ptr(),
+                    chunk,
+                );
+            }
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    fn writable_frame(&mut self, addr: u64) -> Result<u64, VmError> {
+        let page = addr & !(PAGE_SIZE as u64 - 1);
+        match self.pages.get(&page) {
+            Some(&e) if self.page_writable(page, e) => Ok(e.frame),
+            _ => {
+                self.handle_fault(addr, FaultAccess::Write)?;
+                Ok(self.pages[&page].frame)
+            }
+        }
+    }
+
+    /// Returns whether a mapped page currently allows writes without a fault.
+    fn page_writable(&self, page: u64, entry: PageEntry) -> bool {
+        let area_writable = self.area_at(page).map_or(false, |a| a.writable);
+        area_writable
+            && matches!(
+                entry.kind,
+                PageKind::Anon { cow: false } | PageKind::FileShared { dirty: true }
+            )
+    }
+
+    fn alloc_frame(&self) -> Result<u64, VmError> {
+        self.frames
+            .allocate()
+            .map(|f| f.address())
+            .map_err(|_| VmError::OutOfMemory)
+    }
+
+    fn map_zeroed(&mut self, page: u64) -> Result<(), VmError> {
+        let frame = self.alloc_frame()?;
+        // SAFETY: freshly allocated frame
+        unsafe { core::ptr::write_bytes(frame_ptr(frame), 0, PAGE_SIZE) };
+        self.install(page, PageEntry { frame, kind: PageKind::Anon { cow: false } });
+        Ok(())
+    }
+
+    fn install(&mut self, page: u64, entry: PageEntry) {
+        self.pages.insert(page, entry);
+        self.sync(page);
+    }
+
+    fn release(&mut self, page: u64) {
+        let Some(entry) = self.pages.remove(&page) else { return };
+        self.sync(page);
+        match entry.kind {
+            PageKind::Anon { .. } => {
+                if frame_put(entry.frame) {
+                    self.frames.free(FrameNumber::from_address(entry.frame));
+                }
+            }
+            PageKind::FileShared { dirty } => {
+                if let Some((area, backing)) = self.backing_at(page) {
+                    let index = area.file_index(backing, page);
+                    if dirty {
+                        // Writes since the last writeback are not tracked by
+                        // hardware, so unmapping re-dirties the page
+                        backing.pager.set_dirty(index);
+                    }
+                    backing.pager.put_page(index);
+                }
+            }
+            PageKind::FilePrivate => {
+                if let Some((area, backing)) = self.backing_at(page) {
+                    backing.pager.put_page(area.file_index(backing, page));
+                }
+            }
+        }
+    }
+
+    fn backing_at(&self, page: u64) -> Option<(&VmArea, &FileBacking)> {
+        let area = self.area_at(page)?;
+        Some((area, area.backing.as_ref()?))
+    }
+
+    /// Drops this space's stale translations from other CPUs.
+    fn shootdown(&self) {
+        #[cfg(all(target_arch = "x86_64", not(test)))]
+        if self.root != 0 && !crate::smp::tlb_shootdown(self.root) {
+            crate::serial_println!("[vm] TLB shootdown timed out");
+        }
+    }
+
+    /// Mirrors the software entry for `page` into the hardware page tables.
+    fn sync(&self, page: u64) {
+        #[cfg(all(target_arch = "x86_64", not(test)))]
+        if self.root != 0 {
+            let mapping = self.pages.get(&page).map(|&e| {
+                let executable = self.area_at(page).map_or(false, |a| a.executable);
+                (e.frame, self.page_writable(page, e), executable)
+            });
+            hw::set_pte(self.root, page, mapping, self.frames);
+        }
+        #[cfg(not(all(target_arch = "x86_64", not(test))))]
+        let _ = page;
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        let pages: Vec<u64> = self.pages.keys().copied().collect();
+        for page in pages {
+            self.release(page);
+        }
+        #[cfg(all(target_arch = "x86_64", not(test)))]
+        if self.root != 0 {
+            hw::free_tables(self.root, self.frames);
+        }
+    }
+}
+
+fn check_range(start: u64, len: u64) -> Result<u64, VmError> {
+    let mask = PAGE_SIZE as u64 - 1;
+    let end = start.checked_add(len).ok_or(VmError::InvalidRange)?;
+    if len == 0 || start & mask != 0 || len & mask != 0 || end > USER_SPACE_END {
+        return Err(VmError::InvalidRange);
+    }
+    Ok(end)
+}
+
+/// x86_64 page table updates.
+#[cfg(all(target_arch = "x86_64", not(test)))]
+mod hw {
+    use super::{FrameAllocator, FrameNumber, PAGE_SIZE};
+    use crate::arch::x86_64::paging::{
+        invalidate_page, read_cr3, PageFlags, PageTable, PageTableEntry, PhysFrame, VirtPage,
+    };
+
+    const TABLE_FLAGS: PageFlags = PageFlags::PRESENT
+        .union(PageFlags::WRITABLE)
+        .union(PageFlags::USER_ACCESSIBLE);
+
+    /// Returns the table an entry points to, creating it if `create` is set.
+    ///
+    /// # Safety
+    ///
+    /// `entry` must belong to a live page table.
+    unsafe fn next_table(
+        entry: &mut PageTableEntry,
+        create: bool,
+        frames: &FrameAllocator,
+    ) -> Option<&'static mut PageTable> {
+        if !entry.is_present() {
+            if !create {
+                return None;
+            }
+            let frame = frames.allocate().ok()?.address();
+            // SAFETY: freshly allocated, identity-mapped frame
+            unsafe { core::ptr::write_bytes(frame as *mut u8, 0, PAGE_SIZE) };
+            *entry = PageTableEntry::new(PhysFrame::from_address(frame)?, TABLE_FLAGS);
+        }
+        // SAFETY: present entries point to page tables (identity-mapped)
+        Some(unsafe { &mut *(entry.frame()?.address() as *mut PageTable) })
+    }
+
+    /// Sets or clears the 4 KiB mapping for `virt`.
+    pub fn set_pte(
+        root: u64,
+        virt: u64,
+        mapping: Option<(u64, bool, bool)>,
+        frames: &FrameAllocator,
+    ) {
+        let Some(page) = VirtPage::from_address(virt) else { return };
+        let create = mapping.is_some();
+
+        // SAFETY: root is the PML4 of this address space
+        let pml4 = unsafe { &mut *(root as *mut PageTable) };
+        let Some(pdpt) = (unsafe { next_table(pml4.entry_mut(page.pml4_index()), create, frames) })
+        else {
+            return;
+        };
+        let Some(pd) = (unsafe { next_table(pdpt.entry_mut(page.pdpt_index()), create, frames) })
+        else {
+            return;
+        };
+        let Some(pt) = (unsafe { next_table(pd.entry_mut(page.pd_index()), create, frames) })
+        else {
+            return;
+        };
+
+        let pte = pt.entry_mut(page.pt_index());
+        *pte = match mapping.and_then(|(f, w, x)| Some((PhysFrame::from_address(f)?, w, x))) {
+            Some((frame, writable, executable)) => {
+                let mut flags = PageFlags::PRESENT | PageFlags::USER_ACCESSIBLE;
+                if writable {
+                    flags |= PageFlags::WRITABLE;
+                }
+                if !executable {
+                    flags |= PageFlags::NO_EXECUTE;
+                }
+                PageTableEntry::new(frame, flags)
+            }
+            None => PageTableEntry::empty(),
+        };
+
+        if read_cr3() & !0xFFF == root {
+            invalidate_page(virt);
+        }
+    }
+
+    /// Frees the user-half intermediate tables under `root`.
+    pub fn free_tables(root: u64, frames: &FrameAllocator) {
+        // SAFETY: root is the PML4 of an address space being torn down
+        let pml4 = unsafe { &mut *(root as *mut PageTable) };
+        for i in 0..256 {
+            let Some(pdpt_frame) = pml4.entry(i).frame() else { continue };
+            // SAFETY: present entries point to page tables
+            let pdpt = unsafe { &mut *(pdpt_frame.address() as *mut PageTable) };
+            for j in 0..512 {
+                let Some(pd_frame) = pdpt.entry(j).frame() else { continue };
+                // SAFETY: as above
+                let pd = unsafe { &mut *(pd_frame.address() as *mut PageTable) };
+                for k in 0..512 {
+                    if let Some(pt_frame) = pd.entry(k).frame() {
+                        frames.free(FrameNumber::from_address(pt_frame.address()));
+                    }
+                }
+                frames.free(FrameNumber::from_address(pd_frame.address()));
+            }
+            frames.free(FrameNumber::from_address(pdpt_frame.address()));
+            *pml4.entry_mut(i) = PageTableEntry::empty();
+        }
+    }
+}
+
+/// Address spaces of processes, keyed by PID.
+static ADDRESS_SPACES: Mutex<BTreeMap<ProcessId, AddressSpace>> = Mutex::new(BTreeMap::new());
+
+/// Registers a strict address space for a process.
+pub fn create(pid: ProcessId, root: u64) {
+    ADDRESS_SPACES
+        .lock()
+        .entry(pid)
+        .or_insert_with(|| AddressSpace::new(root));
+}
+
+/// Switches a process's address space to lazy mode.
+///
+/// `token` must be owned by `pid`, allow `WRITE` and name a
+/// [`LAZY_VM_RESOURCE`] resource.
+pub fn enable_lazy(
+    pid: ProcessId,
+    caps: &CapabilityTable,
+    token: CapabilityToken,
+) -> Result<(), VmError> {
+    caps.check(pid, token, Operations::WRITE)
+        .map_err(|_| VmError::PermissionDenied)?;
+    let resource = caps.get_resource(&token).map_err(|_| VmError::PermissionDenied)?;
+    if resource.resource_type != LAZY_VM_RESOURCE {
+        return Err(VmError::PermissionDenied);
+    }
+
+    let mut spaces = ADDRESS_SPACES.lock();
+    let space = spaces.get_mut(&pid).ok_or(VmError::NoAddressSpace)?;
+    space.set_lazy();
+    Ok(())
+}
+
+/// Runs `f` on a process's address space.
+pub fn with_space<R>(pid: ProcessId, f: impl FnOnce(&mut AddressSpace) -> R) -> Option<R> {
+    ADDRESS_SPACES.lock().get_mut(&pid).map(f)
+}
+
+/// Checks that `[addr, addr + len)` lies within user space.
+fn user_range(addr: u64, len: usize) -> Result<(), VmError> {
+    match addr.checked_add(len as u64) {
+        Some(end) if end <= USER_SPACE_END => Ok(()),
+        _ => Err(VmError::InvalidRange),
+    }
+}
+
+/// Copies `data` to a process's user memory.
+///
+/// System calls write results through this rather than dereferencing user
+/// pointers: the range must lie in user space and inside the process's
+/// regions with write access.
+pub fn copy_to_user(pid: ProcessId, addr: u64, data: &[u8]) -> Result<(), VmError> {
+    user_range(addr, data.len())?;
+    with_space(pid, |space| space.copy_to_user(addr, data)).ok_or(VmError::NoAddressSpace)?
+}
+
+/// Copies a process's user memory into `buf`, with the checks of
+/// [`copy_to_user`] for read access.
+pub fn copy_from_user(pid: ProcessId, addr: u64, buf: &mut [u8]) -> Result<(), VmError> {
+    user_range(addr, buf.len())?;
+    with_space(pid, |space| space.copy_from_user(addr, buf)).ok_or(VmError::NoAddressSpace)?
+}
+
+/// Resolves a user page fault for a process.
+pub fn handle_page_fault(
+    pid: ProcessId,
+    addr: u64,
+    access: FaultAccess,
+) -> Result<FaultKind, VmError> {
+    with_space(pid, |space| space.handle_fault(addr, access)).ok_or(VmError::NoAddressSpace)?
+}
+
+/// Gives `child` a copy of `parent`'s address space.
+///
+/// Does nothing if the parent has no registered address space.
+pub fn fork(parent: ProcessId, child: ProcessId, child_root: u64) -> Result<(), VmError> {
+    let mut spaces = ADDRESS_SPACES.lock();
+    let Some(space) = spaces.get_mut(&parent) else {
+        return Ok(());
+    };
+    let copy = space.fork(child_root)?;
+    spaces.insert(child, copy);
+    Ok(())
+}
+
+/// Returns the resident page count of every address space.
+pub fn resident_pages() -> Vec<(ProcessId, usize)> {
+    ADDRESS_SPACES
+        .lock()
+        .iter()
+        .map(|(&pid, space)| (pid, space.resident_pages()))
+        .collect()
+}
+
+/// Tears down a process's address space.
+pub fn destroy(pid: ProcessId) {
+    let space = ADDRESS_SPACES.lock().remove(&pid);
+    drop(space);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::alloc::{alloc_zeroed, Layout};
+    use crate::cap::ResourceId;
+
+    const PHYS_BASE: u64 = 0x100_0000;
+    const ARENA_PAGES: usize = 512;
+
+    static ARENA: spin::Once<usize> = spin::Once::new();
+    static FRAMES: FrameAllocator = FrameAllocator::new();
+
+    fn frames() -> &'static FrameAllocator {
+        ARENA.call_once(|| {
+            let layout = Layout::from_size_align(ARENA_PAGES * PAGE_SIZE, PAGE_SIZE).unwrap();
+            // SAFETY: non-zero size; the arena lives for the whole test run
+            let base = unsafe { alloc_zeroed(layout) } as usize;
+            FRAMES.add_region(PHYS_BASE, ARENA_PAGES * PAGE_SIZE);
+            base
+        });
+        &FRAMES
+    }
+
+    /// Test stand-in for the identity map: frames live in a heap arena.
+    pub(super) fn phys_ptr(frame: u64) -> *mut u8 {
+        let base = *ARENA.get().expect("test arena not initialised");
+        (base + (frame - PHYS_BASE) as usize) as *mut u8
+    }
+
+    fn lazy_space() -> AddressSpace {
+        let mut space = AddressSpace::with_allocator(0, frames());
+        space.set_lazy();
+        space
+    }
+
+    fn read_u64(space: &mut AddressSpace, addr: u64) -> u64 {
+        let mut buf = [0u8; 8];
+        space.copy_from_user(addr, &mut buf).unwrap();
+        u64::from_le_bytes(buf)
+    }
+
+    #[test]
+    fn test_strict_populates_eagerly() {
+        let mut space = AddressSpace::with_allocator(0, frames());
+        space.map_anonymous(0x40_0000, 4 * PAGE_SIZE as u64, true, false).unwrap();
+        assert_eq!(space.resident_pages(), 4);
+    }
+
+    #[test]
+    fn test_demand_zero() {
+        let mut space = lazy_space();
+        space.map_anonymous(0x40_0000, 16 * PAGE_SIZE as u64, true, false).unwrap();
+        assert_eq!(space.resident_pages(), 0);
+
+        assert_eq!(read_u64(&mut space, 0x40_2008), 0);
+        assert_eq!(space.resident_pages(), 1);
+        assert_eq!(space.stats().demand_zero, 1);
+
+        assert_eq!(
+            space.handle_fault(0x80_0000, FaultAccess::Read),
+            Err(VmError::SegmentationFault)
+        );
+        assert_eq!(space.stats().invalid, 1);
+    }
+
+    #[test]
+    fn test_cow_parent_writes_first() {
+        let mut parent = lazy_space();
+        parent.map_anonymous(0x40_0000, PAGE_SIZE as u64, true, false).unwrap();
+        parent.copy_to_user(0x40_0000, &7u64.to_le_bytes()).unwrap();
+
+        let mut child = parent.fork(0).unwrap();
+        assert_eq!(parent.translate(0x40_0000), child.translate(0x40_0000));
+
+        parent.copy_to_user(0x40_0000, &8u64.to_le_bytes()).unwrap();
+        assert_eq!(parent.stats().cow_copies, 1);
+        assert_ne!(parent.translate(0x40_0000), child.translate(0x40_0000));
+        assert_eq!(read_u64(&mut parent, 0x40_0000), 8);
+        assert_eq!(read_u64(&mut child, 0x40_0000), 7);
+
+        // The child is now the frame's only user and keeps it
+        child.copy_to_user(0x40_0000, &9u64.to_le_bytes()).unwrap();
+        assert_eq!(child.stats().cow_reuses, 1);
+        assert_eq!(read_u64(&mut parent, 0x40_0000), 8);
+    }
+
+    #[test]
+    fn test_cow_child_writes_first() {
+        let mut parent = lazy_space();
+        parent.map_anonymous(0x40_0000, 2 * PAGE_SIZE as u64, true, false).unwrap();
+        parent.copy_to_user(0x40_0000, &1u64.to_le_bytes()).unwrap();
+
+        let mut child = parent.fork(0).unwrap();
+        child.copy_to_user(0x40_0000, &2u64.to_le_bytes()).unwrap();
+        assert_eq!(child.stats().cow_copies, 1);
+        assert_eq!(read_u64(&mut parent, 0x40_0000), 1);
+        assert_eq!(read_u64(&mut child, 0x40_0000), 2);
+
+        // Untouched pages are still demand-zero in both
+        assert_eq!(read_u64(&mut child, 0x40_1000), 0);
+        assert_eq!(parent.translate(0x40_1000), None);
+
+        parent.copy_to_user(0x40_0000, &3u64.to_le_bytes()).unwrap();
+        assert_eq!(parent.stats().cow_reuses, 1);
+        assert_eq!(read_u64(&mut child, 0x40_0000), 2);
+    }
+
+    #[test]
+    fn test_strict_fork_copies() {
+        let mut parent = AddressSpace::with_allocator(0, frames());
+        parent.map_anonymous(0x40_0000, 2 * PAGE_SIZE as u64, true, false).unwrap();
+        parent.copy_to_user(0x40_0000, &5u64.to_le_bytes()).unwrap();
+
+        let mut child = parent.fork(0).unwrap();
+        assert_eq!(child.mode(), VmMode::Strict);
+        assert_eq!(child.resident_pages(), 2);
+        assert_ne!(parent.translate(0x40_0000), child.translate(0x40_0000));
+        assert_eq!(read_u64(&mut child, 0x40_0000), 5);
+    }
+
+    #[test]
+    fn test_read_only_area() {
+        let mut space = lazy_space();
+        space.map_anonymous(0x40_0000, PAGE_SIZE as u64, false, false).unwrap();
+        assert_eq!(
+            space.copy_to_user(0x40_0000, &[1]),
+            Err(VmError::ProtectionViolation)
+        );
+    }
+
+    /// Pager over a fixed set of frames that records pins and dirty pages.
+    struct FakePager {
+        frames: Vec<u64>,
+        pins: Mutex<BTreeMap<u64, u32>>,
+        dirty: Mutex<Vec<u64>>,
+    }
+
+    impl FakePager {
+        fn new(pages: usize) -> Arc<Self> {
+            let frames = (0..pages)
+                .map(|_| frames().allocate().unwrap().address())
+                .collect();
+            Arc::new(Self {
+                frames,
+                pins: Mutex::new(BTreeMap::new()),
+                dirty: Mutex::new(Vec::new()),
+            })
+        }
+
+        fn pins(&self) -> u32 {
+            self.pins.lock().values().sum()
+        }
+    }
+
+    impl Pager for FakePager {
+        fn get_page(&self, index: u64) -> Result<u64, VmError> {
+            let frame = *self.frames.get(index as usize).ok_or(VmError::PagerError)?;
+            *self.pins.lock().entry(index).or_insert(0) += 1;
+            Ok(frame)
+        }
+
+        fn put_page(&self, index: u64) {
+            *self.pins.lock().get_mut(&index).unwrap() -= 1;
+        }
+
+        fn set_dirty(&self, index: u64) {
+            let mut dirty = self.dirty.lock();
+            if !dirty.contains(&index) {
+                dirty.push(index);
+            }
+        }
+    }
+
+    fn backing(pager: &Arc<FakePager>, offset: u64, shared: bool) -> FileBacking {
+        FileBacking {
+            pager: pager.clone(),
+            offset,
+            shared,
+        }
+    }
+
+    #[test]
+    fn test_shared_file_mapping() {
+        let pager = FakePager::new(4);
+        let mut a = lazy_space();
+        let mut b = lazy_space();
+        let len = 2 * PAGE_SIZE as u64;
+        a.map_file(0x40_0000, len, true, false, backing(&pager, PAGE_SIZE as u64, true)).unwrap();
+        b.map_file(0x50_0000, len, true, false, backing(&pager, PAGE_SIZE as u64, true)).unwrap();
+
+        assert_eq!(read_u64(&mut a, 0x40_0000), 0);
+        assert_eq!(a.stats().page_cache, 1);
+        assert_eq!(a.translate(0x40_0000), Some(pager.frames[1]));
+
+        a.copy_to_user(0x40_0008, &42u64.to_le_bytes()).unwrap();
+        assert_eq!(a.stats().shared_writes, 1);
+        assert_eq!(*pager.dirty.lock(), [1]);
+        assert_eq!(read_u64(&mut b, 0x50_0008), 42);
+
+        drop(a);
+        drop(b);
+        assert_eq!(pager.pins(), 0);
+    }
+
+    #[test]
+    fn test_private_file_mapping() {
+        let pager = FakePager::new(2);
+        let mut space = lazy_space();
+        let len = 2 * PAGE_SIZE as u64;
+        space.map_file(0x40_0000, len, true, false, backing(&pager, 0, false)).unwrap();
+
+        assert_eq!(read_u64(&mut space, 0x40_0000), 0);
+        assert_eq!(space.translate(0x40_0000), Some(pager.frames[0]));
+
+        space.copy_to_user(0x40_0000, &3u64.to_le_bytes()).unwrap();
+        assert_eq!(space.stats().cow_copies, 1);
+        assert_ne!(space.translate(0x40_0000), Some(pager.frames[0]));
+        assert!(pager.dirty.lock().is_empty());
+        assert_eq!(pager.pins(), 0);
+
+        // The cached page is untouched
+        let mut other = lazy_space();
+        other.map_file(0x40_0000, len, false, false, backing(&pager, 0, false)).unwrap();
+        assert_eq!(read_u64(&mut other, 0x40_0000), 0);
+
+        // Splitting keeps file offsets: the tail still maps page 1
+        space.unmap(0x40_0000, PAGE_SIZE as u64).unwrap();
+        read_u64(&mut space, 0x40_1000);
+        assert_eq!(space.translate(0x40_1000), Some(pager.frames[1]));
+    }
+
+    #[test]
+    fn test_find_free() {
+        let mut space = lazy_space();
+        let len = 4 * PAGE_SIZE as u64;
+        assert_eq!(space.find_free(len), Some(MMAP_BASE));
+        space.map_anonymous(MMAP_BASE, len, true, false).unwrap();
+        space.map_anonymous(MMAP_BASE + 2 * len, len, true, false).unwrap();
+        assert_eq!(space.find_free(len), Some(MMAP_BASE + len));
+        assert_eq!(space.find_free(2 * len), Some(MMAP_BASE + 3 * len));
+
+        // A randomized base moves the search window
+        space.set_mmap_base(MMAP_BASE + 0x1234_5000);
+        assert_eq!(space.find_free(len), Some(MMAP_BASE + 0x1234_5000));
+    }
+
+    #[test]
+    fn test_enable_lazy_requires_capability() {
+        let table = CapabilityTable::new(16);
+        let pid = ProcessId::new(0xC0_0001);
+        create(pid, 0);
+
+        let other = table
+            .create_root(pid, ResourceId::new("memory", 0), Operations::ALL)
+            .unwrap();
+        assert_eq!(enable_lazy(pid, &table, other), Err(VmError::PermissionDenied));
+
+        let lazy = table
+            .create_root(pid, ResourceId::new(LAZY_VM_RESOURCE, 0), Operations::ALL)
+            .unwrap();
+        assert_eq!(enable_lazy(pid, &table, lazy), Ok(()));
+        assert_eq!(with_space(pid, |s| s.mode()), Some(VmMode::Lazy));
+        destroy(pid);
+    }
+
+    #[test]
+    fn test_user_copy_checks_range() {
+        let pid = ProcessId::new(0xC0_0002);
+        assert_eq!(copy_to_user(pid, 0x40_0000, &[1]), Err(VmError::NoAddressSpace));
+
+        create(pid, 0);
+        assert_eq!(
+            copy_to_user(pid, USER_SPACE_END - 4, &[0; 8]),
+            Err(VmError::InvalidRange)
+        );
+        assert_eq!(
+            copy_from_user(pid, u64::MAX - 2, &mut [0; 8]),
+            Err(VmError::InvalidRange)
+        );
+        // In range but outside every region
+        assert_eq!(
+            copy_from_user(pid, 0x40_0000, &mut [0; 8]),
+            Err(VmError::SegmentationFault)
+        );
+        destroy(pid);
+    }
+}
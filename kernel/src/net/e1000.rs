@@ -651,7 +651,9 @@ pub fn probe_e1000() -> Option<Arc<Mutex<E1000Device>>> {
                             
                             // Map the MMIO region before accessing it
                             // E1000 needs about 128KB of MMIO space
-                            let mmio_virt = crate::arch::x86_64::paging::map_mmio(mmio_base, 0x20000);
+                            let Some(mmio_virt) = crate::arch::x86_64::paging::map_mmio(mmio_base, 0x20000) else {
+                                continue;
+                            };
                             
                             if let Some(mut serial) = crate::arch::x86_64::serial::SERIAL.try_lock() {
                                 let _ = writeln!(serial, "[e1000] MMIO mapped at 0x{:08x}", mmio_virt);
@@ -398,7 +398,7 @@ pub fn connect(handle: SocketHandle, addr: SocketAddr) -> Result<(), NetworkErro
         } => {
             let local_addr = local.map(|l| l.addr).unwrap_or(Ipv4Address::new(10, 0, 2, 15));
             
-            let key = tcp_state().lock().connect(local_addr, addr.into());
+            let key = tcp_state().lock().connect(local_addr, addr.into())?;
             *connection_key = Some(key);
             *local = Some(SocketAddr::from(key.local));
             
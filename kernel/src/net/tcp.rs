@@ -6,6 +6,8 @@ use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
 use spin::Mutex;
 
+use crate::mm::slab::{SlabBox, SlabCache};
+
 use super::device::NetworkError;
 use super::ip::{checksum_bytes, Ipv4Address, Ipv4Packet, PROTOCOL_TCP};
 
@@ -484,10 +486,13 @@ impl TcpListener {
     }
 }
 
+/// Slab cache for TCP connection control blocks.
+static TCP_CONN_CACHE: SlabCache = SlabCache::for_type::<TcpConnection>("tcp_connection");
+
 /// Global TCP state.
 pub struct TcpState {
     /// Active connections.
-    connections: BTreeMap<TcpConnectionKey, TcpConnection>,
+    connections: BTreeMap<TcpConnectionKey, SlabBox<TcpConnection>>,
     /// Listening sockets.
     listeners: BTreeMap<u16, TcpListener>,
     /// Next ephemeral port.
@@ -537,7 +542,11 @@ impl TcpState {
     }
     
     /// Creates a connection.
-    pub fn connect(&mut self, local_addr: Ipv4Address, remote: TcpEndpoint) -> TcpConnectionKey {
+    pub fn connect(
+        &mut self,
+        local_addr: Ipv4Address,
+        remote: TcpEndpoint,
+    ) -> Result<TcpConnectionKey, NetworkError> {
         let local_port = self.allocate_port();
         let key = TcpConnectionKey {
             local: TcpEndpoint::new(local_addr, local_port),
@@ -546,19 +555,20 @@ impl TcpState {
         
         let mut conn = TcpConnection::new(key);
         conn.connect();
+        let conn = SlabBox::new(conn, &TCP_CONN_CACHE).ok_or(NetworkError::NoBuffer)?;
         self.connections.insert(key, conn);
         
-        key
+        Ok(key)
     }
 
     /// Gets a mutable reference to a connection.
     pub fn connection_mut(&mut self, key: TcpConnectionKey) -> Option<&mut TcpConnection> {
-        self.connections.get_mut(&key)
+        self.connections.get_mut(&key).map(|c| &mut **c)
     }
 
     /// Gets an immutable reference to a connection.
     pub fn connection(&self, key: TcpConnectionKey) -> Option<&TcpConnection> {
-        self.connections.get(&key)
+        self.connections.get(&key).map(|c| &**c)
     }
 }
 
@@ -665,7 +675,7 @@ pub fn tcp_connect(addr: SocketAddr) -> Result<SocketHandle, NetworkError> {
     let local_addr = Ipv4Address([0, 0, 0, 0]); // INADDR_ANY - kernel will select
     let remote = TcpEndpoint::new(addr.addr, addr.port);
     
-    let key = state.connect(local_addr, remote);
+    let key = state.connect(local_addr, remote)?;
     
     let handle = allocate_socket_handle();
     SOCKET_CONNECTIONS.lock().insert(handle.0, key);
@@ -42,12 +42,22 @@
 //! 0x0000_8000_0000_0000  └───────────────────┘
 //!                        │  Kernel space     │
 //! ```
+//!
+//! These are the addresses with ASLR disabled. Otherwise each exec moves
+//! the PIE base up, the program break up past the image, the mmap base
+//! (anonymous and file mappings, shared libraries) up from
+//! [`crate::mm::vm::MMAP_BASE`], and the stack top down, each by a random
+//! page count drawn from the ASLR CSPRNG. The widths come from the ASLR
+//! policy of the process calling exec (see [`crate::mm::security`]).
+//! Non-PIE executables keep their link-time addresses; only their stack,
+//! break and mmap base move.
 
 use alloc::string::String;
 use alloc::vec;
 use alloc::vec::Vec;
 
 use super::elf::{self, ElfInfo};
+use crate::mm::security::{self, RandomizedAddressSpace};
 
 /// Default stack size for new processes (1 MiB)
 pub const DEFAULT_STACK_SIZE: usize = 1024 * 1024;
@@ -61,7 +71,7 @@ pub const PIE_BASE_ADDR: u64 = 0x0000_0000_0040_0000;
 /// Allocate a fresh page table for a user process
 ///
 /// Returns the physical address of the new PML4 (page table root).
-fn allocate_user_page_table() -> Result<u64, ExecError> {
+pub(crate) fn allocate_user_page_table() -> Result<u64, ExecError> {
     use crate::mm::frame::{FRAME_ALLOCATOR, PAGE_SIZE};
     
     // Allocate a frame for the PML4 (top-level page table)
@@ -149,8 +159,12 @@ pub struct ExecContext {
     pub stack_ptr: u64,
     /// Base address where ELF was loaded
     pub base_addr: u64,
-    /// Program break (end of loaded segments + BSS)
+    /// Program break (end of loaded segments + BSS, plus the heap offset)
     pub brk: u64,
+    /// Initial stack top
+    pub stack_top: u64,
+    /// Base of the mmap region
+    pub mmap_base: u64,
     /// Arguments passed to the program
     pub argc: usize,
     /// Environment variables count
@@ -264,14 +278,23 @@ impl UserStack {
 ///   │   argc              │  Argument count
 /// low addr (SP)
 /// ```
+///
+/// `load_bias` is added to the addresses taken from the ELF headers.
 fn build_user_stack(
     stack_top: u64,
     args: &[&str],
     env: &[&str],
-    _elf_info: &ElfInfo,
+    elf_info: &ElfInfo,
+    load_bias: u64,
 ) -> (u64, Vec<u8>) {
     let mut stack = UserStack::new(stack_top);
 
+    // AT_RANDOM bytes for the C library's stack protector and pointer guard
+    let mut at_random = [0u8; 16];
+    security::aslr_random_bytes(&mut at_random);
+    stack.push_bytes(&at_random);
+    let random_addr = stack.sp();
+
     // Push environment strings and collect addresses
     let env_addrs: Vec<u64> = env.iter().map(|s| stack.push_string(s)).collect();
 
@@ -289,14 +312,19 @@ fn build_user_stack(
     const AT_PAGESZ: u64 = 6; // System page size
     const AT_BASE: u64 = 7;   // Interpreter base address
     const AT_ENTRY: u64 = 9;  // Entry point of program
+    const AT_RANDOM: u64 = 25; // Address of 16 random bytes
 
     // Push auxiliary vectors (in reverse order since stack grows down)
     // AT_NULL marks the end of auxiliary vectors
     stack.push_u64(0);         // AT_NULL value
     stack.push_u64(AT_NULL);   // AT_NULL type
 
+    // AT_RANDOM: address of the random bytes pushed above
+    stack.push_u64(random_addr);
+    stack.push_u64(AT_RANDOM);
+
     // AT_ENTRY: program entry point
-    stack.push_u64(_elf_info.entry);
+    stack.push_u64(load_bias + elf_info.entry);
     stack.push_u64(AT_ENTRY);
 
     // AT_PAGESZ: system page size (4 KiB)
@@ -304,7 +332,7 @@ fn build_user_stack(
     stack.push_u64(AT_PAGESZ);
 
     // AT_PHNUM: number of program headers
-    stack.push_u64(_elf_info.segments.len() as u64);
+    stack.push_u64(elf_info.segments.len() as u64);
     stack.push_u64(AT_PHNUM);
 
     // AT_PHENT: size of program header entry (56 bytes for ELF64)
@@ -312,14 +340,14 @@ fn build_user_stack(
     stack.push_u64(AT_PHENT);
 
     // AT_PHDR: program headers address (if available)
-    if let Some(phdr_vaddr) = _elf_info.phdr_vaddr {
-        stack.push_u64(phdr_vaddr);
+    if let Some(phdr_vaddr) = elf_info.phdr_vaddr {
+        stack.push_u64(load_bias + phdr_vaddr);
         stack.push_u64(AT_PHDR);
     }
 
     // AT_BASE: interpreter base address (for dynamic executables)
     // Only push if there's an interpreter
-    if _elf_info.interp.is_some() {
+    if elf_info.interp.is_some() {
         // The interpreter base would be set during dynamic loading
         // For now, use 0 as a placeholder (static executables don't need this)
         stack.push_u64(0);
@@ -356,6 +384,9 @@ fn build_user_stack(
 /// 3. Builds the initial stack with args/env
 /// 4. Returns the execution context
 ///
+/// The layout is randomized according to the ASLR policy of the calling
+/// process.
+///
 /// The caller is responsible for:
 /// - Creating the process
 /// - Mapping memory for segments
@@ -365,38 +396,45 @@ pub fn prepare_exec(
     elf_data: &[u8],
     args: &[&str],
     env: &[&str],
+) -> Result<(ElfInfo, ExecContext, Vec<u8>), ExecError> {
+    let layout = RandomizedAddressSpace::new(current_aslr_policy());
+    prepare_exec_with(elf_data, args, env, &layout)
+}
+
+/// Like [`prepare_exec`], but places the image in the given `layout`.
+pub fn prepare_exec_with(
+    elf_data: &[u8],
+    args: &[&str],
+    env: &[&str],
+    layout: &RandomizedAddressSpace,
 ) -> Result<(ElfInfo, ExecContext, Vec<u8>), ExecError> {
     // Parse ELF
     let elf_info = elf::parse(elf_data)?;
     elf::validate_for_exec(&elf_info)?;
 
-    // Calculate base address
+    // PIE images are linked at zero and relocated to the layout's base;
+    // everything else loads where it was linked
+    let load_bias = if elf_info.is_pie { layout.exe_base } else { 0 };
+
     let base_addr = if elf_info.is_pie {
-        PIE_BASE_ADDR
+        layout.exe_base
     } else {
         elf_info.base_addr
     };
 
     // Calculate entry point (adjust for PIE relocation)
-    let entry = if elf_info.is_pie {
-        PIE_BASE_ADDR + elf_info.entry
-    } else {
-        elf_info.entry
-    };
+    let entry = load_bias + elf_info.entry;
 
-    // Calculate break (end of loaded memory)
-    let brk = if elf_info.is_pie {
-        PIE_BASE_ADDR + elf_info.top_addr
-    } else {
-        elf_info.top_addr
-    };
+    // Calculate break (end of loaded memory, moved up by the heap offset)
+    let brk = layout.heap_start(load_bias + elf_info.top_addr);
 
     // Build user stack
     let (stack_ptr, stack_data) = build_user_stack(
-        DEFAULT_STACK_TOP,
+        layout.stack_base,
         args,
         env,
         &elf_info,
+        load_bias,
     );
 
     let ctx = ExecContext {
@@ -404,6 +442,8 @@ pub fn prepare_exec(
         stack_ptr,
         base_addr,
         brk,
+        stack_top: layout.stack_base,
+        mmap_base: layout.mmap_base,
         argc: args.len(),
         envc: env.len(),
     };
@@ -411,6 +451,14 @@ pub fn prepare_exec(
     Ok((elf_info, ctx, stack_data))
 }
 
+/// ASLR policy of the process calling exec
+fn current_aslr_policy() -> security::AslrConfig {
+    crate::process::PROCESS_MANAGER
+        .current_pid()
+        .map(security::aslr_policy)
+        .unwrap_or_default()
+}
+
 /// Calculate the memory layout for a loaded ELF
 pub fn calculate_layout(elf_info: &ElfInfo, base_offset: u64) -> MemoryLayout {
     let mut text_start = u64::MAX;
@@ -496,6 +544,17 @@ pub fn exec_from_memory(
     env: &[&str],
     name: &str,
 ) -> Result<u64, ExecError> {
+    spawn_image(elf_data, args, env, name).map(|(pid, _)| pid)
+}
+
+/// Create a process for an ELF image, returning its PID and layout
+fn spawn_image(
+    elf_data: &[u8],
+    args: &[&str],
+    env: &[&str],
+    name: &str,
+) -> Result<(u64, ExecContext), ExecError> {
+    let parent = crate::process::PROCESS_MANAGER.current_pid();
     let (elf_info, ctx, _stack_data) = prepare_exec(elf_data, args, env)?;
 
     // Check if this is a dynamically linked executable
@@ -535,6 +594,7 @@ pub fn exec_from_memory(
     let pid = crate::process::PROCESS_MANAGER.spawn_user(
         alloc::string::String::from(name),
         entry_point,
+        ctx.stack_ptr,
         page_table,
         cap_token,
     ).map_err(|_| ExecError::ProcessCreationFailed)?;
@@ -542,10 +602,18 @@ pub fn exec_from_memory(
     // Step 7: The process is automatically added to scheduler by spawn_user
     // Process context is already set up by spawn_user with entry and stack
     
+    // Step 8: Apply the randomized heap and mmap bases; the child keeps
+    // its parent's ASLR policy for its own execs
+    crate::mm::vm::with_space(pid, |space| space.set_mmap_base(ctx.mmap_base));
+    let _ = crate::process::PROCESS_MANAGER.set_brk(pid, ctx.brk);
+    if let Some(parent) = parent {
+        security::inherit_aslr_policy(parent, pid);
+    }
+    
     // Store ELF info for debugging
     let _ = &elf_info;
     
-    Ok(pid.0)
+    Ok((pid.0, ctx))
 }
 
 // =============================================================================
@@ -765,25 +833,113 @@ pub fn exec(path: &str, args: &[&str], env: &[&str]) -> Result<u64, ExecError> {
         return Err(ExecError::InvalidFormat);
     }
     
-    // Read the entire file
+    // Read the entire file (headers are parsed from memory; segments are
+    // mapped from the page cache below)
     let mut file_data: Vec<u8> = Vec::with_capacity(file_size);
     file_data.resize(file_size, 0);
     
-    let bytes_read = VFS.read(KERNEL_PID, fd, &mut file_data)
-        .map_err(|_| ExecError::InvalidFormat)?;
+    let bytes_read = match VFS.read(KERNEL_PID, fd, &mut file_data) {
+        Ok(n) if n > 0 => n,
+        _ => {
+            let _ = VFS.close(KERNEL_PID, fd);
+            return Err(ExecError::InvalidFormat);
+        }
+    };
+    
+    // Truncate to actual bytes read
+    file_data.truncate(bytes_read);
+    
+    // Create the process from the loaded data, then map its image
+    let result = spawn_image(&file_data, args, env, path).and_then(|(pid, ctx)| {
+        let elf_info = elf::parse(&file_data)?;
+        let load_bias = if elf_info.is_pie { ctx.base_addr } else { 0 };
+        match map_segments(KERNEL_PID, fd, pid, &elf_info, load_bias) {
+            Ok(()) => Ok(pid),
+            Err(e) => {
+                let _ = crate::process::PROCESS_MANAGER
+                    .terminate(crate::sched::ProcessId::new(pid), -1);
+                Err(e)
+            }
+        }
+    });
     
-    // Close the file
+    // Mappings hold their own reference to the file
     let _ = VFS.close(KERNEL_PID, fd);
+    result
+}
+
+/// Map the PT_LOAD segments of an open ELF file into a process
+///
+/// File-backed parts are mapped MAP_PRIVATE from the page cache, so
+/// processes running the same binary share clean text and rodata pages.
+/// BSS beyond the last file page is mapped anonymous; the part of the last
+/// file page past the file data is zeroed. Segments are moved by `base`,
+/// the PIE load bias chosen at exec.
+#[cfg(not(feature = "microkernel"))]
+fn map_segments(
+    owner: u64,
+    fd: crate::fs::vfs::Fd,
+    pid: u64,
+    elf_info: &ElfInfo,
+    base: u64,
+) -> Result<(), ExecError> {
+    use crate::fs::vfs::{MmapRequest, VfsError, VFS};
+    use crate::mm::vm::{self, VmError};
+    use crate::mm::PAGE_SIZE;
     
-    if bytes_read == 0 {
-        return Err(ExecError::InvalidFormat);
-    }
+    let page_mask = PAGE_SIZE as u64 - 1;
+    let vm_err = |e: VmError| match e {
+        VmError::OutOfMemory => ExecError::OutOfMemory,
+        _ => ExecError::InvalidFormat,
+    };
     
-    // Truncate to actual bytes read
-    file_data.truncate(bytes_read);
+    for seg in &elf_info.segments {
+        let vaddr = base + seg.vaddr;
+        let page_start = vaddr & !page_mask;
+        let file_end = vaddr + seg.file_size;
+        let mem_end = (vaddr + seg.mem_size + page_mask) & !page_mask;
+        let file_pages_end = (file_end + page_mask) & !page_mask;
+        
+        let mut anon_start = page_start;
+        if seg.file_size > 0 {
+            let offset = seg.file_offset.checked_sub(vaddr - page_start)
+                .filter(|o| o & page_mask == 0)
+                .ok_or(ExecError::InvalidFormat)?;
+            let req = MmapRequest {
+                addr: Some(page_start),
+                len: file_pages_end - page_start,
+                offset,
+                writable: seg.prot.write,
+                executable: seg.prot.execute,
+                shared: false,
+            };
+            VFS.mmap_into(owner, fd, pid, &req).map_err(|e| match e {
+                VfsError::NoSpace => ExecError::OutOfMemory,
+                _ => ExecError::InvalidFormat,
+            })?;
+            anon_start = file_pages_end;
+            
+            // The rest of the last file page belongs to BSS
+            if seg.mem_size > seg.file_size && file_end < file_pages_end && seg.prot.write {
+                let zeros = vec![0u8; (file_pages_end - file_end) as usize];
+                vm::with_space(crate::sched::ProcessId::new(pid), |space| {
+                    space.copy_to_user(file_end, &zeros)
+                })
+                .ok_or(ExecError::ProcessCreationFailed)?
+                .map_err(vm_err)?;
+            }
+        }
+        
+        if mem_end > anon_start {
+            vm::with_space(crate::sched::ProcessId::new(pid), |space| {
+                space.map_anonymous(anon_start, mem_end - anon_start, seg.prot.write, seg.prot.execute)
+            })
+            .ok_or(ExecError::ProcessCreationFailed)?
+            .map_err(vm_err)?;
+        }
+    }
     
-    // Execute from the loaded data
-    exec_from_memory(&file_data, args, env, path)
+    Ok(())
 }
 
 /// Information for a simple in-memory test binary
@@ -933,3 +1089,74 @@ fn read_file_from_vfs(path: &str) -> Result<alloc::vec::Vec<u8>, ExecError> {
         Err(_) => Err(ExecError::FileNotFound),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mm::security::{AslrConfig, AslrState};
+    use crate::mm::vm::MMAP_BASE;
+
+    /// The test binary relinked at zero as a position-independent executable
+    fn create_test_pie() -> Vec<u8> {
+        let mut elf = create_test_elf();
+        elf[16..18].copy_from_slice(&elf::ET_DYN.to_le_bytes());
+        elf[24..32].copy_from_slice(&(64u64 + 56).to_le_bytes());
+        // p_vaddr and p_paddr
+        elf[64 + 16..64 + 32].copy_from_slice(&[0u8; 16]);
+        elf
+    }
+
+    #[test]
+    fn test_pie_layout_differs_across_execs() {
+        let pie = create_test_pie();
+        let config = AslrConfig::default_config();
+        let mut aslr = AslrState::with_seed(0x5EED);
+
+        let mut layouts = Vec::new();
+        for _ in 0..4 {
+            let layout = RandomizedAddressSpace::with_state(config, &mut aslr);
+            let (info, ctx, _) = prepare_exec_with(&pie, &["pie"], &[], &layout).unwrap();
+            assert!(info.is_pie);
+            assert_eq!(ctx.base_addr, layout.exe_base);
+            assert_eq!(ctx.entry, layout.exe_base + 64 + 56);
+            assert!(ctx.brk >= ctx.base_addr + info.top_addr);
+            assert!(ctx.stack_ptr < ctx.stack_top);
+            layouts.push((ctx.base_addr, ctx.stack_top, ctx.brk, ctx.mmap_base));
+        }
+
+        for (i, a) in layouts.iter().enumerate() {
+            for b in &layouts[i + 1..] {
+                assert_ne!(a.0, b.0, "PIE base repeated");
+                assert_ne!(a.1, b.1, "stack top repeated");
+                assert_ne!(a.2, b.2, "program break repeated");
+                assert_ne!(a.3, b.3, "mmap base repeated");
+            }
+        }
+    }
+
+    #[test]
+    fn test_static_binary_keeps_link_address() {
+        let elf = create_test_elf();
+        let mut aslr = AslrState::with_seed(0x5EED);
+        let layout = RandomizedAddressSpace::with_state(AslrConfig::default_config(), &mut aslr);
+
+        let (info, ctx, _) = prepare_exec_with(&elf, &["static"], &[], &layout).unwrap();
+        assert!(!info.is_pie);
+        assert_eq!(ctx.base_addr, 0x40_0000);
+        assert_eq!(ctx.entry, 0x40_0000 + 64 + 56);
+        assert_eq!(ctx.stack_top, layout.stack_base);
+        assert_eq!(ctx.mmap_base, layout.mmap_base);
+    }
+
+    #[test]
+    fn test_disabled_aslr_uses_fixed_layout() {
+        let pie = create_test_pie();
+        let layout = RandomizedAddressSpace::new(AslrConfig::disabled());
+
+        let (_, ctx, _) = prepare_exec_with(&pie, &["pie"], &[], &layout).unwrap();
+        assert_eq!(ctx.base_addr, PIE_BASE_ADDR);
+        assert_eq!(ctx.stack_top, DEFAULT_STACK_TOP);
+        assert_eq!(ctx.mmap_base, MMAP_BASE);
+        assert_eq!(ctx.brk, PIE_BASE_ADDR + 0x1000);
+    }
+}
@@ -252,15 +252,19 @@ impl ProcessManager {
         let process = Process::new_kernel(pid, name, parent, entry, kernel_stack, cap_token);
         
         self.processes.lock().insert(pid, process);
+        #[cfg(not(feature = "microkernel"))]
+        crate::fs::vfs::inherit_namespace(parent, pid);
         
         Ok(pid)
     }
 
-    /// Spawns a new user process.
+    /// Spawns a new user process starting at `entry` with stack pointer
+    /// `user_stack`.
     pub fn spawn_user(
         &self,
         name: String,
         entry: u64,
+        user_stack: u64,
         page_table: u64,
         cap_token: CapabilityToken,
     ) -> Result<ProcessId, ProcessError> {
@@ -273,17 +277,158 @@ impl ProcessManager {
             .allocate_contiguous(stack_frames)
             .map_err(|_| ProcessError::OutOfMemory)?
             .address() + KERNEL_STACK_SIZE as u64;
-        let user_stack = USER_STACK_TOP;
         
         let process = Process::new_user(
             pid, name, parent, entry, page_table, kernel_stack, user_stack, cap_token
         );
         
         self.processes.lock().insert(pid, process);
+        crate::mm::vm::create(pid, page_table);
+        #[cfg(not(feature = "microkernel"))]
+        crate::fs::vfs::inherit_namespace(parent, pid);
         
         Ok(pid)
     }
 
+    /// Forks a process.
+    ///
+    /// The child gets a copy of the parent's address space: copy-on-write if
+    /// the parent opted into lazy VM, an eager copy otherwise. It resumes from
+    /// the parent's last saved context on a copy of the parent's kernel stack.
+    pub fn fork(&self, parent_pid: ProcessId) -> Result<ProcessId, ProcessError> {
+        let parent = self.get(parent_pid).ok_or(ProcessError::NotFound)?;
+        if parent.state == ProcessState::Terminated {
+            return Err(ProcessError::InvalidState);
+        }
+        let pid = self.alloc_pid();
+
+        let page_table = if parent.page_table == 0 {
+            0
+        } else {
+            exec::allocate_user_page_table().map_err(|_| ProcessError::OutOfMemory)?
+        };
+
+        let stack_frames = KERNEL_STACK_SIZE / crate::mm::PAGE_SIZE;
+        let kernel_stack_base = match crate::mm::FRAME_ALLOCATOR.allocate_contiguous(stack_frames) {
+            Ok(frame) => frame.address(),
+            Err(_) => {
+                if page_table != 0 {
+                    crate::mm::FRAME_ALLOCATOR.free(crate::mm::FrameNumber::from_address(page_table));
+                }
+                return Err(ProcessError::OutOfMemory);
+            }
+        };
+        let kernel_stack = kernel_stack_base + KERNEL_STACK_SIZE as u64;
+
+        if let Err(e) = crate::mm::vm::fork(parent_pid, pid, page_table) {
+            crate::mm::FRAME_ALLOCATOR.free_contiguous(
+                crate::mm::FrameNumber::from_address(kernel_stack_base),
+                stack_frames,
+            );
+            if page_table != 0 {
+                crate::mm::FRAME_ALLOCATOR.free(crate::mm::FrameNumber::from_address(page_table));
+            }
+            return Err(match e {
+                crate::mm::vm::VmError::OutOfMemory => ProcessError::OutOfMemory,
+                _ => ProcessError::InvalidState,
+            });
+        }
+
+        // Copy the kernel stack and rebase the saved stack pointers onto it
+        let parent_base = parent.kernel_stack.saturating_sub(KERNEL_STACK_SIZE as u64);
+        if parent_base != 0 {
+            // SAFETY: both stacks are KERNEL_STACK_SIZE bytes of identity-mapped memory
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    parent_base as *const u8,
+                    kernel_stack_base as *mut u8,
+                    KERNEL_STACK_SIZE,
+                );
+            }
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        let context = {
+            let mut context = parent.context.clone();
+            let rebase = |addr: u64| {
+                if addr >= parent_base && addr <= parent.kernel_stack {
+                    addr - parent_base + kernel_stack_base
+                } else {
+                    addr
+                }
+            };
+            context.rsp = rebase(context.rsp);
+            context.rbp = rebase(context.rbp);
+            if page_table != 0 {
+                context.cr3 = page_table;
+            }
+            context
+        };
+
+        let child = Process {
+            pid,
+            name: parent.name.clone(),
+            parent: parent_pid,
+            state: ProcessState::Ready,
+            class: parent.class,
+            priority: parent.priority,
+            #[cfg(target_arch = "x86_64")]
+            context,
+            page_table,
+            kernel_stack,
+            kernel_stack_size: KERNEL_STACK_SIZE,
+            user_stack: parent.user_stack,
+            cap_token: parent.cap_token.clone(),
+            exit_code: None,
+            children: Vec::new(),
+            cpu_time: 0,
+            created_at: 0,
+            brk: parent.brk,
+            cwd: parent.cwd.clone(),
+        };
+
+        let mut processes = self.processes.lock();
+        processes.insert(pid, child);
+        if let Some(p) = processes.get_mut(&parent_pid) {
+            p.children.push(pid);
+        }
+        drop(processes);
+        crate::mm::security::inherit_aslr_policy(parent_pid, pid);
+        #[cfg(not(feature = "microkernel"))]
+        crate::fs::vfs::inherit_namespace(parent_pid, pid);
+
+        Ok(pid)
+    }
+
+    /// Forks the caller of a system call.
+    ///
+    /// The child starts on a copy of the caller's trap frame at the top of
+    /// its kernel stack, with 0 as the system call's return value, and
+    /// leaves through the system call exit path into user mode.
+    #[cfg(target_arch = "x86_64")]
+    pub fn fork_from_syscall(
+        &self,
+        parent_pid: ProcessId,
+        frame: &crate::arch::x86_64::syscall::SyscallFrame,
+    ) -> Result<ProcessId, ProcessError> {
+        use crate::arch::x86_64::syscall::{syscall_return, SyscallFrame};
+
+        let pid = self.fork(parent_pid)?;
+        let mut processes = self.processes.lock();
+        let child = processes.get_mut(&pid).ok_or(ProcessError::NotFound)?;
+
+        let child_frame = SyscallFrame { rax: 0, ..frame.clone() };
+        let frame_addr = child.kernel_stack - core::mem::size_of::<SyscallFrame>() as u64;
+        // SAFETY: the top of the child's kernel stack is ours and not in use
+        unsafe { (frame_addr as *mut SyscallFrame).write(child_frame) };
+
+        child.context.rip = syscall_return as *const () as u64;
+        child.context.rsp = frame_addr;
+        // Interrupts stay off until iretq loads the user flags
+        child.context.rflags = 0x2;
+        Ok(pid)
+    }
+
     /// Spawns a process from an ELF binary.
     pub fn spawn_elf(
         &self,
@@ -392,6 +537,13 @@ impl ProcessManager {
         
         // Notify parent via wait subsystem
         drop(processes); // Release lock before calling wait manager
+        crate::mm::vm::destroy(pid);
+        crate::mm::pressure::release(pid);
+        crate::mm::security::clear_aslr_policy(pid);
+        #[cfg(not(feature = "microkernel"))]
+        crate::fs::vfs::close_all_fds(pid);
+        #[cfg(not(feature = "microkernel"))]
+        crate::fs::vfs::leave_namespace(pid);
         crate::process::wait::WAIT_MANAGER.do_exit(
             pid, 
             parent, 
@@ -411,6 +563,8 @@ impl ProcessManager {
         // Get target process
         let target = processes.get_mut(&target_pid).ok_or(ProcessError::NotFound)?;
         let target_context = &target.context as *const Context;
+        // Traps from the target's user mode land on its own kernel stack
+        crate::arch::x86_64::set_kernel_stack(target.kernel_stack);
         
         // Save current process context and switch
         if let Some(current_pid) = *current_lock {
@@ -472,10 +472,11 @@ pub fn exit(pid: ProcessId, exit_code: i32) -> Result<(), ExitError> {
     // Clean up signal state
     crate::process::signal::SIGNAL_MANAGER.cleanup_process(pid);
 
-    // Close file descriptors (handled by VFS if implemented)
-    // For now, just log that we would close FDs
-    #[cfg(feature = "vfs")]
+    // Close file descriptors, releasing file locks
+    #[cfg(not(feature = "microkernel"))]
     crate::fs::vfs::close_all_fds(pid);
+    #[cfg(not(feature = "microkernel"))]
+    crate::fs::vfs::leave_namespace(pid);
 
     // Free process memory via scheduler termination
     // The scheduler's terminate() handles memory cleanup
@@ -497,6 +498,10 @@ pub fn exit_signal(pid: ProcessId, signal: u8, core_dump: bool) -> Result<(), Ex
     WAIT_MANAGER.do_signal_exit(pid, parent, signal, core_dump, rusage);
     WAIT_MANAGER.reparent_children(pid, ProcessId::new(1));
     crate::process::signal::SIGNAL_MANAGER.cleanup_process(pid);
+    #[cfg(not(feature = "microkernel"))]
+    crate::fs::vfs::close_all_fds(pid);
+    #[cfg(not(feature = "microkernel"))]
+    crate::fs::vfs::leave_namespace(pid);
 
     Ok(())
 }
@@ -213,6 +213,22 @@ impl Scheduler {
         Ok(())
     }
 
+    /// Marks a process that blocked and is running again as running.
+    ///
+    /// For a waiter that finds its condition met when it next runs; it no
+    /// longer needs the ready-queue entry a wakeup gave it.
+    pub fn resume(&self, pid: ProcessId) -> Result<(), SchedulerError> {
+        let mut processes = self.processes.lock();
+        let process = processes.get_mut(&pid).ok_or(SchedulerError::ProcessNotFound)?;
+
+        process.state = ProcessState::Running;
+        let mut queues = self.ready_queues.lock();
+        queues.realtime.retain(|&p| p != pid);
+        queues.interactive.retain(|&p| p != pid);
+        queues.background.retain(|&p| p != pid);
+        Ok(())
+    }
+
     /// Terminates a process.
     pub fn terminate(&self, pid: ProcessId) -> Result<(), SchedulerError> {
         let mut processes = self.processes.lock();
@@ -329,6 +345,15 @@ impl Scheduler {
         }
     }
 
+    /// Gives up the CPU to the next ready process, or waits for an
+    /// interrupt if there is none.
+    pub fn yield_now(&self) {
+        match self.schedule() {
+            Some(next) => self.switch_to(next),
+            None => crate::arch::halt(),
+        }
+    }
+
     /// Enqueues a process in the appropriate ready queue.
     fn enqueue(&self, pid: ProcessId, class: SchedulingClass) {
         let mut queues = self.ready_queues.lock();
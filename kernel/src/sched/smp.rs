@@ -8,8 +8,9 @@
 //! - CPU affinity for processes
 //! - Load balancing across cores
 //! - Work stealing for idle CPUs
+//! - Migration off CPUs taken down by hotplug
 
-use alloc::collections::VecDeque;
+use alloc::collections::{BTreeMap, VecDeque};
 use alloc::vec::Vec;
 use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 
@@ -84,6 +85,27 @@ impl CpuMask {
     pub fn count(&self) -> u32 {
         self.bits.iter().map(|w| w.count_ones()).sum()
     }
+
+    /// Returns true if no CPU is in the mask.
+    pub fn is_empty(&self) -> bool {
+        self.bits.iter().all(|&w| w == 0)
+    }
+
+    /// Returns the CPUs present in both masks.
+    pub fn intersection(&self, other: &CpuMask) -> CpuMask {
+        let mut bits = [0u64; 4];
+        for (i, word) in bits.iter_mut().enumerate() {
+            *word = self.bits[i] & other.bits[i];
+        }
+        Self { bits }
+    }
+
+    /// Iterates over the CPUs in the mask in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = CpuId> + '_ {
+        (0..256u32)
+            .map(CpuId::new)
+            .filter(move |&cpu| self.contains(cpu))
+    }
 }
 
 impl Default for CpuMask {
@@ -181,6 +203,32 @@ impl CpuRunQueue {
         None
     }
 
+    /// Removes every queued process, highest class first.
+    ///
+    /// Used when the owning CPU goes offline and its work must move.
+    pub fn drain(&self) -> Vec<(ProcessId, SchedulingClass)> {
+        let mut drained = Vec::new();
+        drained.extend(self.realtime.lock().drain(..).map(|p| (p, SchedulingClass::Realtime)));
+        drained.extend(self.interactive.lock().drain(..).map(|p| (p, SchedulingClass::Interactive)));
+        drained.extend(self.background.lock().drain(..).map(|p| (p, SchedulingClass::Background)));
+        self.nr_running.store(0, Ordering::Relaxed);
+        self.load.store(0, Ordering::Relaxed);
+        drained
+    }
+
+    /// Removes a queued process. Returns false if it is not queued here.
+    pub fn remove(&self, pid: ProcessId) -> bool {
+        for queue in [&self.realtime, &self.interactive, &self.background] {
+            let mut queue = queue.lock();
+            if let Some(pos) = queue.iter().position(|&p| p == pid) {
+                queue.remove(pos);
+                self.nr_running.fetch_sub(1, Ordering::Relaxed);
+                return true;
+            }
+        }
+        false
+    }
+
     /// Returns true if the queue is empty.
     pub fn is_empty(&self) -> bool {
         self.nr_running.load(Ordering::Relaxed) == 0
@@ -192,12 +240,27 @@ impl CpuRunQueue {
     }
 }
 
+/// A process moved off a CPU going offline.
+#[derive(Debug, Clone, Copy)]
+pub struct Migration {
+    /// Process moved
+    pub pid: ProcessId,
+    /// Its scheduling class
+    pub class: SchedulingClass,
+    /// CPU whose queue it went to
+    pub to: CpuId,
+}
+
 /// Global SMP scheduler state.
 pub struct SmpScheduler {
     /// Per-CPU run queues.
     run_queues: [CpuRunQueue; MAX_CPUS],
     /// Number of active CPUs.
     nr_cpus: AtomicU32,
+    /// CPUs that may receive work.
+    online: Mutex<CpuMask>,
+    /// Explicit per-process affinity (absent means all CPUs).
+    affinities: Mutex<BTreeMap<ProcessId, CpuMask>>,
     /// Load balancing tick counter.
     balance_tick: AtomicU64,
 }
@@ -209,6 +272,9 @@ impl SmpScheduler {
         Self {
             run_queues: [INIT_QUEUE; MAX_CPUS],
             nr_cpus: AtomicU32::new(1),
+            // Only the BSP is online until APs report in
+            online: Mutex::new(CpuMask { bits: [1, 0, 0, 0] }),
+            affinities: Mutex::new(BTreeMap::new()),
             balance_tick: AtomicU64::new(0),
         }
     }
@@ -233,11 +299,11 @@ impl SmpScheduler {
 
     /// Selects the best CPU for a task.
     fn select_cpu(&self, affinity: CpuMask, last_cpu: Option<CpuId>) -> CpuId {
-        let nr_cpus = self.nr_cpus.load(Ordering::Relaxed) as usize;
+        let allowed = affinity.intersection(&self.online.lock());
 
         // Prefer last CPU for cache locality
         if let Some(last) = last_cpu {
-            if affinity.contains(last) {
+            if allowed.contains(last) {
                 return last;
             }
         }
@@ -246,30 +312,63 @@ impl SmpScheduler {
         let mut best_cpu = CpuId::BSP;
         let mut best_load = u64::MAX;
 
-        for i in 0..nr_cpus {
-            let cpu = CpuId::new(i as u32);
-            if affinity.contains(cpu) {
-                let load = self.run_queues[i].get_load();
-                if load < best_load {
-                    best_load = load;
-                    best_cpu = cpu;
-                }
+        for cpu in allowed.iter() {
+            let load = self.run_queues[cpu.as_index()].get_load();
+            if load < best_load {
+                best_load = load;
+                best_cpu = cpu;
             }
         }
 
         best_cpu
     }
 
+    /// Sets the affinity mask for a process.
+    pub fn set_affinity(&self, pid: ProcessId, mask: CpuMask) {
+        if mask == CpuMask::all() {
+            self.affinities.lock().remove(&pid);
+        } else {
+            self.affinities.lock().insert(pid, mask);
+        }
+    }
+
+    /// Returns the affinity mask for a process.
+    pub fn affinity(&self, pid: ProcessId) -> CpuMask {
+        self.affinities.lock().get(&pid).copied().unwrap_or_default()
+    }
+
+    /// Forgets the affinity of a terminated process.
+    pub fn clear_affinity(&self, pid: ProcessId) {
+        self.affinities.lock().remove(&pid);
+    }
+
+    /// Returns the set of CPUs currently accepting work.
+    pub fn online_mask(&self) -> CpuMask {
+        *self.online.lock()
+    }
+
+    /// Returns a process whose affinity would leave it no CPU if `cpu` went
+    /// offline, if there is one.
+    pub fn stranded_by(&self, cpu: CpuId) -> Option<ProcessId> {
+        let mut remaining = *self.online.lock();
+        remaining.clear(cpu);
+        self.affinities
+            .lock()
+            .iter()
+            .find(|(_, mask)| mask.contains(cpu) && mask.intersection(&remaining).is_empty())
+            .map(|(&pid, _)| pid)
+    }
+
     /// Attempts work stealing for an idle CPU.
     pub fn try_steal(&self, idle_cpu: CpuId) -> Option<(ProcessId, SchedulingClass)> {
-        let nr_cpus = self.nr_cpus.load(Ordering::Relaxed) as usize;
+        let online = self.online_mask();
         let idle_idx = idle_cpu.as_index();
 
         // Find the most loaded CPU and steal from it
         let mut busiest_idx = 0;
         let mut busiest_load = 0u64;
 
-        for i in 0..nr_cpus {
+        for i in online.iter().map(|cpu| cpu.as_index()) {
             if i != idle_idx {
                 let load = self.run_queues[i].get_load();
                 if load > busiest_load {
@@ -301,14 +400,17 @@ impl SmpScheduler {
             return;
         }
 
+        let online = self.online_mask();
+
         // Calculate average load
-        let total_load: u64 = (0..nr_cpus)
-            .map(|i| self.run_queues[i].get_load())
+        let total_load: u64 = online
+            .iter()
+            .map(|cpu| self.run_queues[cpu.as_index()].get_load())
             .sum();
         let avg_load = total_load / nr_cpus as u64;
 
         // Find imbalanced CPUs
-        for i in 0..nr_cpus {
+        for i in online.iter().map(|cpu| cpu.as_index()) {
             let load = self.run_queues[i].get_load();
             if load < avg_load / 2 {
                 // This CPU is underloaded, try to steal work
@@ -324,21 +426,63 @@ impl SmpScheduler {
     }
 
     /// Called when a CPU goes online.
-    pub fn cpu_online(&self, _cpu: CpuId) {
-        self.nr_cpus.fetch_add(1, Ordering::SeqCst);
+    pub fn cpu_online(&self, cpu: CpuId) {
+        let mut online = self.online.lock();
+        if !online.contains(cpu) {
+            online.set(cpu);
+            self.nr_cpus.fetch_add(1, Ordering::SeqCst);
+        }
     }
 
     /// Called when a CPU goes offline.
-    pub fn cpu_offline(&self, cpu: CpuId) {
-        self.nr_cpus.fetch_sub(1, Ordering::SeqCst);
+    ///
+    /// The CPU stops receiving work and everything queued on it is moved to
+    /// the least loaded CPU its affinity still allows. Returns the processes
+    /// migrated, for [`cancel_offline`](Self::cancel_offline).
+    pub fn cpu_offline(&self, cpu: CpuId) -> Vec<Migration> {
+        {
+            let mut online = self.online.lock();
+            if !online.contains(cpu) {
+                return Vec::new();
+            }
+            online.clear(cpu);
+            self.nr_cpus.fetch_sub(1, Ordering::SeqCst);
+        }
 
         // Migrate all tasks from the offline CPU
-        let rq = &self.run_queues[cpu.as_index()];
-        while let Some((pid, class)) = rq.steal() {
-            // Re-enqueue on BSP
-            self.run_queues[0].enqueue(pid, class, 128);
+        self.run_queues[cpu.as_index()]
+            .drain()
+            .into_iter()
+            .map(|(pid, class)| Migration {
+                pid,
+                class,
+                to: self.migrate(pid, class),
+            })
+            .collect()
+    }
+
+    /// Undoes [`cpu_offline`](Self::cpu_offline) when the CPU failed to stop.
+    ///
+    /// The CPU accepts work again and migrated processes that have not run
+    /// yet go back to its queue.
+    pub fn cancel_offline(&self, cpu: CpuId, migrated: &[Migration]) {
+        self.cpu_online(cpu);
+        let queue = &self.run_queues[cpu.as_index()];
+        for m in migrated {
+            if self.run_queues[m.to.as_index()].remove(m.pid) {
+                queue.enqueue(m.pid, m.class, 128);
+            }
         }
     }
+
+    /// Re-enqueues a process on a CPU permitted by its affinity. Returns the
+    /// CPU chosen.
+    pub fn migrate(&self, pid: ProcessId, class: SchedulingClass) -> CpuId {
+        let target = self.select_cpu(self.affinity(pid), None);
+        self.run_queues[target.as_index()].enqueue(pid, class, 128);
+        crate::smp::send_ipi(target, IpiType::Reschedule);
+        target
+    }
 }
 
 /// Global SMP scheduler instance.
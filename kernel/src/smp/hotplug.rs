@@ -0,0 +1,389 @@
+//! # CPU Hotplug
+//!
+//! Takes application processors offline and brings them back at runtime.
+//!
+//! ## Offline
+//!
+//! 1. Refuse if the CPU is the BSP, is not online, or is the last online CPU
+//! 2. Refuse if a process's affinity would leave it no online CPU
+//! 3. Remove the CPU from the scheduler's online mask and migrate its run queue
+//! 4. Re-route IRQs pinned to the CPU to the least loaded online CPUs,
+//!    reprogramming the interrupt controller
+//! 5. Send the CPU a reschedule IPI and wait until it runs no process
+//! 6. Ask the CPU (via function-call IPI) to park
+//!
+//! The CPU parks on whatever stack the IPI interrupted, which step 5 makes
+//! its own idle stack rather than a process's kernel stack that another CPU
+//! could resume. It parks after it has acknowledged the IPI, with interrupts
+//! enabled, so the reschedule IPI from [`online`] can reach it. If it does not
+//! go idle or park in time, steps 3 and 4 are undone: the CPU rejoins the
+//! online mask, its migrated processes that have not run yet return to it,
+//! and its IRQs are routed back.
+//!
+//! ## Online
+//!
+//! A parked CPU is released from its park loop. A CPU that was never started
+//! goes through the normal INIT-SIPI-SIPI sequence. Either way it rejoins the
+//! scheduler's online mask, so processes whose affinity names it can run there
+//! again.
+//!
+//! Both operations are exposed through `/sys/devices/system/cpu/cpuN/online`.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use spin::Mutex;
+
+use super::{percpu, smp_state, CpuId, CpuState, IpiType, MAX_CPUS};
+use crate::sched::smp::Migration;
+use crate::sched::{smp_scheduler, CpuMask, ProcessId};
+
+/// Hotplug errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HotplugError {
+    /// CPU is not present in the system
+    NoSuchCpu,
+    /// The bootstrap processor cannot be taken offline
+    BspNotRemovable,
+    /// Operation would leave no CPU online
+    LastCpu,
+    /// CPU is already in the requested state
+    AlreadyInState,
+    /// A process's affinity allows no other online CPU
+    AffinityConflict(ProcessId),
+    /// CPU did not respond within the timeout
+    Timeout,
+}
+
+/// Set by [`offline`] until the CPU takes the request.
+static PARK_REQUESTED: [AtomicBool; MAX_CPUS] = {
+    const INIT: AtomicBool = AtomicBool::new(false);
+    [INIT; MAX_CPUS]
+};
+
+/// Set by a CPU that took its park request, until it parks.
+static PARK_PENDING: [AtomicBool; MAX_CPUS] = {
+    const INIT: AtomicBool = AtomicBool::new(false);
+    [INIT; MAX_CPUS]
+};
+
+/// Set when a parked CPU should leave its park loop.
+static UNPARK: [AtomicBool; MAX_CPUS] = {
+    const INIT: AtomicBool = AtomicBool::new(false);
+    [INIT; MAX_CPUS]
+};
+
+/// Serialises hotplug operations.
+static HOTPLUG_LOCK: Mutex<()> = Mutex::new(());
+
+/// IRQs pinned to a specific CPU (GSI -> target CPU).
+static IRQ_AFFINITY: Mutex<BTreeMap<u32, CpuId>> = Mutex::new(BTreeMap::new());
+
+/// Spin iterations to wait for a CPU to change state.
+const STATE_TIMEOUT_SPINS: usize = 10_000_000;
+
+/// Pins an IRQ to a CPU and routes it there.
+pub fn set_irq_affinity(gsi: u32, cpu: CpuId) {
+    IRQ_AFFINITY.lock().insert(gsi, cpu);
+    route_irq(gsi, cpu);
+}
+
+/// Points the interrupt controller's entry for `gsi` at `cpu`.
+fn route_irq(gsi: u32, cpu: CpuId) {
+    #[cfg(target_arch = "x86_64")]
+    {
+        let Some(apic_id) = smp_state().hw_id(cpu) else {
+            return;
+        };
+        // GSIs no I/O APIC handles arrive through the legacy PIC on the BSP
+        let _ = crate::arch::x86_64::ioapic::set_destination(gsi, apic_id as u8);
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        // aarch64 would write GICD_ITARGETSR / GICD_IROUTER
+        let _ = (gsi, cpu);
+    }
+}
+
+/// Picks the online CPU to take over a pinned IRQ: the one with the fewest
+/// IRQs pinned to it, then the lightest run queue.
+fn irq_fallback(
+    routes: &BTreeMap<u32, CpuId>,
+    online: CpuMask,
+    load: impl Fn(CpuId) -> u64,
+) -> Option<CpuId> {
+    online.iter().min_by_key(|&cpu| {
+        let pinned = routes.values().filter(|&&target| target == cpu).count();
+        (pinned, load(cpu))
+    })
+}
+
+/// Returns the CPU an IRQ is pinned to, or the BSP if it is not pinned.
+pub fn irq_target(gsi: u32) -> CpuId {
+    IRQ_AFFINITY.lock().get(&gsi).copied().unwrap_or(CpuId::BSP)
+}
+
+/// Returns true if the CPU is online.
+pub fn is_online(cpu: CpuId) -> bool {
+    smp_state().cpu_state(cpu) == Some(CpuState::Online)
+}
+
+/// Takes an application processor offline.
+pub fn offline(cpu: CpuId) -> Result<(), HotplugError> {
+    let _guard = HOTPLUG_LOCK.lock();
+    let smp = smp_state();
+
+    match smp.cpu_state(cpu) {
+        None => return Err(HotplugError::NoSuchCpu),
+        Some(CpuState::Online) => {}
+        Some(_) => return Err(HotplugError::AlreadyInState),
+    }
+    if smp.is_bsp(cpu) {
+        return Err(HotplugError::BspNotRemovable);
+    }
+    if smp.num_online() <= 1 {
+        return Err(HotplugError::LastCpu);
+    }
+
+    let sched = smp_scheduler();
+    if let Some(pid) = sched.stranded_by(cpu) {
+        return Err(HotplugError::AffinityConflict(pid));
+    }
+
+    // Stop new work landing on the CPU and move what is already queued
+    let migrated = sched.cpu_offline(cpu);
+
+    // Move pinned IRQs to the least loaded CPUs still online
+    let moved_irqs: Vec<u32> = {
+        let online = sched.online_mask();
+        let mut routes = IRQ_AFFINITY.lock();
+        let pinned: Vec<u32> = routes
+            .iter()
+            .filter(|(_, &target)| target == cpu)
+            .map(|(&gsi, _)| gsi)
+            .collect();
+        for &gsi in &pinned {
+            let target = irq_fallback(&routes, online, |c| sched.get_run_queue(c).get_load())
+                .unwrap_or(CpuId::BSP);
+            routes.insert(gsi, target);
+            route_irq(gsi, target);
+        }
+        pinned
+    };
+
+    // With its queue gone and the CPU out of the online mask, its next
+    // reschedule leaves it idle
+    super::send_ipi(cpu, IpiType::Reschedule);
+    if !wait_for_idle(cpu) {
+        rollback(cpu, &migrated, &moved_irqs);
+        return Err(HotplugError::Timeout);
+    }
+
+    // Let the idle CPU park itself
+    let idx = cpu.as_index();
+    UNPARK[idx].store(false, Ordering::Release);
+    PARK_REQUESTED[idx].store(true, Ordering::Release);
+    let mut parked = super::call_on_cpu(cpu, take_park_request, 0) && wait_for_state(cpu, CpuState::Parked);
+    if !parked && !PARK_REQUESTED[idx].swap(false, Ordering::AcqRel) {
+        // It took the request as we gave up and is on its way to parking
+        parked = wait_for_state(cpu, CpuState::Parked);
+    }
+    if !parked {
+        // Put everything back so the scheduler doesn't lose a CPU that is
+        // still running
+        rollback(cpu, &migrated, &moved_irqs);
+        return Err(HotplugError::Timeout);
+    }
+
+    crate::serial_println!(
+        "[SMP] CPU {} offline ({} tasks, {} IRQs migrated)",
+        cpu.as_u32(),
+        migrated.len(),
+        moved_irqs.len()
+    );
+    Ok(())
+}
+
+/// Undoes the migration steps of a failed [`offline`].
+fn rollback(cpu: CpuId, migrated: &[Migration], moved_irqs: &[u32]) {
+    {
+        let mut routes = IRQ_AFFINITY.lock();
+        for &gsi in moved_irqs {
+            routes.insert(gsi, cpu);
+            route_irq(gsi, cpu);
+        }
+    }
+    smp_scheduler().cancel_offline(cpu, migrated);
+}
+
+/// Brings an application processor online.
+pub fn online(cpu: CpuId) -> Result<(), HotplugError> {
+    let _guard = HOTPLUG_LOCK.lock();
+    let smp = smp_state();
+
+    match smp.cpu_state(cpu) {
+        None => return Err(HotplugError::NoSuchCpu),
+        Some(CpuState::Online) => return Err(HotplugError::AlreadyInState),
+        Some(CpuState::Parked) => {
+            UNPARK[cpu.as_index()].store(true, Ordering::Release);
+            // Wake it from HLT
+            super::send_ipi(cpu, IpiType::Reschedule);
+        }
+        Some(_) => {
+            if !start_cpu(cpu) {
+                return Err(HotplugError::Timeout);
+            }
+        }
+    }
+
+    if !wait_for_state(cpu, CpuState::Online) {
+        return Err(HotplugError::Timeout);
+    }
+    smp_scheduler().cpu_online(cpu);
+
+    crate::serial_println!("[SMP] CPU {} online", cpu.as_u32());
+    Ok(())
+}
+
+/// Starts a CPU that was never brought up.
+fn start_cpu(cpu: CpuId) -> bool {
+    #[cfg(target_arch = "x86_64")]
+    {
+        match smp_state().hw_id(cpu) {
+            Some(apic_id) => super::start_ap(apic_id as u8),
+            None => false,
+        }
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        // aarch64 would use PSCI CPU_ON
+        let _ = cpu;
+        false
+    }
+}
+
+/// Spins until a CPU reaches the given state.
+fn wait_for_state(cpu: CpuId, state: CpuState) -> bool {
+    for _ in 0..STATE_TIMEOUT_SPINS {
+        if smp_state().cpu_state(cpu) == Some(state) {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    false
+}
+
+/// Spins until a CPU runs no process.
+fn wait_for_idle(cpu: CpuId) -> bool {
+    for _ in 0..STATE_TIMEOUT_SPINS {
+        // SAFETY: the CPU is up, so its per-CPU data is initialised; the
+        // field is only read, and volatile as that CPU may be changing it
+        let current = unsafe { core::ptr::read_volatile(&percpu::get(cpu).current_process) };
+        if current.is_none() {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    false
+}
+
+/// Runs on the CPU going offline (from the function-call IPI).
+///
+/// Takes the park request unless [`offline`] has already given up; the CPU
+/// parks in [`park_if_requested`] once the IPI is acknowledged. A CPU that
+/// is running a process again leaves the request, so `offline` rolls back:
+/// parking would hold that process's kernel stack.
+fn take_park_request(_arg: u64) {
+    let idx = percpu::cpu_id().as_index();
+    if percpu::current().current_process.is_some() {
+        return;
+    }
+    if !PARK_REQUESTED[idx].swap(false, Ordering::AcqRel) {
+        return;
+    }
+    PARK_PENDING[idx].store(true, Ordering::Release);
+}
+
+/// Parks the current CPU if it took a park request, until [`online`]
+/// releases it.
+///
+/// Called by the function-call IPI handler after EOI: parking with the IPI
+/// still in service would hold off the reschedule IPI that unparks the CPU.
+/// `halt` enables interrupts, so that IPI is delivered while parked. Only an
+/// idle CPU takes the request, so the stack it parks on is its own.
+pub fn park_if_requested() {
+    let cpu = percpu::cpu_id();
+    let idx = cpu.as_index();
+    if !PARK_PENDING[idx].swap(false, Ordering::AcqRel) {
+        return;
+    }
+
+    // SAFETY: We are running on the CPU that owns this per-CPU data
+    let data = unsafe { percpu::current_mut() };
+    data.state = CpuState::Parked;
+    smp_state().cpu_parked(cpu);
+
+    while !UNPARK[idx].swap(false, Ordering::AcqRel) {
+        crate::arch::halt();
+    }
+
+    data.state = CpuState::Online;
+    smp_state().cpu_online(cpu);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sched::smp::SmpScheduler;
+    use crate::sched::SchedulingClass;
+
+    #[test]
+    fn test_irq_fallback_spreads_irqs() {
+        let mut online = CpuMask::none();
+        online.set(CpuId::new(0));
+        online.set(CpuId::new(2));
+        online.set(CpuId::new(3));
+
+        let mut routes = BTreeMap::new();
+        routes.insert(10, CpuId::new(0));
+        routes.insert(11, CpuId::new(1));
+        routes.insert(12, CpuId::new(1));
+
+        // CPU 2 is busier than CPU 3; neither has IRQs yet
+        let load = |cpu: CpuId| if cpu == CpuId::new(2) { 10 } else { 0 };
+        assert_eq!(irq_fallback(&routes, online, load), Some(CpuId::new(3)));
+
+        // Once CPU 3 holds one, CPU 2 is next despite its load
+        routes.insert(11, CpuId::new(3));
+        assert_eq!(irq_fallback(&routes, online, load), Some(CpuId::new(2)));
+
+        assert_eq!(irq_fallback(&routes, CpuMask::none(), load), None);
+    }
+
+    #[test]
+    fn test_cancel_offline_restores_queue() {
+        static SCHED: SmpScheduler = SmpScheduler::new();
+        let (cpu0, cpu1) = (CpuId::new(0), CpuId::new(1));
+        let (a, b) = (ProcessId::new(100), ProcessId::new(101));
+
+        // As left by cpu_offline(cpu1): both processes moved to CPU 0
+        let migrated = [
+            Migration { pid: a, class: SchedulingClass::Interactive, to: cpu0 },
+            Migration { pid: b, class: SchedulingClass::Background, to: cpu0 },
+        ];
+        for m in &migrated {
+            SCHED.get_run_queue(cpu0).enqueue(m.pid, m.class, 128);
+        }
+        assert!(!SCHED.online_mask().contains(cpu1));
+
+        // One of them already ran on CPU 0; only the other goes back
+        assert_eq!(SCHED.get_run_queue(cpu0).dequeue(), Some(a));
+        SCHED.cancel_offline(cpu1, &migrated);
+        assert!(SCHED.online_mask().contains(cpu1));
+        assert_eq!(SCHED.get_run_queue(cpu1).dequeue(), Some(b));
+        assert!(SCHED.get_run_queue(cpu0).is_empty());
+    }
+}
@@ -8,6 +8,7 @@
 //! - Application Processor (AP) startup
 //! - Inter-Processor Interrupts (IPI)
 //! - CPU-local storage
+//! - CPU hotplug (taking APs offline and back online at runtime)
 //!
 //! ## Design
 //!
@@ -17,9 +18,11 @@
 //! - Local APIC (x86_64) or redistributor (aarch64)
 //! - Stack
 
+use alloc::vec::Vec;
 use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
 use spin::Mutex;
 
+pub mod hotplug;
 pub mod percpu;
 
 /// Maximum number of supported CPUs.
@@ -64,6 +67,8 @@ pub enum CpuState {
     Halted = 3,
     /// CPU is in panic state.
     Panicked = 4,
+    /// CPU was taken offline by hotplug and is parked waiting to be restarted.
+    Parked = 5,
 }
 
 /// Information about a single CPU.
@@ -107,6 +112,7 @@ impl CpuInfo {
             2 => CpuState::Online,
             3 => CpuState::Halted,
             4 => CpuState::Panicked,
+            5 => CpuState::Parked,
             _ => CpuState::Offline,
         }
     }
@@ -163,13 +169,63 @@ impl SmpState {
         }
     }
 
+    /// Gets the state of a registered CPU.
+    pub fn cpu_state(&self, id: CpuId) -> Option<CpuState> {
+        let idx = id.as_index();
+        if idx < MAX_CPUS {
+            self.cpus[idx].lock().as_ref().map(|c| c.get_state())
+        } else {
+            None
+        }
+    }
+
+    /// Gets the hardware ID (APIC ID or MPIDR) of a registered CPU.
+    pub fn hw_id(&self, id: CpuId) -> Option<u64> {
+        let idx = id.as_index();
+        if idx < MAX_CPUS {
+            self.cpus[idx].lock().as_ref().map(|c| c.hw_id)
+        } else {
+            None
+        }
+    }
+
+    /// Returns true if the CPU is the bootstrap processor.
+    pub fn is_bsp(&self, id: CpuId) -> bool {
+        let idx = id.as_index();
+        idx < MAX_CPUS && self.cpus[idx].lock().as_ref().map(|c| c.is_bsp).unwrap_or(false)
+    }
+
     /// Marks a CPU as online.
     pub fn cpu_online(&self, id: CpuId) {
         let idx = id.as_index();
         if idx < MAX_CPUS {
             if let Some(ref cpu) = *self.cpus[idx].lock() {
-                cpu.set_state(CpuState::Online);
-                self.online_count.fetch_add(1, Ordering::SeqCst);
+                if cpu.get_state() != CpuState::Online {
+                    cpu.set_state(CpuState::Online);
+                    self.online_count.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        }
+    }
+
+    /// Marks an online CPU as parked.
+    pub fn cpu_parked(&self, id: CpuId) {
+        let idx = id.as_index();
+        if idx < MAX_CPUS {
+            if let Some(ref cpu) = *self.cpus[idx].lock() {
+                if cpu.get_state() == CpuState::Online {
+                    self.online_count.fetch_sub(1, Ordering::SeqCst);
+                }
+                cpu.set_state(CpuState::Parked);
+            }
+        }
+    }
+
+    /// Iterates over all registered CPUs regardless of state.
+    pub fn for_each_present<F: FnMut(CpuId, CpuState)>(&self, mut f: F) {
+        for i in 0..MAX_CPUS {
+            if let Some(ref cpu) = *self.cpus[i].lock() {
+                f(cpu.id, cpu.get_state());
             }
         }
     }
@@ -321,6 +377,70 @@ pub fn call_on_all_others(func: fn(u64), arg: u64) {
     });
 }
 
+/// CPUs yet to acknowledge the TLB shootdown in progress.
+static SHOOTDOWN_PENDING: AtomicUsize = AtomicUsize::new(0);
+
+/// Serialises TLB shootdowns.
+static SHOOTDOWN_LOCK: Mutex<()> = Mutex::new(());
+
+/// Spin iterations to wait for shootdown acknowledgements.
+const SHOOTDOWN_TIMEOUT_SPINS: usize = 10_000_000;
+
+/// Flushes stale translations of the address space rooted at `root` from
+/// every other online CPU, and waits until they have done so.
+///
+/// Needed after a mapping loses permissions (e.g. pages turned
+/// copy-on-write): a CPU running the address space may still hold the old
+/// writable entry. Returns false if a CPU did not respond in time.
+pub fn tlb_shootdown(root: u64) -> bool {
+    let _guard = SHOOTDOWN_LOCK.lock();
+    let current = current_cpu_id();
+    let mut targets = Vec::new();
+    SMP_STATE.for_each_online(|cpu| {
+        if cpu != current {
+            targets.push(cpu);
+        }
+    });
+    if targets.is_empty() {
+        return true;
+    }
+
+    // Count first: a target may acknowledge before the next one is queued
+    SHOOTDOWN_PENDING.store(targets.len(), Ordering::Release);
+    for cpu in targets {
+        if !call_on_cpu(cpu, flush_address_space, root) {
+            SHOOTDOWN_PENDING.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+    for _ in 0..SHOOTDOWN_TIMEOUT_SPINS {
+        if SHOOTDOWN_PENDING.load(Ordering::Acquire) == 0 {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    false
+}
+
+/// Runs on a shootdown target.
+fn flush_address_space(root: u64) {
+    #[cfg(target_arch = "x86_64")]
+    {
+        use crate::arch::x86_64::paging;
+        // Other address spaces' entries went with the last CR3 switch
+        if paging::read_cr3() & !0xFFF == root {
+            paging::flush_tlb();
+        }
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        let _ = root;
+        handle_ipi(IpiType::TlbShootdown);
+    }
+
+    SHOOTDOWN_PENDING.fetch_sub(1, Ordering::AcqRel);
+}
+
 /// Returns a reference to the global SMP state.
 pub fn smp_state() -> &'static SmpState {
     &SMP_STATE
@@ -600,16 +720,19 @@ fn send_ipi_x86(target: CpuId, ipi_type: IpiType) {
     const ICR_LOW: usize = LAPIC_BASE + 0x300;
     const ICR_HIGH: usize = LAPIC_BASE + 0x310;
     
+    use crate::arch::x86_64::lapic::ipi_vectors;
+
     let vector = match ipi_type {
-        IpiType::Reschedule => 0xFD,    // Reschedule vector
-        IpiType::TlbShootdown => 0xFC,  // TLB shootdown vector
-        IpiType::Stop => 0xFE,          // Stop vector
-        IpiType::FunctionCall => 0xFB,  // Function call vector
+        IpiType::Reschedule => ipi_vectors::RESCHEDULE,
+        IpiType::TlbShootdown => ipi_vectors::TLB_SHOOTDOWN,
+        IpiType::Stop => ipi_vectors::STOP,
+        IpiType::FunctionCall => ipi_vectors::FUNCTION_CALL,
     };
     
     // Fixed delivery mode (000), physical destination
+    let apic_id = SMP_STATE.hw_id(target).unwrap_or(target.as_u32() as u64) as u32;
     let icr_low: u32 = vector as u32;
-    let icr_high: u32 = (target.as_u32()) << 24;
+    let icr_high: u32 = apic_id << 24;
     
     unsafe {
         // Write high first (sets destination)
@@ -648,9 +771,7 @@ pub fn handle_ipi(ipi_type: IpiType) {
         IpiType::TlbShootdown => {
             // Invalidate local TLB
             #[cfg(target_arch = "x86_64")]
-            unsafe {
-                core::arch::asm!("invlpg [{}]", in(reg) 0u64, options(nostack));
-            }
+            crate::arch::x86_64::paging::flush_tlb();
 
             #[cfg(target_arch = "aarch64")]
             unsafe {
@@ -0,0 +1,127 @@
+//! # Splax Compression
+//!
+//! Pure Rust compressors that work in both kernel (no_std) and userspace
+//! (std) environments.
+//!
+//! ## Algorithms
+//!
+//! - **LZ4**: The LZ4 block format; fast, moderate ratio
+//! - **LZH**: LZ77 with Huffman-coded literals, lengths and offsets; a
+//!   zstd-like ratio at a lower speed
+//!
+//! Decoders only, for data written by other systems (SquashFS images):
+//!
+//! - **zlib**: DEFLATE in a zlib stream, as `gzip` SquashFS images use
+//! - **Zstandard**: zstd frames
+//!
+//! ## Design
+//!
+//! - One buffer at a time, matches reaching back at most 64 KiB
+//! - Streams do not record their uncompressed length; callers store it
+//!   and pass it to [`decompress`], which fails rather than return
+//!   anything else
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+
+pub mod lz4;
+pub mod lzh;
+pub mod zlib;
+pub mod zstd;
+
+/// Compression algorithm
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Algorithm {
+    /// LZ4 block format
+    Lz4 = 1,
+    /// LZ77 with Huffman coding
+    Lzh = 2,
+}
+
+impl Algorithm {
+    /// Algorithm with an on-disk number
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(Self::Lz4),
+            2 => Some(Self::Lzh),
+            _ => None,
+        }
+    }
+
+    /// Algorithm with a name, as in [`name`](Self::name)
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "lz4" => Some(Self::Lz4),
+            "lzh" => Some(Self::Lzh),
+            _ => None,
+        }
+    }
+
+    /// Lowercase name
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Lz4 => "lz4",
+            Self::Lzh => "lzh",
+        }
+    }
+}
+
+/// Compressed data is malformed or does not decompress to the expected
+/// length
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CorruptData;
+
+/// Compresses a buffer. The result can be larger than the input.
+pub fn compress(algorithm: Algorithm, input: &[u8]) -> Vec<u8> {
+    match algorithm {
+        Algorithm::Lz4 => lz4::compress(input),
+        Algorithm::Lzh => lzh::compress(input),
+    }
+}
+
+/// Decompresses a buffer that decompresses to exactly `len` bytes
+pub fn decompress(algorithm: Algorithm, input: &[u8], len: usize) -> Result<Vec<u8>, CorruptData> {
+    match algorithm {
+        Algorithm::Lz4 => lz4::decompress(input, len),
+        Algorithm::Lzh => lzh::decompress(input, len),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod testing {
+    use alloc::vec::Vec;
+
+    /// Log-like text: repetitive lines with changing numbers
+    pub fn log_text(lines: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        for i in 0..lines {
+            let line = alloc::format!(
+                "[{:08}] INFO  net::tcp: connection {} from 10.0.{}.{} accepted, window {}\n",
+                i * 37,
+                i,
+                i % 7,
+                i % 251,
+                4096 + (i % 3) * 1024
+            );
+            out.extend_from_slice(line.as_bytes());
+        }
+        out
+    }
+
+    /// Incompressible bytes (xorshift)
+    pub fn noise(len: usize) -> Vec<u8> {
+        let mut state = 0x9E37_79B9_7F4A_7C15u64;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+}
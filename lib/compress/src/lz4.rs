@@ -0,0 +1,206 @@
+//! LZ4 block format
+//!
+//! A block is a series of sequences. Each starts with a token whose high
+//! nibble is the literal count and low nibble the match length minus 4,
+//! 15 meaning that more length bytes follow (each adds up to 255):
+//!
+//! ```text
+//! +-------+-------------+----------+--------+-------------+
+//! | token | literal len | literals | offset | match len   |
+//! |  u8   | 255, ...    |          | u16 le | 255, ...    |
+//! +-------+-------------+----------+--------+-------------+
+//! ```
+//!
+//! The last sequence has literals only, and covers at least the last 5
+//! bytes; the last match starts at least 12 bytes before the end.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::CorruptData;
+
+/// Shortest match
+const MIN_MATCH: usize = 4;
+
+/// The last match starts at least this far from the end
+const MF_LIMIT: usize = 12;
+
+/// The last literals cover at least this many bytes
+const LAST_LITERALS: usize = 5;
+
+/// Farthest a match reaches back
+const MAX_OFFSET: usize = u16::MAX as usize;
+
+/// Hash table size (log2)
+const HASH_BITS: u32 = 12;
+
+fn read_u32(data: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]])
+}
+
+fn hash(sequence: u32) -> usize {
+    (sequence.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+}
+
+/// Appends the extra bytes of a length past its token nibble
+fn write_length(out: &mut Vec<u8>, mut len: usize) {
+    while len >= 255 {
+        out.push(255);
+        len -= 255;
+    }
+    out.push(len as u8);
+}
+
+/// Reads the extra bytes of a length
+fn read_length(input: &[u8], pos: &mut usize) -> Result<usize, CorruptData> {
+    let mut len = 0;
+    loop {
+        let byte = *input.get(*pos).ok_or(CorruptData)?;
+        *pos += 1;
+        len += byte as usize;
+        if byte != 255 {
+            return Ok(len);
+        }
+    }
+}
+
+/// Appends a sequence: literals, then a match of (offset, length)
+fn push_sequence(out: &mut Vec<u8>, literals: &[u8], found: Option<(usize, usize)>) {
+    let match_len = found.map_or(0, |(_, len)| len - MIN_MATCH);
+    out.push(((literals.len().min(15) as u8) << 4) | match_len.min(15) as u8);
+    if literals.len() >= 15 {
+        write_length(out, literals.len() - 15);
+    }
+    out.extend_from_slice(literals);
+    if let Some((offset, _)) = found {
+        out.extend_from_slice(&(offset as u16).to_le_bytes());
+        if match_len >= 15 {
+            write_length(out, match_len - 15);
+        }
+    }
+}
+
+/// Compresses a buffer into one block
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len() / 2 + 16);
+    // Position + 1 of the last sequence with each hash; 0 is none
+    let mut table = vec![0u32; 1 << HASH_BITS];
+    let mut anchor = 0;
+    let mut pos = 0;
+
+    while pos + MF_LIMIT <= input.len() {
+        let sequence = read_u32(input, pos);
+        let slot = hash(sequence);
+        let candidate = table[slot] as usize;
+        table[slot] = pos as u32 + 1;
+        if candidate == 0 || pos - (candidate - 1) > MAX_OFFSET || read_u32(input, candidate - 1) != sequence {
+            pos += 1;
+            continue;
+        }
+
+        let (mut start, mut at) = (candidate - 1, pos);
+        let mut len = MIN_MATCH;
+        let end = input.len() - LAST_LITERALS;
+        while at + len < end && input[start + len] == input[at + len] {
+            len += 1;
+        }
+        while at > anchor && start > 0 && input[start - 1] == input[at - 1] {
+            start -= 1;
+            at -= 1;
+            len += 1;
+        }
+        push_sequence(&mut out, &input[anchor..at], Some((at - start, len)));
+        pos = at + len;
+        anchor = pos;
+    }
+
+    push_sequence(&mut out, &input[anchor..], None);
+    out
+}
+
+/// Decompresses a block of `len` bytes
+pub fn decompress(input: &[u8], len: usize) -> Result<Vec<u8>, CorruptData> {
+    let out = decompress_at_most(input, len)?;
+    if out.len() != len {
+        return Err(CorruptData);
+    }
+    Ok(out)
+}
+
+/// Decompresses a block of at most `limit` bytes, for formats that do not
+/// record the exact length
+pub fn decompress_at_most(input: &[u8], limit: usize) -> Result<Vec<u8>, CorruptData> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+    loop {
+        let token = *input.get(pos).ok_or(CorruptData)?;
+        pos += 1;
+
+        let mut literal_len = (token >> 4) as usize;
+        if literal_len == 15 {
+            literal_len += read_length(input, &mut pos)?;
+        }
+        let literals = input.get(pos..pos + literal_len).ok_or(CorruptData)?;
+        if out.len() + literal_len > limit {
+            return Err(CorruptData);
+        }
+        out.extend_from_slice(literals);
+        pos += literal_len;
+        if pos == input.len() {
+            break;
+        }
+
+        let offset = input.get(pos..pos + 2).ok_or(CorruptData)?;
+        let offset = u16::from_le_bytes([offset[0], offset[1]]) as usize;
+        pos += 2;
+        let mut match_len = (token & 0x0F) as usize;
+        if match_len == 15 {
+            match_len += read_length(input, &mut pos)?;
+        }
+        match_len += MIN_MATCH;
+        if offset == 0 || offset > out.len() || out.len() + match_len > limit {
+            return Err(CorruptData);
+        }
+        // Matches can overlap what they produce
+        let start = out.len() - offset;
+        for i in 0..match_len {
+            out.push(out[start + i]);
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{log_text, noise};
+
+    #[test]
+    fn test_round_trip() {
+        let inputs = [
+            Vec::new(),
+            b"short".to_vec(),
+            vec![0u8; 16384],
+            log_text(200),
+            noise(5000),
+            [noise(300), noise(300)].concat(),
+        ];
+        for input in inputs {
+            let packed = compress(&input);
+            assert_eq!(decompress(&packed, input.len()).unwrap(), input);
+        }
+        assert!(compress(&vec![0u8; 16384]).len() < 100);
+    }
+
+    #[test]
+    fn test_block_format() {
+        // "abc", then 6 bytes from 3 back, then the last literals
+        let block = [0x36 - 4, b'a', b'b', b'c', 3, 0, 0x50, b'v', b'w', b'x', b'y', b'z'];
+        assert_eq!(decompress(&block, 14).unwrap(), b"abcabcabcvwxyz");
+        assert_eq!(decompress(&block, 13), Err(CorruptData));
+        assert_eq!(decompress_at_most(&block, 100).unwrap(), b"abcabcabcvwxyz");
+        assert_eq!(decompress(&block[..5], 14), Err(CorruptData));
+        // Offset past the start
+        assert_eq!(decompress(&[0x10, b'a', 2, 0, 0x00], 6), Err(CorruptData));
+    }
+}
@@ -0,0 +1,502 @@
+//! LZH: LZ77 with Huffman coding
+//!
+//! The compressor finds matches of 4 to 258 bytes up to 64 KiB back
+//! through hash chains, and defers a match by one byte when the next one
+//! is longer (lazy matching). Literals and match lengths share one Huffman
+//! code and offsets have another, as in DEFLATE. A stream is one block:
+//!
+//! ```text
+//! +-----------------------+---------------------+------------------------+
+//! | literal/length code   | offset code lengths | symbols ..., end (256) |
+//! | lengths, 273 x 4 bits | 32 x 4 bits         |                        |
+//! +-----------------------+---------------------+------------------------+
+//! ```
+//!
+//! Bits are packed from the least significant end of each byte; Huffman
+//! codes are sent most significant bit first. Match lengths (minus 4) and
+//! offsets (minus 1) are sent as a code plus extra bits: values below 4
+//! have their own codes, larger ones two codes per power of two.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::CorruptData;
+
+/// Shortest match
+const MIN_MATCH: usize = 4;
+
+/// Longest match
+const MAX_MATCH: usize = 258;
+
+/// Hash chain window; also the farthest a match reaches back, plus one
+const WINDOW: usize = 1 << 16;
+
+/// Hash chain entries to try per position
+const CHAIN_DEPTH: usize = 48;
+
+/// Hash table size (log2)
+const HASH_BITS: u32 = 15;
+
+/// End of block symbol
+const END: usize = 256;
+
+/// Literal/length alphabet: 256 literals, end of block, 16 length codes
+const LITLEN_SYMBOLS: usize = 273;
+
+/// Offset alphabet
+const DIST_SYMBOLS: usize = 32;
+
+/// Longest Huffman code
+const MAX_BITS: usize = 15;
+
+/// No hash chain entry
+const NONE: u32 = u32::MAX;
+
+/// Code, extra bit count and extra bits of a length or offset value
+fn bucket(value: u32) -> (usize, u32, u32) {
+    if value < 4 {
+        return (value as usize, 0, 0);
+    }
+    let n = 31 - value.leading_zeros();
+    let code = 4 + (n - 2) * 2 + ((value >> (n - 1)) & 1);
+    (code as usize, n - 1, value & ((1 << (n - 1)) - 1))
+}
+
+/// Base value and extra bit count of a length or offset code
+fn bucket_base(code: usize) -> (u32, u32) {
+    if code < 4 {
+        return (code as u32, 0);
+    }
+    let n = (code as u32 - 4) / 2 + 2;
+    ((2 | (code as u32 & 1)) << (n - 1), n - 1)
+}
+
+/// A literal, or a match of (length, offset)
+#[derive(Clone, Copy)]
+enum Token {
+    Literal(u8),
+    Match(usize, usize),
+}
+
+/// Hash chains over the positions seen so far
+struct Chains<'a> {
+    input: &'a [u8],
+    head: Vec<u32>,
+    prev: Vec<u32>,
+}
+
+impl<'a> Chains<'a> {
+    fn new(input: &'a [u8]) -> Self {
+        Self { input, head: vec![NONE; 1 << HASH_BITS], prev: vec![NONE; WINDOW] }
+    }
+
+    fn hash(&self, pos: usize) -> usize {
+        let data = &self.input[pos..pos + MIN_MATCH];
+        let sequence = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        (sequence.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+    }
+
+    fn insert(&mut self, pos: usize) {
+        if pos + MIN_MATCH <= self.input.len() {
+            let slot = self.hash(pos);
+            self.prev[pos % WINDOW] = self.head[slot];
+            self.head[slot] = pos as u32;
+        }
+    }
+
+    /// Longest earlier match at a position as (length, offset)
+    fn longest(&self, pos: usize) -> (usize, usize) {
+        let input = self.input;
+        if pos + MIN_MATCH > input.len() {
+            return (0, 0);
+        }
+        let max = (input.len() - pos).min(MAX_MATCH);
+        let mut best = (0, 0);
+        let mut candidate = self.head[self.hash(pos)];
+        for _ in 0..CHAIN_DEPTH {
+            if candidate == NONE {
+                break;
+            }
+            let start = candidate as usize;
+            if pos - start >= WINDOW {
+                break;
+            }
+            if input[start + best.0] == input[pos + best.0] {
+                let len = (0..max).find(|&i| input[start + i] != input[pos + i]).unwrap_or(max);
+                if len > best.0 {
+                    best = (len, pos - start);
+                    if len == max {
+                        break;
+                    }
+                }
+            }
+            // Entries older than the window may have been overwritten
+            let next = self.prev[start % WINDOW];
+            if next != NONE && next as usize >= start {
+                break;
+            }
+            candidate = next;
+        }
+        if best.0 < MIN_MATCH {
+            return (0, 0);
+        }
+        best
+    }
+}
+
+/// Splits a buffer into literals and matches
+fn tokenize(input: &[u8]) -> Vec<Token> {
+    let mut chains = Chains::new(input);
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    while pos < input.len() {
+        let (len, offset) = chains.longest(pos);
+        chains.insert(pos);
+        if len == 0 || chains.longest(pos + 1).0 > len {
+            tokens.push(Token::Literal(input[pos]));
+            pos += 1;
+            continue;
+        }
+        tokens.push(Token::Match(len, offset));
+        for p in pos + 1..pos + len {
+            chains.insert(p);
+        }
+        pos += len;
+    }
+    tokens
+}
+
+/// Huffman code lengths for symbol frequencies, at most `MAX_BITS` long
+fn code_lengths(freqs: &[u32]) -> Vec<u8> {
+    let mut freqs = freqs.to_vec();
+    loop {
+        let lengths = tree_depths(&freqs);
+        if lengths.iter().all(|&len| len <= MAX_BITS) {
+            return lengths.iter().map(|&len| len as u8).collect();
+        }
+        // Flatten the distribution; every used symbol stays used
+        for freq in freqs.iter_mut().filter(|freq| **freq > 0) {
+            *freq = freq.div_ceil(2);
+        }
+    }
+}
+
+/// Leaf depths of a Huffman tree over symbol frequencies
+fn tree_depths(freqs: &[u32]) -> Vec<usize> {
+    let mut depths = vec![0; freqs.len()];
+    let used: Vec<usize> = (0..freqs.len()).filter(|&sym| freqs[sym] > 0).collect();
+    if used.len() == 1 {
+        depths[used[0]] = 1;
+        return depths;
+    }
+
+    // Nodes as (weight, parent); leaves first, in `used` order
+    let mut nodes: Vec<(u64, usize)> = used.iter().map(|&sym| (freqs[sym] as u64, usize::MAX)).collect();
+    let mut active: Vec<usize> = (0..nodes.len()).collect();
+    while active.len() > 1 {
+        active.sort_by(|&a, &b| nodes[b].0.cmp(&nodes[a].0));
+        let (a, b) = (active.pop().unwrap(), active.pop().unwrap());
+        let parent = nodes.len();
+        nodes.push((nodes[a].0 + nodes[b].0, usize::MAX));
+        nodes[a].1 = parent;
+        nodes[b].1 = parent;
+        active.push(parent);
+    }
+
+    for (leaf, &sym) in used.iter().enumerate() {
+        let mut node = leaf;
+        while nodes[node].1 != usize::MAX {
+            node = nodes[node].1;
+            depths[sym] += 1;
+        }
+    }
+    depths
+}
+
+/// Canonical codes for code lengths
+fn canonical_codes(lengths: &[u8]) -> Vec<u16> {
+    let mut counts = [0u16; MAX_BITS + 1];
+    for &len in lengths.iter().filter(|&&len| len > 0) {
+        counts[len as usize] += 1;
+    }
+    let mut next = [0u16; MAX_BITS + 1];
+    let mut code = 0u16;
+    for len in 1..=MAX_BITS {
+        code = (code + counts[len - 1]) << 1;
+        next[len] = code;
+    }
+    lengths
+        .iter()
+        .map(|&len| {
+            if len == 0 {
+                return 0;
+            }
+            let code = next[len as usize];
+            next[len as usize] += 1;
+            code
+        })
+        .collect()
+}
+
+/// Packs bits from the least significant end of each byte
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        Self { out: Vec::new(), bits: 0, count: 0 }
+    }
+
+    fn write(&mut self, value: u32, count: u32) {
+        self.bits |= (value as u64) << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Writes a Huffman code, most significant bit first
+    fn write_code(&mut self, code: u16, len: u8) {
+        let reversed = code.reverse_bits() >> (16 - len as u32);
+        self.write(reversed as u32, len as u32);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.bits as u8);
+        }
+        self.out
+    }
+}
+
+/// Compresses a buffer into one block
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let tokens = tokenize(input);
+
+    let mut litlen_freqs = [0u32; LITLEN_SYMBOLS];
+    let mut dist_freqs = [0u32; DIST_SYMBOLS];
+    litlen_freqs[END] = 1;
+    for &token in &tokens {
+        match token {
+            Token::Literal(byte) => litlen_freqs[byte as usize] += 1,
+            Token::Match(len, offset) => {
+                litlen_freqs[END + 1 + bucket((len - MIN_MATCH) as u32).0] += 1;
+                dist_freqs[bucket(offset as u32 - 1).0] += 1;
+            }
+        }
+    }
+    let litlen_lengths = code_lengths(&litlen_freqs);
+    let dist_lengths = code_lengths(&dist_freqs);
+    let litlen_codes = canonical_codes(&litlen_lengths);
+    let dist_codes = canonical_codes(&dist_lengths);
+
+    let mut writer = BitWriter::new();
+    for &len in litlen_lengths.iter().chain(&dist_lengths) {
+        writer.write(len as u32, 4);
+    }
+    for &token in &tokens {
+        match token {
+            Token::Literal(byte) => {
+                writer.write_code(litlen_codes[byte as usize], litlen_lengths[byte as usize]);
+            }
+            Token::Match(len, offset) => {
+                let (code, extra_bits, extra) = bucket((len - MIN_MATCH) as u32);
+                let sym = END + 1 + code;
+                writer.write_code(litlen_codes[sym], litlen_lengths[sym]);
+                writer.write(extra, extra_bits);
+                let (code, extra_bits, extra) = bucket(offset as u32 - 1);
+                writer.write_code(dist_codes[code], dist_lengths[code]);
+                writer.write(extra, extra_bits);
+            }
+        }
+    }
+    writer.write_code(litlen_codes[END], litlen_lengths[END]);
+    writer.finish()
+}
+
+/// Reads bits from the least significant end of each byte
+pub(crate) struct BitReader<'a> {
+    input: &'a [u8],
+    pos: usize,
+    bits: u64,
+    count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    pub(crate) fn new(input: &'a [u8]) -> Self {
+        Self { input, pos: 0, bits: 0, count: 0 }
+    }
+
+    pub(crate) fn read(&mut self, count: u32) -> Result<u32, CorruptData> {
+        while self.count < count {
+            let byte = *self.input.get(self.pos).ok_or(CorruptData)?;
+            self.pos += 1;
+            self.bits |= (byte as u64) << self.count;
+            self.count += 8;
+        }
+        let value = (self.bits & ((1u64 << count) - 1)) as u32;
+        self.bits >>= count;
+        self.count -= count;
+        Ok(value)
+    }
+
+    /// Skips to the next byte boundary
+    pub(crate) fn align(&mut self) {
+        // Bytes are loaded only as needed, so under 8 bits are left over
+        self.bits = 0;
+        self.count = 0;
+    }
+
+    /// Bytes consumed so far, after [`align`](Self::align)
+    pub(crate) fn byte_pos(&self) -> usize {
+        self.pos
+    }
+}
+
+/// Canonical Huffman decoder
+pub(crate) struct Decoder {
+    /// Codes of each length
+    counts: [u16; MAX_BITS + 1],
+    /// Symbols ordered by code
+    symbols: Vec<u16>,
+}
+
+impl Decoder {
+    pub(crate) fn new(lengths: &[u8]) -> Result<Self, CorruptData> {
+        let mut counts = [0u16; MAX_BITS + 1];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        // Reject over-subscribed codes; incomplete ones are fine
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left = (left << 1) - count as i32;
+            if left < 0 {
+                return Err(CorruptData);
+            }
+        }
+
+        let mut symbols = Vec::with_capacity(lengths.len());
+        for len in 1..=MAX_BITS as u8 {
+            symbols.extend((0..lengths.len() as u16).filter(|&sym| lengths[sym as usize] == len));
+        }
+        Ok(Self { counts, symbols })
+    }
+
+    /// Decodes one symbol, a bit at a time
+    pub(crate) fn decode(&self, reader: &mut BitReader) -> Result<usize, CorruptData> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for &count in &self.counts[1..] {
+            code |= reader.read(1)? as i32;
+            let count = count as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize] as usize);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(CorruptData)
+    }
+}
+
+/// Decompresses a block of `len` bytes
+pub fn decompress(input: &[u8], len: usize) -> Result<Vec<u8>, CorruptData> {
+    let mut reader = BitReader::new(input);
+    let mut lengths = [0u8; LITLEN_SYMBOLS + DIST_SYMBOLS];
+    for length in lengths.iter_mut() {
+        *length = reader.read(4)? as u8;
+    }
+    let litlen = Decoder::new(&lengths[..LITLEN_SYMBOLS])?;
+    let dist = Decoder::new(&lengths[LITLEN_SYMBOLS..])?;
+
+    let mut out = Vec::with_capacity(len);
+    loop {
+        let sym = litlen.decode(&mut reader)?;
+        if sym < END {
+            if out.len() == len {
+                return Err(CorruptData);
+            }
+            out.push(sym as u8);
+            continue;
+        }
+        if sym == END {
+            break;
+        }
+
+        let (base, extra_bits) = bucket_base(sym - END - 1);
+        let match_len = (base + reader.read(extra_bits)?) as usize + MIN_MATCH;
+        let (base, extra_bits) = bucket_base(dist.decode(&mut reader)?);
+        let offset = (base + reader.read(extra_bits)?) as usize + 1;
+        if offset > out.len() || out.len() + match_len > len {
+            return Err(CorruptData);
+        }
+        // Matches can overlap what they produce
+        let start = out.len() - offset;
+        for i in 0..match_len {
+            out.push(out[start + i]);
+        }
+    }
+
+    if out.len() != len {
+        return Err(CorruptData);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{log_text, noise};
+
+    #[test]
+    fn test_buckets() {
+        for value in (0..WINDOW as u32).step_by(7).chain([0, 1, 3, 4, 254, 65_535]) {
+            let (code, extra_bits, extra) = bucket(value);
+            assert!(code < DIST_SYMBOLS);
+            assert_eq!(bucket_base(code), (value - extra, extra_bits));
+        }
+        assert!(bucket((MAX_MATCH - MIN_MATCH) as u32).0 < LITLEN_SYMBOLS - END - 1);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let inputs = [
+            Vec::new(),
+            b"a".to_vec(),
+            b"abcabcabcabcabcabcabc".to_vec(),
+            vec![0u8; 100_000],
+            log_text(2000),
+            noise(20_000),
+            [noise(70_000), noise(70_000)].concat(),
+        ];
+        for input in inputs {
+            let packed = compress(&input);
+            assert_eq!(decompress(&packed, input.len()).unwrap(), input);
+        }
+    }
+
+    #[test]
+    fn test_ratio() {
+        let text = log_text(2000);
+        let lzh = compress(&text).len();
+        assert!(lzh < crate::lz4::compress(&text).len());
+        assert!(lzh * 5 < text.len());
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let text = log_text(100);
+        let packed = compress(&text);
+        assert_eq!(decompress(&packed, text.len() + 1), Err(CorruptData));
+        assert_eq!(decompress(&packed, text.len() - 1), Err(CorruptData));
+        assert_eq!(decompress(&packed[..packed.len() / 2], text.len()), Err(CorruptData));
+        // Every literal/length code 1 bit long
+        assert_eq!(decompress(&[0x11; 200], 10), Err(CorruptData));
+    }
+}
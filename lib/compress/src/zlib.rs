@@ -0,0 +1,269 @@
+//! zlib streams (RFC 1950) of DEFLATE data (RFC 1951), decode only
+//!
+//! A stream is a two-byte header, DEFLATE blocks and the Adler-32 of the
+//! decompressed data, most significant byte first:
+//!
+//! ```text
+//! +-----+-----+--------------------------------+---------+
+//! | CMF | FLG | blocks: final bit, type, data  | Adler-32|
+//! +-----+-----+--------------------------------+---------+
+//! ```
+//!
+//! Blocks are stored, or Huffman coded with the fixed codes or codes sent
+//! before the block. Bits are packed from the least significant end of
+//! each byte and Huffman codes sent most significant bit first, as in
+//! [`lzh`](crate::lzh), whose bit reader and decoder this shares.
+
+use alloc::vec::Vec;
+
+use crate::lzh::{BitReader, Decoder};
+use crate::CorruptData;
+
+/// Compression method: DEFLATE
+const CM_DEFLATE: u8 = 8;
+
+/// Largest window, as log2 minus 8
+const MAX_CINFO: u8 = 7;
+
+/// Preset dictionary flag
+const FDICT: u8 = 0x20;
+
+/// Block types
+const STORED: u32 = 0;
+const FIXED: u32 = 1;
+const DYNAMIC: u32 = 2;
+
+/// End of block symbol
+const END: usize = 256;
+
+/// Literal/length alphabet, including two codes that never occur
+const LITLEN_SYMBOLS: usize = 288;
+
+/// Distance alphabet, including two codes that never occur
+const DIST_SYMBOLS: usize = 32;
+
+/// Order in which code length code lengths are sent
+const CLEN_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+/// Base length and extra bits of length codes 257..=285
+const LENGTH_BASE: [u16; 29] =
+    [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+
+/// Base distance and extra bits of distance codes 0..=29
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+    8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] =
+    [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+
+/// Adler-32 modulus
+const ADLER_MOD: u32 = 65521;
+
+/// Adler-32 checksum
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    // 5552 bytes is the most that cannot overflow before the modulus
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= ADLER_MOD;
+        b %= ADLER_MOD;
+    }
+    (b << 16) | a
+}
+
+/// The fixed literal/length and distance codes
+fn fixed_decoders() -> Result<(Decoder, Decoder), CorruptData> {
+    let mut lengths = [0u8; LITLEN_SYMBOLS];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    Ok((Decoder::new(&lengths)?, Decoder::new(&[5; DIST_SYMBOLS])?))
+}
+
+/// Reads the codes sent before a dynamic block
+fn dynamic_decoders(reader: &mut BitReader) -> Result<(Decoder, Decoder), CorruptData> {
+    let litlen_count = reader.read(5)? as usize + 257;
+    let dist_count = reader.read(5)? as usize + 1;
+    let clen_count = reader.read(4)? as usize + 4;
+
+    let mut clen_lengths = [0u8; 19];
+    for &sym in &CLEN_ORDER[..clen_count] {
+        clen_lengths[sym] = reader.read(3)? as u8;
+    }
+    let clen = Decoder::new(&clen_lengths)?;
+
+    let mut lengths = [0u8; LITLEN_SYMBOLS + DIST_SYMBOLS];
+    let total = litlen_count + dist_count;
+    let mut i = 0;
+    while i < total {
+        let (value, repeat) = match clen.decode(reader)? {
+            sym @ 0..=15 => (sym as u8, 1),
+            16 => (*lengths[..i].last().ok_or(CorruptData)?, 3 + reader.read(2)? as usize),
+            17 => (0, 3 + reader.read(3)? as usize),
+            _ => (0, 11 + reader.read(7)? as usize),
+        };
+        if i + repeat > total {
+            return Err(CorruptData);
+        }
+        lengths[i..i + repeat].fill(value);
+        i += repeat;
+    }
+    if lengths[END] == 0 {
+        return Err(CorruptData);
+    }
+    Ok((Decoder::new(&lengths[..litlen_count])?, Decoder::new(&lengths[litlen_count..total])?))
+}
+
+/// Decodes the symbols of a Huffman coded block
+fn inflate_block(
+    reader: &mut BitReader,
+    litlen: &Decoder,
+    dist: &Decoder,
+    out: &mut Vec<u8>,
+    limit: usize,
+) -> Result<(), CorruptData> {
+    loop {
+        let sym = litlen.decode(reader)?;
+        if sym < END {
+            if out.len() == limit {
+                return Err(CorruptData);
+            }
+            out.push(sym as u8);
+            continue;
+        }
+        if sym == END {
+            return Ok(());
+        }
+
+        let code = sym - END - 1;
+        if code >= LENGTH_BASE.len() {
+            return Err(CorruptData);
+        }
+        let match_len = LENGTH_BASE[code] as usize + reader.read(LENGTH_EXTRA[code] as u32)? as usize;
+        let code = dist.decode(reader)?;
+        if code >= DIST_BASE.len() {
+            return Err(CorruptData);
+        }
+        let offset = DIST_BASE[code] as usize + reader.read(DIST_EXTRA[code] as u32)? as usize;
+        if offset > out.len() || out.len() + match_len > limit {
+            return Err(CorruptData);
+        }
+        // Matches can overlap what they produce
+        let start = out.len() - offset;
+        for i in 0..match_len {
+            out.push(out[start + i]);
+        }
+    }
+}
+
+/// Decodes raw DEFLATE blocks, returning the data and the bytes read
+fn inflate(input: &[u8], limit: usize) -> Result<(Vec<u8>, usize), CorruptData> {
+    let mut reader = BitReader::new(input);
+    let mut out = Vec::new();
+    loop {
+        let last = reader.read(1)? == 1;
+        match reader.read(2)? {
+            STORED => {
+                reader.align();
+                let len = reader.read(16)?;
+                if reader.read(16)? != !len & 0xFFFF || out.len() + len as usize > limit {
+                    return Err(CorruptData);
+                }
+                for _ in 0..len {
+                    out.push(reader.read(8)? as u8);
+                }
+            }
+            FIXED => {
+                let (litlen, dist) = fixed_decoders()?;
+                inflate_block(&mut reader, &litlen, &dist, &mut out, limit)?;
+            }
+            DYNAMIC => {
+                let (litlen, dist) = dynamic_decoders(&mut reader)?;
+                inflate_block(&mut reader, &litlen, &dist, &mut out, limit)?;
+            }
+            _ => return Err(CorruptData),
+        }
+        if last {
+            reader.align();
+            return Ok((out, reader.byte_pos()));
+        }
+    }
+}
+
+/// Decompresses a zlib stream of at most `limit` bytes
+pub fn decompress(input: &[u8], limit: usize) -> Result<Vec<u8>, CorruptData> {
+    let (&cmf, &flg) = (input.first().ok_or(CorruptData)?, input.get(1).ok_or(CorruptData)?);
+    if cmf & 0x0F != CM_DEFLATE
+        || cmf >> 4 > MAX_CINFO
+        || flg & FDICT != 0
+        || !(cmf as u16 * 256 + flg as u16).is_multiple_of(31)
+    {
+        return Err(CorruptData);
+    }
+    let (out, used) = inflate(&input[2..], limit)?;
+    let trailer = input.get(2 + used..2 + used + 4).ok_or(CorruptData)?;
+    if u32::from_be_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]) != adler32(&out) {
+        return Err(CorruptData);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bytes drawn from skewed letters, which zlib sends as a dynamic block
+    fn letters(len: usize) -> Vec<u8> {
+        let mut state = 1u32;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345) & 0x7FFF_FFFF;
+                b"eeeeeeeettaaoins"[(state >> 16) as usize % 16]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_adler32() {
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+
+    #[test]
+    fn test_block_types() {
+        let stored = [0x78, 0x01, 0x01, 0x05, 0x00, 0xFA, 0xFF, b'S', b'p', b'l', b'a', b'x', 0x05, 0xE2, 0x02, 0x09];
+        assert_eq!(decompress(&stored, 5).unwrap(), b"Splax");
+
+        let fixed =
+            [0x78, 0xDA, 0xCB, 0x48, 0xCD, 0xC9, 0xC9, 0x57, 0xC8, 0x40, 0x27, 0xB9, 0x00, 0x70, 0xBE, 0x08, 0xBB];
+        assert_eq!(decompress(&fixed, 100).unwrap(), b"hello hello hello hello\n");
+
+        let dynamic = [
+            0x78, 0xDA, 0x25, 0x8A, 0x21, 0x0E, 0x00, 0x30, 0x10, 0xC2, 0xDE, 0x8A, 0xA8, 0x98, 0x39, 0xC4, 0xF1, 0xFF,
+            0x8C, 0x65, 0x88, 0xA6, 0x49, 0x61, 0x90, 0x84, 0xE0, 0xAC, 0x0D, 0x2C, 0xF3, 0xD0, 0x45, 0x71, 0x1A, 0xF4,
+            0xDC, 0xBF, 0xF4, 0x9A, 0xBA, 0x98, 0x4D, 0x2E, 0xE8, 0x31, 0x18, 0x9D,
+        ];
+        assert_eq!(decompress(&dynamic, 60).unwrap(), letters(60));
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let fixed =
+            [0x78, 0xDA, 0xCB, 0x48, 0xCD, 0xC9, 0xC9, 0x57, 0xC8, 0x40, 0x27, 0xB9, 0x00, 0x70, 0xBE, 0x08, 0xBB];
+        // Too long, truncated, wrong checksum, bad header check
+        assert_eq!(decompress(&fixed, 23), Err(CorruptData));
+        assert_eq!(decompress(&fixed[..12], 100), Err(CorruptData));
+        let mut bad = fixed;
+        bad[16] ^= 1;
+        assert_eq!(decompress(&bad, 100), Err(CorruptData));
+        assert_eq!(decompress(&[0x78, 0xDB, 0x03, 0x00], 100), Err(CorruptData));
+        // Stored length and its complement disagree
+        assert_eq!(decompress(&[0x78, 0x01, 0x01, 0x05, 0x00, 0xFA, 0xFE], 100), Err(CorruptData));
+    }
+}
@@ -0,0 +1,907 @@
+//! Zstandard frames (RFC 8878), decode only
+//!
+//! A frame is a header, blocks and an optional checksum:
+//!
+//! ```text
+//! +-------+------------+--------+---------+--------------+----------+
+//! | magic | descriptor | window | dict id | content size | blocks   |
+//! | u32   | u8         | 0-1    | 0-4     | 0-8          | ..., crc |
+//! +-------+------------+--------+---------+--------------+----------+
+//! ```
+//!
+//! Blocks are raw, a repeated byte, or compressed: Huffman-coded literals
+//! followed by sequences of (literal length, match length, offset), whose
+//! codes are FSE (tANS) coded with predefined tables, tables sent in the
+//! block, or those of the previous block. FSE and Huffman streams are
+//! read backwards from their last byte, whose highest set bit marks the
+//! end.
+//!
+//! Frames written with a dictionary are rejected; skippable frames are
+//! skipped, and frames that follow each other are decoded in turn.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::CorruptData;
+
+/// Frame magic number
+const MAGIC: u32 = 0xFD2F_B528;
+
+/// Skippable frame magic numbers, the low 4 bits being free
+const SKIPPABLE_MAGIC: u32 = 0x184D_2A50;
+
+/// Block types
+const BLOCK_RAW: u32 = 0;
+const BLOCK_RLE: u32 = 1;
+const BLOCK_COMPRESSED: u32 = 2;
+
+/// Largest block
+const MAX_BLOCK: usize = 128 * 1024;
+
+/// Literals section types
+const LITERALS_RAW: u8 = 0;
+const LITERALS_RLE: u8 = 1;
+const LITERALS_COMPRESSED: u8 = 2;
+
+/// Sequence table modes
+const MODE_PREDEFINED: u8 = 0;
+const MODE_RLE: u8 = 1;
+const MODE_FSE: u8 = 2;
+
+/// Longest Huffman code
+const MAX_HUFFMAN_BITS: u32 = 11;
+
+/// Largest accuracy of the FSE table coding Huffman weights
+const MAX_WEIGHT_ACCURACY: u32 = 6;
+
+/// Largest literal length, match length and offset codes
+const MAX_LL: usize = 35;
+const MAX_ML: usize = 52;
+const MAX_OF: usize = 31;
+
+/// Largest accuracy of the literal length, match length and offset tables
+const MAX_LL_ACCURACY: u32 = 9;
+const MAX_ML_ACCURACY: u32 = 9;
+const MAX_OF_ACCURACY: u32 = 8;
+
+/// Predefined literal length distribution, accuracy 6
+const LL_DEFAULT: [i16; 36] =
+    [4, 3, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 3, 2, 1, 1, 1, 1, 1, -1, -1, -1, -1];
+
+/// Predefined match length distribution, accuracy 6
+const ML_DEFAULT: [i16; 53] = [
+    1, 4, 3, 2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+    1, 1, 1, 1, 1, 1, 1, 1, -1, -1, -1, -1, -1, -1, -1,
+];
+
+/// Predefined offset distribution, accuracy 5
+const OF_DEFAULT: [i16; 29] =
+    [1, 1, 1, 1, 1, 1, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, -1, -1, -1, -1, -1];
+
+/// Base value and extra bits of literal length codes 16 and up; codes
+/// below stand for themselves
+const LL_BASE: [(u32, u32); 20] = [
+    (16, 1),
+    (18, 1),
+    (20, 1),
+    (22, 1),
+    (24, 2),
+    (28, 2),
+    (32, 3),
+    (40, 3),
+    (48, 4),
+    (64, 6),
+    (128, 7),
+    (256, 8),
+    (512, 9),
+    (1024, 10),
+    (2048, 11),
+    (4096, 12),
+    (8192, 13),
+    (16384, 14),
+    (32768, 15),
+    (65536, 16),
+];
+
+/// Base value and extra bits of match length codes 32 and up; codes
+/// below stand for themselves plus 3
+const ML_BASE: [(u32, u32); 21] = [
+    (35, 1),
+    (37, 1),
+    (39, 1),
+    (41, 1),
+    (43, 2),
+    (47, 2),
+    (51, 3),
+    (59, 3),
+    (67, 4),
+    (83, 4),
+    (99, 5),
+    (131, 7),
+    (259, 8),
+    (515, 9),
+    (1027, 10),
+    (2051, 11),
+    (4099, 12),
+    (8195, 13),
+    (16387, 14),
+    (32771, 15),
+    (65539, 16),
+];
+
+fn le_bytes(data: &[u8]) -> u64 {
+    data.iter().rev().fold(0, |value, &byte| (value << 8) | byte as u64)
+}
+
+/// Reads a bit stream backwards from the end marker in its last byte
+struct ReverseBits<'a> {
+    data: &'a [u8],
+    /// Bits left; negative once reads run past the start, which yield
+    /// zeros
+    pos: i64,
+}
+
+impl<'a> ReverseBits<'a> {
+    fn new(data: &'a [u8]) -> Result<Self, CorruptData> {
+        let last = *data.last().ok_or(CorruptData)?;
+        if last == 0 {
+            return Err(CorruptData);
+        }
+        let pos = data.len() as i64 * 8 - last.leading_zeros() as i64 - 1;
+        Ok(Self { data, pos })
+    }
+
+    /// The next `count` bits (at most 32) without consuming them
+    fn peek(&self, count: u32) -> u64 {
+        if count == 0 {
+            return 0;
+        }
+        let start = self.pos - count as i64;
+        let from = start.max(0) as usize;
+        let have = (self.pos - from as i64).max(0) as u32;
+        if have == 0 {
+            return 0;
+        }
+        let byte = from / 8;
+        let word = le_bytes(&self.data[byte..(byte + 8).min(self.data.len())]);
+        let value = (word >> (from % 8)) & ((1u64 << have) - 1);
+        value << (count - have)
+    }
+
+    fn consume(&mut self, count: u32) {
+        self.pos -= count as i64;
+    }
+
+    fn read(&mut self, count: u32) -> u64 {
+        let value = self.peek(count);
+        self.consume(count);
+        value
+    }
+
+    /// Every bit read, and no more
+    fn finished(&self) -> bool {
+        self.pos == 0
+    }
+
+    fn overflowed(&self) -> bool {
+        self.pos < 0
+    }
+}
+
+/// One state of an FSE decoding table
+#[derive(Clone, Copy, Default)]
+struct FseEntry {
+    symbol: u8,
+    bits: u8,
+    baseline: u16,
+}
+
+/// FSE decoding table
+#[derive(Clone)]
+struct FseTable {
+    accuracy: u32,
+    entries: Vec<FseEntry>,
+}
+
+impl FseTable {
+    /// Builds the table of a normalized distribution, -1 standing for
+    /// "less than one"
+    fn new(counts: &[i16], accuracy: u32) -> Result<Self, CorruptData> {
+        let size = 1usize << accuracy;
+        let mut entries = vec![FseEntry::default(); size];
+        let mut next = vec![0u16; counts.len()];
+
+        // Symbols with a "less than one" count take the last states
+        let mut high = size;
+        for (symbol, &count) in counts.iter().enumerate() {
+            if count == -1 {
+                high = high.checked_sub(1).ok_or(CorruptData)?;
+                entries[high].symbol = symbol as u8;
+                next[symbol] = 1;
+            } else {
+                next[symbol] = count.max(0) as u16;
+            }
+        }
+
+        // Spread the others over the rest
+        let step = (size >> 1) + (size >> 3) + 3;
+        let mask = size - 1;
+        let mut pos = 0;
+        for (symbol, &count) in counts.iter().enumerate() {
+            for _ in 0..count.max(0) {
+                entries[pos].symbol = symbol as u8;
+                pos = (pos + step) & mask;
+                while pos >= high {
+                    pos = (pos + step) & mask;
+                }
+            }
+        }
+        if pos != 0 {
+            return Err(CorruptData);
+        }
+
+        for entry in entries.iter_mut() {
+            let state = next[entry.symbol as usize];
+            next[entry.symbol as usize] += 1;
+            let bits = accuracy - (15 - state.leading_zeros());
+            entry.bits = bits as u8;
+            entry.baseline = ((state as u32) << bits).wrapping_sub(size as u32) as u16;
+        }
+        Ok(Self { accuracy, entries })
+    }
+
+    /// A table that always decodes to one symbol
+    fn rle(symbol: u8) -> Self {
+        Self { accuracy: 0, entries: vec![FseEntry { symbol, bits: 0, baseline: 0 }] }
+    }
+
+    /// Reads a table description, returning the table and the bytes read
+    fn read(input: &[u8], max_symbol: usize, max_accuracy: u32) -> Result<(Self, usize), CorruptData> {
+        // A little-endian bit stream, read forwards
+        let mut bit = 0usize;
+        let mut read = |count: u32, consume: bool| {
+            let byte = bit / 8;
+            let word = le_bytes(input.get(byte..(byte + 8).min(input.len())).unwrap_or(&[]));
+            let value = ((word >> (bit % 8)) & ((1u64 << count) - 1)) as i32;
+            if consume {
+                bit += count as usize;
+            }
+            value
+        };
+
+        let accuracy = read(4, true) as u32 + 5;
+        if accuracy > max_accuracy {
+            return Err(CorruptData);
+        }
+        let mut remaining = (1i32 << accuracy) + 1;
+        let mut threshold = 1i32 << accuracy;
+        let mut bits = accuracy + 1;
+        let mut counts = Vec::new();
+        while remaining > 1 {
+            if counts.len() > max_symbol {
+                return Err(CorruptData);
+            }
+            // Small values take one bit less
+            let max = 2 * threshold - 1 - remaining;
+            let low = read(bits - 1, false);
+            let value = if low < max {
+                read(bits - 1, true)
+            } else {
+                let value = read(bits, true);
+                if value >= threshold {
+                    value - max
+                } else {
+                    value
+                }
+            };
+            let count = value - 1;
+            remaining -= count.abs();
+            counts.push(count as i16);
+            if count == 0 {
+                // Runs of zero counts follow, 2 bits at a time
+                loop {
+                    let repeat = read(2, true);
+                    counts.extend(core::iter::repeat_n(0, repeat as usize));
+                    if repeat != 3 {
+                        break;
+                    }
+                }
+            }
+            while remaining < threshold && bits > 1 {
+                bits -= 1;
+                threshold >>= 1;
+            }
+        }
+        let used = bit.div_ceil(8);
+        if remaining != 1 || counts.len() > max_symbol + 1 || used > input.len() {
+            return Err(CorruptData);
+        }
+        Ok((Self::new(&counts, accuracy)?, used))
+    }
+}
+
+/// A decoder's position in an FSE table
+struct FseState<'t> {
+    table: &'t FseTable,
+    state: usize,
+}
+
+impl<'t> FseState<'t> {
+    fn new(table: &'t FseTable, bits: &mut ReverseBits) -> Self {
+        Self { table, state: bits.read(table.accuracy) as usize }
+    }
+
+    fn symbol(&self) -> u8 {
+        self.table.entries[self.state].symbol
+    }
+
+    fn update(&mut self, bits: &mut ReverseBits) {
+        let entry = self.table.entries[self.state];
+        self.state = entry.baseline as usize + bits.read(entry.bits as u32) as usize;
+    }
+}
+
+/// Huffman decoding table, indexed by the next `max_bits` bits
+#[derive(Clone)]
+struct HuffmanTable {
+    max_bits: u32,
+    /// Symbol and code length
+    entries: Vec<(u8, u8)>,
+}
+
+impl HuffmanTable {
+    /// Reads a table description, returning the table and the bytes read
+    fn read(input: &[u8]) -> Result<(Self, usize), CorruptData> {
+        let header = *input.first().ok_or(CorruptData)? as usize;
+        let mut weights = Vec::new();
+        let used = if header < 128 {
+            // Weights coded by two interleaved FSE states
+            let data = input.get(1..1 + header).ok_or(CorruptData)?;
+            let (table, table_len) = FseTable::read(data, 12, MAX_WEIGHT_ACCURACY)?;
+            let mut bits = ReverseBits::new(&data[table_len..])?;
+            let mut states = [FseState::new(&table, &mut bits), FseState::new(&table, &mut bits)];
+            let mut turn = 0;
+            loop {
+                if weights.len() >= 255 {
+                    return Err(CorruptData);
+                }
+                weights.push(states[turn].symbol());
+                states[turn].update(&mut bits);
+                if bits.overflowed() {
+                    weights.push(states[1 - turn].symbol());
+                    break;
+                }
+                turn = 1 - turn;
+            }
+            1 + header
+        } else {
+            // Weights sent directly, 4 bits each
+            let count = header - 127;
+            let data = input.get(1..1 + count.div_ceil(2)).ok_or(CorruptData)?;
+            weights.extend((0..count).map(|i| if i % 2 == 0 { data[i / 2] >> 4 } else { data[i / 2] & 0x0F }));
+            1 + data.len()
+        };
+
+        // The last weight makes the total a power of two
+        let mut total = 0u32;
+        for &weight in &weights {
+            if weight as u32 > MAX_HUFFMAN_BITS {
+                return Err(CorruptData);
+            }
+            total += (1 << weight) >> 1;
+        }
+        if total == 0 {
+            return Err(CorruptData);
+        }
+        let max_bits = 32 - total.leading_zeros();
+        let rest = (1 << max_bits) - total;
+        if max_bits > MAX_HUFFMAN_BITS || !rest.is_power_of_two() {
+            return Err(CorruptData);
+        }
+        weights.push(rest.trailing_zeros() as u8 + 1);
+        if weights.len() > 256 {
+            return Err(CorruptData);
+        }
+
+        // Codes of each weight take consecutive ranges, lightest first
+        let mut starts = [0usize; MAX_HUFFMAN_BITS as usize + 2];
+        let mut next = 0;
+        for (weight, start) in starts.iter_mut().enumerate().skip(1) {
+            *start = next;
+            next += weights.iter().filter(|&&w| w as usize == weight).count() << (weight - 1);
+        }
+        let mut entries = vec![(0u8, 0u8); 1 << max_bits];
+        for (symbol, &weight) in weights.iter().enumerate() {
+            if weight == 0 {
+                continue;
+            }
+            let len = (1usize << weight) >> 1;
+            let start = starts[weight as usize];
+            entries[start..start + len].fill((symbol as u8, (max_bits + 1 - weight as u32) as u8));
+            starts[weight as usize] += len;
+        }
+        Ok((Self { max_bits, entries }, used))
+    }
+
+    /// Decodes `count` symbols from one stream
+    fn decode(&self, stream: &[u8], count: usize, out: &mut Vec<u8>) -> Result<(), CorruptData> {
+        let mut bits = ReverseBits::new(stream)?;
+        for _ in 0..count {
+            let (symbol, len) = self.entries[bits.peek(self.max_bits) as usize];
+            bits.consume(len as u32);
+            out.push(symbol);
+        }
+        if !bits.finished() {
+            return Err(CorruptData);
+        }
+        Ok(())
+    }
+}
+
+/// A sequence table: its mode and the table it reads, if any
+fn sequence_table(
+    mode: u8,
+    input: &[u8],
+    default: &[i16],
+    default_accuracy: u32,
+    max_symbol: usize,
+    max_accuracy: u32,
+    previous: &mut Option<FseTable>,
+) -> Result<usize, CorruptData> {
+    let used = match mode {
+        MODE_PREDEFINED => {
+            *previous = Some(FseTable::new(default, default_accuracy)?);
+            0
+        }
+        MODE_RLE => {
+            let symbol = *input.first().ok_or(CorruptData)?;
+            if symbol as usize > max_symbol {
+                return Err(CorruptData);
+            }
+            *previous = Some(FseTable::rle(symbol));
+            1
+        }
+        MODE_FSE => {
+            let (table, used) = FseTable::read(input, max_symbol, max_accuracy)?;
+            *previous = Some(table);
+            used
+        }
+        // Repeat the previous block's table
+        _ => {
+            previous.as_ref().ok_or(CorruptData)?;
+            0
+        }
+    };
+    Ok(used)
+}
+
+/// Decoding state carried from block to block of a frame
+struct Frame {
+    huffman: Option<HuffmanTable>,
+    ll: Option<FseTable>,
+    of: Option<FseTable>,
+    ml: Option<FseTable>,
+    /// Recent offsets, most recent first
+    repeats: [usize; 3],
+}
+
+impl Frame {
+    fn new() -> Self {
+        Self { huffman: None, ll: None, of: None, ml: None, repeats: [1, 4, 8] }
+    }
+
+    /// Decodes the literals section, returning the literals and the bytes
+    /// read
+    fn literals(&mut self, input: &[u8]) -> Result<(Vec<u8>, usize), CorruptData> {
+        let b0 = *input.first().ok_or(CorruptData)?;
+        let kind = b0 & 3;
+        let format = (b0 >> 2) & 3;
+
+        if kind == LITERALS_RAW || kind == LITERALS_RLE {
+            let (header, size) = match format {
+                0 | 2 => (1, (b0 >> 3) as usize),
+                1 => (2, (le_bytes(input.get(..2).ok_or(CorruptData)?) >> 4) as usize),
+                _ => (3, (le_bytes(input.get(..3).ok_or(CorruptData)?) >> 4) as usize),
+            };
+            if size > MAX_BLOCK {
+                return Err(CorruptData);
+            }
+            if kind == LITERALS_RAW {
+                let data = input.get(header..header + size).ok_or(CorruptData)?;
+                return Ok((data.to_vec(), header + size));
+            }
+            return Ok((vec![*input.get(header).ok_or(CorruptData)?; size], header + 1));
+        }
+
+        let (header, streams, size_bits) = match format {
+            0 => (3, 1, 10),
+            1 => (3, 4, 10),
+            2 => (4, 4, 14),
+            _ => (5, 4, 18),
+        };
+        let fields = le_bytes(input.get(..header).ok_or(CorruptData)?) >> 4;
+        let regenerated = (fields & ((1 << size_bits) - 1)) as usize;
+        let compressed = ((fields >> size_bits) & ((1 << size_bits) - 1)) as usize;
+        if regenerated > MAX_BLOCK {
+            return Err(CorruptData);
+        }
+        let mut data = input.get(header..header + compressed).ok_or(CorruptData)?;
+
+        if kind == LITERALS_COMPRESSED {
+            let (table, used) = HuffmanTable::read(data)?;
+            self.huffman = Some(table);
+            data = &data[used..];
+        }
+        let table = self.huffman.as_ref().ok_or(CorruptData)?;
+
+        let mut out = Vec::with_capacity(regenerated);
+        if streams == 1 {
+            table.decode(data, regenerated, &mut out)?;
+        } else {
+            // A jump table gives the sizes of the first three streams
+            let jump = data.get(..6).ok_or(CorruptData)?;
+            let sizes = [0, 2, 4].map(|i| u16::from_le_bytes([jump[i], jump[i + 1]]) as usize);
+            let mut rest = &data[6..];
+            // The first three streams decode a quarter each, rounded up
+            let per_stream = regenerated.div_ceil(4);
+            let last = regenerated.checked_sub(3 * per_stream).ok_or(CorruptData)?;
+            for size in sizes {
+                table.decode(rest.get(..size).ok_or(CorruptData)?, per_stream, &mut out)?;
+                rest = &rest[size..];
+            }
+            table.decode(rest, last, &mut out)?;
+        }
+        Ok((out, header + compressed))
+    }
+
+    /// Decodes a compressed block onto `out`
+    fn block(&mut self, input: &[u8], out: &mut Vec<u8>, limit: usize) -> Result<(), CorruptData> {
+        let (literals, used) = self.literals(input)?;
+        let input = &input[used..];
+
+        let b0 = *input.first().ok_or(CorruptData)? as usize;
+        let (count, used) = match b0 {
+            0 => (0, 1),
+            1..=127 => (b0, 1),
+            128..=254 => (((b0 - 128) << 8) + *input.get(1).ok_or(CorruptData)? as usize, 2),
+            _ => (le_bytes(input.get(1..3).ok_or(CorruptData)?) as usize + 0x7F00, 3),
+        };
+        if count == 0 {
+            if out.len() + literals.len() > limit {
+                return Err(CorruptData);
+            }
+            out.extend_from_slice(&literals);
+            return Ok(());
+        }
+
+        let modes = *input.get(used).ok_or(CorruptData)?;
+        if modes & 3 != 0 {
+            return Err(CorruptData);
+        }
+        let mut pos = used + 1;
+        let rest = input.get(pos..).ok_or(CorruptData)?;
+        pos += sequence_table(modes >> 6, rest, &LL_DEFAULT, 6, MAX_LL, MAX_LL_ACCURACY, &mut self.ll)?;
+        let rest = input.get(pos..).ok_or(CorruptData)?;
+        pos += sequence_table((modes >> 4) & 3, rest, &OF_DEFAULT, 5, MAX_OF, MAX_OF_ACCURACY, &mut self.of)?;
+        let rest = input.get(pos..).ok_or(CorruptData)?;
+        pos += sequence_table((modes >> 2) & 3, rest, &ML_DEFAULT, 6, MAX_ML, MAX_ML_ACCURACY, &mut self.ml)?;
+
+        let (ll_table, of_table, ml_table) = match (&self.ll, &self.of, &self.ml) {
+            (Some(ll), Some(of), Some(ml)) => (ll, of, ml),
+            _ => return Err(CorruptData),
+        };
+        let mut bits = ReverseBits::new(input.get(pos..).ok_or(CorruptData)?)?;
+        let mut ll_state = FseState::new(ll_table, &mut bits);
+        let mut of_state = FseState::new(of_table, &mut bits);
+        let mut ml_state = FseState::new(ml_table, &mut bits);
+
+        let mut literal_pos = 0;
+        for i in 0..count {
+            let of_code = of_state.symbol() as u32;
+            let ll_code = ll_state.symbol() as usize;
+            let ml_code = ml_state.symbol() as usize;
+            if of_code as usize > MAX_OF || ll_code > MAX_LL || ml_code > MAX_ML {
+                return Err(CorruptData);
+            }
+
+            let offset_value = (1usize << of_code) + bits.read(of_code) as usize;
+            let match_len = match ml_code {
+                0..=31 => ml_code + 3,
+                _ => {
+                    let (base, extra) = ML_BASE[ml_code - 32];
+                    base as usize + bits.read(extra) as usize
+                }
+            };
+            let literal_len = match ll_code {
+                0..=15 => ll_code,
+                _ => {
+                    let (base, extra) = LL_BASE[ll_code - 16];
+                    base as usize + bits.read(extra) as usize
+                }
+            };
+            if i + 1 < count {
+                ll_state.update(&mut bits);
+                ml_state.update(&mut bits);
+                of_state.update(&mut bits);
+            }
+
+            // Offsets 1 to 3 pick a recent offset, shifted by one after
+            // an empty run of literals
+            let offset = if offset_value > 3 {
+                let offset = offset_value - 3;
+                self.repeats = [offset, self.repeats[0], self.repeats[1]];
+                offset
+            } else {
+                let index = offset_value - 1 + (literal_len == 0) as usize;
+                match index {
+                    0 => self.repeats[0],
+                    1 => {
+                        let offset = self.repeats[1];
+                        self.repeats.swap(0, 1);
+                        offset
+                    }
+                    _ => {
+                        let offset = if index == 3 {
+                            self.repeats[0].checked_sub(1).ok_or(CorruptData)?
+                        } else {
+                            self.repeats[2]
+                        };
+                        self.repeats = [offset, self.repeats[0], self.repeats[1]];
+                        offset
+                    }
+                }
+            };
+
+            let run = literals.get(literal_pos..literal_pos + literal_len).ok_or(CorruptData)?;
+            if out.len() + literal_len + match_len > limit {
+                return Err(CorruptData);
+            }
+            out.extend_from_slice(run);
+            literal_pos += literal_len;
+            if offset == 0 || offset > out.len() {
+                return Err(CorruptData);
+            }
+            // Matches can overlap what they produce
+            let start = out.len() - offset;
+            for i in 0..match_len {
+                out.push(out[start + i]);
+            }
+        }
+        if !bits.finished() {
+            return Err(CorruptData);
+        }
+
+        let run = &literals[literal_pos..];
+        if out.len() + run.len() > limit {
+            return Err(CorruptData);
+        }
+        out.extend_from_slice(run);
+        Ok(())
+    }
+}
+
+/// Decodes one frame onto `out`, returning the bytes read
+fn decode_frame(input: &[u8], out: &mut Vec<u8>, limit: usize) -> Result<usize, CorruptData> {
+    let descriptor = *input.get(4).ok_or(CorruptData)?;
+    let size_flag = descriptor >> 6;
+    let single_segment = descriptor & 0x20 != 0;
+    let checksum = descriptor & 0x04 != 0;
+    if descriptor & 0x08 != 0 {
+        return Err(CorruptData);
+    }
+    let dict_len = [0, 1, 2, 4][(descriptor & 3) as usize];
+    let size_len = match size_flag {
+        0 => single_segment as usize,
+        1 => 2,
+        2 => 4,
+        _ => 8,
+    };
+    let mut pos = 5 + !single_segment as usize;
+    let dict_id = le_bytes(input.get(pos..pos + dict_len).ok_or(CorruptData)?);
+    if dict_id != 0 {
+        return Err(CorruptData);
+    }
+    pos += dict_len;
+    let mut content_size = le_bytes(input.get(pos..pos + size_len).ok_or(CorruptData)?);
+    if size_len == 2 {
+        content_size += 256;
+    }
+    pos += size_len;
+
+    let start = out.len();
+    let mut frame = Frame::new();
+    loop {
+        let header = le_bytes(input.get(pos..pos + 3).ok_or(CorruptData)?) as u32;
+        pos += 3;
+        let last = header & 1 != 0;
+        let size = (header >> 3) as usize;
+        match (header >> 1) & 3 {
+            BLOCK_RAW => {
+                let data = input.get(pos..pos + size).ok_or(CorruptData)?;
+                if out.len() + size > limit {
+                    return Err(CorruptData);
+                }
+                out.extend_from_slice(data);
+                pos += size;
+            }
+            BLOCK_RLE => {
+                let byte = *input.get(pos).ok_or(CorruptData)?;
+                if size > MAX_BLOCK || out.len() + size > limit {
+                    return Err(CorruptData);
+                }
+                out.resize(out.len() + size, byte);
+                pos += 1;
+            }
+            BLOCK_COMPRESSED => {
+                if size > MAX_BLOCK {
+                    return Err(CorruptData);
+                }
+                frame.block(input.get(pos..pos + size).ok_or(CorruptData)?, out, limit)?;
+                pos += size;
+            }
+            _ => return Err(CorruptData),
+        }
+        if last {
+            break;
+        }
+    }
+
+    if size_len > 0 && (out.len() - start) as u64 != content_size {
+        return Err(CorruptData);
+    }
+    if checksum {
+        let stored = input.get(pos..pos + 4).ok_or(CorruptData)?;
+        if le_bytes(stored) as u32 != xxh64(&out[start..]) as u32 {
+            return Err(CorruptData);
+        }
+        pos += 4;
+    }
+    Ok(pos)
+}
+
+/// Decompresses zstd frames of at most `limit` bytes in all
+pub fn decompress(input: &[u8], limit: usize) -> Result<Vec<u8>, CorruptData> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+    while pos < input.len() {
+        let rest = &input[pos..];
+        let magic = le_bytes(rest.get(..4).ok_or(CorruptData)?) as u32;
+        if magic & !0x0F == SKIPPABLE_MAGIC {
+            let len = le_bytes(rest.get(4..8).ok_or(CorruptData)?) as usize;
+            pos += 8 + len;
+            if pos > input.len() {
+                return Err(CorruptData);
+            }
+        } else if magic == MAGIC {
+            pos += decode_frame(rest, &mut out, limit)?;
+        } else {
+            return Err(CorruptData);
+        }
+    }
+    if pos == 0 {
+        return Err(CorruptData);
+    }
+    Ok(out)
+}
+
+const PRIME64_1: u64 = 0x9E37_79B1_85EB_CA87;
+const PRIME64_2: u64 = 0xC2B2_AE3D_27D4_EB4F;
+const PRIME64_3: u64 = 0x1656_67B1_9E37_79F9;
+const PRIME64_4: u64 = 0x85EB_CA77_C2B2_AE63;
+const PRIME64_5: u64 = 0x27D4_EB2F_1656_67C5;
+
+fn xxh64_round(acc: u64, lane: u64) -> u64 {
+    acc.wrapping_add(lane.wrapping_mul(PRIME64_2)).rotate_left(31).wrapping_mul(PRIME64_1)
+}
+
+fn xxh64_merge(acc: u64, value: u64) -> u64 {
+    (acc ^ xxh64_round(0, value)).wrapping_mul(PRIME64_1).wrapping_add(PRIME64_4)
+}
+
+/// XXH64 with seed 0, whose low 32 bits are a frame's checksum
+fn xxh64(data: &[u8]) -> u64 {
+    let mut chunks = data.chunks_exact(32);
+    let mut hash = if data.len() >= 32 {
+        let mut v = [PRIME64_1.wrapping_add(PRIME64_2), PRIME64_2, 0, 0u64.wrapping_sub(PRIME64_1)];
+        for chunk in &mut chunks {
+            for (i, lane) in v.iter_mut().enumerate() {
+                *lane = xxh64_round(*lane, le_bytes(&chunk[i * 8..i * 8 + 8]));
+            }
+        }
+        let hash = v[0]
+            .rotate_left(1)
+            .wrapping_add(v[1].rotate_left(7))
+            .wrapping_add(v[2].rotate_left(12))
+            .wrapping_add(v[3].rotate_left(18));
+        v.iter().fold(hash, |hash, &lane| xxh64_merge(hash, lane))
+    } else {
+        PRIME64_5
+    };
+    hash = hash.wrapping_add(data.len() as u64);
+
+    let mut tail = chunks.remainder();
+    while tail.len() >= 8 {
+        hash ^= xxh64_round(0, le_bytes(&tail[..8]));
+        hash = hash.rotate_left(27).wrapping_mul(PRIME64_1).wrapping_add(PRIME64_4);
+        tail = &tail[8..];
+    }
+    if tail.len() >= 4 {
+        hash ^= le_bytes(&tail[..4]).wrapping_mul(PRIME64_1);
+        hash = hash.rotate_left(23).wrapping_mul(PRIME64_2).wrapping_add(PRIME64_3);
+        tail = &tail[4..];
+    }
+    for &byte in tail {
+        hash ^= (byte as u64).wrapping_mul(PRIME64_5);
+        hash = hash.rotate_left(11).wrapping_mul(PRIME64_1);
+    }
+
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(PRIME64_2);
+    hash ^= hash >> 29;
+    hash = hash.wrapping_mul(PRIME64_3);
+    hash ^ (hash >> 32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::log_text;
+
+    /// `zstd -19` of `log_text(10)`: Huffman-coded literals and FSE-coded
+    /// sequences, with a checksum
+    const LOG_FRAME: [u8; 168] = [
+        0x28, 0xB5, 0x2F, 0xFD, 0x64, 0xF8, 0x01, 0xD5, 0x04, 0x00, 0x62, 0x08, 0x1A, 0x17, 0x80, 0xAB, 0x03, 0x68,
+        0xDA, 0x18, 0xBF, 0x2C, 0xC2, 0xC5, 0x2B, 0x03, 0x03, 0x73, 0xCB, 0x6D, 0x49, 0xA1, 0x19, 0x6D, 0x53, 0x59,
+        0xC1, 0xDA, 0xB6, 0x9B, 0xBB, 0xB3, 0x65, 0xA6, 0x5A, 0xAF, 0x45, 0xAB, 0xA5, 0x56, 0xAB, 0x54, 0x77, 0xA7,
+        0x4D, 0x29, 0x31, 0xFE, 0xFD, 0x67, 0xCF, 0xBE, 0xEE, 0xCC, 0xFC, 0xCF, 0xAA, 0xB7, 0xFB, 0x17, 0x6A, 0x4E,
+        0xBC, 0xCC, 0xCB, 0x01, 0xD5, 0xD0, 0xEB, 0x23, 0x93, 0xB8, 0x3C, 0x1A, 0x24, 0x70, 0x36, 0xF0, 0x3D, 0x44,
+        0x17, 0xB1, 0x32, 0xD2, 0x51, 0x14, 0x69, 0x94, 0xCB, 0x7C, 0x30, 0x46, 0xF9, 0xD4, 0x35, 0x6F, 0x3A, 0x37,
+        0x61, 0x54, 0x2D, 0x04, 0x62, 0xD0, 0x03, 0x19, 0x01, 0x20, 0xA8, 0x11, 0x30, 0x9D, 0xBB, 0xFD, 0xB7, 0x1A,
+        0xE0, 0x85, 0xE9, 0x11, 0x34, 0x04, 0x1C, 0x23, 0x34, 0x85, 0x2D, 0xC1, 0x11, 0xF6, 0x03, 0x8F, 0x71, 0x42,
+        0xC0, 0xC1, 0x88, 0x4D, 0x67, 0x11, 0x31, 0x32, 0x82, 0x3C, 0xDC, 0x29, 0xE2, 0x47, 0xB2, 0xFF, 0xAE, 0x06,
+        0xC0, 0x08, 0xBB, 0xC2, 0x37, 0x03,
+    ];
+
+    #[test]
+    fn test_xxh64() {
+        assert_eq!(xxh64(b""), 0xEF46_DB37_51D8_E999);
+    }
+
+    #[test]
+    fn test_frames() {
+        assert_eq!(decompress(&LOG_FRAME, 1 << 20).unwrap(), log_text(10));
+
+        // Raw literals, then a match with the predefined tables
+        let hello = [
+            0x28, 0xB5, 0x2F, 0xFD, 0x24, 0x18, 0x6D, 0x00, 0x00, 0x38, 0x68, 0x65, 0x6C, 0x6C, 0x6F, 0x20, 0x0A, 0x01,
+            0x00, 0x99, 0x4B, 0x11, 0xA8, 0x7C, 0x2E, 0xA8,
+        ];
+        assert_eq!(decompress(&hello, 100).unwrap(), b"hello hello hello hello\n");
+
+        // A raw block and an RLE block, after a skippable frame
+        let blocks = [
+            0x50, 0x2A, 0x4D, 0x18, 0x02, 0x00, 0x00, 0x00, 0xAA, 0xBB, // skippable
+            0x28, 0xB5, 0x2F, 0xFD, 0x20, 0x08, // header, 8 bytes
+            0x18, 0x00, 0x00, b'a', b'b', b'c', // raw, 3 bytes
+            0x2B, 0x00, 0x00, b'z', // RLE, 5 bytes, last
+        ];
+        assert_eq!(decompress(&blocks, 8).unwrap(), b"abczzzzz");
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let text = log_text(10);
+        assert_eq!(decompress(&LOG_FRAME, text.len() - 1), Err(CorruptData));
+        assert_eq!(decompress(&LOG_FRAME[..100], text.len()), Err(CorruptData));
+        // Checksum
+        let mut bad = LOG_FRAME;
+        bad[167] ^= 1;
+        assert_eq!(decompress(&bad, text.len()), Err(CorruptData));
+        // Sequence data
+        let mut bad = LOG_FRAME;
+        bad[120] ^= 0x10;
+        assert_eq!(decompress(&bad, text.len()), Err(CorruptData));
+        assert_eq!(decompress(b"not zstd", 100), Err(CorruptData));
+        assert_eq!(decompress(&[], 100), Err(CorruptData));
+    }
+}
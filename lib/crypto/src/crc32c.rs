@@ -0,0 +1,38 @@
+//! CRC-32C Implementation
+//!
+//! Castagnoli CRC used for on-disk checksums by SplaxFS, ext4 and jbd2; the
+//! kernel re-exports it as `crypto::hash::crc32c`. There is no pre- or
+//! post-inversion, matching the Linux `crc32c()` those formats chain.
+
+/// Lookup table, reflected polynomial 0x82F63B78.
+const TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0x82F6_3B78 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Continues a CRC-32C over `data`; start from `!0` or a seed.
+pub fn crc32c(crc: u32, data: &[u8]) -> u32 {
+    data.iter().fold(crc, |crc, &byte| TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32c_check_value() {
+        assert_eq!(!crc32c(!0, b"123456789"), 0xE306_9283);
+        assert_eq!(crc32c(crc32c(!0, b"1234"), b"56789"), crc32c(!0, b"123456789"));
+    }
+}
@@ -0,0 +1,128 @@
+//! AES-256-CBC with Ciphertext Stealing
+//!
+//! CBC-CS3 (NIST SP 800-38A addendum), the variant Kerberos and Linux
+//! fscrypt use for filenames: the last two ciphertext blocks are always
+//! swapped and the final one truncated, so the ciphertext is exactly as
+//! long as the plaintext. Input must be at least one block long; a single
+//! block is plain CBC.
+
+use crate::aes::{Aes256, BLOCK_SIZE, KEY_SIZE};
+
+fn xor(block: &mut [u8; BLOCK_SIZE], other: &[u8]) {
+    for (byte, o) in block.iter_mut().zip(other) {
+        *byte ^= o;
+    }
+}
+
+/// AES-256-CBC-CS3 with an expanded key.
+pub struct Aes256Cts {
+    aes: Aes256,
+}
+
+impl Aes256Cts {
+    /// Expand a 256-bit key.
+    pub fn new(key: &[u8; KEY_SIZE]) -> Self {
+        Self { aes: Aes256::new(key) }
+    }
+
+    /// Encrypt in place.
+    ///
+    /// # Panics
+    ///
+    /// If `data` is shorter than 16 bytes.
+    pub fn encrypt(&self, iv: &[u8; BLOCK_SIZE], data: &mut [u8]) {
+        assert!(data.len() >= BLOCK_SIZE, "CTS input shorter than a block");
+        let blocks = data.len().div_ceil(BLOCK_SIZE);
+        let mut chain = *iv;
+        for chunk in data[..(blocks - 1).max(1) * BLOCK_SIZE].chunks_exact_mut(BLOCK_SIZE) {
+            xor(&mut chain, chunk);
+            self.aes.encrypt_block(&mut chain);
+            chunk.copy_from_slice(&chain);
+        }
+        if blocks == 1 {
+            return;
+        }
+
+        // `chain` is the ciphertext of the next-to-last block; the last
+        // block, zero padded, is chained on it and takes its place
+        let start = (blocks - 2) * BLOCK_SIZE;
+        let last = data.len() - start - BLOCK_SIZE;
+        let mut block = chain;
+        xor(&mut block, &data[start + BLOCK_SIZE..]);
+        self.aes.encrypt_block(&mut block);
+        data[start..start + BLOCK_SIZE].copy_from_slice(&block);
+        data[start + BLOCK_SIZE..].copy_from_slice(&chain[..last]);
+    }
+
+    /// Decrypt in place.
+    ///
+    /// # Panics
+    ///
+    /// If `data` is shorter than 16 bytes.
+    pub fn decrypt(&self, iv: &[u8; BLOCK_SIZE], data: &mut [u8]) {
+        assert!(data.len() >= BLOCK_SIZE, "CTS input shorter than a block");
+        let blocks = data.len().div_ceil(BLOCK_SIZE);
+        let mut chain = *iv;
+        for chunk in data[..blocks.saturating_sub(2) * BLOCK_SIZE].chunks_exact_mut(BLOCK_SIZE) {
+            let cipher: [u8; BLOCK_SIZE] = (&*chunk).try_into().unwrap();
+            let mut block = cipher;
+            self.aes.decrypt_block(&mut block);
+            xor(&mut block, &chain);
+            chunk.copy_from_slice(&block);
+            chain = cipher;
+        }
+        if blocks == 1 {
+            let mut block: [u8; BLOCK_SIZE] = (&*data).try_into().unwrap();
+            self.aes.decrypt_block(&mut block);
+            xor(&mut block, &chain);
+            data.copy_from_slice(&block);
+            return;
+        }
+
+        // The full block decrypts to the padded last block chained on the
+        // next-to-last ciphertext, whose missing tail it gives back
+        let start = (blocks - 2) * BLOCK_SIZE;
+        let last = data.len() - start - BLOCK_SIZE;
+        let mut padded: [u8; BLOCK_SIZE] = data[start..start + BLOCK_SIZE].try_into().unwrap();
+        self.aes.decrypt_block(&mut padded);
+        let mut stolen = padded;
+        stolen[..last].copy_from_slice(&data[start + BLOCK_SIZE..]);
+        xor(&mut padded, &stolen);
+        let mut block = stolen;
+        self.aes.decrypt_block(&mut block);
+        xor(&mut block, &chain);
+        data[start..start + BLOCK_SIZE].copy_from_slice(&block);
+        data[start + BLOCK_SIZE..].copy_from_slice(&padded[..last]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex<const N: usize>(s: &str) -> [u8; N] {
+        core::array::from_fn(|i| u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).unwrap())
+    }
+
+    fn round_trip<const N: usize>(cts: &Aes256Cts, expected: &str) {
+        let plain: [u8; N] = core::array::from_fn(|i| i as u8);
+        let mut data = plain;
+        cts.encrypt(&[0; BLOCK_SIZE], &mut data);
+        assert_eq!(data, hex::<N>(expected));
+        cts.decrypt(&[0; BLOCK_SIZE], &mut data);
+        assert_eq!(data, plain);
+    }
+
+    #[test]
+    fn test_vectors() {
+        let cts = Aes256Cts::new(&core::array::from_fn(|i| 100 + i as u8));
+        round_trip::<16>(&cts, "08d2bd0b8c324259659323c916820567");
+        round_trip::<17>(&cts, "4d28746eedd1f152e41da861824e9a3308");
+        // Whole blocks are swapped too
+        round_trip::<32>(&cts, "045d22fa3f13bb3f12950fb4ae074b7e08d2bd0b8c324259659323c916820567");
+        round_trip::<45>(
+            &cts,
+            "08d2bd0b8c324259659323c916820567d3b89d8eb54b600caf1c32854156ab31045d22fa3f13bb3f12950fb4ae",
+        );
+    }
+}
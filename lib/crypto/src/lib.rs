@@ -8,6 +8,9 @@
 //! - **SHA-256**: Secure hash function, 256-bit output
 //! - **SHA-512**: Secure hash function, 512-bit output
 //! - **HMAC-SHA256**: Message authentication code
+//! - **CRC-32C**: Block checksums
+//! - **AES-256**: Block cipher, with the XTS mode for storage and CBC with
+//!   ciphertext stealing (CS3) for short messages such as filenames
 //!
 //! ## Design
 //!
@@ -26,10 +29,18 @@ use alloc::vec::Vec;
 pub mod sha256;
 pub mod sha512;
 pub mod hmac;
+pub mod crc32c;
+pub mod aes;
+pub mod xts;
+pub mod cts;
 
 pub use sha256::Sha256;
 pub use sha512::Sha512;
 pub use hmac::{HmacSha256, HmacSha512};
+pub use crc32c::crc32c;
+pub use aes::Aes256;
+pub use xts::Aes256Xts;
+pub use cts::Aes256Cts;
 
 /// Hash trait for consistent interface.
 pub trait Hash {
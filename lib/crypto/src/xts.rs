@@ -0,0 +1,143 @@
+//! XTS-AES-256
+//!
+//! IEEE 1619 XTS mode for sector-style encryption: each data unit (a disk
+//! block, say) is encrypted on its own under a 128-bit tweak, usually its
+//! number, so equal plaintext in different units gives different
+//! ciphertext and a unit can be rewritten without touching its
+//! neighbours. A unit whose length is not a multiple of 16 bytes ends in
+//! ciphertext stealing; it must be at least one block long.
+
+use crate::aes::{Aes256, BLOCK_SIZE, KEY_SIZE as AES_KEY_SIZE};
+
+/// Key size in bytes: the data key, then the tweak key.
+pub const KEY_SIZE: usize = 2 * AES_KEY_SIZE;
+
+/// Multiplies a tweak by x in GF(2^128), little-endian as IEEE 1619 has it.
+fn next_tweak(tweak: &mut [u8; BLOCK_SIZE]) {
+    let mut carry = 0;
+    for byte in tweak.iter_mut() {
+        let next = *byte >> 7;
+        *byte = (*byte << 1) | carry;
+        carry = next;
+    }
+    if carry != 0 {
+        tweak[0] ^= 0x87;
+    }
+}
+
+fn xor(block: &mut [u8; BLOCK_SIZE], other: &[u8; BLOCK_SIZE]) {
+    for (byte, o) in block.iter_mut().zip(other) {
+        *byte ^= o;
+    }
+}
+
+/// XTS-AES-256 with expanded keys.
+pub struct Aes256Xts {
+    data: Aes256,
+    tweak: Aes256,
+}
+
+impl Aes256Xts {
+    /// Expand a 512-bit key.
+    pub fn new(key: &[u8; KEY_SIZE]) -> Self {
+        let (data, tweak) = key.split_at(AES_KEY_SIZE);
+        Self {
+            data: Aes256::new(data.try_into().unwrap()),
+            tweak: Aes256::new(tweak.try_into().unwrap()),
+        }
+    }
+
+    /// One block through the data cipher, whitened with a tweak.
+    fn crypt_block(&self, block: &mut [u8; BLOCK_SIZE], tweak: &[u8; BLOCK_SIZE], decrypt: bool) {
+        xor(block, tweak);
+        if decrypt {
+            self.data.decrypt_block(block);
+        } else {
+            self.data.encrypt_block(block);
+        }
+        xor(block, tweak);
+    }
+
+    fn crypt(&self, unit: u128, data: &mut [u8], decrypt: bool) {
+        assert!(data.len() >= BLOCK_SIZE, "XTS data unit shorter than a block");
+        let mut tweak = unit.to_le_bytes();
+        self.tweak.encrypt_block(&mut tweak);
+
+        let partial = data.len() % BLOCK_SIZE;
+        // With a partial block at the end, the last full block is handled
+        // with it
+        let full = data.len() / BLOCK_SIZE - (partial != 0) as usize;
+        for chunk in data[..full * BLOCK_SIZE].chunks_exact_mut(BLOCK_SIZE) {
+            let mut block: [u8; BLOCK_SIZE] = chunk.try_into().unwrap();
+            self.crypt_block(&mut block, &tweak, decrypt);
+            chunk.copy_from_slice(&block);
+            next_tweak(&mut tweak);
+        }
+        if partial == 0 {
+            return;
+        }
+
+        // Ciphertext stealing: the last full block is processed under the
+        // tweak of the partial block when decrypting, and the other way
+        // round when encrypting
+        let mut last_tweak = tweak;
+        next_tweak(&mut last_tweak);
+        let (first, second) = if decrypt { (&last_tweak, &tweak) } else { (&tweak, &last_tweak) };
+        let (head, tail) = data[full * BLOCK_SIZE..].split_at_mut(BLOCK_SIZE);
+        let mut block: [u8; BLOCK_SIZE] = (&*head).try_into().unwrap();
+        self.crypt_block(&mut block, first, decrypt);
+        let mut stolen = block;
+        stolen[..partial].copy_from_slice(tail);
+        tail.copy_from_slice(&block[..partial]);
+        self.crypt_block(&mut stolen, second, decrypt);
+        head.copy_from_slice(&stolen);
+    }
+
+    /// Encrypt a data unit in place.
+    ///
+    /// # Panics
+    ///
+    /// If `data` is shorter than 16 bytes.
+    pub fn encrypt(&self, unit: u128, data: &mut [u8]) {
+        self.crypt(unit, data, false);
+    }
+
+    /// Decrypt a data unit in place.
+    ///
+    /// # Panics
+    ///
+    /// If `data` is shorter than 16 bytes.
+    pub fn decrypt(&self, unit: u128, data: &mut [u8]) {
+        self.crypt(unit, data, true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex<const N: usize>(s: &str) -> [u8; N] {
+        core::array::from_fn(|i| u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).unwrap())
+    }
+
+    #[test]
+    fn test_vectors() {
+        let xts = Aes256Xts::new(&core::array::from_fn(|i| i as u8));
+
+        let plain: [u8; 4096] = core::array::from_fn(|i| (i * 7 + 3) as u8);
+        let mut data = plain;
+        xts.encrypt(5, &mut data);
+        assert_eq!(data[..16], hex::<16>("f89e38302834fc8d2523b7497fed0181"));
+        assert_eq!(data[4080..], hex::<16>("c5c831e24a8a6a10c22a4094011a23a7"));
+        xts.decrypt(5, &mut data);
+        assert_eq!(data, plain);
+
+        // Ciphertext stealing
+        let plain: [u8; 37] = core::array::from_fn(|i| i as u8);
+        let mut data = plain;
+        xts.encrypt(0x01_2345_6789, &mut data);
+        assert_eq!(data, hex("dbe0e0e854a3505aa1430b2304c01c5c5df5af1fcd62104ecb02f7248d33dd40b71dbac84f"));
+        xts.decrypt(0x01_2345_6789, &mut data);
+        assert_eq!(data, plain);
+    }
+}
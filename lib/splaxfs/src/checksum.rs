@@ -0,0 +1,132 @@
+//! Block checksums
+//!
+//! Every block the file system writes outside the journal has a CRC-32C in
+//! the checksum table, which follows the reference count table. The CRC is
+//! seeded with the block number, so a block written to or read from the
+//! wrong place does not verify either. Each table block ends with the
+//! checksum of the table block itself:
+//!
+//! ```text
+//! +-----------+-----------+-----+----------------+--------------------+
+//! | block 0   | block 1   | ... | block 1022     | this table block   |
+//! | u32       | u32       |     | u32            | u32                |
+//! +-----------+-----------+-----+----------------+--------------------+
+//! ```
+//!
+//! Entries of free blocks, of the superblock (which carries its own
+//! checksum) and of the journal (whose transactions are checksummed)
+//! mean nothing.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use splax_crypto::crc32c;
+
+use crate::layout::*;
+
+/// Checksum of `data` stored at block `block_num`
+pub fn block_checksum(block_num: u32, data: &[u8]) -> u32 {
+    crc32c(crc32c(!0, &block_num.to_le_bytes()), data)
+}
+
+/// Block checksum table, all table blocks back to back
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChecksumTable {
+    data: Vec<u8>,
+}
+
+impl ChecksumTable {
+    /// Table of a volume, every entry zero
+    pub fn new(sb: &Superblock) -> Self {
+        Self { data: vec![0u8; sb.csum_blocks() as usize * BLOCK_SIZE] }
+    }
+
+    /// Table read from the device
+    pub fn from_bytes(data: Vec<u8>) -> Self {
+        Self { data }
+    }
+
+    /// Index of the table block holding a block's entry
+    pub fn index_of(block_num: u32) -> u32 {
+        block_num / CSUMS_PER_BLOCK
+    }
+
+    fn offset(block_num: u32) -> usize {
+        Self::index_of(block_num) as usize * BLOCK_SIZE + (block_num % CSUMS_PER_BLOCK) as usize * 4
+    }
+
+    /// Checksum recorded for a block
+    pub fn get(&self, block_num: u32) -> u32 {
+        get_u32(&self.data, Self::offset(block_num))
+    }
+
+    /// Sets the checksum recorded for a block
+    pub fn set(&mut self, block_num: u32, csum: u32) {
+        let offset = Self::offset(block_num);
+        self.data[offset..offset + 4].copy_from_slice(&csum.to_le_bytes());
+    }
+
+    /// Records the checksum of a block's new content
+    pub fn record(&mut self, block_num: u32, data: &[u8]) {
+        self.set(block_num, block_checksum(block_num, data));
+    }
+
+    /// Checks a block's content against its entry
+    pub fn verify(&self, block_num: u32, data: &[u8]) -> Result<(), SplaxFsError> {
+        if block_checksum(block_num, data) != self.get(block_num) {
+            return Err(SplaxFsError::ChecksumMismatch);
+        }
+        Ok(())
+    }
+
+    /// Image of table block `index`, sealed with its own checksum; the
+    /// table starts at block `start`
+    pub fn block_image(&self, start: u32, index: u32) -> Vec<u8> {
+        let offset = index as usize * BLOCK_SIZE;
+        let mut image = self.data[offset..offset + BLOCK_SIZE].to_vec();
+        let seal = block_checksum(start + index, &image[..BLOCK_SIZE - 4]);
+        image[BLOCK_SIZE - 4..].copy_from_slice(&seal.to_le_bytes());
+        image
+    }
+
+    /// Indices of the table blocks whose own checksum is wrong
+    pub fn bad_blocks(&self, start: u32) -> Vec<u32> {
+        self.data
+            .chunks(BLOCK_SIZE)
+            .enumerate()
+            .filter(|(i, chunk)| {
+                block_checksum(start + *i as u32, &chunk[..BLOCK_SIZE - 4]) != get_u32(chunk, BLOCK_SIZE - 4)
+            })
+            .map(|(i, _)| i as u32)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table() {
+        let sb = Superblock::new(3000, 256, 10);
+        let mut table = ChecksumTable::new(&sb);
+        let data = [7u8; BLOCK_SIZE];
+        table.record(2000, &data);
+        assert_eq!(table.verify(2000, &data), Ok(()));
+        // Same content at another block, or changed content
+        assert_eq!(table.verify(2001, &data), Err(SplaxFsError::ChecksumMismatch));
+        let mut changed = data;
+        changed[100] ^= 1;
+        assert_eq!(table.verify(2000, &changed), Err(SplaxFsError::ChecksumMismatch));
+        assert_eq!(ChecksumTable::index_of(2000), 1);
+
+        // Sealed images verify where they were sealed for
+        let images: Vec<u8> = (0..sb.csum_blocks()).flat_map(|i| table.block_image(50, i)).collect();
+        let mut loaded = ChecksumTable::from_bytes(images);
+        assert!(loaded.bad_blocks(50).is_empty());
+        assert_eq!(loaded.bad_blocks(51).len(), 3);
+        assert_eq!(loaded.get(2000), table.get(2000));
+        loaded.set(5, 1);
+        assert_eq!(loaded.bad_blocks(50), vec![0]);
+    }
+}
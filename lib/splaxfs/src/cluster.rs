@@ -0,0 +1,143 @@
+//! Compressed clusters
+//!
+//! A file with a compression algorithm writes its data in clusters of
+//! [`DIRECT_CLUSTER_BLOCKS`] file blocks among the direct blocks and
+//! [`CLUSTER_BLOCKS`] behind pointer blocks. Clusters are aligned in the
+//! block map, so the slots of one are always in the inode or in one
+//! pointer block. A
+//! cluster whose data compresses into fewer blocks than it covers is
+//! stored compressed: the stream fills its first slots, and the slots it
+//! saves, up to the end of the data, hold [`COMPRESSED_SLOT`]. Slots past
+//! the end of the data are holes.
+//!
+//! ```text
+//! slots:   | block | block | COMPRESSED_SLOT | COMPRESSED_SLOT |
+//!
+//! +--------------+--------------+-----------+-------+-------------------+
+//! | stream len   | data len     | algorithm | pad   | stream ...        |
+//! | u32          | u32          | u8        | 3     |                   |
+//! +--------------+--------------+-----------+-------+-------------------+
+//! ```
+//!
+//! A cluster without [`COMPRESSED_SLOT`] is plain file blocks, so files
+//! can mix both and changing the algorithm only affects new writes.
+//! Compressed clusters are read and rewritten whole.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use splax_compress::Algorithm;
+
+use crate::layout::*;
+
+/// Bytes before the stream
+const HEADER_SIZE: usize = 12;
+
+/// First file block and block count of the cluster holding file block
+/// `index`
+pub fn span(index: u64) -> (u64, usize) {
+    let (base, blocks) = if index < DIRECT_BLOCKS as u64 {
+        (0, DIRECT_CLUSTER_BLOCKS)
+    } else {
+        (DIRECT_BLOCKS as u64, CLUSTER_BLOCKS)
+    };
+    (index - (index - base) % blocks as u64, blocks)
+}
+
+/// The slot holds a block number
+pub fn is_block(ptr: u32) -> bool {
+    ptr != 0 && ptr != COMPRESSED_SLOT
+}
+
+/// The slots of a cluster describe a compressed cluster
+pub fn is_compressed(slots: &[u32]) -> bool {
+    slots.contains(&COMPRESSED_SLOT)
+}
+
+/// The slots of a cluster are plain blocks and holes, or blocks followed
+/// by [`COMPRESSED_SLOT`] and then holes
+pub fn is_valid(slots: &[u32]) -> bool {
+    if !is_compressed(slots) {
+        return true;
+    }
+    let blocks = slots.iter().take_while(|&&ptr| is_block(ptr)).count();
+    let saved = slots[blocks..].iter().take_while(|&&ptr| ptr == COMPRESSED_SLOT).count();
+    blocks > 0 && slots[blocks + saved..].iter().all(|&ptr| ptr == 0)
+}
+
+/// [`COMPRESSED_SLOT`] entries in a pointer block
+pub fn saved_slots(block: &[u8]) -> u32 {
+    (0..PTRS_PER_BLOCK).filter(|slot| get_u32(block, slot * 4) == COMPRESSED_SLOT).count() as u32
+}
+
+/// Compresses the data of a cluster, up to [`CLUSTER_SIZE`] bytes. Returns
+/// the stream padded to whole blocks, or `None` if it would not save a
+/// block.
+pub fn pack(algorithm: Algorithm, data: &[u8]) -> Option<Vec<u8>> {
+    debug_assert!(data.len() <= CLUSTER_SIZE);
+    let stream = splax_compress::compress(algorithm, data);
+    let blocks = (HEADER_SIZE + stream.len()).div_ceil(BLOCK_SIZE);
+    if blocks >= data.len().div_ceil(BLOCK_SIZE) {
+        return None;
+    }
+    let mut image = vec![0u8; blocks * BLOCK_SIZE];
+    image[0..4].copy_from_slice(&(stream.len() as u32).to_le_bytes());
+    image[4..8].copy_from_slice(&(data.len() as u32).to_le_bytes());
+    image[8] = algorithm as u8;
+    image[HEADER_SIZE..HEADER_SIZE + stream.len()].copy_from_slice(&stream);
+    Some(image)
+}
+
+/// Decompresses the stored blocks of a compressed cluster
+pub fn unpack(image: &[u8]) -> Result<Vec<u8>, SplaxFsError> {
+    if image.len() < HEADER_SIZE {
+        return Err(SplaxFsError::Corrupted);
+    }
+    let stream_len = get_u32(image, 0) as usize;
+    let data_len = get_u32(image, 4) as usize;
+    let algorithm = Algorithm::from_u8(image[8]).ok_or(SplaxFsError::Corrupted)?;
+    let stream = image.get(HEADER_SIZE..HEADER_SIZE + stream_len).ok_or(SplaxFsError::Corrupted)?;
+    if data_len > CLUSTER_SIZE {
+        return Err(SplaxFsError::Corrupted);
+    }
+    splax_compress::decompress(algorithm, stream, data_len).map_err(|_| SplaxFsError::Corrupted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pack() {
+        let text: Vec<u8> = b"Mar 14 12:00:01 splax net: link up\n".iter().copied().cycle().take(CLUSTER_SIZE).collect();
+        for algorithm in [Algorithm::Lz4, Algorithm::Lzh] {
+            let image = pack(algorithm, &text).unwrap();
+            assert_eq!(image.len(), BLOCK_SIZE);
+            assert_eq!(unpack(&image).unwrap(), text);
+            // A cluster of one block cannot save anything
+            assert_eq!(pack(algorithm, &text[..BLOCK_SIZE]), None);
+            assert_eq!(unpack(&pack(algorithm, &text[..5000]).unwrap()).unwrap(), &text[..5000]);
+        }
+
+        let mut image = pack(Algorithm::Lz4, &text).unwrap();
+        image[8] = 9;
+        assert_eq!(unpack(&image), Err(SplaxFsError::Corrupted));
+        image[8] = Algorithm::Lzh as u8;
+        assert_eq!(unpack(&image), Err(SplaxFsError::Corrupted));
+    }
+
+    #[test]
+    fn test_slots() {
+        const S: u32 = COMPRESSED_SLOT;
+        for slots in [[0, 0, 0, 0], [7, 0, 9, 0], [7, S, S, S], [7, 8, S, 0], [7, S, 0, 0]] {
+            assert!(is_valid(&slots), "{:?}", slots);
+        }
+        for slots in [[S, 0, 0, 0], [0, S, 0, 0], [7, S, 8, 0], [7, S, 0, S]] {
+            assert!(!is_valid(&slots), "{:?}", slots);
+        }
+        assert!(!is_block(S) && !is_block(0) && is_block(7));
+        assert_eq!(span(5), (4, DIRECT_CLUSTER_BLOCKS));
+        assert_eq!(span(12), (12, CLUSTER_BLOCKS));
+        assert_eq!(span(12 + 1024 + 17), (12 + 1024 + 16, CLUSTER_BLOCKS));
+    }
+}
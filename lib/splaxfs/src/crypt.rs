@@ -0,0 +1,288 @@
+//! Encryption policies
+//!
+//! A directory can be given an encryption policy naming a master key;
+//! files and directories created under it are encrypted with keys
+//! derived from that key, and carry [`INODE_ENCRYPTED`] and an encryption
+//! context in the [`ENCRYPTION_XATTR`] attribute:
+//!
+//! ```text
+//! +---------+----------+-------+-------+----------------+-------+
+//! | version | contents | names | flags | key identifier | nonce |
+//! |   u8    |    u8    |  u8   |  u8   | 16             | 16    |
+//! +---------+----------+-------+-------+----------------+-------+
+//! ```
+//!
+//! The master key never reaches the disk. The keys used are derived from
+//! it with HKDF-SHA256 (empty salt), the info being [`KDF_LABEL`], a
+//! purpose byte and, for the keys of one inode, its nonce:
+//!
+//! - [`KDF_IDENTIFIER`]: the 16-byte key identifier that names the master
+//!   key in contexts
+//! - [`KDF_CONTENTS`]: the 64-byte AES-256-XTS key of a file's data. Each
+//!   file block is a data unit whose tweak is its index in the file;
+//!   compressed clusters are compressed first and then encrypted block by
+//!   block, by slot
+//! - [`KDF_NAMES`]: the 32-byte AES-256-CBC-CS3 key of a directory's entry
+//!   names. Names are padded with NULs to a multiple of [`NAME_PADDING`]
+//!   bytes, at least one AES block, and encrypted with a zero IV, so equal
+//!   names give equal ciphertext and lookups need not decrypt every entry
+//!
+//! `.` and `..` are stored in the clear. Without the master key, names
+//! are shown as the base64url of their ciphertext ([`encode_name`]), which
+//! can still be looked up and unlinked. The context does not fit among
+//! the inline attributes, so an encrypted inode has an overflow block.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use splax_crypto::aes::BLOCK_SIZE as AES_BLOCK_SIZE;
+use splax_crypto::{Aes256Cts, Aes256Xts};
+
+use crate::layout::*;
+use crate::xattr::Xattrs;
+
+/// Attribute holding the encryption context of an encrypted inode
+pub const ENCRYPTION_XATTR: &str = "system.splax.encryption";
+
+/// Context format version
+pub const CONTEXT_VERSION: u8 = 1;
+
+/// Contents mode: AES-256-XTS
+pub const CONTENTS_AES_256_XTS: u8 = 1;
+
+/// Names mode: AES-256-CBC-CS3
+pub const NAMES_AES_256_CTS: u8 = 1;
+
+/// Bytes in a key identifier
+pub const KEY_IDENTIFIER_SIZE: usize = 16;
+
+/// Bytes in an inode nonce
+pub const NONCE_SIZE: usize = 16;
+
+/// Bytes in a context
+pub const CONTEXT_SIZE: usize = 4 + KEY_IDENTIFIER_SIZE + NONCE_SIZE;
+
+/// Bytes in a contents key
+pub const CONTENTS_KEY_SIZE: usize = splax_crypto::xts::KEY_SIZE;
+
+/// Bytes in a names key
+pub const NAMES_KEY_SIZE: usize = splax_crypto::aes::KEY_SIZE;
+
+/// Start of every HKDF info
+pub const KDF_LABEL: &[u8] = b"splaxfs\0";
+
+/// HKDF purpose: key identifier
+pub const KDF_IDENTIFIER: u8 = 1;
+
+/// HKDF purpose: contents key of an inode
+pub const KDF_CONTENTS: u8 = 2;
+
+/// HKDF purpose: names key of a directory
+pub const KDF_NAMES: u8 = 3;
+
+/// Encrypted names are padded to a multiple of this
+pub const NAME_PADDING: usize = 4;
+
+/// Longest name an encrypted directory takes
+pub const MAX_ENCRYPTED_NAME: usize = MAX_FILENAME / NAME_PADDING * NAME_PADDING;
+
+/// HKDF info for a purpose; `nonce` is empty for the key identifier
+pub fn kdf_info(purpose: u8, nonce: &[u8]) -> Vec<u8> {
+    let mut info = Vec::with_capacity(KDF_LABEL.len() + 1 + nonce.len());
+    info.extend_from_slice(KDF_LABEL);
+    info.push(purpose);
+    info.extend_from_slice(nonce);
+    info
+}
+
+/// Encryption context of an inode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Context {
+    /// Identifier of the master key
+    pub identifier: [u8; KEY_IDENTIFIER_SIZE],
+    /// Random value the inode's keys are derived with
+    pub nonce: [u8; NONCE_SIZE],
+}
+
+impl Context {
+    /// Serialize to bytes
+    pub fn to_bytes(&self) -> [u8; CONTEXT_SIZE] {
+        let mut buf = [0u8; CONTEXT_SIZE];
+        buf[..4].copy_from_slice(&[CONTEXT_VERSION, CONTENTS_AES_256_XTS, NAMES_AES_256_CTS, 0]);
+        buf[4..4 + KEY_IDENTIFIER_SIZE].copy_from_slice(&self.identifier);
+        buf[4 + KEY_IDENTIFIER_SIZE..].copy_from_slice(&self.nonce);
+        buf
+    }
+
+    /// Deserialize from bytes; anything but a version 1 context with the
+    /// modes above is corrupt
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, SplaxFsError> {
+        if buf.len() != CONTEXT_SIZE || buf[..4] != [CONTEXT_VERSION, CONTENTS_AES_256_XTS, NAMES_AES_256_CTS, 0] {
+            return Err(SplaxFsError::Corrupted);
+        }
+        let mut context = Self { identifier: [0; KEY_IDENTIFIER_SIZE], nonce: [0; NONCE_SIZE] };
+        context.identifier.copy_from_slice(&buf[4..4 + KEY_IDENTIFIER_SIZE]);
+        context.nonce.copy_from_slice(&buf[4 + KEY_IDENTIFIER_SIZE..]);
+        Ok(context)
+    }
+
+    /// Context among an inode's attributes
+    pub fn find(attrs: &Xattrs) -> Result<Option<Self>, SplaxFsError> {
+        attrs
+            .iter()
+            .find(|(name, _)| name == ENCRYPTION_XATTR)
+            .map(|(_, value)| Self::from_bytes(value))
+            .transpose()
+    }
+}
+
+/// Cipher of a file's blocks
+pub struct ContentsCipher(Aes256Xts);
+
+impl ContentsCipher {
+    /// Cipher with a contents key
+    pub fn new(key: &[u8; CONTENTS_KEY_SIZE]) -> Self {
+        Self(Aes256Xts::new(key))
+    }
+
+    /// Encrypts file block `index` in place
+    pub fn encrypt_block(&self, index: u64, block: &mut [u8]) {
+        self.0.encrypt(index as u128, block);
+    }
+
+    /// Decrypts file block `index` in place
+    pub fn decrypt_block(&self, index: u64, block: &mut [u8]) {
+        self.0.decrypt(index as u128, block);
+    }
+}
+
+/// Cipher of a directory's entry names
+pub struct NamesCipher(Aes256Cts);
+
+impl NamesCipher {
+    /// Cipher with a names key
+    pub fn new(key: &[u8; NAMES_KEY_SIZE]) -> Self {
+        Self(Aes256Cts::new(key))
+    }
+
+    /// Name as stored in an entry
+    pub fn encrypt(&self, name: &[u8]) -> Result<Vec<u8>, SplaxFsError> {
+        if name.len() > MAX_ENCRYPTED_NAME {
+            return Err(SplaxFsError::NameTooLong);
+        }
+        let mut stored = name.to_vec();
+        stored.resize(name.len().next_multiple_of(NAME_PADDING).max(AES_BLOCK_SIZE), 0);
+        self.0.encrypt(&[0; AES_BLOCK_SIZE], &mut stored);
+        Ok(stored)
+    }
+
+    /// Name from what is stored in an entry
+    pub fn decrypt(&self, stored: &[u8]) -> Result<Vec<u8>, SplaxFsError> {
+        if !is_stored_name(stored) {
+            return Err(SplaxFsError::Corrupted);
+        }
+        let mut name = stored.to_vec();
+        self.0.decrypt(&[0; AES_BLOCK_SIZE], &mut name);
+        let len = name.iter().rposition(|&b| b != 0).map_or(0, |last| last + 1);
+        if len == 0 {
+            return Err(SplaxFsError::Corrupted);
+        }
+        name.truncate(len);
+        Ok(name)
+    }
+}
+
+/// The entry name has the length of an encrypted one
+pub fn is_stored_name(stored: &[u8]) -> bool {
+    (AES_BLOCK_SIZE..=MAX_ENCRYPTED_NAME).contains(&stored.len()) && stored.len().is_multiple_of(NAME_PADDING)
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+/// Name an encrypted entry is shown with without the key: its ciphertext
+/// in unpadded base64url
+pub fn encode_name(stored: &[u8]) -> String {
+    let mut name = String::with_capacity(stored.len().div_ceil(3) * 4);
+    for chunk in stored.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, &b)| bits | (b as u32) << (16 - 8 * i));
+        for i in 0..=chunk.len() {
+            name.push(BASE64[(bits >> (18 - 6 * i) & 0x3F) as usize] as char);
+        }
+    }
+    name
+}
+
+/// Ciphertext from a name shown by [`encode_name`]
+pub fn decode_name(name: &str) -> Option<Vec<u8>> {
+    if name.len() % 4 == 1 {
+        return None;
+    }
+    let mut stored = Vec::with_capacity(name.len() * 3 / 4);
+    for chunk in name.as_bytes().chunks(4) {
+        let mut bits = 0u32;
+        for (i, &c) in chunk.iter().enumerate() {
+            let value = BASE64.iter().position(|&b| b == c)? as u32;
+            bits |= value << (18 - 6 * i);
+        }
+        for i in 0..chunk.len() - 1 {
+            stored.push((bits >> (16 - 8 * i)) as u8);
+        }
+    }
+    // Only the canonical encoding is accepted
+    (encode_name(&stored) == name && is_stored_name(&stored)).then_some(stored)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_context() {
+        let context = Context { identifier: [7; KEY_IDENTIFIER_SIZE], nonce: [9; NONCE_SIZE] };
+        let bytes = context.to_bytes();
+        assert_eq!(Context::from_bytes(&bytes), Ok(context));
+        let mut attrs = alloc::vec![(String::from("user.a"), alloc::vec![1])];
+        assert_eq!(Context::find(&attrs), Ok(None));
+        attrs.push((String::from(ENCRYPTION_XATTR), bytes.to_vec()));
+        assert_eq!(Context::find(&attrs), Ok(Some(context)));
+
+        let mut bad = bytes;
+        bad[1] = 9;
+        assert_eq!(Context::from_bytes(&bad), Err(SplaxFsError::Corrupted));
+        assert_eq!(Context::from_bytes(&bytes[..CONTEXT_SIZE - 1]), Err(SplaxFsError::Corrupted));
+    }
+
+    #[test]
+    fn test_names() {
+        let names = NamesCipher::new(&[3; NAMES_KEY_SIZE]);
+        for name in ["a", "notes.txt", "a name longer than one block", &"x".repeat(MAX_ENCRYPTED_NAME)] {
+            let stored = names.encrypt(name.as_bytes()).unwrap();
+            assert!(is_stored_name(&stored));
+            assert_ne!(&stored[..name.len().min(stored.len())], name.as_bytes());
+            assert_eq!(names.decrypt(&stored).unwrap(), name.as_bytes());
+            assert_eq!(decode_name(&encode_name(&stored)), Some(stored));
+        }
+        assert_eq!(names.encrypt(b"notes.txt").unwrap().len(), 16);
+        assert_eq!(names.encrypt(b"notes.txt").unwrap(), names.encrypt(b"notes.txt").unwrap());
+        assert_eq!(names.encrypt(&[b'x'; MAX_ENCRYPTED_NAME + 1]), Err(SplaxFsError::NameTooLong));
+        assert_eq!(names.decrypt(b"short"), Err(SplaxFsError::Corrupted));
+
+        assert_eq!(encode_name(b"\xfb\xff\x00"), "-_8A");
+        assert_eq!(decode_name("not base64!"), None);
+        assert_eq!(decode_name(&encode_name(&[1; 15])), None);
+    }
+
+    #[test]
+    fn test_contents() {
+        let contents = ContentsCipher::new(&[5; CONTENTS_KEY_SIZE]);
+        let plain = [0x42u8; BLOCK_SIZE];
+        let mut block = plain;
+        contents.encrypt_block(3, &mut block);
+        let mut other = plain;
+        contents.encrypt_block(4, &mut other);
+        assert_ne!(block, plain);
+        assert_ne!(block, other);
+        contents.decrypt_block(3, &mut block);
+        assert_eq!(block, plain);
+    }
+}
@@ -0,0 +1,2037 @@
+//! Offline consistency checker
+//!
+//! Checks an unmounted volume and optionally repairs it. The passes follow
+//! e2fsck:
+//!
+//! 1. Inodes: file types, block pointers (range and duplicates),
+//!    compressed cluster shapes, block counts, sizes against block maps,
+//!    extended attributes
+//! 2. Directories: entry sanity, `.`, entry types, duplicate names
+//! 3. Connectivity: every directory reachable from the root, `..` naming
+//!    the parent; lost directories go to `lost+found`
+//! 4. References: inodes without entries go to `lost+found`, link counts
+//! 5. Block reference counts, inode bitmaps, free and saved block counts
+//! 6. Checksums: every block in use against the checksum table, and the
+//!    table itself. A block that does not match keeps its content, which
+//!    is all there is, and gets a fresh checksum; the problem names it so
+//!    the file can be restored from elsewhere
+//!
+//! Fixes are applied to an in-memory view of the metadata as problems are
+//! found, so later passes see the repaired volume and a check-only run
+//! reports exactly what a repair would do. Only a repair run writes the
+//! view back.
+//!
+//! ## Snapshots
+//!
+//! Pass 1 walks the live tree first and then every snapshot tree. A block
+//! counts one reference per tree block (or inode) pointing at it, and its
+//! content is checked by the first tree that reaches it; later trees only
+//! count it. Snapshots get no directory checks. Fixes to blocks a snapshot
+//! shares with the live tree apply to the snapshot too.
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::checksum::ChecksumTable;
+use crate::cluster;
+use crate::crypt;
+use crate::journal::Journal;
+use crate::layout::*;
+use crate::xattr;
+use crate::BlockIo;
+
+/// Name of the directory lost files are moved to
+pub const LOST_AND_FOUND: &str = "lost+found";
+
+/// How to run a check
+#[derive(Debug, Clone, Copy)]
+pub struct CheckOptions {
+    /// Write fixes back to the device
+    pub repair: bool,
+    /// Timestamp for inodes the checker creates or changes
+    pub time: u32,
+}
+
+/// Inconsistency found by [`check`], with the fix that applies to it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// Journal superblock is unreadable; the journal is reinitialized
+    BadJournal,
+    /// Superblock checksum is wrong; it is rewritten
+    SuperblockChecksum,
+    /// Inode has an unknown type or an unusable layout; it is cleared
+    BadInode { ino: u32 },
+    /// Block pointer outside the data area; it is cleared
+    IllegalBlock { ino: u32, block: u32 },
+    /// Block already claimed by another inode; the pointer is cleared
+    DuplicateBlock { ino: u32, block: u32 },
+    /// Block count disagrees with the block map
+    BlockCount { ino: u32, found: u32, expected: u32 },
+    /// Extended attributes do not decode; they are dropped
+    BadXattrs { ino: u32 },
+    /// Size disagrees with the block map
+    Size { ino: u32, found: u64, expected: u64 },
+    /// Malformed entry or entry for a free inode; it is removed
+    BadEntry { dir: u32, name: String, ino: u32 },
+    /// Entry file type disagrees with the inode
+    EntryType { dir: u32, name: String },
+    /// Second entry with the same name; it is removed
+    DuplicateEntry { dir: u32, name: String },
+    /// Second entry for a directory; it is removed
+    DirectoryLink { dir: u32, name: String, ino: u32 },
+    /// `.` is missing or names another inode
+    BadDot { dir: u32 },
+    /// `..` is missing (0) or does not name the parent
+    BadDotDot { dir: u32, found: u32, expected: u32 },
+    /// Root directory is missing; an empty one is created
+    NoRoot,
+    /// `lost+found` is missing; it is created
+    NoLostFound,
+    /// Directory not reachable from the root; moved to `lost+found`
+    DisconnectedDir { ino: u32 },
+    /// Inode in use without a directory entry; moved to `lost+found`
+    Unattached { ino: u32 },
+    /// Empty inode in use without a directory entry; it is cleared
+    UnattachedEmpty { ino: u32 },
+    /// Link count disagrees with the directory entries
+    LinkCount { ino: u32, found: u16, expected: u16 },
+    /// Inode table block outside the data area or used twice in a tree;
+    /// it is dropped with its inodes
+    BadTableBlock { snapshot: u32, index: u32, block: u32 },
+    /// Snapshot root, inode map or inode bitmap block is unusable; the
+    /// snapshot is dropped
+    BadSnapshot { id: u32 },
+    /// Illegal or duplicate block pointer in a snapshot; it is cleared
+    SnapshotBlock { snapshot: u32, ino: u32, block: u32 },
+    /// Compressed cluster starting at file block `index` is malformed, or
+    /// in a file that cannot have one; its saved slots become holes
+    BadCluster { ino: u32, index: u64 },
+    /// Snapshot inode bitmap or free inode count is wrong
+    SnapshotInodes { id: u32 },
+    /// Reference count table differs from the references found
+    BlockRefs { marked_free: u32, marked_used: u32, miscounted: u32 },
+    /// Inode bitmap differs from the inodes in use
+    InodeBitmap { marked_free: u32, marked_used: u32 },
+    /// Superblock free block count is wrong
+    FreeBlocks { found: u32, expected: u32 },
+    /// Superblock free inode count is wrong
+    FreeInodes { found: u32, expected: u32 },
+    /// Superblock count of blocks saved by compression is wrong
+    SavedBlocks { found: u32, expected: u32 },
+    /// Checksum table block is damaged; its entries are recomputed
+    ChecksumTable { index: u32 },
+    /// Block in use does not match its checksum; the checksum is updated
+    Checksum { block: u32 },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::BadJournal => write!(f, "journal superblock is invalid, reinitializing the journal"),
+            Problem::SuperblockChecksum => write!(f, "superblock checksum is wrong, fixing"),
+            Problem::BadInode { ino } => write!(f, "inode {} is invalid, clearing", ino),
+            Problem::BadXattrs { ino } => write!(f, "inode {} has invalid extended attributes, clearing", ino),
+            Problem::IllegalBlock { ino, block } => write!(f, "inode {} has illegal block {}, clearing", ino, block),
+            Problem::DuplicateBlock { ino, block } => {
+                write!(f, "inode {} claims block {} already in use, clearing", ino, block)
+            }
+            Problem::BlockCount { ino, found, expected } => {
+                write!(f, "inode {} block count is {}, should be {}", ino, found, expected)
+            }
+            Problem::Size { ino, found, expected } => write!(f, "inode {} size is {}, should be {}", ino, found, expected),
+            Problem::BadEntry { dir, name, ino } => {
+                write!(f, "entry '{}' in directory {} (inode {}) is invalid, removing", name, dir, ino)
+            }
+            Problem::EntryType { dir, name } => write!(f, "entry '{}' in directory {} has the wrong type", name, dir),
+            Problem::DuplicateEntry { dir, name } => {
+                write!(f, "duplicate entry '{}' in directory {}, removing", name, dir)
+            }
+            Problem::DirectoryLink { dir, name, ino } => {
+                write!(f, "entry '{}' in directory {} is a second link to directory {}, removing", name, dir, ino)
+            }
+            Problem::BadDot { dir } => write!(f, "'.' in directory {} is missing or wrong", dir),
+            Problem::BadDotDot { dir, found: 0, expected } => {
+                write!(f, "'..' in directory {} is missing, should be {}", dir, expected)
+            }
+            Problem::BadDotDot { dir, found, expected } => {
+                write!(f, "'..' in directory {} is {}, should be {}", dir, found, expected)
+            }
+            Problem::NoRoot => write!(f, "root directory is missing, creating"),
+            Problem::NoLostFound => write!(f, "/{} is missing, creating", LOST_AND_FOUND),
+            Problem::DisconnectedDir { ino } => {
+                write!(f, "directory {} is not connected, moving to /{}", ino, LOST_AND_FOUND)
+            }
+            Problem::Unattached { ino } => write!(f, "inode {} has no directory entry, moving to /{}", ino, LOST_AND_FOUND),
+            Problem::UnattachedEmpty { ino } => write!(f, "empty inode {} has no directory entry, clearing", ino),
+            Problem::LinkCount { ino, found, expected } => {
+                write!(f, "inode {} link count is {}, should be {}", ino, found, expected)
+            }
+            Problem::BadTableBlock { snapshot: 0, index, block } => {
+                write!(f, "inode table block {} is illegal block {}, dropping its inodes", index, block)
+            }
+            Problem::BadTableBlock { snapshot, index, block } => write!(
+                f,
+                "inode table block {} of snapshot {} is illegal block {}, dropping its inodes",
+                index, snapshot, block
+            ),
+            Problem::BadSnapshot { id } => write!(f, "snapshot {} is unreadable, dropping", id),
+            Problem::SnapshotBlock { snapshot, ino, block } => write!(
+                f,
+                "inode {} of snapshot {} has illegal or duplicate block {}, clearing",
+                ino, snapshot, block
+            ),
+            Problem::BadCluster { ino, index } => {
+                write!(f, "inode {} has a bad compressed cluster at block {}, clearing", ino, index)
+            }
+            Problem::SnapshotInodes { id } => write!(f, "inode bitmap or count of snapshot {} is wrong", id),
+            Problem::BlockRefs { marked_free, marked_used, miscounted } => write!(
+                f,
+                "block reference counts differ: {} used blocks marked free, {} free blocks marked used, {} miscounted",
+                marked_free, marked_used, miscounted
+            ),
+            Problem::InodeBitmap { marked_free, marked_used } => write!(
+                f,
+                "inode bitmap differs: {} used inodes marked free, {} free inodes marked used",
+                marked_free, marked_used
+            ),
+            Problem::FreeBlocks { found, expected } => {
+                write!(f, "free block count is {}, should be {}", found, expected)
+            }
+            Problem::SavedBlocks { found, expected } => {
+                write!(f, "saved block count is {}, should be {}", found, expected)
+            }
+            Problem::FreeInodes { found, expected } => {
+                write!(f, "free inode count is {}, should be {}", found, expected)
+            }
+            Problem::ChecksumTable { index } => {
+                write!(f, "checksum table block {} is damaged, recomputing its checksums", index)
+            }
+            Problem::Checksum { block } => {
+                write!(f, "block {} does not match its checksum, content may be corrupt", block)
+            }
+        }
+    }
+}
+
+/// Result of a check
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    /// Problems found, in the order they were found
+    pub problems: Vec<Problem>,
+    /// A committed journal transaction was replayed (only in memory when
+    /// not repairing)
+    pub journal_replayed: bool,
+    /// The volume was cleanly unmounted
+    pub was_clean: bool,
+    /// Fixes were written to the device
+    pub repaired: bool,
+    /// Regular files
+    pub files: u32,
+    /// Directories
+    pub directories: u32,
+    /// Symbolic links
+    pub symlinks: u32,
+    /// Snapshots
+    pub snapshots: u32,
+    /// Blocks in use
+    pub used_blocks: u32,
+    /// Blocks on the volume
+    pub total_blocks: u32,
+    /// Inodes in use
+    pub used_inodes: u32,
+    /// Inodes on the volume
+    pub total_inodes: u32,
+}
+
+impl Report {
+    /// No problems were found
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Checks a volume, repairing it if asked to
+///
+/// Fails with [`SplaxFsError::Corrupted`] if the superblock is unusable;
+/// there is no backup to rebuild the volume from.
+pub fn check(dev: &impl BlockIo, options: &CheckOptions) -> Result<Report, SplaxFsError> {
+    let mut buf = vec![0u8; BLOCK_SIZE];
+    dev.read(0, &mut buf)?;
+    let sb = Superblock::from_bytes(&buf);
+    check_superblock(&sb, dev.total_blocks())?;
+
+    let mut report = Report::default();
+    let mut overlay = BTreeMap::new();
+    let mut reset_journal = false;
+    if sb.journal_block != 0 {
+        match Journal::open(dev, sb.journal_block) {
+            Ok(mut journal) if options.repair => report.journal_replayed = journal.recover(dev)? > 0,
+            Ok(journal) => {
+                if let Some(blocks) = journal.pending(dev)? {
+                    overlay = blocks;
+                    report.journal_replayed = true;
+                }
+            }
+            Err(SplaxFsError::JournalError) => {
+                report.problems.push(Problem::BadJournal);
+                reset_journal = true;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    let mut checker = Checker::new(dev, overlay, *options)?;
+    check_superblock(&checker.sb, dev.total_blocks())?;
+    report.was_clean = checker.sb.state == STATE_CLEAN;
+    if !checker.sb.checksum_is_valid() {
+        report.problems.push(Problem::SuperblockChecksum);
+    }
+    checker.problems = core::mem::take(&mut report.problems);
+    checker.run()?;
+
+    if options.repair {
+        if reset_journal {
+            let sb = &checker.sb;
+            Journal::format(dev, sb.journal_block, sb.refcount_block - sb.journal_block - 1)?;
+        }
+        checker.write_back()?;
+        report.repaired = !checker.problems.is_empty();
+    }
+
+    for inode in checker.inodes.values() {
+        match inode.file_type() {
+            FileType::Directory => report.directories += 1,
+            FileType::Symlink => report.symlinks += 1,
+            _ => report.files += 1,
+        }
+    }
+    report.snapshots = checker.snapshots.len() as u32;
+    report.total_blocks = checker.sb.total_blocks;
+    report.used_blocks = checker.sb.total_blocks - checker.sb.free_blocks;
+    report.total_inodes = checker.sb.total_inodes;
+    report.used_inodes = checker.sb.total_inodes - checker.sb.free_inodes;
+    report.problems = checker.problems;
+    Ok(report)
+}
+
+/// Checks the fields everything else depends on
+fn check_superblock(sb: &Superblock, device_blocks: u32) -> Result<(), SplaxFsError> {
+    if !sb.is_valid()
+        || sb.version != SPLAXFS_VERSION
+        || sb.inode_size != INODE_SIZE as u32
+        || !sb.layout_is_valid()
+        || sb.total_blocks > device_blocks
+    {
+        return Err(SplaxFsError::Corrupted);
+    }
+    Ok(())
+}
+
+/// Where a directory entry lives
+#[derive(Debug, Clone, Copy)]
+struct EntryLoc {
+    dir: u32,
+    block: u32,
+    offset: usize,
+}
+
+/// What a block reference turned out to be
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Claim {
+    /// Outside the data area
+    Illegal,
+    /// Already referenced from the tree being walked, or a block only one
+    /// reference is allowed to
+    Duplicate,
+    /// First reference from any tree; the block's content is checked now
+    First,
+    /// Also referenced from an earlier tree, which checked the content
+    Shared,
+}
+
+/// Snapshot found in pass 1
+struct Snapshot {
+    /// Slot in the snapshot table
+    slot: usize,
+    id: u32,
+    /// Inode map blocks
+    map_blocks: Vec<u32>,
+    /// Inode bitmap blocks
+    bitmap_blocks: Vec<u32>,
+}
+
+/// State of one check
+struct Checker<'a, D: BlockIo> {
+    dev: &'a D,
+    options: CheckOptions,
+    sb: Superblock,
+    /// Metadata blocks as the checker sees them, where they differ from
+    /// the device
+    overlay: BTreeMap<u32, Vec<u8>>,
+    /// Inodes in use in the live tree
+    inodes: BTreeMap<u32, DiskInode>,
+    /// Where each live inode table block is
+    table: Vec<u32>,
+    /// Snapshot table
+    snapshot_table: SnapshotTable,
+    /// Snapshots that survived pass 1
+    snapshots: Vec<Snapshot>,
+    /// References found to each block (metadata areas count one)
+    block_refs: Vec<u16>,
+    /// Blocks referenced from the tree being walked
+    seen: Vec<u8>,
+    /// Blocks that can have only one reference (snapshot roots, maps and
+    /// bitmaps)
+    pinned: Vec<u8>,
+    /// Snapshot being walked, 0 for the live tree
+    tree: u32,
+    /// The inode being walked can have compressed clusters
+    clusters: bool,
+    /// Saved slots found in the inodes and pointer blocks walked
+    saved: u32,
+    /// Directory entries naming each inode, `.` and `..` excluded
+    refs: BTreeMap<u32, u32>,
+    /// Entry naming each directory
+    parent: BTreeMap<u32, EntryLoc>,
+    /// `..` entry of each directory, with the inode it names
+    dotdot: BTreeMap<u32, (u32, usize, u32)>,
+    problems: Vec<Problem>,
+}
+
+impl<'a, D: BlockIo> Checker<'a, D> {
+    fn new(dev: &'a D, overlay: BTreeMap<u32, Vec<u8>>, options: CheckOptions) -> Result<Self, SplaxFsError> {
+        let mut checker = Self {
+            dev,
+            options,
+            sb: Superblock::from_bytes(&[]),
+            overlay,
+            inodes: BTreeMap::new(),
+            table: Vec::new(),
+            snapshot_table: SnapshotTable::new(),
+            snapshots: Vec::new(),
+            block_refs: Vec::new(),
+            seen: Vec::new(),
+            pinned: Vec::new(),
+            tree: 0,
+            clusters: false,
+            saved: 0,
+            refs: BTreeMap::new(),
+            parent: BTreeMap::new(),
+            dotdot: BTreeMap::new(),
+            problems: Vec::new(),
+        };
+        checker.sb = Superblock::from_bytes(&checker.read_block(0)?);
+        Ok(checker)
+    }
+
+    fn run(&mut self) -> Result<(), SplaxFsError> {
+        self.pass1()?;
+        self.pass2()?;
+        self.pass3()?;
+        self.pass4()?;
+        self.pass5()?;
+        self.pass6()
+    }
+
+    /// Writes the repaired metadata to the device
+    fn write_back(&mut self) -> Result<(), SplaxFsError> {
+        let mut sb = self.sb;
+        sb.state = STATE_CLEAN;
+        self.write_superblock(&sb)?;
+        for (&block_num, image) in &self.overlay {
+            self.dev.write(block_num, image)?;
+        }
+        self.dev.flush()
+    }
+
+    // -------------------------------------------------------------------------
+    // Block and inode access
+    // -------------------------------------------------------------------------
+
+    fn read_block(&self, block_num: u32) -> Result<Vec<u8>, SplaxFsError> {
+        if let Some(image) = self.overlay.get(&block_num) {
+            return Ok(image.clone());
+        }
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        self.dev.read(block_num, &mut buf)?;
+        Ok(buf)
+    }
+
+    fn write_block(&mut self, block_num: u32, data: Vec<u8>) {
+        self.overlay.insert(block_num, data);
+    }
+
+    fn write_superblock(&mut self, sb: &Superblock) -> Result<(), SplaxFsError> {
+        self.sb = *sb;
+        let mut image = self.read_block(0)?;
+        image[..512].copy_from_slice(&sb.to_bytes());
+        self.write_block(0, image);
+        Ok(())
+    }
+
+    /// Points an inode map entry at another block
+    fn set_map_entry(&mut self, map_block: u32, index: u32, block_num: u32) -> Result<(), SplaxFsError> {
+        let mut data = self.read_block(map_block)?;
+        let offset = index as usize * 4;
+        data[offset..offset + 4].copy_from_slice(&block_num.to_le_bytes());
+        self.write_block(map_block, data);
+        Ok(())
+    }
+
+    /// Moves live inode table block `index`
+    fn set_table_block(&mut self, index: u32, block_num: u32) -> Result<(), SplaxFsError> {
+        self.table[index as usize] = block_num;
+        let ptrs = PTRS_PER_BLOCK as u32;
+        self.set_map_entry(self.sb.inode_map_block + index / ptrs, index % ptrs, block_num)
+    }
+
+    /// Live inode table block and byte offset of an inode, allocating the
+    /// table block if it was dropped
+    fn inode_location(&mut self, ino: u32) -> Result<(u32, usize), SplaxFsError> {
+        let index = (ino - 1) as usize;
+        let table_index = (index / INODES_PER_BLOCK) as u32;
+        let mut block_num = self.table[table_index as usize];
+        if block_num == 0 {
+            block_num = self.alloc_block()?;
+            self.write_block(block_num, vec![0u8; BLOCK_SIZE]);
+            self.set_table_block(table_index, block_num)?;
+        }
+        Ok((block_num, (index % INODES_PER_BLOCK) * INODE_SIZE))
+    }
+
+    /// Stores a live inode, which is in use unless its mode is 0
+    fn write_inode(&mut self, ino: u32, inode: &DiskInode) -> Result<(), SplaxFsError> {
+        let (block_num, offset) = self.inode_location(ino)?;
+        let mut data = self.read_block(block_num)?;
+        data[offset..offset + INODE_SIZE].copy_from_slice(&inode.to_bytes());
+        self.write_block(block_num, data);
+        if inode.mode == 0 {
+            self.inodes.remove(&ino);
+        } else {
+            self.inodes.insert(ino, *inode);
+        }
+        Ok(())
+    }
+
+    /// Clears a live inode and gives back the blocks it claimed
+    fn clear_inode(&mut self, ino: u32, claimed: &[u32]) -> Result<(), SplaxFsError> {
+        for &block_num in claimed {
+            let refs = &mut self.block_refs[block_num as usize];
+            *refs = refs.saturating_sub(1);
+        }
+        let mut dead = DiskInode::new();
+        dead.dtime = self.options.time;
+        self.write_inode(ino, &dead)
+    }
+
+    /// Maps file block `index` to a disk block; 0 is a hole
+    fn bmap(&self, inode: &DiskInode, index: u64) -> Result<u32, SplaxFsError> {
+        let ptrs = PTRS_PER_BLOCK as u64;
+        let read_ptr = |block_num: u32, slot: u64| -> Result<u32, SplaxFsError> {
+            if block_num == 0 {
+                return Ok(0);
+            }
+            Ok(get_u32(&self.read_block(block_num)?, slot as usize * 4))
+        };
+        if index < DIRECT_BLOCKS as u64 {
+            return Ok(inode.direct[index as usize]);
+        }
+        let index = index - DIRECT_BLOCKS as u64;
+        if index < ptrs {
+            return read_ptr(inode.indirect, index);
+        }
+        let index = index - ptrs;
+        if index < ptrs * ptrs {
+            return read_ptr(read_ptr(inode.double_indirect, index / ptrs)?, index % ptrs);
+        }
+        Ok(0)
+    }
+
+    /// Allocates a block the checker found free
+    fn alloc_block(&mut self) -> Result<u32, SplaxFsError> {
+        let first = self.sb.first_data_block as usize;
+        let block_num = (first..self.sb.total_blocks as usize)
+            .find(|&n| self.block_refs[n] == 0)
+            .ok_or(SplaxFsError::NoSpace)?;
+        self.block_refs[block_num] = 1;
+        Ok(block_num as u32)
+    }
+
+    /// Allocates a zeroed pointer block for an empty inode pointer
+    fn ensure_ptr_block(&mut self, ptr: &mut u32, blocks: &mut u32) -> Result<u32, SplaxFsError> {
+        if *ptr == 0 {
+            *ptr = self.alloc_block()?;
+            *blocks += SECTORS_PER_BLOCK;
+            self.write_block(*ptr, vec![0u8; BLOCK_SIZE]);
+        }
+        Ok(*ptr)
+    }
+
+    /// Returns slot `slot` of a pointer block, allocating it if it is empty
+    fn ensure_slot(&mut self, block_num: u32, slot: u64, blocks: &mut u32) -> Result<u32, SplaxFsError> {
+        let mut data = self.read_block(block_num)?;
+        let offset = slot as usize * 4;
+        let mut ptr = get_u32(&data, offset);
+        if ptr == 0 {
+            ptr = self.ensure_ptr_block(&mut 0, blocks)?;
+            data[offset..offset + 4].copy_from_slice(&ptr.to_le_bytes());
+            self.write_block(block_num, data);
+        }
+        Ok(ptr)
+    }
+
+    /// Allocates a zeroed block for file block `index`, which is a hole
+    fn map_new(&mut self, inode: &mut DiskInode, index: u64) -> Result<u32, SplaxFsError> {
+        let ptrs = PTRS_PER_BLOCK as u64;
+        if index < DIRECT_BLOCKS as u64 {
+            return self.ensure_ptr_block(&mut inode.direct[index as usize], &mut inode.blocks);
+        }
+        let index = index - DIRECT_BLOCKS as u64;
+        if index < ptrs {
+            let indirect = self.ensure_ptr_block(&mut inode.indirect, &mut inode.blocks)?;
+            return self.ensure_slot(indirect, index, &mut inode.blocks);
+        }
+        let index = index - ptrs;
+        if index < ptrs * ptrs {
+            let double = self.ensure_ptr_block(&mut inode.double_indirect, &mut inode.blocks)?;
+            let indirect = self.ensure_slot(double, index / ptrs, &mut inode.blocks)?;
+            return self.ensure_slot(indirect, index % ptrs, &mut inode.blocks);
+        }
+        Err(SplaxFsError::NoSpace)
+    }
+
+    /// Entries of a directory with their block and byte offset, free
+    /// slots included (inode 0)
+    fn dir_slots(&self, dir: &DiskInode) -> Result<Vec<(u32, usize, DirEntry)>, SplaxFsError> {
+        let mut slots = Vec::new();
+        for index in 0..dir.size() / BLOCK_SIZE as u64 {
+            let block_num = self.bmap(dir, index)?;
+            if block_num == 0 {
+                continue;
+            }
+            let data = self.read_block(block_num)?;
+            for slot in 0..DIRENTS_PER_BLOCK {
+                let offset = slot * DIRENT_SIZE;
+                slots.push((block_num, offset, DirEntry::from_bytes(&data[offset..])));
+            }
+        }
+        Ok(slots)
+    }
+
+    fn write_entry(&mut self, block_num: u32, offset: usize, entry: Option<&DirEntry>) -> Result<(), SplaxFsError> {
+        let mut data = self.read_block(block_num)?;
+        match entry {
+            Some(entry) => data[offset..offset + DIRENT_SIZE].copy_from_slice(&entry.to_bytes()),
+            None => data[offset..offset + DIRENT_SIZE].fill(0),
+        }
+        self.write_block(block_num, data);
+        Ok(())
+    }
+
+    /// Adds an entry to a directory, growing it by a block if it is full
+    fn add_entry(&mut self, dir_ino: u32, name: &str, ino: u32) -> Result<EntryLoc, SplaxFsError> {
+        let mut dir = self.inodes[&dir_ino];
+        let file_type = self.inodes[&ino].file_type();
+        let entry = DirEntry::new(ino, name, file_type);
+        let free = self.dir_slots(&dir)?.into_iter().find(|(_, _, slot)| slot.inode == 0);
+        let (block, offset) = match free {
+            Some((block, offset, _)) => (block, offset),
+            None => {
+                let index = dir.size() / BLOCK_SIZE as u64;
+                let block = self.map_new(&mut dir, index)?;
+                dir.set_size((index + 1) * BLOCK_SIZE as u64);
+                (block, 0)
+            }
+        };
+        self.write_entry(block, offset, Some(&entry))?;
+        dir.mtime = self.options.time;
+        dir.ctime = self.options.time;
+        self.write_inode(dir_ino, &dir)?;
+        *self.refs.entry(ino).or_insert(0) += 1;
+        let loc = EntryLoc { dir: dir_ino, block, offset };
+        if file_type == FileType::Directory {
+            self.parent.insert(ino, loc);
+        }
+        Ok(loc)
+    }
+
+    /// Creates an empty directory at `ino`
+    fn make_dir(&mut self, ino: u32, parent: u32) -> Result<(), SplaxFsError> {
+        let block_num = self.alloc_block()?;
+        let mut data = vec![0u8; BLOCK_SIZE];
+        data[..DIRENT_SIZE].copy_from_slice(&DirEntry::new(ino, ".", FileType::Directory).to_bytes());
+        data[DIRENT_SIZE..2 * DIRENT_SIZE].copy_from_slice(&DirEntry::new(parent, "..", FileType::Directory).to_bytes());
+        self.write_block(block_num, data);
+
+        let mut inode = DiskInode::new_directory();
+        inode.atime = self.options.time;
+        inode.ctime = self.options.time;
+        inode.mtime = self.options.time;
+        inode.direct[0] = block_num;
+        inode.blocks = SECTORS_PER_BLOCK;
+        inode.set_size(BLOCK_SIZE as u64);
+        self.write_inode(ino, &inode)?;
+        self.dotdot.insert(ino, (block_num, DIRENT_SIZE, parent));
+        Ok(())
+    }
+
+    /// Finds or creates `/lost+found`
+    fn lost_and_found(&mut self) -> Result<u32, SplaxFsError> {
+        let root = self.inodes[&ROOT_INODE];
+        for (block, offset, entry) in self.dir_slots(&root)? {
+            if entry.inode == 0 || entry.name_str() != LOST_AND_FOUND {
+                continue;
+            }
+            if self.inodes.get(&entry.inode).is_some_and(|inode| inode.is_directory()) {
+                return Ok(entry.inode);
+            }
+            // Not a directory: take the name, the inode ends up inside
+            self.write_entry(block, offset, None)?;
+            if let Some(refs) = self.refs.get_mut(&entry.inode) {
+                *refs -= 1;
+            }
+        }
+
+        self.problems.push(Problem::NoLostFound);
+        let ino = (ROOT_INODE + 1..=self.sb.total_inodes)
+            .find(|ino| !self.inodes.contains_key(ino))
+            .ok_or(SplaxFsError::NoSpace)?;
+        self.make_dir(ino, ROOT_INODE)?;
+        self.add_entry(ROOT_INODE, LOST_AND_FOUND, ino)?;
+        Ok(ino)
+    }
+
+    /// Links an inode into `/lost+found` as `#<ino>`
+    fn reconnect(&mut self, ino: u32) -> Result<(), SplaxFsError> {
+        let lost = self.lost_and_found()?;
+        let taken: BTreeSet<String> = self
+            .dir_slots(&self.inodes[&lost])?
+            .into_iter()
+            .filter(|(_, _, entry)| entry.inode != 0)
+            .map(|(_, _, entry)| String::from(entry.name_str()))
+            .collect();
+        let mut name = format!("#{}", ino);
+        let mut n = 1;
+        while taken.contains(&name) {
+            name = format!("#{}.{}", ino, n);
+            n += 1;
+        }
+        self.add_entry(lost, &name, ino)?;
+        Ok(())
+    }
+
+    // -------------------------------------------------------------------------
+    // Pass 1: inodes and block maps
+    // -------------------------------------------------------------------------
+
+    /// Counts a reference from the tree being walked
+    fn claim(&mut self, block_num: u32) -> Claim {
+        if block_num < self.sb.first_data_block || block_num >= self.sb.total_blocks {
+            return Claim::Illegal;
+        }
+        let refs = self.block_refs[block_num as usize];
+        if test_bit(&self.seen, block_num) || test_bit(&self.pinned, block_num) || refs == u16::MAX {
+            return Claim::Duplicate;
+        }
+        set_bit(&mut self.seen, block_num, true);
+        self.block_refs[block_num as usize] = refs + 1;
+        if refs == 0 {
+            Claim::First
+        } else {
+            Claim::Shared
+        }
+    }
+
+    /// Claims a block an inode points at; `None` if the pointer must go,
+    /// otherwise whether this is the first reference
+    fn claim_for(&mut self, ino: u32, block_num: u32) -> Option<bool> {
+        match self.claim(block_num) {
+            Claim::First => Some(true),
+            Claim::Shared => Some(false),
+            Claim::Illegal if self.tree == 0 => {
+                self.problems.push(Problem::IllegalBlock { ino, block: block_num });
+                None
+            }
+            Claim::Duplicate if self.tree == 0 => {
+                self.problems.push(Problem::DuplicateBlock { ino, block: block_num });
+                None
+            }
+            _ => {
+                self.problems.push(Problem::SnapshotBlock { snapshot: self.tree, ino, block: block_num });
+                None
+            }
+        }
+    }
+
+    /// Claims a pointer block of the given depth and everything below it,
+    /// clearing pointers that cannot be claimed. A block another tree
+    /// reached first is counted but not descended into.
+    fn walk_tree(
+        &mut self,
+        ino: u32,
+        ptr: &mut u32,
+        depth: u32,
+        base: u64,
+        claimed: &mut Vec<u32>,
+        last: &mut Option<u64>,
+    ) -> Result<(), SplaxFsError> {
+        let Some(first) = self.claim_for(ino, *ptr) else {
+            *ptr = 0;
+            return Ok(());
+        };
+        claimed.push(*ptr);
+        if !first {
+            return Ok(());
+        }
+        let span = (PTRS_PER_BLOCK as u64).pow(depth - 1);
+        let mut data = self.read_block(*ptr)?;
+        let mut changed = false;
+
+        for slot in 0..PTRS_PER_BLOCK {
+            let old = get_u32(&data, slot * 4);
+            if old == 0 || (depth == 1 && old == COMPRESSED_SLOT) {
+                continue;
+            }
+            let index = base + slot as u64 * span;
+            let mut child = old;
+            if depth > 1 {
+                self.walk_tree(ino, &mut child, depth - 1, index, claimed, last)?;
+            } else if self.claim_for(ino, child).is_some() {
+                claimed.push(child);
+                *last = Some(index);
+            } else {
+                child = 0;
+            }
+            if child != old {
+                data[slot * 4..slot * 4 + 4].copy_from_slice(&child.to_le_bytes());
+                changed = true;
+            }
+        }
+
+        if depth == 1 {
+            let mut slots: Vec<u32> = (0..PTRS_PER_BLOCK).map(|slot| get_u32(&data, slot * 4)).collect();
+            if self.check_clusters(ino, base, &mut slots, last) {
+                for (slot, ptr) in slots.iter().enumerate() {
+                    data[slot * 4..slot * 4 + 4].copy_from_slice(&ptr.to_le_bytes());
+                }
+                changed = true;
+            }
+        }
+        if changed {
+            self.write_block(*ptr, data);
+        }
+        Ok(())
+    }
+
+    /// Checks the compressed clusters in block map slots starting at file
+    /// block `base`, turning the saved slots of bad ones into holes, and
+    /// counts the saved slots left. Returns true if any slot changed.
+    fn check_clusters(&mut self, ino: u32, base: u64, slots: &mut [u32], last: &mut Option<u64>) -> bool {
+        let mut changed = false;
+        let (_, blocks) = cluster::span(base);
+        for (i, slots) in slots.chunks_mut(blocks).enumerate() {
+            let index = base + (i * blocks) as u64;
+            if cluster::is_compressed(slots) && !(self.clusters && cluster::is_valid(slots)) {
+                self.problems.push(Problem::BadCluster { ino, index });
+                slots.iter_mut().filter(|ptr| **ptr == COMPRESSED_SLOT).for_each(|ptr| *ptr = 0);
+                changed = true;
+            }
+            for (j, _) in slots.iter().enumerate().filter(|(_, &ptr)| ptr == COMPRESSED_SLOT) {
+                self.saved += 1;
+                *last = (*last).max(Some(index + j as u64));
+            }
+        }
+        changed
+    }
+
+    /// Claims every block of an inode, clearing pointers that cannot be
+    /// claimed. Returns the blocks claimed and the last file block mapped.
+    fn walk_inode(&mut self, ino: u32, inode: &mut DiskInode) -> Result<(Vec<u32>, Option<u64>), SplaxFsError> {
+        let mut claimed = Vec::new();
+        let mut last = None;
+        for index in 0..DIRECT_BLOCKS {
+            let block_num = inode.direct[index];
+            if !cluster::is_block(block_num) {
+                continue;
+            }
+            if self.claim_for(ino, block_num).is_some() {
+                claimed.push(block_num);
+                last = Some(index as u64);
+            } else {
+                inode.direct[index] = 0;
+            }
+        }
+        self.clusters = inode.is_file() && inode.is_compressed();
+        self.check_clusters(ino, 0, &mut inode.direct, &mut last);
+        let mut indirect = inode.indirect;
+        if indirect != 0 {
+            self.walk_tree(ino, &mut indirect, 1, DIRECT_BLOCKS as u64, &mut claimed, &mut last)?;
+            inode.indirect = indirect;
+        }
+        let mut double = inode.double_indirect;
+        if double != 0 {
+            let base = (DIRECT_BLOCKS + PTRS_PER_BLOCK) as u64;
+            self.walk_tree(ino, &mut double, 2, base, &mut claimed, &mut last)?;
+            inode.double_indirect = double;
+        }
+        if inode.triple_indirect != 0 {
+            let block = inode.triple_indirect;
+            self.problems.push(if self.tree == 0 {
+                Problem::IllegalBlock { ino, block }
+            } else {
+                Problem::SnapshotBlock { snapshot: self.tree, ino, block }
+            });
+            inode.triple_indirect = 0;
+        }
+        if inode.xattr_block != 0 || inode.xattr_inline[0] != 0 {
+            self.walk_xattrs(ino, inode, &mut claimed)?;
+        }
+        Ok((claimed, last))
+    }
+
+    /// Claims an inode's attribute overflow block and checks its
+    /// attributes. Attributes that do not decode are all dropped.
+    fn walk_xattrs(&mut self, ino: u32, inode: &mut DiskInode, claimed: &mut Vec<u32>) -> Result<(), SplaxFsError> {
+        let block_num = inode.xattr_block;
+        if block_num != 0 {
+            if self.claim_for(ino, block_num).is_some() {
+                claimed.push(block_num);
+            } else {
+                inode.xattr_block = 0;
+            }
+        }
+        let block = match inode.xattr_block {
+            0 => None,
+            block_num => Some(self.read_block(block_num)?),
+        };
+        if xattr::decode(inode, block.as_deref()).is_ok() {
+            return Ok(());
+        }
+        self.problems.push(Problem::BadXattrs { ino });
+        inode.xattr_inline = [0; XATTR_INLINE_SIZE];
+        if inode.xattr_block != 0 {
+            claimed.pop();
+            self.block_refs[block_num as usize] -= 1;
+            set_bit(&mut self.seen, block_num, false);
+            inode.xattr_block = 0;
+        }
+        Ok(())
+    }
+
+    /// Reads the inode map at `map_blocks`
+    fn read_map(&self, map_blocks: &[u32]) -> Result<Vec<u32>, SplaxFsError> {
+        let mut table = Vec::with_capacity(self.sb.inode_table_blocks() as usize);
+        for &block_num in map_blocks {
+            let data = self.read_block(block_num)?;
+            for slot in 0..PTRS_PER_BLOCK {
+                if table.len() < self.sb.inode_table_blocks() as usize {
+                    table.push(get_u32(&data, slot * 4));
+                }
+            }
+        }
+        Ok(table)
+    }
+
+    /// Claims an inode table block for the tree being walked; false if it
+    /// must be dropped
+    fn claim_table_block(&mut self, index: u32, block_num: u32) -> Option<bool> {
+        match self.claim(block_num) {
+            Claim::First => Some(true),
+            Claim::Shared => Some(false),
+            _ => {
+                self.problems.push(Problem::BadTableBlock { snapshot: self.tree, index, block: block_num });
+                None
+            }
+        }
+    }
+
+    fn pass1(&mut self) -> Result<(), SplaxFsError> {
+        let total_blocks = self.sb.total_blocks as usize;
+        self.block_refs = vec![0u16; total_blocks];
+        self.pinned = vec![0u8; total_blocks.div_ceil(8)];
+        for block_num in 0..self.sb.first_data_block {
+            self.block_refs[block_num as usize] = 1;
+        }
+
+        let map_blocks: Vec<u32> = (0..self.sb.inode_map_blocks()).map(|i| self.sb.inode_map_block + i).collect();
+        self.table = self.read_map(&map_blocks)?;
+        self.seen = vec![0u8; total_blocks.div_ceil(8)];
+        for table_index in 0..self.sb.inode_table_blocks() {
+            let block_num = self.table[table_index as usize];
+            if block_num == 0 {
+                continue;
+            }
+            let Some(first) = self.claim_table_block(table_index, block_num) else {
+                self.set_table_block(table_index, 0)?;
+                continue;
+            };
+            // Only a snapshot can have reached the block first, and
+            // snapshots are walked after the live tree
+            debug_assert!(first);
+            let data = self.read_block(block_num)?;
+            for slot in 0..INODES_PER_BLOCK {
+                let ino = table_index * INODES_PER_BLOCK as u32 + slot as u32 + 1;
+                if ino > self.sb.total_inodes {
+                    break;
+                }
+                let inode = DiskInode::from_bytes(&data[slot * INODE_SIZE..]);
+                // Inode 1 is reserved and never holds a file
+                if inode.mode != 0 && ino != 1 {
+                    self.check_inode(ino, inode)?;
+                }
+            }
+        }
+
+        self.load_snapshots()?;
+        for index in 0..self.snapshots.len() {
+            self.walk_snapshot(index)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the snapshot table and claims each snapshot's root, inode map
+    /// and inode bitmap blocks, dropping snapshots whose blocks are unusable
+    fn load_snapshots(&mut self) -> Result<(), SplaxFsError> {
+        self.snapshot_table = SnapshotTable::from_bytes(&self.read_block(self.sb.snapshot_block)?);
+        let map_count = self.sb.inode_map_blocks() as usize;
+        let ptr_count = self.sb.snapshot_root_ptrs() as usize;
+
+        for slot in 0..MAX_SNAPSHOTS {
+            let entry = self.snapshot_table.entries[slot];
+            if entry.id == 0 {
+                continue;
+            }
+            let usable = |checker: &Self, block_num: u32| {
+                block_num >= checker.sb.first_data_block
+                    && block_num < checker.sb.total_blocks
+                    && checker.block_refs[block_num as usize] == 0
+            };
+            // Root first, then the inode map and inode bitmap blocks
+            let mut blocks = Vec::new();
+            let mut ok = usable(self, entry.root) && entry.id < self.snapshot_table.next_id;
+            if ok {
+                self.block_refs[entry.root as usize] = 1;
+                blocks.push(entry.root);
+                let root = self.read_block(entry.root)?;
+                for i in 0..ptr_count {
+                    let block_num = get_u32(&root, i * 4);
+                    if !usable(self, block_num) {
+                        ok = false;
+                        break;
+                    }
+                    self.block_refs[block_num as usize] = 1;
+                    blocks.push(block_num);
+                }
+            }
+            if !ok {
+                // The blocks claimed so far go back to the pool
+                for &block_num in &blocks {
+                    self.block_refs[block_num as usize] = 0;
+                }
+                self.problems.push(Problem::BadSnapshot { id: entry.id });
+                self.snapshot_table.entries[slot] = SnapshotEntry::from_bytes(&[]);
+                let table = self.snapshot_table.to_bytes().to_vec();
+                self.write_block(self.sb.snapshot_block, table);
+                continue;
+            }
+            for &block_num in &blocks {
+                set_bit(&mut self.pinned, block_num, true);
+            }
+            let mut ptrs = blocks.split_off(1);
+            let bitmap_blocks = ptrs.split_off(map_count);
+            self.snapshots.push(Snapshot { slot, id: entry.id, map_blocks: ptrs, bitmap_blocks });
+        }
+        Ok(())
+    }
+
+    /// Counts the references from a snapshot tree and checks the block maps
+    /// of inodes only it reached
+    fn walk_snapshot(&mut self, index: usize) -> Result<(), SplaxFsError> {
+        let id = self.snapshots[index].id;
+        let map_blocks = self.snapshots[index].map_blocks.clone();
+        let table = self.read_map(&map_blocks)?;
+        self.tree = id;
+        self.seen.fill(0);
+
+        for (table_index, &block_num) in table.iter().enumerate() {
+            let table_index = table_index as u32;
+            if block_num == 0 {
+                continue;
+            }
+            let Some(first) = self.claim_table_block(table_index, block_num) else {
+                let ptrs = PTRS_PER_BLOCK as u32;
+                self.set_map_entry(map_blocks[(table_index / ptrs) as usize], table_index % ptrs, 0)?;
+                continue;
+            };
+            if !first {
+                continue;
+            }
+            let mut data = self.read_block(block_num)?;
+            let mut changed = false;
+            for slot in 0..INODES_PER_BLOCK {
+                let ino = table_index * INODES_PER_BLOCK as u32 + slot as u32 + 1;
+                if ino > self.sb.total_inodes {
+                    break;
+                }
+                let offset = slot * INODE_SIZE;
+                let mut inode = DiskInode::from_bytes(&data[offset..]);
+                if inode.mode == 0 || ino == 1 {
+                    continue;
+                }
+                let original = inode.to_bytes();
+                self.walk_inode(ino, &mut inode)?;
+                if inode.to_bytes() != original {
+                    data[offset..offset + INODE_SIZE].copy_from_slice(&inode.to_bytes());
+                    changed = true;
+                }
+            }
+            if changed {
+                self.write_block(block_num, data);
+            }
+        }
+        self.tree = 0;
+        Ok(())
+    }
+
+    fn check_inode(&mut self, ino: u32, mut inode: DiskInode) -> Result<(), SplaxFsError> {
+        let original = inode.to_bytes();
+        let file_type = inode.file_type();
+        if file_type == FileType::Unknown || (ino == ROOT_INODE && file_type != FileType::Directory) {
+            self.problems.push(Problem::BadInode { ino });
+            return self.clear_inode(ino, &[]);
+        }
+
+        let saved = self.saved;
+        let (claimed, last) = self.walk_inode(ino, &mut inode)?;
+
+        let block_size = BLOCK_SIZE as u64;
+        let mapped_end = last.map_or(0, |index| (index + 1) * block_size);
+        let size = inode.size();
+        let expected_size = match file_type {
+            FileType::Directory if last.is_none() => None,
+            FileType::Directory => Some(mapped_end),
+            FileType::Symlink if size == 0 || size >= block_size || inode.direct[0] == 0 || last != Some(0) => None,
+            FileType::Symlink => Some(size),
+            _ if size > MAX_FILE_BLOCKS * block_size || mapped_end > size.next_multiple_of(block_size) => Some(mapped_end),
+            _ => Some(size),
+        };
+        let Some(expected_size) = expected_size else {
+            self.problems.push(Problem::BadInode { ino });
+            self.saved = saved;
+            return self.clear_inode(ino, &claimed);
+        };
+        if expected_size != size {
+            self.problems.push(Problem::Size { ino, found: size, expected: expected_size });
+            inode.set_size(expected_size);
+        }
+
+        let expected_blocks = claimed.len() as u32 * SECTORS_PER_BLOCK;
+        if inode.blocks != expected_blocks {
+            self.problems.push(Problem::BlockCount { ino, found: inode.blocks, expected: expected_blocks });
+            inode.blocks = expected_blocks;
+        }
+
+        if inode.to_bytes() != original {
+            self.write_inode(ino, &inode)
+        } else {
+            self.inodes.insert(ino, inode);
+            Ok(())
+        }
+    }
+
+    // -------------------------------------------------------------------------
+    // Pass 2: directory entries
+    // -------------------------------------------------------------------------
+
+    fn pass2(&mut self) -> Result<(), SplaxFsError> {
+        let dirs: Vec<u32> = self
+            .inodes
+            .iter()
+            .filter(|(_, inode)| inode.is_directory())
+            .map(|(&ino, _)| ino)
+            .collect();
+        for dir in dirs {
+            self.check_dir(dir)?;
+        }
+        Ok(())
+    }
+
+    fn check_dir(&mut self, dir: u32) -> Result<(), SplaxFsError> {
+        let slots = self.dir_slots(&self.inodes[&dir])?;
+        let encrypted = self.inodes[&dir].is_encrypted();
+        let mut names = BTreeSet::new();
+
+        for (position, (block, offset, entry)) in slots.into_iter().enumerate() {
+            let name_bytes = entry.raw_name();
+            // Encrypted names are reported as they are shown without the key
+            let name = if encrypted && position > 1 {
+                crypt::encode_name(name_bytes)
+            } else {
+                String::from_utf8_lossy(name_bytes).into_owned()
+            };
+            let target = self.inodes.get(&entry.inode).copied();
+
+            if position == 0 {
+                if entry.inode != dir || name != "." || entry.rec_len != DIRENT_SIZE as u16 {
+                    // Anything else in this slot loses its entry and turns
+                    // up as unattached in pass 4
+                    self.problems.push(Problem::BadDot { dir });
+                    self.write_entry(block, offset, Some(&DirEntry::new(dir, ".", FileType::Directory)))?;
+                }
+                continue;
+            }
+            if position == 1 {
+                let valid = name == ".."
+                    && entry.rec_len == DIRENT_SIZE as u16
+                    && target.is_some_and(|inode| inode.is_directory());
+                if valid {
+                    self.dotdot.insert(dir, (block, offset, entry.inode));
+                } else {
+                    self.dotdot.insert(dir, (block, offset, 0));
+                }
+                continue;
+            }
+            if entry.inode == 0 {
+                continue;
+            }
+
+            let valid_name = if encrypted {
+                crypt::is_stored_name(name_bytes)
+            } else {
+                core::str::from_utf8(name_bytes).is_ok_and(|name| check_name(name).is_ok())
+            };
+            let Some(target) = target.filter(|_| valid_name && entry.rec_len == DIRENT_SIZE as u16) else {
+                self.problems.push(Problem::BadEntry { dir, name, ino: entry.inode });
+                self.write_entry(block, offset, None)?;
+                continue;
+            };
+            if !names.insert(name.clone()) {
+                self.problems.push(Problem::DuplicateEntry { dir, name });
+                self.write_entry(block, offset, None)?;
+                continue;
+            }
+            if target.is_directory() && (entry.inode == ROOT_INODE || entry.inode == dir || self.parent.contains_key(&entry.inode)) {
+                self.problems.push(Problem::DirectoryLink { dir, name, ino: entry.inode });
+                self.write_entry(block, offset, None)?;
+                continue;
+            }
+            if entry.file_type != target.file_type() as u8 {
+                self.problems.push(Problem::EntryType { dir, name });
+                let mut fixed = entry.clone();
+                fixed.file_type = target.file_type() as u8;
+                self.write_entry(block, offset, Some(&fixed))?;
+            }
+
+            *self.refs.entry(entry.inode).or_insert(0) += 1;
+            if target.is_directory() {
+                self.parent.insert(entry.inode, EntryLoc { dir, block, offset });
+            }
+        }
+        Ok(())
+    }
+
+    // -------------------------------------------------------------------------
+    // Pass 3: directory connectivity
+    // -------------------------------------------------------------------------
+
+    /// Directories reachable from `top` through the parent links
+    fn subtree(&self, top: u32) -> BTreeSet<u32> {
+        let mut children: BTreeMap<u32, Vec<u32>> = BTreeMap::new();
+        for (&child, loc) in &self.parent {
+            children.entry(loc.dir).or_default().push(child);
+        }
+        let mut seen = BTreeSet::new();
+        let mut stack = vec![top];
+        while let Some(dir) = stack.pop() {
+            if seen.insert(dir) {
+                stack.extend(children.get(&dir).into_iter().flatten());
+            }
+        }
+        seen
+    }
+
+    fn pass3(&mut self) -> Result<(), SplaxFsError> {
+        if !self.inodes.get(&ROOT_INODE).is_some_and(|inode| inode.is_directory()) {
+            self.problems.push(Problem::NoRoot);
+            self.make_dir(ROOT_INODE, ROOT_INODE)?;
+        }
+
+        let mut reachable = self.subtree(ROOT_INODE);
+        let dirs: Vec<u32> = self
+            .inodes
+            .iter()
+            .filter(|(_, inode)| inode.is_directory())
+            .map(|(&ino, _)| ino)
+            .collect();
+        for &dir in &dirs {
+            if reachable.contains(&dir) {
+                continue;
+            }
+            // Reconnect the top of the lost subtree (or a point on a loop)
+            let mut top = dir;
+            let mut walked = BTreeSet::from([dir]);
+            while let Some(loc) = self.parent.get(&top) {
+                if reachable.contains(&loc.dir) || !walked.insert(loc.dir) {
+                    break;
+                }
+                top = loc.dir;
+            }
+            if let Some(loc) = self.parent.remove(&top) {
+                self.write_entry(loc.block, loc.offset, None)?;
+                if let Some(refs) = self.refs.get_mut(&top) {
+                    *refs -= 1;
+                }
+            }
+            self.problems.push(Problem::DisconnectedDir { ino: top });
+            self.reconnect(top)?;
+            reachable.extend(self.subtree(top));
+            // lost+found may be new
+            reachable.extend(self.subtree(ROOT_INODE));
+        }
+
+        for dir in self.inodes.keys().copied().collect::<Vec<_>>() {
+            if !self.inodes[&dir].is_directory() {
+                continue;
+            }
+            let expected = match self.parent.get(&dir) {
+                _ if dir == ROOT_INODE => ROOT_INODE,
+                Some(loc) => loc.dir,
+                None => continue,
+            };
+            let Some(&(block, offset, found)) = self.dotdot.get(&dir) else {
+                continue;
+            };
+            if found != expected {
+                self.problems.push(Problem::BadDotDot { dir, found, expected });
+                self.write_entry(block, offset, Some(&DirEntry::new(expected, "..", FileType::Directory)))?;
+                self.dotdot.insert(dir, (block, offset, expected));
+            }
+        }
+        Ok(())
+    }
+
+    // -------------------------------------------------------------------------
+    // Pass 4: reference counts
+    // -------------------------------------------------------------------------
+
+    fn pass4(&mut self) -> Result<(), SplaxFsError> {
+        let unattached = |checker: &Self| -> Vec<u32> {
+            checker
+                .inodes
+                .iter()
+                .filter(|&(ino, inode)| !inode.is_directory() && checker.refs.get(ino).copied().unwrap_or(0) == 0)
+                .map(|(&ino, _)| ino)
+                .collect()
+        };
+        // Creating lost+found can itself unattach a file squatting on the name
+        if !unattached(self).is_empty() {
+            self.lost_and_found()?;
+        }
+        for ino in unattached(self) {
+            let inode = self.inodes[&ino];
+            if inode.size() == 0 && inode.blocks == 0 {
+                self.problems.push(Problem::UnattachedEmpty { ino });
+                self.clear_inode(ino, &[])?;
+            } else {
+                self.problems.push(Problem::Unattached { ino });
+                self.reconnect(ino)?;
+            }
+        }
+
+        let mut subdirs: BTreeMap<u32, u32> = BTreeMap::new();
+        for loc in self.parent.values() {
+            *subdirs.entry(loc.dir).or_insert(0) += 1;
+        }
+        for ino in self.inodes.keys().copied().collect::<Vec<_>>() {
+            let mut inode = self.inodes[&ino];
+            let expected = if inode.is_directory() {
+                2 + subdirs.get(&ino).copied().unwrap_or(0)
+            } else {
+                self.refs.get(&ino).copied().unwrap_or(0)
+            };
+            let expected = expected.min(u16::MAX as u32) as u16;
+            if inode.links_count != expected {
+                self.problems.push(Problem::LinkCount { ino, found: inode.links_count, expected });
+                inode.links_count = expected;
+                self.write_inode(ino, &inode)?;
+            }
+        }
+        Ok(())
+    }
+
+    // -------------------------------------------------------------------------
+    // Pass 5: bitmaps and counts
+    // -------------------------------------------------------------------------
+
+    /// Compares a bitmap on disk with the expected one, rewriting the
+    /// blocks that differ. Returns the (marked free, marked used) counts.
+    fn sync_bitmap(&mut self, blocks: &[u32], expected: &[u8], bits: u32) -> Result<(u32, u32), SplaxFsError> {
+        let mut marked_free = 0;
+        let mut marked_used = 0;
+        for (index, (chunk, &block_num)) in expected.chunks(BLOCK_SIZE).zip(blocks).enumerate() {
+            let on_disk = self.read_block(block_num)?;
+            if on_disk == chunk {
+                continue;
+            }
+            let first = index as u32 * BITS_PER_BLOCK;
+            for bit in first..(first + BITS_PER_BLOCK).min(bits) {
+                match (test_bit(expected, bit), test_bit(&on_disk, bit - first)) {
+                    (true, false) => marked_free += 1,
+                    (false, true) => marked_used += 1,
+                    _ => {}
+                }
+            }
+            self.write_block(block_num, chunk.to_vec());
+        }
+        Ok((marked_free, marked_used))
+    }
+
+    /// Inode bitmap with the given inodes and the reserved ones set
+    fn inode_bitmap(&self, inodes: impl Iterator<Item = u32>) -> Vec<u8> {
+        let mut bitmap = vec![0u8; self.sb.inode_bitmap_blocks() as usize * BLOCK_SIZE];
+        set_bit(&mut bitmap, 0, true);
+        set_bit(&mut bitmap, 1, true);
+        for ino in inodes {
+            set_bit(&mut bitmap, ino, true);
+        }
+        bitmap
+    }
+
+    /// Rewrites the reference count table where it differs from the
+    /// references found
+    fn sync_refcounts(&mut self) -> Result<(), SplaxFsError> {
+        let mut marked_free = 0;
+        let mut marked_used = 0;
+        let mut miscounted = 0;
+        for index in 0..self.sb.refcount_blocks() {
+            let block_num = self.sb.refcount_block + index;
+            let mut table = self.read_block(block_num)?;
+            let mut changed = false;
+            let first = index * REFS_PER_BLOCK;
+            for n in first..(first + REFS_PER_BLOCK).min(self.sb.total_blocks) {
+                let expected = self.block_refs[n as usize];
+                let found = get_ref(&table, n - first);
+                if found == expected {
+                    continue;
+                }
+                match (found, expected) {
+                    (0, _) => marked_free += 1,
+                    (_, 0) => marked_used += 1,
+                    _ => miscounted += 1,
+                }
+                set_ref(&mut table, n - first, expected);
+                changed = true;
+            }
+            if changed {
+                self.write_block(block_num, table);
+            }
+        }
+        if marked_free + marked_used + miscounted > 0 {
+            self.problems.push(Problem::BlockRefs { marked_free, marked_used, miscounted });
+        }
+        Ok(())
+    }
+
+    /// Fixes a snapshot's inode bitmap and free inode count
+    fn sync_snapshot_inodes(&mut self, index: usize) -> Result<(), SplaxFsError> {
+        let snapshot = &self.snapshots[index];
+        let (id, slot, bitmap_blocks) = (snapshot.id, snapshot.slot, snapshot.bitmap_blocks.clone());
+        let table = self.read_map(&snapshot.map_blocks)?;
+
+        let mut inodes = Vec::new();
+        for (table_index, &block_num) in table.iter().enumerate() {
+            if block_num == 0 {
+                continue;
+            }
+            let data = self.read_block(block_num)?;
+            for slot in 0..INODES_PER_BLOCK {
+                let ino = (table_index * INODES_PER_BLOCK + slot) as u32 + 1;
+                if ino > self.sb.total_inodes {
+                    break;
+                }
+                if ino != 1 && DiskInode::from_bytes(&data[slot * INODE_SIZE..]).mode != 0 {
+                    inodes.push(ino);
+                }
+            }
+        }
+
+        let bitmap = self.inode_bitmap(inodes.iter().copied());
+        let (marked_free, marked_used) = self.sync_bitmap(&bitmap_blocks, &bitmap, self.sb.total_inodes + 1)?;
+        let free_inodes = self.sb.total_inodes - 1 - inodes.len() as u32;
+        let entry = &mut self.snapshot_table.entries[slot];
+        if marked_free + marked_used > 0 || entry.free_inodes != free_inodes {
+            self.problems.push(Problem::SnapshotInodes { id });
+            entry.free_inodes = free_inodes;
+            let table = self.snapshot_table.to_bytes().to_vec();
+            self.write_block(self.sb.snapshot_block, table);
+        }
+        Ok(())
+    }
+
+    fn pass5(&mut self) -> Result<(), SplaxFsError> {
+        self.sync_refcounts()?;
+        let used_blocks = self.block_refs.iter().filter(|&&refs| refs > 0).count() as u32;
+
+        let inode_map = self.inode_bitmap(self.inodes.keys().copied());
+        let blocks: Vec<u32> = (0..self.sb.inode_bitmap_blocks()).map(|i| self.sb.inode_bitmap_block + i).collect();
+        let (marked_free, marked_used) = self.sync_bitmap(&blocks, &inode_map, self.sb.total_inodes + 1)?;
+        if marked_free + marked_used > 0 {
+            self.problems.push(Problem::InodeBitmap { marked_free, marked_used });
+        }
+        for index in 0..self.snapshots.len() {
+            self.sync_snapshot_inodes(index)?;
+        }
+
+        let mut sb = self.sb;
+        let free_blocks = sb.total_blocks - used_blocks;
+        if sb.free_blocks != free_blocks {
+            self.problems.push(Problem::FreeBlocks { found: sb.free_blocks, expected: free_blocks });
+            sb.free_blocks = free_blocks;
+        }
+        // Inode 1 is reserved
+        let free_inodes = sb.total_inodes - 1 - self.inodes.len() as u32;
+        if sb.free_inodes != free_inodes {
+            self.problems.push(Problem::FreeInodes { found: sb.free_inodes, expected: free_inodes });
+            sb.free_inodes = free_inodes;
+        }
+        if sb.saved_blocks != self.saved {
+            self.problems.push(Problem::SavedBlocks { found: sb.saved_blocks, expected: self.saved });
+            sb.saved_blocks = self.saved;
+        }
+        self.write_superblock(&sb)
+    }
+
+    // -------------------------------------------------------------------------
+    // Pass 6: checksums
+    // -------------------------------------------------------------------------
+
+    /// Checks every block in use against the checksum table. Blocks the
+    /// checker changed and blocks that do not match get fresh checksums;
+    /// so do all blocks covered by a damaged table block, whose entries
+    /// cannot be trusted.
+    fn pass6(&mut self) -> Result<(), SplaxFsError> {
+        let sb = self.sb;
+        let mut area = Vec::with_capacity(sb.csum_blocks() as usize * BLOCK_SIZE);
+        for index in 0..sb.csum_blocks() {
+            area.extend(self.read_block(sb.csum_block + index)?);
+        }
+        let mut table = ChecksumTable::from_bytes(area);
+        let damaged = table.bad_blocks(sb.csum_block);
+        self.problems.extend(damaged.iter().map(|&index| Problem::ChecksumTable { index }));
+        let mut dirty: BTreeSet<u32> = damaged.iter().copied().collect();
+
+        // The superblock, the journal and the table are checked otherwise
+        let journal = match sb.journal_block {
+            0 => 0..0,
+            start => start..sb.refcount_block,
+        };
+        let own = sb.csum_block..sb.csum_block + sb.csum_blocks();
+        for block_num in 1..sb.total_blocks {
+            if self.block_refs[block_num as usize] == 0 || journal.contains(&block_num) || own.contains(&block_num) {
+                continue;
+            }
+            let data = self.read_block(block_num)?;
+            if table.verify(block_num, &data).is_ok() {
+                continue;
+            }
+            let index = ChecksumTable::index_of(block_num);
+            if !self.overlay.contains_key(&block_num) && !damaged.contains(&index) {
+                self.problems.push(Problem::Checksum { block: block_num });
+            }
+            table.record(block_num, &data);
+            dirty.insert(index);
+        }
+
+        for index in dirty {
+            self.write_block(sb.csum_block + index, table.block_image(sb.csum_block, index));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mkfs::format;
+    use crate::testing::MemDisk;
+
+    const CHECK: CheckOptions = CheckOptions { repair: false, time: 0 };
+    const REPAIR: CheckOptions = CheckOptions { repair: true, time: 0 };
+
+    /// Freshly formatted volume
+    fn volume(journaled: bool) -> (MemDisk, Superblock) {
+        let disk = MemDisk::new(512);
+        let sb = format(&disk, journaled, 0).unwrap();
+        (disk, sb)
+    }
+
+    fn read(disk: &MemDisk, block_num: u32) -> Vec<u8> {
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        disk.read(block_num, &mut buf).unwrap();
+        buf
+    }
+
+    /// Writes a block and records its checksum, as the driver would
+    fn put(disk: &MemDisk, block_num: u32, data: &[u8]) {
+        disk.write(block_num, data).unwrap();
+        if block_num == 0 {
+            return;
+        }
+        let sb = Superblock::from_bytes(&read(disk, 0));
+        let mut table = ChecksumTable::from_bytes((0..sb.csum_blocks()).flat_map(|i| read(disk, sb.csum_block + i)).collect());
+        table.record(block_num, data);
+        let index = ChecksumTable::index_of(block_num);
+        disk.write(sb.csum_block + index, &table.block_image(sb.csum_block, index)).unwrap();
+    }
+
+    fn update(disk: &MemDisk, block_num: u32, f: impl FnOnce(&mut Vec<u8>)) {
+        let mut buf = read(disk, block_num);
+        f(&mut buf);
+        put(disk, block_num, &buf);
+    }
+
+    /// Where live inode table block `index` is
+    fn table_block(disk: &MemDisk, sb: &Superblock, index: u32) -> u32 {
+        get_u32(&read(disk, sb.inode_map_block), index as usize * 4)
+    }
+
+    /// First block mkfs leaves free
+    fn first_free(sb: &Superblock) -> u32 {
+        sb.first_data_block + 1 + sb.inode_table_blocks()
+    }
+
+    fn get_inode(disk: &MemDisk, sb: &Superblock, ino: u32) -> DiskInode {
+        let index = (ino - 1) as usize;
+        let block_num = table_block(disk, sb, (index / INODES_PER_BLOCK) as u32);
+        DiskInode::from_bytes(&read(disk, block_num)[(index % INODES_PER_BLOCK) * INODE_SIZE..])
+    }
+
+    fn put_inode(disk: &MemDisk, sb: &Superblock, ino: u32, inode: &DiskInode) {
+        let index = (ino - 1) as usize;
+        let block_num = table_block(disk, sb, (index / INODES_PER_BLOCK) as u32);
+        let offset = (index % INODES_PER_BLOCK) * INODE_SIZE;
+        update(disk, block_num, |buf| buf[offset..offset + INODE_SIZE].copy_from_slice(&inode.to_bytes()));
+    }
+
+    fn put_entry(disk: &MemDisk, block_num: u32, slot: usize, entry: &DirEntry) {
+        let offset = slot * DIRENT_SIZE;
+        update(disk, block_num, |buf| buf[offset..offset + DIRENT_SIZE].copy_from_slice(&entry.to_bytes()));
+    }
+
+    /// Marks a block and an inode used, keeping the free counts right; the
+    /// block keeps its content
+    fn allocate(disk: &MemDisk, block_num: Option<u32>, ino: Option<u32>) {
+        let mut sb = Superblock::from_bytes(&read(disk, 0));
+        if let Some(block_num) = block_num {
+            update(disk, block_num, |_| {});
+            update(disk, sb.refcount_block, |buf| set_ref(buf, block_num, 1));
+            sb.free_blocks -= 1;
+        }
+        if let Some(ino) = ino {
+            update(disk, sb.inode_bitmap_block, |buf| set_bit(buf, ino, true));
+            sb.free_inodes -= 1;
+        }
+        update(disk, 0, |buf| buf[..512].copy_from_slice(&sb.to_bytes()));
+    }
+
+    /// A consistent one-block regular file
+    fn file(disk: &MemDisk, sb: &Superblock, ino: u32, block_num: u32, size: u64) -> DiskInode {
+        let mut inode = DiskInode::new_file();
+        inode.direct[0] = block_num;
+        inode.blocks = SECTORS_PER_BLOCK;
+        inode.set_size(size);
+        put_inode(disk, sb, ino, &inode);
+        allocate(disk, Some(block_num), Some(ino));
+        inode
+    }
+
+    /// Snapshots the live tree the way the kernel does: private copies of
+    /// the inode map and bitmap, shared inode table blocks
+    fn take_snapshot(disk: &MemDisk, name: &str) -> u32 {
+        let mut sb = Superblock::from_bytes(&read(disk, 0));
+        let mut refs = read(disk, sb.refcount_block);
+        let mut blocks = Vec::new();
+        for _ in 0..=sb.snapshot_root_ptrs() {
+            let block_num = find_free(&refs, sb.first_data_block, sb.total_blocks).unwrap();
+            set_ref(&mut refs, block_num, 1);
+            blocks.push(block_num);
+        }
+        sb.free_blocks -= blocks.len() as u32;
+
+        let root = blocks[0];
+        let mut ptrs = vec![0u8; BLOCK_SIZE];
+        let sources = (0..sb.inode_map_blocks())
+            .map(|i| sb.inode_map_block + i)
+            .chain((0..sb.inode_bitmap_blocks()).map(|i| sb.inode_bitmap_block + i));
+        for (i, (source, &copy)) in sources.zip(&blocks[1..]).enumerate() {
+            put(disk, copy, &read(disk, source));
+            ptrs[i * 4..i * 4 + 4].copy_from_slice(&copy.to_le_bytes());
+        }
+        put(disk, root, &ptrs);
+        for index in 0..sb.inode_table_blocks() {
+            let block_num = table_block(disk, &sb, index);
+            let count = get_ref(&refs, block_num);
+            set_ref(&mut refs, block_num, count + 1);
+        }
+        put(disk, sb.refcount_block, &refs);
+
+        let mut table = SnapshotTable::from_bytes(&read(disk, sb.snapshot_block));
+        let id = table.next_id;
+        table.next_id += 1;
+        let slot = table.entries.iter().position(|entry| entry.id == 0).unwrap();
+        table.entries[slot] = SnapshotEntry::new(id, name, 0, 0);
+        table.entries[slot].root = root;
+        table.entries[slot].free_inodes = sb.free_inodes;
+        put(disk, sb.snapshot_block, &table.to_bytes());
+        update(disk, 0, |buf| buf[..512].copy_from_slice(&sb.to_bytes()));
+        id
+    }
+
+    /// Names in a directory, `.` and `..` included
+    fn names(disk: &MemDisk, dir: u32) -> Vec<(String, u32)> {
+        let mut checker = Checker::new(disk, BTreeMap::new(), CHECK).unwrap();
+        checker.pass1().unwrap();
+        checker
+            .dir_slots(&checker.inodes[&dir])
+            .unwrap()
+            .into_iter()
+            .filter(|(_, _, entry)| entry.inode != 0)
+            .map(|(_, _, entry)| (String::from(entry.name_str()), entry.inode))
+            .collect()
+    }
+
+    #[test]
+    fn test_fresh_volume_is_clean() {
+        for journaled in [false, true] {
+            let (disk, sb) = volume(journaled);
+            let report = check(&disk, &CHECK).unwrap();
+            assert!(report.is_clean(), "{:?}", report.problems);
+            assert_eq!(report.directories, 1);
+            assert_eq!(report.used_blocks, first_free(&sb));
+            assert!(check(&disk, &REPAIR).unwrap().is_clean());
+        }
+    }
+
+    #[test]
+    fn test_bad_superblock_is_fatal() {
+        let (disk, _) = volume(false);
+        update(&disk, 0, |buf| buf[0] ^= 0xFF);
+        assert_eq!(check(&disk, &REPAIR).err(), Some(SplaxFsError::Corrupted));
+    }
+
+    #[test]
+    fn test_unattached_file_moves_to_lost_and_found() {
+        let (disk, sb) = volume(true);
+        let data = first_free(&sb);
+        file(&disk, &sb, 3, data, 100);
+        put_entry(&disk, sb.first_data_block, 2, &DirEntry::new(3, "kept", FileType::Regular));
+        // Inode 4 is allocated but nothing names it
+        file(&disk, &sb, 4, data + 1, 100);
+
+        let table = table_block(&disk, &sb, 0);
+        let before = read(&disk, table);
+        let report = check(&disk, &CHECK).unwrap();
+        assert!(report.problems.contains(&Problem::NoLostFound));
+        assert!(report.problems.contains(&Problem::Unattached { ino: 4 }));
+        assert!(!report.repaired);
+        assert_eq!(read(&disk, table), before);
+
+        let repaired = check(&disk, &REPAIR).unwrap();
+        assert_eq!(repaired.problems, report.problems);
+        assert!(repaired.repaired);
+        let report = check(&disk, &CHECK).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+        assert_eq!((report.files, report.directories), (2, 2));
+
+        let root = names(&disk, ROOT_INODE);
+        let (_, lost) = root.iter().find(|(name, _)| name == LOST_AND_FOUND).unwrap().clone();
+        assert!(names(&disk, lost).contains(&(String::from("#4"), 4)));
+    }
+
+    #[test]
+    fn test_bitmaps_and_counts() {
+        let (disk, sb) = volume(false);
+        let root_block = sb.first_data_block;
+        let table = table_block(&disk, &sb, 0);
+        update(&disk, sb.refcount_block, |buf| {
+            set_ref(buf, root_block, 0);
+            set_ref(buf, first_free(&sb) + 10, 1);
+            set_ref(buf, table, 3);
+        });
+        update(&disk, sb.inode_bitmap_block, |buf| set_bit(buf, 50, true));
+        let mut root = get_inode(&disk, &sb, ROOT_INODE);
+        root.links_count = 7;
+        put_inode(&disk, &sb, ROOT_INODE, &root);
+
+        let report = check(&disk, &REPAIR).unwrap();
+        assert_eq!(
+            report.problems,
+            [
+                Problem::LinkCount { ino: ROOT_INODE, found: 7, expected: 2 },
+                Problem::BlockRefs { marked_free: 1, marked_used: 1, miscounted: 1 },
+                Problem::InodeBitmap { marked_free: 0, marked_used: 1 },
+            ]
+        );
+        assert!(check(&disk, &CHECK).unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_block_map_problems() {
+        let (disk, sb) = volume(false);
+        let data = first_free(&sb);
+        let mut inode = file(&disk, &sb, 3, data, 3 * BLOCK_SIZE as u64);
+        // Metadata block, the root directory's block, a good block
+        inode.direct[1] = sb.inode_map_block;
+        inode.direct[2] = sb.first_data_block;
+        inode.direct[5] = data + 1;
+        inode.blocks = 99;
+        put_inode(&disk, &sb, 3, &inode);
+        allocate(&disk, Some(data + 1), None);
+        put_entry(&disk, sb.first_data_block, 2, &DirEntry::new(3, "f", FileType::Regular));
+
+        let report = check(&disk, &REPAIR).unwrap();
+        assert_eq!(
+            report.problems,
+            [
+                Problem::IllegalBlock { ino: 3, block: sb.inode_map_block },
+                Problem::DuplicateBlock { ino: 3, block: sb.first_data_block },
+                Problem::Size { ino: 3, found: 3 * BLOCK_SIZE as u64, expected: 6 * BLOCK_SIZE as u64 },
+                Problem::BlockCount { ino: 3, found: 99, expected: 2 * SECTORS_PER_BLOCK },
+            ]
+        );
+        let report = check(&disk, &CHECK).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+    }
+
+    #[test]
+    fn test_cluster_problems() {
+        let (disk, sb) = volume(false);
+        let data = first_free(&sb);
+        // Three blocks of data compressed into one
+        let mut inode = file(&disk, &sb, 3, data, 3 * BLOCK_SIZE as u64);
+        inode.flags = INODE_COMPRESS_LZ4 | INODE_COMPRESSED;
+        inode.direct[1] = COMPRESSED_SLOT;
+        inode.direct[2] = COMPRESSED_SLOT;
+        put_inode(&disk, &sb, 3, &inode);
+        put_entry(&disk, sb.first_data_block, 2, &DirEntry::new(3, "f", FileType::Regular));
+        // Saved slots in a file without compressed clusters, and a cluster
+        // without blocks
+        let mut other = file(&disk, &sb, 4, data + 1, 2 * BLOCK_SIZE as u64);
+        other.direct[1] = COMPRESSED_SLOT;
+        put_inode(&disk, &sb, 4, &other);
+        let mut other = file(&disk, &sb, 5, data + 2, 6 * BLOCK_SIZE as u64);
+        other.flags = INODE_COMPRESSED;
+        other.direct[4] = COMPRESSED_SLOT;
+        put_inode(&disk, &sb, 5, &other);
+        put_entry(&disk, sb.first_data_block, 3, &DirEntry::new(4, "g", FileType::Regular));
+        put_entry(&disk, sb.first_data_block, 4, &DirEntry::new(5, "h", FileType::Regular));
+
+        let report = check(&disk, &REPAIR).unwrap();
+        assert_eq!(
+            report.problems,
+            [
+                Problem::BadCluster { ino: 4, index: 0 },
+                Problem::BadCluster { ino: 5, index: 4 },
+                Problem::SavedBlocks { found: 0, expected: 2 },
+            ]
+        );
+        assert_eq!(get_inode(&disk, &sb, 4).direct[1], 0);
+        assert_eq!(get_inode(&disk, &sb, 3).direct, inode.direct);
+        let report = check(&disk, &CHECK).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+    }
+
+    #[test]
+    fn test_xattr_problems() {
+        let (disk, sb) = volume(false);
+        let data = first_free(&sb);
+        let attrs = vec![
+            (String::from("user.a"), vec![1u8; 8]),
+            (String::from("user.big"), vec![2u8; 600]),
+        ];
+        let (inline, block) = xattr::encode(&attrs).unwrap();
+        for (ino, block_num, slot) in [(3, data, 2), (4, data + 2, 3)] {
+            let mut inode = file(&disk, &sb, ino, block_num, 10);
+            inode.xattr_inline = inline;
+            inode.xattr_block = block_num + 1;
+            inode.blocks += SECTORS_PER_BLOCK;
+            put_inode(&disk, &sb, ino, &inode);
+            put(&disk, block_num + 1, block.as_ref().unwrap());
+            allocate(&disk, Some(block_num + 1), None);
+            let name = format!("f{}", ino);
+            put_entry(&disk, sb.first_data_block, slot, &DirEntry::new(ino, &name, FileType::Regular));
+        }
+        assert!(check(&disk, &CHECK).unwrap().is_clean());
+
+        update(&disk, data + 3, |buf| buf[4] = 9);
+        let report = check(&disk, &REPAIR).unwrap();
+        assert_eq!(
+            report.problems,
+            [
+                Problem::BadXattrs { ino: 4 },
+                Problem::BlockCount { ino: 4, found: 2 * SECTORS_PER_BLOCK, expected: SECTORS_PER_BLOCK },
+                Problem::BlockRefs { marked_free: 0, marked_used: 1, miscounted: 0 },
+                Problem::FreeBlocks { found: sb.free_blocks - 4, expected: sb.free_blocks - 3 },
+            ]
+        );
+        let inode = get_inode(&disk, &sb, 4);
+        assert_eq!((inode.xattr_block, inode.xattr_inline), (0, [0; XATTR_INLINE_SIZE]));
+        assert!(check(&disk, &CHECK).unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_checksum_problems() {
+        let (disk, sb) = volume(true);
+        let data = first_free(&sb);
+        put(&disk, data, &[5u8; BLOCK_SIZE]);
+        file(&disk, &sb, 3, data, 10);
+        put_entry(&disk, sb.first_data_block, 2, &DirEntry::new(3, "f", FileType::Regular));
+        assert!(check(&disk, &CHECK).unwrap().is_clean());
+
+        // A bit flips behind the driver's back; repair accepts the content
+        let mut buf = read(&disk, data);
+        buf[100] ^= 1;
+        disk.write(data, &buf).unwrap();
+        let report = check(&disk, &REPAIR).unwrap();
+        assert_eq!(report.problems, [Problem::Checksum { block: data }]);
+        assert!(check(&disk, &CHECK).unwrap().is_clean());
+
+        // A damaged table block is rebuilt without blaming the blocks in it
+        let mut buf = read(&disk, sb.csum_block);
+        buf[data as usize * 4] ^= 1;
+        disk.write(sb.csum_block, &buf).unwrap();
+        let report = check(&disk, &REPAIR).unwrap();
+        assert_eq!(report.problems, [Problem::ChecksumTable { index: 0 }]);
+        assert!(check(&disk, &CHECK).unwrap().is_clean());
+
+        update(&disk, 0, |buf| buf[508] ^= 1);
+        let report = check(&disk, &REPAIR).unwrap();
+        assert_eq!(report.problems, [Problem::SuperblockChecksum]);
+        assert!(check(&disk, &CHECK).unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_directory_problems() {
+        let (disk, sb) = volume(false);
+        let root_block = sb.first_data_block;
+        let free = first_free(&sb);
+        let (a_block, b_block) = (free, free + 1);
+
+        // /a, and directory 4 holding file 5, which nothing links to
+        for (ino, block_num, parent) in [(3, a_block, ROOT_INODE), (4, b_block, 3)] {
+            let mut dir = DiskInode::new_directory();
+            dir.direct[0] = block_num;
+            dir.blocks = SECTORS_PER_BLOCK;
+            dir.set_size(BLOCK_SIZE as u64);
+            put_inode(&disk, &sb, ino, &dir);
+            allocate(&disk, Some(block_num), Some(ino));
+            put_entry(&disk, block_num, 0, &DirEntry::new(ino, ".", FileType::Directory));
+            put_entry(&disk, block_num, 1, &DirEntry::new(parent, "..", FileType::Directory));
+        }
+        file(&disk, &sb, 5, free + 2, 10);
+        put_entry(&disk, b_block, 2, &DirEntry::new(5, "inner", FileType::Regular));
+        put_entry(&disk, root_block, 2, &DirEntry::new(3, "a", FileType::Symlink));
+        put_entry(&disk, root_block, 3, &DirEntry::new(60, "ghost", FileType::Regular));
+        put_entry(&disk, root_block, 4, &DirEntry::new(3, "a", FileType::Directory));
+        put_entry(&disk, a_block, 2, &DirEntry::new(3, "self", FileType::Directory));
+        let mut root = get_inode(&disk, &sb, ROOT_INODE);
+        root.links_count = 3;
+        put_inode(&disk, &sb, ROOT_INODE, &root);
+
+        let report = check(&disk, &REPAIR).unwrap();
+        for problem in [
+            Problem::EntryType { dir: ROOT_INODE, name: String::from("a") },
+            Problem::BadEntry { dir: ROOT_INODE, name: String::from("ghost"), ino: 60 },
+            Problem::DuplicateEntry { dir: ROOT_INODE, name: String::from("a") },
+            Problem::DirectoryLink { dir: 3, name: String::from("self"), ino: 3 },
+            Problem::DisconnectedDir { ino: 4 },
+            Problem::BadDotDot { dir: 4, found: 3, expected: 6 },
+        ] {
+            assert!(report.problems.contains(&problem), "{:?} not in {:?}", problem, report.problems);
+        }
+        let report = check(&disk, &CHECK).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+
+        // The lost directory keeps its content
+        assert!(names(&disk, 4).contains(&(String::from("inner"), 5)));
+        assert!(names(&disk, 6).contains(&(String::from("#4"), 4)));
+    }
+
+    #[test]
+    fn test_encrypted_names() {
+        let (disk, sb) = volume(false);
+        let free = first_free(&sb);
+
+        // /a is encrypted: its names are ciphertext, never text
+        let mut dir = DiskInode::new_directory();
+        dir.direct[0] = free;
+        dir.blocks = SECTORS_PER_BLOCK;
+        dir.set_size(BLOCK_SIZE as u64);
+        dir.flags |= INODE_ENCRYPTED;
+        put_inode(&disk, &sb, 3, &dir);
+        allocate(&disk, Some(free), Some(3));
+        put_entry(&disk, free, 0, &DirEntry::new(3, ".", FileType::Directory));
+        put_entry(&disk, free, 1, &DirEntry::new(ROOT_INODE, "..", FileType::Directory));
+        put_entry(&disk, sb.first_data_block, 2, &DirEntry::new(3, "a", FileType::Directory));
+        let mut root = get_inode(&disk, &sb, ROOT_INODE);
+        root.links_count = 3;
+        put_inode(&disk, &sb, ROOT_INODE, &root);
+
+        let stored = [0xC3u8; 20];
+        file(&disk, &sb, 4, free + 1, 10);
+        put_entry(&disk, free, 2, &DirEntry::from_raw(4, &stored, FileType::Regular));
+        file(&disk, &sb, 5, free + 2, 10);
+        put_entry(&disk, free, 3, &DirEntry::new(5, "plain", FileType::Regular));
+
+        let report = check(&disk, &REPAIR).unwrap();
+        assert_eq!(
+            report.problems[..2],
+            [
+                Problem::BadEntry { dir: 3, name: crypt::encode_name(b"plain"), ino: 5 },
+                Problem::NoLostFound,
+            ]
+        );
+        let report = check(&disk, &CHECK).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+        assert!(names(&disk, 3).iter().any(|(_, ino)| *ino == 4));
+    }
+
+    #[test]
+    fn test_snapshots() {
+        let (disk, sb) = volume(true);
+        let data = first_free(&sb);
+        file(&disk, &sb, 3, data, 100);
+        put_entry(&disk, sb.first_data_block, 2, &DirEntry::new(3, "kept", FileType::Regular));
+        let used = check(&disk, &CHECK).unwrap().used_blocks;
+        let id = take_snapshot(&disk, "before");
+
+        let report = check(&disk, &CHECK).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+        assert_eq!(report.snapshots, 1);
+        assert_eq!(report.used_blocks, used + 1 + sb.inode_map_blocks() + sb.inode_bitmap_blocks());
+
+        // A wrong free count in the snapshot is its own problem
+        update(&disk, sb.snapshot_block, |buf| {
+            let mut table = SnapshotTable::from_bytes(buf);
+            table.entries[0].free_inodes += 1;
+            buf.copy_from_slice(&table.to_bytes());
+        });
+        assert_eq!(check(&disk, &REPAIR).unwrap().problems, [Problem::SnapshotInodes { id }]);
+
+        // A snapshot whose root is unusable is dropped and its space reclaimed
+        update(&disk, sb.snapshot_block, |buf| {
+            let mut table = SnapshotTable::from_bytes(buf);
+            table.entries[0].root = sb.refcount_block;
+            buf.copy_from_slice(&table.to_bytes());
+        });
+        let report = check(&disk, &REPAIR).unwrap();
+        assert_eq!(report.problems[0], Problem::BadSnapshot { id });
+        let report = check(&disk, &CHECK).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+        assert_eq!((report.snapshots, report.used_blocks, report.files), (0, used, 1));
+    }
+}
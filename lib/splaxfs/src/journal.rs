@@ -0,0 +1,495 @@
+//! Redo journal
+//!
+//! Journaled volumes log each transaction (the new images of the metadata
+//! blocks it changes) before writing anything in place. A transaction whose
+//! commit record made it to disk is replayed on the next mount.
+
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use splax_crypto::crc32c;
+
+use crate::layout::{get_u32, SplaxFsError, BLOCK_SIZE};
+use crate::BlockIo;
+
+/// Journal magic number ("JRNL")
+const JOURNAL_MAGIC: u32 = 0x4A524E4C;
+
+/// Journal version; version 1 logs had a commit checksum that only covered
+/// the start of the transaction and are not replayed
+const JOURNAL_VERSION: u32 = 2;
+
+/// Journal entry type of a transaction descriptor
+const ENTRY_DESCRIPTOR: u8 = 0;
+
+/// Journal entry type of a commit record
+const ENTRY_COMMIT: u8 = 1;
+
+/// Journal entry state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum JournalState {
+    /// Entry is free
+    Free = 0,
+    /// Transaction in progress (not yet committed)
+    Pending = 1,
+    /// Transaction committed (can be replayed)
+    Committed = 2,
+    /// Transaction checkpointed (applied to disk)
+    Checkpointed = 3,
+}
+
+impl From<u8> for JournalState {
+    fn from(val: u8) -> Self {
+        match val {
+            1 => JournalState::Pending,
+            2 => JournalState::Committed,
+            3 => JournalState::Checkpointed,
+            _ => JournalState::Free,
+        }
+    }
+}
+
+/// Journal superblock (first block of the journal)
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct JournalSuperblock {
+    /// Magic number (JOURNAL_MAGIC)
+    pub magic: u32,
+    /// Journal version
+    pub version: u32,
+    /// First log block number
+    pub first_log_block: u32,
+    /// Number of log blocks
+    pub log_blocks: u32,
+    /// Head position (next write)
+    pub head: u32,
+    /// Tail position (oldest uncommitted)
+    pub tail: u32,
+    /// ID of the next transaction; a logged transaction with this ID has
+    /// not been checkpointed yet
+    pub sequence: u64,
+    /// Number of active transactions
+    pub active_transactions: u32,
+    /// Reserved for future use
+    pub _reserved: [u8; 476],
+}
+
+const _: () = assert!(core::mem::size_of::<JournalSuperblock>() == 512);
+
+impl JournalSuperblock {
+    /// Creates a new journal superblock
+    pub fn new(first_log_block: u32, log_blocks: u32) -> Self {
+        Self {
+            magic: JOURNAL_MAGIC,
+            version: JOURNAL_VERSION,
+            first_log_block,
+            log_blocks,
+            head: 0,
+            tail: 0,
+            sequence: 1,
+            active_transactions: 0,
+            _reserved: [0; 476],
+        }
+    }
+
+    /// Validates the journal superblock
+    pub fn is_valid(&self) -> bool {
+        self.magic == JOURNAL_MAGIC && self.version == JOURNAL_VERSION
+    }
+
+    /// Serialize to bytes
+    pub fn to_bytes(&self) -> [u8; 512] {
+        let mut buf = [0u8; 512];
+        unsafe {
+            let ptr = self as *const Self as *const u8;
+            core::ptr::copy_nonoverlapping(ptr, buf.as_mut_ptr(), 512);
+        }
+        buf
+    }
+
+    /// Deserialize from bytes
+    pub fn from_bytes(buf: &[u8]) -> Self {
+        let mut jsb = Self {
+            magic: 0,
+            version: 0,
+            first_log_block: 0,
+            log_blocks: 0,
+            head: 0,
+            tail: 0,
+            sequence: 0,
+            active_transactions: 0,
+            _reserved: [0; 476],
+        };
+        unsafe {
+            let ptr = &mut jsb as *mut Self as *mut u8;
+            core::ptr::copy_nonoverlapping(buf.as_ptr(), ptr, core::cmp::min(buf.len(), 512));
+        }
+        jsb
+    }
+}
+
+/// A journal transaction entry (header)
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct JournalEntry {
+    /// Entry type (0=descriptor, 1=commit, 2=abort)
+    pub entry_type: u8,
+    /// Entry state
+    pub state: u8,
+    /// Number of block updates in this transaction
+    pub block_count: u16,
+    /// Transaction ID
+    pub transaction_id: u64,
+    /// Timestamp
+    pub timestamp: u64,
+    /// Checksum
+    pub checksum: u32,
+    /// Reserved
+    pub _reserved: [u8; 8],
+}
+
+impl JournalEntry {
+    /// Size of entry header
+    pub const SIZE: usize = 32;
+
+    /// Creates a new transaction descriptor
+    pub fn new_descriptor(transaction_id: u64, block_count: u16, timestamp: u64) -> Self {
+        Self {
+            entry_type: ENTRY_DESCRIPTOR,
+            state: JournalState::Pending as u8,
+            block_count,
+            transaction_id,
+            timestamp,
+            checksum: 0,
+            _reserved: [0; 8],
+        }
+    }
+
+    /// Creates a commit marker
+    pub fn new_commit(transaction_id: u64, timestamp: u64) -> Self {
+        Self {
+            entry_type: ENTRY_COMMIT,
+            state: JournalState::Committed as u8,
+            block_count: 0,
+            transaction_id,
+            timestamp,
+            checksum: 0,
+            _reserved: [0; 8],
+        }
+    }
+
+    /// Creates an abort marker
+    pub fn new_abort(transaction_id: u64, timestamp: u64) -> Self {
+        Self {
+            entry_type: 2,
+            state: JournalState::Free as u8,
+            block_count: 0,
+            transaction_id,
+            timestamp,
+            checksum: 0,
+            _reserved: [0; 8],
+        }
+    }
+
+    /// Serialize to bytes
+    pub fn to_bytes(&self) -> [u8; 32] {
+        let mut buf = [0u8; 32];
+        buf[0] = self.entry_type;
+        buf[1] = self.state;
+        buf[2..4].copy_from_slice(&self.block_count.to_le_bytes());
+        buf[4..12].copy_from_slice(&self.transaction_id.to_le_bytes());
+        buf[12..20].copy_from_slice(&self.timestamp.to_le_bytes());
+        buf[20..24].copy_from_slice(&self.checksum.to_le_bytes());
+        buf
+    }
+
+    /// Deserialize from bytes
+    pub fn from_bytes(buf: &[u8]) -> Self {
+        Self {
+            entry_type: buf.first().copied().unwrap_or(0),
+            state: buf.get(1).copied().unwrap_or(0),
+            block_count: u16::from_le_bytes([buf.get(2).copied().unwrap_or(0), buf.get(3).copied().unwrap_or(0)]),
+            transaction_id: u64::from_le_bytes([
+                buf.get(4).copied().unwrap_or(0), buf.get(5).copied().unwrap_or(0),
+                buf.get(6).copied().unwrap_or(0), buf.get(7).copied().unwrap_or(0),
+                buf.get(8).copied().unwrap_or(0), buf.get(9).copied().unwrap_or(0),
+                buf.get(10).copied().unwrap_or(0), buf.get(11).copied().unwrap_or(0),
+            ]),
+            timestamp: u64::from_le_bytes([
+                buf.get(12).copied().unwrap_or(0), buf.get(13).copied().unwrap_or(0),
+                buf.get(14).copied().unwrap_or(0), buf.get(15).copied().unwrap_or(0),
+                buf.get(16).copied().unwrap_or(0), buf.get(17).copied().unwrap_or(0),
+                buf.get(18).copied().unwrap_or(0), buf.get(19).copied().unwrap_or(0),
+            ]),
+            checksum: u32::from_le_bytes([
+                buf.get(20).copied().unwrap_or(0), buf.get(21).copied().unwrap_or(0),
+                buf.get(22).copied().unwrap_or(0), buf.get(23).copied().unwrap_or(0),
+            ]),
+            _reserved: [0; 8],
+        }
+    }
+}
+
+/// Redo journal of a volume
+///
+/// One transaction is in the log at a time:
+///
+/// ```text
+/// log block 0        descriptor: JournalEntry + target block numbers
+/// log blocks 1..=n   new images of the target blocks
+/// log block n+1      commit: JournalEntry with checksum of the above
+/// ```
+pub struct Journal {
+    /// Block holding the journal superblock
+    block: u32,
+    /// Journal superblock (cached)
+    superblock: JournalSuperblock,
+}
+
+impl Journal {
+    /// Initializes a new journal at `block`, followed by its log area
+    pub fn format(dev: &impl BlockIo, block: u32, log_blocks: u32) -> Result<(), SplaxFsError> {
+        let jsb = JournalSuperblock::new(block + 1, log_blocks);
+        let mut block_buf = vec![0u8; BLOCK_SIZE];
+        block_buf[..512].copy_from_slice(&jsb.to_bytes());
+        dev.write(block, &block_buf)?;
+
+        // A zeroed descriptor never matches a sequence number
+        dev.write(block + 1, &[0u8; BLOCK_SIZE])
+    }
+
+    /// Opens an existing journal
+    pub fn open(dev: &impl BlockIo, block: u32) -> Result<Self, SplaxFsError> {
+        let mut block_buf = vec![0u8; BLOCK_SIZE];
+        dev.read(block, &mut block_buf)?;
+        let jsb = JournalSuperblock::from_bytes(&block_buf);
+
+        if !jsb.is_valid() || jsb.log_blocks < 3 {
+            return Err(SplaxFsError::JournalError);
+        }
+
+        Ok(Self { block, superblock: jsb })
+    }
+
+    /// Journal superblock
+    pub fn superblock(&self) -> &JournalSuperblock {
+        &self.superblock
+    }
+
+    /// Largest number of blocks one transaction can update
+    pub fn capacity(&self) -> usize {
+        let per_descriptor = (BLOCK_SIZE - JournalEntry::SIZE) / 4;
+        (self.superblock.log_blocks as usize - 2).min(per_descriptor)
+    }
+
+    /// CRC-32C of the transaction ID and every target and block image
+    fn checksum(id: u64, targets: &[u32], images: &[&[u8]]) -> u32 {
+        let mut crc = crc32c(!0, &id.to_le_bytes());
+        for (target, image) in targets.iter().zip(images) {
+            crc = crc32c(crc, &target.to_le_bytes());
+            crc = crc32c(crc, image);
+        }
+        !crc
+    }
+
+    /// Makes a set of block updates durable and applies them in place
+    pub fn commit(&mut self, dev: &impl BlockIo, blocks: &BTreeMap<u32, Vec<u8>>, timestamp: u64) -> Result<(), SplaxFsError> {
+        if blocks.len() > self.capacity() {
+            return Err(SplaxFsError::JournalError);
+        }
+
+        let id = self.superblock.sequence;
+        let log = self.superblock.first_log_block;
+        let targets: Vec<u32> = blocks.keys().copied().collect();
+        let images: Vec<&[u8]> = blocks.values().map(|data| data.as_slice()).collect();
+
+        let mut descriptor = vec![0u8; BLOCK_SIZE];
+        descriptor[..JournalEntry::SIZE]
+            .copy_from_slice(&JournalEntry::new_descriptor(id, targets.len() as u16, timestamp).to_bytes());
+        for (i, target) in targets.iter().enumerate() {
+            let offset = JournalEntry::SIZE + i * 4;
+            descriptor[offset..offset + 4].copy_from_slice(&target.to_le_bytes());
+        }
+        dev.write(log, &descriptor)?;
+        for (i, image) in images.iter().enumerate() {
+            dev.write(log + 1 + i as u32, image)?;
+        }
+        // File data and the log must be stable before the commit record
+        dev.flush()?;
+
+        let mut commit = JournalEntry::new_commit(id, timestamp);
+        commit.checksum = Self::checksum(id, &targets, &images);
+        let mut record = vec![0u8; BLOCK_SIZE];
+        record[..JournalEntry::SIZE].copy_from_slice(&commit.to_bytes());
+        dev.write(log + 1 + targets.len() as u32, &record)?;
+        dev.flush()?;
+
+        // Checkpoint
+        for (target, image) in targets.iter().zip(&images) {
+            dev.write(*target, image)?;
+        }
+        dev.flush()?;
+
+        self.superblock.sequence = id + 1;
+        self.sync_superblock(dev)
+    }
+
+    /// Returns the block images of a committed transaction that has not
+    /// been checkpointed, without writing anything
+    pub fn pending(&self, dev: &impl BlockIo) -> Result<Option<BTreeMap<u32, Vec<u8>>>, SplaxFsError> {
+        let id = self.superblock.sequence;
+        let log = self.superblock.first_log_block;
+
+        let mut block_buf = vec![0u8; BLOCK_SIZE];
+        dev.read(log, &mut block_buf)?;
+        let descriptor = JournalEntry::from_bytes(&block_buf);
+        let count = descriptor.block_count as usize;
+        if descriptor.entry_type != ENTRY_DESCRIPTOR
+            || descriptor.transaction_id != id
+            || count == 0
+            || count > self.capacity()
+        {
+            return Ok(None);
+        }
+
+        let targets: Vec<u32> = (0..count)
+            .map(|i| get_u32(&block_buf, JournalEntry::SIZE + i * 4))
+            .collect();
+        let mut images = Vec::with_capacity(count);
+        for i in 0..count {
+            let mut image = vec![0u8; BLOCK_SIZE];
+            dev.read(log + 1 + i as u32, &mut image)?;
+            images.push(image);
+        }
+
+        dev.read(log + 1 + count as u32, &mut block_buf)?;
+        let commit = JournalEntry::from_bytes(&block_buf);
+        let image_refs: Vec<&[u8]> = images.iter().map(|image| image.as_slice()).collect();
+        if commit.entry_type != ENTRY_COMMIT
+            || commit.transaction_id != id
+            || commit.checksum != Self::checksum(id, &targets, &image_refs)
+        {
+            // Crashed before the commit record: the transaction never happened
+            return Ok(None);
+        }
+
+        if targets.iter().any(|&target| target == 0 || target >= dev.total_blocks()) {
+            return Err(SplaxFsError::JournalError);
+        }
+        Ok(Some(targets.into_iter().zip(images).collect()))
+    }
+
+    /// Replays a committed transaction that was not checkpointed
+    ///
+    /// Returns the number of transactions replayed.
+    pub fn recover(&mut self, dev: &impl BlockIo) -> Result<usize, SplaxFsError> {
+        let Some(blocks) = self.pending(dev)? else {
+            return Ok(0);
+        };
+        for (target, image) in &blocks {
+            dev.write(*target, image)?;
+        }
+        dev.flush()?;
+
+        self.superblock.sequence += 1;
+        self.sync_superblock(dev)?;
+        Ok(1)
+    }
+
+    /// Writes journal superblock to disk
+    fn sync_superblock(&self, dev: &impl BlockIo) -> Result<(), SplaxFsError> {
+        let mut block_buf = vec![0u8; BLOCK_SIZE];
+        block_buf[..512].copy_from_slice(&self.superblock.to_bytes());
+        dev.write(self.block, &block_buf)?;
+        dev.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MemDisk;
+
+    #[test]
+    fn test_journal_replay() {
+        let disk = MemDisk::new(64);
+        Journal::format(&disk, 1, 16).unwrap();
+        let mut journal = Journal::open(&disk, 1).unwrap();
+        let sequence = journal.superblock.sequence;
+
+        let target = 40;
+        let mut blocks = BTreeMap::new();
+        blocks.insert(target, vec![0xABu8; BLOCK_SIZE]);
+        journal.commit(&disk, &blocks, 0).unwrap();
+
+        // Crash after the commit record, before the checkpoint finished
+        disk.write(target, &[0u8; BLOCK_SIZE]).unwrap();
+        journal.superblock.sequence = sequence;
+        journal.sync_superblock(&disk).unwrap();
+
+        let mut journal = Journal::open(&disk, 1).unwrap();
+        assert_eq!(journal.pending(&disk).unwrap().map(|blocks| blocks.len()), Some(1));
+        assert_eq!(journal.recover(&disk), Ok(1));
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        disk.read(target, &mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 0xAB));
+        assert_eq!(journal.recover(&disk), Ok(0));
+    }
+
+    #[test]
+    fn test_torn_commit_is_ignored() {
+        let disk = MemDisk::new(64);
+        Journal::format(&disk, 1, 16).unwrap();
+        let mut journal = Journal::open(&disk, 1).unwrap();
+        let sequence = journal.superblock.sequence;
+
+        let mut blocks = BTreeMap::new();
+        blocks.insert(40, vec![0xCDu8; BLOCK_SIZE]);
+        journal.commit(&disk, &blocks, 0).unwrap();
+        journal.superblock.sequence = sequence;
+        journal.sync_superblock(&disk).unwrap();
+
+        // The commit record never reached the disk
+        disk.write(2 + 1 + 1, &[0u8; BLOCK_SIZE]).unwrap();
+        let mut journal = Journal::open(&disk, 1).unwrap();
+        assert_eq!(journal.pending(&disk), Ok(None));
+        assert_eq!(journal.recover(&disk), Ok(0));
+    }
+
+    #[test]
+    fn test_damaged_image_is_ignored() {
+        let disk = MemDisk::new(64);
+        Journal::format(&disk, 1, 16).unwrap();
+        let mut journal = Journal::open(&disk, 1).unwrap();
+        let sequence = journal.superblock.sequence;
+
+        let mut blocks = BTreeMap::new();
+        blocks.insert(40, vec![0x11u8; BLOCK_SIZE]);
+        blocks.insert(41, vec![0x22u8; BLOCK_SIZE]);
+        journal.commit(&disk, &blocks, 0).unwrap();
+        journal.superblock.sequence = sequence;
+        journal.sync_superblock(&disk).unwrap();
+
+        // Flip one byte at the end of the second logged image
+        let image = 2 + 1 + 1;
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        disk.read(image, &mut buf).unwrap();
+        buf[BLOCK_SIZE - 1] ^= 0xFF;
+        disk.write(image, &buf).unwrap();
+
+        let journal = Journal::open(&disk, 1).unwrap();
+        assert_eq!(journal.pending(&disk), Ok(None));
+    }
+
+    #[test]
+    fn test_old_version_is_rejected() {
+        let disk = MemDisk::new(64);
+        Journal::format(&disk, 1, 16).unwrap();
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        disk.read(1, &mut buf).unwrap();
+        buf[4..8].copy_from_slice(&1u32.to_le_bytes());
+        disk.write(1, &buf).unwrap();
+        assert!(matches!(Journal::open(&disk, 1), Err(SplaxFsError::JournalError)));
+    }
+}
@@ -0,0 +1,892 @@
+//! On-disk structures
+//!
+//! Everything here is shared by the kernel driver and the host tools, so
+//! both sides agree on the layout byte for byte.
+
+use splax_compress::Algorithm;
+use splax_crypto::crc32c;
+
+/// Filesystem magic number (ASCII "SPLX")
+pub const SPLAXFS_MAGIC: u32 = 0x53504C58;
+
+/// On-disk format version
+pub const SPLAXFS_VERSION: u32 = 4;
+
+/// Block size (4 KB)
+pub const BLOCK_SIZE: usize = 4096;
+
+/// Inode size (128 bytes)
+pub const INODE_SIZE: usize = 128;
+
+/// Inodes per block
+pub const INODES_PER_BLOCK: usize = BLOCK_SIZE / INODE_SIZE;
+
+/// Directory entry size (fixed)
+pub const DIRENT_SIZE: usize = 264; // 8 + 255 + 1 padding
+
+/// Directory entries per directory block
+pub const DIRENTS_PER_BLOCK: usize = BLOCK_SIZE / DIRENT_SIZE;
+
+/// Maximum filename length
+pub const MAX_FILENAME: usize = 255;
+
+/// Number of direct block pointers in an inode
+pub const DIRECT_BLOCKS: usize = 12;
+
+/// Block pointers per indirect block
+pub const PTRS_PER_BLOCK: usize = BLOCK_SIZE / 4;
+
+/// Largest file size in blocks (direct + indirect + double indirect)
+pub const MAX_FILE_BLOCKS: u64 =
+    DIRECT_BLOCKS as u64 + PTRS_PER_BLOCK as u64 + (PTRS_PER_BLOCK * PTRS_PER_BLOCK) as u64;
+
+/// Root inode number
+pub const ROOT_INODE: u32 = 2; // Inode 0 is null, 1 is reserved
+
+/// Bits in one bitmap block
+pub const BITS_PER_BLOCK: u32 = (BLOCK_SIZE * 8) as u32;
+
+/// Block reference counts (`u16`) in one block of the reference count table
+pub const REFS_PER_BLOCK: u32 = (BLOCK_SIZE / 2) as u32;
+
+/// Block checksums (`u32`) in one block of the checksum table; the last
+/// word holds the checksum of the table block itself
+pub const CSUMS_PER_BLOCK: u32 = (BLOCK_SIZE / 4 - 1) as u32;
+
+/// Snapshot table slots (slot 0 of the table block is the header)
+pub const MAX_SNAPSHOTS: usize = BLOCK_SIZE / SNAPSHOT_ENTRY_SIZE - 1;
+
+/// Snapshot table entry size
+pub const SNAPSHOT_ENTRY_SIZE: usize = 64;
+
+/// Longest snapshot name
+pub const MAX_SNAPSHOT_NAME: usize = 43;
+
+/// Snapshot flag: the snapshot can be mounted writable
+pub const SNAPSHOT_WRITABLE: u32 = 1 << 0;
+
+/// Snapshot flag: the snapshot is being deleted and its blocks reclaimed
+pub const SNAPSHOT_DELETING: u32 = 1 << 1;
+
+/// Bytes of extended attributes stored in the inode itself
+pub const XATTR_INLINE_SIZE: usize = 24;
+
+/// Magic number of an extended attribute block ("SXAT")
+pub const XATTR_MAGIC: u32 = 0x5441_5853;
+
+/// Inode flag: compress new file data with LZ4
+pub const INODE_COMPRESS_LZ4: u32 = 1 << 0;
+
+/// Inode flag: compress new file data with LZH
+pub const INODE_COMPRESS_LZH: u32 = 1 << 1;
+
+/// Inode flags: algorithm for new file data, inherited by the files and
+/// directories created in a directory
+pub const INODE_COMPRESS_MASK: u32 = INODE_COMPRESS_LZ4 | INODE_COMPRESS_LZH;
+
+/// Inode flag: the file may have compressed clusters
+pub const INODE_COMPRESSED: u32 = 1 << 2;
+
+/// Inode flag: contents and, for a directory, entry names are encrypted;
+/// the context is in an extended attribute
+pub const INODE_ENCRYPTED: u32 = 1 << 3;
+
+/// File blocks per compressed cluster behind pointer blocks
+pub const CLUSTER_BLOCKS: usize = 16;
+
+/// File blocks per compressed cluster among the direct blocks
+pub const DIRECT_CLUSTER_BLOCKS: usize = 4;
+
+/// Bytes in the largest compressed cluster
+pub const CLUSTER_SIZE: usize = CLUSTER_BLOCKS * BLOCK_SIZE;
+
+/// Block map slot covered by a compressed cluster but holding no block
+pub const COMPRESSED_SLOT: u32 = u32::MAX;
+
+/// 512-byte units per block, as counted in [`DiskInode::blocks`]
+pub const SECTORS_PER_BLOCK: u32 = (BLOCK_SIZE / 512) as u32;
+
+/// Superblock state: cleanly unmounted
+pub const STATE_CLEAN: u16 = 1;
+
+/// Superblock state: mounted or not cleanly unmounted
+pub const STATE_DIRTY: u16 = 2;
+
+/// Mode bits: file type mask
+pub const S_IFMT: u16 = 0o170000;
+/// Mode bits: directory
+pub const S_IFDIR: u16 = 0o040000;
+/// Mode bits: regular file
+pub const S_IFREG: u16 = 0o100000;
+/// Mode bits: symbolic link
+pub const S_IFLNK: u16 = 0o120000;
+
+/// Filesystem errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SplaxFsError {
+    /// No such file or directory
+    NotFound,
+    /// File exists
+    Exists,
+    /// Not a directory
+    NotDirectory,
+    /// Is a directory
+    IsDirectory,
+    /// Directory not empty
+    NotEmpty,
+    /// No space left
+    NoSpace,
+    /// Filename too long
+    NameTooLong,
+    /// Filesystem corrupted
+    Corrupted,
+    /// I/O error
+    IoError,
+    /// Invalid argument
+    InvalidArg,
+    /// Not mounted
+    NotMounted,
+    /// Read-only filesystem
+    ReadOnly,
+    /// Journal error
+    JournalError,
+    /// Transaction not found
+    TransactionNotFound,
+    /// Operation not supported on this kind of file
+    NotSupported,
+    /// Volume is mounted
+    Busy,
+    /// No such extended attribute
+    NoAttribute,
+    /// Block content does not match its checksum
+    ChecksumMismatch,
+    /// Encryption key not available
+    NoKey,
+    /// Encryption policies differ
+    PolicyMismatch,
+}
+
+/// File types
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FileType {
+    Unknown = 0,
+    Regular = 1,
+    Directory = 2,
+    Symlink = 7,
+}
+
+impl From<u8> for FileType {
+    fn from(val: u8) -> Self {
+        match val {
+            1 => FileType::Regular,
+            2 => FileType::Directory,
+            7 => FileType::Symlink,
+            _ => FileType::Unknown,
+        }
+    }
+}
+
+/// On-disk superblock (block 0)
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Superblock {
+    /// Magic number (SPLAXFS_MAGIC)
+    pub magic: u32,
+    /// Filesystem version
+    pub version: u32,
+    /// Total number of blocks
+    pub total_blocks: u32,
+    /// Total number of inodes
+    pub total_inodes: u32,
+    /// Free blocks count
+    pub free_blocks: u32,
+    /// Free inodes count
+    pub free_inodes: u32,
+    /// Block size (always 4096)
+    pub block_size: u32,
+    /// Inode size (always 128)
+    pub inode_size: u32,
+    /// First data block number
+    pub first_data_block: u32,
+    /// Blocks per group (for future)
+    pub blocks_per_group: u32,
+    /// Inodes per group (for future)
+    pub inodes_per_group: u32,
+    /// Mount count
+    pub mount_count: u16,
+    /// Max mount count before fsck
+    pub max_mount_count: u16,
+    /// Filesystem state (1=clean, 2=dirty)
+    pub state: u16,
+    /// Error behavior
+    pub errors: u16,
+    /// Last mount time
+    pub last_mount_time: u32,
+    /// Last write time
+    pub last_write_time: u32,
+    /// Volume name (16 bytes)
+    pub volume_name: [u8; 16],
+    /// First block of the block reference count table
+    pub refcount_block: u32,
+    /// First block of the inode bitmap
+    pub inode_bitmap_block: u32,
+    /// First block of the inode map (where each inode table block is)
+    pub inode_map_block: u32,
+    /// Journal superblock block (0 = no journal)
+    pub journal_block: u32,
+    /// Snapshot table block
+    pub snapshot_block: u32,
+    /// First block of the block checksum table
+    pub csum_block: u32,
+    /// Block map slots holding [`COMPRESSED_SLOT`], counted once per
+    /// inode table and pointer block: blocks compression saves
+    pub saved_blocks: u32,
+    /// Reserved
+    pub _reserved: [u8; 404],
+    /// Checksum of the superblock, this field excluded
+    pub checksum: u32,
+}
+
+const _: () = assert!(core::mem::size_of::<Superblock>() == 512);
+const _: () = assert!(core::mem::offset_of!(Superblock, checksum) == 508);
+
+impl Superblock {
+    /// Creates a new superblock for formatting
+    pub fn new(total_blocks: u32, total_inodes: u32, first_data_block: u32) -> Self {
+        let mut sb = Self {
+            magic: SPLAXFS_MAGIC,
+            version: SPLAXFS_VERSION,
+            total_blocks,
+            total_inodes,
+            free_blocks: total_blocks - first_data_block,
+            free_inodes: total_inodes - 2, // 1=reserved, 2=root
+            block_size: BLOCK_SIZE as u32,
+            inode_size: INODE_SIZE as u32,
+            first_data_block,
+            blocks_per_group: 0,
+            inodes_per_group: 0,
+            mount_count: 0,
+            max_mount_count: 20,
+            state: STATE_CLEAN,
+            errors: 1, // Continue on error
+            last_mount_time: 0,
+            last_write_time: 0,
+            volume_name: [0; 16],
+            refcount_block: 0,
+            inode_bitmap_block: 0,
+            inode_map_block: 0,
+            journal_block: 0,
+            snapshot_block: 0,
+            csum_block: 0,
+            saved_blocks: 0,
+            _reserved: [0; 404],
+            checksum: 0,
+        };
+        // Set volume name
+        let name = b"SplaxFS";
+        sb.volume_name[..name.len()].copy_from_slice(name);
+        sb
+    }
+
+    /// Validates the superblock
+    pub fn is_valid(&self) -> bool {
+        self.magic == SPLAXFS_MAGIC && self.block_size == BLOCK_SIZE as u32
+    }
+
+    /// Blocks in the block reference count table
+    pub fn refcount_blocks(&self) -> u32 {
+        self.total_blocks.div_ceil(REFS_PER_BLOCK)
+    }
+
+    /// Blocks in the block checksum table
+    pub fn csum_blocks(&self) -> u32 {
+        self.total_blocks.div_ceil(CSUMS_PER_BLOCK)
+    }
+
+    /// Blocks in the inode bitmap (bit 0 stands for the null inode)
+    pub fn inode_bitmap_blocks(&self) -> u32 {
+        bitmap_blocks(self.total_inodes + 1)
+    }
+
+    /// Blocks in the inode table
+    pub fn inode_table_blocks(&self) -> u32 {
+        self.total_inodes.div_ceil(INODES_PER_BLOCK as u32)
+    }
+
+    /// Blocks in the inode map
+    pub fn inode_map_blocks(&self) -> u32 {
+        self.inode_table_blocks().div_ceil(PTRS_PER_BLOCK as u32)
+    }
+
+    /// Pointers in a snapshot root block (inode map blocks, then inode
+    /// bitmap blocks); snapshots need them to fit in one block
+    pub fn snapshot_root_ptrs(&self) -> u32 {
+        self.inode_map_blocks() + self.inode_bitmap_blocks()
+    }
+
+    /// Checks that the metadata areas fit before the first data block
+    pub fn layout_is_valid(&self) -> bool {
+        let areas = [
+            (self.refcount_block, self.refcount_blocks()),
+            (self.csum_block, self.csum_blocks()),
+            (self.inode_bitmap_block, self.inode_bitmap_blocks()),
+            (self.inode_map_block, self.inode_map_blocks()),
+            (self.snapshot_block, 1),
+        ];
+        self.total_inodes > ROOT_INODE
+            && self.first_data_block < self.total_blocks
+            && areas
+                .iter()
+                .all(|&(start, len)| start != 0 && start.checked_add(len).is_some_and(|end| end <= self.first_data_block))
+            && (self.journal_block == 0 || self.journal_block < self.first_data_block)
+    }
+
+    /// Checksum over everything before the `checksum` field
+    pub fn compute_checksum(&self) -> u32 {
+        let mut buf = [0u8; 512];
+        unsafe {
+            let ptr = self as *const Self as *const u8;
+            core::ptr::copy_nonoverlapping(ptr, buf.as_mut_ptr(), 512);
+        }
+        crc32c(!0, &buf[..508])
+    }
+
+    /// The stored checksum matches the content
+    pub fn checksum_is_valid(&self) -> bool {
+        self.checksum == self.compute_checksum()
+    }
+
+    /// Serialize to bytes, with a fresh checksum
+    pub fn to_bytes(&self) -> [u8; 512] {
+        let mut buf = [0u8; 512];
+        unsafe {
+            let ptr = self as *const Self as *const u8;
+            core::ptr::copy_nonoverlapping(ptr, buf.as_mut_ptr(), 512);
+        }
+        let checksum = crc32c(!0, &buf[..508]);
+        buf[508..].copy_from_slice(&checksum.to_le_bytes());
+        buf
+    }
+
+    /// Deserialize from bytes
+    pub fn from_bytes(buf: &[u8]) -> Self {
+        let mut sb = Self {
+            magic: 0,
+            version: 0,
+            total_blocks: 0,
+            total_inodes: 0,
+            free_blocks: 0,
+            free_inodes: 0,
+            block_size: 0,
+            inode_size: 0,
+            first_data_block: 0,
+            blocks_per_group: 0,
+            inodes_per_group: 0,
+            mount_count: 0,
+            max_mount_count: 0,
+            state: 0,
+            errors: 0,
+            last_mount_time: 0,
+            last_write_time: 0,
+            volume_name: [0; 16],
+            refcount_block: 0,
+            inode_bitmap_block: 0,
+            inode_map_block: 0,
+            journal_block: 0,
+            snapshot_block: 0,
+            csum_block: 0,
+            saved_blocks: 0,
+            _reserved: [0; 404],
+            checksum: 0,
+        };
+        unsafe {
+            let ptr = &mut sb as *mut Self as *mut u8;
+            core::ptr::copy_nonoverlapping(buf.as_ptr(), ptr, core::cmp::min(buf.len(), 512));
+        }
+        sb
+    }
+}
+
+/// On-disk inode (128 bytes)
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct DiskInode {
+    /// File mode (permissions + type)
+    pub mode: u16,
+    /// Owner user ID
+    pub uid: u16,
+    /// File size (lower 32 bits)
+    pub size_low: u32,
+    /// Access time
+    pub atime: u32,
+    /// Creation time
+    pub ctime: u32,
+    /// Modification time
+    pub mtime: u32,
+    /// Deletion time
+    pub dtime: u32,
+    /// Owner group ID
+    pub gid: u16,
+    /// Hard link count
+    pub links_count: u16,
+    /// Block count (in 512-byte units)
+    pub blocks: u32,
+    /// File flags
+    pub flags: u32,
+    /// Extended attribute overflow block
+    pub xattr_block: u32,
+    /// Direct block pointers
+    pub direct: [u32; DIRECT_BLOCKS],
+    /// Single indirect block pointer
+    pub indirect: u32,
+    /// Double indirect block pointer
+    pub double_indirect: u32,
+    /// Triple indirect block pointer
+    pub triple_indirect: u32,
+    /// File size (upper 32 bits)
+    pub size_high: u32,
+    /// Inline extended attributes
+    pub xattr_inline: [u8; XATTR_INLINE_SIZE],
+}
+
+const _: () = assert!(core::mem::size_of::<DiskInode>() == INODE_SIZE);
+
+impl Default for DiskInode {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DiskInode {
+    /// Creates a new empty inode
+    pub fn new() -> Self {
+        Self {
+            mode: 0,
+            uid: 0,
+            size_low: 0,
+            atime: 0,
+            ctime: 0,
+            mtime: 0,
+            dtime: 0,
+            gid: 0,
+            links_count: 0,
+            blocks: 0,
+            flags: 0,
+            xattr_block: 0,
+            direct: [0; DIRECT_BLOCKS],
+            indirect: 0,
+            double_indirect: 0,
+            triple_indirect: 0,
+            size_high: 0,
+            xattr_inline: [0; XATTR_INLINE_SIZE],
+        }
+    }
+
+    /// Creates a directory inode
+    pub fn new_directory() -> Self {
+        let mut inode = Self::new();
+        inode.mode = S_IFDIR | 0o755;
+        inode.links_count = 2; // . and parent's link
+        inode
+    }
+
+    /// Creates a regular file inode
+    pub fn new_file() -> Self {
+        let mut inode = Self::new();
+        inode.mode = S_IFREG | 0o644;
+        inode.links_count = 1;
+        inode
+    }
+
+    /// Creates a symbolic link inode
+    pub fn new_symlink() -> Self {
+        let mut inode = Self::new();
+        inode.mode = S_IFLNK | 0o777;
+        inode.links_count = 1;
+        inode
+    }
+
+    /// Returns the file type
+    pub fn file_type(&self) -> FileType {
+        match self.mode & S_IFMT {
+            S_IFDIR => FileType::Directory,
+            S_IFREG => FileType::Regular,
+            S_IFLNK => FileType::Symlink,
+            _ => FileType::Unknown,
+        }
+    }
+
+    /// Returns the file size
+    pub fn size(&self) -> u64 {
+        ((self.size_high as u64) << 32) | (self.size_low as u64)
+    }
+
+    /// Sets the file size
+    pub fn set_size(&mut self, size: u64) {
+        self.size_low = size as u32;
+        self.size_high = (size >> 32) as u32;
+    }
+
+    /// Is this a directory?
+    pub fn is_directory(&self) -> bool {
+        self.file_type() == FileType::Directory
+    }
+
+    /// Is this a regular file?
+    pub fn is_file(&self) -> bool {
+        self.file_type() == FileType::Regular
+    }
+
+    /// Algorithm new file data is compressed with
+    pub fn compression(&self) -> Option<Algorithm> {
+        match self.flags & INODE_COMPRESS_MASK {
+            INODE_COMPRESS_LZ4 => Some(Algorithm::Lz4),
+            INODE_COMPRESS_LZH => Some(Algorithm::Lzh),
+            _ => None,
+        }
+    }
+
+    /// Sets the algorithm new file data is compressed with
+    pub fn set_compression(&mut self, algorithm: Option<Algorithm>) {
+        self.flags &= !INODE_COMPRESS_MASK;
+        self.flags |= match algorithm {
+            Some(Algorithm::Lz4) => INODE_COMPRESS_LZ4,
+            Some(Algorithm::Lzh) => INODE_COMPRESS_LZH,
+            None => 0,
+        };
+    }
+
+    /// The file may have compressed clusters
+    pub fn is_compressed(&self) -> bool {
+        self.flags & INODE_COMPRESSED != 0
+    }
+
+    /// The inode has an encryption context
+    pub fn is_encrypted(&self) -> bool {
+        self.flags & INODE_ENCRYPTED != 0
+    }
+
+    /// Direct block pointers holding [`COMPRESSED_SLOT`]
+    pub fn compressed_slots(&self) -> u32 {
+        self.direct.iter().filter(|&&ptr| ptr == COMPRESSED_SLOT).count() as u32
+    }
+
+    /// Serialize to bytes
+    pub fn to_bytes(&self) -> [u8; INODE_SIZE] {
+        let mut buf = [0u8; INODE_SIZE];
+        unsafe {
+            let ptr = self as *const Self as *const u8;
+            core::ptr::copy_nonoverlapping(ptr, buf.as_mut_ptr(), INODE_SIZE);
+        }
+        buf
+    }
+
+    /// Deserialize from bytes
+    pub fn from_bytes(buf: &[u8]) -> Self {
+        let mut inode = Self::new();
+        unsafe {
+            let ptr = &mut inode as *mut Self as *mut u8;
+            core::ptr::copy_nonoverlapping(buf.as_ptr(), ptr, core::cmp::min(buf.len(), INODE_SIZE));
+        }
+        inode
+    }
+}
+
+/// Directory entry (on-disk format)
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    /// Inode number
+    pub inode: u32,
+    /// Entry length (for variable size)
+    pub rec_len: u16,
+    /// Name length
+    pub name_len: u8,
+    /// File type
+    pub file_type: u8,
+    /// Filename (up to 255 bytes)
+    pub name: [u8; MAX_FILENAME],
+}
+
+impl DirEntry {
+    /// Creates a new directory entry
+    pub fn new(inode: u32, name: &str, file_type: FileType) -> Self {
+        Self::from_raw(inode, name.as_bytes(), file_type)
+    }
+
+    /// Creates a new directory entry with a name as stored, which in an
+    /// encrypted directory is not text
+    pub fn from_raw(inode: u32, name: &[u8], file_type: FileType) -> Self {
+        let copy_len = core::cmp::min(name.len(), MAX_FILENAME);
+        let mut entry = Self {
+            inode,
+            rec_len: DIRENT_SIZE as u16,
+            name_len: copy_len as u8,
+            file_type: file_type as u8,
+            name: [0; MAX_FILENAME],
+        };
+        entry.name[..copy_len].copy_from_slice(&name[..copy_len]);
+        entry
+    }
+
+    /// Gets the filename as a string
+    pub fn name_str(&self) -> &str {
+        core::str::from_utf8(self.raw_name()).unwrap_or("")
+    }
+
+    /// Gets the filename as stored
+    pub fn raw_name(&self) -> &[u8] {
+        &self.name[..self.name_len as usize]
+    }
+
+    /// Serialize to bytes
+    pub fn to_bytes(&self) -> [u8; DIRENT_SIZE] {
+        let mut buf = [0u8; DIRENT_SIZE];
+        // Manual serialization
+        buf[0..4].copy_from_slice(&self.inode.to_le_bytes());
+        buf[4..6].copy_from_slice(&self.rec_len.to_le_bytes());
+        buf[6] = self.name_len;
+        buf[7] = self.file_type;
+        buf[8..8 + MAX_FILENAME].copy_from_slice(&self.name);
+        buf
+    }
+
+    /// Deserialize from bytes
+    pub fn from_bytes(buf: &[u8]) -> Self {
+        let mut entry = Self {
+            inode: 0,
+            rec_len: 0,
+            name_len: 0,
+            file_type: 0,
+            name: [0; MAX_FILENAME],
+        };
+        if buf.len() >= DIRENT_SIZE {
+            entry.inode = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
+            entry.rec_len = u16::from_le_bytes([buf[4], buf[5]]);
+            entry.name_len = buf[6];
+            entry.file_type = buf[7];
+            entry.name.copy_from_slice(&buf[8..8 + MAX_FILENAME]);
+        }
+        entry
+    }
+}
+
+/// Snapshot table entry (64 bytes)
+///
+/// A snapshot is a frozen copy of the inode map and inode bitmap; its root
+/// block lists the blocks of both. The inode table blocks and everything
+/// below them are shared with the live tree until either side changes them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SnapshotEntry {
+    /// Snapshot id (0 = free slot)
+    pub id: u32,
+    /// SNAPSHOT_* flags
+    pub flags: u32,
+    /// Creation time
+    pub created: u32,
+    /// Root block: inode map blocks, then inode bitmap blocks
+    pub root: u32,
+    /// Free inodes in the snapshot
+    pub free_inodes: u32,
+    /// Name length
+    pub name_len: u8,
+    /// Name
+    pub name: [u8; MAX_SNAPSHOT_NAME],
+}
+
+impl SnapshotEntry {
+    /// Creates an entry
+    pub fn new(id: u32, name: &str, flags: u32, created: u32) -> Self {
+        let mut entry = Self::from_bytes(&[]);
+        entry.id = id;
+        entry.flags = flags;
+        entry.created = created;
+        let len = name.len().min(MAX_SNAPSHOT_NAME);
+        entry.name_len = len as u8;
+        entry.name[..len].copy_from_slice(&name.as_bytes()[..len]);
+        entry
+    }
+
+    /// Gets the name as a string
+    pub fn name_str(&self) -> &str {
+        let len = (self.name_len as usize).min(MAX_SNAPSHOT_NAME);
+        core::str::from_utf8(&self.name[..len]).unwrap_or("")
+    }
+
+    /// Can be mounted writable
+    pub fn is_writable(&self) -> bool {
+        self.flags & SNAPSHOT_WRITABLE != 0
+    }
+
+    /// Is being deleted
+    pub fn is_deleting(&self) -> bool {
+        self.flags & SNAPSHOT_DELETING != 0
+    }
+
+    /// Serialize to bytes
+    pub fn to_bytes(&self) -> [u8; SNAPSHOT_ENTRY_SIZE] {
+        let mut buf = [0u8; SNAPSHOT_ENTRY_SIZE];
+        for (i, field) in [self.id, self.flags, self.created, self.root, self.free_inodes].iter().enumerate() {
+            buf[i * 4..i * 4 + 4].copy_from_slice(&field.to_le_bytes());
+        }
+        buf[20] = self.name_len;
+        buf[21..].copy_from_slice(&self.name);
+        buf
+    }
+
+    /// Deserialize from bytes
+    pub fn from_bytes(buf: &[u8]) -> Self {
+        let mut entry = Self {
+            id: 0,
+            flags: 0,
+            created: 0,
+            root: 0,
+            free_inodes: 0,
+            name_len: 0,
+            name: [0; MAX_SNAPSHOT_NAME],
+        };
+        if buf.len() >= SNAPSHOT_ENTRY_SIZE {
+            entry.id = get_u32(buf, 0);
+            entry.flags = get_u32(buf, 4);
+            entry.created = get_u32(buf, 8);
+            entry.root = get_u32(buf, 12);
+            entry.free_inodes = get_u32(buf, 16);
+            entry.name_len = buf[20];
+            entry.name.copy_from_slice(&buf[21..SNAPSHOT_ENTRY_SIZE]);
+        }
+        entry
+    }
+}
+
+/// Snapshot table (one block): a header with the next id, then the entries
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotTable {
+    /// Id the next snapshot gets
+    pub next_id: u32,
+    /// Slots, free ones included
+    pub entries: [SnapshotEntry; MAX_SNAPSHOTS],
+}
+
+impl SnapshotTable {
+    /// Empty table
+    pub fn new() -> Self {
+        Self { next_id: 1, entries: [SnapshotEntry::from_bytes(&[]); MAX_SNAPSHOTS] }
+    }
+
+    /// Used slots
+    pub fn iter(&self) -> impl Iterator<Item = &SnapshotEntry> {
+        self.entries.iter().filter(|entry| entry.id != 0)
+    }
+
+    /// Slot of the snapshot with a name
+    pub fn find(&self, name: &str) -> Option<usize> {
+        self.entries.iter().position(|entry| entry.id != 0 && entry.name_str() == name)
+    }
+
+    /// Serialize to a block
+    pub fn to_bytes(&self) -> [u8; BLOCK_SIZE] {
+        let mut buf = [0u8; BLOCK_SIZE];
+        buf[0..4].copy_from_slice(&self.next_id.to_le_bytes());
+        for (i, entry) in self.entries.iter().enumerate() {
+            let offset = (i + 1) * SNAPSHOT_ENTRY_SIZE;
+            buf[offset..offset + SNAPSHOT_ENTRY_SIZE].copy_from_slice(&entry.to_bytes());
+        }
+        buf
+    }
+
+    /// Deserialize from a block
+    pub fn from_bytes(buf: &[u8]) -> Self {
+        let mut table = Self::new();
+        if buf.len() >= BLOCK_SIZE {
+            table.next_id = get_u32(buf, 0).max(1);
+            for (i, entry) in table.entries.iter_mut().enumerate() {
+                *entry = SnapshotEntry::from_bytes(&buf[(i + 1) * SNAPSHOT_ENTRY_SIZE..]);
+            }
+        }
+        table
+    }
+}
+
+impl Default for SnapshotTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Reads a little-endian `u32` at `offset`
+pub fn get_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([buf[offset], buf[offset + 1], buf[offset + 2], buf[offset + 3]])
+}
+
+/// Tests bit `n` of a bitmap
+pub fn test_bit(map: &[u8], n: u32) -> bool {
+    map[(n / 8) as usize] & (1 << (n % 8)) != 0
+}
+
+/// Sets or clears bit `n` of a bitmap
+pub fn set_bit(map: &mut [u8], n: u32, value: bool) {
+    if value {
+        map[(n / 8) as usize] |= 1 << (n % 8);
+    } else {
+        map[(n / 8) as usize] &= !(1 << (n % 8));
+    }
+}
+
+/// First clear bit in `from..to`
+pub fn find_clear(map: &[u8], from: u32, to: u32) -> Option<u32> {
+    let mut n = from;
+    while n < to {
+        if n.is_multiple_of(8) && map[(n / 8) as usize] == 0xFF {
+            n += 8;
+            continue;
+        }
+        if !test_bit(map, n) {
+            return Some(n);
+        }
+        n += 1;
+    }
+    None
+}
+
+/// Blocks needed for a bitmap of `bits` bits
+pub fn bitmap_blocks(bits: u32) -> u32 {
+    bits.div_ceil(BITS_PER_BLOCK)
+}
+
+/// Reference count of block `n` in a reference count table
+pub fn get_ref(table: &[u8], n: u32) -> u16 {
+    let offset = n as usize * 2;
+    u16::from_le_bytes([table[offset], table[offset + 1]])
+}
+
+/// Sets the reference count of block `n`
+pub fn set_ref(table: &mut [u8], n: u32, count: u16) {
+    let offset = n as usize * 2;
+    table[offset..offset + 2].copy_from_slice(&count.to_le_bytes());
+}
+
+/// First unreferenced block in `from..to`
+pub fn find_free(table: &[u8], from: u32, to: u32) -> Option<u32> {
+    (from..to).find(|&n| get_ref(table, n) == 0)
+}
+
+/// Checks a name for a new snapshot
+pub fn check_snapshot_name(name: &str) -> Result<(), SplaxFsError> {
+    check_name(name)?;
+    if name.len() > MAX_SNAPSHOT_NAME {
+        return Err(SplaxFsError::NameTooLong);
+    }
+    Ok(())
+}
+
+/// Checks a name for a new directory entry
+pub fn check_name(name: &str) -> Result<(), SplaxFsError> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') || name.contains('\0') {
+        return Err(SplaxFsError::InvalidArg);
+    }
+    if name.len() > MAX_FILENAME {
+        return Err(SplaxFsError::NameTooLong);
+    }
+    Ok(())
+}
@@ -0,0 +1,93 @@
+//! # SplaxFS Core
+//!
+//! The parts of SplaxFS that do not depend on the kernel: the on-disk
+//! structures, the redo journal, block checksums, compressed clusters,
+//! encryption policies, extended attribute storage, formatting and the
+//! offline checker.
+//! The kernel driver (`fs/splaxfs.rs`) builds on this crate, and so do the
+//! host tools that work on image files.
+//!
+//! ## Design
+//!
+//! - Everything goes through [`BlockIo`], block by block; the kernel
+//!   implements it on top of a block device, the host tools on a file
+//! - No global state and no logging; callers report what happened
+//! - `no_std` with `alloc`
+
+#![no_std]
+
+extern crate alloc;
+
+pub mod checksum;
+pub mod cluster;
+pub mod crypt;
+pub mod fsck;
+pub mod journal;
+pub mod layout;
+pub mod mkfs;
+pub mod xattr;
+
+pub use checksum::{block_checksum, ChecksumTable};
+pub use fsck::{check, CheckOptions, Problem, Report};
+pub use journal::{Journal, JournalEntry, JournalSuperblock};
+pub use layout::*;
+pub use mkfs::format;
+pub use splax_compress::Algorithm;
+
+/// Access to the device holding a volume, in filesystem blocks
+pub trait BlockIo {
+    /// Whole blocks on the device
+    fn total_blocks(&self) -> u32;
+
+    /// Reads `buf.len()` bytes starting at a block
+    fn read(&self, block_num: u32, buf: &mut [u8]) -> Result<(), SplaxFsError>;
+
+    /// Writes `buf.len()` bytes starting at a block
+    fn write(&self, block_num: u32, buf: &[u8]) -> Result<(), SplaxFsError>;
+
+    /// Makes earlier writes durable
+    fn flush(&self) -> Result<(), SplaxFsError>;
+}
+
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use core::cell::RefCell;
+
+    /// RAM-backed device for tests
+    pub struct MemDisk {
+        data: RefCell<Vec<u8>>,
+    }
+
+    impl MemDisk {
+        pub fn new(blocks: usize) -> Self {
+            Self { data: RefCell::new(vec![0u8; blocks * BLOCK_SIZE]) }
+        }
+    }
+
+    impl BlockIo for MemDisk {
+        fn total_blocks(&self) -> u32 {
+            (self.data.borrow().len() / BLOCK_SIZE) as u32
+        }
+
+        fn read(&self, block_num: u32, buf: &mut [u8]) -> Result<(), SplaxFsError> {
+            let start = block_num as usize * BLOCK_SIZE;
+            let data = self.data.borrow();
+            buf.copy_from_slice(data.get(start..start + buf.len()).ok_or(SplaxFsError::IoError)?);
+            Ok(())
+        }
+
+        fn write(&self, block_num: u32, buf: &[u8]) -> Result<(), SplaxFsError> {
+            let start = block_num as usize * BLOCK_SIZE;
+            let mut data = self.data.borrow_mut();
+            data.get_mut(start..start + buf.len()).ok_or(SplaxFsError::IoError)?.copy_from_slice(buf);
+            Ok(())
+        }
+
+        fn flush(&self) -> Result<(), SplaxFsError> {
+            Ok(())
+        }
+    }
+}
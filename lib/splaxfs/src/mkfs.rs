@@ -0,0 +1,127 @@
+//! Formatting
+
+use alloc::vec;
+
+use crate::checksum::ChecksumTable;
+use crate::journal::Journal;
+use crate::layout::*;
+use crate::BlockIo;
+
+/// Smallest device that can be formatted, in blocks
+const MIN_BLOCKS: u32 = 64;
+
+/// Largest journal log area, in blocks
+const JOURNAL_LOG_BLOCKS: u32 = 256;
+
+/// Writes an empty SplaxFS volume (root directory only) to a device
+///
+/// The root directory takes the first data block and the inode table the
+/// blocks after it. `time` stamps the root directory. Every block written
+/// gets its checksum in the checksum table. Returns the new superblock.
+pub fn format(dev: &impl BlockIo, journaled: bool, time: u32) -> Result<Superblock, SplaxFsError> {
+    let total_blocks = dev.total_blocks();
+    if total_blocks < MIN_BLOCKS {
+        return Err(SplaxFsError::NoSpace);
+    }
+
+    // One inode per 16 KB, at least 256
+    let total_inodes = (total_blocks / 4).max(256).next_multiple_of(INODES_PER_BLOCK as u32);
+    let log_blocks = (total_blocks / 16).clamp(16, JOURNAL_LOG_BLOCKS);
+
+    let mut next = 1;
+    let journal_block = if journaled {
+        next += 1 + log_blocks;
+        1
+    } else {
+        0
+    };
+    let mut sb = Superblock::new(total_blocks, total_inodes, 0);
+    sb.journal_block = journal_block;
+    sb.refcount_block = next;
+    next += sb.refcount_blocks();
+    sb.csum_block = next;
+    next += sb.csum_blocks();
+    sb.inode_bitmap_block = next;
+    next += sb.inode_bitmap_blocks();
+    sb.inode_map_block = next;
+    next += sb.inode_map_blocks();
+    sb.snapshot_block = next;
+    next += 1;
+    let first_data_block = next;
+    let table_blocks = sb.inode_table_blocks();
+    let root_block = first_data_block;
+    let table_start = root_block + 1;
+    if table_start + table_blocks >= total_blocks {
+        return Err(SplaxFsError::NoSpace);
+    }
+    sb.first_data_block = first_data_block;
+    // Everything up to the end of the inode table is used
+    sb.free_blocks = total_blocks - table_start - table_blocks;
+
+    let mut block_buf = vec![0u8; BLOCK_SIZE];
+    block_buf[..512].copy_from_slice(&sb.to_bytes());
+    dev.write(0, &block_buf)?;
+
+    let mut checksums = ChecksumTable::new(&sb);
+    let mut write = |block_num: u32, data: &[u8]| {
+        checksums.record(block_num, data);
+        dev.write(block_num, data)
+    };
+
+    let mut refcounts = vec![0u8; sb.refcount_blocks() as usize * BLOCK_SIZE];
+    for block_num in 0..table_start + table_blocks {
+        set_ref(&mut refcounts, block_num, 1);
+    }
+    for (i, chunk) in refcounts.chunks(BLOCK_SIZE).enumerate() {
+        write(sb.refcount_block + i as u32, chunk)?;
+    }
+
+    let mut inode_bitmap = vec![0u8; sb.inode_bitmap_blocks() as usize * BLOCK_SIZE];
+    inode_bitmap[0] = 0b00000111; // null, reserved and root inodes
+    for (i, chunk) in inode_bitmap.chunks(BLOCK_SIZE).enumerate() {
+        write(sb.inode_bitmap_block + i as u32, chunk)?;
+    }
+
+    let mut inode_map = vec![0u8; sb.inode_map_blocks() as usize * BLOCK_SIZE];
+    for i in 0..table_blocks as usize {
+        inode_map[i * 4..i * 4 + 4].copy_from_slice(&(table_start + i as u32).to_le_bytes());
+    }
+    for (i, chunk) in inode_map.chunks(BLOCK_SIZE).enumerate() {
+        write(sb.inode_map_block + i as u32, chunk)?;
+    }
+    write(sb.snapshot_block, &SnapshotTable::new().to_bytes())?;
+
+    // Inode table, with the root inode in the first block
+    let mut root_inode = DiskInode::new_directory();
+    root_inode.atime = time;
+    root_inode.ctime = time;
+    root_inode.mtime = time;
+    root_inode.set_size(BLOCK_SIZE as u64);
+    root_inode.blocks = SECTORS_PER_BLOCK;
+    root_inode.direct[0] = root_block;
+    let zero_block = vec![0u8; BLOCK_SIZE];
+    for i in 1..table_blocks {
+        write(table_start + i, &zero_block)?;
+    }
+    let root_offset = (ROOT_INODE as usize - 1) * INODE_SIZE;
+    block_buf.fill(0);
+    block_buf[root_offset..root_offset + INODE_SIZE].copy_from_slice(&root_inode.to_bytes());
+    write(table_start, &block_buf)?;
+
+    // Root directory content (. and ..)
+    block_buf.fill(0);
+    block_buf[..DIRENT_SIZE].copy_from_slice(&DirEntry::new(ROOT_INODE, ".", FileType::Directory).to_bytes());
+    block_buf[DIRENT_SIZE..2 * DIRENT_SIZE]
+        .copy_from_slice(&DirEntry::new(ROOT_INODE, "..", FileType::Directory).to_bytes());
+    write(root_block, &block_buf)?;
+
+    for index in 0..sb.csum_blocks() {
+        dev.write(sb.csum_block + index, &checksums.block_image(sb.csum_block, index))?;
+    }
+
+    if journaled {
+        Journal::format(dev, journal_block, log_blocks)?;
+    }
+    dev.flush()?;
+    Ok(sb)
+}
@@ -0,0 +1,164 @@
+//! Extended attribute storage
+//!
+//! An inode keeps its extended attributes in the [`XATTR_INLINE_SIZE`]
+//! bytes at its end and, when they do not all fit, in one overflow block
+//! it points at ([`DiskInode::xattr_block`]). Both areas hold the same
+//! packed entries:
+//!
+//! ```text
+//! +-------+----------+-----------+------+-------+
+//! | index | name_len | value_len | name | value |
+//! |  u8   |    u8    |    u16    |      |       |
+//! +-------+----------+-----------+------+-------+
+//! ```
+//!
+//! `index` selects the name's namespace prefix in [`NAMESPACES`], which is
+//! not stored; index 0 ends the list. The overflow block starts with
+//! [`XATTR_MAGIC`]. Overflow blocks are counted in the inode's `blocks`
+//! and shared between snapshots like any other block.
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::layout::*;
+
+/// Namespace prefixes, by index minus one
+pub const NAMESPACES: [&str; 4] = ["user.", "trusted.", "security.", "system."];
+
+/// Bytes before the name of an entry
+const ENTRY_HEADER: usize = 4;
+
+/// Bytes before the entries of an overflow block
+const BLOCK_HEADER: usize = 4;
+
+/// Extended attributes of an inode
+pub type Xattrs = Vec<(String, Vec<u8>)>;
+
+/// Splits a name into its namespace index and the rest
+fn split_name(name: &str) -> Result<(u8, &str), SplaxFsError> {
+    let (index, rest) = NAMESPACES
+        .iter()
+        .enumerate()
+        .find_map(|(i, prefix)| name.strip_prefix(prefix).map(|rest| (i as u8 + 1, rest)))
+        .ok_or(SplaxFsError::NotSupported)?;
+    if rest.is_empty() {
+        return Err(SplaxFsError::InvalidArg);
+    }
+    if rest.len() > u8::MAX as usize {
+        return Err(SplaxFsError::NameTooLong);
+    }
+    Ok((index, rest))
+}
+
+/// Reads the entries of one area into `attrs`
+fn decode_area(area: &[u8], attrs: &mut Xattrs) -> Result<(), SplaxFsError> {
+    let mut pos = 0;
+    while pos + ENTRY_HEADER <= area.len() && area[pos] != 0 {
+        let prefix = NAMESPACES.get(area[pos] as usize - 1).ok_or(SplaxFsError::Corrupted)?;
+        let name_len = area[pos + 1] as usize;
+        let value_len = u16::from_le_bytes([area[pos + 2], area[pos + 3]]) as usize;
+        let name_start = pos + ENTRY_HEADER;
+        let value_start = name_start + name_len;
+        let end = value_start + value_len;
+        if name_len == 0 || end > area.len() {
+            return Err(SplaxFsError::Corrupted);
+        }
+        let rest = core::str::from_utf8(&area[name_start..value_start]).map_err(|_| SplaxFsError::Corrupted)?;
+        let mut name = String::from(*prefix);
+        name.push_str(rest);
+        attrs.push((name, area[value_start..end].to_vec()));
+        pos = end;
+    }
+    Ok(())
+}
+
+/// Reads an inode's attributes; `block` is the content of its overflow
+/// block, if it has one
+pub fn decode(inode: &DiskInode, block: Option<&[u8]>) -> Result<Xattrs, SplaxFsError> {
+    let mut attrs = Vec::new();
+    decode_area(&inode.xattr_inline, &mut attrs)?;
+    if let Some(block) = block {
+        if block.len() < BLOCK_HEADER || u32::from_le_bytes([block[0], block[1], block[2], block[3]]) != XATTR_MAGIC {
+            return Err(SplaxFsError::Corrupted);
+        }
+        decode_area(&block[BLOCK_HEADER..], &mut attrs)?;
+    }
+    Ok(attrs)
+}
+
+/// Lays attributes out for storage: the inline area, and the overflow
+/// block if they do not all fit inline
+pub fn encode(attrs: &Xattrs) -> Result<([u8; XATTR_INLINE_SIZE], Option<Vec<u8>>), SplaxFsError> {
+    let mut inline = [0u8; XATTR_INLINE_SIZE];
+    let mut inline_pos = 0;
+    let mut block = vec![0u8; BLOCK_SIZE];
+    block[..BLOCK_HEADER].copy_from_slice(&XATTR_MAGIC.to_le_bytes());
+    let mut block_pos = BLOCK_HEADER;
+
+    for (name, value) in attrs {
+        let (index, rest) = split_name(name)?;
+        let len = ENTRY_HEADER + rest.len() + value.len();
+        let (area, pos): (&mut [u8], &mut usize) = if inline_pos + len <= XATTR_INLINE_SIZE {
+            (&mut inline, &mut inline_pos)
+        } else if block_pos + len <= BLOCK_SIZE {
+            (&mut block, &mut block_pos)
+        } else {
+            return Err(SplaxFsError::NoSpace);
+        };
+        let entry = &mut area[*pos..*pos + len];
+        entry[0] = index;
+        entry[1] = rest.len() as u8;
+        entry[2..4].copy_from_slice(&(value.len() as u16).to_le_bytes());
+        entry[ENTRY_HEADER..ENTRY_HEADER + rest.len()].copy_from_slice(rest.as_bytes());
+        entry[ENTRY_HEADER + rest.len()..].copy_from_slice(value);
+        *pos += len;
+    }
+
+    Ok((inline, (block_pos > BLOCK_HEADER).then_some(block)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attr(name: &str, value: &[u8]) -> (String, Vec<u8>) {
+        (String::from(name), value.to_vec())
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut inode = DiskInode::new_file();
+        let small = vec![attr("security.splax.caps", b"net:r")];
+        let (inline, block) = encode(&small).unwrap();
+        assert!(block.is_none());
+        inode.xattr_inline = inline;
+        assert_eq!(decode(&inode, None).unwrap(), small);
+
+        // Entries that do not fit inline go to the block; later small
+        // ones can still fill the inline area
+        let mut many = small.clone();
+        many.push(attr("user.comment", &[7u8; 300]));
+        many.push(attr("trusted.x", b""));
+        let (inline, block) = encode(&many).unwrap();
+        inode.xattr_inline = inline;
+        let mut decoded = decode(&inode, block.as_deref()).unwrap();
+        decoded.sort();
+        many.sort();
+        assert_eq!(decoded, many);
+
+        assert_eq!(encode(&vec![attr("user.big", &[0u8; BLOCK_SIZE])]), Err(SplaxFsError::NoSpace));
+        assert_eq!(encode(&vec![attr("os2.name", b"")]), Err(SplaxFsError::NotSupported));
+    }
+
+    #[test]
+    fn test_corrupt_entries() {
+        let mut inode = DiskInode::new_file();
+        inode.xattr_inline[0] = 9;
+        assert_eq!(decode(&inode, None), Err(SplaxFsError::Corrupted));
+        inode.xattr_inline[..4].copy_from_slice(&[1, 4, 200, 0]);
+        assert_eq!(decode(&inode, None), Err(SplaxFsError::Corrupted));
+        inode.xattr_inline = [0; XATTR_INLINE_SIZE];
+        assert_eq!(decode(&inode, Some(&[0u8; BLOCK_SIZE])), Err(SplaxFsError::Corrupted));
+    }
+}
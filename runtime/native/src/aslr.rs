@@ -0,0 +1,221 @@
+//! # Sandbox ASLR
+//!
+//! Layout randomization for S-NATIVE sandboxes and the libraries they load.
+//!
+//! ## Design
+//!
+//! The kernel hands every exec 16 bytes from its CSPRNG (`AT_RANDOM`). A
+//! sandbox expands them with [`LayoutRng`] to pick page-granular offsets
+//! for its code, stack and heap regions and for the base its
+//! [`DynamicLinker`](crate::DynamicLinker) loads shared libraries at. How
+//! many bits each offset may use is set per sandbox by an [`AslrPolicy`],
+//! clamped so that regions cannot run into each other.
+//!
+//! The runtime reseeds its generator from the kernel CSPRNG
+//! ([`kernel_seed`]) for every sandbox it loads, and each sandbox's linker
+//! is seeded from that stream.
+
+/// Page size used for layout offsets.
+const PAGE_SIZE: u64 = 4096;
+
+/// Widest code, stack and heap offset, in pages (256 MiB).
+pub const MAX_REGION_ENTROPY: u8 = 16;
+
+/// Widest shared-library base offset, in pages (1 TiB).
+pub const MAX_MMAP_ENTROPY: u8 = 28;
+
+/// Per-sandbox ASLR policy, in bits of page offset per region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AslrPolicy {
+    /// Randomize the layout at all
+    pub enabled: bool,
+    /// Code region offset bits
+    pub code_entropy: u8,
+    /// Stack offset bits
+    pub stack_entropy: u8,
+    /// Heap offset bits
+    pub heap_entropy: u8,
+    /// Shared-library base offset bits
+    pub mmap_entropy: u8,
+}
+
+impl AslrPolicy {
+    /// Default policy: every region moves.
+    pub const fn default_policy() -> Self {
+        Self {
+            enabled: true,
+            code_entropy: 12,
+            stack_entropy: 12,
+            heap_entropy: 12,
+            mmap_entropy: 20,
+        }
+    }
+
+    /// Fixed layout (for debugging).
+    pub const fn disabled() -> Self {
+        Self {
+            enabled: false,
+            code_entropy: 0,
+            stack_entropy: 0,
+            heap_entropy: 0,
+            mmap_entropy: 0,
+        }
+    }
+
+    /// Limit each width to what the sandbox layout can absorb.
+    pub fn clamped(self) -> Self {
+        Self {
+            enabled: self.enabled,
+            code_entropy: self.code_entropy.min(MAX_REGION_ENTROPY),
+            stack_entropy: self.stack_entropy.min(MAX_REGION_ENTROPY),
+            heap_entropy: self.heap_entropy.min(MAX_REGION_ENTROPY),
+            mmap_entropy: self.mmap_entropy.min(MAX_MMAP_ENTROPY),
+        }
+    }
+}
+
+impl Default for AslrPolicy {
+    fn default() -> Self {
+        Self::default_policy()
+    }
+}
+
+/// Expands an exec's random bytes into layout offsets.
+///
+/// SplitMix64 over the seed; not a CSPRNG itself, so it must be seeded
+/// from one for every exec.
+#[derive(Debug, Clone)]
+pub struct LayoutRng {
+    state: u64,
+}
+
+impl LayoutRng {
+    /// Creates a generator from 16 random bytes, e.g. `AT_RANDOM`.
+    pub fn from_seed(seed: [u8; 16]) -> Self {
+        let lo = u64::from_le_bytes([
+            seed[0], seed[1], seed[2], seed[3], seed[4], seed[5], seed[6], seed[7],
+        ]);
+        let hi = u64::from_le_bytes([
+            seed[8], seed[9], seed[10], seed[11], seed[12], seed[13], seed[14], seed[15],
+        ]);
+        // Run the low half through the mixer so equal halves do not cancel
+        let mut rng = Self { state: lo };
+        Self {
+            state: rng.next_u64() ^ hi,
+        }
+    }
+
+    /// Mixes more random bytes into the state.
+    pub fn reseed(&mut self, seed: [u8; 16]) {
+        let other = Self::from_seed(seed);
+        self.state ^= other.state;
+        self.next_u64();
+    }
+
+    /// Returns the next 64-bit value.
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Draws 16 bytes to seed another generator with.
+    pub fn next_seed(&mut self) -> [u8; 16] {
+        let mut seed = [0u8; 16];
+        seed[..8].copy_from_slice(&self.next_u64().to_le_bytes());
+        seed[8..].copy_from_slice(&self.next_u64().to_le_bytes());
+        seed
+    }
+
+    /// Returns a page-aligned offset below `2^bits` pages.
+    pub fn page_offset(&mut self, bits: u8) -> u64 {
+        if bits == 0 {
+            return 0;
+        }
+        let mask = (1u64 << bits.min(52)) - 1;
+        (self.next_u64() & mask) * PAGE_SIZE
+    }
+}
+
+/// Draws 16 bytes from the kernel CSPRNG (`getrandom`).
+///
+/// Returns `None` when not running on the Splax kernel, as in host builds.
+pub fn kernel_seed() -> Option<[u8; 16]> {
+    #[cfg(all(target_os = "none", target_arch = "x86_64"))]
+    {
+        let mut seed = [0u8; 16];
+        let filled: i64;
+        unsafe {
+            core::arch::asm!(
+                "int 0x80",
+                inlateout("rax") 318i64 => filled, // getrandom
+                in("rdi") seed.as_mut_ptr(),
+                in("rsi") seed.len(),
+                in("rdx") 0u64,
+                options(nostack)
+            );
+        }
+        (filled == seed.len() as i64).then_some(seed)
+    }
+
+    #[cfg(all(target_os = "none", target_arch = "aarch64"))]
+    {
+        let mut seed = [0u8; 16];
+        let filled: i64;
+        unsafe {
+            core::arch::asm!(
+                "svc #0",
+                in("x8") 278u64, // getrandom
+                inlateout("x0") seed.as_mut_ptr() as i64 => filled,
+                in("x1") seed.len(),
+                in("x2") 0u64,
+                options(nostack)
+            );
+        }
+        (filled == seed.len() as i64).then_some(seed)
+    }
+
+    #[cfg(not(all(target_os = "none", any(target_arch = "x86_64", target_arch = "aarch64"))))]
+    {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_page_offsets() {
+        let mut rng = LayoutRng::from_seed([7; 16]);
+        for _ in 0..64 {
+            let offset = rng.page_offset(12);
+            assert_eq!(offset % PAGE_SIZE, 0);
+            assert!(offset < (1 << 12) * PAGE_SIZE);
+        }
+        assert_eq!(rng.page_offset(0), 0);
+    }
+
+    #[test]
+    fn test_seeds_diverge() {
+        let mut a = LayoutRng::from_seed([1; 16]);
+        let mut b = LayoutRng::from_seed([2; 16]);
+        assert_ne!(a.next_u64(), b.next_u64());
+    }
+
+    #[test]
+    fn test_policy_clamp() {
+        let wide = AslrPolicy {
+            enabled: true,
+            code_entropy: 64,
+            stack_entropy: 64,
+            heap_entropy: 64,
+            mmap_entropy: 64,
+        }
+        .clamped();
+        assert_eq!(wide.code_entropy, MAX_REGION_ENTROPY);
+        assert_eq!(wide.mmap_entropy, MAX_MMAP_ENTROPY);
+    }
+}
@@ -9,7 +9,7 @@
 //! - PLT/GOT relocation
 //! - Lazy symbol binding
 //! - Library dependency resolution
-//! - ASLR-compatible loading
+//! - ASLR: libraries load above a per-exec random base (see [`crate::aslr`])
 
 #![allow(dead_code)]
 
@@ -17,6 +17,8 @@ use alloc::collections::BTreeMap;
 use alloc::string::String;
 use alloc::vec::Vec;
 
+use crate::aslr::{AslrPolicy, LayoutRng};
+
 // =============================================================================
 // ELF Types
 // =============================================================================
@@ -39,6 +41,9 @@ const EM_X86_64: u16 = 62;
 /// Machine type: AArch64.
 const EM_AARCH64: u16 = 183;
 
+/// Lowest address shared libraries are loaded at.
+pub const LIBRARY_BASE: usize = 0x7f00_0000_0000;
+
 /// ELF64 header.
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
@@ -378,10 +383,12 @@ pub struct DynamicLinker {
     next_handle: u64,
     /// Global symbol table.
     global_symbols: BTreeMap<String, (LibraryHandle, usize)>,
+    /// Address the next library is loaded at.
+    next_addr: usize,
 }
 
 impl DynamicLinker {
-    /// Create a new dynamic linker.
+    /// Create a new dynamic linker loading libraries from [`LIBRARY_BASE`].
     pub fn new() -> Self {
         Self {
             libraries: BTreeMap::new(),
@@ -389,9 +396,29 @@ impl DynamicLinker {
             overrides: BTreeMap::new(),
             next_handle: 1,
             global_symbols: BTreeMap::new(),
+            next_addr: LIBRARY_BASE,
         }
     }
 
+    /// Create a dynamic linker whose library base is moved by `policy`.
+    ///
+    /// `seed` must be fresh CSPRNG output for this exec, such as the
+    /// `AT_RANDOM` bytes from the auxiliary vector.
+    pub fn with_aslr(policy: AslrPolicy, seed: [u8; 16]) -> Self {
+        let policy = policy.clamped();
+        let mut linker = Self::new();
+        if policy.enabled {
+            let offset = LayoutRng::from_seed(seed).page_offset(policy.mmap_entropy);
+            linker.next_addr += offset as usize;
+        }
+        linker
+    }
+
+    /// Address the next library will be loaded at.
+    pub fn library_base(&self) -> usize {
+        self.next_addr
+    }
+
     /// Add a library search path.
     pub fn add_search_path(&mut self, path: String) {
         self.search_paths.push(path);
@@ -599,14 +626,11 @@ impl DynamicLinker {
         (min_vaddr, max_vaddr)
     }
 
-    fn allocate_memory(&self, size: usize) -> Result<usize, LinkError> {
-        // In a real implementation, this would use mmap with ASLR
-        // For now, use a simple static allocator
-        static NEXT_ADDR: core::sync::atomic::AtomicUsize =
-            core::sync::atomic::AtomicUsize::new(0x7f00_0000_0000);
-
+    fn allocate_memory(&mut self, size: usize) -> Result<usize, LinkError> {
+        // Libraries are packed upwards from the (randomized) library base
         let aligned_size = (size + 4095) & !4095;
-        let addr = NEXT_ADDR.fetch_add(aligned_size, core::sync::atomic::Ordering::SeqCst);
+        let addr = self.next_addr;
+        self.next_addr = addr.checked_add(aligned_size).ok_or(LinkError::MemoryError)?;
 
         Ok(addr)
     }
@@ -1126,4 +1150,29 @@ mod tests {
         let linker = DynamicLinker::new();
         assert!(linker.lookup("nonexistent").is_none());
     }
+
+    #[test]
+    fn test_library_base_randomized() {
+        let policy = AslrPolicy::default_policy();
+        let a = DynamicLinker::with_aslr(policy, [1; 16]);
+        let b = DynamicLinker::with_aslr(policy, [2; 16]);
+        assert_ne!(a.library_base(), b.library_base());
+        for linker in [&a, &b] {
+            assert_eq!(linker.library_base() % 4096, 0);
+            assert!(linker.library_base() >= LIBRARY_BASE);
+        }
+
+        let fixed = DynamicLinker::with_aslr(AslrPolicy::disabled(), [1; 16]);
+        assert_eq!(fixed.library_base(), LIBRARY_BASE);
+    }
+
+    #[test]
+    fn test_libraries_do_not_overlap() {
+        let mut linker = DynamicLinker::with_aslr(AslrPolicy::default_policy(), [3; 16]);
+        let base = linker.library_base();
+        let first = linker.allocate_memory(5000).unwrap();
+        let second = linker.allocate_memory(100).unwrap();
+        assert_eq!(first, base);
+        assert_eq!(second, base + 8192);
+    }
 }
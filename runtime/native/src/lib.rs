@@ -11,6 +11,7 @@
 //! - System call filtering
 //! - Capability-gated resource access
 //! - Time and memory limits
+//! - Randomized code, stack, heap and library placement (see [`aslr`])
 //!
 //! ## When to Use S-NATIVE
 //!
@@ -33,6 +34,7 @@
 
 extern crate alloc;
 
+pub mod aslr;
 pub mod dynlink;
 
 use alloc::collections::BTreeMap;
@@ -43,6 +45,7 @@ use spin::Mutex;
 
 // Import shared capability token
 pub use splax_cap::{CapabilityToken, Operations, Permission};
+pub use aslr::{AslrPolicy, LayoutRng};
 pub use dynlink::{DynamicLinker, LoadedLibrary, LibraryHandle, LinkError};
 
 /// Native process identifier.
@@ -132,6 +135,8 @@ pub struct SandboxConfig {
     pub stack_size: usize,
     /// Heap size
     pub heap_size: usize,
+    /// Layout randomization
+    pub aslr: AslrPolicy,
 }
 
 impl Default for SandboxConfig {
@@ -141,6 +146,7 @@ impl Default for SandboxConfig {
             max_cpu_time: 10_000_000_000, // ~10 seconds at 1GHz
             stack_size: 1024 * 1024,       // 1 MB
             heap_size: 16 * 1024 * 1024,   // 16 MB
+            aslr: AslrPolicy::default_policy(),
         }
     }
 }
@@ -185,6 +191,8 @@ pub struct NativeProcess {
     instruction_pointer: u64,
     /// Current stack pointer
     stack_pointer: u64,
+    /// Loads the process's shared libraries
+    linker: DynamicLinker,
 }
 
 impl NativeProcess {
@@ -231,6 +239,8 @@ pub struct Native {
     next_id: Mutex<u64>,
     /// Maximum concurrent processes
     max_processes: usize,
+    /// Source of layout offsets
+    layout_rng: Mutex<LayoutRng>,
 }
 
 impl Native {
@@ -240,9 +250,19 @@ impl Native {
             processes: Mutex::new(BTreeMap::new()),
             next_id: Mutex::new(1),
             max_processes,
+            layout_rng: Mutex::new(LayoutRng::from_seed(aslr::kernel_seed().unwrap_or([0; 16]))),
         }
     }
 
+    /// Mixes fresh random bytes into the layout generator.
+    ///
+    /// Every load already reseeds from the kernel CSPRNG; this adds more,
+    /// such as the exec's `AT_RANDOM` bytes, and is the only source where
+    /// there is no Splax kernel to ask.
+    pub fn seed_layout(&self, seed: [u8; 16]) {
+        self.layout_rng.lock().reseed(seed);
+    }
+
     /// Loads a native binary.
     ///
     /// # Arguments
@@ -282,28 +302,45 @@ impl Native {
         let id = NativeProcessId(*next_id);
         *next_id += 1;
 
-        // Set up memory regions
+        // Set up memory regions, each moved by the sandbox's ASLR policy
+        let aslr = config.aslr.clamped();
+        let (code_base, stack_base, heap_base, linker) = {
+            let mut rng = self.layout_rng.lock();
+            if let Some(seed) = aslr::kernel_seed() {
+                rng.reseed(seed);
+            }
+            let linker = DynamicLinker::with_aslr(aslr, rng.next_seed());
+            let mut offset = |bits: u8| if aslr.enabled { rng.page_offset(bits) } else { 0 };
+            (
+                0x1000_0000 + offset(aslr.code_entropy),
+                0x7FFF_0000 - offset(aslr.stack_entropy),
+                0x4000_0000 + offset(aslr.heap_entropy),
+                linker,
+            )
+        };
+
         let code_region = MemoryRegion {
-            base: 0x1000_0000,
+            base: code_base,
             size: binary.len(),
             permissions: MemoryPermissions::READ_EXECUTE,
             region_type: RegionType::Code,
         };
 
         let stack_region = MemoryRegion {
-            base: 0x7FFF_0000,
+            base: stack_base,
             size: config.stack_size,
             permissions: MemoryPermissions::READ_WRITE,
             region_type: RegionType::Stack,
         };
 
         let heap_region = MemoryRegion {
-            base: 0x4000_0000,
+            base: heap_base,
             size: config.heap_size,
             permissions: MemoryPermissions::READ_WRITE,
             region_type: RegionType::Heap,
         };
 
+        let stack_top = stack_base + config.stack_size as u64 - 8;
         let process = NativeProcess {
             id,
             name,
@@ -313,9 +350,10 @@ impl Native {
             capabilities: BTreeMap::new(),
             state: ProcessState::Loading,
             cpu_time: 0,
-            entry_point: 0x1000_0000,
-            instruction_pointer: 0x1000_0000,
-            stack_pointer: 0x7FFF_0000 + 1024 * 1024 - 8, // Top of stack
+            entry_point: code_base,
+            instruction_pointer: code_base,
+            stack_pointer: stack_top,
+            linker,
         };
 
         processes.insert(id, process);
@@ -344,6 +382,25 @@ impl Native {
         Ok(())
     }
 
+    /// Loads a shared library into a process, above the library base its
+    /// [`AslrPolicy`] picked when the process was loaded.
+    pub fn load_library(
+        &self,
+        id: NativeProcessId,
+        name: &str,
+        elf_data: &[u8],
+        _cap_token: &CapabilityToken,
+    ) -> Result<LibraryHandle, NativeError> {
+        let mut processes = self.processes.lock();
+        let process = processes.get_mut(&id).ok_or(NativeError::ProcessNotFound)?;
+
+        if process.state != ProcessState::Loading {
+            return Err(NativeError::InvalidState);
+        }
+
+        process.linker.load(name, elf_data).map_err(|_| NativeError::InvalidBinary)
+    }
+
     /// Starts a process.
     pub fn start(
         &self,
@@ -455,6 +512,9 @@ impl Native {
             state: p.state,
             cpu_time: p.cpu_time,
             memory_used: p.regions.iter().map(|r| r.size).sum(),
+            entry_point: p.entry_point,
+            stack_pointer: p.stack_pointer,
+            library_base: p.linker.library_base(),
         })
     }
 }
@@ -467,6 +527,9 @@ pub struct ProcessInfo {
     pub state: ProcessState,
     pub cpu_time: u64,
     pub memory_used: usize,
+    pub entry_point: u64,
+    pub stack_pointer: u64,
+    pub library_base: usize,
 }
 
 /// Result of running a process.
@@ -527,4 +590,35 @@ mod tests {
         let info = native.process_info(id).expect("should exist");
         assert_eq!(info.state, ProcessState::Loading);
     }
+
+    #[test]
+    fn test_layout_randomized_per_load() {
+        let native = Native::new(100);
+        native.seed_layout([0xA5; 16]);
+        let token = dummy_token();
+        let raw = [0x90u8; 16];
+
+        let mut entries = Vec::new();
+        for _ in 0..4 {
+            let id = native
+                .load(&raw, BinaryFormat::Raw, SandboxConfig::default(), None, &token)
+                .expect("should load");
+            let info = native.process_info(id).expect("should exist");
+            let layout = (info.entry_point, info.stack_pointer, info.library_base);
+            assert!(!entries.contains(&layout));
+            entries.push(layout);
+        }
+
+        let fixed = SandboxConfig {
+            aslr: AslrPolicy::disabled(),
+            ..SandboxConfig::default()
+        };
+        let id = native
+            .load(&raw, BinaryFormat::Raw, fixed, None, &token)
+            .expect("should load");
+        let info = native.process_info(id).expect("should exist");
+        assert_eq!(info.entry_point, 0x1000_0000);
+        assert_eq!(info.stack_pointer, 0x7FFF_0000 + 1024 * 1024 - 8);
+        assert_eq!(info.library_base, dynlink::LIBRARY_BASE);
+    }
 }
@@ -570,6 +570,9 @@ pub struct OverlayFs {
     pub work_dir: String,
     /// Merged mount point.
     pub merged_dir: String,
+    /// Where the host root is moved by `pivot_root`; the image's root must
+    /// have a `.old_root` directory.
+    pub old_root: String,
 }
 
 impl OverlayFs {
@@ -582,8 +585,134 @@ impl OverlayFs {
             upper_dir: alloc::format!("{}/upper", base),
             work_dir: alloc::format!("{}/work", base),
             merged_dir: alloc::format!("{}/merged", base),
+            old_root: alloc::format!("{}/merged/.old_root", base),
         }
     }
+
+    /// The directory bound at the merged directory to form the root.
+    ///
+    /// The kernel has no overlay filesystem yet, so only a single-layer
+    /// image has one: its layer.
+    pub fn root_source(&self) -> Option<&str> {
+        match self.lower_dirs.as_slice() {
+            [layer] => Some(layer),
+            _ => None,
+        }
+    }
+
+    /// Make the merged directory the root of the calling process, in a
+    /// mount namespace of its own. The old root stays reachable at
+    /// `/.old_root`.
+    pub fn enter(&self, token: &MountNsToken) -> Result<(), ContainerError> {
+        let source = self.root_source().ok_or(ContainerError::FilesystemError)?;
+        sys::unshare(sys::CLONE_NEWNS, token).map_err(|_| ContainerError::PermissionDenied)?;
+        sys::mount(source, &self.merged_dir, "rbind", token).map_err(|_| ContainerError::FilesystemError)?;
+        sys::pivot_root(&self.merged_dir, &self.old_root, token).map_err(|_| ContainerError::FilesystemError)
+    }
+}
+
+// =============================================================================
+// Kernel Interface
+// =============================================================================
+
+/// Kernel capability token for a `mount_ns` resource, which creating a
+/// mount namespace and switching its root require.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MountNsToken(pub [u64; 4]);
+
+/// System calls used to set up a container's root.
+///
+/// Strings are passed as (pointer, length) pairs and tokens by pointer.
+mod sys {
+    use super::MountNsToken;
+
+    /// `unshare` flag for a new mount namespace.
+    pub const CLONE_NEWNS: u64 = 0x2_0000;
+
+    #[cfg(target_arch = "x86_64")]
+    mod nr {
+        pub const PIVOT_ROOT: u64 = 155;
+        pub const MOUNT: u64 = 165;
+        pub const UNSHARE: u64 = 272;
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    mod nr {
+        pub const PIVOT_ROOT: u64 = 41;
+        pub const MOUNT: u64 = 40;
+        pub const UNSHARE: u64 = 97;
+    }
+
+    /// Enter the kernel through its `int 0x80` gate.
+    #[cfg(target_arch = "x86_64")]
+    fn syscall(nr: u64, args: [u64; 6]) -> i64 {
+        let result: i64;
+        unsafe {
+            core::arch::asm!(
+                "int 0x80",
+                inlateout("rax") nr as i64 => result,
+                in("rdi") args[0],
+                in("rsi") args[1],
+                in("rdx") args[2],
+                in("r10") args[3],
+                in("r8") args[4],
+                in("r9") args[5],
+                options(nostack)
+            );
+        }
+        result
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn syscall(nr: u64, args: [u64; 6]) -> i64 {
+        let result: i64;
+        unsafe {
+            core::arch::asm!(
+                "svc #0",
+                in("x8") nr,
+                inlateout("x0") args[0] => result,
+                in("x1") args[1],
+                in("x2") args[2],
+                in("x3") args[3],
+                in("x4") args[4],
+                in("x5") args[5],
+                options(nostack)
+            );
+        }
+        result
+    }
+
+    fn check(result: i64) -> Result<u64, i64> {
+        if result < 0 { Err(-result) } else { Ok(result as u64) }
+    }
+
+    fn token_ptr(token: &MountNsToken) -> u64 {
+        token.0.as_ptr() as u64
+    }
+
+    pub fn unshare(flags: u64, token: &MountNsToken) -> Result<(), i64> {
+        check(syscall(nr::UNSHARE, [flags, token_ptr(token), 0, 0, 0, 0])).map(|_| ())
+    }
+
+    /// Mount under the same token; the strings go by a pointer to their
+    /// (pointer, length) pairs.
+    pub fn mount(source: &str, target: &str, fs_type: &str, token: &MountNsToken) -> Result<(), i64> {
+        let strings = [
+            source.as_ptr() as u64, source.len() as u64,
+            target.as_ptr() as u64, target.len() as u64,
+            fs_type.as_ptr() as u64, fs_type.len() as u64,
+        ];
+        check(syscall(nr::MOUNT, [strings.as_ptr() as u64, token_ptr(token), 0, 0, 0, 0])).map(|_| ())
+    }
+
+    pub fn pivot_root(new_root: &str, put_old: &str, token: &MountNsToken) -> Result<(), i64> {
+        let args = [
+            new_root.as_ptr() as u64, new_root.len() as u64,
+            put_old.as_ptr() as u64, put_old.len() as u64,
+            token_ptr(token), 0,
+        ];
+        check(syscall(nr::PIVOT_ROOT, args)).map(|_| ())
+    }
 }
 
 // =============================================================================
@@ -604,6 +733,9 @@ pub struct Container {
     pub namespaces: Option<ContainerNamespaces>,
     /// Splax capability token.
     pub capability: Option<CapabilityToken>,
+    /// Kernel `mount_ns` token; with one, the container can get its own
+    /// root through [`enter_root`](Self::enter_root).
+    pub mount_ns: Option<MountNsToken>,
     /// Health status.
     pub health: HealthStatus,
     /// Resource usage statistics.
@@ -622,6 +754,7 @@ impl Container {
             overlay,
             namespaces: None,
             capability: None,
+            mount_ns: None,
             health: HealthStatus::None,
             stats: ContainerStats::default(),
         }
@@ -660,6 +793,16 @@ impl Container {
         Ok(())
     }
 
+    /// Move the calling process into the container's root: a mount
+    /// namespace of its own, rooted at the overlay's merged directory.
+    ///
+    /// For the process that goes on to run the container's command. `start`
+    /// does not launch one itself, as the kernel cannot exec in place yet.
+    pub fn enter_root(&self) -> Result<(), ContainerError> {
+        let token = self.mount_ns.ok_or(ContainerError::PermissionDenied)?;
+        self.overlay.enter(&token)
+    }
+
     /// Stop the container.
     pub fn stop(&mut self, timeout_ms: u64) -> Result<(), ContainerError> {
         if self.status.state != ContainerState::Running {
@@ -782,6 +925,8 @@ pub struct ContainerRuntime {
     images: BTreeMap<String, ImageManifest>,
     /// Random number generator.
     random_state: u64,
+    /// Kernel `mount_ns` token given to new containers.
+    mount_ns: Option<MountNsToken>,
 }
 
 impl ContainerRuntime {
@@ -791,9 +936,16 @@ impl ContainerRuntime {
             containers: BTreeMap::new(),
             images: BTreeMap::new(),
             random_state: 0x12345678_9ABCDEF0,
+            mount_ns: None,
         }
     }
 
+    /// Let containers created from now on enter their own root
+    /// filesystem, under a kernel `mount_ns` capability.
+    pub fn set_mount_ns_token(&mut self, token: MountNsToken) {
+        self.mount_ns = Some(token);
+    }
+
     /// Generate random number.
     fn random(&mut self) -> u64 {
         self.random_state ^= self.random_state << 13;
@@ -814,7 +966,8 @@ impl ContainerRuntime {
         let layers = self.resolve_image_layers(&config.image)?;
 
         // Create container
-        let container = Container::new(id, config, layers);
+        let mut container = Container::new(id, config, layers);
+        container.mount_ns = self.mount_ns;
 
         self.containers.insert(id, container);
 
@@ -1190,6 +1343,19 @@ mod tests {
         assert!(runtime.get(&id).is_none());
     }
 
+    #[test]
+    fn test_overlay_root_source() {
+        let id = ContainerId([7; 32]);
+        let base = String::from("/var/lib/containers/layers/base");
+        let overlay = OverlayFs::new(&id, core::slice::from_ref(&base));
+        assert_eq!(overlay.root_source(), Some(base.as_str()));
+        assert!(overlay.old_root.starts_with(&overlay.merged_dir));
+
+        // Stacking layers needs an overlay filesystem
+        let overlay = OverlayFs::new(&id, &[base.clone(), String::from("/var/lib/containers/layers/app")]);
+        assert_eq!(overlay.root_source(), None);
+    }
+
     #[test]
     fn test_resource_limits() {
         let limits = ResourceLimits {
@@ -7,6 +7,8 @@
 //! - **Dependency Resolution**: Ensuring services start in the correct order
 //! - **Process Supervision**: Restarting crashed services automatically
 //! - **Runlevel Management**: Managing system states (boot, running, shutdown)
+//! - **Mount Table**: Mounting filesystems, such as host shares, before
+//!   services start
 //!
 //! ## Architecture
 //!
@@ -158,6 +160,54 @@ fn kill_process(pid: u64, signal: i32) -> Result<(), ()> {
     if result == 0 { Ok(()) } else { Err(()) }
 }
 
+/// Mount a filesystem via syscall
+///
+/// Strings are passed as (pointer, length) pairs, like the spawn path.
+fn mount_filesystem(source: &str, target: &str, fs_type: &str) -> Result<(), i64> {
+    let result: i64;
+    
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        core::arch::asm!(
+            "syscall",
+            in("rax") 165u64,  // mount syscall
+            in("rdi") source.as_ptr() as u64,
+            in("rsi") source.len() as u64,
+            in("rdx") target.as_ptr() as u64,
+            in("r10") target.len() as u64,
+            in("r8") fs_type.as_ptr() as u64,
+            in("r9") fs_type.len() as u64,
+            lateout("rax") result,
+            out("rcx") _,
+            out("r11") _,
+            options(nostack)
+        );
+    }
+    
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        core::arch::asm!(
+            "svc #0",
+            in("x8") 40u64,  // mount syscall
+            in("x0") source.as_ptr() as u64,
+            in("x1") source.len() as u64,
+            in("x2") target.as_ptr() as u64,
+            in("x3") target.len() as u64,
+            in("x4") fs_type.as_ptr() as u64,
+            in("x5") fs_type.len() as u64,
+            lateout("x0") result,
+            options(nostack)
+        );
+    }
+    
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        result = -38;
+    }
+    
+    if result == 0 { Ok(()) } else { Err(result) }
+}
+
 /// Result of a non-blocking waitpid call
 enum WaitResult {
     /// Process exited with status code
@@ -457,6 +507,37 @@ impl ServiceDef {
     }
 }
 
+/// A filesystem to mount at boot
+#[derive(Debug, Clone)]
+pub struct MountDef {
+    /// Block device, or mount tag for `9p`
+    pub source: String,
+    /// Mount point
+    pub target: String,
+    /// Filesystem type (as in `mount -t`)
+    pub fs_type: String,
+    /// Boot continues if this fails (e.g. a host share not passed to QEMU)
+    pub optional: bool,
+}
+
+impl MountDef {
+    /// Create a new mount definition
+    pub fn new(source: &str, target: &str, fs_type: &str) -> Self {
+        Self {
+            source: String::from(source),
+            target: String::from(target),
+            fs_type: String::from(fs_type),
+            optional: false,
+        }
+    }
+    
+    /// Allow boot to continue without this mount
+    pub fn optional(mut self) -> Self {
+        self.optional = true;
+        self
+    }
+}
+
 // =============================================================================
 // RUNTIME STATE
 // =============================================================================
@@ -567,6 +648,8 @@ pub struct ServiceManager {
     next_id: RwLock<u64>,
     /// Boot complete flag
     boot_complete: RwLock<bool>,
+    /// Mount table, in mount order
+    mounts: RwLock<Vec<MountDef>>,
 }
 
 impl ServiceManager {
@@ -578,6 +661,7 @@ impl ServiceManager {
             runlevel: RwLock::new(Runlevel::Boot),
             next_id: RwLock::new(1),
             boot_complete: RwLock::new(false),
+            mounts: RwLock::new(Vec::new()),
         }
     }
     
@@ -818,6 +902,34 @@ impl ServiceManager {
         *self.boot_complete.read()
     }
     
+    /// Add a filesystem to the mount table
+    pub fn add_mount(&self, def: MountDef) {
+        self.mounts.write().push(def);
+    }
+    
+    /// List the mount table
+    pub fn list_mounts(&self) -> Vec<MountDef> {
+        self.mounts.read().clone()
+    }
+    
+    /// Mount the mount table in order, returning how many mounted
+    pub fn mount_all(&self) -> Result<usize, InitError> {
+        self.mount_with(|def| mount_filesystem(&def.source, &def.target, &def.fs_type))
+    }
+    
+    /// Mount the mount table with `mount`; a required mount failing stops
+    fn mount_with(&self, mut mount: impl FnMut(&MountDef) -> Result<(), i64>) -> Result<usize, InitError> {
+        let mut mounted = 0;
+        for def in self.mounts.read().iter() {
+            match mount(def) {
+                Ok(()) => mounted += 1,
+                Err(_) if def.optional => {}
+                Err(_) => return Err(InitError::MountFailed(def.target.clone())),
+            }
+        }
+        Ok(mounted)
+    }
+    
     /// List all services
     pub fn list_services(&self) -> Vec<(ServiceId, String, ServiceState)> {
         self.services.read()
@@ -856,6 +968,8 @@ pub enum InitError {
     InvalidDefinition,
     /// Permission denied
     PermissionDenied,
+    /// A required mount failed
+    MountFailed(String),
 }
 
 impl core::fmt::Display for InitError {
@@ -869,6 +983,7 @@ impl core::fmt::Display for InitError {
             InitError::ExecFailed(path) => write!(f, "Failed to exec: {}", path),
             InitError::InvalidDefinition => write!(f, "Invalid service definition"),
             InitError::PermissionDenied => write!(f, "Permission denied"),
+            InitError::MountFailed(target) => write!(f, "Failed to mount: {}", target),
         }
     }
 }
@@ -940,6 +1055,12 @@ pub fn register_builtin_services() {
     INIT.register(atlas);
 }
 
+/// Register the default mount table
+pub fn register_builtin_mounts() {
+    // Host directory shared by `scripts/splax run --share`
+    INIT.add_mount(MountDef::new("host", "/mnt/host", "9p").optional());
+}
+
 /// Register microkernel core services
 fn register_microkernel_services() {
     use microkernel::CoreService;
@@ -1046,6 +1167,10 @@ pub fn init_main() -> ! {
         }
     }
     
+    // Mount filesystems before services need them
+    register_builtin_mounts();
+    let _ = INIT.mount_all();
+    
     // Start boot sequence
     let _ = INIT.set_runlevel(Runlevel::Graphical);
     
@@ -1119,4 +1244,35 @@ mod tests {
         let _ = manager.set_runlevel(Runlevel::Network);
         assert_eq!(manager.runlevel(), Runlevel::Network);
     }
+    
+    #[test]
+    fn test_mount_table() {
+        let manager = ServiceManager::new();
+        manager.add_mount(MountDef::new("vda", "/", "splaxfs"));
+        manager.add_mount(MountDef::new("host", "/mnt/host", "9p").optional());
+        manager.add_mount(MountDef::new("vdb", "/data", "ext4"));
+        assert_eq!(manager.list_mounts().len(), 3);
+        
+        // Mounted in order
+        let mut order = Vec::new();
+        let result = manager.mount_with(|def| {
+            order.push(def.target.clone());
+            Ok(())
+        });
+        assert_eq!(result.unwrap(), 3);
+        assert_eq!(order, ["/", "/mnt/host", "/data"]);
+        
+        // A missing host share is skipped
+        let result = manager.mount_with(|def| if def.fs_type == "9p" { Err(-2) } else { Ok(()) });
+        assert_eq!(result.unwrap(), 2);
+        
+        // A failed required mount stops the rest
+        let mut tried = 0;
+        let result = manager.mount_with(|def| {
+            tried += 1;
+            if def.target == "/" { Err(-5) } else { Ok(()) }
+        });
+        assert!(matches!(result, Err(InitError::MountFailed(target)) if target == "/"));
+        assert_eq!(tried, 1);
+    }
 }
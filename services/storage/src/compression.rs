@@ -0,0 +1,63 @@
+//! Object data compression (`compression` feature)
+//!
+//! Stored data starts with a tag byte: 0 for raw data, otherwise the
+//! [`Algorithm`] number of the compressed stream that follows. Data that
+//! does not shrink is stored raw, and the metadata size tells how long
+//! the stream decompresses to.
+
+use alloc::vec::Vec;
+
+pub use splax_compress::Algorithm;
+
+use crate::StorageError;
+
+/// Tag of data stored raw
+const RAW: u8 = 0;
+
+/// Encodes object data for storage
+pub fn pack(algorithm: Option<Algorithm>, data: &[u8]) -> Vec<u8> {
+    if let Some(algorithm) = algorithm {
+        let stream = splax_compress::compress(algorithm, data);
+        if stream.len() < data.len() {
+            let mut out = Vec::with_capacity(stream.len() + 1);
+            out.push(algorithm as u8);
+            out.extend_from_slice(&stream);
+            return out;
+        }
+    }
+    let mut out = Vec::with_capacity(data.len() + 1);
+    out.push(RAW);
+    out.extend_from_slice(data);
+    out
+}
+
+/// Decodes stored object data of `len` bytes
+pub fn unpack(stored: &[u8], len: usize) -> Result<Vec<u8>, StorageError> {
+    match stored.split_first() {
+        Some((&RAW, data)) => Ok(data.to_vec()),
+        Some((&tag, stream)) => {
+            let algorithm = Algorithm::from_u8(tag).ok_or(StorageError::Corrupted)?;
+            splax_compress::decompress(algorithm, stream, len).map_err(|_| StorageError::Corrupted)
+        }
+        None => Err(StorageError::Corrupted),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pack() {
+        let text = b"GET /index.html 200\n".repeat(100);
+        let packed = pack(Some(Algorithm::Lz4), &text);
+        assert!(packed.len() < text.len() / 4);
+        assert_eq!(unpack(&packed, text.len()).unwrap(), text);
+
+        // Data that does not shrink is kept as is
+        let packed = pack(Some(Algorithm::Lzh), b"abc");
+        assert_eq!(packed, [RAW, b'a', b'b', b'c']);
+        assert_eq!(unpack(&packed, 3).unwrap(), b"abc");
+        assert_eq!(unpack(&[9, 1, 2], 3), Err(StorageError::Corrupted));
+    }
+}
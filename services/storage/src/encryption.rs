@@ -0,0 +1,115 @@
+//! Object data encryption (`encryption` feature)
+//!
+//! Stored data (compressed first, with the `compression` feature) is
+//! sealed with AES-256-XTS: a little-endian `u32` length, then the data
+//! zero-padded to at least one AES block and encrypted in 4 KiB units.
+//! A tail shorter than a block joins the unit before it. The tweak of a
+//! unit combines the object id (bits 0-63), the unit number (bits 64-95)
+//! and the low 32 bits of the object version (bits 96-127), so no two
+//! units of the store, nor two versions of one, share a tweak.
+//!
+//! XTS does not authenticate: damaged or tampered data decrypts to
+//! garbage rather than failing, which the decompressor or the content
+//! hash usually catches.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use splax_crypto::aes::BLOCK_SIZE;
+use splax_crypto::Aes256Xts;
+
+use crate::StorageError;
+
+/// Bytes in a key: the data key, then the tweak key
+pub const KEY_SIZE: usize = splax_crypto::xts::KEY_SIZE;
+
+/// Bytes encrypted under one tweak
+const UNIT_SIZE: usize = 4096;
+
+/// Bytes before the sealed data
+const HEADER_SIZE: usize = 4;
+
+/// Key object data is encrypted with
+#[derive(Clone)]
+pub struct Key(pub [u8; KEY_SIZE]);
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Key(..)")
+    }
+}
+
+/// Encrypts or decrypts the padded data of an object in place
+fn crypt(key: &Key, id: u64, version: u64, data: &mut [u8], decrypt: bool) {
+    let xts = Aes256Xts::new(&key.0);
+    let base = id as u128 | ((version as u32 as u128) << 96);
+    let mut start = 0;
+    let mut unit: u128 = 0;
+    while start < data.len() {
+        let mut end = (start + UNIT_SIZE).min(data.len());
+        if data.len() - end < BLOCK_SIZE {
+            end = data.len();
+        }
+        let tweak = base | (unit << 64);
+        if decrypt {
+            xts.decrypt(tweak, &mut data[start..end]);
+        } else {
+            xts.encrypt(tweak, &mut data[start..end]);
+        }
+        start = end;
+        unit += 1;
+    }
+}
+
+/// Encrypts version `version` of an object's data for storage
+pub fn seal(key: &Key, id: u64, version: u64, data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(HEADER_SIZE + data.len().max(BLOCK_SIZE));
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+    out.resize(HEADER_SIZE + data.len().max(BLOCK_SIZE), 0);
+    crypt(key, id, version, &mut out[HEADER_SIZE..], false);
+    out
+}
+
+/// Decrypts stored data sealed by [`seal`]
+pub fn open(key: &Key, id: u64, version: u64, stored: &[u8]) -> Result<Vec<u8>, StorageError> {
+    if stored.len() < HEADER_SIZE + BLOCK_SIZE {
+        return Err(StorageError::Corrupted);
+    }
+    let len = u32::from_le_bytes([stored[0], stored[1], stored[2], stored[3]]) as usize;
+    if len > stored.len() - HEADER_SIZE {
+        return Err(StorageError::Corrupted);
+    }
+    let mut data = stored[HEADER_SIZE..].to_vec();
+    crypt(key, id, version, &mut data, true);
+    data.truncate(len);
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal() {
+        let key = Key([7; KEY_SIZE]);
+        for len in [0, 5, 16, 4096, 4100, 4112, 10_000] {
+            let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            let sealed = seal(&key, 3, 1, &data);
+            assert!(sealed.len() >= HEADER_SIZE + BLOCK_SIZE);
+            if len >= BLOCK_SIZE {
+                assert_ne!(&sealed[HEADER_SIZE..HEADER_SIZE + 16], &data[..16]);
+            }
+            assert_eq!(open(&key, 3, 1, &sealed).unwrap(), data);
+        }
+
+        // Equal data under another object or version seals differently
+        let data = [0x42u8; 64];
+        let sealed = seal(&key, 3, 1, &data);
+        assert_ne!(seal(&key, 4, 1, &data), sealed);
+        assert_ne!(seal(&key, 3, 2, &data), sealed);
+        assert_ne!(open(&key, 3, 2, &sealed).unwrap(), data);
+        assert_eq!(open(&key, 3, 1, &sealed[..10]), Err(StorageError::Corrupted));
+        assert_eq!(alloc::format!("{:?}", key), "Key(..)");
+    }
+}
@@ -66,6 +66,14 @@ pub mod cas;
 // Distributed Storage Protocol
 pub mod distributed;
 
+// Object data compression
+#[cfg(feature = "compression")]
+pub mod compression;
+
+// Object data encryption
+#[cfg(feature = "encryption")]
+pub mod encryption;
+
 // Re-export VFS types
 pub use vfs_protocol::*;
 pub use vfs_server::{Filesystem, VfsServer};
@@ -165,6 +173,8 @@ impl ObjectMetadata {
 struct StoredObject {
     id: ObjectId,
     metadata: ObjectMetadata,
+    /// Data as stored (compressed with the `compression` feature, then
+    /// encrypted with the `encryption` feature)
     data: Vec<u8>,
 }
 
@@ -179,6 +189,12 @@ pub struct StorageConfig {
     pub enable_dedup: bool,
     /// Enable versioning
     pub enable_versioning: bool,
+    /// Algorithm object data is compressed with
+    #[cfg(feature = "compression")]
+    pub compression: Option<compression::Algorithm>,
+    /// Key object data is encrypted with
+    #[cfg(feature = "encryption")]
+    pub encryption_key: Option<encryption::Key>,
 }
 
 impl Default for StorageConfig {
@@ -188,6 +204,10 @@ impl Default for StorageConfig {
             max_objects: 1_000_000,
             enable_dedup: true,
             enable_versioning: false,
+            #[cfg(feature = "compression")]
+            compression: Some(compression::Algorithm::Lz4),
+            #[cfg(feature = "encryption")]
+            encryption_key: None,
         }
     }
 }
@@ -205,6 +225,8 @@ pub struct Storage {
     next_id: Mutex<u64>,
     /// Total storage used
     used_bytes: Mutex<usize>,
+    /// Storage used after compression
+    stored_bytes: Mutex<usize>,
 }
 
 impl Storage {
@@ -217,7 +239,42 @@ impl Storage {
             by_name: Mutex::new(BTreeMap::new()),
             next_id: Mutex::new(1),
             used_bytes: Mutex::new(0),
+            stored_bytes: Mutex::new(0),
+        }
+    }
+
+    /// Object data as stored
+    fn pack(&self, id: ObjectId, metadata: &ObjectMetadata, data: Vec<u8>) -> Vec<u8> {
+        #[cfg(feature = "compression")]
+        let data = compression::pack(self.config.compression, &data);
+        #[cfg(feature = "encryption")]
+        if let Some(key) = &self.config.encryption_key {
+            return encryption::seal(key, id.0, metadata.version, &data);
         }
+        let _ = (id, metadata);
+        data
+    }
+
+    /// Object data from what is stored
+    fn unpack(&self, object: &StoredObject) -> Result<Vec<u8>, StorageError> {
+        #[cfg(feature = "encryption")]
+        if let Some(key) = &self.config.encryption_key {
+            let stored = encryption::open(key, object.id.0, object.metadata.version, &object.data)?;
+            return Self::decode(&stored, object.metadata.size);
+        }
+        Self::decode(&object.data, object.metadata.size)
+    }
+
+    /// Object data from stored data, once decrypted
+    fn decode(stored: &[u8], size: usize) -> Result<Vec<u8>, StorageError> {
+        #[cfg(feature = "compression")]
+        let data = compression::unpack(stored, size)?;
+        #[cfg(not(feature = "compression"))]
+        let data = {
+            let _ = size;
+            stored.to_vec()
+        };
+        Ok(data)
     }
 
     /// Creates a new object.
@@ -282,6 +339,8 @@ impl Storage {
 
         // Update used bytes
         *self.used_bytes.lock() += data.len();
+        let data = self.pack(id, &metadata, data);
+        *self.stored_bytes.lock() += data.len();
 
         // Store object
         objects.insert(id, StoredObject { id, metadata, data });
@@ -297,7 +356,7 @@ impl Storage {
     ) -> Result<Vec<u8>, StorageError> {
         let objects = self.objects.lock();
         let object = objects.get(&id).ok_or(StorageError::ObjectNotFound)?;
-        Ok(object.data.clone())
+        self.unpack(object)
     }
 
     /// Gets an object's metadata.
@@ -326,21 +385,23 @@ impl Storage {
         let object = objects.get_mut(&id).ok_or(StorageError::ObjectNotFound)?;
 
         // Update used bytes
-        let old_size = object.data.len();
-        *self.used_bytes.lock() = self.used_bytes.lock().saturating_sub(old_size) + data.len();
-
-        // Update object
-        object.data = data;
-        object.metadata.size = object.data.len();
-        object.metadata.modified_at = 0; // Would use real timestamp
-        object.metadata.version += 1;
+        let old_size = object.metadata.size;
+        adjust(&self.used_bytes, old_size, data.len());
 
         // Update hash if dedup enabled
         if self.config.enable_dedup {
-            let hash = ContentHash::compute(&object.data);
+            let hash = ContentHash::compute(&data);
             object.metadata.content_hash = Some(hash);
         }
 
+        // Update object
+        object.metadata.size = data.len();
+        object.metadata.modified_at = 0; // Would use real timestamp
+        object.metadata.version += 1;
+        let old_stored = object.data.len();
+        object.data = self.pack(id, &object.metadata, data);
+        adjust(&self.stored_bytes, old_stored, object.data.len());
+
         Ok(())
     }
 
@@ -364,7 +425,8 @@ impl Storage {
         }
 
         // Update used bytes
-        *self.used_bytes.lock() = self.used_bytes.lock().saturating_sub(object.data.len());
+        adjust(&self.used_bytes, object.metadata.size, 0);
+        adjust(&self.stored_bytes, object.data.len(), 0);
 
         Ok(())
     }
@@ -397,17 +459,28 @@ impl Storage {
         StorageStats {
             object_count: objects.len(),
             used_bytes: *self.used_bytes.lock(),
+            stored_bytes: *self.stored_bytes.lock(),
             max_objects: self.config.max_objects,
             max_object_size: self.config.max_object_size,
         }
     }
 }
 
+/// Replaces `old` bytes with `new` in a byte count. The guard is taken
+/// once: a second `lock()` in the same statement would spin forever.
+fn adjust(counter: &Mutex<usize>, old: usize, new: usize) {
+    let mut count = counter.lock();
+    *count = count.saturating_sub(old) + new;
+}
+
 /// Storage statistics.
 #[derive(Debug, Clone)]
 pub struct StorageStats {
     pub object_count: usize,
+    /// Bytes of object data
     pub used_bytes: usize,
+    /// Bytes the data takes in storage, after compression
+    pub stored_bytes: usize,
     pub max_objects: usize,
     pub max_object_size: usize,
 }
@@ -425,6 +498,8 @@ pub enum StorageError {
     InvalidCapability,
     /// Permission denied
     PermissionDenied,
+    /// Stored data is damaged
+    Corrupted,
 }
 
 #[cfg(test)]
@@ -483,4 +558,49 @@ mod tests {
         let found_id = storage.find_by_name("my-object", &token).expect("should find");
         assert_eq!(found_id, id);
     }
+
+    #[test]
+    fn test_stats() {
+        let storage = Storage::new(StorageConfig::default());
+        let token = dummy_token();
+
+        let data = b"level=info msg=\"request served\"\n".repeat(200);
+        let metadata = ObjectMetadata::new("text/plain", data.len());
+        let id = storage.create(data.clone(), metadata, &token).expect("should create");
+        assert_eq!(storage.read(id, &token).expect("should read"), data);
+
+        let stats = storage.stats();
+        assert_eq!(stats.used_bytes, data.len());
+        if cfg!(feature = "compression") {
+            assert!(stats.stored_bytes * 5 < stats.used_bytes);
+        } else {
+            assert_eq!(stats.stored_bytes, stats.used_bytes);
+        }
+
+        storage.update(id, b"short".to_vec(), &token).expect("should update");
+        assert_eq!(storage.read(id, &token).expect("should read"), b"short");
+        storage.delete(id, &token).expect("should delete");
+        let stats = storage.stats();
+        assert_eq!((stats.used_bytes, stats.stored_bytes), (0, 0));
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_encryption() {
+        let storage = Storage::new(StorageConfig {
+            encryption_key: Some(encryption::Key([9; encryption::KEY_SIZE])),
+            ..StorageConfig::default()
+        });
+        let token = dummy_token();
+
+        let data = b"card=4111111111111111\n".repeat(10);
+        let metadata = ObjectMetadata::new("text/plain", data.len());
+        let id = storage.create(data.clone(), metadata, &token).expect("should create");
+        assert_eq!(storage.read(id, &token).expect("should read"), data);
+        let stored = storage.objects.lock()[&id].data.clone();
+        assert!(!stored.windows(4).any(|window| window == b"4111"));
+
+        storage.update(id, b"short".to_vec(), &token).expect("should update");
+        assert_eq!(storage.read(id, &token).expect("should read"), b"short");
+    }
 }
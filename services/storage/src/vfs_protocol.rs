@@ -139,6 +139,55 @@ pub enum SeekFrom {
     End = 2,
 }
 
+/// File lock operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum LockType {
+    /// Shared (read) lock
+    Shared = 0,
+    /// Exclusive (write) lock
+    Exclusive = 1,
+    /// Release
+    Unlock = 2,
+}
+
+/// Lock operation on a byte range
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockRequest {
+    /// Operation
+    pub lock_type: LockType,
+    /// First byte
+    pub start: u64,
+    /// Length in bytes; 0 extends to the end of the file
+    pub len: u64,
+    /// Enforce the lock on reads and writes of other processes
+    pub mandatory: bool,
+}
+
+impl LockRequest {
+    /// Request covering the whole file
+    pub const fn whole_file(lock_type: LockType) -> Self {
+        Self { lock_type, start: 0, len: 0, mandatory: false }
+    }
+}
+
+/// A lock held, as reported by [`VfsResponse::Lock`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockInfo {
+    /// [`LockType::Shared`] or [`LockType::Exclusive`]
+    pub lock_type: LockType,
+    /// First byte
+    pub start: u64,
+    /// Length in bytes, 0 for up to the end of the file
+    pub len: u64,
+    /// Process holding the lock
+    pub pid: u64,
+    /// Whether the lock is mandatory
+    pub mandatory: bool,
+    /// Whether it is a whole-file `flock` lock
+    pub flock: bool,
+}
+
 /// VFS request types (kernel -> storage service)
 #[derive(Debug, Clone)]
 pub enum VfsRequest {
@@ -270,6 +319,77 @@ pub enum VfsRequest {
         request_id: RequestId,
         path: String,
     },
+
+    /// Get an extended attribute
+    Getxattr {
+        request_id: RequestId,
+        path: String,
+        name: String,
+    },
+
+    /// Set an extended attribute
+    Setxattr {
+        request_id: RequestId,
+        path: String,
+        name: String,
+        value: Vec<u8>,
+    },
+
+    /// List extended attribute names
+    Listxattr {
+        request_id: RequestId,
+        path: String,
+    },
+
+    /// Remove an extended attribute
+    Removexattr {
+        request_id: RequestId,
+        path: String,
+        name: String,
+    },
+
+    /// Watch a file or directory; its events are sent on `channel`
+    AddWatch {
+        request_id: RequestId,
+        path: String,
+        mask: u32,
+        channel: u64,
+    },
+
+    /// Remove a watch
+    RemoveWatch {
+        request_id: RequestId,
+        wd: WatchDescriptor,
+    },
+
+    /// Take, change or release a lock for process `pid`. A `flock` lock
+    /// covers the whole file and belongs to the handle; otherwise the
+    /// range is locked for the process. With `wait`, a conflict is
+    /// recorded for deadlock detection and answered with
+    /// [`VfsError::WouldBlock`]; the kernel retries until the lock is
+    /// granted or [`VfsError::Deadlock`] is returned.
+    Lock {
+        request_id: RequestId,
+        handle: FileHandle,
+        pid: u64,
+        lock: LockRequest,
+        flock: bool,
+        wait: bool,
+    },
+
+    /// Find a byte-range lock that would prevent `lock` by `pid`
+    GetLock {
+        request_id: RequestId,
+        handle: FileHandle,
+        pid: u64,
+        lock: LockRequest,
+    },
+
+    /// Release every lock of an exited process
+    ReleaseLocks {
+        request_id: RequestId,
+        pid: u64,
+    },
 }
 
 impl VfsRequest {
@@ -294,6 +414,15 @@ impl VfsRequest {
             VfsRequest::Sync { request_id, .. } => *request_id,
             VfsRequest::Seek { request_id, .. } => *request_id,
             VfsRequest::Statfs { request_id, .. } => *request_id,
+            VfsRequest::Getxattr { request_id, .. } => *request_id,
+            VfsRequest::Setxattr { request_id, .. } => *request_id,
+            VfsRequest::Listxattr { request_id, .. } => *request_id,
+            VfsRequest::Removexattr { request_id, .. } => *request_id,
+            VfsRequest::AddWatch { request_id, .. } => *request_id,
+            VfsRequest::RemoveWatch { request_id, .. } => *request_id,
+            VfsRequest::Lock { request_id, .. } => *request_id,
+            VfsRequest::GetLock { request_id, .. } => *request_id,
+            VfsRequest::ReleaseLocks { request_id, .. } => *request_id,
         }
     }
 }
@@ -342,6 +471,96 @@ pub enum VfsError {
     NameTooLong = 18,
     /// Cross-device link (cannot rename across mount points)
     CrossDevice = 19,
+    /// No such extended attribute
+    NoAttribute = 20,
+    /// Conflicting lock held (or mandatory lock refuses the I/O)
+    WouldBlock = 21,
+    /// Waiting for the lock would deadlock
+    Deadlock = 22,
+}
+
+/// Extended attribute holding a file's capability label. Only the kernel
+/// VFS changes it; [`VfsRequest::Setxattr`] and
+/// [`VfsRequest::Removexattr`] on it are refused.
+pub const CAPS_XATTR: &str = "security.splax.caps";
+
+/// Watch descriptor, unique within the server
+pub type WatchDescriptor = u32;
+
+/// Watch event bits, as in the kernel VFS
+pub mod watch_mask {
+    /// File written or truncated
+    pub const MODIFY: u32 = 0x0002;
+    /// Extended attributes changed
+    pub const ATTRIB: u32 = 0x0004;
+    /// Entry moved out of a watched directory
+    pub const MOVED_FROM: u32 = 0x0040;
+    /// Entry moved into a watched directory
+    pub const MOVED_TO: u32 = 0x0080;
+    /// Entry created in a watched directory
+    pub const CREATE: u32 = 0x0100;
+    /// Entry removed from a watched directory
+    pub const DELETE: u32 = 0x0200;
+    /// The watched file or directory itself was removed
+    pub const DELETE_SELF: u32 = 0x0400;
+    /// The filesystem holding the watch was unmounted
+    pub const UNMOUNT: u32 = 0x2000;
+    /// Events were dropped because the queue was full
+    pub const OVERFLOW: u32 = 0x4000;
+    /// The watch was removed
+    pub const IGNORED: u32 = 0x8000;
+    /// The entry of the event is a directory
+    pub const IS_DIR: u32 = 0x4000_0000;
+    /// Every event a watch can ask for
+    pub const ALL: u32 = MODIFY | ATTRIB | MOVED_FROM | MOVED_TO | CREATE | DELETE | DELETE_SELF | UNMOUNT;
+}
+
+/// Change to a watched file or directory, sent to the watcher's channel
+/// in [`MessageType::Event`] messages.
+///
+/// Encoded as `wd: u32, mask: u32, cookie: u32, name_len: u32, name`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchEvent {
+    /// Watch reporting the event
+    pub wd: WatchDescriptor,
+    /// [`watch_mask`] bits
+    pub mask: u32,
+    /// Shared by the two halves of a rename, else 0
+    pub cookie: u32,
+    /// Entry name, for events on a directory's entries
+    pub name: String,
+}
+
+impl WatchEvent {
+    /// Append the encoded event
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.wd.to_le_bytes());
+        out.extend_from_slice(&self.mask.to_le_bytes());
+        out.extend_from_slice(&self.cookie.to_le_bytes());
+        out.extend_from_slice(&(self.name.len() as u32).to_le_bytes());
+        out.extend_from_slice(self.name.as_bytes());
+    }
+
+    /// Decode all events of a message payload
+    pub fn decode_all(mut data: &[u8]) -> Result<Vec<WatchEvent>, VfsError> {
+        let field = |data: &[u8], at: usize| u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]]);
+        let mut events = Vec::new();
+        while !data.is_empty() {
+            if data.len() < 16 {
+                return Err(VfsError::InvalidArgument);
+            }
+            let len = field(data, 12) as usize;
+            let name = data.get(16..16 + len).ok_or(VfsError::InvalidArgument)?;
+            events.push(WatchEvent {
+                wd: field(data, 0),
+                mask: field(data, 4),
+                cookie: field(data, 8),
+                name: String::from_utf8_lossy(name).into(),
+            });
+            data = &data[16 + len..];
+        }
+        Ok(events)
+    }
 }
 
 /// Filesystem statistics
@@ -361,6 +580,8 @@ pub struct StatFs {
     pub block_size: u32,
     /// Maximum name length
     pub name_max: u32,
+    /// Blocks saved by compression
+    pub saved_blocks: u64,
     /// Filesystem type
     pub fs_type: String,
 }
@@ -426,6 +647,24 @@ pub enum VfsResponse {
         request_id: RequestId,
         stats: StatFs,
     },
+
+    /// Extended attribute names
+    XattrNames {
+        request_id: RequestId,
+        names: Vec<String>,
+    },
+
+    /// Watch added
+    Watch {
+        request_id: RequestId,
+        wd: WatchDescriptor,
+    },
+
+    /// Conflicting lock, if any
+    Lock {
+        request_id: RequestId,
+        holder: Option<LockInfo>,
+    },
 }
 
 impl VfsResponse {
@@ -441,6 +680,9 @@ impl VfsResponse {
             VfsResponse::Link { request_id, .. } => *request_id,
             VfsResponse::Position { request_id, .. } => *request_id,
             VfsResponse::FsStat { request_id, .. } => *request_id,
+            VfsResponse::XattrNames { request_id, .. } => *request_id,
+            VfsResponse::Watch { request_id, .. } => *request_id,
+            VfsResponse::Lock { request_id, .. } => *request_id,
         }
     }
 
@@ -486,6 +728,15 @@ pub enum MessageType {
     ReqSync = 17,
     ReqSeek = 18,
     ReqStatfs = 19,
+    ReqGetxattr = 20,
+    ReqSetxattr = 21,
+    ReqListxattr = 22,
+    ReqRemovexattr = 23,
+    ReqAddWatch = 24,
+    ReqRemoveWatch = 25,
+    ReqLock = 26,
+    ReqGetLock = 27,
+    ReqReleaseLocks = 28,
 
     // Responses
     RespOk = 100,
@@ -498,6 +749,12 @@ pub enum MessageType {
     RespLink = 107,
     RespPosition = 108,
     RespFsStat = 109,
+    RespXattrNames = 110,
+    RespWatch = 111,
+    RespLock = 112,
+
+    // Notifications (storage service -> watcher)
+    Event = 200,
 }
 
 #[cfg(test)]
@@ -525,5 +782,46 @@ mod tests {
             mode: 0o644,
         };
         assert_eq!(req.request_id(), 42);
+
+        let req = VfsRequest::Setxattr {
+            request_id: 7,
+            path: String::from("/test"),
+            name: String::from("user.mime"),
+            value: b"text/plain".to_vec(),
+        };
+        assert_eq!(req.request_id(), 7);
+
+        let req = VfsRequest::AddWatch {
+            request_id: 9,
+            path: String::from("/etc"),
+            mask: watch_mask::CREATE | watch_mask::MODIFY,
+            channel: 3,
+        };
+        assert_eq!(req.request_id(), 9);
+
+        let req = VfsRequest::Lock {
+            request_id: 11,
+            handle: FileHandle(4),
+            pid: 0x500,
+            lock: LockRequest::whole_file(LockType::Exclusive),
+            flock: true,
+            wait: true,
+        };
+        assert_eq!(req.request_id(), 11);
+        assert_eq!(VfsResponse::Lock { request_id: 11, holder: None }.request_id(), 11);
+    }
+
+    #[test]
+    fn test_watch_event_encoding() {
+        let events = [
+            WatchEvent { wd: 1, mask: watch_mask::MOVED_TO, cookie: 5, name: String::from("init.conf") },
+            WatchEvent { wd: 2, mask: watch_mask::OVERFLOW, cookie: 0, name: String::new() },
+        ];
+        let mut data = Vec::new();
+        for event in &events {
+            event.encode(&mut data);
+        }
+        assert_eq!(WatchEvent::decode_all(&data).unwrap(), events);
+        assert_eq!(WatchEvent::decode_all(&data[..10]), Err(VfsError::InvalidArgument));
     }
 }
@@ -39,7 +39,7 @@
 extern crate alloc;
 
 use alloc::boxed::Box;
-use alloc::collections::BTreeMap;
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
 use alloc::string::{String, ToString};
 use alloc::sync::Arc;
 use alloc::vec::Vec;
@@ -55,6 +55,12 @@ const MAX_OPEN_FILES: usize = 65536;
 /// Maximum number of mounts
 const MAX_MOUNTS: usize = 256;
 
+/// Events queued per watcher channel before they are dropped
+const MAX_QUEUED_EVENTS: usize = 256;
+
+/// Maximum number of watches
+const MAX_WATCHES: usize = 8192;
+
 /// Filesystem trait - implemented by each filesystem driver
 pub trait Filesystem: Send + Sync {
     /// Get filesystem type name
@@ -143,6 +149,26 @@ pub trait Filesystem: Send + Sync {
         Ok(())
     }
 
+    /// Get an extended attribute
+    fn getxattr(&self, _ino: InodeNum, _name: &str) -> Result<Vec<u8>, VfsError> {
+        Err(VfsError::NotSupported)
+    }
+
+    /// Set an extended attribute
+    fn setxattr(&self, _ino: InodeNum, _name: &str, _value: &[u8]) -> Result<(), VfsError> {
+        Err(VfsError::NotSupported)
+    }
+
+    /// List extended attribute names
+    fn listxattr(&self, _ino: InodeNum) -> Result<Vec<String>, VfsError> {
+        Err(VfsError::NotSupported)
+    }
+
+    /// Remove an extended attribute
+    fn removexattr(&self, _ino: InodeNum, _name: &str) -> Result<(), VfsError> {
+        Err(VfsError::NotSupported)
+    }
+
     /// Get filesystem statistics
     fn statfs(&self) -> Result<StatFs, VfsError>;
 
@@ -152,6 +178,8 @@ pub trait Filesystem: Send + Sync {
 
 /// Mount point information
 struct MountPoint {
+    /// Mount ID, stable while mounted (watches refer to it)
+    id: u64,
     /// Path where mounted
     path: String,
     /// Filesystem instance
@@ -174,6 +202,280 @@ struct OpenFile {
     flags: OpenFlags,
     /// File size (cached)
     size: u64,
+    /// Directory and name the file was opened through (for watches)
+    parent: Option<(InodeNum, String)>,
+    /// Process that last locked through this handle; its I/O is checked
+    /// against mandatory locks as that process's
+    pid: Option<u64>,
+}
+
+/// A watch added with [`VfsRequest::AddWatch`]
+struct Watch {
+    mount_id: u64,
+    ino: InodeNum,
+    mask: u32,
+    /// Channel of the watcher
+    channel: u64,
+}
+
+/// Events waiting to be sent to a watcher channel
+#[derive(Default)]
+struct EventQueue {
+    events: VecDeque<WatchEvent>,
+    /// An overflow event is queued
+    overflowed: bool,
+}
+
+/// Watches and the event queues of their channels
+#[derive(Default)]
+struct WatchTable {
+    watches: BTreeMap<WatchDescriptor, Watch>,
+    queues: BTreeMap<u64, EventQueue>,
+    next_wd: WatchDescriptor,
+    next_cookie: u32,
+}
+
+impl WatchTable {
+    /// Queue an event for a channel; past [`MAX_QUEUED_EVENTS`] events are
+    /// dropped and one overflow event is queued instead.
+    fn push(&mut self, channel: u64, event: WatchEvent) {
+        let queue = self.queues.entry(channel).or_default();
+        if queue.events.back() == Some(&event) {
+            return;
+        }
+        if queue.events.len() < MAX_QUEUED_EVENTS {
+            queue.events.push_back(event);
+        } else if !queue.overflowed {
+            queue.overflowed = true;
+            queue.events.push_back(WatchEvent {
+                wd: WatchDescriptor::MAX,
+                mask: watch_mask::OVERFLOW,
+                cookie: 0,
+                name: String::new(),
+            });
+        }
+    }
+
+    /// Report an event on an inode, and on entry `name` of directory
+    /// `parent`
+    fn notify(&mut self, mount_id: u64, ino: Option<InodeNum>, parent: Option<(InodeNum, &str)>, mask: u32, cookie: u32) {
+        if self.watches.is_empty() {
+            return;
+        }
+        let event = mask & !watch_mask::IS_DIR;
+        let mut targets = Vec::new();
+        for (&wd, watch) in self.watches.iter() {
+            if watch.mount_id != mount_id || watch.mask & event == 0 {
+                continue;
+            }
+            if Some(watch.ino) == ino {
+                targets.push((watch.channel, wd, ""));
+            }
+            if let Some((dir, name)) = parent {
+                if watch.ino == dir {
+                    targets.push((watch.channel, wd, name));
+                }
+            }
+        }
+        for (channel, wd, name) in targets {
+            self.push(channel, WatchEvent { wd, mask, cookie, name: name.to_string() });
+        }
+    }
+
+    /// Remove the watches matching `matches`, reporting `reason` (if
+    /// watched) and then `IGNORED`
+    fn remove_where(&mut self, reason: u32, matches: impl Fn(WatchDescriptor, &Watch) -> bool) {
+        let doomed: Vec<WatchDescriptor> =
+            self.watches.iter().filter(|(&wd, w)| matches(wd, w)).map(|(&wd, _)| wd).collect();
+        for wd in doomed {
+            let watch = self.watches.remove(&wd).unwrap();
+            if watch.mask & reason != 0 {
+                self.push(watch.channel, WatchEvent { wd, mask: reason, cookie: 0, name: String::new() });
+            }
+            self.push(watch.channel, WatchEvent { wd, mask: watch_mask::IGNORED, cookie: 0, name: String::new() });
+        }
+    }
+}
+
+/// Who a lock belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LockOwner {
+    /// Byte-range lock of a process
+    Process(u64),
+    /// `flock` lock of an open file
+    Handle(FileHandle),
+}
+
+/// A held lock over `start..end` (`end` is `u64::MAX` up to the end of
+/// the file)
+#[derive(Debug, Clone, Copy)]
+struct Lock {
+    owner: LockOwner,
+    pid: u64,
+    exclusive: bool,
+    start: u64,
+    end: u64,
+    mandatory: bool,
+}
+
+impl Lock {
+    fn new(owner: LockOwner, pid: u64, request: &LockRequest) -> Result<Self, VfsError> {
+        let end = match request.len {
+            0 => u64::MAX,
+            len => request.start.checked_add(len).ok_or(VfsError::InvalidArgument)?,
+        };
+        Ok(Lock {
+            owner,
+            pid,
+            exclusive: request.lock_type == LockType::Exclusive,
+            start: request.start,
+            end,
+            mandatory: request.mandatory,
+        })
+    }
+
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start < end && start < self.end
+    }
+
+    /// Whether `self`, held, prevents `other` from being taken. `flock`
+    /// and byte-range locks are independent.
+    fn conflicts(&self, other: &Lock) -> bool {
+        let same_kind = matches!(
+            (self.owner, other.owner),
+            (LockOwner::Process(_), LockOwner::Process(_)) | (LockOwner::Handle(_), LockOwner::Handle(_))
+        );
+        self.owner != other.owner
+            && same_kind
+            && self.overlaps(other.start, other.end)
+            && (self.exclusive || other.exclusive)
+    }
+
+    fn info(&self) -> LockInfo {
+        LockInfo {
+            lock_type: if self.exclusive { LockType::Exclusive } else { LockType::Shared },
+            start: self.start,
+            len: if self.end == u64::MAX { 0 } else { self.end - self.start },
+            pid: self.pid,
+            mandatory: self.mandatory,
+            flock: matches!(self.owner, LockOwner::Handle(_)),
+        }
+    }
+}
+
+/// Inode a lock is on: mount ID and inode number
+type LockKey = (u64, InodeNum);
+
+/// Locks held, and the lock each waiting process asked for
+#[derive(Default)]
+struct LockTable {
+    locks: BTreeMap<LockKey, Vec<Lock>>,
+    waiters: BTreeMap<u64, (LockKey, Lock)>,
+}
+
+impl LockTable {
+    fn conflict(&self, key: LockKey, lock: &Lock) -> Option<&Lock> {
+        self.locks.get(&key)?.iter().find(|held| held.conflicts(lock))
+    }
+
+    /// Take, change or release a lock. A conflict fails with
+    /// [`VfsError::WouldBlock`]; with `wait`, the process is recorded as
+    /// waiting first, and the request fails with [`VfsError::Deadlock`]
+    /// instead if the holders wait, directly or not, for it.
+    fn lock(&mut self, key: LockKey, lock: Lock, unlock: bool, wait: bool) -> Result<(), VfsError> {
+        if unlock {
+            self.clear(key, lock.owner, lock.start, lock.end);
+            return Ok(());
+        }
+        if self.conflict(key, &lock).is_none() {
+            self.waiters.remove(&lock.pid);
+            self.clear(key, lock.owner, lock.start, lock.end);
+            self.locks.entry(key).or_default().push(lock);
+            return Ok(());
+        }
+        if !wait {
+            return Err(VfsError::WouldBlock);
+        }
+        self.waiters.insert(lock.pid, (key, lock));
+        if self.deadlocked(lock.pid) {
+            self.waiters.remove(&lock.pid);
+            return Err(VfsError::Deadlock);
+        }
+        Err(VfsError::WouldBlock)
+    }
+
+    /// Processes holding locks `pid` waits for
+    fn blockers(&self, pid: u64) -> Vec<u64> {
+        let Some((key, lock)) = self.waiters.get(&pid) else {
+            return Vec::new();
+        };
+        self.locks
+            .get(key)
+            .map(|held| held.iter().filter(|h| h.conflicts(lock)).map(|h| h.pid).collect())
+            .unwrap_or_default()
+    }
+
+    /// Whether `pid` waits, through a chain of waiting processes, for
+    /// itself
+    fn deadlocked(&self, pid: u64) -> bool {
+        let mut seen = BTreeSet::new();
+        let mut pending = self.blockers(pid);
+        while let Some(holder) = pending.pop() {
+            if holder == pid {
+                return true;
+            }
+            if seen.insert(holder) {
+                pending.extend(self.blockers(holder));
+            }
+        }
+        false
+    }
+
+    /// Remove what `owner` holds over `start..end`, keeping the parts
+    /// outside it
+    fn clear(&mut self, key: LockKey, owner: LockOwner, start: u64, end: u64) {
+        let Some(held) = self.locks.get_mut(&key) else {
+            return;
+        };
+        let mut kept = Vec::with_capacity(held.len());
+        for lock in held.drain(..) {
+            if lock.owner != owner || !lock.overlaps(start, end) {
+                kept.push(lock);
+                continue;
+            }
+            if lock.start < start {
+                kept.push(Lock { end: start, ..lock });
+            }
+            if lock.end > end {
+                kept.push(Lock { start: end, ..lock });
+            }
+        }
+        *held = kept;
+        if held.is_empty() {
+            self.locks.remove(&key);
+        }
+    }
+
+    /// Remove the matching locks
+    fn release(&mut self, matches: impl Fn(LockKey, &Lock) -> bool) {
+        self.locks.retain(|&key, held| {
+            held.retain(|lock| !matches(key, lock));
+            !held.is_empty()
+        });
+    }
+
+    /// Check I/O of `len` bytes at `offset` against mandatory locks of
+    /// processes other than `pid` (`None` if unknown)
+    fn check_io(&self, key: LockKey, pid: Option<u64>, offset: u64, len: usize, write: bool) -> Result<(), VfsError> {
+        let Some(held) = self.locks.get(&key) else {
+            return Ok(());
+        };
+        let end = offset.saturating_add(len as u64);
+        let blocked = held.iter().any(|lock| {
+            lock.mandatory && Some(lock.pid) != pid && lock.overlaps(offset, end) && (write || lock.exclusive)
+        });
+        if blocked { Err(VfsError::WouldBlock) } else { Ok(()) }
+    }
 }
 
 /// VFS Server state
@@ -189,6 +491,15 @@ pub struct VfsServer {
 
     /// Request counter for generating IDs
     request_counter: AtomicU64,
+
+    /// Next mount ID
+    next_mount_id: AtomicU64,
+
+    /// Watches and undelivered events
+    watches: Mutex<WatchTable>,
+
+    /// File locks
+    locks: Mutex<LockTable>,
 }
 
 impl VfsServer {
@@ -199,6 +510,9 @@ impl VfsServer {
             open_files: Mutex::new(BTreeMap::new()),
             next_handle: AtomicU64::new(1),
             request_counter: AtomicU64::new(1),
+            next_mount_id: AtomicU64::new(1),
+            watches: Mutex::new(WatchTable::default()),
+            locks: Mutex::new(LockTable::default()),
         }
     }
 
@@ -226,6 +540,7 @@ impl VfsServer {
         let path = Self::normalize_path(path);
 
         mounts.push(MountPoint {
+            id: self.next_mount_id.fetch_add(1, Ordering::Relaxed),
             path,
             fs,
             read_only,
@@ -256,7 +571,9 @@ impl VfsServer {
         }
         drop(open_files);
 
-        mounts.remove(idx);
+        let mount = mounts.remove(idx);
+        self.watches.lock().remove_where(watch_mask::UNMOUNT, |_, w| w.mount_id == mount.id);
+        self.locks.lock().release(|(mount_id, _), _| mount_id == mount.id);
         Ok(())
     }
 
@@ -311,6 +628,7 @@ impl VfsServer {
 
         let fs = &mount.fs;
         let ino;
+        let mut parent = None;
 
         // Resolve path
         let parent_path = Self::parent_path(&relative);
@@ -340,9 +658,11 @@ impl VfsServer {
                 }
                 Err(VfsError::NotFound) if flags.create => {
                     ino = fs.create(parent_ino, name, mode)?;
+                    self.watches.lock().notify(mount.id, None, Some((parent_ino, name)), watch_mask::CREATE, 0);
                 }
                 Err(e) => return Err(e),
             }
+            parent = Some((parent_ino, name.to_string()));
         }
 
         // Get file info
@@ -354,10 +674,13 @@ impl VfsServer {
         }
 
         // Truncate if requested
+        let mount_id = mount.id;
         if flags.truncate {
             drop(mounts);
             let mounts = self.mounts.read();
             mounts[mount_idx].fs.truncate(ino, 0)?;
+            let entry = parent.as_ref().map(|(dir, name)| (*dir, name.as_str()));
+            self.watches.lock().notify(mount_id, Some(ino), entry, watch_mask::MODIFY, 0);
         }
 
         // Check open file limit
@@ -377,6 +700,8 @@ impl VfsServer {
                 position: if flags.append { attr.size } else { 0 },
                 flags,
                 size: attr.size,
+                parent,
+                pid: None,
             },
         );
 
@@ -384,11 +709,19 @@ impl VfsServer {
     }
 
     /// Close a file
+    /// Close a file, releasing its `flock` lock and the byte-range locks
+    /// of the process that locked through it
     pub fn close(&self, handle: FileHandle) -> Result<(), VfsError> {
         let mut open_files = self.open_files.lock();
-        open_files
+        let file = open_files
             .remove(&handle)
             .ok_or(VfsError::BadHandle)?;
+        drop(open_files);
+
+        let key = (self.mounts.read()[file.mount_idx].id, file.ino);
+        self.locks.lock().release(|k, lock| {
+            k == key && (lock.owner == LockOwner::Handle(handle) || Some(lock.pid) == file.pid)
+        });
         Ok(())
     }
 
@@ -408,7 +741,9 @@ impl VfsServer {
 
         let read_offset = offset.unwrap_or(file.position);
         let mounts = self.mounts.read();
-        let fs = &mounts[file.mount_idx].fs;
+        let mount = &mounts[file.mount_idx];
+        self.locks.lock().check_io((mount.id, file.ino), file.pid, read_offset, len, false)?;
+        let fs = &mount.fs;
 
         let data = fs.read(file.ino, read_offset, len)?;
 
@@ -441,8 +776,11 @@ impl VfsServer {
         if mount.read_only {
             return Err(VfsError::ReadOnlyFs);
         }
+        self.locks.lock().check_io((mount.id, file.ino), file.pid, write_offset, data.len(), true)?;
 
         let written = mount.fs.write(file.ino, write_offset, data)?;
+        let entry = file.parent.as_ref().map(|(dir, name)| (*dir, name.as_str()));
+        self.watches.lock().notify(mount.id, Some(file.ino), entry, watch_mask::MODIFY, 0);
 
         // Update position and size
         if offset.is_none() {
@@ -508,6 +846,8 @@ impl VfsServer {
         };
 
         mount.fs.mkdir(parent_ino, name, mode)?;
+        let mask = watch_mask::CREATE | watch_mask::IS_DIR;
+        self.watches.lock().notify(mount.id, None, Some((parent_ino, name)), mask, 0);
         Ok(())
     }
 
@@ -534,7 +874,13 @@ impl VfsServer {
             curr
         };
 
-        mount.fs.rmdir(parent_ino, name)
+        let ino = mount.fs.lookup(parent_ino, name)?;
+        mount.fs.rmdir(parent_ino, name)?;
+        let mut watches = self.watches.lock();
+        let mask = watch_mask::DELETE | watch_mask::IS_DIR;
+        watches.notify(mount.id, None, Some((parent_ino, name)), mask, 0);
+        watches.remove_where(watch_mask::DELETE_SELF, |_, w| w.mount_id == mount.id && w.ino == ino);
+        Ok(())
     }
 
     /// Unlink file
@@ -560,7 +906,15 @@ impl VfsServer {
             curr
         };
 
-        mount.fs.unlink(parent_ino, name)
+        let ino = mount.fs.lookup(parent_ino, name)?;
+        let last_link = mount.fs.getattr(ino).map_or(true, |attr| attr.nlink <= 1);
+        mount.fs.unlink(parent_ino, name)?;
+        let mut watches = self.watches.lock();
+        watches.notify(mount.id, None, Some((parent_ino, name)), watch_mask::DELETE, 0);
+        if last_link {
+            watches.remove_where(watch_mask::DELETE_SELF, |_, w| w.mount_id == mount.id && w.ino == ino);
+        }
+        Ok(())
     }
 
     /// Rename a file or directory
@@ -609,7 +963,18 @@ impl VfsServer {
             curr
         };
 
-        fs.rename(old_parent_ino, old_name, new_parent_ino, new_name)
+        let is_dir = fs.lookup(old_parent_ino, old_name)
+            .and_then(|ino| fs.getattr(ino))
+            .is_ok_and(|attr| attr.file_type == VfsFileType::Directory);
+        fs.rename(old_parent_ino, old_name, new_parent_ino, new_name)?;
+
+        let dir = if is_dir { watch_mask::IS_DIR } else { 0 };
+        let mut watches = self.watches.lock();
+        watches.next_cookie = watches.next_cookie.wrapping_add(1).max(1);
+        let cookie = watches.next_cookie;
+        watches.notify(mount.id, None, Some((old_parent_ino, old_name)), watch_mask::MOVED_FROM | dir, cookie);
+        watches.notify(mount.id, None, Some((new_parent_ino, new_name)), watch_mask::MOVED_TO | dir, cookie);
+        Ok(())
     }
 
     /// Create a symbolic link
@@ -636,6 +1001,7 @@ impl VfsServer {
         };
 
         mount.fs.symlink(parent_ino, name, target)?;
+        self.watches.lock().notify(mount.id, None, Some((parent_ino, name)), watch_mask::CREATE, 0);
         Ok(())
     }
 
@@ -670,7 +1036,9 @@ impl VfsServer {
             return Err(VfsError::IsADirectory);
         }
 
-        mount.fs.truncate(ino, length)
+        mount.fs.truncate(ino, length)?;
+        self.notify_path(mount.id, ino, path, watch_mask::MODIFY);
+        Ok(())
     }
 
     /// Sync a file handle to disk
@@ -723,6 +1091,179 @@ impl VfsServer {
         mounts[mount_idx].fs.statfs()
     }
 
+    /// Get an extended attribute
+    pub fn getxattr(&self, path: &str, name: &str) -> Result<Vec<u8>, VfsError> {
+        let (mount_idx, ino) = self.resolve_path(path)?;
+        let mounts = self.mounts.read();
+        mounts[mount_idx].fs.getxattr(ino, name)
+    }
+
+    /// Set an extended attribute
+    pub fn setxattr(&self, path: &str, name: &str, value: &[u8]) -> Result<(), VfsError> {
+        let (mount_idx, ino) = self.resolve_writable(path, name)?;
+        let mounts = self.mounts.read();
+        mounts[mount_idx].fs.setxattr(ino, name, value)?;
+        self.notify_path(mounts[mount_idx].id, ino, path, watch_mask::ATTRIB);
+        Ok(())
+    }
+
+    /// List extended attribute names
+    pub fn listxattr(&self, path: &str) -> Result<Vec<String>, VfsError> {
+        let (mount_idx, ino) = self.resolve_path(path)?;
+        let mounts = self.mounts.read();
+        mounts[mount_idx].fs.listxattr(ino)
+    }
+
+    /// Remove an extended attribute
+    pub fn removexattr(&self, path: &str, name: &str) -> Result<(), VfsError> {
+        let (mount_idx, ino) = self.resolve_writable(path, name)?;
+        let mounts = self.mounts.read();
+        mounts[mount_idx].fs.removexattr(ino, name)?;
+        self.notify_path(mounts[mount_idx].id, ino, path, watch_mask::ATTRIB);
+        Ok(())
+    }
+
+    /// Resolves a path whose attribute `name` is about to change. The
+    /// capability label is reserved to the kernel VFS.
+    fn resolve_writable(&self, path: &str, name: &str) -> Result<(usize, InodeNum), VfsError> {
+        if name == CAPS_XATTR {
+            return Err(VfsError::PermissionDenied);
+        }
+        let (mount_idx, ino) = self.resolve_path(path)?;
+        if self.mounts.read()[mount_idx].read_only {
+            return Err(VfsError::ReadOnlyFs);
+        }
+        Ok((mount_idx, ino))
+    }
+
+    /// Watch a file or directory for the events in `mask`; they are queued
+    /// for `channel`. Watching it again for the same channel replaces the
+    /// mask. Capability labels are checked by the kernel before it
+    /// forwards the request.
+    pub fn add_watch(&self, path: &str, mask: u32, channel: u64) -> Result<WatchDescriptor, VfsError> {
+        let mask = mask & watch_mask::ALL;
+        if mask == 0 {
+            return Err(VfsError::InvalidArgument);
+        }
+        let (mount_idx, ino) = self.resolve_path(path)?;
+        let mount_id = self.mounts.read()[mount_idx].id;
+
+        let mut watches = self.watches.lock();
+        if let Some((&wd, watch)) = watches
+            .watches
+            .iter_mut()
+            .find(|(_, w)| w.mount_id == mount_id && w.ino == ino && w.channel == channel)
+        {
+            watch.mask = mask;
+            return Ok(wd);
+        }
+        if watches.watches.len() >= MAX_WATCHES {
+            return Err(VfsError::NoSpace);
+        }
+        watches.next_wd += 1;
+        let wd = watches.next_wd;
+        watches.watches.insert(wd, Watch { mount_id, ino, mask, channel });
+        Ok(wd)
+    }
+
+    /// Remove a watch; its channel receives an `IGNORED` event
+    pub fn remove_watch(&self, wd: WatchDescriptor) -> Result<(), VfsError> {
+        let mut watches = self.watches.lock();
+        if !watches.watches.contains_key(&wd) {
+            return Err(VfsError::InvalidArgument);
+        }
+        watches.remove_where(0, |other, _| other == wd);
+        Ok(())
+    }
+
+    /// Channels with events waiting to be sent
+    pub fn pending_events(&self) -> Vec<u64> {
+        let watches = self.watches.lock();
+        watches.queues.iter().filter(|(_, q)| !q.events.is_empty()).map(|(&c, _)| c).collect()
+    }
+
+    /// Take the events waiting for a channel, for the service loop to send
+    /// in [`MessageType::Event`] messages
+    pub fn take_events(&self, channel: u64) -> Vec<WatchEvent> {
+        let mut watches = self.watches.lock();
+        match watches.queues.remove(&channel) {
+            Some(queue) => queue.events.into(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Take, change or release a lock for process `pid` through `handle`:
+    /// the handle's whole-file lock with `flock`, else the process's lock
+    /// on the range. Shared locks need the handle open for reading,
+    /// exclusive ones for writing.
+    pub fn lock(
+        &self,
+        handle: FileHandle,
+        pid: u64,
+        request: &LockRequest,
+        flock: bool,
+        wait: bool,
+    ) -> Result<(), VfsError> {
+        let (key, request) = self.lock_target(handle, pid, request, flock)?;
+        let owner = if flock { LockOwner::Handle(handle) } else { LockOwner::Process(pid) };
+        let lock = Lock::new(owner, pid, &request)?;
+        self.locks.lock().lock(key, lock, request.lock_type == LockType::Unlock, wait)
+    }
+
+    /// First byte-range lock that would prevent `request` by `pid`
+    pub fn get_lock(&self, handle: FileHandle, pid: u64, request: &LockRequest) -> Result<Option<LockInfo>, VfsError> {
+        let (key, request) = self.lock_target(handle, pid, request, false)?;
+        let lock = Lock::new(LockOwner::Process(pid), pid, &request)?;
+        Ok(self.locks.lock().conflict(key, &lock).map(Lock::info))
+    }
+
+    /// Release every lock of an exited process, and stop it waiting
+    pub fn release_locks(&self, pid: u64) {
+        let mut locks = self.locks.lock();
+        locks.waiters.remove(&pid);
+        locks.release(|_, lock| lock.pid == pid);
+    }
+
+    /// Inode `handle` refers to, and the request to apply there; records
+    /// `pid` as the handle's process
+    fn lock_target(
+        &self,
+        handle: FileHandle,
+        pid: u64,
+        request: &LockRequest,
+        flock: bool,
+    ) -> Result<(LockKey, LockRequest), VfsError> {
+        let mut open_files = self.open_files.lock();
+        let file = open_files.get_mut(&handle).ok_or(VfsError::BadHandle)?;
+        let allowed = match request.lock_type {
+            LockType::Shared => file.flags.read,
+            LockType::Exclusive => file.flags.write,
+            LockType::Unlock => true,
+        };
+        if !allowed {
+            return Err(VfsError::BadHandle);
+        }
+        file.pid = Some(pid);
+        let key = (self.mounts.read()[file.mount_idx].id, file.ino);
+        let request = if flock { LockRequest::whole_file(request.lock_type) } else { *request };
+        Ok((key, request))
+    }
+
+    /// Report an event on the inode `path` resolved to, and on its entry
+    /// in its directory
+    fn notify_path(&self, mount_id: u64, ino: InodeNum, path: &str, mask: u32) {
+        if self.watches.lock().watches.is_empty() {
+            return;
+        }
+        let name = Self::basename(path);
+        let dir = self.resolve_path(&Self::parent_path(&Self::normalize_path(path))).ok();
+        let entry = match dir {
+            Some((idx, dir)) if !name.is_empty() && self.mounts.read()[idx].id == mount_id => Some((dir, name)),
+            _ => None,
+        };
+        self.watches.lock().notify(mount_id, Some(ino), entry, mask, 0);
+    }
+
     /// Handle incoming VFS request
     pub fn handle_request(&self, request: VfsRequest) -> VfsResponse {
         match request {
@@ -887,6 +1428,81 @@ impl VfsServer {
                 },
                 Err(e) => VfsResponse::error(request_id, e),
             },
+
+            VfsRequest::Getxattr {
+                request_id,
+                path,
+                name,
+            } => match self.getxattr(&path, &name) {
+                Ok(data) => VfsResponse::Data { request_id, data },
+                Err(e) => VfsResponse::error(request_id, e),
+            },
+
+            VfsRequest::Setxattr {
+                request_id,
+                path,
+                name,
+                value,
+            } => match self.setxattr(&path, &name, &value) {
+                Ok(()) => VfsResponse::ok(request_id),
+                Err(e) => VfsResponse::error(request_id, e),
+            },
+
+            VfsRequest::Listxattr { request_id, path } => match self.listxattr(&path) {
+                Ok(names) => VfsResponse::XattrNames { request_id, names },
+                Err(e) => VfsResponse::error(request_id, e),
+            },
+
+            VfsRequest::Removexattr {
+                request_id,
+                path,
+                name,
+            } => match self.removexattr(&path, &name) {
+                Ok(()) => VfsResponse::ok(request_id),
+                Err(e) => VfsResponse::error(request_id, e),
+            },
+
+            VfsRequest::AddWatch {
+                request_id,
+                path,
+                mask,
+                channel,
+            } => match self.add_watch(&path, mask, channel) {
+                Ok(wd) => VfsResponse::Watch { request_id, wd },
+                Err(e) => VfsResponse::error(request_id, e),
+            },
+
+            VfsRequest::RemoveWatch { request_id, wd } => match self.remove_watch(wd) {
+                Ok(()) => VfsResponse::ok(request_id),
+                Err(e) => VfsResponse::error(request_id, e),
+            },
+
+            VfsRequest::Lock {
+                request_id,
+                handle,
+                pid,
+                lock,
+                flock,
+                wait,
+            } => match self.lock(handle, pid, &lock, flock, wait) {
+                Ok(()) => VfsResponse::ok(request_id),
+                Err(e) => VfsResponse::error(request_id, e),
+            },
+
+            VfsRequest::GetLock {
+                request_id,
+                handle,
+                pid,
+                lock,
+            } => match self.get_lock(handle, pid, &lock) {
+                Ok(holder) => VfsResponse::Lock { request_id, holder },
+                Err(e) => VfsResponse::error(request_id, e),
+            },
+
+            VfsRequest::ReleaseLocks { request_id, pid } => {
+                self.release_locks(pid);
+                VfsResponse::ok(request_id)
+            }
         }
     }
 
@@ -966,4 +1582,90 @@ mod tests {
         assert_eq!(VfsServer::basename("/foo"), "foo");
         assert_eq!(VfsServer::basename("/"), "");
     }
+
+    #[test]
+    fn test_watch_events() {
+        let mut table = WatchTable::default();
+        table.watches.insert(1, Watch { mount_id: 1, ino: 2, mask: watch_mask::CREATE | watch_mask::DELETE_SELF, channel: 7 });
+        table.watches.insert(2, Watch { mount_id: 1, ino: 3, mask: watch_mask::ALL, channel: 8 });
+
+        table.notify(1, None, Some((2, "a")), watch_mask::CREATE | watch_mask::IS_DIR, 0);
+        // Filtered by mask, and by mount
+        table.notify(1, None, Some((2, "a")), watch_mask::DELETE, 0);
+        table.notify(2, Some(3), None, watch_mask::MODIFY, 0);
+        table.notify(1, Some(3), Some((2, "b")), watch_mask::MODIFY, 0);
+        table.remove_where(watch_mask::DELETE_SELF, |_, w| w.ino == 2);
+
+        let event = |wd, mask, name: &str| WatchEvent { wd, mask, cookie: 0, name: String::from(name) };
+        let server = VfsServer::new();
+        *server.watches.lock() = table;
+        assert_eq!(server.pending_events(), [7, 8]);
+        assert_eq!(server.take_events(7), [
+            event(1, watch_mask::CREATE | watch_mask::IS_DIR, "a"),
+            event(1, watch_mask::DELETE_SELF, ""),
+            event(1, watch_mask::IGNORED, ""),
+        ]);
+        assert_eq!(server.take_events(8), [event(2, watch_mask::MODIFY, "")]);
+        assert!(server.pending_events().is_empty());
+
+        assert_eq!(server.remove_watch(1), Err(VfsError::InvalidArgument));
+        server.remove_watch(2).unwrap();
+        assert_eq!(server.take_events(8), [event(2, watch_mask::IGNORED, "")]);
+        assert_eq!(server.add_watch("/", watch_mask::ALL, 8), Err(VfsError::NotFound));
+        assert_eq!(server.add_watch("/", 0, 8), Err(VfsError::InvalidArgument));
+    }
+
+    #[test]
+    fn test_watch_overflow() {
+        let mut table = WatchTable::default();
+        table.watches.insert(1, Watch { mount_id: 1, ino: 2, mask: watch_mask::CREATE, channel: 7 });
+        for i in 0..MAX_QUEUED_EVENTS * 2 {
+            table.notify(1, None, Some((2, &i.to_string())), watch_mask::CREATE, 0);
+        }
+        let queue = &table.queues[&7];
+        assert_eq!(queue.events.len(), MAX_QUEUED_EVENTS + 1);
+        assert_eq!(queue.events.back().unwrap().mask, watch_mask::OVERFLOW);
+        assert_eq!(queue.events[MAX_QUEUED_EVENTS - 1].name, (MAX_QUEUED_EVENTS - 1).to_string());
+    }
+
+    #[test]
+    fn test_locks() {
+        let mut table = LockTable::default();
+        let key = (1, 2);
+        let range = |pid, lock_type, start, len| {
+            let request = LockRequest { lock_type, start, len, mandatory: false };
+            Lock::new(LockOwner::Process(pid), pid, &request).unwrap()
+        };
+        table.lock(key, range(10, LockType::Shared, 0, 100), false, false).unwrap();
+        table.lock(key, range(11, LockType::Shared, 50, 100), false, false).unwrap();
+        assert_eq!(table.lock(key, range(11, LockType::Exclusive, 90, 20), false, false), Err(VfsError::WouldBlock));
+
+        // Unlocking the middle splits a lock; flock locks are independent
+        table.lock(key, range(10, LockType::Unlock, 20, 10), true, false).unwrap();
+        let flock = Lock::new(LockOwner::Handle(FileHandle(3)), 12, &LockRequest::whole_file(LockType::Exclusive)).unwrap();
+        table.lock(key, flock, false, false).unwrap();
+        let mut held: Vec<(u64, u64, u64)> = table.locks[&key].iter().map(|l| (l.pid, l.start, l.end)).collect();
+        held.sort();
+        assert_eq!(held, [(10, 0, 20), (10, 30, 100), (11, 50, 150), (12, 0, u64::MAX)]);
+
+        // Waiting is recorded, and a cycle of waiters is refused
+        assert_eq!(table.lock(key, range(11, LockType::Exclusive, 0, 10), false, true), Err(VfsError::WouldBlock));
+        assert_eq!(table.lock(key, range(10, LockType::Exclusive, 140, 10), false, true), Err(VfsError::Deadlock));
+        table.waiters.remove(&11);
+        table.release(|_, lock| lock.pid == 11);
+        table.lock(key, range(10, LockType::Exclusive, 140, 10), false, true).unwrap();
+        assert!(table.waiters.is_empty());
+
+        // Mandatory locks refuse other processes' I/O
+        let mandatory = LockRequest { lock_type: LockType::Shared, start: 200, len: 0, mandatory: true };
+        table.lock(key, Lock::new(LockOwner::Process(10), 10, &mandatory).unwrap(), false, false).unwrap();
+        assert_eq!(table.check_io(key, None, 190, 20, true), Err(VfsError::WouldBlock));
+        assert_eq!(table.check_io(key, Some(11), 190, 20, false), Ok(()));
+        assert_eq!(table.check_io(key, Some(10), 190, 20, true), Ok(()));
+        assert!(Lock::new(LockOwner::Process(10), 10, &LockRequest { len: 2, ..mandatory }).is_ok());
+        assert_eq!(
+            Lock::new(LockOwner::Process(10), 10, &LockRequest { start: u64::MAX, len: 2, ..mandatory }).err(),
+            Some(VfsError::InvalidArgument)
+        );
+    }
 }
@@ -0,0 +1,56 @@
+//! Image files as SplaxFS devices
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use splaxfs_core::{BlockIo, SplaxFsError, BLOCK_SIZE};
+
+/// SplaxFS device backed by an image file
+pub struct Image {
+    file: File,
+    blocks: u32,
+}
+
+impl Image {
+    /// Opens an existing image, read-only unless `writable`
+    pub fn open(path: &Path, writable: bool) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(writable).open(path)?;
+        let blocks = (file.metadata()?.len() / BLOCK_SIZE as u64).min(u32::MAX as u64) as u32;
+        Ok(Self { file, blocks })
+    }
+
+    /// Creates an image of `size` bytes, replacing any file at `path`
+    pub fn create(path: &Path, size: u64) -> io::Result<Self> {
+        let file = File::create(path)?;
+        file.set_len(size)?;
+        drop(file);
+        Self::open(path, true)
+    }
+}
+
+impl BlockIo for Image {
+    fn total_blocks(&self) -> u32 {
+        self.blocks
+    }
+
+    fn read(&self, block_num: u32, buf: &mut [u8]) -> Result<(), SplaxFsError> {
+        self.file
+            .read_exact_at(buf, block_num as u64 * BLOCK_SIZE as u64)
+            .map_err(|_| SplaxFsError::IoError)
+    }
+
+    fn write(&self, block_num: u32, buf: &[u8]) -> Result<(), SplaxFsError> {
+        if block_num as u64 * BLOCK_SIZE as u64 + buf.len() as u64 > self.blocks as u64 * BLOCK_SIZE as u64 {
+            return Err(SplaxFsError::IoError);
+        }
+        self.file
+            .write_all_at(buf, block_num as u64 * BLOCK_SIZE as u64)
+            .map_err(|_| SplaxFsError::IoError)
+    }
+
+    fn flush(&self) -> Result<(), SplaxFsError> {
+        self.file.sync_data().map_err(|_| SplaxFsError::IoError)
+    }
+}
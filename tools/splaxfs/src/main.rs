@@ -0,0 +1,421 @@
+//! # splaxfs: SplaxFS Image Tool
+//!
+//! Works on SplaxFS image files from the build host, using the same
+//! `splaxfs_core` code as the kernel driver.
+//!
+//! ## Commands
+//!
+//! - `mkfs [-j] <image> <size>` - Create an empty image of `size` bytes
+//!   (`K`, `M` and `G` suffixes allowed); `-j` adds a journal
+//! - `build [-j] [-r] [-s <size>] <image> <dir>` - Create an image holding
+//!   the tree at `dir`, sized to fit unless `-s` is given; `-r` makes
+//!   everything owned by root
+//! - `ls [-l] [-R] <image> [path]` - List a directory; `-R` lists the whole
+//!   tree below it
+//! - `extract <image> <dir> [path]` - Copy the tree at `path` (default `/`)
+//!   into host directory `dir`
+//! - `fsck [-n|-y] <image>` - Check an image; `-y` repairs it, moving lost
+//!   files into `/lost+found`
+//!
+//! `fsck` exits like e2fsck: 0 when the image is clean, 1 when problems were
+//! repaired, 4 when problems remain and 8 on an operational error. The
+//! other commands exit with 0 or 8.
+
+mod image;
+mod tree;
+mod volume;
+
+use std::path::Path;
+use std::process::ExitCode;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use splaxfs_core::{CheckOptions, DiskInode, FileType, Report, SplaxFsError, BLOCK_SIZE, ROOT_INODE};
+
+use image::Image;
+use volume::Volume;
+
+/// Exit codes, as in e2fsck
+const EXIT_CLEAN: u8 = 0;
+const EXIT_FIXED: u8 = 1;
+const EXIT_UNCORRECTED: u8 = 4;
+const EXIT_ERROR: u8 = 8;
+
+const USAGE: &str = "Usage: splaxfs <command> [options]
+  mkfs [-j] <image> <size>                    Create an empty image (-j: with a journal)
+  build [-j] [-r] [-s <size>] <image> <dir>   Create an image from a directory (-r: owned by root)
+  ls [-l] [-R] <image> [path]                 List a directory (-l: long, -R: recursive)
+  extract <image> <dir> [path]                Copy a tree out of an image
+  fsck [-n|-y] <image>                        Check (-n, default) or repair (-y) an image";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let code = match args.first().map(String::as_str) {
+        Some("mkfs") => cmd_mkfs(&args[1..]),
+        Some("build") => cmd_build(&args[1..]),
+        Some("ls") => cmd_ls(&args[1..]),
+        Some("extract") => cmd_extract(&args[1..]),
+        Some("fsck") => cmd_fsck(&args[1..]),
+        Some("help" | "-h" | "--help") => {
+            println!("{}", USAGE);
+            EXIT_CLEAN
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            EXIT_ERROR
+        }
+    };
+    ExitCode::from(code)
+}
+
+/// Flags given, with their values
+type Flags<'a> = Vec<(char, Option<&'a str>)>;
+
+/// Splits arguments into the flags among `known` and the rest, taking the
+/// argument after each flag in `with_value` as its value
+fn parse_args<'a>(args: &'a [String], known: &str, with_value: &str) -> Option<(Flags<'a>, Vec<&'a str>)> {
+    let mut flags = Vec::new();
+    let mut rest = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let Some(flag) = arg.strip_prefix('-').filter(|flag| !flag.is_empty()) else {
+            rest.push(arg.as_str());
+            continue;
+        };
+        for c in flag.chars() {
+            if !known.contains(c) {
+                return None;
+            }
+            let value = if with_value.contains(c) { Some(args.next()?.as_str()) } else { None };
+            flags.push((c, value));
+        }
+    }
+    Some((flags, rest))
+}
+
+/// Parses a size in bytes, with an optional `K`, `M` or `G` suffix
+fn parse_size(size: &str) -> Option<u64> {
+    let (digits, shift) = match size.as_bytes().last()? {
+        b'K' | b'k' => (&size[..size.len() - 1], 10),
+        b'M' | b'm' => (&size[..size.len() - 1], 20),
+        b'G' | b'g' => (&size[..size.len() - 1], 30),
+        _ => (size, 0),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+fn cmd_mkfs(args: &[String]) -> u8 {
+    let Some((flags, rest)) = parse_args(args, "j", "") else {
+        eprintln!("{}", USAGE);
+        return EXIT_ERROR;
+    };
+    let journaled = flags.iter().any(|&(flag, _)| flag == 'j');
+    let &[path, size] = rest.as_slice() else {
+        eprintln!("{}", USAGE);
+        return EXIT_ERROR;
+    };
+    let Some(size) = parse_size(size) else {
+        eprintln!("{}", USAGE);
+        return EXIT_ERROR;
+    };
+    let result = Image::create(Path::new(path), size)
+        .map_err(|_| SplaxFsError::IoError)
+        .and_then(|image| splaxfs_core::format(&image, journaled, now()));
+    match result {
+        Ok(sb) => {
+            println!("{}: {} blocks, {} inodes", path, sb.total_blocks, sb.total_inodes);
+            EXIT_CLEAN
+        }
+        Err(e) => {
+            eprintln!("{}: {:?}", path, e);
+            EXIT_ERROR
+        }
+    }
+}
+
+fn cmd_build(args: &[String]) -> u8 {
+    let Some((flags, rest)) = parse_args(args, "jrs", "s") else {
+        eprintln!("{}", USAGE);
+        return EXIT_ERROR;
+    };
+    let &[path, dir] = rest.as_slice() else {
+        eprintln!("{}", USAGE);
+        return EXIT_ERROR;
+    };
+    let mut size = None;
+    for &(flag, value) in &flags {
+        if flag == 's' {
+            let Some(bytes) = value.and_then(parse_size) else {
+                eprintln!("{}", USAGE);
+                return EXIT_ERROR;
+            };
+            size = Some(bytes);
+        }
+    }
+    let journaled = flags.iter().any(|&(flag, _)| flag == 'j');
+    let all_root = flags.iter().any(|&(flag, _)| flag == 'r');
+
+    match build(Path::new(path), Path::new(dir), size, journaled, all_root) {
+        Ok((stats, sb)) => {
+            for skipped in &stats.skipped {
+                eprintln!("{}: skipped (not a file, directory or symlink)", skipped.display());
+            }
+            println!(
+                "{}: {} files, {} directories, {} symlinks, {}/{} blocks used",
+                path,
+                stats.files,
+                stats.directories,
+                stats.symlinks,
+                sb.total_blocks - sb.free_blocks,
+                sb.total_blocks
+            );
+            EXIT_CLEAN
+        }
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            EXIT_ERROR
+        }
+    }
+}
+
+/// Creates an image holding the tree at `dir`
+fn build(
+    path: &Path,
+    dir: &Path,
+    size: Option<u64>,
+    journaled: bool,
+    all_root: bool,
+) -> Result<(tree::Stats, splaxfs_core::Superblock), tree::Error> {
+    let size = match size {
+        Some(size) => size,
+        None => tree::estimate_blocks(dir)? as u64 * BLOCK_SIZE as u64,
+    };
+    let fs_err = |e| tree::Error::Fs(path.to_path_buf(), e);
+    let image = Image::create(path, size).map_err(|e| tree::Error::Io(path.to_path_buf(), e))?;
+    splaxfs_core::format(&image, journaled, now()).map_err(fs_err)?;
+    let mut volume = Volume::open(&image, true).map_err(fs_err)?;
+    let stats = tree::copy_in(&mut volume, ROOT_INODE, dir, all_root)?;
+    Ok((stats, *volume.superblock()))
+}
+
+fn cmd_ls(args: &[String]) -> u8 {
+    let Some((flags, rest)) = parse_args(args, "lR", "") else {
+        eprintln!("{}", USAGE);
+        return EXIT_ERROR;
+    };
+    let (path, dir) = match *rest.as_slice() {
+        [path] => (path, "/"),
+        [path, dir] => (path, dir),
+        _ => {
+            eprintln!("{}", USAGE);
+            return EXIT_ERROR;
+        }
+    };
+    let long = flags.iter().any(|&(flag, _)| flag == 'l');
+    let recursive = flags.iter().any(|&(flag, _)| flag == 'R');
+
+    let result = Image::open(Path::new(path), false).map_err(|_| SplaxFsError::IoError).and_then(|image| {
+        let volume = Volume::open(&image, false)?;
+        let ino = volume.lookup(dir)?;
+        let mut lines = Vec::new();
+        list(&volume, ino, dir.trim_end_matches('/'), long, recursive, &mut lines)?;
+        Ok(lines)
+    });
+    match result {
+        Ok(lines) => {
+            for line in lines {
+                println!("{}", line);
+            }
+            EXIT_CLEAN
+        }
+        Err(e) => {
+            eprintln!("{}: {}: {:?}", path, dir, e);
+            EXIT_ERROR
+        }
+    }
+}
+
+/// Lines listing directory `ino`, found at `prefix`; recursive listings
+/// show full paths
+fn list(
+    volume: &Volume<Image>,
+    ino: u32,
+    prefix: &str,
+    long: bool,
+    recursive: bool,
+    lines: &mut Vec<String>,
+) -> Result<(), SplaxFsError> {
+    for (name, child) in volume.entries(ino)? {
+        let inode = volume.inode(child)?;
+        let path = format!("{}/{}", prefix, name);
+        let shown = if recursive { path.as_str() } else { name.as_str() };
+        lines.push(if long { long_entry(volume, &inode, shown)? } else { String::from(shown) });
+        if recursive && inode.is_directory() {
+            list(volume, child, &path, long, recursive, lines)?;
+        }
+    }
+    Ok(())
+}
+
+/// `ls -l` style line: mode, links, owner, group, size and name
+fn long_entry(volume: &Volume<Image>, inode: &DiskInode, name: &str) -> Result<String, SplaxFsError> {
+    let mut mode = String::from(match inode.file_type() {
+        FileType::Directory => "d",
+        FileType::Symlink => "l",
+        _ => "-",
+    });
+    for (bit, c) in [(0o400, 'r'), (0o200, 'w'), (0o100, 'x'), (0o40, 'r'), (0o20, 'w'), (0o10, 'x'), (0o4, 'r'), (0o2, 'w'), (0o1, 'x')] {
+        mode.push(if inode.mode & bit != 0 { c } else { '-' });
+    }
+    let mut line = format!("{} {:>3} {:>5} {:>5} {:>10} {}", mode, inode.links_count, inode.uid, inode.gid, inode.size(), name);
+    if inode.file_type() == FileType::Symlink && !inode.is_encrypted() {
+        line.push_str(" -> ");
+        line.push_str(&volume.readlink(inode)?);
+    }
+    Ok(line)
+}
+
+fn cmd_extract(args: &[String]) -> u8 {
+    let (path, dest, dir) = match args {
+        [path, dest] => (path, dest, "/"),
+        [path, dest, dir] => (path, dest, dir.as_str()),
+        _ => {
+            eprintln!("{}", USAGE);
+            return EXIT_ERROR;
+        }
+    };
+    let image = match Image::open(Path::new(path), false) {
+        Ok(image) => image,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            return EXIT_ERROR;
+        }
+    };
+    let result = Volume::open(&image, false)
+        .and_then(|volume| volume.lookup(dir).map(|ino| (volume, ino)))
+        .map_err(|e| tree::Error::Fs(Path::new(dir).to_path_buf(), e))
+        .and_then(|(volume, ino)| tree::copy_out(&volume, ino, Path::new(dest)));
+    match result {
+        Ok(stats) => {
+            for skipped in &stats.skipped {
+                eprintln!("{}: skipped (encrypted)", skipped.display());
+            }
+            println!("{}: {} files, {} directories, {} symlinks", dest, stats.files, stats.directories, stats.symlinks);
+            EXIT_CLEAN
+        }
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            EXIT_ERROR
+        }
+    }
+}
+
+fn cmd_fsck(args: &[String]) -> u8 {
+    let mut repair = false;
+    let mut path = None;
+    for arg in args {
+        match arg.as_str() {
+            "-n" => repair = false,
+            "-y" => repair = true,
+            _ if path.is_none() && !arg.starts_with('-') => path = Some(arg),
+            _ => {
+                eprintln!("{}", USAGE);
+                return EXIT_ERROR;
+            }
+        }
+    }
+    let Some(path) = path else {
+        eprintln!("{}", USAGE);
+        return EXIT_ERROR;
+    };
+
+    match fsck(Path::new(path), repair) {
+        Ok(report) => {
+            if report.journal_replayed {
+                println!("{}: replayed journal", path);
+            }
+            for problem in &report.problems {
+                println!("  {}", problem);
+            }
+            println!(
+                "{}: {} files, {} directories, {} symlinks, {}/{} inodes, {}/{} blocks",
+                path,
+                report.files,
+                report.directories,
+                report.symlinks,
+                report.used_inodes,
+                report.total_inodes,
+                report.used_blocks,
+                report.total_blocks
+            );
+            if report.snapshots > 0 {
+                println!("{}: {} snapshots", path, report.snapshots);
+            }
+            if report.repaired {
+                println!("{}: repaired {} problems", path, report.problems.len());
+            } else if !report.is_clean() {
+                println!("{}: {} problems (run with -y to repair)", path, report.problems.len());
+            }
+            exit_code(&report)
+        }
+        Err(e) => {
+            eprintln!("{}: {:?}", path, e);
+            EXIT_ERROR
+        }
+    }
+}
+
+/// Checks, and with `repair` fixes, an image file
+fn fsck(path: &Path, repair: bool) -> Result<Report, SplaxFsError> {
+    let image = Image::open(path, repair).map_err(|_| SplaxFsError::IoError)?;
+    splaxfs_core::check(&image, &CheckOptions { repair, time: now() })
+}
+
+fn exit_code(report: &Report) -> u8 {
+    if report.is_clean() {
+        EXIT_CLEAN
+    } else if report.repaired {
+        EXIT_FIXED
+    } else {
+        EXIT_UNCORRECTED
+    }
+}
+
+/// Seconds since the epoch, as stored in inodes
+fn now() -> u32 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use splaxfs_core::{Problem, Superblock, BLOCK_SIZE};
+    use std::fs::File;
+
+    #[test]
+    fn test_fsck_image() {
+        let path = std::env::temp_dir().join(format!("splaxfs-fsck-{}.img", std::process::id()));
+        File::create(&path).unwrap().set_len(256 * BLOCK_SIZE as u64).unwrap();
+        splaxfs_core::format(&Image::open(&path, true).unwrap(), true, now()).unwrap();
+
+        let report = fsck(&path, false).unwrap();
+        assert!(report.is_clean());
+        assert_eq!(exit_code(&report), EXIT_CLEAN);
+
+        // Skew the free block count
+        let image = Image::open(&path, true).unwrap();
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        splaxfs_core::BlockIo::read(&image, 0, &mut buf).unwrap();
+        let mut sb = Superblock::from_bytes(&buf);
+        sb.free_blocks -= 1;
+        buf[..512].copy_from_slice(&sb.to_bytes());
+        splaxfs_core::BlockIo::write(&image, 0, &buf).unwrap();
+
+        let report = fsck(&path, false).unwrap();
+        assert!(matches!(report.problems[..], [Problem::FreeBlocks { .. }]));
+        assert_eq!(exit_code(&report), EXIT_UNCORRECTED);
+        assert_eq!(exit_code(&fsck(&path, true).unwrap()), EXIT_FIXED);
+        assert_eq!(exit_code(&fsck(&path, false).unwrap()), EXIT_CLEAN);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,295 @@
+//! Copying directory trees between the host and a volume
+//!
+//! Regular files, directories and symlinks are copied with their
+//! permission bits and modification times; hard links stay hard links.
+//! Going into a volume, owners are kept too. Device nodes, FIFOs and
+//! sockets have no SplaxFS equivalent and are skipped, and so are
+//! encrypted files coming out, whose content needs the key.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use splaxfs_core::layout::*;
+use splaxfs_core::BlockIo;
+
+use crate::volume::Volume;
+
+/// Error while copying, with the host path it happened on
+#[derive(Debug)]
+pub enum Error {
+    Io(PathBuf, io::Error),
+    Fs(PathBuf, SplaxFsError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            Error::Fs(path, e) => write!(f, "{}: {:?}", path.display(), e),
+        }
+    }
+}
+
+/// What a copy did
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub files: u32,
+    pub directories: u32,
+    pub symlinks: u32,
+    /// Files left out
+    pub skipped: Vec<PathBuf>,
+}
+
+/// Entries of a host directory, sorted so images come out the same
+fn read_dir_sorted(dir: &Path) -> Result<Vec<(String, PathBuf)>, Error> {
+    let io_err = |e| Error::Io(dir.to_path_buf(), e);
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir).map_err(io_err)? {
+        let entry = entry.map_err(io_err)?;
+        let name = entry.file_name().into_string().map_err(|_| {
+            Error::Fs(entry.path(), SplaxFsError::InvalidArg)
+        })?;
+        entries.push((name, entry.path()));
+    }
+    entries.sort();
+    Ok(entries)
+}
+
+/// Blocks a volume holding the tree at `dir` should have: its data,
+/// directory and pointer blocks, the metadata areas `format` lays out, an
+/// inode for each entry and some room to spare
+pub fn estimate_blocks(dir: &Path) -> Result<u32, Error> {
+    fn walk(dir: &Path, data: &mut u64, inodes: &mut u64) -> Result<(), Error> {
+        let entries = read_dir_sorted(dir)?;
+        *data += (entries.len() as u64 + 2).div_ceil(DIRENTS_PER_BLOCK as u64);
+        for (_, path) in entries {
+            let meta = fs::symlink_metadata(&path).map_err(|e| Error::Io(path.clone(), e))?;
+            *inodes += 1;
+            if meta.is_dir() {
+                walk(&path, data, inodes)?;
+            } else {
+                let blocks = meta.len().div_ceil(BLOCK_SIZE as u64).max(1);
+                // Pointer blocks
+                *data += blocks + blocks.saturating_sub(DIRECT_BLOCKS as u64).div_ceil(PTRS_PER_BLOCK as u64) + 1;
+            }
+        }
+        Ok(())
+    }
+
+    let (mut data, mut inodes) = (0, 0);
+    walk(dir, &mut data, &mut inodes)?;
+    // `format` gives a volume one inode per 4 blocks; the inode table,
+    // journal and allocation tables come out of the slack
+    let blocks = (data + data / 8 + 1024).max((inodes + 3) * 4);
+    u32::try_from(blocks.next_multiple_of(256)).map_err(|_| Error::Fs(dir.to_path_buf(), SplaxFsError::NoSpace))
+}
+
+/// Inode for a host file, with its permissions, owner and times
+fn new_inode(meta: &fs::Metadata, all_root: bool) -> DiskInode {
+    let mut inode = if meta.is_dir() {
+        DiskInode::new_directory()
+    } else if meta.file_type().is_symlink() {
+        DiskInode::new_symlink()
+    } else {
+        DiskInode::new_file()
+    };
+    inode.mode = (inode.mode & S_IFMT) | (meta.mode() & 0o7777) as u16;
+    if !all_root {
+        inode.uid = meta.uid() as u16;
+        inode.gid = meta.gid() as u16;
+    }
+    inode.atime = meta.atime() as u32;
+    inode.mtime = meta.mtime() as u32;
+    inode.ctime = inode.mtime;
+    inode
+}
+
+/// Copies the content of host directory `src` into directory `dir` of a
+/// volume; with `all_root`, everything is owned by root
+pub fn copy_in<D: BlockIo>(volume: &mut Volume<D>, dir: u32, src: &Path, all_root: bool) -> Result<Stats, Error> {
+    let mut stats = Stats::default();
+    let mut links = HashMap::new();
+    copy_dir_in(volume, dir, src, all_root, &mut links, &mut stats)?;
+    volume.commit().map_err(|e| Error::Fs(src.to_path_buf(), e))?;
+    Ok(stats)
+}
+
+fn copy_dir_in<D: BlockIo>(
+    volume: &mut Volume<D>,
+    dir: u32,
+    src: &Path,
+    all_root: bool,
+    links: &mut HashMap<(u64, u64), u32>,
+    stats: &mut Stats,
+) -> Result<(), Error> {
+    for (name, path) in read_dir_sorted(src)? {
+        let meta = fs::symlink_metadata(&path).map_err(|e| Error::Io(path.clone(), e))?;
+        let fs_err = |e| Error::Fs(path.clone(), e);
+        let kind = meta.file_type();
+        if !kind.is_dir() && !kind.is_file() && !kind.is_symlink() {
+            stats.skipped.push(path);
+            continue;
+        }
+        if !kind.is_dir() && meta.nlink() > 1 {
+            if let Some(&ino) = links.get(&(meta.dev(), meta.ino())) {
+                volume.link(dir, &name, ino).map_err(fs_err)?;
+                continue;
+            }
+        }
+
+        let ino = volume.create(dir, &name, new_inode(&meta, all_root)).map_err(fs_err)?;
+        if kind.is_dir() {
+            stats.directories += 1;
+            copy_dir_in(volume, ino, &path, all_root, links, stats)?;
+            continue;
+        }
+        let data = if kind.is_symlink() {
+            stats.symlinks += 1;
+            let target = fs::read_link(&path).map_err(|e| Error::Io(path.clone(), e))?;
+            target.into_os_string().into_string().map_err(|_| fs_err(SplaxFsError::InvalidArg))?.into_bytes()
+        } else {
+            stats.files += 1;
+            fs::read(&path).map_err(|e| Error::Io(path.clone(), e))?
+        };
+        volume.write(ino, &data).map_err(fs_err)?;
+        if meta.nlink() > 1 {
+            links.insert((meta.dev(), meta.ino()), ino);
+        }
+    }
+    Ok(())
+}
+
+/// Copies the content of directory `dir` of a volume into host directory
+/// `dest`, which is created if needed
+pub fn copy_out<D: BlockIo>(volume: &Volume<D>, dir: u32, dest: &Path) -> Result<Stats, Error> {
+    let mut stats = Stats::default();
+    fs::create_dir_all(dest).map_err(|e| Error::Io(dest.to_path_buf(), e))?;
+    copy_dir_out(volume, dir, dest, &mut BTreeMap::new(), &mut stats)?;
+    Ok(stats)
+}
+
+fn copy_dir_out<D: BlockIo>(
+    volume: &Volume<D>,
+    dir: u32,
+    dest: &Path,
+    links: &mut BTreeMap<u32, PathBuf>,
+    stats: &mut Stats,
+) -> Result<(), Error> {
+    let entries = volume.entries(dir).map_err(|e| Error::Fs(dest.to_path_buf(), e))?;
+    for (name, ino) in entries {
+        let path = dest.join(&name);
+        let io_err = |e| Error::Io(path.clone(), e);
+        let inode = volume.inode(ino).map_err(|e| Error::Fs(path.clone(), e))?;
+        if inode.is_encrypted() {
+            stats.skipped.push(path);
+            continue;
+        }
+        if let Some(first) = links.get(&ino) {
+            fs::hard_link(first, &path).map_err(io_err)?;
+            continue;
+        }
+
+        let mode = (inode.mode & 0o7777) as u32;
+        let mtime = UNIX_EPOCH + Duration::from_secs(inode.mtime as u64);
+        match inode.file_type() {
+            FileType::Directory => {
+                stats.directories += 1;
+                match fs::create_dir(&path) {
+                    Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(io_err(e)),
+                    _ => {}
+                }
+                copy_dir_out(volume, ino, &path, links, stats)?;
+                // After the content, which a read-only directory would refuse
+                fs::set_permissions(&path, fs::Permissions::from_mode(mode)).map_err(io_err)?;
+            }
+            FileType::Symlink => {
+                stats.symlinks += 1;
+                let target = volume.readlink(&inode).map_err(|e| Error::Fs(path.clone(), e))?;
+                std::os::unix::fs::symlink(target, &path).map_err(io_err)?;
+            }
+            FileType::Regular => {
+                stats.files += 1;
+                let data = volume.read(&inode).map_err(|e| Error::Fs(path.clone(), e))?;
+                fs::write(&path, data).map_err(io_err)?;
+                let file = fs::File::options().write(true).open(&path).map_err(io_err)?;
+                file.set_modified(mtime).map_err(io_err)?;
+                file.set_permissions(fs::Permissions::from_mode(mode)).map_err(io_err)?;
+            }
+            FileType::Unknown => {
+                stats.skipped.push(path);
+                continue;
+            }
+        }
+        if inode.links_count > 1 && !inode.is_directory() {
+            links.insert(ino, path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::Image;
+    use splaxfs_core::CheckOptions;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn test_copy_round_trip() {
+        let base = std::env::temp_dir().join(format!("splaxfs-tree-{}", std::process::id()));
+        let src = base.join("src");
+        fs::create_dir_all(src.join("etc/init.d")).unwrap();
+        fs::create_dir_all(src.join("empty")).unwrap();
+        fs::write(src.join("etc/hostname"), b"splax\n").unwrap();
+        fs::set_permissions(src.join("etc/hostname"), fs::Permissions::from_mode(0o600)).unwrap();
+        fs::write(src.join("etc/init.d/rc"), b"#!/bin/sh\n").unwrap();
+        fs::set_permissions(src.join("etc/init.d/rc"), fs::Permissions::from_mode(0o755)).unwrap();
+        fs::hard_link(src.join("etc/hostname"), src.join("hostname")).unwrap();
+        symlink("etc/init.d/rc", src.join("rc")).unwrap();
+        // Past the direct and single indirect blocks, with a hole
+        let mut big: Vec<u8> = (0..(DIRECT_BLOCKS + PTRS_PER_BLOCK + 3) * BLOCK_SIZE).map(|i| (i % 251) as u8).collect();
+        big[BLOCK_SIZE..2 * BLOCK_SIZE].fill(0);
+        fs::write(src.join("big"), &big).unwrap();
+        // Enough entries to take several directory blocks
+        for i in 0..40 {
+            fs::write(src.join("empty").join(format!("f{}", i)), b"").unwrap();
+        }
+
+        let path = base.join("test.img");
+        let blocks = estimate_blocks(&src).unwrap();
+        let image = Image::create(&path, blocks as u64 * BLOCK_SIZE as u64).unwrap();
+        splaxfs_core::format(&image, true, 0).unwrap();
+        let mut volume = Volume::open(&image, true).unwrap();
+        let stats = copy_in(&mut volume, ROOT_INODE, &src, false).unwrap();
+        assert_eq!((stats.files, stats.directories, stats.symlinks), (43, 3, 1));
+
+        let report = splaxfs_core::check(&image, &CheckOptions { repair: false, time: 0 }).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+        assert_eq!((report.files, report.directories, report.symlinks), (43, 4, 1));
+
+        let volume = Volume::open(&image, false).unwrap();
+        let hostname = volume.lookup("/etc/hostname").unwrap();
+        assert_eq!(volume.lookup("/hostname").unwrap(), hostname);
+        let inode = volume.inode(hostname).unwrap();
+        assert_eq!((inode.mode & 0o7777, inode.links_count), (0o600, 2));
+        assert_eq!(volume.readlink(&volume.inode(volume.lookup("/rc").unwrap()).unwrap()).unwrap(), "etc/init.d/rc");
+        assert_eq!(volume.lookup("/missing"), Err(SplaxFsError::NotFound));
+
+        let dest = base.join("dest");
+        let stats = copy_out(&volume, ROOT_INODE, &dest).unwrap();
+        assert!(stats.skipped.is_empty());
+        assert_eq!(fs::read(dest.join("big")).unwrap(), big);
+        assert_eq!(fs::read(dest.join("hostname")).unwrap(), b"splax\n");
+        assert_eq!(fs::metadata(dest.join("hostname")).unwrap().nlink(), 2);
+        assert_eq!(fs::metadata(dest.join("etc/init.d/rc")).unwrap().mode() & 0o7777, 0o755);
+        assert_eq!(fs::read_link(dest.join("rc")).unwrap(), Path::new("etc/init.d/rc"));
+        assert_eq!(fs::read_dir(dest.join("empty")).unwrap().count(), 40);
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
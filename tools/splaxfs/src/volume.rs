@@ -0,0 +1,482 @@
+//! Offline volume access
+//!
+//! Reads files and directories of an unmounted volume, and adds new ones
+//! to it, without going through the journal. Writes are meant for images
+//! being built: the volume must be clean and have no snapshots, and the
+//! data of a file is written once, right after the file is created.
+//!
+//! Inode table, directory and pointer blocks are kept in memory until
+//! [`Volume::commit`], which writes them with the allocation tables, the
+//! block checksums and the superblock.
+
+use std::collections::BTreeMap;
+
+use splaxfs_core::layout::*;
+use splaxfs_core::{cluster, crypt, BlockIo, ChecksumTable, Journal};
+
+/// Volume opened by the host tools
+pub struct Volume<'a, D: BlockIo> {
+    dev: &'a D,
+    sb: Superblock,
+    /// Live inode table block of each table index
+    table: Vec<u32>,
+    refcounts: Vec<u8>,
+    inode_bitmap: Vec<u8>,
+    checksums: ChecksumTable,
+    /// Metadata blocks changed since the last commit
+    dirty: BTreeMap<u32, Vec<u8>>,
+    writable: bool,
+    /// Where the next block and inode searches start
+    next_block: u32,
+    next_inode: u32,
+}
+
+impl<'a, D: BlockIo> Volume<'a, D> {
+    /// Opens a volume. A volume with journal transactions still to replay
+    /// is refused (`fsck -y` replays them); so is writing to one that is
+    /// not clean or has snapshots.
+    pub fn open(dev: &'a D, writable: bool) -> Result<Self, SplaxFsError> {
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        dev.read(0, &mut buf)?;
+        let sb = Superblock::from_bytes(&buf);
+        if !sb.is_valid() || !sb.checksum_is_valid() || !sb.layout_is_valid() || sb.total_blocks > dev.total_blocks() {
+            return Err(SplaxFsError::Corrupted);
+        }
+        if sb.journal_block != 0 && Journal::open(dev, sb.journal_block)?.pending(dev)?.is_some() {
+            return Err(SplaxFsError::JournalError);
+        }
+
+        let read_area = |start: u32, blocks: u32| -> Result<Vec<u8>, SplaxFsError> {
+            let mut data = vec![0u8; blocks as usize * BLOCK_SIZE];
+            for (i, chunk) in data.chunks_mut(BLOCK_SIZE).enumerate() {
+                dev.read(start + i as u32, chunk)?;
+            }
+            Ok(data)
+        };
+        let map = read_area(sb.inode_map_block, sb.inode_map_blocks())?;
+        let table = (0..sb.inode_table_blocks() as usize).map(|i| get_u32(&map, i * 4)).collect();
+        let volume = Self {
+            dev,
+            sb,
+            table,
+            refcounts: read_area(sb.refcount_block, sb.refcount_blocks())?,
+            inode_bitmap: read_area(sb.inode_bitmap_block, sb.inode_bitmap_blocks())?,
+            checksums: ChecksumTable::from_bytes(read_area(sb.csum_block, sb.csum_blocks())?),
+            dirty: BTreeMap::new(),
+            writable,
+            next_block: sb.first_data_block,
+            next_inode: ROOT_INODE + 1,
+        };
+
+        if writable {
+            let snapshots = SnapshotTable::from_bytes(&read_area(sb.snapshot_block, 1)?);
+            if sb.state != STATE_CLEAN {
+                return Err(SplaxFsError::Busy);
+            }
+            if snapshots.iter().next().is_some() {
+                return Err(SplaxFsError::NotSupported);
+            }
+        }
+        Ok(volume)
+    }
+
+    /// Superblock, with the counts as of the last change
+    pub fn superblock(&self) -> &Superblock {
+        &self.sb
+    }
+
+    // -------------------------------------------------------------------------
+    // Reading
+    // -------------------------------------------------------------------------
+
+    fn read_block(&self, block_num: u32) -> Result<Vec<u8>, SplaxFsError> {
+        if let Some(image) = self.dirty.get(&block_num) {
+            return Ok(image.clone());
+        }
+        if block_num >= self.sb.total_blocks {
+            return Err(SplaxFsError::Corrupted);
+        }
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        self.dev.read(block_num, &mut buf)?;
+        Ok(buf)
+    }
+
+    /// Inode table block and byte offset of an inode
+    fn inode_location(&self, ino: u32) -> Result<(u32, usize), SplaxFsError> {
+        if ino == 0 || ino > self.sb.total_inodes {
+            return Err(SplaxFsError::InvalidArg);
+        }
+        let index = (ino - 1) as usize;
+        match self.table[index / INODES_PER_BLOCK] {
+            0 => Err(SplaxFsError::Corrupted),
+            block_num => Ok((block_num, (index % INODES_PER_BLOCK) * INODE_SIZE)),
+        }
+    }
+
+    /// Reads an inode in use
+    pub fn inode(&self, ino: u32) -> Result<DiskInode, SplaxFsError> {
+        let (block_num, offset) = self.inode_location(ino)?;
+        let inode = DiskInode::from_bytes(&self.read_block(block_num)?[offset..]);
+        if inode.mode == 0 || !test_bit(&self.inode_bitmap, ino) {
+            return Err(SplaxFsError::NotFound);
+        }
+        Ok(inode)
+    }
+
+    /// Maps file block `index` to a disk block; 0 is a hole
+    fn bmap(&self, inode: &DiskInode, index: u64) -> Result<u32, SplaxFsError> {
+        let ptrs = PTRS_PER_BLOCK as u64;
+        let read_ptr = |block_num: u32, slot: u64| -> Result<u32, SplaxFsError> {
+            if block_num == 0 {
+                return Ok(0);
+            }
+            Ok(get_u32(&self.read_block(block_num)?, slot as usize * 4))
+        };
+        if index < DIRECT_BLOCKS as u64 {
+            return Ok(inode.direct[index as usize]);
+        }
+        let index = index - DIRECT_BLOCKS as u64;
+        if index < ptrs {
+            return read_ptr(inode.indirect, index);
+        }
+        let index = index - ptrs;
+        if index < ptrs * ptrs {
+            return read_ptr(read_ptr(inode.double_indirect, index / ptrs)?, index % ptrs);
+        }
+        Ok(0)
+    }
+
+    /// Whole content of a file or symlink. Encrypted content cannot be
+    /// read without the key.
+    pub fn read(&self, inode: &DiskInode) -> Result<Vec<u8>, SplaxFsError> {
+        if inode.is_directory() {
+            return Err(SplaxFsError::IsDirectory);
+        }
+        if inode.is_encrypted() {
+            return Err(SplaxFsError::NoKey);
+        }
+        let size = usize::try_from(inode.size()).map_err(|_| SplaxFsError::NoSpace)?;
+        let mut data = Vec::with_capacity(size);
+        let mut index = 0;
+        while data.len() < size {
+            let (first, blocks) = cluster::span(index);
+            let slots = (first..first + blocks as u64)
+                .map(|i| self.bmap(inode, i))
+                .collect::<Result<Vec<u32>, _>>()?;
+            if inode.is_compressed() && cluster::is_compressed(&slots) {
+                if !cluster::is_valid(&slots) {
+                    return Err(SplaxFsError::Corrupted);
+                }
+                let mut image = Vec::new();
+                for &block_num in slots.iter().take_while(|&&ptr| cluster::is_block(ptr)) {
+                    image.extend_from_slice(&self.read_block(block_num)?);
+                }
+                let mut content = cluster::unpack(&image)?;
+                content.resize(blocks * BLOCK_SIZE, 0);
+                data.extend_from_slice(&content);
+            } else {
+                for &block_num in &slots {
+                    match block_num {
+                        0 => data.resize(data.len() + BLOCK_SIZE, 0),
+                        _ => data.extend_from_slice(&self.read_block(block_num)?),
+                    }
+                }
+            }
+            index = first + blocks as u64;
+        }
+        data.truncate(size);
+        Ok(data)
+    }
+
+    /// Target of a symlink
+    pub fn readlink(&self, inode: &DiskInode) -> Result<String, SplaxFsError> {
+        if inode.file_type() != FileType::Symlink {
+            return Err(SplaxFsError::InvalidArg);
+        }
+        String::from_utf8(self.read(inode)?).map_err(|_| SplaxFsError::Corrupted)
+    }
+
+    /// Entries of a directory with their block and byte offset, free slots
+    /// included (inode 0)
+    fn dir_slots(&self, dir: &DiskInode) -> Result<Vec<(u32, usize, DirEntry)>, SplaxFsError> {
+        let mut slots = Vec::new();
+        for index in 0..dir.size() / BLOCK_SIZE as u64 {
+            let block_num = self.bmap(dir, index)?;
+            if block_num == 0 {
+                continue;
+            }
+            let data = self.read_block(block_num)?;
+            for slot in 0..DIRENTS_PER_BLOCK {
+                let offset = slot * DIRENT_SIZE;
+                slots.push((block_num, offset, DirEntry::from_bytes(&data[offset..])));
+            }
+        }
+        Ok(slots)
+    }
+
+    /// Names and inodes in a directory, without `.` and `..`. Names in an
+    /// encrypted directory are shown as they are without the key.
+    pub fn entries(&self, ino: u32) -> Result<Vec<(String, u32)>, SplaxFsError> {
+        let dir = self.inode(ino)?;
+        if !dir.is_directory() {
+            return Err(SplaxFsError::NotDirectory);
+        }
+        let mut entries = Vec::new();
+        for (position, (_, _, entry)) in self.dir_slots(&dir)?.into_iter().enumerate() {
+            if position < 2 || entry.inode == 0 {
+                continue;
+            }
+            let name = if dir.is_encrypted() {
+                crypt::encode_name(entry.raw_name())
+            } else {
+                String::from_utf8_lossy(entry.raw_name()).into_owned()
+            };
+            entries.push((name, entry.inode));
+        }
+        Ok(entries)
+    }
+
+    /// Inode at an absolute path; symlinks are not followed
+    pub fn lookup(&self, path: &str) -> Result<u32, SplaxFsError> {
+        let mut ino = ROOT_INODE;
+        for name in path.split('/').filter(|name| !name.is_empty() && *name != ".") {
+            ino = self
+                .entries(ino)?
+                .into_iter()
+                .find(|(entry, _)| entry == name)
+                .map(|(_, ino)| ino)
+                .ok_or(SplaxFsError::NotFound)?;
+        }
+        Ok(ino)
+    }
+
+    // -------------------------------------------------------------------------
+    // Writing
+    // -------------------------------------------------------------------------
+
+    fn write_block(&mut self, block_num: u32, data: Vec<u8>) {
+        self.dirty.insert(block_num, data);
+    }
+
+    fn write_inode(&mut self, ino: u32, inode: &DiskInode) -> Result<(), SplaxFsError> {
+        let (block_num, offset) = self.inode_location(ino)?;
+        let mut data = self.read_block(block_num)?;
+        data[offset..offset + INODE_SIZE].copy_from_slice(&inode.to_bytes());
+        self.write_block(block_num, data);
+        Ok(())
+    }
+
+    fn alloc_block(&mut self) -> Result<u32, SplaxFsError> {
+        let (first, total) = (self.sb.first_data_block, self.sb.total_blocks);
+        let block_num = find_free(&self.refcounts, self.next_block, total)
+            .or_else(|| find_free(&self.refcounts, first, self.next_block))
+            .ok_or(SplaxFsError::NoSpace)?;
+        set_ref(&mut self.refcounts, block_num, 1);
+        self.sb.free_blocks -= 1;
+        self.next_block = block_num + 1;
+        Ok(block_num)
+    }
+
+    fn alloc_inode(&mut self) -> Result<u32, SplaxFsError> {
+        let end = self.sb.total_inodes + 1;
+        let ino = find_clear(&self.inode_bitmap, self.next_inode, end)
+            .or_else(|| find_clear(&self.inode_bitmap, ROOT_INODE + 1, self.next_inode))
+            .ok_or(SplaxFsError::NoSpace)?;
+        set_bit(&mut self.inode_bitmap, ino, true);
+        self.sb.free_inodes -= 1;
+        self.next_inode = ino + 1;
+        Ok(ino)
+    }
+
+    /// Allocates a zeroed pointer block for an empty inode pointer
+    fn ensure_ptr_block(&mut self, ptr: &mut u32, blocks: &mut u32) -> Result<u32, SplaxFsError> {
+        if *ptr == 0 {
+            *ptr = self.alloc_block()?;
+            *blocks += SECTORS_PER_BLOCK;
+            self.write_block(*ptr, vec![0u8; BLOCK_SIZE]);
+        }
+        Ok(*ptr)
+    }
+
+    /// Sets slot `slot` of a pointer block
+    fn set_slot(&mut self, block_num: u32, slot: u64, ptr: u32) -> Result<(), SplaxFsError> {
+        let mut data = self.read_block(block_num)?;
+        let offset = slot as usize * 4;
+        data[offset..offset + 4].copy_from_slice(&ptr.to_le_bytes());
+        self.write_block(block_num, data);
+        Ok(())
+    }
+
+    /// Allocates a block for file block `index`, which is a hole. The
+    /// caller writes its content.
+    fn map_new(&mut self, inode: &mut DiskInode, index: u64) -> Result<u32, SplaxFsError> {
+        let ptrs = PTRS_PER_BLOCK as u64;
+        if index < DIRECT_BLOCKS as u64 {
+            let block_num = self.alloc_block()?;
+            inode.blocks += SECTORS_PER_BLOCK;
+            inode.direct[index as usize] = block_num;
+            return Ok(block_num);
+        }
+        let index = index - DIRECT_BLOCKS as u64;
+        let (indirect, slot) = if index < ptrs {
+            (self.ensure_ptr_block(&mut inode.indirect, &mut inode.blocks)?, index)
+        } else if index - ptrs < ptrs * ptrs {
+            let index = index - ptrs;
+            let double = self.ensure_ptr_block(&mut inode.double_indirect, &mut inode.blocks)?;
+            let mut indirect = get_u32(&self.read_block(double)?, (index / ptrs) as usize * 4);
+            if indirect == 0 {
+                indirect = self.ensure_ptr_block(&mut 0, &mut inode.blocks)?;
+                self.set_slot(double, index / ptrs, indirect)?;
+            }
+            (indirect, index % ptrs)
+        } else {
+            return Err(SplaxFsError::NoSpace);
+        };
+        let block_num = self.alloc_block()?;
+        inode.blocks += SECTORS_PER_BLOCK;
+        self.set_slot(indirect, slot, block_num)?;
+        Ok(block_num)
+    }
+
+    fn check_writable(&self) -> Result<(), SplaxFsError> {
+        if self.writable {
+            Ok(())
+        } else {
+            Err(SplaxFsError::ReadOnly)
+        }
+    }
+
+    /// Adds an entry to a directory, growing it by a block if it is full
+    fn add_entry(&mut self, dir_ino: u32, name: &str, ino: u32, file_type: FileType) -> Result<(), SplaxFsError> {
+        let mut dir = self.inode(dir_ino)?;
+        let slots = self.dir_slots(&dir)?;
+        if slots.iter().skip(2).any(|(_, _, entry)| entry.inode != 0 && entry.raw_name() == name.as_bytes()) {
+            return Err(SplaxFsError::Exists);
+        }
+        let entry = DirEntry::new(ino, name, file_type);
+        let (block_num, offset) = match slots.iter().find(|(_, _, slot)| slot.inode == 0) {
+            Some(&(block_num, offset, _)) => (block_num, offset),
+            None => {
+                let index = dir.size() / BLOCK_SIZE as u64;
+                let block_num = self.map_new(&mut dir, index)?;
+                self.write_block(block_num, vec![0u8; BLOCK_SIZE]);
+                dir.set_size((index + 1) * BLOCK_SIZE as u64);
+                self.write_inode(dir_ino, &dir)?;
+                (block_num, 0)
+            }
+        };
+        let mut data = self.read_block(block_num)?;
+        data[offset..offset + DIRENT_SIZE].copy_from_slice(&entry.to_bytes());
+        self.write_block(block_num, data);
+        Ok(())
+    }
+
+    /// Creates a file, directory or symlink from a new inode (mode, owner
+    /// and times set, no blocks) and links it into a directory
+    pub fn create(&mut self, parent: u32, name: &str, mut inode: DiskInode) -> Result<u32, SplaxFsError> {
+        self.check_writable()?;
+        check_name(name)?;
+        let mut dir = self.inode(parent)?;
+        if !dir.is_directory() {
+            return Err(SplaxFsError::NotDirectory);
+        }
+        // Names would have to be encrypted, with a key we do not have
+        if dir.is_encrypted() {
+            return Err(SplaxFsError::NoKey);
+        }
+        let file_type = inode.file_type();
+        if file_type == FileType::Unknown {
+            return Err(SplaxFsError::InvalidArg);
+        }
+
+        let ino = self.alloc_inode()?;
+        inode.set_size(0);
+        inode.blocks = 0;
+        if file_type == FileType::Directory {
+            inode.links_count = 2;
+            let block_num = self.map_new(&mut inode, 0)?;
+            let mut data = vec![0u8; BLOCK_SIZE];
+            data[..DIRENT_SIZE].copy_from_slice(&DirEntry::new(ino, ".", FileType::Directory).to_bytes());
+            data[DIRENT_SIZE..2 * DIRENT_SIZE].copy_from_slice(&DirEntry::new(parent, "..", FileType::Directory).to_bytes());
+            self.write_block(block_num, data);
+            inode.set_size(BLOCK_SIZE as u64);
+        } else {
+            inode.links_count = 1;
+        }
+        self.write_inode(ino, &inode)?;
+        self.add_entry(parent, name, ino, file_type)?;
+        if file_type == FileType::Directory {
+            dir = self.inode(parent)?;
+            dir.links_count += 1;
+            self.write_inode(parent, &dir)?;
+        }
+        Ok(ino)
+    }
+
+    /// Adds another name for a file or symlink
+    pub fn link(&mut self, parent: u32, name: &str, ino: u32) -> Result<(), SplaxFsError> {
+        self.check_writable()?;
+        check_name(name)?;
+        let mut inode = self.inode(ino)?;
+        if inode.is_directory() {
+            return Err(SplaxFsError::IsDirectory);
+        }
+        if self.inode(parent)?.is_encrypted() {
+            return Err(SplaxFsError::NoKey);
+        }
+        self.add_entry(parent, name, ino, inode.file_type())?;
+        inode.links_count += 1;
+        self.write_inode(ino, &inode)
+    }
+
+    /// Writes the content of a new, empty file or symlink. Blocks of zeros
+    /// are left as holes.
+    pub fn write(&mut self, ino: u32, data: &[u8]) -> Result<(), SplaxFsError> {
+        self.check_writable()?;
+        let mut inode = self.inode(ino)?;
+        match inode.file_type() {
+            FileType::Directory => return Err(SplaxFsError::IsDirectory),
+            FileType::Symlink if data.is_empty() || data.len() >= BLOCK_SIZE => return Err(SplaxFsError::InvalidArg),
+            _ if inode.size() != 0 => return Err(SplaxFsError::Exists),
+            _ if data.len() as u64 > MAX_FILE_BLOCKS * BLOCK_SIZE as u64 => return Err(SplaxFsError::NoSpace),
+            _ => {}
+        }
+        let symlink = inode.file_type() == FileType::Symlink;
+        for (index, chunk) in data.chunks(BLOCK_SIZE).enumerate() {
+            // A symlink keeps its one block
+            if !symlink && chunk.iter().all(|&b| b == 0) {
+                continue;
+            }
+            let block_num = self.map_new(&mut inode, index as u64)?;
+            let mut buf = vec![0u8; BLOCK_SIZE];
+            buf[..chunk.len()].copy_from_slice(chunk);
+            self.checksums.record(block_num, &buf);
+            self.dev.write(block_num, &buf)?;
+        }
+        inode.set_size(data.len() as u64);
+        self.write_inode(ino, &inode)
+    }
+
+    /// Writes the changed metadata, the allocation tables, the checksum
+    /// table and the superblock
+    pub fn commit(&mut self) -> Result<(), SplaxFsError> {
+        self.check_writable()?;
+        for (block_num, image) in std::mem::take(&mut self.dirty) {
+            self.checksums.record(block_num, &image);
+            self.dev.write(block_num, &image)?;
+        }
+        for (start, area) in [(self.sb.refcount_block, &self.refcounts), (self.sb.inode_bitmap_block, &self.inode_bitmap)] {
+            for (i, chunk) in area.chunks(BLOCK_SIZE).enumerate() {
+                self.checksums.record(start + i as u32, chunk);
+                self.dev.write(start + i as u32, chunk)?;
+            }
+        }
+        for index in 0..self.sb.csum_blocks() {
+            self.dev.write(self.sb.csum_block + index, &self.checksums.block_image(self.sb.csum_block, index))?;
+        }
+        let mut buf = self.read_block(0)?;
+        buf[..512].copy_from_slice(&self.sb.to_bytes());
+        self.dev.write(0, &buf)?;
+        self.dev.flush()
+    }
+}